@@ -0,0 +1,114 @@
+//! Emails for account management, such as verifying a new user's address and resetting a
+//! password.
+
+use std::time::Duration;
+
+use lettre::{message::Mailbox, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use tracing::{event, Level};
+
+use crate::error::{Error, Result};
+
+pub struct AccountMailer {
+    /// None if SMTP is not configured, in which case emails are dropped.
+    transport: Option<AsyncSmtpTransport<Tokio1Executor>>,
+    from: Mailbox,
+    /// The base URL of the web app, for the links in each email.
+    public_url: String,
+}
+
+impl AccountMailer {
+    /// Read the settings from `SMTP_URL`, `EMAIL_FROM`, and `PUBLIC_URL`.
+    pub fn from_env() -> Result<AccountMailer> {
+        let transport = std::env::var("SMTP_URL")
+            .ok()
+            .map(|url| {
+                AsyncSmtpTransport::<Tokio1Executor>::from_url(&url)
+                    .map(|t| t.timeout(Some(Duration::from_secs(30))).build())
+                    .map_err(|e| Error::ConfigError(format!("SMTP_URL: {}", e)))
+            })
+            .transpose()?;
+
+        let from = std::env::var("EMAIL_FROM")
+            .unwrap_or_else(|_| "Ergo <ergo@localhost>".to_string())
+            .parse()
+            .map_err(|e| Error::ConfigError(format!("EMAIL_FROM: {}", e)))?;
+
+        let public_url = std::env::var("PUBLIC_URL")
+            .unwrap_or_else(|_| "http://localhost:6543".to_string())
+            .trim_end_matches('/')
+            .to_string();
+
+        Ok(AccountMailer {
+            transport,
+            from,
+            public_url,
+        })
+    }
+
+    pub async fn send_verification(&self, to: &str, token: &str) -> Result<()> {
+        let link = format!("{}/signup/verify?token={}", self.public_url, token);
+        self.send(
+            to,
+            "Verify your email address",
+            format!(
+                "Welcome to Ergo! Open this link to verify your email address and finish \
+                signing up:\n\n{}\n",
+                link
+            ),
+        )
+        .await
+    }
+
+    pub async fn send_password_reset(&self, to: &str, token: &str) -> Result<()> {
+        let link = format!("{}/password_reset?token={}", self.public_url, token);
+        self.send(
+            to,
+            "Reset your password",
+            format!(
+                "Open this link to choose a new password. It expires in one hour.\n\n{}\n\n\
+                If you didn't ask to reset your password, you can ignore this email.\n",
+                link
+            ),
+        )
+        .await
+    }
+
+    pub async fn send_invitation(&self, to: &str, org_name: &str, invited_by: &str) -> Result<()> {
+        let link = format!("{}/invitations", self.public_url);
+        self.send(
+            to,
+            &format!("Join {} on Ergo", org_name),
+            format!(
+                "{} invited you to join {} on Ergo. Open this link to accept or decline. If you \
+                don't have an account yet, sign up with this email address first.\n\n{}\n",
+                invited_by, org_name, link
+            ),
+        )
+        .await
+    }
+
+    async fn send(&self, to: &str, subject: &str, body: String) -> Result<()> {
+        let transport = match &self.transport {
+            Some(t) => t,
+            None => {
+                event!(Level::WARN, %subject, "SMTP_URL is not set, so account emails can't be sent");
+                return Ok(());
+            }
+        };
+
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to
+                .parse()
+                .map_err(|_| Error::InvalidRequest("Invalid email address".to_string()))?)
+            .subject(subject)
+            .body(body)
+            .map_err(|e| Error::StringError(e.to_string()))?;
+
+        transport
+            .send(message)
+            .await
+            .map_err(|e| Error::StringError(format!("Sending email: {}", e)))?;
+        Ok(())
+    }
+}
@@ -2,14 +2,16 @@ use actix_web::web::Data;
 use ergo_auth::AuthData;
 use ergo_database::PostgresPool;
 use ergo_notifications::NotificationManager;
+use ergo_queues::Queue;
 use ergo_tasks::{actions::queue::ActionQueue, inputs::queue::InputQueue};
 
-use crate::error::Result;
+use crate::{account_email::AccountMailer, error::Result};
 
 pub struct BackendAppState {
     pub pg: PostgresPool,
     pub auth: AuthData,
     pub notifications: NotificationManager,
+    pub mailer: AccountMailer,
     action_queue: ActionQueue,
     input_queue: InputQueue,
     pub redis_key_prefix: Option<String>,
@@ -17,6 +19,17 @@ pub struct BackendAppState {
 
 pub type BackendAppStateData = Data<BackendAppState>;
 
+impl BackendAppState {
+    /// Look up one of the server's queues by its short name, `input` or `action`.
+    pub fn queue(&self, name: &str) -> Option<&Queue> {
+        match name {
+            "input" => Some(&self.input_queue),
+            "action" => Some(&self.action_queue),
+            _ => None,
+        }
+    }
+}
+
 pub fn app_data(
     pg_pool: PostgresPool,
     notifications: NotificationManager,
@@ -28,6 +41,7 @@ pub fn app_data(
         auth: AuthData::new(pg_pool.clone())?,
         pg: pg_pool,
         notifications,
+        mailer: AccountMailer::from_env()?,
         action_queue,
         input_queue,
         redis_key_prefix,
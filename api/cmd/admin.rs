@@ -0,0 +1,361 @@
+use crate::error::{Error, Result};
+use ergo_database::object_id::*;
+use ergo_tasks::dependents::{apply_dependent_validation, InvalidDependentPolicy};
+use sqlx::{Connection, PgConnection};
+use structopt::StructOpt;
+use uuid::Uuid;
+
+use super::make_api_key::make_key;
+
+#[derive(Debug, StructOpt)]
+pub struct Args {
+    #[structopt(short, long, help = "Database connection string", env = "DATABASE_URL")]
+    database: String,
+    #[structopt(subcommand)]
+    cmd: AdminCmd,
+}
+
+#[derive(Debug, StructOpt)]
+enum AdminCmd {
+    #[structopt(about = "Create an organization")]
+    CreateOrg { name: String },
+    #[structopt(about = "Create a user")]
+    CreateUser {
+        #[structopt(short, long, help = "The user's organization", env = "ORG_ID")]
+        org: OrgId,
+        #[structopt(short, long)]
+        name: String,
+        #[structopt(short, long)]
+        email: String,
+        #[structopt(short, long, help = "A password, for users that will log in to the UI")]
+        password: Option<String>,
+    },
+    #[structopt(about = "Deactivate a user and disable their API keys")]
+    DeactivateUser { user: UserId },
+    #[structopt(about = "Add a user to a role, creating the role if it doesn't exist")]
+    AssignRole {
+        #[structopt(short, long)]
+        user: UserId,
+        #[structopt(short, long, help = "The organization that the role belongs to")]
+        org: OrgId,
+        #[structopt(short, long, help = "The name of the role")]
+        role: String,
+    },
+    #[structopt(about = "Disable an API key and create a new one with the same settings")]
+    RotateApiKey { api_key_id: Uuid },
+    #[structopt(about = "Show daily usage for each organization")]
+    Usage {
+        #[structopt(short, long, help = "Only show this organization")]
+        org: Option<OrgId>,
+        #[structopt(short, long, default_value = "7", help = "Number of days to show")]
+        days: i32,
+    },
+    #[structopt(
+        about = "Encrypt plaintext account fields, and rewrap fields encrypted with an old key"
+    )]
+    EncryptAccountFields {
+        #[structopt(long, help = "Only show how many accounts would change")]
+        dry_run: bool,
+    },
+    #[structopt(
+        about = "Validate and recompile stored tasks, and flag the ones that no longer validate"
+    )]
+    RevalidateTasks {
+        #[structopt(short, long, help = "Only check this organization")]
+        org: Option<OrgId>,
+        #[structopt(long, help = "Disable the tasks that no longer validate")]
+        disable: bool,
+        #[structopt(long, help = "Only show the results, without saving anything")]
+        dry_run: bool,
+    },
+}
+
+async fn create_org(conn: &mut PgConnection, name: &str) -> Result<()> {
+    let org_id = OrgId::new();
+    sqlx::query!(
+        "INSERT INTO orgs (org_id, name) VALUES ($1, $2)",
+        &org_id.0,
+        name
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    println!("Org ID: {}", org_id);
+    Ok(())
+}
+
+async fn create_user(
+    conn: &mut PgConnection,
+    org: &OrgId,
+    name: &str,
+    email: &str,
+    password: Option<&str>,
+) -> Result<()> {
+    let password_hash = password.map(ergo_auth::password::new_hash).transpose()?;
+
+    let user_id = UserId::new();
+    sqlx::query!(
+        "INSERT INTO users (user_id, active_org_id, name, email, password_hash)
+        VALUES ($1, $2, $3, $4, $5)",
+        &user_id.0,
+        &org.0,
+        name,
+        email,
+        password_hash
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        "INSERT INTO org_members (org_id, user_id) VALUES ($1, $2)",
+        &org.0,
+        &user_id.0
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    println!("User ID: {}", user_id);
+    Ok(())
+}
+
+async fn deactivate_user(conn: &mut PgConnection, user: &UserId) -> Result<()> {
+    let result = sqlx::query!(
+        "UPDATE users SET deleted=true WHERE user_id=$1 AND NOT deleted",
+        &user.0
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    let keys = sqlx::query!(
+        "UPDATE api_keys SET active=false WHERE user_id=$1 AND active",
+        &user.0
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    println!(
+        "Deactivated user {} and {} API keys",
+        user,
+        keys.rows_affected()
+    );
+    Ok(())
+}
+
+async fn assign_role(
+    conn: &mut PgConnection,
+    user: &UserId,
+    org: &OrgId,
+    role: &str,
+) -> Result<()> {
+    let existing = sqlx::query_scalar!(
+        r##"SELECT role_id AS "role_id: RoleId" FROM roles WHERE org_id=$1 AND name=$2"##,
+        &org.0,
+        role
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    let role_id = match existing {
+        Some(role_id) => role_id,
+        None => {
+            let role_id = RoleId::new();
+            sqlx::query!(
+                "INSERT INTO roles (role_id, org_id, name) VALUES ($1, $2, $3)",
+                &role_id.0,
+                &org.0,
+                role
+            )
+            .execute(&mut *conn)
+            .await?;
+            println!("Created role {} ({})", role, role_id);
+            role_id
+        }
+    };
+
+    sqlx::query!(
+        "INSERT INTO user_roles (user_id, role_id, org_id) VALUES ($1, $2, $3)
+        ON CONFLICT DO NOTHING",
+        &user.0,
+        &role_id.0,
+        &org.0
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    println!("Assigned role {} to user {}", role, user);
+    Ok(())
+}
+
+async fn rotate_api_key(conn: &mut PgConnection, api_key_id: &Uuid) -> Result<()> {
+    let old = sqlx::query!(
+        r##"UPDATE api_keys SET active=false
+        WHERE api_key_id=$1 AND active
+        RETURNING org_id AS "org_id: OrgId", user_id AS "user_id: UserId",
+            inherits_user_permissions, description"##,
+        api_key_id
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    make_key(
+        conn,
+        &old.org_id,
+        old.user_id.as_ref(),
+        !old.inherits_user_permissions,
+        old.description.as_deref(),
+    )
+    .await?;
+
+    println!("Disabled key {}", api_key_id);
+    Ok(())
+}
+
+async fn show_usage(conn: &mut PgConnection, org: Option<&OrgId>, days: i32) -> Result<()> {
+    let rows = sqlx::query!(
+        r##"SELECT orgs.org_id AS "org_id: OrgId", orgs.name, ou.day,
+            ou.inputs, ou.actions, ou.js_cpu_ms
+        FROM org_usage ou
+        JOIN orgs USING(org_id)
+        WHERE ($1::uuid IS NULL OR ou.org_id=$1)
+            AND ou.day > (now() AT TIME ZONE 'UTC')::date - $2::int
+        ORDER BY orgs.name, ou.day DESC"##,
+        org.map(|o| o.0),
+        days
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    println!("Org\tName\tDay\tInputs\tActions\tScript ms");
+    for row in rows {
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            row.org_id, row.name, row.day, row.inputs, row.actions, row.js_cpu_ms
+        );
+    }
+
+    Ok(())
+}
+
+async fn encrypt_account_fields(conn: &mut PgConnection, dry_run: bool) -> Result<()> {
+    let keys = ergo_database::encryption::account_field_keys()?;
+    if !keys.enabled() {
+        return Err(Error::StringError(
+            "Set ACCOUNT_FIELDS_KEY to encrypt account fields".to_string(),
+        ));
+    }
+
+    let rows = sqlx::query!(
+        r##"SELECT account_id AS "account_id: AccountId", fields AS "fields!"
+        FROM accounts
+        WHERE fields IS NOT NULL
+        FOR UPDATE"##
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mut count = 0;
+    for row in rows {
+        if !keys.needs_encryption(&row.fields) {
+            continue;
+        }
+
+        count += 1;
+        if dry_run {
+            continue;
+        }
+
+        let fields = keys.encrypt(&keys.decrypt(row.fields)?)?;
+        sqlx::query!(
+            "UPDATE accounts SET fields=$2 WHERE account_id=$1",
+            row.account_id.0,
+            fields
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    if dry_run {
+        println!("{} accounts need to be encrypted", count);
+    } else {
+        println!("Encrypted {} accounts", count);
+    }
+
+    Ok(())
+}
+
+async fn revalidate_tasks(
+    conn: &mut PgConnection,
+    org: Option<&OrgId>,
+    disable: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let result = ergo_tasks::revalidate::revalidate_tasks(conn, org, dry_run).await?;
+
+    if !dry_run {
+        let policy = if disable {
+            InvalidDependentPolicy::Disable
+        } else {
+            InvalidDependentPolicy::Flag
+        };
+        apply_dependent_validation(conn, None, None, &result.validation, policy, None).await?;
+    }
+
+    let report = result.report(None);
+    for task in &report.invalid {
+        println!("Invalid: {} {} ({})", task.org_id, task.task_id, task.name);
+        for error in &task.errors {
+            println!("  {}", error);
+        }
+    }
+
+    println!(
+        "Checked {} tasks: {} invalid, {} {}",
+        report.checked,
+        report.invalid.len(),
+        report.recompiled.len(),
+        if dry_run {
+            "need to be recompiled"
+        } else {
+            "recompiled"
+        }
+    );
+
+    Ok(())
+}
+
+pub async fn main(args: Args) -> Result<()> {
+    let mut conn = sqlx::PgConnection::connect(&args.database).await?;
+    let mut tx = conn.begin().await?;
+
+    match args.cmd {
+        AdminCmd::CreateOrg { name } => create_org(&mut tx, &name).await?,
+        AdminCmd::CreateUser {
+            org,
+            name,
+            email,
+            password,
+        } => create_user(&mut tx, &org, &name, &email, password.as_deref()).await?,
+        AdminCmd::DeactivateUser { user } => deactivate_user(&mut tx, &user).await?,
+        AdminCmd::AssignRole { user, org, role } => {
+            assign_role(&mut tx, &user, &org, &role).await?
+        }
+        AdminCmd::RotateApiKey { api_key_id } => rotate_api_key(&mut tx, &api_key_id).await?,
+        AdminCmd::Usage { org, days } => show_usage(&mut tx, org.as_ref(), days).await?,
+        AdminCmd::EncryptAccountFields { dry_run } => {
+            encrypt_account_fields(&mut tx, dry_run).await?
+        }
+        AdminCmd::RevalidateTasks {
+            org,
+            disable,
+            dry_run,
+        } => revalidate_tasks(&mut tx, org.as_ref(), disable, dry_run).await?,
+    };
+
+    tx.commit().await?;
+    Ok(())
+}
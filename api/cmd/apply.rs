@@ -0,0 +1,152 @@
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use structopt::StructOpt;
+
+use crate::{
+    error::{Error, Result},
+    routes::apply::{ApplyBundle, ApplyOperation, ApplyResult},
+};
+
+#[derive(Debug, StructOpt)]
+pub struct Args {
+    #[structopt(
+        short = "f",
+        long = "filename",
+        help = "A file, or a directory of .yaml, .yml, and .json files"
+    )]
+    path: PathBuf,
+    #[structopt(long, help = "Show the changes without making them")]
+    dry_run: bool,
+    #[structopt(
+        long,
+        help = "What to do with tasks that stop validating: flag or disable",
+        default_value = "disable"
+    )]
+    invalid_dependents: String,
+    #[structopt(
+        long,
+        help = "The Ergo server",
+        env = "ERGO_URL",
+        default_value = "http://localhost:6543"
+    )]
+    url: String,
+    #[structopt(
+        long,
+        help = "An admin API key",
+        env = "ERGO_API_KEY",
+        hide_env_values = true
+    )]
+    api_key: String,
+}
+
+fn is_config_file(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("yaml" | "yml" | "json")
+    )
+}
+
+/// Read the objects from a file. YAML files may contain multiple documents.
+fn read_file(path: &Path) -> Result<ApplyBundle> {
+    let contents = std::fs::read_to_string(path)?;
+    let parse_error =
+        |e: &dyn std::fmt::Display| Error::StringError(format!("{}: {}", path.display(), e));
+
+    if path.extension().and_then(|e| e.to_str()) == Some("json") {
+        return serde_json::from_str(&contents).map_err(|e| parse_error(&e));
+    }
+
+    let mut bundle = ApplyBundle::default();
+    for document in serde_yaml::Deserializer::from_str(&contents) {
+        let doc = ApplyBundle::deserialize(document).map_err(|e| parse_error(&e))?;
+        bundle.merge(doc);
+    }
+
+    Ok(bundle)
+}
+
+pub fn read_bundle(path: &Path) -> Result<ApplyBundle> {
+    if !path.is_dir() {
+        return read_file(path);
+    }
+
+    let mut files = std::fs::read_dir(path)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    files.retain(|f| f.is_file() && is_config_file(f));
+    files.sort();
+
+    let mut bundle = ApplyBundle::default();
+    for file in files {
+        bundle.merge(read_file(&file)?);
+    }
+
+    Ok(bundle)
+}
+
+fn print_result(result: &ApplyResult) {
+    let mut counts = [0; 3];
+    for change in &result.changes {
+        let (symbol, index) = match change.operation {
+            ApplyOperation::Create => ('+', 0),
+            ApplyOperation::Update => ('~', 1),
+            ApplyOperation::Unchanged => (' ', 2),
+        };
+        counts[index] += 1;
+
+        if change.operation == ApplyOperation::Unchanged {
+            continue;
+        }
+
+        println!("{} {} {}", symbol, change.kind, change.name);
+        for diff in &change.changes {
+            let show = |v: &Option<serde_json::Value>| {
+                v.as_ref()
+                    .map(|v| v.to_string())
+                    .unwrap_or_else(|| "(none)".to_string())
+            };
+            println!(
+                "    {}: {} -> {}",
+                diff.path,
+                show(&diff.before),
+                show(&diff.after)
+            );
+        }
+    }
+
+    let verbs = if result.dry_run {
+        ["to create", "to update", "unchanged"]
+    } else {
+        ["created", "updated", "unchanged"]
+    };
+    println!(
+        "{} {}, {} {}, {} {}",
+        counts[0], verbs[0], counts[1], verbs[1], counts[2], verbs[2]
+    );
+}
+
+pub async fn main(args: Args) -> Result<()> {
+    let bundle = read_bundle(&args.path)?;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/api/apply", args.url.trim_end_matches('/')))
+        .bearer_auth(&args.api_key)
+        .query(&[
+            ("dry_run", args.dry_run.to_string()),
+            ("invalid_dependents", args.invalid_dependents),
+        ])
+        .json(&bundle)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(Error::StringError(format!("{}: {}", status, body)));
+    }
+
+    let result: ApplyResult = response.json().await?;
+    print_result(&result);
+    Ok(())
+}
@@ -20,6 +20,7 @@ pub async fn main() -> Result<()> {
     )?;
 
     shutdown.consumer().wait_for_shutdown().await;
+    crate::tracing_config::shutdown();
 
     Ok(())
 }
@@ -1,6 +1,6 @@
 use std::{borrow::Cow, time::Duration};
 
-use ergo_queues::{Job, JobStatus, Queue};
+use ergo_queues::{metrics::QueueMetrics, Job, JobStatus, Queue};
 use structopt::StructOpt;
 
 use crate::error::Error;
@@ -18,12 +18,19 @@ enum QueueCmd {
     Add { id: String, data: String },
     #[structopt(about = "Show information about the queue")]
     Show,
+    #[structopt(about = "Show per-minute throughput and wait times")]
+    Metrics {
+        #[structopt(short, long, default_value = "15", help = "How many minutes to show")]
+        minutes: usize,
+    },
     #[structopt(about = "List scheduled jobs")]
     ListScheduled,
     #[structopt(about = "List jobs waiting to run")]
     ListPending,
     #[structopt(about = "List jobs currently processing")]
     ListProcessing,
+    #[structopt(about = "List the workers running the queue and their jobs")]
+    ListWorkers,
     #[structopt(name = "show-job", about = "Show information about a job")]
     ShowJob { id: String },
     #[structopt(
@@ -68,6 +75,26 @@ pub async fn main(args: Args) -> Result<(), Error> {
             let status = queue.status().await?;
             println!("{:?}", status);
         }
+        QueueCmd::Metrics { minutes } => {
+            let history = queue.metrics_history(minutes).await?;
+            let format_ms = |ms: Option<f64>| ms.map(|ms| format!("{:.0}", ms)).unwrap_or_default();
+            println!("minute\tenqueued\tdequeued\tp50_ms\tp95_ms\tp99_ms");
+            let print_row = |label: String, m: &QueueMetrics| {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    label,
+                    m.enqueued,
+                    m.dequeued,
+                    format_ms(m.p50_wait_ms),
+                    format_ms(m.p95_wait_ms),
+                    format_ms(m.p99_wait_ms)
+                );
+            };
+            for m in &history.minutes {
+                print_row(m.start.format("%H:%M").to_string(), m);
+            }
+            print_row("total".to_string(), &history.overall);
+        }
         QueueCmd::ListScheduled => {
             let tasks = queue.list_scheduled().await?;
             for (task_id, scheduled) in tasks {
@@ -86,6 +113,20 @@ pub async fn main(args: Args) -> Result<(), Error> {
                 println!("{}\t{}", task_id, expires);
             }
         }
+        QueueCmd::ListWorkers => {
+            let workers = queue.list_workers().await?;
+            for worker in workers {
+                println!(
+                    "{}\t{}\t{}\t{}{}\t{}",
+                    worker.id,
+                    worker.hostname,
+                    worker.pid,
+                    worker.last_heartbeat,
+                    if worker.is_dead() { " (dead)" } else { "" },
+                    worker.jobs.join(",")
+                );
+            }
+        }
         QueueCmd::Cancel { id } => {
             let old_status = queue.cancel_job(&id).await?;
             match old_status {
@@ -127,7 +127,7 @@ async fn cleanup(pool: RedisPool, queue_name: &str) -> Result<(), Error> {
         .arg("COUNT")
         .arg(100)
         .clone()
-        .iter_async(&mut *conn)
+        .iter_async(&mut conn)
         .await?;
 
     let mut del_cmd = redis::cmd("DEL");
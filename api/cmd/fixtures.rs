@@ -0,0 +1,86 @@
+use structopt::StructOpt;
+
+use crate::{
+    error::{Error, Result},
+    routes::{
+        apply::ApplyOperation,
+        fixtures::{FixtureResult, FIXTURES},
+    },
+};
+
+#[derive(Debug, StructOpt)]
+pub struct Args {
+    #[structopt(
+        long,
+        help = "The Ergo server",
+        env = "ERGO_URL",
+        default_value = "http://localhost:6543"
+    )]
+    url: String,
+    #[structopt(
+        long,
+        help = "An admin API key",
+        env = "ERGO_API_KEY",
+        hide_env_values = true
+    )]
+    api_key: String,
+    #[structopt(subcommand)]
+    cmd: FixtureCmd,
+}
+
+#[derive(Debug, StructOpt)]
+enum FixtureCmd {
+    #[structopt(about = "List the available fixture packs")]
+    List,
+    #[structopt(about = "Load a fixture pack into the API key's organization")]
+    Load { name: String },
+}
+
+async fn load(args: &Args, name: &str) -> Result<()> {
+    let response = reqwest::Client::new()
+        .post(format!(
+            "{}/api/fixtures/{}",
+            args.url.trim_end_matches('/'),
+            name
+        ))
+        .bearer_auth(&args.api_key)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(Error::StringError(format!("{}: {}", status, body)));
+    }
+
+    let result: FixtureResult = response.json().await?;
+    for change in &result.changes {
+        let verb = match change.operation {
+            ApplyOperation::Create => "Created",
+            ApplyOperation::Update => "Updated",
+            ApplyOperation::Unchanged => "Unchanged",
+        };
+        println!(
+            "{} {} {} ({})",
+            verb,
+            change.kind,
+            change.name,
+            change.id.as_deref().unwrap_or_default()
+        );
+    }
+    println!("Added {} sample log entries", result.logs);
+
+    Ok(())
+}
+
+pub async fn main(args: Args) -> Result<()> {
+    match &args.cmd {
+        FixtureCmd::List => {
+            for (name, _) in FIXTURES {
+                println!("{}", name);
+            }
+            Ok(())
+        }
+        FixtureCmd::Load { name } => load(&args, name).await,
+    }
+}
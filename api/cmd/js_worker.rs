@@ -0,0 +1,12 @@
+use crate::error::Result;
+
+pub async fn main() -> Result<()> {
+    // Stdout carries the results back to the server, so logs have to go somewhere else.
+    crate::tracing_config::configure("js-worker", std::io::stderr);
+
+    let result = ergo_tasks::scripting::process::run_worker().await;
+    crate::tracing_config::shutdown();
+
+    result?;
+    Ok(())
+}
@@ -107,5 +107,8 @@ pub fn main() -> crate::error::Result<()> {
     let schema = schema_for!(AccountPublicInfo);
     write(&dir, "account_public_info", &schema)?;
 
+    let openapi = serde_json::to_string_pretty(&crate::openapi::openapi_document()).unwrap();
+    std::fs::write(dir.join("openapi.json"), openapi)?;
+
     Ok(())
 }
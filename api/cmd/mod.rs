@@ -1,8 +1,13 @@
+pub mod admin;
+pub mod apply;
 pub mod drain_queues;
 pub mod erq;
 pub mod erq_stress;
+pub mod fixtures;
 pub mod hash_passwd;
+pub mod js_worker;
 pub mod make_api_key;
 pub mod make_id;
 pub mod make_json_schema;
+pub mod repl;
 pub mod server;
@@ -0,0 +1,139 @@
+use std::io::Write;
+
+use ergo_database::object_id::TaskId;
+use ergo_tasks::scripting::repl::ReplOutput;
+use structopt::StructOpt;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::{
+    error::{Error, Result},
+    routes::tasks::ReplInput,
+};
+
+#[derive(Debug, StructOpt)]
+pub struct Args {
+    #[structopt(short, long, help = "The JS task to load")]
+    task: TaskId,
+    #[structopt(
+        long,
+        help = "Load the last payload from this trigger instead of from the task's latest input"
+    )]
+    trigger: Option<String>,
+    #[structopt(
+        long,
+        help = "The Ergo server",
+        env = "ERGO_URL",
+        default_value = "http://localhost:6543"
+    )]
+    url: String,
+    #[structopt(
+        long,
+        help = "An API key",
+        env = "ERGO_API_KEY",
+        hide_env_values = true
+    )]
+    api_key: String,
+}
+
+const HELP: &str = r##"Enter JavaScript to evaluate it with the task's context and last payload.
+End a line with \ to continue on the next line.
+
+.history  Show the code that has run in this session
+.reset    Start a new session
+.exit     Quit"##;
+
+fn prompt(continuing: bool) -> std::io::Result<()> {
+    let mut stdout = std::io::stdout();
+    write!(stdout, "{}", if continuing { "... " } else { "> " })?;
+    stdout.flush()
+}
+
+fn print_output(output: &ReplOutput) {
+    for message in &output.console {
+        println!("{}", message.message.trim_end());
+    }
+
+    match (&output.error, &output.result) {
+        (Some(error), _) => println!("Error: {}", error),
+        (None, Some(result)) => println!(
+            "{}",
+            serde_json::to_string_pretty(result).unwrap_or_default()
+        ),
+        (None, None) => println!("undefined"),
+    }
+}
+
+pub async fn main(args: Args) -> Result<()> {
+    let client = reqwest::Client::new();
+    let url = format!(
+        "{}/api/tasks/{}/repl",
+        args.url.trim_end_matches('/'),
+        args.task
+    );
+
+    println!("{}\n", HELP);
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut history: Vec<String> = Vec::new();
+    let mut code = String::new();
+
+    prompt(false)?;
+    while let Some(line) = lines.next_line().await? {
+        if let Some(partial) = line.strip_suffix('\\') {
+            code.push_str(partial);
+            code.push('\n');
+            prompt(true)?;
+            continue;
+        }
+
+        code.push_str(&line);
+        let entry = std::mem::take(&mut code);
+        match entry.trim() {
+            "" => {}
+            ".exit" => break,
+            ".help" => println!("{}", HELP),
+            ".history" => {
+                for entry in &history {
+                    println!("{}", entry);
+                }
+            }
+            ".reset" => {
+                history.clear();
+                println!("Started a new session");
+            }
+            _ => {
+                let input = ReplInput {
+                    history: history.clone(),
+                    code: entry.clone(),
+                    trigger: args.trigger.clone(),
+                    payload: None,
+                };
+
+                let response = client
+                    .post(&url)
+                    .bearer_auth(&args.api_key)
+                    .json(&input)
+                    .send()
+                    .await?;
+
+                if !response.status().is_success() {
+                    let status = response.status();
+                    let body = response.text().await.unwrap_or_default();
+                    return Err(Error::StringError(format!("{}: {}", status, body)));
+                }
+
+                let output: ReplOutput = response.json().await?;
+                print_output(&output);
+
+                // Only keep code that worked, so that replaying the history doesn't fail.
+                if output.error.is_none() {
+                    history.push(entry);
+                }
+            }
+        }
+
+        prompt(false)?;
+    }
+
+    Ok(())
+}
@@ -1,4 +1,6 @@
-use ergo_database::database_configuration_from_env;
+use std::time::Duration;
+
+use ergo_database::{database_configuration_from_env, migrations::run_migrations};
 use ergo_graceful_shutdown::GracefulShutdown;
 use structopt::StructOpt;
 use tracing::{event, Level};
@@ -7,10 +9,41 @@ use tracing::{event, Level};
 pub struct Args {
     #[structopt(long, help = "Do not run the PostgreSQL queue stage drain tasks")]
     no_drain_queues: bool,
+    #[structopt(
+        long,
+        help = "Apply pending database migrations before starting. This can also be enabled by setting MIGRATE_ON_STARTUP"
+    )]
+    migrate: bool,
+    #[structopt(long, help = "Apply pending database migrations and exit")]
+    migrate_only: bool,
+    #[structopt(
+        long,
+        help = "Connection string for a role that can change the schema, used for migrations",
+        env = "DATABASE_URL",
+        hide_env_values = true
+    )]
+    database_url: Option<String>,
 }
 
 pub async fn main(args: Args) -> Result<(), crate::error::Error> {
-    let shutdown = GracefulShutdown::new();
+    let shutdown_deadline = envoption::with_default("SHUTDOWN_DEADLINE_SECS", 30u64)?;
+    let log_filter = crate::tracing_config::configure("ergo", std::io::stdout);
+
+    let migrate_on_startup = envoption::with_default("MIGRATE_ON_STARTUP", false)?;
+    if args.migrate || args.migrate_only || migrate_on_startup {
+        let database_url = args.database_url.as_deref().ok_or_else(|| {
+            crate::error::Error::ConfigError("DATABASE_URL is required to migrate".to_string())
+        })?;
+        run_migrations(database_url).await?;
+
+        if args.migrate_only {
+            crate::tracing_config::shutdown();
+            return Ok(());
+        }
+    }
+
+    let shutdown = GracefulShutdown::with_deadline(Duration::from_secs(shutdown_deadline));
+
     let config = crate::server::Config {
         bind_address: Some(envoption::with_default("BIND_ADDRESS", "127.0.0.1")?),
         bind_port: envoption::with_default("BIND_PORT", 6543_u16)?,
@@ -19,10 +52,9 @@ pub async fn main(args: Args) -> Result<(), crate::error::Error> {
         redis_queue_prefix: None,
         no_drain_queues: args.no_drain_queues,
         shutdown: shutdown.consumer(),
+        log_filter: Some(log_filter),
     };
 
-    crate::tracing_config::configure("ergo", std::io::stdout);
-
     let server = crate::server::start(config).await?;
     event!(
         Level::INFO,
@@ -31,8 +63,11 @@ pub async fn main(args: Args) -> Result<(), crate::error::Error> {
         server.bind_port
     );
 
+    // The server stops once the shutdown begins, and then the rest of the shutdown waits for the
+    // queues to finish their running jobs before closing the database pools.
     server.server.await?;
 
     shutdown.shutdown().await?;
+    crate::tracing_config::shutdown();
     Ok(())
 }
@@ -1,8 +1,11 @@
 use std::str::ParseBoolError;
 
-use actix_web::{http::StatusCode, HttpResponse};
+use actix_web::{http::StatusCode, HttpRequest, HttpResponse};
 use envoption::EnvOptionError;
-use ergo_tasks::{actions::template::TemplateError, state_machine::StateMachineError};
+use ergo_problem::{format_field_errors, FieldError, ProblemDetails};
+use ergo_tasks::{
+    actions::template::TemplateError, schema_field_error, state_machine::StateMachineError,
+};
 use smallvec::{smallvec, SmallVec};
 use thiserror::Error;
 
@@ -42,8 +45,8 @@ pub enum Error {
     #[error(transparent)]
     UuidError(#[from] uuid::Error),
 
-    #[error("{0:?}")]
-    JsonSchemaValidationError(SmallVec<[String; 2]>),
+    #[error("{}", format_field_errors(.0))]
+    JsonSchemaValidationError(SmallVec<[FieldError; 2]>),
 
     #[error("State Machine Error: {0}")]
     StateMachineError(#[from] StateMachineError),
@@ -92,6 +95,29 @@ pub enum Error {
 
     #[error(transparent)]
     NotificationError(#[from] ergo_notifications::Error),
+
+    #[error(transparent)]
+    LocalizationError(#[from] ergo_localization::Error),
+
+    /// Validation failures, already rendered in the requester's locale.
+    #[error("{}", .0.join("\n"))]
+    ValidationError(Vec<String>),
+
+    /// Validation failures that can be tied to specific fields of the request.
+    #[error("{}", format_field_errors(.0))]
+    FieldValidationError(Vec<FieldError>),
+
+    /// A request body, query string, or path that could not be parsed.
+    #[error("{0}")]
+    InvalidRequest(String),
+
+    /// The request conflicts with a change made since the client last read the object.
+    #[error("{0}")]
+    Conflict(String),
+
+    /// The request would change data while the server is in maintenance mode.
+    #[error("{0}")]
+    MaintenanceMode(String),
 }
 
 impl<T: std::error::Error> From<EnvOptionError<T>> for Error {
@@ -102,14 +128,14 @@ impl<T: std::error::Error> From<EnvOptionError<T>> for Error {
 
 impl<'a> From<jsonschema::ErrorIterator<'a>> for Error {
     fn from(e: jsonschema::ErrorIterator<'a>) -> Error {
-        let inner = e.map(|e| e.to_string()).collect::<SmallVec<_>>();
+        let inner = e.map(|e| schema_field_error(&e)).collect::<SmallVec<_>>();
         Error::JsonSchemaValidationError(inner)
     }
 }
 
 impl<'a> From<jsonschema::ValidationError<'a>> for Error {
     fn from(e: jsonschema::ValidationError<'a>) -> Error {
-        Error::JsonSchemaValidationError(smallvec![e.to_string()])
+        Error::JsonSchemaValidationError(smallvec![schema_field_error(&e)])
     }
 }
 
@@ -132,22 +158,122 @@ impl ergo_database::transaction::TryIntoSqlxError for Error {
     }
 }
 
+impl ProblemDetails for Error {
+    fn code(&self) -> &'static str {
+        match self {
+            Error::AuthError(e) => e.code(),
+            Error::TasksError(e) => e.code(),
+            Error::ConfigError(_) | Error::EnvOptionError(_) => "config_error",
+            Error::AuthenticationError => "unauthenticated",
+            Error::AuthorizationError => "forbidden",
+            Error::NotFound => "not_found",
+            Error::Conflict(_) => "conflict",
+            Error::MaintenanceMode(_) => "maintenance_mode",
+            Error::ActixError { status_code, .. } => match *status_code {
+                StatusCode::NOT_FOUND => "not_found",
+                StatusCode::PAYLOAD_TOO_LARGE => "payload_too_large",
+                s if s.is_client_error() => "invalid_request",
+                _ => "internal_error",
+            },
+            Error::RedisError(_) | Error::RedisPoolError(_) => "redis_error",
+            Error::SerdeJsonError(_) => "serialization_error",
+            Error::UuidError(_) => "invalid_id",
+            Error::JsonSchemaValidationError(_)
+            | Error::ValidationError(_)
+            | Error::FieldValidationError(_) => "validation_failed",
+            Error::StateMachineError(_) => "state_machine_error",
+            Error::ParseBool(_) | Error::ParseIntError(_) | Error::InvalidRequest(_) => {
+                "invalid_request"
+            }
+            Error::UnknownExecutor(_) => "unknown_executor",
+            Error::TemplateError(_) => "template_error",
+            Error::SqlError(_) | Error::DatabaseError(_) => "database_error",
+            Error::ReqwestError(_) => "upstream_request_failed",
+            Error::ScriptError(_) => "script_error",
+            Error::QueueError(_) => "queue_error",
+            Error::NotificationError(_) => "notification_error",
+            Error::LocalizationError(_) => "localization_error",
+            Error::IoError(_) | Error::JoinError(_) | Error::StringError(_) => "internal_error",
+        }
+    }
+
+    fn status(&self) -> u16 {
+        match self {
+            Error::AuthError(e) => e.status(),
+            Error::TasksError(e) => e.status(),
+            Error::AuthenticationError => 401,
+            Error::AuthorizationError => 403,
+            Error::NotFound => 404,
+            Error::Conflict(_) => 409,
+            Error::MaintenanceMode(_) => 503,
+            Error::UnknownExecutor(_)
+            | Error::ValidationError(_)
+            | Error::FieldValidationError(_)
+            | Error::JsonSchemaValidationError(_)
+            | Error::InvalidRequest(_)
+            | Error::UuidError(_)
+            | Error::ParseBool(_)
+            | Error::ParseIntError(_)
+            | Error::TemplateError(_) => 400,
+            Error::ActixError { status_code, .. } => status_code.as_u16(),
+            _ => 500,
+        }
+    }
+
+    fn field_errors(&self) -> Vec<FieldError> {
+        match self {
+            Error::TasksError(e) => e.field_errors(),
+            Error::JsonSchemaValidationError(errors) => errors.to_vec(),
+            Error::FieldValidationError(errors) => errors.clone(),
+            Error::ValidationError(messages) => messages
+                .iter()
+                .map(|m| FieldError::without_field(m.as_str()))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
 impl actix_web::error::ResponseError for Error {
     fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
-        HttpResponse::build(self.status_code()).body(self.to_string())
+        match self {
+            // Unlike other server errors, the maintenance message is written for clients.
+            Error::MaintenanceMode(message) => self.problem().with_detail(message).to_response(),
+            _ => self.problem().to_response(),
+        }
     }
 
     fn status_code(&self) -> StatusCode {
-        match self {
-            Error::AuthenticationError => StatusCode::UNAUTHORIZED,
-            Error::AuthorizationError => StatusCode::FORBIDDEN,
-            Error::AuthError(ergo_auth::Error::AuthenticationError) => StatusCode::UNAUTHORIZED,
-            Error::AuthError(ergo_auth::Error::AuthorizationError) => StatusCode::FORBIDDEN,
-            Error::NotFound => StatusCode::NOT_FOUND,
-            Error::UnknownExecutor(_) => StatusCode::BAD_REQUEST,
-            Error::ActixError { status_code, .. } => *status_code,
-            Error::TasksError(ergo_tasks::Error::NotFound) => StatusCode::NOT_FOUND,
-            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        StatusCode::from_u16(self.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+/// Report request bodies that fail to deserialize as problem details instead of Actix's plain
+/// text errors.
+pub fn json_error_handler(
+    err: actix_web::error::JsonPayloadError,
+    _req: &HttpRequest,
+) -> actix_web::Error {
+    use actix_web::error::JsonPayloadError;
+    match err {
+        JsonPayloadError::OverflowKnownLength { .. } | JsonPayloadError::Overflow { .. } => {
+            Error::ActixError {
+                status_code: StatusCode::PAYLOAD_TOO_LARGE,
+                body: err.to_string(),
+            }
+            .into()
+        }
+        JsonPayloadError::Deserialize(e) => {
+            Error::FieldValidationError(vec![FieldError::without_field(e.to_string())]).into()
         }
+        _ => Error::InvalidRequest(err.to_string()).into(),
     }
 }
+
+/// Report query strings that fail to deserialize as problem details.
+pub fn query_error_handler(
+    err: actix_web::error::QueryPayloadError,
+    _req: &HttpRequest,
+) -> actix_web::Error {
+    Error::InvalidRequest(err.to_string()).into()
+}
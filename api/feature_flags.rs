@@ -0,0 +1,48 @@
+//! An extractor for the feature flags that are enabled for the request's org.
+
+use std::rc::Rc;
+
+use actix_web::{FromRequest, HttpMessage, HttpRequest};
+use ergo_auth::AuthenticationInfo;
+use ergo_tasks::feature_flags::FeatureFlagSet;
+use futures::future::LocalBoxFuture;
+
+use crate::{error::Error, web_app_server::AppStateData};
+
+/// The feature flags for the authenticated user's org. Returns an Error::AuthenticationError
+/// if the user is not authenticated.
+pub struct Flags(FeatureFlagSet);
+
+impl Flags {
+    pub fn into_inner(self) -> FeatureFlagSet {
+        self.0
+    }
+}
+
+impl FromRequest for Flags {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let org_id = req
+            .extensions()
+            .get::<Rc<AuthenticationInfo>>()
+            .map(|auth| *auth.org_id());
+        let data = req.app_data::<AppStateData>().cloned();
+
+        Box::pin(async move {
+            let org_id = org_id.ok_or(Error::AuthenticationError)?;
+            let data = data.ok_or_else(|| Error::ConfigError("Missing app state".to_string()))?;
+            let flags = data.feature_flags.for_org(&org_id).await?;
+            Ok(Flags(flags))
+        })
+    }
+}
+
+impl std::ops::Deref for Flags {
+    type Target = FeatureFlagSet;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
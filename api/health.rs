@@ -0,0 +1,163 @@
+//! Health probes for Kubernetes. The liveness probe only fails when the process is stuck in a way
+//! that a restart would fix. The readiness probe also checks the services that the server
+//! depends on, so that traffic is routed away from an instance that can't reach them, without
+//! restarting it.
+
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use ergo_database::{redis::CircuitState, PostgresPool, RedisPool};
+use ergo_queues::Queue;
+use ergo_tasks::scripting::POOL;
+use serde::Serialize;
+
+/// Each probe fails if it doesn't finish within this long.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A dequeuer that hasn't checked for work in this long, while not busy with jobs, is considered
+/// stuck. The loop normally polls at least once a second.
+const DEQUEUER_MAX_IDLE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Serialize)]
+pub struct ProbeResult {
+    pub name: &'static str,
+    pub ok: bool,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    pub ok: bool,
+    pub probes: Vec<ProbeResult>,
+}
+
+impl HealthReport {
+    fn new(probes: Vec<ProbeResult>) -> HealthReport {
+        HealthReport {
+            ok: probes.iter().all(|p| p.ok),
+            probes,
+        }
+    }
+}
+
+pub struct HealthChecks {
+    pg_pool: PostgresPool,
+    redis_pool: RedisPool,
+    /// The queues whose dequeuer loops run in this process.
+    dequeuers: Vec<(&'static str, Queue)>,
+}
+
+impl HealthChecks {
+    pub fn new(pg_pool: PostgresPool, redis_pool: RedisPool) -> HealthChecks {
+        HealthChecks {
+            pg_pool,
+            redis_pool,
+            dequeuers: Vec::new(),
+        }
+    }
+
+    /// Check the dequeuer loop for this queue in the readiness probe.
+    pub fn with_dequeuer(mut self, name: &'static str, queue: &Queue) -> Self {
+        self.dequeuers.push((name, queue.clone()));
+        self
+    }
+
+    pub fn liveness(&self) -> HealthReport {
+        // The pool probe runs in the background, so this only reads its latest result.
+        let js_pool = if POOL.healthy() {
+            probe_ok("js_pool", Duration::ZERO)
+        } else {
+            probe_failed(
+                "js_pool",
+                Duration::ZERO,
+                "JS runtime pool is not responding".to_string(),
+            )
+        };
+
+        HealthReport::new(vec![js_pool])
+    }
+
+    pub async fn readiness(&self) -> HealthReport {
+        let (postgres, redis) = futures::join!(
+            run_probe("postgres", async {
+                sqlx::query("SELECT 1").execute(&self.pg_pool).await?;
+                Ok(())
+            }),
+            run_probe("redis", async {
+                let mut conn = self.redis_pool.get().await?;
+                redis::cmd("PING").query_async::<_, ()>(&mut conn).await?;
+                Ok(())
+            })
+        );
+
+        let mut probes = vec![postgres, redis, redis_circuit_probe(&self.redis_pool)];
+        probes.extend(
+            self.dequeuers
+                .iter()
+                .map(|(name, queue)| dequeuer_probe(*name, queue)),
+        );
+
+        HealthReport::new(probes)
+    }
+}
+
+/// The Redis pool stops handing out connections for a while after repeated failures. This shows
+/// that state directly, since the PING probe would only report the resulting error.
+fn redis_circuit_probe(pool: &RedisPool) -> ProbeResult {
+    match pool.circuit_state() {
+        CircuitState::Open { retry_in } => probe_failed(
+            "redis_circuit",
+            Duration::ZERO,
+            format!("Circuit is open, retrying in {}ms", retry_in.as_millis()),
+        ),
+        CircuitState::Closed | CircuitState::HalfOpen => probe_ok("redis_circuit", Duration::ZERO),
+    }
+}
+
+fn dequeuer_probe(name: &'static str, queue: &Queue) -> ProbeResult {
+    let error = match queue.dequeuer_status() {
+        Some(status) if status.is_live(DEQUEUER_MAX_IDLE) => return probe_ok(name, Duration::ZERO),
+        Some(status) if !status.running => "Dequeuer stopped".to_string(),
+        Some(status) => match status.last_poll {
+            Some(t) => format!("Dequeuer has not polled since {}", t.to_rfc3339()),
+            None => "Dequeuer has not polled".to_string(),
+        },
+        None => "Dequeuer not started".to_string(),
+    };
+
+    probe_failed(name, Duration::ZERO, error)
+}
+
+async fn run_probe(
+    name: &'static str,
+    probe: impl Future<Output = Result<(), anyhow::Error>>,
+) -> ProbeResult {
+    let start = Instant::now();
+    match tokio::time::timeout(PROBE_TIMEOUT, probe).await {
+        Ok(Ok(())) => probe_ok(name, start.elapsed()),
+        Ok(Err(e)) => probe_failed(name, start.elapsed(), e.to_string()),
+        Err(_) => probe_failed(name, start.elapsed(), "Timed out".to_string()),
+    }
+}
+
+fn probe_ok(name: &'static str, latency: Duration) -> ProbeResult {
+    ProbeResult {
+        name,
+        ok: true,
+        latency_ms: latency.as_millis() as u64,
+        error: None,
+    }
+}
+
+fn probe_failed(name: &'static str, latency: Duration, error: String) -> ProbeResult {
+    ProbeResult {
+        name,
+        ok: false,
+        latency_ms: latency.as_millis() as u64,
+        error: Some(error),
+    }
+}
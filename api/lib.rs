@@ -1,10 +1,18 @@
 #![allow(dead_code)] // Remove this once the basic application is up and working
+pub mod account_email;
 pub mod auth;
 pub mod backend_data;
 pub mod cmd;
 pub mod error;
+pub mod feature_flags;
+pub mod health;
+pub mod maintenance_mode;
+pub mod openapi;
+pub mod pagination;
 pub mod routes;
+pub mod runtime_config;
 pub mod server;
 pub mod service_config;
 pub mod tracing_config;
+pub mod transaction;
 pub mod web_app_server;
@@ -7,6 +7,14 @@ enum Args {
     Server(cmd::server::Args),
     #[structopt(about = "Run a task that only drains the Postgres queues")]
     DrainQueues,
+    #[structopt(about = "Manage organizations and users")]
+    Admin(cmd::admin::Args),
+    #[structopt(about = "Create or update inputs, actions, and tasks from config files")]
+    Apply(cmd::apply::Args),
+    #[structopt(about = "Load sample data for demos and testing")]
+    Fixtures(cmd::fixtures::Args),
+    #[structopt(about = "Run scripts sent on stdin. The server starts these itself")]
+    JsWorker,
     #[structopt(about = "Development commands")]
     Dev(DevCmds),
 }
@@ -23,6 +31,8 @@ enum DevCmds {
     Queue(cmd::erq::Args),
     #[structopt(about = "Create an object ID")]
     Id(cmd::make_id::Args),
+    #[structopt(about = "Evaluate code interactively with a JS task's state and last payload")]
+    Repl(cmd::repl::Args),
 }
 
 fn main() -> Result<(), error::Error> {
@@ -43,12 +53,17 @@ async fn run() -> Result<(), error::Error> {
     match args {
         Args::Server(s) => cmd::server::main(s).await,
         Args::DrainQueues => cmd::drain_queues::main().await,
+        Args::Admin(args) => cmd::admin::main(args).await,
+        Args::Apply(args) => cmd::apply::main(args).await,
+        Args::Fixtures(args) => cmd::fixtures::main(args).await,
+        Args::JsWorker => cmd::js_worker::main().await,
         Args::Dev(cmd) => match cmd {
             DevCmds::HashPassword(args) => cmd::hash_passwd::main(args),
             DevCmds::MakeApiKey(args) => cmd::make_api_key::main(args).await,
             DevCmds::Id(args) => cmd::make_id::main(args).await,
             DevCmds::MakeJsonSchema => cmd::make_json_schema::main(),
             DevCmds::Queue(args) => cmd::erq::main(args).await,
+            DevCmds::Repl(args) => cmd::repl::main(args).await,
         },
     }?;
 
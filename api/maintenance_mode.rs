@@ -0,0 +1,239 @@
+//! Maintenance mode, for database maintenance windows and other times when the servers should
+//! stop changing anything without going down. While it is on:
+//!
+//! - Requests that could change data fail with a 503 response, except for the request that turns
+//!   maintenance mode off. Reads and the health checks keep working.
+//! - The input and action dequeuers stop taking jobs. Jobs that are already running finish
+//!   normally, and the rest wait in the queues until maintenance mode is turned off.
+//!
+//! The setting lives in Redis so that it applies to every server. The server that changes it
+//! applies the change right away, and the others pick it up the next time they poll.
+
+use std::{
+    rc::Rc,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::Method,
+};
+use chrono::{DateTime, Utc};
+use ergo_database::RedisPool;
+use ergo_graceful_shutdown::GracefulShutdownConsumer;
+use ergo_queues::Queue;
+use futures::{
+    future::{ready, LocalBoxFuture, Ready},
+    FutureExt,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tracing::{event, Level};
+
+use crate::error::{Error, Result};
+
+/// The endpoint that changes maintenance mode, which has to keep working while it is on.
+pub const MAINTENANCE_PATH: &str = "/api/status/maintenance";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct MaintenanceStatus {
+    pub enabled: bool,
+    /// A message for API clients, returned with each rejected request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// When maintenance mode was turned on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<DateTime<Utc>>,
+}
+
+struct MaintenanceModeInner {
+    redis_pool: RedisPool,
+    key: String,
+    status: RwLock<MaintenanceStatus>,
+    /// The queues whose dequeuers pause during maintenance.
+    queues: Vec<Queue>,
+}
+
+#[derive(Clone)]
+pub struct MaintenanceMode(Arc<MaintenanceModeInner>);
+
+impl MaintenanceMode {
+    pub fn new(redis_pool: RedisPool, queues: Vec<Queue>) -> MaintenanceMode {
+        let key = match redis_pool.key_prefix() {
+            Some(prefix) => format!("{}-maintenance", prefix),
+            None => "er-maintenance".to_string(),
+        };
+
+        MaintenanceMode(Arc::new(MaintenanceModeInner {
+            redis_pool,
+            key,
+            status: RwLock::new(MaintenanceStatus::default()),
+            queues,
+        }))
+    }
+
+    pub fn status(&self) -> MaintenanceStatus {
+        self.0.status.read().unwrap().clone()
+    }
+
+    /// Turn maintenance mode on or off for every server.
+    pub async fn set(&self, enabled: bool, message: Option<String>) -> Result<MaintenanceStatus> {
+        let mut conn = self.0.redis_pool.get().await?;
+        let status = if enabled {
+            let current = self.status();
+            MaintenanceStatus {
+                enabled: true,
+                message,
+                // Changing the message of an existing maintenance window keeps its start time.
+                since: current
+                    .since
+                    .filter(|_| current.enabled)
+                    .or_else(|| Some(Utc::now())),
+            }
+        } else {
+            MaintenanceStatus::default()
+        };
+
+        if status.enabled {
+            redis::cmd("SET")
+                .arg(&self.0.key)
+                .arg(serde_json::to_string(&status)?)
+                .query_async::<_, ()>(&mut conn)
+                .await?;
+        } else {
+            redis::cmd("DEL")
+                .arg(&self.0.key)
+                .query_async::<_, ()>(&mut conn)
+                .await?;
+        }
+
+        self.apply(status.clone());
+        Ok(status)
+    }
+
+    /// Read the setting from Redis and apply it if it changed.
+    pub async fn refresh(&self) -> Result<()> {
+        let mut conn = self.0.redis_pool.get().await?;
+        let value: Option<String> = redis::cmd("GET")
+            .arg(&self.0.key)
+            .query_async(&mut conn)
+            .await?;
+        let status = match value {
+            Some(value) => serde_json::from_str(&value)?,
+            None => MaintenanceStatus::default(),
+        };
+
+        self.apply(status);
+        Ok(())
+    }
+
+    fn apply(&self, status: MaintenanceStatus) {
+        let mut current = self.0.status.write().unwrap();
+        if current.enabled != status.enabled {
+            if status.enabled {
+                event!(Level::WARN, message=?status.message, "Maintenance mode is on");
+            } else {
+                event!(Level::WARN, "Maintenance mode is off");
+            }
+
+            for queue in &self.0.queues {
+                queue.set_paused(status.enabled);
+            }
+        }
+
+        *current = status;
+    }
+
+    fn rejects(&self, req: &ServiceRequest) -> Option<Error> {
+        let read_only = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+        if read_only || req.path() == MAINTENANCE_PATH {
+            return None;
+        }
+
+        let status = self.0.status.read().unwrap();
+        status.enabled.then(|| {
+            Error::MaintenanceMode(
+                status
+                    .message
+                    .clone()
+                    .unwrap_or_else(|| "The server is down for maintenance".to_string()),
+            )
+        })
+    }
+}
+
+/// Poll Redis for changes to maintenance mode, after the server has read it once at startup. If
+/// Redis can't be reached, the server stays in its current mode until the next successful read.
+pub fn start_maintenance_mode_watcher(
+    mut shutdown: GracefulShutdownConsumer,
+    mode: MaintenanceMode,
+    interval: Option<Duration>,
+) -> JoinHandle<()> {
+    let interval = interval.unwrap_or_else(|| Duration::from_secs(5));
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {},
+                _ = shutdown.wait_for_shutdown() => break,
+            }
+
+            if let Err(e) = mode.refresh().await {
+                event!(Level::ERROR, error=%e, "Failed to read maintenance mode");
+            }
+        }
+    })
+}
+
+/// Rejects requests that could change data while maintenance mode is on.
+pub struct MaintenanceMiddlewareFactory(MaintenanceMode);
+
+impl MaintenanceMiddlewareFactory {
+    pub fn new(mode: MaintenanceMode) -> Self {
+        MaintenanceMiddlewareFactory(mode)
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for MaintenanceMiddlewareFactory
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = MaintenanceMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(MaintenanceMiddleware {
+            service: Rc::new(service),
+            mode: self.0.clone(),
+        }))
+    }
+}
+
+pub struct MaintenanceMiddleware<S> {
+    service: Rc<S>,
+    mode: MaintenanceMode,
+}
+
+impl<S, B> Service<ServiceRequest> for MaintenanceMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if let Some(e) = self.mode.rejects(&req) {
+            return ready(Err(e.into())).boxed_local();
+        }
+
+        let srv = Rc::clone(&self.service);
+        async move { srv.call(req).await }.boxed_local()
+    }
+}
@@ -0,0 +1,546 @@
+//! An OpenAPI 3 description of the API. The schemas come from the same types that the route
+//! handlers take and return, so the document can't drift from the handlers' types. The routes
+//! themselves are listed in [api_routes], since actix doesn't expose its route table, and a
+//! route has to be added there to be documented.
+
+use ergo_auth::trigger_tokens::TriggerToken;
+use ergo_tasks::{
+    actions::Action,
+    inputs::{form::FormField, Input},
+    revalidate::RevalidateReport,
+    script_logs::ScriptLogEntry,
+    state_history::StateSnapshot,
+};
+use schemars::{
+    gen::{SchemaGenerator, SchemaSettings},
+    schema::{Schema, SchemaObject},
+    JsonSchema,
+};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    pagination::PaginationQuery,
+    routes::{
+        actions::{ActionPayload, ExecutorInfo},
+        inputs::InputPayload,
+        logs::{
+            ActionLineage, ActionsLogEntry, ActionsLogQuery, LineageAction, ReplayInput,
+            RetryAction, RetryActionResponse, RunTimeline, ScriptLogQuery, StateDiff,
+            StateDiffQuery, TimelineQuery,
+        },
+        orgs::{Invitation, InvitationInput, OrgMember, OrgMembership, SwitchOrgInput},
+        sessions::{LoginInput, SessionInfo},
+        tags::{ListQuery, TagCount},
+        task_plans::{ApplyTaskPlanInput, TaskPlan},
+        tasks::{
+            BufferedInputsQuery, BufferedInputsResult, DependentsQuery, InferredPayloadSchema,
+            InputsLogEntry, InputsLogQuery, NewTaskResult, PayloadSchemaQuery, RejectedInputs,
+            RejectedInputsQuery, ReplInput, RevalidateQuery, TaskDescription, TaskInput,
+            TaskResult, TaskTriggerResponse,
+        },
+        trigger_tokens::{NewTriggerToken, NewTriggerTokenInput},
+        users::{
+            ChangePasswordInput, PasswordResetConfirmInput, PasswordResetInput, SignupInput,
+            TokenInput,
+        },
+    },
+};
+
+type SchemaFn = fn(&mut SchemaGenerator) -> Schema;
+
+fn subschema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Schema {
+    gen.subschema_for::<T>()
+}
+
+/// The schema of `T` itself instead of a reference to it, so that its properties can be listed
+/// as query parameters.
+fn inline_schema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Schema {
+    T::json_schema(gen)
+}
+
+/// A documented route.
+pub struct ApiRoute {
+    pub method: &'static str,
+    /// The path within `/api`, with path parameters in braces as in the actix route.
+    pub path: &'static str,
+    pub tag: &'static str,
+    pub summary: &'static str,
+    query: Vec<SchemaFn>,
+    body: Option<SchemaFn>,
+    response: Option<SchemaFn>,
+    status: u16,
+}
+
+impl ApiRoute {
+    fn new(
+        method: &'static str,
+        path: &'static str,
+        tag: &'static str,
+        summary: &'static str,
+    ) -> Self {
+        ApiRoute {
+            method,
+            path,
+            tag,
+            summary,
+            query: Vec::new(),
+            body: None,
+            response: None,
+            status: 200,
+        }
+    }
+
+    fn get(path: &'static str, tag: &'static str, summary: &'static str) -> Self {
+        Self::new("get", path, tag, summary)
+    }
+
+    fn post(path: &'static str, tag: &'static str, summary: &'static str) -> Self {
+        Self::new("post", path, tag, summary)
+    }
+
+    fn put(path: &'static str, tag: &'static str, summary: &'static str) -> Self {
+        Self::new("put", path, tag, summary)
+    }
+
+    fn delete(path: &'static str, tag: &'static str, summary: &'static str) -> Self {
+        Self::new("delete", path, tag, summary)
+    }
+
+    /// Add the properties of `T` to the query parameters. This can be called more than once for
+    /// handlers that take several query extractors.
+    fn query<T: JsonSchema>(mut self) -> Self {
+        self.query.push(inline_schema::<T>);
+        self
+    }
+
+    fn body<T: JsonSchema>(mut self) -> Self {
+        self.body = Some(subschema::<T>);
+        self
+    }
+
+    fn response<T: JsonSchema>(mut self) -> Self {
+        self.response = Some(subschema::<T>);
+        self
+    }
+
+    fn status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    fn path_parameters(&self) -> Vec<Value> {
+        self.path
+            .split('/')
+            .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+            .map(|name| {
+                json!({
+                    "name": name,
+                    "in": "path",
+                    "required": true,
+                    "schema": { "type": "string" },
+                })
+            })
+            .collect()
+    }
+
+    fn query_parameters(&self, gen: &mut SchemaGenerator) -> Vec<Value> {
+        self.query
+            .iter()
+            .flat_map(|query| Self::query_schema_parameters(query(gen).into_object()))
+            .collect()
+    }
+
+    fn query_schema_parameters(schema: SchemaObject) -> Vec<Value> {
+        let object = match schema.object {
+            Some(object) => object,
+            None => return Vec::new(),
+        };
+
+        object
+            .properties
+            .iter()
+            .map(|(name, schema)| {
+                let description = schema
+                    .clone()
+                    .into_object()
+                    .metadata
+                    .and_then(|m| m.description);
+                let mut param = json!({
+                    "name": name,
+                    "in": "query",
+                    "required": object.required.contains(name),
+                    "schema": schema,
+                });
+                if let Some(description) = description {
+                    param["description"] = Value::String(description);
+                }
+                param
+            })
+            .collect()
+    }
+
+    fn operation(&self, gen: &mut SchemaGenerator) -> Value {
+        let mut parameters = self.path_parameters();
+        parameters.extend(self.query_parameters(gen));
+
+        let response = match self.response {
+            Some(response) => json!({
+                "description": "Success",
+                "content": { "application/json": { "schema": response(gen) } },
+            }),
+            None => json!({ "description": "Success" }),
+        };
+
+        let mut operation = json!({
+            "tags": [self.tag],
+            "summary": self.summary,
+            "operationId": operation_id(self.method, self.path),
+            "parameters": parameters,
+            "responses": {
+                self.status.to_string(): response,
+                "default": { "$ref": "#/components/responses/Error" },
+            },
+        });
+
+        if let Some(body) = self.body {
+            operation["requestBody"] = json!({
+                "required": true,
+                "content": { "application/json": { "schema": body(gen) } },
+            });
+        }
+
+        operation
+    }
+}
+
+/// An operation ID such as `get_tasks_task_id` for `GET /tasks/{task_id}`.
+fn operation_id(method: &str, path: &str) -> String {
+    let path = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(|s| s.trim_start_matches('{').trim_end_matches('}'))
+        .collect::<Vec<_>>()
+        .join("_");
+    format!("{}_{}", method, path)
+}
+
+/// The routes in the OpenAPI document.
+pub fn api_routes() -> Vec<ApiRoute> {
+    vec![
+        // Tasks
+        ApiRoute::get("/tasks", "tasks", "List tasks")
+            .query::<ListQuery>()
+            .response::<Vec<TaskDescription>>(),
+        ApiRoute::post("/tasks", "tasks", "Create a task")
+            .body::<TaskInput>()
+            .response::<NewTaskResult>()
+            .status(201),
+        ApiRoute::get("/tasks/{task_id}", "tasks", "Get a task").response::<TaskResult>(),
+        ApiRoute::put("/tasks/{task_id}", "tasks", "Update a task").body::<TaskInput>(),
+        ApiRoute::delete("/tasks/{task_id}", "tasks", "Delete a task"),
+        ApiRoute::post(
+            "/tasks/{task_id}/plan",
+            "tasks",
+            "Preview and validate a task update",
+        )
+        .body::<TaskInput>()
+        .response::<TaskPlan>(),
+        ApiRoute::post(
+            "/tasks/{task_id}/apply",
+            "tasks",
+            "Apply a planned task update",
+        )
+        .body::<ApplyTaskPlanInput>(),
+        ApiRoute::post(
+            "/tasks/{task_id}/trigger/{trigger_id}",
+            "tasks",
+            "Send a payload to a task trigger",
+        )
+        .body::<Value>()
+        .response::<TaskTriggerResponse>()
+        .status(202),
+        ApiRoute::get(
+            "/tasks/{task_id}/trigger/{trigger_id}/payload_schema",
+            "tasks",
+            "Infer a trigger's payload schema from its recent payloads",
+        )
+        .query::<PayloadSchemaQuery>()
+        .response::<InferredPayloadSchema>(),
+        ApiRoute::get(
+            "/tasks/{task_id}/trigger/{trigger_id}/rejected",
+            "tasks",
+            "List the inputs that a trigger rejected",
+        )
+        .query::<RejectedInputsQuery>()
+        .response::<RejectedInputs>(),
+        ApiRoute::get(
+            "/tasks/{task_id}/trigger/{trigger_id}/tokens",
+            "tasks",
+            "List the tokens that can send payloads to a trigger",
+        )
+        .response::<Vec<TriggerToken>>(),
+        ApiRoute::post(
+            "/tasks/{task_id}/trigger/{trigger_id}/tokens",
+            "tasks",
+            "Create a token that can only send payloads to a trigger",
+        )
+        .body::<NewTriggerTokenInput>()
+        .response::<NewTriggerToken>()
+        .status(201),
+        ApiRoute::post(
+            "/tasks/{task_id}/trigger/{trigger_id}/tokens/{token_id}/rotate",
+            "tasks",
+            "Replace a trigger token with a new value",
+        )
+        .response::<NewTriggerToken>(),
+        ApiRoute::delete(
+            "/tasks/{task_id}/trigger/{trigger_id}/tokens/{token_id}",
+            "tasks",
+            "Revoke a trigger token",
+        ),
+        ApiRoute::post(
+            "/tasks/{task_id}/repl",
+            "tasks",
+            "Evaluate code in a task's script environment",
+        )
+        .body::<ReplInput>()
+        .response::<Value>(),
+        ApiRoute::post(
+            "/tasks/{task_id}/buffered_inputs/flush",
+            "tasks",
+            "Run or discard the inputs buffered while a task was disabled",
+        )
+        .query::<BufferedInputsQuery>()
+        .response::<BufferedInputsResult>(),
+        ApiRoute::post(
+            "/tasks/revalidate",
+            "tasks",
+            "Revalidate and recompile the org's tasks",
+        )
+        .query::<RevalidateQuery>()
+        .response::<RevalidateReport>(),
+        ApiRoute::get("/tags", "tasks", "List the tags in use").response::<Vec<TagCount>>(),
+        // Inputs
+        ApiRoute::get("/inputs", "inputs", "List inputs").response::<Vec<Input>>(),
+        ApiRoute::post("/inputs", "inputs", "Create an input")
+            .body::<InputPayload>()
+            .response::<Input>()
+            .status(201),
+        ApiRoute::put("/inputs/{input_id}", "inputs", "Create or update an input")
+            .query::<DependentsQuery>()
+            .body::<InputPayload>()
+            .response::<Input>(),
+        ApiRoute::put(
+            "/inputs/{input_id}/payload_schema",
+            "inputs",
+            "Update an input's payload schema",
+        )
+        .query::<DependentsQuery>()
+        .body::<Value>()
+        .response::<Input>(),
+        ApiRoute::delete("/inputs/{input_id}", "inputs", "Delete an input"),
+        ApiRoute::get(
+            "/inputs/{input_id}/form",
+            "inputs",
+            "Get a form description for an input's payload",
+        )
+        .response::<FormField>(),
+        // Actions
+        ApiRoute::get("/actions", "actions", "List actions")
+            .query::<ListQuery>()
+            .response::<Vec<Action>>(),
+        ApiRoute::post("/actions", "actions", "Create an action")
+            .body::<ActionPayload>()
+            .response::<Action>()
+            .status(201),
+        ApiRoute::put(
+            "/actions/{action_id}",
+            "actions",
+            "Create or update an action",
+        )
+        .query::<DependentsQuery>()
+        .body::<ActionPayload>()
+        .response::<Action>(),
+        ApiRoute::delete("/actions/{action_id}", "actions", "Delete an action"),
+        ApiRoute::get("/executors", "actions", "List action executors")
+            .response::<Vec<ExecutorInfo<'static>>>(),
+        // Logs
+        ApiRoute::get("/logs", "logs", "List recent task runs")
+            .query::<InputsLogQuery>()
+            .query::<PaginationQuery>()
+            .response::<Vec<InputsLogEntry>>(),
+        ApiRoute::get("/actions_log", "logs", "List recent action runs")
+            .query::<ActionsLogQuery>()
+            .query::<PaginationQuery>()
+            .response::<Vec<ActionsLogEntry>>(),
+        ApiRoute::get(
+            "/inputs_log/{inputs_log_id}/timeline",
+            "logs",
+            "Get the timeline of a task run",
+        )
+        .query::<TimelineQuery>()
+        .response::<RunTimeline>(),
+        ApiRoute::get(
+            "/inputs_log/{inputs_log_id}/lineage",
+            "logs",
+            "List the actions caused by an input, across chained tasks",
+        )
+        .response::<Vec<LineageAction>>(),
+        ApiRoute::get(
+            "/actions_log/{actions_log_id}/lineage",
+            "logs",
+            "Trace an action back to the inputs that produced it",
+        )
+        .response::<ActionLineage>(),
+        ApiRoute::get(
+            "/actions_log/{actions_log_id}/progress",
+            "logs",
+            "Follow a running action's progress as server-sent events",
+        ),
+        ApiRoute::get(
+            "/inputs_log/{inputs_log_id}/state",
+            "logs",
+            "Get a task's state after a run",
+        )
+        .response::<StateSnapshot>(),
+        ApiRoute::get(
+            "/inputs_log/{inputs_log_id}/state/diff",
+            "logs",
+            "Compare a task's state after two runs",
+        )
+        .query::<StateDiffQuery>()
+        .response::<StateDiff>(),
+        ApiRoute::get(
+            "/inputs_log/{inputs_log_id}/script_logs",
+            "logs",
+            "List the log entries that a task script wrote during a run",
+        )
+        .query::<ScriptLogQuery>()
+        .response::<Vec<ScriptLogEntry>>(),
+        ApiRoute::get(
+            "/tasks/{task_id}/script_logs",
+            "logs",
+            "List the log entries that a task's script has written",
+        )
+        .query::<ScriptLogQuery>()
+        .response::<Vec<ScriptLogEntry>>(),
+        ApiRoute::post(
+            "/inputs_log/{inputs_log_id}/replay",
+            "logs",
+            "Run a task again with a previous input",
+        )
+        .body::<ReplayInput>()
+        .response::<TaskTriggerResponse>()
+        .status(202),
+        ApiRoute::post(
+            "/actions_log/{actions_log_id}/retry",
+            "logs",
+            "Run a failed action again",
+        )
+        .body::<RetryAction>()
+        .response::<RetryActionResponse>()
+        .status(202),
+        // Auth
+        ApiRoute::post("/login", "auth", "Log in")
+            .body::<LoginInput>()
+            .response::<SessionInfo>(),
+        ApiRoute::post("/logout", "auth", "Log out"),
+        ApiRoute::post("/signup", "auth", "Sign up")
+            .body::<SignupInput>()
+            .status(202),
+        ApiRoute::post("/signup/verify", "auth", "Verify an email address")
+            .body::<TokenInput>()
+            .response::<SessionInfo>(),
+        ApiRoute::post("/password_reset", "auth", "Request a password reset email")
+            .body::<PasswordResetInput>()
+            .status(202),
+        ApiRoute::post("/password_reset/confirm", "auth", "Set a new password")
+            .body::<PasswordResetConfirmInput>(),
+        ApiRoute::put("/user/password", "auth", "Change the password")
+            .body::<ChangePasswordInput>(),
+        ApiRoute::get("/sessions", "auth", "List the user's sessions")
+            .response::<Vec<SessionInfo>>(),
+        ApiRoute::delete("/sessions", "auth", "Revoke the user's other sessions"),
+        ApiRoute::delete("/sessions/{session_id}", "auth", "Revoke a session"),
+        // Orgs
+        ApiRoute::get("/orgs", "orgs", "List the user's orgs").response::<Vec<OrgMembership>>(),
+        ApiRoute::put("/orgs/active", "orgs", "Switch the active org").body::<SwitchOrgInput>(),
+        ApiRoute::get("/orgs/members", "orgs", "List the org's members")
+            .response::<Vec<OrgMember>>(),
+        ApiRoute::delete(
+            "/orgs/members/{user_id}",
+            "orgs",
+            "Remove a member from the org",
+        ),
+        ApiRoute::post("/orgs/invitations", "orgs", "Invite someone to the org")
+            .body::<InvitationInput>()
+            .response::<Invitation>()
+            .status(201),
+        ApiRoute::get(
+            "/orgs/invitations",
+            "orgs",
+            "List the org's pending invitations",
+        )
+        .response::<Vec<Invitation>>(),
+        ApiRoute::delete(
+            "/orgs/invitations/{invitation_id}",
+            "orgs",
+            "Revoke an invitation",
+        ),
+        ApiRoute::get("/invitations", "orgs", "List the user's invitations")
+            .response::<Vec<Invitation>>(),
+        ApiRoute::post(
+            "/invitations/{invitation_id}/accept",
+            "orgs",
+            "Accept an invitation",
+        ),
+        ApiRoute::post(
+            "/invitations/{invitation_id}/decline",
+            "orgs",
+            "Decline an invitation",
+        ),
+    ]
+}
+
+/// Build the OpenAPI document.
+pub fn openapi_document() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+
+    let mut paths = Map::new();
+    for route in api_routes() {
+        let operation = route.operation(&mut gen);
+        let path = paths
+            .entry(format!("/api{}", route.path))
+            .or_insert_with(|| json!({}));
+        path[route.method] = operation;
+    }
+
+    let schemas = gen
+        .take_definitions()
+        .into_iter()
+        .map(|(name, schema)| (name, json!(schema)))
+        .collect::<Map<_, _>>();
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Ergo API",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "apiKey": { "type": "http", "scheme": "bearer" },
+                "session": { "type": "apiKey", "in": "cookie", "name": "id" },
+            },
+            "responses": {
+                "Error": {
+                    "description": "An error, in the RFC 7807 problem details format",
+                    "content": { "application/problem+json": { "schema": { "type": "object" } } },
+                },
+            },
+        },
+        "security": [{ "apiKey": [] }, { "session": [] }],
+    })
+}
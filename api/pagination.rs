@@ -0,0 +1,234 @@
+//! Cursor-based pagination for the log endpoints. Offsets get slower as a client pages further
+//! back and skip or repeat rows when new entries arrive, so these endpoints page by keyset
+//! instead: each page ends with an opaque cursor holding the sort time and ID of its last row,
+//! and the next page starts after that row.
+//!
+//! A handler takes a [Pagination] extractor alongside its own filter query, and passes the rows
+//! it fetched to [Pagination::page], which returns the cursor for the next page in the
+//! [NEXT_CURSOR_HEADER] header. The header is omitted on the last page.
+
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse};
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::future::{ready, Ready};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::error::{Error, Result};
+
+pub const NEXT_CURSOR_HEADER: &str = "X-Next-Cursor";
+pub const DEFAULT_PAGE_SIZE: i64 = 50;
+pub const MAX_PAGE_SIZE: i64 = 500;
+
+/// The time column that log entries are sorted by.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LogSort {
+    /// When the entry last changed. Entries that are still running can move between pages.
+    #[default]
+    Updated,
+    /// When the entry was created.
+    Created,
+}
+
+impl LogSort {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Updated => "updated",
+            Self::Created => "created",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// The pagination parameters shared by the log endpoints.
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+pub struct PaginationQuery {
+    /// The most entries to return, up to 500. Defaults to 50.
+    pub limit: Option<i64>,
+    /// The `X-Next-Cursor` header from the previous page.
+    pub cursor: Option<String>,
+    /// Defaults to `updated`.
+    pub sort: Option<LogSort>,
+    /// Defaults to `desc`.
+    pub order: Option<SortOrder>,
+}
+
+/// The position of the last row on a page.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cursor {
+    pub time: DateTime<Utc>,
+    pub id: Uuid,
+}
+
+impl Cursor {
+    /// Encode the cursor along with the sort that produced it, since the cursor's time is
+    /// meaningless under a different sort.
+    fn encode(&self, sort: LogSort) -> String {
+        let raw = format!(
+            "{}|{}|{}",
+            sort.as_str(),
+            self.time.to_rfc3339_opts(SecondsFormat::Micros, true),
+            self.id
+        );
+        base64::encode_config(raw, base64::URL_SAFE_NO_PAD)
+    }
+
+    fn decode(encoded: &str, sort: LogSort) -> Result<Cursor> {
+        let invalid = || Error::ValidationError(vec!["Invalid cursor".to_string()]);
+
+        let raw = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+        let raw = String::from_utf8(raw).map_err(|_| invalid())?;
+        let mut parts = raw.splitn(3, '|');
+        let (cursor_sort, time, id) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(s), Some(t), Some(i)) => (s, t, i),
+            _ => return Err(invalid()),
+        };
+
+        if cursor_sort != sort.as_str() {
+            return Err(Error::ValidationError(vec![format!(
+                "Cursor was created for sort {}, not {}",
+                cursor_sort,
+                sort.as_str()
+            )]));
+        }
+
+        Ok(Cursor {
+            time: DateTime::parse_from_rfc3339(time)
+                .map_err(|_| invalid())?
+                .with_timezone(&Utc),
+            id: Uuid::parse_str(id).map_err(|_| invalid())?,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Pagination {
+    pub limit: i64,
+    pub cursor: Option<Cursor>,
+    pub sort: LogSort,
+    pub order: SortOrder,
+}
+
+impl Pagination {
+    fn from_query(query: PaginationQuery) -> Result<Pagination> {
+        let sort = query.sort.unwrap_or_default();
+        let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+        if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+            return Err(Error::ValidationError(vec![format!(
+                "limit must be between 1 and {}",
+                MAX_PAGE_SIZE
+            )]));
+        }
+
+        let cursor = query
+            .cursor
+            .as_deref()
+            .filter(|c| !c.is_empty())
+            .map(|c| Cursor::decode(c, sort))
+            .transpose()?;
+
+        Ok(Pagination {
+            limit,
+            cursor,
+            sort,
+            order: query.order.unwrap_or_default(),
+        })
+    }
+
+    pub fn desc(&self) -> bool {
+        self.order == SortOrder::Desc
+    }
+
+    /// The number of rows to fetch. This is one more than the page size, so that
+    /// [Pagination::page] can tell whether there is another page.
+    pub fn fetch_limit(&self) -> i64 {
+        self.limit + 1
+    }
+
+    pub fn cursor_time(&self) -> Option<DateTime<Utc>> {
+        self.cursor.as_ref().map(|c| c.time)
+    }
+
+    pub fn cursor_id(&self) -> Option<Uuid> {
+        self.cursor.as_ref().map(|c| c.id)
+    }
+
+    /// Build the response for rows fetched with [Pagination::fetch_limit], in page order.
+    /// `key` returns the sort time and ID of a row.
+    pub fn page<T: Serialize>(
+        &self,
+        mut rows: Vec<T>,
+        key: impl Fn(&T) -> (DateTime<Utc>, Uuid),
+    ) -> HttpResponse {
+        let next = if rows.len() as i64 > self.limit {
+            rows.truncate(self.limit as usize);
+            rows.last().map(|row| {
+                let (time, id) = key(row);
+                Cursor { time, id }.encode(self.sort)
+            })
+        } else {
+            None
+        };
+
+        let mut response = HttpResponse::Ok();
+        if let Some(next) = next {
+            response.insert_header((NEXT_CURSOR_HEADER, next));
+        }
+        response.json(rows)
+    }
+}
+
+impl FromRequest for Pagination {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let result = web::Query::<PaginationQuery>::from_query(req.query_string())
+            .map_err(|e| Error::ValidationError(vec![e.to_string()]))
+            .and_then(|query| Pagination::from_query(query.into_inner()));
+        ready(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_round_trip() {
+        let cursor = Cursor {
+            time: DateTime::parse_from_rfc3339("2023-02-20T10:11:12.345678Z")
+                .unwrap()
+                .with_timezone(&Utc),
+            id: Uuid::new_v4(),
+        };
+
+        let encoded = cursor.encode(LogSort::Created);
+        assert_eq!(Cursor::decode(&encoded, LogSort::Created).unwrap(), cursor);
+        assert!(Cursor::decode(&encoded, LogSort::Updated).is_err());
+        assert!(Cursor::decode("not a cursor", LogSort::Created).is_err());
+    }
+
+    #[test]
+    fn limits() {
+        let query = |limit| PaginationQuery {
+            limit,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            Pagination::from_query(query(None)).unwrap().limit,
+            DEFAULT_PAGE_SIZE
+        );
+        assert!(Pagination::from_query(query(Some(0))).is_err());
+        assert!(Pagination::from_query(query(Some(MAX_PAGE_SIZE + 1))).is_err());
+    }
+}
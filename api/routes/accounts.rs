@@ -1,9 +1,16 @@
-use actix_web::{get, web, HttpResponse, Responder};
+use actix_web::{
+    get, put,
+    web::{self, Path},
+    HttpResponse, Responder,
+};
 use ergo_auth::Authenticated;
 use ergo_database::object_id::AccountId;
-use ergo_tasks::actions::accounts::{AccountPublicInfo, AccountType};
+use ergo_tasks::actions::accounts::{AccountLimits, AccountPublicInfo, AccountType};
 
-use crate::{error::Result, web_app_server::AppStateData};
+use crate::{
+    error::{Error, Result},
+    web_app_server::AppStateData,
+};
 
 #[get("/account_types")]
 pub async fn list_account_types(data: AppStateData) -> Result<impl Responder> {
@@ -21,7 +28,9 @@ pub async fn list_account_types(data: AppStateData) -> Result<impl Responder> {
 pub async fn list_accounts(data: AppStateData, auth: Authenticated) -> Result<impl Responder> {
     let accounts = sqlx::query_as!(
         AccountPublicInfo,
-        r##"SELECT account_id AS "account_id: AccountId", account_type_id, name FROM accounts
+        r##"SELECT account_id AS "account_id: AccountId", account_type_id, name,
+            max_concurrency, requests_per_minute
+            FROM accounts
             WHERE org_id=$1"##,
         auth.org_id().0
     )
@@ -31,6 +40,47 @@ pub async fn list_accounts(data: AppStateData, auth: Authenticated) -> Result<im
     Ok(HttpResponse::Ok().json(accounts))
 }
 
+/// Set the limits on how actions that use the account can run.
+#[put("/accounts/{account_id}/limits")]
+pub async fn set_account_limits(
+    data: AppStateData,
+    auth: Authenticated,
+    account_id: Path<AccountId>,
+    payload: web::Json<AccountLimits>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+
+    let mut errors = Vec::new();
+    if payload.max_concurrency.map(|n| n < 1).unwrap_or(false) {
+        errors.push("max_concurrency must be at least 1".to_string());
+    }
+    if payload.requests_per_minute.map(|n| n < 1).unwrap_or(false) {
+        errors.push("requests_per_minute must be at least 1".to_string());
+    }
+    if !errors.is_empty() {
+        return Err(Error::ValidationError(errors));
+    }
+
+    let result = sqlx::query!(
+        "UPDATE accounts SET max_concurrency=$3, requests_per_minute=$4
+        WHERE account_id=$1 AND org_id=$2",
+        account_id.0,
+        auth.org_id().0,
+        payload.max_concurrency,
+        payload.requests_per_minute
+    )
+    .execute(&data.pg)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    Ok(HttpResponse::Ok().json(&*payload))
+}
+
 pub fn config(cfg: &mut web::ServiceConfig) {
-    cfg.service(list_account_types).service(list_accounts);
+    cfg.service(list_account_types)
+        .service(list_accounts)
+        .service(set_account_limits);
 }
@@ -3,21 +3,37 @@ use actix_web::{
     web::{self, Path},
     HttpResponse, Responder,
 };
-use ergo_auth::Authenticated;
+use ergo_auth::{Authenticated, PermissionType};
 use ergo_database::{
     object_id::{ActionCategoryId, ActionId},
     sql_insert_parameters,
 };
-use ergo_tasks::actions::{
-    execute::{ScriptOrTemplate, EXECUTOR_REGISTRY},
-    template::TemplateFields,
-    Action,
+use ergo_localization::Localize;
+use ergo_problem::FieldError;
+use ergo_tasks::{
+    actions::{
+        execute::{ScriptOrTemplate, EXECUTOR_REGISTRY},
+        template::TemplateFields,
+        Action,
+    },
+    dependents::{apply_dependent_validation, validate_action_dependents},
+    ActionValidateErrors,
 };
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use sqlx::Connection;
+use sqlx::{Connection, PgConnection};
 
-use crate::{error::Result, web_app_server::AppStateData};
+use crate::{
+    backend_data::BackendAppStateData,
+    error::{Error, Result},
+    routes::{
+        permissions::require_permission,
+        tags::{normalize_tags, ListQuery, ListSort, TOTAL_COUNT_HEADER},
+        tasks::DependentsQuery,
+    },
+    transaction::RequestTx,
+    web_app_server::AppStateData,
+};
 
 #[derive(Serialize, Clone, Debug, JsonSchema)]
 pub struct ExecutorInfo<'a> {
@@ -38,8 +54,30 @@ pub async fn list_executors() -> Result<impl Responder> {
     Ok(HttpResponse::Ok().json(info))
 }
 
+/// List the actions, optionally filtered by tag and searched. Actions can only be sorted by name
+/// or by relevance to the search. The total number of matching actions is returned in the
+/// [TOTAL_COUNT_HEADER] header.
 #[get("/actions")]
-pub async fn list_actions(data: AppStateData) -> Result<impl Responder> {
+pub async fn list_actions(
+    data: AppStateData,
+    query: web::Query<ListQuery>,
+) -> Result<impl Responder> {
+    let tags = query.tag_filter();
+    let search = query.search();
+    let search_pattern = query.search_pattern();
+    let by_relevance = query.sort() == ListSort::Relevance && search.is_some();
+
+    let total = sqlx::query_scalar!(
+        r##"SELECT COUNT(*) AS "count!" FROM actions
+        WHERE ($1::text[] IS NULL OR tags @> $1)
+            AND ($2::text IS NULL
+                OR (name || ' ' || COALESCE(description, '')) ILIKE $2)"##,
+        tags.as_deref(),
+        search_pattern,
+    )
+    .fetch_one(&data.pg)
+    .await?;
+
     let actions = sqlx::query_as!(
         Action,
         r##"SELECT
@@ -53,15 +91,37 @@ pub async fn list_actions(data: AppStateData) -> Result<impl Responder> {
         timeout,
         postprocess_script,
         account_required,
-        COALESCE(array_agg(account_type_id) FILTER(WHERE account_type_id IS NOT NULL), ARRAY[]::text[]) "account_types!"
+        COALESCE(array_agg(account_type_id) FILTER(WHERE account_type_id IS NOT NULL), ARRAY[]::text[]) "account_types!",
+        tags
         FROM actions
         LEFT JOIN allowed_action_account_types USING(action_id)
-        GROUP BY action_id"##,
+        WHERE ($1::text[] IS NULL OR tags @> $1)
+            AND ($2::text IS NULL
+                OR (name || ' ' || COALESCE(description, '')) ILIKE $2)
+        GROUP BY action_id
+        ORDER BY
+            CASE WHEN $4 AND NOT $5
+                THEN word_similarity($3, name || ' ' || COALESCE(description, '')) END DESC,
+            CASE WHEN $4 AND $5
+                THEN word_similarity($3, name || ' ' || COALESCE(description, '')) END ASC,
+            CASE WHEN NOT $5 THEN name END ASC,
+            CASE WHEN $5 THEN name END DESC,
+            action_id
+        LIMIT $6 OFFSET $7"##,
+        tags.as_deref(),
+        search_pattern,
+        search,
+        by_relevance,
+        query.desc,
+        query.limit(),
+        query.offset(),
     )
     .fetch_all(&data.pg)
     .await?;
 
-    Ok(HttpResponse::Ok().json(actions))
+    Ok(HttpResponse::Ok()
+        .insert_header((TOTAL_COUNT_HEADER, total.to_string()))
+        .json(actions))
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
@@ -81,6 +141,8 @@ pub struct ActionPayload {
     pub account_required: bool,
     #[serde(default)]
     pub account_types: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 
 impl ActionPayload {
@@ -97,32 +159,46 @@ impl ActionPayload {
             postprocess_script: self.postprocess_script,
             account_required: self.account_required,
             account_types: self.account_types,
+            tags: self.tags,
         }
     }
 }
 
+fn action_validation_error(errors: &ActionValidateErrors, locale: Option<&str>) -> Error {
+    Error::FieldValidationError(
+        errors
+            .0
+            .iter()
+            .map(|e| FieldError {
+                field: e.path().map(|p| p.to_string()),
+                message: e.localize(locale),
+            })
+            .collect(),
+    )
+}
+
 #[post("/actions")]
 pub async fn new_action(
-    data: AppStateData,
+    tx: RequestTx,
     auth: Authenticated,
     payload: web::Json<ActionPayload>,
 ) -> Result<impl Responder> {
     auth.expect_admin()?;
 
-    let payload: Action = payload.into_inner().into_action(ActionId::new());
+    let mut payload = payload.into_inner();
+    payload.tags = normalize_tags(payload.tags)?;
+    let payload: Action = payload.into_action(ActionId::new());
     payload
         .validate()
         .await
-        .map_err(ergo_tasks::Error::ActionValidateError)?;
-
-    let mut conn = data.pg.acquire().await?;
-    let mut tx = conn.begin().await?;
+        .map_err(|e| action_validation_error(&e, auth.locale()))?;
 
+    let mut tx = tx.lock().await?;
     sqlx::query!(
         "INSERT INTO actions (action_id, action_category_id, name, description,
         executor_id, executor_template, template_fields, account_required,
-        postprocess_script, timeout) VALUES
-        ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+        postprocess_script, timeout, tags) VALUES
+        ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
         &payload.action_id.0,
         &payload.action_category_id.0,
         &payload.name,
@@ -133,8 +209,9 @@ pub async fn new_action(
         &payload.account_required,
         payload.postprocess_script.as_ref(),
         payload.timeout,
+        &payload.tags,
     )
-    .execute(&mut tx)
+    .execute(&mut *tx)
     .await?;
 
     if !payload.account_types.is_empty() {
@@ -148,7 +225,7 @@ pub async fn new_action(
             query = query.bind(account_type).bind(payload.action_id.0);
         }
 
-        query.execute(&mut tx).await?;
+        query.execute(&mut *tx).await?;
     }
 
     sqlx::query!(
@@ -157,78 +234,101 @@ pub async fn new_action(
         &payload.action_id.0,
         &payload.account_types
     )
-    .execute(&mut tx)
+    .execute(&mut *tx)
     .await?;
 
-    tx.commit().await?;
-
     Ok(HttpResponse::Created().json(payload))
 }
 
 #[put("/actions/{action_id}")]
 pub async fn write_action(
     data: AppStateData,
+    backend_data: BackendAppStateData,
     auth: Authenticated,
     action_id: Path<ActionId>,
     payload: web::Json<ActionPayload>,
+    query: web::Query<DependentsQuery>,
 ) -> Result<impl Responder> {
-    auth.expect_admin()?;
+    let mut conn = data.pg.acquire().await?;
+    require_permission(&mut conn, &auth, PermissionType::Write, action_id.0).await?;
 
-    let payload: Action = payload.into_inner().into_action(action_id.into_inner());
+    let mut payload = payload.into_inner();
+    payload.tags = normalize_tags(payload.tags)?;
+    let payload: Action = payload.into_action(action_id.into_inner());
 
     payload
         .validate()
         .await
-        .map_err(ergo_tasks::Error::ActionValidateError)?;
+        .map_err(|e| action_validation_error(&e, auth.locale()))?;
 
-    let mut conn = data.pg.acquire().await?;
     let mut tx = conn.begin().await?;
+    upsert_action(&mut tx, &payload).await?;
+    tx.commit().await?;
+
+    // Check that the tasks using this action still work with the new definition.
+    let mut conn = backend_data.pg.acquire().await?;
+    let mut tx = conn.begin().await?;
+    let validation = validate_action_dependents(&mut tx, &payload).await?;
+    apply_dependent_validation(
+        &mut tx,
+        Some(&backend_data.notifications),
+        backend_data.redis_key_prefix.as_deref(),
+        &validation,
+        query.invalid_dependents,
+        auth.locale(),
+    )
+    .await?;
+    tx.commit().await?;
 
+    Ok(HttpResponse::Ok().json(payload))
+}
+
+/// Create the action, or replace it if it already exists.
+pub(crate) async fn upsert_action(tx: &mut PgConnection, action: &Action) -> Result<()> {
     sqlx::query!(
         "INSERT INTO actions (action_id, action_category_id, name, description,
             executor_id, executor_template, template_fields, account_required,
-            postprocess_script, timeout)
-        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            postprocess_script, timeout, tags)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
         ON CONFLICT(action_id) DO UPDATE
         SET action_category_id=$2, name=$3, description=$4,
         executor_id=$5, executor_template=$6, template_fields=$7, account_required=$8,
-        postprocess_script=$9",
-        &payload.action_id.0,
-        &payload.action_category_id.0,
-        &payload.name,
-        &payload.description as _,
-        &payload.executor_id,
-        sqlx::types::Json(&payload.executor_template) as _,
-        sqlx::types::Json(&payload.template_fields) as _,
-        &payload.account_required,
-        payload.postprocess_script.as_ref(),
-        payload.timeout
+        postprocess_script=$9, tags=$11",
+        &action.action_id.0,
+        &action.action_category_id.0,
+        &action.name,
+        &action.description as _,
+        &action.executor_id,
+        sqlx::types::Json(&action.executor_template) as _,
+        sqlx::types::Json(&action.template_fields) as _,
+        &action.account_required,
+        action.postprocess_script.as_ref(),
+        action.timeout,
+        &action.tags
     )
-    .execute(&mut tx)
+    .execute(&mut *tx)
     .await?;
 
     sqlx::query!("DELETE FROM allowed_action_account_types WHERE action_id=$1 AND account_type_id <> ALL($2)",
-        &payload.action_id.0,
-        &payload.account_types).execute(&mut tx).await?;
+        &action.action_id.0,
+        &action.account_types).execute(&mut *tx).await?;
 
-    if !payload.account_types.is_empty() {
+    if !action.account_types.is_empty() {
         let q = format!(
             "INSERT INTO allowed_action_account_types (account_type_id, action_id) VALUES {}
             ON CONFLICT DO NOTHING",
-            sql_insert_parameters::<2>(payload.account_types.len())
+            sql_insert_parameters::<2>(action.account_types.len())
         );
 
         let mut query = sqlx::query(&q);
-        for account_type in &payload.account_types {
-            query = query.bind(account_type).bind(payload.action_id.0);
+        for account_type in &action.account_types {
+            query = query.bind(account_type).bind(action.action_id.0);
         }
 
-        query.execute(&mut tx).await?;
+        query.execute(&mut *tx).await?;
     }
 
-    tx.commit().await?;
-
-    Ok(HttpResponse::Ok().json(payload))
+    Ok(())
 }
 
 #[delete("/actions/{action_id}")]
@@ -237,12 +337,14 @@ pub async fn delete_action(
     auth: Authenticated,
     action_id: Path<ActionId>,
 ) -> Result<impl Responder> {
-    auth.expect_admin()?;
+    let mut conn = data.pg.acquire().await?;
+    require_permission(&mut conn, &auth, PermissionType::Write, action_id.0).await?;
+
     sqlx::query!(
         "DELETE FROM actions WHERE action_id=$1",
         action_id.into_inner().0
     )
-    .execute(&data.pg)
+    .execute(&mut conn)
     .await?;
     Ok(HttpResponse::Ok().finish())
 }
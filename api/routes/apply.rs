@@ -0,0 +1,797 @@
+//! Declarative configuration. A bundle of inputs, actions, and tasks is reconciled against the
+//! existing objects, which are matched by name. Tasks refer to their actions and inputs by name
+//! as well, so the same files can be applied to any server.
+
+use actix_web::{post, web, HttpResponse, Responder};
+use ergo_auth::Authenticated;
+use ergo_database::object_id::{
+    AccountId, ActionCategoryId, ActionId, InputCategoryId, InputId, TaskId,
+};
+use ergo_localization::Localize;
+use ergo_tasks::{
+    actions::{execute::ScriptOrTemplate, template::TemplateFields, Action, TaskActionTemplate},
+    dependents::{
+        apply_dependent_validation, validate_action_dependents, validate_input_dependents,
+        InvalidDependentPolicy,
+    },
+    inputs::{
+        routing::TriggerRoute, secrets::mask_secrets, DisabledInputMode, Input, TriggerDedupeConfig,
+    },
+    state_history::{diff_states, StateChange},
+    state_reset::StateResetPolicy,
+    PeriodicTaskTriggerInput, TaskConfig,
+};
+use fxhash::FxHashMap;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, Connection, PgConnection};
+
+use super::{
+    actions::{upsert_action, ActionPayload},
+    inputs::{upsert_input, InputPayload},
+    tags::normalize_tags,
+    tasks::{
+        bundle_task_script, create_task, write_task, TaskActionInput, TaskInput, TaskTriggerInput,
+    },
+};
+use crate::{
+    backend_data::{BackendAppState, BackendAppStateData},
+    error::{Error, Result},
+    web_app_server::{AppState, AppStateData},
+};
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct ApplyBundle {
+    #[serde(default)]
+    pub inputs: Vec<InputPayload>,
+    #[serde(default)]
+    pub actions: Vec<ActionPayload>,
+    #[serde(default)]
+    pub tasks: Vec<TaskSpec>,
+}
+
+impl ApplyBundle {
+    /// Add the objects from another bundle to this one.
+    pub fn merge(&mut self, other: ApplyBundle) {
+        self.inputs.extend(other.inputs);
+        self.actions.extend(other.actions);
+        self.tasks.extend(other.tasks);
+    }
+
+    fn check_unique_names(&self) -> Result<()> {
+        let mut errors = Vec::new();
+        check_unique(
+            ObjectKind::Input,
+            self.inputs.iter().map(|i| i.name.as_str()),
+            &mut errors,
+        );
+        check_unique(
+            ObjectKind::Action,
+            self.actions.iter().map(|a| a.name.as_str()),
+            &mut errors,
+        );
+        check_unique(
+            ObjectKind::Task,
+            self.tasks.iter().map(|t| t.name.as_str()),
+            &mut errors,
+        );
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::ValidationError(errors))
+        }
+    }
+}
+
+fn check_unique<'a>(
+    kind: ObjectKind,
+    names: impl Iterator<Item = &'a str>,
+    errors: &mut Vec<String>,
+) {
+    let mut seen = FxHashMap::default();
+    for name in names {
+        let count = seen.entry(name).or_insert(0);
+        *count += 1;
+        if *count == 2 {
+            errors.push(format!("More than one {} is named {}", kind, name));
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+pub struct TaskActionSpec {
+    pub name: String,
+    /// The name of the action to run.
+    pub action: String,
+    pub account_id: Option<AccountId>,
+    pub action_template: Option<TaskActionTemplate>,
+    /// The local ID of another action in the task that undoes this one.
+    #[serde(default)]
+    pub compensate_with: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+pub struct TaskTriggerSpec {
+    pub name: String,
+    /// The name of the input that the trigger accepts.
+    pub input: String,
+    pub description: Option<String>,
+    pub periodic: Option<Vec<PeriodicTaskTriggerInput>>,
+    pub dedupe: Option<TriggerDedupeConfig>,
+    #[serde(default)]
+    pub sensitive: bool,
+    #[serde(default)]
+    pub routes: Option<Vec<TriggerRoute>>,
+}
+
+/// A task, with its actions and triggers referring to other objects by name instead of ID.
+/// The task's state is left alone when the task already exists.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct TaskSpec {
+    pub name: String,
+    pub description: Option<String>,
+    pub alias: Option<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub disabled_input_mode: DisabledInputMode,
+    pub compiled: TaskConfig,
+    #[serde(default)]
+    pub source: serde_json::Value,
+    #[serde(default)]
+    pub state_reset: Option<StateResetPolicy>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub actions: FxHashMap<String, TaskActionSpec>,
+    #[serde(default)]
+    pub triggers: FxHashMap<String, TaskTriggerSpec>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl TaskSpec {
+    /// The spec in the form used for diffs, with secrets in the periodic trigger payloads masked.
+    fn diff_value(
+        &self,
+        schemas: &FxHashMap<&str, &serde_json::Value>,
+    ) -> Result<serde_json::Value> {
+        let mut spec = self.clone();
+        for trigger in spec.triggers.values_mut() {
+            if matches!(trigger.periodic.as_deref(), Some([])) {
+                trigger.periodic = None;
+            }
+
+            if let Some(schema) = schemas.get(trigger.input.as_str()) {
+                for periodic in trigger.periodic.iter_mut().flatten() {
+                    mask_secrets(schema, &mut periodic.payload);
+                }
+            }
+        }
+
+        Ok(serde_json::to_value(&spec)?)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ObjectKind {
+    Input,
+    Action,
+    Task,
+}
+
+impl std::fmt::Display for ObjectKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ObjectKind::Input => write!(f, "input"),
+            ObjectKind::Action => write!(f, "action"),
+            ObjectKind::Task => write!(f, "task"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ApplyOperation {
+    Create,
+    Update,
+    Unchanged,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ApplyChange {
+    pub kind: ObjectKind,
+    pub name: String,
+    /// The object's ID. This is None for objects that a dry run would create.
+    pub id: Option<String>,
+    pub operation: ApplyOperation,
+    /// The differences from the existing object, for updates.
+    pub changes: Vec<StateChange>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ApplyResult {
+    pub dry_run: bool,
+    pub changes: Vec<ApplyChange>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApplyQuery {
+    /// Return the changes without making them.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// What to do with tasks that no longer validate after an input or action changes.
+    #[serde(default)]
+    pub invalid_dependents: InvalidDependentPolicy,
+}
+
+/// Existing objects indexed by name. Names that more than one object uses map to None, and
+/// can't be referenced from a bundle.
+struct NameIndex<T>(FxHashMap<String, Option<T>>);
+
+impl<T> NameIndex<T> {
+    fn new(objects: impl IntoIterator<Item = (String, T)>) -> Self {
+        let mut index = FxHashMap::default();
+        for (name, object) in objects {
+            index
+                .entry(name)
+                .and_modify(|existing| *existing = None)
+                .or_insert(Some(object));
+        }
+
+        NameIndex(index)
+    }
+
+    fn get(&self, kind: ObjectKind, name: &str) -> Result<Option<&T>, String> {
+        match self.0.get(name) {
+            Some(Some(object)) => Ok(Some(object)),
+            Some(None) => Err(format!(
+                "More than one existing {} is named {}, so it can't be applied by name",
+                kind, name
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+struct PlannedInput {
+    input: Input,
+    operation: ApplyOperation,
+}
+
+struct PlannedAction {
+    action: Action,
+    operation: ApplyOperation,
+}
+
+struct PlannedTask {
+    task_id: Option<TaskId>,
+    task: TaskInput,
+}
+
+async fn existing_inputs(tx: &mut PgConnection) -> Result<NameIndex<Input>> {
+    let inputs = sqlx::query_as!(
+        Input,
+        r##"SELECT
+            input_id as "input_id: InputId",
+            input_category_id as "input_category_id: InputCategoryId",
+            name, description, payload_schema
+        FROM inputs"##
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    Ok(NameIndex::new(
+        inputs.into_iter().map(|i| (i.name.clone(), i)),
+    ))
+}
+
+async fn existing_actions(tx: &mut PgConnection) -> Result<NameIndex<Action>> {
+    let actions = sqlx::query_as!(
+        Action,
+        r##"SELECT
+        action_id as "action_id: ActionId",
+        action_category_id as "action_category_id: ActionCategoryId",
+        name,
+        description,
+        executor_id,
+        executor_template as "executor_template: ScriptOrTemplate",
+        template_fields as "template_fields: TemplateFields",
+        timeout,
+        postprocess_script,
+        account_required,
+        COALESCE(array_agg(account_type_id ORDER BY account_type_id) FILTER(WHERE account_type_id IS NOT NULL), ARRAY[]::text[]) "account_types!",
+        tags
+        FROM actions
+        LEFT JOIN allowed_action_account_types USING(action_id)
+        GROUP BY action_id"##,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    Ok(NameIndex::new(
+        actions.into_iter().map(|a| (a.name.clone(), a)),
+    ))
+}
+
+async fn existing_tasks(
+    tx: &mut PgConnection,
+    auth: &Authenticated,
+) -> Result<NameIndex<(TaskId, TaskSpec)>> {
+    let tasks = sqlx::query!(
+        r##"SELECT task_id AS "task_id: TaskId",
+            tasks.name, tasks.description, alias, enabled,
+            disabled_input_mode AS "disabled_input_mode: DisabledInputMode",
+            compiled AS "compiled!: Json<TaskConfig>",
+            source AS "source!",
+            state_reset AS "state_reset: Json<StateResetPolicy>",
+            tasks.tags,
+            COALESCE(ta.actions, '{}'::jsonb) AS "actions!: Json<FxHashMap<String, TaskActionSpec>>",
+            COALESCE(tt.triggers, '{}'::jsonb) AS "triggers!: Json<FxHashMap<String, TaskTriggerSpec>>"
+        FROM tasks
+        JOIN task_templates USING (task_template_id, task_template_version)
+
+        LEFT JOIN LATERAL (
+            SELECT jsonb_object_agg(task_action_local_id, jsonb_build_object(
+                'name', task_actions.name,
+                'action', actions.name,
+                'account_id', task_actions.account_id,
+                'action_template', task_actions.action_template,
+                'compensate_with', task_actions.compensate_with
+            )) AS actions
+            FROM task_actions
+            JOIN actions USING (action_id)
+            WHERE task_actions.task_id = tasks.task_id
+        ) ta ON true
+
+        LEFT JOIN LATERAL (
+            SELECT jsonb_object_agg(task_trigger_local_id, jsonb_build_object(
+                'name', task_triggers.name,
+                'input', inputs.name,
+                'description', task_triggers.description,
+                'periodic', periodic,
+                'dedupe', task_triggers.dedupe,
+                'sensitive', task_triggers.sensitive,
+                'routes', task_triggers.routes
+            )) AS triggers
+            FROM task_triggers
+            JOIN inputs USING (input_id)
+            LEFT JOIN LATERAL (
+                SELECT jsonb_agg(jsonb_build_object(
+                    'name', pt.name,
+                    'schedule', pt.schedule,
+                    'payload', pt.payload,
+                    'enabled', pt.enabled
+                )) periodic
+                FROM periodic_triggers pt WHERE pt.task_trigger_id = task_triggers.task_trigger_id
+            ) AS periodic ON true
+            WHERE task_triggers.task_id = tasks.task_id
+        ) tt ON true
+
+        WHERE tasks.org_id = $1 AND NOT tasks.deleted"##,
+        auth.org_id().0
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    Ok(NameIndex::new(tasks.into_iter().map(|t| {
+        let spec = TaskSpec {
+            name: t.name.clone(),
+            description: t.description,
+            alias: t.alias,
+            enabled: t.enabled,
+            disabled_input_mode: t.disabled_input_mode,
+            compiled: t.compiled.0,
+            source: t.source,
+            state_reset: t.state_reset.map(|s| s.0),
+            tags: t.tags,
+            actions: t.actions.0,
+            triggers: t.triggers.0,
+        };
+
+        (t.name, (t.task_id, spec))
+    })))
+}
+
+fn plan_change(
+    kind: ObjectKind,
+    name: &str,
+    id: Option<String>,
+    before: Option<serde_json::Value>,
+    after: serde_json::Value,
+) -> ApplyChange {
+    let (operation, changes) = match before {
+        None => (ApplyOperation::Create, Vec::new()),
+        Some(before) => {
+            let changes = diff_states(&before, &after);
+            if changes.is_empty() {
+                (ApplyOperation::Unchanged, changes)
+            } else {
+                (ApplyOperation::Update, changes)
+            }
+        }
+    };
+
+    ApplyChange {
+        kind,
+        name: name.to_string(),
+        id,
+        operation,
+        changes,
+    }
+}
+
+/// Reconcile a bundle against the existing objects. Nothing is written when `dry_run` is set.
+pub(crate) async fn apply_bundle(
+    data: &AppState,
+    backend_data: &BackendAppState,
+    auth: &Authenticated,
+    mut bundle: ApplyBundle,
+    dry_run: bool,
+    invalid_dependents: InvalidDependentPolicy,
+) -> Result<ApplyResult> {
+    bundle.check_unique_names()?;
+
+    // Bundle the scripts up front so that the compiled configs match what would be saved.
+    for task in bundle.tasks.iter_mut() {
+        task.tags = normalize_tags(std::mem::take(&mut task.tags))?;
+        bundle_task_script(&data.pg, auth.org_id(), &mut task.compiled).await?;
+    }
+
+    let mut conn = data.pg.acquire().await?;
+    let mut tx = conn.begin().await?;
+
+    let old_inputs = existing_inputs(&mut tx).await?;
+    let old_actions = existing_actions(&mut tx).await?;
+    let old_tasks = existing_tasks(&mut tx, auth).await?;
+
+    let mut errors = Vec::new();
+    let mut changes = Vec::new();
+
+    let mut inputs = Vec::with_capacity(bundle.inputs.len());
+    for payload in bundle.inputs {
+        if let Err(e) = jsonschema::JSONSchema::compile(&payload.payload_schema) {
+            errors.push(format!("Input {}: {}", payload.name, e));
+            continue;
+        }
+
+        let existing = match old_inputs.get(ObjectKind::Input, &payload.name) {
+            Ok(existing) => existing,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+
+        let input_id = existing
+            .map(|i| i.input_id.clone())
+            .unwrap_or_else(InputId::new);
+        let input = payload.into_input(input_id);
+        let before = existing.map(serde_json::to_value).transpose()?;
+        let change = plan_change(
+            ObjectKind::Input,
+            &input.name,
+            existing.map(|i| i.input_id.to_string()),
+            before,
+            serde_json::to_value(&input)?,
+        );
+
+        inputs.push(PlannedInput {
+            input,
+            operation: change.operation,
+        });
+        changes.push(change);
+    }
+
+    let mut actions = Vec::with_capacity(bundle.actions.len());
+    for payload in bundle.actions {
+        let existing = match old_actions.get(ObjectKind::Action, &payload.name) {
+            Ok(existing) => existing,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+
+        let action_id = existing
+            .map(|a| a.action_id.clone())
+            .unwrap_or_else(ActionId::new);
+        let mut action = payload.into_action(action_id);
+        action.account_types.sort();
+        action.tags = normalize_tags(std::mem::take(&mut action.tags))?;
+        if let Err(e) = action.validate().await {
+            errors.extend(
+                e.0.iter()
+                    .map(|e| format!("Action {}: {}", action.name, e.localize(auth.locale()))),
+            );
+            continue;
+        }
+
+        let before = existing.map(serde_json::to_value).transpose()?;
+        let change = plan_change(
+            ObjectKind::Action,
+            &action.name,
+            existing.map(|a| a.action_id.to_string()),
+            before,
+            serde_json::to_value(&action)?,
+        );
+
+        actions.push(PlannedAction {
+            action,
+            operation: change.operation,
+        });
+        changes.push(change);
+    }
+
+    let input_ids = inputs
+        .iter()
+        .map(|p| (p.input.name.as_str(), &p.input.input_id))
+        .collect::<FxHashMap<_, _>>();
+    let action_ids = actions
+        .iter()
+        .map(|p| (p.action.name.as_str(), &p.action.action_id))
+        .collect::<FxHashMap<_, _>>();
+
+    // The schemas are used to mask secrets in the diffs of periodic trigger payloads.
+    let mut schemas = old_inputs
+        .0
+        .iter()
+        .filter_map(|(name, input)| Some((name.as_str(), &input.as_ref()?.payload_schema)))
+        .collect::<FxHashMap<_, _>>();
+    schemas.extend(
+        inputs
+            .iter()
+            .map(|p| (p.input.name.as_str(), &p.input.payload_schema)),
+    );
+
+    let mut tasks = Vec::with_capacity(bundle.tasks.len());
+    for spec in bundle.tasks {
+        let existing = match old_tasks.get(ObjectKind::Task, &spec.name) {
+            Ok(existing) => existing,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+
+        let mut task_actions = FxHashMap::default();
+        for (local_id, action) in &spec.actions {
+            let action_id = match action_ids.get(action.action.as_str()) {
+                Some(id) => Some((*id).clone()),
+                None => match old_actions.get(ObjectKind::Action, &action.action) {
+                    Ok(a) => a.map(|a| a.action_id.clone()),
+                    Err(e) => {
+                        errors.push(e);
+                        continue;
+                    }
+                },
+            };
+
+            match action_id {
+                Some(action_id) => {
+                    task_actions.insert(
+                        local_id.clone(),
+                        TaskActionInput {
+                            name: action.name.clone(),
+                            action_id,
+                            account_id: action.account_id.clone(),
+                            action_template: action.action_template.clone(),
+                            compensate_with: action.compensate_with.clone(),
+                        },
+                    );
+                }
+                None => errors.push(format!(
+                    "Task {} action {}: There is no action named {}",
+                    spec.name, local_id, action.action
+                )),
+            }
+        }
+
+        let mut task_triggers = FxHashMap::default();
+        for (local_id, trigger) in &spec.triggers {
+            let input_id = match input_ids.get(trigger.input.as_str()) {
+                Some(id) => Some((*id).clone()),
+                None => match old_inputs.get(ObjectKind::Input, &trigger.input) {
+                    Ok(i) => i.map(|i| i.input_id.clone()),
+                    Err(e) => {
+                        errors.push(e);
+                        continue;
+                    }
+                },
+            };
+
+            match input_id {
+                Some(input_id) => {
+                    task_triggers.insert(
+                        local_id.clone(),
+                        TaskTriggerInput {
+                            input_id,
+                            name: trigger.name.clone(),
+                            description: trigger.description.clone(),
+                            periodic: trigger.periodic.clone(),
+                            dedupe: trigger.dedupe.clone(),
+                            sensitive: trigger.sensitive,
+                            routes: trigger.routes.clone(),
+                        },
+                    );
+                }
+                None => errors.push(format!(
+                    "Task {} trigger {}: There is no input named {}",
+                    spec.name, local_id, trigger.input
+                )),
+            }
+        }
+
+        let before = existing
+            .map(|(_, spec)| spec.diff_value(&schemas))
+            .transpose()?;
+        let change = plan_change(
+            ObjectKind::Task,
+            &spec.name,
+            existing.map(|(task_id, _)| task_id.to_string()),
+            before,
+            spec.diff_value(&schemas)?,
+        );
+
+        if change.operation != ApplyOperation::Unchanged {
+            tasks.push(PlannedTask {
+                task_id: existing.map(|(task_id, _)| task_id.clone()),
+                task: TaskInput {
+                    name: spec.name,
+                    description: spec.description,
+                    alias: spec.alias,
+                    enabled: spec.enabled,
+                    disabled_input_mode: spec.disabled_input_mode,
+                    compiled: spec.compiled,
+                    source: spec.source,
+                    state: None,
+                    state_reset: spec.state_reset,
+                    tags: spec.tags,
+                    actions: task_actions,
+                    triggers: task_triggers,
+                },
+            });
+        }
+        changes.push(change);
+    }
+
+    if !errors.is_empty() {
+        return Err(Error::ValidationError(errors));
+    }
+
+    if dry_run {
+        return Ok(ApplyResult {
+            dry_run: true,
+            changes,
+        });
+    }
+
+    for planned in &inputs {
+        if planned.operation != ApplyOperation::Unchanged {
+            upsert_input(&mut tx, &planned.input).await?;
+        }
+    }
+
+    for planned in &actions {
+        if planned.operation != ApplyOperation::Unchanged {
+            upsert_action(&mut tx, &planned.action).await?;
+        }
+    }
+
+    for planned in tasks {
+        match planned.task_id {
+            Some(task_id) => {
+                write_task(
+                    &mut tx,
+                    &data.redis_key_prefix,
+                    auth,
+                    &task_id,
+                    &planned.task,
+                )
+                .await?;
+            }
+            None => {
+                let name = planned.task.name.clone();
+                let task_id =
+                    create_task(&mut tx, &data.redis_key_prefix, auth, planned.task).await?;
+                if let Some(change) = changes
+                    .iter_mut()
+                    .find(|c| c.kind == ObjectKind::Task && c.name == name)
+                {
+                    change.id = Some(task_id.to_string());
+                }
+            }
+        }
+    }
+
+    tx.commit().await?;
+
+    for change in changes.iter_mut() {
+        if change.id.is_none() {
+            change.id = match change.kind {
+                ObjectKind::Input => input_ids.get(change.name.as_str()).map(|id| id.to_string()),
+                ObjectKind::Action => action_ids
+                    .get(change.name.as_str())
+                    .map(|id| id.to_string()),
+                ObjectKind::Task => None,
+            };
+        }
+    }
+
+    // Check that the tasks using the updated inputs and actions still work with them.
+    let mut conn = backend_data.pg.acquire().await?;
+    let mut tx = conn.begin().await?;
+    for planned in inputs
+        .iter()
+        .filter(|p| p.operation == ApplyOperation::Update)
+    {
+        let validation = validate_input_dependents(
+            &mut tx,
+            &planned.input.input_id,
+            &planned.input.payload_schema,
+        )
+        .await?;
+        apply_dependent_validation(
+            &mut tx,
+            Some(&backend_data.notifications),
+            backend_data.redis_key_prefix.as_deref(),
+            &validation,
+            invalid_dependents,
+            auth.locale(),
+        )
+        .await?;
+    }
+
+    for planned in actions
+        .iter()
+        .filter(|p| p.operation == ApplyOperation::Update)
+    {
+        let validation = validate_action_dependents(&mut tx, &planned.action).await?;
+        apply_dependent_validation(
+            &mut tx,
+            Some(&backend_data.notifications),
+            backend_data.redis_key_prefix.as_deref(),
+            &validation,
+            invalid_dependents,
+            auth.locale(),
+        )
+        .await?;
+    }
+    tx.commit().await?;
+
+    Ok(ApplyResult {
+        dry_run: false,
+        changes,
+    })
+}
+
+#[post("/apply")]
+async fn apply(
+    data: AppStateData,
+    backend_data: BackendAppStateData,
+    auth: Authenticated,
+    query: web::Query<ApplyQuery>,
+    payload: web::Json<ApplyBundle>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+
+    let result = apply_bundle(
+        &data,
+        &backend_data,
+        &auth,
+        payload.into_inner(),
+        query.dry_run,
+        query.invalid_dependents,
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(apply);
+}
@@ -0,0 +1,248 @@
+//! Manual approvals requested by the `approval` action. A decision can be made by a logged-in
+//! user who can trigger the task, or by anyone with the token from the approval's
+//! notification links.
+
+use actix_web::{
+    get, post,
+    web::{self, Path},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use ergo_auth::{Authenticated, MaybeAuthenticated};
+use ergo_database::object_id::{TaskId, UserId};
+use ergo_tasks::approvals::{decide_approval, hash_token, ApprovalStatus, DecideApproval};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::Connection;
+use uuid::Uuid;
+
+use super::tasks::TaskTriggerResponse;
+use crate::{
+    backend_data::BackendAppStateData,
+    error::{Error, Result},
+};
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct Approval {
+    pub approval_id: Uuid,
+    pub task_id: TaskId,
+    pub task_name: String,
+    pub task_trigger_local_id: String,
+    pub message: String,
+    pub payload: Option<serde_json::Value>,
+    pub status: ApprovalStatus,
+    pub expires: DateTime<Utc>,
+    pub created: DateTime<Utc>,
+    pub decided_by: Option<UserId>,
+    pub decided_at: Option<DateTime<Utc>>,
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListApprovalsQuery {
+    /// Only return approvals with this status.
+    pub status: Option<ApprovalStatus>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenQuery {
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct ApprovalDecisionInput {
+    /// `approved` or `denied`.
+    pub decision: ApprovalStatus,
+    pub comment: Option<String>,
+    /// The token from the approval's links, when deciding without logging in.
+    pub token: Option<String>,
+}
+
+/// List the org's approvals, newest first, for the tasks that the user can read.
+#[get("/approvals")]
+async fn list_approvals(
+    data: BackendAppStateData,
+    auth: Authenticated,
+    query: web::Query<ListApprovalsQuery>,
+) -> Result<impl Responder> {
+    let ids = auth.user_entity_ids();
+    let approvals = sqlx::query_as!(
+        Approval,
+        r##"SELECT approval_id,
+            a.task_id AS "task_id: TaskId",
+            tasks.name AS task_name,
+            task_trigger_local_id,
+            message,
+            payload,
+            CASE WHEN status = 'pending' AND expires < now() THEN 'expired'
+                ELSE status END AS "status!: ApprovalStatus",
+            expires,
+            a.created,
+            decided_by AS "decided_by: UserId",
+            decided_at,
+            comment
+        FROM approvals a
+        JOIN tasks USING (task_id)
+        WHERE a.org_id=$1 AND NOT tasks.deleted
+            AND ($3::approval_status IS NULL
+                OR $3 = CASE WHEN status = 'pending' AND expires < now() THEN 'expired'
+                    ELSE status END)
+            AND EXISTS(SELECT 1 FROM user_entity_permissions
+                WHERE user_entity_id = ANY($2)
+                AND permission_type = 'read'
+                AND permissioned_object IN (uuid_nil(), a.task_id)
+            )
+        ORDER BY a.created DESC
+        LIMIT 200"##,
+        auth.org_id().0,
+        ids.as_slice(),
+        query.status as _
+    )
+    .fetch_all(&data.pg)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(approvals))
+}
+
+/// Check that the request can see and decide on the approval, either with the approval's token
+/// or as a user who can trigger the task.
+async fn check_access(
+    data: &BackendAppStateData,
+    auth: &MaybeAuthenticated,
+    approval_id: &Uuid,
+    token: Option<&str>,
+) -> Result<()> {
+    let row = sqlx::query!(
+        "SELECT org_id, task_id, token_hash FROM approvals WHERE approval_id=$1",
+        approval_id
+    )
+    .fetch_optional(&data.pg)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    if let Some(token) = token {
+        return if hash_token(token) == row.token_hash {
+            Ok(())
+        } else {
+            Err(Error::AuthenticationError)
+        };
+    }
+
+    let auth = auth.as_ref().ok_or(Error::AuthenticationError)?;
+    let ids = auth.user_entity_ids();
+    let allowed = sqlx::query_scalar!(
+        r##"SELECT EXISTS(SELECT 1 FROM user_entity_permissions
+            WHERE user_entity_id = ANY($1)
+            AND permission_type = 'trigger_event'
+            AND permissioned_object IN (uuid_nil(), $2)
+        ) AS "allowed!""##,
+        ids.as_slice(),
+        row.task_id
+    )
+    .fetch_one(&data.pg)
+    .await?;
+
+    if row.org_id != auth.org_id().0 || !allowed {
+        return Err(Error::NotFound);
+    }
+
+    Ok(())
+}
+
+#[get("/approvals/{approval_id}")]
+async fn get_approval(
+    data: BackendAppStateData,
+    auth: MaybeAuthenticated,
+    approval_id: Path<Uuid>,
+    query: web::Query<TokenQuery>,
+) -> Result<impl Responder> {
+    let approval_id = approval_id.into_inner();
+    check_access(&data, &auth, &approval_id, query.token.as_deref()).await?;
+
+    let approval = sqlx::query_as!(
+        Approval,
+        r##"SELECT approval_id,
+            a.task_id AS "task_id: TaskId",
+            tasks.name AS task_name,
+            task_trigger_local_id,
+            message,
+            payload,
+            CASE WHEN status = 'pending' AND expires < now() THEN 'expired'
+                ELSE status END AS "status!: ApprovalStatus",
+            expires,
+            a.created,
+            decided_by AS "decided_by: UserId",
+            decided_at,
+            comment
+        FROM approvals a
+        JOIN tasks USING (task_id)
+        WHERE approval_id=$1"##,
+        approval_id
+    )
+    .fetch_one(&data.pg)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(approval))
+}
+
+/// Approve or deny a pending approval. The decision is sent to the approval's trigger, and the
+/// response has the ID of the new input.
+#[post("/approvals/{approval_id}")]
+async fn decide(
+    data: BackendAppStateData,
+    auth: MaybeAuthenticated,
+    approval_id: Path<Uuid>,
+    payload: web::Json<ApprovalDecisionInput>,
+) -> Result<impl Responder> {
+    let approval_id = approval_id.into_inner();
+    let ApprovalDecisionInput {
+        decision,
+        comment,
+        token,
+    } = payload.into_inner();
+
+    if !matches!(decision, ApprovalStatus::Approved | ApprovalStatus::Denied) {
+        return Err(Error::InvalidRequest(
+            "decision must be approved or denied".to_string(),
+        ));
+    }
+
+    check_access(&data, &auth, &approval_id, token.as_deref()).await?;
+    // Decisions made with a token are anonymous, even if the user happens to be logged in.
+    let decided_by = match token {
+        Some(_) => None,
+        None => auth.as_ref().map(|a| a.user_id().clone()),
+    };
+
+    let mut conn = data.pg.acquire().await?;
+    let mut tx = conn.begin().await?;
+    let log_id = decide_approval(DecideApproval {
+        pg: &mut *tx,
+        notifications: Some(data.notifications.clone()),
+        redis_key_prefix: data.redis_key_prefix.as_deref(),
+        approval_id,
+        decision,
+        decided_by,
+        comment,
+    })
+    .await;
+
+    // An expired approval is marked as such even though the decision failed.
+    let log_id = match log_id {
+        Ok(id) => id,
+        Err(e @ ergo_tasks::Error::ApprovalClosed(_)) => {
+            tx.commit().await?;
+            return Err(e.into());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    tx.commit().await?;
+
+    Ok(HttpResponse::Accepted().json(TaskTriggerResponse { log_id }))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_approvals)
+        .service(get_approval)
+        .service(decide);
+}
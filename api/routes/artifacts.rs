@@ -0,0 +1,68 @@
+use actix_web::{
+    get,
+    http::header::{ContentDisposition, ContentType, DispositionParam, DispositionType},
+    web::{self, Path},
+    HttpResponse, Responder,
+};
+use ergo_auth::Authenticated;
+use uuid::Uuid;
+
+use crate::{
+    error::{Error, Result},
+    web_app_server::AppStateData,
+};
+
+#[get("/artifacts/{artifact_id}")]
+async fn get_artifact(
+    data: AppStateData,
+    auth: Authenticated,
+    artifact_id: Path<Uuid>,
+) -> Result<impl Responder> {
+    let artifact_id = artifact_id.into_inner();
+    let ids = auth.user_entity_ids();
+
+    let artifact = sqlx::query!(
+        r##"SELECT content_type, data
+        FROM action_artifacts aa
+        JOIN tasks USING(task_id)
+        WHERE aa.artifact_id=$1 AND aa.expires > now() AND tasks.org_id=$2 AND
+            EXISTS(SELECT 1 FROM user_entity_permissions
+                WHERE user_entity_id = ANY($3)
+                AND permission_type = 'read'
+                AND permissioned_object IN (uuid_nil(), tasks.task_id)
+            )"##,
+        artifact_id,
+        auth.org_id().0,
+        ids.as_slice()
+    )
+    .fetch_optional(&data.pg)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let extension = if artifact.content_type.starts_with("application/json") {
+        "json"
+    } else {
+        "txt"
+    };
+
+    let content_type = artifact
+        .content_type
+        .parse()
+        .map(ContentType)
+        .unwrap_or_else(|_| ContentType::octet_stream());
+
+    Ok(HttpResponse::Ok()
+        .insert_header(content_type)
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!(
+                "artifact-{}.{}",
+                artifact_id, extension
+            ))],
+        })
+        .body(artifact.data))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_artifact);
+}
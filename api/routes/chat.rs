@@ -0,0 +1,270 @@
+//! Chat subscriptions send the messages or commands in a Discord or Telegram channel to a task
+//! trigger. The server's chat bridge picks up changes to the subscriptions within a minute.
+
+use actix_web::{
+    delete, get, post, put,
+    web::{self, Path},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use ergo_auth::Authenticated;
+use ergo_database::object_id::{AccountId, ChatSubscriptionId, TaskId, TaskTriggerId};
+use ergo_tasks::inputs::chat::ChatPlatform;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+
+use crate::{
+    error::{Error, Result},
+    web_app_server::AppStateData,
+};
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ChatSubscription {
+    pub chat_subscription_id: ChatSubscriptionId,
+    pub account_id: AccountId,
+    pub channel_id: String,
+    pub command: Option<String>,
+    pub task_id: TaskId,
+    pub trigger: String,
+    pub enabled: bool,
+    pub created: DateTime<Utc>,
+    pub modified: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ChatSubscriptionInput {
+    /// An account with the `discord_bot` or `telegram_bot` account type.
+    pub account_id: AccountId,
+    /// The Discord channel ID or Telegram chat ID.
+    pub channel_id: String,
+    /// Only send this command, such as `deploy` for `/deploy`. Without a command, every message
+    /// in the channel is sent.
+    pub command: Option<String>,
+    pub task_id: TaskId,
+    /// The local ID of the task trigger to send messages to.
+    pub trigger: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+async fn get_subscription(
+    tx: &mut PgConnection,
+    auth: &Authenticated,
+    chat_subscription_id: &ChatSubscriptionId,
+) -> Result<ChatSubscription> {
+    sqlx::query_as!(
+        ChatSubscription,
+        r##"SELECT chat_subscription_id AS "chat_subscription_id: ChatSubscriptionId",
+            account_id AS "account_id: AccountId",
+            channel_id, command,
+            tt.task_id AS "task_id: TaskId",
+            tt.task_trigger_local_id AS trigger,
+            sub.enabled, sub.created, sub.modified
+        FROM chat_subscriptions sub
+        JOIN task_triggers tt USING (task_trigger_id)
+        WHERE chat_subscription_id=$1 AND sub.org_id=$2"##,
+        chat_subscription_id.0,
+        auth.org_id().0
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::NotFound)
+}
+
+/// Check the input and look up the task trigger that it refers to. The command's leading slash
+/// is removed if it has one.
+async fn validate_input(
+    tx: &mut PgConnection,
+    auth: &Authenticated,
+    input: &mut ChatSubscriptionInput,
+) -> Result<TaskTriggerId> {
+    let mut errors = Vec::new();
+
+    input.channel_id = input.channel_id.trim().to_string();
+    if input.channel_id.is_empty() {
+        errors.push("channel_id is required".to_string());
+    }
+
+    if let Some(command) = input.command.as_mut() {
+        *command = command.trim().trim_start_matches('/').to_string();
+        if command.is_empty() || command.contains(|c: char| c.is_whitespace() || c == '@') {
+            errors.push(format!("Invalid command {}", command));
+        }
+    }
+
+    let account_type = sqlx::query_scalar!(
+        "SELECT account_type_id FROM accounts WHERE account_id=$1 AND org_id=$2",
+        input.account_id.0,
+        auth.org_id().0
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let is_bot_account = account_type
+        .as_deref()
+        .and_then(ChatPlatform::from_account_type)
+        .is_some();
+    if !is_bot_account {
+        errors.push(format!(
+            "Account {} is not a Discord or Telegram bot account",
+            input.account_id
+        ));
+    }
+
+    if !errors.is_empty() {
+        return Err(Error::ValidationError(errors));
+    }
+
+    let task_trigger_id = sqlx::query_scalar!(
+        r##"SELECT task_trigger_id AS "task_trigger_id: TaskTriggerId"
+        FROM task_triggers
+        JOIN tasks USING (task_id)
+        WHERE task_id=$1 AND task_trigger_local_id=$2 AND org_id=$3 AND NOT deleted
+            AND EXISTS(SELECT 1 FROM user_entity_permissions
+                WHERE permissioned_object IN (uuid_nil(), task_id)
+                AND user_entity_id=ANY($4)
+                AND permission_type='write')"##,
+        input.task_id.0,
+        input.trigger,
+        auth.org_id().0,
+        auth.user_entity_ids().as_slice()
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    Ok(task_trigger_id)
+}
+
+#[get("/chat_subscriptions")]
+async fn list_subscriptions(data: AppStateData, auth: Authenticated) -> Result<impl Responder> {
+    let subscriptions = sqlx::query_as!(
+        ChatSubscription,
+        r##"SELECT chat_subscription_id AS "chat_subscription_id: ChatSubscriptionId",
+            account_id AS "account_id: AccountId",
+            channel_id, command,
+            tt.task_id AS "task_id: TaskId",
+            tt.task_trigger_local_id AS trigger,
+            sub.enabled, sub.created, sub.modified
+        FROM chat_subscriptions sub
+        JOIN task_triggers tt USING (task_trigger_id)
+        WHERE sub.org_id=$1
+        ORDER BY sub.channel_id, sub.command"##,
+        auth.org_id().0
+    )
+    .fetch_all(&data.pg)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(subscriptions))
+}
+
+#[get("/chat_subscriptions/{chat_subscription_id}")]
+async fn get_subscription_handler(
+    data: AppStateData,
+    auth: Authenticated,
+    chat_subscription_id: Path<ChatSubscriptionId>,
+) -> Result<impl Responder> {
+    let mut conn = data.pg.acquire().await?;
+    let subscription = get_subscription(&mut conn, &auth, &chat_subscription_id).await?;
+    Ok(HttpResponse::Ok().json(subscription))
+}
+
+#[post("/chat_subscriptions")]
+async fn new_subscription(
+    data: AppStateData,
+    auth: Authenticated,
+    payload: web::Json<ChatSubscriptionInput>,
+) -> Result<impl Responder> {
+    let mut payload = payload.into_inner();
+    let mut conn = data.pg.acquire().await?;
+    let task_trigger_id = validate_input(&mut conn, &auth, &mut payload).await?;
+
+    let chat_subscription_id = ChatSubscriptionId::new();
+    sqlx::query!(
+        "INSERT INTO chat_subscriptions
+            (chat_subscription_id, org_id, account_id, channel_id, command, task_trigger_id,
+                run_as_user, enabled)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        chat_subscription_id.0,
+        auth.org_id().0,
+        payload.account_id.0,
+        payload.channel_id,
+        payload.command,
+        task_trigger_id.0,
+        auth.user_id().0,
+        payload.enabled
+    )
+    .execute(&mut conn)
+    .await?;
+
+    let subscription = get_subscription(&mut conn, &auth, &chat_subscription_id).await?;
+    Ok(HttpResponse::Created().json(subscription))
+}
+
+#[put("/chat_subscriptions/{chat_subscription_id}")]
+async fn update_subscription(
+    data: AppStateData,
+    auth: Authenticated,
+    chat_subscription_id: Path<ChatSubscriptionId>,
+    payload: web::Json<ChatSubscriptionInput>,
+) -> Result<impl Responder> {
+    let mut payload = payload.into_inner();
+    let mut conn = data.pg.acquire().await?;
+    let task_trigger_id = validate_input(&mut conn, &auth, &mut payload).await?;
+
+    let result = sqlx::query!(
+        "UPDATE chat_subscriptions SET
+            account_id=$3, channel_id=$4, command=$5, task_trigger_id=$6, enabled=$7,
+            modified=now()
+        WHERE chat_subscription_id=$1 AND org_id=$2",
+        chat_subscription_id.0,
+        auth.org_id().0,
+        payload.account_id.0,
+        payload.channel_id,
+        payload.command,
+        task_trigger_id.0,
+        payload.enabled
+    )
+    .execute(&mut conn)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    let subscription = get_subscription(&mut conn, &auth, &chat_subscription_id).await?;
+    Ok(HttpResponse::Ok().json(subscription))
+}
+
+#[delete("/chat_subscriptions/{chat_subscription_id}")]
+async fn delete_subscription(
+    data: AppStateData,
+    auth: Authenticated,
+    chat_subscription_id: Path<ChatSubscriptionId>,
+) -> Result<impl Responder> {
+    let result = sqlx::query!(
+        "DELETE FROM chat_subscriptions WHERE chat_subscription_id=$1 AND org_id=$2",
+        chat_subscription_id.0,
+        auth.org_id().0
+    )
+    .execute(&data.pg)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_subscriptions)
+        .service(get_subscription_handler)
+        .service(new_subscription)
+        .service(update_subscription)
+        .service(delete_subscription);
+}
@@ -0,0 +1,31 @@
+//! The org's policy for outbound requests from task scripts and actions.
+
+use actix_web::{get, put, web, HttpResponse, Responder};
+use ergo_auth::Authenticated;
+use ergo_tasks::egress::{self, EgressPolicy};
+
+use crate::{error::Result, web_app_server::AppStateData};
+
+#[get("/org/egress_policy")]
+pub async fn get_egress_policy(data: AppStateData, auth: Authenticated) -> Result<impl Responder> {
+    let mut conn = data.pg.acquire().await?;
+    let policy = egress::get_policy(&mut conn, auth.org_id()).await?;
+    Ok(HttpResponse::Ok().json(policy))
+}
+
+#[put("/org/egress_policy")]
+pub async fn set_egress_policy(
+    data: AppStateData,
+    auth: Authenticated,
+    payload: web::Json<EgressPolicy>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+
+    let mut conn = data.pg.acquire().await?;
+    egress::set_policy(&mut conn, auth.org_id(), &payload).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_egress_policy).service(set_egress_policy);
+}
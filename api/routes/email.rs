@@ -0,0 +1,346 @@
+//! Mailboxes that send the email they receive to a task trigger. A mailbox with an IMAP account
+//! is polled by the server, and any mailbox can also receive messages from an inbound mail
+//! provider that posts the raw message to `/email_mailboxes/{id}/inbound`.
+
+use actix_web::{
+    delete, get, post, put,
+    web::{self, Bytes, Path},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use ergo_auth::Authenticated;
+use ergo_database::object_id::{AccountId, EmailMailboxId, TaskId, TaskTriggerId};
+use ergo_tasks::inputs::email::{enqueue_email, mailbox_target, parse_email};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+
+use super::tasks::TaskTriggerResponse;
+use crate::{
+    backend_data::BackendAppStateData,
+    error::{Error, Result},
+    web_app_server::AppStateData,
+};
+
+/// The largest raw message that the inbound route accepts.
+const MAX_INBOUND_BYTES: usize = 25 * 1024 * 1024;
+
+/// Mailboxes can't be polled more often than this.
+const MIN_POLL_INTERVAL_SECS: i32 = 60;
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct EmailMailbox {
+    pub email_mailbox_id: EmailMailboxId,
+    pub name: String,
+    pub task_id: TaskId,
+    pub trigger: String,
+    pub account_id: Option<AccountId>,
+    pub folder: String,
+    pub poll_interval_secs: i32,
+    pub enabled: bool,
+    pub last_polled: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub created: DateTime<Utc>,
+    pub modified: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct EmailMailboxInput {
+    pub name: String,
+    pub task_id: TaskId,
+    /// The local ID of the task trigger to send messages to.
+    pub trigger: String,
+    /// The IMAP account to poll. Without one, the mailbox only receives inbound webhooks.
+    pub account_id: Option<AccountId>,
+    #[serde(default = "default_folder")]
+    pub folder: String,
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval_secs: i32,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_folder() -> String {
+    "INBOX".to_string()
+}
+
+fn default_poll_interval() -> i32 {
+    300
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+async fn get_mailbox(
+    tx: &mut PgConnection,
+    auth: &Authenticated,
+    email_mailbox_id: &EmailMailboxId,
+) -> Result<EmailMailbox> {
+    sqlx::query_as!(
+        EmailMailbox,
+        r##"SELECT email_mailbox_id AS "email_mailbox_id: EmailMailboxId",
+            mb.name,
+            tt.task_id AS "task_id: TaskId",
+            tt.task_trigger_local_id AS trigger,
+            account_id AS "account_id: AccountId",
+            folder, poll_interval_secs, mb.enabled, last_polled, last_error,
+            mb.created, mb.modified
+        FROM email_mailboxes mb
+        JOIN task_triggers tt USING (task_trigger_id)
+        WHERE email_mailbox_id=$1 AND mb.org_id=$2"##,
+        email_mailbox_id.0,
+        auth.org_id().0
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::NotFound)
+}
+
+/// Check the input and look up the task trigger that it refers to.
+async fn validate_input(
+    tx: &mut PgConnection,
+    auth: &Authenticated,
+    input: &EmailMailboxInput,
+) -> Result<TaskTriggerId> {
+    let mut errors = Vec::new();
+    if input.name.is_empty() {
+        errors.push("name must not be empty".to_string());
+    }
+    if input.folder.is_empty() {
+        errors.push("folder must not be empty".to_string());
+    }
+    if input.poll_interval_secs < MIN_POLL_INTERVAL_SECS {
+        errors.push(format!(
+            "poll_interval_secs must be at least {}",
+            MIN_POLL_INTERVAL_SECS
+        ));
+    }
+    if !errors.is_empty() {
+        return Err(Error::ValidationError(errors));
+    }
+
+    let task_trigger_id = sqlx::query_scalar!(
+        r##"SELECT task_trigger_id AS "task_trigger_id: TaskTriggerId"
+        FROM task_triggers
+        JOIN tasks USING (task_id)
+        WHERE task_id=$1 AND task_trigger_local_id=$2 AND org_id=$3 AND NOT deleted
+            AND EXISTS(SELECT 1 FROM user_entity_permissions
+                WHERE permissioned_object IN (uuid_nil(), task_id)
+                AND user_entity_id=ANY($4)
+                AND permission_type='write')"##,
+        input.task_id.0,
+        input.trigger,
+        auth.org_id().0,
+        auth.user_entity_ids().as_slice()
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    if let Some(account_id) = input.account_id.as_ref() {
+        let account_exists = sqlx::query_scalar!(
+            "SELECT EXISTS(SELECT 1 FROM accounts WHERE account_id=$1 AND org_id=$2)",
+            account_id.0,
+            auth.org_id().0
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .unwrap_or(false);
+
+        if !account_exists {
+            return Err(Error::ValidationError(vec![format!(
+                "Account {} does not exist",
+                account_id
+            )]));
+        }
+    }
+
+    Ok(task_trigger_id)
+}
+
+#[get("/email_mailboxes")]
+async fn list_mailboxes(data: AppStateData, auth: Authenticated) -> Result<impl Responder> {
+    let mailboxes = sqlx::query_as!(
+        EmailMailbox,
+        r##"SELECT email_mailbox_id AS "email_mailbox_id: EmailMailboxId",
+            mb.name,
+            tt.task_id AS "task_id: TaskId",
+            tt.task_trigger_local_id AS trigger,
+            account_id AS "account_id: AccountId",
+            folder, poll_interval_secs, mb.enabled, last_polled, last_error,
+            mb.created, mb.modified
+        FROM email_mailboxes mb
+        JOIN task_triggers tt USING (task_trigger_id)
+        WHERE mb.org_id=$1
+        ORDER BY mb.name"##,
+        auth.org_id().0
+    )
+    .fetch_all(&data.pg)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(mailboxes))
+}
+
+#[get("/email_mailboxes/{email_mailbox_id}")]
+async fn get_mailbox_handler(
+    data: AppStateData,
+    auth: Authenticated,
+    email_mailbox_id: Path<EmailMailboxId>,
+) -> Result<impl Responder> {
+    let mut conn = data.pg.acquire().await?;
+    let mailbox = get_mailbox(&mut conn, &auth, &email_mailbox_id).await?;
+    Ok(HttpResponse::Ok().json(mailbox))
+}
+
+#[post("/email_mailboxes")]
+async fn new_mailbox(
+    data: AppStateData,
+    auth: Authenticated,
+    payload: web::Json<EmailMailboxInput>,
+) -> Result<impl Responder> {
+    let payload = payload.into_inner();
+    let mut conn = data.pg.acquire().await?;
+    let task_trigger_id = validate_input(&mut conn, &auth, &payload).await?;
+
+    let email_mailbox_id = EmailMailboxId::new();
+    sqlx::query!(
+        "INSERT INTO email_mailboxes
+            (email_mailbox_id, org_id, name, task_trigger_id, account_id, folder,
+                poll_interval_secs, run_as_user, enabled)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+        email_mailbox_id.0,
+        auth.org_id().0,
+        payload.name,
+        task_trigger_id.0,
+        payload.account_id.as_ref().map(|id| id.0),
+        payload.folder,
+        payload.poll_interval_secs,
+        auth.user_id().0,
+        payload.enabled
+    )
+    .execute(&mut conn)
+    .await?;
+
+    let mailbox = get_mailbox(&mut conn, &auth, &email_mailbox_id).await?;
+    Ok(HttpResponse::Created().json(mailbox))
+}
+
+#[put("/email_mailboxes/{email_mailbox_id}")]
+async fn update_mailbox(
+    data: AppStateData,
+    auth: Authenticated,
+    email_mailbox_id: Path<EmailMailboxId>,
+    payload: web::Json<EmailMailboxInput>,
+) -> Result<impl Responder> {
+    let payload = payload.into_inner();
+    let mut conn = data.pg.acquire().await?;
+    let task_trigger_id = validate_input(&mut conn, &auth, &payload).await?;
+
+    // Changing the account or folder makes the saved IMAP position meaningless.
+    let result = sqlx::query!(
+        "UPDATE email_mailboxes SET
+            name=$3, task_trigger_id=$4, account_id=$5, folder=$6, poll_interval_secs=$7,
+            enabled=$8, modified=now(),
+            uid_validity = CASE WHEN account_id IS NOT DISTINCT FROM $5 AND folder=$6
+                THEN uid_validity ELSE NULL END,
+            last_uid = CASE WHEN account_id IS NOT DISTINCT FROM $5 AND folder=$6
+                THEN last_uid ELSE NULL END
+        WHERE email_mailbox_id=$1 AND org_id=$2",
+        email_mailbox_id.0,
+        auth.org_id().0,
+        payload.name,
+        task_trigger_id.0,
+        payload.account_id.as_ref().map(|id| id.0),
+        payload.folder,
+        payload.poll_interval_secs,
+        payload.enabled
+    )
+    .execute(&mut conn)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    let mailbox = get_mailbox(&mut conn, &auth, &email_mailbox_id).await?;
+    Ok(HttpResponse::Ok().json(mailbox))
+}
+
+#[delete("/email_mailboxes/{email_mailbox_id}")]
+async fn delete_mailbox(
+    data: AppStateData,
+    auth: Authenticated,
+    email_mailbox_id: Path<EmailMailboxId>,
+) -> Result<impl Responder> {
+    let result = sqlx::query!(
+        "DELETE FROM email_mailboxes WHERE email_mailbox_id=$1 AND org_id=$2",
+        email_mailbox_id.0,
+        auth.org_id().0
+    )
+    .execute(&data.pg)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Receive a raw RFC 5322 message from an inbound mail provider.
+async fn inbound_email(
+    data: BackendAppStateData,
+    auth: Authenticated,
+    email_mailbox_id: Path<EmailMailboxId>,
+    body: Bytes,
+) -> Result<impl Responder> {
+    let mut conn = data.pg.acquire().await?;
+    let target = mailbox_target(&mut conn, &email_mailbox_id)
+        .await?
+        .filter(|target| &target.org_id == auth.org_id())
+        .ok_or(Error::NotFound)?;
+
+    let allowed = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM user_entity_permissions
+            WHERE user_entity_id = ANY($1)
+            AND permission_type = 'trigger_event'
+            AND permissioned_object IN (uuid_nil(), $2, $3))",
+        auth.user_entity_ids().as_slice(),
+        target.task_trigger_id.0,
+        target.task_id.0
+    )
+    .fetch_one(&mut conn)
+    .await?
+    .unwrap_or(false);
+
+    if !allowed {
+        return Err(Error::NotFound);
+    }
+
+    let payload = parse_email(&body)?;
+    let log_id = enqueue_email(
+        &mut conn,
+        Some(data.notifications.clone()),
+        data.redis_key_prefix.as_deref(),
+        &target,
+        auth.user_id(),
+        &payload,
+    )
+    .await?;
+
+    Ok(HttpResponse::Accepted().json(TaskTriggerResponse { log_id }))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_mailboxes)
+        .service(get_mailbox_handler)
+        .service(new_mailbox)
+        .service(update_mailbox)
+        .service(delete_mailbox)
+        .service(
+            web::resource("/email_mailboxes/{email_mailbox_id}/inbound")
+                .app_data(web::PayloadConfig::new(MAX_INBOUND_BYTES))
+                .route(web::post().to(inbound_email)),
+        );
+}
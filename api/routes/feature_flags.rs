@@ -0,0 +1,71 @@
+//! The org's feature flags. Orgs can override the global setting for each flag.
+
+use actix_web::{delete, get, put, web, HttpResponse, Responder};
+use ergo_auth::Authenticated;
+use ergo_tasks::feature_flags::{self, FeatureFlag, FeatureFlagInput};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Error, Result},
+    feature_flags::Flags,
+    web_app_server::AppStateData,
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FeatureFlagsResponse {
+    /// The flags that are enabled for the org.
+    pub enabled: Vec<String>,
+    /// The org's overrides of the global settings.
+    pub overrides: Vec<FeatureFlag>,
+}
+
+#[get("/org/feature_flags")]
+async fn list_feature_flags(
+    data: AppStateData,
+    auth: Authenticated,
+    flags: Flags,
+) -> Result<impl Responder> {
+    let mut conn = data.pg.acquire().await?;
+    let overrides = feature_flags::list_flags(&mut conn, Some(auth.org_id())).await?;
+    let enabled = flags.enabled().into_iter().map(String::from).collect();
+    Ok(HttpResponse::Ok().json(FeatureFlagsResponse { enabled, overrides }))
+}
+
+#[put("/org/feature_flags/{flag}")]
+async fn set_feature_flag(
+    data: AppStateData,
+    auth: Authenticated,
+    flag: web::Path<String>,
+    payload: web::Json<FeatureFlagInput>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+
+    let mut conn = data.pg.acquire().await?;
+    feature_flags::set_org_flag(&mut conn, auth.org_id(), &flag, &payload).await?;
+    data.feature_flags.invalidate(Some(auth.org_id())).await;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[delete("/org/feature_flags/{flag}")]
+async fn delete_feature_flag(
+    data: AppStateData,
+    auth: Authenticated,
+    flag: web::Path<String>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+
+    let mut conn = data.pg.acquire().await?;
+    let deleted = feature_flags::delete_org_flag(&mut conn, auth.org_id(), &flag).await?;
+    if !deleted {
+        return Err(Error::NotFound);
+    }
+
+    data.feature_flags.invalidate(Some(auth.org_id())).await;
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_feature_flags)
+        .service(set_feature_flag)
+        .service(delete_feature_flag);
+}
@@ -0,0 +1,295 @@
+//! File watches send each new file in a directory or S3 prefix to a task trigger. Directories
+//! are relative to the server's `FILE_WATCH_ROOT`, and S3 watches use the credentials from an
+//! `s3` account. The server's file watcher picks up changes to the watches within a minute.
+
+use actix_web::{
+    delete, get, post, put,
+    web::{self, Path},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use ergo_auth::Authenticated;
+use ergo_database::object_id::{AccountId, FileWatchId, TaskId, TaskTriggerId};
+use ergo_tasks::inputs::file_watch::{valid_relative_path, watch_root, FileParse, FileWatchSource};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+
+use crate::{
+    error::{Error, Result},
+    web_app_server::AppStateData,
+};
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct FileWatch {
+    pub file_watch_id: FileWatchId,
+    pub source: FileWatchSource,
+    pub account_id: Option<AccountId>,
+    pub bucket: Option<String>,
+    pub path: String,
+    pub parse: FileParse,
+    pub task_id: TaskId,
+    pub trigger: String,
+    pub enabled: bool,
+    pub created: DateTime<Utc>,
+    pub modified: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct FileWatchInput {
+    pub source: FileWatchSource,
+    /// For S3 watches, an account with the `s3` account type.
+    pub account_id: Option<AccountId>,
+    /// The bucket to watch, for S3 watches.
+    pub bucket: Option<String>,
+    /// For a directory watch, the directory relative to the server's file watch root. For an S3
+    /// watch, the key prefix to watch.
+    #[serde(default)]
+    pub path: String,
+    /// How to parse each file into the input payload.
+    #[serde(default)]
+    pub parse: FileParse,
+    pub task_id: TaskId,
+    /// The local ID of the task trigger to send files to.
+    pub trigger: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+async fn get_watch(
+    tx: &mut PgConnection,
+    auth: &Authenticated,
+    file_watch_id: &FileWatchId,
+) -> Result<FileWatch> {
+    sqlx::query_as!(
+        FileWatch,
+        r##"SELECT file_watch_id AS "file_watch_id: FileWatchId",
+            source AS "source: FileWatchSource",
+            account_id AS "account_id: AccountId",
+            bucket, path,
+            parse AS "parse: FileParse",
+            tt.task_id AS "task_id: TaskId",
+            tt.task_trigger_local_id AS trigger,
+            fw.enabled, fw.created, fw.modified
+        FROM file_watches fw
+        JOIN task_triggers tt USING (task_trigger_id)
+        WHERE file_watch_id=$1 AND fw.org_id=$2"##,
+        file_watch_id.0,
+        auth.org_id().0
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::NotFound)
+}
+
+/// Check the input and look up the task trigger that it refers to.
+async fn validate_input(
+    tx: &mut PgConnection,
+    auth: &Authenticated,
+    input: &mut FileWatchInput,
+) -> Result<TaskTriggerId> {
+    let mut errors = Vec::new();
+    match input.source {
+        FileWatchSource::Directory => {
+            if watch_root().is_none() {
+                errors.push("Directory watches are not enabled on this server".to_string());
+            }
+            if !valid_relative_path(&input.path) {
+                errors.push(format!("Invalid directory {}", input.path));
+            }
+
+            input.account_id = None;
+            input.bucket = None;
+        }
+        FileWatchSource::S3 => {
+            if input.bucket.as_deref().unwrap_or_default().is_empty() {
+                errors.push("S3 watches require a bucket".to_string());
+            }
+
+            let is_s3_account = match input.account_id.as_ref() {
+                Some(account_id) => sqlx::query_scalar!(
+                    "SELECT EXISTS(SELECT 1 FROM accounts
+                        WHERE account_id=$1 AND org_id=$2 AND account_type_id='s3')",
+                    account_id.0,
+                    auth.org_id().0
+                )
+                .fetch_one(&mut *tx)
+                .await?
+                .unwrap_or(false),
+                None => false,
+            };
+            if !is_s3_account {
+                errors.push("S3 watches require an S3 account".to_string());
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(Error::ValidationError(errors));
+    }
+
+    let task_trigger_id = sqlx::query_scalar!(
+        r##"SELECT task_trigger_id AS "task_trigger_id: TaskTriggerId"
+        FROM task_triggers
+        JOIN tasks USING (task_id)
+        WHERE task_id=$1 AND task_trigger_local_id=$2 AND org_id=$3 AND NOT deleted
+            AND EXISTS(SELECT 1 FROM user_entity_permissions
+                WHERE permissioned_object IN (uuid_nil(), task_id)
+                AND user_entity_id=ANY($4)
+                AND permission_type='write')"##,
+        input.task_id.0,
+        input.trigger,
+        auth.org_id().0,
+        auth.user_entity_ids().as_slice()
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    Ok(task_trigger_id)
+}
+
+#[get("/file_watches")]
+async fn list_watches(data: AppStateData, auth: Authenticated) -> Result<impl Responder> {
+    let watches = sqlx::query_as!(
+        FileWatch,
+        r##"SELECT file_watch_id AS "file_watch_id: FileWatchId",
+            source AS "source: FileWatchSource",
+            account_id AS "account_id: AccountId",
+            bucket, path,
+            parse AS "parse: FileParse",
+            tt.task_id AS "task_id: TaskId",
+            tt.task_trigger_local_id AS trigger,
+            fw.enabled, fw.created, fw.modified
+        FROM file_watches fw
+        JOIN task_triggers tt USING (task_trigger_id)
+        WHERE fw.org_id=$1
+        ORDER BY fw.bucket NULLS FIRST, fw.path"##,
+        auth.org_id().0
+    )
+    .fetch_all(&data.pg)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(watches))
+}
+
+#[get("/file_watches/{file_watch_id}")]
+async fn get_watch_handler(
+    data: AppStateData,
+    auth: Authenticated,
+    file_watch_id: Path<FileWatchId>,
+) -> Result<impl Responder> {
+    let mut conn = data.pg.acquire().await?;
+    let watch = get_watch(&mut conn, &auth, &file_watch_id).await?;
+    Ok(HttpResponse::Ok().json(watch))
+}
+
+#[post("/file_watches")]
+async fn new_watch(
+    data: AppStateData,
+    auth: Authenticated,
+    payload: web::Json<FileWatchInput>,
+) -> Result<impl Responder> {
+    let mut payload = payload.into_inner();
+    let mut conn = data.pg.acquire().await?;
+    let task_trigger_id = validate_input(&mut conn, &auth, &mut payload).await?;
+
+    let file_watch_id = FileWatchId::new();
+    sqlx::query!(
+        "INSERT INTO file_watches
+            (file_watch_id, org_id, source, account_id, bucket, path, parse, task_trigger_id,
+                run_as_user, enabled)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+        file_watch_id.0,
+        auth.org_id().0,
+        payload.source as _,
+        payload.account_id.as_ref().map(|id| id.0),
+        payload.bucket,
+        payload.path,
+        payload.parse as _,
+        task_trigger_id.0,
+        auth.user_id().0,
+        payload.enabled
+    )
+    .execute(&mut conn)
+    .await?;
+
+    let watch = get_watch(&mut conn, &auth, &file_watch_id).await?;
+    Ok(HttpResponse::Created().json(watch))
+}
+
+#[put("/file_watches/{file_watch_id}")]
+async fn update_watch(
+    data: AppStateData,
+    auth: Authenticated,
+    file_watch_id: Path<FileWatchId>,
+    payload: web::Json<FileWatchInput>,
+) -> Result<impl Responder> {
+    let mut payload = payload.into_inner();
+    let mut conn = data.pg.acquire().await?;
+    let task_trigger_id = validate_input(&mut conn, &auth, &mut payload).await?;
+
+    // Files that are already in a new location aren't sent when the watch moves, the same as
+    // when it is created.
+    let result = sqlx::query!(
+        "UPDATE file_watches SET
+            source=$3, account_id=$4, bucket=$5, path=$6, parse=$7, task_trigger_id=$8,
+            enabled=$9, modified=now(),
+            baseline_done = baseline_done
+                AND source = $3
+                AND bucket IS NOT DISTINCT FROM $5
+                AND path = $6
+        WHERE file_watch_id=$1 AND org_id=$2",
+        file_watch_id.0,
+        auth.org_id().0,
+        payload.source as _,
+        payload.account_id.as_ref().map(|id| id.0),
+        payload.bucket,
+        payload.path,
+        payload.parse as _,
+        task_trigger_id.0,
+        payload.enabled
+    )
+    .execute(&mut conn)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    let watch = get_watch(&mut conn, &auth, &file_watch_id).await?;
+    Ok(HttpResponse::Ok().json(watch))
+}
+
+#[delete("/file_watches/{file_watch_id}")]
+async fn delete_watch(
+    data: AppStateData,
+    auth: Authenticated,
+    file_watch_id: Path<FileWatchId>,
+) -> Result<impl Responder> {
+    let result = sqlx::query!(
+        "DELETE FROM file_watches WHERE file_watch_id=$1 AND org_id=$2",
+        file_watch_id.0,
+        auth.org_id().0
+    )
+    .execute(&data.pg)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_watches)
+        .service(get_watch_handler)
+        .service(new_watch)
+        .service(update_watch)
+        .service(delete_watch);
+}
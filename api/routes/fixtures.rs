@@ -0,0 +1,369 @@
+//! Fixture packs are curated sets of inputs, actions, tasks, and sample run history that can be
+//! loaded into a database for demos, documentation, and tests. The objects are loaded through
+//! the same reconciliation as [apply](super::apply), so loading a pack again updates the
+//! objects in place.
+
+use actix_web::{post, web, HttpResponse, Responder};
+use ergo_auth::Authenticated;
+use ergo_database::{
+    new_uuid,
+    object_id::{ActionCategoryId, TaskId, TaskTriggerId},
+};
+use ergo_tasks::{actions::ActionStatus, dependents::InvalidDependentPolicy, inputs::InputStatus};
+use fxhash::FxHashMap;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::{Connection, PgConnection};
+use uuid::Uuid;
+
+use super::{
+    apply::{apply_bundle, ApplyBundle, ApplyChange, ApplyOperation, ObjectKind},
+    inputs::InputPayload,
+};
+use crate::{
+    backend_data::{BackendAppState, BackendAppStateData},
+    error::{Error, Result},
+    web_app_server::{AppState, AppStateData},
+};
+
+/// The fixture packs built into the server.
+pub const FIXTURES: &[(&str, &str)] = &[("demo", include_str!("../../data/fixtures/demo.yaml"))];
+
+#[derive(Debug, Deserialize)]
+struct FixturePack {
+    /// The category for actions that don't specify one.
+    action_category: String,
+    #[serde(default)]
+    inputs: Vec<InputPayload>,
+    /// Actions and tasks are kept as JSON until the category and trigger IDs are filled in.
+    #[serde(default)]
+    actions: Vec<serde_json::Value>,
+    #[serde(default)]
+    tasks: Vec<serde_json::Value>,
+    #[serde(default)]
+    logs: Vec<FixtureLog>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FixtureLog {
+    task: String,
+    trigger: String,
+    payload: serde_json::Value,
+    status: InputStatus,
+    #[serde(default)]
+    info: Option<serde_json::Value>,
+    #[serde(default)]
+    minutes_ago: i32,
+    #[serde(default)]
+    actions: Vec<FixtureActionLog>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FixtureActionLog {
+    action: String,
+    payload: serde_json::Value,
+    status: ActionStatus,
+    #[serde(default)]
+    result: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct FixtureResult {
+    pub fixture: String,
+    pub changes: Vec<ApplyChange>,
+    /// The number of sample inputs added to the log. History is only added for tasks that
+    /// this load created, so loading a pack again doesn't duplicate it.
+    pub logs: usize,
+}
+
+impl FixtureResult {
+    /// The ID of an object from the pack.
+    pub fn id(&self, kind: ObjectKind, name: &str) -> Option<&str> {
+        self.changes
+            .iter()
+            .find(|c| c.kind == kind && c.name == name)
+            .and_then(|c| c.id.as_deref())
+    }
+}
+
+fn parse_pack(name: &str) -> Result<FixturePack> {
+    let (_, contents) = FIXTURES
+        .iter()
+        .find(|(fixture, _)| *fixture == name)
+        .ok_or(Error::NotFound)?;
+
+    serde_yaml::from_str(contents)
+        .map_err(|e| Error::StringError(format!("Fixture {}: {}", name, e)))
+}
+
+async fn action_category(tx: &mut PgConnection, name: &str) -> Result<ActionCategoryId> {
+    let existing = sqlx::query_scalar!(
+        r##"SELECT action_category_id AS "action_category_id: ActionCategoryId"
+        FROM action_categories WHERE name=$1
+        ORDER BY action_category_id LIMIT 1"##,
+        name
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if let Some(id) = existing {
+        return Ok(id);
+    }
+
+    let id = ActionCategoryId::new();
+    sqlx::query!(
+        "INSERT INTO action_categories (action_category_id, name) VALUES ($1, $2)",
+        id.0,
+        name
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(id)
+}
+
+/// The trigger IDs of the organization's tasks, keyed by task name and trigger local ID.
+async fn task_triggers(
+    tx: &mut PgConnection,
+    auth: &Authenticated,
+) -> Result<FxHashMap<(String, String), (TaskId, TaskTriggerId)>> {
+    let rows = sqlx::query!(
+        r##"SELECT tasks.name,
+            task_id AS "task_id: TaskId",
+            task_trigger_local_id,
+            task_trigger_id AS "task_trigger_id: TaskTriggerId"
+        FROM task_triggers
+        JOIN tasks USING (task_id)
+        WHERE tasks.org_id=$1 AND NOT tasks.deleted"##,
+        auth.org_id().0
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| {
+            (
+                (r.name, r.task_trigger_local_id),
+                (r.task_id, r.task_trigger_id),
+            )
+        })
+        .collect())
+}
+
+/// Point the trigger nodes of a DataFlow task at the task trigger with the same name. Triggers
+/// that don't exist yet get a placeholder ID, and the return value indicates whether any did.
+fn link_trigger_nodes(
+    task: &mut serde_json::Value,
+    triggers: &FxHashMap<(String, String), (TaskId, TaskTriggerId)>,
+) -> bool {
+    if task["compiled"]["type"] != "DataFlow" {
+        return false;
+    }
+
+    let task_name = task["name"].as_str().unwrap_or_default().to_string();
+    let nodes = match task["compiled"]["data"]["nodes"].as_array_mut() {
+        Some(nodes) => nodes,
+        None => return false,
+    };
+
+    let mut missing = false;
+    for node in nodes {
+        if node["func"]["type"] != "trigger" {
+            continue;
+        }
+
+        let node_name = node["name"].as_str().unwrap_or_default().to_string();
+        let trigger_id = match triggers.get(&(task_name.clone(), node_name)) {
+            Some((_, trigger_id)) => trigger_id.clone(),
+            None => {
+                missing = true;
+                TaskTriggerId::from_uuid(Uuid::nil())
+            }
+        };
+
+        node["func"]["task_trigger_id"] = serde_json::json!(trigger_id);
+    }
+
+    missing
+}
+
+async fn add_logs(
+    tx: &mut PgConnection,
+    logs: &[FixtureLog],
+    triggers: &FxHashMap<(String, String), (TaskId, TaskTriggerId)>,
+) -> Result<()> {
+    for log in logs {
+        let (task_id, task_trigger_id) = triggers
+            .get(&(log.task.clone(), log.trigger.clone()))
+            .ok_or_else(|| {
+                Error::StringError(format!(
+                    "Fixture log refers to unknown trigger {} on task {}",
+                    log.trigger, log.task
+                ))
+            })?;
+
+        let inputs_log_id = new_uuid();
+        sqlx::query!(
+            r##"INSERT INTO inputs_log
+                (inputs_log_id, task_trigger_id, task_id, task_trigger_local_id, status, payload,
+                    info, queue_job_id, created, updated)
+            VALUES
+                ($1, $2, $3, $4, $5, $6, $7, '',
+                    now() - make_interval(mins => $8), now() - make_interval(mins => $8))"##,
+            inputs_log_id,
+            task_trigger_id.0,
+            task_id.0,
+            log.trigger,
+            log.status as _,
+            log.payload,
+            log.info,
+            log.minutes_ago
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        for action in &log.actions {
+            sqlx::query!(
+                r##"INSERT INTO actions_log
+                    (actions_log_id, inputs_log_id, task_id, task_action_local_id, payload, result,
+                        status, created, updated)
+                VALUES
+                    ($1, $2, $3, $4, $5, $6, $7,
+                        now() - make_interval(mins => $8), now() - make_interval(mins => $8))"##,
+                new_uuid(),
+                inputs_log_id,
+                task_id.0,
+                action.action,
+                action.payload,
+                action.result,
+                action.status as _,
+                log.minutes_ago
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Load a fixture pack into the authenticated user's organization.
+pub(crate) async fn load_fixture(
+    data: &AppState,
+    backend_data: &BackendAppState,
+    auth: &Authenticated,
+    name: &str,
+) -> Result<FixtureResult> {
+    let pack = parse_pack(name)?;
+
+    let mut conn = data.pg.acquire().await?;
+    let mut tx = conn.begin().await?;
+    let category_id = action_category(&mut tx, &pack.action_category).await?;
+    let existing_triggers = task_triggers(&mut tx, auth).await?;
+    tx.commit().await?;
+
+    let mut actions = pack.actions;
+    for action in actions.iter_mut() {
+        if action.get("action_category_id").is_none() {
+            action["action_category_id"] = serde_json::json!(category_id);
+        }
+    }
+
+    let mut tasks = pack.tasks;
+    let mut unlinked = Vec::new();
+    for (i, task) in tasks.iter_mut().enumerate() {
+        if link_trigger_nodes(task, &existing_triggers) {
+            unlinked.push(i);
+        }
+    }
+
+    let bundle = ApplyBundle {
+        inputs: pack.inputs,
+        actions: serde_json::from_value(serde_json::Value::Array(actions))?,
+        tasks: serde_json::from_value(serde_json::Value::Array(tasks.clone()))?,
+    };
+
+    let applied = apply_bundle(
+        data,
+        backend_data,
+        auth,
+        bundle,
+        false,
+        InvalidDependentPolicy::default(),
+    )
+    .await?;
+    let mut result = FixtureResult {
+        fixture: name.to_string(),
+        changes: applied.changes,
+        logs: 0,
+    };
+
+    let mut conn = data.pg.acquire().await?;
+    let mut tx = conn.begin().await?;
+    let triggers = task_triggers(&mut tx, auth).await?;
+
+    // The triggers of new DataFlow tasks only have IDs now that the tasks exist.
+    for i in unlinked {
+        let task = &mut tasks[i];
+        link_trigger_nodes(task, &triggers);
+
+        let task_name = task["name"].as_str().unwrap_or_default();
+        let task_id = result
+            .id(ObjectKind::Task, task_name)
+            .and_then(|id| id.parse::<TaskId>().ok())
+            .ok_or_else(|| {
+                Error::StringError(format!("Fixture task {} was not saved", task_name))
+            })?;
+
+        sqlx::query!(
+            "UPDATE task_templates SET compiled=$2
+            FROM tasks
+            WHERE tasks.task_id=$1
+                AND task_templates.task_template_id=tasks.task_template_id
+                AND task_templates.task_template_version=tasks.task_template_version",
+            task_id.0,
+            task["compiled"]
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    let created_tasks = result
+        .changes
+        .iter()
+        .filter(|c| c.kind == ObjectKind::Task && c.operation == ApplyOperation::Create)
+        .map(|c| c.name.as_str())
+        .collect::<Vec<_>>();
+    let logs = pack
+        .logs
+        .into_iter()
+        .filter(|log| created_tasks.contains(&log.task.as_str()))
+        .collect::<Vec<_>>();
+
+    let mut conn = backend_data.pg.acquire().await?;
+    let mut tx = conn.begin().await?;
+    add_logs(&mut tx, &logs, &triggers).await?;
+    tx.commit().await?;
+
+    result.logs = logs.len();
+    Ok(result)
+}
+
+#[post("/fixtures/{name}")]
+async fn load(
+    data: AppStateData,
+    backend_data: BackendAppStateData,
+    auth: Authenticated,
+    name: web::Path<String>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+
+    let result = load_fixture(&data, &backend_data, &auth, &name).await?;
+    Ok(HttpResponse::Ok().json(result))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(load);
+}
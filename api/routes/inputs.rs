@@ -5,12 +5,21 @@ use actix_web::{
 };
 use ergo_auth::Authenticated;
 use ergo_database::object_id::{InputCategoryId, InputId};
-use ergo_tasks::inputs::Input;
+use ergo_tasks::{
+    dependents::{apply_dependent_validation, validate_input_dependents},
+    inputs::{form::payload_form, Input},
+};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use sqlx::Connection;
-
-use crate::{error::Result, web_app_server::AppStateData};
+use sqlx::{Connection, PgConnection};
+
+use crate::{
+    backend_data::BackendAppStateData,
+    error::{Error, Result},
+    routes::tasks::DependentsQuery,
+    transaction::RequestTx,
+    web_app_server::AppStateData,
+};
 
 #[derive(Debug, Deserialize, JsonSchema, Serialize)]
 pub struct InputPayload {
@@ -47,9 +56,23 @@ pub async fn list_inputs(data: AppStateData) -> Result<impl Responder> {
     Ok(HttpResponse::Ok().json(inputs))
 }
 
+/// Describe a form for entering the input's payload.
+#[get("/inputs/{input_id}/form")]
+pub async fn get_input_form(data: AppStateData, input_id: Path<InputId>) -> Result<impl Responder> {
+    let schema = sqlx::query_scalar!(
+        "SELECT payload_schema FROM inputs WHERE input_id=$1",
+        input_id.0
+    )
+    .fetch_optional(&data.pg)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    Ok(HttpResponse::Ok().json(payload_form(&schema)))
+}
+
 #[post("/inputs")]
 pub async fn new_input(
-    data: AppStateData,
+    tx: RequestTx,
     payload: web::Json<InputPayload>,
     auth: Authenticated,
 ) -> Result<impl Responder> {
@@ -60,9 +83,6 @@ pub async fn new_input(
     // Make sure the schema is valid.
     jsonschema::JSONSchema::compile(&payload.payload_schema)?;
 
-    let mut conn = data.pg.acquire().await?;
-    let mut tx = conn.begin().await?;
-
     sqlx::query!(
         "INSERT INTO inputs (input_id, input_category_id, name, description, payload_schema) VALUES
         ($1, $2, $3, $4, $5)",
@@ -72,55 +92,114 @@ pub async fn new_input(
         &payload.description as _,
         &payload.payload_schema
     )
-    .execute(&mut tx)
+    .execute(&mut *tx.lock().await?)
     .await?;
 
-    tx.commit().await?;
-
     Ok(HttpResponse::Created().json(payload))
 }
 
 #[put("/inputs/{input_id}")]
 pub async fn write_input(
     data: AppStateData,
+    backend_data: BackendAppStateData,
     input_id: Path<InputId>,
     payload: web::Json<InputPayload>,
     auth: Authenticated,
+    query: web::Query<DependentsQuery>,
 ) -> Result<impl Responder> {
     auth.expect_admin()?;
 
-    let payload = payload.into_inner();
-    let input_id = input_id.into_inner();
+    let input = payload.into_inner().into_input(input_id.into_inner());
 
     // Make sure the schema is valid.
-    jsonschema::JSONSchema::compile(&payload.payload_schema)?;
+    jsonschema::JSONSchema::compile(&input.payload_schema)?;
 
     let mut conn = data.pg.acquire().await?;
     let mut tx = conn.begin().await?;
+    upsert_input(&mut tx, &input).await?;
+    tx.commit().await?;
+
+    check_input_dependents(&backend_data, &input, &query, &auth).await?;
+
+    Ok(HttpResponse::Ok().json(input))
+}
+
+/// Replace only the input's payload schema, such as with a schema inferred from the payloads
+/// that a trigger has received.
+#[put("/inputs/{input_id}/payload_schema")]
+pub async fn write_input_payload_schema(
+    data: AppStateData,
+    backend_data: BackendAppStateData,
+    input_id: Path<InputId>,
+    payload: web::Json<serde_json::Value>,
+    auth: Authenticated,
+    query: web::Query<DependentsQuery>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+
+    let payload_schema = payload.into_inner();
+    jsonschema::JSONSchema::compile(&payload_schema)?;
+
+    let input = sqlx::query_as!(
+        Input,
+        r##"UPDATE inputs SET payload_schema=$2
+        WHERE input_id=$1
+        RETURNING input_id AS "input_id: InputId",
+            input_category_id AS "input_category_id: InputCategoryId",
+            name, description, payload_schema"##,
+        input_id.0,
+        payload_schema
+    )
+    .fetch_optional(&data.pg)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    check_input_dependents(&backend_data, &input, &query, &auth).await?;
+
+    Ok(HttpResponse::Ok().json(input))
+}
+
+/// Check that the tasks using this input still work with its new schema.
+async fn check_input_dependents(
+    backend_data: &BackendAppStateData,
+    input: &Input,
+    query: &DependentsQuery,
+    auth: &Authenticated,
+) -> Result<()> {
+    let mut conn = backend_data.pg.acquire().await?;
+    let mut tx = conn.begin().await?;
+    let validation =
+        validate_input_dependents(&mut tx, &input.input_id, &input.payload_schema).await?;
+    apply_dependent_validation(
+        &mut tx,
+        Some(&backend_data.notifications),
+        backend_data.redis_key_prefix.as_deref(),
+        &validation,
+        query.invalid_dependents,
+        auth.locale(),
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
 
+/// Create the input, or replace it if it already exists.
+pub(crate) async fn upsert_input(tx: &mut PgConnection, input: &Input) -> Result<()> {
     sqlx::query!(
         "INSERT INTO inputs (input_id, input_category_id, name, description, payload_schema)
         VALUES ($1, $2, $3, $4, $5)
         ON CONFLICT(input_id) DO UPDATE
         SET input_category_id=$2, name=$3, description=$4, payload_schema=$5",
-        &input_id.0,
-        &payload.input_category_id as _,
-        &payload.name,
-        &payload.description as _,
-        &payload.payload_schema
+        &input.input_id.0,
+        &input.input_category_id as _,
+        &input.name,
+        &input.description as _,
+        &input.payload_schema
     )
-    .execute(&mut tx)
+    .execute(&mut *tx)
     .await?;
 
-    tx.commit().await?;
-
-    Ok(HttpResponse::Ok().json(Input {
-        input_id,
-        input_category_id: payload.input_category_id,
-        name: payload.name,
-        description: payload.description,
-        payload_schema: payload.payload_schema,
-    }))
+    Ok(())
 }
 
 #[delete("/inputs/{input_id}")]
@@ -140,7 +219,9 @@ pub async fn delete_input(
 
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.service(list_inputs)
+        .service(get_input_form)
         .service(new_input)
         .service(write_input)
+        .service(write_input_payload_schema)
         .service(delete_input);
 }
@@ -0,0 +1,185 @@
+//! Shared JS libraries that task scripts and `js` actions import with `ergo:lib/<name>`.
+//! Uploading a library adds a new version, and old versions stay available to scripts that pin
+//! them with `ergo:lib/<name>@<version>`.
+
+use actix_web::{
+    delete, get, post,
+    web::{self, Path},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use ergo_auth::Authenticated;
+use ergo_database::object_id::UserId;
+use ergo_tasks::scripting::libraries::valid_library_name;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Error, Result},
+    web_app_server::AppStateData,
+};
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct JsLibrarySummary {
+    pub name: String,
+    /// The latest version of the library.
+    pub version: i32,
+    pub description: Option<String>,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct JsLibrary {
+    pub name: String,
+    pub version: i32,
+    pub description: Option<String>,
+    pub source: String,
+    pub created_by: Option<UserId>,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct JsLibraryInput {
+    pub description: Option<String>,
+    /// The library's code, as a JavaScript module.
+    pub source: String,
+}
+
+#[get("/js_libraries")]
+async fn list_libraries(data: AppStateData, auth: Authenticated) -> Result<impl Responder> {
+    let libraries = sqlx::query_as!(
+        JsLibrarySummary,
+        "SELECT DISTINCT ON (name) name, version, description, created
+        FROM js_libraries
+        WHERE org_id=$1
+        ORDER BY name, version DESC",
+        auth.org_id().0
+    )
+    .fetch_all(&data.pg)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(libraries))
+}
+
+#[get("/js_libraries/{name}")]
+async fn get_library(
+    data: AppStateData,
+    auth: Authenticated,
+    name: Path<String>,
+) -> Result<impl Responder> {
+    let library = sqlx::query_as!(
+        JsLibrary,
+        r##"SELECT name, version, description, source,
+            created_by AS "created_by: UserId", created
+        FROM js_libraries
+        WHERE org_id=$1 AND name=$2
+        ORDER BY version DESC
+        LIMIT 1"##,
+        auth.org_id().0,
+        name.as_str()
+    )
+    .fetch_optional(&data.pg)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    Ok(HttpResponse::Ok().json(library))
+}
+
+#[get("/js_libraries/{name}/versions/{version}")]
+async fn get_library_version(
+    data: AppStateData,
+    auth: Authenticated,
+    path: Path<(String, i32)>,
+) -> Result<impl Responder> {
+    let (name, version) = path.into_inner();
+    let library = sqlx::query_as!(
+        JsLibrary,
+        r##"SELECT name, version, description, source,
+            created_by AS "created_by: UserId", created
+        FROM js_libraries
+        WHERE org_id=$1 AND name=$2 AND version=$3"##,
+        auth.org_id().0,
+        name,
+        version
+    )
+    .fetch_optional(&data.pg)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    Ok(HttpResponse::Ok().json(library))
+}
+
+/// Upload a new version of a library.
+#[post("/js_libraries/{name}")]
+async fn new_library_version(
+    data: AppStateData,
+    auth: Authenticated,
+    name: Path<String>,
+    payload: web::Json<JsLibraryInput>,
+) -> Result<impl Responder> {
+    let name = name.into_inner();
+    let payload = payload.into_inner();
+
+    let mut errors = Vec::new();
+    if !valid_library_name(&name) {
+        errors.push(format!(
+            "Library name {} may only contain letters, numbers, _ and -",
+            name
+        ));
+    }
+    if payload.source.trim().is_empty() {
+        errors.push("Library source is empty".to_string());
+    }
+    if !errors.is_empty() {
+        return Err(Error::ValidationError(errors));
+    }
+
+    let library = sqlx::query_as!(
+        JsLibrary,
+        r##"INSERT INTO js_libraries (org_id, name, version, description, source, created_by)
+        SELECT $1, $2, COALESCE(MAX(version), 0) + 1, $3, $4, $5
+            FROM js_libraries WHERE org_id=$1 AND name=$2
+        RETURNING name, version, description, source,
+            created_by AS "created_by: UserId", created"##,
+        auth.org_id().0,
+        name,
+        payload.description,
+        payload.source,
+        auth.user_id().0
+    )
+    .fetch_one(&data.pg)
+    .await?;
+
+    Ok(HttpResponse::Created().json(library))
+}
+
+/// Delete every version of a library. Saved tasks keep the library code that they were saved
+/// with, but actions that import it will fail.
+#[delete("/js_libraries/{name}")]
+async fn delete_library(
+    data: AppStateData,
+    auth: Authenticated,
+    name: Path<String>,
+) -> Result<impl Responder> {
+    let result = sqlx::query!(
+        "DELETE FROM js_libraries WHERE org_id=$1 AND name=$2",
+        auth.org_id().0,
+        name.as_str()
+    )
+    .execute(&data.pg)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_libraries)
+        .service(get_library)
+        .service(get_library_version)
+        .service(new_library_version)
+        .service(delete_library);
+}
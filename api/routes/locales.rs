@@ -0,0 +1,77 @@
+use actix_web::{get, put, web, HttpResponse, Responder};
+use ergo_auth::Authenticated;
+use serde::Deserialize;
+
+use crate::{
+    error::{Error, Result},
+    web_app_server::AppStateData,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct LocaleInput {
+    /// The locale to use, or None to fall back to the org or server default.
+    pub locale: Option<String>,
+}
+
+fn normalize_input(input: LocaleInput) -> Result<Option<String>> {
+    match input.locale {
+        Some(locale) => {
+            let locale = ergo_localization::normalize_locale(&locale);
+            if locale.is_empty() {
+                return Err(Error::StringError("Locale must not be empty".to_string()));
+            }
+            Ok(Some(locale))
+        }
+        None => Ok(None),
+    }
+}
+
+#[get("/locales")]
+pub async fn list_locales() -> Result<impl Responder> {
+    Ok(HttpResponse::Ok().json(ergo_localization::available_locales()))
+}
+
+#[put("/user/locale")]
+pub async fn set_user_locale(
+    data: AppStateData,
+    auth: Authenticated,
+    payload: web::Json<LocaleInput>,
+) -> Result<impl Responder> {
+    let locale = normalize_input(payload.into_inner())?;
+
+    sqlx::query!(
+        "UPDATE users SET locale=$2 WHERE user_id=$1",
+        auth.user_id().0,
+        locale.as_deref()
+    )
+    .execute(&data.pg)
+    .await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[put("/org/locale")]
+pub async fn set_org_locale(
+    data: AppStateData,
+    auth: Authenticated,
+    payload: web::Json<LocaleInput>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+    let locale = normalize_input(payload.into_inner())?;
+
+    sqlx::query!(
+        "UPDATE orgs SET locale=$2 WHERE org_id=$1",
+        auth.org_id().0,
+        locale.as_deref()
+    )
+    .execute(&data.pg)
+    .await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_locales)
+        .service(set_user_locale)
+        .service(set_org_locale);
+}
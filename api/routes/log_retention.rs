@@ -0,0 +1,82 @@
+//! The org's retention policy for the inputs and actions logs, and the archives written when
+//! old entries are removed.
+
+use actix_web::{get, post, put, web, HttpResponse, Responder};
+use ergo_auth::Authenticated;
+use ergo_tasks::{
+    log_retention::{self, RetentionPolicy},
+    maintenance::{enqueue_maintenance_job, MaintenanceJob},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend_data::BackendAppStateData,
+    error::{Error, Result},
+    web_app_server::AppStateData,
+};
+
+#[get("/org/log_retention")]
+pub async fn get_log_retention(data: AppStateData, auth: Authenticated) -> Result<impl Responder> {
+    let mut conn = data.pg.acquire().await?;
+    let policy = log_retention::get_policy(&mut conn, auth.org_id()).await?;
+    Ok(HttpResponse::Ok().json(policy))
+}
+
+#[put("/org/log_retention")]
+pub async fn set_log_retention(
+    data: AppStateData,
+    auth: Authenticated,
+    payload: web::Json<RetentionPolicy>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+
+    if payload.keep_days.map(|n| n < 1).unwrap_or(false) {
+        return Err(Error::ValidationError(vec![
+            "keep_days must be at least 1".to_string()
+        ]));
+    }
+
+    let mut conn = data.pg.acquire().await?;
+    log_retention::set_policy(&mut conn, auth.org_id(), &payload).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogRetentionRunResponse {
+    pub job_id: String,
+}
+
+/// Apply the org's retention policy now instead of waiting for the next scheduled run.
+#[post("/org/log_retention/run")]
+pub async fn run_log_retention(
+    backend_data: BackendAppStateData,
+    auth: Authenticated,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+
+    let mut conn = backend_data.pg.acquire().await?;
+    let job_id = enqueue_maintenance_job(
+        &mut conn,
+        &MaintenanceJob::LogRetention {
+            org_id: Some(auth.org_id().clone()),
+        },
+        backend_data.redis_key_prefix.as_deref(),
+    )
+    .await?;
+
+    Ok(HttpResponse::Accepted().json(LogRetentionRunResponse { job_id }))
+}
+
+#[get("/org/log_retention/archives")]
+pub async fn list_log_archives(data: AppStateData, auth: Authenticated) -> Result<impl Responder> {
+    let mut conn = data.pg.acquire().await?;
+    let archives = log_retention::list_archives(&mut conn, auth.org_id()).await?;
+    Ok(HttpResponse::Ok().json(archives))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_log_retention)
+        .service(set_log_retention)
+        .service(run_log_retention)
+        .service(list_log_archives);
+}
@@ -0,0 +1,1321 @@
+use actix_web::{
+    get,
+    http::header::{
+        CacheControl, CacheDirective, ContentDisposition, DispositionParam, DispositionType,
+    },
+    post,
+    web::{self, Path},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use ergo_auth::{Authenticated, PermissionType};
+use ergo_database::{
+    new_uuid,
+    object_id::{InputId, OrgId, TaskId, TaskTriggerId},
+    redaction::contains_redacted,
+    PostgresPool,
+};
+use ergo_js::ConsoleLevel;
+use ergo_notifications::{NotificationStatus, NotifyEvent, NotifyService};
+use ergo_tasks::{
+    actions::{enqueue_actions, ActionInvocation, ActionInvocations, ActionSource, ActionStatus},
+    inputs::{
+        chain::InputChain,
+        enqueue_input,
+        secrets::{masked, restore_masked_secrets, MASKED_VALUE},
+        sensitive::{decrypt_payload, is_sensitive, redact},
+        EnqueueInputOptions, InputStatus,
+    },
+    quotas::{consume_daily_quota, QuotaKind},
+    script_logs::{self, ScriptLogFilter},
+    state_history::{self, StateChange, StateSnapshot},
+};
+use fxhash::FxHashMap;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use smallvec::smallvec;
+use sqlx::PgConnection;
+use std::time::Duration;
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use crate::{
+    backend_data::BackendAppStateData,
+    error::{Error, Result},
+    pagination::Pagination,
+    routes::{
+        permissions::require_permission,
+        tasks::{parse_status_filter, TaskTriggerResponse},
+    },
+    web_app_server::AppStateData,
+};
+
+/// Shows the payloads of sensitive triggers to requesters with the `read_sensitive` permission
+/// on the task, and redacts them for everyone else. The permission is checked once per task.
+#[derive(Default)]
+pub(crate) struct SensitivePayloads {
+    allowed: FxHashMap<TaskId, bool>,
+}
+
+impl SensitivePayloads {
+    async fn allowed(
+        &mut self,
+        conn: &mut PgConnection,
+        auth: &Authenticated,
+        task_id: &TaskId,
+    ) -> Result<bool> {
+        if let Some(allowed) = self.allowed.get(task_id) {
+            return Ok(*allowed);
+        }
+
+        let allowed =
+            match require_permission(conn, auth, PermissionType::ReadSensitive, task_id.0).await {
+                Ok(()) => true,
+                Err(Error::AuthorizationError) => false,
+                Err(e) => return Err(e),
+            };
+        self.allowed.insert(task_id.clone(), allowed);
+        Ok(allowed)
+    }
+
+    /// Decrypt or redact the payload if it is encrypted. Other payloads are returned unchanged.
+    pub async fn reveal(
+        &mut self,
+        conn: &mut PgConnection,
+        auth: &Authenticated,
+        task_id: &TaskId,
+        payload: serde_json::Value,
+    ) -> Result<serde_json::Value> {
+        if !is_sensitive(&payload) || !self.allowed(conn, auth, task_id).await? {
+            return Ok(redact(payload));
+        }
+
+        // A payload that can't be decrypted shouldn't stop the rest of the log from loading.
+        match decrypt_payload(payload).await {
+            Ok(payload) => Ok(payload),
+            Err(e) => {
+                event!(Level::WARN, %task_id, error=%e, "Failed to decrypt sensitive payload");
+                Ok(json!(MASKED_VALUE))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct TimelineEvent {
+    pub name: String,
+    /// "input" for events in the input processing, or "action" for an action invocation.
+    pub category: &'static str,
+    pub actions_log_id: Option<Uuid>,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub duration_ms: i64,
+    pub info: Option<serde_json::Value>,
+}
+
+impl TimelineEvent {
+    fn new(
+        name: impl Into<String>,
+        actions_log_id: Option<Uuid>,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        info: Option<serde_json::Value>,
+    ) -> Self {
+        TimelineEvent {
+            name: name.into(),
+            category: if actions_log_id.is_some() {
+                "action"
+            } else {
+                "input"
+            },
+            actions_log_id,
+            start,
+            end,
+            duration_ms: (end - start).num_milliseconds(),
+            info,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct TimelineAction {
+    pub actions_log_id: Uuid,
+    pub task_action_local_id: Option<String>,
+    pub task_action_name: Option<String>,
+    pub status: ActionStatus,
+    pub result: Option<serde_json::Value>,
+    /// Set when this action was run to undo another action in the run, after a later action
+    /// failed.
+    pub compensates: Option<Uuid>,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+    pub duration_ms: i64,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct TimelineNotification {
+    pub notifications_log_id: i64,
+    #[schemars(with = "String")]
+    pub event: NotifyEvent,
+    #[schemars(with = "String")]
+    pub service: NotifyService,
+    /// Set when the notification is about one of the input's actions.
+    pub actions_log_id: Option<Uuid>,
+    #[schemars(with = "String")]
+    pub status: NotificationStatus,
+    pub error: Option<String>,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+}
+
+/// Everything that happened while handling an input.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct RunTimeline {
+    pub inputs_log_id: Uuid,
+    pub task_id: TaskId,
+    pub task_trigger_local_id: String,
+    pub status: InputStatus,
+    /// The input payload, with secret fields masked. Payloads sent to sensitive triggers are
+    /// redacted unless the requester has the `read_sensitive` permission.
+    pub payload: serde_json::Value,
+    /// The state machine or dataflow log from applying the input, or the error if it failed.
+    pub info: Option<serde_json::Value>,
+    /// The task's state after the input was applied.
+    pub state: Option<StateSnapshot>,
+    pub actions: Vec<TimelineAction>,
+    pub notifications: Vec<TimelineNotification>,
+    pub events: Vec<TimelineEvent>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TimelineFormat {
+    Json,
+    /// The Chrome trace event format, viewable in chrome://tracing or Perfetto.
+    Chrome,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct TimelineQuery {
+    format: Option<TimelineFormat>,
+}
+
+async fn build_timeline(
+    data: &AppStateData,
+    auth: &Authenticated,
+    inputs_log_id: Uuid,
+) -> Result<RunTimeline> {
+    let ids = auth.user_entity_ids();
+    let mut conn = data.pg.acquire().await?;
+
+    let input = sqlx::query!(
+        r##"SELECT il.task_id AS "task_id!: TaskId",
+            il.task_trigger_local_id,
+            il.status AS "status: InputStatus",
+            COALESCE(il.payload, 'null'::jsonb) AS "payload!",
+            il.info,
+            COALESCE(inputs.payload_schema, 'null'::jsonb) AS "payload_schema!",
+            COALESCE(il.scheduled_for, il.created) AS "queued!",
+            il.updated
+        FROM inputs_log il
+        JOIN tasks USING(task_id)
+        LEFT JOIN task_triggers tt ON tt.task_trigger_id = il.task_trigger_id
+        LEFT JOIN inputs ON inputs.input_id = tt.input_id
+        WHERE il.inputs_log_id=$1 AND tasks.org_id=$2 AND
+            EXISTS(SELECT 1 FROM user_entity_permissions
+                WHERE user_entity_id = ANY($3)
+                AND permission_type = 'read'
+                AND permissioned_object IN (uuid_nil(), tasks.task_id)
+            )"##,
+        inputs_log_id,
+        auth.org_id().0,
+        ids.as_slice()
+    )
+    .fetch_optional(&mut conn)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let actions = sqlx::query!(
+        r##"SELECT al.actions_log_id, al.task_action_local_id,
+            ta.name AS "task_action_name?",
+            al.status AS "status: ActionStatus",
+            al.result, al.compensates, al.created, al.updated
+        FROM actions_log al
+        LEFT JOIN task_actions ta
+            ON ta.task_id = al.task_id AND ta.task_action_local_id = al.task_action_local_id
+        WHERE al.inputs_log_id=$1
+        ORDER BY al.created"##,
+        inputs_log_id
+    )
+    .fetch_all(&mut conn)
+    .await?;
+
+    let notifications = sqlx::query!(
+        r##"SELECT notifications_log_id,
+            event AS "event: NotifyEvent",
+            service AS "service: NotifyService",
+            NULLIF(log_id, $1) AS actions_log_id,
+            status AS "status: NotificationStatus",
+            error, created, updated
+        FROM notifications_log
+        WHERE log_id = $1
+            OR log_id IN (SELECT actions_log_id FROM actions_log WHERE inputs_log_id=$1)
+        ORDER BY notifications_log_id"##,
+        inputs_log_id
+    )
+    .fetch_all(&mut conn)
+    .await?
+    .into_iter()
+    .map(|row| TimelineNotification {
+        notifications_log_id: row.notifications_log_id,
+        event: row.event,
+        service: row.service,
+        actions_log_id: row.actions_log_id,
+        status: row.status,
+        error: row.error,
+        created: row.created,
+        updated: row.updated,
+    })
+    .collect::<Vec<_>>();
+
+    let state = state_history::state_as_of(&mut conn, inputs_log_id).await?;
+
+    let spans = sqlx::query!(
+        r##"SELECT actions_log_id, name, start_time, end_time, info
+        FROM run_timeline_spans
+        WHERE inputs_log_id=$1
+        ORDER BY start_time"##,
+        inputs_log_id
+    )
+    .fetch_all(&mut conn)
+    .await?;
+
+    let mut events = Vec::with_capacity(spans.len() + actions.len() + 1);
+
+    // Queue wait is the time between the input arriving (or its scheduled time) and when
+    // processing started.
+    let input_start = spans
+        .iter()
+        .filter(|s| s.actions_log_id.is_none())
+        .map(|s| s.start_time)
+        .min()
+        .unwrap_or(input.updated);
+    events.push(TimelineEvent::new(
+        "input_queue_wait",
+        None,
+        input.queued,
+        input_start.max(input.queued),
+        None,
+    ));
+
+    for action in &actions {
+        let action_start = spans
+            .iter()
+            .filter(|s| s.actions_log_id == Some(action.actions_log_id))
+            .map(|s| s.start_time)
+            .min();
+
+        let info = Some(json!({
+            "task_action_local_id": action.task_action_local_id,
+            "status": action.status,
+            "compensates": action.compensates,
+        }));
+
+        match action_start {
+            Some(start) => events.push(TimelineEvent::new(
+                "action_queue_wait",
+                Some(action.actions_log_id),
+                action.created,
+                start.max(action.created),
+                info,
+            )),
+            // Actions that ran before timeline recording existed only have the log timestamps.
+            None => events.push(TimelineEvent::new(
+                "action",
+                Some(action.actions_log_id),
+                action.created,
+                action.updated,
+                info,
+            )),
+        }
+    }
+
+    events.extend(spans.into_iter().map(|span| {
+        TimelineEvent::new(
+            span.name,
+            span.actions_log_id,
+            span.start_time,
+            span.end_time,
+            span.info,
+        )
+    }));
+
+    events.sort_by_key(|e| e.start);
+
+    let actions = actions
+        .into_iter()
+        .map(|action| TimelineAction {
+            actions_log_id: action.actions_log_id,
+            task_action_local_id: action.task_action_local_id,
+            task_action_name: action.task_action_name,
+            status: action.status,
+            result: action.result,
+            compensates: action.compensates,
+            duration_ms: (action.updated - action.created).num_milliseconds(),
+            created: action.created,
+            updated: action.updated,
+        })
+        .collect();
+
+    let payload = SensitivePayloads::default()
+        .reveal(&mut conn, auth, &input.task_id, input.payload)
+        .await?;
+
+    Ok(RunTimeline {
+        inputs_log_id,
+        task_id: input.task_id,
+        task_trigger_local_id: input.task_trigger_local_id,
+        status: input.status,
+        payload: masked(&input.payload_schema, &payload),
+        info: input.info,
+        state,
+        actions,
+        notifications,
+        events,
+    })
+}
+
+/// Convert the timeline to the Chrome trace event format. Each action invocation gets its own
+/// thread row so that concurrent actions are displayed separately.
+fn chrome_trace(timeline: &RunTimeline) -> serde_json::Value {
+    let mut action_threads = Vec::<Uuid>::new();
+
+    let events = timeline
+        .events
+        .iter()
+        .map(|event| {
+            let tid = match event.actions_log_id {
+                Some(id) => match action_threads.iter().position(|a| *a == id) {
+                    Some(pos) => pos + 1,
+                    None => {
+                        action_threads.push(id);
+                        action_threads.len()
+                    }
+                },
+                None => 0,
+            };
+
+            json!({
+                "name": event.name,
+                "cat": event.category,
+                "ph": "X",
+                "ts": event.start.timestamp_nanos() / 1000,
+                "dur": (event.end - event.start).num_microseconds().unwrap_or(0),
+                "pid": 1,
+                "tid": tid,
+                "args": {
+                    "actions_log_id": event.actions_log_id,
+                    "info": event.info,
+                },
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "traceEvents": events,
+        "displayTimeUnit": "ms",
+        "otherData": {
+            "inputs_log_id": timeline.inputs_log_id,
+            "task_id": timeline.task_id,
+        },
+    })
+}
+
+#[get("/inputs_log/{inputs_log_id}/timeline")]
+async fn get_timeline(
+    data: AppStateData,
+    auth: Authenticated,
+    inputs_log_id: Path<Uuid>,
+    query: web::Query<TimelineQuery>,
+) -> Result<impl Responder> {
+    let inputs_log_id = inputs_log_id.into_inner();
+    let timeline = build_timeline(&data, &auth, inputs_log_id).await?;
+
+    let response = match query.format.unwrap_or(TimelineFormat::Json) {
+        TimelineFormat::Json => HttpResponse::Ok().json(timeline),
+        TimelineFormat::Chrome => HttpResponse::Ok()
+            .insert_header(ContentDisposition {
+                disposition: DispositionType::Attachment,
+                parameters: vec![DispositionParam::Filename(format!(
+                    "trace-{}.json",
+                    inputs_log_id
+                ))],
+            })
+            .json(chrome_trace(&timeline)),
+    };
+
+    Ok(response)
+}
+
+/// Return an error unless the requester can read the task that an input was for.
+async fn check_input_readable(
+    conn: &mut PgConnection,
+    auth: &Authenticated,
+    inputs_log_id: Uuid,
+) -> Result<()> {
+    let ids = auth.user_entity_ids();
+    let readable = sqlx::query_scalar!(
+        r##"SELECT EXISTS(
+            SELECT 1 FROM inputs_log il
+            JOIN tasks USING(task_id)
+            WHERE il.inputs_log_id=$1 AND tasks.org_id=$2 AND
+                EXISTS(SELECT 1 FROM user_entity_permissions
+                    WHERE user_entity_id = ANY($3)
+                    AND permission_type = 'read'
+                    AND permissioned_object IN (uuid_nil(), tasks.task_id)
+                )
+        ) AS "readable!""##,
+        inputs_log_id,
+        auth.org_id().0,
+        ids.as_slice()
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    if !readable {
+        return Err(Error::NotFound);
+    }
+
+    Ok(())
+}
+
+/// Get the state snapshot for an input, if the requester can read its task.
+async fn readable_state(
+    data: &AppStateData,
+    auth: &Authenticated,
+    inputs_log_id: Uuid,
+) -> Result<StateSnapshot> {
+    let mut conn = data.pg.acquire().await?;
+    check_input_readable(&mut conn, auth, inputs_log_id).await?;
+
+    state_history::state_as_of(&mut conn, inputs_log_id)
+        .await?
+        .ok_or(Error::NotFound)
+}
+
+/// The task's state as it was right after this input was applied.
+#[get("/inputs_log/{inputs_log_id}/state")]
+async fn get_input_state(
+    data: AppStateData,
+    auth: Authenticated,
+    inputs_log_id: Path<Uuid>,
+) -> Result<impl Responder> {
+    let snapshot = readable_state(&data, &auth, inputs_log_id.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(snapshot))
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct StateDiffQuery {
+    /// The earlier input to compare against.
+    since: Uuid,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct StateDiff {
+    pub task_id: TaskId,
+    pub since: StateSnapshot,
+    pub until: StateSnapshot,
+    pub changes: Vec<StateChange>,
+}
+
+/// Compare the task's state after the input in `since` to its state after this input.
+#[get("/inputs_log/{inputs_log_id}/state/diff")]
+async fn get_input_state_diff(
+    data: AppStateData,
+    auth: Authenticated,
+    inputs_log_id: Path<Uuid>,
+    query: web::Query<StateDiffQuery>,
+) -> Result<impl Responder> {
+    let until = readable_state(&data, &auth, inputs_log_id.into_inner()).await?;
+    let since = readable_state(&data, &auth, query.since).await?;
+
+    if since.task_id != until.task_id {
+        return Err(Error::ValidationError(vec![
+            "Both inputs must be for the same task".to_string(),
+        ]));
+    }
+
+    let null = serde_json::Value::Null;
+    let changes = state_history::diff_states(
+        since.state.as_ref().unwrap_or(&null),
+        until.state.as_ref().unwrap_or(&null),
+    );
+
+    Ok(HttpResponse::Ok().json(StateDiff {
+        task_id: until.task_id,
+        since,
+        until,
+        changes,
+    }))
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ScriptLogQuery {
+    /// Only return entries at this level or above: `debug`, `info`, `warn`, or `error`.
+    level: Option<String>,
+    /// Return entries older than this entry ID, to page through a task's entries.
+    before: Option<i64>,
+    /// How many entries to return, for a task's entries.
+    limit: Option<i64>,
+}
+
+impl ScriptLogQuery {
+    fn min_level(&self) -> Result<Option<ConsoleLevel>> {
+        self.level
+            .as_deref()
+            .map(|level| {
+                script_logs::parse_level(level).ok_or_else(|| {
+                    Error::ValidationError(vec![format!("Unknown log level {}", level)])
+                })
+            })
+            .transpose()
+    }
+}
+
+const DEFAULT_SCRIPT_LOG_LIMIT: i64 = 100;
+const MAX_SCRIPT_LOG_LIMIT: i64 = 1000;
+
+/// List the structured log entries that the task script wrote during this run, oldest first.
+#[get("/inputs_log/{inputs_log_id}/script_logs")]
+async fn list_input_script_logs(
+    data: AppStateData,
+    auth: Authenticated,
+    inputs_log_id: Path<Uuid>,
+    query: web::Query<ScriptLogQuery>,
+) -> Result<impl Responder> {
+    let inputs_log_id = inputs_log_id.into_inner();
+    let mut conn = data.pg.acquire().await?;
+    check_input_readable(&mut conn, &auth, inputs_log_id).await?;
+
+    let filter = ScriptLogFilter {
+        inputs_log_id: Some(inputs_log_id),
+        min_level: query.min_level()?,
+        limit: script_logs::MAX_ENTRIES_PER_RUN as i64,
+        ..Default::default()
+    };
+    let mut entries = script_logs::list(&mut conn, &filter).await?;
+    entries.reverse();
+
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+/// List the structured log entries that a task's script wrote, newest first.
+#[get("/tasks/{task_id}/script_logs")]
+async fn list_task_script_logs(
+    data: AppStateData,
+    auth: Authenticated,
+    task_id: Path<TaskId>,
+    query: web::Query<ScriptLogQuery>,
+) -> Result<impl Responder> {
+    let task_id = task_id.into_inner();
+    let ids = auth.user_entity_ids();
+    let mut conn = data.pg.acquire().await?;
+
+    let readable = sqlx::query_scalar!(
+        r##"SELECT EXISTS(
+            SELECT 1 FROM tasks
+            WHERE task_id=$1 AND org_id=$2 AND NOT deleted AND
+                EXISTS(SELECT 1 FROM user_entity_permissions
+                    WHERE user_entity_id = ANY($3)
+                    AND permission_type = 'read'
+                    AND permissioned_object IN (uuid_nil(), tasks.task_id)
+                )
+        ) AS "readable!""##,
+        task_id.0,
+        auth.org_id().0,
+        ids.as_slice()
+    )
+    .fetch_one(&mut conn)
+    .await?;
+
+    if !readable {
+        return Err(Error::NotFound);
+    }
+
+    let filter = ScriptLogFilter {
+        task_id: Some(task_id),
+        min_level: query.min_level()?,
+        before: query.before,
+        limit: query
+            .limit
+            .unwrap_or(DEFAULT_SCRIPT_LOG_LIMIT)
+            .clamp(1, MAX_SCRIPT_LOG_LIMIT),
+        ..Default::default()
+    };
+    let entries = script_logs::list(&mut conn, &filter).await?;
+
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+/// An input in the lineage of an action.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct LineageInput {
+    pub inputs_log_id: Uuid,
+    pub task_id: TaskId,
+    pub task_name: String,
+    pub task_trigger_local_id: String,
+    /// If another task sent this input, the action in that task that sent it.
+    pub source_actions_log_id: Option<Uuid>,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ActionLineage {
+    pub actions_log_id: Uuid,
+    pub task_id: TaskId,
+    pub task_action_local_id: Option<String>,
+    /// The state machine transition, dataflow node, or script that emitted the action. This is
+    /// empty for actions logged before sources were recorded.
+    pub source: Option<ActionSource>,
+    /// Set when this action was run to undo another action.
+    pub compensates: Option<Uuid>,
+    /// The input that produced the action, followed by the inputs to each earlier task in the
+    /// chain, back to the input that started it.
+    pub inputs: Vec<LineageInput>,
+}
+
+/// Trace an action back to the input that produced it, and through any tasks that passed that
+/// input along.
+#[get("/actions_log/{actions_log_id}/lineage")]
+async fn get_action_lineage(
+    data: AppStateData,
+    auth: Authenticated,
+    actions_log_id: Path<Uuid>,
+) -> Result<impl Responder> {
+    let actions_log_id = actions_log_id.into_inner();
+    let ids = auth.user_entity_ids();
+    let mut conn = data.pg.acquire().await?;
+
+    let action = sqlx::query!(
+        r##"SELECT al.task_id AS "task_id!: TaskId",
+            al.task_action_local_id,
+            al.inputs_log_id,
+            al.source AS "source: sqlx::types::Json<ActionSource>",
+            al.compensates
+        FROM actions_log al
+        JOIN tasks USING(task_id)
+        WHERE al.actions_log_id=$1 AND tasks.org_id=$2 AND
+            EXISTS(SELECT 1 FROM user_entity_permissions
+                WHERE user_entity_id = ANY($3)
+                AND permission_type = 'read'
+                AND permissioned_object IN (uuid_nil(), tasks.task_id)
+            )"##,
+        actions_log_id,
+        auth.org_id().0,
+        ids.as_slice()
+    )
+    .fetch_optional(&mut conn)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    // Inputs from tasks that the user can't read end the list, since the chain can't be
+    // followed past them.
+    let inputs = sqlx::query!(
+        r##"WITH RECURSIVE chain AS (
+            SELECT inputs_log_id, source_inputs_log_id, 0 AS depth
+            FROM inputs_log
+            WHERE inputs_log_id = $1
+            UNION ALL
+            SELECT il.inputs_log_id, il.source_inputs_log_id, chain.depth + 1
+            FROM inputs_log il
+            JOIN chain ON il.inputs_log_id = chain.source_inputs_log_id
+        )
+        SELECT il.inputs_log_id AS "inputs_log_id!",
+            il.task_id AS "task_id!: TaskId",
+            tasks.name AS task_name,
+            il.task_trigger_local_id,
+            il.source_actions_log_id,
+            il.created,
+            EXISTS(SELECT 1 FROM user_entity_permissions
+                WHERE user_entity_id = ANY($3)
+                AND permission_type = 'read'
+                AND permissioned_object IN (uuid_nil(), tasks.task_id)
+            ) AS "readable!"
+        FROM chain
+        JOIN inputs_log il USING(inputs_log_id)
+        JOIN tasks ON tasks.task_id = il.task_id AND tasks.org_id = $2
+        ORDER BY chain.depth"##,
+        action.inputs_log_id,
+        auth.org_id().0,
+        ids.as_slice()
+    )
+    .fetch_all(&mut conn)
+    .await?
+    .into_iter()
+    .take_while(|row| row.readable)
+    .map(|row| LineageInput {
+        inputs_log_id: row.inputs_log_id,
+        task_id: row.task_id,
+        task_name: row.task_name,
+        task_trigger_local_id: row.task_trigger_local_id,
+        source_actions_log_id: row.source_actions_log_id,
+        created: row.created,
+    })
+    .collect();
+
+    Ok(HttpResponse::Ok().json(ActionLineage {
+        actions_log_id,
+        task_id: action.task_id,
+        task_action_local_id: action.task_action_local_id,
+        source: action.source.map(|s| s.0),
+        compensates: action.compensates,
+        inputs,
+    }))
+}
+
+/// An action caused by an input.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct LineageAction {
+    pub actions_log_id: Uuid,
+    pub task_id: TaskId,
+    pub task_action_local_id: Option<String>,
+    /// The input that the action's task was handling.
+    pub inputs_log_id: Uuid,
+    pub source: Option<ActionSource>,
+    pub status: ActionStatus,
+    /// The number of tasks that passed the input along before it reached this action's task.
+    /// Actions of the task that received the original input have a depth of 0.
+    pub depth: i32,
+    pub created: DateTime<Utc>,
+}
+
+/// List every action caused by an input, including the actions of other tasks that received
+/// inputs sent while handling it. Actions of tasks that the user can't read are left out.
+#[get("/inputs_log/{inputs_log_id}/lineage")]
+async fn get_input_lineage(
+    data: AppStateData,
+    auth: Authenticated,
+    inputs_log_id: Path<Uuid>,
+) -> Result<impl Responder> {
+    let inputs_log_id = inputs_log_id.into_inner();
+    let ids = auth.user_entity_ids();
+    let mut conn = data.pg.acquire().await?;
+
+    let readable = sqlx::query_scalar!(
+        r##"SELECT EXISTS(
+            SELECT 1 FROM inputs_log il
+            JOIN tasks USING(task_id)
+            WHERE il.inputs_log_id=$1 AND tasks.org_id=$2 AND
+                EXISTS(SELECT 1 FROM user_entity_permissions
+                    WHERE user_entity_id = ANY($3)
+                    AND permission_type = 'read'
+                    AND permissioned_object IN (uuid_nil(), tasks.task_id)
+                )
+        ) AS "readable!""##,
+        inputs_log_id,
+        auth.org_id().0,
+        ids.as_slice()
+    )
+    .fetch_one(&mut conn)
+    .await?;
+
+    if !readable {
+        return Err(Error::NotFound);
+    }
+
+    let actions = sqlx::query!(
+        r##"WITH RECURSIVE descendants AS (
+            SELECT $1::uuid AS inputs_log_id, 0 AS depth
+            UNION ALL
+            SELECT il.inputs_log_id, d.depth + 1
+            FROM inputs_log il
+            JOIN descendants d ON il.source_inputs_log_id = d.inputs_log_id
+        )
+        SELECT al.actions_log_id,
+            al.task_id AS "task_id!: TaskId",
+            al.task_action_local_id,
+            al.inputs_log_id AS "inputs_log_id!",
+            al.source AS "source: sqlx::types::Json<ActionSource>",
+            al.status AS "status: ActionStatus",
+            d.depth AS "depth!",
+            al.created
+        FROM descendants d
+        JOIN actions_log al ON al.inputs_log_id = d.inputs_log_id
+        JOIN tasks ON tasks.task_id = al.task_id
+        WHERE tasks.org_id = $2 AND
+            EXISTS(SELECT 1 FROM user_entity_permissions
+                WHERE user_entity_id = ANY($3)
+                AND permission_type = 'read'
+                AND permissioned_object IN (uuid_nil(), tasks.task_id)
+            )
+        ORDER BY d.depth, al.created"##,
+        inputs_log_id,
+        auth.org_id().0,
+        ids.as_slice()
+    )
+    .fetch_all(&mut conn)
+    .await?
+    .into_iter()
+    .map(|row| LineageAction {
+        actions_log_id: row.actions_log_id,
+        task_id: row.task_id,
+        task_action_local_id: row.task_action_local_id,
+        inputs_log_id: row.inputs_log_id,
+        source: row.source.map(|s| s.0),
+        status: row.status,
+        depth: row.depth,
+        created: row.created,
+    })
+    .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(actions))
+}
+
+/// The last progress update from a running action.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ActionProgress {
+    /// Percent complete, from 0 to 100.
+    pub percent: Option<f64>,
+    pub message: Option<String>,
+    pub updated: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ActionProgressUpdate {
+    pub status: ActionStatus,
+    pub progress: Option<ActionProgress>,
+}
+
+/// How often the progress stream checks the action for changes.
+const PROGRESS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+async fn action_progress(
+    pg: &PostgresPool,
+    actions_log_id: Uuid,
+) -> Result<Option<ActionProgressUpdate>> {
+    let row = sqlx::query!(
+        r##"SELECT status AS "status: ActionStatus",
+            progress AS "progress: sqlx::types::Json<ActionProgress>"
+        FROM actions_log
+        WHERE actions_log_id=$1"##,
+        actions_log_id
+    )
+    .fetch_optional(pg)
+    .await?;
+
+    Ok(row.map(|row| ActionProgressUpdate {
+        status: row.status,
+        progress: row.progress.map(|p| p.0),
+    }))
+}
+
+/// Follow an action as it runs, as a stream of server-sent events. Each event holds an
+/// `ActionProgressUpdate`, and is sent when the action's status or progress changes. The stream
+/// ends when the action finishes.
+#[get("/actions_log/{actions_log_id}/progress")]
+async fn stream_action_progress(
+    data: AppStateData,
+    auth: Authenticated,
+    actions_log_id: Path<Uuid>,
+) -> Result<impl Responder> {
+    let actions_log_id = actions_log_id.into_inner();
+    let ids = auth.user_entity_ids();
+
+    let readable = sqlx::query_scalar!(
+        r##"SELECT EXISTS(
+            SELECT 1 FROM actions_log al
+            JOIN tasks USING(task_id)
+            WHERE al.actions_log_id=$1 AND tasks.org_id=$2 AND
+                EXISTS(SELECT 1 FROM user_entity_permissions
+                    WHERE user_entity_id = ANY($3)
+                    AND permission_type = 'read'
+                    AND permissioned_object IN (uuid_nil(), tasks.task_id)
+                )
+        ) AS "readable!""##,
+        actions_log_id,
+        auth.org_id().0,
+        ids.as_slice()
+    )
+    .fetch_one(&data.pg)
+    .await?;
+
+    if !readable {
+        return Err(Error::NotFound);
+    }
+
+    let pg = data.pg.clone();
+    let events = async_stream::stream! {
+        let mut last = None;
+        loop {
+            let update = match action_progress(&pg, actions_log_id).await {
+                Ok(Some(update)) => update,
+                Ok(None) => break,
+                Err(e) => {
+                    event!(Level::ERROR, error=%e, %actions_log_id, "Failed to read action progress");
+                    break;
+                }
+            };
+
+            let finished = matches!(update.status, ActionStatus::Success | ActionStatus::Error);
+            if last.as_ref() != Some(&update) {
+                let json = serde_json::to_string(&update).unwrap_or_default();
+                yield Ok::<_, actix_web::Error>(web::Bytes::from(format!("data: {json}\n\n")));
+                last = Some(update);
+            }
+
+            if finished {
+                break;
+            }
+
+            tokio::time::sleep(PROGRESS_POLL_INTERVAL).await;
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        .streaming(events))
+}
+
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+pub struct ReplayInput {
+    /// Run with this payload instead of the original one. Secret fields that are still masked
+    /// are filled in from the original payload.
+    #[serde(default)]
+    pub payload: Option<serde_json::Value>,
+}
+
+/// Run a past input again, as a new input linked to the original.
+#[post("/inputs_log/{inputs_log_id}/replay")]
+async fn replay_input(
+    data: BackendAppStateData,
+    auth: Authenticated,
+    inputs_log_id: Path<Uuid>,
+    body: web::Json<ReplayInput>,
+) -> Result<impl Responder> {
+    let inputs_log_id = inputs_log_id.into_inner();
+    let ids = auth.user_entity_ids();
+
+    let original = sqlx::query!(
+        r##"SELECT tasks.task_id AS "task_id: TaskId",
+            tasks.name AS task_name,
+            tasks.org_id AS "org_id: OrgId",
+            tt.task_trigger_id AS "task_trigger_id: TaskTriggerId",
+            tt.task_trigger_local_id,
+            tt.name AS task_trigger_name,
+            tt.input_id AS "input_id: InputId",
+            inputs.payload_schema,
+            COALESCE(il.payload, 'null'::jsonb) AS "payload!"
+        FROM inputs_log il
+        JOIN task_triggers tt ON tt.task_trigger_id = il.task_trigger_id
+        JOIN tasks ON tasks.task_id = tt.task_id
+        JOIN inputs ON inputs.input_id = tt.input_id
+        WHERE il.inputs_log_id = $1 AND tasks.org_id = $2 AND NOT tasks.deleted
+            AND EXISTS(
+                SELECT 1 FROM user_entity_permissions
+                WHERE user_entity_id = ANY($3)
+                AND permission_type = 'trigger_event'
+                AND permissioned_object IN (uuid_nil(), tt.task_trigger_id, tasks.task_id)
+            )"##,
+        inputs_log_id,
+        auth.org_id().0,
+        ids.as_slice()
+    )
+    .fetch_optional(&data.pg)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    // The payload goes straight back into the queue, where it is encrypted again if the trigger
+    // is sensitive, so replaying doesn't need the `read_sensitive` permission.
+    let original_payload = decrypt_payload(original.payload).await?;
+    let payload = match body.into_inner().payload {
+        Some(mut payload) => {
+            restore_masked_secrets(&original.payload_schema, &mut payload, &original_payload);
+            payload
+        }
+        None => original_payload,
+    };
+
+    let mut conn = data.pg.acquire().await?;
+    let log_id = enqueue_input(EnqueueInputOptions {
+        pg: &mut conn,
+        notifications: Some(data.notifications.clone()),
+        org_id: original.org_id,
+        user_id: auth.user_id().clone(),
+        task_id: original.task_id,
+        task_name: original.task_name,
+        input_id: original.input_id,
+        task_trigger_id: original.task_trigger_id,
+        task_trigger_local_id: original.task_trigger_local_id,
+        task_trigger_name: original.task_trigger_name,
+        // A replay is a one-off run, so it shouldn't schedule the next periodic run.
+        periodic_trigger_id: None,
+        payload_schema: &original.payload_schema,
+        payload,
+        redis_key_prefix: data.redis_key_prefix.as_deref(),
+        trigger_at: None,
+        replay_of: Some(inputs_log_id),
+        interactive: auth.session_id().is_some(),
+        chain: InputChain::default(),
+    })
+    .await?;
+
+    Ok(HttpResponse::Accepted().json(TaskTriggerResponse { log_id }))
+}
+
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+pub struct RetryAction {
+    /// Run with this payload instead of the original one.
+    #[serde(default)]
+    pub payload: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct RetryActionResponse {
+    /// The actions log ID of the new invocation.
+    pub actions_log_id: Uuid,
+}
+
+/// Run a failed action again, as a new invocation linked to the original. Only this action
+/// runs again; the input and the task's other actions are left alone.
+///
+/// The actions log stores payloads with secret values redacted. A payload that still contains
+/// redacted values is rejected, so those actions have to be retried with an edited payload.
+#[post("/actions_log/{actions_log_id}/retry")]
+async fn retry_action(
+    data: BackendAppStateData,
+    auth: Authenticated,
+    actions_log_id: Path<Uuid>,
+    body: web::Json<RetryAction>,
+) -> Result<impl Responder> {
+    let actions_log_id = actions_log_id.into_inner();
+    let ids = auth.user_entity_ids();
+    let mut tx = data.pg.begin().await?;
+
+    // The task action has to still exist, since the worker looks up the action to run from it.
+    let original = sqlx::query!(
+        r##"SELECT al.task_id AS "task_id!: TaskId",
+            tasks.org_id AS "org_id: OrgId",
+            al.task_action_local_id AS "task_action_local_id!",
+            al.inputs_log_id,
+            al.status AS "status: ActionStatus",
+            COALESCE(al.payload, 'null'::jsonb) AS "payload!",
+            al.source
+        FROM actions_log al
+        JOIN tasks USING(task_id)
+        JOIN task_actions ta
+            ON ta.task_id = al.task_id AND ta.task_action_local_id = al.task_action_local_id
+        WHERE al.actions_log_id=$1 AND tasks.org_id=$2 AND NOT tasks.deleted AND
+            EXISTS(SELECT 1 FROM user_entity_permissions
+                WHERE user_entity_id = ANY($3)
+                AND permission_type = 'write'
+                AND permissioned_object IN (uuid_nil(), tasks.task_id)
+            )"##,
+        actions_log_id,
+        auth.org_id().0,
+        ids.as_slice()
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    if original.status != ActionStatus::Error {
+        return Err(Error::InvalidRequest(
+            "Only failed actions can be retried".to_string(),
+        ));
+    }
+
+    let payload = body.into_inner().payload.unwrap_or(original.payload);
+    if contains_redacted(&payload) {
+        return Err(Error::ValidationError(vec![
+            "The payload contains redacted secret values. Retry with a payload that fills them in."
+                .to_string(),
+        ]));
+    }
+
+    consume_daily_quota(&mut tx, &original.org_id, QuotaKind::ActionsPerDay, 1).await?;
+
+    let retry_id = new_uuid();
+    sqlx::query!(
+        "INSERT INTO actions_log (task_id, task_action_local_id, actions_log_id, inputs_log_id,
+            payload, status, source, retry_of)
+        VALUES ($1, $2, $3, $4, $5, 'pending', $6, $7)",
+        original.task_id.0,
+        original.task_action_local_id,
+        retry_id,
+        original.inputs_log_id,
+        payload,
+        original.source,
+        actions_log_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    let actions: ActionInvocations = smallvec![ActionInvocation {
+        task_id: original.task_id,
+        task_action_local_id: original.task_action_local_id,
+        actions_log_id: retry_id,
+        input_arrival_id: original.inputs_log_id,
+        user_id: auth.user_id().clone(),
+        payload,
+        chain: InputChain::default(),
+        trace: Default::default(),
+        payload_in_log: false,
+        compensates: None,
+        source: None,
+    }];
+    enqueue_actions(
+        &mut tx,
+        &actions,
+        &data.redis_key_prefix,
+        auth.session_id().is_some(),
+    )
+    .await?;
+    tx.commit().await?;
+
+    event!(Level::INFO, %actions_log_id, %retry_id, "Retrying action");
+
+    Ok(HttpResponse::Accepted().json(RetryActionResponse {
+        actions_log_id: retry_id,
+    }))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ActionsLogEntry {
+    pub actions_log_id: Uuid,
+    /// The input that the action ran for. This is empty for actions run directly, and once the
+    /// input has been removed from the log.
+    pub inputs_log_id: Option<Uuid>,
+    pub task_id: TaskId,
+    pub task_name: String,
+    pub task_action_local_id: Option<String>,
+    pub task_action_name: Option<String>,
+    pub status: ActionStatus,
+    /// Set when this invocation retries an earlier one that failed.
+    pub retry_of: Option<Uuid>,
+    pub result: serde_json::Value,
+    pub progress: Option<ActionProgress>,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+}
+
+/// Filters for the actions log. Times apply to the column that the results are sorted by.
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+pub struct ActionsLogQuery {
+    /// A comma-separated list of statuses, such as `pending,running`.
+    pub status: Option<String>,
+    pub task_id: Option<TaskId>,
+    /// The local ID of a task action. This is usually combined with `task_id`.
+    pub action: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+/// List the actions log for the tasks that the user can read, newest first by default. See
+/// [crate::pagination] for paging through the results.
+#[get("/actions_log")]
+async fn list_actions_log(
+    data: AppStateData,
+    auth: Authenticated,
+    query: web::Query<ActionsLogQuery>,
+    pagination: Pagination,
+) -> Result<impl Responder> {
+    let ids = auth.user_entity_ids();
+    let statuses = parse_status_filter::<ActionStatus>(query.status.as_deref())?;
+
+    let rows = sqlx::query!(
+        r##"SELECT al.actions_log_id, al.inputs_log_id,
+            al.task_id AS "task_id: TaskId",
+            tasks.name AS task_name,
+            al.task_action_local_id,
+            ta.name AS "task_action_name?",
+            al.status AS "status: ActionStatus",
+            al.retry_of,
+            COALESCE(al.result, 'null'::jsonb) AS "result!",
+            al.progress AS "progress: sqlx::types::Json<ActionProgress>",
+            al.created, al.updated,
+            CASE WHEN $3 = 'created' THEN al.created ELSE al.updated END AS "sort_time!"
+        FROM actions_log al
+        JOIN tasks USING (task_id)
+        LEFT JOIN task_actions ta
+            ON ta.task_id = al.task_id AND ta.task_action_local_id = al.task_action_local_id
+        WHERE tasks.org_id = $2 AND
+            EXISTS(SELECT 1 FROM user_entity_permissions
+                WHERE user_entity_id = ANY($1)
+                AND permission_type = 'read'
+                AND permissioned_object IN (uuid_nil(), tasks.task_id)
+            )
+            AND ($5::text[] IS NULL OR al.status::text = ANY($5))
+            AND ($6::uuid IS NULL OR al.task_id = $6)
+            AND ($7::text IS NULL OR al.task_action_local_id = $7)
+            AND ($8::timestamptz IS NULL OR
+                CASE WHEN $3 = 'created' THEN al.created ELSE al.updated END >= $8)
+            AND ($9::timestamptz IS NULL OR
+                CASE WHEN $3 = 'created' THEN al.created ELSE al.updated END < $9)
+            AND ($10::timestamptz IS NULL OR CASE WHEN $4
+                THEN (CASE WHEN $3 = 'created' THEN al.created ELSE al.updated END, al.actions_log_id) < ($10, $11::uuid)
+                ELSE (CASE WHEN $3 = 'created' THEN al.created ELSE al.updated END, al.actions_log_id) > ($10, $11::uuid)
+            END)
+        ORDER BY
+            CASE WHEN $4 THEN CASE WHEN $3 = 'created' THEN al.created ELSE al.updated END END DESC,
+            CASE WHEN $4 THEN al.actions_log_id END DESC,
+            CASE WHEN NOT $4 THEN CASE WHEN $3 = 'created' THEN al.created ELSE al.updated END END ASC,
+            CASE WHEN NOT $4 THEN al.actions_log_id END ASC
+        LIMIT $12"##,
+        ids.as_slice(),
+        auth.org_id().0,
+        pagination.sort.as_str(),
+        pagination.desc(),
+        statuses.as_deref(),
+        query.task_id.as_ref().map(|id| id.0),
+        query.action.as_deref(),
+        query.since,
+        query.until,
+        pagination.cursor_time(),
+        pagination.cursor_id(),
+        pagination.fetch_limit()
+    )
+    .fetch_all(&data.pg)
+    .await?;
+
+    let sort_times = rows
+        .iter()
+        .map(|row| (row.actions_log_id, row.sort_time))
+        .collect::<FxHashMap<_, _>>();
+    let entries = rows
+        .into_iter()
+        .map(|row| ActionsLogEntry {
+            actions_log_id: row.actions_log_id,
+            inputs_log_id: row.inputs_log_id,
+            task_id: row.task_id,
+            task_name: row.task_name,
+            task_action_local_id: row.task_action_local_id,
+            task_action_name: row.task_action_name,
+            status: row.status,
+            retry_of: row.retry_of,
+            result: row.result,
+            progress: row.progress.map(|p| p.0),
+            created: row.created,
+            updated: row.updated,
+        })
+        .collect::<Vec<_>>();
+
+    Ok(pagination.page(entries, |entry| {
+        (sort_times[&entry.actions_log_id], entry.actions_log_id)
+    }))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_timeline)
+        .service(get_action_lineage)
+        .service(get_input_lineage)
+        .service(get_input_state)
+        .service(get_input_state_diff)
+        .service(list_input_script_logs)
+        .service(list_task_script_logs)
+        .service(stream_action_progress)
+        .service(list_actions_log)
+        .service(replay_input)
+        .service(retry_action);
+}
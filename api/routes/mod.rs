@@ -1,6 +1,32 @@
 pub mod accounts;
 pub mod action_categories;
 pub mod actions;
+pub mod apply;
+pub mod approvals;
+pub mod artifacts;
+pub mod chat;
+pub mod egress;
+pub mod email;
+pub mod feature_flags;
+pub mod file_watches;
+pub mod fixtures;
 pub mod inputs;
+pub mod js_libraries;
+pub mod locales;
+pub mod log_retention;
+pub mod logs;
+pub mod mqtt;
+pub mod notify_templates;
+pub mod oidc;
+pub mod openapi;
+pub mod orgs;
+pub mod permissions;
+pub mod push;
+pub mod quotas;
+pub mod sessions;
 pub mod status;
+pub mod tags;
+pub mod task_plans;
 pub mod tasks;
+pub mod trigger_tokens;
+pub mod users;
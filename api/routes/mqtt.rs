@@ -0,0 +1,264 @@
+//! MQTT subscriptions send the messages on a broker's topics to a task trigger. The server's
+//! MQTT bridge picks up changes to the subscriptions within a minute.
+
+use actix_web::{
+    delete, get, post, put,
+    web::{self, Path},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use ergo_auth::Authenticated;
+use ergo_database::object_id::{AccountId, MqttSubscriptionId, TaskId, TaskTriggerId};
+use ergo_tasks::inputs::mqtt::valid_topic_filter;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+
+use crate::{
+    error::{Error, Result},
+    web_app_server::AppStateData,
+};
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct MqttSubscription {
+    pub mqtt_subscription_id: MqttSubscriptionId,
+    pub account_id: AccountId,
+    pub topic_filter: String,
+    pub qos: i16,
+    pub task_id: TaskId,
+    pub trigger: String,
+    pub enabled: bool,
+    pub created: DateTime<Utc>,
+    pub modified: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct MqttSubscriptionInput {
+    /// An account with the `mqtt` account type.
+    pub account_id: AccountId,
+    /// The topic filter to subscribe to. This may contain `+` and `#` wildcards.
+    pub topic_filter: String,
+    #[serde(default = "default_qos")]
+    pub qos: i16,
+    pub task_id: TaskId,
+    /// The local ID of the task trigger to send messages to.
+    pub trigger: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_qos() -> i16 {
+    1
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+async fn get_subscription(
+    tx: &mut PgConnection,
+    auth: &Authenticated,
+    mqtt_subscription_id: &MqttSubscriptionId,
+) -> Result<MqttSubscription> {
+    sqlx::query_as!(
+        MqttSubscription,
+        r##"SELECT mqtt_subscription_id AS "mqtt_subscription_id: MqttSubscriptionId",
+            account_id AS "account_id: AccountId",
+            topic_filter, qos,
+            tt.task_id AS "task_id: TaskId",
+            tt.task_trigger_local_id AS trigger,
+            sub.enabled, sub.created, sub.modified
+        FROM mqtt_subscriptions sub
+        JOIN task_triggers tt USING (task_trigger_id)
+        WHERE mqtt_subscription_id=$1 AND sub.org_id=$2"##,
+        mqtt_subscription_id.0,
+        auth.org_id().0
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::NotFound)
+}
+
+/// Check the input and look up the task trigger that it refers to.
+async fn validate_input(
+    tx: &mut PgConnection,
+    auth: &Authenticated,
+    input: &MqttSubscriptionInput,
+) -> Result<TaskTriggerId> {
+    let mut errors = Vec::new();
+    if !valid_topic_filter(&input.topic_filter) {
+        errors.push(format!("Invalid topic filter {}", input.topic_filter));
+    }
+    if !(0..=2).contains(&input.qos) {
+        errors.push("qos must be 0, 1, or 2".to_string());
+    }
+
+    let is_mqtt_account = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM accounts
+            WHERE account_id=$1 AND org_id=$2 AND account_type_id='mqtt')",
+        input.account_id.0,
+        auth.org_id().0
+    )
+    .fetch_one(&mut *tx)
+    .await?
+    .unwrap_or(false);
+    if !is_mqtt_account {
+        errors.push(format!(
+            "Account {} is not an MQTT account",
+            input.account_id
+        ));
+    }
+
+    if !errors.is_empty() {
+        return Err(Error::ValidationError(errors));
+    }
+
+    let task_trigger_id = sqlx::query_scalar!(
+        r##"SELECT task_trigger_id AS "task_trigger_id: TaskTriggerId"
+        FROM task_triggers
+        JOIN tasks USING (task_id)
+        WHERE task_id=$1 AND task_trigger_local_id=$2 AND org_id=$3 AND NOT deleted
+            AND EXISTS(SELECT 1 FROM user_entity_permissions
+                WHERE permissioned_object IN (uuid_nil(), task_id)
+                AND user_entity_id=ANY($4)
+                AND permission_type='write')"##,
+        input.task_id.0,
+        input.trigger,
+        auth.org_id().0,
+        auth.user_entity_ids().as_slice()
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    Ok(task_trigger_id)
+}
+
+#[get("/mqtt_subscriptions")]
+async fn list_subscriptions(data: AppStateData, auth: Authenticated) -> Result<impl Responder> {
+    let subscriptions = sqlx::query_as!(
+        MqttSubscription,
+        r##"SELECT mqtt_subscription_id AS "mqtt_subscription_id: MqttSubscriptionId",
+            account_id AS "account_id: AccountId",
+            topic_filter, qos,
+            tt.task_id AS "task_id: TaskId",
+            tt.task_trigger_local_id AS trigger,
+            sub.enabled, sub.created, sub.modified
+        FROM mqtt_subscriptions sub
+        JOIN task_triggers tt USING (task_trigger_id)
+        WHERE sub.org_id=$1
+        ORDER BY sub.topic_filter"##,
+        auth.org_id().0
+    )
+    .fetch_all(&data.pg)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(subscriptions))
+}
+
+#[get("/mqtt_subscriptions/{mqtt_subscription_id}")]
+async fn get_subscription_handler(
+    data: AppStateData,
+    auth: Authenticated,
+    mqtt_subscription_id: Path<MqttSubscriptionId>,
+) -> Result<impl Responder> {
+    let mut conn = data.pg.acquire().await?;
+    let subscription = get_subscription(&mut conn, &auth, &mqtt_subscription_id).await?;
+    Ok(HttpResponse::Ok().json(subscription))
+}
+
+#[post("/mqtt_subscriptions")]
+async fn new_subscription(
+    data: AppStateData,
+    auth: Authenticated,
+    payload: web::Json<MqttSubscriptionInput>,
+) -> Result<impl Responder> {
+    let payload = payload.into_inner();
+    let mut conn = data.pg.acquire().await?;
+    let task_trigger_id = validate_input(&mut conn, &auth, &payload).await?;
+
+    let mqtt_subscription_id = MqttSubscriptionId::new();
+    sqlx::query!(
+        "INSERT INTO mqtt_subscriptions
+            (mqtt_subscription_id, org_id, account_id, topic_filter, qos, task_trigger_id,
+                run_as_user, enabled)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        mqtt_subscription_id.0,
+        auth.org_id().0,
+        payload.account_id.0,
+        payload.topic_filter,
+        payload.qos,
+        task_trigger_id.0,
+        auth.user_id().0,
+        payload.enabled
+    )
+    .execute(&mut conn)
+    .await?;
+
+    let subscription = get_subscription(&mut conn, &auth, &mqtt_subscription_id).await?;
+    Ok(HttpResponse::Created().json(subscription))
+}
+
+#[put("/mqtt_subscriptions/{mqtt_subscription_id}")]
+async fn update_subscription(
+    data: AppStateData,
+    auth: Authenticated,
+    mqtt_subscription_id: Path<MqttSubscriptionId>,
+    payload: web::Json<MqttSubscriptionInput>,
+) -> Result<impl Responder> {
+    let payload = payload.into_inner();
+    let mut conn = data.pg.acquire().await?;
+    let task_trigger_id = validate_input(&mut conn, &auth, &payload).await?;
+
+    let result = sqlx::query!(
+        "UPDATE mqtt_subscriptions SET
+            account_id=$3, topic_filter=$4, qos=$5, task_trigger_id=$6, enabled=$7,
+            modified=now()
+        WHERE mqtt_subscription_id=$1 AND org_id=$2",
+        mqtt_subscription_id.0,
+        auth.org_id().0,
+        payload.account_id.0,
+        payload.topic_filter,
+        payload.qos,
+        task_trigger_id.0,
+        payload.enabled
+    )
+    .execute(&mut conn)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    let subscription = get_subscription(&mut conn, &auth, &mqtt_subscription_id).await?;
+    Ok(HttpResponse::Ok().json(subscription))
+}
+
+#[delete("/mqtt_subscriptions/{mqtt_subscription_id}")]
+async fn delete_subscription(
+    data: AppStateData,
+    auth: Authenticated,
+    mqtt_subscription_id: Path<MqttSubscriptionId>,
+) -> Result<impl Responder> {
+    let result = sqlx::query!(
+        "DELETE FROM mqtt_subscriptions WHERE mqtt_subscription_id=$1 AND org_id=$2",
+        mqtt_subscription_id.0,
+        auth.org_id().0
+    )
+    .execute(&data.pg)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_subscriptions)
+        .service(get_subscription_handler)
+        .service(new_subscription)
+        .service(update_subscription)
+        .service(delete_subscription);
+}
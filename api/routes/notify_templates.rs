@@ -0,0 +1,70 @@
+//! The org's templates for notification text.
+
+use actix_web::{delete, get, put, web, HttpResponse, Responder};
+use ergo_auth::Authenticated;
+use ergo_notifications::{
+    templates::{self, validate_template},
+    NotifyEvent, NotifyService,
+};
+use ergo_problem::FieldError;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Error, Result},
+    web_app_server::AppStateData,
+};
+
+#[get("/org/notify_templates")]
+async fn list_notify_templates(data: AppStateData, auth: Authenticated) -> Result<impl Responder> {
+    let mut conn = data.pg.acquire().await?;
+    let templates = templates::list_templates(&mut conn, auth.org_id()).await?;
+    Ok(HttpResponse::Ok().json(templates))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NotifyTemplateInput {
+    pub template: String,
+}
+
+#[put("/org/notify_templates/{event}/{service}")]
+async fn set_notify_template(
+    data: AppStateData,
+    auth: Authenticated,
+    path: web::Path<(NotifyEvent, NotifyService)>,
+    payload: web::Json<NotifyTemplateInput>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+
+    validate_template(&payload.template).map_err(|e| {
+        Error::FieldValidationError(vec![FieldError::new("template", e.to_string())])
+    })?;
+
+    let (event, service) = path.into_inner();
+    let mut conn = data.pg.acquire().await?;
+    templates::set_template(&mut conn, auth.org_id(), event, service, &payload.template).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[delete("/org/notify_templates/{event}/{service}")]
+async fn delete_notify_template(
+    data: AppStateData,
+    auth: Authenticated,
+    path: web::Path<(NotifyEvent, NotifyService)>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+
+    let (event, service) = path.into_inner();
+    let mut conn = data.pg.acquire().await?;
+    let deleted = templates::delete_template(&mut conn, auth.org_id(), event, service).await?;
+    if !deleted {
+        return Err(Error::NotFound);
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_notify_templates)
+        .service(set_notify_template)
+        .service(delete_notify_template);
+}
@@ -0,0 +1,483 @@
+//! Login through OpenID Connect providers. Admins configure each provider along with the claim
+//! rules that map its users to an org and roles. A user who logs in through a provider for the
+//! first time is linked to the existing account with the same email, if the provider has verified
+//! the address, or gets a new account.
+
+use actix_identity::Identity;
+use actix_session::Session;
+use actix_web::{
+    delete, get, http::header, put, web, web::Path, HttpMessage, HttpRequest, HttpResponse,
+    Responder,
+};
+use ergo_auth::{
+    oidc::{map_claims, ClaimMapping, ClaimRule, LoginState, OidcProvider},
+    session, Authenticated,
+};
+use ergo_database::{
+    encryption::{account_field_keys, decrypt_account_fields},
+    object_id::UserId,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use super::sessions::device_info;
+use crate::{
+    backend_data::BackendAppStateData,
+    error::{Error, Result},
+    web_app_server::AppStateData,
+};
+
+/// The session key that holds the [LoginState] between the login redirect and the callback.
+const LOGIN_STATE_KEY: &str = "oidc_login";
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct OidcProviderSummary {
+    pub oidc_provider_id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct OidcProviderInput {
+    /// The name shown on the login page.
+    pub name: String,
+    pub issuer: String,
+    pub client_id: String,
+    /// Omit for public clients, which rely on PKCE alone.
+    pub client_secret: Option<String>,
+    /// Scopes to request in addition to `openid`.
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Rules that pick the org and roles for a user, from the claims in their ID token.
+    pub claim_rules: Vec<ClaimRule>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginQuery {
+    redirect_to: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+fn oidc_error(message: impl Into<String>) -> Error {
+    Error::AuthError(ergo_auth::Error::OidcError(message.into()))
+}
+
+/// Only allow redirects within the app after login.
+fn local_redirect(redirect_to: Option<String>) -> String {
+    redirect_to
+        .filter(|r| r.starts_with('/') && !r.starts_with("//") && !r.contains('\\'))
+        .unwrap_or_else(|| "/".to_string())
+}
+
+fn callback_url(backend_data: &BackendAppStateData, oidc_provider_id: &str) -> String {
+    format!(
+        "{}/api/oidc/{}/callback",
+        backend_data.auth.public_url(),
+        oidc_provider_id
+    )
+}
+
+async fn get_provider(
+    tx: &mut PgConnection,
+    oidc_provider_id: &str,
+) -> Result<(OidcProvider, Vec<ClaimRule>)> {
+    let row = sqlx::query!(
+        r##"SELECT oidc_provider_id, issuer, client_id, client_secret, scopes,
+            claim_rules AS "claim_rules: sqlx::types::Json<Vec<ClaimRule>>"
+        FROM oidc_providers
+        WHERE oidc_provider_id=$1"##,
+        oidc_provider_id
+    )
+    .fetch_optional(tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let client_secret = row
+        .client_secret
+        .map(decrypt_account_fields)
+        .transpose()?
+        .and_then(|s| s.as_str().map(String::from));
+
+    let provider = OidcProvider {
+        oidc_provider_id: row.oidc_provider_id,
+        issuer: row.issuer,
+        client_id: row.client_id,
+        client_secret,
+        scopes: row.scopes,
+    };
+
+    Ok((provider, row.claim_rules.0))
+}
+
+async fn validate_claim_rules(tx: &mut PgConnection, rules: &[ClaimRule]) -> Result<()> {
+    let mut errors = Vec::new();
+    for (i, rule) in rules.iter().enumerate() {
+        if rule.claim.is_empty() {
+            errors.push(format!("Rule {i} has no claim"));
+        }
+
+        let org_exists = sqlx::query_scalar!(
+            "SELECT EXISTS(SELECT 1 FROM orgs WHERE org_id=$1 AND NOT deleted)",
+            rule.org_id.0
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .unwrap_or(false);
+        if !org_exists {
+            errors.push(format!("Rule {i} has unknown org {}", rule.org_id));
+        }
+
+        if let Some(role_id) = rule.role_id.as_ref() {
+            let role_exists = sqlx::query_scalar!(
+                "SELECT EXISTS(SELECT 1 FROM roles WHERE role_id=$1 AND org_id=$2)",
+                role_id.0,
+                rule.org_id.0
+            )
+            .fetch_one(&mut *tx)
+            .await?
+            .unwrap_or(false);
+            if !role_exists {
+                errors.push(format!(
+                    "Rule {i} has role {role_id}, which is not in org {}",
+                    rule.org_id
+                ));
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::ValidationError(errors))
+    }
+}
+
+/// Find the user for an identity at the provider, creating or linking an account if this is the
+/// first time it has logged in, and update the user's org and roles from the claim mapping.
+async fn login_user(
+    tx: &mut PgConnection,
+    oidc_provider_id: &str,
+    claims: &Value,
+    mapping: &ClaimMapping,
+) -> Result<UserId> {
+    let subject = claims["sub"].as_str().unwrap_or_default();
+
+    let linked = sqlx::query_scalar!(
+        r##"SELECT user_id AS "user_id: UserId"
+        FROM user_identities
+        JOIN users USING (user_id)
+        WHERE oidc_provider_id=$1 AND subject=$2 AND NOT deleted"##,
+        oidc_provider_id,
+        subject
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let user_id = match linked {
+        Some(user_id) => user_id,
+        None => {
+            let email = claims["email"]
+                .as_str()
+                .ok_or_else(|| oidc_error("The provider did not supply an email address"))?;
+            let email_verified = claims["email_verified"].as_bool().unwrap_or(false);
+
+            let existing = sqlx::query_scalar!(
+                r##"SELECT user_id AS "user_id: UserId"
+                FROM users
+                WHERE email=$1 AND NOT deleted"##,
+                email
+            )
+            .fetch_optional(&mut *tx)
+            .await?;
+
+            let user_id = match existing {
+                // Without a verified address, anyone who can set their email at the provider
+                // could take over the account.
+                Some(_) if !email_verified => {
+                    return Err(oidc_error(
+                        "An account with this email already exists, and the provider has not verified the address",
+                    ));
+                }
+                Some(user_id) => user_id,
+                None => {
+                    let user_id = UserId::new();
+                    let name = claims["name"].as_str().unwrap_or(email);
+                    sqlx::query!(
+                        r##"INSERT INTO users
+                            (user_id, active_org_id, name, email, password_hash, email_verified)
+                        VALUES ($1, $2, $3, $4, NULL, $5)"##,
+                        user_id.0,
+                        mapping.org_id.0,
+                        name,
+                        email,
+                        email_verified
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                    user_id
+                }
+            };
+
+            sqlx::query!(
+                "INSERT INTO user_identities (oidc_provider_id, subject, user_id) VALUES ($1, $2, $3)",
+                oidc_provider_id,
+                subject,
+                user_id.0
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            user_id
+        }
+    };
+
+    sqlx::query!(
+        "UPDATE user_identities SET last_login=now() WHERE oidc_provider_id=$1 AND subject=$2",
+        oidc_provider_id,
+        subject
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "INSERT INTO org_members (org_id, user_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        mapping.org_id.0,
+        user_id.0
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "UPDATE users SET active_org_id=$2 WHERE user_id=$1",
+        user_id.0,
+        mapping.org_id.0
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let role_ids = mapping.role_ids.iter().map(|r| r.0).collect::<Vec<Uuid>>();
+    sqlx::query!(
+        "DELETE FROM user_roles
+        WHERE user_id=$1 AND oidc_provider_id=$2 AND (org_id<>$3 OR role_id <> ALL($4))",
+        user_id.0,
+        oidc_provider_id,
+        mapping.org_id.0,
+        &role_ids
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "INSERT INTO user_roles (user_id, role_id, org_id, oidc_provider_id)
+        SELECT $1, role_id, $3, $4 FROM UNNEST($2::uuid[]) role_id
+        ON CONFLICT DO NOTHING",
+        user_id.0,
+        &role_ids,
+        mapping.org_id.0,
+        oidc_provider_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(user_id)
+}
+
+/// List the providers that users can log in with.
+#[get("/oidc/providers")]
+async fn list_providers(data: AppStateData) -> Result<impl Responder> {
+    let mut conn = data.pg.acquire().await?;
+    let providers = sqlx::query_as!(
+        OidcProviderSummary,
+        "SELECT oidc_provider_id, name FROM oidc_providers ORDER BY name"
+    )
+    .fetch_all(&mut conn)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(providers))
+}
+
+#[put("/oidc/providers/{oidc_provider_id}")]
+async fn put_provider(
+    data: AppStateData,
+    auth: Authenticated,
+    oidc_provider_id: Path<String>,
+    payload: web::Json<OidcProviderInput>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+    let payload = payload.into_inner();
+
+    let mut conn = data.pg.acquire().await?;
+    validate_claim_rules(&mut conn, &payload.claim_rules).await?;
+
+    let client_secret = payload
+        .client_secret
+        .map(|s| account_field_keys()?.encrypt(&Value::String(s)))
+        .transpose()?;
+
+    sqlx::query!(
+        "INSERT INTO oidc_providers
+            (oidc_provider_id, name, issuer, client_id, client_secret, scopes, claim_rules)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        ON CONFLICT (oidc_provider_id) DO UPDATE SET
+            name=EXCLUDED.name,
+            issuer=EXCLUDED.issuer,
+            client_id=EXCLUDED.client_id,
+            client_secret=EXCLUDED.client_secret,
+            scopes=EXCLUDED.scopes,
+            claim_rules=EXCLUDED.claim_rules,
+            updated=now()",
+        oidc_provider_id.as_str(),
+        payload.name,
+        payload.issuer,
+        payload.client_id,
+        client_secret,
+        &payload.scopes,
+        sqlx::types::Json(&payload.claim_rules) as _
+    )
+    .execute(&mut conn)
+    .await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[delete("/oidc/providers/{oidc_provider_id}")]
+async fn delete_provider(
+    data: AppStateData,
+    auth: Authenticated,
+    oidc_provider_id: Path<String>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+
+    let mut conn = data.pg.acquire().await?;
+    let deleted = sqlx::query!(
+        "DELETE FROM oidc_providers WHERE oidc_provider_id=$1",
+        oidc_provider_id.as_str()
+    )
+    .execute(&mut conn)
+    .await?;
+
+    if deleted.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Start a login by redirecting to the provider.
+#[get("/oidc/{oidc_provider_id}/login")]
+async fn login(
+    data: AppStateData,
+    backend_data: BackendAppStateData,
+    session: Session,
+    oidc_provider_id: Path<String>,
+    query: web::Query<LoginQuery>,
+) -> Result<impl Responder> {
+    let mut conn = data.pg.acquire().await?;
+    let (provider, _) = get_provider(&mut conn, &oidc_provider_id).await?;
+    drop(conn);
+
+    let metadata = provider.discover(backend_data.auth.http_client()).await?;
+    let login = LoginState::new(
+        &provider.oidc_provider_id,
+        local_redirect(query.into_inner().redirect_to),
+    );
+    let url = provider.authorize_url(
+        &metadata,
+        &callback_url(&backend_data, &provider.oidc_provider_id),
+        &login,
+    )?;
+
+    session
+        .insert(LOGIN_STATE_KEY, &login)
+        .map_err(|e| Error::StringError(e.to_string()))?;
+
+    Ok(HttpResponse::Found()
+        .insert_header((header::LOCATION, url))
+        .finish())
+}
+
+/// Finish a login when the provider redirects back to the app.
+#[get("/oidc/{oidc_provider_id}/callback")]
+async fn callback(
+    req: HttpRequest,
+    data: AppStateData,
+    backend_data: BackendAppStateData,
+    session: Session,
+    oidc_provider_id: Path<String>,
+    query: web::Query<CallbackQuery>,
+) -> Result<impl Responder> {
+    let query = query.into_inner();
+    let login = session
+        .remove_as::<LoginState>(LOGIN_STATE_KEY)
+        .and_then(|l| l.ok())
+        .filter(|l| {
+            l.oidc_provider_id == oidc_provider_id.as_str()
+                && query.state.as_deref() == Some(l.state.as_str())
+        })
+        .ok_or(Error::AuthenticationError)?;
+
+    if let Some(error) = query.error {
+        let message = match query.error_description {
+            Some(description) => format!("{error}: {description}"),
+            None => error,
+        };
+        return Err(oidc_error(message));
+    }
+
+    let code = query
+        .code
+        .ok_or_else(|| oidc_error("Missing authorization code"))?;
+
+    let mut conn = data.pg.acquire().await?;
+    let (provider, rules) = get_provider(&mut conn, &oidc_provider_id).await?;
+    drop(conn);
+
+    let http = backend_data.auth.http_client();
+    let metadata = provider.discover(http).await?;
+    let claims = provider
+        .exchange_code(
+            http,
+            &metadata,
+            &callback_url(&backend_data, &provider.oidc_provider_id),
+            &code,
+            &login,
+        )
+        .await?;
+
+    let mapping = map_claims(&rules, &claims).ok_or(Error::AuthorizationError)?;
+
+    let mut tx = data.pg.begin().await?;
+    let user_id = login_user(&mut tx, &provider.oidc_provider_id, &claims, &mapping).await?;
+    let session = session::create_session(
+        &mut tx,
+        &user_id,
+        backend_data.auth.session_lifetime(),
+        device_info(&req),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Identity::login(&req.extensions(), session.session_id.to_string())
+        .map_err(|e| Error::StringError(e.to_string()))?;
+
+    Ok(HttpResponse::Found()
+        .insert_header((header::LOCATION, login.redirect_to))
+        .finish())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_providers)
+        .service(put_provider)
+        .service(delete_provider)
+        .service(login)
+        .service(callback);
+}
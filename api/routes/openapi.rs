@@ -0,0 +1,47 @@
+//! Serve the OpenAPI document, and a Swagger UI page for browsing it.
+
+use actix_web::{get, http::header::ContentType, web, HttpResponse, Responder};
+use lazy_static::lazy_static;
+
+use crate::{error::Result, openapi::openapi_document};
+
+lazy_static! {
+    static ref OPENAPI_DOCUMENT: serde_json::Value = openapi_document();
+}
+
+const SWAGGER_UI_VERSION: &str = "4.15.5";
+
+#[get("/openapi.json")]
+async fn get_openapi() -> Result<impl Responder> {
+    Ok(HttpResponse::Ok().json(&*OPENAPI_DOCUMENT))
+}
+
+#[get("/docs")]
+async fn swagger_ui() -> Result<impl Responder> {
+    let page = format!(
+        r##"<!DOCTYPE html>
+<html>
+<head>
+  <title>Ergo API</title>
+  <meta charset="utf-8">
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@{version}/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@{version}/swagger-ui-bundle.js"></script>
+  <script>
+    window.ui = SwaggerUIBundle({{ url: "openapi.json", dom_id: "#swagger-ui" }});
+  </script>
+</body>
+</html>"##,
+        version = SWAGGER_UI_VERSION
+    );
+
+    Ok(HttpResponse::Ok()
+        .insert_header(ContentType::html())
+        .body(page))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_openapi).service(swagger_ui);
+}
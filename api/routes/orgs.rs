@@ -0,0 +1,499 @@
+//! Org membership. A user can belong to several orgs and switch which one is active, and their
+//! roles are kept separately for each org. Admins invite people by email, and the user with
+//! that email address accepts or declines the invitation.
+
+use actix_web::{
+    delete, get, post, put,
+    web::{self, Path},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Duration, Utc};
+use ergo_auth::Authenticated;
+use ergo_database::object_id::{OrgId, RoleId, UserId};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::{Connection, PgConnection};
+use uuid::Uuid;
+
+use crate::{
+    backend_data::BackendAppStateData,
+    error::{Error, Result},
+    web_app_server::AppStateData,
+};
+
+/// How long an invitation can be accepted.
+const INVITATION_LIFETIME_DAYS: i64 = 7;
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct OrgMembership {
+    pub org_id: OrgId,
+    pub name: String,
+    /// True for the org that the user is currently working in.
+    pub active: bool,
+    pub joined: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct SwitchOrgInput {
+    pub org_id: OrgId,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct OrgMember {
+    pub user_id: UserId,
+    pub name: String,
+    pub email: String,
+    /// The member's roles in this org.
+    pub role_ids: Vec<RoleId>,
+    pub joined: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema, sqlx::Type)]
+#[sqlx(type_name = "org_invitation_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum InvitationStatus {
+    Pending,
+    Accepted,
+    Declined,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct InvitationInput {
+    pub email: String,
+    /// Roles in the org to give the user when they accept.
+    #[serde(default)]
+    pub role_ids: Vec<RoleId>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Invitation {
+    pub invitation_id: Uuid,
+    pub org_id: OrgId,
+    pub org_name: String,
+    pub email: String,
+    pub role_ids: Vec<RoleId>,
+    pub invited_by: Option<UserId>,
+    pub status: InvitationStatus,
+    pub expires: DateTime<Utc>,
+    pub created: DateTime<Utc>,
+}
+
+/// List the orgs that the requester belongs to.
+#[get("/orgs")]
+async fn list_orgs(data: AppStateData, auth: Authenticated) -> Result<impl Responder> {
+    let orgs = sqlx::query_as!(
+        OrgMembership,
+        r##"SELECT orgs.org_id AS "org_id: OrgId", orgs.name,
+            orgs.org_id = users.active_org_id AS "active!",
+            org_members.created AS joined
+        FROM org_members
+        JOIN orgs USING(org_id)
+        JOIN users USING(user_id)
+        WHERE org_members.user_id=$1 AND NOT orgs.deleted
+        ORDER BY orgs.name"##,
+        auth.user_id().0
+    )
+    .fetch_all(&data.pg)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(orgs))
+}
+
+/// Change the requester's active org. API keys stay in the org they were created in.
+#[put("/orgs/active")]
+async fn switch_org(
+    data: AppStateData,
+    auth: Authenticated,
+    payload: web::Json<SwitchOrgInput>,
+) -> Result<impl Responder> {
+    let result = sqlx::query!(
+        "UPDATE users SET active_org_id=$2
+        WHERE user_id=$1 AND EXISTS(
+            SELECT 1 FROM org_members
+            JOIN orgs USING(org_id)
+            WHERE org_members.user_id=$1 AND org_id=$2 AND NOT orgs.deleted
+        )",
+        auth.user_id().0,
+        payload.org_id.0
+    )
+    .execute(&data.pg)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[get("/orgs/members")]
+async fn list_members(data: AppStateData, auth: Authenticated) -> Result<impl Responder> {
+    let members = sqlx::query_as!(
+        OrgMember,
+        r##"SELECT users.user_id AS "user_id: UserId", users.name, users.email,
+            COALESCE(
+                array_agg(user_roles.role_id) FILTER(WHERE user_roles.role_id IS NOT NULL),
+                '{}'
+            ) AS "role_ids!: Vec<RoleId>",
+            org_members.created AS joined
+        FROM org_members
+        JOIN users USING(user_id)
+        LEFT JOIN user_roles ON user_roles.user_id = org_members.user_id
+            AND user_roles.org_id = org_members.org_id
+        WHERE org_members.org_id=$1 AND NOT users.deleted
+        GROUP BY users.user_id, org_members.created
+        ORDER BY users.name"##,
+        auth.org_id().0
+    )
+    .fetch_all(&data.pg)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(members))
+}
+
+/// Remove a user from the active org, along with their roles there. Admins can remove anyone,
+/// and other users can remove themselves to leave the org. Every user must keep at least one
+/// org.
+#[delete("/orgs/members/{user_id}")]
+async fn remove_member(
+    data: AppStateData,
+    auth: Authenticated,
+    user_id: Path<UserId>,
+) -> Result<impl Responder> {
+    let user_id = user_id.into_inner();
+    if &user_id != auth.user_id() {
+        auth.expect_admin()?;
+    }
+
+    let org_id = auth.org_id();
+    let mut conn = data.pg.acquire().await?;
+    let mut tx = conn.begin().await?;
+
+    let removed = sqlx::query!(
+        "DELETE FROM org_members WHERE org_id=$1 AND user_id=$2",
+        org_id.0,
+        user_id.0
+    )
+    .execute(&mut tx)
+    .await?;
+
+    if removed.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    sqlx::query!(
+        "DELETE FROM user_roles WHERE org_id=$1 AND user_id=$2",
+        org_id.0,
+        user_id.0
+    )
+    .execute(&mut tx)
+    .await?;
+
+    // Move the user to another org if they were working in this one.
+    let other_org = sqlx::query_scalar!(
+        r##"SELECT org_id FROM org_members
+        JOIN orgs USING(org_id)
+        WHERE user_id=$1 AND NOT orgs.deleted
+        ORDER BY org_members.created
+        LIMIT 1"##,
+        user_id.0
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .ok_or_else(|| {
+        Error::ValidationError(vec![
+            "A user can not be removed from their only org".to_string()
+        ])
+    })?;
+
+    sqlx::query!(
+        "UPDATE users SET active_org_id=$2 WHERE user_id=$1 AND active_org_id=$3",
+        user_id.0,
+        other_org,
+        org_id.0
+    )
+    .execute(&mut tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Check that every role belongs to the org.
+async fn check_roles(conn: &mut PgConnection, org_id: &OrgId, role_ids: &[RoleId]) -> Result<()> {
+    let ids = role_ids.iter().map(|r| r.0).collect::<Vec<_>>();
+    let found = sqlx::query_scalar!(
+        r##"SELECT COUNT(*) AS "count!" FROM roles WHERE org_id=$1 AND role_id = ANY($2)"##,
+        org_id.0,
+        &ids
+    )
+    .fetch_one(conn)
+    .await?;
+
+    let mut unique = ids.clone();
+    unique.sort();
+    unique.dedup();
+    if found != unique.len() as i64 {
+        return Err(Error::ValidationError(vec![
+            "Invitations can only give roles in the org".to_string(),
+        ]));
+    }
+
+    Ok(())
+}
+
+/// Invite someone to the active org by email. Inviting the same address again replaces the
+/// pending invitation.
+#[post("/orgs/invitations")]
+async fn invite(
+    data: AppStateData,
+    backend_data: BackendAppStateData,
+    auth: Authenticated,
+    payload: web::Json<InvitationInput>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+
+    let InvitationInput { email, role_ids } = payload.into_inner();
+    let email = email.trim();
+    if !email.contains('@') {
+        return Err(Error::ValidationError(vec![
+            "Invalid email address".to_string()
+        ]));
+    }
+
+    let org_id = auth.org_id();
+    let mut conn = data.pg.acquire().await?;
+    let mut tx = conn.begin().await?;
+    check_roles(&mut tx, org_id, &role_ids).await?;
+
+    let is_member = sqlx::query_scalar!(
+        r##"SELECT EXISTS(
+            SELECT 1 FROM org_members
+            JOIN users USING(user_id)
+            WHERE org_id=$1 AND lower(email) = lower($2) AND NOT deleted
+        ) AS "exists!""##,
+        org_id.0,
+        email
+    )
+    .fetch_one(&mut tx)
+    .await?;
+
+    if is_member {
+        return Err(Error::ValidationError(vec![format!(
+            "{} is already a member of this org",
+            email
+        )]));
+    }
+
+    let ids = role_ids.iter().map(|r| r.0).collect::<Vec<_>>();
+    let invitation = sqlx::query_as!(
+        Invitation,
+        r##"WITH ins AS (
+            INSERT INTO org_invitations
+                (invitation_id, org_id, email, role_ids, invited_by, expires)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (org_id, lower(email)) WHERE status = 'pending'
+            DO UPDATE SET role_ids=EXCLUDED.role_ids, invited_by=EXCLUDED.invited_by,
+                expires=EXCLUDED.expires, updated=now()
+            RETURNING *
+        )
+        SELECT invitation_id, ins.org_id AS "org_id: OrgId", orgs.name AS org_name,
+            email, role_ids AS "role_ids: Vec<RoleId>", invited_by AS "invited_by: UserId",
+            status AS "status: InvitationStatus", expires, ins.created
+        FROM ins
+        JOIN orgs USING(org_id)"##,
+        Uuid::new_v4(),
+        org_id.0,
+        email,
+        &ids,
+        auth.user_id().0,
+        Utc::now() + Duration::days(INVITATION_LIFETIME_DAYS)
+    )
+    .fetch_one(&mut tx)
+    .await?;
+
+    let inviter = sqlx::query_scalar!("SELECT name FROM users WHERE user_id=$1", auth.user_id().0)
+        .fetch_one(&mut tx)
+        .await?;
+
+    tx.commit().await?;
+
+    backend_data
+        .mailer
+        .send_invitation(email, &invitation.org_name, &inviter)
+        .await?;
+
+    Ok(HttpResponse::Created().json(invitation))
+}
+
+/// List the active org's pending invitations.
+#[get("/orgs/invitations")]
+async fn list_org_invitations(data: AppStateData, auth: Authenticated) -> Result<impl Responder> {
+    auth.expect_admin()?;
+
+    let invitations = sqlx::query_as!(
+        Invitation,
+        r##"SELECT invitation_id, org_id AS "org_id: OrgId", orgs.name AS org_name,
+            email, role_ids AS "role_ids: Vec<RoleId>", invited_by AS "invited_by: UserId",
+            status AS "status: InvitationStatus", expires, org_invitations.created
+        FROM org_invitations
+        JOIN orgs USING(org_id)
+        WHERE org_id=$1 AND status = 'pending'
+        ORDER BY org_invitations.created DESC"##,
+        auth.org_id().0
+    )
+    .fetch_all(&data.pg)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(invitations))
+}
+
+#[delete("/orgs/invitations/{invitation_id}")]
+async fn revoke_invitation(
+    data: AppStateData,
+    auth: Authenticated,
+    invitation_id: Path<Uuid>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+
+    let result = sqlx::query!(
+        "DELETE FROM org_invitations WHERE invitation_id=$1 AND org_id=$2 AND status = 'pending'",
+        invitation_id.into_inner(),
+        auth.org_id().0
+    )
+    .execute(&data.pg)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// List the pending invitations sent to the requester's email address.
+#[get("/invitations")]
+async fn list_my_invitations(data: AppStateData, auth: Authenticated) -> Result<impl Responder> {
+    let invitations = sqlx::query_as!(
+        Invitation,
+        r##"SELECT invitation_id, org_id AS "org_id: OrgId", orgs.name AS org_name,
+            org_invitations.email, role_ids AS "role_ids: Vec<RoleId>",
+            invited_by AS "invited_by: UserId",
+            status AS "status: InvitationStatus", expires, org_invitations.created
+        FROM org_invitations
+        JOIN orgs USING(org_id)
+        JOIN users ON lower(users.email) = lower(org_invitations.email)
+        WHERE users.user_id=$1 AND users.email_verified
+            AND status = 'pending' AND expires > now() AND NOT orgs.deleted
+        ORDER BY org_invitations.created DESC"##,
+        auth.user_id().0
+    )
+    .fetch_all(&data.pg)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(invitations))
+}
+
+/// Mark one of the requester's pending invitations as accepted or declined, and return the org
+/// and roles that it was for.
+async fn answer_invitation(
+    tx: &mut PgConnection,
+    user_id: &UserId,
+    invitation_id: Uuid,
+    status: InvitationStatus,
+) -> Result<(Uuid, Vec<Uuid>)> {
+    let invitation = sqlx::query!(
+        "UPDATE org_invitations SET status=$3, updated=now()
+        WHERE invitation_id=$1 AND status = 'pending' AND expires > now()
+            AND lower(email) = (
+                SELECT lower(email) FROM users
+                WHERE user_id=$2 AND email_verified AND NOT deleted
+            )
+        RETURNING org_id, role_ids",
+        invitation_id,
+        user_id.0,
+        status as _
+    )
+    .fetch_optional(tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    Ok((invitation.org_id, invitation.role_ids))
+}
+
+/// Join the org that sent the invitation. This doesn't change the active org.
+#[post("/invitations/{invitation_id}/accept")]
+async fn accept_invitation(
+    data: AppStateData,
+    auth: Authenticated,
+    invitation_id: Path<Uuid>,
+) -> Result<impl Responder> {
+    let user_id = auth.user_id();
+    let mut conn = data.pg.acquire().await?;
+    let mut tx = conn.begin().await?;
+
+    let (org_id, role_ids) = answer_invitation(
+        &mut tx,
+        user_id,
+        invitation_id.into_inner(),
+        InvitationStatus::Accepted,
+    )
+    .await?;
+
+    sqlx::query!(
+        "INSERT INTO org_members (org_id, user_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        org_id,
+        user_id.0
+    )
+    .execute(&mut tx)
+    .await?;
+
+    // Roles deleted since the invitation was sent are skipped.
+    sqlx::query!(
+        "INSERT INTO user_roles (user_id, role_id, org_id)
+        SELECT $1, role_id, org_id FROM roles
+        WHERE org_id=$2 AND role_id = ANY($3)
+        ON CONFLICT DO NOTHING",
+        user_id.0,
+        org_id,
+        &role_ids
+    )
+    .execute(&mut tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[post("/invitations/{invitation_id}/decline")]
+async fn decline_invitation(
+    data: AppStateData,
+    auth: Authenticated,
+    invitation_id: Path<Uuid>,
+) -> Result<impl Responder> {
+    let mut conn = data.pg.acquire().await?;
+    answer_invitation(
+        &mut conn,
+        auth.user_id(),
+        invitation_id.into_inner(),
+        InvitationStatus::Declined,
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_orgs)
+        .service(switch_org)
+        .service(list_members)
+        .service(remove_member)
+        .service(invite)
+        .service(list_org_invitations)
+        .service(revoke_invitation)
+        .service(list_my_invitations)
+        .service(accept_invitation)
+        .service(decline_invitation);
+}
@@ -0,0 +1,511 @@
+//! Roles, and the permissions that they grant on tasks, triggers, and actions.
+//!
+//! A grant gives a role `read`, `write`, or `trigger_event` permission on a single object, or on
+//! every object in the org when no object is given. A `trigger_event` grant on a task covers all
+//! of the task's triggers. `read_sensitive` on a task shows the payloads of its sensitive
+//! triggers in the logs. Managing roles requires an admin.
+
+use actix_web::{
+    delete, get, post, put,
+    web::{self, Path},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use ergo_auth::{Authenticated, PermissionType, UserEntityList};
+use ergo_database::object_id::{OrgId, RoleId, TaskId, UserId};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::{Connection, PgConnection};
+use uuid::Uuid;
+
+use crate::{
+    error::{Error, Result},
+    web_app_server::AppStateData,
+};
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Role {
+    pub role_id: RoleId,
+    pub name: String,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct RoleInput {
+    pub name: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum GrantObjectType {
+    Task,
+    TaskTrigger,
+    Action,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+pub struct Grant {
+    pub permission_type: PermissionType,
+    /// The object that the permission applies to, or `None` for every object.
+    pub object_id: Option<Uuid>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct EffectiveGrant {
+    pub permission_type: PermissionType,
+    pub object_id: Option<Uuid>,
+    /// The role that holds the grant, or `None` if it was granted directly to the user or API
+    /// key.
+    pub role_id: Option<RoleId>,
+    pub role_name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct EffectivePermissions {
+    pub object_id: Uuid,
+    pub object_type: GrantObjectType,
+    pub permissions: Vec<PermissionType>,
+    /// The grants that the permissions come from.
+    pub grants: Vec<EffectiveGrant>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EffectivePermissionsQuery {
+    pub object_id: Uuid,
+    /// The user to check. Defaults to the requester. Only admins can check other users.
+    pub user_id: Option<UserId>,
+}
+
+struct GrantObject {
+    object_type: GrantObjectType,
+    /// For a trigger, the task that it belongs to.
+    task_id: Option<TaskId>,
+}
+
+/// Figure out what kind of object the ID refers to, if the org can see it.
+async fn find_object(
+    conn: &mut PgConnection,
+    org_id: &OrgId,
+    object_id: Uuid,
+) -> Result<Option<GrantObject>> {
+    let found = sqlx::query!(
+        r##"SELECT
+            EXISTS(SELECT 1 FROM tasks WHERE task_id=$1 AND org_id=$2 AND NOT deleted) AS "task!",
+            (SELECT task_id FROM task_triggers
+                JOIN tasks USING(task_id)
+                WHERE task_trigger_id=$1 AND org_id=$2 AND NOT deleted
+            ) AS "trigger_task_id: TaskId",
+            EXISTS(SELECT 1 FROM actions WHERE action_id=$1) AS "action!"
+        "##,
+        object_id,
+        org_id.0
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    let object = if found.task {
+        Some(GrantObject {
+            object_type: GrantObjectType::Task,
+            task_id: None,
+        })
+    } else if let Some(task_id) = found.trigger_task_id {
+        Some(GrantObject {
+            object_type: GrantObjectType::TaskTrigger,
+            task_id: Some(task_id),
+        })
+    } else if found.action {
+        Some(GrantObject {
+            object_type: GrantObjectType::Action,
+            task_id: None,
+        })
+    } else {
+        None
+    };
+
+    Ok(object)
+}
+
+/// Check whether a permission type means anything for the object type. Triggers are read and
+/// written through their tasks, and anyone can read actions.
+fn valid_grant(object_type: GrantObjectType, permission_type: PermissionType) -> bool {
+    match object_type {
+        GrantObjectType::Task => true,
+        GrantObjectType::TaskTrigger => permission_type == PermissionType::TriggerEvent,
+        GrantObjectType::Action => permission_type == PermissionType::Write,
+    }
+}
+
+async fn check_role(conn: &mut PgConnection, org_id: &OrgId, role_id: &RoleId) -> Result<()> {
+    let exists = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM roles WHERE role_id=$1 AND org_id=$2)",
+        role_id.0,
+        org_id.0
+    )
+    .fetch_one(&mut *conn)
+    .await?
+    .unwrap_or(false);
+
+    if exists {
+        Ok(())
+    } else {
+        Err(Error::NotFound)
+    }
+}
+
+/// Return an authorization error unless the requester is an admin, or has been granted the
+/// permission on the object or on every object.
+pub(crate) async fn require_permission(
+    conn: &mut PgConnection,
+    auth: &Authenticated,
+    permission_type: PermissionType,
+    object_id: Uuid,
+) -> Result<()> {
+    if auth.is_admin() {
+        return Ok(());
+    }
+
+    let allowed = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM user_entity_permissions
+            WHERE user_entity_id = ANY($1)
+            AND permission_type = $2
+            AND permissioned_object IN (uuid_nil(), $3))",
+        auth.user_entity_ids().as_slice(),
+        permission_type as _,
+        object_id
+    )
+    .fetch_one(&mut *conn)
+    .await?
+    .unwrap_or(false);
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(Error::AuthorizationError)
+    }
+}
+
+#[get("/roles")]
+async fn list_roles(data: AppStateData, auth: Authenticated) -> Result<impl Responder> {
+    let roles = sqlx::query_as!(
+        Role,
+        r##"SELECT role_id AS "role_id: RoleId", name, created
+        FROM roles
+        WHERE org_id=$1
+        ORDER BY name"##,
+        auth.org_id().0
+    )
+    .fetch_all(&data.pg)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(roles))
+}
+
+#[post("/roles")]
+async fn new_role(
+    data: AppStateData,
+    auth: Authenticated,
+    payload: web::Json<RoleInput>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+
+    let name = payload.into_inner().name;
+    if name.trim().is_empty() {
+        return Err(Error::ValidationError(vec![
+            "Role name is empty".to_string()
+        ]));
+    }
+
+    let role = sqlx::query_as!(
+        Role,
+        r##"INSERT INTO roles (role_id, org_id, name) VALUES ($1, $2, $3)
+        ON CONFLICT DO NOTHING
+        RETURNING role_id AS "role_id: RoleId", name, created"##,
+        RoleId::new().0,
+        auth.org_id().0,
+        name
+    )
+    .fetch_optional(&data.pg)
+    .await?
+    .ok_or_else(|| Error::ValidationError(vec![format!("A role named {} already exists", name)]))?;
+
+    Ok(HttpResponse::Created().json(role))
+}
+
+/// Delete a role, along with its grants and memberships.
+#[delete("/roles/{role_id}")]
+async fn delete_role(
+    data: AppStateData,
+    auth: Authenticated,
+    role_id: Path<RoleId>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+
+    let mut conn = data.pg.acquire().await?;
+    let mut tx = conn.begin().await?;
+    check_role(&mut tx, auth.org_id(), &role_id).await?;
+
+    sqlx::query!(
+        "DELETE FROM user_entity_permissions WHERE user_entity_id=$1",
+        role_id.0
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!("DELETE FROM user_roles WHERE role_id=$1", role_id.0)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("DELETE FROM roles WHERE role_id=$1", role_id.0)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[put("/roles/{role_id}/users/{user_id}")]
+async fn add_role_user(
+    data: AppStateData,
+    auth: Authenticated,
+    path: Path<(RoleId, UserId)>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+    let (role_id, user_id) = path.into_inner();
+
+    let mut conn = data.pg.acquire().await?;
+    check_role(&mut conn, auth.org_id(), &role_id).await?;
+
+    let added = sqlx::query!(
+        "INSERT INTO user_roles (user_id, role_id, org_id)
+        SELECT user_id, $2, $3 FROM org_members
+        JOIN users USING(user_id)
+        WHERE user_id=$1 AND org_id=$3 AND NOT deleted
+        ON CONFLICT DO NOTHING",
+        user_id.0,
+        role_id.0,
+        auth.org_id().0
+    )
+    .execute(&mut conn)
+    .await?;
+
+    if added.rows_affected() == 0 {
+        // Either the user is already in the role, or isn't a user in this org.
+        let exists = sqlx::query_scalar!(
+            "SELECT EXISTS(SELECT 1 FROM user_roles WHERE user_id=$1 AND role_id=$2)",
+            user_id.0,
+            role_id.0
+        )
+        .fetch_one(&mut conn)
+        .await?
+        .unwrap_or(false);
+
+        if !exists {
+            return Err(Error::NotFound);
+        }
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[delete("/roles/{role_id}/users/{user_id}")]
+async fn remove_role_user(
+    data: AppStateData,
+    auth: Authenticated,
+    path: Path<(RoleId, UserId)>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+    let (role_id, user_id) = path.into_inner();
+
+    let result = sqlx::query!(
+        "DELETE FROM user_roles WHERE role_id=$1 AND user_id=$2 AND org_id=$3",
+        role_id.0,
+        user_id.0,
+        auth.org_id().0
+    )
+    .execute(&data.pg)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[get("/roles/{role_id}/grants")]
+async fn list_grants(
+    data: AppStateData,
+    auth: Authenticated,
+    role_id: Path<RoleId>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+
+    let mut conn = data.pg.acquire().await?;
+    check_role(&mut conn, auth.org_id(), &role_id).await?;
+
+    let grants = sqlx::query_as!(
+        Grant,
+        r##"SELECT permission_type AS "permission_type: PermissionType",
+            NULLIF(permissioned_object, uuid_nil()) AS object_id
+        FROM user_entity_permissions
+        WHERE user_entity_id=$1 AND permission_type <> 'create'
+        ORDER BY permissioned_object, permission_type"##,
+        role_id.0
+    )
+    .fetch_all(&mut conn)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(grants))
+}
+
+#[post("/roles/{role_id}/grants")]
+async fn add_grant(
+    data: AppStateData,
+    auth: Authenticated,
+    role_id: Path<RoleId>,
+    payload: web::Json<Grant>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+    let grant = payload.into_inner();
+
+    let mut conn = data.pg.acquire().await?;
+    check_role(&mut conn, auth.org_id(), &role_id).await?;
+
+    if let Some(object_id) = grant.object_id {
+        let object = find_object(&mut conn, auth.org_id(), object_id)
+            .await?
+            .ok_or(Error::NotFound)?;
+
+        if !valid_grant(object.object_type, grant.permission_type) {
+            let message = match object.object_type {
+                GrantObjectType::TaskTrigger => "Triggers only accept trigger_event grants",
+                _ => "Actions only accept write grants",
+            };
+            return Err(Error::ValidationError(vec![message.to_string()]));
+        }
+    }
+
+    sqlx::query!(
+        "INSERT INTO user_entity_permissions (user_entity_id, permission_type, permissioned_object)
+        VALUES ($1, $2, COALESCE($3, uuid_nil()))
+        ON CONFLICT DO NOTHING",
+        role_id.0,
+        grant.permission_type as _,
+        grant.object_id
+    )
+    .execute(&mut conn)
+    .await?;
+
+    Ok(HttpResponse::Created().json(grant))
+}
+
+/// Revoke a grant from a role. Use the nil UUID as the object ID to revoke a grant that
+/// applies to every object.
+#[delete("/roles/{role_id}/grants/{permission_type}/{object_id}")]
+async fn remove_grant(
+    data: AppStateData,
+    auth: Authenticated,
+    path: Path<(RoleId, PermissionType, Uuid)>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+    let (role_id, permission_type, object_id) = path.into_inner();
+
+    let mut conn = data.pg.acquire().await?;
+    check_role(&mut conn, auth.org_id(), &role_id).await?;
+
+    let result = sqlx::query!(
+        "DELETE FROM user_entity_permissions
+        WHERE user_entity_id=$1 AND permission_type=$2 AND permissioned_object=$3",
+        role_id.0,
+        permission_type as _,
+        object_id
+    )
+    .execute(&mut conn)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// List the permissions that a user has on an object, and where each one comes from.
+#[get("/permissions/effective")]
+async fn effective_permissions(
+    data: AppStateData,
+    auth: Authenticated,
+    query: web::Query<EffectivePermissionsQuery>,
+) -> Result<impl Responder> {
+    let EffectivePermissionsQuery { object_id, user_id } = query.into_inner();
+    let mut conn = data.pg.acquire().await?;
+
+    let user_entity_ids = match user_id {
+        Some(user_id) if &user_id != auth.user_id() => {
+            auth.expect_admin()?;
+            let roles = sqlx::query_scalar!(
+                r##"SELECT role_id AS "role_id: RoleId" FROM user_roles
+                WHERE user_id=$1 AND org_id=$2"##,
+                user_id.0,
+                auth.org_id().0
+            )
+            .fetch_all(&mut conn)
+            .await?;
+
+            let mut ids = roles.into_iter().map(|r| r.0).collect::<UserEntityList>();
+            ids.push(user_id.0);
+            ids
+        }
+        _ => auth.user_entity_ids(),
+    };
+
+    let object = find_object(&mut conn, auth.org_id(), object_id)
+        .await?
+        .ok_or(Error::NotFound)?;
+
+    let grants = sqlx::query_as!(
+        EffectiveGrant,
+        r##"SELECT p.permission_type AS "permission_type: PermissionType",
+            NULLIF(p.permissioned_object, uuid_nil()) AS object_id,
+            roles.role_id AS "role_id?: RoleId",
+            roles.name AS "role_name?"
+        FROM user_entity_permissions p
+        LEFT JOIN roles ON roles.role_id = p.user_entity_id
+        WHERE p.user_entity_id = ANY($1)
+            AND p.permission_type <> 'create'
+            AND (
+                p.permissioned_object IN (uuid_nil(), $2)
+                OR (p.permission_type = 'trigger_event' AND p.permissioned_object = $3)
+            )
+        ORDER BY p.permission_type, roles.name NULLS FIRST"##,
+        user_entity_ids.as_slice(),
+        object_id,
+        object.task_id.map(|t| t.0)
+    )
+    .fetch_all(&mut conn)
+    .await?;
+
+    let mut permissions = grants
+        .iter()
+        .map(|g| g.permission_type)
+        .filter(|p| valid_grant(object.object_type, *p))
+        .collect::<Vec<_>>();
+    permissions.dedup();
+
+    Ok(HttpResponse::Ok().json(EffectivePermissions {
+        object_id,
+        object_type: object.object_type,
+        permissions,
+        grants,
+    }))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_roles)
+        .service(new_role)
+        .service(delete_role)
+        .service(add_role_user)
+        .service(remove_role_user)
+        .service(list_grants)
+        .service(add_grant)
+        .service(remove_grant)
+        .service(effective_permissions);
+}
@@ -0,0 +1,196 @@
+use actix_web::{
+    delete, get,
+    http::header,
+    post, put,
+    web::{self, Path},
+    HttpRequest, HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use ergo_auth::Authenticated;
+use ergo_notifications::{
+    push::{PushSubscriptionKind, DEFAULT_PUSH_EVENTS},
+    NotifyEvent,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    error::{Error, Result},
+    web_app_server::AppStateData,
+};
+
+#[derive(Debug, Serialize)]
+struct VapidPublicKey {
+    public_key: String,
+}
+
+/// The key that browsers need in order to subscribe to push notifications from this server.
+#[get("/push/vapid_public_key")]
+async fn vapid_public_key() -> Result<impl Responder> {
+    let public_key = std::env::var("VAPID_PUBLIC_KEY").map_err(|_| Error::NotFound)?;
+    Ok(HttpResponse::Ok().json(VapidPublicKey { public_key }))
+}
+
+#[derive(Debug, Serialize)]
+struct PushSubscription {
+    push_subscription_id: Uuid,
+    kind: PushSubscriptionKind,
+    user_agent: Option<String>,
+    created: DateTime<Utc>,
+}
+
+#[get("/push/subscriptions")]
+async fn list_subscriptions(data: AppStateData, auth: Authenticated) -> Result<impl Responder> {
+    let subscriptions = sqlx::query_as!(
+        PushSubscription,
+        r##"SELECT push_subscription_id, kind AS "kind: PushSubscriptionKind", user_agent, created
+        FROM push_subscriptions
+        WHERE user_id=$1
+        ORDER BY created"##,
+        auth.user_id().0
+    )
+    .fetch_all(&data.pg)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(subscriptions))
+}
+
+#[derive(Debug, Deserialize)]
+struct SubscriptionKeys {
+    p256dh: String,
+    auth: String,
+}
+
+/// A browser `PushSubscription` in its JSON form, or an FCM registration token.
+#[derive(Debug, Deserialize)]
+struct SubscriptionInput {
+    #[serde(default = "default_kind")]
+    kind: PushSubscriptionKind,
+    /// The web push endpoint, or the FCM registration token.
+    endpoint: String,
+    keys: Option<SubscriptionKeys>,
+}
+
+fn default_kind() -> PushSubscriptionKind {
+    PushSubscriptionKind::WebPush
+}
+
+#[post("/push/subscriptions")]
+async fn add_subscription(
+    data: AppStateData,
+    auth: Authenticated,
+    req: HttpRequest,
+    payload: web::Json<SubscriptionInput>,
+) -> Result<impl Responder> {
+    let payload = payload.into_inner();
+    if payload.kind == PushSubscriptionKind::WebPush && payload.keys.is_none() {
+        return Err(Error::ValidationError(vec![
+            "Web push subscriptions require keys".to_string(),
+        ]));
+    }
+
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|h| h.to_str().ok());
+
+    // Browsers reuse the endpoint when resubscribing, so move it to this user if it exists.
+    let push_subscription_id = sqlx::query_scalar!(
+        r##"INSERT INTO push_subscriptions (user_id, kind, endpoint, p256dh, auth, user_agent)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (endpoint) DO UPDATE SET user_id=EXCLUDED.user_id, kind=EXCLUDED.kind,
+            p256dh=EXCLUDED.p256dh, auth=EXCLUDED.auth, user_agent=EXCLUDED.user_agent
+        RETURNING push_subscription_id"##,
+        auth.user_id().0,
+        payload.kind as _,
+        payload.endpoint,
+        payload.keys.as_ref().map(|k| k.p256dh.as_str()),
+        payload.keys.as_ref().map(|k| k.auth.as_str()),
+        user_agent
+    )
+    .fetch_one(&data.pg)
+    .await?;
+
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "push_subscription_id": push_subscription_id
+    })))
+}
+
+#[delete("/push/subscriptions/{push_subscription_id}")]
+async fn delete_subscription(
+    data: AppStateData,
+    auth: Authenticated,
+    push_subscription_id: Path<Uuid>,
+) -> Result<impl Responder> {
+    let result = sqlx::query!(
+        "DELETE FROM push_subscriptions WHERE push_subscription_id=$1 AND user_id=$2",
+        push_subscription_id.into_inner(),
+        auth.user_id().0
+    )
+    .execute(&data.pg)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PushPreferences {
+    /// The events that should be pushed to the user's devices.
+    push_events: Vec<NotifyEvent>,
+    /// Don't send any push notifications until this time.
+    muted_until: Option<DateTime<Utc>>,
+}
+
+#[get("/push/preferences")]
+async fn get_preferences(data: AppStateData, auth: Authenticated) -> Result<impl Responder> {
+    let prefs = sqlx::query_as!(
+        PushPreferences,
+        r##"SELECT push_events AS "push_events: Vec<NotifyEvent>", muted_until
+        FROM user_notify_preferences
+        WHERE user_id=$1"##,
+        auth.user_id().0
+    )
+    .fetch_optional(&data.pg)
+    .await?
+    .unwrap_or_else(|| PushPreferences {
+        push_events: DEFAULT_PUSH_EVENTS.to_vec(),
+        muted_until: None,
+    });
+
+    Ok(HttpResponse::Ok().json(prefs))
+}
+
+#[put("/push/preferences")]
+async fn set_preferences(
+    data: AppStateData,
+    auth: Authenticated,
+    payload: web::Json<PushPreferences>,
+) -> Result<impl Responder> {
+    let payload = payload.into_inner();
+    sqlx::query!(
+        "INSERT INTO user_notify_preferences (user_id, push_events, muted_until)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (user_id) DO UPDATE
+            SET push_events=EXCLUDED.push_events, muted_until=EXCLUDED.muted_until",
+        auth.user_id().0,
+        payload.push_events.as_slice() as _,
+        payload.muted_until
+    )
+    .execute(&data.pg)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(payload))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(vapid_public_key)
+        .service(list_subscriptions)
+        .service(add_subscription)
+        .service(delete_subscription)
+        .service(get_preferences)
+        .service(set_preferences);
+}
@@ -0,0 +1,106 @@
+use actix_web::{
+    get, put,
+    web::{self, Path},
+    HttpResponse, Responder,
+};
+use chrono::NaiveDate;
+use ergo_auth::Authenticated;
+use ergo_database::object_id::{OrgId, TaskId};
+use ergo_tasks::{
+    costs::{self, UsageReportFilter},
+    quotas::{self, OrgQuotas},
+};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::{error::Result, web_app_server::AppStateData};
+
+/// The quotas and current usage for the requester's org.
+#[get("/org/quotas")]
+pub async fn get_own_quotas(data: AppStateData, auth: Authenticated) -> Result<impl Responder> {
+    let mut conn = data.pg.acquire().await?;
+    let report = quotas::get_quota_report(&mut conn, auth.org_id()).await?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+#[get("/orgs/{org_id}/quotas")]
+pub async fn get_org_quotas(
+    data: AppStateData,
+    auth: Authenticated,
+    org_id: Path<OrgId>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+
+    let mut conn = data.pg.acquire().await?;
+    let report = quotas::get_quota_report(&mut conn, &org_id).await?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+#[put("/orgs/{org_id}/quotas")]
+pub async fn set_org_quotas(
+    data: AppStateData,
+    auth: Authenticated,
+    org_id: Path<OrgId>,
+    payload: web::Json<OrgQuotas>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+
+    let mut conn = data.pg.acquire().await?;
+    quotas::set_quotas(&mut conn, &org_id, &payload).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+pub struct UsageReportQuery {
+    /// The first day to include. Defaults to 30 days before `until`.
+    pub since: Option<NaiveDate>,
+    /// The last day to include, in UTC. Defaults to today.
+    pub until: Option<NaiveDate>,
+    pub task_id: Option<TaskId>,
+}
+
+impl UsageReportQuery {
+    fn filter(&self, auth: &Authenticated) -> UsageReportFilter {
+        UsageReportFilter {
+            since: self.since,
+            until: self.until,
+            task_id: self.task_id.clone(),
+            readable_by: (!auth.is_admin()).then(|| auth.user_entity_ids().to_vec()),
+        }
+    }
+}
+
+/// Daily resource usage for the requester's org. The org totals cover every task, but only the
+/// tasks that the requester can read are listed individually.
+#[get("/org/usage")]
+pub async fn get_own_usage(
+    data: AppStateData,
+    auth: Authenticated,
+    query: web::Query<UsageReportQuery>,
+) -> Result<impl Responder> {
+    let mut conn = data.pg.acquire().await?;
+    let report = costs::get_usage_report(&mut conn, auth.org_id(), &query.filter(&auth)).await?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+#[get("/orgs/{org_id}/usage")]
+pub async fn get_org_usage(
+    data: AppStateData,
+    auth: Authenticated,
+    org_id: Path<OrgId>,
+    query: web::Query<UsageReportQuery>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+
+    let mut conn = data.pg.acquire().await?;
+    let report = costs::get_usage_report(&mut conn, &org_id, &query.filter(&auth)).await?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_own_quotas)
+        .service(get_org_quotas)
+        .service(set_org_quotas)
+        .service(get_own_usage)
+        .service(get_org_usage);
+}
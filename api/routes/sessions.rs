@@ -0,0 +1,161 @@
+use actix_identity::Identity;
+use actix_web::{
+    delete, get, http::header, post, web, web::Path, HttpMessage, HttpRequest, HttpResponse,
+    Responder,
+};
+use chrono::{DateTime, Utc};
+use ergo_auth::{
+    session::{self, DeviceInfo},
+    Authenticated,
+};
+use ergo_database::object_id::UserId;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    backend_data::BackendAppStateData,
+    error::{Error, Result},
+    web_app_server::AppStateData,
+};
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct LoginInput {
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct SessionInfo {
+    pub session_id: Uuid,
+    pub created: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    /// True if this is the session making the request.
+    pub current: bool,
+}
+
+impl SessionInfo {
+    pub(crate) fn new(session: session::Session, current: Option<&Uuid>) -> Self {
+        SessionInfo {
+            current: current == Some(&session.session_id),
+            session_id: session.session_id,
+            created: session.created,
+            last_seen: session.last_seen,
+            expires: session.expires,
+            user_agent: session.user_agent,
+            ip_address: session.ip_address,
+        }
+    }
+}
+
+pub(crate) fn device_info(req: &HttpRequest) -> DeviceInfo {
+    DeviceInfo {
+        user_agent: req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|h| h.to_str().ok())
+            .map(String::from),
+        ip_address: req.connection_info().realip_remote_addr().map(String::from),
+    }
+}
+
+#[post("/login")]
+async fn login(
+    req: HttpRequest,
+    data: AppStateData,
+    backend_data: BackendAppStateData,
+    payload: web::Json<LoginInput>,
+) -> Result<impl Responder> {
+    let LoginInput { email, password } = payload.into_inner();
+    let mut conn = data.pg.acquire().await?;
+
+    let user = sqlx::query!(
+        r##"SELECT user_id AS "user_id: UserId", password_hash
+        FROM users
+        WHERE email=$1 AND email_verified AND NOT deleted"##,
+        email
+    )
+    .fetch_optional(&mut conn)
+    .await?
+    .ok_or(Error::AuthenticationError)?;
+
+    let hash = user.password_hash.ok_or(Error::AuthenticationError)?;
+    tokio::task::spawn_blocking(move || ergo_auth::password::verify_password(&password, &hash))
+        .await??;
+
+    let session = session::create_session(
+        &mut conn,
+        &user.user_id,
+        backend_data.auth.session_lifetime(),
+        device_info(&req),
+    )
+    .await?;
+
+    Identity::login(&req.extensions(), session.session_id.to_string())
+        .map_err(|e| Error::StringError(e.to_string()))?;
+
+    Ok(HttpResponse::Ok().json(SessionInfo::new(session, None)))
+}
+
+#[post("/logout")]
+async fn logout(
+    data: AppStateData,
+    auth: Authenticated,
+    identity: Identity,
+) -> Result<impl Responder> {
+    if let Some(session_id) = auth.session_id() {
+        let mut conn = data.pg.acquire().await?;
+        session::revoke_session(&mut conn, auth.user_id(), session_id).await?;
+    }
+
+    identity.logout();
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[get("/sessions")]
+async fn list_sessions(data: AppStateData, auth: Authenticated) -> Result<impl Responder> {
+    let mut conn = data.pg.acquire().await?;
+    let sessions = session::list_sessions(&mut conn, auth.user_id())
+        .await?
+        .into_iter()
+        .map(|s| SessionInfo::new(s, auth.session_id()))
+        .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+#[delete("/sessions/{session_id}")]
+async fn revoke_session(
+    data: AppStateData,
+    auth: Authenticated,
+    session_id: Path<Uuid>,
+) -> Result<impl Responder> {
+    let mut conn = data.pg.acquire().await?;
+    let revoked = session::revoke_session(&mut conn, auth.user_id(), &session_id).await?;
+
+    if revoked {
+        Ok(HttpResponse::Ok().finish())
+    } else {
+        Err(Error::NotFound)
+    }
+}
+
+/// Revoke all of the user's sessions other than the one making the request.
+#[delete("/sessions")]
+async fn revoke_other_sessions(data: AppStateData, auth: Authenticated) -> Result<impl Responder> {
+    let mut conn = data.pg.acquire().await?;
+    let count = session::revoke_user_sessions(&mut conn, auth.user_id(), auth.session_id()).await?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "revoked": count })))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(login)
+        .service(logout)
+        .service(list_sessions)
+        .service(revoke_session)
+        .service(revoke_other_sessions);
+}
@@ -1,9 +1,113 @@
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::{
+    get, put,
+    web::{self, Path},
+    HttpResponse, Responder,
+};
+use ergo_auth::Authenticated;
+use ergo_queues::postgres_drain::DrainStatsHandle;
+use ergo_tasks::scripting::POOL;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
-async fn health() -> impl Responder {
-    HttpResponse::Ok().finish()
+use crate::{
+    backend_data::BackendAppStateData,
+    error::{Error, Result},
+    health::{HealthChecks, HealthReport},
+    maintenance_mode::MaintenanceMode,
+};
+
+fn health_response(report: HealthReport) -> HttpResponse {
+    if report.ok {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
+
+/// Fails when the process should be restarted.
+#[get("/healthz/live")]
+async fn live(checks: web::Data<HealthChecks>) -> impl Responder {
+    health_response(checks.liveness())
+}
+
+/// Fails when the server can't reach the services it depends on, and so shouldn't get traffic.
+#[get("/healthz/ready")]
+async fn ready(checks: web::Data<HealthChecks>) -> impl Responder {
+    health_response(checks.readiness().await)
+}
+
+/// Statistics for the pool of JS runtimes, including the result of the latest probe.
+#[get("/status/js_pool")]
+async fn js_pool_status(auth: Authenticated) -> Result<impl Responder> {
+    auth.expect_admin()?;
+    Ok(HttpResponse::Ok().json(POOL.stats()))
+}
+
+/// Progress of each Postgres stage drain running in this process, including how long the jobs in
+/// the latest batch waited to be drained.
+#[get("/status/queue_drain")]
+async fn queue_drain_status(
+    auth: Authenticated,
+    drains: web::Data<DrainStatsHandle>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+    Ok(HttpResponse::Ok().json(drains.current()))
+}
+
+/// Whether the servers are in maintenance mode. This doesn't need authentication, so that clients
+/// can show the maintenance message.
+#[get("/status/maintenance")]
+async fn get_maintenance(mode: web::Data<MaintenanceMode>) -> impl Responder {
+    HttpResponse::Ok().json(mode.status())
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct MaintenanceInput {
+    pub enabled: bool,
+    /// A message for API clients, returned with each rejected request.
+    pub message: Option<String>,
+}
+
+/// Turn maintenance mode on or off for every server.
+#[put("/status/maintenance")]
+async fn set_maintenance(
+    auth: Authenticated,
+    mode: web::Data<MaintenanceMode>,
+    input: web::Json<MaintenanceInput>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+    let MaintenanceInput { enabled, message } = input.into_inner();
+    let status = mode.set(enabled, message).await?;
+    Ok(HttpResponse::Ok().json(status))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueueMetricsQuery {
+    /// How many minutes of history to return. Defaults to 60.
+    pub minutes: Option<usize>,
+}
+
+/// Per-minute throughput and wait time percentiles for the `input` or `action` queue.
+#[get("/status/queues/{queue}/metrics")]
+async fn queue_metrics(
+    data: BackendAppStateData,
+    auth: Authenticated,
+    queue: Path<String>,
+    query: web::Query<QueueMetricsQuery>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+    let queue = data.queue(&queue).ok_or(Error::NotFound)?;
+    let minutes = query.minutes.unwrap_or(60).min(24 * 60);
+    let history = queue.metrics_history(minutes).await?;
+    Ok(HttpResponse::Ok().json(history))
 }
 
 pub fn config(cfg: &mut web::ServiceConfig) {
-    cfg.route("/healthz", web::get().to(health));
+    cfg.service(live)
+        .service(ready)
+        .service(js_pool_status)
+        .service(queue_drain_status)
+        .service(get_maintenance)
+        .service(set_maintenance)
+        .service(queue_metrics);
 }
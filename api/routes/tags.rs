@@ -0,0 +1,186 @@
+//! Tags group tasks and actions so that long lists can be filtered. This module has the
+//! filtering and search options shared by the list endpoints, and an endpoint that lists the
+//! tags in use.
+
+use actix_web::{get, web, HttpResponse, Responder};
+use ergo_auth::Authenticated;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Error, Result},
+    web_app_server::AppStateData,
+};
+
+const MAX_TAG_LENGTH: usize = 64;
+const MAX_TAGS: usize = 32;
+const MAX_LIST_LIMIT: i64 = 500;
+
+/// Trim, lowercase, and deduplicate a list of tags. Commas are not allowed, since the list
+/// endpoints take the tags to filter by as a comma-separated list.
+pub(crate) fn normalize_tags(tags: Vec<String>) -> Result<Vec<String>> {
+    let mut errors = Vec::new();
+    let mut normalized = tags
+        .into_iter()
+        .map(|tag| tag.trim().to_lowercase())
+        .filter(|tag| {
+            if tag.is_empty() {
+                return false;
+            }
+
+            if tag.len() > MAX_TAG_LENGTH || tag.contains(',') {
+                errors.push(format!("Invalid tag {}", tag));
+                return false;
+            }
+
+            true
+        })
+        .collect::<Vec<_>>();
+
+    normalized.sort();
+    normalized.dedup();
+
+    if normalized.len() > MAX_TAGS {
+        errors.push(format!("Too many tags, the limit is {}", MAX_TAGS));
+    }
+
+    if errors.is_empty() {
+        Ok(normalized)
+    } else {
+        Err(Error::ValidationError(errors))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ListSort {
+    Name,
+    Created,
+    Modified,
+    LastTriggered,
+    /// How closely the name and description match the search. This is the default when
+    /// searching.
+    Relevance,
+}
+
+impl ListSort {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Name => "name",
+            Self::Created => "created",
+            Self::Modified => "modified",
+            Self::LastTriggered => "last_triggered",
+            Self::Relevance => "relevance",
+        }
+    }
+}
+
+/// Filtering, sorting, and pagination for the task and action lists.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ListQuery {
+    /// A comma-separated list of tags. Only objects with all of the tags are returned.
+    pub tags: Option<String>,
+    /// Search for this text in the name and description.
+    pub q: Option<String>,
+    pub sort: Option<ListSort>,
+    #[serde(default)]
+    pub desc: bool,
+    /// Return everything when omitted.
+    pub limit: Option<i64>,
+    #[serde(default)]
+    pub offset: i64,
+}
+
+impl ListQuery {
+    pub fn tag_filter(&self) -> Option<Vec<String>> {
+        let tags = self
+            .tags
+            .as_deref()?
+            .split(',')
+            .map(|t| t.trim().to_lowercase())
+            .filter(|t| !t.is_empty())
+            .collect::<Vec<_>>();
+
+        (!tags.is_empty()).then(|| tags)
+    }
+
+    pub fn search(&self) -> Option<&str> {
+        self.q
+            .as_deref()
+            .map(|q| q.trim())
+            .filter(|q| !q.is_empty())
+    }
+
+    /// The search text as an ILIKE pattern.
+    pub fn search_pattern(&self) -> Option<String> {
+        self.search().map(|q| {
+            let escaped = q
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            format!("%{}%", escaped)
+        })
+    }
+
+    pub fn sort(&self) -> ListSort {
+        match (self.sort, self.search()) {
+            (Some(sort), _) => sort,
+            (None, Some(_)) => ListSort::Relevance,
+            (None, None) => ListSort::Name,
+        }
+    }
+
+    pub fn limit(&self) -> Option<i64> {
+        self.limit.map(|l| l.clamp(1, MAX_LIST_LIMIT))
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset.max(0)
+    }
+}
+
+/// The header that holds the total number of matching objects, before any pagination.
+pub const TOTAL_COUNT_HEADER: &str = "X-Total-Count";
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct TagCount {
+    pub tag: String,
+    pub tasks: i64,
+    pub actions: i64,
+}
+
+/// List the tags used by the org's tasks and by actions, with how many of each use them.
+#[get("/tags")]
+async fn list_tags(data: AppStateData, auth: Authenticated) -> Result<impl Responder> {
+    let ids = auth.user_entity_ids();
+    let tags = sqlx::query_as!(
+        TagCount,
+        r##"SELECT tag AS "tag!",
+            COUNT(task_id) AS "tasks!",
+            COUNT(action_id) AS "actions!"
+        FROM (
+            SELECT unnest(tags) AS tag, task_id, NULL::uuid AS action_id
+            FROM tasks
+            WHERE org_id=$1 AND NOT deleted
+                AND EXISTS(SELECT 1 FROM user_entity_permissions
+                    WHERE permissioned_object IN (uuid_nil(), tasks.task_id)
+                    AND user_entity_id = ANY($2)
+                    AND permission_type = 'read'
+                )
+            UNION ALL
+            SELECT unnest(tags), NULL, action_id FROM actions
+        ) t
+        GROUP BY tag
+        ORDER BY tag"##,
+        auth.org_id().0,
+        ids.as_slice()
+    )
+    .fetch_all(&data.pg)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(tags))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_tags);
+}
@@ -0,0 +1,531 @@
+//! Two-phase task updates. Planning an update validates the new version of the task and
+//! describes what would change, without writing anything. A valid plan comes with a token, and
+//! applying that token writes exactly the update that was reviewed.
+//!
+//! A plan is tied to the version of the task it was made against. If the task is modified in
+//! the meantime, applying the plan fails with a conflict and the update has to be planned again.
+//! Plans that are never applied expire after [PLAN_LIFETIME_MINUTES].
+
+use actix_web::{
+    post,
+    web::{self, Path},
+    HttpResponse,
+};
+use chrono::{DateTime, Utc};
+use ergo_auth::Authenticated;
+use ergo_database::object_id::{AccountId, ActionId, TaskId};
+use ergo_tasks::{
+    state_history::{diff_states, StateChange},
+    PeriodicSchedule, TaskTrigger,
+};
+use fxhash::{FxHashMap, FxHashSet};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{types::Json, Postgres, Transaction};
+use uuid::Uuid;
+
+use super::{
+    tags::normalize_tags,
+    tasks::{
+        bundle_task_script, read_task, validate_compensations, validate_conditions, write_task,
+        TaskInput, TaskResult, TaskTriggerInput,
+    },
+};
+use crate::{
+    error::{Error, Result},
+    transaction::RequestTx,
+    web_app_server::AppStateData,
+};
+
+/// How long a plan can be applied after it is made.
+pub const PLAN_LIFETIME_MINUTES: i32 = 60;
+
+/// The fields of an object that changed, as JSON Pointers relative to the object.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct ObjectChanges {
+    /// The task-local ID of the trigger or action.
+    pub id: String,
+    pub changes: Vec<StateChange>,
+}
+
+/// Periodic schedules added to or removed from a trigger. Periodic triggers are matched by
+/// their schedule, so changing a schedule shows up as a removal and an addition.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct ScheduleChange {
+    /// The task-local ID of the trigger.
+    pub trigger: String,
+    pub added: Vec<PeriodicSchedule>,
+    pub removed: Vec<PeriodicSchedule>,
+    /// Schedules that stay on the trigger but are enabled, disabled, or renamed.
+    pub updated: Vec<PeriodicSchedule>,
+}
+
+/// An action whose underlying action or account changes.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct ActionRebind {
+    /// The task-local ID of the action.
+    pub action: String,
+    pub before_action_id: ActionId,
+    pub after_action_id: ActionId,
+    pub before_account_id: Option<AccountId>,
+    pub after_account_id: Option<AccountId>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct TaskPlanChanges {
+    /// Changes to the task's own fields, including its compiled configuration.
+    pub task: Vec<StateChange>,
+    pub triggers_added: Vec<String>,
+    pub triggers_removed: Vec<String>,
+    pub triggers_changed: Vec<ObjectChanges>,
+    pub schedules: Vec<ScheduleChange>,
+    pub actions_added: Vec<String>,
+    pub actions_removed: Vec<String>,
+    pub actions_rebound: Vec<ActionRebind>,
+    /// Changes to an action's name, template, or compensation.
+    pub actions_changed: Vec<ObjectChanges>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct TaskPlan {
+    /// The token to pass to the apply endpoint. This is only present when the plan is valid.
+    pub token: Option<Uuid>,
+    pub expires: Option<DateTime<Utc>>,
+    /// Problems that would prevent the update from being applied.
+    pub errors: Vec<String>,
+    pub changes: TaskPlanChanges,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ApplyTaskPlanInput {
+    pub token: Uuid,
+}
+
+fn sorted_keys<V>(map: &FxHashMap<String, V>) -> Vec<&String> {
+    let mut keys = map.keys().collect::<Vec<_>>();
+    keys.sort();
+    keys
+}
+
+fn trigger_fields(trigger: &TaskTrigger) -> serde_json::Value {
+    json!({
+        "input_id": trigger.input_id,
+        "name": trigger.name,
+        "description": trigger.description,
+        "dedupe": trigger.dedupe,
+        "sensitive": trigger.sensitive,
+        "routes": trigger.routes,
+    })
+}
+
+fn trigger_input_fields(trigger: &TaskTriggerInput) -> serde_json::Value {
+    json!({
+        "input_id": trigger.input_id,
+        "name": trigger.name,
+        "description": trigger.description,
+        "dedupe": trigger.dedupe,
+        "sensitive": trigger.sensitive,
+        "routes": trigger.routes,
+    })
+}
+
+fn schedule_change(
+    trigger: &str,
+    before: Option<&TaskTrigger>,
+    after: Option<&TaskTriggerInput>,
+) -> Option<ScheduleChange> {
+    let before = before
+        .and_then(|t| t.periodic.as_deref())
+        .unwrap_or_default();
+    let after = after
+        .and_then(|t| t.periodic.as_deref())
+        .unwrap_or_default();
+
+    let added = after
+        .iter()
+        .filter(|a| !before.iter().any(|b| b.schedule == a.schedule))
+        .map(|a| a.schedule.clone())
+        .collect::<Vec<_>>();
+    let removed = before
+        .iter()
+        .filter(|b| !after.iter().any(|a| a.schedule == b.schedule))
+        .map(|b| b.schedule.clone())
+        .collect::<Vec<_>>();
+    // Payloads aren't compared, since payloads read from the API have their secrets masked.
+    let updated = after
+        .iter()
+        .filter(|a| {
+            before
+                .iter()
+                .any(|b| b.schedule == a.schedule && (b.enabled != a.enabled || b.name != a.name))
+        })
+        .map(|a| a.schedule.clone())
+        .collect::<Vec<_>>();
+
+    if added.is_empty() && removed.is_empty() && updated.is_empty() {
+        None
+    } else {
+        Some(ScheduleChange {
+            trigger: trigger.to_string(),
+            added,
+            removed,
+            updated,
+        })
+    }
+}
+
+/// Describe how applying `payload` would change the task.
+pub fn plan_changes(current: &TaskResult, payload: &TaskInput) -> TaskPlanChanges {
+    let mut before = json!({
+        "name": current.name,
+        "description": current.description,
+        "alias": current.alias,
+        "enabled": current.enabled,
+        "disabled_input_mode": current.disabled_input_mode,
+        "compiled": current.compiled.0,
+        "source": current.source.0,
+        "state_reset": current.state_reset.as_ref().map(|s| &s.0),
+        "tags": current.tags,
+    });
+    let mut after = json!({
+        "name": payload.name,
+        "description": payload.description,
+        "alias": payload.alias,
+        "enabled": payload.enabled,
+        "disabled_input_mode": payload.disabled_input_mode,
+        "compiled": payload.compiled,
+        "source": payload.source,
+        "state_reset": payload.state_reset,
+        "tags": payload.tags,
+    });
+    // Omitting the state leaves it alone.
+    if let Some(state) = payload.state.as_ref() {
+        before["state"] = json!(current.state.0);
+        after["state"] = json!(state);
+    }
+
+    let mut changes = TaskPlanChanges {
+        task: diff_states(&before, &after),
+        ..Default::default()
+    };
+
+    let current_triggers = &current.triggers.0;
+    for id in sorted_keys(current_triggers) {
+        if !payload.triggers.contains_key(id) {
+            changes.triggers_removed.push(id.clone());
+        }
+    }
+
+    for id in sorted_keys(&payload.triggers) {
+        let new_trigger = &payload.triggers[id];
+        match current_triggers.get(id) {
+            Some(old_trigger) => {
+                let trigger_changes = diff_states(
+                    &trigger_fields(old_trigger),
+                    &trigger_input_fields(new_trigger),
+                );
+                if !trigger_changes.is_empty() {
+                    changes.triggers_changed.push(ObjectChanges {
+                        id: id.clone(),
+                        changes: trigger_changes,
+                    });
+                }
+            }
+            None => changes.triggers_added.push(id.clone()),
+        }
+    }
+
+    let mut trigger_ids = current_triggers
+        .keys()
+        .chain(payload.triggers.keys())
+        .collect::<FxHashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    trigger_ids.sort();
+    changes.schedules = trigger_ids
+        .into_iter()
+        .filter_map(|id| schedule_change(id, current_triggers.get(id), payload.triggers.get(id)))
+        .collect();
+
+    let current_actions = &current.actions.0;
+    for id in sorted_keys(current_actions) {
+        if !payload.actions.contains_key(id) {
+            changes.actions_removed.push(id.clone());
+        }
+    }
+
+    for id in sorted_keys(&payload.actions) {
+        let new_action = &payload.actions[id];
+        let old_action = match current_actions.get(id) {
+            Some(a) => a,
+            None => {
+                changes.actions_added.push(id.clone());
+                continue;
+            }
+        };
+
+        if old_action.action_id != new_action.action_id
+            || old_action.account_id != new_action.account_id
+        {
+            changes.actions_rebound.push(ActionRebind {
+                action: id.clone(),
+                before_action_id: old_action.action_id.clone(),
+                after_action_id: new_action.action_id.clone(),
+                before_account_id: old_action.account_id.clone(),
+                after_account_id: new_action.account_id.clone(),
+            });
+        }
+
+        let action_changes = diff_states(
+            &json!({
+                "name": old_action.name,
+                "action_template": old_action.action_template,
+                "compensate_with": old_action.compensate_with,
+            }),
+            &json!({
+                "name": new_action.name,
+                "action_template": new_action.action_template,
+                "compensate_with": new_action.compensate_with,
+            }),
+        );
+        if !action_changes.is_empty() {
+            changes.actions_changed.push(ObjectChanges {
+                id: id.clone(),
+                changes: action_changes,
+            });
+        }
+    }
+
+    changes
+}
+
+/// Add the messages from a validation failure to `errors`, and pass any other error through.
+fn collect_validation_errors(result: Result<()>, errors: &mut Vec<String>) -> Result<()> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(Error::ValidationError(messages)) => {
+            errors.extend(messages);
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Run the checks that [write_task] would run, along with checks for objects that don't exist,
+/// and return every problem found instead of stopping at the first one.
+async fn validate_plan(
+    tx: &mut Transaction<'_, Postgres>,
+    auth: &Authenticated,
+    payload: &TaskInput,
+) -> Result<Vec<String>> {
+    let mut errors = Vec::new();
+    collect_validation_errors(validate_compensations(&payload.actions), &mut errors)?;
+    collect_validation_errors(validate_conditions(tx, payload).await, &mut errors)?;
+
+    let input_ids = payload
+        .triggers
+        .values()
+        .map(|t| t.input_id.0)
+        .collect::<Vec<_>>();
+    let found_inputs = sqlx::query_scalar!(
+        "SELECT input_id FROM inputs WHERE input_id = ANY($1)",
+        &input_ids
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .collect::<FxHashSet<_>>();
+
+    for id in sorted_keys(&payload.triggers) {
+        let input_id = &payload.triggers[id].input_id;
+        if !found_inputs.contains(&input_id.0) {
+            errors.push(format!(
+                "Trigger {id} uses input {input_id}, which does not exist"
+            ));
+        }
+    }
+
+    let action_ids = payload
+        .actions
+        .values()
+        .map(|a| a.action_id.0)
+        .collect::<Vec<_>>();
+    let found_actions = sqlx::query_scalar!(
+        "SELECT action_id FROM actions WHERE action_id = ANY($1)",
+        &action_ids
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .collect::<FxHashSet<_>>();
+
+    let account_ids = payload
+        .actions
+        .values()
+        .filter_map(|a| a.account_id.as_ref().map(|id| id.0))
+        .collect::<Vec<_>>();
+    let found_accounts = sqlx::query_scalar!(
+        "SELECT account_id FROM accounts WHERE account_id = ANY($1) AND org_id = $2",
+        &account_ids,
+        auth.org_id().0
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .collect::<FxHashSet<_>>();
+
+    for id in sorted_keys(&payload.actions) {
+        let action = &payload.actions[id];
+        if !found_actions.contains(&action.action_id.0) {
+            errors.push(format!(
+                "Action {id} uses action {}, which does not exist",
+                action.action_id
+            ));
+        }
+
+        if let Some(account_id) = action.account_id.as_ref() {
+            if !found_accounts.contains(&account_id.0) {
+                errors.push(format!(
+                    "Action {id} uses account {account_id}, which does not exist"
+                ));
+            }
+        }
+    }
+
+    Ok(errors)
+}
+
+/// Plan an update to a task. The response lists what would change and any validation errors,
+/// and includes a token for the apply endpoint if the update is valid.
+#[post("/tasks/{task_id}/plan")]
+async fn plan_task_update(
+    task_id: Path<TaskId>,
+    data: AppStateData,
+    auth: Authenticated,
+    tx: RequestTx,
+    payload: web::Json<TaskInput>,
+) -> Result<HttpResponse> {
+    let task_id = task_id.into_inner();
+    let mut payload = payload.into_inner();
+    payload.tags = normalize_tags(payload.tags)?;
+    bundle_task_script(&data.pg, auth.org_id(), &mut payload.compiled).await?;
+
+    let mut tx = tx.lock().await?;
+    let current = read_task(&mut tx, &auth, &task_id)
+        .await?
+        .ok_or(Error::NotFound)?;
+
+    let user_ids = auth.user_entity_ids();
+    let can_write = sqlx::query_scalar!(
+        r##"SELECT EXISTS(
+            SELECT 1 FROM user_entity_permissions
+            WHERE permissioned_object IN (uuid_nil(), $1)
+            AND user_entity_id=ANY($2)
+            AND permission_type = 'write'
+        ) AS "can_write!""##,
+        task_id.0,
+        user_ids.as_slice()
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if !can_write {
+        return Err(Error::AuthorizationError);
+    }
+
+    let changes = plan_changes(&current, &payload);
+    let errors = validate_plan(&mut tx, &auth, &payload).await?;
+
+    if !errors.is_empty() {
+        return Ok(HttpResponse::Ok().json(TaskPlan {
+            token: None,
+            expires: None,
+            errors,
+            changes,
+        }));
+    }
+
+    sqlx::query!(
+        "DELETE FROM task_plans WHERE task_id=$1 AND expires <= now()",
+        task_id.0
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let token = Uuid::new_v4();
+    let expires = sqlx::query_scalar!(
+        "INSERT INTO task_plans (task_plan_id, task_id, org_id, payload, task_modified, expires)
+        VALUES ($1, $2, $3, $4, $5, now() + ($6::int * interval '1 minute'))
+        RETURNING expires",
+        token,
+        task_id.0,
+        auth.org_id().0,
+        Json(&payload) as _,
+        current.modified,
+        PLAN_LIFETIME_MINUTES
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(TaskPlan {
+        token: Some(token),
+        expires: Some(expires),
+        errors,
+        changes,
+    }))
+}
+
+/// Apply a plan made by the plan endpoint. This fails with a conflict if the task has been
+/// modified since the plan was made.
+#[post("/tasks/{task_id}/apply")]
+async fn apply_task_plan(
+    task_id: Path<TaskId>,
+    data: AppStateData,
+    auth: Authenticated,
+    tx: RequestTx,
+    body: web::Json<ApplyTaskPlanInput>,
+) -> Result<HttpResponse> {
+    let task_id = task_id.into_inner();
+    let mut tx = tx.lock().await?;
+
+    let plan = sqlx::query!(
+        r##"DELETE FROM task_plans
+        WHERE task_plan_id=$1 AND task_id=$2 AND org_id=$3 AND expires > now()
+        RETURNING payload AS "payload: Json<TaskInput>", task_modified"##,
+        body.token,
+        task_id.0,
+        auth.org_id().0
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let modified = sqlx::query_scalar!(
+        "SELECT modified FROM tasks WHERE task_id=$1 AND NOT deleted FOR UPDATE",
+        task_id.0
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    if modified != plan.task_modified {
+        return Err(Error::Conflict(
+            "The task was modified after this plan was made".to_string(),
+        ));
+    }
+
+    write_task(
+        &mut tx,
+        &data.redis_key_prefix,
+        &auth,
+        &task_id,
+        &plan.payload.0,
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(plan_task_update).service(apply_task_plan);
+}
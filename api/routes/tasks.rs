@@ -1,6 +1,12 @@
 use crate::{
     backend_data::BackendAppStateData,
     error::{Error, Result},
+    pagination::Pagination,
+    routes::{
+        logs::{ActionProgress, SensitivePayloads},
+        tags::{normalize_tags, ListQuery, TOTAL_COUNT_HEADER},
+    },
+    transaction::RequestTx,
     web_app_server::AppStateData,
 };
 
@@ -10,23 +16,49 @@ use actix_web::{
     HttpRequest, HttpResponse, Responder,
 };
 use chrono::{DateTime, Utc};
-use ergo_auth::Authenticated;
-use ergo_database::object_id::{
-    AccountId, ActionId, InputId, OrgId, TaskId, TaskTemplateId, TaskTriggerId, UserId,
+use ergo_auth::{trigger_tokens, Authenticated, MaybeAuthenticated, UserEntityList};
+use ergo_database::{
+    object_id::{
+        AccountId, ActionId, InputId, OrgId, TaskId, TaskTemplateId, TaskTriggerId, UserId,
+    },
+    PostgresPool,
 };
 use ergo_tasks::{
     actions::{ActionStatus, TaskAction, TaskActionTemplate},
-    inputs::{EnqueueInputOptions, InputStatus},
+    dependents::{apply_dependent_validation, InvalidDependentPolicy},
+    inputs::{
+        buffered::{discard_buffered_inputs, flush_buffered_inputs},
+        chain::InputChain,
+        routing::{select_route, validate_routes, TriggerRoute},
+        schema_inference::infer_schema,
+        secrets::masked,
+        sensitive::is_sensitive,
+        DisabledInputMode, EnqueueInputOptions, InputStatus, TriggerDedupeConfig,
+    },
+    payload_limits::PAYLOAD_LIMITS,
+    revalidate::revalidate_tasks,
+    scripting::{
+        bundle::{bundle_task, BUNDLE_CONFIG},
+        libraries::{library_imports, resolve_libraries},
+    },
+    state_reset::{set_state_reset, StateResetPolicy},
     PeriodicTaskTriggerInput, TaskConfig, TaskState, TaskTrigger,
 };
 use fxhash::FxHashMap;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use sqlx::{Connection, Postgres, Transaction};
+use sqlx::{Connection, PgConnection, Postgres, Transaction};
 use std::str::FromStr;
 use tracing::{field, instrument};
 use uuid::Uuid;
 
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct DependentsQuery {
+    /// What to do with tasks that no longer validate after the change.
+    #[serde(default)]
+    pub invalid_dependents: InvalidDependentPolicy,
+}
+
 #[derive(Debug, Deserialize)]
 struct TaskAndTriggerPath {
     task_id: String,
@@ -46,18 +78,53 @@ pub struct TaskDescription {
     pub successes: i64,
     pub failures: i64,
     pub stats_since: DateTime<Utc>,
+    /// Set when the task stopped validating after an action or input that it uses changed.
+    pub validation_errors: Option<sqlx::types::Json<Vec<String>>>,
+    pub tags: Vec<String>,
 }
 
+/// List the tasks that the user can read. The list can be filtered by tag and searched, and is
+/// paginated when `limit` is given. The total number of matching tasks is returned in the
+/// [TOTAL_COUNT_HEADER] header.
 #[get("/tasks")]
-async fn list_tasks(data: AppStateData, auth: Authenticated) -> Result<impl Responder> {
+async fn list_tasks(
+    data: AppStateData,
+    auth: Authenticated,
+    query: web::Query<ListQuery>,
+) -> Result<impl Responder> {
     let user_ids = auth.user_entity_ids();
+    let tags = query.tag_filter();
+    let search = query.search();
+    let search_pattern = query.search_pattern();
+
+    let total = sqlx::query_scalar!(
+        r##"SELECT COUNT(*) AS "count!" FROM tasks
+        WHERE org_id = $2 AND NOT deleted
+            AND ($3::text[] IS NULL OR tags @> $3)
+            AND ($4::text IS NULL
+                OR (name || ' ' || COALESCE(description, '')) ILIKE $4)
+            AND EXISTS (SELECT 1 FROM user_entity_permissions
+                WHERE permissioned_object IN (uuid_nil(), tasks.task_id)
+                AND user_entity_id = ANY($1)
+                AND permission_type = 'read'
+            )"##,
+        user_ids.as_slice(),
+        &auth.org_id().0,
+        tags.as_deref(),
+        search_pattern,
+    )
+    .fetch_one(&data.pg)
+    .await?;
+
     let tasks = sqlx::query_as!(
         TaskDescription,
         r##"SELECT task_id AS "task_id: TaskId", name, description, alias, enabled, created, modified,
+            tags,
             last_triggered AS "last_triggered?",
             COALESCE(successes, 0) as "successes!",
             COALESCE(failures, 0) as "failures!",
-            (now() - '7 days'::interval) as "stats_since!"
+            (now() - '7 days'::interval) as "stats_since!",
+            validation_errors AS "validation_errors: sqlx::types::Json<Vec<String>>"
         FROM tasks
         LEFT JOIN LATERAL(
             SELECT
@@ -74,19 +141,46 @@ async fn list_tasks(data: AppStateData, auth: Authenticated) -> Result<impl Resp
             ORDER BY created DESC
             LIMIT 1
         ) last_triggered ON true
-        WHERE tasks.org_id = $2 AND NOT tasks.deleted AND
-            EXISTS (SELECT 1 FROM user_entity_permissions
+        WHERE tasks.org_id = $2 AND NOT tasks.deleted
+            AND ($3::text[] IS NULL OR tasks.tags @> $3)
+            AND ($4::text IS NULL
+                OR (tasks.name || ' ' || COALESCE(tasks.description, '')) ILIKE $4)
+            AND EXISTS (SELECT 1 FROM user_entity_permissions
                 WHERE permissioned_object IN (uuid_nil(), tasks.task_id)
                 AND user_entity_id = ANY($1)
                 AND permission_type = 'read'
-            )"##,
+            )
+        ORDER BY
+            CASE WHEN $6 = 'relevance' AND NOT $7
+                THEN word_similarity($5, tasks.name || ' ' || COALESCE(tasks.description, '')) END DESC,
+            CASE WHEN $6 = 'relevance' AND $7
+                THEN word_similarity($5, tasks.name || ' ' || COALESCE(tasks.description, '')) END ASC,
+            CASE WHEN $6 = 'name' AND NOT $7 THEN tasks.name END ASC,
+            CASE WHEN $6 = 'name' AND $7 THEN tasks.name END DESC,
+            CASE WHEN $6 = 'created' AND NOT $7 THEN tasks.created END ASC,
+            CASE WHEN $6 = 'created' AND $7 THEN tasks.created END DESC,
+            CASE WHEN $6 = 'modified' AND NOT $7 THEN tasks.modified END ASC,
+            CASE WHEN $6 = 'modified' AND $7 THEN tasks.modified END DESC,
+            CASE WHEN $6 = 'last_triggered' AND NOT $7 THEN last_triggered END ASC NULLS FIRST,
+            CASE WHEN $6 = 'last_triggered' AND $7 THEN last_triggered END DESC NULLS LAST,
+            tasks.name, tasks.task_id
+        LIMIT $8 OFFSET $9"##,
         user_ids.as_slice(),
         &auth.org_id().0,
+        tags.as_deref(),
+        search_pattern,
+        search,
+        query.sort().as_str(),
+        query.desc,
+        query.limit(),
+        query.offset(),
     )
     .fetch_all(&data.pg)
     .await?;
 
-    Ok(HttpResponse::Ok().json(tasks))
+    Ok(HttpResponse::Ok()
+        .insert_header((TOTAL_COUNT_HEADER, total.to_string()))
+        .json(tasks))
 }
 
 #[derive(Debug, Deserialize, Serialize, JsonSchema, sqlx::FromRow)]
@@ -96,35 +190,38 @@ pub struct TaskResult {
     pub description: Option<String>,
     pub alias: Option<String>,
     pub enabled: bool,
+    pub disabled_input_mode: DisabledInputMode,
     pub task_template_version: i64,
     pub compiled: sqlx::types::Json<TaskConfig>,
     pub source: sqlx::types::Json<serde_json::Value>,
     pub state: sqlx::types::Json<TaskState>,
+    pub state_reset: Option<sqlx::types::Json<StateResetPolicy>>,
+    pub tags: Vec<String>,
     pub created: DateTime<Utc>,
     pub modified: DateTime<Utc>,
     pub actions: sqlx::types::Json<FxHashMap<String, TaskAction>>,
     pub triggers: sqlx::types::Json<FxHashMap<String, TaskTrigger>>,
 }
 
-#[get("/tasks/{task_id}")]
-#[instrument(skip(data), fields(task))]
-async fn get_task(
-    task_id: Path<TaskId>,
-    data: AppStateData,
-    req: HttpRequest,
-    auth: Authenticated,
-) -> Result<impl Responder> {
-    let task_id = task_id.into_inner();
+/// Load a task that the user can read, along with its actions and triggers.
+pub(crate) async fn read_task(
+    conn: &mut PgConnection,
+    auth: &Authenticated,
+    task_id: &TaskId,
+) -> Result<Option<TaskResult>> {
     let user_ids = auth.user_entity_ids();
 
     let task = sqlx::query_as!(
         TaskResult,
         r##"SELECT task_id as "task_id: TaskId",
         tasks.name, tasks.description, alias, enabled,
+        disabled_input_mode as "disabled_input_mode: _",
         task_template_version,
         compiled as "compiled!: _",
         source as "source!: _",
         state as "state!: _",
+        state_reset as "state_reset: _",
+        tasks.tags,
         tasks.created, tasks.modified,
         COALESCE(task_triggers, '{}'::jsonb) as "triggers!: _",
         COALESCE(task_actions, '{}'::jsonb) as "actions!: _"
@@ -138,7 +235,8 @@ async fn get_task(
                 'task_id', task_actions.task_id,
                 'account_id', account_id,
                 'name', task_actions.name,
-                'action_template', task_actions.action_template
+                'action_template', task_actions.action_template,
+                'compensate_with', task_actions.compensate_with
             )) AS task_actions
 
             FROM task_actions WHERE task_actions.task_id = tasks.task_id
@@ -152,7 +250,11 @@ async fn get_task(
                 'input_id', input_id,
                 'name', task_triggers.name,
                 'description', task_triggers.description,
-                'periodic', periodic
+                'last_payload', task_triggers.last_payload,
+                'periodic', periodic,
+                'dedupe', task_triggers.dedupe,
+                'sensitive', task_triggers.sensitive,
+                'routes', task_triggers.routes
             )) task_triggers
             FROM task_triggers
             LEFT JOIN LATERAL (
@@ -180,17 +282,67 @@ async fn get_task(
         user_ids.as_slice(),
         &auth.org_id().0
     )
-    .fetch_optional(&data.pg)
+    .fetch_optional(&mut *conn)
     .await?;
 
+    Ok(task)
+}
+
+#[get("/tasks/{task_id}")]
+#[instrument(skip(data), fields(task))]
+async fn get_task(
+    task_id: Path<TaskId>,
+    data: AppStateData,
+    req: HttpRequest,
+    auth: Authenticated,
+) -> Result<impl Responder> {
+    let task_id = task_id.into_inner();
+    let mut conn = data.pg.acquire().await?;
+    let task = read_task(&mut conn, &auth, &task_id).await?;
+
     tracing::Span::current().record("task", &field::debug(&task));
 
     match task {
-        Some(task) => Ok(HttpResponse::Ok().json(task)),
+        Some(mut task) => {
+            mask_trigger_secrets(&data, &mut task.triggers).await?;
+            Ok(HttpResponse::Ok().json(task))
+        }
         None => Ok(HttpResponse::NotFound().finish()),
     }
 }
 
+/// Mask payload values in the triggers that their input schemas mark as secret.
+async fn mask_trigger_secrets(
+    data: &AppStateData,
+    triggers: &mut FxHashMap<String, TaskTrigger>,
+) -> Result<()> {
+    if triggers.is_empty() {
+        return Ok(());
+    }
+
+    let input_ids = triggers.values().map(|t| t.input_id.0).collect::<Vec<_>>();
+
+    let schemas = sqlx::query!(
+        r##"SELECT input_id AS "input_id: InputId", payload_schema
+        FROM inputs
+        WHERE input_id = ANY($1)"##,
+        input_ids.as_slice()
+    )
+    .fetch_all(&data.pg)
+    .await?
+    .into_iter()
+    .map(|row| (row.input_id, row.payload_schema))
+    .collect::<FxHashMap<_, _>>();
+
+    for trigger in triggers.values_mut() {
+        if let Some(schema) = schemas.get(&trigger.input_id) {
+            trigger.mask_secrets(schema);
+        }
+    }
+
+    Ok(())
+}
+
 #[delete("/tasks/{task_id}")]
 async fn delete_task(
     task_id: Path<TaskId>,
@@ -224,6 +376,10 @@ pub struct TaskActionInput {
     pub action_id: ActionId,
     pub account_id: Option<AccountId>,
     pub action_template: Option<TaskActionTemplate>,
+    /// Another action in the task that undoes this one. It runs if a later action in the same
+    /// input run fails permanently.
+    #[serde(default)]
+    pub compensate_with: Option<String>,
 }
 
 impl PartialEq<TaskAction> for TaskActionInput {
@@ -232,6 +388,93 @@ impl PartialEq<TaskAction> for TaskActionInput {
             && self.action_id == other.action_id
             && self.account_id == other.account_id
             && self.action_template == other.action_template
+            && self.compensate_with == other.compensate_with
+    }
+}
+
+/// Make sure that every compensation refers to another action in the task.
+pub(crate) fn validate_compensations(actions: &FxHashMap<String, TaskActionInput>) -> Result<()> {
+    let errors = actions
+        .iter()
+        .filter_map(|(local_id, action)| {
+            let compensation = action.compensate_with.as_deref()?;
+            if compensation == local_id {
+                Some(format!("Action {local_id} can not compensate for itself"))
+            } else if !actions.contains_key(compensation) {
+                Some(format!(
+                    "Action {local_id} is compensated by {compensation}, which is not an action in this task"
+                ))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::ValidationError(errors))
+    }
+}
+
+/// Check the state machine's declarative conditions and the triggers' routing rules against the
+/// payload schemas of the inputs that the triggers listen to.
+pub(crate) async fn validate_conditions(
+    tx: &mut Transaction<'_, Postgres>,
+    payload: &TaskInput,
+) -> Result<()> {
+    let machines = match &payload.compiled {
+        TaskConfig::StateMachine(machines) => machines.as_slice(),
+        _ => &[],
+    };
+
+    let has_routes = payload.triggers.values().any(|t| t.routes.is_some());
+    if !has_routes && !machines.iter().any(|m| m.has_conditions()) {
+        return Ok(());
+    }
+
+    let input_ids = payload
+        .triggers
+        .values()
+        .map(|t| t.input_id.0)
+        .collect::<Vec<_>>();
+    let schemas = sqlx::query!(
+        r##"SELECT input_id AS "input_id: InputId", payload_schema
+        FROM inputs
+        WHERE input_id = ANY($1)"##,
+        &input_ids
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .map(|row| (row.input_id, row.payload_schema))
+    .collect::<FxHashMap<_, _>>();
+
+    let mut errors = machines
+        .iter()
+        .flat_map(|m| {
+            m.validate_conditions(|trigger_id| {
+                let trigger = payload.triggers.get(trigger_id)?;
+                schemas.get(&trigger.input_id)
+            })
+        })
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>();
+
+    for (trigger_id, trigger) in &payload.triggers {
+        if let Some(routes) = trigger.routes.as_deref() {
+            errors.extend(validate_routes(
+                trigger_id,
+                routes,
+                schemas.get(&trigger.input_id),
+            ));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::ValidationError(errors))
     }
 }
 
@@ -241,6 +484,16 @@ pub struct TaskTriggerInput {
     pub name: String,
     pub description: Option<String>,
     pub periodic: Option<Vec<PeriodicTaskTriggerInput>>,
+    /// Drop inputs that duplicate a recent input on this trigger.
+    pub dedupe: Option<TriggerDedupeConfig>,
+    /// Encrypt payloads sent to this trigger, and redact them from the logs for users without
+    /// the `read_sensitive` permission.
+    #[serde(default)]
+    pub sensitive: bool,
+    /// Rules that send inputs to other triggers based on their payload. See
+    /// [ergo_tasks::inputs::routing].
+    #[serde(default)]
+    pub routes: Option<Vec<TriggerRoute>>,
 }
 
 impl PartialEq<TaskTrigger> for TaskTriggerInput {
@@ -248,6 +501,9 @@ impl PartialEq<TaskTrigger> for TaskTriggerInput {
         self.input_id == other.input_id
             && self.name == other.name
             && self.description == other.description
+            && self.dedupe == other.dedupe
+            && self.sensitive == other.sensitive
+            && self.routes == other.routes
     }
 }
 
@@ -257,26 +513,80 @@ pub struct TaskInput {
     pub description: Option<String>,
     pub alias: Option<String>,
     pub enabled: bool,
+    /// What to do with inputs that arrive while the task is disabled.
+    #[serde(default)]
+    pub disabled_input_mode: DisabledInputMode,
     pub compiled: TaskConfig,
     pub source: serde_json::Value,
     pub state: Option<TaskState>,
+    /// Reset the state on a schedule.
+    #[serde(default)]
+    pub state_reset: Option<StateResetPolicy>,
+    #[serde(default)]
+    pub tags: Vec<String>,
     pub actions: FxHashMap<String, TaskActionInput>,
     pub triggers: FxHashMap<String, TaskTriggerInput>,
 }
 
+/// Build the bundle for a JS task script that imports NPM packages, and look up the shared
+/// libraries that it imports. This is done before the transaction starts since installing the
+/// packages can take a while.
+pub(crate) async fn bundle_task_script(
+    pg: &PostgresPool,
+    org_id: &OrgId,
+    config: &mut TaskConfig,
+) -> Result<()> {
+    if let TaskConfig::Js(js) = config {
+        bundle_task(js, &BUNDLE_CONFIG).await?;
+
+        js.libraries = if library_imports(&js.script).is_empty() {
+            Default::default()
+        } else {
+            let mut conn = pg.acquire().await?;
+            resolve_libraries(&mut conn, org_id, &js.script).await?
+        };
+    }
+
+    Ok(())
+}
+
 #[put("/tasks/{task_id}")]
 async fn update_task(
     task_id: Path<TaskId>,
     data: AppStateData,
     auth: Authenticated,
+    tx: RequestTx,
     payload: web::Json<TaskInput>,
 ) -> Result<HttpResponse> {
+    let mut payload = payload.into_inner();
+    payload.tags = normalize_tags(payload.tags)?;
+    bundle_task_script(&data.pg, auth.org_id(), &mut payload.compiled).await?;
+
+    write_task(
+        &mut *tx.lock().await?,
+        &data.redis_key_prefix,
+        &auth,
+        &task_id.into_inner(),
+        &payload,
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Update an existing task. The task's script should already be bundled.
+pub(crate) async fn write_task(
+    tx: &mut Transaction<'_, Postgres>,
+    redis_key_prefix: &Option<String>,
+    auth: &Authenticated,
+    task_id: &TaskId,
+    payload: &TaskInput,
+) -> Result<()> {
     let user_ids = auth.user_entity_ids();
-    let task_id = task_id.into_inner();
-    let mut conn = data.pg.acquire().await?;
-    let mut tx = conn.begin().await?;
 
     // TODO Validate task actions against action templates.
+    validate_compensations(&payload.actions)?;
+    validate_conditions(tx, payload).await?;
 
     struct TaskUpdateResult {
         task_template_id: Uuid,
@@ -291,6 +601,9 @@ async fn update_task(
         "UPDATE tasks SET
         name=$2, description=$3, alias=$4, enabled=$5,
         state=COALESCE($6, state),
+        disabled_input_mode=$9,
+        tags=$10,
+        validation_errors=NULL,
         modified=now()
         WHERE task_id=$1 AND org_id=$7 AND EXISTS (
             SELECT 1 FROM user_entity_permissions
@@ -307,12 +620,21 @@ async fn update_task(
         payload.enabled,
         payload.state.as_ref().map(sqlx::types::Json) as _,
         auth.org_id().0,
-        user_ids.as_slice()
+        user_ids.as_slice(),
+        payload.disabled_input_mode as _,
+        &payload.tags
     )
-    .fetch_optional(&mut tx)
+    .fetch_optional(&mut *tx)
     .await?
     .ok_or(Error::NotFound)?;
 
+    set_state_reset(tx, task_id, payload.state_reset.as_ref()).await?;
+
+    if payload.enabled {
+        // Run any inputs that were held while the task was disabled.
+        flush_buffered_inputs(&mut *tx, task_id, redis_key_prefix.as_deref()).await?;
+    }
+
     sqlx::query!(
         "UPDATE task_templates
         SET source=$3, compiled=$4
@@ -322,25 +644,28 @@ async fn update_task(
         &payload.source,
         sqlx::types::Json(&payload.compiled) as _,
     )
-    .execute(&mut tx)
+    .execute(&mut *tx)
     .await?;
 
     for (action_local_id, action) in &payload.actions {
         sqlx::query!(
             "INSERT INTO task_actions
-            (task_id, task_action_local_id, action_id, account_id, name, action_template)
-            VALUES ($1, $2, $3, $4, $5, $6)
+            (task_id, task_action_local_id, action_id, account_id, name, action_template,
+                compensate_with)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
             ON CONFLICT (task_id, task_action_local_id) DO UPDATE SET
                 action_id=EXCLUDED.action_id, account_id=EXCLUDED.account_id,
-                name=EXCLUDED.name, action_template=EXCLUDED.action_template",
+                name=EXCLUDED.name, action_template=EXCLUDED.action_template,
+                compensate_with=EXCLUDED.compensate_with",
             &task_id.0,
             action_local_id,
             &action.action_id.0,
             action.account_id.as_ref().map(|x| x.0),
             action.name,
-            sqlx::types::Json(&action.action_template) as _
+            sqlx::types::Json(&action.action_template) as _,
+            action.compensate_with
         )
-        .execute(&mut tx)
+        .execute(&mut *tx)
         .await?;
     }
 
@@ -355,7 +680,7 @@ async fn update_task(
             &task_id.0,
             action_local_ids.as_slice() as _
         )
-        .execute(&mut tx)
+        .execute(&mut *tx)
         .await?;
     }
 
@@ -364,16 +689,19 @@ async fn update_task(
     for (trigger_local_id, trigger) in &payload.triggers {
         let updated = sqlx::query!(
             "UPDATE task_triggers
-            SET input_id=$3, name=$4, description=$5
+            SET input_id=$3, name=$4, description=$5, dedupe=$6, sensitive=$7, routes=$8
             WHERE task_id=$1 and task_trigger_local_id=$2
             RETURNING task_trigger_id",
             &task_id.0,
             &trigger_local_id,
             &trigger.input_id.0,
             &trigger.name,
-            &trigger.description as _
+            &trigger.description as _,
+            trigger.dedupe.as_ref().map(sqlx::types::Json) as _,
+            trigger.sensitive,
+            trigger.routes.as_ref().map(sqlx::types::Json) as _
         )
-        .fetch_optional(&mut tx)
+        .fetch_optional(&mut *tx)
         .await?;
 
         let trigger_id = match updated {
@@ -381,10 +709,10 @@ async fn update_task(
             None => {
                 // The object didn't exist, so update it here.
                 add_task_trigger(
-                    &mut tx,
-                    &data.redis_key_prefix,
+                    tx,
+                    redis_key_prefix,
                     trigger_local_id,
-                    &task_id,
+                    task_id,
                     &payload.name,
                     payload.enabled,
                     trigger,
@@ -398,13 +726,13 @@ async fn update_task(
         let empty_vec = Vec::new();
         let periodic = trigger.periodic.as_ref().unwrap_or(&empty_vec);
         ergo_tasks::periodic::update_triggers(
-            &mut tx,
-            data.redis_key_prefix.as_deref(),
+            tx,
+            redis_key_prefix.as_deref(),
             &trigger.input_id,
             &trigger_id,
             trigger_local_id.as_str(),
             &trigger.name,
-            &task_id,
+            task_id,
             &payload.name,
             payload.enabled,
             user_id,
@@ -425,12 +753,11 @@ async fn update_task(
             &task_id.0,
             &task_trigger_ids as _
         )
-        .execute(&mut tx)
+        .execute(&mut *tx)
         .await?;
     }
 
-    tx.commit().await?;
-    Ok(HttpResponse::Ok().finish())
+    Ok(())
 }
 
 async fn add_task_trigger(
@@ -447,15 +774,18 @@ async fn add_task_trigger(
     let trigger_id = TaskTriggerId::new();
     sqlx::query!(
         "INSERT INTO task_triggers (task_trigger_id, task_id, input_id, task_trigger_local_id,
-                name, description
+                name, description, dedupe, sensitive, routes
             ) VALUES
-            ($1, $2, $3, $4, $5, $6)",
+            ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
         trigger_id.0,
         task_id.0,
         trigger.input_id.0,
         local_id,
         trigger.name,
-        trigger.description as _
+        trigger.description as _,
+        trigger.dedupe.as_ref().map(sqlx::types::Json) as _,
+        trigger.sensitive,
+        trigger.routes.as_ref().map(sqlx::types::Json) as _
     )
     .execute(&mut *tx)
     .await?;
@@ -490,7 +820,7 @@ async fn add_task_trigger(
     Ok(trigger_id)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct NewTaskResult {
     pub task_id: TaskId,
 }
@@ -499,29 +829,48 @@ pub struct NewTaskResult {
 async fn new_task_handler(
     data: AppStateData,
     auth: Authenticated,
+    tx: RequestTx,
     payload: web::Json<TaskInput>,
 ) -> Result<HttpResponse> {
-    new_task(data, auth, payload).await
+    new_task(data, auth, tx, payload).await
 }
 
-#[instrument(skip(data))]
+#[instrument(skip(data, tx))]
 async fn new_task(
     data: AppStateData,
     auth: Authenticated,
+    tx: RequestTx,
     payload: web::Json<TaskInput>,
 ) -> Result<HttpResponse> {
-    let payload = payload.into_inner();
+    let mut payload = payload.into_inner();
+    payload.tags = normalize_tags(payload.tags)?;
+    bundle_task_script(&data.pg, auth.org_id(), &mut payload.compiled).await?;
+
+    let mut tx = tx.lock().await?;
+    let task_id = create_task(&mut tx, &data.redis_key_prefix, &auth, payload).await?;
+
+    Ok(HttpResponse::Created().json(NewTaskResult { task_id }))
+}
+
+/// Create a new task. The task's script should already be bundled.
+pub(crate) async fn create_task(
+    tx: &mut Transaction<'_, Postgres>,
+    redis_key_prefix: &Option<String>,
+    auth: &Authenticated,
+    payload: TaskInput,
+) -> Result<TaskId> {
     let user_id = auth.user_id();
 
     // TODO Validate task actions against action templates.
-
-    let mut conn = data.pg.acquire().await?;
-    let mut tx = conn.begin().await?;
+    validate_compensations(&payload.actions)?;
+    validate_conditions(tx, &payload).await?;
 
     let task_id = TaskId::new();
     let task_template_id = TaskTemplateId::new();
     let org_id = auth.org_id();
 
+    ergo_tasks::quotas::check_task_quota(&mut *tx, org_id).await?;
+
     let task_state = payload
         .state
         .unwrap_or_else(|| payload.compiled.default_state());
@@ -540,13 +889,13 @@ async fn new_task(
         sqlx::types::Json(payload.compiled) as _,
         sqlx::types::Json(&task_state) as _
     )
-    .execute(&mut tx)
+    .execute(&mut *tx)
     .await?;
 
     sqlx::query!(
         "INSERT INTO tasks (task_id, org_id, task_template_id, task_template_version, name,
-        description, alias, enabled, state) VALUES
-        ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+        description, alias, enabled, state, disabled_input_mode, tags) VALUES
+        ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
         &task_id.0,
         &org_id.0,
         &task_template_id.0,
@@ -555,11 +904,15 @@ async fn new_task(
         payload.description,
         payload.alias,
         payload.enabled,
-        sqlx::types::Json(&task_state) as _
+        sqlx::types::Json(&task_state) as _,
+        payload.disabled_input_mode as _,
+        &payload.tags
     )
-    .execute(&mut tx)
+    .execute(&mut *tx)
     .await?;
 
+    set_state_reset(tx, &task_id, payload.state_reset.as_ref()).await?;
+
     sqlx::query!(
         "INSERT INTO user_entity_permissions (user_entity_id, permission_type, permissioned_object)
             VALUES
@@ -568,30 +921,31 @@ async fn new_task(
         &auth.user_id().0,
         &task_id.0
     )
-    .execute(&mut tx)
+    .execute(&mut *tx)
     .await?;
 
     for (local_id, action) in &payload.actions {
         sqlx::query!(
             "INSERT INTO task_actions (task_id, task_action_local_id,
-                action_id, account_id, name, action_template)
+                action_id, account_id, name, action_template, compensate_with)
                 VALUES
-                ($1, $2, $3, $4, $5, $6)",
+                ($1, $2, $3, $4, $5, $6, $7)",
             &task_id.0,
             local_id,
             &action.action_id.0,
             action.account_id.as_ref().map(|x| x.0),
             action.name,
-            sqlx::types::Json(action.action_template.as_ref()) as _
+            sqlx::types::Json(action.action_template.as_ref()) as _,
+            action.compensate_with
         )
-        .execute(&mut tx)
+        .execute(&mut *tx)
         .await?;
     }
 
     for (local_id, trigger) in &payload.triggers {
         add_task_trigger(
-            &mut tx,
-            &data.redis_key_prefix,
+            tx,
+            redis_key_prefix,
             local_id,
             &task_id,
             payload.name.as_str(),
@@ -603,9 +957,7 @@ async fn new_task(
         .await?;
     }
 
-    tx.commit().await?;
-
-    Ok(HttpResponse::Created().json(NewTaskResult { task_id }))
+    Ok(task_id)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
@@ -613,44 +965,83 @@ pub struct TaskTriggerResponse {
     pub log_id: Uuid,
 }
 
-#[post("/tasks/{task_id}/trigger/{trigger_id}")]
-async fn post_task_trigger(
-    path: Path<TaskAndTriggerPath>,
-    data: BackendAppStateData,
-    auth: Authenticated,
-    payload: web::Json<serde_json::Value>,
-) -> Result<impl Responder> {
-    let ids = auth.user_entity_ids();
-    let org_id = auth.org_id();
+/// The user sending an input to a trigger.
+struct TriggerSender {
+    user_entity_ids: UserEntityList,
+    org_id: OrgId,
+    user_id: UserId,
+    interactive: bool,
+    /// Set when the request used a trigger token, which only works for one trigger.
+    token_trigger_id: Option<TaskTriggerId>,
+}
 
-    let TaskAndTriggerPath {
-        task_id,
-        trigger_id,
-    } = path.into_inner();
+impl TriggerSender {
+    /// Use the request's session or API key, or else a trigger token.
+    async fn from_request(
+        req: &HttpRequest,
+        pg: &PostgresPool,
+        auth: MaybeAuthenticated,
+    ) -> Result<TriggerSender> {
+        if let Some(auth) = auth.into_inner() {
+            return Ok(TriggerSender {
+                user_entity_ids: auth.user_entity_ids(),
+                org_id: auth.org_id().clone(),
+                user_id: auth.user_id().clone(),
+                // Triggers sent from the UI skip ahead of the batch work in the queues.
+                interactive: auth.session_id().is_some(),
+                token_trigger_id: None,
+            });
+        }
+
+        let token =
+            trigger_tokens::extract_token(req).ok_or(ergo_auth::Error::AuthenticationError)?;
+        let mut conn = pg.acquire().await?;
+        let token = trigger_tokens::verify_token(&mut conn, &token).await?;
+        let user =
+            ergo_auth::get_user_info(&mut conn, &token.user_id, Some(&token.org_id), None).await?;
+
+        Ok(TriggerSender {
+            user_entity_ids: user.user_entity_ids,
+            org_id: user.org_id,
+            user_id: user.user_id,
+            interactive: false,
+            token_trigger_id: Some(token.task_trigger_id),
+        })
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct SendTrigger {
+    task_id: TaskId,
+    task_name: String,
+    task_trigger_name: String,
+    task_trigger_id: TaskTriggerId,
+    input_id: InputId,
+    input_schema: serde_json::Value,
+    routes: Option<sqlx::types::Json<Vec<TriggerRoute>>>,
+}
 
-    let (task_query_field, task_query_value, task_query_field_cast) = TaskId::from_str(&task_id)
+/// Look up a trigger by its task's ID or alias, if the sender can send inputs to it.
+async fn find_send_trigger(
+    pg: &PostgresPool,
+    sender: &TriggerSender,
+    task: String,
+    trigger_local_id: &str,
+) -> Result<SendTrigger> {
+    let (task_query_field, task_query_value, task_query_field_cast) = TaskId::from_str(&task)
         .map(|s| ("task_id", s.as_uuid().to_string(), "uuid"))
-        .unwrap_or(("alias", task_id, "text"));
+        .unwrap_or(("alias", task, "text"));
 
     tracing::event!(tracing::Level::INFO, %task_query_field, %task_query_value);
 
-    #[derive(sqlx::FromRow)]
-    struct QueryResult {
-        task_id: TaskId,
-        task_name: String,
-        task_trigger_name: String,
-        task_trigger_id: TaskTriggerId,
-        input_id: InputId,
-        input_schema: serde_json::Value,
-    }
-
-    let trigger: QueryResult = sqlx::query_as(&format!(
+    let trigger: SendTrigger = sqlx::query_as(&format!(
         r##"SELECT tasks.task_id,
             tasks.name as task_name,
             tt.name as task_trigger_name,
             task_trigger_id,
             input_id,
-            inputs.payload_schema as input_schema
+            inputs.payload_schema as input_schema,
+            tt.routes
         FROM task_triggers tt
         JOIN tasks USING(task_id)
         JOIN inputs USING(input_id)
@@ -659,19 +1050,60 @@ async fn post_task_trigger(
                 SELECT 1 FROM user_entity_permissions
                 WHERE user_entity_id = ANY($1)
                 AND permission_type = 'trigger_event'
-                AND permissioned_object IN(uuid_nil(), task_trigger_id)
+                AND permissioned_object IN(uuid_nil(), task_trigger_id, task_id)
             )
         "##,
         task_query_field, task_query_field_cast
     ))
-    .bind(ids.as_slice())
-    .bind(org_id.0)
-    .bind(&trigger_id)
+    .bind(sender.user_entity_ids.as_slice())
+    .bind(sender.org_id.0)
+    .bind(trigger_local_id)
     .bind(task_query_value)
-    .fetch_optional(&data.pg)
+    .fetch_optional(pg)
     .await?
     .ok_or(Error::NotFound)?;
 
+    Ok(trigger)
+}
+
+async fn post_task_trigger(
+    req: HttpRequest,
+    path: Path<TaskAndTriggerPath>,
+    data: BackendAppStateData,
+    auth: MaybeAuthenticated,
+    payload: web::Json<serde_json::Value>,
+) -> Result<impl Responder> {
+    let sender = TriggerSender::from_request(&req, &data.pg, auth).await?;
+    let org_id = &sender.org_id;
+
+    let TaskAndTriggerPath {
+        task_id,
+        mut trigger_id,
+    } = path.into_inner();
+
+    let mut trigger = find_send_trigger(&data.pg, &sender, task_id, &trigger_id).await?;
+
+    if let Some(token_trigger_id) = &sender.token_trigger_id {
+        if token_trigger_id != &trigger.task_trigger_id {
+            return Err(Error::AuthorizationError);
+        }
+    }
+
+    // The sender needs permission to send to the route's target too, so a route can't be used
+    // to reach a trigger that the sender couldn't reach directly.
+    let payload = payload.into_inner();
+    let route = trigger
+        .routes
+        .as_ref()
+        .and_then(|routes| select_route(&routes.0, &payload))
+        .cloned();
+    if let Some(target) = route {
+        let task = target.task.unwrap_or_else(|| trigger.task_id.to_string());
+        tracing::event!(tracing::Level::INFO, from_trigger=%trigger.task_trigger_id, %task, trigger=%target.trigger, "Routing input");
+        trigger = find_send_trigger(&data.pg, &sender, task, &target.trigger).await?;
+        trigger_id = target.trigger;
+    }
+
     let mut conn = data.pg.acquire().await?;
     let input_arrival_id = ergo_tasks::inputs::enqueue_input(EnqueueInputOptions {
         pg: &mut conn,
@@ -683,12 +1115,15 @@ async fn post_task_trigger(
         task_trigger_local_id: trigger_id,
         task_trigger_name: trigger.task_trigger_name,
         task_name: trigger.task_name,
-        user_id: auth.user_id().clone(),
+        user_id: sender.user_id.clone(),
         payload_schema: &trigger.input_schema,
-        payload: payload.into_inner(),
+        payload,
         redis_key_prefix: data.redis_key_prefix.as_deref(),
         trigger_at: None,
+        replay_of: None,
         periodic_trigger_id: None,
+        interactive: sender.interactive,
+        chain: InputChain::default(),
     })
     .await?;
 
@@ -704,6 +1139,9 @@ pub struct InputLogEntryAction {
     pub task_action_name: String,
     pub result: serde_json::Value,
     pub status: ActionStatus,
+    /// The last progress that the action reported while it ran.
+    #[serde(default)]
+    pub progress: Option<ActionProgress>,
     pub timestamp: DateTime<Utc>,
 }
 
@@ -714,28 +1152,347 @@ pub struct InputsLogEntry {
     pub task_id: TaskId,
     pub input_status: InputStatus,
     pub info: serde_json::Value,
+    /// The input payload, with secret fields masked. Payloads sent to sensitive triggers are
+    /// redacted unless the requester has the `read_sensitive` permission.
+    pub payload: serde_json::Value,
     pub task_trigger_name: String,
     pub task_trigger_local_id: String,
     pub timestamp: DateTime<Utc>,
+    /// If this input is a replay, the ID of the input that it replays.
+    pub replay_of: Option<Uuid>,
+    /// True if the input was triggered by a user from the UI.
+    pub interactive: bool,
+    /// If another task sent this input, the ID of the input that the other task was handling.
+    pub source_inputs_log_id: Option<Uuid>,
+    /// The number of tasks that passed this input along before it arrived.
+    pub chain_depth: i32,
     pub actions: sqlx::types::Json<Vec<InputLogEntryAction>>,
 }
 
+/// Filters for the inputs log. Times apply to the column that the results are sorted by.
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+pub struct InputsLogQuery {
+    /// A comma-separated list of statuses, such as `error,rejected`.
+    pub status: Option<String>,
+    pub task_id: Option<TaskId>,
+    /// The local ID of a trigger. This is usually combined with `task_id`.
+    pub trigger: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+/// Parse a comma-separated list of statuses, checking each against the status type `T`.
+pub(crate) fn parse_status_filter<T: serde::de::DeserializeOwned>(
+    status: Option<&str>,
+) -> Result<Option<Vec<String>>> {
+    let status = match status {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+
+    let mut statuses = Vec::new();
+    let mut errors = Vec::new();
+    for s in status
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+    {
+        match serde_json::from_value::<T>(serde_json::Value::String(s.to_string())) {
+            Ok(_) => statuses.push(s.to_string()),
+            Err(_) => errors.push(format!("Unknown status {}", s)),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(Error::ValidationError(errors));
+    }
+
+    Ok(Some(statuses).filter(|s| !s.is_empty()))
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct PayloadSchemaQuery {
+    /// How many of the trigger's most recent payloads to sample.
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InferredPayloadSchema {
+    /// The input that the trigger uses. The schema can be saved as this input's payload schema.
+    pub input_id: InputId,
+    /// The number of payloads that the schema was inferred from.
+    pub samples: usize,
+    pub schema: serde_json::Value,
+}
+
+const DEFAULT_SCHEMA_SAMPLES: i64 = 100;
+const MAX_SCHEMA_SAMPLES: i64 = 1000;
+
+/// Infer a schema for a trigger's payloads from the most recent inputs that it received.
+/// Encrypted payloads from sensitive triggers are skipped.
+#[get("/tasks/{task_id}/trigger/{trigger_id}/payload_schema")]
+async fn infer_trigger_payload_schema(
+    data: BackendAppStateData,
+    auth: Authenticated,
+    path: Path<(TaskId, String)>,
+    query: web::Query<PayloadSchemaQuery>,
+) -> Result<impl Responder> {
+    let (task_id, trigger_id) = path.into_inner();
+    let ids = auth.user_entity_ids();
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SCHEMA_SAMPLES)
+        .clamp(1, MAX_SCHEMA_SAMPLES);
+
+    let trigger = sqlx::query!(
+        r##"SELECT tt.task_trigger_id, tt.input_id AS "input_id: InputId"
+        FROM task_triggers tt
+        JOIN tasks USING (task_id)
+        WHERE tt.task_id=$1 AND tt.task_trigger_local_id=$2 AND tasks.org_id=$3
+            AND NOT tasks.deleted
+            AND EXISTS(SELECT 1 FROM user_entity_permissions
+                WHERE user_entity_id = ANY($4)
+                AND permission_type = 'read'
+                AND permissioned_object IN (uuid_nil(), tasks.task_id)
+            )"##,
+        task_id.0,
+        trigger_id,
+        auth.org_id().0,
+        ids.as_slice()
+    )
+    .fetch_optional(&data.pg)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let payloads = sqlx::query_scalar!(
+        r##"SELECT payload AS "payload!" FROM inputs_log
+        WHERE task_trigger_id=$1 AND payload IS NOT NULL
+        ORDER BY created DESC
+        LIMIT $2"##,
+        trigger.task_trigger_id,
+        limit
+    )
+    .fetch_all(&data.pg)
+    .await?
+    .into_iter()
+    .filter(|payload| !is_sensitive(payload))
+    .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(InferredPayloadSchema {
+        input_id: trigger.input_id,
+        samples: payloads.len(),
+        schema: infer_schema(payloads.iter()),
+    }))
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct RejectedInputsQuery {
+    /// How many of the most recent rejected inputs to return.
+    pub limit: Option<i64>,
+    /// Only look at inputs rejected within this many days.
+    pub days: Option<i32>,
+}
+
+/// A field that failed validation in the rejected inputs.
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct RejectedInputPath {
+    /// A JSON pointer to the field, or an empty string for the payload as a whole.
+    pub field: String,
+    pub count: i64,
+    pub last_seen: DateTime<Utc>,
+    /// The most recent error for this field.
+    pub message: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct RejectedInput {
+    pub inputs_log_id: Uuid,
+    /// The payload, with secret fields masked.
+    pub payload: serde_json::Value,
+    pub errors: serde_json::Value,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct RejectedInputs {
+    /// The number of inputs rejected in the time period.
+    pub total: i64,
+    /// The fields that failed validation, most common first.
+    pub paths: Vec<RejectedInputPath>,
+    pub recent: Vec<RejectedInput>,
+}
+
+const DEFAULT_REJECTED_INPUTS: i64 = 20;
+const MAX_REJECTED_INPUTS: i64 = 200;
+const DEFAULT_REJECTED_DAYS: i32 = 7;
+
+/// Show the inputs that a trigger rejected because they didn't match its payload schema, so
+/// that changes in what a sender sends can be found and fixed.
+#[get("/tasks/{task_id}/trigger/{trigger_id}/rejected")]
+async fn list_rejected_inputs(
+    data: BackendAppStateData,
+    auth: Authenticated,
+    path: Path<(TaskId, String)>,
+    query: web::Query<RejectedInputsQuery>,
+) -> Result<impl Responder> {
+    let (task_id, trigger_id) = path.into_inner();
+    let ids = auth.user_entity_ids();
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_REJECTED_INPUTS)
+        .clamp(1, MAX_REJECTED_INPUTS);
+    let days = query.days.unwrap_or(DEFAULT_REJECTED_DAYS).max(1);
+
+    let trigger = sqlx::query!(
+        r##"SELECT tt.task_trigger_id, inputs.payload_schema
+        FROM task_triggers tt
+        JOIN tasks USING (task_id)
+        JOIN inputs USING (input_id)
+        WHERE tt.task_id=$1 AND tt.task_trigger_local_id=$2 AND tasks.org_id=$3
+            AND NOT tasks.deleted
+            AND EXISTS(SELECT 1 FROM user_entity_permissions
+                WHERE user_entity_id = ANY($4)
+                AND permission_type = 'read'
+                AND permissioned_object IN (uuid_nil(), tasks.task_id)
+            )"##,
+        task_id.0,
+        trigger_id,
+        auth.org_id().0,
+        ids.as_slice()
+    )
+    .fetch_optional(&data.pg)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let total = sqlx::query_scalar!(
+        r##"SELECT COUNT(*) AS "count!" FROM inputs_log
+        WHERE task_trigger_id=$1 AND status='rejected'
+            AND created > now() - ($2::int * interval '1 day')"##,
+        trigger.task_trigger_id,
+        days
+    )
+    .fetch_one(&data.pg)
+    .await?;
+
+    let paths = sqlx::query_as!(
+        RejectedInputPath,
+        r##"SELECT COALESCE(e->>'field', '') AS "field!",
+            COUNT(*) AS "count!",
+            MAX(il.created) AS "last_seen!",
+            (array_agg(e->>'message' ORDER BY il.created DESC))[1] AS "message!"
+        FROM inputs_log il
+        CROSS JOIN LATERAL jsonb_array_elements(il.info->'errors') e
+        WHERE il.task_trigger_id=$1 AND il.status='rejected'
+            AND il.created > now() - ($2::int * interval '1 day')
+        GROUP BY 1
+        ORDER BY 2 DESC, 1"##,
+        trigger.task_trigger_id,
+        days
+    )
+    .fetch_all(&data.pg)
+    .await?;
+
+    let rows = sqlx::query!(
+        r##"SELECT inputs_log_id,
+            COALESCE(payload, 'null'::jsonb) AS "payload!",
+            COALESCE(info->'errors', '[]'::jsonb) AS "errors!",
+            created
+        FROM inputs_log
+        WHERE task_trigger_id=$1 AND status='rejected'
+            AND created > now() - ($2::int * interval '1 day')
+        ORDER BY created DESC
+        LIMIT $3"##,
+        trigger.task_trigger_id,
+        days,
+        limit
+    )
+    .fetch_all(&data.pg)
+    .await?;
+
+    let mut conn = data.pg.acquire().await?;
+    let mut sensitive = SensitivePayloads::default();
+    let mut recent = Vec::with_capacity(rows.len());
+    for row in rows {
+        let payload = sensitive
+            .reveal(&mut conn, &auth, &task_id, row.payload)
+            .await?;
+        recent.push(RejectedInput {
+            inputs_log_id: row.inputs_log_id,
+            payload: masked(&trigger.payload_schema, &payload),
+            errors: row.errors,
+            created: row.created,
+        });
+    }
+
+    Ok(HttpResponse::Ok().json(RejectedInputs {
+        total,
+        paths,
+        recent,
+    }))
+}
+
+/// List the inputs log for the tasks that the user can read, newest first by default. See
+/// [crate::pagination] for paging through the results.
 #[get("/logs")]
-async fn get_logs(data: BackendAppStateData, auth: Authenticated) -> Result<impl Responder> {
+async fn get_logs(
+    data: BackendAppStateData,
+    auth: Authenticated,
+    query: web::Query<InputsLogQuery>,
+    pagination: Pagination,
+) -> Result<impl Responder> {
     let ids = auth.user_entity_ids();
     let org_id = auth.org_id();
+    let statuses = parse_status_filter::<InputStatus>(query.status.as_deref())?;
 
-    let logs = sqlx::query_as!(
-        InputsLogEntry,
+    // The page of inputs is chosen before joining the actions, so that the limit applies to
+    // inputs and not to input/action pairs.
+    let rows = sqlx::query!(
         r##"
+            WITH page AS (
+                SELECT il.inputs_log_id,
+                    CASE WHEN $3 = 'created' THEN il.created ELSE il.updated END AS sort_time
+                FROM inputs_log il
+                JOIN tasks USING (task_id)
+                JOIN task_triggers USING (task_trigger_id)
+                WHERE tasks.org_id = $2 AND
+                    EXISTS(SELECT 1 FROM user_entity_permissions
+                        WHERE user_entity_id = ANY($1)
+                        AND permission_type = 'read'
+                        AND permissioned_object IN (uuid_nil(), tasks.task_id)
+                    )
+                    AND ($5::text[] IS NULL OR il.status::text = ANY($5))
+                    AND ($6::uuid IS NULL OR il.task_id = $6)
+                    AND ($7::text IS NULL OR il.task_trigger_local_id = $7)
+                    AND ($8::timestamptz IS NULL OR
+                        CASE WHEN $3 = 'created' THEN il.created ELSE il.updated END >= $8)
+                    AND ($9::timestamptz IS NULL OR
+                        CASE WHEN $3 = 'created' THEN il.created ELSE il.updated END < $9)
+                    AND ($10::timestamptz IS NULL OR CASE WHEN $4
+                        THEN (CASE WHEN $3 = 'created' THEN il.created ELSE il.updated END, il.inputs_log_id) < ($10, $11::uuid)
+                        ELSE (CASE WHEN $3 = 'created' THEN il.created ELSE il.updated END, il.inputs_log_id) > ($10, $11::uuid)
+                    END)
+                ORDER BY
+                    CASE WHEN $4 THEN CASE WHEN $3 = 'created' THEN il.created ELSE il.updated END END DESC,
+                    CASE WHEN $4 THEN il.inputs_log_id END DESC,
+                    CASE WHEN NOT $4 THEN CASE WHEN $3 = 'created' THEN il.created ELSE il.updated END END ASC,
+                    CASE WHEN NOT $4 THEN il.inputs_log_id END ASC
+                LIMIT $12
+            )
             SELECT inputs_log_id,
+                page.sort_time AS "sort_time!",
                 tasks.name AS task_name,
                 tasks.task_id AS "task_id: TaskId",
                 il.status AS "input_status!: InputStatus",
                 COALESCE(il.info, 'null'::jsonb) AS "info!",
+                COALESCE(il.payload, 'null'::jsonb) AS "payload!",
+                inputs.payload_schema,
                 MAX(tt.name) AS "task_trigger_name!",
                 il.task_trigger_local_id,
                 il.updated AS "timestamp",
+                il.replay_of,
+                il.interactive,
+                il.source_inputs_log_id,
+                il.chain_depth,
                 COALESCE(
                     jsonb_agg(jsonb_build_object(
                         'actions_log_id', al.actions_log_id,
@@ -743,40 +1500,266 @@ async fn get_logs(data: BackendAppStateData, auth: Authenticated) -> Result<impl
                         'task_action_name', ta.name,
                         'result', COALESCE(al.result, 'null'::jsonb),
                         'status', al.status,
+                        'progress', al.progress,
                         'timestamp', al.updated
                     ))
                     FILTER (WHERE al.actions_log_id IS NOT NULL)
                 , '[]'::jsonb) AS "actions!: sqlx::types::Json<Vec<InputLogEntryAction>>"
-            FROM tasks
-            JOIN inputs_log il USING (task_id)
+            FROM page
+            JOIN inputs_log il USING (inputs_log_id)
+            JOIN tasks USING (task_id)
             LEFT JOIN actions_log al USING(inputs_log_id)
             LEFT JOIN task_actions ta USING(task_action_local_id)
             JOIN task_triggers tt USING(task_trigger_id)
-            WHERE tasks.org_id = $2 AND
-                EXISTS(SELECT 1 FROM user_entity_permissions
-                    WHERE user_entity_id = ANY($1)
-                    AND permission_type = 'read'
-                    AND permissioned_object IN (uuid_nil(), tasks.task_id)
-                )
-            GROUP BY tasks.task_id, inputs_log_id
-            ORDER BY il.updated DESC
-            LIMIT 50
+            JOIN inputs ON inputs.input_id = tt.input_id
+            GROUP BY tasks.task_id, inputs_log_id, page.sort_time, inputs.input_id
+            ORDER BY
+                CASE WHEN $4 THEN page.sort_time END DESC,
+                CASE WHEN $4 THEN inputs_log_id END DESC,
+                CASE WHEN NOT $4 THEN page.sort_time END ASC,
+                CASE WHEN NOT $4 THEN inputs_log_id END ASC
         "##,
         ids.as_slice(),
-        org_id.0
+        org_id.0,
+        pagination.sort.as_str(),
+        pagination.desc(),
+        statuses.as_deref(),
+        query.task_id.as_ref().map(|id| id.0),
+        query.trigger.as_deref(),
+        query.since,
+        query.until,
+        pagination.cursor_time(),
+        pagination.cursor_id(),
+        pagination.fetch_limit()
     )
     .fetch_all(&data.pg)
     .await?;
 
-    Ok(HttpResponse::Ok().json(logs))
+    let mut conn = data.pg.acquire().await?;
+    let mut sensitive = SensitivePayloads::default();
+    let mut logs = Vec::with_capacity(rows.len());
+    let mut keys = Vec::with_capacity(rows.len());
+    for row in rows {
+        keys.push((row.inputs_log_id, row.sort_time));
+        let payload = sensitive
+            .reveal(&mut conn, &auth, &row.task_id, row.payload)
+            .await?;
+        logs.push(InputsLogEntry {
+            inputs_log_id: row.inputs_log_id,
+            task_name: row.task_name,
+            task_id: row.task_id,
+            input_status: row.input_status,
+            info: row.info,
+            payload: masked(&row.payload_schema, &payload),
+            task_trigger_name: row.task_trigger_name,
+            task_trigger_local_id: row.task_trigger_local_id,
+            timestamp: row.timestamp,
+            replay_of: row.replay_of,
+            interactive: row.interactive,
+            source_inputs_log_id: row.source_inputs_log_id,
+            chain_depth: row.chain_depth,
+            actions: row.actions,
+        });
+    }
+
+    let sort_times = keys.into_iter().collect::<FxHashMap<_, _>>();
+    Ok(pagination.page(logs, |log| {
+        (sort_times[&log.inputs_log_id], log.inputs_log_id)
+    }))
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct ReplInput {
+    /// Code already evaluated in this session, which is run again before `code`.
+    #[serde(default)]
+    pub history: Vec<String>,
+    pub code: String,
+    /// Use the most recent payload from this trigger, instead of from any of the task's triggers.
+    pub trigger: Option<String>,
+    /// Use this payload instead of one from a previous input.
+    pub payload: Option<serde_json::Value>,
+}
+
+/// Evaluate code in a sandbox with the task's state and input payload, for trying out changes
+/// to a JS task. The task itself is not changed.
+#[post("/tasks/{task_id}/repl")]
+async fn task_repl(
+    task_id: Path<TaskId>,
+    data: AppStateData,
+    auth: Authenticated,
+    payload: web::Json<ReplInput>,
+) -> Result<impl Responder> {
+    let input = payload.into_inner();
+    let task_id = task_id.into_inner();
+    let user_ids = auth.user_entity_ids();
+
+    let task = sqlx::query!(
+        r##"SELECT compiled AS "compiled: sqlx::types::Json<TaskConfig>",
+            state AS "state: sqlx::types::Json<TaskState>"
+        FROM tasks
+        JOIN task_templates USING (task_template_id, task_template_version)
+        WHERE task_id=$1 AND tasks.org_id=$2 AND NOT tasks.deleted
+        AND EXISTS(SELECT 1 FROM user_entity_permissions
+            WHERE permissioned_object IN (uuid_nil(), task_id)
+            AND user_entity_id=ANY($3)
+            AND permission_type = 'write'
+        )"##,
+        task_id.0,
+        auth.org_id().0,
+        user_ids.as_slice()
+    )
+    .fetch_optional(&data.pg)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let state = match (task.compiled.0, task.state.0) {
+        (TaskConfig::Js(_), TaskState::Js(state)) => state,
+        _ => {
+            return Err(Error::ValidationError(vec![
+                "The REPL only supports JS tasks".to_string(),
+            ]))
+        }
+    };
+
+    let payload = match input.payload {
+        Some(payload) => payload,
+        None => {
+            let payload = sqlx::query_scalar!(
+                r##"SELECT payload FROM inputs_log
+                WHERE task_id=$1 AND ($2::text IS NULL OR task_trigger_local_id=$2)
+                ORDER BY created DESC
+                LIMIT 1"##,
+                task_id.0,
+                input.trigger
+            )
+            .fetch_optional(&data.pg)
+            .await?
+            .flatten()
+            .unwrap_or(serde_json::Value::Null);
+
+            let mut conn = data.pg.acquire().await?;
+            SensitivePayloads::default()
+                .reveal(&mut conn, &auth, &task_id, payload)
+                .await?
+        }
+    };
+
+    let output =
+        ergo_tasks::scripting::repl::evaluate(state, payload, input.history, input.code).await?;
+    Ok(HttpResponse::Ok().json(output))
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct BufferedInputsQuery {
+    /// Remove the buffered inputs without running them.
+    #[serde(default)]
+    pub discard: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct BufferedInputsResult {
+    /// The number of buffered inputs that were queued or discarded.
+    pub count: usize,
+}
+
+/// Queue the inputs that arrived while the task was disabled, without waiting for it to be
+/// enabled again, or discard them.
+#[post("/tasks/{task_id}/buffered_inputs/flush")]
+async fn flush_task_buffered_inputs(
+    task_id: Path<TaskId>,
+    data: AppStateData,
+    auth: Authenticated,
+    tx: RequestTx,
+    query: web::Query<BufferedInputsQuery>,
+) -> Result<impl Responder> {
+    let task_id = task_id.into_inner();
+    let user_ids = auth.user_entity_ids();
+
+    let mut tx = tx.lock().await?;
+
+    sqlx::query_scalar!(
+        r##"SELECT task_id FROM tasks
+        WHERE task_id=$1 AND org_id=$2 AND NOT deleted
+        AND EXISTS(SELECT 1 FROM user_entity_permissions
+            WHERE permissioned_object IN (uuid_nil(), task_id)
+            AND user_entity_id=ANY($3)
+            AND permission_type = 'write'
+        )"##,
+        task_id.0,
+        auth.org_id().0,
+        user_ids.as_slice()
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let count = if query.discard {
+        discard_buffered_inputs(&mut tx, &task_id).await?
+    } else {
+        flush_buffered_inputs(&mut tx, &task_id, data.redis_key_prefix.as_deref()).await?
+    };
+
+    Ok(HttpResponse::Ok().json(BufferedInputsResult { count }))
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct RevalidateQuery {
+    /// What to do with tasks that no longer validate.
+    #[serde(default)]
+    pub invalid_dependents: InvalidDependentPolicy,
+    /// Report the results without saving anything.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Validate and recompile all of the org's tasks against the current task engine. This is meant
+/// to be run after an upgrade, so that tasks which no longer validate are found right away.
+#[post("/tasks/revalidate")]
+async fn revalidate_org_tasks(
+    data: BackendAppStateData,
+    auth: Authenticated,
+    query: web::Query<RevalidateQuery>,
+) -> Result<impl Responder> {
+    auth.expect_admin()?;
+
+    let mut conn = data.pg.acquire().await?;
+    let mut tx = conn.begin().await?;
+    let result = revalidate_tasks(&mut tx, Some(auth.org_id()), query.dry_run).await?;
+    if !query.dry_run {
+        apply_dependent_validation(
+            &mut tx,
+            Some(&data.notifications),
+            data.redis_key_prefix.as_deref(),
+            &result.validation,
+            query.invalid_dependents,
+            auth.locale(),
+        )
+        .await?;
+    }
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(result.report(auth.locale())))
 }
 
 pub fn config(cfg: &mut web::ServiceConfig) {
-    cfg.service(post_task_trigger)
-        .service(list_tasks)
+    cfg.service(list_tasks)
         .service(get_task)
         .service(new_task_handler)
+        .service(revalidate_org_tasks)
         .service(update_task)
         .service(delete_task)
-        .service(get_logs);
+        .service(task_repl)
+        .service(flush_task_buffered_inputs)
+        .service(infer_trigger_payload_schema)
+        .service(list_rejected_inputs)
+        .service(get_logs)
+        .service(
+            web::resource("/tasks/{task_id}/trigger/{trigger_id}")
+                .app_data(
+                    web::JsonConfig::default()
+                        .limit(PAYLOAD_LIMITS.max_input_bytes)
+                        .error_handler(crate::error::json_error_handler),
+                )
+                .route(web::post().to(post_task_trigger)),
+        );
 }
@@ -0,0 +1,158 @@
+//! Manage the tokens that can send inputs to a single task trigger. See
+//! [ergo_auth::trigger_tokens] for how the tokens are checked.
+
+use actix_web::{
+    delete, get, post,
+    web::{self, Path},
+    HttpResponse, Responder,
+};
+use ergo_auth::{
+    trigger_tokens::{self, TriggerToken},
+    Authenticated,
+};
+use ergo_database::object_id::{TaskId, TaskTriggerId};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::{
+    error::{Error, Result},
+    web_app_server::AppStateData,
+};
+
+#[derive(Debug, Deserialize)]
+struct TriggerPath {
+    task_id: TaskId,
+    trigger_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TriggerTokenPath {
+    task_id: TaskId,
+    trigger_id: String,
+    token_id: Uuid,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct NewTriggerTokenInput {
+    pub description: Option<String>,
+}
+
+/// A newly created or rotated token. The token value is only returned here.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct NewTriggerToken {
+    #[serde(flatten)]
+    pub info: TriggerToken,
+    pub token: String,
+}
+
+/// Look up a trigger, and check that the user can modify its task.
+async fn writable_trigger(
+    tx: &mut PgConnection,
+    auth: &Authenticated,
+    task_id: &TaskId,
+    trigger_id: &str,
+) -> Result<TaskTriggerId> {
+    let user_ids = auth.user_entity_ids();
+    let trigger = sqlx::query!(
+        r##"SELECT tt.task_trigger_id AS "task_trigger_id: TaskTriggerId",
+            EXISTS(
+                SELECT 1 FROM user_entity_permissions
+                WHERE permissioned_object IN (uuid_nil(), tt.task_id)
+                AND user_entity_id = ANY($4)
+                AND permission_type = 'write'
+            ) AS "can_write!"
+        FROM task_triggers tt
+        JOIN tasks USING (task_id)
+        WHERE tt.task_id = $1 AND tt.task_trigger_local_id = $2 AND tasks.org_id = $3
+            AND NOT tasks.deleted"##,
+        task_id.0,
+        trigger_id,
+        auth.org_id().0,
+        user_ids.as_slice()
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    if !trigger.can_write {
+        return Err(Error::AuthorizationError);
+    }
+
+    Ok(trigger.task_trigger_id)
+}
+
+#[get("/tasks/{task_id}/trigger/{trigger_id}/tokens")]
+async fn list_trigger_tokens(
+    data: AppStateData,
+    auth: Authenticated,
+    path: Path<TriggerPath>,
+) -> Result<impl Responder> {
+    let mut conn = data.pg.acquire().await?;
+    let task_trigger_id =
+        writable_trigger(&mut conn, &auth, &path.task_id, &path.trigger_id).await?;
+    let tokens = trigger_tokens::list_tokens(&mut conn, &task_trigger_id).await?;
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+#[post("/tasks/{task_id}/trigger/{trigger_id}/tokens")]
+async fn create_trigger_token(
+    data: AppStateData,
+    auth: Authenticated,
+    path: Path<TriggerPath>,
+    payload: web::Json<NewTriggerTokenInput>,
+) -> Result<impl Responder> {
+    let mut conn = data.pg.acquire().await?;
+    let task_trigger_id =
+        writable_trigger(&mut conn, &auth, &path.task_id, &path.trigger_id).await?;
+    let (info, token) = trigger_tokens::create_token(
+        &mut conn,
+        auth.org_id(),
+        &task_trigger_id,
+        auth.user_id(),
+        payload.into_inner().description,
+    )
+    .await?;
+
+    Ok(HttpResponse::Created().json(NewTriggerToken { info, token }))
+}
+
+#[post("/tasks/{task_id}/trigger/{trigger_id}/tokens/{token_id}/rotate")]
+async fn rotate_trigger_token(
+    data: AppStateData,
+    auth: Authenticated,
+    path: Path<TriggerTokenPath>,
+) -> Result<impl Responder> {
+    let mut conn = data.pg.acquire().await?;
+    let task_trigger_id =
+        writable_trigger(&mut conn, &auth, &path.task_id, &path.trigger_id).await?;
+    let (info, token) = trigger_tokens::rotate_token(&mut conn, &task_trigger_id, &path.token_id)
+        .await?
+        .ok_or(Error::NotFound)?;
+
+    Ok(HttpResponse::Ok().json(NewTriggerToken { info, token }))
+}
+
+#[delete("/tasks/{task_id}/trigger/{trigger_id}/tokens/{token_id}")]
+async fn revoke_trigger_token(
+    data: AppStateData,
+    auth: Authenticated,
+    path: Path<TriggerTokenPath>,
+) -> Result<impl Responder> {
+    let mut conn = data.pg.acquire().await?;
+    let task_trigger_id =
+        writable_trigger(&mut conn, &auth, &path.task_id, &path.trigger_id).await?;
+    if !trigger_tokens::revoke_token(&mut conn, &task_trigger_id, &path.token_id).await? {
+        return Err(Error::NotFound);
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_trigger_tokens)
+        .service(create_trigger_token)
+        .service(rotate_trigger_token)
+        .service(revoke_trigger_token);
+}
@@ -0,0 +1,311 @@
+//! Self-service account management: signing up, verifying an email address, and resetting or
+//! changing a password.
+
+use actix_identity::Identity;
+use actix_web::{post, put, web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use ergo_auth::{
+    password,
+    session::{self, revoke_user_sessions},
+    user_tokens::{consume_token, create_token, UserTokenKind},
+    Authenticated,
+};
+use ergo_database::object_id::{OrgId, UserId};
+use ergo_problem::FieldError;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use sqlx::Connection;
+
+use super::sessions::{device_info, SessionInfo};
+use crate::{
+    backend_data::BackendAppStateData,
+    error::{Error, Result},
+    web_app_server::AppStateData,
+};
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct SignupInput {
+    pub name: String,
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct TokenInput {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct PasswordResetInput {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct PasswordResetConfirmInput {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ChangePasswordInput {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+/// Check and hash a password that the user chose.
+async fn hash_new_password(field: &str, password: String) -> Result<String> {
+    password::check_new_password(&password).map_err(|e| match e {
+        ergo_auth::Error::WeakPassword(message) => {
+            Error::FieldValidationError(vec![FieldError::new(field, message)])
+        }
+        e => e.into(),
+    })?;
+
+    let hash = tokio::task::spawn_blocking(move || password::new_hash(&password)).await??;
+    Ok(hash)
+}
+
+/// Create an unverified user in a new org, and email them a link to verify their address. The
+/// response doesn't say whether the email was already in use, so that signup can't be used to
+/// find out who has an account.
+#[post("/signup")]
+async fn signup(
+    data: AppStateData,
+    backend_data: BackendAppStateData,
+    payload: web::Json<SignupInput>,
+) -> Result<impl Responder> {
+    if !backend_data.auth.signup_enabled() {
+        return Err(Error::NotFound);
+    }
+
+    let SignupInput {
+        name,
+        email,
+        password,
+    } = payload.into_inner();
+    let name = name.trim();
+    let email = email.trim();
+    if name.is_empty() {
+        return Err(Error::FieldValidationError(vec![FieldError::new(
+            "name",
+            "Name is required",
+        )]));
+    }
+    if !email.contains('@') {
+        return Err(Error::FieldValidationError(vec![FieldError::new(
+            "email",
+            "Invalid email address",
+        )]));
+    }
+
+    let password_hash = hash_new_password("password", password).await?;
+
+    let mut conn = data.pg.acquire().await?;
+    let mut tx = conn.begin().await?;
+
+    let existing = sqlx::query!(
+        r##"SELECT user_id AS "user_id: UserId", email_verified
+        FROM users
+        WHERE email=$1 AND NOT deleted"##,
+        email
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+
+    let user_id = match existing {
+        // Nothing to do, and the user can reset their password if they forgot it.
+        Some(user) if user.email_verified => None,
+        // Someone signed up but never verified, so send the link again.
+        Some(user) => Some(user.user_id),
+        None => {
+            let org_id = OrgId::new();
+            sqlx::query!(
+                "INSERT INTO orgs (org_id, name) VALUES ($1, $2)",
+                org_id.0,
+                name
+            )
+            .execute(&mut tx)
+            .await?;
+
+            let user_id = UserId::new();
+            sqlx::query!(
+                r##"INSERT INTO users
+                    (user_id, active_org_id, name, email, password_hash, email_verified)
+                VALUES ($1, $2, $3, $4, $5, false)"##,
+                user_id.0,
+                org_id.0,
+                name,
+                email,
+                password_hash
+            )
+            .execute(&mut tx)
+            .await?;
+
+            sqlx::query!(
+                "INSERT INTO org_members (org_id, user_id) VALUES ($1, $2)",
+                org_id.0,
+                user_id.0
+            )
+            .execute(&mut tx)
+            .await?;
+
+            Some(user_id)
+        }
+    };
+
+    let token = match user_id {
+        Some(user_id) => Some(create_token(&mut tx, &user_id, UserTokenKind::VerifyEmail).await?),
+        None => None,
+    };
+
+    tx.commit().await?;
+
+    if let Some(token) = token {
+        backend_data.mailer.send_verification(email, &token).await?;
+    }
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+/// Verify a new user's email address, and log them in.
+#[post("/signup/verify")]
+async fn verify_email(
+    req: HttpRequest,
+    data: AppStateData,
+    backend_data: BackendAppStateData,
+    payload: web::Json<TokenInput>,
+) -> Result<impl Responder> {
+    let mut conn = data.pg.acquire().await?;
+    let mut tx = conn.begin().await?;
+
+    let user_id = consume_token(&mut tx, UserTokenKind::VerifyEmail, &payload.token).await?;
+    sqlx::query!(
+        "UPDATE users SET email_verified=true WHERE user_id=$1",
+        user_id.0
+    )
+    .execute(&mut tx)
+    .await?;
+
+    let session = session::create_session(
+        &mut tx,
+        &user_id,
+        backend_data.auth.session_lifetime(),
+        device_info(&req),
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Identity::login(&req.extensions(), session.session_id.to_string())
+        .map_err(|e| Error::StringError(e.to_string()))?;
+
+    Ok(HttpResponse::Ok().json(SessionInfo::new(session, None)))
+}
+
+/// Email a password reset link. Like signup, this responds the same way whether or not the
+/// email belongs to a user.
+#[post("/password_reset")]
+async fn request_password_reset(
+    data: AppStateData,
+    backend_data: BackendAppStateData,
+    payload: web::Json<PasswordResetInput>,
+) -> Result<impl Responder> {
+    let email = payload.email.trim();
+    let mut conn = data.pg.acquire().await?;
+
+    let user_id = sqlx::query_scalar!(
+        r##"SELECT user_id AS "user_id: UserId" FROM users WHERE email=$1 AND NOT deleted"##,
+        email
+    )
+    .fetch_optional(&mut conn)
+    .await?;
+
+    if let Some(user_id) = user_id {
+        let token = create_token(&mut conn, &user_id, UserTokenKind::PasswordReset).await?;
+        backend_data
+            .mailer
+            .send_password_reset(email, &token)
+            .await?;
+    }
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+/// Set a new password with a reset token. This logs out all of the user's sessions.
+#[post("/password_reset/confirm")]
+async fn confirm_password_reset(
+    data: AppStateData,
+    payload: web::Json<PasswordResetConfirmInput>,
+) -> Result<impl Responder> {
+    let PasswordResetConfirmInput { token, password } = payload.into_inner();
+    let password_hash = hash_new_password("password", password).await?;
+
+    let mut conn = data.pg.acquire().await?;
+    let mut tx = conn.begin().await?;
+
+    let user_id = consume_token(&mut tx, UserTokenKind::PasswordReset, &token).await?;
+    // The reset link went to the user's address, so it counts as verified now too.
+    sqlx::query!(
+        "UPDATE users SET password_hash=$2, email_verified=true WHERE user_id=$1",
+        user_id.0,
+        password_hash
+    )
+    .execute(&mut tx)
+    .await?;
+
+    revoke_user_sessions(&mut tx, &user_id, None).await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Change the password of the logged-in user. This logs out the user's other sessions.
+#[put("/user/password")]
+async fn change_password(
+    data: AppStateData,
+    auth: Authenticated,
+    payload: web::Json<ChangePasswordInput>,
+) -> Result<impl Responder> {
+    let ChangePasswordInput {
+        current_password,
+        new_password,
+    } = payload.into_inner();
+
+    let mut conn = data.pg.acquire().await?;
+    let current_hash = sqlx::query_scalar!(
+        "SELECT password_hash FROM users WHERE user_id=$1 AND NOT deleted",
+        auth.user_id().0
+    )
+    .fetch_optional(&mut conn)
+    .await?
+    .flatten()
+    .ok_or(Error::AuthenticationError)?;
+
+    tokio::task::spawn_blocking(move || {
+        password::verify_password(&current_password, &current_hash)
+    })
+    .await??;
+
+    let password_hash = hash_new_password("new_password", new_password).await?;
+
+    let mut tx = conn.begin().await?;
+    sqlx::query!(
+        "UPDATE users SET password_hash=$2 WHERE user_id=$1",
+        auth.user_id().0,
+        password_hash
+    )
+    .execute(&mut tx)
+    .await?;
+
+    revoke_user_sessions(&mut tx, auth.user_id(), auth.session_id()).await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(signup)
+        .service(verify_email)
+        .service(request_password_reset)
+        .service(confirm_password_reset)
+        .service(change_password);
+}
@@ -0,0 +1,251 @@
+//! Settings that can change while the server is running. The server reads them at startup and
+//! then polls for changes, applying each change without a restart.
+//!
+//! The settings are layered from several sources. Each source only replaces the settings that it
+//! contains, and later sources take precedence:
+//!
+//! 1. Environment variables: `LOG`, `INPUT_MAX_CONCURRENT_JOBS`, `ACTION_MAX_CONCURRENT_JOBS`,
+//!    `INPUT_MAX_JOBS_PER_ORG`, and `ACTION_MAX_JOBS_PER_TASK`.
+//! 2. A JSON or YAML file at the path in `RUNTIME_CONFIG_FILE`.
+//! 3. A JSON or YAML value in the Consul KV store, when both `CONSUL_HTTP_ADDR` and
+//!    `RUNTIME_CONFIG_CONSUL_KEY` are set. `CONSUL_HTTP_TOKEN` is sent with the request if it is
+//!    set.
+//!
+//! The file and the Consul value contain a [RuntimeSettings] object.
+
+use std::{env, num::NonZeroU32, path::PathBuf, time::Duration};
+
+use ergo_graceful_shutdown::GracefulShutdownConsumer;
+use ergo_queues::Queue;
+use fxhash::FxHashMap;
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+use tracing::{event, Level};
+
+use crate::{
+    error::{Error, Result},
+    tracing_config::LogFilterHandle,
+};
+
+const DEFAULT_LOG_FILTER: &str = "info";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuntimeSettings {
+    /// The log filter, in the same format as the `LOG` environment variable.
+    pub log_filter: Option<String>,
+    /// The most inputs that this server processes at once.
+    pub input_max_concurrent_jobs: Option<NonZeroU32>,
+    /// The most actions that this server runs at once.
+    pub action_max_concurrent_jobs: Option<NonZeroU32>,
+    /// The most inputs for a single org that this server processes at once.
+    pub input_max_jobs_per_org: Option<usize>,
+    /// The most actions for a single task that this server runs at once.
+    pub action_max_jobs_per_task: Option<usize>,
+    /// Force feature flags on or off for every org.
+    pub feature_flags: FxHashMap<String, bool>,
+}
+
+impl RuntimeSettings {
+    fn from_env() -> Result<RuntimeSettings> {
+        Ok(RuntimeSettings {
+            log_filter: env::var("LOG").ok(),
+            input_max_concurrent_jobs: envoption::optional("INPUT_MAX_CONCURRENT_JOBS")?,
+            action_max_concurrent_jobs: envoption::optional("ACTION_MAX_CONCURRENT_JOBS")?,
+            input_max_jobs_per_org: envoption::optional("INPUT_MAX_JOBS_PER_ORG")?,
+            action_max_jobs_per_task: envoption::optional("ACTION_MAX_JOBS_PER_TASK")?,
+            feature_flags: FxHashMap::default(),
+        })
+    }
+
+    fn parse(source: &str, data: &str) -> Result<RuntimeSettings> {
+        // YAML is a superset of JSON, so this handles both.
+        serde_yaml::from_str(data)
+            .map_err(|e| Error::ConfigError(format!("Runtime config from {}: {}", source, e)))
+    }
+
+    /// Replace the settings that are present in `other`.
+    fn merge(&mut self, other: RuntimeSettings) {
+        if other.log_filter.is_some() {
+            self.log_filter = other.log_filter;
+        }
+        if other.input_max_concurrent_jobs.is_some() {
+            self.input_max_concurrent_jobs = other.input_max_concurrent_jobs;
+        }
+        if other.action_max_concurrent_jobs.is_some() {
+            self.action_max_concurrent_jobs = other.action_max_concurrent_jobs;
+        }
+        if other.input_max_jobs_per_org.is_some() {
+            self.input_max_jobs_per_org = other.input_max_jobs_per_org;
+        }
+        if other.action_max_jobs_per_task.is_some() {
+            self.action_max_jobs_per_task = other.action_max_jobs_per_task;
+        }
+        self.feature_flags.extend(other.feature_flags);
+    }
+}
+
+struct ConsulSource {
+    client: reqwest::Client,
+    url: String,
+    token: Option<String>,
+}
+
+impl ConsulSource {
+    fn from_env() -> Option<ConsulSource> {
+        let address = env::var("CONSUL_HTTP_ADDR").ok()?;
+        let key = env::var("RUNTIME_CONFIG_CONSUL_KEY").ok()?;
+
+        // Consul's own tools accept an address without a scheme.
+        let address = if address.starts_with("http://") || address.starts_with("https://") {
+            address
+        } else {
+            format!("http://{}", address)
+        };
+
+        Some(ConsulSource {
+            client: reqwest::Client::new(),
+            url: format!(
+                "{}/v1/kv/{}?raw",
+                address.trim_end_matches('/'),
+                key.trim_start_matches('/')
+            ),
+            token: env::var("CONSUL_HTTP_TOKEN").ok(),
+        })
+    }
+
+    async fn read(&self) -> Result<Option<String>> {
+        let mut request = self.client.get(&self.url).timeout(Duration::from_secs(10));
+        if let Some(token) = &self.token {
+            request = request.header("X-Consul-Token", token);
+        }
+
+        let response = request.send().await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let body = response.error_for_status()?.text().await?;
+        Ok(Some(body))
+    }
+}
+
+/// The places to read runtime settings from.
+pub struct RuntimeConfigSources {
+    file: Option<PathBuf>,
+    consul: Option<ConsulSource>,
+}
+
+impl RuntimeConfigSources {
+    pub fn from_env() -> RuntimeConfigSources {
+        RuntimeConfigSources {
+            file: env::var_os("RUNTIME_CONFIG_FILE").map(PathBuf::from),
+            consul: ConsulSource::from_env(),
+        }
+    }
+
+    /// Read the settings from every source. A missing file or Consul key is treated as empty.
+    pub async fn load(&self) -> Result<RuntimeSettings> {
+        let mut settings = RuntimeSettings::from_env()?;
+
+        if let Some(path) = &self.file {
+            match tokio::fs::read_to_string(path).await {
+                Ok(data) => {
+                    settings.merge(RuntimeSettings::parse(&path.to_string_lossy(), &data)?);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        if let Some(consul) = &self.consul {
+            if let Some(data) = consul.read().await? {
+                settings.merge(RuntimeSettings::parse("Consul", &data)?);
+            }
+        }
+
+        Ok(settings)
+    }
+}
+
+/// The parts of the server that runtime settings are applied to.
+pub struct RuntimeConfigTargets {
+    pub log_filter: Option<LogFilterHandle>,
+    pub input_queue: Queue,
+    pub action_queue: Queue,
+}
+
+impl RuntimeConfigTargets {
+    /// Apply the settings that differ from `previous`, or all of them if there are no previous
+    /// settings.
+    pub fn apply(&self, settings: &RuntimeSettings, previous: Option<&RuntimeSettings>) {
+        if let Some(handle) = &self.log_filter {
+            if previous.map_or(true, |p| p.log_filter != settings.log_filter) {
+                let filter = settings.log_filter.as_deref().unwrap_or(DEFAULT_LOG_FILTER);
+                match handle.set_filter(filter) {
+                    Ok(()) => event!(Level::INFO, %filter, "Changed log filter"),
+                    Err(e) => event!(Level::ERROR, error=%e, %filter, "Invalid log filter"),
+                }
+            }
+        }
+
+        self.input_queue.set_max_jobs(
+            settings
+                .input_max_concurrent_jobs
+                .unwrap_or_else(default_max_jobs),
+        );
+        self.action_queue.set_max_jobs(
+            settings
+                .action_max_concurrent_jobs
+                .unwrap_or_else(default_max_jobs),
+        );
+        self.input_queue
+            .set_max_jobs_per_key(settings.input_max_jobs_per_org);
+        self.action_queue
+            .set_max_jobs_per_key(settings.action_max_jobs_per_task);
+
+        if previous.map_or(true, |p| p.feature_flags != settings.feature_flags) {
+            event!(Level::INFO, flags=?settings.feature_flags, "Changed feature flag overrides");
+            ergo_tasks::feature_flags::set_overrides(settings.feature_flags.clone());
+        }
+    }
+}
+
+/// The same default that the queues use when no limit is given.
+fn default_max_jobs() -> NonZeroU32 {
+    NonZeroU32::new(num_cpus::get() as u32 * 2).expect("num_cpus is always at least 1")
+}
+
+/// Poll the sources for changes to the settings, starting from the `current` settings which
+/// have already been applied. If the settings can't be read, the current settings stay in place
+/// until the next successful read.
+pub fn start_runtime_config_watcher(
+    mut shutdown: GracefulShutdownConsumer,
+    sources: RuntimeConfigSources,
+    targets: RuntimeConfigTargets,
+    mut current: RuntimeSettings,
+    interval: Option<Duration>,
+) -> JoinHandle<()> {
+    let interval = interval.unwrap_or_else(|| Duration::from_secs(30));
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {},
+                _ = shutdown.wait_for_shutdown() => break,
+            }
+
+            let settings = match sources.load().await {
+                Ok(s) => s,
+                Err(e) => {
+                    event!(Level::ERROR, error=%e, "Failed to read runtime config");
+                    continue;
+                }
+            };
+
+            if settings != current {
+                targets.apply(&settings, Some(&current));
+                current = settings;
+            }
+        }
+    })
+}
@@ -1,29 +1,49 @@
-use crate::{error::Result, routes};
+use crate::{
+    error::Result,
+    health::HealthChecks,
+    maintenance_mode::{
+        start_maintenance_mode_watcher, MaintenanceMiddlewareFactory, MaintenanceMode,
+    },
+    routes,
+    runtime_config::{start_runtime_config_watcher, RuntimeConfigSources, RuntimeConfigTargets},
+    tracing_config::LogFilterHandle,
+    transaction::TransactionMiddlewareFactory,
+};
 
-use std::{env, net::TcpListener, path::PathBuf};
+use std::{env, net::TcpListener, path::PathBuf, time::Duration};
 
 use actix_files::NamedFile;
 use actix_identity::IdentityMiddleware;
 use actix_session::{storage::CookieSessionStore, SessionMiddleware};
 use actix_web::{
-    web::{self, PathConfig},
+    web::{self, JsonConfig, PathConfig, QueryConfig},
     App, HttpServer,
 };
 use ergo_auth::middleware::AuthenticateMiddlewareFactory;
 use ergo_database::DatabaseConfiguration;
-use ergo_graceful_shutdown::GracefulShutdownConsumer;
+use ergo_graceful_shutdown::{GracefulShutdownConsumer, ShutdownPhase};
 use ergo_notifications::NotificationManager;
+use ergo_queues::{circuit_breaker::BreakerConfig, recurring::RecurringSchedule, Queue};
 use ergo_tasks::{
     actions::{
-        dequeue::{ActionExecutor, ActionExecutorConfig},
+        artifacts::start_artifact_cleanup,
+        dequeue::{ActionBreakerConfig, ActionExecutor, ActionExecutorConfig, OpenBreakerMode},
         queue::ActionQueue,
     },
+    firehose::{FirehoseExporter, FirehoseExporterConfig, FirehoseSink},
     inputs::{
+        chat::start_chat_bridge,
         dequeue::{TaskExecutor, TaskExecutorConfig},
+        email::start_email_poller,
+        file_watch::start_file_watcher,
+        mqtt::start_mqtt_bridge,
         queue::InputQueue,
     },
+    log_retention::ArchiveStore,
+    maintenance::{MaintenanceRunner, MaintenanceRunnerConfig},
     periodic::monitor_missing_periodic_triggers,
     queue_drain_runner::AllQueuesDrain,
+    scripting::start_pool_probe,
 };
 use tracing::{event, info, Level};
 use tracing_actix_web::TracingLogger;
@@ -37,6 +57,8 @@ pub struct Config {
 
     pub no_drain_queues: bool,
     pub shutdown: GracefulShutdownConsumer,
+    /// Lets runtime config changes update the log filter.
+    pub log_filter: Option<LogFilterHandle>,
 }
 
 /// Tasks that run within the server. Keep this object alive for the duration of the process.
@@ -46,6 +68,18 @@ pub struct ServerTasks {
     input_runner: TaskExecutor,
     action_runner: ActionExecutor,
     periodic_task_monitor: tokio::task::JoinHandle<()>,
+    artifact_cleanup: tokio::task::JoinHandle<()>,
+    email_poller: tokio::task::JoinHandle<()>,
+    mqtt_bridge: tokio::task::JoinHandle<()>,
+    chat_bridge: tokio::task::JoinHandle<()>,
+    file_watcher: tokio::task::JoinHandle<()>,
+    js_pool_probe: tokio::task::JoinHandle<()>,
+    runtime_config_watcher: tokio::task::JoinHandle<()>,
+    maintenance_mode_watcher: tokio::task::JoinHandle<()>,
+    maintenance_runner: MaintenanceRunner,
+    firehose_exporter: Option<FirehoseExporter>,
+    http_stopper: tokio::task::JoinHandle<()>,
+    database_closer: tokio::task::JoinHandle<()>,
 }
 
 pub struct Server {
@@ -64,8 +98,22 @@ pub async fn start(config: Config) -> Result<Server> {
         redis_queue_prefix,
         no_drain_queues,
         shutdown,
+        log_filter,
     } = config;
 
+    // Fail on startup instead of when an action first needs an account.
+    if ergo_database::encryption::account_field_keys()?.enabled() {
+        info!("Account field encryption is enabled");
+    }
+
+    let loaded_catalogs = ergo_localization::load_catalogs_from_env()?;
+    if loaded_catalogs > 0 {
+        info!(count = loaded_catalogs, "Loaded message catalogs");
+    }
+
+    let runtime_config = RuntimeConfigSources::from_env();
+    let runtime_settings = runtime_config.load().await?;
+
     let bind_address = bind_address.unwrap_or_else(|| "127.0.0.1".to_string());
     let listener = TcpListener::bind(&format!("{}:{}", bind_address, bind_port))?;
     let bind_port = listener.local_addr()?.port();
@@ -75,6 +123,21 @@ pub async fn start(config: Config) -> Result<Server> {
 
     let redis_pool = ergo_database::RedisPool::new(redis_url, redis_queue_prefix.clone())?;
 
+    // Close the database pools at the end of the shutdown, once the queue processors that use
+    // them have finished their jobs.
+    let mut database_shutdown = shutdown.register("database", ShutdownPhase::Close);
+    let database_closer = {
+        let web_pg_pool = web_pg_pool.clone();
+        let backend_pg_pool = backend_pg_pool.clone();
+        tokio::spawn(async move {
+            database_shutdown.wait_for_shutdown().await;
+            database_shutdown.drain_started();
+            web_pg_pool.close().await;
+            backend_pg_pool.close().await;
+            database_shutdown.drain_finished();
+        })
+    };
+
     let input_queue = InputQueue::new(redis_pool.clone());
     let action_queue = ActionQueue::new(redis_pool.clone());
 
@@ -89,8 +152,7 @@ pub async fn start(config: Config) -> Result<Server> {
 
     notifications.start_task_queue_loop()?;
 
-    let web_app_data =
-        crate::web_app_server::app_data(web_pg_pool.clone(), redis_queue_prefix.clone());
+    let web_app_data = crate::web_app_server::app_data(web_pg_pool.clone(), redis_pool.clone());
     let backend_app_data = crate::backend_data::app_data(
         backend_pg_pool.clone(),
         notifications.clone(),
@@ -117,22 +179,140 @@ pub async fn start(config: Config) -> Result<Server> {
         None,
     );
 
+    let artifact_cleanup = start_artifact_cleanup(shutdown.clone(), backend_pg_pool.clone(), None);
+
+    let email_poller = start_email_poller(
+        shutdown.clone(),
+        backend_pg_pool.clone(),
+        redis_queue_prefix.clone(),
+        envoption::optional::<u64>("EMAIL_POLL_INTERVAL_SECS")?.map(Duration::from_secs),
+    );
+
+    let mqtt_bridge = start_mqtt_bridge(
+        shutdown.clone(),
+        backend_pg_pool.clone(),
+        redis_queue_prefix.clone(),
+        envoption::with_default("MQTT_SHARE_GROUP", "ergo".to_string())?,
+        envoption::optional::<u64>("MQTT_REFRESH_INTERVAL_SECS")?.map(Duration::from_secs),
+    );
+
+    let chat_bridge = start_chat_bridge(
+        shutdown.clone(),
+        backend_pg_pool.clone(),
+        redis_queue_prefix.clone(),
+        envoption::optional::<u64>("CHAT_REFRESH_INTERVAL_SECS")?.map(Duration::from_secs),
+    );
+
+    let file_watcher = start_file_watcher(
+        shutdown.clone(),
+        backend_pg_pool.clone(),
+        redis_queue_prefix.clone(),
+        envoption::optional::<u64>("FILE_WATCH_REFRESH_INTERVAL_SECS")?.map(Duration::from_secs),
+        envoption::optional::<u64>("FILE_WATCH_POLL_INTERVAL_SECS")?.map(Duration::from_secs),
+    );
+
+    let js_pool_probe = start_pool_probe(
+        shutdown.clone(),
+        envoption::optional::<u64>("JS_POOL_PROBE_INTERVAL_SECS")?.map(Duration::from_secs),
+        envoption::optional::<u64>("JS_POOL_PROBE_TIMEOUT_SECS")?.map(Duration::from_secs),
+    );
+
     let input_runner = TaskExecutor::new(TaskExecutorConfig {
         redis_pool: redis_pool.clone(),
         pg_pool: backend_pg_pool.clone(),
         shutdown: shutdown.clone(),
         notifications: Some(notifications.clone()),
-        max_concurrent_jobs: None,
+        max_concurrent_jobs: runtime_settings
+            .input_max_concurrent_jobs
+            .map(|n| n.get() as usize),
+        max_jobs_per_org: runtime_settings.input_max_jobs_per_org,
+        max_action_backlog: envoption::optional("INPUT_BACKPRESSURE_ACTION_BACKLOG")?,
+        heartbeat_interval: heartbeat_interval("INPUT_HEARTBEAT_INTERVAL_SECS")?,
     })?;
 
+    let log_retention_schedule = match env::var("LOG_RETENTION_SCHEDULE") {
+        Ok(s) if s == "off" => None,
+        Ok(s) => Some(RecurringSchedule::Cron(s)),
+        Err(_) => Some(RecurringSchedule::Cron("0 0 3 * * * *".to_string())),
+    };
+
+    let maintenance_runner = MaintenanceRunner::new(MaintenanceRunnerConfig {
+        pg_pool: backend_pg_pool.clone(),
+        redis_pool: redis_pool.clone(),
+        shutdown: shutdown.clone(),
+        log_retention_schedule,
+        archive_store: ArchiveStore::from_env()?,
+    })
+    .await?;
+
+    let firehose_exporter = FirehoseSink::from_env()?
+        .map(|sink| {
+            FirehoseExporter::new(FirehoseExporterConfig {
+                pg_pool: backend_pg_pool.clone(),
+                redis_pool: redis_pool.clone(),
+                shutdown: shutdown.clone(),
+                sink,
+            })
+        })
+        .transpose()?;
+
     let action_runner = ActionExecutor::new(ActionExecutorConfig {
-        redis_pool,
+        redis_pool: redis_pool.clone(),
         pg_pool: backend_pg_pool,
         shutdown: shutdown.clone(),
         notifications: Some(notifications.clone()),
-        max_concurrent_jobs: None,
+        max_concurrent_jobs: runtime_settings
+            .action_max_concurrent_jobs
+            .map(|n| n.get() as usize),
+        max_jobs_per_task: runtime_settings.action_max_jobs_per_task,
+        heartbeat_interval: heartbeat_interval("ACTION_HEARTBEAT_INTERVAL_SECS")?,
+        circuit_breaker: action_circuit_breaker()?,
     })?;
 
+    let runtime_config_targets = RuntimeConfigTargets {
+        log_filter,
+        input_queue: Queue::clone(input_runner.queue()),
+        action_queue: Queue::clone(action_runner.queue()),
+    };
+    runtime_config_targets.apply(&runtime_settings, None);
+    let runtime_config_watcher = start_runtime_config_watcher(
+        shutdown.clone(),
+        runtime_config,
+        runtime_config_targets,
+        runtime_settings,
+        envoption::optional::<u64>("RUNTIME_CONFIG_POLL_INTERVAL_SECS")?.map(Duration::from_secs),
+    );
+
+    let maintenance_mode = MaintenanceMode::new(
+        redis_pool.clone(),
+        vec![
+            Queue::clone(input_runner.queue()),
+            Queue::clone(action_runner.queue()),
+            Queue::clone(maintenance_runner.queue()),
+        ],
+    );
+    if let Err(e) = maintenance_mode.refresh().await {
+        event!(Level::ERROR, error=%e, "Failed to read maintenance mode");
+    }
+    let maintenance_mode_watcher = start_maintenance_mode_watcher(
+        shutdown.clone(),
+        maintenance_mode.clone(),
+        envoption::optional::<u64>("MAINTENANCE_POLL_INTERVAL_SECS")?.map(Duration::from_secs),
+    );
+
+    let drain_stats = web::Data::new(
+        queue_drain
+            .as_ref()
+            .map(|drain| drain.stats())
+            .unwrap_or_default(),
+    );
+
+    let health_checks = web::Data::new(
+        HealthChecks::new(web_pg_pool, redis_pool)
+            .with_dequeuer("input_dequeuer", input_runner.queue())
+            .with_dequeuer("action_dequeuer", action_runner.queue()),
+    );
+
     let cookie_signing_key = env::var("COOKIE_SIGNING_KEY")
         .ok()
         .unwrap_or_else(|| {
@@ -158,10 +338,17 @@ pub async fn start(config: Config) -> Result<Server> {
                 .app_data(PathConfig::default().error_handler(|err, req| {
                     event!(Level::ERROR, ?err, ?req);
                     eprintln!("{}", err);
-                    actix_web::error::ErrorNotFound(err)
+                    crate::error::Error::NotFound.into()
                 }))
+                .app_data(JsonConfig::default().error_handler(crate::error::json_error_handler))
+                .app_data(QueryConfig::default().error_handler(crate::error::query_error_handler))
                 .app_data(web_app_data.clone())
                 .app_data(backend_app_data.clone())
+                .app_data(health_checks.clone())
+                .app_data(drain_stats.clone())
+                .app_data(web::Data::new(maintenance_mode.clone()))
+                .wrap(TransactionMiddlewareFactory)
+                .wrap(MaintenanceMiddlewareFactory::new(maintenance_mode.clone()))
                 .wrap(AuthenticateMiddlewareFactory::new(
                     backend_app_data.auth.clone(),
                 ))
@@ -171,9 +358,35 @@ pub async fn start(config: Config) -> Result<Server> {
                 .configure(routes::accounts::config)
                 .configure(routes::actions::config)
                 .configure(routes::action_categories::config)
+                .configure(routes::apply::config)
+                .configure(routes::approvals::config)
+                .configure(routes::artifacts::config)
+                .configure(routes::chat::config)
+                .configure(routes::egress::config)
+                .configure(routes::email::config)
+                .configure(routes::feature_flags::config)
+                .configure(routes::file_watches::config)
+                .configure(routes::fixtures::config)
                 .configure(routes::inputs::config)
+                .configure(routes::js_libraries::config)
+                .configure(routes::locales::config)
+                .configure(routes::log_retention::config)
+                .configure(routes::logs::config)
+                .configure(routes::mqtt::config)
+                .configure(routes::notify_templates::config)
+                .configure(routes::oidc::config)
+                .configure(routes::openapi::config)
+                .configure(routes::orgs::config)
+                .configure(routes::permissions::config)
+                .configure(routes::push::config)
+                .configure(routes::quotas::config)
+                .configure(routes::sessions::config)
                 .configure(routes::status::config)
-                .configure(routes::tasks::config),
+                .configure(routes::tags::config)
+                .configure(routes::task_plans::config)
+                .configure(routes::tasks::config)
+                .configure(routes::trigger_tokens::config)
+                .configure(routes::users::config),
         );
 
         if !serve_dir.is_empty() {
@@ -190,9 +403,21 @@ pub async fn start(config: Config) -> Result<Server> {
 
         app
     })
+    // Signals are handled by the graceful shutdown, which stops the server below.
+    .disable_signals()
     .listen(listener)?
     .run();
 
+    let mut http_shutdown = shutdown.register("http", ShutdownPhase::StopAccepting);
+    let server_handle = server.handle();
+    let http_stopper = tokio::spawn(async move {
+        http_shutdown.wait_for_shutdown().await;
+        http_shutdown.drain_started();
+        // Stop accepting connections and wait for the requests in progress to finish.
+        server_handle.stop(true).await;
+        http_shutdown.drain_finished();
+    });
+
     Ok(Server {
         server,
         bind_address,
@@ -203,6 +428,49 @@ pub async fn start(config: Config) -> Result<Server> {
             input_runner,
             action_runner,
             periodic_task_monitor,
+            artifact_cleanup,
+            email_poller,
+            mqtt_bridge,
+            chat_bridge,
+            file_watcher,
+            js_pool_probe,
+            runtime_config_watcher,
+            maintenance_mode_watcher,
+            maintenance_runner,
+            firehose_exporter,
+            http_stopper,
+            database_closer,
         },
     })
 }
+
+/// Read the action circuit breaker settings. The breakers are off unless a failure threshold is
+/// set.
+fn action_circuit_breaker() -> Result<Option<ActionBreakerConfig>> {
+    let failure_threshold: u32 = envoption::with_default("ACTION_BREAKER_THRESHOLD", 0u32)?;
+    if failure_threshold == 0 {
+        return Ok(None);
+    }
+
+    let cooldown: u64 = envoption::with_default("ACTION_BREAKER_COOLDOWN_SECS", 60u64)?;
+    let mode = match env::var("ACTION_BREAKER_MODE").ok().as_deref() {
+        Some("pause") => OpenBreakerMode::Pause,
+        _ => OpenBreakerMode::Fail,
+    };
+
+    Ok(Some(ActionBreakerConfig {
+        breaker: BreakerConfig {
+            failure_threshold,
+            cooldown: Duration::from_secs(cooldown),
+            ..Default::default()
+        },
+        mode,
+    }))
+}
+
+/// Read a queue heartbeat interval in seconds. Heartbeats are on by default, and setting the
+/// interval to 0 turns them off.
+fn heartbeat_interval(var: &str) -> Result<Option<Duration>> {
+    let secs: u64 = envoption::with_default(var, 30u64)?;
+    Ok(Some(secs).filter(|s| *s > 0).map(Duration::from_secs))
+}
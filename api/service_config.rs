@@ -1,5 +1,5 @@
 use crate::error::Error;
-use ergo_database::{DatabaseConfiguration, PostgresAuth, PostgresPool};
+use ergo_database::{DatabaseConfiguration, PostgresAuth, PostgresPool, PostgresPoolOptions};
 use log::LevelFilter;
 use sqlx::{
     postgres::{PgConnectOptions, PgPoolOptions},
@@ -10,18 +10,22 @@ async fn pg_pool(
     auth: PostgresAuth,
     configuration: &DatabaseConfiguration,
 ) -> Result<PostgresPool, Error> {
-    let mut connect_options = PgConnectOptions::new()
+    let connect_options = PgConnectOptions::new()
         .host(&configuration.host)
         .port(configuration.port)
         .username(&auth.username)
         .password(&auth.password)
         .database(&configuration.database);
 
+    let pool_options = PostgresPoolOptions::from_env()?;
+    let mut connect_options = connect_options.options(pool_options.session_settings());
     connect_options.log_statements(LevelFilter::Debug);
 
     PgPoolOptions::new()
-        .max_connections(16)
-        .max_lifetime(Some(std::time::Duration::from_secs(3600 * 12)))
+        .max_connections(pool_options.max_connections)
+        .max_lifetime(pool_options.max_lifetime)
+        .idle_timeout(pool_options.idle_timeout)
+        .test_before_acquire(pool_options.test_before_acquire)
         .acquire_timeout(std::time::Duration::from_secs(30))
         .connect_with(connect_options)
         .await
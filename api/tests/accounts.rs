@@ -0,0 +1,86 @@
+use ergo_database::object_id::AccountId;
+use ergo_tasks::actions::accounts::{AccountLimits, AccountPublicInfo};
+
+use crate::common::{run_app_test, TestApp};
+
+async fn add_account(app: &TestApp) -> anyhow::Result<AccountId> {
+    let mut conn = app.database.pool.acquire().await?;
+    sqlx::query!(
+        "INSERT INTO account_types (account_type_id, name, fields)
+        VALUES ('test_api', 'Test API', ARRAY['token'])
+        ON CONFLICT DO NOTHING"
+    )
+    .execute(&mut conn)
+    .await?;
+
+    let account_id = AccountId::new();
+    sqlx::query!(
+        "INSERT INTO accounts (account_id, account_type_id, name, org_id, fields)
+        VALUES ($1, 'test_api', 'Rate limited API', $2, $3)",
+        account_id.0,
+        app.org_id.0,
+        serde_json::json!({ "token": "abc" })
+    )
+    .execute(&mut conn)
+    .await?;
+
+    Ok(account_id)
+}
+
+#[actix_rt::test]
+async fn account_limits() {
+    run_app_test(|app| async move {
+        let client = &app.admin_user.client;
+        let account_id = add_account(&app).await?;
+        let url = format!("accounts/{}/limits", account_id);
+
+        let limits = AccountLimits {
+            max_concurrency: Some(2),
+            requests_per_minute: Some(30),
+        };
+        client
+            .put(&url)
+            .json(&limits)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let accounts: Vec<AccountPublicInfo> = client
+            .get("accounts")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let account = accounts
+            .iter()
+            .find(|a| a.account_id == account_id)
+            .expect("account in list");
+        assert_eq!(account.max_concurrency, Some(2));
+        assert_eq!(account.requests_per_minute, Some(30));
+
+        let response = client
+            .put(&url)
+            .json(&AccountLimits {
+                max_concurrency: Some(0),
+                requests_per_minute: None,
+            })
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 400, "zero concurrency");
+
+        let user = app.add_user(&app.admin_user.org_id, "account user").await?;
+        let response = user.client.put(&url).json(&limits).send().await?;
+        assert_eq!(response.status().as_u16(), 403, "non-admin");
+
+        let response = client
+            .put(format!("accounts/{}/limits", AccountId::new()))
+            .json(&limits)
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 404, "missing account");
+
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,129 @@
+use ergo_api::routes::{
+    logs::{ActionsLogEntry, RetryActionResponse},
+    tasks::TaskInput,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    common::run_app_test,
+    tasks::{bootstrap_inputs_and_actions, simple_state_machine, simple_task_actions},
+};
+
+#[actix_rt::test]
+async fn retry_failed_action() {
+    run_app_test(|app| async move {
+        let (_, actions) = bootstrap_inputs_and_actions(&app).await;
+        let (config, state) = simple_state_machine();
+        let admin = &app.admin_user.client;
+        let task = admin
+            .new_task(&TaskInput {
+                name: "retried task".to_string(),
+                alias: None,
+                description: None,
+                enabled: true,
+                disabled_input_mode: Default::default(),
+                compiled: config,
+                state: Some(state),
+                source: serde_json::Value::Null,
+                state_reset: None,
+                tags: Vec::new(),
+                actions: simple_task_actions(&actions),
+                triggers: Default::default(),
+            })
+            .await?;
+
+        let mut conn = app.database.pool.acquire().await?;
+        let add_log = |status: &'static str, payload: serde_json::Value| {
+            let id = Uuid::new_v4();
+            let query = sqlx::query(
+                "INSERT INTO actions_log (actions_log_id, task_id, task_action_local_id, status,
+                    payload)
+                VALUES ($1, $2, 'run', $3::action_status, $4)",
+            )
+            .bind(id)
+            .bind(task.task_id.0)
+            .bind(status)
+            .bind(payload);
+            (id, query)
+        };
+
+        let (failed, query) = add_log("error", json!({ "url": "https://bad.example.com/" }));
+        query.execute(&mut conn).await?;
+        let (succeeded, query) = add_log("success", json!({}));
+        query.execute(&mut conn).await?;
+        let (redacted, query) = add_log("error", json!({ "token": "********" }));
+        query.execute(&mut conn).await?;
+
+        let edited = json!({ "url": "https://good.example.com/" });
+        let retry: RetryActionResponse = admin
+            .post(format!("actions_log/{}/retry", failed))
+            .json(&json!({ "payload": edited }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let row = sqlx::query!(
+            r##"SELECT payload AS "payload!", retry_of, status::text AS "status!"
+            FROM actions_log WHERE actions_log_id=$1"##,
+            retry.actions_log_id
+        )
+        .fetch_one(&mut conn)
+        .await?;
+        assert_eq!(row.payload, edited);
+        assert_eq!(row.retry_of, Some(failed));
+        assert_eq!(row.status, "pending");
+
+        let entries: Vec<ActionsLogEntry> = admin
+            .get("actions_log")
+            .query(&[("task_id", task.task_id.to_string())])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let entry = entries
+            .iter()
+            .find(|e| e.actions_log_id == retry.actions_log_id)
+            .expect("retry is in the actions log");
+        assert_eq!(entry.retry_of, Some(failed));
+
+        let response = admin
+            .post(format!("actions_log/{}/retry", succeeded))
+            .json(&json!({}))
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "successful actions can not be retried"
+        );
+
+        let response = admin
+            .post(format!("actions_log/{}/retry", redacted))
+            .json(&json!({}))
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "redacted payloads must be edited before retrying"
+        );
+
+        let response = admin
+            .post(format!("actions_log/{}/retry", redacted))
+            .json(&json!({ "payload": { "token": "a-real-token" } }))
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            202,
+            "edited payload is accepted"
+        );
+
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,146 @@
+use ergo_api::routes::apply::{ApplyOperation, ApplyResult, ObjectKind};
+use serde_json::json;
+
+use crate::{
+    common::{run_app_test, TestApp},
+    tasks::simple_state_machine,
+};
+
+fn bundle(app: &TestApp, task_description: &str) -> serde_json::Value {
+    let (machine, _) = simple_state_machine();
+    json!({
+        "inputs": [{
+            "name": "Applied URL",
+            "description": null,
+            "input_category_id": null,
+            "payload_schema": {
+                "type": "object",
+                "properties": { "url": { "type": "string" } },
+            },
+        }],
+        "actions": [{
+            "action_category_id": app.base_action_category,
+            "name": "Applied Echo",
+            "description": null,
+            "executor_id": "raw_command",
+            "executor_template": {
+                "t": "Template",
+                "c": [["command", "/bin/echo"], ["args", ["{{text}}"]]],
+            },
+            "template_fields": [{
+                "name": "text",
+                "format": { "type": "string" },
+                "optional": false,
+                "description": null,
+            }],
+            "timeout": null,
+            "postprocess_script": null,
+            "account_required": false,
+        }],
+        "tasks": [{
+            "name": "Applied task",
+            "description": task_description,
+            "alias": null,
+            "compiled": machine,
+            "actions": {
+                "run": { "name": "Run", "action": "Applied Echo", "account_id": null, "action_template": null },
+            },
+            "triggers": {
+                "go": { "name": "Go", "input": "Applied URL", "description": null, "periodic": null, "dedupe": null },
+            },
+        }],
+    })
+}
+
+async fn apply(app: &TestApp, bundle: &serde_json::Value, dry_run: bool) -> ApplyResult {
+    app.admin_user
+        .client
+        .post(format!("apply?dry_run={}", dry_run))
+        .json(bundle)
+        .send()
+        .await
+        .expect("Sending request")
+        .error_for_status()
+        .expect("Applying bundle")
+        .json()
+        .await
+        .expect("Parsing result")
+}
+
+fn operations(result: &ApplyResult) -> Vec<(ObjectKind, ApplyOperation)> {
+    result
+        .changes
+        .iter()
+        .map(|c| (c.kind, c.operation))
+        .collect()
+}
+
+#[actix_rt::test]
+async fn apply_bundle() {
+    run_app_test(|app| async move {
+        let original = bundle(&app, "first");
+
+        let result = apply(&app, &original, true).await;
+        assert_eq!(
+            operations(&result),
+            vec![
+                (ObjectKind::Input, ApplyOperation::Create),
+                (ObjectKind::Action, ApplyOperation::Create),
+                (ObjectKind::Task, ApplyOperation::Create),
+            ]
+        );
+        let tasks = app.admin_user.client.list_tasks().await?;
+        assert!(tasks.is_empty(), "dry run should not create the task");
+
+        let result = apply(&app, &original, false).await;
+        assert!(result.changes.iter().all(|c| c.id.is_some()));
+        let tasks = app.admin_user.client.list_tasks().await?;
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].name, "Applied task");
+
+        let result = apply(&app, &original, false).await;
+        assert!(
+            result
+                .changes
+                .iter()
+                .all(|c| c.operation == ApplyOperation::Unchanged),
+            "applying the same bundle again should not change anything: {:?}",
+            result.changes
+        );
+
+        let updated = bundle(&app, "second");
+        let result = apply(&app, &updated, false).await;
+        let task_change = &result.changes[2];
+        assert_eq!(task_change.operation, ApplyOperation::Update);
+        assert_eq!(task_change.changes.len(), 1);
+        assert_eq!(task_change.changes[0].path, "/description");
+        assert_eq!(task_change.changes[0].after, Some(json!("second")));
+
+        let tasks = app.admin_user.client.list_tasks().await?;
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].description.as_deref(), Some("second"));
+
+        Ok(())
+    })
+    .await
+}
+
+#[actix_rt::test]
+async fn unknown_reference() {
+    run_app_test(|app| async move {
+        let mut bundle = bundle(&app, "first");
+        bundle["actions"] = json!([]);
+
+        let response = app
+            .admin_user
+            .client
+            .post("apply?dry_run=true")
+            .json(&bundle)
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 400);
+
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,222 @@
+use chrono::{Duration, Utc};
+use ergo_api::routes::{
+    inputs::InputPayload,
+    tasks::{TaskInput, TaskTriggerInput},
+};
+use ergo_database::object_id::TaskId;
+use ergo_tasks::approvals::hash_token;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    common::{run_app_test, TestApp, TestUser},
+    tasks::{bootstrap_inputs_and_actions, simple_state_machine, simple_task_actions},
+};
+
+/// Create a task whose `decided` trigger accepts approval decisions.
+async fn bootstrap_task(app: &TestApp, user: &TestUser) -> TaskId {
+    let (_, actions) = bootstrap_inputs_and_actions(app).await;
+    let input = app
+        .admin_user
+        .client
+        .new_input(&InputPayload {
+            input_category_id: None,
+            name: "Decision".to_string(),
+            description: None,
+            payload_schema: json!({ "type": "object" }),
+        })
+        .await
+        .expect("Creating input");
+
+    let (config, state) = simple_state_machine();
+    let task_input = TaskInput {
+        name: "task".to_string(),
+        alias: None,
+        description: None,
+        enabled: true,
+        disabled_input_mode: Default::default(),
+        compiled: config,
+        state: Some(state),
+        source: serde_json::Value::Null,
+        state_reset: None,
+        tags: Vec::new(),
+        actions: simple_task_actions(&actions),
+        triggers: [(
+            "decided".to_string(),
+            TaskTriggerInput {
+                name: "Decided".to_string(),
+                description: None,
+                input_id: input.input_id,
+                periodic: None,
+                dedupe: None,
+                sensitive: false,
+                routes: None,
+            },
+        )]
+        .into_iter()
+        .collect(),
+    };
+
+    user.client
+        .new_task(&task_input)
+        .await
+        .expect("Creating task")
+        .task_id
+}
+
+async fn add_approval(
+    app: &TestApp,
+    user: &TestUser,
+    task_id: &TaskId,
+    token: &str,
+    expires_in: Duration,
+) -> Uuid {
+    let approval_id = Uuid::new_v4();
+    sqlx::query!(
+        r##"INSERT INTO approvals
+            (approval_id, org_id, task_id, task_trigger_local_id, user_id, message, token_hash,
+                expires)
+        VALUES ($1, $2, $3, 'decided', $4, 'Deploy?', $5, $6)"##,
+        approval_id,
+        user.org_id.0,
+        task_id.0,
+        user.user_id.0,
+        hash_token(token),
+        Utc::now() + expires_in
+    )
+    .execute(&app.database.pool)
+    .await
+    .expect("Adding approval");
+    approval_id
+}
+
+#[actix_rt::test]
+async fn decide_with_token() {
+    run_app_test(|app| async move {
+        let org_id = app.add_org("user org").await?;
+        let user = app.add_user(&org_id, "User 1").await?;
+        let task_id = bootstrap_task(&app, &user).await;
+        let approval_id =
+            add_approval(&app, &user, &task_id, "the token", Duration::hours(1)).await;
+        let url = format!("approvals/{}", approval_id);
+
+        let response = app
+            .client
+            .post(&url)
+            .json(&json!({ "decision": "approved", "token": "wrong token" }))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 401, "wrong token");
+
+        let response = app
+            .client
+            .post(&url)
+            .json(&json!({ "decision": "pending", "token": "the token" }))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 400, "pending is not a decision");
+
+        let response = app
+            .client
+            .post(&url)
+            .json(&json!({ "decision": "approved", "comment": "ship it", "token": "the token" }))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 202);
+
+        let response = app
+            .client
+            .post(&url)
+            .json(&json!({ "decision": "denied", "token": "the token" }))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 409, "second decision");
+
+        let approval: serde_json::Value = user
+            .client
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(approval["status"], json!("approved"));
+        assert_eq!(approval["comment"], json!("ship it"));
+
+        Ok(())
+    })
+    .await
+}
+
+#[actix_rt::test]
+async fn decide_as_user() {
+    run_app_test(|app| async move {
+        let org_id = app.add_org("user org").await?;
+        let user = app.add_user(&org_id, "User 1").await?;
+        let other_org_id = app.add_org("other org").await?;
+        let other_user = app.add_user(&other_org_id, "User 2").await?;
+        let task_id = bootstrap_task(&app, &user).await;
+
+        let approval_id =
+            add_approval(&app, &user, &task_id, "the token", Duration::hours(1)).await;
+        let url = format!("approvals/{}", approval_id);
+
+        let response = other_user
+            .client
+            .post(&url)
+            .json(&json!({ "decision": "approved" }))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 404, "user in another org");
+
+        let response = user
+            .client
+            .post(&url)
+            .json(&json!({ "decision": "denied" }))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 202);
+
+        let decided_by = sqlx::query_scalar!(
+            "SELECT decided_by FROM approvals WHERE approval_id=$1",
+            approval_id
+        )
+        .fetch_one(&app.database.pool)
+        .await?;
+        assert_eq!(decided_by, Some(user.user_id.0));
+
+        let expired_id =
+            add_approval(&app, &user, &task_id, "the token", Duration::hours(-1)).await;
+        let response = user
+            .client
+            .post(format!("approvals/{}", expired_id))
+            .json(&json!({ "decision": "approved" }))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 409, "expired approval");
+
+        let pending: Vec<serde_json::Value> = user
+            .client
+            .get("approvals?status=pending")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert!(pending.is_empty(), "no pending approvals");
+
+        let expired: Vec<serde_json::Value> = user
+            .client
+            .get("approvals?status=expired")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0]["approval_id"], json!(expired_id));
+
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,145 @@
+use ergo_api::routes::{
+    chat::{ChatSubscription, ChatSubscriptionInput},
+    tasks::TaskInput,
+};
+use ergo_database::object_id::AccountId;
+
+use crate::{
+    common::{run_app_test, TestApp},
+    tasks::{
+        bootstrap_inputs_and_actions, simple_state_machine, simple_task_actions,
+        simple_task_triggers,
+    },
+};
+
+async fn add_bot_account(app: &TestApp) -> anyhow::Result<AccountId> {
+    let mut conn = app.database.pool.acquire().await?;
+    sqlx::query!(
+        "INSERT INTO account_types (account_type_id, name, fields)
+        VALUES ('discord_bot', 'Discord Bot', ARRAY['token'])
+        ON CONFLICT DO NOTHING"
+    )
+    .execute(&mut conn)
+    .await?;
+
+    let account_id = AccountId::new();
+    sqlx::query!(
+        "INSERT INTO accounts (account_id, account_type_id, name, org_id, fields)
+        VALUES ($1, 'discord_bot', 'Test bot', $2, $3)",
+        account_id.0,
+        app.org_id.0,
+        serde_json::json!({ "token": "bot token" })
+    )
+    .execute(&mut conn)
+    .await?;
+
+    Ok(account_id)
+}
+
+#[actix_rt::test]
+async fn subscription_crud() {
+    run_app_test(|app| async move {
+        let client = &app.admin_user.client;
+        let account_id = add_bot_account(&app).await?;
+
+        let (inputs, actions) = bootstrap_inputs_and_actions(&app).await;
+        let (machine, states) = simple_state_machine();
+        let task = client
+            .new_task(&TaskInput {
+                name: "chat task".to_string(),
+                alias: None,
+                description: None,
+                enabled: true,
+                disabled_input_mode: Default::default(),
+                compiled: machine,
+                source: serde_json::Value::Null,
+                state: Some(states),
+                state_reset: None,
+                tags: Vec::new(),
+                actions: simple_task_actions(&actions),
+                triggers: simple_task_triggers(&inputs),
+            })
+            .await?;
+
+        let mut input = ChatSubscriptionInput {
+            account_id: account_id.clone(),
+            channel_id: "12345".to_string(),
+            command: Some("/deploy now".to_string()),
+            task_id: task.task_id.clone(),
+            trigger: "run_it".to_string(),
+            enabled: true,
+        };
+
+        let response = client
+            .post("chat_subscriptions")
+            .json(&input)
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "command with whitespace should be rejected"
+        );
+
+        input.command = Some("/deploy".to_string());
+        let subscription: ChatSubscription = client
+            .post("chat_subscriptions")
+            .json(&input)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(subscription.command.as_deref(), Some("deploy"));
+        assert_eq!(subscription.trigger, "run_it");
+
+        input.trigger = "prepare".to_string();
+        input.command = None;
+        let updated: ChatSubscription = client
+            .put(format!(
+                "chat_subscriptions/{}",
+                subscription.chat_subscription_id
+            ))
+            .json(&input)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(updated.trigger, "prepare");
+        assert_eq!(updated.command, None);
+
+        let other_org = app.add_org("other org").await?;
+        let other_user = app.add_user(&other_org, "other user").await?;
+        let response = other_user
+            .client
+            .get(format!(
+                "chat_subscriptions/{}",
+                subscription.chat_subscription_id
+            ))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 404);
+
+        client
+            .delete(format!(
+                "chat_subscriptions/{}",
+                subscription.chat_subscription_id
+            ))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let subscriptions: Vec<ChatSubscription> = client
+            .get("chat_subscriptions")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert!(subscriptions.is_empty());
+
+        Ok(())
+    })
+    .await
+}
@@ -1,5 +1,5 @@
 use anyhow::{anyhow, Result};
-use ergo_api::{cmd::make_api_key, server::Server};
+use ergo_api::{cmd::make_api_key, routes::fixtures::FixtureResult, server::Server};
 use ergo_database::object_id::{ActionCategoryId, OrgId, UserId};
 use futures::Future;
 use fxhash::FxHashMap;
@@ -46,6 +46,9 @@ async fn start_app(
     let shutdown = ergo_graceful_shutdown::GracefulShutdown::new();
     let redis_key_prefix = Uuid::new_v4().to_string();
     let redis_url = std::env::var("TEST_REDIS_URL").ok();
+    // The tests run their mock servers on localhost.
+    std::env::set_var("EGRESS_ALLOW_PRIVATE_NETWORKS", "true");
+    std::env::set_var("SIGNUP_ENABLED", "true");
 
     let config = ergo_api::server::Config {
         database: database.config.clone(),
@@ -55,6 +58,7 @@ async fn start_app(
         redis_queue_prefix: Some(redis_key_prefix.clone()),
         no_drain_queues: false,
         shutdown: shutdown.consumer(),
+        log_filter: None,
     };
     Lazy::force(&ergo_test::TRACING);
     let Server {
@@ -146,9 +150,10 @@ impl TestApp {
         name: &str,
         password: Option<&str>,
     ) -> Result<TestUser> {
-        if password.is_some() {
-            todo!("Password support will be implemented once the API supports creating users");
-        }
+        let password_hash = password
+            .map(ergo_auth::password::new_hash)
+            .transpose()?
+            .unwrap_or_default();
 
         let user_id = UserId::new();
         let mut conn = self.database.pool.acquire().await?;
@@ -160,7 +165,15 @@ impl TestApp {
             &org_id.0,
             name,
             format!("test_user_{}@example.com", user_id),
-            ""
+            password_hash
+        )
+        .execute(&mut conn)
+        .await?;
+
+        sqlx::query!(
+            "INSERT INTO org_members (org_id, user_id) VALUES ($1, $2)",
+            &org_id.0,
+            &user_id.0
         )
         .execute(&mut conn)
         .await?;
@@ -171,7 +184,7 @@ impl TestApp {
         Ok(TestUser {
             user_id,
             org_id: org_id.clone(),
-            password: None,
+            password: password.map(String::from),
             client: self.client.clone_with_api_key(key.clone()),
             api_key: key,
         })
@@ -180,6 +193,20 @@ impl TestApp {
     pub async fn add_user(&self, org_id: &OrgId, name: &str) -> Result<TestUser> {
         self.add_user_with_password(org_id, name, None).await
     }
+
+    /// Load a fixture pack into the admin user's organization.
+    pub async fn load_fixture(&self, name: &str) -> Result<FixtureResult> {
+        let result = self
+            .admin_user
+            .client
+            .post(format!("fixtures/{}", name))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<FixtureResult>()
+            .await?;
+        Ok(result)
+    }
 }
 
 /** Compare hashmaps that have different value types, if those types implement PartialEq
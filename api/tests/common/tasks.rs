@@ -68,6 +68,21 @@ impl TestClient {
             .await
     }
 
+    pub async fn replay_input(
+        &self,
+        log_id: &uuid::Uuid,
+        payload: Option<serde_json::Value>,
+    ) -> Result<TaskTriggerResponse> {
+        let url = format!("inputs_log/{}/replay", log_id);
+        self.post(url)
+            .json(&serde_json::json!({ "payload": payload }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<_>()
+            .await
+    }
+
     pub async fn list_inputs(&self) -> Result<Vec<Input>> {
         self.get("inputs")
             .send()
@@ -0,0 +1,69 @@
+use ergo_tasks::egress::EgressPolicy;
+
+use crate::common::run_app_test;
+
+#[actix_rt::test]
+async fn egress_policy() {
+    run_app_test(|app| async move {
+        let admin = &app.admin_user.client;
+
+        let policy: EgressPolicy = admin
+            .get("org/egress_policy")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert!(
+            policy.allow_private_networks,
+            "test environment allows private networks by default"
+        );
+
+        let new_policy = EgressPolicy {
+            allow: vec![
+                "*.example.com".parse().unwrap(),
+                "10.1.0.0/16".parse().unwrap(),
+            ],
+            deny: vec!["internal.example.com".parse().unwrap()],
+            allow_private_networks: false,
+        };
+        admin
+            .put("org/egress_policy")
+            .json(&new_policy)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let policy: EgressPolicy = admin
+            .get("org/egress_policy")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(policy, new_policy);
+
+        let response = admin
+            .put("org/egress_policy")
+            .json(&serde_json::json!({ "allow": ["http://"] }))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 400, "invalid rule is rejected");
+
+        let user = app.add_user(&app.admin_user.org_id, "egress user").await?;
+        let response = user
+            .client
+            .put("org/egress_policy")
+            .json(&EgressPolicy::allow_all())
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            403,
+            "non-admin can not change the policy"
+        );
+
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,151 @@
+use ergo_api::routes::{
+    email::{EmailMailbox, EmailMailboxInput},
+    inputs::InputPayload,
+    tasks::{TaskInput, TaskTriggerInput},
+};
+use fxhash::FxHashMap;
+use serde_json::json;
+
+use crate::{common::run_app_test, tasks::simple_state_machine};
+
+const MESSAGE: &str = "From: Sender <sender@example.com>\r
+To: tasks@example.com\r
+Subject: Weekly report\r
+Date: Tue, 24 Jan 2023 10:00:00 +0000\r
+\r
+The report is attached.\r
+";
+
+#[actix_rt::test]
+async fn inbound_email() {
+    run_app_test(|app| async move {
+        let client = &app.admin_user.client;
+
+        let input = client
+            .new_input(&InputPayload {
+                input_category_id: None,
+                name: "Email".to_string(),
+                description: None,
+                payload_schema: json!({
+                    "type": "object",
+                    "required": ["from", "subject"],
+                }),
+            })
+            .await?;
+
+        let (machine, states) = simple_state_machine();
+        let task = client
+            .new_task(&TaskInput {
+                name: "email task".to_string(),
+                alias: None,
+                description: None,
+                enabled: true,
+                disabled_input_mode: Default::default(),
+                compiled: machine,
+                source: serde_json::Value::Null,
+                state: Some(states),
+                state_reset: None,
+                tags: Vec::new(),
+                actions: FxHashMap::default(),
+                triggers: [(
+                    "email".to_string(),
+                    TaskTriggerInput {
+                        name: "New email".to_string(),
+                        description: None,
+                        input_id: input.input_id.clone(),
+                        periodic: None,
+                        dedupe: None,
+                        sensitive: false,
+                        routes: None,
+                    },
+                )]
+                .into_iter()
+                .collect(),
+            })
+            .await?;
+
+        let response = client
+            .post("email_mailboxes")
+            .json(&EmailMailboxInput {
+                name: "reports".to_string(),
+                task_id: task.task_id.clone(),
+                trigger: "email".to_string(),
+                account_id: None,
+                folder: "INBOX".to_string(),
+                poll_interval_secs: 10,
+                enabled: true,
+            })
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "poll interval below the minimum should be rejected"
+        );
+
+        let mailbox: EmailMailbox = client
+            .post("email_mailboxes")
+            .json(&EmailMailboxInput {
+                name: "reports".to_string(),
+                task_id: task.task_id.clone(),
+                trigger: "email".to_string(),
+                account_id: None,
+                folder: "INBOX".to_string(),
+                poll_interval_secs: 300,
+                enabled: true,
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(mailbox.trigger, "email");
+
+        let inbound_url = format!("email_mailboxes/{}/inbound", mailbox.email_mailbox_id);
+
+        let response = client
+            .post(&inbound_url)
+            .header("Content-Type", "message/rfc822")
+            .body(MESSAGE)
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 202);
+
+        let logs = client.get_recent_logs().await?;
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].task_trigger_local_id, "email");
+        assert_eq!(logs[0].payload["subject"], "Weekly report");
+        assert_eq!(logs[0].payload["from"][0]["address"], "sender@example.com");
+
+        let other_user = app.add_user(&app.admin_user.org_id, "other").await?;
+        let response = other_user
+            .client
+            .post(&inbound_url)
+            .body(MESSAGE)
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            404,
+            "user without trigger permission should not be able to send email"
+        );
+
+        client
+            .delete(format!("email_mailboxes/{}", mailbox.email_mailbox_id))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let mailboxes: Vec<EmailMailbox> = client
+            .get("email_mailboxes")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert!(mailboxes.is_empty());
+
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,95 @@
+use ergo_api::routes::feature_flags::FeatureFlagsResponse;
+use ergo_tasks::feature_flags::FeatureFlagInput;
+
+use crate::common::run_app_test;
+
+#[actix_rt::test]
+async fn org_overrides() {
+    run_app_test(|app| async move {
+        let admin = &app.admin_user.client;
+
+        let flags: FeatureFlagsResponse = admin
+            .get("org/feature_flags")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert!(flags.overrides.is_empty(), "no overrides by default");
+
+        admin
+            .put("org/feature_flags/new_thing")
+            .json(&FeatureFlagInput {
+                enabled: true,
+                description: Some("Trying it out".to_string()),
+            })
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let flags: FeatureFlagsResponse = admin
+            .get("org/feature_flags")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert!(flags.enabled.contains(&"new_thing".to_string()));
+        assert_eq!(flags.overrides.len(), 1);
+        assert_eq!(flags.overrides[0].flag, "new_thing");
+        assert_eq!(
+            flags.overrides[0].description.as_deref(),
+            Some("Trying it out")
+        );
+
+        let user = app.add_user(&app.admin_user.org_id, "flag user").await?;
+        let response = user
+            .client
+            .put("org/feature_flags/new_thing")
+            .json(&FeatureFlagInput {
+                enabled: false,
+                description: None,
+            })
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            403,
+            "non-admin can not change flags"
+        );
+
+        let flags: FeatureFlagsResponse = user
+            .client
+            .get("org/feature_flags")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert!(
+            flags.enabled.contains(&"new_thing".to_string()),
+            "non-admin can see flags"
+        );
+
+        admin
+            .delete("org/feature_flags/new_thing")
+            .send()
+            .await?
+            .error_for_status()?;
+        let flags: FeatureFlagsResponse = admin
+            .get("org/feature_flags")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert!(!flags.enabled.contains(&"new_thing".to_string()));
+        assert!(flags.overrides.is_empty());
+
+        let response = admin.delete("org/feature_flags/new_thing").send().await?;
+        assert_eq!(response.status().as_u16(), 404);
+
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,150 @@
+use ergo_api::routes::{
+    file_watches::{FileWatch, FileWatchInput},
+    tasks::TaskInput,
+};
+use ergo_database::object_id::AccountId;
+use ergo_tasks::inputs::file_watch::{FileParse, FileWatchSource};
+
+use crate::{
+    common::{run_app_test, TestApp},
+    tasks::{
+        bootstrap_inputs_and_actions, simple_state_machine, simple_task_actions,
+        simple_task_triggers,
+    },
+};
+
+async fn add_s3_account(app: &TestApp) -> anyhow::Result<AccountId> {
+    let mut conn = app.database.pool.acquire().await?;
+    sqlx::query!(
+        "INSERT INTO account_types (account_type_id, name, fields)
+        VALUES ('s3', 'S3', ARRAY['access_key_id', 'secret_access_key', 'region', 'endpoint'])
+        ON CONFLICT DO NOTHING"
+    )
+    .execute(&mut conn)
+    .await?;
+
+    let account_id = AccountId::new();
+    sqlx::query!(
+        "INSERT INTO accounts (account_id, account_type_id, name, org_id, fields)
+        VALUES ($1, 's3', 'Test bucket', $2, $3)",
+        account_id.0,
+        app.org_id.0,
+        serde_json::json!({ "access_key_id": "key", "secret_access_key": "secret" })
+    )
+    .execute(&mut conn)
+    .await?;
+
+    Ok(account_id)
+}
+
+#[actix_rt::test]
+async fn file_watch_crud() {
+    run_app_test(|app| async move {
+        let client = &app.admin_user.client;
+        let account_id = add_s3_account(&app).await?;
+
+        let (inputs, actions) = bootstrap_inputs_and_actions(&app).await;
+        let (machine, states) = simple_state_machine();
+        let task = client
+            .new_task(&TaskInput {
+                name: "file task".to_string(),
+                alias: None,
+                description: None,
+                enabled: true,
+                disabled_input_mode: Default::default(),
+                compiled: machine,
+                source: serde_json::Value::Null,
+                state: Some(states),
+                state_reset: None,
+                tags: Vec::new(),
+                actions: simple_task_actions(&actions),
+                triggers: simple_task_triggers(&inputs),
+            })
+            .await?;
+
+        let mut input = FileWatchInput {
+            source: FileWatchSource::S3,
+            account_id: Some(account_id.clone()),
+            bucket: None,
+            path: "incoming/".to_string(),
+            parse: FileParse::Csv,
+            task_id: task.task_id.clone(),
+            trigger: "run_it".to_string(),
+            enabled: true,
+        };
+
+        let response = client.post("file_watches").json(&input).send().await?;
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "S3 watch without a bucket should be rejected"
+        );
+
+        input.bucket = Some("uploads".to_string());
+        let watch: FileWatch = client
+            .post("file_watches")
+            .json(&input)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(watch.bucket.as_deref(), Some("uploads"));
+        assert_eq!(watch.path, "incoming/");
+        assert_eq!(watch.parse, FileParse::Csv);
+        assert_eq!(watch.trigger, "run_it");
+
+        input.trigger = "prepare".to_string();
+        input.parse = FileParse::Json;
+        let updated: FileWatch = client
+            .put(format!("file_watches/{}", watch.file_watch_id))
+            .json(&input)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(updated.trigger, "prepare");
+        assert_eq!(updated.parse, FileParse::Json);
+
+        input.source = FileWatchSource::Directory;
+        input.path = "../outside".to_string();
+        let response = client
+            .put(format!("file_watches/{}", watch.file_watch_id))
+            .json(&input)
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "directory outside the watch root should be rejected"
+        );
+
+        let other_org = app.add_org("other org").await?;
+        let other_user = app.add_user(&other_org, "other user").await?;
+        let response = other_user
+            .client
+            .get(format!("file_watches/{}", watch.file_watch_id))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 404);
+
+        client
+            .delete(format!("file_watches/{}", watch.file_watch_id))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let watches: Vec<FileWatch> = client
+            .get("file_watches")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert!(watches.is_empty());
+
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,74 @@
+use ergo_api::routes::apply::{ApplyOperation, ObjectKind};
+use ergo_database::object_id::TaskId;
+
+use crate::common::run_app_test;
+
+#[actix_rt::test]
+async fn load_demo_fixture() {
+    run_app_test(|app| async move {
+        let result = app.load_fixture("demo").await?;
+        assert!(result
+            .changes
+            .iter()
+            .all(|c| c.operation == ApplyOperation::Create));
+        assert_eq!(result.logs, 5);
+
+        let mut tasks = app.admin_user.client.list_tasks().await?;
+        tasks.sort_by(|a, b| a.name.cmp(&b.name));
+        let names = tasks.iter().map(|t| t.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec!["Demo counter", "Demo dataflow", "Demo link checker"]
+        );
+
+        let logs = app.admin_user.client.get_recent_logs().await?;
+        assert_eq!(logs.len(), 5);
+
+        // The DataFlow trigger node should point at the task's trigger.
+        let dataflow_id: TaskId = result
+            .id(ObjectKind::Task, "Demo dataflow")
+            .expect("dataflow task id")
+            .parse()?;
+        let task = app.admin_user.client.get_task(&dataflow_id).await?;
+        let trigger_id = task.triggers.0["reading"].task_trigger_id.to_string();
+        let compiled = serde_json::to_value(&task.compiled.0)?;
+        assert_eq!(
+            compiled["data"]["nodes"][0]["func"]["task_trigger_id"],
+            trigger_id
+        );
+
+        // Loading the pack again changes nothing and doesn't add more history.
+        let result = app.load_fixture("demo").await?;
+        assert!(
+            result
+                .changes
+                .iter()
+                .all(|c| c.operation == ApplyOperation::Unchanged),
+            "reloading the fixture should not change anything: {:?}",
+            result.changes
+        );
+        assert_eq!(result.logs, 0);
+
+        let logs = app.admin_user.client.get_recent_logs().await?;
+        assert_eq!(logs.len(), 5);
+
+        Ok(())
+    })
+    .await
+}
+
+#[actix_rt::test]
+async fn unknown_fixture() {
+    run_app_test(|app| async move {
+        let response = app
+            .admin_user
+            .client
+            .post("fixtures/nonexistent")
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 404);
+
+        Ok(())
+    })
+    .await
+}
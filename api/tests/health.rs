@@ -0,0 +1,36 @@
+use crate::common::run_app_test;
+use serde_json::Value;
+
+#[actix_rt::test]
+async fn probes() {
+    run_app_test(|app| async move {
+        let response = app.client.get("healthz/live").send().await?;
+        assert_eq!(response.status().as_u16(), 200, "liveness status");
+        let body: Value = response.json().await?;
+        assert_eq!(body["ok"], true);
+
+        let response = app.client.get("healthz/ready").send().await?;
+        assert_eq!(response.status().as_u16(), 200, "readiness status");
+        let body: Value = response.json().await?;
+        assert_eq!(body["ok"], true);
+
+        let probes = body["probes"]
+            .as_array()
+            .expect("probes is an array")
+            .iter()
+            .map(|p| p["name"].as_str().unwrap_or_default())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            probes,
+            vec![
+                "postgres",
+                "redis",
+                "redis_circuit",
+                "input_dequeuer",
+                "action_dequeuer"
+            ]
+        );
+        Ok(())
+    })
+    .await
+}
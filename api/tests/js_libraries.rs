@@ -0,0 +1,157 @@
+use ergo_api::routes::{
+    js_libraries::{JsLibrary, JsLibraryInput, JsLibrarySummary},
+    tasks::TaskInput,
+};
+use ergo_tasks::{
+    scripting::{TaskJsConfig, TaskJsState},
+    TaskConfig, TaskState,
+};
+
+use crate::common::run_app_test;
+
+fn script_task(script: &str) -> TaskInput {
+    TaskInput {
+        name: "library task".to_string(),
+        alias: None,
+        description: None,
+        enabled: true,
+        disabled_input_mode: Default::default(),
+        compiled: TaskConfig::Js(TaskJsConfig {
+            map: String::new(),
+            script: script.to_string(),
+            timeout: None,
+            dependencies: Default::default(),
+            bundle: None,
+            libraries: Default::default(),
+        }),
+        source: serde_json::Value::Null,
+        state: Some(TaskState::Js(TaskJsState {
+            context: String::new(),
+        })),
+        state_reset: None,
+        tags: Vec::new(),
+        actions: Default::default(),
+        triggers: Default::default(),
+    }
+}
+
+#[actix_rt::test]
+async fn library_versions() {
+    run_app_test(|app| async move {
+        let client = &app.admin_user.client;
+
+        let response = client
+            .post("js_libraries/bad name")
+            .json(&JsLibraryInput {
+                description: None,
+                source: "export const a = 1;".to_string(),
+            })
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 400, "invalid name is rejected");
+
+        for greeting in ["hello", "hi"] {
+            client
+                .post("js_libraries/greet")
+                .json(&JsLibraryInput {
+                    description: Some("Greetings".to_string()),
+                    source: format!("export const greeting = '{}';", greeting),
+                })
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+
+        let latest: JsLibrary = client
+            .get("js_libraries/greet")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(latest.version, 2);
+        assert_eq!(latest.source, "export const greeting = 'hi';");
+
+        let first: JsLibrary = client
+            .get("js_libraries/greet/versions/1")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(first.source, "export const greeting = 'hello';");
+
+        let list: Vec<JsLibrarySummary> = client
+            .get("js_libraries")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].version, 2);
+
+        let other_org = app.add_org("other org").await?;
+        let other_user = app.add_user(&other_org, "other user").await?;
+        let response = other_user.client.get("js_libraries/greet").send().await?;
+        assert_eq!(response.status().as_u16(), 404);
+
+        Ok(())
+    })
+    .await
+}
+
+#[actix_rt::test]
+async fn task_imports_library() {
+    run_app_test(|app| async move {
+        let client = &app.admin_user.client;
+
+        for greeting in ["hello", "hi"] {
+            client
+                .post("js_libraries/greet")
+                .json(&JsLibraryInput {
+                    description: None,
+                    source: format!("export const greeting = '{}';", greeting),
+                })
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+
+        let response = client
+            .post("tasks")
+            .json(&script_task(r##"import { x } from "ergo:lib/missing";"##))
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "missing library is rejected"
+        );
+
+        let task = client
+            .new_task(&script_task(
+                r##"import { greeting } from "ergo:lib/greet@1";
+                import * as latest from "ergo:lib/greet";
+                Ergo.setContext({ greeting, latest: latest.greeting });"##,
+            ))
+            .await?;
+
+        let saved = client.get_task(&task.task_id).await?;
+        let libraries = match &saved.compiled.0 {
+            TaskConfig::Js(js) => js.libraries.clone(),
+            _ => panic!("Expected a JS task"),
+        };
+        assert_eq!(
+            libraries.get("ergo:lib/greet@1").map(|s| s.as_str()),
+            Some("export const greeting = 'hello';")
+        );
+        assert_eq!(
+            libraries.get("ergo:lib/greet").map(|s| s.as_str()),
+            Some("export const greeting = 'hi';")
+        );
+
+        Ok(())
+    })
+    .await
+}
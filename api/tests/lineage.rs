@@ -0,0 +1,161 @@
+use ergo_api::routes::{
+    logs::{ActionLineage, LineageAction},
+    tasks::TaskInput,
+};
+use ergo_database::object_id::TaskId;
+use ergo_tasks::{
+    actions::ActionSource,
+    scripting::{TaskJsConfig, TaskJsState},
+    TaskConfig, TaskState,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::common::{run_app_test, TestClient};
+
+async fn new_task(client: &TestClient, name: &str) -> anyhow::Result<TaskId> {
+    let task = client
+        .new_task(&TaskInput {
+            name: name.to_string(),
+            alias: None,
+            description: None,
+            enabled: true,
+            disabled_input_mode: Default::default(),
+            compiled: TaskConfig::Js(TaskJsConfig {
+                map: String::new(),
+                script: String::new(),
+                timeout: None,
+                dependencies: Default::default(),
+                bundle: None,
+                libraries: Default::default(),
+            }),
+            source: serde_json::Value::Null,
+            state: Some(TaskState::Js(TaskJsState {
+                context: String::new(),
+            })),
+            state_reset: None,
+            tags: Vec::new(),
+            actions: Default::default(),
+            triggers: Default::default(),
+        })
+        .await?;
+
+    Ok(task.task_id)
+}
+
+#[actix_rt::test]
+async fn lineage_across_chained_tasks() {
+    run_app_test(|app| async move {
+        let admin = &app.admin_user.client;
+        let first_task = new_task(admin, "first").await?;
+        let second_task = new_task(admin, "second").await?;
+
+        let first_input = Uuid::new_v4();
+        let second_input = Uuid::new_v4();
+        let send_action = Uuid::new_v4();
+        let email_action = Uuid::new_v4();
+
+        let send_source = ActionSource::StateMachine {
+            machine: 0,
+            trigger: "start".to_string(),
+            from_state: "idle".to_string(),
+            to_state: "running".to_string(),
+        };
+        let email_source = ActionSource::DataFlowNode {
+            node: "email".to_string(),
+        };
+
+        // Write the logs directly, as if the first task's action had sent an input to the
+        // second task.
+        let mut conn = app.database.pool.acquire().await?;
+        for (inputs_log_id, task_id, source_input, source_action) in [
+            (first_input, &first_task, None, None),
+            (
+                second_input,
+                &second_task,
+                Some(first_input),
+                Some(send_action),
+            ),
+        ] {
+            sqlx::query!(
+                "INSERT INTO inputs_log (inputs_log_id, task_id, task_trigger_local_id, status,
+                    queue_job_id, source_inputs_log_id, source_actions_log_id)
+                VALUES ($1, $2, 'trigger', 'success', '', $3, $4)",
+                inputs_log_id,
+                task_id.0,
+                source_input,
+                source_action
+            )
+            .execute(&mut conn)
+            .await?;
+        }
+
+        for (actions_log_id, task_id, inputs_log_id, local_id, source) in [
+            (send_action, &first_task, first_input, "send", &send_source),
+            (
+                email_action,
+                &second_task,
+                second_input,
+                "email",
+                &email_source,
+            ),
+        ] {
+            sqlx::query!(
+                "INSERT INTO actions_log (actions_log_id, task_id, inputs_log_id,
+                    task_action_local_id, status, source)
+                VALUES ($1, $2, $3, $4, 'success', $5)",
+                actions_log_id,
+                task_id.0,
+                inputs_log_id,
+                local_id,
+                json!(source)
+            )
+            .execute(&mut conn)
+            .await?;
+        }
+
+        let actions: Vec<LineageAction> = admin
+            .get(format!("inputs_log/{first_input}/lineage"))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let actions = actions
+            .iter()
+            .map(|a| (a.actions_log_id, a.depth))
+            .collect::<Vec<_>>();
+        assert_eq!(actions, vec![(send_action, 0), (email_action, 1)]);
+
+        let lineage: ActionLineage = admin
+            .get(format!("actions_log/{email_action}/lineage"))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(lineage.task_id, second_task);
+        assert_eq!(lineage.source, Some(email_source));
+        let inputs = lineage
+            .inputs
+            .iter()
+            .map(|i| (i.inputs_log_id, i.source_actions_log_id))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            inputs,
+            vec![(second_input, Some(send_action)), (first_input, None)]
+        );
+
+        let other_org = app.add_org("other org").await?;
+        let outsider = app.add_user(&other_org, "outsider").await?;
+        let response = outsider
+            .client
+            .get(format!("actions_log/{email_action}/lineage"))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 404);
+
+        Ok(())
+    })
+    .await
+}
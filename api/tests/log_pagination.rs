@@ -0,0 +1,126 @@
+use chrono::{Duration, Utc};
+use ergo_api::{
+    pagination::NEXT_CURSOR_HEADER,
+    routes::{logs::ActionsLogEntry, tasks::TaskInput},
+};
+use ergo_tasks::{
+    scripting::{TaskJsConfig, TaskJsState},
+    TaskConfig, TaskState,
+};
+use uuid::Uuid;
+
+use crate::common::run_app_test;
+
+fn simple_task() -> TaskInput {
+    TaskInput {
+        name: "paged task".to_string(),
+        alias: None,
+        description: None,
+        enabled: true,
+        disabled_input_mode: Default::default(),
+        compiled: TaskConfig::Js(TaskJsConfig {
+            map: String::new(),
+            script: "Ergo.setContext({});".to_string(),
+            timeout: None,
+            dependencies: Default::default(),
+            bundle: None,
+            libraries: Default::default(),
+        }),
+        source: serde_json::Value::Null,
+        state: Some(TaskState::Js(TaskJsState {
+            context: String::new(),
+        })),
+        state_reset: None,
+        tags: Vec::new(),
+        actions: Default::default(),
+        triggers: Default::default(),
+    }
+}
+
+#[actix_rt::test]
+async fn page_through_actions_log() {
+    run_app_test(|app| async move {
+        let admin = &app.admin_user.client;
+        let task = admin.new_task(&simple_task()).await?;
+        let task_id = task.task_id.to_string();
+
+        let now = Utc::now();
+        let mut conn = app.database.pool.acquire().await?;
+        let mut ids = Vec::new();
+        for i in 0..5 {
+            let id = Uuid::new_v4();
+            let status = if i == 2 { "error" } else { "success" };
+            sqlx::query(
+                "INSERT INTO actions_log (actions_log_id, task_id, task_action_local_id, status,
+                    created, updated)
+                VALUES ($1, $2, 'action', $3::action_status, $4, $4)",
+            )
+            .bind(id)
+            .bind(task.task_id.0)
+            .bind(status)
+            .bind(now - Duration::minutes(i))
+            .execute(&mut conn)
+            .await?;
+            ids.push(id);
+        }
+
+        // Newest first, two at a time.
+        let mut seen = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut request = admin
+                .get("actions_log")
+                .query(&[("limit", "2"), ("task_id", task_id.as_str())]);
+            if let Some(cursor) = &cursor {
+                request = request.query(&[("cursor", cursor.as_str())]);
+            }
+            let response = request.send().await?.error_for_status()?;
+            cursor = response
+                .headers()
+                .get(NEXT_CURSOR_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from);
+            let page: Vec<ActionsLogEntry> = response.json().await?;
+            assert!(page.len() <= 2);
+            seen.extend(page.into_iter().map(|e| e.actions_log_id));
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(seen, ids, "every entry appears once, in order");
+
+        let errors: Vec<ActionsLogEntry> = admin
+            .get("actions_log")
+            .query(&[
+                ("status", "error"),
+                ("order", "asc"),
+                ("task_id", task_id.as_str()),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(
+            errors.iter().map(|e| e.actions_log_id).collect::<Vec<_>>(),
+            vec![ids[2]]
+        );
+
+        let response = admin
+            .get("actions_log")
+            .query(&[("status", "finished")])
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 400, "unknown status");
+
+        let response = admin
+            .get("actions_log")
+            .query(&[("cursor", "garbage")])
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 400, "invalid cursor");
+
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,200 @@
+use chrono::{Duration, Utc};
+use ergo_api::routes::tasks::TaskInput;
+use ergo_problem::{Problem, PROBLEM_CONTENT_TYPE};
+use ergo_tasks::{
+    log_retention::{apply_retention, ArchiveStore, LogArchive, RetentionPolicy},
+    scripting::{TaskJsConfig, TaskJsState},
+    TaskConfig, TaskState,
+};
+use uuid::Uuid;
+
+use crate::common::run_app_test;
+
+fn simple_task() -> TaskInput {
+    TaskInput {
+        name: "logged task".to_string(),
+        alias: None,
+        description: None,
+        enabled: true,
+        disabled_input_mode: Default::default(),
+        compiled: TaskConfig::Js(TaskJsConfig {
+            map: String::new(),
+            script: "Ergo.setContext({});".to_string(),
+            timeout: None,
+            dependencies: Default::default(),
+            bundle: None,
+            libraries: Default::default(),
+        }),
+        source: serde_json::Value::Null,
+        state: Some(TaskState::Js(TaskJsState {
+            context: String::new(),
+        })),
+        state_reset: None,
+        tags: Vec::new(),
+        actions: Default::default(),
+        triggers: Default::default(),
+    }
+}
+
+#[actix_rt::test]
+async fn retention_policy() {
+    run_app_test(|app| async move {
+        let admin = &app.admin_user.client;
+
+        let policy: RetentionPolicy = admin
+            .get("org/log_retention")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(policy.keep_days, None, "logs are kept forever by default");
+
+        let new_policy = RetentionPolicy {
+            keep_days: Some(30),
+            archive: true,
+        };
+        admin
+            .put("org/log_retention")
+            .json(&new_policy)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let policy: RetentionPolicy = admin
+            .get("org/log_retention")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(policy, new_policy);
+
+        let response = admin
+            .put("org/log_retention")
+            .json(&RetentionPolicy {
+                keep_days: Some(0),
+                archive: false,
+            })
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "keep_days must be positive"
+        );
+        assert_eq!(
+            response
+                .headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok()),
+            Some(PROBLEM_CONTENT_TYPE)
+        );
+        let problem: Problem = response.json().await?;
+        assert_eq!(problem.status, 400);
+        assert_eq!(problem.code, "validation_failed");
+        assert_eq!(problem.errors.len(), 1);
+
+        let user = app
+            .add_user(&app.admin_user.org_id, "retention user")
+            .await?;
+        let response = user
+            .client
+            .put("org/log_retention")
+            .json(&RetentionPolicy {
+                keep_days: None,
+                archive: false,
+            })
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            403,
+            "non-admin can not change the policy"
+        );
+        let problem: Problem = response.json().await?;
+        assert_eq!(problem.code, "forbidden");
+
+        let response = user.client.post("org/log_retention/run").send().await?;
+        assert_eq!(
+            response.status().as_u16(),
+            403,
+            "non-admin can not start a retention run"
+        );
+
+        let response = admin.post("org/log_retention/run").send().await?;
+        assert_eq!(response.status().as_u16(), 202);
+
+        Ok(())
+    })
+    .await
+}
+
+#[actix_rt::test]
+async fn archive_old_entries() {
+    run_app_test(|app| async move {
+        let admin = &app.admin_user.client;
+        let task = admin.new_task(&simple_task()).await?;
+
+        admin
+            .put("org/log_retention")
+            .json(&RetentionPolicy {
+                keep_days: Some(30),
+                archive: true,
+            })
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let now = Utc::now();
+        let old_id = Uuid::new_v4();
+        let recent_id = Uuid::new_v4();
+        let mut conn = app.database.pool.acquire().await?;
+        for (id, updated) in [
+            (old_id, now - Duration::days(60)),
+            (recent_id, now - Duration::days(1)),
+        ] {
+            sqlx::query!(
+                "INSERT INTO inputs_log (inputs_log_id, task_id, task_trigger_local_id, status,
+                    payload, created, updated)
+                VALUES ($1, $2, 'trigger', 'success', '{}'::jsonb, $3, $3)",
+                id,
+                task.task_id.0,
+                updated
+            )
+            .execute(&mut conn)
+            .await?;
+        }
+
+        let dir = std::env::temp_dir().join(format!("ergo-log-archive-{}", Uuid::new_v4()));
+        let store = ArchiveStore::Directory(dir.clone());
+        let summary =
+            apply_retention(&app.database.pool, Some(&store), Some(&app.org_id), now).await?;
+        assert_eq!(summary.removed, 1);
+        assert_eq!(summary.archived, 1);
+
+        let remaining = sqlx::query_scalar!(
+            "SELECT inputs_log_id FROM inputs_log WHERE task_id=$1",
+            task.task_id.0
+        )
+        .fetch_all(&mut conn)
+        .await?;
+        assert_eq!(remaining, vec![recent_id]);
+
+        let archives: Vec<LogArchive> = admin
+            .get("org/log_retention/archives")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(archives.len(), 1);
+        assert_eq!(archives[0].log_table, "inputs_log");
+        assert_eq!(archives[0].entries, 1);
+        assert!(archives[0].location.starts_with("file://"));
+
+        std::fs::remove_dir_all(&dir).ok();
+        Ok(())
+    })
+    .await
+}
@@ -1,4 +1,30 @@
+mod accounts;
+mod action_retry;
+mod apply;
+mod approvals;
 mod auth;
+mod chat;
 mod common;
+mod egress;
+mod email;
+mod feature_flags;
+mod file_watches;
+mod fixtures;
+mod health;
+mod js_libraries;
+mod lineage;
+mod log_pagination;
+mod log_retention;
+mod maintenance_mode;
+mod mqtt;
+mod notify_templates;
+mod oidc;
+mod openapi;
+mod orgs;
+mod permissions;
+mod quotas;
+mod sensitive_payloads;
 mod smoke_test;
 mod tasks;
+mod trigger_tokens;
+mod users;
@@ -0,0 +1,100 @@
+use ergo_api::{maintenance_mode::MaintenanceStatus, routes::status::MaintenanceInput};
+use ergo_tasks::feature_flags::FeatureFlagInput;
+use serde_json::Value;
+
+use crate::common::run_app_test;
+
+#[actix_rt::test]
+async fn maintenance_mode() {
+    run_app_test(|app| async move {
+        let admin = &app.admin_user.client;
+        let user = app
+            .add_user(&app.admin_user.org_id, "maintenance user")
+            .await?;
+        let flag = FeatureFlagInput {
+            enabled: true,
+            description: None,
+        };
+
+        let response = user
+            .client
+            .put("status/maintenance")
+            .json(&MaintenanceInput {
+                enabled: true,
+                message: None,
+            })
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            403,
+            "non-admin can not turn on maintenance mode"
+        );
+
+        let status: MaintenanceStatus = admin
+            .put("status/maintenance")
+            .json(&MaintenanceInput {
+                enabled: true,
+                message: Some("Upgrading the database".to_string()),
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert!(status.enabled);
+        assert!(status.since.is_some());
+
+        let status: MaintenanceStatus = app
+            .client
+            .get("status/maintenance")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert!(status.enabled, "status is readable without logging in");
+
+        let response = admin
+            .put("org/feature_flags/during_maintenance")
+            .json(&flag)
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 503, "writes are rejected");
+        let body: Value = response.json().await?;
+        assert_eq!(body["code"], "maintenance_mode");
+        assert_eq!(body["detail"], "Upgrading the database");
+
+        admin
+            .get("org/feature_flags")
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let response = app.client.get("healthz/ready").send().await?;
+        assert_eq!(response.status().as_u16(), 200, "readiness status");
+
+        let status: MaintenanceStatus = admin
+            .put("status/maintenance")
+            .json(&MaintenanceInput {
+                enabled: false,
+                message: None,
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert!(!status.enabled);
+
+        admin
+            .put("org/feature_flags/during_maintenance")
+            .json(&flag)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,145 @@
+use ergo_api::routes::{
+    mqtt::{MqttSubscription, MqttSubscriptionInput},
+    tasks::TaskInput,
+};
+use ergo_database::object_id::AccountId;
+
+use crate::{
+    common::{run_app_test, TestApp},
+    tasks::{
+        bootstrap_inputs_and_actions, simple_state_machine, simple_task_actions,
+        simple_task_triggers,
+    },
+};
+
+async fn add_mqtt_account(app: &TestApp) -> anyhow::Result<AccountId> {
+    let mut conn = app.database.pool.acquire().await?;
+    sqlx::query!(
+        "INSERT INTO account_types (account_type_id, name, fields)
+        VALUES ('mqtt', 'MQTT Broker', ARRAY['host', 'port', 'username', 'password', 'tls'])
+        ON CONFLICT DO NOTHING"
+    )
+    .execute(&mut conn)
+    .await?;
+
+    let account_id = AccountId::new();
+    sqlx::query!(
+        "INSERT INTO accounts (account_id, account_type_id, name, org_id, fields)
+        VALUES ($1, 'mqtt', 'Test broker', $2, $3)",
+        account_id.0,
+        app.org_id.0,
+        serde_json::json!({ "host": "localhost" })
+    )
+    .execute(&mut conn)
+    .await?;
+
+    Ok(account_id)
+}
+
+#[actix_rt::test]
+async fn subscription_crud() {
+    run_app_test(|app| async move {
+        let client = &app.admin_user.client;
+        let account_id = add_mqtt_account(&app).await?;
+
+        let (inputs, actions) = bootstrap_inputs_and_actions(&app).await;
+        let (machine, states) = simple_state_machine();
+        let task = client
+            .new_task(&TaskInput {
+                name: "mqtt task".to_string(),
+                alias: None,
+                description: None,
+                enabled: true,
+                disabled_input_mode: Default::default(),
+                compiled: machine,
+                source: serde_json::Value::Null,
+                state: Some(states),
+                state_reset: None,
+                tags: Vec::new(),
+                actions: simple_task_actions(&actions),
+                triggers: simple_task_triggers(&inputs),
+            })
+            .await?;
+
+        let mut input = MqttSubscriptionInput {
+            account_id: account_id.clone(),
+            topic_filter: "home/#/temp".to_string(),
+            qos: 1,
+            task_id: task.task_id.clone(),
+            trigger: "run_it".to_string(),
+            enabled: true,
+        };
+
+        let response = client
+            .post("mqtt_subscriptions")
+            .json(&input)
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "invalid topic filter should be rejected"
+        );
+
+        input.topic_filter = "home/+/temp".to_string();
+        let subscription: MqttSubscription = client
+            .post("mqtt_subscriptions")
+            .json(&input)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(subscription.topic_filter, "home/+/temp");
+        assert_eq!(subscription.trigger, "run_it");
+
+        input.trigger = "prepare".to_string();
+        input.qos = 2;
+        let updated: MqttSubscription = client
+            .put(format!(
+                "mqtt_subscriptions/{}",
+                subscription.mqtt_subscription_id
+            ))
+            .json(&input)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(updated.trigger, "prepare");
+        assert_eq!(updated.qos, 2);
+
+        let other_org = app.add_org("other org").await?;
+        let other_user = app.add_user(&other_org, "other user").await?;
+        let response = other_user
+            .client
+            .get(format!(
+                "mqtt_subscriptions/{}",
+                subscription.mqtt_subscription_id
+            ))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 404);
+
+        client
+            .delete(format!(
+                "mqtt_subscriptions/{}",
+                subscription.mqtt_subscription_id
+            ))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let subscriptions: Vec<MqttSubscription> = client
+            .get("mqtt_subscriptions")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert!(subscriptions.is_empty());
+
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,92 @@
+use ergo_api::routes::notify_templates::NotifyTemplateInput;
+use ergo_notifications::{templates::NotifyTemplate, NotifyEvent, NotifyService};
+use ergo_problem::Problem;
+
+use crate::common::run_app_test;
+
+#[actix_rt::test]
+async fn notify_templates() {
+    run_app_test(|app| async move {
+        let admin = &app.admin_user.client;
+
+        let templates: Vec<NotifyTemplate> = admin
+            .get("org/notify_templates")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert!(templates.is_empty(), "no templates by default");
+
+        for template in ["{{task_name}} failed", "{{task_name}}: {{error}}"] {
+            admin
+                .put("org/notify_templates/action_error/discord_incoming_webhook")
+                .json(&NotifyTemplateInput {
+                    template: template.to_string(),
+                })
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+
+        let templates: Vec<NotifyTemplate> = admin
+            .get("org/notify_templates")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(templates.len(), 1, "setting a template again replaces it");
+        assert_eq!(templates[0].event, NotifyEvent::ActionError);
+        assert_eq!(templates[0].service, NotifyService::DiscordIncomingWebhook);
+        assert_eq!(templates[0].template, "{{task_name}}: {{error}}");
+
+        let response = admin
+            .put("org/notify_templates/action_error/slack_incoming_webhook")
+            .json(&NotifyTemplateInput {
+                template: "{{#if error}}unclosed".to_string(),
+            })
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 400, "invalid template");
+        let problem: Problem = response.json().await?;
+        assert_eq!(problem.errors[0].field.as_deref(), Some("template"));
+
+        let response = admin
+            .put("org/notify_templates/not_an_event/slack_incoming_webhook")
+            .json(&NotifyTemplateInput {
+                template: "{{task_name}}".to_string(),
+            })
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 404, "unknown event");
+
+        let user = app
+            .add_user(&app.admin_user.org_id, "template user")
+            .await?;
+        let response = user
+            .client
+            .delete("org/notify_templates/action_error/discord_incoming_webhook")
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            403,
+            "non-admin can not remove templates"
+        );
+
+        admin
+            .delete("org/notify_templates/action_error/discord_incoming_webhook")
+            .send()
+            .await?
+            .error_for_status()?;
+        let response = admin
+            .delete("org/notify_templates/action_error/discord_incoming_webhook")
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 404);
+
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,248 @@
+use ergo_api::routes::{
+    oidc::OidcProviderInput,
+    permissions::{Role, RoleInput},
+};
+use ergo_auth::oidc::{code_challenge, ClaimRule};
+use fxhash::FxHashMap;
+use reqwest::{header, redirect::Policy, Url};
+use serde_json::json;
+use wiremock::{
+    matchers::{body_string_contains, method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+use crate::common::run_app_test;
+
+/// The cookies that a response sets, in the format of a `Cookie` header.
+fn response_cookies(response: &reqwest::Response) -> String {
+    response
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .filter_map(|v| v.split(';').next())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+fn id_token(claims: serde_json::Value) -> String {
+    let encode = |v: &[u8]| base64::encode_config(v, base64::URL_SAFE_NO_PAD);
+    format!(
+        "{}.{}.{}",
+        encode(br#"{"alg":"RS256"}"#),
+        encode(claims.to_string().as_bytes()),
+        encode(b"signature")
+    )
+}
+
+#[actix_rt::test]
+async fn oidc_login() {
+    run_app_test(|app| async move {
+        let admin = &app.admin_user.client;
+        let provider = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/.well-known/openid-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "issuer": provider.uri(),
+                "authorization_endpoint": format!("{}/authorize", provider.uri()),
+                "token_endpoint": format!("{}/token", provider.uri()),
+            })))
+            .mount(&provider)
+            .await;
+
+        let role: Role = admin
+            .post("roles")
+            .json(&RoleInput {
+                name: "engineers".to_string(),
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let response = admin
+            .put("oidc/providers/test_idp")
+            .json(&OidcProviderInput {
+                name: "Test IdP".to_string(),
+                issuer: provider.uri(),
+                client_id: "ergo".to_string(),
+                client_secret: Some("the secret".to_string()),
+                scopes: vec!["email".to_string(), "profile".to_string()],
+                claim_rules: vec![ClaimRule {
+                    claim: "groups".to_string(),
+                    value: "eng".to_string(),
+                    org_id: app.admin_user.org_id.clone(),
+                    role_id: Some(role.role_id.clone()),
+                }],
+            })
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 200, "creating provider");
+
+        let client = reqwest::Client::builder()
+            .redirect(Policy::none())
+            .build()?;
+
+        let response = client
+            .get(format!("{}/oidc/test_idp/login", app.base_url))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 302, "login redirect");
+        let cookies = response_cookies(&response);
+        let location = Url::parse(response.headers()[header::LOCATION].to_str()?)?;
+        assert_eq!(location.path(), "/authorize");
+        let params = location
+            .query_pairs()
+            .into_owned()
+            .collect::<FxHashMap<String, String>>();
+        assert_eq!(params["client_id"], "ergo");
+        assert_eq!(params["scope"], "openid email profile");
+        assert_eq!(params["code_challenge_method"], "S256");
+
+        let response = client
+            .get(format!(
+                "{}/oidc/test_idp/callback?code=the_code&state=wrong",
+                app.base_url
+            ))
+            .header(header::COOKIE, cookies)
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 401, "callback with wrong state");
+
+        let response = client
+            .get(format!(
+                "{}/oidc/test_idp/login?redirect_to=https://evil.example.com",
+                app.base_url
+            ))
+            .send()
+            .await?;
+        let cookies = response_cookies(&response);
+        let location = Url::parse(response.headers()[header::LOCATION].to_str()?)?;
+        let params = location
+            .query_pairs()
+            .into_owned()
+            .collect::<FxHashMap<String, String>>();
+        let state = params["state"].clone();
+
+        let token = id_token(json!({
+            "iss": provider.uri(),
+            "aud": "ergo",
+            "sub": "idp-user-1",
+            "exp": chrono::Utc::now().timestamp() + 300,
+            "nonce": params["nonce"],
+            "email": "sso_user@example.com",
+            "email_verified": true,
+            "name": "SSO User",
+            "groups": ["eng", "everyone"],
+        }));
+
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .and(body_string_contains("code=the_code"))
+            .and(body_string_contains("client_secret=the+secret"))
+            .respond_with(move |req: &wiremock::Request| {
+                let body = String::from_utf8_lossy(&req.body).to_string();
+                let verifier = body
+                    .split('&')
+                    .find_map(|p| p.strip_prefix("code_verifier="))
+                    .unwrap_or_default();
+                if code_challenge(verifier) == params["code_challenge"] {
+                    ResponseTemplate::new(200).set_body_json(json!({ "id_token": token }))
+                } else {
+                    ResponseTemplate::new(400)
+                }
+            })
+            .mount(&provider)
+            .await;
+
+        let response = client
+            .get(format!(
+                "{}/oidc/test_idp/callback?code=the_code&state={}",
+                app.base_url, state
+            ))
+            .header(header::COOKIE, cookies)
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 302, "callback");
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "/",
+            "redirects outside the app are ignored"
+        );
+        let session_cookies = response_cookies(&response);
+
+        let response = client
+            .get(format!("{}/sessions", app.base_url))
+            .header(header::COOKIE, session_cookies)
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            200,
+            "new session is authenticated"
+        );
+
+        let mut conn = app.database.pool.acquire().await?;
+        let user = sqlx::query!(
+            r##"SELECT users.user_id, active_org_id, name,
+                array_agg(role_id) FILTER(WHERE oidc_provider_id = 'test_idp') AS roles
+            FROM users
+            LEFT JOIN user_roles USING (user_id)
+            WHERE email = 'sso_user@example.com'
+            GROUP BY users.user_id"##
+        )
+        .fetch_one(&mut conn)
+        .await?;
+        assert_eq!(user.name, "SSO User");
+        assert_eq!(user.active_org_id, app.admin_user.org_id.0);
+        assert_eq!(user.roles, Some(vec![role.role_id.0]));
+
+        Ok(())
+    })
+    .await
+}
+
+#[actix_rt::test]
+async fn oidc_provider_rules_must_reference_org_roles() {
+    run_app_test(|app| async move {
+        let other_org = app.add_org("other org").await?;
+        let role: Role = app
+            .admin_user
+            .client
+            .post("roles")
+            .json(&RoleInput {
+                name: "engineers".to_string(),
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let response = app
+            .admin_user
+            .client
+            .put("oidc/providers/test_idp")
+            .json(&OidcProviderInput {
+                name: "Test IdP".to_string(),
+                issuer: "https://idp.example.com".to_string(),
+                client_id: "ergo".to_string(),
+                client_secret: None,
+                scopes: Vec::new(),
+                claim_rules: vec![ClaimRule {
+                    claim: "groups".to_string(),
+                    value: "eng".to_string(),
+                    org_id: other_org,
+                    role_id: Some(role.role_id),
+                }],
+            })
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 400);
+
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,45 @@
+use crate::common::run_app_test;
+use serde_json::Value;
+
+#[actix_rt::test]
+async fn serves_document() {
+    run_app_test(|app| async move {
+        let doc: Value = app
+            .client
+            .get("openapi.json")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        assert_eq!(doc["openapi"], "3.0.3");
+
+        let task = &doc["paths"]["/api/tasks/{task_id}"];
+        assert!(task["put"]["requestBody"].is_object(), "update has a body");
+        assert_eq!(task["get"]["parameters"][0]["name"], "task_id");
+
+        let list = &doc["paths"]["/api/tasks"]["get"];
+        let params = list["parameters"]
+            .as_array()
+            .expect("parameters is an array")
+            .iter()
+            .map(|p| p["name"].as_str().unwrap_or_default())
+            .collect::<Vec<_>>();
+        assert!(params.contains(&"tags"), "query parameters {:?}", params);
+
+        // Every referenced schema should be in the components.
+        let response_ref = list["responses"]["200"]["content"]["application/json"]["schema"]
+            ["items"]["$ref"]
+            .as_str()
+            .expect("list response refers to a schema");
+        let name = response_ref.trim_start_matches("#/components/schemas/");
+        assert!(doc["components"]["schemas"][name].is_object());
+
+        let docs = app.client.get("docs").send().await?.error_for_status()?;
+        assert!(docs.text().await?.contains("openapi.json"));
+
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,147 @@
+use ergo_api::routes::{
+    orgs::{Invitation, InvitationInput, OrgMember, OrgMembership, SwitchOrgInput},
+    permissions::{Role, RoleInput},
+};
+
+use crate::common::run_app_test;
+
+#[actix_rt::test]
+async fn invite_switch_and_remove() {
+    run_app_test(|app| async move {
+        let admin = &app.admin_user.client;
+        let other_org = app.add_org("other org").await?;
+        let user = app.add_user(&other_org, "invitee").await?;
+        let email = format!("test_user_{}@example.com", user.user_id);
+
+        let role: Role = admin
+            .post("roles")
+            .json(&RoleInput {
+                name: "engineers".to_string(),
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let invitation: Invitation = admin
+            .post("orgs/invitations")
+            .json(&InvitationInput {
+                email: email.clone(),
+                role_ids: vec![role.role_id.clone()],
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(invitation.org_id, app.admin_user.org_id);
+
+        let response = user
+            .client
+            .post("orgs/invitations")
+            .json(&InvitationInput {
+                email: "someone@example.com".to_string(),
+                role_ids: Vec::new(),
+            })
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 403, "only admins can invite");
+
+        let invitations: Vec<Invitation> = user
+            .client
+            .get("invitations")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(invitations.len(), 1);
+        assert_eq!(invitations[0].invitation_id, invitation.invitation_id);
+
+        let response = user
+            .client
+            .put("orgs/active")
+            .json(&SwitchOrgInput {
+                org_id: app.admin_user.org_id.clone(),
+            })
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            404,
+            "can't switch to an org before joining"
+        );
+
+        user.client
+            .post(format!("invitations/{}/accept", invitation.invitation_id))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        user.client
+            .put("orgs/active")
+            .json(&SwitchOrgInput {
+                org_id: app.admin_user.org_id.clone(),
+            })
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let orgs: Vec<OrgMembership> = user
+            .client
+            .get("orgs")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(orgs.len(), 2);
+        let active = orgs.iter().find(|o| o.active).expect("an active org");
+        assert_eq!(active.org_id, app.admin_user.org_id);
+
+        let members: Vec<OrgMember> = admin
+            .get("orgs/members")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let member = members
+            .iter()
+            .find(|m| m.user_id == user.user_id)
+            .expect("invitee is a member");
+        assert_eq!(member.role_ids, vec![role.role_id.clone()]);
+
+        admin
+            .delete(format!("orgs/members/{}", user.user_id))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let orgs: Vec<OrgMembership> = user
+            .client
+            .get("orgs")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(orgs.len(), 1);
+        assert_eq!(orgs[0].org_id, other_org);
+        assert!(
+            orgs[0].active,
+            "removed user is moved back to their other org"
+        );
+
+        let response = user
+            .client
+            .delete(format!("orgs/members/{}", user.user_id))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 400, "can't leave the only org");
+
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,203 @@
+use ergo_api::routes::{
+    permissions::{EffectivePermissions, Grant, GrantObjectType, Role, RoleInput},
+    tasks::TaskInput,
+};
+use ergo_auth::PermissionType;
+use ergo_tasks::{
+    scripting::{TaskJsConfig, TaskJsState},
+    TaskConfig, TaskState,
+};
+
+use crate::common::run_app_test;
+
+fn simple_task() -> TaskInput {
+    TaskInput {
+        name: "shared task".to_string(),
+        alias: None,
+        description: None,
+        enabled: true,
+        disabled_input_mode: Default::default(),
+        compiled: TaskConfig::Js(TaskJsConfig {
+            map: String::new(),
+            script: "Ergo.setContext({});".to_string(),
+            timeout: None,
+            dependencies: Default::default(),
+            bundle: None,
+            libraries: Default::default(),
+        }),
+        source: serde_json::Value::Null,
+        state: Some(TaskState::Js(TaskJsState {
+            context: String::new(),
+        })),
+        state_reset: None,
+        tags: Vec::new(),
+        actions: Default::default(),
+        triggers: Default::default(),
+    }
+}
+
+#[actix_rt::test]
+async fn role_grants() {
+    run_app_test(|app| async move {
+        let admin = &app.admin_user.client;
+        let user = app.add_user(&app.admin_user.org_id, "role user").await?;
+
+        let task = admin.new_task(&simple_task()).await?;
+        let task_url = format!("tasks/{}", task.task_id);
+
+        let response = user.client.get(&task_url).send().await?;
+        assert_eq!(response.status().as_u16(), 404, "no access before grant");
+
+        let response = user
+            .client
+            .post("roles")
+            .json(&RoleInput {
+                name: "viewers".to_string(),
+            })
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            403,
+            "non-admin can not create roles"
+        );
+
+        let role: Role = admin
+            .post("roles")
+            .json(&RoleInput {
+                name: "viewers".to_string(),
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        admin
+            .put(format!("roles/{}/users/{}", role.role_id, user.user_id))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let response = admin
+            .post(format!("roles/{}/grants", role.role_id))
+            .json(&Grant {
+                permission_type: PermissionType::Read,
+                object_id: Some(task.task_id.0),
+            })
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 201);
+
+        let grants: Vec<Grant> = admin
+            .get(format!("roles/{}/grants", role.role_id))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(
+            grants,
+            vec![Grant {
+                permission_type: PermissionType::Read,
+                object_id: Some(task.task_id.0),
+            }]
+        );
+
+        user.client
+            .get(&task_url)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let response = user.client.delete(&task_url).send().await?;
+        assert_eq!(
+            response.status().as_u16(),
+            404,
+            "read grant does not allow writes"
+        );
+
+        let effective: EffectivePermissions = user
+            .client
+            .get(format!(
+                "permissions/effective?object_id={}",
+                task.task_id.0
+            ))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(effective.object_type, GrantObjectType::Task);
+        assert_eq!(effective.permissions, vec![PermissionType::Read]);
+        assert_eq!(effective.grants.len(), 1);
+        assert_eq!(effective.grants[0].role_id.as_ref(), Some(&role.role_id));
+
+        admin
+            .delete(format!(
+                "roles/{}/grants/read/{}",
+                role.role_id, task.task_id.0
+            ))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let response = user.client.get(&task_url).send().await?;
+        assert_eq!(response.status().as_u16(), 404, "no access after revoke");
+
+        Ok(())
+    })
+    .await
+}
+
+#[actix_rt::test]
+async fn grants_are_scoped_to_org() {
+    run_app_test(|app| async move {
+        let admin = &app.admin_user.client;
+        let other_org = app.add_org("other org").await?;
+        let other_user = app.add_user(&other_org, "other user").await?;
+
+        let task = other_user.client.new_task(&simple_task()).await?;
+
+        let role: Role = admin
+            .post("roles")
+            .json(&RoleInput {
+                name: "editors".to_string(),
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let response = admin
+            .post(format!("roles/{}/grants", role.role_id))
+            .json(&Grant {
+                permission_type: PermissionType::Write,
+                object_id: Some(task.task_id.0),
+            })
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            404,
+            "can not grant access to another org's task"
+        );
+
+        let response = admin
+            .put(format!(
+                "roles/{}/users/{}",
+                role.role_id, other_user.user_id
+            ))
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            404,
+            "can not add a user from another org"
+        );
+
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,214 @@
+use crate::{
+    common::run_app_test,
+    tasks::{
+        bootstrap_inputs_and_actions, simple_state_machine, simple_task_actions,
+        simple_task_triggers,
+    },
+};
+use chrono::NaiveDate;
+use ergo_api::routes::tasks::TaskInput;
+use ergo_tasks::{
+    costs::UsageReport,
+    quotas::{OrgQuotas, QuotaReport},
+};
+
+#[actix_rt::test]
+async fn task_count_quota() {
+    run_app_test(|app| async move {
+        let org_id = app.add_org("quota org").await?;
+        let user = app.add_user(&org_id, "User 1").await?;
+        let quotas = OrgQuotas {
+            max_tasks: Some(1),
+            ..Default::default()
+        };
+
+        let response = user
+            .client
+            .put(format!("orgs/{}/quotas", org_id))
+            .json(&quotas)
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            403,
+            "non-admin user should not be able to set quotas"
+        );
+
+        app.admin_user
+            .client
+            .put(format!("orgs/{}/quotas", org_id))
+            .json(&quotas)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let (inputs, actions) = bootstrap_inputs_and_actions(&app).await;
+        let (machine, states) = simple_state_machine();
+        let task = TaskInput {
+            name: "task 1".to_string(),
+            alias: None,
+            description: None,
+            enabled: true,
+            disabled_input_mode: Default::default(),
+            compiled: machine,
+            source: serde_json::Value::Null,
+            state: Some(states),
+            state_reset: None,
+            tags: Vec::new(),
+            actions: simple_task_actions(&actions),
+            triggers: simple_task_triggers(&inputs),
+        };
+
+        user.client.new_task(&task).await?;
+
+        let response = user.client.post("tasks").json(&task).send().await?;
+        assert_eq!(
+            response.status().as_u16(),
+            403,
+            "creating a task over the quota should fail"
+        );
+
+        let report: QuotaReport = user
+            .client
+            .get("org/quotas")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(report.quotas, quotas);
+        assert_eq!(report.usage.tasks, 1);
+
+        Ok(())
+    })
+    .await
+}
+
+#[actix_rt::test]
+async fn usage_report() {
+    run_app_test(|app| async move {
+        let (inputs, actions) = bootstrap_inputs_and_actions(&app).await;
+        let (machine, states) = simple_state_machine();
+        let mut task = TaskInput {
+            name: "cheap task".to_string(),
+            alias: None,
+            description: None,
+            enabled: true,
+            disabled_input_mode: Default::default(),
+            compiled: machine,
+            source: serde_json::Value::Null,
+            state: Some(states),
+            state_reset: None,
+            tags: Vec::new(),
+            actions: simple_task_actions(&actions),
+            triggers: simple_task_triggers(&inputs),
+        };
+
+        let admin = &app.admin_user.client;
+        let cheap_task = admin.new_task(&task).await?.task_id;
+        task.name = "expensive task".to_string();
+        let expensive_task = admin.new_task(&task).await?.task_id;
+
+        let mut conn = app.database.pool.acquire().await?;
+        let day = NaiveDate::from_ymd(2023, 2, 20);
+        for (task_id, day, runs, js_cpu_ms, action_ms) in [
+            (&cheap_task, day, 3, 20, 100),
+            (&expensive_task, day, 1, 500, 2000),
+            (&expensive_task, day.succ(), 2, 300, 0),
+        ] {
+            sqlx::query!(
+                "INSERT INTO task_usage (task_id, day, org_id, runs, js_cpu_ms, actions, action_ms)
+                VALUES ($1, $2, $3, $4, $5, $4, $6)",
+                task_id.0,
+                day,
+                app.org_id.0,
+                runs,
+                js_cpu_ms,
+                action_ms
+            )
+            .execute(&mut conn)
+            .await?;
+        }
+
+        let query = [("since", "2023-02-01"), ("until", "2023-02-28")];
+        let report: UsageReport = admin
+            .get("org/usage")
+            .query(&query)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        assert_eq!(report.days.len(), 2);
+        assert_eq!(report.days[0].day, day);
+        assert_eq!(report.days[0].usage.runs, 4);
+        assert_eq!(report.days[0].usage.js_cpu_ms, 520);
+        assert_eq!(report.days[0].usage.action_ms, 2100);
+        assert_eq!(report.days[1].usage.runs, 2);
+
+        let task_names = report
+            .tasks
+            .iter()
+            .map(|t| (t.day, t.task_name.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            task_names,
+            vec![
+                (day, "expensive task"),
+                (day, "cheap task"),
+                (day.succ(), "expensive task")
+            ],
+            "tasks are sorted by cost within each day"
+        );
+
+        let report: UsageReport = admin
+            .get("org/usage")
+            .query(&query)
+            .query(&[("task_id", cheap_task.to_string())])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(report.tasks.len(), 1);
+        assert_eq!(report.tasks[0].task_id, cheap_task);
+
+        let user = app.add_user(&app.org_id, "User 1").await?;
+        let report: UsageReport = user
+            .client
+            .get("org/usage")
+            .query(&query)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(report.days.len(), 2, "org totals are visible to members");
+        assert!(
+            report.tasks.is_empty(),
+            "tasks without read permission are not listed"
+        );
+
+        let response = user
+            .client
+            .get(format!("orgs/{}/usage", app.org_id))
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            403,
+            "non-admin user should not be able to read other org reports"
+        );
+
+        let response = admin
+            .get("org/usage")
+            .query(&[("since", "2023-03-01"), ("until", "2023-02-01")])
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 400, "backwards date range");
+
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,159 @@
+use ergo_api::routes::{
+    inputs::InputPayload,
+    permissions::{Grant, Role, RoleInput},
+    tasks::{TaskInput, TaskTriggerInput},
+};
+use ergo_auth::PermissionType;
+use ergo_tasks::{
+    inputs::secrets::MASKED_VALUE,
+    scripting::{TaskJsConfig, TaskJsState},
+    TaskConfig, TaskState,
+};
+use fxhash::FxHashMap;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::common::run_app_test;
+
+#[actix_rt::test]
+async fn sensitive_payloads_are_redacted() {
+    run_app_test(|app| async move {
+        let admin = &app.admin_user.client;
+        let user = app.add_user(&app.admin_user.org_id, "log reader").await?;
+
+        let input = admin
+            .new_input(&InputPayload {
+                input_category_id: None,
+                name: "Card".to_string(),
+                description: None,
+                payload_schema: json!({ "type": "object" }),
+            })
+            .await?;
+
+        let mut triggers = FxHashMap::default();
+        for (local_id, sensitive) in [("card", true), ("plain", false)] {
+            triggers.insert(
+                local_id.to_string(),
+                TaskTriggerInput {
+                    input_id: input.input_id.clone(),
+                    name: local_id.to_string(),
+                    description: None,
+                    periodic: None,
+                    dedupe: None,
+                    sensitive,
+                    routes: None,
+                },
+            );
+        }
+
+        let task = admin
+            .new_task(&TaskInput {
+                name: "payments".to_string(),
+                alias: None,
+                description: None,
+                enabled: true,
+                disabled_input_mode: Default::default(),
+                compiled: TaskConfig::Js(TaskJsConfig {
+                    map: String::new(),
+                    script: "Ergo.setContext({});".to_string(),
+                    timeout: None,
+                    dependencies: Default::default(),
+                    bundle: None,
+                    libraries: Default::default(),
+                }),
+                source: serde_json::Value::Null,
+                state: Some(TaskState::Js(TaskJsState {
+                    context: String::new(),
+                })),
+                state_reset: None,
+                tags: Vec::new(),
+                actions: Default::default(),
+                triggers,
+            })
+            .await?;
+
+        let task_result = admin.get_task(&task.task_id).await?;
+        let card_trigger = &task_result.triggers["card"];
+        assert!(card_trigger.sensitive, "card trigger is sensitive");
+        assert!(!task_result.triggers["plain"].sensitive);
+
+        // Write the log entries directly, since encrypting a payload requires Vault.
+        let encrypted = json!({
+            "$sensitive": { "v": 1, "key": "vault:v1:abc", "data": "ZGF0YQ==" }
+        });
+        let sensitive_log_id = Uuid::new_v4();
+        let plain_log_id = Uuid::new_v4();
+        let mut conn = app.database.pool.acquire().await?;
+        for (log_id, trigger, payload) in [
+            (sensitive_log_id, "card", encrypted),
+            (plain_log_id, "plain", json!({ "amount": 5 })),
+        ] {
+            sqlx::query!(
+                "INSERT INTO inputs_log (inputs_log_id, task_trigger_id, task_id,
+                    task_trigger_local_id, status, payload, queue_job_id)
+                VALUES ($1, $2, $3, $4, 'success', $5, '')",
+                log_id,
+                task_result.triggers[trigger].task_trigger_id.0,
+                task.task_id.0,
+                trigger,
+                payload
+            )
+            .execute(&mut conn)
+            .await?;
+        }
+
+        let role: Role = admin
+            .post("roles")
+            .json(&RoleInput {
+                name: "log readers".to_string(),
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        admin
+            .put(format!("roles/{}/users/{}", role.role_id, user.user_id))
+            .send()
+            .await?
+            .error_for_status()?;
+        admin
+            .post(format!("roles/{}/grants", role.role_id))
+            .json(&Grant {
+                permission_type: PermissionType::Read,
+                object_id: Some(task.task_id.0),
+            })
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let logs = user.client.get_recent_logs().await?;
+        let sensitive_entry = logs
+            .iter()
+            .find(|l| l.inputs_log_id == sensitive_log_id)
+            .expect("sensitive log entry");
+        assert_eq!(sensitive_entry.payload, json!(MASKED_VALUE));
+        let plain_entry = logs
+            .iter()
+            .find(|l| l.inputs_log_id == plain_log_id)
+            .expect("plain log entry");
+        assert_eq!(plain_entry.payload, json!({ "amount": 5 }));
+
+        let response = admin
+            .post(format!("roles/{}/grants", role.role_id))
+            .json(&Grant {
+                permission_type: PermissionType::ReadSensitive,
+                object_id: Some(task.task_id.0),
+            })
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            201,
+            "read_sensitive can be granted on a task"
+        );
+
+        Ok(())
+    })
+    .await
+}
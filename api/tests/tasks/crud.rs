@@ -47,9 +47,12 @@ async fn bootstrap_data(app: &TestApp) -> Result<BootstrappedData> {
             alias: None,
             description: Some("task 1 description".to_string()),
             enabled: true,
+            disabled_input_mode: Default::default(),
             compiled: machine.clone(),
             source: serde_json::Value::Null,
             state: Some(states.clone()),
+            state_reset: None,
+            tags: vec!["Ops".to_string(), "nightly".to_string()],
             actions: test_actions.clone(),
             triggers: test_triggers.clone(),
         },
@@ -58,9 +61,12 @@ async fn bootstrap_data(app: &TestApp) -> Result<BootstrappedData> {
             alias: Some("task_2".to_string()),
             description: Some("a task 2 description".to_string()),
             enabled: true,
+            disabled_input_mode: Default::default(),
             compiled: machine.clone(),
             source: serde_json::Value::Null,
             state: Some(states.clone()),
+            state_reset: None,
+            tags: vec!["ops".to_string()],
             actions: test_actions.clone(),
             triggers: test_triggers.clone(),
         },
@@ -69,9 +75,12 @@ async fn bootstrap_data(app: &TestApp) -> Result<BootstrappedData> {
             alias: None,
             description: None,
             enabled: false,
+            disabled_input_mode: Default::default(),
             compiled: machine.clone(),
             source: serde_json::Value::Null,
             state: Some(states.clone()),
+            state_reset: None,
+            tags: Vec::new(),
             actions: test_actions.clone(),
             triggers: test_triggers.clone(),
         },
@@ -82,9 +91,12 @@ async fn bootstrap_data(app: &TestApp) -> Result<BootstrappedData> {
         alias: None,
         description: None,
         enabled: true,
+        disabled_input_mode: Default::default(),
         compiled: machine.clone(),
         source: serde_json::Value::Null,
         state: Some(states.clone()),
+        state_reset: None,
+        tags: Vec::new(),
         actions: test_actions.clone(),
         triggers: test_triggers.clone(),
     };
@@ -115,6 +127,64 @@ async fn bootstrap_data(app: &TestApp) -> Result<BootstrappedData> {
     })
 }
 
+async fn list_tasks_with_query(
+    user: &TestUser,
+    query: &[(&str, &str)],
+) -> Result<(Vec<TaskDescription>, usize)> {
+    let response = user
+        .client
+        .get("tasks")
+        .query(query)
+        .send()
+        .await?
+        .error_for_status()?;
+    let total = response
+        .headers()
+        .get("X-Total-Count")
+        .ok_or_else(|| anyhow!("Missing total count"))?
+        .to_str()?
+        .parse()?;
+    let tasks = response.json().await?;
+    Ok((tasks, total))
+}
+
+#[actix_rt::test]
+async fn list_tasks_filtered() {
+    run_app_test(|app| async move {
+        let BootstrappedData { user1, .. } = bootstrap_data(&app).await?;
+
+        let names =
+            |tasks: &[TaskDescription]| tasks.iter().map(|t| t.name.clone()).collect::<Vec<_>>();
+
+        let (tasks, total) = list_tasks_with_query(&user1, &[("tags", "ops")]).await?;
+        assert_eq!(names(&tasks), vec!["task 1", "task 2"], "filter by one tag");
+        assert_eq!(total, 2);
+        assert_eq!(tasks[0].tags, vec!["nightly", "ops"], "tags are normalized");
+
+        let (tasks, _) = list_tasks_with_query(&user1, &[("tags", "OPS,nightly")]).await?;
+        assert_eq!(names(&tasks), vec!["task 1"], "filter by multiple tags");
+
+        let (tasks, _) = list_tasks_with_query(&user1, &[("q", "2 descr")]).await?;
+        assert_eq!(names(&tasks), vec!["task 2"], "search the description");
+
+        let (tasks, total) = list_tasks_with_query(
+            &user1,
+            &[
+                ("sort", "name"),
+                ("desc", "true"),
+                ("limit", "2"),
+                ("offset", "1"),
+            ],
+        )
+        .await?;
+        assert_eq!(names(&tasks), vec!["task 2", "task 1"], "paginated list");
+        assert_eq!(total, 3, "total ignores pagination");
+
+        Ok(())
+    })
+    .await;
+}
+
 #[actix_rt::test]
 async fn list_tasks() {
     run_app_test(|app| async move {
@@ -142,6 +212,8 @@ async fn list_tasks() {
                         successes: 0,
                         failures: 0,
                         stats_since: Utc::now() - chrono::Duration::days(7),
+                        validation_errors: None,
+                        tags: Vec::new(),
                     },
                 )
             })
@@ -363,9 +435,12 @@ async fn put_existing_task() {
             alias: None,
             description: Some("a new description".to_string()),
             enabled: false,
+            disabled_input_mode: Default::default(),
             compiled: config.clone(),
             source: serde_json::Value::Null,
             state: Some(state.clone()),
+            state_reset: None,
+            tags: Vec::new(),
             actions: vec![].into_iter().collect::<FxHashMap<_, _>>(),
             triggers: vec![].into_iter().collect::<FxHashMap<_, _>>(),
         };
@@ -408,6 +483,9 @@ async fn update_task_triggers() {
                 description: None,
                 input_id: inputs.url.input_id.clone(),
                 periodic: None,
+                dedupe: None,
+                sensitive: false,
+                routes: None,
             },
         );
 
@@ -452,6 +530,9 @@ async fn update_task_triggers() {
                 description: Some("A description".to_string()),
                 input_id: inputs.url.input_id.clone(),
                 periodic: None,
+                dedupe: None,
+                sensitive: false,
+                routes: None,
             },
         );
 
@@ -481,6 +562,9 @@ async fn update_task_triggers() {
                 description: Some("A description".to_string()),
                 input_id: inputs.url.input_id.clone(),
                 periodic: None,
+                dedupe: None,
+                sensitive: false,
+                routes: None,
             },
         );
         task2.triggers.insert(
@@ -490,6 +574,9 @@ async fn update_task_triggers() {
                 description: Some("this is another change".to_string()),
                 input_id: inputs.url.input_id.clone(),
                 periodic: None,
+                dedupe: None,
+                sensitive: false,
+                routes: None,
             },
         );
         task2.triggers.insert(
@@ -499,6 +586,9 @@ async fn update_task_triggers() {
                 description: None,
                 input_id: inputs.url.input_id.clone(),
                 periodic: None,
+                dedupe: None,
+                sensitive: false,
+                routes: None,
             },
         );
 
@@ -541,6 +631,7 @@ async fn update_task_actions() {
                 action_id: actions.echo.action_id.clone(),
                 account_id: None,
                 action_template: None,
+                compensate_with: None,
             },
         );
         user1
@@ -584,6 +675,7 @@ async fn update_task_actions() {
                 action_id: actions.echo.action_id.clone(),
                 account_id: None,
                 action_template: None,
+                compensate_with: None,
             },
         );
         user1
@@ -612,6 +704,7 @@ async fn update_task_actions() {
                 action_id: actions.echo.action_id.clone(),
                 account_id: None,
                 action_template: None,
+                compensate_with: None,
             },
         );
         task2.actions.insert(
@@ -621,6 +714,7 @@ async fn update_task_actions() {
                 action_id: actions.echo.action_id.clone(),
                 account_id: None,
                 action_template: None,
+                compensate_with: None,
             },
         );
         task2.actions.insert(
@@ -630,6 +724,7 @@ async fn update_task_actions() {
                 action_id: actions.echo.action_id.clone(),
                 account_id: None,
                 action_template: None,
+                compensate_with: None,
             },
         );
 
@@ -654,6 +749,82 @@ async fn update_task_actions() {
     .await
 }
 
+#[actix_rt::test]
+async fn task_action_compensations() {
+    run_app_test(|app| async move {
+        let BootstrappedData {
+            user1,
+            user1_tasks,
+            actions,
+            ..
+        } = bootstrap_data(&app).await?;
+
+        let task_id = &user1_tasks[0].0.task_id;
+        let mut task = user1_tasks[0].1.clone();
+        task.actions.insert(
+            "create".to_string(),
+            TaskActionInput {
+                name: "Create a server".to_string(),
+                action_id: actions.echo.action_id.clone(),
+                account_id: None,
+                action_template: None,
+                compensate_with: Some("destroy".to_string()),
+            },
+        );
+        task.actions.insert(
+            "destroy".to_string(),
+            TaskActionInput {
+                name: "Destroy the server".to_string(),
+                action_id: actions.echo.action_id.clone(),
+                account_id: None,
+                action_template: None,
+                compensate_with: None,
+            },
+        );
+
+        user1
+            .client
+            .put_task(task_id, &task)
+            .await
+            .expect("Adding actions with compensation");
+        let result = user1.client.get_task(task_id).await?;
+        assert_eq!(
+            result.actions.0["create"].compensate_with.as_deref(),
+            Some("destroy")
+        );
+        compare_hashmaps!(task.actions, result.actions.0, "compensation was saved");
+
+        task.actions.remove("destroy");
+        let response = user1
+            .client
+            .put(format!("tasks/{}", task_id))
+            .json(&task)
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "compensation must refer to an action in the task"
+        );
+
+        task.actions.get_mut("create").unwrap().compensate_with = Some("create".to_string());
+        let response = user1
+            .client
+            .put(format!("tasks/{}", task_id))
+            .json(&task)
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "an action can not compensate for itself"
+        );
+
+        Ok(())
+    })
+    .await
+}
+
 #[actix_rt::test]
 async fn list_inputs() {
     run_app_test(|app| async move {
@@ -728,3 +899,86 @@ fn update_action() {}
 #[test]
 #[ignore]
 fn delete_action() {}
+
+#[actix_rt::test]
+async fn revalidate_tasks() {
+    run_app_test(|app| async move {
+        let (inputs, actions) = bootstrap_inputs_and_actions(&app).await;
+        let (machine, states) = simple_state_machine();
+        let task = app
+            .admin_user
+            .client
+            .new_task(&TaskInput {
+                name: "admin task".to_string(),
+                alias: None,
+                description: None,
+                enabled: true,
+                disabled_input_mode: Default::default(),
+                compiled: machine,
+                source: serde_json::Value::Null,
+                state: Some(states),
+                state_reset: None,
+                tags: Vec::new(),
+                actions: simple_task_actions(&actions),
+                triggers: simple_task_triggers(&inputs),
+            })
+            .await?;
+
+        // Simulate a config that stopped validating after an upgrade.
+        sqlx::query!(
+            r##"UPDATE task_templates
+            SET compiled=jsonb_set(compiled, '{data,0,initial}', '"missing"')
+            WHERE (task_template_id, task_template_version) =
+                (SELECT task_template_id, task_template_version FROM tasks WHERE task_id=$1)"##,
+            task.task_id.0
+        )
+        .execute(&app.database.pool)
+        .await?;
+
+        let user = app.add_user(&app.org_id, "Not an admin").await?;
+        let response = user.client.post("tasks/revalidate").send().await?;
+        assert_eq!(response.status().as_u16(), 403, "non-admin user");
+
+        let report: serde_json::Value = app
+            .admin_user
+            .client
+            .post("tasks/revalidate?dry_run=true")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let invalid = report["invalid"].as_array().unwrap();
+        assert_eq!(invalid.len(), 1, "one invalid task");
+        assert_eq!(invalid[0]["task_id"], serde_json::json!(task.task_id));
+
+        let listed = app.admin_user.client.list_tasks().await?;
+        let listed_task = listed.iter().find(|t| t.task_id == task.task_id).unwrap();
+        assert!(
+            listed_task.validation_errors.is_none(),
+            "dry run saves nothing"
+        );
+
+        let report: serde_json::Value = app
+            .admin_user
+            .client
+            .post("tasks/revalidate?invalid_dependents=flag")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(report["invalid"].as_array().map(|a| a.len()), Some(1));
+
+        let listed = app.admin_user.client.list_tasks().await?;
+        let listed_task = listed.iter().find(|t| t.task_id == task.task_id).unwrap();
+        assert!(listed_task.enabled, "flagged task stays enabled");
+        assert_eq!(
+            listed_task.validation_errors.as_ref().map(|e| e.len()),
+            Some(1)
+        );
+
+        Ok(())
+    })
+    .await
+}
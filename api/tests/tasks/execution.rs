@@ -4,7 +4,10 @@ use anyhow::Result;
 use ergo_api::routes::{
     actions::ActionPayload,
     inputs::InputPayload,
-    tasks::{InputsLogEntry, TaskActionInput, TaskInput, TaskTriggerInput},
+    tasks::{
+        InferredPayloadSchema, InputsLogEntry, RejectedInputs, TaskActionInput, TaskInput,
+        TaskTriggerInput,
+    },
 };
 use ergo_database::object_id::{ActionId, InputId, OrgId, TaskId};
 use ergo_tasks::{
@@ -17,7 +20,7 @@ use ergo_tasks::{
         edge_indexes_from_names, DataFlowAction, DataFlowConfig, DataFlowJs, DataFlowNode,
         DataFlowNodeFunction, DataFlowState, DataFlowTrigger, JsCodeFormat,
     },
-    inputs::{Input, InputStatus},
+    inputs::{DisabledInputMode, Input, InputStatus, TriggerDedupeConfig},
     scripting::{TaskJsConfig, TaskJsState},
     state_machine::{
         ActionInvokeDef, ActionPayloadBuilder, EventHandler, StateDefinition, StateMachine,
@@ -165,6 +168,7 @@ async fn bootstrap(app: &TestApp) -> Result<BootstrappedData> {
         .into(),
         account_required: false,
         account_types: vec![],
+        tags: vec![],
         postprocess_script: None,
         timeout: None,
     };
@@ -185,6 +189,7 @@ async fn bootstrap(app: &TestApp) -> Result<BootstrappedData> {
         timeout: None,
         postprocess_script: None,
         account_types: vec![],
+        tags: vec![],
         account_required: false,
         executor_template: ScriptOrTemplate::Template(vec![
             ("url".to_string(), json!("{{url}}")),
@@ -244,6 +249,7 @@ async fn bootstrap_state_machine_task(base: &BootstrappedData) -> (TaskId, TaskI
         description: None,
         alias: Some("run_script".to_string()),
         enabled: true,
+        disabled_input_mode: Default::default(),
         state: Some(TaskState::StateMachine(smallvec![StateMachineData {
             state: "initial".to_string(),
             context: json!(null)
@@ -260,6 +266,8 @@ async fn bootstrap_state_machine_task(base: &BootstrappedData) -> (TaskId, TaskI
                 StateDefinition {
                     on: smallvec![EventHandler {
                         trigger_id: "run".to_string(),
+                        cond: None,
+                        when: None,
                         target: None,
                         actions: Some(vec![ActionInvokeDef {
                             task_action_local_id: "run".to_string(),
@@ -275,6 +283,8 @@ async fn bootstrap_state_machine_task(base: &BootstrappedData) -> (TaskId, TaskI
             .collect::<FxHashMap<_, _>>()
         }]),
 
+        state_reset: None,
+        tags: Vec::new(),
         actions: vec![(
             "run".to_string(),
             TaskActionInput {
@@ -282,6 +292,7 @@ async fn bootstrap_state_machine_task(base: &BootstrappedData) -> (TaskId, TaskI
                 action_id: base.script_action_id.clone(),
                 account_id: None,
                 action_template: None,
+                compensate_with: None,
             },
         )]
         .into_iter()
@@ -294,6 +305,9 @@ async fn bootstrap_state_machine_task(base: &BootstrappedData) -> (TaskId, TaskI
                 name: "Run a script".to_string(),
                 description: None,
                 periodic: None,
+                dedupe: None,
+                sensitive: false,
+                routes: None,
             },
         )]
         .into_iter()
@@ -329,6 +343,7 @@ async fn bootstrap_script_task(base: &BootstrappedData) -> (TaskId, TaskInput) {
         description: None,
         alias: None,
         enabled: true,
+        disabled_input_mode: Default::default(),
         state: Some(TaskState::Js(TaskJsState {
             context: String::new(),
         })),
@@ -337,6 +352,9 @@ async fn bootstrap_script_task(base: &BootstrappedData) -> (TaskId, TaskInput) {
             map: String::new(),
             script,
             timeout: None,
+            dependencies: Default::default(),
+            bundle: None,
+            libraries: Default::default(),
         }),
         triggers: vec![(
             "request_url".to_string(),
@@ -345,10 +363,15 @@ async fn bootstrap_script_task(base: &BootstrappedData) -> (TaskId, TaskInput) {
                 description: None,
                 input_id: base.url_input_id.clone(),
                 periodic: None,
+                dedupe: None,
+                sensitive: false,
+                routes: None,
             },
         )]
         .into_iter()
         .collect(),
+        state_reset: None,
+        tags: Vec::new(),
         actions: vec![(
             "send".to_string(),
             TaskActionInput {
@@ -356,6 +379,7 @@ async fn bootstrap_script_task(base: &BootstrappedData) -> (TaskId, TaskInput) {
                 action_id: base.http_action_id.clone(),
                 account_id: None,
                 action_template: Some(vec![(
+                compensate_with: None,
                     "method".to_string(),
                     serde_json::Value::String("POST".to_string()),
                 )]),
@@ -435,6 +459,7 @@ async fn bootstrap_dataflow_task(base: &BootstrappedData) -> (TaskId, TaskInput)
         source: serde_json::Value::Null,
         alias: None,
         enabled: true,
+        disabled_input_mode: Default::default(),
         triggers: vec![
             (
                 "request_url".to_string(),
@@ -443,6 +468,9 @@ async fn bootstrap_dataflow_task(base: &BootstrappedData) -> (TaskId, TaskInput)
                     description: None,
                     input_id: base.url_input_id.clone(),
                     periodic: None,
+                    dedupe: None,
+                    sensitive: false,
+                    routes: None,
                 },
             ),
             (
@@ -452,11 +480,16 @@ async fn bootstrap_dataflow_task(base: &BootstrappedData) -> (TaskId, TaskInput)
                     description: None,
                     input_id: base.string_input_id.clone(),
                     periodic: None,
+                    dedupe: None,
+                    sensitive: false,
+                    routes: None,
                 },
             ),
         ]
         .into_iter()
         .collect(),
+        state_reset: None,
+        tags: Vec::new(),
         actions: vec![(
             "send".to_string(),
             TaskActionInput {
@@ -464,6 +497,7 @@ async fn bootstrap_dataflow_task(base: &BootstrappedData) -> (TaskId, TaskInput)
                 action_id: base.http_action_id.clone(),
                 account_id: None,
                 action_template: Some(vec![(
+                compensate_with: None,
                     "method".to_string(),
                     serde_json::Value::String("POST".to_string()),
                 )]),
@@ -576,6 +610,478 @@ async fn state_machine_task() {
     .await
 }
 
+#[actix_rt::test]
+async fn deduplicated_input() {
+    run_app_test(|app| async move {
+        let base = bootstrap(&app).await?;
+        let (task_id, mut task) = bootstrap_state_machine_task(&base).await;
+        let BootstrappedData { user, .. } = base;
+
+        task.triggers.get_mut("run").unwrap().dedupe = Some(TriggerDedupeConfig {
+            key: vec!["/script".to_string()],
+            window_seconds: 60,
+        });
+        user.client.put_task(&task_id, &task).await?;
+
+        let script = r##"Ergo.setResult({ value: 5 })"##;
+        let first_id = user
+            .client
+            .run_task_trigger("run_script", "run", json!({ "script": script }))
+            .await?
+            .log_id;
+        let second_id = user
+            .client
+            .run_task_trigger("run_script", "run", json!({ "script": script }))
+            .await?
+            .log_id;
+
+        let logs = wait_for_task_to_finish(&user, &first_id).await?;
+        let first = logs.iter().find(|l| l.inputs_log_id == first_id).unwrap();
+        assert_eq!(first.input_status, InputStatus::Success);
+
+        let second = logs.iter().find(|l| l.inputs_log_id == second_id).unwrap();
+        assert_eq!(second.input_status, InputStatus::Duplicate);
+        assert_eq!(second.info, json!({ "duplicate_of": first_id }));
+        assert!(second.actions.is_empty(), "duplicate input should not run");
+
+        Ok(())
+    })
+    .await
+}
+
+#[actix_rt::test]
+async fn routed_input() {
+    run_app_test(|app| async move {
+        let base = bootstrap(&app).await?;
+        let (task_id, mut task) = bootstrap_state_machine_task(&base).await;
+        let BootstrappedData { user, .. } = base;
+
+        let mut router = task.triggers.get("run").unwrap().clone();
+        router.name = "Router".to_string();
+        router.routes = Some(serde_json::from_value(json!([
+            {
+                "condition": { "path": "$.script", "op": "contains", "value": "routed" },
+                "target": { "trigger": "run" }
+            },
+            {
+                "condition": { "path": "$.script", "op": "contains", "value": "elsewhere" },
+                "target": { "task": "not_a_task", "trigger": "run" }
+            }
+        ]))?);
+        task.triggers.insert("router".to_string(), router.clone());
+        user.client.put_task(&task_id, &task).await?;
+
+        let routed_id = user
+            .client
+            .run_task_trigger(
+                "run_script",
+                "router",
+                json!({ "script": "// routed\nErgo.setResult(1)" }),
+            )
+            .await?
+            .log_id;
+        let logs = wait_for_task_to_finish(&user, &routed_id).await?;
+        let routed = logs.iter().find(|l| l.inputs_log_id == routed_id).unwrap();
+        assert_eq!(
+            routed.task_trigger_local_id, "run",
+            "input went to the target"
+        );
+        assert_eq!(routed.actions.len(), 1, "target trigger runs the action");
+
+        let unrouted_id = user
+            .client
+            .run_task_trigger(
+                "run_script",
+                "router",
+                json!({ "script": "Ergo.setResult(1)" }),
+            )
+            .await?
+            .log_id;
+        let logs = wait_for_actionless_task_to_finish(&user, &unrouted_id).await?;
+        let unrouted = logs
+            .iter()
+            .find(|l| l.inputs_log_id == unrouted_id)
+            .unwrap();
+        assert_eq!(
+            unrouted.task_trigger_local_id, "router",
+            "input without a matching route stays on its trigger"
+        );
+
+        let response = user
+            .client
+            .post("tasks/run_script/trigger/router")
+            .json(&json!({ "script": "// elsewhere" }))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 404, "missing route target");
+
+        router.routes = Some(serde_json::from_value(json!([{
+            "condition": { "path": "$.script", "op": "exists" },
+            "target": { "trigger": "router" }
+        }]))?);
+        task.triggers.insert("router".to_string(), router);
+        let response = user
+            .client
+            .put(format!("tasks/{}", task_id))
+            .json(&task)
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 400, "route to itself");
+
+        Ok(())
+    })
+    .await
+}
+
+#[actix_rt::test]
+async fn state_machine_declarative_condition() {
+    run_app_test(|app| async move {
+        let base = bootstrap(&app).await?;
+        let (task_id, mut task) = bootstrap_state_machine_task(&base).await;
+        let BootstrappedData { user, .. } = base;
+
+        let set_condition = |task: &mut TaskInput, when: serde_json::Value| {
+            if let TaskConfig::StateMachine(machines) = &mut task.compiled {
+                let handler = &mut machines[0].states.get_mut("initial").unwrap().on[0];
+                handler.when = Some(serde_json::from_value(when).unwrap());
+            }
+        };
+
+        // The input's schema says that `script` is a string.
+        set_condition(
+            &mut task,
+            json!({ "path": "$.script", "op": "gt", "value": 5 }),
+        );
+        let response = user
+            .client
+            .put(format!("tasks/{}", task_id))
+            .json(&task)
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 400, "condition type mismatch");
+
+        set_condition(
+            &mut task,
+            json!({ "not": { "path": "$.script", "op": "contains", "value": "skip" } }),
+        );
+        user.client.put_task(&task_id, &task).await?;
+
+        let skipped_id = user
+            .client
+            .run_task_trigger(
+                "run_script",
+                "run",
+                json!({ "script": "// skip\nErgo.setResult(1)" }),
+            )
+            .await?
+            .log_id;
+        let logs = wait_for_actionless_task_to_finish(&user, &skipped_id).await?;
+        let skipped = logs.iter().find(|l| l.inputs_log_id == skipped_id).unwrap();
+        assert!(skipped.actions.is_empty(), "condition should not match");
+
+        let run_id = user
+            .client
+            .run_task_trigger(
+                "run_script",
+                "run",
+                json!({ "script": "Ergo.setResult(1)" }),
+            )
+            .await?
+            .log_id;
+        let logs = wait_for_task_to_finish(&user, &run_id).await?;
+        let run = logs.iter().find(|l| l.inputs_log_id == run_id).unwrap();
+        assert_eq!(run.actions.len(), 1, "condition should match");
+
+        Ok(())
+    })
+    .await
+}
+
+#[actix_rt::test]
+async fn disabled_task_inputs() {
+    run_app_test(|app| async move {
+        let base = bootstrap(&app).await?;
+        let (task_id, mut task) = bootstrap_state_machine_task(&base).await;
+        let BootstrappedData { user, .. } = base;
+        let script = r##"Ergo.setResult({ value: 5 })"##;
+
+        task.enabled = false;
+        task.disabled_input_mode = DisabledInputMode::Reject;
+        user.client.put_task(&task_id, &task).await?;
+        let response = user
+            .client
+            .post("tasks/run_script/trigger/run")
+            .json(&json!({ "script": script }))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 400, "reject mode");
+
+        task.disabled_input_mode = DisabledInputMode::Drop;
+        user.client.put_task(&task_id, &task).await?;
+        let dropped_id = user
+            .client
+            .run_task_trigger("run_script", "run", json!({ "script": script }))
+            .await?
+            .log_id;
+
+        task.disabled_input_mode = DisabledInputMode::Buffer;
+        user.client.put_task(&task_id, &task).await?;
+        let buffered_id = user
+            .client
+            .run_task_trigger("run_script", "run", json!({ "script": script }))
+            .await?
+            .log_id;
+
+        let logs = user.client.get_recent_logs().await?;
+        let dropped = logs.iter().find(|l| l.inputs_log_id == dropped_id).unwrap();
+        assert_eq!(dropped.input_status, InputStatus::Dropped);
+        let buffered = logs
+            .iter()
+            .find(|l| l.inputs_log_id == buffered_id)
+            .unwrap();
+        assert_eq!(buffered.input_status, InputStatus::Buffered);
+
+        // Enabling the task runs the buffered input.
+        task.enabled = true;
+        user.client.put_task(&task_id, &task).await?;
+        let logs = wait_for_task_to_finish(&user, &buffered_id).await?;
+        let buffered = logs
+            .iter()
+            .find(|l| l.inputs_log_id == buffered_id)
+            .unwrap();
+        assert_eq!(buffered.input_status, InputStatus::Success);
+        let dropped = logs.iter().find(|l| l.inputs_log_id == dropped_id).unwrap();
+        assert_eq!(dropped.input_status, InputStatus::Dropped);
+        assert!(dropped.actions.is_empty(), "dropped input should not run");
+
+        Ok(())
+    })
+    .await
+}
+
+#[actix_rt::test]
+async fn infer_trigger_payload_schema() {
+    run_app_test(|app| async move {
+        let base = bootstrap(&app).await?;
+        let (task_id, _) = bootstrap_state_machine_task(&base).await;
+        let BootstrappedData {
+            user,
+            script_input_id,
+            ..
+        } = base;
+
+        for payload in [
+            json!({ "script": "Ergo.setResult({ value: 5 })", "note": "first" }),
+            json!({ "script": "Ergo.setResult({ value: 6 })", "note": null }),
+            json!({ "script": "Ergo.setResult({ value: 7 })" }),
+        ] {
+            let log_id = user
+                .client
+                .run_task_trigger("run_script", "run", payload)
+                .await?
+                .log_id;
+            wait_for_task_to_finish(&user, &log_id).await?;
+        }
+
+        let inferred: InferredPayloadSchema = user
+            .client
+            .get(format!("tasks/{}/trigger/run/payload_schema", task_id))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(inferred.input_id, script_input_id);
+        assert_eq!(inferred.samples, 3);
+        assert_eq!(inferred.schema["required"], json!(["script"]));
+        assert_eq!(
+            inferred.schema["properties"]["note"]["type"],
+            json!(["string", "null"])
+        );
+
+        let response = user
+            .client
+            .get(format!("tasks/{}/trigger/missing/payload_schema", task_id))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 404);
+
+        let input: Input = app
+            .admin_user
+            .client
+            .put(format!("inputs/{}/payload_schema", script_input_id))
+            .json(&inferred.schema)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(input.payload_schema, inferred.schema);
+
+        Ok(())
+    })
+    .await
+}
+
+#[actix_rt::test]
+async fn rejected_inputs() {
+    run_app_test(|app| async move {
+        let base = bootstrap(&app).await?;
+        let (task_id, _) = bootstrap_state_machine_task(&base).await;
+        let BootstrappedData { user, .. } = base;
+
+        for payload in [json!({ "script": 5 }), json!({ "other": "value" })] {
+            let response = user
+                .client
+                .post("tasks/run_script/trigger/run")
+                .json(&payload)
+                .send()
+                .await?;
+            assert_eq!(response.status().as_u16(), 400, "invalid payload");
+        }
+
+        let rejected: RejectedInputs = user
+            .client
+            .get(format!("tasks/{}/trigger/run/rejected", task_id))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(rejected.total, 2);
+        assert_eq!(rejected.recent.len(), 2);
+        assert_eq!(rejected.recent[0].payload, json!({ "other": "value" }));
+        assert!(!rejected.paths.is_empty());
+        assert_eq!(
+            rejected.paths.iter().map(|p| p.count).sum::<i64>(),
+            rejected
+                .recent
+                .iter()
+                .map(|r| r.errors.as_array().map(|e| e.len()).unwrap_or(0) as i64)
+                .sum::<i64>()
+        );
+
+        let logs = user.client.get_recent_logs().await?;
+        let rejected_logs = logs
+            .iter()
+            .filter(|l| l.input_status == InputStatus::Rejected)
+            .count();
+        assert_eq!(rejected_logs, 2);
+
+        let rejected: RejectedInputs = user
+            .client
+            .get(format!("tasks/{}/trigger/run/rejected?limit=1", task_id))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(rejected.total, 2);
+        assert_eq!(rejected.recent.len(), 1);
+
+        let response = user
+            .client
+            .get(format!("tasks/{}/trigger/missing/rejected", task_id))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 404);
+
+        Ok(())
+    })
+    .await
+}
+
+#[actix_rt::test]
+async fn replay_input() {
+    run_app_test(|app| async move {
+        let base = bootstrap(&app).await?;
+        bootstrap_state_machine_task(&base).await;
+        let BootstrappedData { user, .. } = base;
+
+        let script = r##"Ergo.setResult({ value: 5 })"##;
+        let original_id = user
+            .client
+            .run_task_trigger("run_script", "run", json!({ "script": script }))
+            .await?
+            .log_id;
+        wait_for_task_to_finish(&user, &original_id).await?;
+
+        let replay_id = user.client.replay_input(&original_id, None).await?.log_id;
+        let logs = wait_for_task_to_finish(&user, &replay_id).await?;
+        let replay = logs.iter().find(|l| l.inputs_log_id == replay_id).unwrap();
+        assert_eq!(replay.replay_of, Some(original_id));
+        assert_eq!(replay.payload, json!({ "script": script }));
+        assert_eq!(replay.input_status, InputStatus::Success);
+
+        let new_script = r##"Ergo.setResult({ value: 6 })"##;
+        let override_id = user
+            .client
+            .replay_input(&original_id, Some(json!({ "script": new_script })))
+            .await?
+            .log_id;
+        let logs = wait_for_task_to_finish(&user, &override_id).await?;
+        let replay = logs
+            .iter()
+            .find(|l| l.inputs_log_id == override_id)
+            .unwrap();
+        assert_eq!(replay.replay_of, Some(original_id));
+        assert_eq!(
+            replay.actions[0].result,
+            json!({ "output": { "result": {"value": 6 }, "console": [] } }),
+            "replay with payload override"
+        );
+
+        Ok(())
+    })
+    .await
+}
+
+#[actix_rt::test]
+async fn run_timeline() {
+    run_app_test(|app| async move {
+        let base = bootstrap(&app).await?;
+        bootstrap_state_machine_task(&base).await;
+        let BootstrappedData { user, .. } = base;
+
+        let script = r##"Ergo.setResult({ value: 5 })"##;
+        let log_id = user
+            .client
+            .run_task_trigger("run_script", "run", json!({ "script": script }))
+            .await?
+            .log_id;
+        let logs = wait_for_task_to_finish(&user, &log_id).await?;
+        let log = logs.iter().find(|l| l.inputs_log_id == log_id).unwrap();
+
+        let timeline: serde_json::Value = user
+            .client
+            .get(format!("inputs_log/{}/timeline", log_id))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        assert_eq!(timeline["status"], json!("success"));
+        assert_eq!(timeline["task_trigger_local_id"], json!("run"));
+        assert_eq!(timeline["payload"], json!({ "script": script }));
+        assert_eq!(timeline["info"], log.info);
+        assert_eq!(timeline["state"]["inputs_log_id"], json!(log_id));
+
+        let actions = timeline["actions"].as_array().unwrap();
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0]["status"], json!("success"));
+        assert_eq!(
+            actions[0]["result"],
+            json!({ "output": { "result": {"value": 5 }, "console": [] } })
+        );
+        assert!(actions[0]["duration_ms"].as_i64().unwrap() >= 0);
+        assert!(timeline["notifications"].as_array().unwrap().is_empty());
+
+        Ok(())
+    })
+    .await
+}
+
 #[actix_rt::test]
 async fn postprocess_script() {
     run_app_test(|app| async move {
@@ -21,6 +21,7 @@ use crate::common::TestApp;
 mod crud;
 mod execution;
 mod periodic;
+mod plans;
 
 pub struct BootstrappedInputs {
     pub url: Input,
@@ -73,6 +74,7 @@ pub async fn bootstrap_inputs_and_actions(
         .into(),
         account_required: false,
         account_types: vec![],
+        tags: vec![],
         timeout: None,
     };
 
@@ -127,6 +129,7 @@ pub fn simple_task_actions(actions: &BootstrappedActions) -> FxHashMap<String, T
                 action_id: actions.echo.action_id.clone(),
                 account_id: None,
                 action_template: None,
+                compensate_with: None,
             },
         ),
         (
@@ -136,6 +139,7 @@ pub fn simple_task_actions(actions: &BootstrappedActions) -> FxHashMap<String, T
                 action_id: actions.echo.action_id.clone(),
                 account_id: None,
                 action_template: None,
+                compensate_with: None,
             },
         ),
     ]
@@ -152,6 +156,9 @@ pub fn simple_task_triggers(inputs: &BootstrappedInputs) -> FxHashMap<String, Ta
                 description: Some("Run the task and do something".to_string()),
                 input_id: inputs.url.input_id.clone(),
                 periodic: None,
+                dedupe: None,
+                sensitive: false,
+                routes: None,
             },
         ),
         (
@@ -161,6 +168,9 @@ pub fn simple_task_triggers(inputs: &BootstrappedInputs) -> FxHashMap<String, Ta
                 description: None,
                 input_id: inputs.url.input_id.clone(),
                 periodic: None,
+                dedupe: None,
+                sensitive: false,
+                routes: None,
             },
         ),
     ]
@@ -1,5 +1,8 @@
 use chrono::{DateTime, Datelike, Duration, DurationRound, Timelike, Utc};
-use ergo_api::routes::tasks::{NewTaskResult, TaskInput};
+use ergo_api::routes::{
+    inputs::InputPayload,
+    tasks::{NewTaskResult, TaskInput},
+};
 use ergo_database::{object_id::OrgId, RedisPool};
 use ergo_tasks::{inputs::queue::InputQueue, PeriodicSchedule, PeriodicTaskTriggerInput};
 use ergo_test::wait_for;
@@ -63,9 +66,12 @@ async fn bootstrap_data(app: &TestApp) -> BootstrappedData {
         alias: None,
         description: None,
         enabled: true,
+        disabled_input_mode: Default::default(),
         compiled: config,
         state: Some(state),
         source: serde_json::Value::Null,
+        state_reset: None,
+        tags: Vec::new(),
         actions: simple_task_actions(&actions),
         triggers,
     };
@@ -353,6 +359,69 @@ async fn disable_task() {
     .await;
 }
 
+#[actix_rt::test]
+async fn input_change_disables_invalid_task() {
+    run_app_test(|app| async move {
+        let BootstrappedData {
+            input_queue,
+            inputs,
+            task: (task, _),
+            user,
+            ..
+        } = bootstrap_data(&app).await;
+
+        // The periodic trigger's payload doesn't have a `method` field.
+        let new_input = InputPayload {
+            input_category_id: None,
+            name: inputs.url.name.clone(),
+            description: None,
+            payload_schema: json!({
+                "type": "object",
+                "required": ["url", "method"],
+                "properties": {
+                    "url": { "type": "string" },
+                    "method": { "type": "string" }
+                }
+            }),
+        };
+
+        app.admin_user
+            .client
+            .put_input(&inputs.url.input_id, &new_input)
+            .await
+            .expect("Updating input");
+
+        let tasks = user.client.list_tasks().await.expect("Listing tasks");
+        let updated = tasks
+            .iter()
+            .find(|t| t.task_id == task.task_id)
+            .expect("Finding task");
+        assert!(!updated.enabled, "task should be disabled");
+        assert_eq!(
+            updated.validation_errors.as_ref().map(|e| e.len()),
+            Some(1),
+            "validation errors should be recorded"
+        );
+
+        wait_for(|| async {
+            let scheduled = input_queue
+                .list_scheduled()
+                .await
+                .expect("Listing scheduled tasks");
+            if scheduled.is_empty() {
+                Some(())
+            } else {
+                None
+            }
+        })
+        .await
+        .expect("Waiting for job to be descheduled");
+
+        Ok(())
+    })
+    .await;
+}
+
 #[actix_rt::test]
 #[ignore]
 async fn invalid_payload() {}
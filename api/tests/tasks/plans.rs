@@ -0,0 +1,187 @@
+use ergo_api::routes::{
+    task_plans::TaskPlan,
+    tasks::{TaskActionInput, TaskInput},
+};
+use ergo_tasks::{PeriodicSchedule, PeriodicTaskTriggerInput};
+use serde_json::json;
+
+use crate::common::run_app_test;
+
+use super::{
+    bootstrap_inputs_and_actions, simple_state_machine, simple_task_actions, simple_task_triggers,
+};
+
+#[actix_rt::test]
+async fn plan_and_apply_task_update() {
+    run_app_test(|app| async move {
+        let (inputs, actions) = bootstrap_inputs_and_actions(&app).await;
+        let (config, state) = simple_state_machine();
+        let task = TaskInput {
+            name: "task".to_string(),
+            alias: None,
+            description: None,
+            enabled: true,
+            disabled_input_mode: Default::default(),
+            compiled: config,
+            state: Some(state),
+            source: serde_json::Value::Null,
+            state_reset: None,
+            tags: Vec::new(),
+            actions: simple_task_actions(&actions),
+            triggers: simple_task_triggers(&inputs),
+        };
+
+        let client = &app.admin_user.client;
+        let task_id = client.new_task(&task).await?.task_id;
+
+        let mut updated = task.clone();
+        updated.name = "renamed task".to_string();
+        updated.state = None;
+        updated.actions.remove("ask");
+        updated.actions.insert(
+            "notify".to_string(),
+            TaskActionInput {
+                name: "Send a notification".to_string(),
+                action_id: actions.echo.action_id.clone(),
+                account_id: None,
+                action_template: None,
+                compensate_with: None,
+            },
+        );
+        let schedule = PeriodicSchedule::Cron("0 0 12 * * *".to_string());
+        updated.triggers.get_mut("run_it").unwrap().periodic =
+            Some(vec![PeriodicTaskTriggerInput {
+                name: None,
+                schedule: schedule.clone(),
+                payload: json!({ "url": "https://example.com/" }),
+                enabled: true,
+            }]);
+
+        let plan: TaskPlan = client
+            .post(format!("tasks/{}/plan", task_id))
+            .json(&updated)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        assert!(plan.errors.is_empty(), "plan errors: {:?}", plan.errors);
+        assert_eq!(
+            plan.changes
+                .task
+                .iter()
+                .map(|c| c.path.as_str())
+                .collect::<Vec<_>>(),
+            vec!["/name"]
+        );
+        assert_eq!(plan.changes.actions_added, vec!["notify"]);
+        assert_eq!(plan.changes.actions_removed, vec!["ask"]);
+        assert!(plan.changes.triggers_added.is_empty());
+        assert_eq!(plan.changes.schedules.len(), 1);
+        assert_eq!(plan.changes.schedules[0].trigger, "run_it");
+        assert_eq!(plan.changes.schedules[0].added, vec![schedule]);
+
+        let unchanged = client.get_task(&task_id).await?;
+        assert_eq!(unchanged.name, "task", "planning does not change the task");
+
+        let token = plan.token.expect("valid plan has a token");
+        client
+            .post(format!("tasks/{}/apply", task_id))
+            .json(&json!({ "token": token }))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let result = client.get_task(&task_id).await?;
+        assert_eq!(result.name, "renamed task");
+        assert!(result.actions.0.contains_key("notify"));
+        assert!(!result.actions.0.contains_key("ask"));
+
+        let response = client
+            .post(format!("tasks/{}/apply", task_id))
+            .json(&json!({ "token": token }))
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            404,
+            "a plan can only be applied once"
+        );
+
+        Ok(())
+    })
+    .await
+}
+
+#[actix_rt::test]
+async fn invalid_and_stale_plans() {
+    run_app_test(|app| async move {
+        let (inputs, actions) = bootstrap_inputs_and_actions(&app).await;
+        let (config, state) = simple_state_machine();
+        let task = TaskInput {
+            name: "task".to_string(),
+            alias: None,
+            description: None,
+            enabled: true,
+            disabled_input_mode: Default::default(),
+            compiled: config,
+            state: Some(state),
+            source: serde_json::Value::Null,
+            state_reset: None,
+            tags: Vec::new(),
+            actions: simple_task_actions(&actions),
+            triggers: simple_task_triggers(&inputs),
+        };
+
+        let client = &app.admin_user.client;
+        let task_id = client.new_task(&task).await?.task_id;
+
+        let mut invalid = task.clone();
+        invalid.actions.get_mut("run").unwrap().compensate_with = Some("missing".to_string());
+        let plan: TaskPlan = client
+            .post(format!("tasks/{}/plan", task_id))
+            .json(&invalid)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert!(plan.token.is_none(), "invalid plan should not have a token");
+        assert_eq!(plan.errors.len(), 1, "errors: {:?}", plan.errors);
+
+        let mut updated = task.clone();
+        updated.description = Some("planned description".to_string());
+        let plan: TaskPlan = client
+            .post(format!("tasks/{}/plan", task_id))
+            .json(&updated)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let token = plan.token.expect("valid plan has a token");
+
+        let mut other_update = task.clone();
+        other_update.name = "changed elsewhere".to_string();
+        client.put_task(&task_id, &other_update).await?;
+
+        let response = client
+            .post(format!("tasks/{}/apply", task_id))
+            .json(&json!({ "token": token }))
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            409,
+            "applying a plan for an older version of the task"
+        );
+
+        let result = client.get_task(&task_id).await?;
+        assert_eq!(result.name, "changed elsewhere");
+        assert_eq!(result.description, None);
+
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,129 @@
+use ergo_api::routes::{tasks::TaskInput, trigger_tokens::NewTriggerToken};
+use ergo_auth::trigger_tokens::TriggerToken;
+use serde_json::json;
+
+use crate::{
+    common::run_app_test,
+    tasks::{
+        bootstrap_inputs_and_actions, simple_state_machine, simple_task_actions,
+        simple_task_triggers,
+    },
+};
+
+#[actix_rt::test]
+async fn trigger_tokens() {
+    run_app_test(|app| async move {
+        let (inputs, actions) = bootstrap_inputs_and_actions(&app).await;
+        let (machine, states) = simple_state_machine();
+        let admin = &app.admin_user.client;
+        let task_id = admin
+            .new_task(&TaskInput {
+                name: "webhook task".to_string(),
+                alias: None,
+                description: None,
+                enabled: true,
+                disabled_input_mode: Default::default(),
+                compiled: machine,
+                source: serde_json::Value::Null,
+                state: Some(states),
+                state_reset: None,
+                tags: Vec::new(),
+                actions: simple_task_actions(&actions),
+                triggers: simple_task_triggers(&inputs),
+            })
+            .await?
+            .task_id;
+
+        let tokens_url = format!("tasks/{}/trigger/run_it/tokens", task_id);
+        let run_url = format!("tasks/{}/trigger/run_it", task_id);
+        let payload = json!({ "url": "https://example.com/" });
+
+        let created: NewTriggerToken = admin
+            .post(&tokens_url)
+            .json(&json!({ "description": "GitHub webhook" }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(created.info.description.as_deref(), Some("GitHub webhook"));
+
+        app.client
+            .post(&run_url)
+            .query(&[("trigger_token", &created.token)])
+            .json(&payload)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let token_client = app.client.clone_with_api_key(created.token.clone());
+        let response = token_client.post(&run_url).json(&payload).send().await?;
+        assert_eq!(response.status().as_u16(), 202, "token as a bearer token");
+
+        let response = token_client
+            .post(format!("tasks/{}/trigger/prepare", task_id))
+            .json(&payload)
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            403,
+            "token only works for its own trigger"
+        );
+
+        let response = token_client.get("tasks").send().await?;
+        assert_eq!(
+            response.status().as_u16(),
+            401,
+            "token does not work for other endpoints"
+        );
+
+        let listed: Vec<TriggerToken> = admin
+            .get(&tokens_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].trigger_token_id, created.info.trigger_token_id);
+        assert!(listed[0].last_used.is_some());
+
+        let rotated: NewTriggerToken = admin
+            .post(format!(
+                "{}/{}/rotate",
+                tokens_url, created.info.trigger_token_id
+            ))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_ne!(rotated.token, created.token);
+
+        let response = token_client.post(&run_url).json(&payload).send().await?;
+        assert_eq!(response.status().as_u16(), 401, "rotated token is rejected");
+
+        let rotated_client = app.client.clone_with_api_key(rotated.token.clone());
+        rotated_client
+            .post(&run_url)
+            .json(&payload)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        admin
+            .delete(format!("{}/{}", tokens_url, created.info.trigger_token_id))
+            .send()
+            .await?
+            .error_for_status()?;
+        let response = rotated_client.post(&run_url).json(&payload).send().await?;
+        assert_eq!(response.status().as_u16(), 401, "revoked token is rejected");
+
+        let response = app.client.post(&run_url).json(&payload).send().await?;
+        assert_eq!(response.status().as_u16(), 401, "no token");
+
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,185 @@
+use ergo_auth::user_tokens::{create_token, UserTokenKind};
+use ergo_database::object_id::UserId;
+use serde_json::json;
+
+use crate::common::{run_app_test, TestApp};
+
+async fn login_status(app: &TestApp, email: &str, password: &str) -> u16 {
+    app.client
+        .post("login")
+        .json(&json!({ "email": email, "password": password }))
+        .send()
+        .await
+        .expect("Sending login request")
+        .status()
+        .as_u16()
+}
+
+async fn issue_token(app: &TestApp, user_id: &UserId, kind: UserTokenKind) -> String {
+    let mut conn = app.database.pool.acquire().await.unwrap();
+    create_token(&mut conn, user_id, kind).await.unwrap()
+}
+
+#[actix_rt::test]
+async fn signup_and_verify() {
+    run_app_test(|app| async move {
+        let email = format!("signup_{}@example.com", uuid::Uuid::new_v4());
+        let password = "a good long password";
+
+        let response = app
+            .client
+            .post("signup")
+            .json(&json!({ "name": "New User", "email": email, "password": "short" }))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 400, "weak password");
+
+        let response = app
+            .client
+            .post("signup")
+            .json(&json!({ "name": "New User", "email": email, "password": password }))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 202);
+
+        assert_eq!(
+            login_status(&app, &email, password).await,
+            401,
+            "login before verifying"
+        );
+
+        let user_id = sqlx::query_scalar!(
+            r##"SELECT user_id AS "user_id: UserId" FROM users WHERE email=$1"##,
+            email
+        )
+        .fetch_one(&app.database.pool)
+        .await?;
+
+        let response = app
+            .client
+            .post("signup/verify")
+            .json(&json!({ "token": "not a token" }))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 401, "bad token");
+
+        let token = issue_token(&app, &user_id, UserTokenKind::VerifyEmail).await;
+        let response = app
+            .client
+            .post("signup/verify")
+            .json(&json!({ "token": token }))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 200, "verify");
+
+        let response = app
+            .client
+            .post("signup/verify")
+            .json(&json!({ "token": token }))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 401, "token is single use");
+
+        assert_eq!(login_status(&app, &email, password).await, 200);
+
+        // Signing up again with the same email doesn't reveal that it's taken.
+        let response = app
+            .client
+            .post("signup")
+            .json(&json!({ "name": "Other", "email": email, "password": "another password" }))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 202);
+        assert_eq!(login_status(&app, &email, password).await, 200);
+
+        Ok(())
+    })
+    .await
+}
+
+#[actix_rt::test]
+async fn password_reset() {
+    run_app_test(|app| async move {
+        let user = app
+            .add_user_with_password(&app.org_id, "User", Some("original password"))
+            .await?;
+        let email = format!("test_user_{}@example.com", user.user_id);
+
+        let response = app
+            .client
+            .post("password_reset")
+            .json(&json!({ "email": "nobody@example.com" }))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 202, "unknown email");
+
+        let response = app
+            .client
+            .post("password_reset")
+            .json(&json!({ "email": email }))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 202);
+
+        let token = issue_token(&app, &user.user_id, UserTokenKind::PasswordReset).await;
+        let wrong_kind = issue_token(&app, &user.user_id, UserTokenKind::VerifyEmail).await;
+
+        let response = app
+            .client
+            .post("password_reset/confirm")
+            .json(&json!({ "token": wrong_kind, "password": "replaced password" }))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 401, "verification token");
+
+        let response = app
+            .client
+            .post("password_reset/confirm")
+            .json(&json!({ "token": token, "password": "replaced password" }))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 200);
+
+        assert_eq!(login_status(&app, &email, "original password").await, 401);
+        assert_eq!(login_status(&app, &email, "replaced password").await, 200);
+        Ok(())
+    })
+    .await
+}
+
+#[actix_rt::test]
+async fn change_password() {
+    run_app_test(|app| async move {
+        let user = app
+            .add_user_with_password(&app.org_id, "User", Some("original password"))
+            .await?;
+        let email = format!("test_user_{}@example.com", user.user_id);
+
+        let response = user
+            .client
+            .put("user/password")
+            .json(&json!({
+                "current_password": "wrong password",
+                "new_password": "changed password"
+            }))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 401, "wrong current password");
+
+        let response = user
+            .client
+            .put("user/password")
+            .json(&json!({
+                "current_password": "original password",
+                "new_password": "changed password"
+            }))
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 200);
+
+        assert_eq!(login_status(&app, &email, "original password").await, 401);
+        assert_eq!(login_status(&app, &email, "changed password").await, 200);
+        Ok(())
+    })
+    .await
+}
@@ -1,10 +1,104 @@
+use std::io::Write;
+
+use ergo_database::redaction::redact_str;
+use opentelemetry::{
+    sdk::{propagation::TraceContextPropagator, trace, Resource},
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
 use tracing::subscriber::set_global_default;
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_log::LogTracer;
-use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt, EnvFilter, Registry};
+use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt, reload, EnvFilter, Registry};
 use tracing_tree::HierarchicalLayer;
 
-pub fn configure<W>(name: impl Into<String>, sink: W)
+/// Create the OpenTelemetry tracer, if an OTLP endpoint is configured through
+/// `OTEL_EXPORTER_OTLP_ENDPOINT`.
+fn otlp_tracer(name: String) -> Option<opentelemetry::sdk::trace::Tracer> {
+    let endpoint = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok()?;
+
+    // Queue jobs carry the trace context in W3C format so that traces continue across processes.
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new("service.name", name)])),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)
+        .expect("Creating OTLP exporter");
+
+    Some(tracer)
+}
+
+/// Changes the log filter of a running process.
+#[derive(Clone)]
+pub struct LogFilterHandle(reload::Handle<EnvFilter, Registry>);
+
+impl LogFilterHandle {
+    /// Replace the log filter. This uses the same syntax as the `LOG` environment variable.
+    pub fn set_filter(&self, filter: &str) -> Result<(), anyhow::Error> {
+        let filter = EnvFilter::try_new(filter)?;
+        self.0.reload(filter)?;
+        Ok(())
+    }
+}
+
+/// Wraps a log sink so that secret values registered with [ergo_database::redaction] are
+/// replaced before each event is written. Output is buffered until the formatter is done with
+/// the event, so that a secret split across several writes is still caught.
+pub struct RedactingMakeWriter<M>(M);
+
+impl<M> RedactingMakeWriter<M> {
+    pub fn new(inner: M) -> Self {
+        RedactingMakeWriter(inner)
+    }
+}
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for RedactingMakeWriter<M> {
+    type Writer = RedactingWriter<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter {
+            inner: self.0.make_writer(),
+            buf: Vec::new(),
+        }
+    }
+}
+
+pub struct RedactingWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> Write for RedactingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.buf.is_empty() {
+            let text = String::from_utf8_lossy(&self.buf);
+            self.inner.write_all(redact_str(&text).as_bytes())?;
+            self.buf.clear();
+        }
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for RedactingWriter<W> {
+    fn drop(&mut self) {
+        self.flush().ok();
+    }
+}
+
+pub fn configure<W>(name: impl Into<String>, sink: W) -> LogFilterHandle
 where
     for<'writer> W: MakeWriter<'writer> + Send + Sync + 'static,
 {
@@ -15,15 +109,27 @@ where
         .expect("Failed to create logger");
 
     let env_filter = EnvFilter::try_from_env("LOG").unwrap_or_else(|_| EnvFilter::new("info"));
+    let (env_filter, filter_handle) = reload::Layer::new(env_filter);
+
+    let otel_layer =
+        otlp_tracer(name.into()).map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
 
     // let formatting_layer = BunyanFormattingLayer::new(name.into(), sink);
     let formatting_layer = HierarchicalLayer::new(2)
         .with_bracketed_fields(true)
         .with_targets(true)
-        .with_writer(sink);
+        .with_writer(RedactingMakeWriter::new(sink));
     let subscriber = Registry::default()
         .with(env_filter)
+        .with(otel_layer)
         .with(JsonStorageLayer)
         .with(formatting_layer);
     set_global_default(subscriber).expect("Setting subscriber");
+
+    LogFilterHandle(filter_handle)
+}
+
+/// Flush any spans that haven't been exported yet. Call this before the process exits.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
 }
@@ -0,0 +1,166 @@
+//! A database transaction that spans the whole request. A handler takes a [RequestTx] instead of
+//! acquiring a connection and beginning a transaction itself, and [TransactionMiddlewareFactory]
+//! commits the transaction when the handler returns a successful response, or rolls it back
+//! when it returns an error.
+//!
+//! The transaction begins the first time that the handler locks it, so handlers that return
+//! early don't hold a connection.
+
+use std::{
+    ops::{Deref, DerefMut},
+    rc::Rc,
+};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    FromRequest, HttpMessage, HttpRequest,
+};
+use ergo_database::PostgresPool;
+use futures::{
+    future::{ready, LocalBoxFuture, Ready},
+    lock::{Mutex, MutexGuard},
+    FutureExt,
+};
+use sqlx::{Postgres, Transaction};
+use tracing::{event, Level};
+
+use crate::{
+    error::{Error, Result},
+    web_app_server::AppStateData,
+};
+
+type TxSlot = Rc<Mutex<Option<Transaction<'static, Postgres>>>>;
+
+/// Extracts the request's transaction, on the web server's connection pool.
+pub struct RequestTx {
+    pool: PostgresPool,
+    slot: TxSlot,
+}
+
+impl RequestTx {
+    /// Get the transaction, beginning it if this is the first use.
+    pub async fn lock(&self) -> Result<RequestTxGuard<'_>> {
+        let mut guard = self.slot.lock().await;
+        if guard.is_none() {
+            *guard = Some(self.pool.begin().await?);
+        }
+
+        Ok(RequestTxGuard(guard))
+    }
+}
+
+impl FromRequest for RequestTx {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let pool = match req.app_data::<AppStateData>() {
+            Some(data) => data.pg.clone(),
+            None => {
+                return ready(Err(Error::StringError(
+                    "RequestTx used without app data".to_string(),
+                )))
+            }
+        };
+
+        // Every extractor in the request shares the same transaction.
+        let existing = req.extensions().get::<TxSlot>().cloned();
+        let slot = match existing {
+            Some(slot) => slot,
+            None => {
+                let slot = TxSlot::default();
+                req.extensions_mut().insert(slot.clone());
+                slot
+            }
+        };
+
+        ready(Ok(RequestTx { pool, slot }))
+    }
+}
+
+pub struct RequestTxGuard<'a>(MutexGuard<'a, Option<Transaction<'static, Postgres>>>);
+
+impl<'a> Deref for RequestTxGuard<'a> {
+    type Target = Transaction<'static, Postgres>;
+
+    fn deref(&self) -> &Self::Target {
+        // The slot is only emptied by the middleware, after the handler is done with it.
+        self.0
+            .as_ref()
+            .expect("Request transaction already finished")
+    }
+}
+
+impl<'a> DerefMut for RequestTxGuard<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0
+            .as_mut()
+            .expect("Request transaction already finished")
+    }
+}
+
+/// Commits or rolls back the transaction of each request that used a [RequestTx].
+pub struct TransactionMiddlewareFactory;
+
+impl<S, B> Transform<S, ServiceRequest> for TransactionMiddlewareFactory
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = TransactionMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(TransactionMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct TransactionMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for TransactionMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let srv = Rc::clone(&self.service);
+
+        async move {
+            // If the handler fails outright, the transaction is rolled back when the request
+            // is dropped.
+            let res = srv.call(req).await?;
+
+            let slot = res.request().extensions_mut().remove::<TxSlot>();
+            let tx = match slot {
+                Some(slot) => slot.lock().await.take(),
+                None => None,
+            };
+
+            let tx = match tx {
+                Some(tx) => tx,
+                None => return Ok(res),
+            };
+
+            let status = res.status();
+            if status.is_success() || status.is_redirection() {
+                tx.commit().await.map_err(Error::from)?;
+            } else if let Err(e) = tx.rollback().await {
+                event!(Level::ERROR, error=%e, "Failed to roll back request transaction");
+            }
+
+            Ok(res)
+        }
+        .boxed_local()
+    }
+}
@@ -2,7 +2,8 @@
 use crate::error::Error;
 
 use actix_web::{get, web, web::Data, App, HttpResponse, HttpServer, Responder, Scope};
-use ergo_database::PostgresPool;
+use ergo_database::{PostgresPool, RedisPool};
+use ergo_tasks::feature_flags::FeatureFlags;
 use serde::Serialize;
 use sqlx::query_as;
 use tracing_actix_web::TracingLogger;
@@ -17,14 +18,16 @@ pub struct AppState {
     pub pg: PostgresPool,
     /// Prevents queue conflicts in testing
     pub redis_key_prefix: Option<String>,
+    pub feature_flags: FeatureFlags,
 }
 
 pub type AppStateData = Data<AppState>;
 
-pub fn app_data(pg: PostgresPool, redis_key_prefix: Option<String>) -> AppStateData {
+pub fn app_data(pg: PostgresPool, redis_pool: RedisPool) -> AppStateData {
     Data::new(AppState {
+        feature_flags: FeatureFlags::new(pg.clone(), Some(redis_pool.clone())),
         pg,
-        redis_key_prefix,
+        redis_key_prefix: redis_pool.key_prefix().map(|p| p.to_string()),
     })
 }
 
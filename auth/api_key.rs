@@ -109,7 +109,9 @@ async fn handle_api_key(
 
     // This could be combined with the query above, but for simplicity we just keep it separate
     // for now.
-    let user = auth_data.get_user_info(&auth_key.user_id).await?;
+    let user = auth_data
+        .get_user_info(&auth_key.user_id, Some(&auth_key.org_id))
+        .await?;
 
     Ok(super::AuthenticationInfo::ApiKey {
         key: auth_key,
@@ -125,6 +127,11 @@ fn extract_api_key(req: &ServiceRequest) -> Option<String> {
 
     if let Ok(header) = Authorization::<Bearer>::parse(req) {
         let key = header.into_scheme();
+        // Trigger tokens are also sent as bearer tokens, but are checked by the trigger endpoint.
+        if crate::trigger_tokens::is_trigger_token(key.token()) {
+            return None;
+        }
+
         event!(Level::DEBUG, key=%key, "Got key from auth header");
         return Some(key.token().to_string());
     }
@@ -1,5 +1,6 @@
 use actix_web::{body::BoxBody, http::StatusCode, HttpResponse};
 use envoption::EnvOptionError;
+use ergo_problem::ProblemDetails;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -10,9 +11,15 @@ pub enum Error {
     #[error("Unauthorized")]
     AuthorizationError,
 
+    #[error("{0}")]
+    WeakPassword(String),
+
     #[error("Password hasher error: {0}")]
     PasswordHasherError(String),
 
+    #[error("Single sign-on failed: {0}")]
+    OidcError(String),
+
     #[error("Environment variable error: {0}")]
     EnvOptionError(String),
 
@@ -29,16 +36,35 @@ impl<T: std::error::Error> From<EnvOptionError<T>> for Error {
     }
 }
 
+impl ProblemDetails for Error {
+    fn code(&self) -> &'static str {
+        match self {
+            Error::AuthenticationError => "unauthenticated",
+            Error::AuthorizationError => "forbidden",
+            Error::WeakPassword(_) => "weak_password",
+            Error::PasswordHasherError(_) => "password_hasher_error",
+            Error::OidcError(_) => "sso_failed",
+            Error::EnvOptionError(_) => "config_error",
+            Error::SqlError(_) | Error::DatabaseError(_) => "database_error",
+        }
+    }
+
+    fn status(&self) -> u16 {
+        match self {
+            Error::AuthenticationError | Error::OidcError(_) => 401,
+            Error::AuthorizationError => 403,
+            Error::WeakPassword(_) => 400,
+            _ => 500,
+        }
+    }
+}
+
 impl actix_web::error::ResponseError for Error {
     fn error_response(&self) -> HttpResponse<BoxBody> {
-        HttpResponse::build(self.status_code()).body(self.to_string())
+        self.problem().to_response()
     }
 
     fn status_code(&self) -> StatusCode {
-        match self {
-            Error::AuthenticationError => StatusCode::UNAUTHORIZED,
-            Error::AuthorizationError => StatusCode::FORBIDDEN,
-            _ => StatusCode::INTERNAL_SERVER_ERROR,
-        }
+        StatusCode::from_u16(self.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
     }
 }
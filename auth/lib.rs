@@ -1,7 +1,11 @@
 pub mod api_key;
 pub mod error;
 pub mod middleware;
+pub mod oidc;
 pub mod password;
+pub mod session;
+pub mod trigger_tokens;
+pub mod user_tokens;
 
 pub use error::*;
 
@@ -14,17 +18,18 @@ use std::{
 pub use api_key::ApiKey;
 
 use actix_web::{dev::ServiceRequest, FromRequest, HttpMessage, HttpRequest};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use ergo_database::{
     object_id::{OrgId, RoleId, UserId},
     PostgresPool,
 };
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use sqlx::{query, PgConnection};
 use tracing::{event, field, instrument, Level};
 use uuid::Uuid;
 
-#[derive(Clone, Debug, Serialize, Deserialize, sqlx::Type)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema, sqlx::Type)]
 #[sqlx(type_name = "permission")]
 #[sqlx(rename_all = "snake_case")]
 pub enum PermissionType {
@@ -34,6 +39,9 @@ pub enum PermissionType {
     Read,
     #[serde(rename = "write")]
     Write,
+    /// See the payloads of a task's sensitive triggers in the logs.
+    #[serde(rename = "read_sensitive")]
+    ReadSensitive,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -63,6 +71,8 @@ pub struct RequestUser {
     pub email: String,
     pub user_entity_ids: UserEntityList,
     pub is_admin: bool,
+    /// The user's preferred locale, falling back to the org's locale.
+    pub locale: Option<String>,
 }
 
 /// Extracts authentication information for routes that optionally require it.
@@ -135,7 +145,8 @@ pub enum AuthenticationInfo {
         key: api_key::ApiKeyAuth,
         user: RequestUser,
     },
-    User(RequestUser),
+    /// A user logged in with a session cookie.
+    Session { session_id: Uuid, user: RequestUser },
 }
 
 pub type UserEntityList = smallvec::SmallVec<[Uuid; 4]>;
@@ -143,21 +154,36 @@ pub type UserEntityList = smallvec::SmallVec<[Uuid; 4]>;
 impl AuthenticationInfo {
     pub fn org_id(&self) -> &OrgId {
         match self {
-            Self::User(user) => &user.org_id,
+            Self::Session { user, .. } => &user.org_id,
             Self::ApiKey { key, .. } => &key.org_id,
         }
     }
 
     pub fn user_id(&self) -> &UserId {
         match self {
-            Self::User(user) => &user.user_id,
+            Self::Session { user, .. } => &user.user_id,
             Self::ApiKey { user, .. } => &user.user_id,
         }
     }
 
+    pub fn locale(&self) -> Option<&str> {
+        match self {
+            Self::Session { user, .. } => user.locale.as_deref(),
+            Self::ApiKey { user, .. } => user.locale.as_deref(),
+        }
+    }
+
+    /// The ID of the login session, if the request was authenticated with a session cookie.
+    pub fn session_id(&self) -> Option<&Uuid> {
+        match self {
+            Self::Session { session_id, .. } => Some(session_id),
+            Self::ApiKey { .. } => None,
+        }
+    }
+
     pub fn user_entity_ids(&self) -> UserEntityList {
         match self {
-            Self::User(user) => user.user_entity_ids.clone(),
+            Self::Session { user, .. } => user.user_entity_ids.clone(),
             Self::ApiKey { key, user } => match (key.inherits_user_permissions, user) {
                 (false, _) => {
                     let mut list = UserEntityList::new();
@@ -173,13 +199,15 @@ impl AuthenticationInfo {
         }
     }
 
-    pub fn expect_admin(&self) -> Result<(), Error> {
-        let is_admin = match self {
-            Self::User(user) => user.is_admin,
+    pub fn is_admin(&self) -> bool {
+        match self {
+            Self::Session { user, .. } => user.is_admin,
             Self::ApiKey { user, .. } => user.is_admin,
-        };
+        }
+    }
 
-        if is_admin {
+    pub fn expect_admin(&self) -> Result<(), Error> {
+        if self.is_admin() {
             Ok(())
         } else {
             Err(Error::AuthorizationError)
@@ -192,16 +220,51 @@ pub struct AuthData {
     pg: PostgresPool,
     /// Temporary method of implementing admin user
     admin_user: Option<UserId>,
+    session_lifetime: Duration,
+    signup_enabled: bool,
+    public_url: String,
+    http: reqwest::Client,
 }
 
 impl AuthData {
     pub fn new(pg_pool: PostgresPool) -> Result<AuthData, Error> {
+        let session_lifetime_days: i64 = envoption::with_default("SESSION_LIFETIME_DAYS", 30)?;
         Ok(AuthData {
             pg: pg_pool,
             admin_user: envoption::optional("ADMIN_USER_ID")?,
+            session_lifetime: Duration::days(session_lifetime_days),
+            signup_enabled: envoption::with_default("SIGNUP_ENABLED", false)?,
+            public_url: envoption::with_default(
+                "PUBLIC_URL",
+                String::from("http://localhost:6543"),
+            )?
+            .trim_end_matches('/')
+            .to_string(),
+            http: reqwest::Client::new(),
         })
     }
 
+    /// How long a new login session lasts before it expires.
+    pub fn session_lifetime(&self) -> Duration {
+        self.session_lifetime
+    }
+
+    /// True if anyone can create an account. Closed deployments leave this off and create
+    /// users with the admin CLI.
+    pub fn signup_enabled(&self) -> bool {
+        self.signup_enabled
+    }
+
+    /// The base URL of the web app, which OIDC providers redirect back to.
+    pub fn public_url(&self) -> &str {
+        &self.public_url
+    }
+
+    /// The client for requests to OIDC providers.
+    pub fn http_client(&self) -> &reqwest::Client {
+        &self.http
+    }
+
     // Authenticate via cookie or API key, depending on what's provided.
     pub async fn authenticate(
         &self,
@@ -214,41 +277,56 @@ impl AuthData {
 
         match identity {
             Some(identity) => {
-                // TODO This should be a session ID, not a user ID.
-                let user_id =
-                    UserId::from_str(&identity.id().map_err(|_| Error::AuthenticationError)?)
+                let session_id =
+                    Uuid::from_str(&identity.id().map_err(|_| Error::AuthenticationError)?)
                         .map_err(|_| Error::AuthenticationError)?;
 
-                let req_user = self.get_user_info(&user_id).await?;
-                Ok(Some(AuthenticationInfo::User(req_user)))
+                let mut conn = self.pg.acquire().await?;
+                let user_id = session::resolve_session(&mut conn, &session_id).await?;
+                let user =
+                    get_user_info(&mut conn, &user_id, None, self.admin_user.as_ref()).await?;
+                Ok(Some(AuthenticationInfo::Session { session_id, user }))
             }
             None => Ok(None),
         }
     }
 
     #[instrument(skip(self), fields(user))]
-    async fn get_user_info(&self, user_id: &UserId) -> Result<RequestUser, Error> {
+    async fn get_user_info(
+        &self,
+        user_id: &UserId,
+        org_id: Option<&OrgId>,
+    ) -> Result<RequestUser, Error> {
         let mut conn = self.pg.acquire().await?;
-        get_user_info(&mut conn, user_id, self.admin_user.as_ref()).await
+        get_user_info(&mut conn, user_id, org_id, self.admin_user.as_ref()).await
     }
 }
 
+/// Load a user and their roles in an org, which defaults to the user's active org. This fails
+/// if the user is not a member of the org, so that removing a member takes away their access
+/// right away.
 pub async fn get_user_info(
     tx: &mut PgConnection,
     user_id: &UserId,
+    org_id: Option<&OrgId>,
     admin_user: Option<&UserId>,
 ) -> Result<RequestUser, Error> {
     event!(Level::DEBUG, "Fetching user");
     query!(
-        r##"SELECT user_id as "user_id: UserId",
-            active_org_id AS "org_id: OrgId", users.name, email,
+        r##"SELECT users.user_id as "user_id: UserId",
+            orgs.org_id AS "org_id: OrgId", users.name, email,
+            COALESCE(users.locale, orgs.locale) AS locale,
             array_agg(role_id) FILTER(WHERE role_id IS NOT NULL) AS "roles: Vec<RoleId>"
         FROM users
-        JOIN orgs ON orgs.org_id = active_org_id
-        LEFT JOIN user_roles USING(user_id, org_id)
-        WHERE user_id = $1 AND NOT users.deleted AND NOT orgs.deleted
-        GROUP BY user_id"##,
-        &user_id.0
+        JOIN orgs ON orgs.org_id = COALESCE($2::uuid, users.active_org_id)
+        JOIN org_members ON org_members.org_id = orgs.org_id
+            AND org_members.user_id = users.user_id
+        LEFT JOIN user_roles ON user_roles.user_id = users.user_id
+            AND user_roles.org_id = orgs.org_id
+        WHERE users.user_id = $1 AND NOT users.deleted AND NOT orgs.deleted
+        GROUP BY users.user_id, orgs.org_id"##,
+        &user_id.0,
+        org_id.map(|o| o.0)
     )
     .fetch_optional(tx)
     .await?
@@ -272,6 +350,7 @@ pub async fn get_user_info(
             email: user.email,
             user_entity_ids,
             is_admin: admin_user.map(|u| u == user_id).unwrap_or(false),
+            locale: user.locale,
         };
 
         tracing::Span::current().record("user", &field::debug(&user));
@@ -319,6 +398,7 @@ mod tests {
                 is_admin: false,
                 email: "a@example.com".to_string(),
                 name: "Test User".to_string(),
+                locale: None,
             }
         }
 
@@ -348,8 +428,15 @@ mod tests {
             }
         }
 
+        fn session_id() -> Uuid {
+            Uuid::from_str("0b6a2b8e-5f4c-4d0e-9a83-2f8c1e0d7a61").unwrap()
+        }
+
         fn user_auth() -> AuthenticationInfo {
-            AuthenticationInfo::User(request_user())
+            AuthenticationInfo::Session {
+                session_id: session_id(),
+                user: request_user(),
+            }
         }
 
         #[test]
@@ -382,6 +469,12 @@ mod tests {
             assert_eq!(user_auth().org_id(), &org_id(), "user auth");
         }
 
+        #[test]
+        fn get_session_id() {
+            assert_eq!(user_key_with_inherit().session_id(), None, "api key");
+            assert_eq!(user_auth().session_id(), Some(&session_id()), "user auth");
+        }
+
         #[test]
         fn user_entity_ids() {
             let mut ids = user_key_with_inherit().user_entity_ids();
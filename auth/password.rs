@@ -5,6 +5,29 @@ use argon2::{
 };
 use uuid::Uuid;
 
+const MIN_PASSWORD_LENGTH: usize = 10;
+const MAX_PASSWORD_LENGTH: usize = 1024;
+
+/// Check that a password chosen by a user is acceptable.
+pub fn check_new_password(password: &str) -> Result<(), Error> {
+    let len = password.chars().count();
+    if len < MIN_PASSWORD_LENGTH {
+        return Err(Error::WeakPassword(format!(
+            "Password must be at least {} characters",
+            MIN_PASSWORD_LENGTH
+        )));
+    }
+
+    if len > MAX_PASSWORD_LENGTH {
+        return Err(Error::WeakPassword(format!(
+            "Password must be at most {} characters",
+            MAX_PASSWORD_LENGTH
+        )));
+    }
+
+    Ok(())
+}
+
 pub fn new_hash(password: &str) -> Result<String, Error> {
     let salt = uuid::Uuid::new_v4();
     hash_password(password, &salt)
@@ -55,6 +78,12 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn new_password_length() {
+        assert!(check_new_password("short").is_err());
+        assert!(check_new_password("long enough password").is_ok());
+    }
+
     #[test]
     fn unique_password_salt() {
         let p1 = new_hash("abc").unwrap();
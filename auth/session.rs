@@ -0,0 +1,136 @@
+//! Server-side login sessions. The identity cookie only holds the session ID, so a session
+//! can be expired or revoked without needing to invalidate the cookie itself.
+
+use chrono::{DateTime, Duration, Utc};
+use ergo_database::object_id::UserId;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::error::Error;
+
+/// How long to wait between updates of a session's `last_seen` time, to avoid writing to the
+/// database on every request.
+const LAST_SEEN_RESOLUTION_SECS: i64 = 300;
+
+#[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Session {
+    pub session_id: Uuid,
+    pub user_id: UserId,
+    pub created: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+    pub revoked: bool,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+/// Information about the device that created a session.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+pub async fn create_session(
+    tx: &mut PgConnection,
+    user_id: &UserId,
+    lifetime: Duration,
+    device: DeviceInfo,
+) -> Result<Session, Error> {
+    let session = sqlx::query_as!(
+        Session,
+        r##"INSERT INTO sessions (session_id, user_id, expires, user_agent, ip_address)
+        VALUES ($1, $2, now() + ($3::int * interval '1 second'), $4, $5)
+        RETURNING session_id, user_id AS "user_id: UserId", created, last_seen, expires,
+            revoked, user_agent, ip_address"##,
+        Uuid::new_v4(),
+        user_id.0,
+        lifetime.num_seconds() as i32,
+        device.user_agent,
+        device.ip_address
+    )
+    .fetch_one(tx)
+    .await?;
+
+    Ok(session)
+}
+
+/// Look up the user for an active session, updating the session's `last_seen` time if it is
+/// out of date. Returns [Error::AuthenticationError] if the session is unknown, expired, or
+/// revoked.
+pub async fn resolve_session(tx: &mut PgConnection, session_id: &Uuid) -> Result<UserId, Error> {
+    let session = sqlx::query!(
+        r##"SELECT user_id AS "user_id: UserId", last_seen
+        FROM sessions
+        WHERE session_id=$1 AND NOT revoked AND expires > now()"##,
+        session_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::AuthenticationError)?;
+
+    if Utc::now() - session.last_seen > Duration::seconds(LAST_SEEN_RESOLUTION_SECS) {
+        sqlx::query!(
+            "UPDATE sessions SET last_seen=now() WHERE session_id=$1",
+            session_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    Ok(session.user_id)
+}
+
+/// List the active sessions for a user.
+pub async fn list_sessions(tx: &mut PgConnection, user_id: &UserId) -> Result<Vec<Session>, Error> {
+    let sessions = sqlx::query_as!(
+        Session,
+        r##"SELECT session_id, user_id AS "user_id: UserId", created, last_seen, expires,
+            revoked, user_agent, ip_address
+        FROM sessions
+        WHERE user_id=$1 AND NOT revoked AND expires > now()
+        ORDER BY last_seen DESC"##,
+        user_id.0
+    )
+    .fetch_all(tx)
+    .await?;
+
+    Ok(sessions)
+}
+
+/// Revoke a session. Returns false if the session does not exist or belongs to another user.
+pub async fn revoke_session(
+    tx: &mut PgConnection,
+    user_id: &UserId,
+    session_id: &Uuid,
+) -> Result<bool, Error> {
+    let result = sqlx::query!(
+        "UPDATE sessions SET revoked=true WHERE session_id=$1 AND user_id=$2 AND NOT revoked",
+        session_id,
+        user_id.0
+    )
+    .execute(tx)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Revoke all of a user's sessions, except for `keep` if provided. Returns the number of
+/// sessions revoked.
+pub async fn revoke_user_sessions(
+    tx: &mut PgConnection,
+    user_id: &UserId,
+    keep: Option<&Uuid>,
+) -> Result<u64, Error> {
+    let result = sqlx::query!(
+        r##"UPDATE sessions SET revoked=true
+        WHERE user_id=$1 AND NOT revoked AND session_id IS DISTINCT FROM $2"##,
+        user_id.0,
+        keep.copied()
+    )
+    .execute(tx)
+    .await?;
+
+    Ok(result.rows_affected())
+}
@@ -0,0 +1,257 @@
+//! Tokens that can only send inputs to a single task trigger. These can be pasted into the
+//! webhook settings of other services without handing out an API key, which would allow access
+//! to the rest of the API.
+//!
+//! A token is sent as a bearer token or in the `trigger_token` query string parameter. Inputs
+//! sent with a token are enqueued as the user who created it, so the token stops working if that
+//! user loses permission to trigger the task. Only a hash of each token is stored.
+
+use actix_web::{http::header::Header, HttpRequest};
+use actix_web_httpauth::headers::authorization::{Authorization, Bearer};
+use chrono::{DateTime, Utc};
+use ergo_database::object_id::{OrgId, TaskTriggerId, UserId};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha3::Digest;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::error::Error;
+
+/// The prefix that distinguishes trigger tokens from API keys.
+pub const TOKEN_PREFIX: &str = "ert1.";
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, sqlx::FromRow)]
+pub struct TriggerToken {
+    pub trigger_token_id: Uuid,
+    /// The user whose permissions the token uses.
+    pub user_id: UserId,
+    pub description: Option<String>,
+    pub created: DateTime<Utc>,
+    /// When the token was last replaced with a new value.
+    pub rotated: Option<DateTime<Utc>>,
+    pub last_used: Option<DateTime<Utc>>,
+}
+
+/// A verified trigger token.
+#[derive(Clone, Debug)]
+pub struct TriggerTokenAuth {
+    pub trigger_token_id: Uuid,
+    pub task_trigger_id: TaskTriggerId,
+    pub org_id: OrgId,
+    pub user_id: UserId,
+}
+
+struct TriggerTokenData {
+    token: String,
+    hash: Vec<u8>,
+}
+
+impl TriggerTokenData {
+    fn new(trigger_token_id: &Uuid) -> TriggerTokenData {
+        let base64_id = base64::encode_config(trigger_token_id.as_bytes(), base64::URL_SAFE_NO_PAD);
+        let random = base64::encode_config(Uuid::new_v4().as_bytes(), base64::URL_SAFE_NO_PAD);
+        let token = format!("{}{}.{}", TOKEN_PREFIX, base64_id, random);
+        let hash = hash_token(&token);
+
+        TriggerTokenData { token, hash }
+    }
+}
+
+fn hash_token(token: &str) -> Vec<u8> {
+    let mut hasher = sha3::Sha3_512::default();
+    hasher.update(token.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+fn decode_token(token: &str) -> Result<(Uuid, Vec<u8>), Error> {
+    let id_portion = token
+        .strip_prefix(TOKEN_PREFIX)
+        .and_then(|t| t.split('.').next())
+        .ok_or(Error::AuthenticationError)?;
+    let id_bytes = base64::decode_config(id_portion.as_bytes(), base64::URL_SAFE_NO_PAD)
+        .map_err(|_| Error::AuthenticationError)?;
+    let token_id = Uuid::from_slice(&id_bytes).map_err(|_| Error::AuthenticationError)?;
+    Ok((token_id, hash_token(token)))
+}
+
+pub fn is_trigger_token(token: &str) -> bool {
+    token.starts_with(TOKEN_PREFIX)
+}
+
+#[derive(Deserialize)]
+struct TriggerTokenQuery {
+    trigger_token: String,
+}
+
+/// Find a trigger token in the query string or the Authorization header.
+pub fn extract_token(req: &HttpRequest) -> Option<String> {
+    if let Ok(query) = actix_web::web::Query::<TriggerTokenQuery>::from_query(req.query_string()) {
+        return Some(query.0.trigger_token);
+    }
+
+    Authorization::<Bearer>::parse(req)
+        .ok()
+        .map(|header| header.into_scheme().token().to_string())
+        .filter(|token| is_trigger_token(token))
+}
+
+/// Create a token for a trigger, and return it along with the secret value, which can not be
+/// retrieved again.
+pub async fn create_token(
+    tx: &mut PgConnection,
+    org_id: &OrgId,
+    task_trigger_id: &TaskTriggerId,
+    user_id: &UserId,
+    description: Option<String>,
+) -> Result<(TriggerToken, String), Error> {
+    let trigger_token_id = Uuid::new_v4();
+    let data = TriggerTokenData::new(&trigger_token_id);
+    let token = sqlx::query_as!(
+        TriggerToken,
+        r##"INSERT INTO trigger_tokens
+            (trigger_token_id, task_trigger_id, org_id, user_id, description, hash)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING trigger_token_id, user_id AS "user_id: UserId", description,
+            created, rotated, last_used"##,
+        trigger_token_id,
+        task_trigger_id.0,
+        org_id.0,
+        user_id.0,
+        description,
+        data.hash
+    )
+    .fetch_one(tx)
+    .await?;
+
+    Ok((token, data.token))
+}
+
+pub async fn list_tokens(
+    tx: &mut PgConnection,
+    task_trigger_id: &TaskTriggerId,
+) -> Result<Vec<TriggerToken>, Error> {
+    let tokens = sqlx::query_as!(
+        TriggerToken,
+        r##"SELECT trigger_token_id, user_id AS "user_id: UserId", description,
+            created, rotated, last_used
+        FROM trigger_tokens
+        WHERE task_trigger_id = $1
+        ORDER BY created"##,
+        task_trigger_id.0
+    )
+    .fetch_all(tx)
+    .await?;
+
+    Ok(tokens)
+}
+
+/// Replace a token's value, so that the old value stops working right away. Returns `None` if
+/// the token doesn't exist.
+pub async fn rotate_token(
+    tx: &mut PgConnection,
+    task_trigger_id: &TaskTriggerId,
+    trigger_token_id: &Uuid,
+) -> Result<Option<(TriggerToken, String)>, Error> {
+    let data = TriggerTokenData::new(trigger_token_id);
+    let token = sqlx::query_as!(
+        TriggerToken,
+        r##"UPDATE trigger_tokens SET hash = $3, rotated = now()
+        WHERE trigger_token_id = $1 AND task_trigger_id = $2
+        RETURNING trigger_token_id, user_id AS "user_id: UserId", description,
+            created, rotated, last_used"##,
+        trigger_token_id,
+        task_trigger_id.0,
+        data.hash
+    )
+    .fetch_optional(tx)
+    .await?;
+
+    Ok(token.map(|token| (token, data.token)))
+}
+
+/// Delete a token. Returns false if the token doesn't exist.
+pub async fn revoke_token(
+    tx: &mut PgConnection,
+    task_trigger_id: &TaskTriggerId,
+    trigger_token_id: &Uuid,
+) -> Result<bool, Error> {
+    let result = sqlx::query!(
+        "DELETE FROM trigger_tokens WHERE trigger_token_id = $1 AND task_trigger_id = $2",
+        trigger_token_id,
+        task_trigger_id.0
+    )
+    .execute(tx)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Check a token and record that it was used. Returns [Error::AuthenticationError] if the token
+/// is unknown or has been rotated or revoked.
+pub async fn verify_token(tx: &mut PgConnection, token: &str) -> Result<TriggerTokenAuth, Error> {
+    let (trigger_token_id, hash) = decode_token(token)?;
+
+    let auth = sqlx::query_as!(
+        TriggerTokenAuth,
+        r##"UPDATE trigger_tokens SET last_used = now()
+        WHERE trigger_token_id = $1 AND hash = $2
+        RETURNING trigger_token_id,
+            task_trigger_id AS "task_trigger_id: TaskTriggerId",
+            org_id AS "org_id: OrgId",
+            user_id AS "user_id: UserId""##,
+        trigger_token_id,
+        hash
+    )
+    .fetch_optional(tx)
+    .await?
+    .ok_or(Error::AuthenticationError)?;
+
+    Ok(auth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode() {
+        let id = Uuid::new_v4();
+        let data = TriggerTokenData::new(&id);
+        assert!(is_trigger_token(&data.token));
+
+        let (token_id, hash) = decode_token(&data.token).unwrap();
+        assert_eq!(token_id, id);
+        assert_eq!(hash, data.hash);
+
+        let without_prefix = data.token.trim_start_matches(TOKEN_PREFIX);
+        assert!(decode_token(without_prefix).is_err());
+        assert!(decode_token("er1.abc.def").is_err());
+    }
+
+    #[test]
+    fn token_from_request() {
+        let id = Uuid::new_v4();
+        let data = TriggerTokenData::new(&id);
+
+        let req = actix_web::test::TestRequest::post()
+            .uri(&format!(
+                "http://localhost/api/tasks/a/trigger/b?trigger_token={}",
+                data.token
+            ))
+            .to_http_request();
+        assert_eq!(extract_token(&req), Some(data.token.clone()));
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("http://localhost/api/tasks/a/trigger/b")
+            .insert_header(("authorization", format!("Bearer {}", data.token)))
+            .to_http_request();
+        assert_eq!(extract_token(&req), Some(data.token));
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("http://localhost/api/tasks/a/trigger/b")
+            .insert_header(("authorization", "Bearer er1.an-api-key"))
+            .to_http_request();
+        assert_eq!(extract_token(&req), None, "API keys are not trigger tokens");
+    }
+}
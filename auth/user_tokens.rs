@@ -0,0 +1,138 @@
+//! Single-use tokens that are emailed to a user, to verify their email address or to reset
+//! their password. Only a hash of each token is stored, and a token stops working once it is
+//! used, once it expires, or once a newer token of the same kind is issued.
+
+use chrono::Duration;
+use ergo_database::object_id::UserId;
+use serde::{Deserialize, Serialize};
+use sha3::Digest;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::error::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "user_token_kind", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum UserTokenKind {
+    VerifyEmail,
+    PasswordReset,
+}
+
+impl UserTokenKind {
+    /// How long a token of this kind is valid.
+    pub fn lifetime(&self) -> Duration {
+        match self {
+            Self::VerifyEmail => Duration::days(2),
+            Self::PasswordReset => Duration::hours(1),
+        }
+    }
+}
+
+struct UserTokenData {
+    token_id: Uuid,
+    token: String,
+    hash: Vec<u8>,
+}
+
+impl UserTokenData {
+    fn new() -> UserTokenData {
+        let token_id = Uuid::new_v4();
+        let base64_id = base64::encode_config(token_id.as_bytes(), base64::URL_SAFE_NO_PAD);
+        let random = base64::encode_config(Uuid::new_v4().as_bytes(), base64::URL_SAFE_NO_PAD);
+        let token = format!("{}.{}", base64_id, random);
+        let hash = hash_token(&token);
+
+        UserTokenData {
+            token_id,
+            token,
+            hash,
+        }
+    }
+}
+
+fn hash_token(token: &str) -> Vec<u8> {
+    let mut hasher = sha3::Sha3_512::default();
+    hasher.update(token.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+fn decode_token(token: &str) -> Result<(Uuid, Vec<u8>), Error> {
+    let id_portion = token.split('.').next().ok_or(Error::AuthenticationError)?;
+    let id_bytes = base64::decode_config(id_portion.as_bytes(), base64::URL_SAFE_NO_PAD)
+        .map_err(|_| Error::AuthenticationError)?;
+    let token_id = Uuid::from_slice(&id_bytes).map_err(|_| Error::AuthenticationError)?;
+    Ok((token_id, hash_token(token)))
+}
+
+/// Create a token for the user, and return the token to send to them. Any earlier unused tokens
+/// of the same kind stop working.
+pub async fn create_token(
+    tx: &mut PgConnection,
+    user_id: &UserId,
+    kind: UserTokenKind,
+) -> Result<String, Error> {
+    sqlx::query!(
+        "UPDATE user_tokens SET used=true WHERE user_id=$1 AND kind=$2 AND NOT used",
+        user_id.0,
+        kind as _
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let data = UserTokenData::new();
+    sqlx::query!(
+        r##"INSERT INTO user_tokens (token_id, user_id, kind, hash, expires)
+        VALUES ($1, $2, $3, $4, now() + ($5::int * interval '1 second'))"##,
+        data.token_id,
+        user_id.0,
+        kind as _,
+        data.hash,
+        kind.lifetime().num_seconds() as i32
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(data.token)
+}
+
+/// Check a token and mark it as used, returning the user that it was issued to. Returns
+/// [Error::AuthenticationError] if the token is unknown, expired, already used, or of a
+/// different kind.
+pub async fn consume_token(
+    tx: &mut PgConnection,
+    kind: UserTokenKind,
+    token: &str,
+) -> Result<UserId, Error> {
+    let (token_id, hash) = decode_token(token)?;
+
+    let user_id = sqlx::query_scalar!(
+        r##"UPDATE user_tokens SET used=true
+        WHERE token_id=$1 AND kind=$2 AND hash=$3 AND NOT used AND expires > now()
+        RETURNING user_id AS "user_id: UserId""##,
+        token_id,
+        kind as _,
+        hash
+    )
+    .fetch_optional(tx)
+    .await?
+    .ok_or(Error::AuthenticationError)?;
+
+    Ok(user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode() {
+        let data = UserTokenData::new();
+        let (token_id, hash) = decode_token(&data.token).unwrap();
+        assert_eq!(token_id, data.token_id);
+        assert_eq!(hash, data.hash);
+
+        assert!(decode_token("not a token").is_err());
+        assert!(decode_token("").is_err());
+    }
+}
@@ -1,3 +1,23 @@
 INSERT INTO account_types (account_type_id, name, description, fields) VALUES
   ('discord_incoming_webhook', 'Discord Incoming Webhook', null, ARRAY['webhook_url'])
 ON CONFLICT DO NOTHING;
+
+INSERT INTO account_types (account_type_id, name, description, fields) VALUES
+  ('imap', 'IMAP Mailbox', 'A mailbox to read email from', ARRAY['host', 'port', 'username', 'password', 'tls'])
+ON CONFLICT DO NOTHING;
+
+INSERT INTO account_types (account_type_id, name, description, fields) VALUES
+  ('mqtt', 'MQTT Broker', 'An MQTT broker to subscribe and publish to', ARRAY['host', 'port', 'username', 'password', 'tls'])
+ON CONFLICT DO NOTHING;
+
+INSERT INTO account_types (account_type_id, name, description, fields) VALUES
+  ('discord_bot', 'Discord Bot', 'A Discord bot that receives messages and slash commands', ARRAY['token'])
+ON CONFLICT DO NOTHING;
+
+INSERT INTO account_types (account_type_id, name, description, fields) VALUES
+  ('telegram_bot', 'Telegram Bot', 'A Telegram bot that receives messages and commands', ARRAY['token'])
+ON CONFLICT DO NOTHING;
+
+INSERT INTO account_types (account_type_id, name, description, fields) VALUES
+  ('google_oauth', 'Google OAuth', 'OAuth credentials for Google APIs such as Sheets', ARRAY['access_token', 'refresh_token', 'client_id', 'client_secret'])
+ON CONFLICT DO NOTHING;
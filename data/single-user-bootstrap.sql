@@ -5,3 +5,7 @@ INSERT INTO orgs (org_id, name) VALUES
 INSERT INTO users (user_id, active_org_id, name, email, password_hash) VALUES
   (objectid_to_uuid('{{USER_ID}}'), objectid_to_uuid('{{ORG_ID}}'), '{{USER_NAME}}', '{{USER_EMAIL}}', '{{PASSWORD_HASH}}')
   ON CONFLICT DO NOTHING;
+
+INSERT INTO org_members (org_id, user_id) VALUES
+  (objectid_to_uuid('{{ORG_ID}}'), objectid_to_uuid('{{USER_ID}}'))
+  ON CONFLICT DO NOTHING;
@@ -0,0 +1,341 @@
+//! Envelope encryption for secrets stored in JSON columns, such as `accounts.fields`.
+//!
+//! Each value is encrypted with its own random data key, and the data key is encrypted with the
+//! master key from `ACCOUNT_FIELDS_KEY`. The stored value looks like
+//! `{"$envelope": {"v": 1, "kid": ..., "key": ..., "data": ...}}`, where `kid` identifies the
+//! master key. Values that aren't in an envelope are returned unchanged when decrypting, so
+//! plaintext values from before encryption was enabled keep working until they are encrypted.
+//!
+//! To rotate the master key, move the current key into `ACCOUNT_FIELDS_OLD_KEYS`, set the new
+//! key in `ACCOUNT_FIELDS_KEY`, and run `ergo admin encrypt-account-fields` to rewrap the data
+//! keys.
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use fxhash::FxHashMap;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::Error;
+
+/// The key of the object that wraps an encrypted value.
+pub const ENVELOPE_KEY: &str = "$envelope";
+
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct Envelope {
+    v: u32,
+    /// The ID of the master key that encrypted `key`.
+    kid: String,
+    /// The data key, encrypted with the master key, prefixed by its nonce.
+    key: String,
+    /// The value, encrypted with the data key, prefixed by its nonce.
+    data: String,
+}
+
+fn encryption_error(e: impl std::fmt::Display) -> Error {
+    Error::EncryptionError(e.to_string())
+}
+
+fn seal(cipher: &Aes256Gcm, plaintext: &[u8]) -> Result<String, Error> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let mut output = nonce.to_vec();
+    output.extend(
+        cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(encryption_error)?,
+    );
+    Ok(base64::encode(output))
+}
+
+fn open(cipher: &Aes256Gcm, sealed: &str) -> Result<Vec<u8>, Error> {
+    let sealed = base64::decode(sealed).map_err(encryption_error)?;
+    if sealed.len() < NONCE_LEN {
+        return Err(Error::EncryptionError(
+            "Encrypted value is too short".into(),
+        ));
+    }
+
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(encryption_error)
+}
+
+/// A master key, and the ID that envelopes use to refer to it.
+#[derive(Clone)]
+struct MasterKey {
+    id: String,
+    cipher: Aes256Gcm,
+}
+
+impl MasterKey {
+    fn from_base64(encoded: &str) -> Result<MasterKey, Error> {
+        let bytes = base64::decode(encoded.trim()).map_err(encryption_error)?;
+        if bytes.len() != 32 {
+            return Err(Error::ConfigError(
+                "Account field encryption keys must be 32 bytes".to_string(),
+            ));
+        }
+
+        let id = hex::encode(&Sha256::digest(&bytes)[0..8]);
+        Ok(MasterKey {
+            id,
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&bytes)),
+        })
+    }
+}
+
+/// The master keys for encrypting and decrypting fields.
+#[derive(Clone, Default)]
+pub struct FieldKeys {
+    current: Option<MasterKey>,
+    keys: FxHashMap<String, MasterKey>,
+}
+
+impl std::fmt::Debug for FieldKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FieldKeys")
+            .field("current", &self.current.as_ref().map(|k| &k.id))
+            .field("keys", &self.keys.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl FieldKeys {
+    /// Create the keys from a base64 current key and a list of old base64 keys, which are only
+    /// used for decryption.
+    pub fn new(current: Option<&str>, old: &[&str]) -> Result<FieldKeys, Error> {
+        let current = current.map(MasterKey::from_base64).transpose()?;
+        let mut keys = old
+            .iter()
+            .map(|k| MasterKey::from_base64(k).map(|key| (key.id.clone(), key)))
+            .collect::<Result<FxHashMap<_, _>, _>>()?;
+
+        if let Some(key) = current.as_ref() {
+            keys.insert(key.id.clone(), key.clone());
+        }
+
+        Ok(FieldKeys { current, keys })
+    }
+
+    /// Read the keys from `ACCOUNT_FIELDS_KEY` and the comma-separated `ACCOUNT_FIELDS_OLD_KEYS`.
+    pub fn from_env() -> Result<FieldKeys, Error> {
+        let current = std::env::var("ACCOUNT_FIELDS_KEY")
+            .ok()
+            .filter(|k| !k.is_empty());
+        let old = std::env::var("ACCOUNT_FIELDS_OLD_KEYS").unwrap_or_default();
+        let old = old
+            .split(',')
+            .map(|k| k.trim())
+            .filter(|k| !k.is_empty())
+            .collect::<Vec<_>>();
+
+        FieldKeys::new(current.as_deref(), &old)
+    }
+
+    /// Returns true if new values will be encrypted.
+    pub fn enabled(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Encrypt a value with the current key. Without a current key, the value is returned
+    /// unchanged.
+    pub fn encrypt(&self, value: &serde_json::Value) -> Result<serde_json::Value, Error> {
+        let master = match self.current.as_ref() {
+            Some(k) => k,
+            None => return Ok(value.clone()),
+        };
+
+        let data_key = Aes256Gcm::generate_key(&mut OsRng);
+        let data_cipher = Aes256Gcm::new(&data_key);
+        let plaintext = serde_json::to_vec(value).map_err(encryption_error)?;
+
+        let envelope = Envelope {
+            v: 1,
+            kid: master.id.clone(),
+            key: seal(&master.cipher, data_key.as_slice())?,
+            data: seal(&data_cipher, &plaintext)?,
+        };
+
+        Ok(serde_json::json!({ ENVELOPE_KEY: envelope }))
+    }
+
+    /// Decrypt a value. Values that aren't encrypted are returned unchanged.
+    pub fn decrypt(&self, value: serde_json::Value) -> Result<serde_json::Value, Error> {
+        let envelope = match envelope(&value) {
+            Some(e) => e?,
+            None => return Ok(value),
+        };
+
+        let master = self.keys.get(&envelope.kid).ok_or_else(|| {
+            Error::EncryptionError(format!("No key found with ID {}", envelope.kid))
+        })?;
+
+        let data_key = open(&master.cipher, &envelope.key)?;
+        if data_key.len() != 32 {
+            return Err(Error::EncryptionError("Invalid data key".to_string()));
+        }
+
+        let data_cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&data_key));
+        let plaintext = open(&data_cipher, &envelope.data)?;
+        serde_json::from_slice(&plaintext).map_err(encryption_error)
+    }
+
+    /// Returns true if the value isn't encrypted with the current key.
+    pub fn needs_encryption(&self, value: &serde_json::Value) -> bool {
+        let current = match self.current.as_ref() {
+            Some(k) => k,
+            None => return false,
+        };
+
+        match envelope(value) {
+            Some(Ok(e)) => e.kid != current.id,
+            Some(Err(_)) => false,
+            None => !value.is_null(),
+        }
+    }
+}
+
+fn envelope(value: &serde_json::Value) -> Option<Result<Envelope, Error>> {
+    let obj = value.as_object()?;
+    if obj.len() != 1 {
+        return None;
+    }
+
+    obj.get(ENVELOPE_KEY)
+        .map(|e| serde_json::from_value(e.clone()).map_err(encryption_error))
+}
+
+/// Returns true if the value is encrypted.
+pub fn is_encrypted(value: &serde_json::Value) -> bool {
+    envelope(value).is_some()
+}
+
+fn data_key_cipher(key: &[u8]) -> Result<Aes256Gcm, Error> {
+    if key.len() != 32 {
+        return Err(Error::EncryptionError("Invalid data key".to_string()));
+    }
+
+    Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)))
+}
+
+/// Encrypt a value with a 32 byte data key that is managed elsewhere. The result is base64, and
+/// includes the nonce.
+pub fn seal_value(key: &[u8], value: &serde_json::Value) -> Result<String, Error> {
+    let plaintext = serde_json::to_vec(value).map_err(encryption_error)?;
+    seal(&data_key_cipher(key)?, &plaintext)
+}
+
+/// Decrypt a value encrypted by [seal_value].
+pub fn open_value(key: &[u8], sealed: &str) -> Result<serde_json::Value, Error> {
+    let plaintext = open(&data_key_cipher(key)?, sealed)?;
+    serde_json::from_slice(&plaintext).map_err(encryption_error)
+}
+
+lazy_static! {
+    static ref ACCOUNT_FIELD_KEYS: Result<FieldKeys, String> =
+        FieldKeys::from_env().map_err(|e| e.to_string());
+}
+
+/// The keys for `accounts.fields`, read from the environment.
+pub fn account_field_keys() -> Result<&'static FieldKeys, Error> {
+    ACCOUNT_FIELD_KEYS
+        .as_ref()
+        .map_err(|e| Error::ConfigError(e.clone()))
+}
+
+/// Decrypt the fields of an account, if they are encrypted.
+pub fn decrypt_account_fields(value: serde_json::Value) -> Result<serde_json::Value, Error> {
+    if !is_encrypted(&value) {
+        return Ok(value);
+    }
+
+    account_field_keys()?.decrypt(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const KEY_1: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+    const KEY_2: &str = "ZmVkY2JhOTg3NjU0MzIxMGZlZGNiYTk4NzY1NDMyMTA=";
+
+    #[test]
+    fn round_trip() {
+        let keys = FieldKeys::new(Some(KEY_1), &[]).unwrap();
+        let fields = json!({ "username": "me", "password": "hunter2" });
+
+        let encrypted = keys.encrypt(&fields).unwrap();
+        assert!(is_encrypted(&encrypted));
+        assert!(!encrypted.to_string().contains("hunter2"));
+        assert!(!keys.needs_encryption(&encrypted));
+
+        assert_eq!(keys.decrypt(encrypted).unwrap(), fields);
+    }
+
+    #[test]
+    fn plaintext_passes_through() {
+        let keys = FieldKeys::new(Some(KEY_1), &[]).unwrap();
+        let fields = json!({ "webhook_url": "https://example.com" });
+
+        assert!(keys.needs_encryption(&fields));
+        assert_eq!(keys.decrypt(fields.clone()).unwrap(), fields);
+    }
+
+    #[test]
+    fn disabled() {
+        let keys = FieldKeys::new(None, &[]).unwrap();
+        let fields = json!({ "password": "hunter2" });
+
+        assert!(!keys.enabled());
+        assert_eq!(keys.encrypt(&fields).unwrap(), fields);
+        assert!(!keys.needs_encryption(&fields));
+    }
+
+    #[test]
+    fn rotation() {
+        let old_keys = FieldKeys::new(Some(KEY_1), &[]).unwrap();
+        let fields = json!({ "password": "hunter2" });
+        let encrypted = old_keys.encrypt(&fields).unwrap();
+
+        let new_keys = FieldKeys::new(Some(KEY_2), &[KEY_1]).unwrap();
+        assert!(new_keys.needs_encryption(&encrypted));
+
+        let decrypted = new_keys.decrypt(encrypted).unwrap();
+        assert_eq!(decrypted, fields);
+
+        let rewrapped = new_keys.encrypt(&decrypted).unwrap();
+        assert!(!new_keys.needs_encryption(&rewrapped));
+
+        let without_old = FieldKeys::new(Some(KEY_2), &[]).unwrap();
+        assert!(without_old
+            .decrypt(old_keys.encrypt(&fields).unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn bad_key_length() {
+        assert!(FieldKeys::new(Some("c2hvcnQ="), &[]).is_err());
+    }
+
+    #[test]
+    fn sealed_values() {
+        let key = base64::decode(KEY_1).unwrap();
+        let value = json!({ "card": "4111111111111111" });
+
+        let sealed = seal_value(&key, &value).unwrap();
+        assert!(!sealed.contains("4111"));
+        assert_eq!(open_value(&key, &sealed).unwrap(), value);
+
+        let other_key = base64::decode(KEY_2).unwrap();
+        assert!(open_value(&other_key, &sealed).is_err());
+        assert!(seal_value(&key[0..16], &value).is_err());
+    }
+}
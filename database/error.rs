@@ -18,6 +18,13 @@ pub enum Error {
     #[error("Database Configuration Error: {0}")]
     ConfigError(String),
 
+    #[error("Migration error: {0}")]
+    #[cfg(not(target_family = "wasm"))]
+    MigrateError(#[from] sqlx::migrate::MigrateError),
+
+    #[error("Encryption error: {0}")]
+    EncryptionError(String),
+
     #[error("Connection pool closed")]
     PoolClosed,
 
@@ -1,7 +1,13 @@
+#[cfg(not(target_family = "wasm"))]
+pub mod encryption;
 mod error;
 #[cfg(not(target_family = "wasm"))]
+pub mod migrations;
+#[cfg(not(target_family = "wasm"))]
 mod pool;
 #[cfg(not(target_family = "wasm"))]
+pub mod redaction;
+#[cfg(not(target_family = "wasm"))]
 pub mod redis;
 #[cfg(not(target_family = "wasm"))]
 pub mod transaction;
@@ -0,0 +1,54 @@
+//! Apply the schema migrations from the `migrations` directory, which are embedded in the
+//! binary. Migrating holds a session-level advisory lock, so when several replicas start at once,
+//! one applies the migrations and the others wait for it and then find nothing left to do.
+//!
+//! Migrations need a role that owns the schema, so they run on their own connection instead of
+//! through the web and backend pools.
+
+use sqlx::{migrate::Migrator, Connection, PgConnection};
+use tracing::{event, Level};
+
+use crate::error::Error;
+
+pub static MIGRATOR: Migrator = sqlx::migrate!("../migrations");
+
+/// The advisory lock key held while migrating.
+const MIGRATION_LOCK_KEY: i64 = 2_318_904_571_202;
+
+/// Connect to `database_url` and apply any pending migrations.
+pub async fn run_migrations(database_url: &str) -> Result<(), Error> {
+    let mut conn = PgConnection::connect(database_url).await?;
+
+    let acquired: bool = sqlx::query_scalar("SELECT pg_try_advisory_lock($1)")
+        .bind(MIGRATION_LOCK_KEY)
+        .fetch_one(&mut conn)
+        .await?;
+    if !acquired {
+        event!(
+            Level::INFO,
+            "Waiting for another process to finish migrating"
+        );
+        sqlx::query("SELECT pg_advisory_lock($1)")
+            .bind(MIGRATION_LOCK_KEY)
+            .execute(&mut conn)
+            .await?;
+    }
+
+    // sqlx takes its own advisory lock too. Advisory locks are reentrant within a session, so
+    // that doesn't conflict with the lock held here.
+    let result = MIGRATOR.run(&mut conn).await;
+
+    sqlx::query("SELECT pg_advisory_unlock($1)")
+        .bind(MIGRATION_LOCK_KEY)
+        .execute(&mut conn)
+        .await?;
+    conn.close().await?;
+
+    result?;
+    event!(
+        Level::INFO,
+        version = MIGRATOR.iter().last().map(|m| m.version),
+        "Database schema is up to date"
+    );
+    Ok(())
+}
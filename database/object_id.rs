@@ -34,6 +34,10 @@ pub type TaskTemplateId = ObjectId<10>;
 pub type NotifyEndpointId = ObjectId<11>;
 pub type NotifyListenerId = ObjectId<12>;
 pub type PeriodicTriggerId = ObjectId<13>;
+pub type EmailMailboxId = ObjectId<14>;
+pub type MqttSubscriptionId = ObjectId<15>;
+pub type ChatSubscriptionId = ObjectId<16>;
+pub type FileWatchId = ObjectId<17>;
 
 impl<const PREFIX: usize> ObjectId<PREFIX> {
     /// Once const generics supports strings, this can go away, but for now we
@@ -55,6 +59,10 @@ impl<const PREFIX: usize> ObjectId<PREFIX> {
             11 => "ne",
             12 => "nl",
             13 => "prt",
+            14 => "mbx",
+            15 => "mqs",
+            16 => "chs",
+            17 => "fw",
             _ => "",
         }
     }
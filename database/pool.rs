@@ -1,10 +1,95 @@
 use itertools::Itertools;
-use std::env;
+use std::{env, time::Duration};
 
 use crate::error::Error;
 
 pub type PostgresPool = sqlx::PgPool;
 
+/// Connection and session settings for the Postgres pools.
+#[derive(Clone, Debug)]
+pub struct PostgresPoolOptions {
+    pub max_connections: u32,
+    /// Cancel any statement that runs longer than this.
+    pub statement_timeout: Option<Duration>,
+    /// End any session that stays idle inside a transaction for longer than this, so that an
+    /// abandoned transaction doesn't hold its locks indefinitely.
+    pub idle_in_transaction_timeout: Option<Duration>,
+    /// Retire connections once they have been open this long, even if they're healthy. This
+    /// ensures that connections pick up changed passwords and server settings.
+    pub max_lifetime: Option<Duration>,
+    /// Close connections that have been idle in the pool for this long.
+    pub idle_timeout: Option<Duration>,
+    /// Check that a connection still works before handing it out.
+    pub test_before_acquire: bool,
+}
+
+impl Default for PostgresPoolOptions {
+    fn default() -> Self {
+        PostgresPoolOptions {
+            max_connections: 16,
+            statement_timeout: None,
+            idle_in_transaction_timeout: None,
+            max_lifetime: Some(Duration::from_secs(3600 * 12)),
+            idle_timeout: Some(Duration::from_secs(600)),
+            test_before_acquire: true,
+        }
+    }
+}
+
+impl PostgresPoolOptions {
+    pub fn from_env() -> Result<Self, Error> {
+        let defaults = Self::default();
+
+        let millis = |name: &str| -> Result<Option<Duration>, Error> {
+            let value =
+                envoption::optional::<u64>(name).map_err(|e| Error::ConfigError(e.to_string()))?;
+            Ok(value.map(Duration::from_millis))
+        };
+
+        // Setting these to 0 disables the limit.
+        let secs = |name: &str, default: Option<Duration>| -> Result<Option<Duration>, Error> {
+            let value =
+                envoption::optional::<u64>(name).map_err(|e| Error::ConfigError(e.to_string()))?;
+            Ok(match value {
+                Some(0) => None,
+                Some(s) => Some(Duration::from_secs(s)),
+                None => default,
+            })
+        };
+
+        Ok(PostgresPoolOptions {
+            max_connections: envoption::with_default(
+                "DATABASE_MAX_CONNECTIONS",
+                defaults.max_connections,
+            )
+            .map_err(|e| Error::ConfigError(e.to_string()))?,
+            statement_timeout: millis("DATABASE_STATEMENT_TIMEOUT_MS")?,
+            idle_in_transaction_timeout: millis("DATABASE_IDLE_IN_TRANSACTION_TIMEOUT_MS")?,
+            max_lifetime: secs("DATABASE_MAX_LIFETIME_SECS", defaults.max_lifetime)?,
+            idle_timeout: secs("DATABASE_IDLE_TIMEOUT_SECS", defaults.idle_timeout)?,
+            test_before_acquire: envoption::with_default(
+                "DATABASE_TEST_BEFORE_ACQUIRE",
+                defaults.test_before_acquire,
+            )
+            .map_err(|e| Error::ConfigError(e.to_string()))?,
+        })
+    }
+
+    /// The Postgres settings to apply to each new session.
+    pub fn session_settings(&self) -> Vec<(&'static str, String)> {
+        [
+            ("statement_timeout", self.statement_timeout),
+            (
+                "idle_in_transaction_session_timeout",
+                self.idle_in_transaction_timeout,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, timeout)| Some((name, timeout?.as_millis().to_string())))
+        .collect()
+    }
+}
+
 pub struct PostgresAuth {
     pub username: String,
     pub password: String,
@@ -46,7 +131,22 @@ pub fn sql_insert_parameters<const NCOL: usize>(num_rows: usize) -> String {
 
 #[cfg(test)]
 mod tests {
-    use super::sql_insert_parameters as sip;
+    use std::time::Duration;
+
+    use super::{sql_insert_parameters as sip, PostgresPoolOptions};
+
+    #[test]
+    fn session_settings() {
+        let options = PostgresPoolOptions {
+            statement_timeout: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            options.session_settings(),
+            vec![("statement_timeout", "30000".to_string())]
+        );
+    }
 
     #[test]
     fn sql_insert_parameters() {
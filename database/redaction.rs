@@ -0,0 +1,224 @@
+//! Scrub secret values out of logs and notifications.
+//!
+//! Code that handles a secret, such as an account's decrypted fields, registers its values for
+//! as long as it holds them. While a value is registered, it is replaced with [REDACTED_VALUE]
+//! wherever it shows up in text or JSON passed through [redact_str] or [redact_json]. This
+//! works by matching values instead of field names, so a secret is caught even after it has
+//! been copied into an error message or an unrelated field.
+//!
+//! Values shorter than [MIN_SECRET_LEN] are not registered, since replacing them would mangle
+//! too much unrelated text.
+
+use std::{borrow::Cow, sync::RwLock};
+
+use fxhash::FxHashMap;
+use lazy_static::lazy_static;
+use serde_json::Value;
+
+/// The text that replaces a secret value. This is the same text used to mask secret payload
+/// fields in the API.
+pub const REDACTED_VALUE: &str = "********";
+
+/// Shorter values are not redacted.
+pub const MIN_SECRET_LEN: usize = 4;
+
+lazy_static! {
+    /// Each registered value, with the number of guards that hold it.
+    static ref SECRETS: RwLock<FxHashMap<String, usize>> = RwLock::new(FxHashMap::default());
+}
+
+/// Keeps a set of values registered for redaction until it is dropped.
+#[must_use = "the values are only redacted until the guard is dropped"]
+#[derive(Debug, Default)]
+pub struct SecretsGuard {
+    values: Vec<String>,
+}
+
+impl Drop for SecretsGuard {
+    fn drop(&mut self) {
+        if self.values.is_empty() {
+            return;
+        }
+
+        let mut secrets = SECRETS.write().unwrap();
+        for value in self.values.drain(..) {
+            if let Some(count) = secrets.get_mut(&value) {
+                *count -= 1;
+                if *count == 0 {
+                    secrets.remove(&value);
+                }
+            }
+        }
+    }
+}
+
+/// Register values to redact until the returned guard is dropped.
+pub fn register<I, S>(values: I) -> SecretsGuard
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    let mut registered = Vec::new();
+    for value in values {
+        let value = value.into();
+        if value.len() < MIN_SECRET_LEN {
+            continue;
+        }
+
+        // Serialized JSON escapes some characters, so also catch the value in that form.
+        let escaped = serde_json::to_string(&value).unwrap_or_default();
+        let escaped = &escaped[1..escaped.len() - 1];
+        if escaped != value {
+            registered.push(escaped.to_string());
+        }
+        registered.push(value);
+    }
+
+    if !registered.is_empty() {
+        let mut secrets = SECRETS.write().unwrap();
+        for value in &registered {
+            *secrets.entry(value.clone()).or_insert(0) += 1;
+        }
+    }
+
+    SecretsGuard { values: registered }
+}
+
+/// Register every string in a JSON value, such as an account's fields.
+pub fn register_json(value: &Value) -> SecretsGuard {
+    let mut strings = Vec::new();
+    collect_strings(value, &mut strings);
+    register(strings)
+}
+
+fn collect_strings(value: &Value, out: &mut Vec<String>) {
+    match value {
+        Value::String(s) => out.push(s.clone()),
+        Value::Array(items) => items.iter().for_each(|v| collect_strings(v, out)),
+        Value::Object(map) => map.values().for_each(|v| collect_strings(v, out)),
+        _ => {}
+    }
+}
+
+/// Run `f` with the registered values, longest first so that a value containing another value
+/// is replaced whole. Returns `None` when nothing is registered.
+fn with_secrets<T>(f: impl FnOnce(&[&str]) -> T) -> Option<T> {
+    let secrets = SECRETS.read().unwrap();
+    if secrets.is_empty() {
+        return None;
+    }
+
+    let mut values = secrets.keys().map(|s| s.as_str()).collect::<Vec<_>>();
+    values.sort_unstable_by_key(|s| std::cmp::Reverse(s.len()));
+    Some(f(&values))
+}
+
+fn redact_with<'a>(secrets: &[&str], s: &'a str) -> Cow<'a, str> {
+    let mut output = Cow::Borrowed(s);
+    for secret in secrets {
+        if output.contains(secret) {
+            output = Cow::Owned(output.replace(secret, REDACTED_VALUE));
+        }
+    }
+    output
+}
+
+/// Replace the registered secret values in `s`.
+pub fn redact_str(s: &str) -> Cow<str> {
+    with_secrets(|secrets| redact_with(secrets, s)).unwrap_or(Cow::Borrowed(s))
+}
+
+fn redact_json_with(secrets: &[&str], value: &mut Value) {
+    match value {
+        Value::String(s) => {
+            if let Cow::Owned(redacted) = redact_with(secrets, s) {
+                *s = redacted;
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|v| redact_json_with(secrets, v)),
+        Value::Object(map) => map.values_mut().for_each(|v| redact_json_with(secrets, v)),
+        _ => {}
+    }
+}
+
+/// Replace the registered secret values in every string within `value`.
+pub fn redact_json(value: &mut Value) {
+    with_secrets(|secrets| redact_json_with(secrets, value));
+}
+
+/// Return a copy of `value` with the registered secret values replaced.
+pub fn redacted_json(value: &Value) -> Value {
+    let mut value = value.clone();
+    redact_json(&mut value);
+    value
+}
+
+/// Check if any string within `value` had a secret redacted from it, such as a payload read back
+/// from the actions log.
+pub fn contains_redacted(value: &Value) -> bool {
+    match value {
+        Value::String(s) => s.contains(REDACTED_VALUE),
+        Value::Array(items) => items.iter().any(contains_redacted),
+        Value::Object(map) => map.values().any(contains_redacted),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    // The registry is global, so each test uses values that no other test registers.
+
+    #[test]
+    fn redacts_while_registered() {
+        let guard = register(["hunter2-password"]);
+        assert_eq!(
+            redact_str("login with hunter2-password failed"),
+            "login with ******** failed"
+        );
+
+        let mut value = json!({ "error": "bad token hunter2-password", "n": 5 });
+        redact_json(&mut value);
+        assert_eq!(value, json!({ "error": "bad token ********", "n": 5 }));
+
+        drop(guard);
+        assert_eq!(
+            redact_str("login with hunter2-password failed"),
+            "login with hunter2-password failed"
+        );
+    }
+
+    #[test]
+    fn overlapping_guards() {
+        let first = register(["shared-secret-value"]);
+        let second = register_json(&json!({ "key": "shared-secret-value" }));
+        drop(first);
+        assert_eq!(redact_str("shared-secret-value"), REDACTED_VALUE);
+        drop(second);
+        assert_eq!(redact_str("shared-secret-value"), "shared-secret-value");
+    }
+
+    #[test]
+    fn escaped_values() {
+        let _guard = register(["quote\"secret"]);
+        let serialized = serde_json::to_string(&json!({ "v": "quote\"secret" })).unwrap();
+        assert_eq!(redact_str(&serialized), r#"{"v":"********"}"#);
+    }
+
+    #[test]
+    fn detects_redacted_values() {
+        let _guard = register(["detect-this-secret"]);
+        let value = redacted_json(&json!({ "args": ["--token", "detect-this-secret"] }));
+        assert!(contains_redacted(&value));
+        assert!(!contains_redacted(&json!({ "args": ["--token"] })));
+    }
+
+    #[test]
+    fn short_values_are_ignored() {
+        let _guard = register(["abc"]);
+        assert_eq!(redact_str("abc"), "abc");
+    }
+}
@@ -1,27 +1,191 @@
-use std::{env, ops::Deref, sync::Arc};
+//! A Redis connection pool that fails fast when Redis is unreachable.
+//!
+//! Every command runs with a timeout. Failed connection attempts are retried with exponential
+//! backoff, and after enough consecutive failures the pool's circuit opens. While it is open,
+//! requests for a connection fail immediately instead of piling up behind Redis. Once the open
+//! period passes, requests are let through again as a trial. A success closes the circuit, and
+//! a failure opens it again for twice as long, up to a limit.
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use deadpool::managed::{PoolError, TimeoutType};
+use redis::{aio::ConnectionLike, Cmd, Pipeline, RedisError, RedisFuture, Value};
+use tracing::{event, Level};
 
 use crate::Error;
 
-#[derive(Clone)]
-pub struct RedisPool(Arc<RedisPoolInner>);
+/// Connection and failure handling settings for a [RedisPool].
+#[derive(Clone, Debug)]
+pub struct RedisPoolOptions {
+    /// How long a single command or pipeline can take.
+    pub command_timeout: Duration,
+    /// How long to wait for a connection from the pool, including connecting to Redis if needed.
+    pub connect_timeout: Duration,
+    /// How many times to try getting a connection before returning an error.
+    pub connect_attempts: u32,
+    /// Open the circuit after this many consecutive connection failures.
+    pub failure_threshold: u32,
+    /// How long the circuit stays open after it first opens.
+    pub open_duration: Duration,
+    /// The longest that the circuit stays open after repeated failed trials.
+    pub max_open_duration: Duration,
+}
 
-struct RedisPoolInner {
-    pub pool: deadpool_redis::Pool,
-    pub key_prefix: Option<String>,
+impl Default for RedisPoolOptions {
+    fn default() -> Self {
+        RedisPoolOptions {
+            command_timeout: Duration::from_secs(5),
+            connect_timeout: Duration::from_secs(5),
+            connect_attempts: 3,
+            failure_threshold: 5,
+            open_duration: Duration::from_secs(1),
+            max_open_duration: Duration::from_secs(30),
+        }
+    }
 }
 
-impl Deref for RedisPool {
-    type Target = deadpool_redis::Pool;
+impl RedisPoolOptions {
+    pub fn from_env() -> Result<Self, Error> {
+        let defaults = Self::default();
 
-    fn deref(&self) -> &Self::Target {
-        &self.0.pool
+        let millis = |name: &str, default: Duration| -> Result<Duration, Error> {
+            let value =
+                envoption::optional::<u64>(name).map_err(|e| Error::ConfigError(e.to_string()))?;
+            Ok(value.map(Duration::from_millis).unwrap_or(default))
+        };
+
+        Ok(RedisPoolOptions {
+            command_timeout: millis("REDIS_COMMAND_TIMEOUT_MS", defaults.command_timeout)?,
+            connect_timeout: millis("REDIS_CONNECT_TIMEOUT_MS", defaults.connect_timeout)?,
+            connect_attempts: envoption::with_default(
+                "REDIS_CONNECT_ATTEMPTS",
+                defaults.connect_attempts,
+            )
+            .map_err(|e| Error::ConfigError(e.to_string()))?
+            .max(1),
+            failure_threshold: envoption::with_default(
+                "REDIS_CIRCUIT_FAILURE_THRESHOLD",
+                defaults.failure_threshold,
+            )
+            .map_err(|e| Error::ConfigError(e.to_string()))?
+            .max(1),
+            open_duration: millis("REDIS_CIRCUIT_OPEN_MS", defaults.open_duration)?,
+            max_open_duration: millis("REDIS_CIRCUIT_MAX_OPEN_MS", defaults.max_open_duration)?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests go through as normal.
+    Closed,
+    /// Requests fail immediately. Trial requests are allowed again after `retry_in`.
+    Open { retry_in: Duration },
+    /// The open period has passed, and the next request will decide whether the circuit closes.
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct Circuit {
+    failures: u32,
+    open_until: Option<Instant>,
+    open_for: Duration,
+}
+
+impl Circuit {
+    fn new(options: &RedisPoolOptions) -> Circuit {
+        Circuit {
+            failures: 0,
+            open_until: None,
+            open_for: options.open_duration,
+        }
+    }
+
+    fn state(&self, now: Instant) -> CircuitState {
+        match self.open_until {
+            None => CircuitState::Closed,
+            Some(until) if until > now => CircuitState::Open {
+                retry_in: until - now,
+            },
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Record a success, returning true if this closed the circuit.
+    fn success(&mut self, options: &RedisPoolOptions) -> bool {
+        self.failures = 0;
+        self.open_for = options.open_duration;
+        self.open_until.take().is_some()
+    }
+
+    /// Record a failure, returning how long the circuit was opened for if this opened it.
+    fn failure(&mut self, now: Instant, options: &RedisPoolOptions) -> Option<Duration> {
+        self.failures = self.failures.saturating_add(1);
+
+        match self.state(now) {
+            // A request that started before the circuit opened. The circuit is already open.
+            CircuitState::Open { .. } => None,
+            CircuitState::HalfOpen => {
+                self.open_for = (self.open_for * 2).min(options.max_open_duration);
+                self.open_until = Some(now + self.open_for);
+                Some(self.open_for)
+            }
+            CircuitState::Closed if self.failures >= options.failure_threshold => {
+                self.open_for = options.open_duration;
+                self.open_until = Some(now + self.open_for);
+                Some(self.open_for)
+            }
+            CircuitState::Closed => None,
+        }
+    }
+}
+
+/// Returns true if the error means that Redis couldn't be reached, as opposed to an error
+/// returned by a command.
+fn is_connection_error(e: &RedisError) -> bool {
+    e.is_io_error() || e.is_connection_dropped() || e.is_connection_refusal() || e.is_timeout()
+}
+
+/// An error that callers recognize as a lost connection, so that they wait for Redis to come back
+/// instead of treating it as a failed command.
+fn connection_error(message: String) -> RedisError {
+    RedisError::from(io::Error::new(io::ErrorKind::NotConnected, message))
+}
+
+fn is_pool_connection_error(e: &PoolError<RedisError>) -> bool {
+    match e {
+        PoolError::Timeout(_) => true,
+        PoolError::Backend(e) => is_connection_error(e),
+        _ => false,
     }
 }
 
+#[derive(Clone)]
+pub struct RedisPool(Arc<RedisPoolInner>);
+
+struct RedisPoolInner {
+    pool: deadpool_redis::Pool,
+    key_prefix: Option<String>,
+    options: RedisPoolOptions,
+    circuit: Mutex<Circuit>,
+}
+
 impl RedisPool {
     pub fn new(connection: Option<String>, key_prefix: Option<String>) -> Result<RedisPool, Error> {
-        let redis_host =
-            connection.unwrap_or_else(|| env::var("REDIS_URL").expect("REDIS_URL is required"));
+        Self::with_options(connection, key_prefix, RedisPoolOptions::from_env()?)
+    }
+
+    pub fn with_options(
+        connection: Option<String>,
+        key_prefix: Option<String>,
+        options: RedisPoolOptions,
+    ) -> Result<RedisPool, Error> {
+        let redis_host = connection
+            .unwrap_or_else(|| std::env::var("REDIS_URL").expect("REDIS_URL is required"));
 
         let pool = deadpool_redis::Config {
             url: Some(redis_host),
@@ -30,9 +194,57 @@ impl RedisPool {
         }
         .create_pool()?;
 
-        Ok(RedisPool(Arc::new(RedisPoolInner { pool, key_prefix })))
+        Ok(RedisPool(Arc::new(RedisPoolInner {
+            pool,
+            key_prefix,
+            circuit: Mutex::new(Circuit::new(&options)),
+            options,
+        })))
+    }
+
+    /// Get a connection from the pool. This fails immediately while the circuit is open.
+    pub async fn get(&self) -> Result<RedisConnection, PoolError<RedisError>> {
+        if let CircuitState::Open { retry_in } = self.circuit_state() {
+            return Err(PoolError::Backend(connection_error(format!(
+                "Redis circuit is open, retrying in {}ms",
+                retry_in.as_millis()
+            ))));
+        }
+
+        let options = &self.0.options;
+        let mut delay = Duration::from_millis(50);
+        let mut attempt = 1;
+        loop {
+            let result = tokio::time::timeout(options.connect_timeout, self.0.pool.get())
+                .await
+                .unwrap_or(Err(PoolError::Timeout(TimeoutType::Wait)));
+
+            match result {
+                Ok(conn) => {
+                    return Ok(RedisConnection {
+                        conn: Some(conn),
+                        pool: self.clone(),
+                        broken: false,
+                    })
+                }
+                Err(e) if is_pool_connection_error(&e) && attempt < options.connect_attempts => {
+                    event!(Level::DEBUG, %attempt, error=%e, "Retrying Redis connection");
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    if is_pool_connection_error(&e) {
+                        self.record_failure();
+                    }
+                    return Err(e);
+                }
+            }
+        }
     }
 
+    /// The underlying pool. Connections from here don't have command timeouts and don't
+    /// affect the circuit.
     pub fn pool(&self) -> &deadpool_redis::Pool {
         &self.0.pool
     }
@@ -40,4 +252,193 @@ impl RedisPool {
     pub fn key_prefix(&self) -> Option<&str> {
         self.0.key_prefix.as_deref()
     }
+
+    pub fn circuit_state(&self) -> CircuitState {
+        self.0.circuit.lock().unwrap().state(Instant::now())
+    }
+
+    fn record_success(&self) {
+        let closed = self.0.circuit.lock().unwrap().success(&self.0.options);
+        if closed {
+            event!(Level::INFO, "Redis circuit closed");
+        }
+    }
+
+    fn record_failure(&self) {
+        let opened = self
+            .0
+            .circuit
+            .lock()
+            .unwrap()
+            .failure(Instant::now(), &self.0.options);
+        if let Some(duration) = opened {
+            event!(
+                Level::WARN,
+                open_ms = duration.as_millis() as u64,
+                "Redis circuit opened"
+            );
+        }
+    }
+}
+
+/// A connection from a [RedisPool]. Each command fails with a timeout error if Redis doesn't
+/// respond within the pool's command timeout.
+///
+/// A connection that times out or loses its connection to Redis is discarded instead of being
+/// returned to the pool, since a late reply would be read as the response to a later command.
+pub struct RedisConnection {
+    conn: Option<deadpool_redis::Connection>,
+    pool: RedisPool,
+    broken: bool,
+}
+
+impl RedisConnection {
+    fn finish<T>(
+        &mut self,
+        result: Result<Result<T, RedisError>, tokio::time::error::Elapsed>,
+    ) -> Result<T, RedisError> {
+        let result = result.unwrap_or_else(|_| {
+            Err(RedisError::from(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Redis command timed out",
+            )))
+        });
+
+        match &result {
+            Err(e) if is_connection_error(e) => {
+                self.broken = true;
+                self.pool.record_failure();
+            }
+            // Any reply from Redis, including an error reply, means that it's reachable.
+            _ => self.pool.record_success(),
+        }
+
+        result
+    }
+
+    fn check_broken(&self) -> Result<(), RedisError> {
+        if self.broken {
+            Err(connection_error(
+                "Connection was closed after an earlier error".to_string(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        Box::pin(async move {
+            self.check_broken()?;
+            let timeout = self.pool.0.options.command_timeout;
+            let conn = self
+                .conn
+                .as_mut()
+                .expect("connection is only taken on drop");
+            let result = tokio::time::timeout(timeout, conn.req_packed_command(cmd)).await;
+            self.finish(result)
+        })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        Box::pin(async move {
+            self.check_broken()?;
+            let timeout = self.pool.0.options.command_timeout;
+            let conn = self
+                .conn
+                .as_mut()
+                .expect("connection is only taken on drop");
+            let result =
+                tokio::time::timeout(timeout, conn.req_packed_commands(cmd, offset, count)).await;
+            self.finish(result)
+        })
+    }
+
+    fn get_db(&self) -> i64 {
+        self.conn.as_ref().map(|c| c.get_db()).unwrap_or_default()
+    }
+}
+
+impl Drop for RedisConnection {
+    fn drop(&mut self) {
+        if self.broken {
+            if let Some(conn) = self.conn.take() {
+                // Taking the connection out of the pool closes it when it's dropped.
+                drop(deadpool_redis::Connection::take(conn));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> RedisPoolOptions {
+        RedisPoolOptions {
+            failure_threshold: 3,
+            open_duration: Duration::from_secs(1),
+            max_open_duration: Duration::from_secs(3),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn opens_after_threshold() {
+        let options = options();
+        let mut circuit = Circuit::new(&options);
+        let now = Instant::now();
+
+        assert_eq!(circuit.failure(now, &options), None);
+        assert_eq!(circuit.failure(now, &options), None);
+        assert_eq!(circuit.state(now), CircuitState::Closed);
+
+        assert_eq!(circuit.failure(now, &options), Some(Duration::from_secs(1)));
+        assert_eq!(
+            circuit.state(now),
+            CircuitState::Open {
+                retry_in: Duration::from_secs(1)
+            }
+        );
+
+        // Failures from requests that were already running don't extend it.
+        assert_eq!(circuit.failure(now, &options), None);
+        assert_eq!(
+            circuit.state(now + Duration::from_secs(1)),
+            CircuitState::HalfOpen
+        );
+    }
+
+    #[test]
+    fn failed_trials_back_off() {
+        let options = options();
+        let mut circuit = Circuit::new(&options);
+        let mut now = Instant::now();
+        for _ in 0..3 {
+            circuit.failure(now, &options);
+        }
+
+        now += Duration::from_secs(1);
+        assert_eq!(circuit.failure(now, &options), Some(Duration::from_secs(2)));
+        now += Duration::from_secs(2);
+        assert_eq!(circuit.failure(now, &options), Some(Duration::from_secs(3)));
+        now += Duration::from_secs(3);
+        assert_eq!(circuit.state(now), CircuitState::HalfOpen);
+
+        assert!(circuit.success(&options));
+        assert_eq!(circuit.state(now), CircuitState::Closed);
+        assert!(!circuit.success(&options));
+
+        // The backoff starts over the next time it opens.
+        for _ in 0..2 {
+            assert_eq!(circuit.failure(now, &options), None);
+        }
+        assert_eq!(circuit.failure(now, &options), Some(Duration::from_secs(1)));
+    }
 }
@@ -128,7 +128,7 @@ END; $$;
         .password(&password);
     let pool = sqlx::PgPool::connect_with(pool_options).await?;
 
-    sqlx::migrate!("../migrations").run(&pool).await?;
+    crate::migrations::MIGRATOR.run(&pool).await?;
 
     let mut conn = pool.acquire().await?;
     let admin_user = populate_database(&mut conn).await?;
@@ -167,6 +167,8 @@ async fn populate_database(conn: &mut PgConnection) -> Result<DatabaseUser, anyh
         INSERT INTO users (user_id, active_org_id, name, email, password_hash) VALUES
           ('{user_id}', '{org_id}', 'Test Admin User', 'user@example.com', '{password_hash}');
 
+        INSERT INTO org_members (org_id, user_id) VALUES ('{org_id}', '{user_id}');
+
         -- Temporary until API supporst creating action categories.
         INSERT INTO action_categories(action_category_id, name) VALUES
             ('{action_category_id}', 'General');
@@ -1,11 +1,101 @@
 #![allow(clippy::bool_assert_comparison)]
 
+//! Coordinate shutting down the server. Shutdown starts on SIGINT or when
+//! [GracefulShutdown::shutdown] is called, and then runs in phases. Subsystems register
+//! themselves in a [ShutdownPhase], are told to stop when their phase starts, and acknowledge
+//! when they have finished draining. Each phase starts once every subsystem in the earlier
+//! phases has finished, or when the shutdown deadline passes.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
 use tokio::{
     select,
     signal::ctrl_c,
     sync::{oneshot, watch},
     task::JoinHandle,
+    time::Instant,
 };
+use tracing::{event, Level};
+
+/// How long to wait for subsystems to finish draining when no deadline is given.
+pub const DEFAULT_SHUTDOWN_DEADLINE: Duration = Duration::from_secs(30);
+
+/// The phases of a shutdown, in the order that they run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ShutdownPhase {
+    /// Stop taking new work from outside, such as HTTP requests.
+    StopAccepting,
+    /// Finish the work that is already in progress, such as running queue jobs.
+    Drain,
+    /// Close connection pools and other resources that the earlier phases use.
+    Close,
+}
+
+impl ShutdownPhase {
+    const ALL: [ShutdownPhase; 3] = [
+        ShutdownPhase::StopAccepting,
+        ShutdownPhase::Drain,
+        ShutdownPhase::Close,
+    ];
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubsystemStatus {
+    Running,
+    Draining,
+    Finished,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubsystemInfo {
+    pub name: String,
+    pub phase: ShutdownPhase,
+    pub status: SubsystemStatus,
+}
+
+struct RegisteredSubsystem {
+    name: String,
+    phase: ShutdownPhase,
+    status: watch::Receiver<SubsystemStatus>,
+}
+
+impl RegisteredSubsystem {
+    fn status(&self) -> SubsystemStatus {
+        if self.status.has_changed().is_err() {
+            // The subsystem was dropped, so it can't be doing anything anymore.
+            SubsystemStatus::Finished
+        } else {
+            *self.status.borrow()
+        }
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    subsystems: Mutex<Vec<RegisteredSubsystem>>,
+}
+
+impl Registry {
+    fn phase_subsystems(
+        &self,
+        phase: ShutdownPhase,
+    ) -> Vec<(String, watch::Receiver<SubsystemStatus>)> {
+        self.subsystems
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|s| s.phase == phase)
+            .map(|s| (s.name.clone(), s.status.clone()))
+            .collect()
+    }
+}
 
 #[derive(Debug)]
 pub struct GracefulShutdown {
@@ -15,33 +105,73 @@ pub struct GracefulShutdown {
     consumer: GracefulShutdownConsumer,
 }
 
-#[derive(Clone, Debug)]
-pub struct GracefulShutdownConsumer(watch::Receiver<bool>);
+/// Waits for shutdown to start. A consumer can also register subsystems that take part in the
+/// phased shutdown.
+#[derive(Clone)]
+pub struct GracefulShutdownConsumer {
+    started: watch::Receiver<bool>,
+    phases: [watch::Receiver<bool>; 3],
+    registry: Arc<Registry>,
+}
+
+impl std::fmt::Debug for GracefulShutdownConsumer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GracefulShutdownConsumer")
+            .field("started", &*self.started.borrow())
+            .finish()
+    }
+}
 
 impl GracefulShutdown {
     pub fn new() -> GracefulShutdown {
+        Self::with_deadline(DEFAULT_SHUTDOWN_DEADLINE)
+    }
+
+    /// Create a shutdown coordinator that waits at most `deadline` for the subsystems to finish
+    /// once shutdown starts.
+    pub fn with_deadline(deadline: Duration) -> GracefulShutdown {
         // This channel changes to true and drops when shutdown is started
         let (shutdown_started_tx, shutdown_started_rx) = watch::channel(false);
 
+        // Each phase's channel changes to true when that phase starts.
+        let (phase_txs, phase_rxs): (Vec<_>, Vec<_>) = ShutdownPhase::ALL
+            .iter()
+            .map(|_| watch::channel(false))
+            .unzip();
+        let phase_rxs: [watch::Receiver<bool>; 3] = phase_rxs.try_into().unwrap();
+
         // Send a value or close this channel to start shutting down.
         let (start_shutdown_tx, start_shutdown_rx) = oneshot::channel();
 
+        let registry = Arc::new(Registry::default());
+        let waiter_registry = registry.clone();
+
         let shutdown_waiter = tokio::spawn(async move {
             select! {
                 _ = ctrl_c() => {},
                 _ = start_shutdown_rx => {},
             };
 
-            // Explicitly drop this so that we'll have ownership of it.
-            // In the future there will be more to do here as we start waiting for other things to
-            // actually shut down.
-            shutdown_started_tx.send(true).unwrap();
+            event!(Level::INFO, ?deadline, "Shutting down");
+            shutdown_started_tx.send_replace(true);
+
+            let deadline = Instant::now() + deadline;
+            for (phase, phase_tx) in ShutdownPhase::ALL.iter().zip(phase_txs.iter()) {
+                phase_tx.send_replace(true);
+                wait_for_phase(&waiter_registry, *phase, deadline).await;
+            }
+
+            event!(Level::INFO, "Shutdown finished");
         });
 
         GracefulShutdown {
             start_shutdown: start_shutdown_tx,
             shutdown_finished: shutdown_waiter,
-            consumer: GracefulShutdownConsumer(shutdown_started_rx),
+            consumer: GracefulShutdownConsumer {
+                started: shutdown_started_rx,
+                phases: phase_rxs,
+                registry,
+            },
         }
     }
 
@@ -49,6 +179,11 @@ impl GracefulShutdown {
         self.consumer.clone()
     }
 
+    /// Register a subsystem. See [GracefulShutdownConsumer::register].
+    pub fn register(&self, name: impl Into<String>, phase: ShutdownPhase) -> ShutdownSubsystem {
+        self.consumer.register(name, phase)
+    }
+
     pub fn shutdown(self) -> JoinHandle<()> {
         let GracefulShutdown {
             start_shutdown,
@@ -64,6 +199,43 @@ impl GracefulShutdown {
     }
 }
 
+/// Wait for all the subsystems in a phase to finish, or for the deadline to pass.
+async fn wait_for_phase(registry: &Registry, phase: ShutdownPhase, deadline: Instant) {
+    let subsystems = registry.phase_subsystems(phase);
+    if subsystems.is_empty() {
+        return;
+    }
+
+    event!(
+        Level::INFO,
+        ?phase,
+        count = subsystems.len(),
+        "Starting shutdown phase"
+    );
+
+    for (name, status) in subsystems {
+        match tokio::time::timeout_at(deadline, wait_for_finished(status)).await {
+            Ok(_) => event!(Level::DEBUG, ?phase, subsystem=%name, "Subsystem finished"),
+            Err(_) => {
+                event!(Level::WARN, ?phase, subsystem=%name, "Subsystem did not finish before the shutdown deadline");
+            }
+        }
+    }
+}
+
+async fn wait_for_finished(mut status: watch::Receiver<SubsystemStatus>) {
+    loop {
+        if *status.borrow() == SubsystemStatus::Finished {
+            return;
+        }
+
+        if status.changed().await.is_err() {
+            // The subsystem was dropped.
+            return;
+        }
+    }
+}
+
 impl Default for GracefulShutdown {
     fn default() -> Self {
         Self::new()
@@ -72,29 +244,118 @@ impl Default for GracefulShutdown {
 
 impl GracefulShutdownConsumer {
     pub fn shutting_down(&mut self) -> bool {
-        *self.0.borrow()
+        *self.started.borrow()
     }
 
     pub async fn wait_for_shutdown(&mut self) {
-        if *self.0.borrow() {
-            return;
+        wait_for_flag(&mut self.started).await
+    }
+
+    /// Register a subsystem that takes part in the shutdown. The subsystem is told to stop when
+    /// `phase` starts, and the next phase waits for it to call
+    /// [ShutdownSubsystem::drain_finished] or to be dropped.
+    pub fn register(&self, name: impl Into<String>, phase: ShutdownPhase) -> ShutdownSubsystem {
+        let name = name.into();
+        let (status_tx, status_rx) = watch::channel(SubsystemStatus::Running);
+        self.registry
+            .subsystems
+            .lock()
+            .unwrap()
+            .push(RegisteredSubsystem {
+                name: name.clone(),
+                phase,
+                status: status_rx,
+            });
+
+        ShutdownSubsystem {
+            name,
+            phase,
+            status: status_tx,
+            consumer: GracefulShutdownConsumer {
+                started: self.phases[phase.index()].clone(),
+                phases: self.phases.clone(),
+                registry: self.registry.clone(),
+            },
         }
+    }
 
-        loop {
-            match self.0.changed().await {
-                Ok(_) => {
-                    // Sender is still open, but value is true so we're shutting down.
-                    if *self.0.borrow() {
-                        return;
-                    }
+    /// The registered subsystems and how far along they are in shutting down.
+    pub fn subsystems(&self) -> Vec<SubsystemInfo> {
+        self.registry
+            .subsystems
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|s| SubsystemInfo {
+                name: s.name.clone(),
+                phase: s.phase,
+                status: s.status(),
+            })
+            .collect()
+    }
+}
+
+async fn wait_for_flag(flag: &mut watch::Receiver<bool>) {
+    if *flag.borrow() {
+        return;
+    }
+
+    loop {
+        match flag.changed().await {
+            Ok(_) => {
+                // Sender is still open, but value is true so we're shutting down.
+                if *flag.borrow() {
+                    return;
                 }
-                // Sender closed, which means we're shutting down.
-                Err(_) => return,
             }
+            // Sender closed, which means we're shutting down.
+            Err(_) => return,
         }
     }
 }
 
+/// A part of the server that needs to finish its work before the next shutdown phase starts.
+/// Dropping the subsystem counts as finishing.
+#[derive(Debug)]
+pub struct ShutdownSubsystem {
+    name: String,
+    phase: ShutdownPhase,
+    status: watch::Sender<SubsystemStatus>,
+    consumer: GracefulShutdownConsumer,
+}
+
+impl ShutdownSubsystem {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn phase(&self) -> ShutdownPhase {
+        self.phase
+    }
+
+    /// A consumer that signals when this subsystem's phase starts.
+    pub fn consumer(&self) -> GracefulShutdownConsumer {
+        self.consumer.clone()
+    }
+
+    /// Wait for this subsystem's phase to start.
+    pub async fn wait_for_shutdown(&mut self) {
+        self.consumer.wait_for_shutdown().await
+    }
+
+    /// Note that the subsystem has stopped taking new work and is finishing what it has.
+    pub fn drain_started(&self) {
+        event!(Level::INFO, subsystem=%self.name, "Draining");
+        self.status.send_replace(SubsystemStatus::Draining);
+    }
+
+    /// Note that the subsystem is done, so the shutdown doesn't need to wait for it anymore.
+    pub fn drain_finished(self) {
+        event!(Level::INFO, subsystem=%self.name, "Drained");
+        self.status.send_replace(SubsystemStatus::Finished);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use assert_matches::assert_matches;
@@ -195,4 +456,96 @@ mod tests {
             Ok(())
         );
     }
+
+    #[tokio::test]
+    async fn phases_run_in_order() {
+        let s = GracefulShutdown::new();
+        let (events_tx, mut events_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        for (name, phase) in [
+            ("pools", ShutdownPhase::Close),
+            ("queue", ShutdownPhase::Drain),
+            ("http", ShutdownPhase::StopAccepting),
+        ] {
+            let mut subsystem = s.register(name, phase);
+            let events_tx = events_tx.clone();
+            tokio::spawn(async move {
+                subsystem.wait_for_shutdown().await;
+                events_tx.send(format!("{} started", name)).unwrap();
+                subsystem.drain_started();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                events_tx.send(format!("{} finished", name)).unwrap();
+                subsystem.drain_finished();
+            });
+        }
+        drop(events_tx);
+
+        let consumer = s.consumer();
+        assert_matches!(
+            timeout(Duration::from_secs(2), s.shutdown()).await,
+            Ok(Ok(()))
+        );
+
+        let mut events = Vec::new();
+        while let Some(event) = events_rx.recv().await {
+            events.push(event);
+        }
+
+        assert_eq!(
+            events,
+            vec![
+                "http started",
+                "http finished",
+                "queue started",
+                "queue finished",
+                "pools started",
+                "pools finished",
+            ]
+        );
+
+        assert!(consumer
+            .subsystems()
+            .iter()
+            .all(|s| s.status == SubsystemStatus::Finished));
+    }
+
+    #[tokio::test]
+    async fn dropped_subsystem_is_finished() {
+        let s = GracefulShutdown::new();
+        let subsystem = s.register("dropped", ShutdownPhase::Drain);
+        drop(subsystem);
+
+        assert_matches!(
+            timeout(Duration::from_secs(2), s.shutdown()).await,
+            Ok(Ok(()))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deadline_stops_waiting() {
+        let s = GracefulShutdown::with_deadline(Duration::from_secs(5));
+        let stuck = s.register("stuck", ShutdownPhase::Drain);
+        let mut close = s.register("close", ShutdownPhase::Close);
+        let consumer = s.consumer();
+
+        let close_task = tokio::spawn(async move {
+            close.wait_for_shutdown().await;
+            close.drain_finished();
+        });
+
+        stuck.drain_started();
+        assert_matches!(
+            timeout(Duration::from_secs(10), s.shutdown()).await,
+            Ok(Ok(()))
+        );
+        assert_matches!(close_task.await, Ok(()));
+
+        let status = consumer
+            .subsystems()
+            .into_iter()
+            .find(|s| s.name == "stuck")
+            .map(|s| s.status);
+        assert_eq!(status, Some(SubsystemStatus::Draining));
+        drop(stuck);
+    }
 }
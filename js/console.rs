@@ -24,6 +24,11 @@ pub trait Console: Downcast {
         Vec::new()
     }
 
+    /// Statistics about the messages logged so far, for consoles that keep track of them.
+    fn stats(&self) -> Option<ConsoleStats> {
+        None
+    }
+
     fn clone_settings(&self) -> Box<dyn Console>;
 }
 impl_downcast!(Console);
@@ -55,6 +60,18 @@ pub struct ConsoleMessage {
     pub level: ConsoleLevel,
     pub time: DateTime<Utc>,
     pub message: String,
+    /// Data attached to a message from a structured logging call. This is always set for
+    /// structured messages, even when no data was given, and never set for other console
+    /// output.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fields: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+impl ConsoleMessage {
+    /// True if the message came from a structured logging call.
+    pub fn is_structured(&self) -> bool {
+        self.fields.is_some()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -65,6 +82,8 @@ pub struct ConsoleLimit {
     /// the beginning. If `head >= total`, then no further messages will be retained.
     /// (`head` is not yet implemented.)
     head: usize,
+    /// Messages longer than this, in bytes, are truncated.
+    message: usize,
 }
 
 impl Default for ConsoleLimit {
@@ -72,10 +91,62 @@ impl Default for ConsoleLimit {
         ConsoleLimit {
             total: usize::MAX,
             head: usize::MAX,
+            message: usize::MAX,
+        }
+    }
+}
+
+impl ConsoleLimit {
+    /// Retain up to `total` bytes of the most recent messages, and truncate any single message
+    /// longer than `message` bytes.
+    pub fn new(total: usize, message: usize) -> Self {
+        ConsoleLimit {
+            total,
+            head: usize::MAX,
+            message,
         }
     }
 }
 
+/// Totals for everything logged to a console, including the parts that were dropped or
+/// truncated to fit within the limits.
+#[derive(Clone, Debug, Default, JsonSchema, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConsoleStats {
+    pub messages: usize,
+    /// The size of all the messages before truncation.
+    pub bytes: usize,
+    /// Old messages that were dropped to stay within the total limit.
+    pub dropped_messages: usize,
+    pub dropped_bytes: usize,
+    /// Messages that were shortened to fit within the message limit.
+    pub truncated_messages: usize,
+}
+
+impl ConsoleStats {
+    /// True if any output was dropped or truncated.
+    pub fn is_truncated(&self) -> bool {
+        self.dropped_messages > 0 || self.truncated_messages > 0
+    }
+}
+
+/// Shorten `s` to at most `max` bytes, ending on a character boundary, and add a marker noting
+/// how much was removed. Returns the number of bytes removed.
+pub fn truncate_with_marker(s: &mut String, max: usize) -> usize {
+    if s.len() <= max {
+        return 0;
+    }
+
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    let removed = s.len() - end;
+    s.truncate(end);
+    s.push_str(&format!("... [truncated {} bytes]", removed));
+    removed
+}
+
 /// A console that stores messages for later use.
 pub struct BufferConsole {
     messages: VecDeque<ConsoleMessage>,
@@ -83,6 +154,7 @@ pub struct BufferConsole {
     min_level: ConsoleLevel,
     passthrough: Option<Box<dyn Console>>,
     current_size: usize,
+    stats: ConsoleStats,
 }
 
 impl Default for BufferConsole {
@@ -99,6 +171,7 @@ impl BufferConsole {
             messages: VecDeque::new(),
             current_size: 0,
             passthrough: None,
+            stats: ConsoleStats::default(),
         }
     }
 
@@ -117,11 +190,17 @@ impl BufferConsole {
 }
 
 impl Console for BufferConsole {
-    fn add(&mut self, message: ConsoleMessage) -> bool {
+    fn add(&mut self, mut message: ConsoleMessage) -> bool {
         if message.level < self.min_level {
             return false;
         }
 
+        self.stats.messages += 1;
+        self.stats.bytes += message.message.len();
+        if truncate_with_marker(&mut message.message, self.capacity.message) > 0 {
+            self.stats.truncated_messages += 1;
+        }
+
         let message_size = message.message.len();
         while self.current_size + message_size > self.capacity.total && !self.messages.is_empty() {
             let popped_size = self
@@ -130,6 +209,8 @@ impl Console for BufferConsole {
                 .map(|m| m.message.len())
                 .unwrap_or(0);
             self.current_size -= popped_size;
+            self.stats.dropped_messages += 1;
+            self.stats.dropped_bytes += popped_size;
         }
 
         self.current_size += message_size;
@@ -139,10 +220,26 @@ impl Console for BufferConsole {
 
     fn take_messages(&mut self) -> Vec<ConsoleMessage> {
         self.current_size = 0;
-        let messages = std::mem::take(&mut self.messages);
+        let mut messages = std::mem::take(&mut self.messages);
+        if self.stats.dropped_messages > 0 {
+            messages.push_front(ConsoleMessage {
+                level: ConsoleLevel::Warn,
+                time: messages.front().map(|m| m.time).unwrap_or_else(Utc::now),
+                message: format!(
+                    "[{} earlier messages ({} bytes) were dropped]",
+                    self.stats.dropped_messages, self.stats.dropped_bytes
+                ),
+                fields: None,
+            });
+        }
+
         Vec::from(messages)
     }
 
+    fn stats(&self) -> Option<ConsoleStats> {
+        Some(self.stats.clone())
+    }
+
     fn clone_settings(&self) -> Box<dyn Console> {
         Box::new(BufferConsole {
             capacity: self.capacity.clone(),
@@ -150,6 +247,7 @@ impl Console for BufferConsole {
             messages: VecDeque::new(),
             current_size: 0,
             passthrough: self.passthrough.as_ref().map(|p| p.clone_settings()),
+            stats: ConsoleStats::default(),
         })
     }
 }
@@ -331,12 +429,62 @@ mod tests {
             level: ConsoleLevel::Info,
             message: String::from("test message\n"),
             time: chrono::Utc::now(),
+            fields: None,
         });
 
         c.add(ConsoleMessage {
             level: ConsoleLevel::Debug,
             message: String::from("debug message should not appear\n"),
             time: chrono::Utc::now(),
+            fields: None,
         });
     }
+
+    fn message(text: &str) -> ConsoleMessage {
+        ConsoleMessage {
+            level: ConsoleLevel::Info,
+            message: text.to_string(),
+            time: chrono::Utc::now(),
+            fields: None,
+        }
+    }
+
+    #[test]
+    fn buffer_console_limits() {
+        let mut c =
+            BufferConsole::new(ConsoleLevel::Info).capacity(Some(ConsoleLimit::new(45, 10)));
+        c.add(message("short"));
+        c.add(message("a message that is too long"));
+        c.add(message("third"));
+        c.add(message("fourth"));
+
+        let stats = c.stats().unwrap();
+        assert_eq!(stats.messages, 4);
+        assert_eq!(stats.bytes, 5 + 26 + 5 + 6);
+        assert_eq!(stats.truncated_messages, 1);
+        assert_eq!(stats.dropped_messages, 1);
+        assert_eq!(stats.dropped_bytes, 5);
+
+        let messages = c
+            .take_messages()
+            .into_iter()
+            .map(|m| m.message)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![
+                "[1 earlier messages (5 bytes) were dropped]",
+                "a message ... [truncated 16 bytes]",
+                "third",
+                "fourth",
+            ]
+        );
+    }
+
+    #[test]
+    fn truncate_on_char_boundary() {
+        let mut s = String::from("aé");
+        assert_eq!(truncate_with_marker(&mut s, 2), 2);
+        assert_eq!(s, "a... [truncated 2 bytes]");
+    }
 }
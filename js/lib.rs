@@ -1,16 +1,18 @@
 #![allow(clippy::bool_assert_comparison)]
 
 mod console;
+pub mod locks;
 pub mod module_loader;
 pub mod permissions;
 mod pool;
 mod raw_serde;
 #[cfg(feature = "serialized_execution")]
 pub mod serialized_execution;
+pub mod time_limit;
 pub mod worker;
 
 pub use console::*;
-pub use pool::RuntimePool;
+pub use pool::{PoolStats, ProbeResult, RuntimePool};
 #[cfg(feature = "serialized_execution")]
 pub use serialized_execution::SerializedState;
 
@@ -123,6 +125,16 @@ pub struct RuntimeOptions {
 
     /// Permissions for Javascript code.
     pub permissions: Option<Permissions>,
+
+    /// The maximum heap size in bytes. A script that goes over this is terminated instead of
+    /// crashing the process.
+    pub max_heap_size: Option<usize>,
+
+    /// Modules that scripts can import, keyed by specifier.
+    pub modules: module_loader::ModuleSources,
+
+    /// The storage for locks that the script takes. Scripts can't take locks when this is `None`.
+    pub locks: Option<Rc<dyn locks::LockProvider>>,
 }
 
 impl Default for RuntimeOptions {
@@ -137,6 +149,9 @@ impl Default for RuntimeOptions {
             serialized_state: None,
             console: None,
             permissions: None,
+            max_heap_size: None,
+            modules: module_loader::ModuleSources::new(),
+            locks: None,
         }
     }
 }
@@ -165,14 +180,25 @@ impl Runtime {
             .console
             .unwrap_or_else(|| Box::new(NullConsole::new()));
         options.extensions.push(console_extension(console));
+        options
+            .extensions
+            .push(locks::lock_extension(options.locks.take()));
 
         let has_snapshot = options.snapshot.is_some();
+        let module_loader: Rc<dyn deno_core::ModuleLoader> = if options.modules.is_empty() {
+            Rc::new(module_loader::TrivialModuleLoader {})
+        } else {
+            Rc::new(module_loader::StaticModuleLoader::new(options.modules))
+        };
         let deno_runtime = JsRuntime::new(deno_core::RuntimeOptions {
             will_snapshot: options.will_snapshot,
             extensions: Vec::new(),
             extensions_with_js: options.extensions,
             startup_snapshot: options.snapshot,
-            module_loader: Some(Rc::new(module_loader::TrivialModuleLoader {})),
+            module_loader: Some(module_loader),
+            create_params: options
+                .max_heap_size
+                .map(|max| v8::CreateParams::default().heap_limits(0, max)),
             ..deno_core::RuntimeOptions::default()
         });
 
@@ -180,6 +206,21 @@ impl Runtime {
             runtime: deno_runtime,
         };
 
+        if options.max_heap_size.is_some() {
+            let handle = runtime.v8_isolate().thread_safe_handle();
+            let mut terminated = false;
+            runtime.add_near_heap_limit_callback(move |current, _initial| {
+                if !terminated {
+                    terminated = true;
+                    handle.terminate_execution();
+                    pool::record_out_of_memory();
+                }
+
+                // Raise the limit so that the isolate has room to unwind the termination.
+                current * 2
+            });
+        }
+
         runtime
             .op_state()
             .borrow_mut()
@@ -244,6 +285,16 @@ impl Runtime {
         }
     }
 
+    /// Totals for everything logged to the console, including messages that were dropped or
+    /// truncated.
+    pub fn console_stats(&mut self) -> Option<ConsoleStats> {
+        self.runtime
+            .op_state()
+            .borrow()
+            .try_borrow::<ConsoleWrapper>()
+            .and_then(|console| console.console.stats())
+    }
+
     pub fn make_snapshot(self) -> Vec<u8> {
         let snapshot = self.runtime.snapshot();
         snapshot.as_ref().to_vec()
@@ -421,20 +472,50 @@ impl Runtime {
     }
 }
 
-#[op]
-fn ergo_js_console(state: &mut OpState, message: String, level: usize) -> Result<(), AnyError> {
+fn add_console_message(state: &mut OpState, message: console::ConsoleMessage) {
     if let Some(console) = state.try_borrow_mut::<ConsoleWrapper>() {
-        let message = console::ConsoleMessage {
-            message,
-            level: ConsoleLevel::from(level),
-            time: chrono::Utc::now(),
-        };
-
         console.console.add(message);
     } else {
         panic!("No console wrapper")
     }
+}
+
+#[op]
+fn ergo_js_console(state: &mut OpState, message: String, level: usize) -> Result<(), AnyError> {
+    let message = console::ConsoleMessage {
+        message,
+        level: ConsoleLevel::from(level),
+        time: chrono::Utc::now(),
+        fields: None,
+    };
 
+    add_console_message(state, message);
+    Ok(())
+}
+
+/// Add a message from the structured logging API. Fields that aren't an object are placed under
+/// a `value` key.
+#[op]
+fn ergo_js_log(
+    state: &mut OpState,
+    message: String,
+    level: usize,
+    fields: serde_json::Value,
+) -> Result<(), AnyError> {
+    let fields = match fields {
+        serde_json::Value::Object(fields) => fields,
+        serde_json::Value::Null => serde_json::Map::new(),
+        value => std::iter::once(("value".to_string(), value)).collect(),
+    };
+
+    let message = console::ConsoleMessage {
+        message,
+        level: ConsoleLevel::from(level),
+        time: chrono::Utc::now(),
+        fields: Some(fields),
+    };
+
+    add_console_message(state, message);
     Ok(())
 }
 
@@ -450,7 +531,7 @@ const CONSOLE_EXTENSION_JS: &str = r##"
 fn console_extension(console: Box<dyn Console>) -> deno_core::Extension {
     deno_core::Extension::builder()
         .js(vec![("ergo_js_console", CONSOLE_EXTENSION_JS)])
-        .ops(vec![ergo_js_console::decl()])
+        .ops(vec![ergo_js_console::decl(), ergo_js_log::decl()])
         .state(move |state| {
             state.put(ConsoleWrapper {
                 console: console.clone_settings(),
@@ -859,6 +940,49 @@ mod tests {
         assert_eq!(loaded, true, "loaded is true");
     }
 
+    #[tokio::test]
+    async fn import_static_module() {
+        let mut modules = module_loader::ModuleSources::new();
+        modules.insert(
+            "ergo:lib/math".to_string(),
+            "export function double(x) { return x * 2; }".to_string(),
+        );
+
+        let mut runtime = Runtime::new(RuntimeOptions {
+            extensions: core_extensions(None),
+            modules: modules.clone(),
+            ..Default::default()
+        });
+        let code = r##"
+            import { double } from "ergo:lib/math";
+            globalThis.result = double(21);
+            "##;
+        let main_url = Url::parse("https://ergo/script").expect("creating url");
+        runtime
+            .run_main_module(main_url, code.to_string())
+            .await
+            .expect("run_main_module");
+        let result: usize = runtime
+            .get_global_value("result")
+            .expect("retrieving result")
+            .expect("result should be present");
+        assert_eq!(result, 42);
+
+        let mut runtime = Runtime::new(RuntimeOptions {
+            extensions: core_extensions(None),
+            modules,
+            ..Default::default()
+        });
+        let missing = r##"
+            import { nothing } from "ergo:lib/missing";
+            "##;
+        let main_url = Url::parse("https://ergo/missing").expect("creating url");
+        runtime
+            .run_main_module(main_url, missing.to_string())
+            .await
+            .expect_err("importing a missing module should fail");
+    }
+
     #[tokio::test]
     async fn fetch() {
         let server = MockServer::start().await;
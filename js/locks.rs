@@ -0,0 +1,297 @@
+//! Named locks that let scripts coordinate access to external resources with other scripts that
+//! may be running at the same time. Scripts use them through `Ergo.lock`, `Ergo.tryLock`, and
+//! `Ergo.withLock`.
+//!
+//! The runtime only defines the script API. The embedder sets [crate::RuntimeOptions::locks] to
+//! a [LockProvider] that stores the locks somewhere shared by every process that runs scripts.
+//! Without a provider, trying to take a lock throws an error.
+
+use std::{cell::RefCell, rc::Rc, time::Duration};
+
+use deno_core::{error::AnyError, op, OpState};
+use rand::Rng;
+
+/// The longest time that a lock can be held without being extended.
+pub const MAX_LOCK_TTL: Duration = Duration::from_secs(3600);
+/// The longest time that a script can wait to take a lock.
+pub const MAX_LOCK_WAIT: Duration = Duration::from_secs(300);
+const MAX_NAME_LENGTH: usize = 200;
+
+/// Storage for script locks. Each lock has a random token, which must be passed to extend or
+/// release it so that a script can't touch a lock that expired and was then taken by someone else.
+#[async_trait::async_trait(?Send)]
+pub trait LockProvider {
+    /// Take the lock if it is free, and return its token.
+    async fn try_acquire(&self, name: &str, ttl: Duration) -> Result<Option<String>, AnyError>;
+    /// Reset the lock's expiration. Returns false if the lock is no longer held with this token.
+    async fn extend(&self, name: &str, token: &str, ttl: Duration) -> Result<bool, AnyError>;
+    /// Release the lock. Returns false if the lock is no longer held with this token.
+    async fn release(&self, name: &str, token: &str) -> Result<bool, AnyError>;
+}
+
+struct LockProviderWrapper(Option<Rc<dyn LockProvider>>);
+
+fn provider(state: &Rc<RefCell<OpState>>) -> Result<Rc<dyn LockProvider>, AnyError> {
+    state
+        .borrow()
+        .try_borrow::<LockProviderWrapper>()
+        .and_then(|w| w.0.clone())
+        .ok_or_else(|| anyhow::anyhow!("Locks are not available in this script"))
+}
+
+fn check_name(name: &str) -> Result<(), AnyError> {
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err(anyhow::anyhow!(
+            "Lock names must be between 1 and {} characters",
+            MAX_NAME_LENGTH
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_ttl(ttl_ms: u64) -> Result<Duration, AnyError> {
+    let ttl = Duration::from_millis(ttl_ms);
+    if ttl.is_zero() || ttl > MAX_LOCK_TTL {
+        return Err(anyhow::anyhow!(
+            "Lock TTL must be between 1 and {} milliseconds",
+            MAX_LOCK_TTL.as_millis()
+        ));
+    }
+
+    Ok(ttl)
+}
+
+#[op]
+async fn ergo_lock_acquire(
+    state: Rc<RefCell<OpState>>,
+    name: String,
+    ttl_ms: u64,
+    wait_ms: u64,
+) -> Result<Option<String>, AnyError> {
+    check_name(&name)?;
+    let ttl = check_ttl(ttl_ms)?;
+    let wait = Duration::from_millis(wait_ms).min(MAX_LOCK_WAIT);
+    let provider = provider(&state)?;
+
+    let start = tokio::time::Instant::now();
+    let mut backoff = Duration::from_millis(25);
+    loop {
+        if let Some(token) = provider.try_acquire(&name, ttl).await? {
+            return Ok(Some(token));
+        }
+
+        let remaining = wait.saturating_sub(start.elapsed());
+        if remaining.is_zero() {
+            return Ok(None);
+        }
+
+        // Add some jitter so that scripts waiting on the same lock don't retry in lockstep.
+        let jitter = Duration::from_millis(rand::thread_rng().gen_range(0..25));
+        tokio::time::sleep((backoff + jitter).min(remaining)).await;
+        backoff = (backoff * 2).min(Duration::from_secs(1));
+    }
+}
+
+#[op]
+async fn ergo_lock_extend(
+    state: Rc<RefCell<OpState>>,
+    name: String,
+    token: String,
+    ttl_ms: u64,
+) -> Result<bool, AnyError> {
+    let ttl = check_ttl(ttl_ms)?;
+    let provider = provider(&state)?;
+    provider.extend(&name, &token, ttl).await
+}
+
+#[op]
+async fn ergo_lock_release(
+    state: Rc<RefCell<OpState>>,
+    name: String,
+    token: String,
+) -> Result<bool, AnyError> {
+    let provider = provider(&state)?;
+    provider.release(&name, &token).await
+}
+
+const LOCK_EXTENSION_JS: &str = r##"
+    ((globalThis) => {
+        const core = globalThis.Deno.core;
+
+        class Lock {
+            constructor(name, token) {
+                this.name = name;
+                this.token = token;
+            }
+
+            /** Reset the lock's expiration. Returns false if the lock had already expired. */
+            extend(ttl) {
+                return core.opAsync("ergo_lock_extend", this.name, this.token, ttl);
+            }
+
+            /** Release the lock. Returns false if the lock had already expired. */
+            release() {
+                return core.opAsync("ergo_lock_release", this.name, this.token);
+            }
+        }
+
+        const Ergo = (globalThis.Ergo = globalThis.Ergo || {});
+
+        /** Take a lock, waiting up to `wait` milliseconds for it to be free. */
+        Ergo.lock = async function(name, ttl = 30000, { wait = 10000 } = {}) {
+            const token = await core.opAsync("ergo_lock_acquire", name, ttl, wait);
+            if (!token) {
+                throw new Error(`Timed out waiting for lock ${name}`);
+            }
+            return new Lock(name, token);
+        };
+
+        /** Take a lock if it is free, or return null. */
+        Ergo.tryLock = async function(name, ttl = 30000) {
+            const token = await core.opAsync("ergo_lock_acquire", name, ttl, 0);
+            return token ? new Lock(name, token) : null;
+        };
+
+        /** Run `fn` while holding a lock, and release the lock when it finishes. */
+        Ergo.withLock = async function(name, ttl, fn, options) {
+            const lock = await Ergo.lock(name, ttl, options);
+            try {
+                return await fn(lock);
+            } finally {
+                await lock.release();
+            }
+        };
+    })(globalThis);"##;
+
+pub(crate) fn lock_extension(provider: Option<Rc<dyn LockProvider>>) -> deno_core::Extension {
+    deno_core::Extension::builder()
+        .js(vec![("ergo_locks", LOCK_EXTENSION_JS)])
+        .ops(vec![
+            ergo_lock_acquire::decl(),
+            ergo_lock_extend::decl(),
+            ergo_lock_release::decl(),
+        ])
+        .state(move |state| {
+            state.put(LockProviderWrapper(provider.clone()));
+            Ok(())
+        })
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use fxhash::FxHashMap;
+    use url::Url;
+
+    use super::*;
+    use crate::{Runtime, RuntimeOptions};
+
+    #[derive(Default)]
+    struct MemoryLocks {
+        locks: RefCell<FxHashMap<String, String>>,
+        next_token: RefCell<usize>,
+    }
+
+    #[async_trait::async_trait(?Send)]
+    impl LockProvider for MemoryLocks {
+        async fn try_acquire(
+            &self,
+            name: &str,
+            _ttl: Duration,
+        ) -> Result<Option<String>, AnyError> {
+            let mut locks = self.locks.borrow_mut();
+            if locks.contains_key(name) {
+                return Ok(None);
+            }
+
+            let mut next_token = self.next_token.borrow_mut();
+            *next_token += 1;
+            let token = next_token.to_string();
+            locks.insert(name.to_string(), token.clone());
+            Ok(Some(token))
+        }
+
+        async fn extend(&self, name: &str, token: &str, _ttl: Duration) -> Result<bool, AnyError> {
+            Ok(self.locks.borrow().get(name).map(|t| t.as_str()) == Some(token))
+        }
+
+        async fn release(&self, name: &str, token: &str) -> Result<bool, AnyError> {
+            let mut locks = self.locks.borrow_mut();
+            if locks.get(name).map(|t| t.as_str()) != Some(token) {
+                return Ok(false);
+            }
+
+            locks.remove(name);
+            Ok(true)
+        }
+    }
+
+    async fn run(locks: Option<Rc<dyn LockProvider>>, script: &str) -> Result<(), crate::Error> {
+        let mut runtime = Runtime::new(RuntimeOptions {
+            locks,
+            ..Default::default()
+        });
+        runtime
+            .run_main_module(
+                Url::parse("https://ergo/locks.js").unwrap(),
+                script.to_string(),
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn lock_and_release() {
+        let script = r##"
+            const lock = await Ergo.lock('resource', 1000);
+            if (await Ergo.tryLock('resource', 1000)) {
+                throw new Error('Took a lock that was already held');
+            }
+            if (!(await lock.extend(2000))) {
+                throw new Error('Failed to extend lock');
+            }
+            await lock.release();
+
+            const result = await Ergo.withLock('resource', 1000, async (lock) => lock.token);
+            if (result !== '2') {
+                throw new Error(`Unexpected token ${result}`);
+            }
+            if (!(await Ergo.tryLock('resource', 1000))) {
+                throw new Error('withLock did not release the lock');
+            }
+        "##;
+
+        let locks = Rc::new(MemoryLocks::default());
+        run(Some(locks.clone() as Rc<dyn LockProvider>), script)
+            .await
+            .expect("running script");
+        assert_eq!(
+            locks.locks.borrow().get("resource").map(|t| t.as_str()),
+            Some("3")
+        );
+    }
+
+    #[tokio::test]
+    async fn lock_timeout() {
+        let locks = Rc::new(MemoryLocks::default());
+        locks.try_acquire("resource", MAX_LOCK_TTL).await.unwrap();
+
+        let err = run(
+            Some(locks as Rc<dyn LockProvider>),
+            "await Ergo.lock('resource', 1000, { wait: 50 });",
+        )
+        .await
+        .expect_err("lock should time out");
+        assert!(err
+            .to_string()
+            .contains("Timed out waiting for lock resource"));
+    }
+
+    #[tokio::test]
+    async fn no_provider() {
+        let err = run(None, "await Ergo.lock('resource');")
+            .await
+            .expect_err("lock should fail without a provider");
+        assert!(err.to_string().contains("Locks are not available"));
+    }
+}
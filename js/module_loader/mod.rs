@@ -1,5 +1,7 @@
+use std::collections::HashMap;
+
 use anyhow::anyhow;
-use deno_core::ModuleLoader;
+use deno_core::{ModuleLoader, ModuleSource, ModuleType};
 use futures::FutureExt;
 
 // pub mod memory;
@@ -32,3 +34,49 @@ impl ModuleLoader for TrivialModuleLoader {
         async { Err(anyhow!("Module loading is not supported")) }.boxed_local()
     }
 }
+
+/// Module sources to make available to scripts, keyed by their full specifier.
+pub type ModuleSources = HashMap<String, String>;
+
+/// A module loader that serves a fixed set of modules, such as shared libraries that were
+/// fetched before the script started.
+pub struct StaticModuleLoader {
+    modules: ModuleSources,
+}
+
+impl StaticModuleLoader {
+    pub fn new(modules: ModuleSources) -> Self {
+        StaticModuleLoader { modules }
+    }
+}
+
+impl ModuleLoader for StaticModuleLoader {
+    fn resolve(
+        &self,
+        specifier: &str,
+        referrer: &str,
+        _is_main: bool,
+    ) -> Result<deno_core::ModuleSpecifier, deno_core::error::AnyError> {
+        Ok(deno_core::resolve_import(specifier, referrer)?)
+    }
+
+    fn load(
+        &self,
+        module_specifier: &deno_core::ModuleSpecifier,
+        _maybe_referrer: Option<deno_core::ModuleSpecifier>,
+        _is_dyn_import: bool,
+    ) -> std::pin::Pin<Box<deno_core::ModuleSourceFuture>> {
+        let specifier = module_specifier.to_string();
+        let result = match self.modules.get(&specifier) {
+            Some(code) => Ok(ModuleSource {
+                code: code.as_bytes().to_vec().into_boxed_slice(),
+                module_type: ModuleType::JavaScript,
+                module_url_specified: specifier.clone(),
+                module_url_found: specifier,
+            }),
+            None => Err(anyhow!("Module {} was not found", specifier)),
+        };
+
+        async move { result }.boxed_local()
+    }
+}
@@ -1,4 +1,7 @@
-use std::convert::TryFrom;
+use std::{
+    convert::TryFrom,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs},
+};
 
 use ipnet::IpNet;
 use thiserror::Error;
@@ -10,6 +13,8 @@ pub enum PermissionsError {
     NetAddressDenied,
 }
 
+/// A host and optional port. A host that starts with `*.` matches any subdomain of the rest of
+/// the host.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct NetHostAndPort {
     pub host: String,
@@ -18,7 +23,16 @@ pub struct NetHostAndPort {
 
 impl NetHostAndPort {
     fn check<T: AsRef<str>>(&self, host: T, port: Option<u16>) -> bool {
-        if self.host != host.as_ref() {
+        let host = host.as_ref().to_ascii_lowercase();
+        let host_matches = match self.host.strip_prefix("*.") {
+            Some(domain) => host
+                .strip_suffix(domain)
+                .map(|sub| sub.len() > 1 && sub.ends_with('.'))
+                .unwrap_or(false),
+            None => self.host == host,
+        };
+
+        if !host_matches {
             return false;
         }
 
@@ -34,6 +48,14 @@ impl TryFrom<&str> for NetHostAndPort {
     type Error = url::ParseError;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
+        if let Some(rest) = value.strip_prefix("*.") {
+            let domain = NetHostAndPort::try_from(rest)?;
+            return Ok(NetHostAndPort {
+                host: format!("*.{}", domain.host),
+                port: domain.port,
+            });
+        }
+
         let (url, port) = if value.contains('/') {
             let url = Url::parse(value)?;
             let port = url.port_or_known_default();
@@ -62,6 +84,9 @@ pub struct Permissions {
     pub net_allow_list: Vec<NetHostAndPort>,
     /// Block access to certain domains or IP addresses.
     pub net_block_list: Vec<NetHostAndPort>,
+    /// If not empty, only these hosts can be accessed, on top of the other rules. Unlike
+    /// `net_allow_list`, this can't be satisfied by `cidr_allow_list`.
+    pub net_restrict_list: Vec<NetHostAndPort>,
 
     /// Allow access only to certain IP ranges
     pub cidr_allow_list: Vec<IpNet>,
@@ -69,19 +94,117 @@ pub struct Permissions {
     /// Block access to certain IP ranges. Recommended when hosting public users
     /// to prevent fetch requests to internal network resources.
     pub cidr_block_list: Vec<IpNet>,
+
+    /// Block access to loopback, private, and link-local addresses, unless they are in
+    /// `cidr_allow_list`.
+    pub block_private_networks: bool,
+}
+
+/// Returns true for addresses that point into a local or internal network.
+pub fn is_private_address(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_private_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => is_private_v4(&v4),
+            None => is_private_v6(ip),
+        },
+    }
+}
+
+fn is_private_v4(ip: &Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        // Shared address space used for carrier-grade NAT, 100.64.0.0/10
+        || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+}
+
+fn is_private_v6(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        // Unique local addresses, fc00::/7
+        || (first & 0xfe00) == 0xfc00
+        // Link-local addresses, fe80::/10
+        || (first & 0xffc0) == 0xfe80
 }
 
 impl Permissions {
-    fn check_host(&self, host: &str, port: Option<u16>) -> Result<(), PermissionsError> {
-        if self.net_block_list.iter().any(|hp| hp.check(host, port))
-            || (!self.net_allow_list.is_empty()
-                && !self.net_allow_list.iter().any(|hp| hp.check(host, port)))
+    fn checks_addresses(&self) -> bool {
+        self.block_private_networks
+            || !self.cidr_allow_list.is_empty()
+            || !self.cidr_block_list.is_empty()
+    }
+
+    /// Get the IP addresses for the host. This only does a DNS lookup if there are rules
+    /// that look at IP addresses.
+    fn addresses(&self, host: &str, port: Option<u16>) -> Vec<IpAddr> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return vec![ip];
+        }
+
+        if !self.checks_addresses() {
+            return Vec::new();
+        }
+
+        // If the lookup fails then the request will fail too, so there's nothing to check.
+        (host, port.unwrap_or(0))
+            .to_socket_addrs()
+            .map(|addrs| addrs.map(|a| a.ip()).collect())
+            .unwrap_or_default()
+    }
+
+    fn cidr_allowed(&self, ip: &IpAddr) -> bool {
+        self.cidr_allow_list.iter().any(|net| net.contains(ip))
+    }
+
+    /// Check if a host and port can be accessed.
+    pub fn check_host(&self, host: &str, port: Option<u16>) -> Result<(), PermissionsError> {
+        if self.net_block_list.iter().any(|hp| hp.check(host, port)) {
+            return Err(PermissionsError::NetAddressDenied);
+        }
+
+        if !self.net_restrict_list.is_empty()
+            && !self.net_restrict_list.iter().any(|hp| hp.check(host, port))
         {
             return Err(PermissionsError::NetAddressDenied);
         }
 
+        let addresses = self.addresses(host, port);
+        let blocked_address = addresses.iter().any(|ip| {
+            self.cidr_block_list.iter().any(|net| net.contains(ip))
+                || (self.block_private_networks && is_private_address(ip) && !self.cidr_allowed(ip))
+        });
+        if blocked_address {
+            return Err(PermissionsError::NetAddressDenied);
+        }
+
+        if !self.net_allow_list.is_empty() || !self.cidr_allow_list.is_empty() {
+            let host_allowed = self.net_allow_list.iter().any(|hp| hp.check(host, port));
+            let address_allowed =
+                !addresses.is_empty() && addresses.iter().all(|ip| self.cidr_allowed(ip));
+            if !host_allowed && !address_allowed {
+                return Err(PermissionsError::NetAddressDenied);
+            }
+        }
+
         Ok(())
     }
+
+    /// Check if a URL can be accessed.
+    pub fn check_url(&self, url: &Url) -> Result<(), PermissionsError> {
+        let host = match (url.host_str(), self.allow_relative_urls) {
+            (Some(host), _) => host,
+            (None, true) => return Ok(()),
+            (None, false) => return Err(PermissionsError::NetAddressDenied),
+        };
+
+        self.check_host(host, url.port_or_known_default())
+    }
 }
 
 impl deno_net::NetPermissions for Permissions {
@@ -90,7 +213,6 @@ impl deno_net::NetPermissions for Permissions {
         host: &(T, Option<u16>),
         api_name: &str,
     ) -> Result<(), deno_core::error::AnyError> {
-        // Check the host. CIDR matching takes place at a lower level.
         self.check_host(host.0.as_ref(), host.1)?;
         Ok(())
     }
@@ -118,14 +240,7 @@ impl deno_fetch::FetchPermissions for Permissions {
         url: &url::Url,
         api_name: &str,
     ) -> Result<(), deno_core::error::AnyError> {
-        let host = match (url.host_str(), self.allow_relative_urls) {
-            (Some(host), _) => host,
-            (None, true) => return Ok(()),
-            (None, false) => return Err(PermissionsError::NetAddressDenied.into()),
-        };
-
-        let port = url.port_or_known_default();
-        self.check_host(host, port)?;
+        self.check_url(url)?;
         Ok(())
     }
 
@@ -214,5 +329,122 @@ mod tests {
             assert!(NetHostAndPort::try_from("/abc").is_err());
             assert!(NetHostAndPort::try_from(":34").is_err());
         }
+
+        #[test]
+        fn wildcard() {
+            let hp = NetHostAndPort::try_from("*.example.com").unwrap();
+            assert_eq!(hp.host, "*.example.com");
+            assert!(hp.check("api.example.com", Some(443)));
+            assert!(hp.check("a.b.Example.com", None));
+            assert!(!hp.check("example.com", None));
+            assert!(!hp.check("badexample.com", None));
+        }
+    }
+
+    mod check_host {
+        use super::*;
+
+        fn blocking_private() -> Permissions {
+            Permissions {
+                block_private_networks: true,
+                ..Default::default()
+            }
+        }
+
+        #[test]
+        fn default_allows_all() {
+            let perms = Permissions::default();
+            assert!(perms.check_host("127.0.0.1", Some(80)).is_ok());
+            assert!(perms.check_host("example.com", None).is_ok());
+        }
+
+        #[test]
+        fn private_networks() {
+            let perms = blocking_private();
+            for host in [
+                "127.0.0.1",
+                "10.1.2.3",
+                "172.16.0.5",
+                "192.168.1.1",
+                "169.254.169.254",
+                "100.64.0.1",
+                "0.0.0.0",
+                "[::1]",
+                "[fd00::1]",
+                "[fe80::1]",
+                "[::ffff:10.0.0.1]",
+            ] {
+                assert!(perms.check_host(host, None).is_err(), "{} is blocked", host);
+            }
+
+            assert!(perms.check_host("93.184.216.34", None).is_ok());
+            assert!(perms.check_host("[2606:2800:220:1::1]", None).is_ok());
+        }
+
+        #[test]
+        fn cidr_allow_overrides_private_block() {
+            let perms = Permissions {
+                cidr_allow_list: vec!["10.1.0.0/16".parse().unwrap()],
+                ..blocking_private()
+            };
+
+            assert!(perms.check_host("10.1.2.3", None).is_ok());
+            assert!(perms.check_host("10.2.0.1", None).is_err());
+        }
+
+        #[test]
+        fn cidr_block() {
+            let perms = Permissions {
+                cidr_block_list: vec!["93.184.0.0/16".parse().unwrap()],
+                ..Default::default()
+            };
+
+            assert!(perms.check_host("93.184.216.34", None).is_err());
+            assert!(perms.check_host("8.8.8.8", None).is_ok());
+        }
+
+        #[test]
+        fn host_lists() {
+            let perms = Permissions {
+                net_allow_list: vec![
+                    NetHostAndPort::try_from("*.example.com").unwrap(),
+                    NetHostAndPort::try_from("example.org:8080").unwrap(),
+                ],
+                net_block_list: vec![NetHostAndPort::try_from("internal.example.com").unwrap()],
+                ..Default::default()
+            };
+
+            assert!(perms.check_host("api.example.com", Some(443)).is_ok());
+            assert!(perms.check_host("example.org", Some(8080)).is_ok());
+            assert!(perms.check_host("example.org", Some(443)).is_err());
+            assert!(perms.check_host("internal.example.com", None).is_err());
+            assert!(perms.check_host("example.net", None).is_err());
+        }
+
+        #[test]
+        fn restrict_list() {
+            let perms = Permissions {
+                net_restrict_list: vec![NetHostAndPort::try_from("api.example.com").unwrap()],
+                cidr_allow_list: vec!["93.184.0.0/16".parse().unwrap()],
+                ..Default::default()
+            };
+
+            assert!(perms.check_host("api.example.com", Some(443)).is_ok());
+            assert!(perms.check_host("example.com", None).is_err());
+            assert!(
+                perms.check_host("93.184.216.34", None).is_err(),
+                "allowed network doesn't bypass the restrict list"
+            );
+        }
+
+        #[test]
+        fn check_url() {
+            let perms = blocking_private();
+            let url = Url::parse("http://127.0.0.1:8080/metadata").unwrap();
+            assert!(perms.check_url(&url).is_err());
+
+            let url = Url::parse("https://93.184.216.34/").unwrap();
+            assert!(perms.check_url(&url).is_ok());
+        }
     }
 }
@@ -1,15 +1,82 @@
-use std::{fmt::Debug, pin::Pin, sync::Arc};
+use std::{
+    cell::RefCell,
+    fmt::Debug,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
+use chrono::{DateTime, Utc};
 use futures::{
     future::{ready, FutureExt},
     Future,
 };
+use serde::Serialize;
 use tokio::{sync::oneshot, time::error::Elapsed};
 
+use crate::{Runtime, RuntimeOptions};
+
 lazy_static::lazy_static! {
     static ref NUM_CPUS : usize = num_cpus::get();
 }
 
+thread_local! {
+    /// The counters for the pool that owns the current worker thread, so that runtimes
+    /// can report problems without knowing which pool they are running in.
+    static WORKER_COUNTERS: RefCell<Option<Arc<PoolCounters>>> = RefCell::new(None);
+}
+
+#[derive(Debug, Default)]
+struct PoolCounters {
+    queued: AtomicUsize,
+    active: AtomicUsize,
+    completed: AtomicU64,
+    total_run_micros: AtomicU64,
+    out_of_memory: AtomicU64,
+    panicked: AtomicU64,
+    last_probe: Mutex<Option<ProbeResult>>,
+}
+
+/// The result of the most recent self-test probe.
+#[derive(Clone, Debug, Serialize)]
+pub struct ProbeResult {
+    pub at: DateTime<Utc>,
+    pub ok: bool,
+    /// How long the probe took, or the timeout if it failed.
+    #[serde(with = "serde_millis")]
+    pub duration: Duration,
+    /// The number of probes in a row that have failed.
+    pub consecutive_failures: u32,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PoolStats {
+    pub threads: usize,
+    /// Jobs waiting for a worker thread to pick them up.
+    pub queued: usize,
+    /// Jobs currently running. Each of these has its own isolate.
+    pub active: usize,
+    pub completed: u64,
+    pub average_run_ms: f64,
+    /// Runtimes that were terminated for going over their heap limit.
+    pub out_of_memory: u64,
+    /// Jobs that were terminated by a panic, which includes fatal V8 errors.
+    pub panicked: u64,
+    pub last_probe: Option<ProbeResult>,
+}
+
+/// Record that the runtime on this thread was terminated for using too much memory.
+pub(crate) fn record_out_of_memory() {
+    WORKER_COUNTERS.with(|counters| {
+        if let Some(counters) = counters.borrow().as_ref() {
+            counters.out_of_memory.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+}
+
 #[async_trait::async_trait]
 trait AnyJob: Send {
     fn run(&mut self) -> Pin<Box<dyn Future<Output = ()>>>;
@@ -49,6 +116,7 @@ pub struct RuntimePool(Arc<RuntimePoolInner>);
 struct RuntimePoolInner {
     sender: async_channel::Sender<Box<dyn AnyJob>>,
     threads: Vec<std::thread::JoinHandle<()>>,
+    counters: Arc<PoolCounters>,
 }
 
 impl std::fmt::Debug for RuntimePoolInner {
@@ -66,17 +134,98 @@ impl RuntimePool {
                 .unwrap_or(*NUM_CPUS)
         });
         let (s, r) = async_channel::unbounded();
+        let counters = Arc::new(PoolCounters::default());
 
         let threads = itertools::repeat_n(r, num_threads)
-            .map(|r| std::thread::spawn(|| worker(r)))
+            .map(|r| {
+                let counters = counters.clone();
+                std::thread::spawn(|| worker(r, counters))
+            })
             .collect::<Vec<_>>();
 
-        Self(Arc::new(RuntimePoolInner { sender: s, threads }))
+        Self(Arc::new(RuntimePoolInner {
+            sender: s,
+            threads,
+            counters,
+        }))
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let counters = &self.0.counters;
+        let completed = counters.completed.load(Ordering::Relaxed);
+        let total_run_micros = counters.total_run_micros.load(Ordering::Relaxed);
+        let average_run_ms = if completed == 0 {
+            0.0
+        } else {
+            total_run_micros as f64 / completed as f64 / 1000.0
+        };
+
+        PoolStats {
+            threads: self.0.threads.len(),
+            queued: counters.queued.load(Ordering::Relaxed),
+            active: counters.active.load(Ordering::Relaxed),
+            completed,
+            average_run_ms,
+            out_of_memory: counters.out_of_memory.load(Ordering::Relaxed),
+            panicked: counters.panicked.load(Ordering::Relaxed),
+            last_probe: counters.last_probe.lock().unwrap().clone(),
+        }
+    }
+
+    /// False if the most recent probe failed.
+    pub fn healthy(&self) -> bool {
+        self.0
+            .counters
+            .last_probe
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|p| p.ok)
+            .unwrap_or(true)
+    }
+
+    /// Run a trivial script in the pool to check that the workers are still able to run
+    /// isolates. A probe that doesn't finish within `timeout` counts as a failure.
+    pub async fn probe(&self, timeout: Duration) -> ProbeResult {
+        let start = Instant::now();
+        let result = tokio::time::timeout(
+            timeout,
+            self.run(|| async {
+                let mut runtime = Runtime::new(RuntimeOptions {
+                    extensions: crate::core_extensions(None),
+                    ..Default::default()
+                });
+                runtime
+                    .run_expression::<usize>("probe", "1 + 1")
+                    .map(|v| v == 2)
+                    .unwrap_or(false)
+            }),
+        )
+        .await;
+
+        let ok = result.unwrap_or(false);
+        let mut last_probe = self.0.counters.last_probe.lock().unwrap();
+        let consecutive_failures = match (ok, last_probe.as_ref()) {
+            (true, _) => 0,
+            (false, Some(last)) => last.consecutive_failures + 1,
+            (false, None) => 1,
+        };
+
+        let probe = ProbeResult {
+            at: Utc::now(),
+            ok,
+            duration: if ok { start.elapsed() } else { timeout },
+            consecutive_failures,
+        };
+        *last_probe = Some(probe.clone());
+        probe
     }
 
     /// Shut down the pool and wait for all the threads to finish processing the remaining jobs.
     pub async fn close(self, timeout: Option<tokio::time::Duration>) -> Result<(), Elapsed> {
-        let RuntimePoolInner { sender, threads } = Arc::try_unwrap(self.0).unwrap();
+        let RuntimePoolInner {
+            sender, threads, ..
+        } = Arc::try_unwrap(self.0).unwrap();
         let stop = tokio::task::spawn_blocking(move || {
             drop(sender);
             for t in threads {
@@ -107,12 +256,15 @@ impl RuntimePool {
             data: Some((Box::new(run_fn), s)),
         };
 
+        self.0.counters.queued.fetch_add(1, Ordering::Relaxed);
         self.0.sender.send(Box::new(job)).await;
         r.await.unwrap()
     }
 }
 
-fn worker(r: async_channel::Receiver<Box<dyn AnyJob>>) {
+fn worker(r: async_channel::Receiver<Box<dyn AnyJob>>, counters: Arc<PoolCounters>) {
+    WORKER_COUNTERS.with(|c| *c.borrow_mut() = Some(counters.clone()));
+
     let runtime = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()
@@ -121,8 +273,26 @@ fn worker(r: async_channel::Receiver<Box<dyn AnyJob>>) {
         let local_set = tokio::task::LocalSet::new();
         local_set.spawn_local(async move {
             while let Ok(mut job) = r.recv().await {
+                counters.queued.fetch_sub(1, Ordering::Relaxed);
+                counters.active.fetch_add(1, Ordering::Relaxed);
+                let counters = counters.clone();
                 tokio::task::spawn_local(async move {
-                    job.run().await;
+                    let start = Instant::now();
+                    // Run the job in its own task so that a panic is caught and counted here.
+                    let result = tokio::task::spawn_local(job.run()).await;
+
+                    counters.active.fetch_sub(1, Ordering::Relaxed);
+                    match result {
+                        Ok(()) => {
+                            counters.completed.fetch_add(1, Ordering::Relaxed);
+                            counters
+                                .total_run_micros
+                                .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+                        }
+                        Err(_) => {
+                            counters.panicked.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
                 });
             }
         });
@@ -136,6 +306,35 @@ mod tests {
     use super::*;
     use crate::{Runtime, RuntimeOptions};
 
+    #[tokio::test]
+    async fn stats_and_probe() {
+        let pool = RuntimePool::new(Some(1));
+
+        let probe = pool.probe(Duration::from_secs(5)).await;
+        assert!(probe.ok, "probe succeeded");
+        assert!(pool.healthy());
+
+        // The worker updates the counters just after sending the result, so give it a moment.
+        let mut stats = pool.stats();
+        for _ in 0..50 {
+            if stats.completed > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            stats = pool.stats();
+        }
+
+        assert_eq!(stats.threads, 1);
+        assert_eq!(stats.queued, 0);
+        assert_eq!(stats.active, 0);
+        assert_eq!(stats.completed, 1);
+        assert_eq!(stats.last_probe.map(|p| p.consecutive_failures), Some(0));
+
+        pool.close(Some(Duration::from_secs(10)))
+            .await
+            .expect("close timed out");
+    }
+
     #[tokio::test]
     async fn run_job() {
         let pool = RuntimePool::new(Some(2));
@@ -0,0 +1,74 @@
+//! Stop scripts that run for too long. V8 can only be interrupted from another thread, so a
+//! watchdog thread terminates the isolate once the limit passes. A script stuck in a loop never
+//! yields to the event loop, so a timer on the runtime's own thread wouldn't fire.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::Runtime;
+
+/// Terminates the runtime's execution if this is still alive after the limit. Drop it when the
+/// script finishes.
+pub struct TimeLimit {
+    // Dropping the sender wakes up the watchdog thread so that it exits right away.
+    _done: mpsc::Sender<()>,
+    expired: Arc<AtomicBool>,
+}
+
+impl TimeLimit {
+    pub fn start(runtime: &mut Runtime, limit: Duration) -> TimeLimit {
+        let handle = runtime.v8_isolate().thread_safe_handle();
+        let (done, wait) = mpsc::channel::<()>();
+        let expired = Arc::new(AtomicBool::new(false));
+
+        let thread_expired = expired.clone();
+        std::thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = wait.recv_timeout(limit) {
+                thread_expired.store(true, Ordering::Relaxed);
+                handle.terminate_execution();
+            }
+        });
+
+        TimeLimit {
+            _done: done,
+            expired,
+        }
+    }
+
+    /// True if the limit passed and the execution was terminated.
+    pub fn expired(&self) -> bool {
+        self.expired.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RuntimeOptions;
+
+    #[test]
+    fn terminates_long_script() {
+        let mut runtime = Runtime::new(RuntimeOptions::default());
+        let limit = TimeLimit::start(&mut runtime, Duration::from_millis(100));
+        let result = runtime.execute_script("loop", "while(true) {}");
+        assert!(result.is_err(), "script should be terminated");
+        assert!(limit.expired());
+    }
+
+    #[test]
+    fn fast_script() {
+        let mut runtime = Runtime::new(RuntimeOptions::default());
+        let limit = TimeLimit::start(&mut runtime, Duration::from_secs(5));
+        let result: i64 = runtime
+            .run_expression("add", "1 + 2")
+            .expect("running script");
+        drop(limit);
+        assert_eq!(result, 3);
+    }
+}
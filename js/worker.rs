@@ -3,7 +3,7 @@ use std::{fmt::Debug, future::ready, pin::Pin};
 use futures::{future::FutureExt, Future};
 use tokio::{sync::oneshot, task::JoinError};
 
-use crate::{BufferConsole, Runtime, RuntimeOptions};
+use crate::{permissions::Permissions, BufferConsole, Runtime, RuntimeOptions};
 
 trait WorkerJob: Send {
     fn run<'run>(&mut self, runtime: &'run mut Runtime) -> JobFuture<'run, ()>;
@@ -44,8 +44,13 @@ pub struct JsWorker {
 
 impl JsWorker {
     pub fn new() -> JsWorker {
+        Self::with_permissions(Permissions::default())
+    }
+
+    /// Create a worker whose runtime has network access restricted by `permissions`.
+    pub fn with_permissions(permissions: Permissions) -> JsWorker {
         let (sender, receiver) = async_channel::bounded(1);
-        let worker_thread = std::thread::spawn(|| worker(receiver));
+        let worker_thread = std::thread::spawn(|| worker(receiver, permissions));
 
         JsWorker {
             worker_thread,
@@ -89,7 +94,7 @@ impl Default for JsWorker {
     }
 }
 
-fn worker(r: async_channel::Receiver<Box<dyn WorkerJob>>) {
+fn worker(r: async_channel::Receiver<Box<dyn WorkerJob>>, permissions: Permissions) {
     let runtime = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()
@@ -99,6 +104,7 @@ fn worker(r: async_channel::Receiver<Box<dyn WorkerJob>>) {
         local_set.spawn_local(async move {
             let mut runtime = Runtime::new(RuntimeOptions {
                 console: Some(Box::new(BufferConsole::new(crate::ConsoleLevel::Info))),
+                permissions: Some(permissions),
                 ..Default::default()
             });
             while let Ok(mut job) = r.recv().await {
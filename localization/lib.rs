@@ -0,0 +1,256 @@
+//! Message catalogs for user-facing strings.
+//!
+//! Each catalog is a flat map of message keys to message templates, stored as a JSON object.
+//! Templates can contain `{name}` placeholders, which are filled in by [format_message].
+//! English is built in and is always used as the fallback when a locale or a key is missing.
+//!
+//! Deployments can override built-in messages or add new locales by placing `<locale>.json`
+//! files in the directory named by the `LOCALE_DIR` environment variable and calling
+//! [load_catalogs_from_env] at startup.
+
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use fxhash::FxHashMap;
+use lazy_static::lazy_static;
+use thiserror::Error;
+
+pub const DEFAULT_LOCALE: &str = "en";
+
+pub type Catalog = FxHashMap<String, String>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Reading catalog {path}: {source}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Parsing catalog {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        #[source]
+        source: serde_json::Error,
+    },
+}
+
+lazy_static! {
+    static ref CATALOGS: RwLock<FxHashMap<String, Catalog>> = {
+        let mut catalogs = FxHashMap::default();
+        let en: Catalog = serde_json::from_str(include_str!("catalogs/en.json"))
+            .expect("Built-in English catalog is valid");
+        catalogs.insert(DEFAULT_LOCALE.to_string(), en);
+        RwLock::new(catalogs)
+    };
+}
+
+/// Normalize a locale tag so that `pt_BR`, `pt-br`, and `pt-BR` all refer to the same catalog.
+pub fn normalize_locale(locale: &str) -> String {
+    locale.trim().replace('_', "-").to_lowercase()
+}
+
+/// Add messages to the catalog for a locale. Keys that already exist are overwritten, so
+/// this can be used to override individual built-in messages.
+pub fn add_catalog(locale: &str, messages: Catalog) {
+    let mut catalogs = CATALOGS.write().unwrap();
+    catalogs
+        .entry(normalize_locale(locale))
+        .or_default()
+        .extend(messages);
+}
+
+/// Load a single catalog file. The locale is taken from the file name, e.g. `fr.json`.
+pub fn load_catalog_file(path: &Path) -> Result<(), Error> {
+    let locale = match path.file_stem().and_then(|s| s.to_str()) {
+        Some(l) => l,
+        None => return Ok(()),
+    };
+
+    let data = std::fs::read_to_string(path).map_err(|source| Error::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let messages: Catalog = serde_json::from_str(&data).map_err(|source| Error::Parse {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    add_catalog(locale, messages);
+    Ok(())
+}
+
+/// Load every `*.json` file in a directory as a catalog. Returns the number of files loaded.
+pub fn load_catalog_dir(dir: &Path) -> Result<usize, Error> {
+    let entries = std::fs::read_dir(dir).map_err(|source| Error::Io {
+        path: dir.to_path_buf(),
+        source,
+    })?;
+
+    let mut count = 0;
+    for entry in entries {
+        let path = entry
+            .map_err(|source| Error::Io {
+                path: dir.to_path_buf(),
+                source,
+            })?
+            .path();
+        if path.extension().map(|e| e == "json").unwrap_or(false) {
+            load_catalog_file(&path)?;
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+/// Load catalogs from the directory in the `LOCALE_DIR` environment variable, if it is set.
+pub fn load_catalogs_from_env() -> Result<usize, Error> {
+    match std::env::var("LOCALE_DIR") {
+        Ok(dir) if !dir.is_empty() => load_catalog_dir(Path::new(&dir)),
+        _ => Ok(0),
+    }
+}
+
+/// Return the list of locales with a loaded catalog.
+pub fn available_locales() -> Vec<String> {
+    let mut locales = CATALOGS.read().unwrap().keys().cloned().collect::<Vec<_>>();
+    locales.sort();
+    locales
+}
+
+fn lookup(catalogs: &FxHashMap<String, Catalog>, locale: &str, key: &str) -> Option<String> {
+    catalogs
+        .get(locale)
+        .and_then(|c| c.get(key))
+        .map(|m| m.to_string())
+}
+
+/// Look up a message. The search order is the exact locale, then its base language (`pt` for
+/// `pt-BR`), then English. If no catalog has the key, the key itself is returned.
+pub fn message(locale: Option<&str>, key: &str) -> String {
+    let catalogs = CATALOGS.read().unwrap();
+
+    if let Some(locale) = locale.map(normalize_locale) {
+        if let Some(m) = lookup(&catalogs, &locale, key) {
+            return m;
+        }
+
+        if let Some((language, _)) = locale.split_once('-') {
+            if let Some(m) = lookup(&catalogs, language, key) {
+                return m;
+            }
+        }
+    }
+
+    lookup(&catalogs, DEFAULT_LOCALE, key).unwrap_or_else(|| key.to_string())
+}
+
+/// Look up a message and fill in its `{name}` placeholders from `args`. Placeholders
+/// without a matching argument are left as-is.
+pub fn format_message(locale: Option<&str>, key: &str, args: &[(&str, &dyn Display)]) -> String {
+    let template = message(locale, key);
+    if args.is_empty() {
+        return template;
+    }
+
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template.as_str();
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after.find('}') {
+            Some(end) => {
+                let name = &after[..end];
+                match args.iter().find(|(arg, _)| *arg == name) {
+                    Some((_, value)) => output.push_str(&value.to_string()),
+                    None => {
+                        output.push('{');
+                        output.push_str(name);
+                        output.push('}');
+                    }
+                }
+                rest = &after[end + 1..];
+            }
+            None => {
+                output.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    output.push_str(rest);
+
+    output
+}
+
+/// Types that can render a user-facing message in a particular locale.
+pub trait Localize {
+    fn localize(&self, locale: Option<&str>) -> String;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn falls_back_to_english() {
+        assert_eq!(message(Some("xx"), "notify.field.task"), "Task");
+        assert_eq!(message(None, "notify.field.task"), "Task");
+    }
+
+    #[test]
+    fn missing_key_returns_key() {
+        assert_eq!(message(Some("en"), "no.such.key"), "no.such.key");
+    }
+
+    #[test]
+    fn uses_base_language() {
+        let mut catalog = Catalog::default();
+        catalog.insert("notify.field.task".to_string(), "Tarefa".to_string());
+        add_catalog("zz", catalog);
+
+        assert_eq!(message(Some("zz_BR"), "notify.field.task"), "Tarefa");
+        assert_eq!(message(Some("ZZ"), "notify.field.task"), "Tarefa");
+        // Keys missing from the catalog still fall back to English.
+        assert_eq!(message(Some("zz"), "notify.field.error"), "Error");
+    }
+
+    #[test]
+    fn override_replaces_single_key() {
+        let mut catalog = Catalog::default();
+        catalog.insert("test.override".to_string(), "first".to_string());
+        catalog.insert("test.kept".to_string(), "kept".to_string());
+        add_catalog("yy", catalog);
+
+        let mut catalog = Catalog::default();
+        catalog.insert("test.override".to_string(), "second".to_string());
+        add_catalog("yy", catalog);
+
+        assert_eq!(message(Some("yy"), "test.override"), "second");
+        assert_eq!(message(Some("yy"), "test.kept"), "kept");
+    }
+
+    #[test]
+    fn formats_arguments() {
+        let result = format_message(
+            None,
+            "validate.template.required",
+            &[("name", &"url"), ("unused", &5)],
+        );
+        assert_eq!(result, "Field url is required");
+    }
+
+    #[test]
+    fn leaves_unknown_placeholders() {
+        let mut catalog = Catalog::default();
+        catalog.insert("test.placeholders".to_string(), "{a} and {b} {".to_string());
+        add_catalog("ww", catalog);
+
+        let result = format_message(Some("ww"), "test.placeholders", &[("a", &1)]);
+        assert_eq!(result, "1 and {b} {");
+    }
+}
@@ -0,0 +1,4 @@
+BEGIN;
+ALTER TABLE orgs DROP COLUMN locale;
+ALTER TABLE users DROP COLUMN locale;
+COMMIT;
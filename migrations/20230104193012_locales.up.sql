@@ -0,0 +1,9 @@
+BEGIN;
+ALTER TABLE orgs ADD COLUMN locale text;
+ALTER TABLE users ADD COLUMN locale text;
+
+GRANT SELECT(locale) ON orgs TO ergo_enqueuer;
+GRANT SELECT(locale) ON orgs TO ergo_backend;
+GRANT SELECT(locale) ON users TO ergo_enqueuer;
+GRANT SELECT(locale) ON users TO ergo_backend;
+COMMIT;
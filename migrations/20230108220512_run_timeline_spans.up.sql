@@ -0,0 +1,15 @@
+CREATE TABLE run_timeline_spans (
+  run_timeline_span_id bigint primary key generated always as identity,
+  inputs_log_id uuid,
+  actions_log_id uuid,
+  name text not null,
+  start_time timestamptz not null,
+  end_time timestamptz not null,
+  info jsonb
+);
+
+CREATE INDEX ON run_timeline_spans (inputs_log_id);
+CREATE INDEX ON run_timeline_spans (actions_log_id);
+
+GRANT SELECT ON run_timeline_spans TO ergo_web;
+GRANT SELECT, INSERT, DELETE ON run_timeline_spans TO ergo_backend;
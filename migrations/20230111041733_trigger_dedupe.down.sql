@@ -0,0 +1,13 @@
+REVOKE SELECT(inputs_log_id, task_trigger_id, dedupe_key, status, created) ON inputs_log FROM ergo_enqueuer;
+DROP INDEX inputs_log_dedupe_idx;
+ALTER TABLE inputs_log DROP COLUMN dedupe_key;
+ALTER TABLE task_triggers DROP COLUMN dedupe;
+
+-- Postgres can't remove enum values, so recreate the type without 'duplicate'.
+DELETE FROM inputs_log WHERE status = 'duplicate';
+ALTER TYPE input_status RENAME TO input_status_old;
+CREATE TYPE input_status AS ENUM ('pending', 'success', 'error');
+ALTER TABLE inputs_log ALTER COLUMN status DROP DEFAULT;
+ALTER TABLE inputs_log ALTER COLUMN status TYPE input_status USING status::text::input_status;
+ALTER TABLE inputs_log ALTER COLUMN status SET DEFAULT 'pending';
+DROP TYPE input_status_old;
@@ -0,0 +1,10 @@
+ALTER TYPE input_status ADD VALUE 'duplicate';
+
+ALTER TABLE task_triggers ADD COLUMN dedupe jsonb;
+COMMENT ON COLUMN task_triggers.dedupe IS 'Drop inputs with the same key that arrive within a time window';
+
+ALTER TABLE inputs_log ADD COLUMN dedupe_key text;
+CREATE INDEX inputs_log_dedupe_idx ON inputs_log (task_trigger_id, dedupe_key, created)
+  WHERE dedupe_key IS NOT NULL;
+
+GRANT SELECT(inputs_log_id, task_trigger_id, dedupe_key, status, created) ON inputs_log TO ergo_enqueuer;
@@ -0,0 +1,15 @@
+CREATE TABLE sessions (
+  session_id uuid primary key,
+  user_id uuid not null references users ON DELETE CASCADE,
+  created timestamptz not null default now(),
+  last_seen timestamptz not null default now(),
+  expires timestamptz not null,
+  revoked boolean not null default false,
+  user_agent text,
+  ip_address text
+);
+
+CREATE INDEX ON sessions (user_id);
+
+GRANT SELECT, UPDATE(last_seen) ON sessions TO ergo_backend;
+GRANT SELECT, UPDATE, DELETE, INSERT ON sessions TO ergo_web;
@@ -0,0 +1,18 @@
+CREATE TABLE action_artifacts (
+  artifact_id uuid primary key,
+  actions_log_id uuid not null references actions_log ON DELETE CASCADE,
+  task_id uuid not null,
+  -- JSON pointer to the location of the artifact in the action's result.
+  path text not null,
+  content_type text not null,
+  size bigint not null,
+  data bytea not null,
+  created timestamptz not null default now(),
+  expires timestamptz not null
+);
+
+CREATE INDEX ON action_artifacts (actions_log_id);
+CREATE INDEX ON action_artifacts (expires);
+
+GRANT SELECT ON action_artifacts TO ergo_web;
+GRANT SELECT, INSERT, DELETE ON action_artifacts TO ergo_backend;
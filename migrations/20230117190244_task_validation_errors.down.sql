@@ -0,0 +1,14 @@
+ALTER TABLE tasks DROP COLUMN validation_errors;
+
+-- Postgres can't remove enum values, so recreate the type without 'task_invalidated'.
+DELETE FROM notify_listeners WHERE event = 'task_invalidated';
+ALTER TYPE notify_event RENAME TO notify_event_old;
+CREATE TYPE notify_event AS ENUM (
+  'input_arrived',
+  'input_processed',
+  'action_started',
+  'action_success',
+  'action_error'
+);
+ALTER TABLE notify_listeners ALTER COLUMN event TYPE notify_event USING event::text::notify_event;
+DROP TYPE notify_event_old;
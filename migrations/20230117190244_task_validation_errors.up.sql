@@ -0,0 +1,4 @@
+-- Errors from re-validating a task after an action or input that it uses was changed.
+ALTER TABLE tasks ADD COLUMN validation_errors jsonb;
+
+ALTER TYPE notify_event ADD VALUE 'task_invalidated';
@@ -0,0 +1,2 @@
+DROP TABLE org_usage;
+DROP TABLE org_quotas;
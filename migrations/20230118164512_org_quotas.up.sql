@@ -0,0 +1,27 @@
+-- Quotas are NULL when there is no limit.
+CREATE TABLE org_quotas (
+  org_id uuid primary key references orgs ON DELETE CASCADE,
+  max_tasks bigint,
+  max_inputs_per_day bigint,
+  max_actions_per_day bigint,
+  max_js_cpu_ms_per_day bigint,
+  updated timestamptz not null default now()
+);
+
+-- Usage counters for the quotas that reset each day, in UTC.
+CREATE TABLE org_usage (
+  org_id uuid not null references orgs ON DELETE CASCADE,
+  day date not null,
+  inputs bigint not null default 0,
+  actions bigint not null default 0,
+  js_cpu_ms bigint not null default 0,
+  primary key (org_id, day)
+);
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON org_quotas TO ergo_web;
+GRANT SELECT ON org_quotas TO ergo_backend;
+GRANT SELECT ON org_quotas TO ergo_enqueuer;
+
+GRANT SELECT ON org_usage TO ergo_web;
+GRANT SELECT, INSERT, UPDATE ON org_usage TO ergo_backend;
+GRANT SELECT, INSERT, UPDATE ON org_usage TO ergo_enqueuer;
@@ -0,0 +1 @@
+ALTER TABLE inputs_log DROP COLUMN replay_of;
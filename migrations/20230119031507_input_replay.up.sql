@@ -0,0 +1,2 @@
+ALTER TABLE inputs_log ADD COLUMN replay_of uuid;
+COMMENT ON COLUMN inputs_log.replay_of IS 'The inputs_log_id of the input that this one replays';
@@ -0,0 +1,2 @@
+ALTER TABLE inputs_log DROP COLUMN interactive;
+ALTER TABLE queue_stage DROP COLUMN high_priority;
@@ -0,0 +1,3 @@
+ALTER TABLE queue_stage ADD COLUMN high_priority boolean NOT NULL DEFAULT false;
+ALTER TABLE inputs_log ADD COLUMN interactive boolean NOT NULL DEFAULT false;
+COMMENT ON COLUMN inputs_log.interactive IS 'True if the input was triggered by a user from the UI';
@@ -0,0 +1,2 @@
+ALTER TABLE inputs_log DROP COLUMN chain_depth;
+ALTER TABLE inputs_log DROP COLUMN source_inputs_log_id;
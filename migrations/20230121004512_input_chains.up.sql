@@ -0,0 +1,4 @@
+ALTER TABLE inputs_log ADD COLUMN source_inputs_log_id uuid;
+ALTER TABLE inputs_log ADD COLUMN chain_depth int NOT NULL DEFAULT 0;
+COMMENT ON COLUMN inputs_log.source_inputs_log_id IS 'When another task sent this input, the input that the other task was handling';
+COMMENT ON COLUMN inputs_log.chain_depth IS 'The number of tasks that passed this input along before it arrived';
@@ -0,0 +1 @@
+ALTER TABLE queue_stage DROP COLUMN fairness_key;
@@ -0,0 +1 @@
+ALTER TABLE queue_stage ADD COLUMN fairness_key text;
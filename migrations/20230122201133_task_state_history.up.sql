@@ -0,0 +1,15 @@
+CREATE TABLE task_state_history (
+  task_state_history_id bigint primary key generated always as identity,
+  task_id uuid not null references tasks ON DELETE CASCADE,
+  inputs_log_id uuid not null unique references inputs_log ON DELETE CASCADE,
+  -- Only set when the input changed the state.
+  state jsonb,
+  created timestamptz not null default now()
+);
+
+CREATE INDEX ON task_state_history (task_id, task_state_history_id) WHERE state IS NOT NULL;
+
+COMMENT ON TABLE task_state_history IS 'The state of a task after each input was applied';
+
+GRANT SELECT ON task_state_history TO ergo_web;
+GRANT SELECT, INSERT, UPDATE, DELETE ON task_state_history TO ergo_backend;
@@ -0,0 +1,5 @@
+DELETE FROM task_state_history WHERE inputs_log_id IS NULL;
+ALTER TABLE task_state_history ALTER COLUMN inputs_log_id SET NOT NULL;
+DROP TABLE task_state_resets;
+ALTER TABLE tasks DROP COLUMN state_reset_at;
+ALTER TABLE tasks DROP COLUMN state_reset;
@@ -0,0 +1,21 @@
+ALTER TABLE tasks ADD COLUMN state_reset jsonb;
+ALTER TABLE tasks ADD COLUMN state_reset_at timestamptz;
+CREATE INDEX ON tasks (state_reset_at) WHERE state_reset_at IS NOT NULL;
+COMMENT ON COLUMN tasks.state_reset IS 'A schedule for resetting the task state';
+
+CREATE TABLE task_state_resets (
+  task_state_reset_id bigint primary key generated always as identity,
+  task_id uuid not null references tasks ON DELETE CASCADE,
+  previous_state jsonb not null,
+  new_state jsonb not null,
+  created timestamptz not null default now()
+);
+
+CREATE INDEX ON task_state_resets (task_id, created);
+COMMENT ON TABLE task_state_resets IS 'The state of each task from before its scheduled resets';
+
+GRANT SELECT ON task_state_resets TO ergo_web;
+GRANT SELECT, INSERT ON task_state_resets TO ergo_backend;
+
+-- State resets add a history entry that isn't tied to an input.
+ALTER TABLE task_state_history ALTER COLUMN inputs_log_id DROP NOT NULL;
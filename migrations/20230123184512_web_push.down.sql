@@ -0,0 +1,16 @@
+DROP TABLE user_notify_preferences;
+DROP TABLE push_subscriptions;
+DROP TYPE push_subscription_kind;
+
+-- Postgres can't remove enum values, so recreate the type without 'web_push'.
+DELETE FROM notify_listeners
+  WHERE notify_endpoint_id IN (SELECT notify_endpoint_id FROM notify_endpoints WHERE service = 'web_push');
+DELETE FROM notify_endpoints WHERE service = 'web_push';
+ALTER TYPE notify_service RENAME TO notify_service_old;
+CREATE TYPE notify_service AS ENUM (
+  'email',
+  'discord_incoming_webhook',
+  'slack_incoming_webhook'
+);
+ALTER TABLE notify_endpoints ALTER COLUMN service TYPE notify_service USING service::text::notify_service;
+DROP TYPE notify_service_old;
@@ -0,0 +1,34 @@
+ALTER TYPE notify_service ADD VALUE 'web_push';
+
+CREATE TYPE push_subscription_kind AS ENUM (
+  'web_push',
+  'fcm'
+);
+
+CREATE TABLE push_subscriptions (
+  push_subscription_id uuid primary key default uuid_generate_v4(),
+  user_id uuid not null references users ON DELETE CASCADE,
+  kind push_subscription_kind not null default 'web_push',
+  endpoint text not null unique,
+  p256dh text,
+  auth text,
+  user_agent text,
+  created timestamptz not null default now()
+);
+
+CREATE INDEX ON push_subscriptions (user_id);
+COMMENT ON COLUMN push_subscriptions.endpoint IS 'The web push endpoint URL, or the registration token for FCM';
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON push_subscriptions TO ergo_web;
+GRANT SELECT, DELETE ON push_subscriptions TO ergo_backend;
+
+CREATE TABLE user_notify_preferences (
+  user_id uuid primary key references users ON DELETE CASCADE,
+  push_events notify_event[] not null,
+  muted_until timestamptz
+);
+
+COMMENT ON TABLE user_notify_preferences IS 'Which notifications each user wants pushed to their devices';
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON user_notify_preferences TO ergo_web;
+GRANT SELECT ON user_notify_preferences TO ergo_backend;
@@ -0,0 +1,27 @@
+CREATE TABLE email_mailboxes (
+  email_mailbox_id uuid primary key,
+  org_id uuid not null references orgs ON DELETE CASCADE,
+  name text not null,
+  task_trigger_id uuid not null references task_triggers ON DELETE CASCADE,
+  account_id uuid references accounts ON DELETE SET NULL,
+  folder text not null default 'INBOX',
+  poll_interval_secs int not null default 300,
+  run_as_user uuid not null references users ON DELETE CASCADE,
+  enabled boolean not null default true,
+  uid_validity bigint,
+  last_uid bigint,
+  last_polled timestamptz,
+  last_error text,
+  created timestamptz not null default now(),
+  modified timestamptz not null default now()
+);
+
+CREATE INDEX ON email_mailboxes (org_id);
+CREATE INDEX ON email_mailboxes (task_trigger_id);
+
+COMMENT ON TABLE email_mailboxes IS 'Sources of email that are sent as inputs to a task trigger';
+COMMENT ON COLUMN email_mailboxes.account_id IS 'The IMAP account to poll. Mailboxes without an account only receive mail from the inbound webhook.';
+COMMENT ON COLUMN email_mailboxes.last_uid IS 'The highest IMAP UID that has been ingested. This is only valid while the folder''s UIDVALIDITY matches uid_validity.';
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON email_mailboxes TO ergo_web;
+GRANT SELECT, UPDATE ON email_mailboxes TO ergo_backend;
@@ -0,0 +1 @@
+DROP TABLE mqtt_subscriptions;
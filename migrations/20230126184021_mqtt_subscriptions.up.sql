@@ -0,0 +1,22 @@
+CREATE TABLE mqtt_subscriptions (
+  mqtt_subscription_id uuid primary key,
+  org_id uuid not null references orgs ON DELETE CASCADE,
+  account_id uuid not null references accounts ON DELETE CASCADE,
+  topic_filter text not null,
+  qos smallint not null default 1 CHECK (qos BETWEEN 0 AND 2),
+  task_trigger_id uuid not null references task_triggers ON DELETE CASCADE,
+  run_as_user uuid not null references users ON DELETE CASCADE,
+  enabled boolean not null default true,
+  created timestamptz not null default now(),
+  modified timestamptz not null default now()
+);
+
+CREATE INDEX ON mqtt_subscriptions (org_id);
+CREATE INDEX ON mqtt_subscriptions (account_id);
+CREATE INDEX ON mqtt_subscriptions (task_trigger_id);
+
+COMMENT ON TABLE mqtt_subscriptions IS 'MQTT topics whose messages are sent as inputs to a task trigger';
+COMMENT ON COLUMN mqtt_subscriptions.topic_filter IS 'An MQTT topic filter, which may contain + and # wildcards';
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON mqtt_subscriptions TO ergo_web;
+GRANT SELECT ON mqtt_subscriptions TO ergo_backend;
@@ -0,0 +1,2 @@
+DROP TABLE notifications_log;
+DROP TYPE notification_status;
@@ -0,0 +1,27 @@
+CREATE TYPE notification_status AS ENUM (
+  'pending',
+  'success',
+  'error'
+);
+
+CREATE TABLE notifications_log (
+  notifications_log_id bigint primary key generated always as identity,
+  org_id uuid not null references orgs ON DELETE CASCADE,
+  notify_endpoint_id uuid references notify_endpoints ON DELETE SET NULL,
+  task_id uuid not null,
+  event notify_event not null,
+  service notify_service not null,
+  -- The inputs_log_id or actions_log_id that the notification is about.
+  log_id uuid,
+  status notification_status not null default 'pending',
+  error text,
+  created timestamptz not null default now(),
+  updated timestamptz not null default now()
+);
+
+CREATE INDEX ON notifications_log (log_id);
+
+COMMENT ON TABLE notifications_log IS 'Each notification sent to a notify endpoint';
+
+GRANT SELECT, INSERT ON notifications_log TO ergo_web;
+GRANT SELECT, INSERT, UPDATE ON notifications_log TO ergo_backend;
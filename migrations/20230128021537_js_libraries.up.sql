@@ -0,0 +1,16 @@
+CREATE TABLE js_libraries (
+  org_id uuid not null references orgs ON DELETE CASCADE,
+  name text not null,
+  version int not null,
+  description text,
+  source text not null,
+  created_by uuid references users ON DELETE SET NULL,
+  created timestamptz not null default now(),
+  PRIMARY KEY (org_id, name, version)
+);
+
+COMMENT ON TABLE js_libraries IS 'Shared JS modules that scripts import with ergo:lib/<name>';
+COMMENT ON COLUMN js_libraries.version IS 'Starts at 1 and goes up by one each time the library is uploaded';
+
+GRANT SELECT, INSERT, DELETE ON js_libraries TO ergo_web;
+GRANT SELECT ON js_libraries TO ergo_backend;
@@ -0,0 +1,2 @@
+DROP INDEX user_entity_permissions_permissioned_object_idx;
+DROP INDEX roles_org_id_name_idx;
@@ -0,0 +1,3 @@
+CREATE UNIQUE INDEX roles_org_id_name_idx ON roles (org_id, name);
+CREATE INDEX user_entity_permissions_permissioned_object_idx
+  ON user_entity_permissions (permissioned_object);
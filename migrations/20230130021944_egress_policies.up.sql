@@ -0,0 +1,10 @@
+-- Orgs without a row here use the default policy, which blocks private networks.
+CREATE TABLE org_egress_policies (
+  org_id uuid primary key references orgs ON DELETE CASCADE,
+  policy jsonb not null,
+  updated timestamptz not null default now()
+);
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON org_egress_policies TO ergo_web;
+GRANT SELECT ON org_egress_policies TO ergo_backend;
+GRANT SELECT ON org_egress_policies TO ergo_enqueuer;
@@ -0,0 +1,3 @@
+ALTER TABLE accounts
+  DROP COLUMN max_concurrency,
+  DROP COLUMN requests_per_minute;
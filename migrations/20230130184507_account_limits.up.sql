@@ -0,0 +1,5 @@
+-- Limits on how actions that use an account can run, since third-party APIs usually rate limit
+-- each credential. NULL means no limit.
+ALTER TABLE accounts
+  ADD COLUMN max_concurrency int CHECK (max_concurrency > 0),
+  ADD COLUMN requests_per_minute int CHECK (requests_per_minute > 0);
@@ -0,0 +1,4 @@
+REVOKE DELETE ON actions_log FROM ergo_backend;
+REVOKE DELETE ON inputs_log FROM ergo_backend;
+DROP TABLE log_archives;
+DROP TABLE org_log_retention;
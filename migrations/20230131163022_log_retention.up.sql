@@ -0,0 +1,32 @@
+-- Orgs without a row here use the default policy from LOG_RETENTION_DAYS and LOG_ARCHIVE.
+CREATE TABLE org_log_retention (
+  org_id uuid primary key references orgs ON DELETE CASCADE,
+  -- Remove log entries that were last updated more than this many days ago. Null keeps them
+  -- forever.
+  keep_days int CHECK (keep_days > 0),
+  -- Write log entries to the archive store before removing them.
+  archive boolean not null default false,
+  updated timestamptz not null default now()
+);
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON org_log_retention TO ergo_web;
+GRANT SELECT ON org_log_retention TO ergo_backend;
+
+CREATE TABLE log_archives (
+  log_archive_id uuid primary key,
+  org_id uuid not null references orgs ON DELETE CASCADE,
+  log_table text not null,
+  location text not null,
+  entries int not null,
+  oldest timestamptz not null,
+  newest timestamptz not null,
+  created timestamptz not null default now()
+);
+
+CREATE INDEX log_archives_org_id_created ON log_archives(org_id, created);
+
+GRANT SELECT ON log_archives TO ergo_web;
+GRANT SELECT, INSERT ON log_archives TO ergo_backend;
+
+GRANT DELETE ON inputs_log TO ergo_backend;
+GRANT DELETE ON actions_log TO ergo_backend;
@@ -0,0 +1,14 @@
+REVOKE UPDATE(status, queue_job_id, updated) ON inputs_log FROM ergo_web;
+DROP TABLE buffered_inputs;
+
+-- Postgres can't remove enum values, so recreate the type without 'dropped' and 'buffered'.
+DELETE FROM inputs_log WHERE status IN ('dropped', 'buffered');
+ALTER TYPE input_status RENAME TO input_status_old;
+CREATE TYPE input_status AS ENUM ('pending', 'success', 'error', 'duplicate');
+ALTER TABLE inputs_log ALTER COLUMN status DROP DEFAULT;
+ALTER TABLE inputs_log ALTER COLUMN status TYPE input_status USING status::text::input_status;
+ALTER TABLE inputs_log ALTER COLUMN status SET DEFAULT 'pending';
+DROP TYPE input_status_old;
+
+ALTER TABLE tasks DROP COLUMN disabled_input_mode;
+DROP TYPE disabled_input_mode;
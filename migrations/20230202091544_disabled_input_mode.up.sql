@@ -0,0 +1,23 @@
+CREATE TYPE disabled_input_mode AS ENUM ('reject', 'drop', 'buffer');
+ALTER TABLE tasks ADD COLUMN disabled_input_mode disabled_input_mode NOT NULL DEFAULT 'reject';
+
+ALTER TYPE input_status ADD VALUE 'dropped';
+ALTER TYPE input_status ADD VALUE 'buffered';
+
+-- Inputs that arrived while their task was disabled, waiting to be queued when it is enabled
+-- again.
+CREATE TABLE buffered_inputs (
+  inputs_log_id uuid primary key references inputs_log ON DELETE CASCADE,
+  task_id uuid not null references tasks ON DELETE CASCADE,
+  org_id uuid not null,
+  invocation jsonb not null,
+  created timestamptz not null default now()
+);
+
+CREATE INDEX buffered_inputs_task_id_created ON buffered_inputs(task_id, created);
+
+-- The web role flushes the buffer when a task is enabled.
+GRANT SELECT, DELETE ON buffered_inputs TO ergo_web;
+GRANT UPDATE(status, queue_job_id, updated) ON inputs_log TO ergo_web;
+GRANT SELECT, INSERT, DELETE ON buffered_inputs TO ergo_backend;
+GRANT INSERT ON buffered_inputs TO ergo_enqueuer;
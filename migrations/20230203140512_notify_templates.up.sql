@@ -0,0 +1,13 @@
+-- Org-specific text for notifications. Notifications for an event and service without a
+-- template here use the built-in formatting.
+CREATE TABLE notify_templates (
+  org_id uuid not null references orgs ON DELETE CASCADE,
+  event notify_event not null,
+  service notify_service not null,
+  template text not null,
+  updated timestamptz not null default now(),
+  PRIMARY KEY (org_id, event, service)
+);
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON notify_templates TO ergo_web;
+GRANT SELECT ON notify_templates TO ergo_backend;
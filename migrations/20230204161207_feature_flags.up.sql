@@ -0,0 +1,18 @@
+-- Flags that turn features on and off at runtime. A row without an org_id is the global
+-- setting for a flag, and a row with an org_id overrides the global setting for that org.
+CREATE TABLE feature_flags (
+  flag text not null,
+  org_id uuid references orgs ON DELETE CASCADE,
+  enabled boolean not null default false,
+  -- For a global flag, only enable it for this percentage of orgs.
+  rollout_percent smallint CHECK (rollout_percent BETWEEN 0 AND 100),
+  description text,
+  updated timestamptz not null default now()
+);
+
+CREATE UNIQUE INDEX feature_flags_global_idx ON feature_flags (flag) WHERE org_id IS NULL;
+CREATE UNIQUE INDEX feature_flags_org_idx ON feature_flags (org_id, flag) WHERE org_id IS NOT NULL;
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON feature_flags TO ergo_web;
+GRANT SELECT ON feature_flags TO ergo_backend;
+GRANT SELECT ON feature_flags TO ergo_enqueuer;
@@ -0,0 +1,3 @@
+DROP TABLE user_tokens;
+DROP TYPE user_token_kind;
+ALTER TABLE users DROP COLUMN email_verified;
@@ -0,0 +1,21 @@
+-- Existing users were created by an admin, so they count as verified.
+ALTER TABLE users ADD COLUMN email_verified boolean not null default true;
+
+CREATE TYPE user_token_kind AS ENUM (
+  'verify_email',
+  'password_reset'
+);
+
+CREATE TABLE user_tokens (
+  token_id uuid primary key,
+  user_id uuid not null references users ON DELETE CASCADE,
+  kind user_token_kind not null,
+  hash bytea not null,
+  created timestamptz not null default now(),
+  expires timestamptz not null,
+  used boolean not null default false
+);
+
+CREATE INDEX ON user_tokens (user_id, kind);
+
+GRANT SELECT, UPDATE, DELETE, INSERT ON user_tokens TO ergo_web;
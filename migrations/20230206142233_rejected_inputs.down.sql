@@ -0,0 +1,10 @@
+DROP INDEX inputs_log_trigger_status_idx;
+
+-- Postgres can't remove enum values, so recreate the type without 'rejected'.
+DELETE FROM inputs_log WHERE status = 'rejected';
+ALTER TYPE input_status RENAME TO input_status_old;
+CREATE TYPE input_status AS ENUM ('pending', 'success', 'error', 'duplicate', 'dropped', 'buffered');
+ALTER TABLE inputs_log ALTER COLUMN status DROP DEFAULT;
+ALTER TABLE inputs_log ALTER COLUMN status TYPE input_status USING status::text::input_status;
+ALTER TABLE inputs_log ALTER COLUMN status SET DEFAULT 'pending';
+DROP TYPE input_status_old;
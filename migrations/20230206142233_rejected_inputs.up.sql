@@ -0,0 +1,5 @@
+ALTER TYPE input_status ADD VALUE 'rejected';
+
+-- The new enum value can't be used until this transaction commits, so the index covers every
+-- status instead of being limited to rejected inputs.
+CREATE INDEX inputs_log_trigger_status_idx ON inputs_log (task_trigger_id, status, created);
@@ -0,0 +1,26 @@
+REVOKE UPDATE(info) ON inputs_log FROM ergo_web;
+DROP TABLE approvals;
+DROP TYPE approval_status;
+
+-- Postgres can't remove enum values, so recreate the type without 'approval_requested'.
+DELETE FROM notify_listeners WHERE event = 'approval_requested';
+DELETE FROM notify_templates WHERE event = 'approval_requested';
+DELETE FROM notifications_log WHERE event = 'approval_requested';
+UPDATE user_notify_preferences
+  SET push_events = array_remove(push_events, 'approval_requested'::notify_event);
+
+ALTER TYPE notify_event RENAME TO notify_event_old;
+CREATE TYPE notify_event AS ENUM (
+  'input_arrived',
+  'input_processed',
+  'action_started',
+  'action_success',
+  'action_error',
+  'task_invalidated'
+);
+ALTER TABLE notify_listeners ALTER COLUMN event TYPE notify_event USING event::text::notify_event;
+ALTER TABLE notify_templates ALTER COLUMN event TYPE notify_event USING event::text::notify_event;
+ALTER TABLE notifications_log ALTER COLUMN event TYPE notify_event USING event::text::notify_event;
+ALTER TABLE user_notify_preferences
+  ALTER COLUMN push_events TYPE notify_event[] USING push_events::text[]::notify_event[];
+DROP TYPE notify_event_old;
@@ -0,0 +1,41 @@
+CREATE TYPE approval_status AS ENUM (
+  'pending',
+  'approved',
+  'denied',
+  'expired'
+);
+
+-- A task waiting for someone to approve or deny what it's about to do. The decision is sent to
+-- the task as an input to one of its triggers.
+CREATE TABLE approvals (
+  approval_id uuid primary key,
+  org_id uuid not null references orgs ON DELETE CASCADE,
+  task_id uuid not null references tasks ON DELETE CASCADE,
+  task_trigger_local_id text not null,
+  -- The action that asked for the approval.
+  actions_log_id uuid,
+  -- The user that the task was running as. The decision input is sent as this user.
+  user_id uuid not null references users,
+  message text not null default '',
+  payload jsonb,
+  status approval_status not null default 'pending',
+  -- The approve and deny links carry a token, so that a decision can be made from a
+  -- notification without logging in. Only its hash is stored.
+  token_hash bytea not null,
+  expires timestamptz not null,
+  -- The scheduled input that tells the task that the approval expired. It's removed from the
+  -- queue if a decision is made first.
+  expiry_inputs_log_id uuid,
+  decided_by uuid references users,
+  decided_at timestamptz,
+  comment text,
+  created timestamptz not null default now()
+);
+
+CREATE INDEX approvals_org_status_idx ON approvals (org_id, status, created);
+
+GRANT SELECT, UPDATE ON approvals TO ergo_web;
+GRANT SELECT, INSERT, UPDATE ON approvals TO ergo_backend;
+GRANT UPDATE(info) ON inputs_log TO ergo_web;
+
+ALTER TYPE notify_event ADD VALUE 'approval_requested';
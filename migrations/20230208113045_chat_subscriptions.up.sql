@@ -0,0 +1,24 @@
+CREATE TABLE chat_subscriptions (
+  chat_subscription_id uuid primary key,
+  org_id uuid not null references orgs ON DELETE CASCADE,
+  account_id uuid not null references accounts ON DELETE CASCADE,
+  channel_id text not null,
+  command text,
+  task_trigger_id uuid not null references task_triggers ON DELETE CASCADE,
+  run_as_user uuid not null references users ON DELETE CASCADE,
+  enabled boolean not null default true,
+  created timestamptz not null default now(),
+  modified timestamptz not null default now()
+);
+
+CREATE INDEX ON chat_subscriptions (org_id);
+CREATE INDEX ON chat_subscriptions (account_id);
+CREATE INDEX ON chat_subscriptions (task_trigger_id);
+
+COMMENT ON TABLE chat_subscriptions IS 'Chat channels whose messages or commands are sent as inputs to a task trigger';
+COMMENT ON COLUMN chat_subscriptions.account_id IS 'A discord_bot or telegram_bot account with the bot''s token';
+COMMENT ON COLUMN chat_subscriptions.channel_id IS 'The Discord channel ID or Telegram chat ID';
+COMMENT ON COLUMN chat_subscriptions.command IS 'Only send this command, without the leading slash. When null, every message in the channel is sent.';
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON chat_subscriptions TO ergo_web;
+GRANT SELECT ON chat_subscriptions TO ergo_backend;
@@ -0,0 +1,4 @@
+DROP INDEX tasks_search_idx;
+DROP INDEX actions_search_idx;
+ALTER TABLE tasks DROP COLUMN tags;
+ALTER TABLE actions DROP COLUMN tags;
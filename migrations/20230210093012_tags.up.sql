@@ -0,0 +1,16 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+ALTER TABLE tasks ADD COLUMN tags text[] not null default '{}';
+ALTER TABLE actions ADD COLUMN tags text[] not null default '{}';
+
+CREATE INDEX tasks_tags_idx ON tasks USING gin (tags);
+CREATE INDEX actions_tags_idx ON actions USING gin (tags);
+
+-- Free-text search matches against the name and description together.
+CREATE INDEX tasks_search_idx ON tasks
+  USING gin ((name || ' ' || COALESCE(description, '')) gin_trgm_ops);
+CREATE INDEX actions_search_idx ON actions
+  USING gin ((name || ' ' || COALESCE(description, '')) gin_trgm_ops);
+
+COMMENT ON COLUMN tasks.tags IS 'Lowercase labels used to group and filter tasks';
+COMMENT ON COLUMN actions.tags IS 'Lowercase labels used to group and filter actions';
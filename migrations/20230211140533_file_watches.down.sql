@@ -0,0 +1,4 @@
+DROP TABLE file_watch_files;
+DROP TABLE file_watches;
+DROP TYPE file_watch_parse;
+DROP TYPE file_watch_source;
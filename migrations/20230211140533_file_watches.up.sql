@@ -0,0 +1,43 @@
+CREATE TYPE file_watch_source AS ENUM ('directory', 's3');
+CREATE TYPE file_watch_parse AS ENUM ('none', 'json', 'csv');
+
+CREATE TABLE file_watches (
+  file_watch_id uuid primary key,
+  org_id uuid not null references orgs ON DELETE CASCADE,
+  source file_watch_source not null,
+  account_id uuid references accounts ON DELETE CASCADE,
+  bucket text,
+  path text not null default '',
+  parse file_watch_parse not null default 'none',
+  task_trigger_id uuid not null references task_triggers ON DELETE CASCADE,
+  run_as_user uuid not null references users ON DELETE CASCADE,
+  enabled boolean not null default true,
+  baseline_done boolean not null default false,
+  created timestamptz not null default now(),
+  modified timestamptz not null default now(),
+  CHECK (source = 'directory' OR (account_id IS NOT NULL AND bucket IS NOT NULL))
+);
+
+CREATE INDEX ON file_watches (org_id);
+CREATE INDEX ON file_watches (task_trigger_id);
+
+COMMENT ON TABLE file_watches IS 'Directories and S3 prefixes where each new file is sent as an input to a task trigger';
+COMMENT ON COLUMN file_watches.account_id IS 'For S3 watches, an s3 account with the credentials and endpoint';
+COMMENT ON COLUMN file_watches.path IS 'A directory relative to FILE_WATCH_ROOT, or the S3 key prefix';
+COMMENT ON COLUMN file_watches.parse IS 'How to parse the file contents into the input payload. With none, only the file metadata is sent.';
+COMMENT ON COLUMN file_watches.baseline_done IS 'False until the files that existed when the watch was created have been recorded, so that they are not sent';
+
+CREATE TABLE file_watch_files (
+  file_watch_id uuid not null references file_watches ON DELETE CASCADE,
+  file_key text not null,
+  version text not null,
+  seen timestamptz not null default now(),
+  PRIMARY KEY (file_watch_id, file_key, version)
+);
+
+COMMENT ON TABLE file_watch_files IS 'The files that each watch has already handled. Every server runs the watcher, so this also keeps two servers from sending the same file.';
+COMMENT ON COLUMN file_watch_files.version IS 'The ETag of an S3 object, or the size and modification time of a file';
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON file_watches TO ergo_web;
+GRANT SELECT, UPDATE ON file_watches TO ergo_backend;
+GRANT SELECT, INSERT ON file_watch_files TO ergo_backend;
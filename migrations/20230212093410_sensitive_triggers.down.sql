@@ -0,0 +1,9 @@
+ALTER TABLE task_triggers DROP COLUMN sensitive;
+
+-- Postgres can't remove enum values, so recreate the type without 'read_sensitive'.
+DELETE FROM user_entity_permissions WHERE permission_type = 'read_sensitive';
+ALTER TYPE permission RENAME TO permission_old;
+CREATE TYPE permission AS ENUM ('read', 'write', 'create', 'trigger_event');
+ALTER TABLE user_entity_permissions ALTER COLUMN permission_type TYPE permission
+  USING permission_type::text::permission;
+DROP TYPE permission_old;
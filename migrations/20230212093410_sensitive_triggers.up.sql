@@ -0,0 +1,4 @@
+ALTER TYPE permission ADD VALUE 'read_sensitive';
+
+ALTER TABLE task_triggers ADD COLUMN sensitive boolean NOT NULL DEFAULT false;
+COMMENT ON COLUMN task_triggers.sensitive IS 'Encrypt payloads sent to this trigger, and redact them from the logs for users without the read_sensitive permission';
@@ -0,0 +1,2 @@
+ALTER TABLE actions_log DROP COLUMN compensates;
+ALTER TABLE task_actions DROP COLUMN compensate_with;
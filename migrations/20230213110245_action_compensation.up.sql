@@ -0,0 +1,7 @@
+ALTER TABLE task_actions ADD COLUMN compensate_with text;
+COMMENT ON COLUMN task_actions.compensate_with IS 'The task action that undoes this one, run when a later action in the same input run fails permanently';
+
+-- Compensation actions are logged with the rest of the input's actions, and point back to the
+-- action that they undo. Each action is compensated at most once.
+ALTER TABLE actions_log ADD COLUMN compensates uuid REFERENCES actions_log ON DELETE SET NULL;
+CREATE UNIQUE INDEX actions_log_compensates_idx ON actions_log(compensates) WHERE compensates IS NOT NULL;
@@ -0,0 +1,3 @@
+ALTER TABLE user_roles DROP COLUMN oidc_provider_id;
+DROP TABLE user_identities;
+DROP TABLE oidc_providers;
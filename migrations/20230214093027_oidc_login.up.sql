@@ -0,0 +1,34 @@
+CREATE TABLE oidc_providers (
+  oidc_provider_id text primary key,
+  name text not null,
+  issuer text not null,
+  client_id text not null,
+  -- Encrypted with the account field keys when they are configured.
+  client_secret jsonb,
+  scopes text[] not null default '{}',
+  -- Ordered list of rules that map ID token claims to an org and roles.
+  claim_rules jsonb not null default '[]',
+  created timestamptz not null default now(),
+  updated timestamptz not null default now()
+);
+
+GRANT SELECT, UPDATE, DELETE, INSERT ON oidc_providers TO ergo_web;
+
+-- Links a user to their account at an OIDC provider.
+CREATE TABLE user_identities (
+  oidc_provider_id text not null references oidc_providers ON DELETE CASCADE,
+  subject text not null,
+  user_id uuid not null references users ON DELETE CASCADE,
+  created timestamptz not null default now(),
+  last_login timestamptz not null default now(),
+  primary key (oidc_provider_id, subject)
+);
+
+CREATE INDEX ON user_identities (user_id);
+
+GRANT SELECT, UPDATE, DELETE, INSERT ON user_identities TO ergo_web;
+
+-- Roles granted by a provider's claim rules are removed when the user's claims no longer
+-- match, while roles granted by hand are left alone.
+ALTER TABLE user_roles ADD COLUMN oidc_provider_id text
+  REFERENCES oidc_providers ON DELETE CASCADE;
@@ -0,0 +1,26 @@
+DROP INDEX notifications_log_pending_digest;
+ALTER TABLE notifications_log
+  DROP COLUMN digest_id,
+  DROP COLUMN digest,
+  DROP COLUMN object_id,
+  DROP COLUMN notify_listener_id;
+
+-- Postgres can't remove enum values, so recreate the type without 'suppressed'.
+DELETE FROM notifications_log WHERE status = 'suppressed';
+
+ALTER TABLE notifications_log ALTER COLUMN status DROP DEFAULT;
+ALTER TYPE notification_status RENAME TO notification_status_old;
+CREATE TYPE notification_status AS ENUM (
+  'pending',
+  'success',
+  'error'
+);
+ALTER TABLE notifications_log
+  ALTER COLUMN status TYPE notification_status USING status::text::notification_status;
+ALTER TABLE notifications_log ALTER COLUMN status SET DEFAULT 'pending';
+DROP TYPE notification_status_old;
+
+ALTER TABLE notify_listeners
+  DROP CONSTRAINT notify_listeners_throttle_check,
+  DROP COLUMN throttle_window_secs,
+  DROP COLUMN throttle_limit;
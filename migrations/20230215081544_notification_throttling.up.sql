@@ -0,0 +1,28 @@
+ALTER TABLE notify_listeners
+  ADD COLUMN throttle_limit int,
+  ADD COLUMN throttle_window_secs int,
+  ADD CONSTRAINT notify_listeners_throttle_check
+    CHECK ((throttle_limit IS NULL) = (throttle_window_secs IS NULL));
+
+COMMENT ON COLUMN notify_listeners.throttle_limit IS 'The most notifications to send for each object in throttle_window_secs. Notifications over the limit are suppressed and summarized in a digest when the window ends.';
+
+ALTER TYPE notification_status ADD VALUE 'suppressed';
+
+ALTER TABLE notifications_log
+  ADD COLUMN notify_listener_id uuid references notify_listeners ON DELETE SET NULL,
+  -- The task's local object that the notification is about, such as an action.
+  ADD COLUMN object_id uuid,
+  ADD COLUMN digest bool not null default false,
+  -- For a suppressed notification, the digest that summarizes it.
+  ADD COLUMN digest_id bigint references notifications_log ON DELETE SET NULL;
+
+CREATE INDEX notifications_log_listener_created ON notifications_log
+  (notify_listener_id, task_id, created)
+  WHERE notify_listener_id IS NOT NULL;
+
+CREATE INDEX ON notifications_log (digest_id) WHERE digest_id IS NOT NULL;
+
+-- At most one digest waits to be sent for each listener and object.
+CREATE UNIQUE INDEX notifications_log_pending_digest ON notifications_log
+  (notify_listener_id, task_id, COALESCE(object_id, '00000000-0000-0000-0000-000000000000'::uuid))
+  WHERE digest AND status = 'pending';
@@ -0,0 +1,3 @@
+DROP INDEX inputs_log_source_inputs_log_id;
+ALTER TABLE inputs_log DROP COLUMN source_actions_log_id;
+ALTER TABLE actions_log DROP COLUMN source;
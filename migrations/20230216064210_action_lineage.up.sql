@@ -0,0 +1,8 @@
+ALTER TABLE actions_log ADD COLUMN source jsonb;
+COMMENT ON COLUMN actions_log.source IS 'The state machine transition, dataflow node, or script that emitted the action';
+
+ALTER TABLE inputs_log ADD COLUMN source_actions_log_id uuid;
+COMMENT ON COLUMN inputs_log.source_actions_log_id IS 'When another task sent this input, the action that sent it';
+
+CREATE INDEX inputs_log_source_inputs_log_id ON inputs_log (source_inputs_log_id)
+  WHERE source_inputs_log_id IS NOT NULL;
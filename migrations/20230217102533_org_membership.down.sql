@@ -0,0 +1,3 @@
+DROP TABLE org_invitations;
+DROP TYPE org_invitation_status;
+DROP TABLE org_members;
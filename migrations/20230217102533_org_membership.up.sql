@@ -0,0 +1,45 @@
+-- The orgs that each user belongs to. A user can only switch to, and be given roles in, an
+-- org where they are a member.
+CREATE TABLE org_members (
+  org_id uuid not null references orgs ON DELETE CASCADE,
+  user_id uuid not null references users ON DELETE CASCADE,
+  created timestamptz not null default now(),
+  primary key (org_id, user_id)
+);
+
+CREATE INDEX ON org_members (user_id);
+
+INSERT INTO org_members (org_id, user_id)
+SELECT active_org_id, user_id FROM users;
+
+INSERT INTO org_members (org_id, user_id)
+SELECT DISTINCT org_id, user_id FROM user_roles
+ON CONFLICT DO NOTHING;
+
+GRANT SELECT ON org_members TO ergo_enqueuer;
+GRANT SELECT ON org_members TO ergo_backend;
+GRANT SELECT, INSERT, DELETE ON org_members TO ergo_web;
+
+CREATE TYPE org_invitation_status AS ENUM (
+  'pending',
+  'accepted',
+  'declined'
+);
+
+CREATE TABLE org_invitations (
+  invitation_id uuid primary key,
+  org_id uuid not null references orgs ON DELETE CASCADE,
+  email text not null,
+  -- Roles in the org to give the user when they accept.
+  role_ids uuid[] not null default '{}',
+  invited_by uuid references users ON DELETE SET NULL,
+  status org_invitation_status not null default 'pending',
+  expires timestamptz not null,
+  created timestamptz not null default now(),
+  updated timestamptz not null default now()
+);
+
+CREATE INDEX ON org_invitations (lower(email)) WHERE status = 'pending';
+CREATE UNIQUE INDEX ON org_invitations (org_id, lower(email)) WHERE status = 'pending';
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON org_invitations TO ergo_web;
@@ -0,0 +1 @@
+ALTER TABLE actions_log DROP COLUMN progress;
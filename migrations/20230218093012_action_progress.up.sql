@@ -0,0 +1,2 @@
+ALTER TABLE actions_log ADD COLUMN progress jsonb;
+COMMENT ON COLUMN actions_log.progress IS 'The last progress update that a running action reported';
@@ -0,0 +1,18 @@
+CREATE TABLE task_script_logs (
+  task_script_log_id bigint primary key generated always as identity,
+  task_id uuid not null references tasks ON DELETE CASCADE,
+  inputs_log_id uuid not null references inputs_log ON DELETE CASCADE,
+  -- 0 is debug, 1 is info, 2 is warn, and 3 is error.
+  level smallint not null,
+  message text not null,
+  fields jsonb not null default '{}'::jsonb,
+  created timestamptz not null default now()
+);
+
+CREATE INDEX ON task_script_logs (inputs_log_id, task_script_log_id);
+CREATE INDEX ON task_script_logs (task_id, task_script_log_id);
+
+COMMENT ON TABLE task_script_logs IS 'Structured log entries written by task scripts. These are removed along with their inputs_log entry.';
+
+GRANT SELECT ON task_script_logs TO ergo_web;
+GRANT SELECT, INSERT, UPDATE, DELETE ON task_script_logs TO ergo_backend;
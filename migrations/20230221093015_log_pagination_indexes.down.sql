@@ -0,0 +1,4 @@
+DROP INDEX inputs_log_updated_idx;
+DROP INDEX inputs_log_created_idx;
+DROP INDEX actions_log_updated_idx;
+DROP INDEX actions_log_created_idx;
@@ -0,0 +1,5 @@
+-- Keyset pagination across an org's logs walks these in order.
+CREATE INDEX inputs_log_updated_idx ON inputs_log (updated, inputs_log_id);
+CREATE INDEX inputs_log_created_idx ON inputs_log (created, inputs_log_id);
+CREATE INDEX actions_log_updated_idx ON actions_log (updated, actions_log_id);
+CREATE INDEX actions_log_created_idx ON actions_log (created, actions_log_id);
@@ -0,0 +1,18 @@
+CREATE TABLE task_plans (
+  task_plan_id uuid primary key,
+  task_id uuid not null references tasks ON DELETE CASCADE,
+  org_id uuid not null references orgs ON DELETE CASCADE,
+  -- The task update to apply, with its script already bundled.
+  payload jsonb not null,
+  -- The task's modified time when the plan was made. The plan can only be applied if this
+  -- still matches.
+  task_modified timestamptz not null,
+  created timestamptz not null default now(),
+  expires timestamptz not null
+);
+
+CREATE INDEX ON task_plans (task_id);
+
+COMMENT ON TABLE task_plans IS 'Validated task updates waiting to be applied';
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON task_plans TO ergo_web;
@@ -0,0 +1,2 @@
+DROP INDEX actions_log_retry_of;
+ALTER TABLE actions_log DROP COLUMN retry_of;
@@ -0,0 +1,4 @@
+ALTER TABLE actions_log ADD COLUMN retry_of uuid REFERENCES actions_log ON DELETE SET NULL;
+COMMENT ON COLUMN actions_log.retry_of IS 'The failed action that this invocation retries';
+
+CREATE INDEX actions_log_retry_of ON actions_log (retry_of) WHERE retry_of IS NOT NULL;
@@ -0,0 +1,19 @@
+CREATE TABLE task_usage (
+  task_id uuid not null references tasks ON DELETE CASCADE,
+  day date not null,
+  org_id uuid not null references orgs ON DELETE CASCADE,
+  runs bigint not null default 0,
+  input_queue_wait_ms bigint not null default 0,
+  js_cpu_ms bigint not null default 0,
+  actions bigint not null default 0,
+  action_queue_wait_ms bigint not null default 0,
+  action_ms bigint not null default 0,
+  primary key (task_id, day)
+);
+
+CREATE INDEX ON task_usage (org_id, day);
+
+COMMENT ON TABLE task_usage IS 'Daily resource usage of each task, for cost reports';
+
+GRANT SELECT ON task_usage TO ergo_web;
+GRANT SELECT, INSERT, UPDATE ON task_usage TO ergo_backend;
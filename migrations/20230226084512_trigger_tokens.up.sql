@@ -0,0 +1,20 @@
+CREATE TABLE trigger_tokens (
+  trigger_token_id uuid primary key,
+  task_trigger_id uuid not null references task_triggers ON DELETE CASCADE,
+  org_id uuid not null references orgs ON DELETE CASCADE,
+  -- Inputs sent with the token are enqueued as this user, and need their permission to
+  -- trigger the task.
+  user_id uuid not null references users ON DELETE CASCADE,
+  description text,
+  hash bytea not null,
+  created timestamptz not null default now(),
+  rotated timestamptz,
+  last_used timestamptz
+);
+
+CREATE INDEX ON trigger_tokens (task_trigger_id);
+
+COMMENT ON TABLE trigger_tokens IS 'Tokens that can only send inputs to a single task trigger';
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON trigger_tokens TO ergo_web;
+GRANT SELECT, UPDATE (last_used) ON trigger_tokens TO ergo_backend;
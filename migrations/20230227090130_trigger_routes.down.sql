@@ -0,0 +1 @@
+ALTER TABLE task_triggers DROP COLUMN routes;
@@ -0,0 +1,2 @@
+ALTER TABLE task_triggers ADD COLUMN routes jsonb;
+COMMENT ON COLUMN task_triggers.routes IS 'Rules that send inputs to other triggers, based on the payload';
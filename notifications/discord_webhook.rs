@@ -15,10 +15,22 @@ pub async fn send_discord_webhook(
     client: &reqwest::Client,
     hook: &str,
     notification: &Notification,
+    locale: Option<&str>,
+    text: Option<String>,
 ) -> Result<(), Error> {
-    let desc = notification.event.description();
+    if let Some(text) = text {
+        let url = format!("https://discord.com/api/webhooks/{}", hook);
+        client
+            .post(&url)
+            .json(&json!({ "content": text }))
+            .send()
+            .await?;
+        return Ok(());
+    }
+
+    let desc = notification.event.description(locale);
     let fields = notification
-        .fields()
+        .fields(locale)
         .into_iter()
         .map(|(name, value, inline)| {
             json!({
@@ -70,10 +82,17 @@ mod tests {
             payload: Some(serde_json::json!({ "payload_value": 5})),
             error: None,
             log_id: Some(uuid::Uuid::new_v4()),
+            suppressed_count: None,
         };
 
-        super::send_discord_webhook(&reqwest::Client::new(), hook.as_str(), &notification)
-            .await
-            .expect("Sending notification");
+        super::send_discord_webhook(
+            &reqwest::Client::new(),
+            hook.as_str(),
+            &notification,
+            None,
+            None,
+        )
+        .await
+        .expect("Sending notification");
     }
 }
@@ -10,4 +10,19 @@ pub enum Error {
 
     #[error("SQL Error: {0}")]
     SqlError(#[from] sqlx::error::Error),
+
+    #[error("Web push error: {0}")]
+    WebPushError(#[from] web_push::WebPushError),
+
+    #[error("The push subscription is no longer valid")]
+    PushSubscriptionExpired,
+
+    #[error("Invalid user ID {0} in notify endpoint")]
+    InvalidUserId(String),
+
+    #[error("Invalid notification template: {0}")]
+    TemplateParse(#[from] handlebars::TemplateError),
+
+    #[error("Rendering notification template: {0}")]
+    TemplateRender(#[from] handlebars::RenderError),
 }
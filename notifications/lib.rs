@@ -1,6 +1,9 @@
 mod discord_webhook;
 mod error;
 mod notification;
+pub mod push;
+mod slack_webhook;
+pub mod templates;
 pub use error::*;
 pub use notification::*;
 use uuid::Uuid;
@@ -11,12 +14,19 @@ use smallvec::SmallVec;
 use sqlx::PgConnection;
 
 use async_trait::async_trait;
-use ergo_database::{PostgresPool, RedisPool};
+use chrono::Utc;
+use ergo_database::{object_id::UserId, PostgresPool, RedisPool};
 use ergo_graceful_shutdown::GracefulShutdownConsumer;
 use ergo_queues::{generic_stage::QueueJob, Queue, QueueJobProcessor};
 use serde::{Deserialize, Serialize};
+use tracing::{event, Level as TracingLevel};
 
-use self::discord_webhook::send_discord_webhook;
+use self::{
+    discord_webhook::send_discord_webhook,
+    push::{PushConfig, PushSender},
+    slack_webhook::send_slack_webhook,
+    templates::render_template,
+};
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "lowercase")]
@@ -42,6 +52,14 @@ impl Clone for NotificationManager {
     }
 }
 
+impl std::fmt::Debug for NotificationManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NotificationManager")
+            .field("queue", &self.0.queue_name)
+            .finish()
+    }
+}
+
 pub struct NotificationManagerInner {
     pg_pool: PostgresPool,
     shutdown: GracefulShutdownConsumer,
@@ -54,12 +72,49 @@ struct NotificationJob<'a> {
     service: NotifyService,
     destination: String,
     notification: Cow<'a, Notification>,
+    #[serde(default)]
+    locale: Option<String>,
+    /// The entry in `notifications_log` to update with the result.
+    #[serde(default)]
+    notifications_log_id: Option<i64>,
+    /// The org's template for this event and service, if it has one.
+    #[serde(default)]
+    template: Option<String>,
+    /// This job sends a digest of the notifications that were suppressed since it was
+    /// enqueued, instead of a single notification.
+    #[serde(default)]
+    digest: bool,
 }
 
 #[derive(sqlx::FromRow)]
 struct ServiceAndDestination {
+    notify_listener_id: Uuid,
+    notify_endpoint_id: Uuid,
     service: NotifyService,
     destination: String,
+    locale: Option<String>,
+    template: Option<String>,
+    throttle_limit: Option<i32>,
+    throttle_window_secs: Option<i32>,
+}
+
+/// A listener's limit on how many notifications it sends for each object.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Throttle {
+    limit: i64,
+    window: chrono::Duration,
+}
+
+impl Throttle {
+    fn new(limit: Option<i32>, window_secs: Option<i32>) -> Option<Throttle> {
+        match (limit, window_secs) {
+            (Some(limit), Some(window_secs)) if limit >= 0 && window_secs > 0 => Some(Throttle {
+                limit: limit as i64,
+                window: chrono::Duration::seconds(window_secs as i64),
+            }),
+            _ => None,
+        }
+    }
 }
 
 impl NotificationManager {
@@ -87,15 +142,47 @@ impl NotificationManager {
         &self,
         tx: &mut PgConnection,
         org_id: &uuid::Uuid,
-        notification: Notification,
+        mut notification: Notification,
     ) -> Result<(), Error> {
+        notification.redact_secrets();
         let notifications = self.get_notifiers(tx, org_id, &notification).await?;
 
         for sd in notifications {
+            let throttle = Throttle::new(sd.throttle_limit, sd.throttle_window_secs);
+            if let Some(throttle) = throttle {
+                if self.throttled(tx, &sd, &notification, throttle).await? {
+                    self.suppress(tx, org_id, sd, &notification, throttle)
+                        .await?;
+                    continue;
+                }
+            }
+
+            let notifications_log_id = sqlx::query_scalar!(
+                "INSERT INTO notifications_log
+                    (org_id, notify_endpoint_id, notify_listener_id, task_id, object_id,
+                        event, service, log_id)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                RETURNING notifications_log_id",
+                org_id,
+                sd.notify_endpoint_id,
+                sd.notify_listener_id,
+                notification.task_id.0,
+                notification.local_object_id,
+                notification.event as _,
+                sd.service as _,
+                notification.log_id
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+
             let payload = NotificationJob {
                 service: sd.service,
                 destination: sd.destination,
                 notification: Cow::Borrowed(&notification),
+                locale: sd.locale,
+                notifications_log_id: Some(notifications_log_id),
+                template: sd.template,
+                digest: false,
             };
 
             QueueJob::new(self.0.queue_name.as_str(), &payload)
@@ -105,6 +192,128 @@ impl NotificationManager {
         Ok(())
     }
 
+    /// Check if the listener has already sent as many notifications about this object as its
+    /// throttle allows in the current window.
+    async fn throttled(
+        &self,
+        tx: &mut PgConnection,
+        sd: &ServiceAndDestination,
+        notification: &Notification,
+        throttle: Throttle,
+    ) -> Result<bool, Error> {
+        let window_start = Utc::now() - throttle.window;
+        let sent = sqlx::query_scalar!(
+            r##"SELECT COUNT(*) AS "count!"
+            FROM notifications_log
+            WHERE notify_listener_id = $1
+                AND task_id = $2
+                AND object_id IS NOT DISTINCT FROM $3
+                AND created > $4
+                AND status <> 'suppressed'
+                AND NOT digest"##,
+            sd.notify_listener_id,
+            notification.task_id.0,
+            notification.local_object_id,
+            window_start
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        Ok(sent >= throttle.limit)
+    }
+
+    /// Record a notification that was over the throttle limit, and make sure that a digest
+    /// will be sent for it when the throttle window ends.
+    async fn suppress(
+        &self,
+        tx: &mut PgConnection,
+        org_id: &Uuid,
+        sd: ServiceAndDestination,
+        notification: &Notification,
+        throttle: Throttle,
+    ) -> Result<(), Error> {
+        let new_digest_id = sqlx::query_scalar!(
+            "INSERT INTO notifications_log
+                (org_id, notify_endpoint_id, notify_listener_id, task_id, object_id,
+                    event, service, digest)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, true)
+            ON CONFLICT (notify_listener_id, task_id,
+                COALESCE(object_id, '00000000-0000-0000-0000-000000000000'::uuid))
+                WHERE digest AND status = 'pending'
+            DO NOTHING
+            RETURNING notifications_log_id",
+            org_id,
+            sd.notify_endpoint_id,
+            sd.notify_listener_id,
+            notification.task_id.0,
+            notification.local_object_id,
+            notification.event as _,
+            sd.service as _
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let digest_id = match new_digest_id {
+            Some(digest_id) => {
+                event!(TracingLevel::INFO,
+                    notify_listener_id=%sd.notify_listener_id,
+                    task_id=%notification.task_id,
+                    "Throttling notifications"
+                );
+
+                let payload = NotificationJob {
+                    service: sd.service,
+                    destination: sd.destination,
+                    notification: Cow::Borrowed(notification),
+                    locale: sd.locale,
+                    notifications_log_id: Some(digest_id),
+                    template: sd.template,
+                    digest: true,
+                };
+
+                let mut job = QueueJob::new(self.0.queue_name.as_str(), &payload);
+                job.run_at(Utc::now() + throttle.window);
+                job.enqueue(&mut *tx).await?;
+
+                digest_id
+            }
+            None => {
+                sqlx::query_scalar!(
+                    "SELECT notifications_log_id FROM notifications_log
+                    WHERE notify_listener_id = $1
+                        AND task_id = $2
+                        AND object_id IS NOT DISTINCT FROM $3
+                        AND digest AND status = 'pending'",
+                    sd.notify_listener_id,
+                    notification.task_id.0,
+                    notification.local_object_id
+                )
+                .fetch_one(&mut *tx)
+                .await?
+            }
+        };
+
+        sqlx::query!(
+            "INSERT INTO notifications_log
+                (org_id, notify_endpoint_id, notify_listener_id, task_id, object_id,
+                    event, service, log_id, status, digest_id)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, 'suppressed', $9)",
+            org_id,
+            sd.notify_endpoint_id,
+            sd.notify_listener_id,
+            notification.task_id.0,
+            notification.local_object_id,
+            notification.event as _,
+            sd.service as _,
+            notification.log_id,
+            digest_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        Ok(())
+    }
+
     async fn get_notifiers(
         &self,
         tx: &mut PgConnection,
@@ -121,9 +330,13 @@ impl NotificationManager {
         let notifications = sqlx::query_as!(
             ServiceAndDestination,
             r##"SELECT
-          service AS "service: NotifyService", destination
+          notify_listener_id, notify_endpoint_id, service AS "service: NotifyService",
+          destination, orgs.locale, notify_templates.template AS "template?",
+          throttle_limit, throttle_window_secs
           FROM notify_listeners
           JOIN notify_endpoints USING(notify_endpoint_id, org_id)
+          JOIN orgs USING(org_id)
+          LEFT JOIN notify_templates USING(org_id, event, service)
           WHERE org_id=$1 AND object_id = ANY($2) AND event=$3"##,
             org_id,
             object_ids.as_slice(),
@@ -148,6 +361,10 @@ impl NotificationManager {
                 http_client: reqwest::ClientBuilder::new()
                     .timeout(std::time::Duration::from_secs(30))
                     .build()?,
+                push: PushConfig::from_env()
+                    .map(PushSender::new)
+                    .transpose()?
+                    .map(Arc::new),
             },
         );
 
@@ -159,6 +376,7 @@ impl NotificationManager {
 struct NotifyExecutor {
     pg_pool: PostgresPool,
     http_client: reqwest::Client,
+    push: Option<Arc<PushSender>>,
 }
 
 #[async_trait]
@@ -169,16 +387,100 @@ impl QueueJobProcessor for NotifyExecutor {
     async fn process(
         &self,
         _item: &ergo_queues::QueueWorkItem<Self::Payload>,
-        data: Self::Payload,
+        mut data: Self::Payload,
     ) -> Result<(), Error> {
+        if data.digest {
+            if let Some(digest_id) = data.notifications_log_id {
+                let suppressed = sqlx::query_scalar!(
+                    r##"SELECT COUNT(*) AS "count!" FROM notifications_log WHERE digest_id = $1"##,
+                    digest_id
+                )
+                .fetch_one(&self.pg_pool)
+                .await?;
+                data.notification.to_mut().suppressed_count = Some(suppressed);
+            }
+        }
+
+        let result = self.send(&data).await;
+
+        if let Some(notifications_log_id) = data.notifications_log_id {
+            let (status, error) = match &result {
+                Ok(_) => (NotificationStatus::Success, None),
+                Err(e) => (NotificationStatus::Error, Some(e.to_string())),
+            };
+
+            let update = sqlx::query!(
+                "UPDATE notifications_log SET status=$2, error=$3, updated=now()
+                WHERE notifications_log_id=$1",
+                notifications_log_id,
+                status as _,
+                error
+            )
+            .execute(&self.pg_pool)
+            .await;
+
+            if let Err(e) = update {
+                event!(TracingLevel::ERROR, err=?e, "Failed to update notification status");
+            }
+        }
+
+        result
+    }
+}
+
+impl NotifyExecutor {
+    async fn send(&self, data: &NotificationJob<'static>) -> Result<(), Error> {
+        let text = data
+            .template
+            .as_deref()
+            .map(|t| render_template(t, data.notification.as_ref(), data.locale.as_deref()))
+            .transpose()?;
+
         match &data.service {
             NotifyService::Email => Ok(()),
-            NotifyService::SlackIncomingWebhook => Ok(()),
+            NotifyService::SlackIncomingWebhook => {
+                send_slack_webhook(
+                    &self.http_client,
+                    &data.destination,
+                    data.notification.as_ref(),
+                    data.locale.as_deref(),
+                    text,
+                )
+                .await
+            }
             NotifyService::DiscordIncomingWebhook => {
                 send_discord_webhook(
                     &self.http_client,
                     &data.destination,
                     data.notification.as_ref(),
+                    data.locale.as_deref(),
+                    text,
+                )
+                .await
+            }
+            NotifyService::WebPush => {
+                let push = match self.push.as_ref() {
+                    Some(push) => push,
+                    None => {
+                        event!(
+                            TracingLevel::WARN,
+                            "Skipping push notification because VAPID_PRIVATE_KEY is not set"
+                        );
+                        return Ok(());
+                    }
+                };
+
+                let user_id = data
+                    .destination
+                    .parse::<UserId>()
+                    .map_err(|_| Error::InvalidUserId(data.destination.clone()))?;
+                push.send(
+                    &self.pg_pool,
+                    &self.http_client,
+                    &user_id,
+                    data.notification.as_ref(),
+                    data.locale.as_deref(),
+                    text,
                 )
                 .await
             }
@@ -188,7 +490,24 @@ impl QueueJobProcessor for NotifyExecutor {
 
 #[cfg(test)]
 mod tests {
+    use super::Throttle;
+
     #[test]
     #[ignore]
     fn runs_all_notifiers() {}
+
+    #[test]
+    fn throttle_config() {
+        assert_eq!(
+            Throttle::new(Some(5), Some(3600)),
+            Some(Throttle {
+                limit: 5,
+                window: chrono::Duration::hours(1)
+            })
+        );
+        assert_eq!(Throttle::new(None, None), None);
+        assert_eq!(Throttle::new(Some(5), None), None);
+        assert_eq!(Throttle::new(Some(5), Some(0)), None);
+        assert_eq!(Throttle::new(Some(-1), Some(60)), None);
+    }
 }
@@ -2,7 +2,8 @@ use std::borrow::Cow;
 
 use super::Level;
 
-use ergo_database::object_id::TaskId;
+use ergo_database::{object_id::TaskId, redaction};
+use ergo_localization::message;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -17,46 +18,103 @@ pub struct Notification {
     pub payload: Option<serde_json::Value>,
     pub error: Option<String>,
     pub log_id: Option<Uuid>,
+    /// For a digest, how many notifications were suppressed by the listener's throttle.
+    #[serde(default)]
+    pub suppressed_count: Option<i64>,
 }
 
 impl Notification {
-    pub fn fields<'a>(&'a self) -> Vec<(&'static str, Cow<'a, str>, bool)> {
+    /// Replace any registered secret values in the payload and error, so that they aren't sent
+    /// to a notification service.
+    pub fn redact_secrets(&mut self) {
+        if let Some(payload) = self.payload.as_mut() {
+            redaction::redact_json(payload);
+        }
+
+        if let Some(error) = self.error.as_mut() {
+            if let Cow::Owned(redacted) = redaction::redact_str(error) {
+                *error = redacted;
+            }
+        }
+    }
+
+    /// Return the fields to display in the notification, with names translated into
+    /// the given locale.
+    pub fn fields<'a>(&'a self, locale: Option<&str>) -> Vec<(String, Cow<'a, str>, bool)> {
         let mut output = Vec::with_capacity(8);
 
-        output.push(("Task", Cow::from(&self.task_name), true));
         output.push((
-            self.event.local_object_type(),
+            message(locale, "notify.field.task"),
+            Cow::from(&self.task_name),
+            true,
+        ));
+        output.push((
+            self.event.local_object_type(locale),
             Cow::from(&self.local_object_name),
             true,
         ));
 
         if let Some(e) = self.error.as_ref() {
-            output.push(("Error", Cow::from(e.as_str()), false));
+            output.push((
+                message(locale, "notify.field.error"),
+                Cow::from(e.as_str()),
+                false,
+            ));
         }
 
         if let Some(p) = self.payload.as_ref() {
             let payload = serde_json::to_string(p).unwrap_or_else(|_| String::new());
-            output.push(("Payload", Cow::from(payload), false));
+            output.push((
+                message(locale, "notify.field.payload"),
+                Cow::from(payload),
+                false,
+            ));
+        }
+
+        if let Some(count) = self.suppressed_count {
+            output.push((
+                message(locale, "notify.field.suppressed_count"),
+                Cow::from(count.to_string()),
+                true,
+            ));
         }
 
         if let Some(id) = self.log_id.as_ref() {
-            output.push(("Log ID", Cow::from(id.to_string()), true));
+            output.push((
+                message(locale, "notify.field.log_id"),
+                Cow::from(id.to_string()),
+                true,
+            ));
         }
 
         output
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, sqlx::Type)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
 #[serde(rename_all = "snake_case")]
 #[sqlx(type_name = "notify_service", rename_all = "snake_case")]
 pub enum NotifyService {
     Email,
     DiscordIncomingWebhook,
     SlackIncomingWebhook,
+    /// Push notifications to a user's devices. The destination is the user ID.
+    WebPush,
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, sqlx::Type)]
+/// Whether a notification in `notifications_log` has been delivered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "notification_status", rename_all = "snake_case")]
+pub enum NotificationStatus {
+    Pending,
+    Success,
+    Error,
+    /// Not sent because the listener's throttle limit was reached.
+    Suppressed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
 #[serde(rename_all = "snake_case")]
 #[sqlx(type_name = "notify_event", rename_all = "snake_case")]
 pub enum NotifyEvent {
@@ -65,6 +123,16 @@ pub enum NotifyEvent {
     ActionStarted,
     ActionSuccess,
     ActionError,
+    /// A task no longer validates after an action or input that it uses was changed.
+    TaskInvalidated,
+    /// A task is waiting for someone to approve or deny what it's about to do.
+    ApprovalRequested,
+}
+
+impl sqlx::postgres::PgHasArrayType for NotifyEvent {
+    fn array_type_info() -> sqlx::postgres::PgTypeInfo {
+        sqlx::postgres::PgTypeInfo::with_name("_notify_event")
+    }
 }
 
 impl NotifyEvent {
@@ -75,23 +143,36 @@ impl NotifyEvent {
             Self::ActionStarted { .. } => Level::Debug,
             Self::ActionSuccess { .. } => Level::Info,
             Self::ActionError { .. } => Level::Error,
+            Self::TaskInvalidated => Level::Warning,
+            Self::ApprovalRequested => Level::Warning,
         }
     }
 
-    pub fn description(&self) -> &'static str {
+    /// The message catalog key for this event's description.
+    pub fn message_key(&self) -> &'static str {
         match self {
-            Self::InputProcessed => "Processed Input",
-            Self::InputArrived => "Input Arrived",
-            Self::ActionError => "Action Error",
-            Self::ActionSuccess => "Action Finished",
-            Self::ActionStarted => "Action Started",
+            Self::InputProcessed => "notify.event.input_processed",
+            Self::InputArrived => "notify.event.input_arrived",
+            Self::ActionError => "notify.event.action_error",
+            Self::ActionSuccess => "notify.event.action_success",
+            Self::ActionStarted => "notify.event.action_started",
+            Self::TaskInvalidated => "notify.event.task_invalidated",
+            Self::ApprovalRequested => "notify.event.approval_requested",
         }
     }
 
-    pub fn local_object_type(&self) -> &'static str {
-        match self {
-            Self::InputArrived | Self::InputProcessed => "Input",
-            Self::ActionStarted | Self::ActionSuccess | Self::ActionError => "Action",
-        }
+    pub fn description(&self, locale: Option<&str>) -> String {
+        message(locale, self.message_key())
+    }
+
+    pub fn local_object_type(&self, locale: Option<&str>) -> String {
+        let key = match self {
+            Self::InputArrived | Self::InputProcessed => "notify.object.input",
+            Self::ActionStarted | Self::ActionSuccess | Self::ActionError => "notify.object.action",
+            Self::TaskInvalidated => "notify.object.task",
+            Self::ApprovalRequested => "notify.object.approval",
+        };
+
+        message(locale, key)
     }
 }
@@ -0,0 +1,271 @@
+//! Push notifications to users' browsers and phones. A `web_push` notify endpoint's destination
+//! is a user ID, and each notification goes to all of that user's push subscriptions, filtered
+//! by the user's notification preferences.
+
+use ergo_database::{object_id::UserId, PostgresPool};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{event, Level};
+use uuid::Uuid;
+use web_push::{
+    ContentEncoding, SubscriptionInfo, VapidSignatureBuilder, WebPushClient, WebPushError,
+    WebPushMessageBuilder, URL_SAFE_NO_PAD,
+};
+
+use super::{Error, Notification, NotifyEvent};
+
+/// The events that are pushed to users who haven't set any preferences.
+pub const DEFAULT_PUSH_EVENTS: &[NotifyEvent] = &[
+    NotifyEvent::ActionError,
+    NotifyEvent::TaskInvalidated,
+    NotifyEvent::ApprovalRequested,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "push_subscription_kind", rename_all = "snake_case")]
+pub enum PushSubscriptionKind {
+    /// A browser subscription, delivered through the browser's push service.
+    WebPush,
+    /// A Firebase Cloud Messaging registration token, for mobile apps.
+    Fcm,
+}
+
+#[derive(Clone, Debug)]
+pub struct PushConfig {
+    /// The base64url-encoded VAPID private key.
+    pub vapid_private_key: String,
+    /// A `mailto:` or `https:` URL that push services can use to contact the operator.
+    pub vapid_subject: Option<String>,
+    /// The server key for sending FCM messages. FCM subscriptions are skipped if this is not set.
+    pub fcm_server_key: Option<String>,
+}
+
+impl PushConfig {
+    /// Read the configuration from `VAPID_PRIVATE_KEY`, `VAPID_SUBJECT`, and `FCM_SERVER_KEY`.
+    /// Returns None if no VAPID key is set.
+    pub fn from_env() -> Option<PushConfig> {
+        let vapid_private_key = std::env::var("VAPID_PRIVATE_KEY")
+            .ok()
+            .filter(|k| !k.is_empty())?;
+
+        Some(PushConfig {
+            vapid_private_key,
+            vapid_subject: std::env::var("VAPID_SUBJECT").ok(),
+            fcm_server_key: std::env::var("FCM_SERVER_KEY").ok(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Subscription {
+    push_subscription_id: Uuid,
+    kind: PushSubscriptionKind,
+    endpoint: String,
+    p256dh: Option<String>,
+    auth: Option<String>,
+}
+
+/// Build the push message. `body` is the org's rendered template, if it has one, and otherwise
+/// the body is built from the notification.
+fn push_payload(
+    notification: &Notification,
+    locale: Option<&str>,
+    body: Option<String>,
+) -> serde_json::Value {
+    let body = body.unwrap_or_else(|| match notification.error.as_ref() {
+        Some(error) => format!(
+            "{}: {}\n{}",
+            notification.task_name, notification.local_object_name, error
+        ),
+        None => format!(
+            "{}: {}",
+            notification.task_name, notification.local_object_name
+        ),
+    });
+
+    json!({
+        "title": notification.event.description(locale),
+        "body": body,
+        "data": {
+            "event": notification.event,
+            "task_id": notification.task_id,
+            "log_id": notification.log_id,
+        },
+    })
+}
+
+pub(crate) struct PushSender {
+    config: PushConfig,
+    web_push: WebPushClient,
+}
+
+impl PushSender {
+    pub fn new(config: PushConfig) -> Result<PushSender, Error> {
+        Ok(PushSender {
+            config,
+            web_push: WebPushClient::new()?,
+        })
+    }
+
+    /// Send the notification to each of the user's subscriptions, if the user wants it.
+    pub async fn send(
+        &self,
+        pg_pool: &PostgresPool,
+        http_client: &reqwest::Client,
+        user_id: &UserId,
+        notification: &Notification,
+        locale: Option<&str>,
+        body: Option<String>,
+    ) -> Result<(), Error> {
+        let subscriptions = sqlx::query_as!(
+            Subscription,
+            r##"SELECT push_subscription_id, kind AS "kind: PushSubscriptionKind",
+                endpoint, p256dh, auth
+            FROM push_subscriptions ps
+            LEFT JOIN user_notify_preferences p USING (user_id)
+            WHERE ps.user_id = $1
+                AND $2 = ANY(COALESCE(p.push_events, $3))
+                AND (p.muted_until IS NULL OR p.muted_until < now())"##,
+            user_id.0,
+            notification.event as _,
+            DEFAULT_PUSH_EVENTS as _
+        )
+        .fetch_all(pg_pool)
+        .await?;
+
+        if subscriptions.is_empty() {
+            return Ok(());
+        }
+
+        let payload = push_payload(notification, locale, body);
+        for subscription in subscriptions {
+            let result = match subscription.kind {
+                PushSubscriptionKind::WebPush => self.send_web_push(&subscription, &payload).await,
+                PushSubscriptionKind::Fcm => {
+                    self.send_fcm(http_client, &subscription, &payload).await
+                }
+            };
+
+            match result {
+                Ok(()) => {}
+                Err(Error::PushSubscriptionExpired) => {
+                    event!(Level::INFO, push_subscription_id=%subscription.push_subscription_id, "Removing expired push subscription");
+                    sqlx::query!(
+                        "DELETE FROM push_subscriptions WHERE push_subscription_id=$1",
+                        subscription.push_subscription_id
+                    )
+                    .execute(pg_pool)
+                    .await?;
+                }
+                // Don't let one broken subscription stop the others, since retrying the job
+                // would send duplicates to the ones that succeeded.
+                Err(e) => {
+                    event!(Level::ERROR, push_subscription_id=%subscription.push_subscription_id, error=%e, "Failed to send push notification");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn send_web_push(
+        &self,
+        subscription: &Subscription,
+        payload: &serde_json::Value,
+    ) -> Result<(), Error> {
+        let info = SubscriptionInfo::new(
+            &subscription.endpoint,
+            subscription.p256dh.as_deref().unwrap_or_default(),
+            subscription.auth.as_deref().unwrap_or_default(),
+        );
+
+        let mut signature = VapidSignatureBuilder::from_base64(
+            &self.config.vapid_private_key,
+            URL_SAFE_NO_PAD,
+            &info,
+        )?;
+        if let Some(subject) = self.config.vapid_subject.as_ref() {
+            signature.add_claim("sub", subject.as_str());
+        }
+
+        let content = payload.to_string();
+        let mut message = WebPushMessageBuilder::new(&info)?;
+        message.set_payload(ContentEncoding::Aes128Gcm, content.as_bytes());
+        message.set_vapid_signature(signature.build()?);
+
+        match self.web_push.send(message.build()?).await {
+            Ok(()) => Ok(()),
+            Err(WebPushError::EndpointNotValid) | Err(WebPushError::EndpointNotFound) => {
+                Err(Error::PushSubscriptionExpired)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn send_fcm(
+        &self,
+        http_client: &reqwest::Client,
+        subscription: &Subscription,
+        payload: &serde_json::Value,
+    ) -> Result<(), Error> {
+        let server_key = match self.config.fcm_server_key.as_ref() {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+
+        let response = http_client
+            .post("https://fcm.googleapis.com/fcm/send")
+            .header("Authorization", format!("key={}", server_key))
+            .json(&json!({
+                "to": subscription.endpoint,
+                "notification": {
+                    "title": payload["title"],
+                    "body": payload["body"],
+                },
+                "data": payload["data"],
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<serde_json::Value>()
+            .await?;
+
+        let unregistered = response["results"][0]["error"]
+            .as_str()
+            .map(|e| e == "NotRegistered" || e == "InvalidRegistration")
+            .unwrap_or(false);
+        if unregistered {
+            return Err(Error::PushSubscriptionExpired);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ergo_database::object_id::TaskId;
+
+    use super::*;
+
+    #[test]
+    fn payload_includes_error() {
+        let notification = Notification {
+            event: NotifyEvent::ActionError,
+            task_id: TaskId::new(),
+            task_name: "Backup".to_string(),
+            local_id: "run".to_string(),
+            local_object_name: "Run backup".to_string(),
+            local_object_id: None,
+            payload: None,
+            error: Some("disk full".to_string()),
+            log_id: None,
+            suppressed_count: None,
+        };
+
+        let payload = push_payload(&notification, None, None);
+        assert_eq!(payload["body"], json!("Backup: Run backup\ndisk full"));
+        assert_eq!(payload["data"]["event"], json!("action_error"));
+    }
+}
@@ -0,0 +1,32 @@
+use super::{Error, Notification};
+
+use serde_json::json;
+
+/// Send a notification to a Slack incoming webhook. `hook` is the part of the webhook URL after
+/// `/services/`.
+pub async fn send_slack_webhook(
+    client: &reqwest::Client,
+    hook: &str,
+    notification: &Notification,
+    locale: Option<&str>,
+    text: Option<String>,
+) -> Result<(), Error> {
+    let text = text.unwrap_or_else(|| {
+        let fields = notification
+            .fields(locale)
+            .into_iter()
+            .map(|(name, value, _)| format!("*{}*: {}", name, value))
+            .collect::<Vec<_>>()
+            .join("\n");
+        format!("{}\n{}", notification.event.description(locale), fields)
+    });
+
+    let url = format!("https://hooks.slack.com/services/{}", hook);
+    client
+        .post(&url)
+        .json(&json!({ "text": text }))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
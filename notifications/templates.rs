@@ -0,0 +1,188 @@
+//! Org-specific text for notifications. An org can set a Handlebars template for each
+//! combination of event and service, and notifications without a template use the built-in
+//! formatting.
+//!
+//! Templates can use all of the [Notification] fields, along with `description`, `level`, and
+//! `local_object_type`. The `json` helper formats a value, such as the payload, as JSON.
+
+use chrono::{DateTime, Utc};
+use handlebars::{handlebars_helper, Handlebars};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use super::{Error, Notification, NotifyEvent, NotifyService};
+
+handlebars_helper!(json_helper: |value: Json| serde_json::to_string(value).unwrap_or_default());
+
+lazy_static! {
+    static ref HANDLEBARS: Handlebars<'static> = {
+        let mut h = Handlebars::new();
+        // Notifications are plain text, so nothing should be HTML-escaped.
+        h.register_escape_fn(|s| s.to_string());
+        h.register_helper("json", Box::new(json_helper));
+        h
+    };
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NotifyTemplate {
+    pub event: NotifyEvent,
+    pub service: NotifyService,
+    pub template: String,
+    pub updated: DateTime<Utc>,
+}
+
+/// Check that a template parses.
+pub fn validate_template(template: &str) -> Result<(), Error> {
+    handlebars::Template::compile(template)?;
+    Ok(())
+}
+
+/// The values that a template can use.
+fn template_data(notification: &Notification, locale: Option<&str>) -> serde_json::Value {
+    json!({
+        "event": notification.event,
+        "description": notification.event.description(locale),
+        "level": notification.event.level(),
+        "task_id": notification.task_id,
+        "task_name": notification.task_name,
+        "local_id": notification.local_id,
+        "local_object_type": notification.event.local_object_type(locale),
+        "local_object_name": notification.local_object_name,
+        "local_object_id": notification.local_object_id,
+        "payload": notification.payload,
+        "error": notification.error,
+        "log_id": notification.log_id,
+        "suppressed_count": notification.suppressed_count,
+    })
+}
+
+/// Render a notification with an org's template.
+pub fn render_template(
+    template: &str,
+    notification: &Notification,
+    locale: Option<&str>,
+) -> Result<String, Error> {
+    let text = HANDLEBARS.render_template(template, &template_data(notification, locale))?;
+    Ok(text)
+}
+
+pub async fn list_templates(
+    tx: &mut PgConnection,
+    org_id: &Uuid,
+) -> Result<Vec<NotifyTemplate>, Error> {
+    let templates = sqlx::query_as!(
+        NotifyTemplate,
+        r##"SELECT event AS "event: NotifyEvent", service AS "service: NotifyService",
+            template, updated
+        FROM notify_templates
+        WHERE org_id=$1
+        ORDER BY event, service"##,
+        org_id
+    )
+    .fetch_all(tx)
+    .await?;
+
+    Ok(templates)
+}
+
+/// Set the template for an event and service, replacing any existing template.
+pub async fn set_template(
+    tx: &mut PgConnection,
+    org_id: &Uuid,
+    event: NotifyEvent,
+    service: NotifyService,
+    template: &str,
+) -> Result<(), Error> {
+    validate_template(template)?;
+
+    sqlx::query!(
+        "INSERT INTO notify_templates (org_id, event, service, template)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (org_id, event, service)
+        DO UPDATE SET template=EXCLUDED.template, updated=now()",
+        org_id,
+        event as _,
+        service as _,
+        template
+    )
+    .execute(tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Remove the template for an event and service, so that its notifications go back to the
+/// built-in formatting. Returns false if there was no template.
+pub async fn delete_template(
+    tx: &mut PgConnection,
+    org_id: &Uuid,
+    event: NotifyEvent,
+    service: NotifyService,
+) -> Result<bool, Error> {
+    let result = sqlx::query!(
+        "DELETE FROM notify_templates WHERE org_id=$1 AND event=$2 AND service=$3",
+        org_id,
+        event as _,
+        service as _
+    )
+    .execute(tx)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use ergo_database::object_id::TaskId;
+    use serde_json::json;
+
+    use super::*;
+
+    fn notification() -> Notification {
+        Notification {
+            event: NotifyEvent::ActionError,
+            task_id: TaskId::new(),
+            task_name: "Deploy".to_string(),
+            local_id: "deploy".to_string(),
+            local_object_name: "Run <deploy>".to_string(),
+            local_object_id: None,
+            payload: Some(json!({ "branch": "main" })),
+            error: Some("exit code 1".to_string()),
+            log_id: None,
+            suppressed_count: None,
+        }
+    }
+
+    #[test]
+    fn render() {
+        let text = render_template(
+            "[{{level}}] {{task_name}}: {{local_object_name}} failed with {{error}} {{json payload}}",
+            &notification(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            text,
+            r##"[error] Deploy: Run <deploy> failed with exit code 1 {"branch":"main"}"##
+        );
+    }
+
+    #[test]
+    fn missing_values_render_empty() {
+        let mut n = notification();
+        n.error = None;
+        let text =
+            render_template("{{task_name}}{{#if error}} ({{error}}){{/if}}", &n, None).unwrap();
+        assert_eq!(text, "Deploy");
+    }
+
+    #[test]
+    fn invalid_template() {
+        assert!(validate_template("{{#if error}}unclosed").is_err());
+        assert!(validate_template("{{task_name}}").is_ok());
+    }
+}
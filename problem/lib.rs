@@ -0,0 +1,191 @@
+//! Error responses in the RFC 7807 `application/problem+json` format.
+//!
+//! Each crate's error type implements [ProblemDetails] to give its errors a stable code and an
+//! HTTP status. The API renders these as a [Problem], so clients can branch on `code` instead
+//! of parsing error messages.
+
+use serde::{Deserialize, Serialize};
+
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+/// A single validation failure, tied to the part of the request that caused it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldError {
+    /// The path to the offending field, or `None` if the failure isn't tied to one field.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        FieldError {
+            field: Some(field.into()),
+            message: message.into(),
+        }
+    }
+
+    pub fn without_field(message: impl Into<String>) -> Self {
+        FieldError {
+            field: None,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for FieldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.field {
+            Some(field) if !field.is_empty() => write!(f, "{}: {}", field, self.message),
+            _ => f.write_str(&self.message),
+        }
+    }
+}
+
+/// Join field errors into a single message, one per line.
+pub fn format_field_errors(errors: &[FieldError]) -> String {
+    errors
+        .iter()
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// An RFC 7807 problem details body.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub type_uri: String,
+    pub title: String,
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// The stable code for this kind of error.
+    pub code: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+impl Problem {
+    pub fn new(status: u16, code: &str) -> Self {
+        let title = http::StatusCode::from_u16(status)
+            .ok()
+            .and_then(|s| s.canonical_reason())
+            .unwrap_or("Error");
+
+        Problem {
+            type_uri: format!("urn:ergo:problem:{}", code),
+            title: title.to_string(),
+            status,
+            detail: None,
+            code: code.to_string(),
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    pub fn with_errors(mut self, errors: Vec<FieldError>) -> Self {
+        self.errors = errors;
+        self
+    }
+}
+
+/// Describes how an error should be reported to API clients.
+pub trait ProblemDetails: std::fmt::Display {
+    /// A stable, machine-readable identifier for the kind of error, in snake_case.
+    fn code(&self) -> &'static str;
+
+    /// The HTTP status for the error.
+    fn status(&self) -> u16 {
+        500
+    }
+
+    /// Field-level details for validation failures.
+    fn field_errors(&self) -> Vec<FieldError> {
+        Vec::new()
+    }
+
+    fn problem(&self) -> Problem {
+        let status = self.status();
+        let problem = Problem::new(status, self.code()).with_errors(self.field_errors());
+
+        // Server errors can contain SQL errors, file paths, and other internals, so the message
+        // is only logged and never returned.
+        if status >= 500 {
+            problem
+        } else {
+            problem.with_detail(self.to_string())
+        }
+    }
+}
+
+#[cfg(feature = "actix")]
+impl Problem {
+    pub fn to_response(&self) -> actix_web::HttpResponse {
+        let status = actix_web::http::StatusCode::from_u16(self.status)
+            .unwrap_or(actix_web::http::StatusCode::INTERNAL_SERVER_ERROR);
+        let body = serde_json::to_string(self).unwrap_or_default();
+        actix_web::HttpResponse::build(status)
+            .content_type(PROBLEM_CONTENT_TYPE)
+            .body(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestError(u16);
+
+    impl std::fmt::Display for TestError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("connection to 10.0.0.5 refused")
+        }
+    }
+
+    impl ProblemDetails for TestError {
+        fn code(&self) -> &'static str {
+            "test_error"
+        }
+
+        fn status(&self) -> u16 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn serialize_problem() {
+        let problem = Problem::new(400, "validation_failed")
+            .with_detail("Invalid input")
+            .with_errors(vec![FieldError::new("/name", "is required")]);
+        let value = serde_json::to_value(&problem).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "type": "urn:ergo:problem:validation_failed",
+                "title": "Bad Request",
+                "status": 400,
+                "detail": "Invalid input",
+                "code": "validation_failed",
+                "errors": [{ "field": "/name", "message": "is required" }],
+            })
+        );
+    }
+
+    #[test]
+    fn server_errors_hide_detail() {
+        let problem = TestError(503).problem();
+        assert_eq!(problem.detail, None);
+        assert_eq!(problem.title, "Service Unavailable");
+
+        let problem = TestError(409).problem();
+        assert_eq!(
+            problem.detail.as_deref(),
+            Some("connection to 10.0.0.5 refused")
+        );
+    }
+}
@@ -0,0 +1,279 @@
+//! Circuit breakers that stop work against a dependency that keeps failing. Each key counts its
+//! consecutive failures in Redis. Once the count reaches the threshold, the breaker opens and
+//! rejects work for the cooldown period. After the cooldown a single probe is let through: if it
+//! succeeds the breaker closes again, and if it fails the breaker stays open for another cooldown.
+
+use std::time::Duration;
+
+use chrono::Utc;
+use ergo_database::RedisPool;
+use lazy_static::lazy_static;
+
+use crate::error::Error;
+
+// Check if work can run for a key.
+// KEYS:
+//  1. breaker hash
+// ARGS:
+//  1. current time in milliseconds
+//  2. holder id
+//  3. probe lease in milliseconds
+//
+// Returns 0 if the breaker is closed, -1 if the holder may run as the half-open probe, or the
+// number of milliseconds to wait before checking again.
+const CHECK_SCRIPT: &str = r##"
+    local now = tonumber(ARGV[1])
+    local state = redis.call("HMGET", KEYS[1], "open_until", "probe", "probe_until")
+    local open_until = tonumber(state[1])
+    if not open_until then
+        return 0
+    end
+
+    if now < open_until then
+        return open_until - now
+    end
+
+    -- Only one probe runs at a time. A probe whose worker went away without reporting back
+    -- loses its lease, and then someone else can probe.
+    local probe_until = tonumber(state[3])
+    if state[2] and state[2] ~= ARGV[2] and probe_until and now < probe_until then
+        return probe_until - now
+    end
+
+    redis.call("HSET", KEYS[1], "probe", ARGV[2], "probe_until", now + tonumber(ARGV[3]))
+    return -1
+"##;
+
+// Record a failure for a key.
+// KEYS:
+//  1. breaker hash
+// ARGS:
+//  1. current time in milliseconds
+//  2. failure threshold
+//  3. cooldown in milliseconds
+//  4. how long to remember the failures, in milliseconds
+//
+// Returns 1 if this failure opened the breaker, and 0 otherwise.
+const FAILURE_SCRIPT: &str = r##"
+    local now = tonumber(ARGV[1])
+    local cooldown = tonumber(ARGV[3])
+    local open_until = tonumber(redis.call("HGET", KEYS[1], "open_until"))
+
+    if open_until then
+        if now < open_until then
+            -- Already open. This is work that started before the breaker opened.
+            return 0
+        end
+
+        -- Half-open, so the dependency is still failing.
+        redis.call("HSET", KEYS[1], "open_until", now + cooldown)
+        redis.call("HDEL", KEYS[1], "probe", "probe_until")
+        redis.call("PEXPIRE", KEYS[1], cooldown + tonumber(ARGV[4]))
+        return 1
+    end
+
+    local failures = redis.call("HINCRBY", KEYS[1], "failures", 1)
+    if failures >= tonumber(ARGV[2]) then
+        redis.call("HSET", KEYS[1], "open_until", now + cooldown)
+        redis.call("PEXPIRE", KEYS[1], cooldown + tonumber(ARGV[4]))
+        return 1
+    end
+
+    redis.call("PEXPIRE", KEYS[1], tonumber(ARGV[4]))
+    return 0
+"##;
+
+lazy_static! {
+    static ref CHECK: redis::Script = redis::Script::new(CHECK_SCRIPT);
+    static ref FAILURE: redis::Script = redis::Script::new(FAILURE_SCRIPT);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BreakerConfig {
+    /// The number of consecutive failures that opens the breaker.
+    pub failure_threshold: u32,
+    /// How long the breaker stays open before letting a probe through.
+    pub cooldown: Duration,
+    /// How long a probe can run before another one is allowed.
+    pub probe_lease: Duration,
+    /// Failures that are older than this, with nothing else happening since, are forgotten.
+    pub failure_window: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        BreakerConfig {
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(60),
+            probe_lease: Duration::from_secs(5 * 60),
+            failure_window: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Work can run as usual.
+    Closed,
+    /// The cooldown is over and this holder should run as the probe. Report the result with
+    /// [CircuitBreaker::success] or [CircuitBreaker::failure].
+    Probe,
+    /// The breaker is open. Check again after this long.
+    Open(Duration),
+}
+
+impl BreakerState {
+    pub fn is_open(&self) -> bool {
+        matches!(self, Self::Open(_))
+    }
+}
+
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    pool: RedisPool,
+    key_prefix: String,
+    config: BreakerConfig,
+}
+
+impl CircuitBreaker {
+    /// Create a circuit breaker. `name` distinguishes this breaker's keys from those of other
+    /// breakers.
+    pub fn new(pool: RedisPool, name: &str, config: BreakerConfig) -> CircuitBreaker {
+        let key_prefix = match pool.key_prefix() {
+            Some(prefix) => format!("erq:breaker:{}-{}:", prefix, name),
+            None => format!("erq:breaker:{}:", name),
+        };
+
+        CircuitBreaker {
+            pool,
+            key_prefix,
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &BreakerConfig {
+        &self.config
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.key_prefix, key)
+    }
+
+    /// Check if `holder` can run work for `key`.
+    pub async fn check(&self, key: &str, holder: &str) -> Result<BreakerState, Error> {
+        let mut conn = self.pool.get().await?;
+        let result: i64 = CHECK
+            .key(self.key(key))
+            .arg(Utc::now().timestamp_millis())
+            .arg(holder)
+            .arg(self.config.probe_lease.as_millis() as u64)
+            .invoke_async(&mut conn)
+            .await?;
+
+        let state = match result {
+            0 => BreakerState::Closed,
+            -1 => BreakerState::Probe,
+            wait => BreakerState::Open(Duration::from_millis(wait.max(1) as u64)),
+        };
+        Ok(state)
+    }
+
+    /// Record that work for `key` succeeded. This resets the failure count and closes the
+    /// breaker.
+    pub async fn success(&self, key: &str) -> Result<(), Error> {
+        let mut conn = self.pool.get().await?;
+        redis::cmd("DEL")
+            .arg(self.key(key))
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Record that work for `key` failed. Returns true if this failure opened the breaker.
+    pub async fn failure(&self, key: &str) -> Result<bool, Error> {
+        let mut conn = self.pool.get().await?;
+        let opened: i64 = FAILURE
+            .key(self.key(key))
+            .arg(Utc::now().timestamp_millis())
+            .arg(self.config.failure_threshold)
+            .arg(self.config.cooldown.as_millis() as u64)
+            .arg(self.config.failure_window.as_millis() as u64)
+            .invoke_async(&mut conn)
+            .await?;
+        Ok(opened == 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(cooldown: Duration) -> CircuitBreaker {
+        dotenv::dotenv().ok();
+        let pool = RedisPool::new(None, None).expect("Creating connection pool");
+        CircuitBreaker::new(
+            pool,
+            &format!("test-{}", uuid::Uuid::new_v4()),
+            BreakerConfig {
+                failure_threshold: 3,
+                cooldown,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn opens_after_consecutive_failures() -> Result<(), Error> {
+        let breaker = breaker(Duration::from_secs(30));
+
+        assert!(!breaker.failure("key").await?);
+        assert!(!breaker.failure("key").await?);
+        breaker.success("key").await?;
+        assert_eq!(
+            breaker.check("key", "a").await?,
+            BreakerState::Closed,
+            "success resets the count"
+        );
+
+        assert!(!breaker.failure("key").await?);
+        assert!(!breaker.failure("key").await?);
+        assert!(breaker.failure("key").await?, "third failure opens");
+        assert!(breaker.check("key", "a").await?.is_open());
+        assert_eq!(
+            breaker.check("other", "a").await?,
+            BreakerState::Closed,
+            "keys are tracked separately"
+        );
+
+        assert!(
+            !breaker.failure("key").await?,
+            "failures while open don't reopen"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn half_open_probe() -> Result<(), Error> {
+        let breaker = breaker(Duration::from_millis(100));
+        for _ in 0..3 {
+            breaker.failure("key").await?;
+        }
+        assert!(breaker.check("key", "a").await?.is_open());
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(breaker.check("key", "a").await?, BreakerState::Probe);
+        assert!(
+            breaker.check("key", "b").await?.is_open(),
+            "only one probe at a time"
+        );
+
+        assert!(breaker.failure("key").await?, "failed probe reopens");
+        assert!(breaker.check("key", "b").await?.is_open());
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(breaker.check("key", "b").await?, BreakerState::Probe);
+        breaker.success("key").await?;
+        assert_eq!(breaker.check("key", "a").await?, BreakerState::Closed);
+        Ok(())
+    }
+}
@@ -1,17 +1,25 @@
 use async_trait::async_trait;
 use backoff::backoff::Backoff;
-use ergo_graceful_shutdown::GracefulShutdownConsumer;
+use ergo_graceful_shutdown::{GracefulShutdownConsumer, ShutdownPhase};
 use futures::{
-    future::ready,
+    future::{ready, BoxFuture},
     stream::{FuturesUnordered, StreamExt},
+    FutureExt,
 };
+use fxhash::FxHashMap;
 use serde::de::DeserializeOwned;
-use tokio::{sync::oneshot, task::JoinHandle};
+use tokio::{
+    sync::oneshot,
+    task::{JoinError, JoinHandle},
+};
 use tracing::{event, Level};
 
-use std::time::Duration;
+use std::{collections::hash_map::Entry, sync::atomic::Ordering, time::Duration};
 
-use super::QueueWorkItem;
+use super::{
+    workers::{start_worker_heartbeat, InFlightJobs},
+    ErrorClass, QueueWorkItem,
+};
 
 #[async_trait]
 pub trait QueueJobProcessor: Clone + Sync + Send {
@@ -23,30 +31,106 @@ pub trait QueueJobProcessor: Clone + Sync + Send {
         item: &QueueWorkItem<Self::Payload>,
         payload: Self::Payload,
     ) -> Result<(), Self::Error>;
+
+    /// Decide if a job that failed with this error should be retried. Permanent errors fail
+    /// the job immediately, without waiting through the retry backoff.
+    fn error_class(&self, _error: &Self::Error) -> ErrorClass {
+        ErrorClass::Retryable
+    }
+
+    /// The maximum number of jobs with the same fairness key that this worker will run at
+    /// once. Jobs with a key that is at the limit are left in the queue for later, so that
+    /// jobs with other keys can run in the meantime.
+    fn max_jobs_per_key(&self) -> Option<usize> {
+        None
+    }
+
+    /// Return a delay to wait before taking another job, when whatever this processor hands
+    /// its work off to is falling behind.
+    async fn backpressure(&self) -> Option<Duration> {
+        None
+    }
+
+    /// How often to send a heartbeat for a running job, which pushes back its processing
+    /// deadline so that jobs which run longer than the queue's timeout aren't retried while they
+    /// are still running. This should be comfortably shorter than the timeout.
+    fn heartbeat_interval(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// How often a paused dequeuer loop checks if it should resume.
+const PAUSED_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+type ActiveJob = BoxFuture<'static, (Option<String>, Result<(), JoinError>)>;
+
+/// Keeps count of the running jobs for each fairness key.
+#[derive(Default)]
+struct KeyCounts(FxHashMap<String, usize>);
+
+impl KeyCounts {
+    fn start(&mut self, key: &str) {
+        *self.0.entry(key.to_string()).or_default() += 1;
+    }
+
+    fn finish(&mut self, (key, result): (Option<String>, Result<(), JoinError>)) {
+        if let Err(e) = result {
+            event!(Level::ERROR, error=%e, "Job task panicked");
+        }
+
+        if let Some(Entry::Occupied(mut entry)) = key.map(|k| self.0.entry(k)) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+
+    fn saturated(&self, max_per_key: Option<usize>) -> Vec<&str> {
+        match max_per_key {
+            Some(max) => self
+                .0
+                .iter()
+                .filter(|(_, count)| **count >= max)
+                .map(|(key, _)| key.as_str())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
 }
 
 pub fn dequeuer_loop<P, T>(
     queue: super::Queue,
-    mut shutdown: GracefulShutdownConsumer,
+    shutdown: GracefulShutdownConsumer,
     closer_rx: oneshot::Receiver<()>,
     mut backoff: Box<dyn Backoff + Send>,
-    max_jobs: usize,
     processor: P,
 ) -> JoinHandle<()>
 where
     P: QueueJobProcessor<Payload = T> + 'static,
     T: DeserializeOwned + Send + Sync + 'static,
 {
+    // Stop taking jobs once the server has stopped accepting new work, and hold up the rest of
+    // the shutdown until the jobs that are already running have finished.
+    let subsystem = shutdown.register(format!("queue {}", queue.0.name), ShutdownPhase::Drain);
+    let mut shutdown = subsystem.consumer();
+
     tokio::spawn(async move {
         let shutdown_fut = shutdown.wait_for_shutdown();
         tokio::pin!(shutdown_fut);
         tokio::pin!(closer_rx);
 
-        let mut active_tasks = FuturesUnordered::<JoinHandle<()>>::new();
+        let mut active_tasks = FuturesUnordered::<ActiveJob>::new();
+        let mut key_counts = KeyCounts::default();
+        let heartbeat_interval = processor.heartbeat_interval();
         let mut sleep_time = Duration::default();
+        let in_flight = InFlightJobs::default();
+        let worker_heartbeat = start_worker_heartbeat(queue.clone(), in_flight.clone());
 
         loop {
+            let max_jobs = queue.0.max_jobs.load(Ordering::Relaxed);
             let wait_for_task = active_tasks.len() >= max_jobs;
+            queue.record_dequeuer_poll(wait_for_task);
             let do_backoff = sleep_time > Duration::default();
             if wait_for_task || do_backoff {
                 tokio::select! {
@@ -54,31 +138,68 @@ where
 
                     _ = &mut shutdown_fut => break,
                     _ = &mut closer_rx => break,
-                    res = active_tasks.select_next_some(), if wait_for_task => {
-                        if let Err(e) = res {
-                            event!(Level::ERROR, error=%e, "Job task panicked");
-                        }
-                    },
+                    res = active_tasks.select_next_some(), if wait_for_task => key_counts.finish(res),
                     _ = tokio::time::sleep(sleep_time), if do_backoff => {},
                 };
             }
 
-            match queue.get_job::<T>().await {
+            if let Some(delay) = processor.backpressure().await {
+                event!(Level::DEBUG, queue=%queue.0.name, delay=?delay, "Slowing down for backpressure");
+                sleep_time = delay;
+                continue;
+            }
+
+            if queue.is_paused() {
+                sleep_time = PAUSED_POLL_INTERVAL;
+                continue;
+            }
+
+            // Catch up on any jobs that finished, so that the per-key counts are current.
+            while let Some(Some(r)) = active_tasks.next().now_or_never() {
+                key_counts.finish(r);
+            }
+
+            let max_per_key = *queue.0.max_jobs_per_key.lock().unwrap();
+            let skip_keys = key_counts.saturated(max_per_key);
+            match queue.get_fair_job::<T>(&skip_keys).await {
                 Ok(Some(mut job)) => {
                     backoff.reset();
                     sleep_time = Duration::default();
 
+                    let key = job.fairness_key.clone();
+                    if let Some(key) = key.as_deref() {
+                        key_counts.start(key);
+                    }
+
                     let p = processor.clone();
                     let queue_name = queue.0.name.clone();
+                    let in_flight_job = in_flight.start(&job.id);
                     let job_task = tokio::spawn(async move {
-                        match job.process(|item, payload| p.process(item, payload)).await {
+                        let _in_flight_job = in_flight_job;
+                        let result = job
+                            .process_with_classifier(
+                                |item, payload| {
+                                    let fut = p.process(item, payload);
+                                    async move {
+                                        match heartbeat_interval {
+                                            Some(interval) => {
+                                                item.with_heartbeat(interval, fut).await
+                                            }
+                                            None => fut.await,
+                                        }
+                                    }
+                                },
+                                |e| p.error_class(e),
+                            )
+                            .await;
+                        match result {
                             Ok(_) => {}
                             Err(e) => {
                                 event!(Level::ERROR, error=?e, job=%job.id, queue=%queue_name, "Job error");
                             }
                         };
                     });
-                    active_tasks.push(job_task);
+                    active_tasks.push(job_task.map(move |result| (key, result)).boxed());
                 }
                 Ok(None) => match backoff.next_backoff() {
                     Some(next_sleep_time) => {
@@ -86,6 +207,20 @@ where
                     }
                     None => break,
                 },
+                Err(e) if e.is_connection_error() => {
+                    // Wait for Redis to come back instead of burning through the backoff, which
+                    // would stop the loop if the backoff has a maximum elapsed time.
+                    tokio::select! {
+                        biased;
+
+                        _ = &mut shutdown_fut => break,
+                        _ = &mut closer_rx => break,
+                        _ = queue.wait_for_reconnect(&e) => {},
+                    };
+
+                    backoff.reset();
+                    sleep_time = Duration::default();
+                }
                 Err(e) => {
                     event!(Level::ERROR, error=%e, queue=%queue.0.name, "Error dequeueing job");
                     match backoff.next_backoff() {
@@ -100,11 +235,22 @@ where
             // Make sure we call this periodically so that futures are processed.
             tokio::select! {
                 biased;
-                r = active_tasks.next() => if let Some(Err(e)) = r {
-                    event!(Level::ERROR, error=%e, "Job task panicked");
+                r = active_tasks.next() => if let Some(r) = r {
+                    key_counts.finish(r);
                 },
                 _ = ready(()) => {}
             };
         }
+
+        subsystem.drain_started();
+        event!(Level::INFO, queue=%queue.0.name, jobs=active_tasks.len(), "Waiting for running jobs to finish");
+        while let Some(r) = active_tasks.next().await {
+            key_counts.finish(r);
+        }
+        worker_heartbeat.abort();
+        if let Err(e) = queue.deregister_worker().await {
+            event!(Level::WARN, queue=%queue.0.name, error=%e, "Error removing worker from registry");
+        }
+        subsystem.drain_finished();
     })
 }
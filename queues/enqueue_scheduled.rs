@@ -1,25 +1,89 @@
 use chrono::{DateTime, Utc};
-use deadpool_redis::Connection;
+use ergo_database::redis::RedisConnection;
 use lazy_static::lazy_static;
 
-use crate::error::Error;
+use crate::{error::Error, recurring::REFILL_THRESHOLD, streams::QueueMode};
 
-use super::Queue;
+use super::{Queue, BACKGROUND_INTERVAL, BACKGROUND_KEY_PREFIX};
 
 // KEYS:
 //  1. scheduled items list
 //  2. pending items list
 //  3. queue stats hash
+//  4. fairness keys sorted set
+//  5. recurring jobs refill set
 // ARGV:
 //  1. current time
-const ENQUEUE_SCHEDULED_SCRIPT: &str = r##"
+//  2. job data prefix
+//  3. fairness list prefix
+//  4. recurring job definition prefix
+//  5. refill threshold for upcoming run times
+//  6. stream to add ready jobs to, or an empty string to use the pending lists
+//  7. how long a new background key waits for its first turn, in milliseconds
+//  8. background key prefix
+//
+// When an occurrence of a recurring job is moved, the next occurrence is created from the job's
+// definition and the first upcoming run time that hasn't passed yet. Jobs that are running low
+// on upcoming run times are added to the refill set, for the queue to top up.
+pub(crate) const ENQUEUE_SCHEDULED_SCRIPT: &str = r##"
     local move_items = redis.call('ZRANGEBYSCORE', KEYS[1], 0, ARGV[1])
     if #move_items == 0 then
         return 0
     end
 
+    local now = tonumber(ARGV[1])
     redis.call('ZREM', KEYS[1], unpack(move_items))
-    redis.call('LPUSH', KEYS[2], unpack(move_items))
+    for _, item in ipairs(move_items) do
+        local job_key = ARGV[2] .. item
+        if ARGV[6] ~= '' then
+            redis.call('XADD', ARGV[6], '*', 'id', item)
+            redis.call('HSET', job_key, 'rdy', 1)
+        else
+            local fairness_key = redis.call('HGET', job_key, 'fk')
+            if fairness_key then
+                redis.call('LPUSH', ARGV[3] .. fairness_key, item)
+            else
+                fairness_key = ''
+                redis.call('LPUSH', KEYS[2], item)
+            end
+            local score = 0
+            if string.sub(fairness_key, 1, #ARGV[8]) == ARGV[8] then
+                score = now + tonumber(ARGV[7])
+            end
+            redis.call('ZADD', KEYS[4], 'NX', score, fairness_key)
+        end
+
+        local recurring = redis.call('HGET', job_key, 'rec')
+        local def_key = recurring and (ARGV[4] .. recurring)
+        -- Skip occurrences of recurring jobs that were removed or replaced.
+        if def_key and redis.call('HGET', def_key, 'cur') == item then
+            local upcoming_key = def_key .. ':upcoming'
+            local next_run = redis.call('LPOP', upcoming_key)
+            while next_run and tonumber(next_run) <= now do
+                next_run = redis.call('LPOP', upcoming_key)
+            end
+
+            if next_run then
+                local next_id = recurring .. '@' .. next_run
+                local next_key = ARGV[2] .. next_id
+                local def = redis.call('HMGET', def_key, 'pay', 'to', 'mr', 'bo', 'fk')
+                redis.call('HSET', next_key,
+                    'pay', def[1], 'to', def[2], 'mr', def[3], 'bo', def[4],
+                    'cr', 0, 'ra', next_run, 'qt', ARGV[1], 'rec', recurring)
+                if def[5] then
+                    redis.call('HSET', next_key, 'fk', def[5])
+                end
+                redis.call('ZADD', KEYS[1], next_run, next_id)
+                redis.call('HSET', def_key, 'cur', next_id)
+            else
+                redis.call('HDEL', def_key, 'cur')
+            end
+
+            if redis.call('LLEN', upcoming_key) < tonumber(ARGV[5]) then
+                redis.call('SADD', KEYS[5], recurring)
+            end
+        end
+    end
     redis.call("HINCRBY", KEYS[3], "scheduled", 1)
     return #move_items
 "##;
@@ -38,15 +102,29 @@ impl EnqueueScript {
     pub async fn run(
         &self,
         queue: &Queue,
-        conn: &mut Connection,
+        conn: &mut RedisConnection,
         now: &DateTime<Utc>,
     ) -> Result<usize, Error> {
+        let stream = match queue.0.mode {
+            QueueMode::Lists => "",
+            QueueMode::Streams => queue.0.stream.as_str(),
+        };
+
         let items_enqueued: usize = self
             .0
             .key(&queue.0.scheduled_list)
             .key(&queue.0.pending_list)
             .key(&queue.0.stats_hash)
+            .key(&queue.0.fair_keys)
+            .key(&queue.0.recurring_refill)
             .arg(now.timestamp_millis() as i64)
+            .arg(&queue.0.job_data_prefix)
+            .arg(&queue.0.fair_list_prefix)
+            .arg(&queue.0.recurring_prefix)
+            .arg(REFILL_THRESHOLD)
+            .arg(stream)
+            .arg(BACKGROUND_INTERVAL.as_millis() as i64)
+            .arg(BACKGROUND_KEY_PREFIX)
             .invoke_async(&mut **conn)
             .await?;
 
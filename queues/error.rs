@@ -28,4 +28,31 @@ pub enum Error {
 
     #[error("Job drain error: {0}")]
     DrainError(anyhow::Error),
+
+    #[error("Invalid schedule: {0}")]
+    InvalidSchedule(String),
+}
+
+impl Error {
+    /// Returns true if the error came from losing the connection to Redis, such as when Redis
+    /// restarts or fails over. These errors go away once the connection comes back.
+    pub fn is_connection_error(&self) -> bool {
+        match self {
+            Error::RedisError(e) => is_redis_connection_error(e),
+            Error::RedisPoolError(deadpool::managed::PoolError::Backend(e)) => {
+                is_redis_connection_error(e)
+            }
+            Error::RedisPoolError(deadpool::managed::PoolError::Timeout(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+fn is_redis_connection_error(e: &redis::RedisError) -> bool {
+    e.is_io_error()
+        || e.is_connection_dropped()
+        || e.is_connection_refusal()
+        || e.is_timeout()
+        // Redis returns this while it's loading its data after a restart.
+        || e.kind() == redis::ErrorKind::BusyLoadingError
 }
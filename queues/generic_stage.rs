@@ -1,16 +1,15 @@
-use super::postgres_drain::Drainer;
 use crate::{
     error::Error,
     postgres_drain::{DrainResult, QueueOperation},
+    table_stage::TableStage,
     Job,
 };
 
-use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use ergo_database::{new_uuid, sql_insert_parameters};
 use serde::Serialize;
 use smallvec::SmallVec;
-use sqlx::{PgConnection, Postgres, Transaction};
+use sqlx::{postgres::PgRow, PgConnection, Row};
 use std::{borrow::Cow, str::FromStr, time::Duration};
 
 pub struct QueueJob<'a, T: Serialize + Send + Sync> {
@@ -21,6 +20,10 @@ pub struct QueueJob<'a, T: Serialize + Send + Sync> {
     pub max_retries: Option<u32>,
     pub run_at: Option<DateTime<Utc>>,
     pub retry_backoff: Option<Duration>,
+    /// Run the job ahead of the normal jobs in the queue.
+    pub high_priority: bool,
+    /// Take turns running this job with jobs that have other keys.
+    pub fairness_key: Option<&'a str>,
 }
 
 impl<'a, T: Serialize + Send + Sync> QueueJob<'a, T> {
@@ -34,6 +37,8 @@ impl<'a, T: Serialize + Send + Sync> QueueJob<'a, T> {
             max_retries: None,
             run_at: None,
             retry_backoff: None,
+            high_priority: false,
+            fairness_key: None,
         }
     }
 
@@ -67,6 +72,18 @@ impl<'a, T: Serialize + Send + Sync> QueueJob<'a, T> {
         self
     }
 
+    #[must_use]
+    pub fn high_priority(&mut self, high_priority: bool) -> &mut Self {
+        self.high_priority = high_priority;
+        self
+    }
+
+    #[must_use]
+    pub fn fairness_key(&mut self, fairness_key: &'a str) -> &mut Self {
+        self.fairness_key = Some(fairness_key);
+        self
+    }
+
     fn get_id_or_default(&self) -> Cow<'a, str> {
         self.id
             .map(|s| Cow::Borrowed(s))
@@ -90,11 +107,11 @@ pub async fn enqueue_jobs<T: Serialize + Send + Sync>(
     }
 
     let q = format!(
-        r##"INSERT INTO queue_stage (queue, job_id, payload, timeout, max_retries, run_at, retry_backoff)
+        r##"INSERT INTO queue_stage (queue, job_id, payload, timeout, max_retries, run_at, retry_backoff, high_priority, fairness_key)
             VALUES
             {}
             RETURNING job_id"##,
-        sql_insert_parameters::<7>(jobs.len())
+        sql_insert_parameters::<9>(jobs.len())
     );
 
     let mut query = sqlx::query_as(&q);
@@ -106,7 +123,9 @@ pub async fn enqueue_jobs<T: Serialize + Send + Sync>(
             .bind(job.timeout.map(|t| t.as_millis() as i32))
             .bind(job.max_retries.map(|i| i as i32))
             .bind(job.run_at)
-            .bind(job.retry_backoff.map(|i| i.as_millis() as i32));
+            .bind(job.retry_backoff.map(|i| i.as_millis() as i32))
+            .bind(job.high_priority)
+            .bind(job.fairness_key);
     }
 
     let ids: Vec<Result> = query.fetch_all(&mut *tx).await?;
@@ -118,65 +137,52 @@ pub async fn enqueue_jobs<T: Serialize + Send + Sync>(
     Ok(ids.into_iter().map(|r| r.job_id).collect())
 }
 
-pub struct QueueDrainer {}
-
 pub(crate) const NOTIFY_CHANNEL: &str = "queue-generic";
 
-#[async_trait]
-impl Drainer for QueueDrainer {
-    type Error = Error;
-
-    fn notify_channel(&self) -> Option<String> {
-        Some(NOTIFY_CHANNEL.to_string())
-    }
-
-    fn lock_key(&self) -> i64 {
-        80235523425
-    }
-
-    async fn get(&'_ self, tx: &mut Transaction<Postgres>) -> Result<Vec<DrainResult<'_>>, Error> {
-        let results = sqlx::query!(
-            "SELECT id, queue, job_id, payload,
-            timeout, max_retries, run_at, retry_backoff, operation
-            FROM queue_stage
-            ORDER BY id LIMIT 50"
-        )
-        .fetch_all(&mut *tx)
-        .await?;
-
-        if let Some(max_id) = results.last().map(|r| r.id) {
-            sqlx::query!("DELETE FROM queue_stage WHERE id <= $1", max_id)
-                .execute(&mut *tx)
-                .await?;
-        }
+/// The stage for the jobs added with [QueueJob] and [enqueue_jobs].
+pub fn queue_stage() -> TableStage {
+    // High priority jobs are drained first, so they don't wait behind a backlog of normal
+    // jobs. Within each priority the jobs keep their original order.
+    TableStage::new("queue_stage", "queue_stage", map_queue_stage_row)
+        .order_by("high_priority DESC, id")
+        .notify_channel(NOTIFY_CHANNEL)
+        .lock_key(80235523425)
+}
 
-        results
-            .into_iter()
-            .map(|row| {
-                let operation = row
-                    .operation
-                    .map(|op| QueueOperation::from_str(op.as_str()).unwrap()) // TODO no unwrap
-                    .unwrap_or(QueueOperation::Add);
-
-                let payload = match (&operation, row.payload.as_ref()) {
-                    (QueueOperation::Update, None) => Cow::Borrowed("".as_bytes()),
-                    (_, None) => Cow::Borrowed("null".as_bytes()),
-                    (_, Some(v)) => Cow::Owned(serde_json::to_vec(v)?),
-                };
-
-                Ok(DrainResult {
-                    queue: Cow::Owned(row.queue),
-                    operation,
-                    job: Job {
-                        id: row.job_id,
-                        retry_backoff: row.retry_backoff.map(|r| Duration::from_millis(r as u64)),
-                        run_at: row.run_at,
-                        max_retries: row.max_retries.map(|r| r as u32),
-                        timeout: row.timeout.map(|t| Duration::from_millis(t as u64)),
-                        payload,
-                    },
-                })
-            })
-            .collect::<Result<Vec<_>, Error>>()
-    }
+fn map_queue_stage_row(row: &PgRow) -> Result<DrainResult<'static>, anyhow::Error> {
+    let operation = row
+        .try_get::<Option<String>, _>("operation")?
+        .and_then(|op| QueueOperation::from_str(op.as_str()).ok())
+        .unwrap_or(QueueOperation::Add);
+
+    let payload = match (
+        &operation,
+        row.try_get::<Option<serde_json::Value>, _>("payload")?,
+    ) {
+        (QueueOperation::Update, None) => Cow::Borrowed("".as_bytes()),
+        (_, None) => Cow::Borrowed("null".as_bytes()),
+        (_, Some(v)) => Cow::Owned(serde_json::to_vec(&v)?),
+    };
+
+    Ok(DrainResult {
+        queue: Cow::Owned(row.try_get("queue")?),
+        operation,
+        job: Job {
+            id: row.try_get("job_id")?,
+            retry_backoff: row
+                .try_get::<Option<i32>, _>("retry_backoff")?
+                .map(|r| Duration::from_millis(r as u64)),
+            run_at: row.try_get("run_at")?,
+            max_retries: row
+                .try_get::<Option<i32>, _>("max_retries")?
+                .map(|r| r as u32),
+            timeout: row
+                .try_get::<Option<i32>, _>("timeout")?
+                .map(|t| Duration::from_millis(t as u64)),
+            payload,
+            high_priority: row.try_get("high_priority")?,
+            fairness_key: row.try_get("fairness_key")?,
+        },
+        staged_at: row.try_get("time")?,
+    })
 }
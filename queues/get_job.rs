@@ -1,19 +1,114 @@
 use chrono::{DateTime, Utc};
-use deadpool_redis::Connection;
+use ergo_database::redis::RedisConnection;
 use lazy_static::lazy_static;
 
 use crate::error::Error;
 
-use super::Queue;
+use super::{Queue, AGING_INTERVAL, BACKGROUND_INTERVAL, BACKGROUND_KEY_PREFIX};
 
 // KEYS:
 //  1. pending items list
 //  2. processing list
 //  3. job data hash
+//  4. priority items list
+//  5. fairness keys sorted set
 // ARGV:
 //  1. queue-default expiration time
-const DEQUEUE_ITEM_SCRIPT: &str = r##"
-    local latest_item = redis.call("LPOP", KEYS[1])
+//  2. fairness list prefix
+//  3. current time
+//  4. how long a background key waits after its turn, in milliseconds
+//  5. background key prefix
+//  6. aging interval in milliseconds, or 0 to disable aging
+//  7. job data prefix
+//  8+. fairness keys to skip
+pub(crate) const DEQUEUE_ITEM_SCRIPT: &str = r##"
+    local now = tonumber(ARGV[3])
+    local aging = tonumber(ARGV[6])
+
+    -- How long the oldest job in a list has been ready to run, counting from its run time for a
+    -- scheduled job or retry. Jobs are pushed onto the head of a list, so the oldest is at the
+    -- tail.
+    local function oldest_wait(list)
+        local oldest = redis.call("LINDEX", list, -1)
+        if oldest == false then
+            return false
+        end
+
+        local times = redis.call("HMGET", ARGV[7] .. oldest, "ra", "qt")
+        local ready_at = tonumber(times[1]) or tonumber(times[2]) or now
+        return now - ready_at
+    end
+
+    -- Take the newest job, unless the oldest job has waited longer than the aging interval.
+    local function pop(list, wait)
+        if aging > 0 and wait >= aging then
+            return redis.call("RPOP", list)
+        end
+        return redis.call("LPOP", list)
+    end
+
+    local skip = {}
+    for i = 8, #ARGV do
+        skip[ARGV[i]] = true
+    end
+
+    -- Find the key that was served least recently, passing over the keys that the worker is
+    -- already running as many jobs as it can for.
+    local fair_key = false
+    local fair_list = false
+    local fair_wait = false
+    local keys = redis.call("ZRANGE", KEYS[5], 0, 99)
+    for _, key in ipairs(keys) do
+        if skip[key] == nil then
+            local list = KEYS[1]
+            if key ~= "" then
+                list = ARGV[2] .. key
+            end
+
+            local wait = oldest_wait(list)
+            if wait == false then
+                redis.call("ZREM", KEYS[5], key)
+            else
+                fair_key = key
+                fair_list = list
+                fair_wait = wait
+                break
+            end
+        end
+    end
+
+    -- Priority jobs count as if they had already waited for the aging interval, so a normal job
+    -- goes first once it has waited that much longer than the oldest priority job.
+    local high_priority = 0
+    local fairness_key = false
+    local latest_item = false
+    local priority_wait = oldest_wait(KEYS[4])
+    if priority_wait ~= false and (fair_wait == false or aging <= 0 or priority_wait + aging >= fair_wait) then
+        high_priority = 1
+        latest_item = pop(KEYS[4], priority_wait)
+    elseif fair_list ~= false then
+        latest_item = pop(fair_list, fair_wait)
+
+        -- A background key's next turn is pushed back so that the other keys go first in the
+        -- meantime.
+        if redis.call("LLEN", fair_list) == 0 then
+            redis.call("ZREM", KEYS[5], fair_key)
+        elseif string.sub(fair_key, 1, #ARGV[5]) == ARGV[5] then
+            redis.call("ZADD", KEYS[5], now + tonumber(ARGV[4]), fair_key)
+        else
+            redis.call("ZADD", KEYS[5], now, fair_key)
+        end
+
+        if fair_key ~= "" then
+            fairness_key = fair_key
+        end
+    end
+
+    -- Jobs enqueued before fairness keys existed are only in the pending list.
+    if latest_item == false then
+        latest_item = redis.call("LPOP", KEYS[1])
+    end
+
     if latest_item == false then
         return false
     end
@@ -21,13 +116,19 @@ const DEQUEUE_ITEM_SCRIPT: &str = r##"
     -- Set the default queue expiration. The job worker will update it if needed
     redis.call("ZADD", KEYS[2], tonumber(ARGV[1]), latest_item)
     redis.call("HINCRBY", KEYS[3], "retrieved", 1)
-    return latest_item
+    return { latest_item, high_priority, fairness_key }
 "##;
 
 lazy_static! {
     static ref SCRIPT: redis::Script = redis::Script::new(DEQUEUE_ITEM_SCRIPT);
 }
 
+pub struct DequeuedJob {
+    pub id: String,
+    pub high_priority: bool,
+    pub fairness_key: Option<String>,
+}
+
 pub struct GetJobScript(&'static redis::Script);
 
 impl GetJobScript {
@@ -38,19 +139,36 @@ impl GetJobScript {
     pub async fn run(
         &self,
         queue: &Queue,
-        conn: &mut Connection,
+        conn: &mut RedisConnection,
         now: &DateTime<Utc>,
-    ) -> Result<Option<String>, Error> {
+        skip_fairness_keys: &[&str],
+    ) -> Result<Option<DequeuedJob>, Error> {
         let now_millis = now.timestamp_millis();
-        let job_id: Option<String> = self
-            .0
+        let mut invocation = self.0.prepare_invoke();
+        invocation
             .key(&queue.0.pending_list)
             .key(&queue.0.processing_list)
             .key(&queue.0.stats_hash)
+            .key(&queue.0.priority_list)
+            .key(&queue.0.fair_keys)
             .arg(now_millis + queue.0.processing_timeout.as_millis() as i64)
-            .invoke_async(&mut **conn)
-            .await?;
+            .arg(&queue.0.fair_list_prefix)
+            .arg(now_millis)
+            .arg(BACKGROUND_INTERVAL.as_millis() as i64)
+            .arg(BACKGROUND_KEY_PREFIX)
+            .arg(AGING_INTERVAL.as_millis() as i64)
+            .arg(&queue.0.job_data_prefix);
+        for key in skip_fairness_keys {
+            invocation.arg(*key);
+        }
+
+        let job: Option<(String, bool, Option<String>)> =
+            invocation.invoke_async(&mut **conn).await?;
 
-        Ok(job_id)
+        Ok(job.map(|(id, high_priority, fairness_key)| DequeuedJob {
+            id,
+            high_priority,
+            fairness_key,
+        }))
     }
 }
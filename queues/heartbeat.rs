@@ -0,0 +1,64 @@
+use chrono::{DateTime, Utc};
+use ergo_database::redis::RedisConnection;
+use lazy_static::lazy_static;
+
+use crate::error::Error;
+
+use super::Queue;
+
+// Push back the processing deadline of a running job.
+// KEYS:
+//  1. job data key
+//  2. processing list
+// ARGS:
+//  1. job id
+//  2. current time
+//  3. expected expiration
+//  4. new expiration
+pub(crate) const HEARTBEAT_SCRIPT: &str = r##"
+    local score = redis.call("ZSCORE", KEYS[2], ARGV[1])
+    if score ~= ARGV[3] then
+        -- The job timed out and may be running somewhere else now, so leave it alone.
+        return 0
+    end
+
+    redis.call("ZADD", KEYS[2], "XX", ARGV[4], ARGV[1])
+    redis.call("HSET", KEYS[1], "hb", ARGV[2])
+    return 1
+"##;
+
+lazy_static! {
+    static ref SCRIPT: redis::Script = redis::Script::new(HEARTBEAT_SCRIPT);
+}
+
+pub struct HeartbeatScript(&'static redis::Script);
+
+impl HeartbeatScript {
+    pub fn new() -> Self {
+        HeartbeatScript(&SCRIPT)
+    }
+
+    pub async fn run(
+        &self,
+        queue: &Queue,
+        conn: &mut RedisConnection,
+        job_id: &str,
+        job_data_key: &str,
+        now: &DateTime<Utc>,
+        expected_expiration: &DateTime<Utc>,
+        new_expiration: &DateTime<Utc>,
+    ) -> Result<bool, Error> {
+        let extended: bool = self
+            .0
+            .key(job_data_key)
+            .key(&queue.0.processing_list)
+            .arg(job_id)
+            .arg(now.timestamp_millis())
+            .arg(expected_expiration.timestamp_millis())
+            .arg(new_expiration.timestamp_millis())
+            .invoke_async(&mut **conn)
+            .await?;
+
+        Ok(extended)
+    }
+}
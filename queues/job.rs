@@ -11,6 +11,13 @@ pub struct Job<'a> {
     pub max_retries: Option<u32>,
     pub run_at: Option<DateTime<Utc>>,
     pub retry_backoff: Option<Duration>,
+    /// Put the job in the priority lane, which is always dequeued before the normal pending
+    /// list. This is ignored for scheduled jobs.
+    pub high_priority: bool,
+    /// Jobs with different fairness keys are dequeued in turn, so that a key with many jobs
+    /// can't keep the others from running. Jobs without a key share a single turn. Keys made
+    /// with [crate::background_fairness_key] give way to the other keys.
+    pub fairness_key: Option<String>,
 }
 
 impl<'a> std::fmt::Debug for Job<'a> {
@@ -22,6 +29,8 @@ impl<'a> std::fmt::Debug for Job<'a> {
             .field("max_retries", &self.max_retries)
             .field("run_at", &self.run_at)
             .field("retry_backoff", &self.retry_backoff)
+            .field("high_priority", &self.high_priority)
+            .field("fairness_key", &self.fairness_key)
             .finish()
     }
 }
@@ -1,4 +1,5 @@
 use chrono::{DateTime, Utc};
+use ergo_database::redis::RedisConnection;
 use lazy_static::lazy_static;
 
 use super::{JobStatus, Queue};
@@ -9,12 +10,27 @@ use crate::error::Error;
 //  2. processing list
 //  3. pending list
 //  4. scheduled items list
+//  5. priority items list
 // ARGS:
 //  1. job ID
 //  2. current time
 //  3. cancel the job if it has already started running
-const CANCEL_SCRIPT: &str = r##"
+//  4. fairness list prefix
+pub(crate) const CANCEL_SCRIPT: &str = r##"
     local was_pending = redis.call("LREM", KEYS[3], 1, ARGV[1])
+    if was_pending == 0 then
+        was_pending = redis.call("LREM", KEYS[5], 1, ARGV[1])
+    end
+    if was_pending == 0 then
+        local fairness_key = redis.call("HGET", KEYS[1], "fk")
+        if fairness_key then
+            was_pending = redis.call("LREM", ARGV[4] .. fairness_key, 1, ARGV[1])
+        end
+    end
+    if was_pending == 0 then
+        -- In streams mode, the job's entry is passed over once it's no longer marked ready.
+        was_pending = redis.call("HDEL", KEYS[1], "rdy")
+    end
     local was_processing = redis.call("ZREM", KEYS[2], ARGV[1])
     local was_scheduled = redis.call("ZREM", KEYS[4], ARGV[1])
 
@@ -46,7 +62,7 @@ impl JobCancelScript {
     pub async fn run(
         &self,
         queue: &Queue,
-        conn: &mut deadpool_redis::Connection,
+        conn: &mut RedisConnection,
         job_id: &str,
         job_data_key: &str,
         now: &DateTime<Utc>,
@@ -58,9 +74,11 @@ impl JobCancelScript {
             .key(&queue.0.processing_list)
             .key(&queue.0.pending_list)
             .key(&queue.0.scheduled_list)
+            .key(&queue.0.priority_list)
             .arg(job_id)
             .arg(now.timestamp_millis())
             .arg(cancel_if_running)
+            .arg(&queue.0.fair_list_prefix)
             .invoke_async(&mut **conn)
             .await?;
 
@@ -1,4 +1,5 @@
 use chrono::{DateTime, Utc};
+use ergo_database::redis::RedisConnection;
 use lazy_static::lazy_static;
 
 use crate::error::Error;
@@ -15,7 +16,7 @@ use super::Queue;
 //  1. job id
 //  2. current time
 //  3. expected expiration
-const DONE_SCRIPT: &str = r##"
+pub(crate) const DONE_SCRIPT: &str = r##"
     local score = redis.call("ZSCORE", KEYS[2], ARGV[1])
     if score ~= ARGV[3] then
         -- We no longer own this item, so don't mess with it.
@@ -43,7 +44,7 @@ impl JobDoneScript {
     pub async fn run(
         &self,
         queue: &Queue,
-        conn: &mut deadpool_redis::Connection,
+        conn: &mut RedisConnection,
         job_id: &str,
         job_data_key: &str,
         now: &DateTime<Utc>,
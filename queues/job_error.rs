@@ -1,10 +1,37 @@
 use chrono::{DateTime, TimeZone, Utc};
+use ergo_database::redis::RedisConnection;
 use lazy_static::lazy_static;
 
 use crate::error::Error;
 
 use super::Queue;
 
+/// Whether a failed job is worth retrying.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The error may be temporary, so the job should be retried with backoff.
+    #[default]
+    Retryable,
+    /// The job will fail the same way every time, so it should fail immediately without
+    /// using up its remaining retries.
+    Permanent,
+}
+
+impl ErrorClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Retryable => "retryable",
+            Self::Permanent => "permanent",
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 // KEYS:
 //  1. job data key
 //  2. processing list
@@ -16,7 +43,8 @@ use super::Queue;
 //  2. current time
 //  3. expected score
 //  4. error description
-const ERROR_SCRIPT: &str = r##"
+//  5. error class, "retryable" or "permanent"
+pub(crate) const ERROR_SCRIPT: &str = r##"
     -- Make sure that the item is still in the queue and still at the expected score
     local score = redis.call("ZSCORE", KEYS[2], ARGV[1])
     if score ~= ARGV[3] then
@@ -29,9 +57,9 @@ const ERROR_SCRIPT: &str = r##"
     local retry = tonumber(retries[1])
     local max_retries = tonumber(retries[2])
     redis.call("HINCRBY", KEYS[5], "errored", 1)
-    if retry >= max_retries then
-        -- No more retries. Mark the job failed.
-        redis.call("HSET", KEYS[1], "err", ARGV[4], "end", ARGV[2], "suc", "false")
+    if retry >= max_retries or ARGV[5] == "permanent" then
+        -- No more retries, or retrying won't help. Mark the job failed.
+        redis.call("HSET", KEYS[1], "err", ARGV[4], "ec", ARGV[5], "end", ARGV[2], "suc", "false")
         redis.call("LPUSH", KEYS[4], ARGV[1])
         redis.call("HINCRBY", KEYS[5], "failed", 1)
         return {retry, -1}
@@ -39,8 +67,9 @@ const ERROR_SCRIPT: &str = r##"
         local next_run = ARGV[2] + (2 ^ retry) * tonumber(retries[3])
         retry = retry + 1
 
-        -- Set the error, increment retries, and schedule the next run.
-        redis.call("HSET", KEYS[1], "err", ARGV[4], "cr", retry)
+        -- Set the error, increment retries, and schedule the next run. The run time is recorded
+        -- so that the retry's wait is counted from when it becomes ready.
+        redis.call("HSET", KEYS[1], "err", ARGV[4], "ec", ARGV[5], "cr", retry, "ra", next_run)
         redis.call("ZADD", KEYS[3], next_run, ARGV[1])
         return {retry, next_run}
     end
@@ -60,12 +89,13 @@ impl JobErrorScript {
     pub async fn run(
         &self,
         queue: &Queue,
-        conn: &mut deadpool_redis::Connection,
+        conn: &mut RedisConnection,
         job_id: &str,
         job_data_key: &str,
         now: &DateTime<Utc>,
         expected_expiration: &DateTime<Utc>,
         error: &str,
+        error_class: ErrorClass,
     ) -> Result<(usize, DateTime<Utc>), Error> {
         let (retry, next_run): (usize, i64) = self
             .0
@@ -78,6 +108,7 @@ impl JobErrorScript {
             .arg(now.timestamp_millis())
             .arg(expected_expiration.timestamp_millis())
             .arg(error)
+            .arg(error_class.as_str())
             .invoke_async(&mut **conn)
             .await?;
 
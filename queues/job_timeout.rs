@@ -0,0 +1,73 @@
+use chrono::{DateTime, Utc};
+use ergo_database::redis::RedisConnection;
+use lazy_static::lazy_static;
+
+use crate::error::Error;
+
+use super::Queue;
+
+// Retry the jobs that have passed their processing deadline without finishing or sending a
+// heartbeat, which usually means that the worker running them went away.
+// KEYS:
+//  1. processing list
+//  2. scheduled items list
+//  3. done items list
+//  4. stats hash
+// ARGS:
+//  1. current time
+//  2. job data prefix
+pub(crate) const TIMEOUT_SCRIPT: &str = r##"
+    local expired = redis.call("ZRANGEBYSCORE", KEYS[1], 0, ARGV[1], "LIMIT", 0, 100)
+    for _, id in ipairs(expired) do
+        local job_key = ARGV[2] .. id
+        redis.call("ZREM", KEYS[1], id)
+
+        local retries = redis.call("HMGET", job_key, "cr", "mr", "bo")
+        local retry = tonumber(retries[1]) or 0
+        local max_retries = tonumber(retries[2]) or 0
+        redis.call("HINCRBY", KEYS[4], "errored", 1)
+        if retry >= max_retries then
+            redis.call("HSET", job_key, "err", "timed out", "ec", "retryable", "end", ARGV[1], "suc", "false")
+            redis.call("LPUSH", KEYS[3], id)
+            redis.call("HINCRBY", KEYS[4], "failed", 1)
+        else
+            local next_run = ARGV[1] + (2 ^ retry) * (tonumber(retries[3]) or 0)
+            redis.call("HSET", job_key, "err", "timed out", "ec", "retryable", "cr", retry + 1, "ra", next_run)
+            redis.call("ZADD", KEYS[2], next_run, id)
+        end
+    end
+
+    return #expired
+"##;
+
+lazy_static! {
+    static ref SCRIPT: redis::Script = redis::Script::new(TIMEOUT_SCRIPT);
+}
+
+pub struct JobTimeoutScript(&'static redis::Script);
+
+impl JobTimeoutScript {
+    pub fn new() -> Self {
+        JobTimeoutScript(&SCRIPT)
+    }
+
+    pub async fn run(
+        &self,
+        queue: &Queue,
+        conn: &mut RedisConnection,
+        now: &DateTime<Utc>,
+    ) -> Result<usize, Error> {
+        let timed_out: usize = self
+            .0
+            .key(&queue.0.processing_list)
+            .key(&queue.0.scheduled_list)
+            .key(&queue.0.done_list)
+            .key(&queue.0.stats_hash)
+            .arg(now.timestamp_millis())
+            .arg(&queue.0.job_data_prefix)
+            .invoke_async(&mut **conn)
+            .await?;
+
+        Ok(timed_out)
+    }
+}
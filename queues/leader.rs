@@ -0,0 +1,92 @@
+use std::time::Duration;
+
+use ergo_database::redis::RedisConnection;
+use lazy_static::lazy_static;
+
+use crate::error::Error;
+
+use super::Queue;
+
+// Take or renew the lease that lets a queue instance run the scheduled jobs checker.
+// KEYS:
+//  1. leader key
+// ARGS:
+//  1. instance id
+//  2. lease duration in milliseconds
+pub(crate) const ACQUIRE_SCRIPT: &str = r##"
+    local current = redis.call("GET", KEYS[1])
+    if current == ARGV[1] then
+        redis.call("PEXPIRE", KEYS[1], ARGV[2])
+        return 1
+    elseif not current then
+        redis.call("SET", KEYS[1], ARGV[1], "PX", ARGV[2])
+        return 1
+    end
+
+    return 0
+"##;
+
+// Give up the lease, if this instance still holds it.
+// KEYS:
+//  1. leader key
+// ARGS:
+//  1. instance id
+pub(crate) const RELEASE_SCRIPT: &str = r##"
+    if redis.call("GET", KEYS[1]) == ARGV[1] then
+        redis.call("DEL", KEYS[1])
+        return 1
+    end
+
+    return 0
+"##;
+
+lazy_static! {
+    static ref ACQUIRE: redis::Script = redis::Script::new(ACQUIRE_SCRIPT);
+    static ref RELEASE: redis::Script = redis::Script::new(RELEASE_SCRIPT);
+}
+
+pub struct LeaderScripts {
+    acquire: &'static redis::Script,
+    release: &'static redis::Script,
+}
+
+impl LeaderScripts {
+    pub fn new() -> Self {
+        LeaderScripts {
+            acquire: &ACQUIRE,
+            release: &RELEASE,
+        }
+    }
+
+    /// Take the lease if nobody holds it, or extend it if this instance already does.
+    /// Returns true if this instance holds the lease afterwards.
+    pub async fn acquire(
+        &self,
+        queue: &Queue,
+        conn: &mut RedisConnection,
+        lease: Duration,
+    ) -> Result<bool, Error> {
+        let leader: bool = self
+            .acquire
+            .key(&queue.0.leader_key)
+            .arg(&queue.0.instance_id)
+            .arg(lease.as_millis() as u64)
+            .invoke_async(&mut **conn)
+            .await?;
+
+        Ok(leader)
+    }
+
+    /// Release the lease so that another instance can take over without waiting for it to
+    /// expire.
+    pub async fn release(&self, queue: &Queue, conn: &mut RedisConnection) -> Result<bool, Error> {
+        let released: bool = self
+            .release
+            .key(&queue.0.leader_key)
+            .arg(&queue.0.instance_id)
+            .invoke_async(&mut **conn)
+            .await?;
+
+        Ok(released)
+    }
+}
@@ -1,6 +1,11 @@
+pub mod circuit_breaker;
 pub mod generic_stage;
 pub mod job;
+pub mod metrics;
 pub mod postgres_drain;
+pub mod rate_limit;
+pub mod recurring;
+pub mod table_stage;
 mod update_stage;
 pub mod work_item;
 
@@ -8,33 +13,49 @@ mod dequeuer_loop;
 mod enqueue_scheduled;
 mod error;
 mod get_job;
+mod heartbeat;
 mod job_cancel;
 mod job_done;
 mod job_error;
+mod job_timeout;
+mod leader;
+mod progress;
+mod reconnect;
 mod redis_job_data;
 mod start_work;
+mod streams;
 mod update_job;
+mod workers;
 
 use self::redis_job_data::{RedisJobField, RedisJobSetCmd};
 pub use self::{
     dequeuer_loop::QueueJobProcessor,
     error::*,
     job::*,
+    job_error::ErrorClass,
+    progress::JobProgress,
+    reconnect::check_connection,
+    streams::{QueueMode, StreamConsumer, StreamStatus},
     update_stage::{remove_pending_job, update_pending_job, JobUpdate},
     work_item::*,
+    workers::{WorkerInfo, WORKER_DEAD_AFTER, WORKER_HEARTBEAT_INTERVAL},
 };
 
 use std::{
     num::NonZeroU32,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     time::Duration,
 };
 
 use backoff::{backoff::Backoff, ExponentialBackoff};
 use chrono::{DateTime, TimeZone, Utc};
-use ergo_database::RedisPool;
+use ergo_database::{redis::RedisConnection, RedisPool};
 use ergo_graceful_shutdown::GracefulShutdownConsumer;
 use itertools::Itertools;
+use lazy_static::lazy_static;
 use redis::AsyncCommands;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tokio::{sync::oneshot, task::JoinHandle};
@@ -46,6 +67,7 @@ impl std::fmt::Debug for Queue {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Queue")
             .field("name", &self.0.name)
+            .field("mode", &self.0.mode)
             .field("max_retries", &self.0.max_retries)
             .field("processing_timeout", &self.0.processing_timeout)
             .field("retry_backoff", &self.0.retry_backoff)
@@ -56,12 +78,38 @@ impl std::fmt::Debug for Queue {
 struct QueueInner {
     pool: RedisPool,
     name: String,
+    mode: QueueMode,
     pending_list: String,
+    priority_list: String,
+    /// Jobs with a fairness key go in a list named with this prefix and the key.
+    fair_list_prefix: String,
+    /// A sorted set of the fairness keys with pending jobs, scored by when each key last had a
+    /// job dequeued. The empty key stands for the pending list.
+    fair_keys: String,
     scheduled_list: String,
+    /// In streams mode, the stream that holds the jobs that are ready to run.
+    stream: String,
+    /// In streams mode, the stream that holds the ready jobs in the priority lane.
+    priority_stream: String,
     processing_list: String,
     done_list: String,
     stats_hash: String,
+    /// Each minute's metrics are in a hash named with this prefix and the minute's timestamp.
+    metrics_prefix: String,
     job_data_prefix: String,
+    /// The IDs of the queue's recurring jobs.
+    recurring_set: String,
+    /// Each recurring job's definition is a hash named with this prefix and its ID.
+    recurring_prefix: String,
+    /// The recurring jobs that are running low on upcoming run times.
+    recurring_refill: String,
+    /// Holds the ID of the instance that is currently allowed to run the scheduled jobs checker.
+    leader_key: String,
+    /// The IDs of the workers running a dequeuer loop, scored by their last heartbeat.
+    workers_set: String,
+    /// Each worker's details are in a hash named with this prefix and its ID.
+    worker_prefix: String,
+    instance_id: String,
     processing_timeout: Duration,
     max_retries: u32,
     retry_backoff: Duration,
@@ -70,11 +118,28 @@ struct QueueInner {
     start_work_script: start_work::StartWorkScript,
     done_script: job_done::JobDoneScript,
     error_script: job_error::JobErrorScript,
+    heartbeat_script: heartbeat::HeartbeatScript,
+    progress_script: progress::ProgressScript,
+    timeout_script: job_timeout::JobTimeoutScript,
     cancel_script: job_cancel::JobCancelScript,
     update_script: update_job::UpdateJobScript,
+    leader_scripts: leader::LeaderScripts,
+    stream_scripts: streams::StreamScripts,
+    reap_workers_script: workers::ReapWorkersScript,
 
     scheduled_job_enqueuer_task: Mutex<Option<(oneshot::Sender<()>, JoinHandle<()>)>>,
     job_dequeuer_task: Mutex<Option<(oneshot::Sender<()>, JoinHandle<()>)>>,
+    /// When the dequeuer loop last checked for work, in milliseconds since the epoch.
+    dequeuer_last_poll: AtomicI64,
+    /// Set while the dequeuer loop is waiting for a running job to finish before it takes more.
+    dequeuer_at_capacity: AtomicBool,
+    /// The most jobs the dequeuer loop runs at once. This is read on every pass through the loop,
+    /// so it can change while the loop is running.
+    max_jobs: AtomicUsize,
+    /// The most jobs with the same fairness key that the dequeuer loop runs at once.
+    max_jobs_per_key: Mutex<Option<usize>>,
+    /// While set, the dequeuer loop doesn't take new jobs.
+    paused: AtomicBool,
 }
 
 pub enum JobStatus {
@@ -100,6 +165,10 @@ pub struct JobTrackingData {
     pub ended_at: Option<DateTime<Utc>>,
     pub succeeded: Option<bool>,
     pub error_details: Option<String>,
+    /// "retryable" or "permanent", if the job has failed.
+    pub error_class: Option<String>,
+    /// The last progress that the job reported while running.
+    pub progress: Option<JobProgress>,
 }
 
 #[derive(Debug, Serialize)]
@@ -107,6 +176,12 @@ pub struct QueueStatus {
     pub current_running: usize,
     pub current_scheduled: usize,
     pub current_pending: usize,
+    pub current_priority_pending: usize,
+
+    pub mode: QueueMode,
+    /// The consumer group's state, if the queue is in streams mode.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<StreamStatus>,
 
     pub total_retrieved: usize,
     pub total_enqueued: usize,
@@ -114,24 +189,172 @@ pub struct QueueStatus {
     pub total_succeeded: usize,
     pub total_failed: usize,
     pub total_errored: usize,
+    /// The number of times that the queue's loops recovered from losing the Redis connection.
+    pub total_reconnects: usize,
+
+    pub priority: PriorityLaneStats,
+}
+
+/// The state of a queue's dequeuer loop in this process.
+#[derive(Debug, Serialize)]
+pub struct DequeuerStatus {
+    /// False if the loop has exited.
+    pub running: bool,
+    /// When the loop last checked for work.
+    pub last_poll: Option<DateTime<Utc>>,
+    /// True if the loop is waiting for running jobs to finish before it takes more, in which
+    /// case it doesn't poll.
+    pub at_capacity: bool,
+}
+
+impl DequeuerStatus {
+    /// Check that the loop is running and that it has polled within `max_idle`, unless it's busy
+    /// with jobs.
+    pub fn is_live(&self, max_idle: Duration) -> bool {
+        let polled_recently = match self.last_poll {
+            // A poll time in the future can only come from the clock moving backwards.
+            Some(t) => (Utc::now() - t)
+                .to_std()
+                .map(|idle| idle <= max_idle)
+                .unwrap_or(true),
+            None => false,
+        };
+        self.running && (self.at_capacity || polled_recently)
+    }
+}
+
+/// Latency statistics for jobs in the priority lane, measured from when the job entered the
+/// queue until it started running.
+#[derive(Debug, Serialize)]
+pub struct PriorityLaneStats {
+    pub total_retrieved: usize,
+    pub total_wait_ms: usize,
+    /// The number of jobs that waited longer than the latency SLO.
+    pub total_slo_missed: usize,
+    pub slo_ms: u64,
+}
+
+impl PriorityLaneStats {
+    pub fn mean_wait_ms(&self) -> Option<f64> {
+        if self.total_retrieved == 0 {
+            None
+        } else {
+            Some(self.total_wait_ms as f64 / self.total_retrieved as f64)
+        }
+    }
+
+    /// The fraction of priority jobs that started within the SLO.
+    pub fn slo_attainment(&self) -> Option<f64> {
+        if self.total_retrieved == 0 {
+            None
+        } else {
+            Some(1.0 - self.total_slo_missed as f64 / self.total_retrieved as f64)
+        }
+    }
+}
+
+lazy_static! {
+    /// Jobs in the priority lane should start running within this long after being enqueued.
+    /// Set with `QUEUE_PRIORITY_SLO_MS`.
+    static ref PRIORITY_SLO: Duration = std::env::var("QUEUE_PRIORITY_SLO_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_millis)
+        .unwrap_or_else(|| Duration::from_millis(1000));
+
+    /// While other fairness keys have jobs waiting, each background key gets a turn at most this
+    /// often. Set with `QUEUE_BACKGROUND_INTERVAL_MS`.
+    static ref BACKGROUND_INTERVAL: Duration = std::env::var("QUEUE_BACKGROUND_INTERVAL_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_millis)
+        .unwrap_or_else(|| Duration::from_millis(500));
+
+    /// In lists mode, the pending lists hand out their newest job first, so under sustained load
+    /// the older jobs, and retries that were moved back into a list, could wait forever. Once the
+    /// oldest job in a list has been ready to run for this long, it is taken instead of the newest
+    /// one. Priority jobs count as if they had already waited this long, so a normal job goes
+    /// ahead of the priority lane once it has waited that much longer than the oldest priority
+    /// job. In streams mode, ready jobs already run in the order they were enqueued.
+    ///
+    /// Set with `QUEUE_AGING_MS`, or set it to 0 to turn aging off.
+    static ref AGING_INTERVAL: Duration = std::env::var("QUEUE_AGING_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_millis)
+        .unwrap_or_else(|| Duration::from_millis(60000));
 }
 
+/// Fairness keys that start with this prefix are in the background lane. When a background key
+/// takes its turn, it goes to the back of the line behind every other key that has been served
+/// within [BACKGROUND_INTERVAL], so a large batch of background jobs only delays other jobs by a
+/// few turns. When nothing else is waiting, background jobs run as fast as the workers can take
+/// them.
+///
+/// In streams mode, ready jobs run in the order they were enqueued, so background keys are
+/// treated like any other key.
+pub const BACKGROUND_KEY_PREFIX: &str = "bg:";
+
+/// Return the background lane version of a fairness key. The background key takes turns
+/// separately from the original key, and has its own count for
+/// [QueueJobProcessor::max_jobs_per_key].
+pub fn background_fairness_key(key: &str) -> String {
+    format!("{}{}", BACKGROUND_KEY_PREFIX, key)
+}
+
+/// How long the scheduled jobs checker's leader keeps the role without renewing it. Another
+/// instance takes over within this long after the leader goes away.
+const SCHEDULER_LEASE: Duration = Duration::from_secs(5);
+
 impl Queue {
+    /// Create a queue in the mode set by the `QUEUE_MODE` environment variable.
     pub fn new(
         pool: RedisPool,
         queue_name: String,
         default_timeout: Option<Duration>,
         default_max_retries: Option<u32>,
         default_retry_backoff: Option<Duration>,
+    ) -> Queue {
+        Queue::with_mode(
+            pool,
+            queue_name,
+            default_timeout,
+            default_max_retries,
+            default_retry_backoff,
+            QueueMode::from_env(),
+        )
+    }
+
+    pub fn with_mode(
+        pool: RedisPool,
+        queue_name: String,
+        default_timeout: Option<Duration>,
+        default_max_retries: Option<u32>,
+        default_retry_backoff: Option<Duration>,
+        mode: QueueMode,
     ) -> Queue {
         Queue(Arc::new(QueueInner {
             pool,
+            mode,
             pending_list: format!("erq:{}:pending", queue_name),
+            priority_list: format!("erq:{}:priority", queue_name),
+            fair_list_prefix: format!("erq:{}:fair:", queue_name),
+            fair_keys: format!("erq:{}:fair_keys", queue_name),
             scheduled_list: format!("erq:{}:scheduled", queue_name),
+            stream: format!("erq:{}:stream", queue_name),
+            priority_stream: format!("erq:{}:stream:priority", queue_name),
             processing_list: format!("erq:{}:processing", queue_name),
             done_list: format!("erq:{}:done", queue_name),
             stats_hash: format!("erq:{}:stats", queue_name),
+            metrics_prefix: format!("erq:{}:metrics:", queue_name),
             job_data_prefix: format!("erq:{}:job:", queue_name),
+            recurring_set: format!("erq:{}:recurring", queue_name),
+            recurring_prefix: format!("erq:{}:recurring:", queue_name),
+            recurring_refill: format!("erq:{}:recurring_refill", queue_name),
+            leader_key: format!("erq:{}:scheduler_leader", queue_name),
+            workers_set: format!("erq:{}:workers", queue_name),
+            worker_prefix: format!("erq:{}:worker:", queue_name),
+            instance_id: uuid::Uuid::new_v4().to_string(),
             processing_timeout: default_timeout.unwrap_or_else(|| Duration::from_secs_f64(120.0)),
             max_retries: default_max_retries.unwrap_or(3),
             retry_backoff: default_retry_backoff.unwrap_or_else(|| Duration::from_millis(30000)),
@@ -140,10 +363,21 @@ impl Queue {
             start_work_script: start_work::StartWorkScript::new(),
             done_script: job_done::JobDoneScript::new(),
             error_script: job_error::JobErrorScript::new(),
+            heartbeat_script: heartbeat::HeartbeatScript::new(),
+            progress_script: progress::ProgressScript::new(),
+            timeout_script: job_timeout::JobTimeoutScript::new(),
             cancel_script: job_cancel::JobCancelScript::new(),
             update_script: update_job::UpdateJobScript::new(),
+            leader_scripts: leader::LeaderScripts::new(),
+            stream_scripts: streams::StreamScripts::new(),
+            reap_workers_script: workers::ReapWorkersScript::new(),
             scheduled_job_enqueuer_task: Mutex::new(None),
             job_dequeuer_task: Mutex::new(None),
+            dequeuer_last_poll: AtomicI64::new(0),
+            dequeuer_at_capacity: AtomicBool::new(false),
+            max_jobs: AtomicUsize::new(0),
+            max_jobs_per_key: Mutex::new(None),
+            paused: AtomicBool::new(false),
             name: queue_name,
         }))
     }
@@ -152,6 +386,10 @@ impl Queue {
         self.0.name.as_str()
     }
 
+    pub fn mode(&self) -> QueueMode {
+        self.0.mode
+    }
+
     fn add_id_to_queue(&self, pipe: &mut redis::Pipeline, job: &'_ Job<'_>) {
         if let Some(timestamp) = job.run_at {
             pipe.zadd(
@@ -159,11 +397,52 @@ impl Queue {
                 &job.id,
                 timestamp.timestamp_millis(),
             );
+        } else if self.0.mode == QueueMode::Streams {
+            let stream = if job.high_priority {
+                &self.0.priority_stream
+            } else {
+                &self.0.stream
+            };
+
+            pipe.cmd("XADD")
+                .arg(stream)
+                .arg("*")
+                .arg("id")
+                .arg(&job.id)
+                .ignore();
+            // Marks the job as waiting in the stream, so that a canceled or rescheduled job's
+            // entry can be passed over.
+            pipe.hset(self.job_data_key(&job.id), "rdy", 1).ignore();
+        } else if job.high_priority {
+            pipe.lpush(&self.0.priority_list, &job.id);
         } else {
-            pipe.lpush(&self.0.pending_list, &job.id);
+            let key = job.fairness_key.as_deref().unwrap_or("");
+            if key.is_empty() {
+                pipe.lpush(&self.0.pending_list, &job.id);
+            } else {
+                pipe.lpush(self.fair_list_key(key), &job.id);
+            }
+
+            // A new key gets a score of 0 so that it gets its turn right away, except for a
+            // background key which waits behind the keys that are already there.
+            let score = if key.starts_with(BACKGROUND_KEY_PREFIX) {
+                Utc::now().timestamp_millis() + BACKGROUND_INTERVAL.as_millis() as i64
+            } else {
+                0
+            };
+            pipe.cmd("ZADD")
+                .arg(&self.0.fair_keys)
+                .arg("NX")
+                .arg(score)
+                .arg(key)
+                .ignore();
         }
     }
 
+    fn fair_list_key(&self, fairness_key: &str) -> String {
+        format!("{}{}", self.0.fair_list_prefix, fairness_key)
+    }
+
     fn job_data_key(&self, job_id: &str) -> String {
         format!("{}{}", self.0.job_data_prefix, job_id)
     }
@@ -182,6 +461,10 @@ impl Queue {
             cmd = cmd.run_at(r);
         }
 
+        if let Some(key) = job.fairness_key.as_deref() {
+            cmd = cmd.fairness_key(key);
+        }
+
         cmd.build()
     }
 
@@ -191,6 +474,7 @@ impl Queue {
             current_scheduled,
             current_running,
             current_pending,
+            current_priority_pending,
             (
                 total_retrieved,
                 total_enqueued,
@@ -198,11 +482,14 @@ impl Queue {
                 total_succeeded,
                 total_failed,
                 total_errored,
+                total_reconnects,
             ),
+            (priority_retrieved, priority_wait_ms, priority_slo_missed),
         ): (
             usize,
             usize,
             usize,
+            usize,
             (
                 Option<usize>,
                 Option<usize>,
@@ -210,14 +497,18 @@ impl Queue {
                 Option<usize>,
                 Option<usize>,
                 Option<usize>,
+                Option<usize>,
             ),
-        ) = redis::Pipeline::with_capacity(4)
+            (Option<usize>, Option<usize>, Option<usize>),
+        ) = redis::Pipeline::with_capacity(6)
             .cmd("ZCARD")
             .arg(&self.0.scheduled_list)
             .cmd("ZCARD")
             .arg(&self.0.processing_list)
             .cmd("LLEN")
             .arg(&self.0.pending_list)
+            .cmd("LLEN")
+            .arg(&self.0.priority_list)
             .cmd("HMGET")
             .arg(&[
                 &self.0.stats_hash,
@@ -227,20 +518,61 @@ impl Queue {
                 "succeeded",
                 "failed",
                 "errored",
+                "reconnects",
+            ])
+            .cmd("HMGET")
+            .arg(&[
+                &self.0.stats_hash,
+                "priority_retrieved",
+                "priority_wait_ms",
+                "priority_slo_missed",
             ])
             .query_async(&mut conn)
             .await?;
 
+        let (current_pending, current_priority_pending, stream) = match self.0.mode {
+            QueueMode::Lists => {
+                let mut current_pending = current_pending;
+                for list in self.fair_lists(&mut conn).await? {
+                    let len: usize = conn.llen(&list).await?;
+                    current_pending += len;
+                }
+
+                (current_pending, current_priority_pending, None)
+            }
+            QueueMode::Streams => {
+                let (ready, delivered) = streams::stream_counts(&mut conn, &self.0.stream).await?;
+                let (priority_ready, priority_delivered) =
+                    streams::stream_counts(&mut conn, &self.0.priority_stream).await?;
+                let status = StreamStatus {
+                    delivered: delivered + priority_delivered,
+                    consumers: self.stream_consumers(&mut conn).await?,
+                };
+
+                (ready, priority_ready, Some(status))
+            }
+        };
+
         Ok(QueueStatus {
             current_running,
             current_scheduled,
             current_pending,
+            current_priority_pending,
+            mode: self.0.mode,
+            stream,
             total_retrieved: total_retrieved.unwrap_or(0),
             total_enqueued: total_enqueued.unwrap_or(0),
             total_scheduled: total_scheduled.unwrap_or(0),
             total_succeeded: total_succeeded.unwrap_or(0),
             total_failed: total_failed.unwrap_or(0),
             total_errored: total_errored.unwrap_or(0),
+            total_reconnects: total_reconnects.unwrap_or(0),
+            priority: PriorityLaneStats {
+                total_retrieved: priority_retrieved.unwrap_or(0),
+                total_wait_ms: priority_wait_ms.unwrap_or(0),
+                total_slo_missed: priority_slo_missed.unwrap_or(0),
+                slo_ms: PRIORITY_SLO.as_millis() as u64,
+            },
         })
     }
 
@@ -274,14 +606,117 @@ impl Queue {
             .collect::<Result<Vec<_>, Error>>()
     }
 
+    /// The workers reading from the queue's streams, combined across the normal and priority
+    /// streams.
+    async fn stream_consumers(
+        &self,
+        conn: &mut RedisConnection,
+    ) -> Result<Vec<StreamConsumer>, Error> {
+        let mut consumers = streams::stream_consumers(conn, &self.0.priority_stream).await?;
+        for consumer in streams::stream_consumers(conn, &self.0.stream).await? {
+            match consumers.iter_mut().find(|c| c.name == consumer.name) {
+                Some(existing) => {
+                    existing.pending += consumer.pending;
+                    existing.idle_ms = existing.idle_ms.min(consumer.idle_ms);
+                }
+                None => consumers.push(consumer),
+            }
+        }
+
+        Ok(consumers)
+    }
+
+    /// The IDs of the jobs waiting in a stream. Entries that were already handed to a worker, or
+    /// whose jobs were canceled or rescheduled, are left out.
+    async fn list_stream_ready(
+        &self,
+        conn: &mut RedisConnection,
+        stream: &str,
+    ) -> Result<Vec<String>, Error> {
+        let entries: Vec<(String, Vec<String>)> = redis::cmd("XRANGE")
+            .arg(stream)
+            .arg("-")
+            .arg("+")
+            .query_async(&mut *conn)
+            .await?;
+        let ids = entries
+            .into_iter()
+            .filter_map(|(_, fields)| fields.into_iter().nth(1))
+            .collect::<Vec<_>>();
+
+        let mut pipe = redis::pipe();
+        for id in &ids {
+            pipe.hexists(self.job_data_key(id), "rdy");
+        }
+        let ready: Vec<bool> = pipe.query_async(&mut *conn).await?;
+
+        Ok(ids
+            .into_iter()
+            .zip(ready)
+            .filter_map(|(id, ready)| ready.then_some(id))
+            .collect())
+    }
+
+    /// List the pending jobs, with the jobs in the priority lane first.
     pub async fn list_pending(&self) -> Result<Vec<String>, Error> {
-        self.0
-            .pool
-            .get()
-            .await?
+        let mut conn = self.0.pool.get().await?;
+        if self.0.mode == QueueMode::Streams {
+            let mut pending = self
+                .list_stream_ready(&mut conn, &self.0.priority_stream)
+                .await?;
+            pending.extend(self.list_stream_ready(&mut conn, &self.0.stream).await?);
+            return Ok(pending);
+        }
+
+        let (mut priority, pending): (Vec<String>, Vec<String>) = redis::pipe()
+            .lrange(&self.0.priority_list, 0, -1)
             .lrange(&self.0.pending_list, 0, -1)
-            .await
-            .map_err(Error::from)
+            .query_async(&mut conn)
+            .await?;
+
+        priority.extend(pending);
+        for list in self.fair_lists(&mut conn).await? {
+            let jobs: Vec<String> = conn.lrange(&list, 0, -1).await?;
+            priority.extend(jobs);
+        }
+
+        Ok(priority)
+    }
+
+    /// The number of jobs that are ready to run and waiting for a worker. This does not
+    /// include scheduled jobs.
+    pub async fn backlog(&self) -> Result<usize, Error> {
+        let mut conn = self.0.pool.get().await?;
+        if self.0.mode == QueueMode::Streams {
+            let (ready, _) = streams::stream_counts(&mut conn, &self.0.stream).await?;
+            let (priority_ready, _) =
+                streams::stream_counts(&mut conn, &self.0.priority_stream).await?;
+            return Ok(ready + priority_ready);
+        }
+
+        let (pending, priority): (usize, usize) = redis::pipe()
+            .llen(&self.0.pending_list)
+            .llen(&self.0.priority_list)
+            .query_async(&mut conn)
+            .await?;
+
+        let mut total = pending + priority;
+        for list in self.fair_lists(&mut conn).await? {
+            let len: usize = conn.llen(&list).await?;
+            total += len;
+        }
+
+        Ok(total)
+    }
+
+    /// The lists holding pending jobs with a fairness key.
+    async fn fair_lists(&self, conn: &mut RedisConnection) -> Result<Vec<String>, Error> {
+        let keys: Vec<String> = conn.zrange(&self.0.fair_keys, 0, -1).await?;
+        Ok(keys
+            .into_iter()
+            .filter(|key| !key.is_empty())
+            .map(|key| self.fair_list_key(&key))
+            .collect())
     }
 
     pub async fn enqueue(&self, item: &'_ Job<'_>) -> Result<(), Error> {
@@ -291,6 +726,7 @@ impl Queue {
         self.add_id_to_queue(&mut pipe, item);
         pipe.cmd("HINCRBY")
             .arg(&[&self.0.stats_hash, "enqueued", "1"]);
+        self.record_enqueue_metrics(&mut pipe, 1, &Utc::now());
 
         let mut conn = self.0.pool.get().await?;
         pipe.query_async(&mut conn).await?;
@@ -304,6 +740,7 @@ impl Queue {
             pipe.add_command(self.initial_job_data_cmd(item));
             self.add_id_to_queue(&mut pipe, item);
         }
+        self.record_enqueue_metrics(&mut pipe, items.len(), &Utc::now());
 
         let mut conn = self.0.pool.get().await?;
         pipe.query_async(&mut conn).await?;
@@ -311,19 +748,57 @@ impl Queue {
         Ok(())
     }
 
-    /// Move each scheduled item that has reached its deadline to the pending list.
+    /// Move each scheduled item that has reached its deadline to the pending list. This also
+    /// schedules the next occurrence of each recurring job that was moved.
     pub async fn enqueue_scheduled_items(&self) -> Result<usize, Error> {
         let mut conn = self.0.pool.get().await?;
+        let now = Utc::now();
         let num_queued = self
             .0
             .enqueue_scheduled_script
-            .run(self, &mut conn, &Utc::now())
+            .run(self, &mut conn, &now)
             .await?;
+        self.refill_recurring(&mut conn, &now).await?;
         Ok(num_queued)
     }
 
+    /// Schedule a retry for each running job that has passed its processing deadline.
+    ///
+    /// In streams mode this does nothing, since workers claim the entries of timed out jobs
+    /// when they look for new jobs.
+    pub async fn retry_timed_out_jobs(&self) -> Result<usize, Error> {
+        if self.0.mode == QueueMode::Streams {
+            return Ok(0);
+        }
+
+        let mut conn = self.0.pool.get().await?;
+        self.0
+            .timeout_script
+            .run(self, &mut conn, &Utc::now())
+            .await
+    }
+
+    /// Try to become the instance that runs the scheduled jobs checker, or stay that instance
+    /// if it already is. Returns true if this instance is the leader.
+    pub async fn acquire_scheduler_lease(&self) -> Result<bool, Error> {
+        let mut conn = self.0.pool.get().await?;
+        self.0
+            .leader_scripts
+            .acquire(self, &mut conn, SCHEDULER_LEASE)
+            .await
+    }
+
+    /// Give up the scheduled jobs checker role, if this instance has it.
+    pub async fn release_scheduler_lease(&self) -> Result<bool, Error> {
+        let mut conn = self.0.pool.get().await?;
+        self.0.leader_scripts.release(self, &mut conn).await
+    }
+
     /// Start the scheduled jobs enqueuer task. This task will automatically be stopped when the
     /// last reference to the queue is dropped.
+    ///
+    /// Every instance of the queue can start the task, but only the one that holds the
+    /// scheduler lease does any work, and another takes over when it stops renewing the lease.
     pub fn start_scheduled_jobs_enqueuer(&self, mut close: GracefulShutdownConsumer) {
         if self.0.scheduled_job_enqueuer_task.lock().unwrap().is_some() {
             return;
@@ -339,6 +814,7 @@ impl Queue {
             tokio::pin!(closer_rx);
 
             let mut interval = tokio::time::interval(Duration::from_millis(1000));
+            let mut leader = false;
 
             loop {
                 tokio::select! {
@@ -349,17 +825,82 @@ impl Queue {
                     _ = interval.tick() => {},
                 };
 
+                let was_leader = leader;
+                leader = match queue.acquire_scheduler_lease().await {
+                    Ok(leader) => leader,
+                    Err(e) if e.is_connection_error() => {
+                        leader = false;
+                        tokio::select! {
+                            biased;
+
+                            _ = &mut shutdown_fut => break,
+                            _ = &mut closer_rx => break,
+                            _ = queue.wait_for_reconnect(&e) => {},
+                        };
+                        continue;
+                    }
+                    Err(e) => {
+                        event!(Level::ERROR, queue=%queue.0.name, error=%e, "Error acquiring scheduler lease");
+                        false
+                    }
+                };
+
+                if leader != was_leader {
+                    if leader {
+                        event!(Level::INFO, queue=%queue.0.name, instance=%queue.0.instance_id, "Took over scheduled jobs checker");
+                    } else {
+                        event!(Level::INFO, queue=%queue.0.name, instance=%queue.0.instance_id, "Lost scheduled jobs checker lease");
+                    }
+                }
+
+                if !leader {
+                    continue;
+                }
+
+                // Retry the jobs of dead workers first, so that they are moved back to the
+                // pending list along with the scheduled jobs below.
+                if let Err(e) = queue.reap_dead_workers().await {
+                    event!(Level::ERROR, queue=%queue.0.name, error=%e, "Error checking for dead workers");
+                }
+
+                match queue.retry_timed_out_jobs().await {
+                    Ok(num) => {
+                        if num > 0 {
+                            event!(Level::WARN, queue=%queue.0.name, count=%num, "Retrying timed out jobs");
+                        }
+                    }
+                    Err(e) => {
+                        event!(Level::ERROR, queue=%queue.0.name, error=%e, "Error checking for timed out jobs");
+                    }
+                };
+
                 match queue.enqueue_scheduled_items().await {
                     Ok(num) => {
                         if num > 0 {
                             event!(Level::INFO, queue=%queue.0.name, count=%num, "Enqueued scheduled jobs");
                         }
                     }
+                    Err(e) if e.is_connection_error() => {
+                        tokio::select! {
+                            biased;
+
+                            _ = &mut shutdown_fut => break,
+                            _ = &mut closer_rx => break,
+                            _ = queue.wait_for_reconnect(&e) => {},
+                        };
+                    }
                     Err(e) => {
                         event!(Level::ERROR, queue=%queue.0.name, error=%e, "Error enqueueing scheduled jobs");
                     }
                 };
             }
+
+            // Let another instance take over right away instead of waiting for the lease to expire.
+            if leader {
+                if let Err(e) = queue.release_scheduler_lease().await {
+                    event!(Level::WARN, queue=%queue.0.name, error=%e, "Error releasing scheduler lease");
+                }
+            }
         });
 
         // We don't have to do anything with `closer_tx` except keep it alive, then when
@@ -409,18 +950,83 @@ impl Queue {
         let max_jobs = max_jobs
             .map(|n| n.get() as usize)
             .unwrap_or_else(|| num_cpus::get() * 2);
+        self.0.max_jobs.store(max_jobs, Ordering::Relaxed);
+        *self.0.max_jobs_per_key.lock().unwrap() = processor.max_jobs_per_key();
+
         let queue = self.clone();
         let (closer_tx, closer_rx) = oneshot::channel::<()>();
 
-        let task =
-            dequeuer_loop::dequeuer_loop(queue, shutdown, closer_rx, backoff, max_jobs, processor);
+        let task = dequeuer_loop::dequeuer_loop(queue, shutdown, closer_rx, backoff, processor);
 
         *self.0.job_dequeuer_task.lock().unwrap() = Some((closer_tx, task));
     }
 
+    /// Change the number of jobs that the dequeuer loop runs at once. When the limit goes down,
+    /// the jobs that are already running are left alone and no new jobs start until enough of
+    /// them have finished.
+    pub fn set_max_jobs(&self, max_jobs: NonZeroU32) {
+        let max_jobs = max_jobs.get() as usize;
+        let old = self.0.max_jobs.swap(max_jobs, Ordering::Relaxed);
+        if old != max_jobs {
+            event!(Level::INFO, queue=%self.0.name, old, new=max_jobs, "Changed max concurrent jobs");
+        }
+    }
+
+    /// Change the number of jobs with the same fairness key that the dequeuer loop runs at once,
+    /// replacing the limit from [QueueJobProcessor::max_jobs_per_key].
+    pub fn set_max_jobs_per_key(&self, max_jobs_per_key: Option<usize>) {
+        let mut current = self.0.max_jobs_per_key.lock().unwrap();
+        if *current != max_jobs_per_key {
+            event!(Level::INFO, queue=%self.0.name, old=?*current, new=?max_jobs_per_key, "Changed max jobs per key");
+            *current = max_jobs_per_key;
+        }
+    }
+
+    /// Stop or resume taking new jobs in the dequeuer loop. Jobs that are already running keep
+    /// going while the loop is paused, and waiting jobs stay in the queue.
+    pub fn set_paused(&self, paused: bool) {
+        let old = self.0.paused.swap(paused, Ordering::Relaxed);
+        if old != paused {
+            event!(Level::INFO, queue=%self.0.name, paused, "Changed dequeuer pause");
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.0.paused.load(Ordering::Relaxed)
+    }
+
+    /// The state of the dequeuer loop, or None if it was never started.
+    pub fn dequeuer_status(&self) -> Option<DequeuerStatus> {
+        let running = match self.0.job_dequeuer_task.lock().unwrap().as_ref() {
+            Some((_, task)) => !task.is_finished(),
+            None => return None,
+        };
+
+        let last_poll = match self.0.dequeuer_last_poll.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Utc.timestamp_millis_opt(millis).single(),
+        };
+
+        Some(DequeuerStatus {
+            running,
+            last_poll,
+            at_capacity: self.0.dequeuer_at_capacity.load(Ordering::Relaxed),
+        })
+    }
+
+    pub(crate) fn record_dequeuer_poll(&self, at_capacity: bool) {
+        self.0
+            .dequeuer_last_poll
+            .store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+        self.0
+            .dequeuer_at_capacity
+            .store(at_capacity, Ordering::Relaxed);
+    }
+
     /// Stop the job dequeuer task, if it was started. This can be used to shut down the
     /// task early, but is not necessary to call as the task will be automatically stopped when the
-    /// last reference to the queue is dropped.
+    /// last reference to the queue is dropped. The returned handle finishes once the jobs that
+    /// were already running have finished.
     pub fn stop_dequeuer_loop(&self) -> Option<JoinHandle<()>> {
         let (_, task_handle) = self.0.job_dequeuer_task.lock().unwrap().take()?;
 
@@ -430,21 +1036,36 @@ impl Queue {
 
     async fn start_working<T: DeserializeOwned + Send + Sync>(
         &self,
-        conn: &mut deadpool_redis::Connection,
+        conn: &mut RedisConnection,
         job_id: &str,
         job_id_key: &str,
         now: &DateTime<Utc>,
+        high_priority: bool,
     ) -> Result<QueueWorkItem<T>, Error> {
-        let (payload, expiration, current_retry, max_retries) = self
+        let start_work::StartWorkResult {
+            payload,
+            expiration,
+            current_retry,
+            max_retries,
+            enqueued_at,
+            run_at,
+        } = self
             .0
             .start_work_script
             .run(self, conn, job_id, job_id_key, now)
             .await?;
 
+        self.record_wait(conn, enqueued_at, run_at, now, high_priority)
+            .await?;
+
+        let timeout = (expiration - *now)
+            .to_std()
+            .unwrap_or(self.0.processing_timeout);
         let item = QueueWorkItem::new(
             self.clone(),
             job_id,
             expiration,
+            timeout,
             current_retry,
             max_retries,
             payload,
@@ -453,14 +1074,62 @@ impl Queue {
         match item {
             Ok(item) => Ok(item),
             Err(e) => {
+                // The payload won't deserialize any better on a retry.
                 let err_str = format!("Failed to start job: {}", e);
-                self.errored_job(job_id, &expiration, err_str.as_str())
+                self.errored_job(job_id, &expiration, err_str.as_str(), ErrorClass::Permanent)
                     .await?;
                 Err(e)
             }
         }
     }
 
+    /// Record how long a job waited to start, in the queue metrics and, for a priority job, in
+    /// the priority lane stats.
+    async fn record_wait(
+        &self,
+        conn: &mut RedisConnection,
+        enqueued_at: Option<DateTime<Utc>>,
+        run_at: Option<DateTime<Utc>>,
+        now: &DateTime<Utc>,
+        high_priority: bool,
+    ) -> Result<(), Error> {
+        let wait_ms = enqueued_at
+            .map(|e| (*now - e).num_milliseconds().max(0))
+            .unwrap_or(0);
+        // A scheduled or retried job only starts waiting once its run time arrives.
+        let ready_wait_ms = match run_at {
+            Some(run_at) => wait_ms.min((*now - run_at).num_milliseconds().max(0)),
+            None => wait_ms,
+        };
+
+        let mut pipe = redis::pipe();
+        self.record_dequeue_metrics(&mut pipe, ready_wait_ms as u64, now);
+
+        if !high_priority {
+            pipe.query_async::<_, ()>(&mut *conn).await?;
+            return Ok(());
+        }
+
+        let missed_slo = wait_ms as u128 > PRIORITY_SLO.as_millis();
+        pipe.hincr(&self.0.stats_hash, "priority_retrieved", 1)
+            .hincr(&self.0.stats_hash, "priority_wait_ms", wait_ms)
+            .hincr(&self.0.stats_hash, "priority_slo_missed", missed_slo as i64)
+            .query_async::<_, ()>(&mut *conn)
+            .await?;
+
+        if missed_slo {
+            event!(Level::WARN, queue=%self.0.name, %wait_ms, "Priority job missed latency SLO");
+        }
+
+        Ok(())
+    }
+
+    /// Wait for the Redis connection to come back after a connection error.
+    pub(crate) async fn wait_for_reconnect(&self, error: &Error) {
+        reconnect::wait_for_reconnect(&self.0.pool, &self.0.name, Some(&self.0.stats_hash), error)
+            .await
+    }
+
     pub async fn job_info(&self, job_id: &str) -> Result<Option<JobTrackingData>, Error> {
         let job_data_key = self.job_data_key(job_id);
         let mut conn = self.0.pool.get().await?;
@@ -476,6 +1145,7 @@ impl Queue {
             ended_at,
             succeeded,
             error,
+            error_class,
         ): (
             Option<Vec<u8>>,
             Option<u64>,
@@ -488,6 +1158,7 @@ impl Queue {
             Option<i64>,
             Option<String>,
             Option<String>,
+            Option<String>,
         ) = redis::cmd("HMGET")
             .arg(&job_data_key)
             .arg(RedisJobField::Payload)
@@ -501,8 +1172,11 @@ impl Queue {
             .arg(RedisJobField::EndedAt)
             .arg(RedisJobField::Succeeded)
             .arg(RedisJobField::ErrorDetails)
+            .arg(RedisJobField::ErrorClass)
             .query_async(&mut conn)
             .await?;
+        // Redis tuples only go up to 12 elements, so this is fetched on its own.
+        let progress: Option<String> = conn.hget(&job_data_key, RedisJobField::Progress).await?;
 
         match (payload, timeout, current_retries, max_retries, enqueued_at) {
             (
@@ -523,6 +1197,8 @@ impl Queue {
                 ended_at: ended_at.map(|d| Utc.timestamp_millis(d)),
                 succeeded: succeeded.map(|val| val.parse::<bool>()).transpose()?,
                 error_details: error,
+                error_class,
+                progress: progress.map(|p| serde_json::from_str(&p)).transpose()?,
             })),
             _ => Ok(None),
         }
@@ -530,27 +1206,48 @@ impl Queue {
 
     pub async fn get_job<T: DeserializeOwned + Send + Sync>(
         &self,
+    ) -> Result<Option<QueueWorkItem<T>>, Error> {
+        self.get_fair_job(&[]).await
+    }
+
+    /// Get the next job, taking turns between fairness keys and passing over any jobs
+    /// with a key in `skip_fairness_keys`. Priority jobs are returned first, unless a normal job
+    /// has aged past them.
+    pub async fn get_fair_job<T: DeserializeOwned + Send + Sync>(
+        &self,
+        skip_fairness_keys: &[&str],
     ) -> Result<Option<QueueWorkItem<T>>, Error> {
         // 1. Run dequeue script
         let now = Utc::now();
         let mut conn = self.0.pool.get().await?;
-        let result: Option<String> = self
-            .0
-            .dequeue_item_script
-            .run(self, &mut conn, &now)
-            .await?;
+        let result = match self.0.mode {
+            QueueMode::Lists => {
+                self.0
+                    .dequeue_item_script
+                    .run(self, &mut conn, &now, skip_fairness_keys)
+                    .await?
+            }
+            QueueMode::Streams => {
+                self.0
+                    .stream_scripts
+                    .dequeue(self, &mut conn, &now, skip_fairness_keys)
+                    .await?
+            }
+        };
 
         // Unwrap the Option or just exit if there was no job.
-        let job_id = match result {
-            Some(id) => id,
+        let job = match result {
+            Some(job) => job,
             None => {
                 return Ok(None);
             }
         };
-        let job_id_key = self.job_data_key(&job_id);
-        self.start_working(&mut conn, &job_id, &job_id_key, &now)
-            .await
-            .map(Some)
+        let job_id_key = self.job_data_key(&job.id);
+        let mut item = self
+            .start_working(&mut conn, &job.id, &job_id_key, &now, job.high_priority)
+            .await?;
+        item.fairness_key = job.fairness_key;
+        Ok(Some(item))
     }
 
     /// Cancel a job if it hasn't started yet.
@@ -558,10 +1255,15 @@ impl Queue {
         let key = self.job_data_key(id);
         let mut conn = self.0.pool.get().await?;
 
-        self.0
+        let status = self
+            .0
             .cancel_script
             .run(self, &mut conn, id, &key, &Utc::now(), false)
-            .await
+            .await?;
+        if self.0.mode == QueueMode::Streams {
+            self.0.stream_scripts.ack(&mut conn, &key).await?;
+        }
+        Ok(status)
     }
 
     /// Cancel a job, and try to mark it cancelled even if it's running.
@@ -569,10 +1271,15 @@ impl Queue {
         let key = self.job_data_key(id);
         let mut conn = self.0.pool.get().await?;
 
-        self.0
+        let status = self
+            .0
             .cancel_script
             .run(self, &mut conn, id, &key, &Utc::now(), true)
-            .await
+            .await?;
+        if self.0.mode == QueueMode::Streams {
+            self.0.stream_scripts.ack(&mut conn, &key).await?;
+        }
+        Ok(status)
     }
 
     pub async fn update_job(
@@ -595,7 +1302,8 @@ impl Queue {
         let now = Utc::now();
 
         let mut conn = self.0.pool.get().await?;
-        self.0
+        let done = self
+            .0
             .done_script
             .run(
                 self,
@@ -605,7 +1313,11 @@ impl Queue {
                 &now,
                 expected_expiration,
             )
-            .await
+            .await?;
+        if done && self.0.mode == QueueMode::Streams {
+            self.0.stream_scripts.ack(&mut conn, &job_data_key).await?;
+        }
+        Ok(done)
     }
 
     async fn errored_job(
@@ -613,6 +1325,7 @@ impl Queue {
         id: &str,
         expected_expiration: &DateTime<Utc>,
         error: &str,
+        error_class: ErrorClass,
     ) -> Result<(), Error> {
         let job_data_key = self.job_data_key(id);
         let now = Utc::now();
@@ -628,31 +1341,100 @@ impl Queue {
                 &now,
                 expected_expiration,
                 error,
+                error_class,
             )
             .await?;
+        // A retry gets a new entry when it's due.
+        if self.0.mode == QueueMode::Streams {
+            self.0.stream_scripts.ack(&mut conn, &job_data_key).await?;
+        }
         Ok(())
     }
 
-    pub async fn job_expires_at(&self, id: &str) -> Result<Option<DateTime<Utc>>, Error> {
-        let mut conn = self.0.pool.get().await?;
-        let score: Option<i64> = redis::cmd("ZSCORE").arg(id).query_async(&mut conn).await?;
-        Ok(score.map(|s| Utc.timestamp_millis(s)))
-    }
-}
+    /// Move a running job's processing deadline to `new_expiration`. Returns false if the job
+    /// is no longer running with the expected expiration.
+    async fn heartbeat_job(
+        &self,
+        id: &str,
+        expected_expiration: &DateTime<Utc>,
+        new_expiration: &DateTime<Utc>,
+    ) -> Result<bool, Error> {
+        let job_data_key = self.job_data_key(id);
+        let now = Utc::now();
 
-impl Clone for Queue {
-    fn clone(&self) -> Queue {
-        Queue(self.0.clone())
+        let mut conn = self.0.pool.get().await?;
+        let extended = self
+            .0
+            .heartbeat_script
+            .run(
+                self,
+                &mut conn,
+                id,
+                &job_data_key,
+                &now,
+                expected_expiration,
+                new_expiration,
+            )
+            .await?;
+        if extended && self.0.mode == QueueMode::Streams {
+            self.0
+                .stream_scripts
+                .touch(self, &mut conn, &job_data_key)
+                .await?;
+        }
+        Ok(extended)
     }
-}
 
-#[cfg(all(test))]
-mod tests {
-    use super::*;
+    /// Save the progress of a running job. Returns false if the job is no longer running with
+    /// the expected expiration.
+    async fn set_job_progress(
+        &self,
+        id: &str,
+        expected_expiration: &DateTime<Utc>,
+        progress: &JobProgress,
+    ) -> Result<bool, Error> {
+        let job_data_key = self.job_data_key(id);
+        let mut conn = self.0.pool.get().await?;
+        self.0
+            .progress_script
+            .run(
+                self,
+                &mut conn,
+                id,
+                &job_data_key,
+                expected_expiration,
+                progress,
+            )
+            .await
+    }
+
+    pub async fn job_expires_at(&self, id: &str) -> Result<Option<DateTime<Utc>>, Error> {
+        let mut conn = self.0.pool.get().await?;
+        let score: Option<i64> = redis::cmd("ZSCORE")
+            .arg(&self.0.processing_list)
+            .arg(id)
+            .query_async(&mut conn)
+            .await?;
+        Ok(score.map(|s| Utc.timestamp_millis(s)))
+    }
+}
+
+impl Clone for Queue {
+    fn clone(&self) -> Queue {
+        Queue(self.0.clone())
+    }
+}
+
+#[cfg(all(test))]
+mod tests {
+    use super::*;
     use crate::error::Error;
     use chrono::{Duration, DurationRound};
     use futures::{Future, FutureExt};
-    use std::borrow::Cow;
+    use std::{
+        borrow::Cow,
+        sync::atomic::{AtomicBool, Ordering},
+    };
 
     #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
     struct SimplePayload {
@@ -673,6 +1455,16 @@ mod tests {
     }
 
     async fn run_queue_test<T, Fut, E>(test: T)
+    where
+        T: Send + Sync + FnOnce(Queue) -> Fut,
+        Fut: Future<Output = Result<(), E>>,
+        E: std::fmt::Debug,
+    {
+        dotenv::dotenv().ok();
+        run_queue_test_with_mode(QueueMode::from_env(), test).await
+    }
+
+    async fn run_queue_test_with_mode<T, Fut, E>(mode: QueueMode, test: T)
     where
         T: Send + Sync + FnOnce(Queue) -> Fut,
         Fut: Future<Output = Result<(), E>>,
@@ -681,7 +1473,7 @@ mod tests {
         dotenv::dotenv().ok();
         let queue_name = format!("test-{}", uuid::Uuid::new_v4());
         let pool = ergo_database::RedisPool::new(None, None).expect("Creating connection pool");
-        let queue = Queue::new(pool.clone(), queue_name.clone(), None, None, None);
+        let queue = Queue::with_mode(pool.clone(), queue_name.clone(), None, None, None, mode);
 
         let result = std::panic::AssertUnwindSafe(test(queue))
             .catch_unwind()
@@ -699,7 +1491,7 @@ mod tests {
             .arg("COUNT")
             .arg(100)
             .clone()
-            .iter_async(&mut *conn)
+            .iter_async(&mut conn)
             .await
             .expect("Cleanup: Scanning keyspace");
 
@@ -744,6 +1536,622 @@ mod tests {
         .await;
     }
 
+    #[tokio::test]
+    async fn priority_jobs_run_first() {
+        run_queue_test(|queue| async move {
+            queue
+                .enqueue(&Job {
+                    id: String::from("normal"),
+                    payload: SimplePayload::generate()?,
+                    ..Default::default()
+                })
+                .await?;
+            queue
+                .enqueue(&Job {
+                    id: String::from("priority"),
+                    payload: SimplePayload::generate()?,
+                    high_priority: true,
+                    ..Default::default()
+                })
+                .await?;
+
+            let status = queue.status().await?;
+            assert_eq!(status.current_pending, 1);
+            assert_eq!(status.current_priority_pending, 1);
+
+            let first = queue
+                .get_job::<SimplePayload>()
+                .await?
+                .expect("Did not see the priority job");
+            assert_eq!(first.id, "priority");
+
+            let second = queue
+                .get_job::<SimplePayload>()
+                .await?
+                .expect("Did not see the normal job");
+            assert_eq!(second.id, "normal");
+
+            let status = queue.status().await?;
+            assert_eq!(status.priority.total_retrieved, 1);
+
+            Ok::<(), Error>(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn metrics_history() {
+        run_queue_test(|queue| async move {
+            let jobs = (0..3)
+                .map(|i| {
+                    Ok(Job {
+                        id: format!("job-{}", i),
+                        payload: SimplePayload::generate()?,
+                        ..Default::default()
+                    })
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            queue.enqueue_multiple(&jobs).await?;
+            queue.get_job::<SimplePayload>().await?.expect("first job");
+            queue.get_job::<SimplePayload>().await?.expect("second job");
+
+            let history = queue.metrics_history(5).await?;
+            assert_eq!(history.minutes.len(), 5);
+            assert_eq!(history.overall.enqueued, 3);
+            assert_eq!(history.overall.dequeued, 2);
+            assert!(history.overall.p99_wait_ms.is_some());
+
+            Ok::<(), Error>(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn fairness_keys_take_turns() {
+        run_queue_test(|queue| async move {
+            for i in 0..3 {
+                queue
+                    .enqueue(&Job {
+                        id: format!("busy-{}", i),
+                        payload: SimplePayload::generate()?,
+                        fairness_key: Some("busy".into()),
+                        ..Default::default()
+                    })
+                    .await?;
+            }
+            queue
+                .enqueue(&Job {
+                    id: String::from("quiet"),
+                    payload: SimplePayload::generate()?,
+                    fairness_key: Some("quiet".into()),
+                    ..Default::default()
+                })
+                .await?;
+
+            assert_eq!(queue.backlog().await?, 4);
+            assert_eq!(queue.status().await?.current_pending, 4);
+
+            let first = queue
+                .get_job::<SimplePayload>()
+                .await?
+                .expect("Did not see the first job");
+            assert_eq!(first.fairness_key.as_deref(), Some("busy"));
+
+            let second = queue
+                .get_job::<SimplePayload>()
+                .await?
+                .expect("Did not see the second job");
+            assert_eq!(second.id, "quiet");
+
+            // Skipping the only key with jobs left returns nothing.
+            let skipped = queue.get_fair_job::<SimplePayload>(&["busy"]).await?;
+            assert!(skipped.is_none());
+
+            let third = queue
+                .get_fair_job::<SimplePayload>(&["quiet"])
+                .await?
+                .expect("Did not see the third job");
+            assert_eq!(third.fairness_key.as_deref(), Some("busy"));
+            assert_eq!(queue.backlog().await?, 1);
+
+            Ok::<(), Error>(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn background_keys_yield() {
+        run_queue_test_with_mode(QueueMode::Lists, |queue| async move {
+            let background = background_fairness_key("org");
+            for i in 0..3 {
+                queue
+                    .enqueue(&Job {
+                        id: format!("background-{}", i),
+                        payload: SimplePayload::generate()?,
+                        fairness_key: Some(background.clone()),
+                        ..Default::default()
+                    })
+                    .await?;
+            }
+            queue
+                .enqueue(&Job {
+                    id: String::from("event-1"),
+                    payload: SimplePayload::generate()?,
+                    fairness_key: Some("org".into()),
+                    ..Default::default()
+                })
+                .await?;
+
+            // The event job arrived after the batch but runs first.
+            let first = queue
+                .get_job::<SimplePayload>()
+                .await?
+                .expect("Did not see the first job");
+            assert_eq!(first.id, "event-1");
+
+            // With nothing else waiting, the background jobs run.
+            let second = queue
+                .get_job::<SimplePayload>()
+                .await?
+                .expect("Did not see the second job");
+            assert_eq!(second.fairness_key.as_deref(), Some(background.as_str()));
+
+            // The background key just had a turn, so a new job with another key goes ahead of
+            // it.
+            queue
+                .enqueue(&Job {
+                    id: String::from("event-2"),
+                    payload: SimplePayload::generate()?,
+                    fairness_key: Some("other".into()),
+                    ..Default::default()
+                })
+                .await?;
+            let third = queue
+                .get_job::<SimplePayload>()
+                .await?
+                .expect("Did not see the third job");
+            assert_eq!(third.id, "event-2");
+
+            for _ in 0..2 {
+                let job = queue
+                    .get_job::<SimplePayload>()
+                    .await?
+                    .expect("Did not see a background job");
+                assert!(job.id.starts_with("background-"));
+            }
+            assert_eq!(queue.backlog().await?, 0);
+
+            Ok::<(), Error>(())
+        })
+        .await;
+    }
+
+    async fn enqueue_job(queue: &Queue, id: &str, high_priority: bool) -> Result<(), Error> {
+        queue
+            .enqueue(&Job {
+                id: id.to_string(),
+                payload: SimplePayload::generate()?,
+                high_priority,
+                ..Default::default()
+            })
+            .await
+    }
+
+    /// Pretend that a job was enqueued `age` ago.
+    async fn backdate_job(queue: &Queue, id: &str, age: std::time::Duration) -> Result<(), Error> {
+        let enqueued = Utc::now().timestamp_millis() - age.as_millis() as i64;
+        let mut conn = queue.0.pool.get().await?;
+        conn.hset::<_, _, _, ()>(queue.job_data_key(id), "qt", enqueued)
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn old_jobs_age_ahead_of_new_jobs() {
+        run_queue_test_with_mode(QueueMode::Lists, |queue| async move {
+            enqueue_job(&queue, "old", false).await?;
+            enqueue_job(&queue, "new-1", false).await?;
+            enqueue_job(&queue, "new-2", false).await?;
+
+            // Until a job has aged, the newest job goes first.
+            let first = queue.get_job::<SimplePayload>().await?.expect("first job");
+            assert_eq!(first.id, "new-2");
+
+            backdate_job(&queue, "old", *AGING_INTERVAL * 2).await?;
+            let second = queue.get_job::<SimplePayload>().await?.expect("second job");
+            assert_eq!(second.id, "old", "aged job runs before newer jobs");
+
+            // The remaining normal job has waited longer than the priority job, but not by the
+            // aging interval.
+            backdate_job(&queue, "new-1", *AGING_INTERVAL / 2).await?;
+            enqueue_job(&queue, "priority-1", true).await?;
+            let third = queue.get_job::<SimplePayload>().await?.expect("third job");
+            assert_eq!(third.id, "priority-1");
+
+            backdate_job(&queue, "new-1", *AGING_INTERVAL * 3).await?;
+            enqueue_job(&queue, "priority-2", true).await?;
+            let fourth = queue.get_job::<SimplePayload>().await?.expect("fourth job");
+            assert_eq!(fourth.id, "new-1", "aged job runs before the priority lane");
+
+            let fifth = queue.get_job::<SimplePayload>().await?.expect("fifth job");
+            assert_eq!(fifth.id, "priority-2");
+
+            Ok::<(), Error>(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn streams_mode() {
+        run_queue_test_with_mode(QueueMode::Streams, |queue| async move {
+            for (id, high_priority) in [("normal", false), ("priority", true), ("canceled", false)]
+            {
+                queue
+                    .enqueue(&Job {
+                        id: id.to_string(),
+                        payload: SimplePayload::generate()?,
+                        high_priority,
+                        ..Default::default()
+                    })
+                    .await?;
+            }
+
+            assert_eq!(queue.backlog().await?, 3);
+            assert_eq!(
+                queue.list_pending().await?,
+                vec!["priority", "normal", "canceled"]
+            );
+
+            queue.cancel_pending_job("canceled").await?;
+            assert_eq!(queue.list_pending().await?, vec!["priority", "normal"]);
+
+            let mut first = queue
+                .get_job::<SimplePayload>()
+                .await?
+                .expect("Did not see the priority job");
+            assert_eq!(first.id, "priority");
+
+            let status = queue.status().await?;
+            assert_eq!(status.mode, QueueMode::Streams);
+            let stream_status = status.stream.expect("stream status");
+            assert_eq!(stream_status.delivered, 1);
+            assert_eq!(stream_status.consumers.len(), 1);
+            assert_eq!(stream_status.consumers[0].pending, 1);
+
+            first
+                .process(|_, _| async move { Ok::<(), Error>(()) })
+                .await?;
+
+            let second = queue
+                .get_job::<SimplePayload>()
+                .await?
+                .expect("Did not see the normal job");
+            assert_eq!(second.id, "normal");
+
+            // The canceled job's entry is passed over.
+            assert!(queue.get_job::<SimplePayload>().await?.is_none());
+
+            let status = queue.status().await?;
+            assert_eq!(status.stream.expect("stream status").delivered, 1);
+            assert_eq!(status.total_succeeded, 1);
+
+            Ok::<(), Error>(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn streams_mode_claims_abandoned_jobs() {
+        run_queue_test_with_mode(QueueMode::Streams, |queue| async move {
+            let short_timeout = |queue: &Queue| {
+                Queue::with_mode(
+                    queue.0.pool.clone(),
+                    queue.0.name.clone(),
+                    Some(std::time::Duration::from_millis(100)),
+                    None,
+                    None,
+                    QueueMode::Streams,
+                )
+            };
+            let crashed_worker = short_timeout(&queue);
+            let other_worker = short_timeout(&queue);
+
+            crashed_worker
+                .enqueue(&Job {
+                    id: String::from("abandoned"),
+                    payload: SimplePayload::generate()?,
+                    ..Default::default()
+                })
+                .await?;
+
+            let job = crashed_worker
+                .get_job::<SimplePayload>()
+                .await?
+                .expect("Did not see the job");
+            assert_eq!(job.current_retry, 0);
+            // The worker goes away without finishing the job.
+            drop(job);
+
+            assert!(
+                other_worker.get_job::<SimplePayload>().await?.is_none(),
+                "job is not claimed before it times out"
+            );
+
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            let mut job = other_worker
+                .get_job::<SimplePayload>()
+                .await?
+                .expect("Did not claim the abandoned job");
+            assert_eq!(job.id, "abandoned");
+            assert_eq!(job.current_retry, 1);
+
+            job.process(|_, _| async move { Ok::<(), Error>(()) })
+                .await?;
+
+            let info = queue
+                .job_info("abandoned")
+                .await?
+                .expect("Job info should exist");
+            assert_eq!(info.succeeded, Some(true));
+            assert_eq!(info.retry_count, 1);
+            assert_eq!(
+                queue
+                    .status()
+                    .await?
+                    .stream
+                    .expect("stream status")
+                    .delivered,
+                0
+            );
+
+            Ok::<(), Error>(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn permanent_error_skips_retries() {
+        run_queue_test(|queue| async move {
+            let job = Job {
+                id: String::from("a-test-id"),
+                payload: SimplePayload::generate()?,
+                max_retries: Some(3),
+                ..Default::default()
+            };
+            queue.enqueue(&job).await?;
+
+            let mut job = queue
+                .get_job::<SimplePayload>()
+                .await?
+                .expect("Did not see a job after enqueueing it");
+            job.process_with_classifier(
+                |_, _| async move {
+                    Err::<(), _>(std::io::Error::new(std::io::ErrorKind::Other, "bad input"))
+                },
+                |_| ErrorClass::Permanent,
+            )
+            .await
+            .expect_err("job should fail");
+
+            let info = queue
+                .job_info("a-test-id")
+                .await?
+                .expect("Job info should exist");
+            assert_eq!(info.succeeded, Some(false), "job is marked failed");
+            assert_eq!(info.retry_count, 0, "job was not retried");
+            assert_eq!(info.error_class.as_deref(), Some("permanent"));
+            assert!(
+                queue.list_scheduled().await?.is_empty(),
+                "job is not scheduled for a retry"
+            );
+
+            Ok::<(), Error>(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn heartbeat_keeps_job_running() {
+        run_queue_test(|queue| async move {
+            for id in ["beating", "silent"] {
+                queue
+                    .enqueue(&Job {
+                        id: String::from(id),
+                        payload: SimplePayload::generate()?,
+                        timeout: Some(std::time::Duration::from_millis(300)),
+                        ..Default::default()
+                    })
+                    .await?;
+            }
+
+            let mut beating = queue
+                .get_job::<SimplePayload>()
+                .await?
+                .expect("Did not see the first job");
+            let silent = queue
+                .get_job::<SimplePayload>()
+                .await?
+                .expect("Did not see the second job");
+            let silent_id = silent.id.clone();
+
+            let first_deadline = beating.expires();
+            let q = queue.clone();
+            beating
+                .process(|item, _| async move {
+                    item.with_heartbeat(std::time::Duration::from_millis(100), async {
+                        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+                        let timed_out = q.retry_timed_out_jobs().await?;
+                        assert_eq!(timed_out, 1, "only the job without heartbeats timed out");
+                        assert!(item.active().await?, "job is still active");
+                        Ok::<(), Error>(())
+                    })
+                    .await
+                })
+                .await?;
+            assert!(beating.expires() > first_deadline, "deadline moved");
+
+            let info = queue
+                .job_info(&beating.id)
+                .await?
+                .expect("Job info should exist");
+            assert_eq!(info.succeeded, Some(true));
+            assert_eq!(info.retry_count, 0);
+
+            let info = queue
+                .job_info(&silent_id)
+                .await?
+                .expect("Job info should exist");
+            assert_eq!(info.retry_count, 1, "timed out job is retried");
+            assert_eq!(info.error_details.as_deref(), Some("timed out"));
+            assert!(!silent.heartbeat().await?, "heartbeat after timeout fails");
+
+            Ok::<(), Error>(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn dead_worker_jobs_are_retried() {
+        run_queue_test_with_mode(QueueMode::Lists, |queue| async move {
+            enqueue_job(&queue, "abandoned", false).await?;
+            let item = queue
+                .get_job::<SimplePayload>()
+                .await?
+                .expect("Did not see the job");
+
+            let jobs = workers::InFlightJobs::default();
+            let _in_flight = jobs.start(&item.id);
+            queue.worker_heartbeat(&Utc::now(), &jobs).await?;
+
+            let registered = queue.list_workers().await?;
+            assert_eq!(registered.len(), 1);
+            assert_eq!(registered[0].id, queue.0.instance_id);
+            assert_eq!(registered[0].jobs, vec!["abandoned".to_string()]);
+            assert!(!registered[0].is_dead());
+            assert_eq!(
+                queue.reap_dead_workers().await?,
+                0,
+                "live worker is left alone"
+            );
+
+            // Pretend that the worker stopped sending heartbeats.
+            let last_heartbeat =
+                Utc::now() - Duration::from_std(*WORKER_DEAD_AFTER).unwrap() - Duration::seconds(1);
+            let mut conn = queue.0.pool.get().await?;
+            conn.zadd::<_, _, _, ()>(
+                &queue.0.workers_set,
+                &queue.0.instance_id,
+                last_heartbeat.timestamp_millis(),
+            )
+            .await?;
+
+            assert_eq!(queue.reap_dead_workers().await?, 1, "job is retried");
+            assert!(queue.list_workers().await?.is_empty(), "worker was removed");
+            assert!(queue.list_processing().await?.is_empty());
+
+            let info = queue
+                .job_info("abandoned")
+                .await?
+                .expect("Job info should exist");
+            assert_eq!(info.retry_count, 1);
+            assert_eq!(
+                info.error_details.as_deref(),
+                Some("worker stopped responding")
+            );
+
+            queue.enqueue_scheduled_items().await?;
+            let retried = queue
+                .get_job::<SimplePayload>()
+                .await?
+                .expect("Job should run again right away");
+            assert_eq!(retried.id, "abandoned");
+
+            Ok::<(), Error>(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn progress_reports() {
+        run_queue_test(|queue| async move {
+            queue
+                .enqueue(&Job {
+                    id: String::from("job"),
+                    payload: SimplePayload::generate()?,
+                    timeout: Some(std::time::Duration::from_millis(300)),
+                    ..Default::default()
+                })
+                .await?;
+
+            let mut item = queue
+                .get_job::<SimplePayload>()
+                .await?
+                .expect("Did not see the job");
+
+            let q = queue.clone();
+            item.process(|item, _| async move {
+                let progress = JobProgress::new(Some(150.0), Some("Almost done".to_string()));
+                assert!(item.report_progress(&progress).await?, "progress saved");
+
+                let info = q.job_info(&item.id).await?.expect("Job info should exist");
+                let saved = info.progress.expect("Job should have progress");
+                assert_eq!(saved.percent, Some(100.0), "percent is clamped");
+                assert_eq!(saved.message.as_deref(), Some("Almost done"));
+                Ok::<(), Error>(())
+            })
+            .await?;
+
+            assert!(
+                !item.report_progress(&JobProgress::new(None, None)).await?,
+                "progress after the job finished is ignored"
+            );
+
+            Ok::<(), Error>(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn scheduler_leader_election() {
+        run_queue_test(|queue| async move {
+            let other = Queue::new(queue.0.pool.clone(), queue.0.name.clone(), None, None, None);
+
+            assert!(
+                queue.acquire_scheduler_lease().await?,
+                "first instance takes the lease"
+            );
+            assert!(
+                !other.acquire_scheduler_lease().await?,
+                "second instance is locked out"
+            );
+            assert!(
+                queue.acquire_scheduler_lease().await?,
+                "leader renews the lease"
+            );
+
+            assert!(
+                !other.release_scheduler_lease().await?,
+                "only the leader can release"
+            );
+            assert!(
+                queue.release_scheduler_lease().await?,
+                "leader releases the lease"
+            );
+
+            assert!(
+                other.acquire_scheduler_lease().await?,
+                "second instance takes over"
+            );
+            assert!(
+                !queue.acquire_scheduler_lease().await?,
+                "first instance is now locked out"
+            );
+
+            Ok::<(), Error>(())
+        })
+        .await;
+    }
+
     #[tokio::test]
     async fn scheduled_task() {
         run_queue_test(|queue| async move {
@@ -863,4 +2271,254 @@ mod tests {
         })
         .await;
     }
+
+    #[tokio::test]
+    async fn recurring_job() {
+        run_queue_test(|queue| async move {
+            let job = recurring::RecurringJob::from_json_payload(
+                "tick",
+                recurring::RecurringSchedule::Interval(std::time::Duration::from_secs(10)),
+                &SimplePayload {
+                    data: "tick".to_string(),
+                },
+            )?;
+            queue.add_recurring(&job).await?;
+
+            let recurring = queue.list_recurring().await?;
+            assert_eq!(recurring.len(), 1);
+            let first_run = recurring[0]
+                .next_run
+                .expect("first occurrence is scheduled");
+            let first_id = format!("tick@{}", first_run.timestamp_millis());
+            assert_eq!(
+                queue.list_scheduled().await?,
+                vec![(first_id.clone(), first_run)]
+            );
+
+            // Run the scheduled jobs checker as if the first occurrence had come due.
+            let now = first_run + Duration::seconds(1);
+            let mut conn = queue.0.pool.get().await?;
+            let moved = queue
+                .0
+                .enqueue_scheduled_script
+                .run(&queue, &mut conn, &now)
+                .await?;
+            assert_eq!(moved, 1, "first occurrence is enqueued");
+            queue.refill_recurring(&mut conn, &now).await?;
+
+            let second_run = first_run + Duration::seconds(10);
+            let second_id = format!("tick@{}", second_run.timestamp_millis());
+            assert_eq!(
+                queue.list_scheduled().await?,
+                vec![(second_id, second_run)],
+                "next occurrence is scheduled"
+            );
+            assert_eq!(queue.list_recurring().await?[0].next_run, Some(second_run));
+
+            let job = queue
+                .get_job::<SimplePayload>()
+                .await?
+                .expect("first occurrence is pending");
+            assert_eq!(job.id, first_id);
+            assert_eq!(
+                job.data,
+                Some(SimplePayload {
+                    data: "tick".to_string()
+                })
+            );
+
+            assert!(queue.remove_recurring("tick").await?);
+            assert!(queue.list_scheduled().await?.is_empty());
+            assert!(queue.list_recurring().await?.is_empty());
+            Ok::<(), Error>(())
+        })
+        .await;
+    }
+
+    #[derive(Clone)]
+    struct ChannelProcessor(tokio::sync::mpsc::Sender<String>);
+
+    #[async_trait::async_trait]
+    impl QueueJobProcessor for ChannelProcessor {
+        type Payload = SimplePayload;
+        type Error = Error;
+
+        async fn process(
+            &self,
+            _item: &QueueWorkItem<SimplePayload>,
+            payload: SimplePayload,
+        ) -> Result<(), Error> {
+            self.0.send(payload.data).await.ok();
+            Ok(())
+        }
+    }
+
+    async fn receive(rx: &mut tokio::sync::mpsc::Receiver<String>) -> String {
+        tokio::time::timeout(std::time::Duration::from_secs(30), rx.recv())
+            .await
+            .expect("Timed out waiting for job")
+            .expect("Processor closed")
+    }
+
+    #[derive(Clone)]
+    struct SlowProcessor {
+        started: tokio::sync::mpsc::Sender<()>,
+        finished: Arc<AtomicBool>,
+    }
+
+    #[async_trait::async_trait]
+    impl QueueJobProcessor for SlowProcessor {
+        type Payload = SimplePayload;
+        type Error = Error;
+
+        async fn process(
+            &self,
+            _item: &QueueWorkItem<SimplePayload>,
+            _payload: SimplePayload,
+        ) -> Result<(), Error> {
+            self.started.send(()).await.ok();
+            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+            self.finished.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_running_jobs() {
+        run_queue_test(|queue| async move {
+            let shutdown = ergo_graceful_shutdown::GracefulShutdown::new();
+            let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+            let finished = Arc::new(AtomicBool::new(false));
+            queue.start_dequeuer_loop(
+                shutdown.consumer(),
+                None,
+                None,
+                SlowProcessor {
+                    started: tx,
+                    finished: finished.clone(),
+                },
+            );
+
+            queue
+                .enqueue(&Job {
+                    id: String::from("slow-job"),
+                    payload: SimplePayload::with_value("slow")?,
+                    ..Default::default()
+                })
+                .await?;
+
+            tokio::time::timeout(std::time::Duration::from_secs(30), rx.recv())
+                .await
+                .expect("Timed out waiting for job to start");
+
+            shutdown.shutdown().await.expect("shutdown");
+            assert!(
+                finished.load(Ordering::SeqCst),
+                "Shutdown finished before the running job"
+            );
+
+            Ok::<(), Error>(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn paused_dequeuer_leaves_jobs_queued() {
+        run_queue_test(|queue| async move {
+            let shutdown = ergo_graceful_shutdown::GracefulShutdown::new();
+            let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+            queue.set_paused(true);
+            queue.start_dequeuer_loop(shutdown.consumer(), None, None, ChannelProcessor(tx));
+
+            queue
+                .enqueue(&Job {
+                    id: String::from("paused-job"),
+                    payload: SimplePayload::with_value("paused")?,
+                    ..Default::default()
+                })
+                .await?;
+
+            let received = tokio::time::timeout(std::time::Duration::from_secs(2), rx.recv()).await;
+            assert!(received.is_err(), "Paused dequeuer should not take jobs");
+
+            queue.set_paused(false);
+            assert_eq!(receive(&mut rx).await, "paused");
+
+            shutdown.shutdown().await.expect("shutdown");
+            Ok::<(), Error>(())
+        })
+        .await;
+    }
+
+    /// Restart Redis while the dequeuer loop is running, and make sure that the loop picks up
+    /// jobs again afterward. This needs Redis to be running in a Docker container, so it only
+    /// runs when requested: `REDIS_TEST_CONTAINER=<name> cargo test -- --ignored redis_restart`
+    #[tokio::test]
+    #[ignore]
+    async fn redis_restart() {
+        run_queue_test(|queue| async move {
+            let container =
+                std::env::var("REDIS_TEST_CONTAINER").expect("REDIS_TEST_CONTAINER is not set");
+            let shutdown = ergo_graceful_shutdown::GracefulShutdown::new();
+            let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+            queue.start_dequeuer_loop(shutdown.consumer(), None, None, ChannelProcessor(tx));
+
+            queue
+                .enqueue(&Job {
+                    id: String::from("before-restart"),
+                    payload: SimplePayload::with_value("before")?,
+                    ..Default::default()
+                })
+                .await?;
+            assert_eq!(receive(&mut rx).await, "before");
+
+            let status = tokio::process::Command::new("docker")
+                .args(["restart", container.as_str()])
+                .status()
+                .await
+                .expect("Running docker restart");
+            assert!(status.success(), "docker restart failed");
+
+            // Wait for Redis to accept connections before enqueueing the next job.
+            let mut attempts = 0;
+            while check_connection(&queue.0.pool).await.is_err() {
+                attempts += 1;
+                assert!(attempts < 100, "Redis did not come back after restart");
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            }
+
+            queue
+                .enqueue(&Job {
+                    id: String::from("after-restart"),
+                    payload: SimplePayload::with_value("after")?,
+                    ..Default::default()
+                })
+                .await?;
+            assert_eq!(receive(&mut rx).await, "after");
+
+            queue.stop_dequeuer_loop();
+            shutdown.shutdown().await.ok();
+            Ok::<(), Error>(())
+        })
+        .await;
+    }
+
+    #[test]
+    fn dequeuer_liveness() {
+        let max_idle = std::time::Duration::from_secs(30);
+        let status = |running, idle_secs: Option<i64>, at_capacity| DequeuerStatus {
+            running,
+            last_poll: idle_secs.map(|s| Utc::now() - Duration::seconds(s)),
+            at_capacity,
+        };
+
+        assert!(status(true, Some(1), false).is_live(max_idle));
+        assert!(!status(true, Some(60), false).is_live(max_idle));
+        assert!(
+            status(true, Some(60), true).is_live(max_idle),
+            "waiting on running jobs"
+        );
+        assert!(!status(false, Some(1), false).is_live(max_idle));
+        assert!(!status(true, None, false).is_live(max_idle));
+    }
 }
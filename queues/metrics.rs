@@ -0,0 +1,288 @@
+//! Per-minute rollups of queue throughput and latency. Each minute has a Redis hash with the
+//! number of jobs enqueued and dequeued, and a histogram of how long the dequeued jobs waited
+//! in the queue. The hashes expire after [METRICS_RETENTION], so the history covers a sliding
+//! window without any cleanup.
+//!
+//! A job's wait is measured from when it became ready to run, which is the later of when it was
+//! enqueued and when it was scheduled to run, until a worker took it.
+
+use std::{collections::HashMap, time::Duration};
+
+use chrono::{DateTime, TimeZone, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Error, Queue};
+
+lazy_static! {
+    /// How long to keep each minute's metrics. Set with `QUEUE_METRICS_RETENTION_MINS`.
+    pub static ref METRICS_RETENTION: Duration = std::env::var("QUEUE_METRICS_RETENTION_MINS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(|mins: u64| Duration::from_secs(mins * 60))
+        .unwrap_or_else(|| Duration::from_secs(24 * 60 * 60));
+}
+
+/// The upper bounds, in milliseconds, of the wait time histogram buckets. Waits longer than
+/// the last bound go in an extra overflow bucket.
+const WAIT_BUCKETS_MS: [u64; 14] = [
+    5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 30000, 60000, 300000,
+];
+
+const ENQUEUED_FIELD: &str = "enq";
+const DEQUEUED_FIELD: &str = "deq";
+const WAIT_SUM_FIELD: &str = "wait_ms";
+
+fn bucket_field(index: usize) -> String {
+    format!("w{}", index)
+}
+
+fn bucket_index(wait_ms: u64) -> usize {
+    WAIT_BUCKETS_MS
+        .iter()
+        .position(|bound| wait_ms <= *bound)
+        .unwrap_or(WAIT_BUCKETS_MS.len())
+}
+
+fn minute_start(time: &DateTime<Utc>) -> i64 {
+    let secs = time.timestamp();
+    secs - secs.rem_euclid(60)
+}
+
+/// Throughput and latency for a minute, or for a range of minutes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueMetrics {
+    /// The start of the minute, or of the first minute in the range.
+    pub start: DateTime<Utc>,
+    pub enqueued: u64,
+    pub dequeued: u64,
+    pub mean_wait_ms: Option<f64>,
+    /// The wait time percentiles are estimated from the histogram, so they are only as precise
+    /// as its buckets.
+    pub p50_wait_ms: Option<f64>,
+    pub p95_wait_ms: Option<f64>,
+    pub p99_wait_ms: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueueMetricsHistory {
+    /// One entry per minute, oldest first. Minutes without any activity are included with zero
+    /// counts.
+    pub minutes: Vec<QueueMetrics>,
+    /// The metrics for the whole range.
+    pub overall: QueueMetrics,
+}
+
+#[derive(Debug, Default, Clone)]
+struct MetricCounts {
+    enqueued: u64,
+    dequeued: u64,
+    wait_sum_ms: u64,
+    buckets: [u64; WAIT_BUCKETS_MS.len() + 1],
+}
+
+impl MetricCounts {
+    fn from_hash(hash: &HashMap<String, u64>) -> Self {
+        let mut counts = MetricCounts {
+            enqueued: hash.get(ENQUEUED_FIELD).copied().unwrap_or(0),
+            dequeued: hash.get(DEQUEUED_FIELD).copied().unwrap_or(0),
+            wait_sum_ms: hash.get(WAIT_SUM_FIELD).copied().unwrap_or(0),
+            ..Default::default()
+        };
+
+        for (i, bucket) in counts.buckets.iter_mut().enumerate() {
+            *bucket = hash.get(&bucket_field(i)).copied().unwrap_or(0);
+        }
+
+        counts
+    }
+
+    fn add(&mut self, other: &MetricCounts) {
+        self.enqueued += other.enqueued;
+        self.dequeued += other.dequeued;
+        self.wait_sum_ms += other.wait_sum_ms;
+        for (bucket, other) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += other;
+        }
+    }
+
+    /// Estimate a wait time percentile by interpolating within the bucket that contains it.
+    /// Waits in the overflow bucket are reported as the last bucket's bound.
+    fn percentile(&self, p: f64) -> Option<f64> {
+        let total = self.buckets.iter().sum::<u64>();
+        if total == 0 {
+            return None;
+        }
+
+        let rank = (p * total as f64).ceil().max(1.0);
+        let mut seen = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+
+            if (seen + count) as f64 >= rank {
+                let lower = if i == 0 { 0 } else { WAIT_BUCKETS_MS[i - 1] };
+                let upper = match WAIT_BUCKETS_MS.get(i) {
+                    Some(upper) => *upper,
+                    None => return Some(lower as f64),
+                };
+
+                let fraction = (rank - seen as f64) / *count as f64;
+                return Some(lower as f64 + fraction * (upper - lower) as f64);
+            }
+
+            seen += count;
+        }
+
+        None
+    }
+
+    fn metrics(&self, start: DateTime<Utc>) -> QueueMetrics {
+        QueueMetrics {
+            start,
+            enqueued: self.enqueued,
+            dequeued: self.dequeued,
+            mean_wait_ms: if self.dequeued == 0 {
+                None
+            } else {
+                Some(self.wait_sum_ms as f64 / self.dequeued as f64)
+            },
+            p50_wait_ms: self.percentile(0.5),
+            p95_wait_ms: self.percentile(0.95),
+            p99_wait_ms: self.percentile(0.99),
+        }
+    }
+}
+
+impl Queue {
+    fn metrics_key(&self, minute: i64) -> String {
+        format!("{}{}", self.0.metrics_prefix, minute)
+    }
+
+    /// Add commands to `pipe` to count `count` enqueued jobs.
+    pub(crate) fn record_enqueue_metrics(
+        &self,
+        pipe: &mut redis::Pipeline,
+        count: usize,
+        now: &DateTime<Utc>,
+    ) {
+        let key = self.metrics_key(minute_start(now));
+        pipe.hincr(&key, ENQUEUED_FIELD, count)
+            .ignore()
+            .expire(&key, METRICS_RETENTION.as_secs() as usize)
+            .ignore();
+    }
+
+    /// Add commands to `pipe` to count a dequeued job that waited `wait_ms` to run.
+    pub(crate) fn record_dequeue_metrics(
+        &self,
+        pipe: &mut redis::Pipeline,
+        wait_ms: u64,
+        now: &DateTime<Utc>,
+    ) {
+        let key = self.metrics_key(minute_start(now));
+        pipe.hincr(&key, DEQUEUED_FIELD, 1)
+            .ignore()
+            .hincr(&key, WAIT_SUM_FIELD, wait_ms)
+            .ignore()
+            .hincr(&key, bucket_field(bucket_index(wait_ms)), 1)
+            .ignore()
+            .expire(&key, METRICS_RETENTION.as_secs() as usize)
+            .ignore();
+    }
+
+    /// Return the metrics for each of the last `minutes` minutes, including the current
+    /// partial minute.
+    pub async fn metrics_history(&self, minutes: usize) -> Result<QueueMetricsHistory, Error> {
+        let minutes = minutes.max(1);
+        let current = minute_start(&Utc::now());
+        let starts = (0..minutes as i64)
+            .rev()
+            .map(|i| current - i * 60)
+            .collect::<Vec<_>>();
+
+        let mut pipe = redis::Pipeline::with_capacity(starts.len());
+        for start in &starts {
+            pipe.hgetall(self.metrics_key(*start));
+        }
+
+        let mut conn = self.0.pool.get().await?;
+        let hashes: Vec<HashMap<String, u64>> = pipe.query_async(&mut conn).await?;
+
+        let mut overall = MetricCounts::default();
+        let minutes = starts
+            .iter()
+            .zip(hashes.iter())
+            .map(|(start, hash)| {
+                let counts = MetricCounts::from_hash(hash);
+                overall.add(&counts);
+                counts.metrics(Utc.timestamp(*start, 0))
+            })
+            .collect::<Vec<_>>();
+
+        Ok(QueueMetricsHistory {
+            overall: overall.metrics(Utc.timestamp(starts[0], 0)),
+            minutes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts_with_waits(waits: &[u64]) -> MetricCounts {
+        let mut counts = MetricCounts::default();
+        for wait in waits {
+            counts.dequeued += 1;
+            counts.wait_sum_ms += wait;
+            counts.buckets[bucket_index(*wait)] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn buckets() {
+        assert_eq!(bucket_index(0), 0);
+        assert_eq!(bucket_index(5), 0);
+        assert_eq!(bucket_index(6), 1);
+        assert_eq!(bucket_index(300000), WAIT_BUCKETS_MS.len() - 1);
+        assert_eq!(bucket_index(300001), WAIT_BUCKETS_MS.len());
+    }
+
+    #[test]
+    fn percentiles() {
+        assert_eq!(MetricCounts::default().percentile(0.5), None);
+
+        // 90 fast jobs and 10 slow ones
+        let mut waits = vec![3; 90];
+        waits.extend([2000; 10]);
+        let counts = counts_with_waits(&waits);
+
+        let p50 = counts.percentile(0.5).unwrap();
+        assert!(p50 > 0.0 && p50 <= 5.0, "p50 {}", p50);
+        let p95 = counts.percentile(0.95).unwrap();
+        assert!(p95 > 1000.0 && p95 <= 2500.0, "p95 {}", p95);
+        let p99 = counts.percentile(0.99).unwrap();
+        assert!(p99 > p95 && p99 <= 2500.0, "p99 {}", p99);
+
+        let metrics = counts.metrics(Utc::now());
+        assert_eq!(metrics.mean_wait_ms, Some(202.7));
+    }
+
+    #[test]
+    fn overflow_percentile() {
+        let counts = counts_with_waits(&[1_000_000]);
+        assert_eq!(counts.percentile(0.99), Some(300000.0));
+    }
+
+    #[test]
+    fn add_counts() {
+        let mut total = counts_with_waits(&[3, 3]);
+        total.add(&counts_with_waits(&[2000, 2000]));
+        assert_eq!(total.dequeued, 4);
+        assert_eq!(total.buckets.iter().sum::<u64>(), 4);
+        assert!(total.percentile(0.75).unwrap() > 1000.0);
+    }
+}
@@ -4,7 +4,7 @@ use anyhow::anyhow;
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use ergo_database::{PostgresPool, RedisPool};
-use futures::future::TryFutureExt;
+use futures::{future::TryFutureExt, StreamExt};
 use fxhash::FxHashMap;
 use serde::Serialize;
 use sqlx::{postgres::PgListener, Connection, Postgres, Row, Transaction};
@@ -15,7 +15,7 @@ use tokio::{
 use tracing::{event, instrument, Level};
 
 use super::{Job, Queue};
-use crate::error::Error;
+use crate::{error::Error, reconnect};
 use ergo_graceful_shutdown::GracefulShutdownConsumer;
 
 pub enum QueueOperation {
@@ -41,12 +41,18 @@ pub struct DrainResult<'a> {
     pub queue: Cow<'static, str>,
     pub operation: QueueOperation,
     pub job: Job<'a>,
+    /// When the job was written to the stage table. This is used to report how far behind the
+    /// drain is.
+    pub staged_at: Option<DateTime<Utc>>,
 }
 
 #[async_trait]
 pub trait Drainer: Send + Sync {
     type Error: 'static + std::error::Error + Send + Sync;
 
+    /// The name of the stage, used in logs and stats.
+    fn name(&self) -> &str;
+
     /// The notify channel to listen on, if any.
     fn notify_channel(&self) -> Option<String>;
 
@@ -62,15 +68,56 @@ pub trait Drainer: Send + Sync {
 
 #[derive(Clone, Debug, Serialize)]
 pub struct QueueStageDrainStats {
+    pub stage: String,
+    /// The number of jobs drained since the drain started.
     pub drained: usize,
+    /// The number of batches drained since the drain started.
+    pub batches: usize,
+    /// The number of jobs in the most recent batch.
+    pub last_batch_size: usize,
+    /// How long the oldest job in the most recent batch waited in the stage table. This is 0
+    /// when the last check found the table empty.
+    pub lag_ms: u64,
     pub last_drain: DateTime<Utc>,
     pub last_check: DateTime<Utc>,
 }
 
+impl QueueStageDrainStats {
+    fn new(stage: &str) -> Self {
+        let now = Utc::now();
+        QueueStageDrainStats {
+            stage: stage.to_string(),
+            drained: 0,
+            batches: 0,
+            last_batch_size: 0,
+            lag_ms: 0,
+            last_drain: now,
+            last_check: now,
+        }
+    }
+}
+
+/// Reads the latest stats from a set of drains.
+#[derive(Clone, Default)]
+pub struct DrainStatsHandle(Vec<watch::Receiver<QueueStageDrainStats>>);
+
+impl DrainStatsHandle {
+    pub fn add(&mut self, drain: &QueueStageDrain) {
+        self.0.push(drain.stats.clone());
+    }
+
+    pub fn current(&self) -> Vec<QueueStageDrainStats> {
+        self.0.iter().map(|stats| stats.borrow().clone()).collect()
+    }
+}
+
 pub struct QueueStageDrainConfig<D: Drainer + 'static> {
     pub db_pool: PostgresPool,
     pub redis_pool: RedisPool,
     pub drainer: D,
+    /// How many jobs from each batch to send to Redis at once. The operations for a single job
+    /// are always applied in order. Values below 1 are treated as 1.
+    pub parallelism: usize,
 
     /// Preinitialize with a queue when you already have the queue object.
     pub queue: Option<Queue>,
@@ -96,16 +143,11 @@ impl QueueStageDrain {
             queue,
             shutdown,
             drainer,
-            ..
+            parallelism,
         } = config;
 
-        let now = Utc::now();
-
-        let (stats_tx, stats_rx) = watch::channel(QueueStageDrainStats {
-            drained: 0,
-            last_drain: now,
-            last_check: now,
-        });
+        let stats = QueueStageDrainStats::new(drainer.name());
+        let (stats_tx, stats_rx) = watch::channel(stats.clone());
 
         let drain = StageDrainTask {
             db_pool,
@@ -115,13 +157,10 @@ impl QueueStageDrain {
                 .map(|q| (q.name().to_string(), q))
                 .collect::<_>(),
             drainer,
+            parallelism: parallelism.max(1),
             close: close_rx,
             stats_tx,
-            stats: QueueStageDrainStats {
-                drained: 0,
-                last_drain: now,
-                last_check: now,
-            },
+            stats,
             shutdown,
         };
 
@@ -159,6 +198,7 @@ struct StageDrainTask<D: Drainer> {
     redis_pool: RedisPool,
     queues: FxHashMap<String, Queue>,
     drainer: D,
+    parallelism: usize,
     close: oneshot::Receiver<()>,
     stats_tx: watch::Sender<QueueStageDrainStats>,
     shutdown: GracefulShutdownConsumer,
@@ -198,13 +238,20 @@ impl<D: Drainer> StageDrainTask<D> {
                 Ok(false) => {
                     // No rows, so just fall through to the delay
                 }
+                Err(e) if e.is_connection_error() => {
+                    // The staged jobs were rolled back, so drain again once Redis is back.
+                    if self.wait_for_redis(&e).await {
+                        sleep_duration = initial_sleep_value();
+                        continue;
+                    } else {
+                        break;
+                    }
+                }
                 Err(e) => {
-                    event!(Level::ERROR, error=?e, "Error draining job queue");
+                    event!(Level::ERROR, stage=%self.drainer.name(), error=?e, "Error draining job queue");
                 }
             };
 
-            self.stats_tx.send(self.stats.clone()).ok();
-
             sleep_duration = sleep_duration.mul_f64(2.0).min(MAX_SLEEP);
             tokio::select! {
                 _ = tokio::time::sleep(sleep_duration) => continue,
@@ -247,8 +294,17 @@ impl<D: Drainer> StageDrainTask<D> {
                 }
                 // No rows, so pass through back to listening again.
                 Ok(false) => {}
+                Err(e) if e.is_connection_error() => {
+                    // Don't wait for another notification, since the jobs that failed are still
+                    // in the stage table.
+                    if self.wait_for_redis(&e).await {
+                        continue;
+                    } else {
+                        break;
+                    }
+                }
                 Err(e) => {
-                    event!(Level::ERROR, error=?e, "Error draining job queue");
+                    event!(Level::ERROR, stage=%self.drainer.name(), error=?e, "Error draining job queue");
                 }
             };
 
@@ -281,7 +337,22 @@ impl<D: Drainer> StageDrainTask<D> {
         }
     }
 
-    #[instrument(level = "DEBUG", skip(self))]
+    /// Wait for Redis to come back after a connection error. Returns false if the drain task
+    /// should shut down instead.
+    async fn wait_for_redis(&mut self, error: &Error) -> bool {
+        let mut shutdown_waiter = self.shutdown.clone();
+        tokio::select! {
+            _ = reconnect::wait_for_reconnect(&self.redis_pool, self.drainer.name(), None, error) => true,
+            _ = shutdown_waiter.wait_for_shutdown() => false,
+            _ = &mut self.close => false,
+        }
+    }
+
+    fn publish_stats(&self) {
+        self.stats_tx.send(self.stats.clone()).ok();
+    }
+
+    #[instrument(level = "DEBUG", skip(self), fields(stage = %self.drainer.name()))]
     async fn try_drain(&mut self) -> Result<bool, Error> {
         let mut conn = self.db_pool.acquire().await?;
         let mut tx = conn.begin().await?;
@@ -307,58 +378,88 @@ impl<D: Drainer> StageDrainTask<D> {
             .map_err(|e| Error::DrainError(anyhow!(e)))?;
 
         if jobs.is_empty() {
+            self.stats.lag_ms = 0;
+            self.publish_stats();
             return Ok(false);
         }
 
-        self.stats.last_drain = now;
+        for DrainResult { queue, .. } in &jobs {
+            if !self.queues.contains_key(queue.as_ref()) {
+                self.queues.insert(
+                    queue.to_string(),
+                    Queue::new(self.redis_pool.clone(), queue.to_string(), None, None, None),
+                );
+            }
+        }
 
-        for DrainResult {
-            queue: queue_name,
-            operation,
-            job,
-        } in &jobs
-        {
-            let queue = match self.queues.get(queue_name.as_ref()) {
-                Some(q) => q,
-                None => {
-                    self.queues.insert(
-                        queue_name.to_string(),
-                        Queue::new(
-                            self.redis_pool.clone(),
-                            queue_name.to_string(),
-                            None,
-                            None,
-                            None,
-                        ),
-                    );
-
-                    self.queues.get(queue_name.as_ref()).unwrap()
-                }
-            };
+        // Different jobs can go to Redis in parallel, but a job that was staged more than once,
+        // such as an add followed by an update, has its operations applied in order.
+        let mut job_groups: Vec<Vec<&DrainResult>> = Vec::new();
+        let mut group_index: FxHashMap<(&str, &str), usize> = FxHashMap::default();
+        for result in &jobs {
+            let index = *group_index
+                .entry((result.queue.as_ref(), result.job.id.as_str()))
+                .or_insert_with(|| {
+                    job_groups.push(Vec::new());
+                    job_groups.len() - 1
+                });
+            job_groups[index].push(result);
+        }
 
-            match operation {
-                QueueOperation::Add => {
-                    event!(Level::INFO, queue=%queue_name, ?job, "Enqueueing job");
-                    queue.enqueue(job).await?;
+        let queues = &self.queues;
+        let results = futures::stream::iter(job_groups)
+            .map(|group| async move {
+                for result in group {
+                    apply_operation(&queues[result.queue.as_ref()], result).await?;
                 }
-                QueueOperation::Remove => {
-                    event!(Level::INFO, queue=%queue_name, job=%job.id, "Removing job");
-                    queue.cancel_pending_job(&job.id).await?;
-                }
-                QueueOperation::Update => {
-                    let payload = if job.payload.is_empty() {
-                        None
-                    } else {
-                        Some(job.payload.as_ref())
-                    };
+                Ok::<(), Error>(())
+            })
+            .buffer_unordered(self.parallelism)
+            .collect::<Vec<_>>()
+            .await;
+        results.into_iter().collect::<Result<(), Error>>()?;
 
-                    event!(Level::INFO, queue=%queue_name, ?job, "Updating pending job");
-                    queue.update_job(&job.id, job.run_at, payload).await?;
-                }
-            }
-        }
         tx.commit().await?;
 
+        self.stats.last_drain = now;
+        self.stats.drained += jobs.len();
+        self.stats.batches += 1;
+        self.stats.last_batch_size = jobs.len();
+        self.stats.lag_ms = jobs
+            .iter()
+            .filter_map(|job| job.staged_at)
+            .min()
+            .map(|oldest| (now - oldest).num_milliseconds().max(0) as u64)
+            .unwrap_or(0);
+        self.publish_stats();
+
         Ok::<bool, Error>(true)
     }
 }
+
+async fn apply_operation(queue: &Queue, result: &DrainResult<'_>) -> Result<(), Error> {
+    let DrainResult { operation, job, .. } = result;
+    let queue_name = queue.name();
+    match operation {
+        QueueOperation::Add => {
+            event!(Level::INFO, queue=%queue_name, ?job, "Enqueueing job");
+            queue.enqueue(job).await?;
+        }
+        QueueOperation::Remove => {
+            event!(Level::INFO, queue=%queue_name, job=%job.id, "Removing job");
+            queue.cancel_pending_job(&job.id).await?;
+        }
+        QueueOperation::Update => {
+            let payload = if job.payload.is_empty() {
+                None
+            } else {
+                Some(job.payload.as_ref())
+            };
+
+            event!(Level::INFO, queue=%queue_name, ?job, "Updating pending job");
+            queue.update_job(&job.id, job.run_at, payload).await?;
+        }
+    }
+
+    Ok(())
+}
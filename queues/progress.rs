@@ -0,0 +1,80 @@
+use chrono::{DateTime, Utc};
+use ergo_database::redis::RedisConnection;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+use super::Queue;
+
+/// How far along a running job is, as reported by the job itself.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct JobProgress {
+    /// Percent complete, from 0 to 100.
+    pub percent: Option<f64>,
+    pub message: Option<String>,
+    pub updated: DateTime<Utc>,
+}
+
+impl JobProgress {
+    pub fn new(percent: Option<f64>, message: Option<String>) -> JobProgress {
+        JobProgress {
+            percent: percent.map(|p| p.clamp(0.0, 100.0)),
+            message,
+            updated: Utc::now(),
+        }
+    }
+}
+
+// Save the progress of a running job.
+// KEYS:
+//  1. job data key
+//  2. processing list
+// ARGS:
+//  1. job id
+//  2. expected expiration
+//  3. progress JSON
+pub(crate) const PROGRESS_SCRIPT: &str = r##"
+    local score = redis.call("ZSCORE", KEYS[2], ARGV[1])
+    if score ~= ARGV[2] then
+        -- The job isn't ours anymore, so its progress belongs to whoever is running it now.
+        return 0
+    end
+
+    redis.call("HSET", KEYS[1], "prog", ARGV[3])
+    return 1
+"##;
+
+lazy_static! {
+    static ref SCRIPT: redis::Script = redis::Script::new(PROGRESS_SCRIPT);
+}
+
+pub struct ProgressScript(&'static redis::Script);
+
+impl ProgressScript {
+    pub fn new() -> Self {
+        ProgressScript(&SCRIPT)
+    }
+
+    pub async fn run(
+        &self,
+        queue: &Queue,
+        conn: &mut RedisConnection,
+        job_id: &str,
+        job_data_key: &str,
+        expected_expiration: &DateTime<Utc>,
+        progress: &JobProgress,
+    ) -> Result<bool, Error> {
+        let saved: bool = self
+            .0
+            .key(job_data_key)
+            .key(&queue.0.processing_list)
+            .arg(job_id)
+            .arg(expected_expiration.timestamp_millis())
+            .arg(serde_json::to_string(progress)?)
+            .invoke_async(&mut **conn)
+            .await?;
+
+        Ok(saved)
+    }
+}
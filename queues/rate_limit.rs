@@ -0,0 +1,254 @@
+//! Concurrency caps and token bucket rate limits that are shared by every worker. The state for
+//! each limited key lives in Redis, so the limits hold across processes.
+
+use std::time::Duration;
+
+use chrono::Utc;
+use ergo_database::RedisPool;
+use lazy_static::lazy_static;
+
+use crate::error::Error;
+
+// Try to take a concurrency slot and a rate limit token for a key.
+// KEYS:
+//  1. concurrency sorted set, holder id -> lease expiration
+//  2. token bucket hash
+// ARGS:
+//  1. current time in milliseconds
+//  2. holder id
+//  3. max concurrency, or 0 for no limit
+//  4. lease duration in milliseconds
+//  5. requests per minute, or 0 for no limit
+//  6. milliseconds to wait when all the concurrency slots are taken
+//
+// Returns 0 if the slot was acquired, or the number of milliseconds to wait before trying again.
+const ACQUIRE_SCRIPT: &str = r##"
+    local now = tonumber(ARGV[1])
+    local max_concurrency = tonumber(ARGV[3])
+    local lease = tonumber(ARGV[4])
+    local per_minute = tonumber(ARGV[5])
+
+    if max_concurrency > 0 then
+        -- Drop leases from workers that went away without releasing them.
+        redis.call("ZREMRANGEBYSCORE", KEYS[1], "-inf", now)
+        local held = redis.call("ZSCORE", KEYS[1], ARGV[2])
+        if not held and redis.call("ZCARD", KEYS[1]) >= max_concurrency then
+            return tonumber(ARGV[6])
+        end
+    end
+
+    if per_minute > 0 then
+        local bucket = redis.call("HMGET", KEYS[2], "tokens", "ts")
+        local tokens = tonumber(bucket[1]) or per_minute
+        local last = tonumber(bucket[2]) or now
+        local per_ms = per_minute / 60000
+        tokens = math.min(per_minute, tokens + math.max(0, now - last) * per_ms)
+
+        if tokens < 1 then
+            redis.call("HSET", KEYS[2], "tokens", tostring(tokens), "ts", now)
+            redis.call("PEXPIRE", KEYS[2], 60000)
+            return math.max(1, math.ceil((1 - tokens) / per_ms))
+        end
+
+        redis.call("HSET", KEYS[2], "tokens", tostring(tokens - 1), "ts", now)
+        -- An untouched bucket refills completely within a minute, so it can go away.
+        redis.call("PEXPIRE", KEYS[2], 60000)
+    end
+
+    if max_concurrency > 0 then
+        redis.call("ZADD", KEYS[1], now + lease, ARGV[2])
+        redis.call("PEXPIRE", KEYS[1], lease)
+    end
+
+    return 0
+"##;
+
+lazy_static! {
+    static ref ACQUIRE: redis::Script = redis::Script::new(ACQUIRE_SCRIPT);
+}
+
+/// The limits for a single key. A limit that is `None` is not enforced.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    /// The most holders that can have a slot at once.
+    pub max_concurrency: Option<u32>,
+    /// The number of slots that can be taken each minute, refilled continuously.
+    pub per_minute: Option<u32>,
+}
+
+impl RateLimit {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_concurrency.unwrap_or(0) == 0 && self.per_minute.unwrap_or(0) == 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Acquired {
+    /// The holder has a slot. It should call [RateLimiter::release] when it is done.
+    Yes,
+    /// The key is at its limit. Try again after this long.
+    RetryAfter(Duration),
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    pool: RedisPool,
+    key_prefix: String,
+    /// How long a slot is held before it is considered abandoned.
+    lease: Duration,
+    /// How long to wait before trying again when every concurrency slot is taken.
+    busy_wait: Duration,
+}
+
+impl RateLimiter {
+    /// Create a limiter. `name` distinguishes this limiter's keys from those of other limiters,
+    /// and `lease` is how long a slot can be held before it is released automatically.
+    pub fn new(pool: RedisPool, name: &str, lease: Duration) -> RateLimiter {
+        let key_prefix = match pool.key_prefix() {
+            Some(prefix) => format!("erq:limit:{}-{}:", prefix, name),
+            None => format!("erq:limit:{}:", name),
+        };
+
+        RateLimiter {
+            pool,
+            key_prefix,
+            lease,
+            busy_wait: Duration::from_secs(1),
+        }
+    }
+
+    /// Set how long to wait before trying again when every concurrency slot is taken.
+    /// Defaults to one second.
+    pub fn busy_wait(mut self, wait: Duration) -> Self {
+        self.busy_wait = wait;
+        self
+    }
+
+    fn concurrency_key(&self, key: &str) -> String {
+        format!("{}{}:running", self.key_prefix, key)
+    }
+
+    fn bucket_key(&self, key: &str) -> String {
+        format!("{}{}:bucket", self.key_prefix, key)
+    }
+
+    /// Try to take a slot for `holder` under the limits for `key`. Taking a slot again with a
+    /// holder that already has one renews its lease without using another concurrency slot.
+    pub async fn acquire(
+        &self,
+        key: &str,
+        holder: &str,
+        limit: &RateLimit,
+    ) -> Result<Acquired, Error> {
+        if limit.is_unlimited() {
+            return Ok(Acquired::Yes);
+        }
+
+        let mut conn = self.pool.get().await?;
+        let wait_ms: u64 = ACQUIRE
+            .key(self.concurrency_key(key))
+            .key(self.bucket_key(key))
+            .arg(Utc::now().timestamp_millis())
+            .arg(holder)
+            .arg(limit.max_concurrency.unwrap_or(0))
+            .arg(self.lease.as_millis() as u64)
+            .arg(limit.per_minute.unwrap_or(0))
+            .arg(self.busy_wait.as_millis() as u64)
+            .invoke_async(&mut conn)
+            .await?;
+
+        if wait_ms == 0 {
+            Ok(Acquired::Yes)
+        } else {
+            Ok(Acquired::RetryAfter(Duration::from_millis(wait_ms)))
+        }
+    }
+
+    /// Give up the concurrency slot taken by `holder`. Rate limit tokens are not returned.
+    pub async fn release(&self, key: &str, holder: &str) -> Result<(), Error> {
+        let mut conn = self.pool.get().await?;
+        redis::cmd("ZREM")
+            .arg(self.concurrency_key(key))
+            .arg(holder)
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter() -> RateLimiter {
+        dotenv::dotenv().ok();
+        let pool = RedisPool::new(None, None).expect("Creating connection pool");
+        RateLimiter::new(
+            pool,
+            &format!("test-{}", uuid::Uuid::new_v4()),
+            Duration::from_secs(10),
+        )
+    }
+
+    #[tokio::test]
+    async fn concurrency() -> Result<(), Error> {
+        let limiter = limiter();
+        let limit = RateLimit {
+            max_concurrency: Some(2),
+            per_minute: None,
+        };
+
+        assert_eq!(limiter.acquire("key", "a", &limit).await?, Acquired::Yes);
+        assert_eq!(limiter.acquire("key", "b", &limit).await?, Acquired::Yes);
+        assert_eq!(
+            limiter.acquire("key", "c", &limit).await?,
+            Acquired::RetryAfter(Duration::from_secs(1))
+        );
+        assert_eq!(
+            limiter.acquire("key", "a", &limit).await?,
+            Acquired::Yes,
+            "renewing a held slot"
+        );
+        assert_eq!(
+            limiter.acquire("other", "c", &limit).await?,
+            Acquired::Yes,
+            "keys are limited separately"
+        );
+
+        limiter.release("key", "a").await?;
+        assert_eq!(limiter.acquire("key", "c", &limit).await?, Acquired::Yes);
+
+        limiter.release("key", "b").await?;
+        limiter.release("key", "c").await?;
+        limiter.release("other", "c").await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn requests_per_minute() -> Result<(), Error> {
+        let limiter = limiter();
+        let limit = RateLimit {
+            max_concurrency: None,
+            per_minute: Some(3),
+        };
+
+        for i in 0..3 {
+            let holder = i.to_string();
+            assert_eq!(
+                limiter.acquire("key", &holder, &limit).await?,
+                Acquired::Yes
+            );
+        }
+
+        match limiter.acquire("key", "3", &limit).await? {
+            Acquired::RetryAfter(wait) => assert!(
+                wait > Duration::from_secs(15) && wait <= Duration::from_secs(20),
+                "wait was {:?}",
+                wait
+            ),
+            Acquired::Yes => panic!("Expected bucket to be empty"),
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+//! Redis can restart or fail over while the queue loops are running. When that happens the
+//! loops wait here until Redis is reachable again instead of spinning on errors.
+//!
+//! The pool checks each connection with a PING before handing it out, so connections that died
+//! with the old server are dropped and replaced as soon as they are used again. The Lua scripts
+//! are loaded again explicitly, since a restarted server starts with an empty script cache.
+
+use std::time::{Duration, Instant};
+
+use backoff::{backoff::Backoff, ExponentialBackoff};
+use ergo_database::RedisPool;
+use redis::AsyncCommands;
+use tracing::{event, Level};
+
+use crate::{
+    enqueue_scheduled, error::Error, get_job, job_cancel, job_done, job_error, start_work, streams,
+    update_job,
+};
+
+const SCRIPTS: [&str; 10] = [
+    enqueue_scheduled::ENQUEUE_SCHEDULED_SCRIPT,
+    get_job::DEQUEUE_ITEM_SCRIPT,
+    start_work::START_WORK_SCRIPT,
+    job_done::DONE_SCRIPT,
+    job_error::ERROR_SCRIPT,
+    job_cancel::CANCEL_SCRIPT,
+    update_job::UPDATE_JOB_SCRIPT,
+    streams::STREAM_DEQUEUE_SCRIPT,
+    streams::STREAM_ACK_SCRIPT,
+    streams::STREAM_TOUCH_SCRIPT,
+];
+
+const MAX_RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Check that Redis is reachable and load the queue scripts into its script cache.
+pub async fn check_connection(pool: &RedisPool) -> Result<(), Error> {
+    let mut conn = pool.get().await?;
+    redis::cmd("PING").query_async::<_, ()>(&mut conn).await?;
+
+    let mut pipe = redis::pipe();
+    for script in SCRIPTS {
+        pipe.cmd("SCRIPT").arg("LOAD").arg(script).ignore();
+    }
+    pipe.query_async::<_, ()>(&mut conn).await?;
+
+    Ok(())
+}
+
+/// Wait until Redis is reachable again after `error`. This never gives up, so callers should
+/// race it against their shutdown signal.
+///
+/// `stats_hash`, if given, is the queue stats hash that the reconnection is counted in.
+pub(crate) async fn wait_for_reconnect(
+    pool: &RedisPool,
+    name: &str,
+    stats_hash: Option<&str>,
+    error: &Error,
+) {
+    event!(Level::WARN, queue=%name, %error, "Lost connection to Redis");
+
+    let start = Instant::now();
+    let mut backoff = ExponentialBackoff {
+        initial_interval: Duration::from_millis(100),
+        current_interval: Duration::from_millis(100),
+        max_interval: MAX_RECONNECT_INTERVAL,
+        max_elapsed_time: None,
+        ..Default::default()
+    };
+
+    loop {
+        let delay = backoff.next_backoff().unwrap_or(MAX_RECONNECT_INTERVAL);
+        tokio::time::sleep(delay).await;
+
+        match check_connection(pool).await {
+            Ok(()) => break,
+            Err(e) => event!(Level::DEBUG, queue=%name, error=%e, "Redis is still unavailable"),
+        }
+    }
+
+    let down_ms = start.elapsed().as_millis() as u64;
+    event!(Level::INFO, queue=%name, %down_ms, "Recovered connection to Redis");
+
+    if let Some(stats_hash) = stats_hash {
+        let recorded = match pool.get().await {
+            Ok(mut conn) => conn
+                .hincr::<_, _, _, ()>(stats_hash, "reconnects", 1)
+                .await
+                .map_err(Error::from),
+            Err(e) => Err(Error::from(e)),
+        };
+
+        if let Err(e) = recorded {
+            event!(Level::ERROR, queue=%name, error=%e, "Failed to record Redis reconnection");
+        }
+    }
+}
@@ -0,0 +1,441 @@
+//! Recurring jobs, which the queue runs on a cron or interval schedule.
+//!
+//! A recurring job's definition lives in Redis next to the queue, along with a short list of its
+//! upcoming run times. Only one occurrence is scheduled at a time. When the scheduled jobs
+//! checker moves an occurrence to the pending list, it also schedules the next occurrence from
+//! the list of upcoming times, and the list is topped up from the schedule when it runs low.
+//! This lets maintenance jobs keep themselves scheduled without anything outside the queue
+//! having to enqueue them.
+
+use std::{borrow::Cow, str::FromStr, time::Duration};
+
+use chrono::{DateTime, TimeZone, Utc};
+use ergo_database::redis::RedisConnection;
+use itertools::Itertools;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    redis_job_data::{RedisJobField, RedisJobSetCmd},
+    Queue,
+};
+
+/// The number of upcoming run times to keep for each recurring job.
+const UPCOMING_RUNS: usize = 10;
+/// Top up the upcoming run times once fewer than this many remain.
+pub(crate) const REFILL_THRESHOLD: usize = 3;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecurringSchedule {
+    /// A cron expression, evaluated in UTC.
+    Cron(String),
+    /// Run at a fixed interval, aligned to the Unix epoch.
+    Interval(#[serde(with = "serde_millis")] Duration),
+}
+
+impl RecurringSchedule {
+    /// Return up to `count` run times that come strictly after `after`.
+    pub fn upcoming(
+        &self,
+        after: DateTime<Utc>,
+        count: usize,
+    ) -> Result<Vec<DateTime<Utc>>, Error> {
+        match self {
+            Self::Cron(expr) => {
+                let schedule = cron::Schedule::from_str(expr)
+                    .map_err(|e| Error::InvalidSchedule(e.to_string()))?;
+                Ok(schedule.after(&after).take(count).collect())
+            }
+            Self::Interval(interval) => {
+                let interval = interval.as_millis() as i64;
+                if interval < 1000 {
+                    return Err(Error::InvalidSchedule(
+                        "Interval must be at least one second".to_string(),
+                    ));
+                }
+
+                let first = (after.timestamp_millis() / interval + 1) * interval;
+                Ok((0..count as i64)
+                    .map(|i| Utc.timestamp_millis(first + i * interval))
+                    .collect())
+            }
+        }
+    }
+}
+
+/// A job that the queue runs on a schedule.
+#[derive(Debug)]
+pub struct RecurringJob<'a> {
+    /// Identifies the recurring job within the queue. Adding a recurring job with an ID that
+    /// already exists replaces it. Each occurrence gets a job ID of `{id}@{run time in ms}`.
+    pub id: String,
+    pub schedule: RecurringSchedule,
+    pub payload: Cow<'a, [u8]>,
+    pub timeout: Option<Duration>,
+    pub max_retries: Option<u32>,
+    pub retry_backoff: Option<Duration>,
+    pub fairness_key: Option<String>,
+}
+
+impl<'a> RecurringJob<'a> {
+    pub fn from_json_payload<T: Serialize>(
+        id: &str,
+        schedule: RecurringSchedule,
+        payload: &T,
+    ) -> Result<RecurringJob<'static>, serde_json::Error> {
+        let data = serde_json::to_vec(payload)?;
+        Ok(RecurringJob {
+            id: id.to_string(),
+            schedule,
+            payload: Cow::Owned(data),
+            timeout: None,
+            max_retries: None,
+            retry_backoff: None,
+            fairness_key: None,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RecurringJobInfo {
+    pub id: String,
+    pub schedule: RecurringSchedule,
+    /// When the next occurrence will run, if one is scheduled.
+    pub next_run: Option<DateTime<Utc>>,
+}
+
+fn occurrence_id(recurring_id: &str, run_at: &DateTime<Utc>) -> String {
+    format!("{}@{}", recurring_id, run_at.timestamp_millis())
+}
+
+/// The run time of an occurrence, from its job ID.
+fn occurrence_time(job_id: &str) -> Option<DateTime<Utc>> {
+    let (_, time) = job_id.rsplit_once('@')?;
+    time.parse::<i64>().ok().map(|t| Utc.timestamp_millis(t))
+}
+
+impl Queue {
+    fn recurring_key(&self, id: &str) -> String {
+        format!("{}{}", self.0.recurring_prefix, id)
+    }
+
+    fn recurring_upcoming_key(&self, id: &str) -> String {
+        format!("{}{}:upcoming", self.0.recurring_prefix, id)
+    }
+
+    /// Add the commands to schedule one occurrence of a recurring job.
+    fn schedule_occurrence(
+        &self,
+        pipe: &mut redis::Pipeline,
+        recurring_id: &str,
+        template: &RecurringTemplate,
+        run_at: &DateTime<Utc>,
+    ) {
+        let job_id = occurrence_id(recurring_id, run_at);
+        let mut cmd = RedisJobSetCmd::new(&self.job_data_key(&job_id))
+            .payload(&template.payload)
+            .timeout(template.timeout)
+            .current_retries(0)
+            .max_retries(template.max_retries)
+            .retry_backoff(template.retry_backoff)
+            .run_at(run_at)
+            .enqueued_at(&Utc::now())
+            .recurring(recurring_id);
+        if let Some(key) = template.fairness_key.as_deref() {
+            cmd = cmd.fairness_key(key);
+        }
+
+        pipe.add_command(cmd.build()).ignore();
+        pipe.zadd(&self.0.scheduled_list, &job_id, run_at.timestamp_millis())
+            .ignore();
+        pipe.hset(self.recurring_key(recurring_id), "cur", &job_id)
+            .ignore();
+    }
+
+    /// Add a recurring job, or replace the recurring job with the same ID. The first occurrence
+    /// is scheduled for the next time that the schedule matches.
+    pub async fn add_recurring(&self, job: &RecurringJob<'_>) -> Result<(), Error> {
+        let schedule = &job.schedule;
+        let mut runs = schedule.upcoming(Utc::now(), UPCOMING_RUNS)?;
+        if runs.is_empty() {
+            return Err(Error::InvalidSchedule(
+                "Schedule never runs after the current time".to_string(),
+            ));
+        }
+        let first = runs.remove(0);
+
+        let template = RecurringTemplate {
+            payload: job.payload.to_vec(),
+            timeout: job.timeout.unwrap_or(self.0.processing_timeout),
+            max_retries: job.max_retries.unwrap_or(self.0.max_retries),
+            retry_backoff: job.retry_backoff.unwrap_or(self.0.retry_backoff),
+            fairness_key: job.fairness_key.clone(),
+        };
+
+        let mut conn = self.0.pool.get().await?;
+        let def_key = self.recurring_key(&job.id);
+        let upcoming_key = self.recurring_upcoming_key(&job.id);
+        let previous: Option<String> = conn.hget(&def_key, "cur").await?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        if let Some(previous) = previous {
+            pipe.zrem(&self.0.scheduled_list, &previous).ignore();
+            pipe.del(self.job_data_key(&previous)).ignore();
+        }
+
+        pipe.del(&[&def_key, &upcoming_key]).ignore();
+        template.write(&mut pipe, &def_key, schedule)?;
+        if !runs.is_empty() {
+            let times = runs
+                .iter()
+                .map(|t| t.timestamp_millis())
+                .collect::<Vec<_>>();
+            pipe.rpush(&upcoming_key, times).ignore();
+        }
+        self.schedule_occurrence(&mut pipe, &job.id, &template, &first);
+        pipe.sadd(&self.0.recurring_set, &job.id).ignore();
+
+        pipe.query_async::<_, ()>(&mut conn).await?;
+        Ok(())
+    }
+
+    /// Stop running a recurring job. The occurrence that is scheduled next is removed too,
+    /// but an occurrence that is already pending or running will still finish.
+    /// Returns false if the recurring job did not exist.
+    pub async fn remove_recurring(&self, id: &str) -> Result<bool, Error> {
+        let mut conn = self.0.pool.get().await?;
+        let def_key = self.recurring_key(id);
+        let current: Option<String> = conn.hget(&def_key, "cur").await?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        if let Some(current) = current {
+            pipe.zrem(&self.0.scheduled_list, &current).ignore();
+            pipe.del(self.job_data_key(&current)).ignore();
+        }
+        pipe.del(&[&def_key, &self.recurring_upcoming_key(id)])
+            .ignore();
+        pipe.srem(&self.0.recurring_set, id);
+
+        let (removed,): (usize,) = pipe.query_async(&mut conn).await?;
+        Ok(removed > 0)
+    }
+
+    pub async fn list_recurring(&self) -> Result<Vec<RecurringJobInfo>, Error> {
+        let mut conn = self.0.pool.get().await?;
+        let ids: Vec<String> = conn.smembers(&self.0.recurring_set).await?;
+
+        let mut jobs = Vec::with_capacity(ids.len());
+        for id in ids.into_iter().sorted() {
+            let (schedule, current): (Option<String>, Option<String>) = conn
+                .hget(self.recurring_key(&id), &["sched", "cur"])
+                .await?;
+            let schedule = match schedule {
+                Some(s) => serde_json::from_str(&s)?,
+                None => continue,
+            };
+
+            jobs.push(RecurringJobInfo {
+                id,
+                schedule,
+                next_run: current.as_deref().and_then(occurrence_time),
+            });
+        }
+
+        Ok(jobs)
+    }
+
+    /// Top up the upcoming run times for the recurring jobs that the scheduled jobs checker
+    /// marked as running low, and schedule an occurrence for any recurring job that ran out
+    /// entirely. Returns the number of recurring jobs that were updated.
+    pub(crate) async fn refill_recurring(
+        &self,
+        conn: &mut RedisConnection,
+        now: &DateTime<Utc>,
+    ) -> Result<usize, Error> {
+        let ids: Vec<String> = conn.smembers(&self.0.recurring_refill).await?;
+
+        for id in &ids {
+            let def_key = self.recurring_key(id);
+            let upcoming_key = self.recurring_upcoming_key(id);
+
+            let template = RecurringTemplate::read(conn, &def_key).await?;
+            let (template, schedule, current) = match template {
+                Some(t) => t,
+                None => {
+                    // The recurring job was removed.
+                    conn.srem::<_, _, ()>(&self.0.recurring_refill, id).await?;
+                    continue;
+                }
+            };
+
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+
+            let runs = match current.as_deref() {
+                Some(current) => {
+                    // Continue from the last run time in the list.
+                    let last: Option<i64> = conn.lindex(&upcoming_key, -1).await?;
+                    let remaining: usize = conn.llen(&upcoming_key).await?;
+                    let after = last
+                        .map(|t| Utc.timestamp_millis(t))
+                        .or_else(|| occurrence_time(current))
+                        .filter(|t| t > now)
+                        .unwrap_or(*now);
+                    schedule.upcoming(after, UPCOMING_RUNS.saturating_sub(remaining))?
+                }
+                None => {
+                    // Nothing is scheduled, so whatever is left in the list has already passed.
+                    // Start over from the current time.
+                    pipe.del(&upcoming_key).ignore();
+                    let mut runs = schedule.upcoming(*now, UPCOMING_RUNS)?;
+                    if !runs.is_empty() {
+                        let first = runs.remove(0);
+                        self.schedule_occurrence(&mut pipe, id, &template, &first);
+                    }
+                    runs
+                }
+            };
+
+            if !runs.is_empty() {
+                let times = runs
+                    .iter()
+                    .map(|t| t.timestamp_millis())
+                    .collect::<Vec<_>>();
+                pipe.rpush(&upcoming_key, times).ignore();
+            }
+            pipe.srem(&self.0.recurring_refill, id).ignore();
+            pipe.query_async::<_, ()>(&mut **conn).await?;
+        }
+
+        Ok(ids.len())
+    }
+}
+
+/// The parts of a recurring job that are copied into each occurrence.
+struct RecurringTemplate {
+    payload: Vec<u8>,
+    timeout: Duration,
+    max_retries: u32,
+    retry_backoff: Duration,
+    fairness_key: Option<String>,
+}
+
+impl RecurringTemplate {
+    /// Write the template into the definition hash. The fields have the same names as in the
+    /// job data hash, so that the scheduled jobs checker can copy them directly.
+    fn write(
+        &self,
+        pipe: &mut redis::Pipeline,
+        def_key: &str,
+        schedule: &RecurringSchedule,
+    ) -> Result<(), Error> {
+        let mut cmd = RedisJobSetCmd::new(def_key)
+            .payload(&self.payload)
+            .timeout(self.timeout)
+            .max_retries(self.max_retries)
+            .retry_backoff(self.retry_backoff);
+        if let Some(key) = self.fairness_key.as_deref() {
+            cmd = cmd.fairness_key(key);
+        }
+
+        let mut cmd = cmd.build();
+        cmd.arg("sched").arg(serde_json::to_string(schedule)?);
+        pipe.add_command(cmd).ignore();
+        Ok(())
+    }
+
+    /// Read a recurring job's template, schedule, and currently scheduled occurrence.
+    async fn read(
+        conn: &mut RedisConnection,
+        def_key: &str,
+    ) -> Result<Option<(RecurringTemplate, RecurringSchedule, Option<String>)>, Error> {
+        let (payload, timeout, max_retries, retry_backoff, fairness_key, schedule, current): (
+            Option<Vec<u8>>,
+            Option<u64>,
+            Option<u32>,
+            Option<u64>,
+            Option<String>,
+            Option<String>,
+            Option<String>,
+        ) = redis::cmd("HMGET")
+            .arg(def_key)
+            .arg(RedisJobField::Payload)
+            .arg(RedisJobField::Timeout)
+            .arg(RedisJobField::MaxRetries)
+            .arg(RedisJobField::RetryBackoff)
+            .arg(RedisJobField::FairnessKey)
+            .arg("sched")
+            .arg("cur")
+            .query_async(&mut **conn)
+            .await?;
+
+        let schedule = match schedule {
+            Some(s) => serde_json::from_str(&s)?,
+            None => return Ok(None),
+        };
+
+        let template = RecurringTemplate {
+            payload: payload.unwrap_or_default(),
+            timeout: Duration::from_millis(timeout.unwrap_or_default()),
+            max_retries: max_retries.unwrap_or_default(),
+            retry_backoff: Duration::from_millis(retry_backoff.unwrap_or_default()),
+            fairness_key,
+        };
+
+        Ok(Some((template, schedule, current)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn cron_upcoming() {
+        let schedule = RecurringSchedule::Cron("0 0 * * * * *".to_string());
+        let runs = schedule.upcoming(time("2023-01-01T10:30:00Z"), 3).unwrap();
+        assert_eq!(
+            runs,
+            vec![
+                time("2023-01-01T11:00:00Z"),
+                time("2023-01-01T12:00:00Z"),
+                time("2023-01-01T13:00:00Z"),
+            ]
+        );
+    }
+
+    #[test]
+    fn interval_upcoming() {
+        let schedule = RecurringSchedule::Interval(Duration::from_secs(15 * 60));
+        let runs = schedule.upcoming(time("2023-01-01T10:30:00Z"), 2).unwrap();
+        assert_eq!(
+            runs,
+            vec![time("2023-01-01T10:45:00Z"), time("2023-01-01T11:00:00Z")]
+        );
+    }
+
+    #[test]
+    fn invalid_schedules() {
+        assert!(RecurringSchedule::Cron("not cron".to_string())
+            .upcoming(Utc::now(), 1)
+            .is_err());
+        assert!(RecurringSchedule::Interval(Duration::from_millis(10))
+            .upcoming(Utc::now(), 1)
+            .is_err());
+    }
+
+    #[test]
+    fn occurrence_ids() {
+        let run_at = time("2023-01-01T10:45:00Z");
+        let id = occurrence_id("prune:logs", &run_at);
+        assert_eq!(occurrence_time(&id), Some(run_at));
+    }
+}
@@ -13,6 +13,10 @@ pub(super) enum RedisJobField {
     EndedAt,
     Succeeded,
     ErrorDetails,
+    ErrorClass,
+    FairnessKey,
+    Recurring,
+    Progress,
 }
 
 impl RedisJobField {
@@ -29,6 +33,10 @@ impl RedisJobField {
             RedisJobField::EndedAt => "end",
             RedisJobField::Succeeded => "suc",
             RedisJobField::ErrorDetails => "err",
+            RedisJobField::ErrorClass => "ec",
+            RedisJobField::FairnessKey => "fk",
+            RedisJobField::Recurring => "rec",
+            RedisJobField::Progress => "prog",
         }
     }
 }
@@ -97,6 +105,17 @@ impl RedisJobSetCmd {
         self
     }
 
+    pub fn fairness_key(mut self, key: &str) -> Self {
+        self.0.arg(RedisJobField::FairnessKey).arg(key);
+        self
+    }
+
+    /// Mark the job as an occurrence of a recurring job.
+    pub fn recurring(mut self, recurring_id: &str) -> Self {
+        self.0.arg(RedisJobField::Recurring).arg(recurring_id);
+        self
+    }
+
     pub fn enqueued_at(mut self, enqueued_at: &DateTime<Utc>) -> Self {
         self.0
             .arg(RedisJobField::EnqueuedAt)
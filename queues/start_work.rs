@@ -1,5 +1,5 @@
 use chrono::{DateTime, TimeZone, Utc};
-use deadpool_redis::Connection;
+use ergo_database::redis::RedisConnection;
 use lazy_static::lazy_static;
 
 use crate::error::Error;
@@ -15,23 +15,34 @@ use super::Queue;
 //  1. job ID
 //  2. current time
 //  3. default expiration,
-const START_WORK_SCRIPT: &str = r##"
-    local job_data = redis.call("HMGET", KEYS[1], "to", "pay", "cr", "mr")
+//  4. ID of the worker taking the job
+pub(crate) const START_WORK_SCRIPT: &str = r##"
+    local job_data = redis.call("HMGET", KEYS[1], "to", "pay", "cr", "mr", "qt", "ra")
     local expiration = ARGV[2] + ARGV[3]
     -- If the job has a different timeout from the queue default, update it here.
     if job_data[1] ~= ARGV[3] then
         redis.call("ZADD", KEYS[2], expiration, ARGV[1])
     end
 
-    -- Set started time
-    redis.call("HSET", KEYS[1], "st", ARGV[2])
-    return {job_data[2], expiration, job_data[3], job_data[4]}
+    -- Set started time and worker, and clear any progress from an earlier attempt.
+    redis.call("HSET", KEYS[1], "st", ARGV[2], "wk", ARGV[4])
+    redis.call("HDEL", KEYS[1], "prog")
+    return {job_data[2], expiration, job_data[3], job_data[4], job_data[5], job_data[6]}
 "##;
 
 lazy_static! {
     static ref SCRIPT: redis::Script = redis::Script::new(START_WORK_SCRIPT);
 }
 
+pub struct StartWorkResult {
+    pub payload: Vec<u8>,
+    pub expiration: DateTime<Utc>,
+    pub current_retry: usize,
+    pub max_retries: usize,
+    pub enqueued_at: Option<DateTime<Utc>>,
+    pub run_at: Option<DateTime<Utc>>,
+}
+
 pub struct StartWorkScript(&'static redis::Script);
 
 impl StartWorkScript {
@@ -42,26 +53,36 @@ impl StartWorkScript {
     pub async fn run(
         &self,
         queue: &Queue,
-        conn: &mut Connection,
+        conn: &mut RedisConnection,
         job_id: &str,
         job_id_key: &str,
         now: &DateTime<Utc>,
-    ) -> Result<(Vec<u8>, DateTime<Utc>, usize, usize), Error> {
-        let (payload, expiration, current_retry, max_retries): (Vec<u8>, i64, usize, usize) = self
+    ) -> Result<StartWorkResult, Error> {
+        let (payload, expiration, current_retry, max_retries, enqueued_at, run_at): (
+            Vec<u8>,
+            i64,
+            usize,
+            usize,
+            Option<i64>,
+            Option<i64>,
+        ) = self
             .0
             .key(job_id_key)
             .key(&queue.0.processing_list)
             .arg(job_id)
             .arg(now.timestamp_millis())
             .arg(queue.0.processing_timeout.as_millis() as i64)
+            .arg(&queue.0.instance_id)
             .invoke_async(&mut **conn)
             .await?;
 
-        Ok((
+        Ok(StartWorkResult {
             payload,
-            Utc.timestamp_millis(expiration),
+            expiration: Utc.timestamp_millis(expiration),
             current_retry,
             max_retries,
-        ))
+            enqueued_at: enqueued_at.map(|t| Utc.timestamp_millis(t)),
+            run_at: run_at.map(|t| Utc.timestamp_millis(t)),
+        })
     }
 }
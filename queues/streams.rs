@@ -0,0 +1,355 @@
+//! Redis Streams mode for the queue.
+//!
+//! In this mode, jobs that are ready to run are added to a stream instead of the pending lists,
+//! and workers read them through a consumer group. A job's stream entry stays in the group's
+//! pending entries list until the job finishes, so when a worker goes away in the middle of a
+//! job, another worker claims the entry with `XAUTOCLAIM` and runs the job again, counting it
+//! as a retry. The consumer group also shows which workers hold which jobs and for how long.
+//!
+//! Everything else, including the job data, scheduled jobs, and the processing deadlines that
+//! decide which worker owns a job, works the same way as in the default lists mode. Switch
+//! modes only while the queue is empty, since jobs waiting in one mode's structures are not
+//! seen by the other. Streams mode needs Redis 6.2 or later.
+//!
+//! Jobs in a stream run in the order they were enqueued. Fairness keys are still recorded, and
+//! a worker that is passing over a key moves that key's jobs to the back of the stream instead
+//! of running them.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use ergo_database::redis::RedisConnection;
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::{error::Error, get_job::DequeuedJob, Queue};
+
+/// The consumer group that a queue's workers read its streams through.
+pub(crate) const STREAM_GROUP: &str = "erq";
+
+/// How a queue holds the jobs that are ready to run.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueMode {
+    /// Lists for the pending jobs, moved into a sorted set while they run.
+    #[default]
+    Lists,
+    /// A Redis stream read through a consumer group.
+    Streams,
+}
+
+impl QueueMode {
+    /// Read the mode from the `QUEUE_MODE` environment variable, which can be `lists` or
+    /// `streams`. Lists mode is the default.
+    pub fn from_env() -> QueueMode {
+        match std::env::var("QUEUE_MODE").ok().as_deref() {
+            Some("streams") => QueueMode::Streams,
+            _ => QueueMode::Lists,
+        }
+    }
+}
+
+// Get the next job from the streams, first claiming entries that have been idle in another
+// worker's pending entries list for too long.
+// KEYS:
+//  1. stream
+//  2. priority stream
+//  3. processing list
+//  4. stats hash
+//  5. done list
+// ARGV:
+//  1. consumer group
+//  2. consumer name
+//  3. current time
+//  4. queue-default expiration time
+//  5. job data prefix
+//  6. how long an entry must be idle before it can be claimed, in milliseconds
+//  7+. fairness keys to skip
+pub(crate) const STREAM_DEQUEUE_SCRIPT: &str = r##"
+    local now = tonumber(ARGV[3])
+    local skip = {}
+    for i = 7, #ARGV do
+        skip[ARGV[i]] = true
+    end
+
+    local function finish_entry(stream, entry_id)
+        redis.call("XACK", stream, ARGV[1], entry_id)
+        redis.call("XDEL", stream, entry_id)
+    end
+
+    local function deliver(stream, entry_id, job_id, high_priority)
+        local job_key = ARGV[5] .. job_id
+        -- Set the default queue expiration. The job worker will update it if needed
+        redis.call("ZADD", KEYS[3], tonumber(ARGV[4]), job_id)
+        redis.call("HSET", job_key, "sst", stream, "sid", entry_id)
+        redis.call("HINCRBY", KEYS[4], "retrieved", 1)
+        return { job_id, high_priority, redis.call("HGET", job_key, "fk") }
+    end
+
+    local streams = { { KEYS[2], 1 }, { KEYS[1], 0 } }
+    for _, s in ipairs(streams) do
+        -- Fails harmlessly if the group already exists.
+        redis.pcall("XGROUP", "CREATE", s[1], ARGV[1], "0", "MKSTREAM")
+    end
+
+    for _, s in ipairs(streams) do
+        local stream = s[1]
+        local claimed = redis.call("XAUTOCLAIM", stream, ARGV[1], ARGV[2], ARGV[6], "0-0", "COUNT", 10)
+        for _, entry in ipairs(claimed[2]) do
+            local entry_id = entry[1]
+            local fields = entry[2]
+            if not fields then
+                -- The entry was deleted while it was pending.
+                redis.call("XACK", stream, ARGV[1], entry_id)
+            else
+                local job_id = fields[2]
+                local job_key = ARGV[5] .. job_id
+                local deadline = redis.call("ZSCORE", KEYS[3], job_id)
+                if deadline == false or redis.call("HGET", job_key, "sid") ~= entry_id then
+                    -- The job already finished, or is running from a newer entry.
+                    finish_entry(stream, entry_id)
+                elseif tonumber(deadline) <= now then
+                    -- The worker running the job went away, so run it here as a retry.
+                    local retries = redis.call("HMGET", job_key, "cr", "mr")
+                    local retry = tonumber(retries[1]) or 0
+                    local max_retries = tonumber(retries[2]) or 0
+                    redis.call("HINCRBY", KEYS[4], "errored", 1)
+                    if retry >= max_retries then
+                        redis.call("ZREM", KEYS[3], job_id)
+                        redis.call("HSET", job_key, "err", "timed out", "ec", "retryable", "end", ARGV[3], "suc", "false")
+                        redis.call("LPUSH", KEYS[5], job_id)
+                        redis.call("HINCRBY", KEYS[4], "failed", 1)
+                        finish_entry(stream, entry_id)
+                    else
+                        redis.call("HSET", job_key, "err", "timed out", "ec", "retryable", "cr", retry + 1)
+                        return deliver(stream, entry_id, job_id, s[2])
+                    end
+                end
+                -- Otherwise the job is still within its deadline and its worker is still
+                -- around, so leave it alone.
+            end
+        end
+    end
+
+    for _, s in ipairs(streams) do
+        local stream = s[1]
+        for _ = 1, 10 do
+            local read = redis.call("XREADGROUP", "GROUP", ARGV[1], ARGV[2], "COUNT", 1, "STREAMS", stream, ">")
+            if not read then
+                break
+            end
+
+            local entry = read[1][2][1]
+            local entry_id = entry[1]
+            local job_id = entry[2][2]
+            local job_key = ARGV[5] .. job_id
+            if redis.call("HDEL", job_key, "rdy") == 0 then
+                -- The job was canceled or rescheduled after it was added to the stream.
+                finish_entry(stream, entry_id)
+            else
+                local fairness_key = redis.call("HGET", job_key, "fk")
+                if s[2] == 0 and fairness_key and skip[fairness_key] then
+                    finish_entry(stream, entry_id)
+                    redis.call("XADD", stream, "*", "id", job_id)
+                    redis.call("HSET", job_key, "rdy", 1)
+                else
+                    return deliver(stream, entry_id, job_id, s[2])
+                end
+            end
+        end
+    end
+
+    return false
+"##;
+
+// Remove a job's entry from its stream once the job no longer needs it.
+// KEYS:
+//  1. job data key
+// ARGV:
+//  1. consumer group
+pub(crate) const STREAM_ACK_SCRIPT: &str = r##"
+    local entry = redis.call("HMGET", KEYS[1], "sst", "sid")
+    if not entry[2] then
+        return 0
+    end
+
+    redis.call("XACK", entry[1], ARGV[1], entry[2])
+    redis.call("XDEL", entry[1], entry[2])
+    redis.call("HDEL", KEYS[1], "sst", "sid")
+    return 1
+"##;
+
+// Reset the idle time of a running job's stream entry, so that other workers don't claim it
+// while its worker is still sending heartbeats.
+// KEYS:
+//  1. job data key
+// ARGV:
+//  1. consumer group
+//  2. consumer name
+pub(crate) const STREAM_TOUCH_SCRIPT: &str = r##"
+    local entry = redis.call("HMGET", KEYS[1], "sst", "sid")
+    if not entry[2] then
+        return 0
+    end
+
+    redis.call("XCLAIM", entry[1], ARGV[1], ARGV[2], 0, entry[2], "JUSTID")
+    return 1
+"##;
+
+lazy_static! {
+    static ref DEQUEUE_SCRIPT: redis::Script = redis::Script::new(STREAM_DEQUEUE_SCRIPT);
+    static ref ACK_SCRIPT: redis::Script = redis::Script::new(STREAM_ACK_SCRIPT);
+    static ref TOUCH_SCRIPT: redis::Script = redis::Script::new(STREAM_TOUCH_SCRIPT);
+}
+
+pub struct StreamScripts {
+    dequeue: &'static redis::Script,
+    ack: &'static redis::Script,
+    touch: &'static redis::Script,
+}
+
+impl StreamScripts {
+    pub fn new() -> Self {
+        StreamScripts {
+            dequeue: &DEQUEUE_SCRIPT,
+            ack: &ACK_SCRIPT,
+            touch: &TOUCH_SCRIPT,
+        }
+    }
+
+    pub async fn dequeue(
+        &self,
+        queue: &Queue,
+        conn: &mut RedisConnection,
+        now: &DateTime<Utc>,
+        skip_fairness_keys: &[&str],
+    ) -> Result<Option<DequeuedJob>, Error> {
+        let now_millis = now.timestamp_millis();
+        let mut invocation = self.dequeue.prepare_invoke();
+        invocation
+            .key(&queue.0.stream)
+            .key(&queue.0.priority_stream)
+            .key(&queue.0.processing_list)
+            .key(&queue.0.stats_hash)
+            .key(&queue.0.done_list)
+            .arg(STREAM_GROUP)
+            .arg(&queue.0.instance_id)
+            .arg(now_millis)
+            .arg(now_millis + queue.0.processing_timeout.as_millis() as i64)
+            .arg(&queue.0.job_data_prefix)
+            .arg(queue.0.processing_timeout.as_millis() as i64);
+        for key in skip_fairness_keys {
+            invocation.arg(*key);
+        }
+
+        let job: Option<(String, bool, Option<String>)> =
+            invocation.invoke_async(&mut **conn).await?;
+
+        Ok(job.map(|(id, high_priority, fairness_key)| DequeuedJob {
+            id,
+            high_priority,
+            fairness_key,
+        }))
+    }
+
+    pub async fn ack(&self, conn: &mut RedisConnection, job_data_key: &str) -> Result<bool, Error> {
+        let acked: bool = self
+            .ack
+            .key(job_data_key)
+            .arg(STREAM_GROUP)
+            .invoke_async(&mut **conn)
+            .await?;
+        Ok(acked)
+    }
+
+    pub async fn touch(
+        &self,
+        queue: &Queue,
+        conn: &mut RedisConnection,
+        job_data_key: &str,
+    ) -> Result<bool, Error> {
+        let touched: bool = self
+            .touch
+            .key(job_data_key)
+            .arg(STREAM_GROUP)
+            .arg(&queue.0.instance_id)
+            .invoke_async(&mut **conn)
+            .await?;
+        Ok(touched)
+    }
+}
+
+/// The state of a queue's consumer group, for queues in streams mode.
+#[derive(Debug, Serialize)]
+pub struct StreamStatus {
+    /// Entries that have been handed to a worker and not finished yet.
+    pub delivered: usize,
+    pub consumers: Vec<StreamConsumer>,
+}
+
+/// A worker reading from the queue's streams.
+#[derive(Debug, Serialize)]
+pub struct StreamConsumer {
+    pub name: String,
+    /// The number of entries that the worker holds.
+    pub pending: usize,
+    /// How long since the worker last read or claimed an entry.
+    pub idle_ms: u64,
+}
+
+/// The number of entries in `stream` that have not been handed to a worker, and the number that
+/// have been handed to a worker and not finished.
+pub(crate) async fn stream_counts(
+    conn: &mut RedisConnection,
+    stream: &str,
+) -> Result<(usize, usize), Error> {
+    let len: usize = redis::cmd("XLEN")
+        .arg(stream)
+        .query_async(&mut **conn)
+        .await?;
+    if len == 0 {
+        return Ok((0, 0));
+    }
+
+    let pending: redis::RedisResult<(usize, redis::Value, redis::Value, redis::Value)> =
+        redis::cmd("XPENDING")
+            .arg(stream)
+            .arg(STREAM_GROUP)
+            .query_async(&mut **conn)
+            .await;
+
+    // The group doesn't exist until a worker first reads from the stream.
+    let delivered = pending.map(|p| p.0).unwrap_or(0);
+    Ok((len.saturating_sub(delivered), delivered))
+}
+
+pub(crate) async fn stream_consumers(
+    conn: &mut RedisConnection,
+    stream: &str,
+) -> Result<Vec<StreamConsumer>, Error> {
+    let consumers: redis::RedisResult<Vec<HashMap<String, redis::Value>>> = redis::cmd("XINFO")
+        .arg("CONSUMERS")
+        .arg(stream)
+        .arg(STREAM_GROUP)
+        .query_async(&mut **conn)
+        .await;
+
+    let consumers = match consumers {
+        Ok(c) => c,
+        // The stream or group doesn't exist yet.
+        Err(e) if e.kind() == redis::ErrorKind::ResponseError => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    consumers
+        .into_iter()
+        .map(|c| {
+            let field = |name: &str| c.get(name).cloned().unwrap_or(redis::Value::Nil);
+            Ok(StreamConsumer {
+                name: redis::from_redis_value(&field("name"))?,
+                pending: redis::from_redis_value(&field("pending"))?,
+                idle_ms: redis::from_redis_value(&field("idle"))?,
+            })
+        })
+        .collect()
+}
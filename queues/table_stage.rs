@@ -0,0 +1,146 @@
+//! A [Drainer] for any Postgres table of staged jobs. The table needs an integer ID column, and a
+//! mapper function turns each row into the job to send to Redis.
+//!
+//! ```ignore
+//! let stage = TableStage::new("webhooks", "webhook_stage", |row| {
+//!     let payload: serde_json::Value = row.try_get("payload")?;
+//!     Ok(DrainResult {
+//!         queue: Cow::Borrowed("webhooks"),
+//!         operation: QueueOperation::Add,
+//!         job: Job {
+//!             id: row.try_get("job_id")?,
+//!             payload: Cow::Owned(serde_json::to_vec(&payload)?),
+//!             ..Default::default()
+//!         },
+//!         staged_at: row.try_get("created")?,
+//!     })
+//! })
+//! .notify_channel("webhook-stage")
+//! .batch_size(200);
+//! ```
+
+use async_trait::async_trait;
+use sqlx::{postgres::PgRow, Postgres, Row, Transaction};
+
+use crate::{
+    error::Error,
+    postgres_drain::{DrainResult, Drainer},
+};
+
+/// The number of rows drained in each transaction when the stage doesn't set a batch size.
+pub const DEFAULT_BATCH_SIZE: usize = 50;
+
+pub type StageRowMapper =
+    Box<dyn Fn(&PgRow) -> Result<DrainResult<'static>, anyhow::Error> + Send + Sync>;
+
+pub struct TableStage {
+    name: String,
+    table: String,
+    id_column: String,
+    order_by: Option<String>,
+    notify_channel: Option<String>,
+    lock_key: i64,
+    batch_size: usize,
+    mapper: StageRowMapper,
+}
+
+impl TableStage {
+    /// Create a stage that drains `table`, converting each row with `mapper`. The table and
+    /// column names are put into the queries as-is, so they must not come from user input.
+    pub fn new(
+        name: impl Into<String>,
+        table: impl Into<String>,
+        mapper: impl Fn(&PgRow) -> Result<DrainResult<'static>, anyhow::Error> + Send + Sync + 'static,
+    ) -> Self {
+        let table = table.into();
+        TableStage {
+            name: name.into(),
+            lock_key: fxhash::hash64(&table) as i64,
+            table,
+            id_column: "id".to_string(),
+            order_by: None,
+            notify_channel: None,
+            batch_size: DEFAULT_BATCH_SIZE,
+            mapper: Box::new(mapper),
+        }
+    }
+
+    /// The column that identifies each row. This must be a `bigint`. Defaults to `id`.
+    #[must_use]
+    pub fn id_column(mut self, id_column: impl Into<String>) -> Self {
+        self.id_column = id_column.into();
+        self
+    }
+
+    /// An `ORDER BY` clause for reading the rows. Defaults to the ID column.
+    #[must_use]
+    pub fn order_by(mut self, order_by: impl Into<String>) -> Self {
+        self.order_by = Some(order_by.into());
+        self
+    }
+
+    /// Drain the stage when a notification arrives on this channel, instead of polling it.
+    #[must_use]
+    pub fn notify_channel(mut self, notify_channel: impl Into<String>) -> Self {
+        self.notify_channel = Some(notify_channel.into());
+        self
+    }
+
+    /// The advisory lock that keeps more than one process from draining the stage at once.
+    /// Defaults to a hash of the table name.
+    #[must_use]
+    pub fn lock_key(mut self, lock_key: i64) -> Self {
+        self.lock_key = lock_key;
+        self
+    }
+
+    /// The most rows to drain in one transaction.
+    #[must_use]
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+}
+
+#[async_trait]
+impl Drainer for TableStage {
+    type Error = Error;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn notify_channel(&self) -> Option<String> {
+        self.notify_channel.clone()
+    }
+
+    fn lock_key(&self) -> i64 {
+        self.lock_key
+    }
+
+    async fn get(&'_ self, tx: &mut Transaction<Postgres>) -> Result<Vec<DrainResult<'_>>, Error> {
+        let query = format!(
+            "SELECT * FROM {table} ORDER BY {order_by} LIMIT {limit}",
+            table = self.table,
+            order_by = self.order_by.as_deref().unwrap_or(&self.id_column),
+            limit = self.batch_size
+        );
+        let rows = sqlx::query(&query).fetch_all(&mut *tx).await?;
+
+        if !rows.is_empty() {
+            let ids = rows
+                .iter()
+                .map(|row| row.try_get::<i64, _>(self.id_column.as_str()))
+                .collect::<Result<Vec<_>, _>>()?;
+            let delete = format!(
+                "DELETE FROM {} WHERE {} = ANY($1)",
+                self.table, self.id_column
+            );
+            sqlx::query(&delete).bind(&ids).execute(&mut *tx).await?;
+        }
+
+        rows.iter()
+            .map(|row| (self.mapper)(row).map_err(Error::DrainError))
+            .collect()
+    }
+}
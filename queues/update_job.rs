@@ -1,5 +1,5 @@
 use chrono::{DateTime, Utc};
-use deadpool_redis::Connection;
+use ergo_database::redis::RedisConnection;
 use lazy_static::lazy_static;
 
 use super::Queue;
@@ -9,11 +9,13 @@ use crate::Error;
 //  1. pending items list
 //  2. scheduled items list
 //  3. job data key
+//  4. priority items list
 // ARGV:
 //  1. Job ID
 //  2. Optional new time to run
 //  3. Optional new payload
-const UPDATE_JOB_SCRIPT: &str = r##"
+//  4. fairness list prefix
+pub(crate) const UPDATE_JOB_SCRIPT: &str = r##"
     local is_scheduled = redis.call("ZSCORE", KEYS[2], ARGV[1])
     local is_pending = false
     local updates_time = string.len(ARGV[2]) > 0
@@ -21,12 +23,30 @@ const UPDATE_JOB_SCRIPT: &str = r##"
     -- Items being updated will usually be in the scheduled list, and accessing the pending list is O(N), so
     -- look up in the pending list only if we have to, and combine with the removal operation if appropriate.
     if is_scheduled == false then
+        local lists = { KEYS[1], KEYS[4] }
+        local fairness_key = redis.call("HGET", KEYS[3], "fk")
+        if fairness_key then
+            table.insert(lists, 1, ARGV[4] .. fairness_key)
+        end
+
+        for _, list in ipairs(lists) do
+            if is_pending == false then
+                if updates_time then
+                    -- If we're updating the scheduled time then we unconditionally move the item to the scheduled list,
+                    -- so remove it here.
+                    is_pending = redis.call("LREM", list, 1, ARGV[1]) > 0
+                else
+                    is_pending = redis.call("LPOS", list, ARGV[1]) ~= false
+                end
+            end
+        end
+    end
+
+    -- In streams mode, a job waiting in the stream is marked ready until a worker takes it.
+    if is_pending == false and is_scheduled == false and redis.call("HEXISTS", KEYS[3], "rdy") == 1 then
+        is_pending = true
         if updates_time then
-            -- If we're updating the scheduled time then we unconditionally move the item to the scheduled list,
-            -- so remove it here.
-            is_pending = redis.call("LREM", KEYS[1], 1, ARGV[1]) > 0
-        else
-            is_pending = redis.call("LPOS", KEYS[1], ARGV[1]) ~= false
+            redis.call("HDEL", KEYS[3], "rdy")
         end
     end
 
@@ -63,7 +83,7 @@ impl UpdateJobScript {
     pub async fn run(
         &self,
         queue: &Queue,
-        conn: &mut Connection,
+        conn: &mut RedisConnection,
         job_id: &str,
         job_data_key: &str,
         new_time: Option<DateTime<Utc>>,
@@ -74,6 +94,7 @@ impl UpdateJobScript {
             .key(&queue.0.pending_list)
             .key(&queue.0.scheduled_list)
             .key(job_data_key)
+            .key(&queue.0.priority_list)
             .arg(job_id)
             .arg(
                 new_time
@@ -81,6 +102,7 @@ impl UpdateJobScript {
                     .unwrap_or_else(String::new), // Send an empty string if it's None
             )
             .arg(new_payload.unwrap_or(&[]))
+            .arg(&queue.0.fair_list_prefix)
             .invoke_async(&mut **conn)
             .await?;
 
@@ -1,18 +1,29 @@
-use super::Queue;
+use super::{ErrorClass, JobProgress, Queue};
 use crate::error::Error;
 use anyhow::anyhow;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeZone, Utc};
 use serde::de::DeserializeOwned;
-use std::future::Future;
+use std::{
+    future::Future,
+    sync::atomic::{AtomicI64, Ordering},
+    time::Duration,
+};
+use tracing::{event, Level};
 
 #[derive(Debug)]
 pub struct QueueWorkItem<T: Send + Sync> {
     queue: Queue,
     pub id: String,
     pub(crate) data: Option<T>,
-    pub expires: DateTime<Utc>,
+    /// The processing deadline, in milliseconds. This also identifies our claim on the job, and
+    /// moves forward on each heartbeat.
+    expires: AtomicI64,
+    /// How far each heartbeat pushes the deadline.
+    timeout: chrono::Duration,
     pub current_retry: usize,
     pub max_retries: usize,
+    /// The fairness key that the job was enqueued with.
+    pub fairness_key: Option<String>,
 
     finished: bool,
 }
@@ -47,6 +58,7 @@ impl<T: DeserializeOwned + Send + Sync> QueueWorkItem<T> {
         queue: Queue,
         job_id: &str,
         expires: DateTime<Utc>,
+        timeout: chrono::Duration,
         current_retry: usize,
         max_retries: usize,
         data: Vec<u8>,
@@ -56,10 +68,12 @@ impl<T: DeserializeOwned + Send + Sync> QueueWorkItem<T> {
             queue,
             id: String::from(job_id),
             data: Some(converted),
-            expires,
+            expires: AtomicI64::new(expires.timestamp_millis()),
+            timeout,
             finished: false,
             current_retry,
             max_retries,
+            fairness_key: None,
         })
     }
 }
@@ -72,17 +86,43 @@ impl<'a, T: Send + Sync> QueueWorkItem<T> {
         T: Send,
         E: 'static + std::error::Error + Send + Sync,
         R: 'static,
+    {
+        self.process_with_classifier(f, |_| ErrorClass::Retryable)
+            .await
+    }
+
+    /// Process the job, using `classify` to decide if a failure should be retried.
+    pub async fn process_with_classifier<F, Fut, C, R, E>(
+        &'a mut self,
+        f: F,
+        classify: C,
+    ) -> Result<R, Error>
+    where
+        F: FnOnce(&'a Self, T) -> Fut,
+        Fut: Future<Output = Result<R, E>>,
+        C: FnOnce(&E) -> ErrorClass,
+        T: Send,
+        E: 'static + std::error::Error + Send + Sync,
+        R: 'static,
     {
         let payload = self.data.take().unwrap();
         match f(self, payload).await {
             Ok(val) => {
-                self.queue.done_job(self.id.as_str(), &self.expires).await?;
+                self.queue
+                    .done_job(self.id.as_str(), &self.expires())
+                    .await?;
                 Ok(val)
             }
             Err(e) => {
+                let error_class = classify(&e);
                 let e = anyhow!(e);
                 self.queue
-                    .errored_job(self.id.as_str(), &self.expires, e.to_string().as_str())
+                    .errored_job(
+                        self.id.as_str(),
+                        &self.expires(),
+                        e.to_string().as_str(),
+                        error_class,
+                    )
                     .await?;
                 Err(Error::JobError(e))
             }
@@ -93,11 +133,67 @@ impl<'a, T: Send + Sync> QueueWorkItem<T> {
     /// that may want to cancel.
     pub async fn active(&self) -> Result<bool, Error> {
         match self.queue.job_expires_at(&self.id).await? {
-            Some(e) => Ok(e == self.expires),
+            Some(e) => Ok(e == self.expires()),
             None => Ok(false),
         }
     }
 
+    /// The time at which the queue will consider this job to have timed out.
+    pub fn expires(&self) -> DateTime<Utc> {
+        Utc.timestamp_millis(self.expires.load(Ordering::Relaxed))
+    }
+
+    /// Push back the processing deadline by the job's timeout, so that a long-running job isn't
+    /// retried while it's still running. Returns false if the job already timed out.
+    pub async fn heartbeat(&self) -> Result<bool, Error> {
+        let expected = self.expires();
+        let new_expiration = std::cmp::max(Utc::now() + self.timeout, expected);
+
+        let extended = self
+            .queue
+            .heartbeat_job(&self.id, &expected, &new_expiration)
+            .await?;
+        if extended {
+            self.expires
+                .store(new_expiration.timestamp_millis(), Ordering::Relaxed);
+        }
+
+        Ok(extended)
+    }
+
+    /// Record how far along the job is, so that other processes can check on it with
+    /// [Queue::job_info]. Returns false if the job already timed out.
+    pub async fn report_progress(&self, progress: &JobProgress) -> Result<bool, Error> {
+        self.queue
+            .set_job_progress(&self.id, &self.expires(), progress)
+            .await
+    }
+
+    /// Run `fut`, sending a heartbeat for the job every `interval` until it finishes.
+    pub async fn with_heartbeat<F: Future>(&self, interval: Duration, fut: F) -> F::Output {
+        tokio::pin!(fut);
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        let mut beating = true;
+
+        loop {
+            tokio::select! {
+                output = &mut fut => return output,
+                _ = ticker.tick(), if beating => {
+                    match self.heartbeat().await {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            event!(Level::WARN, job=%self.id, queue=%self.queue.name(), "Job timed out before its heartbeat");
+                            beating = false;
+                        }
+                        Err(e) => {
+                            event!(Level::ERROR, job=%self.id, queue=%self.queue.name(), error=?e, "Failed to send job heartbeat");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
     pub fn is_final_retry(&self) -> bool {
         self.current_retry >= self.max_retries
     }
@@ -0,0 +1,335 @@
+//! A registry of the workers running each queue's dequeuer loop.
+//!
+//! While its dequeuer loop runs, a worker sends a heartbeat to Redis every
+//! [WORKER_HEARTBEAT_INTERVAL] with its hostname and the jobs it is running. When a worker stops
+//! sending heartbeats for [WORKER_DEAD_AFTER], the instance that runs the scheduled jobs checker
+//! treats it as dead and retries its jobs right away, instead of waiting for each job to pass its
+//! processing deadline, which for long jobs can be many minutes.
+//!
+//! In streams mode, the jobs of a dead worker are claimed from the consumer group as usual, and
+//! the reaper only removes the worker from the registry.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, TimeZone, Utc};
+use ergo_database::redis::RedisConnection;
+use fxhash::FxHashSet;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use tokio::task::JoinHandle;
+use tracing::{event, Level};
+
+use crate::{error::Error, Queue, QueueMode};
+
+lazy_static! {
+    /// How often each dequeuer loop records that it is alive. Set with
+    /// `QUEUE_WORKER_HEARTBEAT_MS`.
+    pub static ref WORKER_HEARTBEAT_INTERVAL: Duration = std::env::var("QUEUE_WORKER_HEARTBEAT_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_millis)
+        .unwrap_or_else(|| Duration::from_millis(5000));
+
+    /// A worker that hasn't sent a heartbeat for this long is considered dead. Set with
+    /// `QUEUE_WORKER_DEAD_MS`.
+    pub static ref WORKER_DEAD_AFTER: Duration = std::env::var("QUEUE_WORKER_DEAD_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_millis)
+        .unwrap_or_else(|| Duration::from_millis(30000));
+
+    static ref HOSTNAME: String = std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| {
+            std::fs::read_to_string("/etc/hostname")
+                .ok()
+                .map(|h| h.trim().to_string())
+        })
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+}
+
+// Retry the running jobs of the workers that stopped sending heartbeats, and remove those
+// workers from the registry. A job is only retried if it is still running and was last started
+// by the dead worker, since the list of jobs in the registry may be slightly out of date.
+// KEYS:
+//  1. workers set
+//  2. processing list
+//  3. scheduled items list
+//  4. done items list
+//  5. stats hash
+// ARGS:
+//  1. current time
+//  2. heartbeats older than this are dead
+//  3. worker data prefix
+//  4. job data prefix
+//  5. "1" to retry jobs, "0" to only clean up the registry
+pub(crate) const REAP_SCRIPT: &str = r##"
+    local dead = redis.call("ZRANGEBYSCORE", KEYS[1], 0, ARGV[2], "LIMIT", 0, 100)
+    local retried = 0
+    for _, worker in ipairs(dead) do
+        local worker_key = ARGV[3] .. worker
+        local jobs_key = worker_key .. ":jobs"
+        if ARGV[5] == "1" then
+            local jobs = redis.call("SMEMBERS", jobs_key)
+            for _, id in ipairs(jobs) do
+                local job_key = ARGV[4] .. id
+                if redis.call("ZSCORE", KEYS[2], id) and redis.call("HGET", job_key, "wk") == worker then
+                    redis.call("ZREM", KEYS[2], id)
+                    retried = retried + 1
+
+                    local retries = redis.call("HMGET", job_key, "cr", "mr")
+                    local retry = tonumber(retries[1]) or 0
+                    local max_retries = tonumber(retries[2]) or 0
+                    redis.call("HINCRBY", KEYS[5], "errored", 1)
+                    if retry >= max_retries then
+                        redis.call("HSET", job_key, "err", "worker stopped responding", "ec", "retryable", "end", ARGV[1], "suc", "false")
+                        redis.call("LPUSH", KEYS[4], id)
+                        redis.call("HINCRBY", KEYS[5], "failed", 1)
+                    else
+                        redis.call("HSET", job_key, "err", "worker stopped responding", "ec", "retryable", "cr", retry + 1, "ra", ARGV[1])
+                        redis.call("ZADD", KEYS[3], ARGV[1], id)
+                    end
+                end
+            end
+        end
+
+        redis.call("DEL", worker_key, jobs_key)
+        redis.call("ZREM", KEYS[1], worker)
+    end
+
+    return {#dead, retried}
+"##;
+
+lazy_static! {
+    static ref SCRIPT: redis::Script = redis::Script::new(REAP_SCRIPT);
+}
+
+pub struct ReapWorkersScript(&'static redis::Script);
+
+impl ReapWorkersScript {
+    pub fn new() -> Self {
+        ReapWorkersScript(&SCRIPT)
+    }
+
+    /// Returns the number of dead workers and the number of jobs that were retried.
+    pub async fn run(
+        &self,
+        queue: &Queue,
+        conn: &mut RedisConnection,
+        now: &DateTime<Utc>,
+    ) -> Result<(usize, usize), Error> {
+        let dead_before = *now - chrono::Duration::from_std(*WORKER_DEAD_AFTER).unwrap();
+        let retry_jobs = queue.0.mode == QueueMode::Lists;
+        let result: (usize, usize) = self
+            .0
+            .key(&queue.0.workers_set)
+            .key(&queue.0.processing_list)
+            .key(&queue.0.scheduled_list)
+            .key(&queue.0.done_list)
+            .key(&queue.0.stats_hash)
+            .arg(now.timestamp_millis())
+            .arg(dead_before.timestamp_millis())
+            .arg(&queue.0.worker_prefix)
+            .arg(&queue.0.job_data_prefix)
+            .arg(if retry_jobs { "1" } else { "0" })
+            .invoke_async(&mut **conn)
+            .await?;
+
+        Ok(result)
+    }
+}
+
+/// A worker in a queue's registry.
+#[derive(Debug, Serialize)]
+pub struct WorkerInfo {
+    pub id: String,
+    pub hostname: String,
+    pub pid: u32,
+    pub started: Option<DateTime<Utc>>,
+    pub last_heartbeat: DateTime<Utc>,
+    /// The jobs that the worker was running at its last heartbeat.
+    pub jobs: Vec<String>,
+}
+
+impl WorkerInfo {
+    /// True if the worker has missed enough heartbeats to be considered dead.
+    pub fn is_dead(&self) -> bool {
+        (Utc::now() - self.last_heartbeat)
+            .to_std()
+            .map(|since| since > *WORKER_DEAD_AFTER)
+            .unwrap_or(false)
+    }
+}
+
+/// The IDs of the jobs that a dequeuer loop is running.
+#[derive(Clone, Default)]
+pub(crate) struct InFlightJobs(Arc<Mutex<FxHashSet<String>>>);
+
+impl InFlightJobs {
+    /// Track a job until the returned guard is dropped.
+    pub fn start(&self, id: &str) -> InFlightJob {
+        self.0.lock().unwrap().insert(id.to_string());
+        InFlightJob {
+            jobs: self.clone(),
+            id: id.to_string(),
+        }
+    }
+
+    fn ids(&self) -> Vec<String> {
+        self.0.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+pub(crate) struct InFlightJob {
+    jobs: InFlightJobs,
+    id: String,
+}
+
+impl Drop for InFlightJob {
+    fn drop(&mut self) {
+        self.jobs.0.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Send worker heartbeats for the queue until the returned task is aborted.
+pub(crate) fn start_worker_heartbeat(queue: Queue, jobs: InFlightJobs) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let started = Utc::now();
+        let mut interval = tokio::time::interval(*WORKER_HEARTBEAT_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = queue.worker_heartbeat(&started, &jobs).await {
+                event!(Level::WARN, queue=%queue.0.name, error=%e, "Error sending worker heartbeat");
+            }
+        }
+    })
+}
+
+impl Queue {
+    fn worker_key(&self, worker_id: &str) -> String {
+        format!("{}{}", self.0.worker_prefix, worker_id)
+    }
+
+    /// Record that this instance's dequeuer loop is alive, along with the jobs it is running.
+    pub(crate) async fn worker_heartbeat(
+        &self,
+        started: &DateTime<Utc>,
+        jobs: &InFlightJobs,
+    ) -> Result<(), Error> {
+        let mut conn = self.0.pool.get().await?;
+        let now = Utc::now();
+        let worker_key = self.worker_key(&self.0.instance_id);
+        let jobs_key = format!("{}:jobs", worker_key);
+        let jobs = jobs.ids();
+        // Keep the keys around for a while after the worker is dead, in case no instance is
+        // running the reaper.
+        let ttl = (WORKER_DEAD_AFTER.as_millis() * 10) as usize;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .zadd(
+                &self.0.workers_set,
+                &self.0.instance_id,
+                now.timestamp_millis(),
+            )
+            .ignore()
+            .hset_multiple(
+                &worker_key,
+                &[
+                    ("host", HOSTNAME.clone()),
+                    ("pid", std::process::id().to_string()),
+                    ("st", started.timestamp_millis().to_string()),
+                    ("hb", now.timestamp_millis().to_string()),
+                ],
+            )
+            .ignore()
+            .pexpire(&worker_key, ttl)
+            .ignore()
+            .del(&jobs_key)
+            .ignore();
+        if !jobs.is_empty() {
+            pipe.sadd(&jobs_key, &jobs)
+                .ignore()
+                .pexpire(&jobs_key, ttl)
+                .ignore();
+        }
+
+        pipe.query_async::<_, ()>(&mut conn).await?;
+        Ok(())
+    }
+
+    /// Remove this instance from the worker registry when its dequeuer loop stops.
+    pub(crate) async fn deregister_worker(&self) -> Result<(), Error> {
+        let mut conn = self.0.pool.get().await?;
+        let worker_key = self.worker_key(&self.0.instance_id);
+        redis::pipe()
+            .atomic()
+            .zrem(&self.0.workers_set, &self.0.instance_id)
+            .ignore()
+            .del(&[format!("{}:jobs", worker_key), worker_key])
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Remove the workers that stopped sending heartbeats from the registry, and retry the jobs
+    /// they were running. Returns the number of jobs that were retried.
+    pub async fn reap_dead_workers(&self) -> Result<usize, Error> {
+        let mut conn = self.0.pool.get().await?;
+        let (dead, retried) = self
+            .0
+            .reap_workers_script
+            .run(self, &mut conn, &Utc::now())
+            .await?;
+        if dead > 0 {
+            event!(Level::WARN, queue=%self.0.name, workers=dead, jobs=retried, "Removed dead workers");
+        }
+
+        Ok(retried)
+    }
+
+    /// List the workers in the registry, including the dead ones that haven't been removed yet.
+    pub async fn list_workers(&self) -> Result<Vec<WorkerInfo>, Error> {
+        let mut conn = self.0.pool.get().await?;
+        let workers: Vec<(String, i64)> = redis::cmd("ZRANGE")
+            .arg(&self.0.workers_set)
+            .arg(0)
+            .arg(-1)
+            .arg("WITHSCORES")
+            .query_async(&mut conn)
+            .await?;
+
+        let mut output = Vec::with_capacity(workers.len());
+        for (id, last_heartbeat) in workers {
+            let worker_key = self.worker_key(&id);
+            let (data, jobs): (HashMap<String, String>, Vec<String>) = redis::pipe()
+                .hgetall(&worker_key)
+                .smembers(format!("{}:jobs", worker_key))
+                .query_async(&mut conn)
+                .await?;
+
+            output.push(WorkerInfo {
+                hostname: data.get("host").cloned().unwrap_or_default(),
+                pid: data
+                    .get("pid")
+                    .and_then(|p| p.parse().ok())
+                    .unwrap_or_default(),
+                started: data
+                    .get("st")
+                    .and_then(|t| t.parse().ok())
+                    .map(|t| Utc.timestamp_millis(t)),
+                last_heartbeat: Utc.timestamp_millis(last_heartbeat),
+                jobs,
+                id,
+            });
+        }
+
+        Ok(output)
+    }
+}
@@ -27,4 +27,25 @@ pub struct AccountPublicInfo {
     pub account_id: AccountId,
     pub account_type_id: String,
     pub name: String,
+    pub max_concurrency: Option<i32>,
+    pub requests_per_minute: Option<i32>,
+}
+
+/// Limits on the actions that use an account. Actions over the limit wait in the queue until
+/// they can run.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct AccountLimits {
+    /// The most actions using the account that can run at once.
+    pub max_concurrency: Option<i32>,
+    /// The most actions using the account that can start in a minute.
+    pub requests_per_minute: Option<i32>,
+}
+
+impl AccountLimits {
+    pub fn rate_limit(&self) -> ergo_queues::rate_limit::RateLimit {
+        ergo_queues::rate_limit::RateLimit {
+            max_concurrency: self.max_concurrency.and_then(|n| u32::try_from(n).ok()),
+            per_minute: self.requests_per_minute.and_then(|n| u32::try_from(n).ok()),
+        }
+    }
 }
@@ -0,0 +1,236 @@
+#[cfg(not(target_family = "wasm"))]
+use super::{
+    delay_executor::parse_duration,
+    execute::{ExecutorError, ExecutorState},
+    send_input_executor::{send_input, TargetTask},
+};
+use super::{
+    execute::Executor,
+    template::{TemplateField, TemplateFieldFormat, TemplateFields},
+};
+#[cfg(not(target_family = "wasm"))]
+use crate::approvals::{
+    approval_link, create_approval, ApprovalDecision, ApprovalStatus, NewApproval,
+};
+
+use async_trait::async_trait;
+#[cfg(not(target_family = "wasm"))]
+use chrono::{Duration, Utc};
+#[cfg(not(target_family = "wasm"))]
+use ergo_database::object_id::OrgId;
+#[cfg(not(target_family = "wasm"))]
+use ergo_notifications::{Notification, NotifyEvent};
+#[cfg(not(target_family = "wasm"))]
+use sqlx::Connection;
+#[cfg(not(target_family = "wasm"))]
+use uuid::Uuid;
+
+static FIELD_TRIGGER: TemplateField = TemplateField::from_static(
+    "trigger_name",
+    TemplateFieldFormat::string_without_default(),
+    false,
+    "The local ID of the trigger to send the decision to",
+);
+
+static FIELD_MESSAGE: TemplateField = TemplateField::from_static(
+    "message",
+    TemplateFieldFormat::string_without_default(),
+    true,
+    "What the approvers are being asked to approve",
+);
+
+static FIELD_EXPIRES_IN: TemplateField = TemplateField::from_static(
+    "expires_in",
+    TemplateFieldFormat::string_without_default(),
+    true,
+    "How long to wait for a decision, such as `30m` or `2d`. Defaults to one day",
+);
+
+static FIELD_PAYLOAD: TemplateField = TemplateField::from_static(
+    "payload",
+    TemplateFieldFormat::object_without_default(true),
+    true,
+    "Data to pass along to the trigger with the decision",
+);
+
+/// Pause the task until someone approves or denies a request. This sends an
+/// `approval_requested` notification with links to approve or deny, and the decision goes to
+/// the task's trigger as an input. If nobody decides in time, the trigger gets an `expired`
+/// decision instead.
+#[derive(Debug)]
+pub struct ApprovalExecutor {
+    template_fields: TemplateFields,
+}
+
+impl ApprovalExecutor {
+    pub fn new() -> ApprovalExecutor {
+        let template_fields = [
+            &FIELD_TRIGGER,
+            &FIELD_MESSAGE,
+            &FIELD_EXPIRES_IN,
+            &FIELD_PAYLOAD,
+        ]
+        .into();
+        ApprovalExecutor { template_fields }
+    }
+}
+
+#[async_trait]
+impl Executor for ApprovalExecutor {
+    #[cfg(not(target_family = "wasm"))]
+    async fn execute(
+        &self,
+        mut state: ExecutorState,
+        template_values: fxhash::FxHashMap<String, serde_json::Value>,
+    ) -> Result<serde_json::Value, ExecutorError> {
+        let trigger_name = FIELD_TRIGGER.extract_str(&template_values)?;
+        let message = FIELD_MESSAGE.extract_str(&template_values)?;
+        let expires_in_arg = FIELD_EXPIRES_IN.extract_str(&template_values)?;
+        let expires_in = if expires_in_arg.is_empty() {
+            Duration::days(1)
+        } else {
+            parse_duration(expires_in_arg.as_ref()).ok_or_else(|| {
+                ExecutorError::FieldFormatError {
+                    field: FIELD_EXPIRES_IN.name.to_string(),
+                    subfield: None,
+                    expected: "a duration such as 30m, 12h, or 2d".to_string(),
+                }
+            })?
+        };
+        let payload = FIELD_PAYLOAD.extract_object(&template_values)?.into_owned();
+
+        let pg_pool = state
+            .pg_pool
+            .clone()
+            .ok_or(ExecutorError::MissingDatabase)?;
+        let mut conn = pg_pool
+            .acquire()
+            .await
+            .map_err(ExecutorError::command_error_without_result)?;
+
+        let task = sqlx::query!(
+            r##"SELECT org_id AS "org_id: OrgId", name FROM tasks WHERE task_id=$1"##,
+            state.task_id.0
+        )
+        .fetch_one(&mut conn)
+        .await
+        .map_err(ExecutorError::command_error_without_result)?;
+
+        let approval_id = Uuid::new_v4();
+        let expires = Utc::now() + expires_in;
+        let token = create_approval(
+            &mut conn,
+            NewApproval {
+                approval_id,
+                org_id: &task.org_id,
+                task_id: &state.task_id,
+                task_trigger_local_id: trigger_name.as_ref(),
+                actions_log_id: state.actions_log_id,
+                user_id: &state.user_id,
+                message: message.as_ref(),
+                payload: &payload,
+                expires,
+            },
+        )
+        .await
+        .map_err(ExecutorError::command_error_without_result)?;
+
+        // The decision is a continuation of this run, like a delay.
+        if state.chain.tasks.last() == Some(&state.task_id) {
+            state.chain.tasks.pop();
+        }
+
+        let expiry = ApprovalDecision {
+            approval_id,
+            decision: ApprovalStatus::Expired,
+            comment: None,
+            decided_by: None,
+            payload: payload.clone(),
+        };
+        let expiry_payload =
+            serde_json::to_value(&expiry).map_err(ExecutorError::command_error_without_result)?;
+        let notifications = state.notifications.clone();
+        let expiry_result = send_input(
+            state.clone(),
+            TargetTask::Id(state.task_id),
+            trigger_name.as_ref(),
+            expiry_payload,
+            Some(expires),
+        )
+        .await;
+
+        let expiry_inputs_log_id = match expiry_result {
+            Ok(id) => id,
+            Err(e) => {
+                // Without the expiry input the task could wait forever, so close the approval.
+                sqlx::query!(
+                    "UPDATE approvals SET status='expired' WHERE approval_id=$1",
+                    approval_id
+                )
+                .execute(&mut conn)
+                .await
+                .map_err(ExecutorError::command_error_without_result)?;
+                return Err(e);
+            }
+        };
+
+        let mut tx = conn
+            .begin()
+            .await
+            .map_err(ExecutorError::command_error_without_result)?;
+        sqlx::query!(
+            "UPDATE approvals SET expiry_inputs_log_id=$2 WHERE approval_id=$1",
+            approval_id,
+            expiry_inputs_log_id
+        )
+        .execute(&mut tx)
+        .await
+        .map_err(ExecutorError::command_error_without_result)?;
+
+        let approve_url = approval_link(&approval_id, &token, ApprovalStatus::Approved);
+        let deny_url = approval_link(&approval_id, &token, ApprovalStatus::Denied);
+        if let Some(notifications) = notifications {
+            let notification = Notification {
+                event: NotifyEvent::ApprovalRequested,
+                task_id: state.task_id,
+                task_name: task.name,
+                local_id: trigger_name.to_string(),
+                local_object_name: message.to_string(),
+                local_object_id: None,
+                payload: Some(serde_json::json!({
+                    "message": message,
+                    "approve_url": approve_url,
+                    "deny_url": deny_url,
+                    "expires": expires,
+                    "payload": payload,
+                })),
+                error: None,
+                log_id: state.actions_log_id,
+                suppressed_count: None,
+            };
+            notifications
+                .notify(&mut tx, &task.org_id.0, notification)
+                .await
+                .map_err(ExecutorError::command_error_without_result)?;
+        }
+
+        tx.commit()
+            .await
+            .map_err(ExecutorError::command_error_without_result)?;
+
+        // The links carry the token, so they go out in the notification but aren't kept in the
+        // action's result.
+        Ok(serde_json::json!({
+            "approval_id": approval_id,
+            "expires": expires,
+        }))
+    }
+
+    fn name(&self) -> &'static str {
+        "approval"
+    }
+
+    fn template_fields(&self) -> &TemplateFields {
+        &self.template_fields
+    }
+}
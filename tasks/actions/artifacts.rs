@@ -0,0 +1,229 @@
+//! Large action outputs, such as command output or HTTP response bodies, are moved out of the
+//! action log into the `action_artifacts` table. The value in the result is replaced with a
+//! pointer object of the form `{ "$artifact": { "artifact_id", "size", "content_type" } }`.
+
+use chrono::{Duration, Utc};
+use ergo_database::{object_id::TaskId, PostgresPool};
+use ergo_graceful_shutdown::GracefulShutdownConsumer;
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use sqlx::PgConnection;
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use crate::error::Error;
+
+#[derive(Debug, Clone)]
+pub struct ArtifactConfig {
+    /// Values whose serialized size is larger than this are stored as artifacts.
+    pub threshold_bytes: usize,
+    /// How long to keep artifacts before deleting them.
+    pub retention: Duration,
+}
+
+impl ArtifactConfig {
+    /// Read the configuration from `ARTIFACT_THRESHOLD_BYTES` and `ARTIFACT_RETENTION_DAYS`.
+    pub fn from_env() -> Self {
+        fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        }
+
+        ArtifactConfig {
+            threshold_bytes: env_or("ARTIFACT_THRESHOLD_BYTES", 64 * 1024),
+            retention: Duration::days(env_or("ARTIFACT_RETENTION_DAYS", 30)),
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref ARTIFACT_CONFIG: ArtifactConfig = ArtifactConfig::from_env();
+}
+
+#[derive(Debug)]
+pub struct PendingArtifact {
+    pub artifact_id: Uuid,
+    pub path: String,
+    pub content_type: &'static str,
+    pub data: Vec<u8>,
+}
+
+fn escape_pointer_segment(s: &str) -> String {
+    s.replace('~', "~0").replace('/', "~1")
+}
+
+fn serialized_size(value: &Value) -> usize {
+    match value {
+        Value::String(s) => s.len(),
+        _ => serde_json::to_vec(value).map(|v| v.len()).unwrap_or(0),
+    }
+}
+
+fn take_artifact(value: &mut Value, path: &str, artifacts: &mut Vec<PendingArtifact>) {
+    let (content_type, data) = match value.take() {
+        Value::String(s) => ("text/plain; charset=utf-8", s.into_bytes()),
+        v => (
+            "application/json",
+            serde_json::to_vec(&v).unwrap_or_default(),
+        ),
+    };
+
+    let artifact_id = Uuid::new_v4();
+    *value = json!({
+        "$artifact": {
+            "artifact_id": artifact_id,
+            "size": data.len(),
+            "content_type": content_type,
+        }
+    });
+
+    artifacts.push(PendingArtifact {
+        artifact_id,
+        path: path.to_string(),
+        content_type,
+        data,
+    });
+}
+
+fn extract(value: &mut Value, path: &str, threshold: usize, artifacts: &mut Vec<PendingArtifact>) {
+    if serialized_size(value) <= threshold {
+        return;
+    }
+
+    let previous_count = artifacts.len();
+    match value {
+        Value::Object(map) => {
+            for (key, child) in map.iter_mut() {
+                let child_path = format!("{}/{}", path, escape_pointer_segment(key));
+                extract(child, &child_path, threshold, artifacts);
+            }
+        }
+        Value::Array(items) => {
+            for (i, child) in items.iter_mut().enumerate() {
+                extract(child, &format!("{}/{}", path, i), threshold, artifacts);
+            }
+        }
+        _ => {}
+    }
+
+    // If the children were all small enough on their own but still add up to more than the
+    // threshold, store the whole value instead.
+    if artifacts.len() == previous_count {
+        take_artifact(value, path, artifacts);
+    }
+}
+
+/// Replace values in `result` that are larger than the threshold with artifact pointers,
+/// returning the removed values.
+pub fn extract_artifacts(result: &mut Value, threshold: usize) -> Vec<PendingArtifact> {
+    let mut artifacts = Vec::new();
+    extract(result, "", threshold, &mut artifacts);
+    artifacts
+}
+
+/// Move large values out of an action result and save them as artifacts.
+pub async fn store_artifacts(
+    tx: &mut PgConnection,
+    actions_log_id: Uuid,
+    task_id: &TaskId,
+    result: &mut Value,
+    config: &ArtifactConfig,
+) -> Result<usize, Error> {
+    let artifacts = extract_artifacts(result, config.threshold_bytes);
+    let expires = Utc::now() + config.retention;
+
+    for artifact in &artifacts {
+        sqlx::query!(
+            r##"INSERT INTO action_artifacts
+            (artifact_id, actions_log_id, task_id, path, content_type, size, data, expires)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)"##,
+            artifact.artifact_id,
+            actions_log_id,
+            task_id.0,
+            artifact.path,
+            artifact.content_type,
+            artifact.data.len() as i64,
+            artifact.data,
+            expires
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    Ok(artifacts.len())
+}
+
+pub async fn delete_expired_artifacts(pool: &PostgresPool) -> Result<u64, Error> {
+    let result = sqlx::query!("DELETE FROM action_artifacts WHERE expires < now()")
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+/// Periodically delete artifacts that are past their retention period.
+pub fn start_artifact_cleanup(
+    mut shutdown: GracefulShutdownConsumer,
+    pool: PostgresPool,
+    check_interval: Option<std::time::Duration>,
+) -> tokio::task::JoinHandle<()> {
+    let check_interval = check_interval.unwrap_or_else(|| std::time::Duration::from_secs(60 * 60));
+    tokio::spawn(async move {
+        loop {
+            match delete_expired_artifacts(&pool).await {
+                Ok(0) => {}
+                Ok(count) => event!(Level::INFO, %count, "Deleted expired artifacts"),
+                Err(e) => event!(Level::ERROR, error=%e, "Failed to delete expired artifacts"),
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(check_interval) => continue,
+                _ = shutdown.wait_for_shutdown() => break,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_result_unchanged() {
+        let mut result = json!({ "output": { "stdout": "abc", "exitcode": 0 } });
+        let original = result.clone();
+        let artifacts = extract_artifacts(&mut result, 100);
+        assert!(artifacts.is_empty());
+        assert_eq!(result, original);
+    }
+
+    #[test]
+    fn large_string_becomes_artifact() {
+        let stdout = "x".repeat(200);
+        let mut result = json!({ "output": { "stdout": stdout, "exitcode": 0 } });
+        let artifacts = extract_artifacts(&mut result, 100);
+
+        assert_eq!(artifacts.len(), 1);
+        assert_eq!(artifacts[0].path, "/output/stdout");
+        assert_eq!(artifacts[0].data, stdout.as_bytes());
+        assert_eq!(
+            result["output"]["stdout"]["$artifact"]["artifact_id"],
+            json!(artifacts[0].artifact_id)
+        );
+        assert_eq!(result["output"]["exitcode"], json!(0));
+    }
+
+    #[test]
+    fn many_small_values_stored_together() {
+        let items = (0..50).map(|i| json!(i)).collect::<Vec<_>>();
+        let mut result = json!({ "output": { "items": items } });
+        let artifacts = extract_artifacts(&mut result, 100);
+
+        assert_eq!(artifacts.len(), 1);
+        assert_eq!(artifacts[0].path, "/output/items");
+        assert_eq!(artifacts[0].content_type, "application/json");
+        let stored: Value = serde_json::from_slice(&artifacts[0].data).unwrap();
+        assert_eq!(stored, json!(items));
+    }
+}
@@ -0,0 +1,154 @@
+//! Compensation for tasks that run a sequence of actions, such as provisioning steps that each
+//! create something. A task action can name another task action that undoes it. When an action
+//! fails permanently, the actions from the same input run that already succeeded are undone one at
+//! a time, starting with the one that finished most recently. Each compensation is enqueued once
+//! the previous one has finished, so they never run at the same time.
+//!
+//! A compensation runs with the original action's payload, plus the original action's output in
+//! the `original_output` field. Both come from the actions log, where secret values have been
+//! redacted, so a compensation that needs a credential should get it from its account.
+
+use ergo_database::{new_uuid, PostgresPool};
+use serde_json::Value;
+use smallvec::smallvec;
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use super::{enqueue_actions, ActionInvocation, ActionInvocations};
+use crate::error::Error;
+
+/// The field of a compensation's payload that holds the original action's output.
+pub const ORIGINAL_OUTPUT_FIELD: &str = "original_output";
+
+fn compensation_payload(payload: Value, result: Option<Value>) -> Value {
+    let output = result
+        .and_then(|mut r| r.get_mut("output").map(Value::take))
+        .unwrap_or(Value::Null);
+
+    let mut fields = match payload {
+        Value::Object(fields) => fields,
+        _ => serde_json::Map::new(),
+    };
+    fields.insert(ORIGINAL_OUTPUT_FIELD.to_string(), output);
+    Value::Object(fields)
+}
+
+/// Enqueue the compensation for the next action to undo, which is the most recent successful
+/// action from the same input run that finished before the `before` action. `before` is the
+/// action that failed when starting the chain, or the action that was just compensated when
+/// continuing it.
+///
+/// Returns the actions log ID of the compensation, or `None` if there is nothing left to undo.
+pub async fn enqueue_next_compensation(
+    pg: &PostgresPool,
+    redis_key_prefix: &Option<String>,
+    invocation: &ActionInvocation,
+    before: Uuid,
+) -> Result<Option<Uuid>, Error> {
+    let Some(input_arrival_id) = invocation.input_arrival_id else {
+        return Ok(None);
+    };
+
+    let mut tx = pg.begin().await?;
+    let next = sqlx::query!(
+        r##"SELECT al.actions_log_id,
+            ta.compensate_with AS "compensate_with!",
+            COALESCE(al.payload, 'null'::jsonb) AS "payload!",
+            al.result
+        FROM actions_log al
+        JOIN task_actions ta
+            ON ta.task_id = al.task_id AND ta.task_action_local_id = al.task_action_local_id
+        WHERE al.inputs_log_id = $1
+            AND al.task_id = $2
+            AND al.status = 'success'
+            AND al.compensates IS NULL
+            AND ta.compensate_with IS NOT NULL
+            AND al.updated < (SELECT updated FROM actions_log WHERE actions_log_id = $3)
+            AND NOT EXISTS (SELECT 1 FROM actions_log c WHERE c.compensates = al.actions_log_id)
+        ORDER BY al.updated DESC
+        LIMIT 1"##,
+        input_arrival_id,
+        invocation.task_id.0,
+        before
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+
+    let Some(next) = next else {
+        return Ok(None);
+    };
+
+    let compensation = ActionInvocation {
+        task_id: invocation.task_id.clone(),
+        task_action_local_id: next.compensate_with,
+        actions_log_id: new_uuid(),
+        input_arrival_id: Some(input_arrival_id),
+        user_id: invocation.user_id.clone(),
+        payload: compensation_payload(next.payload, next.result),
+        chain: invocation.chain.clone(),
+        trace: invocation.trace.clone(),
+        payload_in_log: false,
+        compensates: Some(next.actions_log_id),
+        source: None,
+    };
+
+    // Another worker may have started compensating the same action, if two actions in the run
+    // failed at once.
+    let inserted = sqlx::query!(
+        "INSERT INTO actions_log (task_id, task_action_local_id, actions_log_id, inputs_log_id,
+            payload, status, compensates)
+        VALUES ($1, $2, $3, $4, $5, 'pending', $6)
+        ON CONFLICT (compensates) WHERE compensates IS NOT NULL DO NOTHING",
+        compensation.task_id.0,
+        compensation.task_action_local_id,
+        compensation.actions_log_id,
+        input_arrival_id,
+        compensation.payload,
+        next.actions_log_id
+    )
+    .execute(&mut tx)
+    .await?
+    .rows_affected();
+
+    if inserted == 0 {
+        return Ok(None);
+    }
+
+    event!(Level::INFO,
+        task_id=%compensation.task_id,
+        compensates=%next.actions_log_id,
+        task_action_local_id=%compensation.task_action_local_id,
+        "Enqueueing compensation action"
+    );
+
+    let actions: ActionInvocations = smallvec![compensation];
+    enqueue_actions(&mut tx, &actions, redis_key_prefix, false).await?;
+    tx.commit().await?;
+
+    Ok(Some(actions[0].actions_log_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn payload_with_output() {
+        let payload = compensation_payload(
+            json!({ "name": "server-1" }),
+            Some(json!({ "output": { "id": 5 } })),
+        );
+        assert_eq!(
+            payload,
+            json!({ "name": "server-1", "original_output": { "id": 5 } })
+        );
+    }
+
+    #[test]
+    fn payload_without_output() {
+        let payload = compensation_payload(Value::Null, None);
+        assert_eq!(payload, json!({ "original_output": null }));
+    }
+}
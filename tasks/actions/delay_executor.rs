@@ -0,0 +1,216 @@
+use super::{
+    execute::Executor,
+    template::{TemplateField, TemplateFieldFormat, TemplateFields},
+};
+#[cfg(not(target_family = "wasm"))]
+use super::{
+    execute::{ExecutorError, ExecutorState},
+    send_input_executor::{send_input, TargetTask},
+};
+
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use chrono::{Duration, Utc};
+use ergo_database::object_id::TaskId;
+
+static FIELD_DURATION: TemplateField = TemplateField::from_static(
+    "duration",
+    TemplateFieldFormat::string_without_default(),
+    false,
+    "How long to wait, such as `90s`, `15m`, `1h30m`, or `2d`. A plain number is a count of seconds",
+);
+
+static FIELD_TRIGGER: TemplateField = TemplateField::from_static(
+    "trigger_name",
+    TemplateFieldFormat::string_without_default(),
+    false,
+    "The local ID of the trigger to send the continuation input to",
+);
+
+static FIELD_TASK: TemplateField = TemplateField::from_static(
+    "task",
+    TemplateFieldFormat::string_without_default(),
+    true,
+    "The ID or alias of the task to continue. Defaults to the task running the action",
+);
+
+static FIELD_PAYLOAD: TemplateField = TemplateField::from_static(
+    "payload",
+    TemplateFieldFormat::object_without_default(true),
+    true,
+    "The payload to send to the trigger when the delay is over",
+);
+
+/// Wait for a while, then send an input to a trigger.
+///
+/// The action finishes right away, and the continuation is scheduled on the input queue, so a
+/// long delay doesn't tie up a worker.
+#[derive(Debug)]
+pub struct DelayExecutor {
+    template_fields: TemplateFields,
+}
+
+impl DelayExecutor {
+    pub fn new() -> DelayExecutor {
+        let template_fields = [&FIELD_DURATION, &FIELD_TRIGGER, &FIELD_TASK, &FIELD_PAYLOAD].into();
+        DelayExecutor { template_fields }
+    }
+}
+
+#[async_trait]
+impl Executor for DelayExecutor {
+    #[cfg(not(target_family = "wasm"))]
+    async fn execute(
+        &self,
+        mut state: ExecutorState,
+        template_values: fxhash::FxHashMap<String, serde_json::Value>,
+    ) -> Result<serde_json::Value, ExecutorError> {
+        let duration_arg = FIELD_DURATION.extract_str(&template_values)?;
+        let duration = parse_duration(duration_arg.as_ref()).ok_or_else(|| {
+            ExecutorError::FieldFormatError {
+                field: FIELD_DURATION.name.to_string(),
+                subfield: None,
+                expected: "a duration such as 30s, 15m, 1h30m, or 2d".to_string(),
+            }
+        })?;
+        let trigger_name = FIELD_TRIGGER.extract_str(&template_values)?;
+        let task_arg = FIELD_TASK.extract_str(&template_values)?;
+        let payload = FIELD_PAYLOAD.extract_object(&template_values)?.into_owned();
+
+        let task = if task_arg.is_empty() {
+            TargetTask::Id(state.task_id)
+        } else {
+            match TaskId::from_str(task_arg.as_ref()) {
+                Ok(id) => TargetTask::Id(id),
+                Err(_) => TargetTask::Alias(task_arg.as_ref()),
+            }
+        };
+
+        // A continuation of the same task is part of the same run rather than another hop in
+        // the chain, so it shouldn't count as the task sending an input to itself.
+        if matches!(task, TargetTask::Id(id) if id == state.task_id)
+            && state.chain.tasks.last() == Some(&state.task_id)
+        {
+            state.chain.tasks.pop();
+        }
+
+        let resume_at = Utc::now() + duration;
+        let inputs_log_id =
+            send_input(state, task, trigger_name.as_ref(), payload, Some(resume_at)).await?;
+
+        Ok(serde_json::json!({
+            "resume_at": resume_at,
+            "inputs_log_id": inputs_log_id,
+        }))
+    }
+
+    fn name(&self) -> &'static str {
+        "delay"
+    }
+
+    fn template_fields(&self) -> &TemplateFields {
+        &self.template_fields
+    }
+}
+
+/// Parse a duration made of one or more numbers with `s`, `m`, `h`, `d`, or `w` units, like
+/// `1h30m`. A number by itself is a count of seconds.
+pub(super) fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    if s.is_empty() {
+        return None;
+    }
+
+    if let Ok(seconds) = s.parse::<u32>() {
+        return Some(Duration::seconds(seconds as i64));
+    }
+
+    let mut total_seconds: i64 = 0;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let digits_end = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if digits_end == 0 {
+            return None;
+        }
+
+        let value = rest[..digits_end].parse::<i64>().ok()?;
+        rest = &rest[digits_end..];
+
+        let unit_end = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let unit_seconds = match &rest[..unit_end] {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            "w" => 7 * 24 * 60 * 60,
+            _ => return None,
+        };
+        rest = &rest[unit_end..];
+
+        total_seconds = value
+            .checked_mul(unit_seconds)
+            .and_then(|part| total_seconds.checked_add(part))?;
+    }
+
+    // chrono panics on durations that don't fit in an i64 of milliseconds.
+    if total_seconds > i64::MAX / 1000 {
+        return None;
+    }
+
+    Some(Duration::seconds(total_seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::parse_duration;
+
+    #[test]
+    fn plain_seconds() {
+        assert_eq!(parse_duration("45"), Some(Duration::seconds(45)));
+        assert_eq!(parse_duration(" 0 "), Some(Duration::zero()));
+    }
+
+    #[test]
+    fn units() {
+        assert_eq!(parse_duration("45s"), Some(Duration::seconds(45)));
+        assert_eq!(parse_duration("15m"), Some(Duration::minutes(15)));
+        assert_eq!(parse_duration("24h"), Some(Duration::hours(24)));
+        assert_eq!(parse_duration("2d"), Some(Duration::days(2)));
+        assert_eq!(parse_duration("1w"), Some(Duration::weeks(1)));
+    }
+
+    #[test]
+    fn combined_units() {
+        assert_eq!(
+            parse_duration("1h30m"),
+            Some(Duration::hours(1) + Duration::minutes(30))
+        );
+        assert_eq!(
+            parse_duration("1d2h3m4s"),
+            Some(
+                Duration::days(1)
+                    + Duration::hours(2)
+                    + Duration::minutes(3)
+                    + Duration::seconds(4)
+            )
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration("10y"), None);
+        assert_eq!(parse_duration("-5s"), None);
+        assert_eq!(parse_duration("1.5h"), None);
+        assert_eq!(parse_duration("5 m"), None);
+        assert_eq!(parse_duration("99999999999999999w"), None);
+    }
+}
@@ -1,13 +1,34 @@
 use async_trait::async_trait;
-use ergo_database::{PostgresPool, RedisPool};
+use chrono::Utc;
+use ergo_database::{
+    object_id::{AccountId, ActionId},
+    PostgresPool, RedisPool,
+};
 use ergo_graceful_shutdown::GracefulShutdownConsumer;
 use ergo_notifications::NotificationManager;
-use ergo_queues::{QueueJobProcessor, QueueWorkItem};
-use std::num::NonZeroU32;
+use ergo_queues::{
+    circuit_breaker::{BreakerConfig, BreakerState, CircuitBreaker},
+    rate_limit::{Acquired, RateLimiter},
+    ErrorClass, Job, JobId, QueueJobProcessor, QueueWorkItem,
+};
+use serde_json::json;
+use std::{num::NonZeroU32, time::Duration};
+use tracing::{event, Instrument, Level};
 
-use crate::error::Error;
+use crate::{error::Error, feature_flags::FeatureFlags, payload_limits::load_action_payload};
 
-use super::{execute::execute, queue::ActionQueue, ActionInvocation};
+use super::{
+    accounts::AccountLimits,
+    compensation::enqueue_next_compensation,
+    execute::execute,
+    progress::{forward_progress, ProgressReporter},
+    queue::ActionQueue,
+    ActionInvocation,
+};
+
+/// How long an action can hold one of its account's concurrency slots. Slots are released when
+/// the action finishes, so this only matters when a worker dies while running an action.
+const ACCOUNT_SLOT_LEASE: Duration = Duration::from_secs(15 * 60);
 
 pub struct ActionExecutorConfig {
     pub pg_pool: PostgresPool,
@@ -16,6 +37,27 @@ pub struct ActionExecutorConfig {
     pub notifications: Option<NotificationManager>,
     /// The highest number of concurrent jobs to run. Defaults to twice the number of CPUs.
     pub max_concurrent_jobs: Option<usize>,
+    /// The highest number of actions for a single task to run at once.
+    pub max_jobs_per_task: Option<usize>,
+    /// How often to extend the processing deadline of actions that are still running.
+    pub heartbeat_interval: Option<Duration>,
+    /// Stop running an action with an account after it fails too many times in a row.
+    pub circuit_breaker: Option<ActionBreakerConfig>,
+}
+
+/// What happens to an action while the circuit breaker for its action and account is open.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpenBreakerMode {
+    /// Fail the action without running it or retrying it.
+    Fail,
+    /// Leave the action in the queue until the breaker lets actions through again.
+    Pause,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ActionBreakerConfig {
+    pub breaker: BreakerConfig,
+    pub mode: OpenBreakerMode,
 }
 
 pub struct ActionExecutor {
@@ -25,12 +67,28 @@ pub struct ActionExecutor {
 impl ActionExecutor {
     pub fn new(config: ActionExecutorConfig) -> Result<ActionExecutor, Error> {
         let redis_key_prefix = config.redis_pool.key_prefix().map(|e| e.to_string());
+        let account_limiter =
+            RateLimiter::new(config.redis_pool.clone(), "account", ACCOUNT_SLOT_LEASE);
+        let breaker = config.circuit_breaker.map(|c| {
+            (
+                CircuitBreaker::new(config.redis_pool.clone(), "action", c.breaker),
+                c.mode,
+            )
+        });
+        let feature_flags =
+            FeatureFlags::new(config.pg_pool.clone(), Some(config.redis_pool.clone()));
         let queue = ActionQueue::new(config.redis_pool);
         let executor = ActionExecutor { queue };
         let processor = ActionExecutorJobProcessor {
+            queue: executor.queue.clone(),
+            account_limiter,
+            breaker,
             pg_pool: config.pg_pool,
+            feature_flags,
             notifications: config.notifications,
             redis_key_prefix,
+            max_jobs_per_task: config.max_jobs_per_task,
+            heartbeat_interval: config.heartbeat_interval,
         };
 
         executor.queue.start_dequeuer_loop(
@@ -44,13 +102,23 @@ impl ActionExecutor {
 
         Ok(executor)
     }
+
+    pub fn queue(&self) -> &ActionQueue {
+        &self.queue
+    }
 }
 
 #[derive(Clone)]
 struct ActionExecutorJobProcessor {
+    queue: ActionQueue,
+    account_limiter: RateLimiter,
+    breaker: Option<(CircuitBreaker, OpenBreakerMode)>,
     pg_pool: PostgresPool,
+    feature_flags: FeatureFlags,
     notifications: Option<NotificationManager>,
     redis_key_prefix: Option<String>,
+    max_jobs_per_task: Option<usize>,
+    heartbeat_interval: Option<Duration>,
 }
 
 #[async_trait]
@@ -60,16 +128,231 @@ impl QueueJobProcessor for ActionExecutorJobProcessor {
 
     async fn process(
         &self,
-        _item: &QueueWorkItem<Self::Payload>,
-        data: ActionInvocation,
+        item: &QueueWorkItem<Self::Payload>,
+        mut data: ActionInvocation,
     ) -> Result<(), Error> {
-        execute(
+        let target = self.action_target(&data).await?;
+        let breaker_key = target.as_ref().map(|t| t.breaker_key());
+        if let (Some((breaker, mode)), Some(key)) = (&self.breaker, &breaker_key) {
+            if let BreakerState::Open(wait) = breaker.check(key, &item.id).await? {
+                event!(Level::DEBUG, actions_log_id=%data.actions_log_id, breaker=%key, ?wait, "Circuit breaker open");
+                return match mode {
+                    OpenBreakerMode::Pause => self.requeue(item, &data, wait).await,
+                    OpenBreakerMode::Fail => {
+                        let result = self.short_circuit(&data, wait).await;
+                        self.compensate(&data, false).await;
+                        result
+                    }
+                };
+            }
+        }
+
+        let account = target.and_then(|t| t.account_id.zip(t.limits));
+        if let Some((account_id, limits)) = &account {
+            let acquired = self
+                .account_limiter
+                .acquire(&account_id.to_string(), &item.id, &limits.rate_limit())
+                .await?;
+            if let Acquired::RetryAfter(wait) = acquired {
+                event!(Level::DEBUG, actions_log_id=%data.actions_log_id, %account_id, ?wait, "Account at limit, requeueing action");
+                return self.requeue(item, &data, wait).await;
+            }
+        }
+
+        let span = tracing::info_span!("process_action", actions_log_id=%data.actions_log_id);
+        data.trace.set_parent_of(&span);
+        let invocation = ActionInvocation {
+            payload: serde_json::Value::Null,
+            ..data.clone()
+        };
+        let (progress, progress_updates) = ProgressReporter::new();
+        let run = async {
+            load_action_payload(&self.pg_pool, &mut data).await?;
+            execute(
+                &self.pg_pool,
+                self.redis_key_prefix.clone(),
+                self.notifications.as_ref(),
+                &self.feature_flags,
+                data,
+                Some(progress),
+            )
+            .instrument(span)
+            .await
+        };
+        let forward = forward_progress(
             &self.pg_pool,
-            self.redis_key_prefix.clone(),
-            self.notifications.as_ref(),
-            data,
+            item,
+            invocation.actions_log_id,
+            progress_updates,
+        );
+        // Stop forwarding progress once the action is done, even if something is still holding
+        // on to the reporter.
+        tokio::pin!(run);
+        let result = tokio::select! {
+            result = &mut run => result,
+            _ = forward => run.await,
+        };
+
+        if let Some((account_id, _)) = account {
+            let account_id = account_id.to_string();
+            if let Err(e) = self.account_limiter.release(&account_id, &item.id).await {
+                event!(Level::ERROR, error=%e, %account_id, "Failed to release account slot");
+            }
+        }
+
+        if let (Some((breaker, _)), Some(key)) = (&self.breaker, &breaker_key) {
+            let recorded = match &result {
+                Ok(_) => breaker.success(key).await,
+                Err(Error::ExecuteError(e)) if e.error.is_dependency_failure() => {
+                    breaker.failure(key).await.map(|opened| {
+                        if opened {
+                            event!(Level::WARN, breaker=%key, "Circuit breaker opened");
+                        }
+                    })
+                }
+                Err(_) => Ok(()),
+            };
+
+            if let Err(e) = recorded {
+                event!(Level::ERROR, error=%e, breaker=%key, "Failed to update circuit breaker");
+            }
+        }
+
+        let finished = match &result {
+            Ok(_) => true,
+            Err(e) => e.is_permanent() || item.is_final_retry(),
+        };
+        if finished {
+            self.compensate(&invocation, result.is_ok()).await;
+        }
+
+        result?;
+        Ok(())
+    }
+
+    fn error_class(&self, error: &Error) -> ErrorClass {
+        error.error_class()
+    }
+
+    fn max_jobs_per_key(&self) -> Option<usize> {
+        self.max_jobs_per_task
+    }
+
+    fn heartbeat_interval(&self) -> Option<Duration> {
+        self.heartbeat_interval
+    }
+}
+
+/// The action and account that a task action runs with.
+struct ActionTarget {
+    action_id: ActionId,
+    account_id: Option<AccountId>,
+    /// The account's limits, if it has any.
+    limits: Option<AccountLimits>,
+}
+
+impl ActionTarget {
+    /// Circuit breakers are tracked for each combination of action and account, so that one
+    /// account with bad credentials or a down service doesn't stop other accounts.
+    fn breaker_key(&self) -> String {
+        match &self.account_id {
+            Some(account_id) => format!("{}:{}", self.action_id, account_id),
+            None => self.action_id.to_string(),
+        }
+    }
+}
+
+impl ActionExecutorJobProcessor {
+    /// Look up the action and account that the task action uses.
+    async fn action_target(&self, data: &ActionInvocation) -> Result<Option<ActionTarget>, Error> {
+        let row = sqlx::query!(
+            r##"SELECT task_actions.action_id AS "action_id: ActionId",
+                accounts.account_id AS "account_id?: AccountId",
+                accounts.max_concurrency, accounts.requests_per_minute
+            FROM task_actions
+            LEFT JOIN accounts USING (account_id)
+            WHERE task_actions.task_id=$1 AND task_actions.task_action_local_id=$2"##,
+            data.task_id.0,
+            data.task_action_local_id
+        )
+        .fetch_optional(&self.pg_pool)
+        .await?;
+
+        Ok(row.map(|row| {
+            let limits =
+                (row.max_concurrency.is_some() || row.requests_per_minute.is_some()).then(|| {
+                    AccountLimits {
+                        max_concurrency: row.max_concurrency,
+                        requests_per_minute: row.requests_per_minute,
+                    }
+                });
+
+            ActionTarget {
+                action_id: row.action_id,
+                account_id: row.account_id,
+                limits,
+            }
+        }))
+    }
+
+    /// Start or continue undoing the input run's actions, after an action has run for the last
+    /// time. A permanent failure starts the chain of compensations, and each compensation
+    /// enqueues the next one when it finishes, whether or not it succeeded.
+    async fn compensate(&self, invocation: &ActionInvocation, succeeded: bool) {
+        let before = match invocation.compensates {
+            Some(compensated) => compensated,
+            None if !succeeded => invocation.actions_log_id,
+            None => return,
+        };
+
+        let result =
+            enqueue_next_compensation(&self.pg_pool, &self.redis_key_prefix, invocation, before)
+                .await;
+
+        if let Err(e) = result {
+            event!(Level::ERROR, error=%e, actions_log_id=%invocation.actions_log_id, "Failed to enqueue compensation action");
+        }
+    }
+
+    /// Fail an action without running it, because its circuit breaker is open. The returned
+    /// error is permanent, so the action doesn't use up its retries while the breaker is open.
+    async fn short_circuit(&self, data: &ActionInvocation, wait: Duration) -> Result<(), Error> {
+        let error = Error::ActionCircuitOpen(wait);
+        sqlx::query!(
+            "UPDATE actions_log SET status='error', result=$2, updated=now()
+            WHERE actions_log_id=$1",
+            data.actions_log_id,
+            json!({
+                "error": error.to_string(),
+                "error_class": "circuit_open",
+            })
         )
+        .execute(&self.pg_pool)
         .await?;
+
+        Err(error)
+    }
+
+    /// Put the action back in the queue to run after `wait`. This finishes the current job
+    /// without running the action, and without counting as a retry.
+    async fn requeue(
+        &self,
+        item: &QueueWorkItem<ActionInvocation>,
+        data: &ActionInvocation,
+        wait: Duration,
+    ) -> Result<(), Error> {
+        let run_at = Utc::now()
+            + chrono::Duration::from_std(wait).unwrap_or_else(|_| chrono::Duration::seconds(1));
+        let job = Job {
+            run_at: Some(run_at),
+            fairness_key: item
+                .fairness_key
+                .clone()
+                .or_else(|| Some(data.task_id.to_string())),
+            ..Job::from_json_payload(JobId::Auto, data)?
+        };
+
+        self.queue.enqueue(&job).await?;
         Ok(())
     }
 }
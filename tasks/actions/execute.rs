@@ -2,8 +2,11 @@ use std::borrow::Cow;
 
 use async_trait::async_trait;
 #[cfg(not(target_family = "wasm"))]
-use ergo_database::PostgresPool;
-use ergo_database::{object_id::UserId, sqlx_json_decode};
+use ergo_database::{object_id::OrgId, PostgresPool};
+use ergo_database::{
+    object_id::{TaskId, UserId},
+    sqlx_json_decode,
+};
 use fxhash::FxHashMap;
 use lazy_static::lazy_static;
 use schemars::JsonSchema;
@@ -14,6 +17,16 @@ use super::{
     template::{TemplateFields, TemplateValidationFailure},
     TaskActionTemplate,
 };
+use crate::ActionValidateError;
+#[cfg(not(target_family = "wasm"))]
+use crate::{
+    actions::progress::ProgressReporter, egress::EgressPolicy, feature_flags::FeatureFlagSet,
+    inputs::chain::InputChain,
+};
+#[cfg(not(target_family = "wasm"))]
+use ergo_notifications::NotificationManager;
+#[cfg(not(target_family = "wasm"))]
+use uuid::Uuid;
 
 pub fn json_primitive_as_string<'a>(
     field: &str,
@@ -74,10 +87,16 @@ pub enum ExecutorError {
     #[error("Executor requires database connection but none was provided")]
     MissingDatabase,
 
+    #[cfg(not(target_family = "wasm"))]
+    #[error(transparent)]
+    EgressDenied(#[from] crate::egress::EgressDenied),
+
     #[error("Error during command execution: {source}")]
     CommandError {
         source: anyhow::Error,
         result: serde_json::Value,
+        /// True if running the command again with the same input would fail the same way.
+        permanent: bool,
     },
 }
 
@@ -86,6 +105,20 @@ impl ExecutorError {
         ExecutorError::CommandError {
             source: err.into(),
             result: serde_json::Value::Null,
+            permanent: false,
+        }
+    }
+
+    /// Returns true if retrying the action won't help. Errors in the action's inputs are always
+    /// permanent, and executors mark command errors as permanent when they can tell.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Self::MissingFieldError(_) | Self::FieldFormatError { .. } | Self::MissingDatabase => {
+                true
+            }
+            #[cfg(not(target_family = "wasm"))]
+            Self::EgressDenied(_) => true,
+            Self::CommandError { permanent, .. } => *permanent,
         }
     }
 }
@@ -99,6 +132,13 @@ impl From<TemplateValidationFailure> for ExecutorError {
                 expected: expected.to_string(),
                 subfield: None,
             },
+            // These only come from checking a template when an action is saved.
+            TemplateValidationFailure::Syntax { name, .. }
+            | TemplateValidationFailure::UnknownField { name, .. } => Self::FieldFormatError {
+                field: name.to_string(),
+                expected: "a valid template".to_string(),
+                subfield: None,
+            },
         }
     }
 }
@@ -108,7 +148,21 @@ impl From<TemplateValidationFailure> for ExecutorError {
 pub struct ExecutorState {
     pub pg_pool: Option<PostgresPool>,
     pub redis_key_prefix: Option<String>,
+    pub org_id: OrgId,
     pub user_id: UserId,
+    /// The task that is running the action.
+    pub task_id: TaskId,
+    /// The chain to attach to any inputs that the action sends to other tasks.
+    pub chain: InputChain,
+    /// Restrictions on the network requests that the action can make.
+    pub egress: EgressPolicy,
+    /// The feature flags that are enabled for the task's org.
+    pub flags: FeatureFlagSet,
+    pub notifications: Option<NotificationManager>,
+    /// The log entry for this run of the action.
+    pub actions_log_id: Option<Uuid>,
+    /// Reports how far along the action is, for executors that run for a while.
+    pub progress: Option<ProgressReporter>,
 }
 
 #[cfg(test)]
@@ -117,7 +171,15 @@ impl ExecutorState {
         ExecutorState {
             pg_pool: None,
             redis_key_prefix: None,
+            org_id: OrgId::new(),
             user_id: UserId::new(),
+            task_id: TaskId::new(),
+            chain: InputChain::default(),
+            egress: EgressPolicy::allow_all(),
+            flags: FeatureFlagSet::default(),
+            notifications: None,
+            actions_log_id: None,
+            progress: None,
         }
     }
 }
@@ -135,6 +197,21 @@ pub trait Executor: std::fmt::Debug + Send + Sync {
 
     /// Returns the template fields for the executor
     fn template_fields(&self) -> &TemplateFields;
+
+    /// A feature flag that must be enabled for an org to use this executor. This lets new
+    /// executors be rolled out gradually.
+    fn feature_flag(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Check an action's template values beyond what the template fields can express. This runs
+    /// when the action is created or updated.
+    fn validate_action_values(
+        &self,
+        _values: &FxHashMap<String, serde_json::Value>,
+    ) -> Result<(), ActionValidateError> {
+        Ok(())
+    }
 }
 
 lazy_static! {
@@ -144,6 +221,11 @@ lazy_static! {
             Box::new(super::raw_command_executor::RawCommandExecutor::new()) as Box<dyn Executor>,
             Box::new(super::js_executor::JsExecutor::new()) as Box<dyn Executor>,
             Box::new(super::send_input_executor::SendInputExecutor::new()) as Box<dyn Executor>,
+            Box::new(super::mqtt_executor::MqttExecutor::new()) as Box<dyn Executor>,
+            Box::new(super::delay_executor::DelayExecutor::new()) as Box<dyn Executor>,
+            Box::new(super::report_executor::ReportExecutor::new()) as Box<dyn Executor>,
+            Box::new(super::approval_executor::ApprovalExecutor::new()) as Box<dyn Executor>,
+            Box::new(super::sheets_executor::SheetsExecutor::new()) as Box<dyn Executor>,
         ])
         .map(|e| (e.name(), e))
         .collect::<FxHashMap<&'static str, Box<dyn Executor>>>()
@@ -167,7 +249,9 @@ pub use native::*;
 mod native {
     use chrono::{DateTime, Utc};
     use ergo_database::{
+        encryption::decrypt_account_fields,
         object_id::{AccountId, ActionId, OrgId, TaskId},
+        redaction::{self, SecretsGuard},
         PostgresPool,
     };
     use ergo_notifications::{Notification, NotificationManager, NotifyEvent};
@@ -180,11 +264,16 @@ mod native {
 
     use crate::{
         actions::{
+            artifacts,
             template::{self, TemplateError, TemplateFields},
             ActionInvocation, ActionStatus,
         },
+        costs, egress,
         error::Error,
+        feature_flags::FeatureFlags,
+        firehose::{self, FirehoseEvent, FirehoseEventKind},
         scripting::{self, run_simple_with_args},
+        timeline::TimelineRecorder,
     };
 
     use super::*;
@@ -194,7 +283,9 @@ mod native {
         pg_pool: &PostgresPool,
         redis_key_prefix: Option<String>,
         notifications: Option<&NotificationManager>,
+        feature_flags: &FeatureFlags,
         invocation: ActionInvocation,
+        progress: Option<ProgressReporter>,
     ) -> Result<serde_json::Value, Error> {
         event!(Level::DEBUG, ?invocation);
 
@@ -212,22 +303,55 @@ mod native {
             error: e.into(),
         })?;
 
-        let result = execute_action(pg_pool, redis_key_prefix, notifications, &invocation).await;
+        let timeline = TimelineRecorder::for_action(invocation.actions_log_id);
+        let execute_start = Utc::now();
+        // Holds the account's secrets until the result has been logged.
+        let mut secrets = SecretsGuard::default();
+        let result = execute_action(
+            pg_pool,
+            redis_key_prefix.clone(),
+            notifications,
+            feature_flags,
+            &invocation,
+            &timeline,
+            progress,
+            &mut secrets,
+        )
+        .await;
+        let execute_ms = (Utc::now() - execute_start).num_milliseconds();
+        timeline.add("execute_action", execute_start, None);
         event!(Level::DEBUG, ?result);
 
-        let (status, response) = match &result {
+        let (status, mut response) = match &result {
             Ok(r) => (ActionStatus::Success, json!({ "output": r })),
             Err(e) => {
                 event!(Level::ERROR, err=?e, "Action error");
-                (
-                    ActionStatus::Error,
-                    json!({
-                        "error": e.to_string(),
-                        "info": format!("{:?}", e),
-                    }),
-                )
+                let mut response = json!({
+                    "error": e.to_string(),
+                    "error_class": if e.is_permanent() { "permanent" } else { "retryable" },
+                    "info": format!("{:?}", e),
+                });
+
+                if let Error::ExecuteError(ExecuteError { error, .. }) = e {
+                    if let Some(output) = error.command_result() {
+                        response["output"] = output.clone();
+                    }
+                }
+
+                (ActionStatus::Error, response)
             }
         };
+        redaction::redact_json(&mut response);
+
+        let mut tx = pg_pool.begin().await?;
+        artifacts::store_artifacts(
+            &mut tx,
+            invocation.actions_log_id,
+            &invocation.task_id,
+            &mut response,
+            &artifacts::ARTIFACT_CONFIG,
+        )
+        .await?;
 
         sqlx::query!(
             "UPDATE actions_log SET status=$2, result=$3, updated=now()
@@ -236,7 +360,7 @@ mod native {
             status as _,
             response
         )
-        .execute(pg_pool)
+        .execute(&mut tx)
         .await
         .map_err(|e| ExecuteError {
             task_id: invocation.task_id.clone(),
@@ -246,6 +370,39 @@ mod native {
             error: e.into(),
         })?;
 
+        firehose::export_event(
+            &mut tx,
+            &FirehoseEvent {
+                kind: FirehoseEventKind::Action {
+                    task_action_local_id: invocation.task_action_local_id.clone(),
+                    input_arrival_id: invocation.input_arrival_id,
+                    status: status.clone(),
+                },
+                log_id: invocation.actions_log_id,
+                task_id: invocation.task_id,
+                org_id: None,
+                result: response,
+                timestamp: Utc::now(),
+            },
+            redis_key_prefix.as_deref(),
+        )
+        .await?;
+        tx.commit().await?;
+
+        timeline.write(pg_pool, invocation.input_arrival_id).await;
+
+        if let Err(e) = costs::record_action(
+            pg_pool,
+            &invocation.task_id,
+            invocation.actions_log_id,
+            execute_start,
+            execute_ms,
+        )
+        .await
+        {
+            event!(Level::ERROR, err=?e, "Failed to record action usage");
+        }
+
         result
     }
 
@@ -264,17 +421,31 @@ mod native {
         task_action_name: String,
         task_action_template: Option<Json<TaskActionTemplate>>,
         account_id: Option<AccountId>,
-        account_fields: Option<Json<TaskActionTemplate>>,
+        account_fields: Option<Json<serde_json::Value>>,
         account_expires: Option<DateTime<Utc>>,
         org_id: OrgId,
         run_as: Option<UserId>,
     }
 
+    /// Register the values of an account's fields for redaction. Fields that can't be decrypted
+    /// are skipped here, and the error is reported when the action is prepared.
+    pub fn register_account_secrets(fields: &serde_json::Value) -> SecretsGuard {
+        match decrypt_account_fields(fields.clone()) {
+            Ok(fields) => redaction::register_json(&fields),
+            Err(_) => SecretsGuard::default(),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     async fn execute_action(
         pg_pool: &PostgresPool,
         redis_key_prefix: Option<String>,
         notifications: Option<&NotificationManager>,
+        feature_flags: &FeatureFlags,
         invocation: &ActionInvocation,
+        timeline: &TimelineRecorder,
+        progress: Option<ProgressReporter>,
+        secrets: &mut SecretsGuard,
     ) -> Result<serde_json::Value, Error> {
         let task_id = &invocation.task_id;
         let task_action_local_id = &invocation.task_action_local_id;
@@ -317,6 +488,10 @@ mod native {
             error: e.into(),
         })?;
 
+        if let Some(fields) = &action.account_fields {
+            *secrets = register_account_secrets(&fields.0);
+        }
+
         event!(Level::DEBUG, ?action);
         event!(Level::INFO,
             %task_id,
@@ -336,6 +511,7 @@ mod native {
                 error: None,
                 task_name: action.task_name.clone(),
                 log_id: Some(invocation.actions_log_id),
+                suppressed_count: None,
                 local_id: action.task_action_local_id.clone(),
                 local_object_id: None,
                 local_object_name: action.task_action_name.clone(),
@@ -355,6 +531,18 @@ mod native {
                 )
             })?;
 
+        let flags = feature_flags.for_org(&action.org_id).await?;
+        if let Some(flag) = executor.feature_flag() {
+            if !flags.is_enabled(flag) {
+                let e = ExecuteError::from_action_and_error(
+                    &action,
+                    ExecuteErrorSource::ExecutorDisabled(action.executor_id.clone()),
+                );
+                notify_action_error(pg_pool, notifications, invocation, action, &e).await?;
+                return Err(e.into());
+            }
+        }
+
         let prepare_action = PrepareInvocationAction {
             executor_id: action.executor_id.as_str(),
             action_id: &action.action_id,
@@ -383,29 +571,53 @@ mod native {
 
         event!(Level::DEBUG, ?action_template_values);
 
+        let egress = {
+            let mut conn = pg_pool.acquire().await?;
+            egress::get_policy(&mut conn, &action.org_id).await?
+        };
+
         // Send the executor payload to the executor to actually run it.
         let postprocess = action.postprocess_script.as_ref();
         let executor_state = ExecutorState {
             pg_pool: Some(pg_pool.clone()),
             redis_key_prefix,
+            org_id: action.org_id.clone(),
             user_id: action
                 .run_as
                 .take()
                 .unwrap_or_else(|| invocation.user_id.clone()),
+            task_id: invocation.task_id,
+            chain: invocation.chain.next(
+                &invocation.task_id,
+                invocation.input_arrival_id,
+                Some(invocation.actions_log_id),
+            ),
+            egress,
+            flags,
+            notifications: notifications.cloned(),
+            actions_log_id: Some(invocation.actions_log_id),
+            progress,
         };
 
-        let results = executor
-            .execute(executor_state, action_template_values)
+        let results = timeline
+            .span(
+                executor.name(),
+                executor.execute(executor_state, action_template_values),
+            )
             .map_err(|e| e.into())
             .and_then(|result| async move {
                 match postprocess {
                     Some(script) => {
-                        let processed: serde_json::Value = run_simple_with_args(
-                            script,
-                            &[("output", &result), ("payload", &invocation.payload)],
-                        )
-                        .await
-                        .map_err(ExecuteErrorSource::ScriptError)?;
+                        let processed: serde_json::Value = timeline
+                            .span(
+                                "postprocess_script",
+                                run_simple_with_args(
+                                    script,
+                                    &[("output", &result), ("payload", &invocation.payload)],
+                                ),
+                            )
+                            .await
+                            .map_err(ExecuteErrorSource::ScriptError)?;
 
                         if !processed.is_null() {
                             Ok(processed)
@@ -439,6 +651,7 @@ mod native {
                         error: None,
                         task_name: action.task_name.clone(),
                         log_id: Some(invocation.actions_log_id),
+                        suppressed_count: None,
                         local_id: action.task_action_local_id.clone(),
                         local_object_id: None,
                         local_object_name: action.task_action_name.clone(),
@@ -482,6 +695,7 @@ mod native {
                 error: Some(error.to_string()),
                 task_name: action.task_name.clone(),
                 log_id: Some(invocation.actions_log_id),
+                suppressed_count: None,
                 local_id: action.task_action_local_id.clone(),
                 local_object_id: None,
                 local_object_name: action.task_action_name.clone(),
@@ -505,10 +719,26 @@ mod native {
         pub executor_id: &'a str,
         pub account_required: bool,
         pub account_id: &'a Option<AccountId>,
-        pub account_fields: Option<TaskActionTemplate>,
+        /// The account's fields, which may be encrypted.
+        pub account_fields: Option<serde_json::Value>,
         pub account_expires: Option<DateTime<Utc>>,
     }
 
+    /// Decrypt an account's fields and convert them to a template. The fields may be stored
+    /// as an object or as a list of pairs.
+    fn account_fields_template(
+        value: serde_json::Value,
+    ) -> Result<TaskActionTemplate, ExecuteErrorSource> {
+        let value = decrypt_account_fields(value)
+            .map_err(|e| ExecuteErrorSource::AccountFieldsError(e.to_string()))?;
+
+        match value {
+            serde_json::Value::Object(fields) => Ok(fields.into_iter().collect()),
+            other => serde_json::from_value(other)
+                .map_err(|e| ExecuteErrorSource::AccountFieldsError(e.to_string())),
+        }
+    }
+
     pub async fn validate_and_prepare_invocation(
         executor: &Box<dyn Executor>,
         invocation_payload: &serde_json::Value,
@@ -529,6 +759,12 @@ mod native {
         };
 
         // 1. Merge the invocation payload with action_template and account_fields, if present.
+        let _secrets = action.account_fields.as_ref().map(register_account_secrets);
+        let account_fields = action
+            .account_fields
+            .take()
+            .map(account_fields_template)
+            .transpose()?;
 
         let mut action_payload = FxHashMap::with_capacity_and_hasher(
             action.action_template_fields.0.len()
@@ -537,7 +773,7 @@ mod native {
                     .as_ref()
                     .map(|t| t.len())
                     .unwrap_or(0)
-                + action.account_fields.as_ref().map(|f| f.len()).unwrap_or(0),
+                + account_fields.as_ref().map(|f| f.len()).unwrap_or(0),
             FxBuildHasher::default(),
         );
 
@@ -561,7 +797,7 @@ mod native {
             }
         }
 
-        if let Some(account_fields) = action.account_fields.take() {
+        if let Some(account_fields) = account_fields {
             for (k, v) in account_fields {
                 action_payload.insert(k, v);
             }
@@ -643,6 +879,9 @@ mod native {
         #[error("Unknown executor {0}")]
         MissingExecutor(String),
 
+        #[error("Executor {0} is not enabled for this organization")]
+        ExecutorDisabled(String),
+
         #[error("Action requires an account")]
         AccountRequired,
 
@@ -651,6 +890,44 @@ mod native {
 
         #[error("SQL Error")]
         SqlError(#[from] sqlx::error::Error),
+
+        #[error("Reading account fields: {0}")]
+        AccountFieldsError(String),
+    }
+
+    impl ExecuteErrorSource {
+        /// Returns true if retrying the action won't help.
+        pub fn is_permanent(&self) -> bool {
+            match self {
+                Self::ExecutorError(e) => e.is_permanent(),
+                Self::SqlError(_) | Self::AccountFieldsError(_) => false,
+                Self::TemplateError(_)
+                | Self::ScriptError(_)
+                | Self::MissingExecutor(_)
+                | Self::ExecutorDisabled(_)
+                | Self::AccountRequired
+                | Self::AccountExpired(_) => true,
+            }
+        }
+
+        /// The executor's output for a failed command, such as a process's exit code and stderr.
+        pub fn command_result(&self) -> Option<&serde_json::Value> {
+            match self {
+                Self::ExecutorError(ExecutorError::CommandError { result, .. })
+                    if !result.is_null() =>
+                {
+                    Some(result)
+                }
+                _ => None,
+            }
+        }
+
+        /// Returns true if the executor failed in a way that may go away on its own, which
+        /// usually means that the service the action talks to is having trouble. These are the
+        /// failures that count toward opening the action's circuit breaker.
+        pub fn is_dependency_failure(&self) -> bool {
+            matches!(self, Self::ExecutorError(e) if !e.is_permanent())
+        }
     }
 }
 
@@ -99,6 +99,15 @@ static FIELD_RESULT_FORMAT: TemplateField = TemplateField::from_static(
     "How to process the result. Defaults to JSON",
 );
 
+/// Client errors mean that the request itself is bad, except for timeouts and rate limiting,
+/// which may succeed later.
+#[cfg(not(target_family = "wasm"))]
+pub(super) fn is_permanent_status(status: reqwest::StatusCode) -> bool {
+    status.is_client_error()
+        && status != reqwest::StatusCode::REQUEST_TIMEOUT
+        && status != reqwest::StatusCode::TOO_MANY_REQUESTS
+}
+
 #[derive(Debug)]
 pub struct HttpExecutor {
     template_fields: TemplateFields,
@@ -130,17 +139,40 @@ impl Executor for HttpExecutor {
     }
 
     #[cfg(not(target_family = "wasm"))]
-    #[instrument(level = "debug", name = "HttpExecutor::execute", skip(_state))]
+    #[instrument(level = "debug", name = "HttpExecutor::execute", skip(state))]
     async fn execute(
         &self,
-        _state: super::execute::ExecutorState,
+        state: super::execute::ExecutorState,
         payload: FxHashMap<String, serde_json::Value>,
     ) -> Result<serde_json::Value, ExecutorError> {
+        let url = FIELD_URL.extract_str(&payload)?;
+        let url =
+            reqwest::Url::parse(url.as_ref()).map_err(|_| ExecutorError::FieldFormatError {
+                field: "url".to_string(),
+                subfield: None,
+                expected: "Valid URL".to_string(),
+            })?;
+        state.egress.check_url(&url).await?;
+
+        // Redirects have to pass the egress policy too.
+        let permissions = state.egress.permissions();
+        let redirect_policy = reqwest::redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= 10 {
+                attempt.error("too many redirects")
+            } else if permissions.check_url(attempt.url()).is_err() {
+                let host = attempt.url().host_str().unwrap_or_default().to_string();
+                attempt.error(crate::egress::EgressDenied(host))
+            } else {
+                attempt.follow()
+            }
+        });
+
         let user_agent = FIELD_USER_AGENT.extract_str(&payload)?;
         let timeout: u64 = FIELD_TIMEOUT.extract(&payload)?;
         let client = reqwest::ClientBuilder::new()
             .user_agent(user_agent.as_ref())
             .timeout(std::time::Duration::from_secs(timeout))
+            .redirect(redirect_policy)
             .build()
             .map_err(ExecutorError::command_error_without_result)?;
 
@@ -157,9 +189,7 @@ impl Executor for HttpExecutor {
             }
         })?;
 
-        let url = FIELD_URL.extract_str(&payload)?;
-
-        let req = client.request(method, url.as_ref());
+        let req = client.request(method, url);
 
         let req = match payload.get("headers") {
             Some(serde_json::Value::Object(o)) => {
@@ -216,6 +246,7 @@ impl Executor for HttpExecutor {
             .await
             .and_then(|r| r.error_for_status())
             .map_err(|e| ExecutorError::CommandError {
+                permanent: e.status().map(is_permanent_status).unwrap_or(false),
                 source: anyhow!(e),
                 result: json!(null),
             })?;
@@ -233,6 +264,7 @@ impl Executor for HttpExecutor {
                 .text()
                 .await
                 .map_err(|e| ExecutorError::CommandError {
+                    permanent: e.is_decode(),
                     source: anyhow!(e),
                     result: json!(null),
                 })?;
@@ -240,6 +272,7 @@ impl Executor for HttpExecutor {
         } else {
             let r = result.json::<serde_json::Value>().await.map_err(|e| {
                 ExecutorError::CommandError {
+                    permanent: e.is_decode(),
                     source: anyhow!(e),
                     result: json!(null),
                 }
@@ -257,7 +290,7 @@ impl Executor for HttpExecutor {
 
 #[cfg(test)]
 mod tests {
-    use crate::actions::execute::ExecutorState;
+    use crate::{actions::execute::ExecutorState, egress::EgressPolicy};
 
     use super::*;
     use assert_matches::assert_matches;
@@ -464,4 +497,74 @@ mod tests {
 
         assert_matches!(result, ExecutorError::CommandError { .. });
     }
+
+    #[tokio::test]
+    async fn error_classification() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/unavailable"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&mock_server)
+            .await;
+
+        let exec = HttpExecutor::new();
+        let run = |url: String| {
+            let payload = std::array::IntoIter::new([("url", json!(url))])
+                .map(|(k, v)| (k.to_string(), v))
+                .collect::<FxHashMap<_, _>>();
+            exec.execute(ExecutorState::new_test_state(), payload)
+        };
+
+        let not_found = run(format!("{}/missing", mock_server.uri()))
+            .await
+            .expect_err("404 response");
+        assert!(not_found.is_permanent(), "404 should be permanent");
+
+        let unavailable = run(format!("{}/unavailable", mock_server.uri()))
+            .await
+            .expect_err("503 response");
+        assert!(!unavailable.is_permanent(), "503 should be retryable");
+    }
+
+    #[tokio::test]
+    async fn egress_policy() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/redirect"))
+            .respond_with(
+                ResponseTemplate::new(302).insert_header("Location", "http://10.0.0.1/internal"),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let exec = HttpExecutor::new();
+        let run = |url: String, egress: EgressPolicy| {
+            let payload = std::array::IntoIter::new([("url", json!(url))])
+                .map(|(k, v)| (k.to_string(), v))
+                .collect::<FxHashMap<_, _>>();
+            let state = ExecutorState {
+                egress,
+                ..ExecutorState::new_test_state()
+            };
+            exec.execute(state, payload)
+        };
+
+        let result = run(
+            format!("{}/redirect", mock_server.uri()),
+            EgressPolicy::default(),
+        )
+        .await
+        .expect_err("request to localhost");
+        assert_matches!(result, ExecutorError::EgressDenied(_));
+        assert!(result.is_permanent());
+
+        let allow_mock_server = EgressPolicy {
+            allow: vec!["127.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        };
+        let result = run(format!("{}/redirect", mock_server.uri()), allow_mock_server)
+            .await
+            .expect_err("redirect to private network");
+        assert_matches!(result, ExecutorError::CommandError { .. });
+    }
 }
@@ -1,4 +1,6 @@
 use std::borrow::Cow;
+#[cfg(not(target_family = "wasm"))]
+use std::{collections::BTreeMap, rc::Rc, time::Duration};
 
 use super::{
     execute::{Executor, ExecutorError},
@@ -6,11 +8,33 @@ use super::{
 };
 
 #[cfg(not(target_family = "wasm"))]
-use crate::scripting;
+use chrono::{DateTime, Utc};
+
+#[cfg(not(target_family = "wasm"))]
+use crate::{
+    egress::EgressPolicy,
+    scripting::{
+        self,
+        libraries::{library_imports, resolve_libraries},
+        locks::{LockScope, RedisLocks},
+        process::{ExecutionMode, WorkerJob, WorkerResponse, PROCESS_POOL},
+    },
+    ActionValidateError,
+};
 use async_trait::async_trait;
 
+#[cfg(not(target_family = "wasm"))]
+use ergo_js::{
+    locks::LockProvider,
+    permissions::{NetHostAndPort, Permissions},
+    time_limit::TimeLimit,
+};
 use fxhash::FxHashMap;
 #[cfg(not(target_family = "wasm"))]
+use serde::{Deserialize, Serialize};
+#[cfg(not(target_family = "wasm"))]
+use sha2::{Digest, Sha256};
+#[cfg(not(target_family = "wasm"))]
 use tracing::{event, Level};
 use url::Url;
 
@@ -37,6 +61,38 @@ static FIELD_ARGS: TemplateField = TemplateField::from_static(
     true,
     "Arguments to the script. Exposed as 'args' in the script",
 );
+static FIELD_ALLOWED_HOSTS: TemplateField = TemplateField::from_static(
+    "allowed_hosts",
+    TemplateFieldFormat::StringArray {
+        default: Cow::Borrowed(&[]),
+    },
+    true,
+    "Only allow network requests to these hosts, such as `api.example.com` or `*.example.com:8443`. \
+    The org's egress policy still applies. If empty, any host allowed by the policy can be used",
+);
+static FIELD_ALLOW_TIMERS: TemplateField = TemplateField::from_static(
+    "allow_timers",
+    TemplateFieldFormat::Boolean { default: true },
+    true,
+    "Allow the script to use setTimeout and setInterval",
+);
+static FIELD_TIMEOUT: TemplateField = TemplateField::from_static(
+    "timeout",
+    TemplateFieldFormat::Integer { default: 300 },
+    true,
+    "The maximum time that the script can run, in seconds, up to an hour. Default is 300 seconds",
+);
+static FIELD_DETERMINISTIC: TemplateField = TemplateField::from_static(
+    "deterministic",
+    TemplateFieldFormat::Boolean { default: false },
+    true,
+    "Run reproducibly: random numbers are seeded from the script and its arguments, the current \
+    time is frozen to when the task's input arrived, and network access is disabled",
+);
+
+/// The longest time limit that an action can set.
+#[cfg(not(target_family = "wasm"))]
+const MAX_TIMEOUT_SECS: u64 = 60 * 60;
 
 #[derive(Debug)]
 pub struct JsExecutor {
@@ -45,12 +101,144 @@ pub struct JsExecutor {
 
 impl JsExecutor {
     pub fn new() -> JsExecutor {
-        let template_fields = [&FIELD_NAME, &FIELD_SCRIPT, &FIELD_ARGS].into();
+        let template_fields = [
+            &FIELD_NAME,
+            &FIELD_SCRIPT,
+            &FIELD_ARGS,
+            &FIELD_ALLOWED_HOSTS,
+            &FIELD_ALLOW_TIMERS,
+            &FIELD_TIMEOUT,
+            &FIELD_DETERMINISTIC,
+        ]
+        .into();
 
         JsExecutor { template_fields }
     }
 }
 
+/// Restrictions on what an executor script can do, in addition to the org's egress policy.
+#[cfg(not(target_family = "wasm"))]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScriptLimits {
+    pub allowed_hosts: Vec<String>,
+    pub allow_timers: bool,
+    pub timeout_secs: u64,
+    /// Run the script reproducibly, without network access.
+    #[serde(default)]
+    pub deterministic: Option<DeterministicRun>,
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl Default for ScriptLimits {
+    fn default() -> Self {
+        ScriptLimits {
+            allowed_hosts: Vec::new(),
+            allow_timers: true,
+            timeout_secs: 300,
+            deterministic: None,
+        }
+    }
+}
+
+/// The inputs that a deterministic script sees in place of real randomness and time.
+#[cfg(not(target_family = "wasm"))]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeterministicRun {
+    /// Seeds `crypto.getRandomValues`, which also backs `Math.random`.
+    pub random_seed: u64,
+    /// The time returned by `Date.now()` and `new Date()`.
+    pub now: DateTime<Utc>,
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl DeterministicRun {
+    /// Seed the run from the script and its arguments, so the same payload always produces the
+    /// same random values.
+    fn new(script: &str, args: &serde_json::Value, now: DateTime<Utc>) -> DeterministicRun {
+        let mut hasher = Sha256::new();
+        hasher.update(script.as_bytes());
+        hasher.update(serde_json::to_vec(args).unwrap_or_default());
+        let digest = hasher.finalize();
+        let mut seed = [0u8; 8];
+        seed.copy_from_slice(&digest[..8]);
+
+        DeterministicRun {
+            random_seed: u64::from_le_bytes(seed),
+            now,
+        }
+    }
+
+    fn startup_script(&self) -> String {
+        format!(
+            r##"
+(function makeDeterministic(now) {{
+    const NativeDate = globalThis.Date;
+    const FrozenDate = function Date(...args) {{
+        if(!(this instanceof FrozenDate)) {{
+            return new NativeDate(now).toString();
+        }}
+        return args.length === 0 ? new NativeDate(now) : new NativeDate(...args);
+    }};
+    Object.setPrototypeOf(FrozenDate, NativeDate);
+    FrozenDate.prototype = NativeDate.prototype;
+    FrozenDate.now = () => now;
+    globalThis.Date = FrozenDate;
+
+    Math.random = () => crypto.getRandomValues(new Uint32Array(1))[0] / 0x100000000;
+}})({now});
+"##,
+            now = self.now.timestamp_millis()
+        )
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl ScriptLimits {
+    fn from_payload(payload: &FxHashMap<String, serde_json::Value>) -> Result<Self, ExecutorError> {
+        Ok(ScriptLimits {
+            allowed_hosts: FIELD_ALLOWED_HOSTS
+                .extract_string_array(payload)?
+                .into_iter()
+                .map(|h| h.into_owned())
+                .collect(),
+            allow_timers: FIELD_ALLOW_TIMERS.extract(payload)?,
+            timeout_secs: FIELD_TIMEOUT.extract(payload)?,
+            deterministic: None,
+        })
+    }
+
+    /// Check the limits, and return the name of the invalid field and what is wrong with it.
+    fn check(&self) -> Result<(), (String, String)> {
+        for host in &self.allowed_hosts {
+            if NetHostAndPort::try_from(host.as_str()).is_err() {
+                return Err((
+                    FIELD_ALLOWED_HOSTS.name.to_string(),
+                    format!("{} is not a valid host", host),
+                ));
+            }
+        }
+
+        if !(1..=MAX_TIMEOUT_SECS).contains(&self.timeout_secs) {
+            return Err((
+                FIELD_TIMEOUT.name.to_string(),
+                format!("must be between 1 and {} seconds", MAX_TIMEOUT_SECS),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn permissions(&self, egress: &EgressPolicy) -> Permissions {
+        let mut permissions = egress.permissions();
+        permissions.net_restrict_list = self
+            .allowed_hosts
+            .iter()
+            .filter_map(|h| NetHostAndPort::try_from(h.as_str()).ok())
+            .collect();
+        permissions
+    }
+}
+
 const EXECUTOR_STARTUP_SCRIPT: &str = r##"
 globalThis.Ergo = globalThis.Ergo || {};
 Ergo.setResult = function(value) {
@@ -67,67 +255,183 @@ impl Executor for JsExecutor {
     #[cfg(not(target_family = "wasm"))]
     async fn execute(
         &self,
-        _state: super::execute::ExecutorState,
+        state: super::execute::ExecutorState,
         payload: FxHashMap<String, serde_json::Value>,
     ) -> Result<serde_json::Value, ExecutorError> {
-        let (console, result) = scripting::POOL
-            .run(move || async move {
-                let name = FIELD_NAME.extract_str(&payload)?;
-                let script = FIELD_SCRIPT.extract_str(&payload)?;
-
-                let mut runtime = scripting::create_executor_runtime();
-                let args = FIELD_ARGS.extract_object(&payload)?;
-                runtime
-                    .set_global_value("args", args.as_ref())
-                    .map_err(ExecutorError::command_error_without_result)?;
-
-                event!(Level::DEBUG, %script, "executing script");
-                let name_url =
-                    Url::parse(&format!("https://ergo/executor/{}", name)).map_err(|_| {
-                        ExecutorError::FieldFormatError {
-                            field: "name".to_string(),
-                            subfield: None,
-                            expected: "A formattable name".to_string(),
-                        }
-                    })?;
-
-                runtime
-                    .execute_script("executor_init", EXECUTOR_STARTUP_SCRIPT)
-                    .map_err(ExecutorError::command_error_without_result)?;
+        let name = FIELD_NAME.extract_str(&payload)?;
+        let script = FIELD_SCRIPT.extract_str(&payload)?.into_owned();
+        let args = FIELD_ARGS.extract_object(&payload)?.into_owned();
+        let mut limits = ScriptLimits::from_payload(&payload)?;
+        limits
+            .check()
+            .map_err(|(field, message)| ExecutorError::FieldFormatError {
+                field,
+                subfield: None,
+                expected: message,
+            })?;
+        if FIELD_DETERMINISTIC.extract(&payload)? {
+            let now = input_arrival_time(&state).await?;
+            limits.deterministic = Some(DeterministicRun::new(&script, &args, now));
+        }
+        let libraries = script_libraries(&state, &script).await?;
+        let lock_scope = LockScope {
+            org_id: state.org_id.clone(),
+            redis_key_prefix: state.redis_key_prefix.clone(),
+        };
 
-                let run_result = runtime.run_main_module(name_url, script.to_string()).await;
-                let mut console = serde_json::to_value(runtime.take_console_messages())
-                    .unwrap_or_else(|_| serde_json::Value::Array(Vec::new()));
+        let name_url = Url::parse(&format!("https://ergo/executor/{}", name)).map_err(|_| {
+            ExecutorError::FieldFormatError {
+                field: "name".to_string(),
+                subfield: None,
+                expected: "A formattable name".to_string(),
+            }
+        })?;
 
-                run_result.map_err(|e| ExecutorError::CommandError {
-                    source: e.into(),
-                    result: std::mem::take(&mut console),
-                })?;
+        event!(Level::DEBUG, %script, "executing script");
+        let output = match *scripting::process::EXECUTION_MODE {
+            ExecutionMode::InProcess => {
+                run_executor_script(
+                    name_url,
+                    script,
+                    libraries,
+                    args,
+                    state.egress,
+                    limits,
+                    Some(lock_scope),
+                )
+                .await
+            }
+            ExecutionMode::Subprocess => {
+                let job = WorkerJob::Executor {
+                    url: name_url.to_string(),
+                    script,
+                    libraries,
+                    args,
+                    egress: state.egress,
+                    limits,
+                    lock_scope: Some(lock_scope),
+                };
+                match PROCESS_POOL.run(&job).await {
+                    Ok(WorkerResponse::Ok(output)) => Ok(output),
+                    Ok(WorkerResponse::Err { message, console }) => Err(ScriptError {
+                        error: anyhow::anyhow!(message),
+                        console,
+                    }),
+                    Err(e) => Err(ScriptError {
+                        error: e.into(),
+                        console: serde_json::Value::Null,
+                    }),
+                }
+            }
+        };
 
-                let result = runtime
-                    .get_global_value::<serde_json::Value>("__ergo_result")
-                    .map_err(|e| ExecutorError::CommandError {
-                        source: e.into(),
-                        result: std::mem::take(&mut console),
-                    })?
-                    .unwrap_or(serde_json::Value::Null);
-                Ok::<_, ExecutorError>((console, result))
-            })
-            .await?;
-
-        Ok(serde_json::json!({
-            "result": result,
-            "console": console
-        }))
+        output.map_err(|e| ExecutorError::CommandError {
+            source: e.error,
+            result: e.console,
+            permanent: false,
+        })
     }
 
     fn template_fields(&self) -> &TemplateFields {
         &self.template_fields
     }
+
+    #[cfg(not(target_family = "wasm"))]
+    fn validate_action_values(
+        &self,
+        values: &FxHashMap<String, serde_json::Value>,
+    ) -> Result<(), ActionValidateError> {
+        // Values that come from the task's template can only be checked when the action runs.
+        let templated = [
+            &FIELD_ALLOWED_HOSTS,
+            &FIELD_ALLOW_TIMERS,
+            &FIELD_TIMEOUT,
+            &FIELD_DETERMINISTIC,
+        ]
+        .iter()
+        .filter_map(|field| values.get(field.name.as_ref()))
+        .any(|v| v.to_string().contains("{{"));
+        if templated {
+            return Ok(());
+        }
+
+        let limits = ScriptLimits::from_payload(values).map_err(|e| match e {
+            ExecutorError::FieldFormatError {
+                field, expected, ..
+            } => ActionValidateError::ExecutorConfig {
+                field,
+                message: expected,
+            },
+            e => ActionValidateError::ExecutorConfig {
+                field: String::new(),
+                message: e.to_string(),
+            },
+        })?;
+
+        limits
+            .check()
+            .map_err(|(field, message)| ActionValidateError::ExecutorConfig { field, message })
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    #[cfg(not(target_family = "wasm"))]
+    #[test]
+    fn check_limits() {
+        use super::ScriptLimits;
+
+        ScriptLimits::default().check().expect("default limits");
+
+        let limits = ScriptLimits {
+            allowed_hosts: vec![
+                "api.example.com".to_string(),
+                "*.example.com:8443".to_string(),
+            ],
+            ..Default::default()
+        };
+        limits.check().expect("valid hosts");
+        assert_eq!(
+            limits
+                .permissions(&Default::default())
+                .net_restrict_list
+                .len(),
+            2
+        );
+
+        let limits = ScriptLimits {
+            allowed_hosts: vec!["http://".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(limits.check().unwrap_err().0, "allowed_hosts");
+
+        let limits = ScriptLimits {
+            timeout_secs: 0,
+            ..Default::default()
+        };
+        assert_eq!(limits.check().unwrap_err().0, "timeout");
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[test]
+    fn deterministic_seed() {
+        use super::DeterministicRun;
+
+        let now = chrono::Utc::now();
+        let args = serde_json::json!({ "value": 5 });
+        let first = DeterministicRun::new("Ergo.setResult(Math.random())", &args, now);
+        let second = DeterministicRun::new("Ergo.setResult(Math.random())", &args, now);
+        assert_eq!(first.random_seed, second.random_seed);
+
+        let other_args = serde_json::json!({ "value": 6 });
+        let third = DeterministicRun::new("Ergo.setResult(Math.random())", &other_args, now);
+        assert_ne!(first.random_seed, third.random_seed);
+
+        assert!(first
+            .startup_script()
+            .contains(&now.timestamp_millis().to_string()));
+    }
+
     #[tokio::test]
     #[ignore]
     async fn runs_script() {
@@ -148,3 +452,162 @@ mod tests {
     #[ignore]
     async fn async_script_exception() {}
 }
+
+/// Look up the shared libraries that the script imports, as the org of the user running it.
+#[cfg(not(target_family = "wasm"))]
+async fn script_libraries(
+    state: &super::execute::ExecutorState,
+    script: &str,
+) -> Result<BTreeMap<String, String>, ExecutorError> {
+    if library_imports(script).is_empty() {
+        return Ok(BTreeMap::new());
+    }
+
+    let pg_pool = state
+        .pg_pool
+        .as_ref()
+        .ok_or(ExecutorError::MissingDatabase)?;
+    let mut conn = pg_pool
+        .acquire()
+        .await
+        .map_err(ExecutorError::command_error_without_result)?;
+    let user = ergo_auth::get_user_info(&mut conn, &state.user_id, Some(&state.org_id), None)
+        .await
+        .map_err(ExecutorError::command_error_without_result)?;
+
+    resolve_libraries(&mut conn, &user.org_id, script)
+        .await
+        .map_err(|e| ExecutorError::CommandError {
+            source: e.into(),
+            result: serde_json::Value::Null,
+            permanent: true,
+        })
+}
+
+/// The time that the input which led to this action arrived, or when the action was queued if
+/// it didn't come from an input.
+#[cfg(not(target_family = "wasm"))]
+async fn input_arrival_time(
+    state: &super::execute::ExecutorState,
+) -> Result<DateTime<Utc>, ExecutorError> {
+    let pg_pool = state
+        .pg_pool
+        .as_ref()
+        .ok_or(ExecutorError::MissingDatabase)?;
+    let arrival = sqlx::query_scalar!(
+        r##"SELECT COALESCE(
+            (SELECT created FROM inputs_log WHERE inputs_log_id = $1),
+            (SELECT created FROM actions_log WHERE actions_log_id = $2),
+            now()
+        ) AS "arrival!""##,
+        state.chain.source_inputs_log_id,
+        state.actions_log_id
+    )
+    .fetch_one(pg_pool)
+    .await
+    .map_err(ExecutorError::command_error_without_result)?;
+
+    Ok(arrival)
+}
+
+/// A failed executor script, with the console output from before it failed.
+#[cfg(not(target_family = "wasm"))]
+#[derive(Debug)]
+pub(crate) struct ScriptError {
+    pub error: anyhow::Error,
+    pub console: serde_json::Value,
+}
+
+/// Run an executor script in [scripting::POOL], and return its result and console output.
+/// Output over the size limits is truncated, and the untruncated sizes are added to the output
+/// in a `truncated` object.
+#[cfg(not(target_family = "wasm"))]
+pub(crate) async fn run_executor_script(
+    url: Url,
+    script: String,
+    libraries: BTreeMap<String, String>,
+    args: serde_json::Value,
+    egress: EgressPolicy,
+    limits: ScriptLimits,
+    lock_scope: Option<LockScope>,
+) -> Result<serde_json::Value, ScriptError> {
+    scripting::POOL
+        .run(move || async move {
+            let locks = lock_scope.and_then(RedisLocks::new);
+            let deterministic = limits.deterministic.as_ref();
+            let mut runtime = scripting::create_executor_runtime(
+                libraries.into_iter().collect(),
+                limits.permissions(&egress),
+                limits.allow_timers,
+                deterministic.map(|d| d.random_seed),
+                locks.clone().map(|l| l as Rc<dyn LockProvider>),
+            );
+            let setup = runtime
+                .set_global_value("args", &args)
+                .map_err(anyhow::Error::from)
+                .and_then(|_| runtime.execute_script("executor_init", EXECUTOR_STARTUP_SCRIPT))
+                .and_then(|_| match deterministic {
+                    Some(d) => runtime
+                        .execute_script("deterministic_init", &d.startup_script())
+                        .map(|_| ()),
+                    None => Ok(()),
+                });
+            if let Err(error) = setup {
+                return Err(ScriptError {
+                    error,
+                    console: serde_json::Value::Null,
+                });
+            }
+
+            let time_limit =
+                TimeLimit::start(&mut runtime, Duration::from_secs(limits.timeout_secs));
+            let mut run_result = runtime.run_main_module(url, script).await;
+            if time_limit.expired() {
+                run_result = Err(anyhow::anyhow!(
+                    "Script exceeded its time limit of {} seconds",
+                    limits.timeout_secs
+                ));
+            }
+            drop(time_limit);
+            if let Some(locks) = locks {
+                locks.release_all().await;
+            }
+            let console_stats = runtime.console_stats().filter(|s| s.is_truncated());
+            let console = serde_json::to_value(runtime.take_console_messages())
+                .unwrap_or_else(|_| serde_json::Value::Array(Vec::new()));
+
+            let result = run_result.and_then(|_| {
+                runtime
+                    .get_global_value::<serde_json::Value>("__ergo_result")
+                    .map(|r| r.unwrap_or(serde_json::Value::Null))
+            });
+
+            let result = match result {
+                Ok(result) => result,
+                Err(e) => {
+                    return Err(ScriptError {
+                        error: e.into(),
+                        console,
+                    })
+                }
+            };
+
+            let (result, result_bytes) =
+                scripting::truncate_result(result, *scripting::MAX_RESULT_SIZE);
+
+            let mut output = serde_json::json!({
+                "result": result,
+                "console": console,
+            });
+
+            if result_bytes.is_some() || console_stats.is_some() {
+                output["truncated"] = serde_json::json!({
+                    "result_bytes": result_bytes,
+                    "console": console_stats,
+                });
+            }
+
+            Ok(output)
+        })
+        .await
+}
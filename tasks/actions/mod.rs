@@ -1,18 +1,29 @@
 #[cfg(not(target_family = "wasm"))]
 pub mod accounts;
 #[cfg(not(target_family = "wasm"))]
+pub mod artifacts;
+#[cfg(not(target_family = "wasm"))]
+pub mod compensation;
+#[cfg(not(target_family = "wasm"))]
 pub mod dequeue;
 pub mod execute;
 #[cfg(not(target_family = "wasm"))]
+pub mod progress;
+#[cfg(not(target_family = "wasm"))]
 pub mod queue;
 #[cfg(not(target_family = "wasm"))]
 pub use queue::enqueue_actions;
 pub mod template;
 
+mod approval_executor;
+mod delay_executor;
 mod http_executor;
-mod js_executor;
+pub(crate) mod js_executor;
+mod mqtt_executor;
 mod raw_command_executor;
+mod report_executor;
 mod send_input_executor;
+mod sheets_executor;
 
 #[cfg(target_family = "wasm")]
 use anyhow::anyhow;
@@ -24,7 +35,10 @@ use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
 use uuid::Uuid;
 
-use crate::{scripting, ActionValidateError, ActionValidateErrors};
+use crate::{
+    inputs::chain::InputChain, scripting, trace_context::TraceContext, ActionValidateError,
+    ActionValidateErrors,
+};
 
 use self::{
     execute::{ScriptOrTemplate, EXECUTOR_REGISTRY},
@@ -65,6 +79,8 @@ pub struct Action {
     pub account_required: bool,
     #[serde(default)]
     pub account_types: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 
 impl Action {
@@ -75,6 +91,13 @@ impl Action {
 
         let values_map = match &self.executor_template {
             ScriptOrTemplate::Template(values) => {
+                // Account fields are merged into the payload when the action runs, so
+                // templates for actions that use accounts can refer to fields that aren't declared.
+                let uses_accounts = self.account_required || !self.account_types.is_empty();
+                let fields = (!uses_accounts).then(|| &self.template_fields);
+                self::template::validate_template("action", Some(&self.action_id), values, fields)
+                    .map_err(ActionValidateError::TemplateError)?;
+
                 values.iter().cloned().collect::<FxHashMap<_, _>>()
             }
             ScriptOrTemplate::Script(s) => run_script(s)
@@ -89,6 +112,7 @@ impl Action {
             &values_map,
         )
         .map_err(ActionValidateError::TemplateError)?;
+        executor.validate_action_values(&values_map)?;
         Ok(())
     }
 }
@@ -122,6 +146,11 @@ pub struct TaskAction {
     pub account_id: Option<AccountId>,
     pub name: String,
     pub action_template: Option<TaskActionTemplate>,
+    /// Another action in the task that undoes this one. When a later action in the same input
+    /// run fails permanently, the compensations of the actions that already succeeded are run
+    /// in reverse order.
+    #[serde(default)]
+    pub compensate_with: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
@@ -138,7 +167,28 @@ pub enum ActionStatus {
     Error,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// What in a task emitted an action, recorded in the actions log so that an action can be
+/// traced back to the part of the task that produced it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ActionSource {
+    /// An event handler in a state machine.
+    StateMachine {
+        /// The index of the state machine in the task.
+        machine: usize,
+        trigger: String,
+        /// The state that handled the trigger.
+        from_state: String,
+        /// The state after the transition.
+        to_state: String,
+    },
+    /// An action node in a dataflow task.
+    DataFlowNode { node: String },
+    /// The script of a JS task.
+    Script,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ActionInvocation {
     pub task_id: TaskId,
     pub task_action_local_id: String,
@@ -146,14 +196,33 @@ pub struct ActionInvocation {
     pub input_arrival_id: Option<Uuid>,
     pub user_id: UserId,
     pub payload: serde_json::Value,
+    /// The chain of the input that caused this action to run.
+    #[serde(default)]
+    pub chain: InputChain,
+    #[serde(default)]
+    pub trace: TraceContext,
+    /// The payload was too large to put in the queue job, and should be loaded from the
+    /// actions log instead.
+    #[serde(default)]
+    pub payload_in_log: bool,
+    /// The actions log ID of the action that this invocation undoes.
+    #[serde(default)]
+    pub compensates: Option<Uuid>,
+    /// What emitted the action. This is only used when writing the actions log, so it is not
+    /// sent through the queue.
+    #[serde(skip)]
+    pub source: Option<ActionSource>,
 }
 
 pub type ActionInvocations = SmallVec<[ActionInvocation; 1]>;
 
-#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct TaskActionInvocation {
     pub name: String,
     pub payload: serde_json::Value,
+    /// The dataflow node that emitted the action.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node: Option<String>,
 }
 
 pub type TaskActionInvocations = SmallVec<[TaskActionInvocation; 1]>;
@@ -0,0 +1,290 @@
+use super::{
+    execute::{Executor, ExecutorError},
+    template::{TemplateField, TemplateFieldFormat, TemplateFields},
+};
+use async_trait::async_trait;
+use fxhash::FxHashMap;
+
+static FIELD_HOST: TemplateField = TemplateField::from_static(
+    "host",
+    TemplateFieldFormat::string_without_default(),
+    false,
+    "The MQTT broker's hostname",
+);
+
+static FIELD_PORT: TemplateField = TemplateField::from_static(
+    "port",
+    TemplateFieldFormat::integer_without_default(),
+    true,
+    "The broker's port. Defaults to 8883 with TLS and 1883 without",
+);
+
+static FIELD_USERNAME: TemplateField = TemplateField::from_static(
+    "username",
+    TemplateFieldFormat::string_without_default(),
+    true,
+    "The username to connect with",
+);
+
+static FIELD_PASSWORD: TemplateField = TemplateField::from_static(
+    "password",
+    TemplateFieldFormat::string_without_default(),
+    true,
+    "The password to connect with",
+);
+
+static FIELD_TLS: TemplateField = TemplateField::from_static(
+    "tls",
+    TemplateFieldFormat::Boolean { default: false },
+    true,
+    "Connect to the broker over TLS",
+);
+
+static FIELD_TOPIC: TemplateField = TemplateField::from_static(
+    "topic",
+    TemplateFieldFormat::string_without_default(),
+    false,
+    "The topic to publish to",
+);
+
+static FIELD_MESSAGE: TemplateField = TemplateField::from_static(
+    "message",
+    TemplateFieldFormat::string_without_default(),
+    true,
+    "A raw string message to publish",
+);
+
+static FIELD_JSON: TemplateField = TemplateField::from_static(
+    "json",
+    TemplateFieldFormat::object_without_default(true),
+    true,
+    "A JSON message to publish. This takes precedence over `message`",
+);
+
+static FIELD_QOS: TemplateField = TemplateField::from_static(
+    "qos",
+    TemplateFieldFormat::Integer { default: 1 },
+    true,
+    "The quality of service level, from 0 to 2. Defaults to 1",
+);
+
+static FIELD_RETAIN: TemplateField = TemplateField::from_static(
+    "retain",
+    TemplateFieldFormat::Boolean { default: false },
+    true,
+    "Ask the broker to retain the message for new subscribers",
+);
+
+static FIELD_TIMEOUT: TemplateField = TemplateField::from_static(
+    "timeout",
+    TemplateFieldFormat::Integer { default: 30 },
+    true,
+    "How long to wait for the broker to acknowledge the message, in seconds. Default is 30 seconds",
+);
+
+#[derive(Debug)]
+pub struct MqttExecutor {
+    template_fields: TemplateFields,
+}
+
+impl MqttExecutor {
+    pub fn new() -> MqttExecutor {
+        let template_fields = [
+            &FIELD_HOST,
+            &FIELD_PORT,
+            &FIELD_USERNAME,
+            &FIELD_PASSWORD,
+            &FIELD_TLS,
+            &FIELD_TOPIC,
+            &FIELD_MESSAGE,
+            &FIELD_JSON,
+            &FIELD_QOS,
+            &FIELD_RETAIN,
+            &FIELD_TIMEOUT,
+        ]
+        .into();
+
+        MqttExecutor { template_fields }
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+mod native {
+    use anyhow::anyhow;
+    use rumqttc::{AsyncClient, ConnectionError, Event, Outgoing, Packet, QoS};
+    use serde_json::json;
+
+    use super::super::execute::ExecutorError;
+
+    /// Publish a message and wait until the broker has acknowledged it as far as the QoS level
+    /// requires.
+    pub async fn publish(
+        options: rumqttc::MqttOptions,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        message: Vec<u8>,
+    ) -> Result<(), ExecutorError> {
+        let (client, mut eventloop) = AsyncClient::new(options, 4);
+        client
+            .publish(topic, qos, retain, message)
+            .await
+            .map_err(ExecutorError::command_error_without_result)?;
+
+        loop {
+            let event = eventloop.poll().await.map_err(|e| {
+                // A refused connection means the settings or credentials are wrong.
+                let permanent = matches!(e, ConnectionError::ConnectionRefused(_));
+                ExecutorError::CommandError {
+                    source: anyhow!(e),
+                    result: json!(null),
+                    permanent,
+                }
+            })?;
+
+            let done = match (qos, event) {
+                (QoS::AtMostOnce, Event::Outgoing(Outgoing::Publish(_))) => true,
+                (QoS::AtLeastOnce, Event::Incoming(Packet::PubAck(_))) => true,
+                (QoS::ExactlyOnce, Event::Incoming(Packet::PubComp(_))) => true,
+                _ => false,
+            };
+
+            if done {
+                break;
+            }
+        }
+
+        // The message is delivered, so a failure to disconnect cleanly doesn't matter.
+        if client.disconnect().await.is_ok() {
+            eventloop.poll().await.ok();
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Executor for MqttExecutor {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[tracing::instrument(level = "debug", name = "MqttExecutor::execute", skip(_state, payload))]
+    async fn execute(
+        &self,
+        _state: super::execute::ExecutorState,
+        payload: FxHashMap<String, serde_json::Value>,
+    ) -> Result<serde_json::Value, ExecutorError> {
+        use crate::inputs::mqtt::{client_id, qos, MqttBroker};
+
+        let port: u16 = FIELD_PORT.extract(&payload)?;
+        let username = FIELD_USERNAME.extract_str(&payload)?;
+        let password = FIELD_PASSWORD.extract_str(&payload)?;
+        let broker = MqttBroker {
+            host: FIELD_HOST.extract_str(&payload)?.into_owned(),
+            port: (port > 0).then_some(port),
+            username: (!username.is_empty()).then(|| username.into_owned()),
+            password: (!password.is_empty()).then(|| password.into_owned()),
+            tls: FIELD_TLS.extract(&payload)?,
+        };
+
+        let topic = FIELD_TOPIC.extract_str(&payload)?;
+        if topic.contains(['+', '#']) {
+            return Err(ExecutorError::FieldFormatError {
+                field: FIELD_TOPIC.name.to_string(),
+                subfield: None,
+                expected: "topic without wildcards".to_string(),
+            });
+        }
+
+        let qos_level: i16 = FIELD_QOS.extract(&payload)?;
+        if !(0..=2).contains(&qos_level) {
+            return Err(ExecutorError::FieldFormatError {
+                field: FIELD_QOS.name.to_string(),
+                subfield: None,
+                expected: "QoS level from 0 to 2".to_string(),
+            });
+        }
+
+        let message = match payload.get(FIELD_JSON.name.as_ref()) {
+            Some(json) => serde_json::to_vec(json).map_err(|e| ExecutorError::CommandError {
+                source: e.into(),
+                result: serde_json::Value::Null,
+                permanent: true,
+            })?,
+            None => FIELD_MESSAGE.extract_str(&payload)?.as_bytes().to_vec(),
+        };
+        let size = message.len();
+
+        let retain: bool = FIELD_RETAIN.extract(&payload)?;
+        let timeout: u64 = FIELD_TIMEOUT.extract(&payload)?;
+
+        tokio::time::timeout(
+            std::time::Duration::from_secs(timeout),
+            native::publish(
+                broker.options(&client_id()),
+                topic.as_ref(),
+                qos(qos_level),
+                retain,
+                message,
+            ),
+        )
+        .await
+        .map_err(|_| {
+            ExecutorError::command_error_without_result(anyhow::anyhow!(
+                "Timed out waiting for the MQTT broker"
+            ))
+        })??;
+
+        Ok(serde_json::json!({ "topic": topic, "size": size }))
+    }
+
+    fn template_fields(&self) -> &TemplateFields {
+        &self.template_fields
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::execute::ExecutorState;
+    use serde_json::json;
+
+    fn payload(values: &[(&str, serde_json::Value)]) -> FxHashMap<String, serde_json::Value> {
+        values
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn rejects_wildcard_topic() {
+        let result = MqttExecutor::new()
+            .execute(
+                ExecutorState::new_test_state(),
+                payload(&[("host", json!("localhost")), ("topic", json!("lights/+"))]),
+            )
+            .await;
+
+        let err = result.expect_err("publishing to a wildcard topic should fail");
+        assert!(err.is_permanent());
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_qos() {
+        let result = MqttExecutor::new()
+            .execute(
+                ExecutorState::new_test_state(),
+                payload(&[
+                    ("host", json!("localhost")),
+                    ("topic", json!("lights/porch")),
+                    ("qos", json!(3)),
+                ]),
+            )
+            .await;
+
+        let err = result.expect_err("QoS 3 should fail");
+        assert!(err.is_permanent());
+    }
+}
@@ -0,0 +1,73 @@
+//! Progress updates from long-running actions. An executor reports progress through the
+//! [ProgressReporter] in its state, and the action's worker saves the latest update to the
+//! actions log and the queue job, so that the UI can follow the action while it runs.
+//!
+//! Updates that arrive while the previous one is still being saved are coalesced, so an
+//! executor can report as often as it likes.
+
+use std::sync::Arc;
+
+use ergo_database::PostgresPool;
+use ergo_queues::{JobProgress, QueueWorkItem};
+use tokio::sync::watch;
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use crate::error::Error;
+
+#[derive(Clone, Debug)]
+pub struct ProgressReporter(Arc<watch::Sender<Option<JobProgress>>>);
+
+pub type ProgressUpdates = watch::Receiver<Option<JobProgress>>;
+
+impl ProgressReporter {
+    pub fn new() -> (ProgressReporter, ProgressUpdates) {
+        let (tx, rx) = watch::channel(None);
+        (ProgressReporter(Arc::new(tx)), rx)
+    }
+
+    /// Report progress. `percent` is clamped to the range 0 to 100.
+    pub fn report(&self, percent: Option<f64>, message: Option<String>) {
+        self.0
+            .send_replace(Some(JobProgress::new(percent, message)));
+    }
+}
+
+async fn save_progress(
+    pg: &PostgresPool,
+    actions_log_id: Uuid,
+    progress: &JobProgress,
+) -> Result<(), Error> {
+    sqlx::query!(
+        "UPDATE actions_log SET progress=$2 WHERE actions_log_id=$1 AND status='running'",
+        actions_log_id,
+        sqlx::types::Json(progress) as _
+    )
+    .execute(pg)
+    .await?;
+    Ok(())
+}
+
+/// Save each progress update until all the reporters are dropped. This is meant to run alongside
+/// the action, and can be cancelled once the action finishes.
+pub async fn forward_progress<T: Send + Sync>(
+    pg: &PostgresPool,
+    item: &QueueWorkItem<T>,
+    actions_log_id: Uuid,
+    mut updates: ProgressUpdates,
+) {
+    while updates.changed().await.is_ok() {
+        let progress = match updates.borrow_and_update().clone() {
+            Some(p) => p,
+            None => continue,
+        };
+
+        if let Err(e) = save_progress(pg, actions_log_id, &progress).await {
+            event!(Level::ERROR, error=%e, %actions_log_id, "Failed to save action progress");
+        }
+
+        if let Err(e) = item.report_progress(&progress).await {
+            event!(Level::ERROR, error=%e, %actions_log_id, "Failed to report job progress");
+        }
+    }
+}
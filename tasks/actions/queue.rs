@@ -1,6 +1,9 @@
 use std::{borrow::Cow, ops::Deref};
 
-use crate::error::Error;
+use crate::{
+    error::Error,
+    payload_limits::{PayloadKind, PAYLOAD_LIMITS},
+};
 
 use ergo_database::RedisPool;
 use ergo_queues::{
@@ -10,7 +13,7 @@ use ergo_queues::{
 use smallvec::SmallVec;
 use sqlx::PgConnection;
 
-use super::ActionInvocations;
+use super::{ActionInvocation, ActionInvocations};
 
 const QUEUE_NAME: &str = "er-action";
 
@@ -39,20 +42,46 @@ pub async fn enqueue_actions(
     tx: &mut PgConnection,
     actions: &ActionInvocations,
     key_prefix: &Option<String>,
+    high_priority: bool,
 ) -> Result<(), Error> {
     let queue_name = key_prefix
         .as_ref()
         .map(|prefix| Cow::Owned(format!("{}-{}", prefix, QUEUE_NAME)))
         .unwrap_or(Cow::Borrowed(QUEUE_NAME));
+    // Actions take turns by task, so that one task with a lot of actions doesn't hold up the
+    // actions of every other task.
+    let task_ids = actions
+        .iter()
+        .map(|inv| inv.task_id.to_string())
+        .collect::<SmallVec<[String; 4]>>();
+
+    // Large payloads stay in the actions log, and the worker loads them from there.
+    let mut stored_payloads = SmallVec::<[Option<ActionInvocation>; 4]>::new();
+    for inv in actions {
+        let stored = PAYLOAD_LIMITS
+            .check(PayloadKind::Action, &inv.payload)?
+            .then(|| ActionInvocation {
+                payload: serde_json::Value::Null,
+                payload_in_log: true,
+                ..inv.clone()
+            });
+        stored_payloads.push(stored);
+    }
+
     let jobs = actions
         .iter()
-        .map(|inv| QueueJob {
+        .zip(stored_payloads.iter())
+        .map(|(inv, stored)| stored.as_ref().unwrap_or(inv))
+        .zip(task_ids.iter())
+        .map(|(inv, task_id)| QueueJob {
             timeout: None,
             id: None,
             queue: queue_name.as_ref(),
             run_at: None,
             max_retries: None,
             retry_backoff: None,
+            high_priority,
+            fairness_key: Some(task_id.as_str()),
             payload: inv,
         })
         .collect::<SmallVec<[QueueJob<_>; 4]>>();
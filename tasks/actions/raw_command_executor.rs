@@ -9,6 +9,11 @@ use serde_json::json;
 use std::process::Stdio;
 use tracing::{event, instrument, Level};
 
+#[cfg(not(target_family = "wasm"))]
+use super::progress::ProgressReporter;
+#[cfg(not(target_family = "wasm"))]
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+
 #[cfg(target_family = "unix")]
 use std::os::unix::process::ExitStatusExt;
 
@@ -28,7 +33,33 @@ static FIELD_ENV: TemplateField = TemplateField::from_static(
     "env",
     TemplateFieldFormat::object_without_default(false),
     true,
-    "Environment variables to set",
+    "Environment variables to set. Values can use template fields",
+);
+static FIELD_CWD: TemplateField = TemplateField::from_static(
+    "cwd",
+    TemplateFieldFormat::string_without_default(),
+    true,
+    "The working directory for the command",
+);
+static FIELD_STDIN: TemplateField = TemplateField::from_static(
+    "stdin",
+    TemplateFieldFormat::string_without_default(),
+    true,
+    "Text to write to the command's standard input",
+);
+static FIELD_STDIN_JSON: TemplateField = TemplateField::from_static(
+    "stdin_json",
+    TemplateFieldFormat::object_without_default(true),
+    true,
+    "A value to write to the command's standard input as JSON. This takes precedence over stdin",
+);
+static FIELD_MAX_OUTPUT: TemplateField = TemplateField::from_static(
+    "max_output_bytes",
+    TemplateFieldFormat::Integer {
+        default: 1024 * 1024,
+    },
+    true,
+    "The most output to keep from each of stdout and stderr. Anything past this is discarded",
 );
 static FIELD_ALLOW_FAILURE: TemplateField = TemplateField::from_static(
     "allow_failure",
@@ -37,6 +68,11 @@ static FIELD_ALLOW_FAILURE: TemplateField = TemplateField::from_static(
     "If true, ignore the process exit code. By default, a nonzero exit code counts as failure",
 );
 
+/// Lines that a command writes to stderr with this prefix report the action's progress, and are
+/// left out of the captured output. The prefix can be followed by a percentage, a message, or
+/// both, like `::progress 40 Copying files`.
+pub const PROGRESS_PREFIX: &str = "::progress";
+
 #[derive(Debug)]
 pub struct RawCommandExecutor {
     template_fields: TemplateFields,
@@ -48,6 +84,10 @@ impl RawCommandExecutor {
             &FIELD_COMMAND,
             &FIELD_ARGS,
             &FIELD_ENV,
+            &FIELD_CWD,
+            &FIELD_STDIN,
+            &FIELD_STDIN_JSON,
+            &FIELD_MAX_OUTPUT,
             &FIELD_ALLOW_FAILURE,
         ]
         .into();
@@ -63,10 +103,10 @@ impl Executor for RawCommandExecutor {
     }
 
     #[cfg(not(target_family = "wasm"))]
-    #[instrument(level = "debug", name = "RawCommandExecutor::execute", skip(_state))]
+    #[instrument(level = "debug", name = "RawCommandExecutor::execute", skip(state))]
     async fn execute(
         &self,
-        _state: super::execute::ExecutorState,
+        state: super::execute::ExecutorState,
         payload: FxHashMap<String, serde_json::Value>,
     ) -> Result<serde_json::Value, ExecutorError> {
         let command = FIELD_COMMAND.extract_str(&payload)?;
@@ -90,41 +130,121 @@ impl Executor for RawCommandExecutor {
             }
         }
 
+        if payload.contains_key(FIELD_CWD.name.as_ref()) {
+            let cwd = FIELD_CWD.extract_str(&payload)?;
+            cmd.current_dir(cwd.as_ref());
+        }
+
+        let stdin = if payload.contains_key(FIELD_STDIN_JSON.name.as_ref()) {
+            Some(
+                FIELD_STDIN_JSON
+                    .extract_object(&payload)?
+                    .to_string()
+                    .into_bytes(),
+            )
+        } else if payload.contains_key(FIELD_STDIN.name.as_ref()) {
+            Some(FIELD_STDIN.extract_str(&payload)?.as_bytes().to_vec())
+        } else {
+            None
+        };
+        cmd.stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        });
+
         let allow_failure: bool = FIELD_ALLOW_FAILURE.extract(&payload)?;
+        let max_output: usize = FIELD_MAX_OUTPUT.extract(&payload)?;
+
+        // If the action times out, make sure the command doesn't keep running.
+        cmd.kill_on_drop(true);
 
         event!(Level::DEBUG, ?cmd);
 
-        let output = cmd
-            .output()
-            .await
-            .map_err(|e| ExecutorError::CommandError {
-                source: e.into(),
-                result: json!(null),
-            })?;
+        let mut child = cmd.spawn().map_err(|e| ExecutorError::CommandError {
+            source: e.into(),
+            result: json!(null),
+            permanent: false,
+        })?;
+
+        let child_stdin = child.stdin.take();
+        let write_stdin = async move {
+            if let (Some(mut child_stdin), Some(input)) = (child_stdin, stdin) {
+                match child_stdin.write_all(&input).await {
+                    // The command exited without reading all of its input.
+                    Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+                    result => result?,
+                }
+                // Dropping stdin closes it, so the command sees the end of the input.
+            }
+            Ok::<_, std::io::Error>(())
+        };
 
-        let exitcode = output.status.code();
+        let stdout = child.stdout.take().map(|s| read_limited(s, max_output));
+        let progress = state.progress;
+        let stderr = child.stderr.take().map(|s| async move {
+            match progress {
+                Some(progress) => read_with_progress(s, max_output, progress).await,
+                None => read_limited(s, max_output).await,
+            }
+        });
+        let output = async move {
+            let (_, stdout, stderr, status) = tokio::try_join!(
+                write_stdin,
+                async move {
+                    match stdout {
+                        Some(s) => s.await,
+                        None => Ok(CapturedOutput::default()),
+                    }
+                },
+                async move {
+                    match stderr {
+                        Some(s) => s.await,
+                        None => Ok(CapturedOutput::default()),
+                    }
+                },
+                child.wait()
+            )?;
+            Ok::<_, std::io::Error>((stdout, stderr, status))
+        };
+
+        let (stdout, stderr, status) = output.await.map_err(|e| ExecutorError::CommandError {
+            source: e.into(),
+            result: json!(null),
+            permanent: false,
+        })?;
+
+        let exitcode = status.code();
         event!(Level::DEBUG, exitcode = ?exitcode);
 
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        event!(Level::TRACE, %stdout, %stderr);
+        let stdout_text = String::from_utf8_lossy(&stdout.data);
+        let stderr_text = String::from_utf8_lossy(&stderr.data);
+        event!(Level::TRACE, stdout=%stdout_text, stderr=%stderr_text);
 
         let result = json!({
             "exitcode": exitcode,
-            "stdout": stdout,
-            "stderr": stderr,
+            "stdout": stdout_text,
+            "stderr": stderr_text,
+            "stdout_truncated": stdout.truncated,
+            "stderr_truncated": stderr.truncated,
         });
 
-        if !output.status.success() && !allow_failure {
-            let msg = match (exit_status_message(&output.status), exitcode) {
+        if !status.success() && !allow_failure {
+            let msg = match (exit_status_message(&status), exitcode) {
                 (Some(m), _) => m,
                 (None, Some(code)) => format!("Exited with code {}", code),
                 (None, None) => "Exited with unknown error".to_string(),
             };
 
+            let msg = match stderr_summary(&stderr_text) {
+                Some(summary) => format!("{}: {}", msg, summary),
+                None => msg,
+            };
+
             return Err(ExecutorError::CommandError {
                 source: anyhow!(msg),
                 result,
+                permanent: false,
             });
         }
 
@@ -136,6 +256,116 @@ impl Executor for RawCommandExecutor {
     }
 }
 
+#[cfg(not(target_family = "wasm"))]
+#[derive(Debug, Default)]
+struct CapturedOutput {
+    data: Vec<u8>,
+    truncated: bool,
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl CapturedOutput {
+    fn push(&mut self, data: &[u8], limit: usize) {
+        let room = limit.saturating_sub(self.data.len());
+        if data.len() > room {
+            self.truncated = true;
+        }
+        self.data.extend_from_slice(&data[..data.len().min(room)]);
+    }
+}
+
+/// Read a stream to the end, keeping up to `limit` bytes. The rest is read and discarded so that
+/// the command doesn't block on a full pipe.
+#[cfg(not(target_family = "wasm"))]
+async fn read_limited(
+    mut reader: impl AsyncRead + Unpin,
+    limit: usize,
+) -> Result<CapturedOutput, std::io::Error> {
+    let mut output = CapturedOutput::default();
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+
+        output.push(&buf[..n], limit);
+    }
+
+    Ok(output)
+}
+
+/// Like [read_limited], but lines that start with [PROGRESS_PREFIX] are reported as progress
+/// instead of being captured.
+#[cfg(not(target_family = "wasm"))]
+async fn read_with_progress(
+    reader: impl AsyncRead + Unpin,
+    limit: usize,
+    progress: ProgressReporter,
+) -> Result<CapturedOutput, std::io::Error> {
+    // Very long lines are read in pieces so that they don't all have to fit in memory.
+    const MAX_LINE_BYTES: u64 = 8192;
+
+    let mut reader = BufReader::new(reader);
+    let mut output = CapturedOutput::default();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let n = (&mut reader)
+            .take(MAX_LINE_BYTES)
+            .read_until(b'\n', &mut line)
+            .await?;
+        if n == 0 {
+            break;
+        }
+
+        match parse_progress_line(&line) {
+            Some((percent, message)) => progress.report(percent, message),
+            None => output.push(&line, limit),
+        }
+    }
+
+    Ok(output)
+}
+
+/// Parse a progress line into its percentage and message.
+#[cfg(not(target_family = "wasm"))]
+fn parse_progress_line(line: &[u8]) -> Option<(Option<f64>, Option<String>)> {
+    let line = std::str::from_utf8(line).ok()?;
+    let rest = line.strip_prefix(PROGRESS_PREFIX)?;
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+
+    let rest = rest.trim();
+    let (first, remainder) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    let message = |s: &str| {
+        let s = s.trim();
+        (!s.is_empty()).then(|| s.to_string())
+    };
+
+    match first.trim_end_matches('%').parse::<f64>() {
+        Ok(percent) if percent.is_finite() => Some((Some(percent), message(remainder))),
+        _ => Some((None, message(rest))),
+    }
+}
+
+/// The end of stderr, where the reason for a failure usually is.
+#[cfg(not(target_family = "wasm"))]
+fn stderr_summary(stderr: &str) -> Option<&str> {
+    const MAX_LEN: usize = 500;
+    let stderr = stderr.trim();
+    if stderr.is_empty() {
+        return None;
+    }
+
+    let mut start = stderr.len().saturating_sub(MAX_LEN);
+    while !stderr.is_char_boundary(start) {
+        start += 1;
+    }
+    Some(&stderr[start..])
+}
+
 #[cfg(unix)]
 fn exit_status_message(e: &std::process::ExitStatus) -> Option<String> {
     if let Some(signal) = e.signal() {
@@ -150,13 +380,127 @@ fn exit_status_message(e: &std::process::ExitStatus) -> Option<String> {
     None
 }
 
-#[cfg(test)]
+#[cfg(all(test, unix))]
 mod tests {
     use super::*;
+    use crate::actions::execute::ExecutorState;
+
+    fn payload(values: serde_json::Value) -> FxHashMap<String, serde_json::Value> {
+        match values {
+            serde_json::Value::Object(m) => m.into_iter().collect(),
+            _ => panic!("payload must be an object"),
+        }
+    }
 
-    // #[tokio::test]
-    // fn test_basic_command() {
-    //     let (_, exec) = RawCommandExecutor::new();
-    //     let result = exec.execute(pg_pool, args).await;
-    // }
+    #[tokio::test]
+    async fn stdin_env_and_cwd() {
+        let exec = RawCommandExecutor::new();
+        let result = exec
+            .execute(
+                ExecutorState::new_test_state(),
+                payload(json!({
+                    "command": "/bin/sh",
+                    "args": ["-c", "cat; echo \" $NAME\"; pwd"],
+                    "env": { "NAME": "abc" },
+                    "cwd": "/",
+                    "stdin_json": { "a": 1 },
+                })),
+            )
+            .await
+            .expect("Running command");
+
+        assert_eq!(
+            result,
+            json!({
+                "exitcode": 0,
+                "stdout": "{\"a\":1} abc\n/\n",
+                "stderr": "",
+                "stdout_truncated": false,
+                "stderr_truncated": false,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn output_limit() {
+        let exec = RawCommandExecutor::new();
+        let result = exec
+            .execute(
+                ExecutorState::new_test_state(),
+                payload(json!({
+                    "command": "/bin/sh",
+                    "args": ["-c", "echo abcdefghij; echo 123456 >&2"],
+                    "max_output_bytes": 4,
+                })),
+            )
+            .await
+            .expect("Running command");
+
+        assert_eq!(result["stdout"], json!("abcd"));
+        assert_eq!(result["stdout_truncated"], json!(true));
+        assert_eq!(result["stderr"], json!("1234"));
+        assert_eq!(result["stderr_truncated"], json!(true));
+    }
+
+    #[tokio::test]
+    async fn progress_lines() {
+        let (progress, updates) = ProgressReporter::new();
+        let exec = RawCommandExecutor::new();
+        let result = exec
+            .execute(
+                ExecutorState {
+                    progress: Some(progress),
+                    ..ExecutorState::new_test_state()
+                },
+                payload(json!({
+                    "command": "/bin/sh",
+                    "args": ["-c", "echo '::progress 10' >&2; echo warning >&2; echo '::progress 75% Copying files' >&2"],
+                })),
+            )
+            .await
+            .expect("Running command");
+
+        assert_eq!(result["stderr"], json!("warning\n"));
+        let last = updates.borrow().clone().expect("progress was reported");
+        assert_eq!(last.percent, Some(75.0));
+        assert_eq!(last.message.as_deref(), Some("Copying files"));
+    }
+
+    #[test]
+    fn parse_progress() {
+        assert_eq!(
+            parse_progress_line(b"::progress 50\n"),
+            Some((Some(50.0), None))
+        );
+        assert_eq!(
+            parse_progress_line(b"::progress Uploading report\n"),
+            Some((None, Some("Uploading report".to_string())))
+        );
+        assert_eq!(parse_progress_line(b"::progressive\n"), None);
+        assert_eq!(parse_progress_line(b"some other output\n"), None);
+    }
+
+    #[tokio::test]
+    async fn failure_includes_stderr() {
+        let exec = RawCommandExecutor::new();
+        let err = exec
+            .execute(
+                ExecutorState::new_test_state(),
+                payload(json!({
+                    "command": "/bin/sh",
+                    "args": ["-c", "echo 'no such file' >&2; exit 3"],
+                    "stdin": "ignored",
+                })),
+            )
+            .await
+            .expect_err("Command should fail");
+
+        match err {
+            ExecutorError::CommandError { source, result, .. } => {
+                assert_eq!(source.to_string(), "Exited with code 3: no such file");
+                assert_eq!(result["exitcode"], json!(3));
+            }
+            e => panic!("Expected command error, saw {:?}", e),
+        }
+    }
 }
@@ -0,0 +1,650 @@
+use super::{
+    execute::{Executor, ExecutorError},
+    template::{TemplateField, TemplateFieldFormat, TemplateFields},
+};
+use async_trait::async_trait;
+use fxhash::FxHashMap;
+use std::borrow::Cow;
+
+static FIELD_TEMPLATE: TemplateField = TemplateField::from_static(
+    "template",
+    TemplateFieldFormat::string_without_default(),
+    false,
+    "A Handlebars template for the report's HTML",
+);
+
+static FIELD_DATA: TemplateField = TemplateField::from_static(
+    "data",
+    TemplateFieldFormat::object_without_default(true),
+    true,
+    "The values to render the template with",
+);
+
+static FIELD_FORMAT: TemplateField = TemplateField::from_static(
+    "format",
+    TemplateFieldFormat::from_static_choices(
+        &[Cow::Borrowed("html"), Cow::Borrowed("pdf")],
+        Some(1),
+        Some(1),
+        &[Cow::Borrowed("html")],
+    ),
+    true,
+    "The format of the report. Defaults to HTML",
+);
+
+static FIELD_FILENAME: TemplateField = TemplateField::from_static(
+    "filename",
+    TemplateFieldFormat::string_without_default(),
+    true,
+    "The name of the report file. Defaults to report.html or report.pdf",
+);
+
+static FIELD_PDF_COMMAND: TemplateField = TemplateField::from_static(
+    "pdf_command",
+    TemplateFieldFormat::string_without_default(),
+    true,
+    "The executable that converts HTML to PDF. Defaults to the server's REPORT_PDF_COMMAND, or wkhtmltopdf",
+);
+
+static FIELD_PDF_ARGS: TemplateField = TemplateField::from_static(
+    "pdf_args",
+    TemplateFieldFormat::string_array_without_default(),
+    true,
+    "Arguments to the PDF converter. {input} and {output} are replaced with the paths of the HTML and PDF files",
+);
+
+static FIELD_TIMEOUT: TemplateField = TemplateField::from_static(
+    "timeout",
+    TemplateFieldFormat::Integer { default: 60 },
+    true,
+    "How long to let the PDF converter run, in seconds. Default is 60 seconds",
+);
+
+static FIELD_EMAIL_TO: TemplateField = TemplateField::from_static(
+    "email_to",
+    TemplateFieldFormat::string_array_without_default(),
+    true,
+    "Email the report to these addresses",
+);
+
+static FIELD_EMAIL_FROM: TemplateField = TemplateField::from_static(
+    "email_from",
+    TemplateFieldFormat::string_without_default(),
+    true,
+    "The address to send the email from",
+);
+
+static FIELD_EMAIL_SUBJECT: TemplateField = TemplateField::from_static(
+    "email_subject",
+    TemplateFieldFormat::String {
+        default: Cow::Borrowed("Report"),
+    },
+    true,
+    "The subject of the email",
+);
+
+static FIELD_SMTP_HOST: TemplateField = TemplateField::from_static(
+    "smtp_host",
+    TemplateFieldFormat::string_without_default(),
+    true,
+    "The SMTP server to send email through",
+);
+
+static FIELD_SMTP_PORT: TemplateField = TemplateField::from_static(
+    "smtp_port",
+    TemplateFieldFormat::integer_without_default(),
+    true,
+    "The SMTP server's port. Defaults to 465 with TLS, 587 with STARTTLS, and 25 otherwise",
+);
+
+static FIELD_SMTP_SECURITY: TemplateField = TemplateField::from_static(
+    "smtp_security",
+    TemplateFieldFormat::from_static_choices(
+        &[
+            Cow::Borrowed("starttls"),
+            Cow::Borrowed("tls"),
+            Cow::Borrowed("none"),
+        ],
+        Some(1),
+        Some(1),
+        &[Cow::Borrowed("starttls")],
+    ),
+    true,
+    "How to secure the SMTP connection. Defaults to STARTTLS",
+);
+
+static FIELD_SMTP_USERNAME: TemplateField = TemplateField::from_static(
+    "smtp_username",
+    TemplateFieldFormat::string_without_default(),
+    true,
+    "The username for the SMTP server",
+);
+
+static FIELD_SMTP_PASSWORD: TemplateField = TemplateField::from_static(
+    "smtp_password",
+    TemplateFieldFormat::string_without_default(),
+    true,
+    "The password for the SMTP server",
+);
+
+static FIELD_S3_BUCKET: TemplateField = TemplateField::from_static(
+    "s3_bucket",
+    TemplateFieldFormat::string_without_default(),
+    true,
+    "Upload the report to this S3 bucket",
+);
+
+static FIELD_S3_KEY: TemplateField = TemplateField::from_static(
+    "s3_key",
+    TemplateFieldFormat::string_without_default(),
+    true,
+    "The key to upload the report to. Defaults to the filename",
+);
+
+static FIELD_S3_REGION: TemplateField = TemplateField::from_static(
+    "s3_region",
+    TemplateFieldFormat::String {
+        default: Cow::Borrowed("us-east-1"),
+    },
+    true,
+    "The bucket's region. Defaults to us-east-1",
+);
+
+static FIELD_S3_ENDPOINT: TemplateField = TemplateField::from_static(
+    "s3_endpoint",
+    TemplateFieldFormat::string_without_default(),
+    true,
+    "The endpoint of an S3-compatible service, if not using AWS",
+);
+
+static FIELD_S3_ACCESS_KEY_ID: TemplateField = TemplateField::from_static(
+    "s3_access_key_id",
+    TemplateFieldFormat::string_without_default(),
+    true,
+    "The access key ID for the bucket",
+);
+
+static FIELD_S3_SECRET_ACCESS_KEY: TemplateField = TemplateField::from_static(
+    "s3_secret_access_key",
+    TemplateFieldFormat::string_without_default(),
+    true,
+    "The secret access key for the bucket",
+);
+
+/// Renders an HTML report from a template, optionally converts it to PDF, and delivers it by
+/// email or to S3. When the report isn't delivered anywhere, it is returned in the result.
+#[derive(Debug)]
+pub struct ReportExecutor {
+    template_fields: TemplateFields,
+}
+
+impl ReportExecutor {
+    pub fn new() -> ReportExecutor {
+        let template_fields = [
+            &FIELD_TEMPLATE,
+            &FIELD_DATA,
+            &FIELD_FORMAT,
+            &FIELD_FILENAME,
+            &FIELD_PDF_COMMAND,
+            &FIELD_PDF_ARGS,
+            &FIELD_TIMEOUT,
+            &FIELD_EMAIL_TO,
+            &FIELD_EMAIL_FROM,
+            &FIELD_EMAIL_SUBJECT,
+            &FIELD_SMTP_HOST,
+            &FIELD_SMTP_PORT,
+            &FIELD_SMTP_SECURITY,
+            &FIELD_SMTP_USERNAME,
+            &FIELD_SMTP_PASSWORD,
+            &FIELD_S3_BUCKET,
+            &FIELD_S3_KEY,
+            &FIELD_S3_REGION,
+            &FIELD_S3_ENDPOINT,
+            &FIELD_S3_ACCESS_KEY_ID,
+            &FIELD_S3_SECRET_ACCESS_KEY,
+        ]
+        .into();
+
+        ReportExecutor { template_fields }
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+mod native {
+    use std::{path::Path, process::Stdio, time::Duration};
+
+    use anyhow::anyhow;
+    use lettre::{
+        message::{header::ContentType, Attachment, MultiPart, SinglePart},
+        transport::smtp::authentication::Credentials,
+        AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+    };
+
+    use super::super::execute::ExecutorError;
+    use crate::egress::EgressPolicy;
+
+    fn command_error(e: impl Into<anyhow::Error>, permanent: bool) -> ExecutorError {
+        ExecutorError::CommandError {
+            source: e.into(),
+            result: serde_json::Value::Null,
+            permanent,
+        }
+    }
+
+    /// Render the report template. Unlike action templates, values are HTML-escaped.
+    pub fn render_html(template: &str, data: &serde_json::Value) -> Result<String, ExecutorError> {
+        let mut handlebars = handlebars::Handlebars::new();
+        handlebars.set_strict_mode(true);
+        handlebars
+            .render_template(template, data)
+            .map_err(|e| command_error(e, true))
+    }
+
+    /// Convert HTML to PDF by running an external command, such as wkhtmltopdf or a headless
+    /// browser. Like raw_command, the command does not inherit the server's environment.
+    pub async fn convert_to_pdf(
+        html: &str,
+        command: &str,
+        args: &[std::borrow::Cow<'_, str>],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, ExecutorError> {
+        let dir = std::env::temp_dir().join(format!("ergo-report-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .map_err(|e| command_error(e, false))?;
+
+        let result = run_converter(&dir, html, command, args, timeout).await;
+        tokio::fs::remove_dir_all(&dir).await.ok();
+        result
+    }
+
+    async fn run_converter(
+        dir: &Path,
+        html: &str,
+        command: &str,
+        args: &[std::borrow::Cow<'_, str>],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, ExecutorError> {
+        let input = dir.join("report.html");
+        let output = dir.join("report.pdf");
+        tokio::fs::write(&input, html)
+            .await
+            .map_err(|e| command_error(e, false))?;
+
+        let input_path = input.to_string_lossy();
+        let output_path = output.to_string_lossy();
+        let mut cmd = tokio::process::Command::new(command);
+        cmd.env_clear();
+        cmd.stdout(Stdio::null());
+        cmd.stderr(Stdio::piped());
+        cmd.kill_on_drop(true);
+        for arg in args {
+            cmd.arg(
+                arg.replace("{input}", &input_path)
+                    .replace("{output}", &output_path),
+            );
+        }
+
+        let result = tokio::time::timeout(timeout, cmd.output())
+            .await
+            .map_err(|_| command_error(anyhow!("PDF conversion timed out"), false))?
+            .map_err(|e| command_error(e, false))?;
+
+        if !result.status.success() {
+            return Err(ExecutorError::CommandError {
+                source: anyhow!("PDF conversion failed"),
+                result: serde_json::json!({
+                    "exitcode": result.status.code(),
+                    "stderr": String::from_utf8_lossy(&result.stderr),
+                }),
+                permanent: false,
+            });
+        }
+
+        tokio::fs::read(&output)
+            .await
+            .map_err(|e| command_error(anyhow!("Reading converted PDF: {}", e), false))
+    }
+
+    pub struct EmailOptions<'a> {
+        pub host: &'a str,
+        pub port: u16,
+        pub security: &'a str,
+        pub username: &'a str,
+        pub password: &'a str,
+        pub from: &'a str,
+        pub to: &'a [std::borrow::Cow<'a, str>],
+        pub subject: &'a str,
+    }
+
+    pub async fn send_email(
+        egress: &EgressPolicy,
+        options: EmailOptions<'_>,
+        filename: &str,
+        content_type: &str,
+        report: Vec<u8>,
+    ) -> Result<(), ExecutorError> {
+        let port = match (options.port, options.security) {
+            (0, "tls") => 465,
+            (0, "starttls") => 587,
+            (0, _) => 25,
+            (port, _) => port,
+        };
+
+        let url = url::Url::parse(&format!("smtp://{}:{}", options.host, port)).map_err(|_| {
+            ExecutorError::FieldFormatError {
+                field: "smtp_host".to_string(),
+                subfield: None,
+                expected: "Valid hostname".to_string(),
+            }
+        })?;
+        egress.check_url(&url).await?;
+
+        let invalid_address = |field: &str| ExecutorError::FieldFormatError {
+            field: field.to_string(),
+            subfield: None,
+            expected: "Email address".to_string(),
+        };
+
+        let mut message = Message::builder()
+            .from(
+                options
+                    .from
+                    .parse()
+                    .map_err(|_| invalid_address("email_from"))?,
+            )
+            .subject(options.subject);
+        for to in options.to {
+            message = message.to(to.parse().map_err(|_| invalid_address("email_to"))?);
+        }
+
+        let content_type = ContentType::parse(content_type).map_err(|e| command_error(e, true))?;
+        let message = message
+            .multipart(
+                MultiPart::mixed()
+                    .singlepart(SinglePart::plain(format!("Attached: {}", filename)))
+                    .singlepart(Attachment::new(filename.to_string()).body(report, content_type)),
+            )
+            .map_err(|e| command_error(e, true))?;
+
+        let builder = match options.security {
+            "tls" => AsyncSmtpTransport::<Tokio1Executor>::relay(options.host),
+            "none" => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(
+                options.host,
+            )),
+            _ => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(options.host),
+        }
+        .map_err(|e| command_error(e, true))?;
+
+        let mut builder = builder.port(port).timeout(Some(Duration::from_secs(30)));
+        if !options.username.is_empty() {
+            builder = builder.credentials(Credentials::new(
+                options.username.to_string(),
+                options.password.to_string(),
+            ));
+        }
+
+        builder
+            .build()
+            .send(message)
+            .await
+            .map_err(|e| command_error(e, false))?;
+
+        Ok(())
+    }
+
+    pub struct S3Options<'a> {
+        pub bucket: &'a str,
+        pub key: &'a str,
+        pub region: &'a str,
+        pub endpoint: &'a str,
+        pub access_key_id: &'a str,
+        pub secret_access_key: &'a str,
+    }
+
+    /// Upload the report, and return its location.
+    pub async fn upload_to_s3(
+        egress: &EgressPolicy,
+        options: S3Options<'_>,
+        content_type: &str,
+        report: &[u8],
+    ) -> Result<String, ExecutorError> {
+        let region = if options.endpoint.is_empty() {
+            options
+                .region
+                .parse()
+                .map_err(|_| ExecutorError::FieldFormatError {
+                    field: "s3_region".to_string(),
+                    subfield: None,
+                    expected: "AWS region".to_string(),
+                })?
+        } else {
+            s3::Region::Custom {
+                region: options.region.to_string(),
+                endpoint: options.endpoint.to_string(),
+            }
+        };
+
+        let credentials = if options.access_key_id.is_empty() {
+            s3::creds::Credentials::anonymous()
+        } else {
+            s3::creds::Credentials::new(
+                Some(options.access_key_id),
+                Some(options.secret_access_key),
+                None,
+                None,
+                None,
+            )
+        }
+        .map_err(|e| command_error(e, true))?;
+
+        let mut bucket = s3::Bucket::new(options.bucket, region, credentials)
+            .map_err(|e| command_error(e, true))?;
+        if !options.endpoint.is_empty() {
+            bucket.set_path_style();
+        }
+
+        let url = url::Url::parse(&bucket.url()).map_err(|e| command_error(e, true))?;
+        egress.check_url(&url).await?;
+
+        let response = bucket
+            .put_object_with_content_type(options.key, report, content_type)
+            .await
+            .map_err(|e| command_error(e, false))?;
+        if response.status_code() >= 300 {
+            return Err(ExecutorError::CommandError {
+                source: anyhow!("Upload to S3 returned status {}", response.status_code()),
+                result: serde_json::json!({
+                    "status": response.status_code(),
+                    "body": String::from_utf8_lossy(response.bytes()),
+                }),
+                permanent: false,
+            });
+        }
+
+        Ok(format!("s3://{}/{}", options.bucket, options.key))
+    }
+}
+
+#[async_trait]
+impl Executor for ReportExecutor {
+    fn name(&self) -> &'static str {
+        "report"
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[tracing::instrument(
+        level = "debug",
+        name = "ReportExecutor::execute",
+        skip(state, payload)
+    )]
+    async fn execute(
+        &self,
+        state: super::execute::ExecutorState,
+        payload: FxHashMap<String, serde_json::Value>,
+    ) -> Result<serde_json::Value, ExecutorError> {
+        use native::*;
+        use std::time::Duration;
+
+        let template = FIELD_TEMPLATE.extract_str(&payload)?;
+        let data = FIELD_DATA.extract_object(&payload)?;
+        let html = render_html(&template, &data)?;
+
+        let pdf = FIELD_FORMAT
+            .extract_string_array(&payload)?
+            .first()
+            .map(|f| f == "pdf")
+            .unwrap_or(false);
+        let (report, content_type, extension) = if pdf {
+            let command = FIELD_PDF_COMMAND.extract_str(&payload)?;
+            let command = if command.is_empty() {
+                std::env::var("REPORT_PDF_COMMAND").unwrap_or_else(|_| "wkhtmltopdf".to_string())
+            } else {
+                command.into_owned()
+            };
+
+            let mut args = FIELD_PDF_ARGS.extract_string_array(&payload)?;
+            if args.is_empty() {
+                args = vec![
+                    Cow::Borrowed("--quiet"),
+                    Cow::Borrowed("{input}"),
+                    Cow::Borrowed("{output}"),
+                ];
+            }
+
+            let timeout: u64 = FIELD_TIMEOUT.extract(&payload)?;
+            let pdf = convert_to_pdf(&html, &command, &args, Duration::from_secs(timeout)).await?;
+            (pdf, "application/pdf", "pdf")
+        } else {
+            (html.into_bytes(), "text/html; charset=utf-8", "html")
+        };
+
+        let filename = FIELD_FILENAME.extract_str(&payload)?;
+        let filename = if filename.is_empty() {
+            format!("report.{}", extension)
+        } else {
+            filename.into_owned()
+        };
+
+        let mut delivered = serde_json::Map::new();
+
+        let email_to = FIELD_EMAIL_TO.extract_string_array(&payload)?;
+        if !email_to.is_empty() {
+            let port: u16 = FIELD_SMTP_PORT.extract(&payload)?;
+            let security = FIELD_SMTP_SECURITY.extract_string_array(&payload)?;
+            let options = EmailOptions {
+                host: &FIELD_SMTP_HOST.extract_str(&payload)?,
+                port,
+                security: security.first().map(|s| s.as_ref()).unwrap_or("starttls"),
+                username: &FIELD_SMTP_USERNAME.extract_str(&payload)?,
+                password: &FIELD_SMTP_PASSWORD.extract_str(&payload)?,
+                from: &FIELD_EMAIL_FROM.extract_str(&payload)?,
+                to: &email_to,
+                subject: &FIELD_EMAIL_SUBJECT.extract_str(&payload)?,
+            };
+            send_email(
+                &state.egress,
+                options,
+                &filename,
+                content_type,
+                report.clone(),
+            )
+            .await?;
+            delivered.insert("email".to_string(), serde_json::json!(email_to));
+        }
+
+        let bucket = FIELD_S3_BUCKET.extract_str(&payload)?;
+        if !bucket.is_empty() {
+            let key = FIELD_S3_KEY.extract_str(&payload)?;
+            let options = S3Options {
+                bucket: &bucket,
+                key: if key.is_empty() { &filename } else { &key },
+                region: &FIELD_S3_REGION.extract_str(&payload)?,
+                endpoint: &FIELD_S3_ENDPOINT.extract_str(&payload)?,
+                access_key_id: &FIELD_S3_ACCESS_KEY_ID.extract_str(&payload)?,
+                secret_access_key: &FIELD_S3_SECRET_ACCESS_KEY.extract_str(&payload)?,
+            };
+            let location = upload_to_s3(&state.egress, options, content_type, &report).await?;
+            delivered.insert("s3".to_string(), serde_json::json!(location));
+        }
+
+        let inline = delivered.is_empty();
+        let mut result = serde_json::json!({
+            "filename": filename,
+            "content_type": content_type,
+            "size": report.len(),
+            "delivered": delivered,
+        });
+
+        // With nowhere to deliver it, the report goes in the result. Large results are moved
+        // to artifact storage.
+        if inline {
+            result["content"] = if pdf {
+                serde_json::json!(base64::encode(&report))
+            } else {
+                serde_json::json!(String::from_utf8_lossy(&report))
+            };
+        }
+
+        Ok(result)
+    }
+
+    fn template_fields(&self) -> &TemplateFields {
+        &self.template_fields
+    }
+}
+
+#[cfg(all(test, not(target_family = "wasm")))]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::actions::execute::ExecutorState;
+
+    #[test]
+    fn render_escapes_values() {
+        let html = native::render_html(
+            "<p>{{name}}</p><ul>{{#each rows}}<li>{{this}}</li>{{/each}}</ul>",
+            &json!({ "name": "<b>Q1</b>", "rows": [1, 2] }),
+        )
+        .unwrap();
+        assert_eq!(
+            html,
+            "<p>&lt;b&gt;Q1&lt;/b&gt;</p><ul><li>1</li><li>2</li></ul>"
+        );
+    }
+
+    #[test]
+    fn render_missing_value() {
+        let err = native::render_html("{{missing}}", &json!({})).unwrap_err();
+        assert!(err.is_permanent());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn converts_with_command() {
+        let payload = [
+            ("template", json!("<h1>{{title}}</h1>")),
+            ("data", json!({ "title": "Weekly" })),
+            ("format", json!(["pdf"])),
+            ("pdf_command", json!("/bin/cp")),
+            ("pdf_args", json!(["{input}", "{output}"])),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect::<FxHashMap<_, _>>();
+
+        let result = ReportExecutor::new()
+            .execute(ExecutorState::new_test_state(), payload)
+            .await
+            .unwrap();
+
+        assert_eq!(result["filename"], json!("report.pdf"));
+        assert_eq!(result["content_type"], json!("application/pdf"));
+        assert_eq!(
+            result["content"],
+            json!(base64::encode("<h1>Weekly</h1>")),
+            "the copied file is returned as the PDF"
+        );
+    }
+}
@@ -1,6 +1,8 @@
 #[cfg(not(target_family = "wasm"))]
-use crate::inputs::{enqueue_input, EnqueueInputOptions};
+use crate::inputs::{chain::MAX_CHAIN_DEPTH, enqueue_input, EnqueueInputOptions};
 
+#[cfg(not(target_family = "wasm"))]
+use super::execute::{ExecutorError, ExecutorState};
 use super::{
     execute::Executor,
     template::{TemplateField, TemplateFieldFormat, TemplateFields},
@@ -15,12 +17,14 @@ use ergo_auth::get_user_info;
 use ergo_database::object_id::{InputId, TaskId, TaskTriggerId};
 #[cfg(not(target_family = "wasm"))]
 use sqlx::Connection;
+#[cfg(not(target_family = "wasm"))]
+use uuid::Uuid;
 
 static FIELD_TASK: TemplateField = TemplateField::from_static(
     "task",
     TemplateFieldFormat::string_without_default(),
     false,
-    "The task to send the input to",
+    "The ID or alias of the task to send the input to",
 );
 
 static FIELD_TRIGGER: TemplateField = TemplateField::from_static(
@@ -64,14 +68,7 @@ impl Executor for SendInputExecutor {
         state: super::execute::ExecutorState,
         template_values: fxhash::FxHashMap<String, serde_json::Value>,
     ) -> Result<serde_json::Value, super::execute::ExecutorError> {
-        use super::execute::ExecutorError;
-
-        let task_id = TaskId::from_str(FIELD_TASK.extract_str(&template_values)?.as_ref())
-            .map_err(|e| ExecutorError::FieldFormatError {
-                field: FIELD_TASK.name.to_string(),
-                subfield: None,
-                expected: e.to_string(),
-            })?;
+        let task = FIELD_TASK.extract_str(&template_values)?;
         let trigger_name = FIELD_TRIGGER.extract_str(&template_values)?;
 
         let time_arg = FIELD_TIME.extract_str(&template_values)?;
@@ -90,68 +87,12 @@ impl Executor for SendInputExecutor {
 
         let payload = FIELD_PAYLOAD.extract_object(&template_values)?.into_owned();
 
-        let pg_pool = state.pg_pool.ok_or(ExecutorError::MissingDatabase)?;
-        let mut conn = pg_pool
-            .acquire()
-            .await
-            .map_err(ExecutorError::command_error_without_result)?;
-        let mut tx = conn
-            .begin()
-            .await
-            .map_err(ExecutorError::command_error_without_result)?;
-        let user = get_user_info(&mut tx, &state.user_id, None)
-            .await
-            .map_err(ExecutorError::command_error_without_result)?;
-
-        let data = sqlx::query!(
-            r##"SELECT tasks.task_id as "task_id: TaskId",
-                tasks.name as task_name,
-                tt.name as task_trigger_name,
-                task_trigger_id as "task_trigger_id: TaskTriggerId",
-                input_id as "input_id: InputId",
-                inputs.payload_schema
-            FROM task_triggers tt
-            JOIN tasks USING(task_id)
-            JOIN inputs USING (input_id)
-            WHERE org_id=$2 AND task_trigger_local_id = $3 AND task_id=$4 AND EXISTS (
-                SELECT 1 FROM user_entity_permissions
-                WHERE user_entity_id = ANY($1)
-                AND permission_type = 'trigger_event'
-                AND permissioned_object IN (uuid_nil(), task_trigger_id)
-            )"##,
-            user.user_entity_ids.as_slice(),
-            &user.org_id.0,
-            trigger_name.as_ref(),
-            &task_id.0,
-        )
-        .fetch_one(&mut tx)
-        .await
-        .map_err(ExecutorError::command_error_without_result)?;
-
-        let mut conn = pg_pool
-            .acquire()
-            .await
-            .map_err(ExecutorError::command_error_without_result)?;
-        enqueue_input(EnqueueInputOptions {
-            pg: &mut conn,
-            notifications: None,
-            org_id: user.org_id.clone(),
-            user_id: user.user_id.clone(),
-            task_id,
-            input_id: data.input_id,
-            task_trigger_id: data.task_trigger_id,
-            task_trigger_local_id: trigger_name.to_string(),
-            task_trigger_name: data.task_trigger_name,
-            task_name: data.task_name,
-            payload_schema: &data.payload_schema,
-            payload,
-            redis_key_prefix: state.redis_key_prefix.as_deref(),
-            trigger_at: when,
-            periodic_trigger_id: None,
-        })
-        .await
-        .map_err(ExecutorError::command_error_without_result)?;
+        let task = match TaskId::from_str(task.as_ref()) {
+            Ok(id) => TargetTask::Id(id),
+            Err(_) => TargetTask::Alias(task.as_ref()),
+        };
 
+        send_input(state, task, trigger_name.as_ref(), payload, when).await?;
         Ok(serde_json::Value::Null)
     }
 
@@ -163,3 +104,104 @@ impl Executor for SendInputExecutor {
         &self.template_fields
     }
 }
+
+/// The task to send an input to.
+#[cfg(not(target_family = "wasm"))]
+pub(super) enum TargetTask<'a> {
+    Id(TaskId),
+    Alias(&'a str),
+}
+
+/// Send an input to a task's trigger, as the user in `state`. Returns the new input's log ID.
+#[cfg(not(target_family = "wasm"))]
+pub(super) async fn send_input(
+    state: ExecutorState,
+    task: TargetTask<'_>,
+    trigger_name: &str,
+    payload: serde_json::Value,
+    when: Option<DateTime<Utc>>,
+) -> Result<Uuid, ExecutorError> {
+    let (task_id, task_alias) = match task {
+        TargetTask::Id(id) => (Some(id.0), None),
+        TargetTask::Alias(alias) => (None, Some(alias)),
+    };
+
+    let pg_pool = state.pg_pool.ok_or(ExecutorError::MissingDatabase)?;
+    let mut conn = pg_pool
+        .acquire()
+        .await
+        .map_err(ExecutorError::command_error_without_result)?;
+    let mut tx = conn
+        .begin()
+        .await
+        .map_err(ExecutorError::command_error_without_result)?;
+    let user = get_user_info(&mut tx, &state.user_id, Some(&state.org_id), None)
+        .await
+        .map_err(ExecutorError::command_error_without_result)?;
+
+    let data = sqlx::query!(
+        r##"SELECT tasks.task_id as "task_id: TaskId",
+            tasks.name as task_name,
+            tt.name as task_trigger_name,
+            task_trigger_id as "task_trigger_id: TaskTriggerId",
+            input_id as "input_id: InputId",
+            inputs.payload_schema
+        FROM task_triggers tt
+        JOIN tasks USING(task_id)
+        JOIN inputs USING (input_id)
+        WHERE org_id=$2 AND task_trigger_local_id = $3 AND NOT tasks.deleted
+            AND (task_id=$4 OR alias=$5) AND EXISTS (
+            SELECT 1 FROM user_entity_permissions
+            WHERE user_entity_id = ANY($1)
+            AND permission_type = 'trigger_event'
+            AND permissioned_object IN (uuid_nil(), task_trigger_id, task_id)
+        )"##,
+        user.user_entity_ids.as_slice(),
+        &user.org_id.0,
+        trigger_name,
+        task_id,
+        task_alias,
+    )
+    .fetch_one(&mut tx)
+    .await
+    .map_err(ExecutorError::command_error_without_result)?;
+
+    // Stop chains of tasks that loop back on themselves or grow without bound.
+    state
+        .chain
+        .check_target(&data.task_id, *MAX_CHAIN_DEPTH)
+        .map_err(|e| ExecutorError::CommandError {
+            source: e.into(),
+            result: serde_json::json!({ "chain": &state.chain }),
+            permanent: true,
+        })?;
+
+    let mut conn = pg_pool
+        .acquire()
+        .await
+        .map_err(ExecutorError::command_error_without_result)?;
+    let inputs_log_id = enqueue_input(EnqueueInputOptions {
+        pg: &mut conn,
+        notifications: None,
+        org_id: user.org_id.clone(),
+        user_id: user.user_id.clone(),
+        task_id: data.task_id,
+        input_id: data.input_id,
+        task_trigger_id: data.task_trigger_id,
+        task_trigger_local_id: trigger_name.to_string(),
+        task_trigger_name: data.task_trigger_name,
+        task_name: data.task_name,
+        payload_schema: &data.payload_schema,
+        payload,
+        redis_key_prefix: state.redis_key_prefix.as_deref(),
+        trigger_at: when,
+        replay_of: None,
+        interactive: false,
+        chain: state.chain,
+        periodic_trigger_id: None,
+    })
+    .await
+    .map_err(ExecutorError::command_error_without_result)?;
+
+    Ok(inputs_log_id)
+}
@@ -0,0 +1,492 @@
+use std::borrow::Cow;
+
+use super::{
+    execute::{Executor, ExecutorError},
+    template::{TemplateField, TemplateFieldFormat, TemplateFields},
+};
+use async_trait::async_trait;
+use fxhash::FxHashMap;
+
+static FIELD_SPREADSHEET_ID: TemplateField = TemplateField::from_static(
+    "spreadsheet_id",
+    TemplateFieldFormat::string_without_default(),
+    false,
+    "The ID of the spreadsheet, from its URL",
+);
+
+static FIELD_RANGE: TemplateField = TemplateField::from_static(
+    "range",
+    TemplateFieldFormat::string_without_default(),
+    false,
+    "The range to write to, in A1 notation such as `Sheet1!A:D`. When appending, rows are added after the last table in the range",
+);
+
+static FIELD_VALUES: TemplateField = TemplateField::from_static(
+    "values",
+    TemplateFieldFormat::StringArray {
+        default: Cow::Borrowed(&[]),
+    },
+    false,
+    "The cell values for a single row, or a list of rows that are each a list of cell values",
+);
+
+static FIELD_MODE: TemplateField = TemplateField::from_static(
+    "mode",
+    TemplateFieldFormat::from_static_choices(
+        &[Cow::Borrowed("append"), Cow::Borrowed("update")],
+        Some(1),
+        Some(1),
+        &[Cow::Borrowed("append")],
+    ),
+    true,
+    "Append the rows after the existing data, or overwrite the cells in the range. Defaults to append",
+);
+
+static FIELD_VALUE_INPUT: TemplateField = TemplateField::from_static(
+    "value_input",
+    TemplateFieldFormat::from_static_choices(
+        &[Cow::Borrowed("USER_ENTERED"), Cow::Borrowed("RAW")],
+        Some(1),
+        Some(1),
+        &[Cow::Borrowed("USER_ENTERED")],
+    ),
+    true,
+    "USER_ENTERED parses values as if they were typed into the sheet, so formulas and dates work. RAW stores them as they are. Defaults to USER_ENTERED",
+);
+
+static FIELD_ACCESS_TOKEN: TemplateField = TemplateField::from_static(
+    "access_token",
+    TemplateFieldFormat::string_without_default(),
+    true,
+    "An OAuth access token. Not needed when the refresh token fields are set",
+);
+
+static FIELD_REFRESH_TOKEN: TemplateField = TemplateField::from_static(
+    "refresh_token",
+    TemplateFieldFormat::string_without_default(),
+    true,
+    "An OAuth refresh token, used with the client ID and secret to get a new access token for each run",
+);
+
+static FIELD_CLIENT_ID: TemplateField = TemplateField::from_static(
+    "client_id",
+    TemplateFieldFormat::string_without_default(),
+    true,
+    "The OAuth client ID that the refresh token was issued to",
+);
+
+static FIELD_CLIENT_SECRET: TemplateField = TemplateField::from_static(
+    "client_secret",
+    TemplateFieldFormat::string_without_default(),
+    true,
+    "The OAuth client secret",
+);
+
+static FIELD_TIMEOUT: TemplateField = TemplateField::from_static(
+    "timeout",
+    TemplateFieldFormat::Integer { default: 30 },
+    true,
+    "The timeout for each request to Google, in seconds. Default is 30 seconds",
+);
+
+/// Writes rows to a Google Sheets spreadsheet. The OAuth credentials usually come from a
+/// `google_oauth` account linked to the action.
+#[derive(Debug)]
+pub struct SheetsExecutor {
+    template_fields: TemplateFields,
+}
+
+impl SheetsExecutor {
+    pub fn new() -> SheetsExecutor {
+        let template_fields = [
+            &FIELD_SPREADSHEET_ID,
+            &FIELD_RANGE,
+            &FIELD_VALUES,
+            &FIELD_MODE,
+            &FIELD_VALUE_INPUT,
+            &FIELD_ACCESS_TOKEN,
+            &FIELD_REFRESH_TOKEN,
+            &FIELD_CLIENT_ID,
+            &FIELD_CLIENT_SECRET,
+            &FIELD_TIMEOUT,
+        ]
+        .into();
+
+        SheetsExecutor { template_fields }
+    }
+}
+
+/// Turn the `values` field into a list of rows. A list where every value is a list is already a
+/// list of rows, and anything else is a single row.
+#[cfg(not(target_family = "wasm"))]
+fn rows(values: serde_json::Value) -> Vec<serde_json::Value> {
+    match values {
+        serde_json::Value::Array(values) if values.iter().all(|v| v.is_array()) => values,
+        serde_json::Value::Array(values) => vec![serde_json::Value::Array(values)],
+        value => vec![serde_json::Value::Array(vec![value])],
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+mod native {
+    use anyhow::anyhow;
+    use serde_json::json;
+
+    use super::super::{execute::ExecutorError, http_executor::is_permanent_status};
+
+    pub const SHEETS_API_URL: &str = "https://sheets.googleapis.com/v4";
+    pub const TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+
+    pub struct RefreshCredentials<'a> {
+        pub refresh_token: &'a str,
+        pub client_id: &'a str,
+        pub client_secret: &'a str,
+    }
+
+    pub struct SheetsRequest<'a> {
+        pub spreadsheet_id: &'a str,
+        pub range: &'a str,
+        pub append: bool,
+        pub value_input: &'a str,
+        pub rows: Vec<serde_json::Value>,
+    }
+
+    fn request_error(e: reqwest::Error) -> ExecutorError {
+        ExecutorError::CommandError {
+            permanent: e.status().map(is_permanent_status).unwrap_or(false),
+            source: anyhow!(e),
+            result: json!(null),
+        }
+    }
+
+    /// Exchange a refresh token for a new access token.
+    pub async fn refresh_access_token(
+        client: &reqwest::Client,
+        token_url: &str,
+        credentials: RefreshCredentials<'_>,
+    ) -> Result<String, ExecutorError> {
+        let response = client
+            .post(token_url)
+            .form(&[
+                ("grant_type", "refresh_token"),
+                ("refresh_token", credentials.refresh_token),
+                ("client_id", credentials.client_id),
+                ("client_secret", credentials.client_secret),
+            ])
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(request_error)?
+            .json::<serde_json::Value>()
+            .await
+            .map_err(request_error)?;
+
+        response["access_token"]
+            .as_str()
+            .map(|s| s.to_string())
+            .ok_or_else(|| ExecutorError::CommandError {
+                source: anyhow!("Token response did not contain an access token"),
+                result: json!(null),
+                permanent: true,
+            })
+    }
+
+    pub fn values_url(
+        api_url: &str,
+        spreadsheet_id: &str,
+        range: &str,
+        append: bool,
+    ) -> Result<reqwest::Url, ExecutorError> {
+        let mut url = reqwest::Url::parse(api_url).map_err(|e| ExecutorError::CommandError {
+            source: anyhow!(e),
+            result: json!(null),
+            permanent: true,
+        })?;
+
+        let range_segment = if append {
+            format!("{}:append", range)
+        } else {
+            range.to_string()
+        };
+
+        url.path_segments_mut()
+            .map_err(|_| ExecutorError::CommandError {
+                source: anyhow!("Sheets API URL must be an HTTP URL"),
+                result: json!(null),
+                permanent: true,
+            })?
+            .pop_if_empty()
+            .extend(["spreadsheets", spreadsheet_id, "values", &range_segment]);
+        Ok(url)
+    }
+
+    /// Write the rows and return a summary of what changed.
+    pub async fn write_values(
+        client: &reqwest::Client,
+        url: reqwest::Url,
+        access_token: &str,
+        request: SheetsRequest<'_>,
+    ) -> Result<serde_json::Value, ExecutorError> {
+        let body = json!({
+            "range": request.range,
+            "majorDimension": "ROWS",
+            "values": request.rows,
+        });
+
+        let builder = if request.append {
+            client
+                .post(url)
+                .query(&[("insertDataOption", "INSERT_ROWS")])
+        } else {
+            client.put(url)
+        };
+
+        let response = builder
+            .query(&[("valueInputOption", request.value_input)])
+            .bearer_auth(access_token)
+            .json(&body)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(request_error)?
+            .json::<serde_json::Value>()
+            .await
+            .map_err(request_error)?;
+
+        // Appending nests the same summary that an update returns.
+        let updates = if request.append {
+            &response["updates"]
+        } else {
+            &response
+        };
+
+        Ok(json!({
+            "spreadsheet_id": request.spreadsheet_id,
+            "updated_range": updates["updatedRange"],
+            "updated_rows": updates["updatedRows"],
+            "updated_cells": updates["updatedCells"],
+        }))
+    }
+}
+
+#[async_trait]
+impl Executor for SheetsExecutor {
+    fn name(&self) -> &'static str {
+        "sheets"
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[tracing::instrument(
+        level = "debug",
+        name = "SheetsExecutor::execute",
+        skip(state, payload)
+    )]
+    async fn execute(
+        &self,
+        state: super::execute::ExecutorState,
+        payload: FxHashMap<String, serde_json::Value>,
+    ) -> Result<serde_json::Value, ExecutorError> {
+        use native::*;
+
+        let spreadsheet_id = FIELD_SPREADSHEET_ID.extract_str(&payload)?;
+        let range = FIELD_RANGE.extract_str(&payload)?;
+        let values = payload
+            .get(FIELD_VALUES.name.as_ref())
+            .cloned()
+            .ok_or_else(|| ExecutorError::MissingFieldError(FIELD_VALUES.name.to_string()))?;
+        let append = FIELD_MODE
+            .extract_string_array(&payload)?
+            .first()
+            .map(|m| m == "append")
+            .unwrap_or(true);
+        let value_input = FIELD_VALUE_INPUT
+            .extract_string_array(&payload)?
+            .drain(..)
+            .next()
+            .unwrap_or(Cow::Borrowed("USER_ENTERED"));
+        let timeout: u64 = FIELD_TIMEOUT.extract(&payload)?;
+
+        let url = values_url(SHEETS_API_URL, &spreadsheet_id, &range, append)?;
+        state.egress.check_url(&url).await?;
+
+        let client = reqwest::ClientBuilder::new()
+            .user_agent("Ergo")
+            .timeout(std::time::Duration::from_secs(timeout))
+            .build()
+            .map_err(ExecutorError::command_error_without_result)?;
+
+        let refresh_token = FIELD_REFRESH_TOKEN.extract_str(&payload)?;
+        let access_token = if refresh_token.is_empty() {
+            let token = FIELD_ACCESS_TOKEN.extract_str(&payload)?;
+            if token.is_empty() {
+                return Err(ExecutorError::MissingFieldError(
+                    FIELD_ACCESS_TOKEN.name.to_string(),
+                ));
+            }
+            token.into_owned()
+        } else {
+            let token_url = reqwest::Url::parse(TOKEN_URL)
+                .map_err(ExecutorError::command_error_without_result)?;
+            state.egress.check_url(&token_url).await?;
+            refresh_access_token(
+                &client,
+                TOKEN_URL,
+                RefreshCredentials {
+                    refresh_token: &refresh_token,
+                    client_id: &FIELD_CLIENT_ID.extract_str(&payload)?,
+                    client_secret: &FIELD_CLIENT_SECRET.extract_str(&payload)?,
+                },
+            )
+            .await?
+        };
+
+        write_values(
+            &client,
+            url,
+            &access_token,
+            SheetsRequest {
+                spreadsheet_id: &spreadsheet_id,
+                range: &range,
+                append,
+                value_input: &value_input,
+                rows: rows(values),
+            },
+        )
+        .await
+    }
+
+    fn template_fields(&self) -> &TemplateFields {
+        &self.template_fields
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{native::*, *};
+    use serde_json::json;
+    use wiremock::{
+        matchers::{body_json, body_string_contains, header, method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    #[test]
+    fn values_to_rows() {
+        assert_eq!(rows(json!(["a", 1])), vec![json!(["a", 1])]);
+        assert_eq!(
+            rows(json!([["a", 1], ["b", 2]])),
+            vec![json!(["a", 1]), json!(["b", 2])]
+        );
+        assert_eq!(rows(json!("a")), vec![json!(["a"])]);
+    }
+
+    #[test]
+    fn range_is_escaped() {
+        let url = values_url(SHEETS_API_URL, "abc", "My Sheet!A:C", true).unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://sheets.googleapis.com/v4/spreadsheets/abc/values/My%20Sheet!A:C:append"
+        );
+    }
+
+    #[tokio::test]
+    async fn refresh_and_append() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .and(body_string_contains("refresh_token=the-refresh-token"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({ "access_token": "new-token" })),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        Mock::given(method("POST"))
+            .and(path("/spreadsheets/abc/values/Sheet1!A:B:append"))
+            .and(query_param("valueInputOption", "USER_ENTERED"))
+            .and(query_param("insertDataOption", "INSERT_ROWS"))
+            .and(header("Authorization", "Bearer new-token"))
+            .and(body_json(json!({
+                "range": "Sheet1!A:B",
+                "majorDimension": "ROWS",
+                "values": [["x", 5]],
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "spreadsheetId": "abc",
+                "updates": {
+                    "updatedRange": "Sheet1!A10:B10",
+                    "updatedRows": 1,
+                    "updatedCells": 2,
+                }
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let client = reqwest::Client::new();
+        let token = refresh_access_token(
+            &client,
+            &format!("{}/token", mock_server.uri()),
+            RefreshCredentials {
+                refresh_token: "the-refresh-token",
+                client_id: "client",
+                client_secret: "secret",
+            },
+        )
+        .await
+        .expect("refreshing token");
+
+        let url = values_url(&mock_server.uri(), "abc", "Sheet1!A:B", true).unwrap();
+        let result = write_values(
+            &client,
+            url,
+            &token,
+            SheetsRequest {
+                spreadsheet_id: "abc",
+                range: "Sheet1!A:B",
+                append: true,
+                value_input: "USER_ENTERED",
+                rows: rows(json!(["x", 5])),
+            },
+        )
+        .await
+        .expect("appending rows");
+
+        assert_eq!(
+            result,
+            json!({
+                "spreadsheet_id": "abc",
+                "updated_range": "Sheet1!A10:B10",
+                "updated_rows": 1,
+                "updated_cells": 2,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn update_forbidden_is_permanent() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .and(path("/spreadsheets/abc/values/Sheet1!A1:B2"))
+            .respond_with(ResponseTemplate::new(403))
+            .mount(&mock_server)
+            .await;
+
+        let url = values_url(&mock_server.uri(), "abc", "Sheet1!A1:B2", false).unwrap();
+        let err = write_values(
+            &reqwest::Client::new(),
+            url,
+            "token",
+            SheetsRequest {
+                spreadsheet_id: "abc",
+                range: "Sheet1!A1:B2",
+                append: false,
+                value_input: "RAW",
+                rows: rows(json!([["a", "b"], ["c", "d"]])),
+            },
+        )
+        .await
+        .expect_err("forbidden");
+        assert!(err.is_permanent());
+    }
+}
@@ -2,7 +2,9 @@ use std::{borrow::Cow, fmt::Display};
 
 use assert_matches::assert_matches;
 use ergo_database::sqlx_json_decode;
+use ergo_localization::{format_message, Localize};
 use fxhash::FxHashMap;
+use handlebars::{Context, Handlebars, Helper, HelperDef, RenderContext, RenderError, ScopedJson};
 use itertools::Itertools;
 use lazy_static::lazy_static;
 use schemars::JsonSchema;
@@ -17,10 +19,96 @@ lazy_static! {
         let mut h = handlebars::Handlebars::new();
         h.strict_mode();
         h.register_escape_fn(|s| s.to_string());
+        h.register_helper("default", Box::new(DefaultHelper));
+        h.register_helper("json", Box::new(JsonHelper));
+        h.register_helper("jsonpath", Box::new(JsonPathHelper));
         h
     };
 }
 
+/// `{{default value "fallback"}}` returns the fallback when the value is missing, null, or an
+/// empty string.
+struct DefaultHelper;
+
+impl HelperDef for DefaultHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        let value = h
+            .param(0)
+            .map(|p| p.value())
+            .filter(|v| !v.is_null() && v.as_str() != Some(""));
+        let value = match value {
+            Some(v) => v.clone(),
+            None => h
+                .param(1)
+                .map(|p| p.value().clone())
+                .ok_or_else(|| RenderError::new("Helper \"default\" needs a fallback value"))?,
+        };
+
+        Ok(ScopedJson::Derived(value))
+    }
+}
+
+/// `{{json value}}` formats a value as JSON.
+struct JsonHelper;
+
+impl HelperDef for JsonHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        let value = h
+            .param(0)
+            .ok_or_else(|| RenderError::new("Helper \"json\" needs a value"))?
+            .value();
+        let text = serde_json::to_string(value)
+            .map_err(|e| RenderError::from_error("Formatting JSON", e))?;
+        Ok(ScopedJson::Derived(serde_json::Value::String(text)))
+    }
+}
+
+/// `{{jsonpath value "$.items[0].name"}}` looks up a JSON path in a value. This returns the
+/// value if the path matches one value, an array if it matches more than one, and null if it
+/// matches nothing.
+struct JsonPathHelper;
+
+impl HelperDef for JsonPathHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        let value = h
+            .param(0)
+            .ok_or_else(|| RenderError::new("Helper \"jsonpath\" needs a value"))?
+            .value();
+        let path = h
+            .param(1)
+            .and_then(|p| p.value().as_str())
+            .ok_or_else(|| RenderError::new("Helper \"jsonpath\" needs a path string"))?;
+
+        let mut matches = jsonpath_lib::select(value, path)
+            .map_err(|e| RenderError::new(format!("Invalid JSON path {}: {:?}", path, e)))?;
+        let result = match matches.len() {
+            0 => serde_json::Value::Null,
+            1 => matches.remove(0).clone(),
+            _ => serde_json::Value::Array(matches.into_iter().cloned().collect()),
+        };
+
+        Ok(ScopedJson::Derived(result))
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum TemplateError {
     #[error("{0}")]
@@ -495,6 +583,16 @@ pub enum TemplateValidationFailure {
         expected: TemplateFieldFormat,
         actual: serde_json::Value,
     },
+    /// A template string that doesn't parse.
+    Syntax {
+        name: Cow<'static, str>,
+        error: String,
+    },
+    /// A template that uses a value that isn't one of the declared fields.
+    UnknownField {
+        name: Cow<'static, str>,
+        field: String,
+    },
 }
 
 impl std::error::Error for TemplateValidationFailure {}
@@ -514,6 +612,12 @@ impl Display for TemplateValidationFailure {
                     name, expected, actual
                 )
             }
+            TemplateValidationFailure::Syntax { name, error } => {
+                write!(f, "Field {} has an invalid template: {}", name, error)
+            }
+            TemplateValidationFailure::UnknownField { name, field } => {
+                write!(f, "Field {} uses unknown field {}", name, field)
+            }
         }
     }
 }
@@ -527,6 +631,54 @@ pub struct TemplateValidationError {
 
 impl std::error::Error for TemplateValidationError {}
 
+impl Localize for TemplateValidationFailure {
+    fn localize(&self, locale: Option<&str>) -> String {
+        match self {
+            TemplateValidationFailure::Required(name) => {
+                format_message(locale, "validate.template.required", &[("name", name)])
+            }
+            TemplateValidationFailure::Invalid {
+                name,
+                expected,
+                actual,
+            } => format_message(
+                locale,
+                "validate.template.invalid",
+                &[
+                    ("name", name),
+                    ("expected", &expected.to_string()),
+                    ("actual", actual),
+                ],
+            ),
+            TemplateValidationFailure::Syntax { name, error } => format_message(
+                locale,
+                "validate.template.syntax",
+                &[("name", name), ("error", error)],
+            ),
+            TemplateValidationFailure::UnknownField { name, field } => format_message(
+                locale,
+                "validate.template.unknown_field",
+                &[("name", name), ("field", field)],
+            ),
+        }
+    }
+}
+
+impl Localize for TemplateValidationError {
+    fn localize(&self, locale: Option<&str>) -> String {
+        let mut output = format_message(
+            locale,
+            "validate.template.failure",
+            &[("object", &self.object), ("id", &self.id)],
+        );
+        for field in &self.fields {
+            output.push_str("\n\t");
+            output.push_str(&field.localize(locale));
+        }
+        output
+    }
+}
+
 impl Display for TemplateValidationError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(
@@ -546,6 +698,28 @@ pub fn validate(
     id: Option<impl ToString>,
     fields: &TemplateFields,
     values: &FxHashMap<String, serde_json::Value>,
+) -> Result<(), TemplateError> {
+    validate_fields(object, id, fields, values, true)
+}
+
+/// Validate the values that are present, without requiring that every non-optional field
+/// has a value. This is useful for partial payloads, such as a task action template, where
+/// the rest of the values are supplied at invocation time.
+pub fn validate_present(
+    object: &'static str,
+    id: Option<impl ToString>,
+    fields: &TemplateFields,
+    values: &FxHashMap<String, serde_json::Value>,
+) -> Result<(), TemplateError> {
+    validate_fields(object, id, fields, values, false)
+}
+
+fn validate_fields(
+    object: &'static str,
+    id: Option<impl ToString>,
+    fields: &TemplateFields,
+    values: &FxHashMap<String, serde_json::Value>,
+    check_required: bool,
 ) -> Result<(), TemplateError> {
     let errors = fields
         .iter()
@@ -553,6 +727,7 @@ pub fn validate(
             |field| match (values.get(field.name.as_ref()), field.optional) {
                 (Some(v), _) => Some(field.format.validate(field.name.as_ref(), v)),
                 (None, true) => None,
+                (None, false) if !check_required => None,
                 (None, false) => Some(Err(TemplateValidationFailure::Required(
                     field.name.to_owned(),
                 ))),
@@ -579,6 +754,257 @@ fn is_payload_template(template: &str) -> bool {
     template.starts_with("{{/") && template.ends_with("}}")
 }
 
+/// Check that the strings in a template are valid, and that they only use the given fields.
+/// When `fields` is None, only the syntax is checked.
+pub fn validate_template(
+    object: &'static str,
+    id: Option<impl ToString>,
+    template: &TaskActionTemplate,
+    fields: Option<&TemplateFields>,
+) -> Result<(), TemplateError> {
+    let mut errors = Vec::new();
+    for (name, value) in template {
+        check_template_value(name, value, fields, &mut errors);
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(TemplateError::Validation(TemplateValidationError {
+            object,
+            id: id.map(|i| i.to_string()).unwrap_or_else(String::new),
+            fields: errors,
+        }))
+    }
+}
+
+fn check_template_value(
+    name: &str,
+    value: &serde_json::Value,
+    fields: Option<&TemplateFields>,
+    errors: &mut Vec<TemplateValidationFailure>,
+) {
+    match value {
+        serde_json::Value::String(template) => {
+            if !is_payload_template(template) {
+                if let Err(e) = handlebars::Template::compile(template) {
+                    errors.push(TemplateValidationFailure::Syntax {
+                        name: Cow::Owned(name.to_string()),
+                        error: e.to_string(),
+                    });
+                    return;
+                }
+            }
+
+            let fields = match fields {
+                Some(f) => f,
+                None => return,
+            };
+
+            for field in template_references(template) {
+                let known = fields.iter().any(|f| f.name == field);
+                let reported = errors.iter().any(|e| {
+                    matches!(e, TemplateValidationFailure::UnknownField { name: n, field: f }
+                        if n == name && f == field)
+                });
+                if !known && !reported {
+                    errors.push(TemplateValidationFailure::UnknownField {
+                        name: Cow::Owned(name.to_string()),
+                        field: field.to_string(),
+                    });
+                }
+            }
+        }
+        serde_json::Value::Array(values) => {
+            for value in values {
+                check_template_value(name, value, fields, errors);
+            }
+        }
+        serde_json::Value::Object(values) => {
+            for value in values.values() {
+                check_template_value(name, value, fields, errors);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Find the values that a template uses, by the first segment of each path. Inside `each` and
+/// `with` blocks, paths are relative to the block's value, so those aren't included.
+fn template_references(template: &str) -> Vec<&str> {
+    if is_payload_template(template) {
+        return vec![&template[3..template.len() - 2]];
+    }
+
+    let mut references = Vec::new();
+    // For each open block, whether it changes the context.
+    let mut blocks: Vec<bool> = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let after = &rest[start + 2..];
+
+        let skip_to = if after.starts_with("{{") {
+            // A raw block, which isn't parsed.
+            after
+                .find("{{{{/")
+                .and_then(|i| after[i..].find("}}}}").map(|j| (i + j, 4)))
+        } else if after.starts_with("!--") {
+            after.find("--}}").map(|i| (i, 4))
+        } else if after.starts_with('!') {
+            after.find("}}").map(|i| (i, 2))
+        } else {
+            None
+        };
+
+        if let Some((end, len)) = skip_to {
+            rest = &after[end + len..];
+            continue;
+        }
+
+        let end = match after.find("}}") {
+            Some(end) => end,
+            None => break,
+        };
+        rest = &after[end + 2..];
+
+        let expr = after[..end]
+            .trim_matches(|c| c == '{' || c == '}' || c == '~')
+            .trim();
+        let in_context_block = blocks.iter().any(|b| *b);
+        let (kind, body) = match expr.chars().next() {
+            Some(c @ ('#' | '/' | '^' | '>')) => (Some(c), expr[1..].trim_start()),
+            _ => (None, expr),
+        };
+        let tokens = expression_tokens(body);
+
+        match (kind, tokens.as_slice()) {
+            (Some('/'), _) => {
+                blocks.pop();
+            }
+            (Some('>'), _) | (_, []) => {}
+            (Some('#'), [helper, params @ ..]) => {
+                if !in_context_block {
+                    param_references(params, &mut references);
+                }
+                blocks.push(matches!(*helper, "each" | "with"));
+            }
+            (Some('^'), [name]) => {
+                if !in_context_block {
+                    param_references(&[*name], &mut references);
+                }
+                blocks.push(false);
+            }
+            (_, ["else", params @ ..]) => {
+                if !in_context_block && params.len() > 1 {
+                    param_references(&params[1..], &mut references);
+                }
+            }
+            (_, [name]) => {
+                if !in_context_block {
+                    param_references(&[*name], &mut references);
+                }
+            }
+            (_, [_helper, params @ ..]) => {
+                if !in_context_block {
+                    param_references(params, &mut references);
+                }
+            }
+        }
+    }
+
+    references
+}
+
+/// Split an expression into words, keeping quoted strings together and treating parentheses
+/// as separate tokens.
+fn expression_tokens(expr: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut quote = None;
+    for (i, c) in expr.char_indices() {
+        if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            }
+            continue;
+        }
+
+        match c {
+            '"' | '\'' => {
+                quote = Some(c);
+                start.get_or_insert(i);
+            }
+            '(' | ')' => {
+                if let Some(s) = start.take() {
+                    tokens.push(&expr[s..i]);
+                }
+                tokens.push(&expr[i..i + 1]);
+            }
+            c if c.is_whitespace() => {
+                if let Some(s) = start.take() {
+                    tokens.push(&expr[s..i]);
+                }
+            }
+            _ => {
+                start.get_or_insert(i);
+            }
+        }
+    }
+
+    if let Some(s) = start {
+        tokens.push(&expr[s..]);
+    }
+
+    tokens
+}
+
+fn param_references<'a>(tokens: &[&'a str], references: &mut Vec<&'a str>) {
+    let mut helper_next = false;
+    for token in tokens {
+        match *token {
+            "(" => helper_next = true,
+            ")" => {}
+            // Block parameters, as in `{{#each items as |item|}}`
+            "as" => break,
+            _ if helper_next => helper_next = false,
+            token => {
+                if let Some(root) = reference_root(token) {
+                    references.push(root);
+                }
+            }
+        }
+    }
+}
+
+/// Return the first segment of a path, or None if the token is a literal or doesn't refer to
+/// the template's values.
+fn reference_root(token: &str) -> Option<&str> {
+    // The value of a hash parameter, like `key=value`.
+    let token = match token.find('=') {
+        Some(i) if !token.starts_with(['"', '\'']) => &token[i + 1..],
+        _ => token,
+    };
+
+    if token.is_empty()
+        || token.starts_with(['"', '\'', '@', '.'])
+        || token.parse::<f64>().is_ok()
+        || matches!(token, "true" | "false" | "null" | "undefined")
+    {
+        return None;
+    }
+
+    if let Some(literal) = token.strip_prefix('[') {
+        return literal.split(']').next();
+    }
+
+    let root = token.split(['.', '/', '[']).next().unwrap_or(token);
+    if root == "this" {
+        None
+    } else {
+        Some(root)
+    }
+}
+
 fn apply_field(
     template: &serde_json::Value,
     values: &FxHashMap<String, serde_json::Value>,
@@ -1200,5 +1626,113 @@ mod tests {
 
             Ok(())
         }
+
+        #[test]
+        fn conditionals_loops_and_helpers() -> Result<(), anyhow::Error> {
+            let template = vec![
+                (
+                    "summary".to_string(),
+                    json!("{{#if urgent}}URGENT: {{/if}}{{#each items}}{{name}}{{#unless @last}}, {{/unless}}{{/each}}"),
+                ),
+                (
+                    "owner".to_string(),
+                    json!(r##"{{default (jsonpath order "$.owner.name") "nobody"}}"##),
+                ),
+                (
+                    "first".to_string(),
+                    json!(r##"{{jsonpath order "$.lines[0].sku"}}"##),
+                ),
+                ("label".to_string(), json!(r##"{{default label "none"}}"##)),
+                ("raw".to_string(), json!("{{json order.owner}}")),
+            ];
+
+            let values = FxHashMap::<String, serde_json::Value>::from_iter(IntoIter::new([
+                ("urgent".to_string(), json!(true)),
+                (
+                    "items".to_string(),
+                    json!([{ "name": "a" }, { "name": "b" }]),
+                ),
+                (
+                    "order".to_string(),
+                    json!({ "owner": null, "lines": [{ "sku": "x1" }] }),
+                ),
+            ]));
+
+            let output = apply(&template, &values)?;
+            assert_eq!(output.get("summary"), Some(&json!("URGENT: a, b")));
+            assert_eq!(output.get("owner"), Some(&json!("nobody")));
+            assert_eq!(output.get("first"), Some(&json!("x1")));
+            assert_eq!(output.get("label"), Some(&json!("none")));
+            assert_eq!(output.get("raw"), Some(&json!("null")));
+
+            Ok(())
+        }
+    }
+
+    mod validate_template {
+        use std::borrow::Cow;
+
+        use serde_json::json;
+
+        use super::super::{
+            template_references, validate_template, TemplateError, TemplateField,
+            TemplateFieldFormat, TemplateFields,
+        };
+
+        #[test]
+        fn references() {
+            assert_eq!(template_references("{{/payload}}"), vec!["payload"]);
+            assert_eq!(
+                template_references(
+                    r##"{{#if a.b}}{{c/d}}{{else if e}}{{default (jsonpath f "$.x") g}}{{/if}}"##
+                ),
+                vec!["a", "c", "e", "f", "g"]
+            );
+            assert_eq!(
+                template_references(
+                    "{{#each items as |item|}}{{item.name}} {{this}} {{@index}}{{/each}}{{h}}"
+                ),
+                vec!["items", "h"]
+            );
+            assert_eq!(
+                template_references(r##"{{! {{comment}} }}{{lookup i "key"}} {{{j}}} {{k=1}}"##),
+                vec!["i", "j"]
+            );
+        }
+
+        #[test]
+        fn unknown_fields_and_syntax() {
+            let fields = TemplateFields(vec![TemplateField {
+                name: Cow::from("text"),
+                format: TemplateFieldFormat::string_without_default(),
+                optional: false,
+                description: None,
+            }]);
+
+            let template = vec![(
+                "args".to_string(),
+                json!(["{{text}}", "{{#if text}}x{{/if}}"]),
+            )];
+            validate_template("action", Some("a"), &template, Some(&fields))
+                .expect("valid template");
+
+            let template = vec![
+                (
+                    "args".to_string(),
+                    json!(["{{text}}", "{{other}}", "{{other}}"]),
+                ),
+                ("command".to_string(), json!("{{#if text}}unclosed")),
+            ];
+            let err = validate_template("action", Some("a"), &template, Some(&fields))
+                .expect_err("invalid template");
+            match err {
+                TemplateError::Validation(e) => assert_eq!(e.fields.len(), 2, "{:?}", e),
+                _ => panic!("Expected validation error, saw {:?}", err),
+            }
+
+            let template = vec![("args".to_string(), json!(["{{other}}"]))];
+            validate_template("action", Some("a"), &template, None)
+                .expect("unknown fields are allowed without declared fields");
+        }
     }
 }
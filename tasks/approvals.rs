@@ -0,0 +1,262 @@
+//! Manual approval gates. The `approval` action pauses a task until someone approves or denies
+//! the request, and the decision is then sent to one of the task's triggers as an input. If no
+//! decision is made before the approval expires, the trigger gets an `expired` decision
+//! instead.
+//!
+//! The trigger's input schema must accept an [ApprovalDecision].
+
+use chrono::{DateTime, Utc};
+use ergo_database::object_id::{InputId, OrgId, TaskId, TaskTriggerId, UserId};
+use ergo_notifications::NotificationManager;
+use ergo_queues::remove_pending_job;
+use lazy_static::lazy_static;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::{
+    error::Error,
+    inputs::{chain::InputChain, enqueue_input, queue::InputQueue, EnqueueInputOptions},
+};
+
+lazy_static! {
+    /// The base URL of the web app, for the approve and deny links.
+    static ref PUBLIC_URL: String = std::env::var("PUBLIC_URL")
+        .unwrap_or_else(|_| "http://localhost:6543".to_string())
+        .trim_end_matches('/')
+        .to_string();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema, sqlx::Type)]
+#[sqlx(type_name = "approval_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ApprovalStatus {
+    Pending,
+    Approved,
+    Denied,
+    Expired,
+}
+
+impl std::fmt::Display for ApprovalStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Pending => "pending",
+            Self::Approved => "approved",
+            Self::Denied => "denied",
+            Self::Expired => "expired",
+        };
+        f.write_str(s)
+    }
+}
+
+/// The payload sent to the approval's trigger once it's decided or expires.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ApprovalDecision {
+    pub approval_id: Uuid,
+    /// `approved`, `denied`, or `expired`.
+    pub decision: ApprovalStatus,
+    pub comment: Option<String>,
+    /// The user who made the decision, if they were logged in. Decisions made through the links
+    /// in a notification don't have a user.
+    pub decided_by: Option<UserId>,
+    /// The payload given to the approval action.
+    pub payload: serde_json::Value,
+}
+
+pub struct NewApproval<'a> {
+    pub approval_id: Uuid,
+    pub org_id: &'a OrgId,
+    pub task_id: &'a TaskId,
+    pub task_trigger_local_id: &'a str,
+    pub actions_log_id: Option<Uuid>,
+    pub user_id: &'a UserId,
+    pub message: &'a str,
+    pub payload: &'a serde_json::Value,
+    pub expires: DateTime<Utc>,
+}
+
+/// Create a random token for an approval's links, and return it with its hash.
+fn new_token() -> (String, Vec<u8>) {
+    let bytes: [u8; 32] = rand::random();
+    let token = base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+    let hash = hash_token(&token);
+    (token, hash)
+}
+
+pub fn hash_token(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+/// The link that records a decision on the approval.
+pub fn approval_link(approval_id: &Uuid, token: &str, decision: ApprovalStatus) -> String {
+    format!(
+        "{}/approvals/{}?decision={}&token={}",
+        *PUBLIC_URL, approval_id, decision, token
+    )
+}
+
+/// Record a new pending approval, and return the token for its links.
+pub(crate) async fn create_approval(
+    tx: &mut PgConnection,
+    approval: NewApproval<'_>,
+) -> Result<String, Error> {
+    let (token, token_hash) = new_token();
+    sqlx::query!(
+        r##"INSERT INTO approvals
+            (approval_id, org_id, task_id, task_trigger_local_id, actions_log_id, user_id,
+                message, payload, token_hash, expires)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)"##,
+        approval.approval_id,
+        approval.org_id.0,
+        approval.task_id.0,
+        approval.task_trigger_local_id,
+        approval.actions_log_id,
+        approval.user_id.0,
+        approval.message,
+        approval.payload,
+        token_hash,
+        approval.expires
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(token)
+}
+
+pub struct DecideApproval<'a> {
+    pub pg: &'a mut PgConnection,
+    pub notifications: Option<NotificationManager>,
+    pub redis_key_prefix: Option<&'a str>,
+    pub approval_id: Uuid,
+    pub decision: ApprovalStatus,
+    pub decided_by: Option<UserId>,
+    pub comment: Option<String>,
+}
+
+/// Record a decision on a pending approval and send it to the approval's trigger. The
+/// scheduled expiry input is removed from the queue, and stays in the inputs log as `dropped`.
+///
+/// Returns [Error::ApprovalClosed] if the approval was already decided or has expired.
+pub async fn decide_approval(options: DecideApproval<'_>) -> Result<Uuid, Error> {
+    let DecideApproval {
+        pg,
+        notifications,
+        redis_key_prefix,
+        approval_id,
+        decision,
+        decided_by,
+        comment,
+    } = options;
+
+    let approval = sqlx::query!(
+        r##"SELECT org_id AS "org_id: OrgId",
+            task_id AS "task_id: TaskId",
+            task_trigger_local_id,
+            user_id AS "user_id: UserId",
+            payload,
+            status AS "status: ApprovalStatus",
+            expires < now() AS "expired!",
+            expiry_inputs_log_id
+        FROM approvals
+        WHERE approval_id=$1
+        FOR UPDATE"##,
+        approval_id
+    )
+    .fetch_optional(&mut *pg)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    if approval.status != ApprovalStatus::Pending {
+        return Err(Error::ApprovalClosed(approval.status));
+    }
+
+    if approval.expired {
+        // The expiry input is already on its way to the task.
+        sqlx::query!(
+            "UPDATE approvals SET status='expired' WHERE approval_id=$1",
+            approval_id
+        )
+        .execute(&mut *pg)
+        .await?;
+        return Err(Error::ApprovalClosed(ApprovalStatus::Expired));
+    }
+
+    sqlx::query!(
+        r##"UPDATE approvals
+        SET status=$2, decided_by=$3, decided_at=now(), comment=$4
+        WHERE approval_id=$1"##,
+        approval_id,
+        decision as _,
+        decided_by.as_ref().map(|u| u.0),
+        comment.as_deref()
+    )
+    .execute(&mut *pg)
+    .await?;
+
+    if let Some(expiry_id) = approval.expiry_inputs_log_id {
+        let job_id = sqlx::query_scalar!(
+            r##"UPDATE inputs_log
+            SET status='dropped', info=jsonb_build_object('reason', 'approval_decided'), updated=now()
+            WHERE inputs_log_id=$1 AND status='pending'
+            RETURNING queue_job_id"##,
+            expiry_id
+        )
+        .fetch_optional(&mut *pg)
+        .await?;
+
+        if let Some(job_id) = job_id.filter(|id| !id.is_empty()) {
+            let queue_name = InputQueue::queue_name(redis_key_prefix);
+            remove_pending_job(&mut *pg, queue_name.as_ref(), &job_id).await?;
+        }
+    }
+
+    let trigger = sqlx::query!(
+        r##"SELECT tt.task_trigger_id AS "task_trigger_id: TaskTriggerId",
+            tt.name AS task_trigger_name,
+            tt.input_id AS "input_id: InputId",
+            inputs.payload_schema,
+            tasks.name AS task_name
+        FROM task_triggers tt
+        JOIN tasks USING (task_id)
+        JOIN inputs USING (input_id)
+        WHERE tt.task_id=$1 AND tt.task_trigger_local_id=$2 AND NOT tasks.deleted"##,
+        approval.task_id.0,
+        approval.task_trigger_local_id
+    )
+    .fetch_optional(&mut *pg)
+    .await?
+    .ok_or_else(|| Error::TaskTriggerNotFound(approval.task_trigger_local_id.clone()))?;
+
+    let payload = ApprovalDecision {
+        approval_id,
+        decision,
+        comment,
+        decided_by,
+        payload: approval.payload.unwrap_or(serde_json::Value::Null),
+    };
+
+    enqueue_input(EnqueueInputOptions {
+        pg,
+        notifications,
+        org_id: approval.org_id,
+        user_id: approval.user_id,
+        task_id: approval.task_id,
+        task_name: trigger.task_name,
+        input_id: trigger.input_id,
+        task_trigger_id: trigger.task_trigger_id,
+        task_trigger_local_id: approval.task_trigger_local_id,
+        task_trigger_name: trigger.task_trigger_name,
+        periodic_trigger_id: None,
+        payload_schema: &trigger.payload_schema,
+        payload: serde_json::to_value(&payload)?,
+        redis_key_prefix,
+        trigger_at: None,
+        replay_of: None,
+        // Someone just made the decision, so don't leave the task waiting behind batch work.
+        interactive: true,
+        chain: InputChain::default(),
+    })
+    .await
+}
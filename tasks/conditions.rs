@@ -0,0 +1,505 @@
+//! Declarative conditions for state machine event handlers. These compare values found with a
+//! JSONPath against constants, so unlike script conditions they don't need a JS runtime, and
+//! they can be checked against the trigger's payload schema when the task is saved.
+//!
+//! ```json
+//! {
+//!   "all": [
+//!     { "path": "$.amount", "op": "gte", "value": 100 },
+//!     { "not": { "path": "$.status", "op": "in", "value": ["void", "refunded"] } },
+//!     { "path": "$.approved", "source": "context", "op": "missing" }
+//!   ]
+//! }
+//! ```
+
+use std::cmp::Ordering;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Clone, Debug, JsonSchema, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Condition {
+    /// Passes if every condition passes.
+    All {
+        all: Vec<Condition>,
+    },
+    /// Passes if at least one condition passes.
+    Any {
+        any: Vec<Condition>,
+    },
+    Not {
+        not: Box<Condition>,
+    },
+    Compare(Comparison),
+}
+
+#[derive(Clone, Copy, Debug, Default, JsonSchema, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConditionSource {
+    /// The payload of the input that triggered the handler.
+    #[default]
+    Payload,
+    /// The state machine's context.
+    Context,
+}
+
+#[derive(Clone, Copy, Debug, JsonSchema, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ComparisonOp {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    /// The found value is one of the values in the array.
+    In,
+    /// The found string contains the value, or the found array contains an element equal to the
+    /// value.
+    Contains,
+    /// The path matches a value other than null.
+    Exists,
+    /// The path doesn't match anything, or only matches null.
+    Missing,
+}
+
+impl ComparisonOp {
+    fn is_ordering(&self) -> bool {
+        matches!(self, Self::Gt | Self::Gte | Self::Lt | Self::Lte)
+    }
+
+    fn uses_value(&self) -> bool {
+        !matches!(self, Self::Exists | Self::Missing)
+    }
+
+    fn test(&self, found: &Value, value: &Value) -> bool {
+        match self {
+            Self::Eq => values_equal(found, value),
+            Self::Ne => !values_equal(found, value),
+            Self::Gt => compare(found, value) == Some(Ordering::Greater),
+            Self::Gte => matches!(
+                compare(found, value),
+                Some(Ordering::Greater | Ordering::Equal)
+            ),
+            Self::Lt => compare(found, value) == Some(Ordering::Less),
+            Self::Lte => matches!(
+                compare(found, value),
+                Some(Ordering::Less | Ordering::Equal)
+            ),
+            Self::In => value
+                .as_array()
+                .map(|values| values.iter().any(|v| values_equal(found, v)))
+                .unwrap_or(false),
+            Self::Contains => match found {
+                Value::String(s) => value.as_str().map(|v| s.contains(v)).unwrap_or(false),
+                Value::Array(a) => a.iter().any(|v| values_equal(v, value)),
+                _ => false,
+            },
+            Self::Exists => !found.is_null(),
+            Self::Missing => found.is_null(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, JsonSchema, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Comparison {
+    /// A JSONPath, such as `$.order.total`. If it matches more than one value, the comparison
+    /// passes if any of them pass, except for `ne` and `missing`, which pass only if all of
+    /// them do.
+    pub path: String,
+    #[serde(default)]
+    pub source: ConditionSource,
+    pub op: ComparisonOp,
+    /// The value to compare against. This is not used by `exists` and `missing`.
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub value: Value,
+}
+
+/// Numbers compare equal regardless of whether they were written as integers or floats.
+fn values_equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        _ => a == b,
+    }
+}
+
+/// Numbers and strings can be ordered. Other values, and values of different types, can't.
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+impl Condition {
+    pub fn evaluate(&self, context: &Value, payload: Option<&Value>) -> bool {
+        match self {
+            Self::All { all } => all.iter().all(|c| c.evaluate(context, payload)),
+            Self::Any { any } => any.iter().any(|c| c.evaluate(context, payload)),
+            Self::Not { not } => !not.evaluate(context, payload),
+            Self::Compare(c) => c.evaluate(context, payload),
+        }
+    }
+
+    /// Check if any comparison in the condition looks at the context instead of the payload.
+    pub fn uses_context(&self) -> bool {
+        match self {
+            Self::All { all: conditions } | Self::Any { any: conditions } => {
+                conditions.iter().any(|c| c.uses_context())
+            }
+            Self::Not { not } => not.uses_context(),
+            Self::Compare(c) => c.source == ConditionSource::Context,
+        }
+    }
+
+    /// Check the condition for mistakes, using the trigger's payload schema if there is one to
+    /// make sure that payload paths exist and have a type that suits the comparison.
+    pub fn validate(&self, payload_schema: Option<&Value>) -> Vec<String> {
+        let mut errors = Vec::new();
+        self.validate_into(payload_schema, &mut errors);
+        errors
+    }
+
+    fn validate_into(&self, payload_schema: Option<&Value>, errors: &mut Vec<String>) {
+        match self {
+            Self::All { all: conditions } | Self::Any { any: conditions } => {
+                for c in conditions {
+                    c.validate_into(payload_schema, errors);
+                }
+            }
+            Self::Not { not } => not.validate_into(payload_schema, errors),
+            Self::Compare(c) => c.validate_into(payload_schema, errors),
+        }
+    }
+}
+
+impl Comparison {
+    fn evaluate(&self, context: &Value, payload: Option<&Value>) -> bool {
+        let root = match self.source {
+            ConditionSource::Payload => payload.unwrap_or(&Value::Null),
+            ConditionSource::Context => context,
+        };
+
+        // Paths are checked when the task is saved, so an invalid one here just doesn't match.
+        let found = jsonpath_lib::select(root, &self.path).unwrap_or_default();
+        match self.op {
+            ComparisonOp::Ne | ComparisonOp::Missing => {
+                found.iter().all(|v| self.op.test(v, &self.value))
+            }
+            _ => found.iter().any(|v| self.op.test(v, &self.value)),
+        }
+    }
+
+    fn validate_into(&self, payload_schema: Option<&Value>, errors: &mut Vec<String>) {
+        if let Err(e) = jsonpath_lib::select(&Value::Null, &self.path) {
+            errors.push(format!("Invalid path {}: {:?}", self.path, e));
+            return;
+        }
+
+        let value_ok = match self.op {
+            ComparisonOp::In => self.value.is_array(),
+            op if op.is_ordering() => self.value.is_number() || self.value.is_string(),
+            _ => true,
+        };
+        if !value_ok {
+            errors.push(format!(
+                "{}: `{}` can not compare against {}",
+                self.path,
+                op_name(self.op),
+                self.value
+            ));
+        }
+
+        if !self.op.uses_value() && !self.value.is_null() {
+            errors.push(format!(
+                "{}: `{}` does not use a value",
+                self.path,
+                op_name(self.op)
+            ));
+        }
+
+        let schema = match (self.source, payload_schema) {
+            (ConditionSource::Payload, Some(schema)) => schema,
+            _ => return,
+        };
+
+        match schema_at_path(schema, &self.path) {
+            Ok(Some(field_schema)) => {
+                if let Some(e) = self.check_type(field_schema) {
+                    errors.push(e);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => errors.push(e),
+        }
+    }
+
+    /// Make sure that the type in the schema can be used with the operator.
+    fn check_type(&self, field_schema: &Value) -> Option<String> {
+        let types = schema_types(field_schema)?;
+        let allowed: &[&str] = match self.op {
+            ComparisonOp::Contains => &["string", "array"],
+            op if op.is_ordering() && self.value.is_string() => &["string"],
+            op if op.is_ordering() => &["number", "integer"],
+            _ => return None,
+        };
+
+        if types.iter().any(|t| allowed.contains(t)) {
+            None
+        } else {
+            Some(format!(
+                "{} is {}, which can not be used with `{}`",
+                self.path,
+                types.join(" or "),
+                op_name(self.op)
+            ))
+        }
+    }
+}
+
+fn op_name(op: ComparisonOp) -> String {
+    serde_json::to_value(op)
+        .ok()
+        .and_then(|v| v.as_str().map(String::from))
+        .unwrap_or_default()
+}
+
+fn schema_types(schema: &Value) -> Option<Vec<&str>> {
+    match schema.get("type")? {
+        Value::String(t) => Some(vec![t.as_str()]),
+        Value::Array(types) => Some(types.iter().filter_map(|t| t.as_str()).collect()),
+        _ => None,
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum PathSegment {
+    Field(String),
+    /// An array index or wildcard
+    Element,
+}
+
+/// Split a JSONPath into plain field and index lookups. Returns None if the path uses anything
+/// else, such as filters or recursive descent, since those can't be followed through a schema.
+fn path_segments(path: &str) -> Option<Vec<PathSegment>> {
+    let mut rest = path.trim().strip_prefix('$')?;
+    let mut segments = Vec::new();
+
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix('.') {
+            let end = r
+                .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+                .unwrap_or(r.len());
+            if end == 0 {
+                return None;
+            }
+            segments.push(PathSegment::Field(r[..end].to_string()));
+            rest = &r[end..];
+        } else if let Some(r) = rest.strip_prefix('[') {
+            let end = r.find(']')?;
+            let inner = r[..end].trim();
+            let quoted = inner
+                .strip_prefix('\'')
+                .and_then(|s| s.strip_suffix('\''))
+                .or_else(|| inner.strip_prefix('"').and_then(|s| s.strip_suffix('"')));
+
+            match quoted {
+                Some(name) => segments.push(PathSegment::Field(name.to_string())),
+                None if inner == "*" || inner.parse::<usize>().is_ok() => {
+                    segments.push(PathSegment::Element)
+                }
+                None => return None,
+            }
+            rest = &r[end + 1..];
+        } else {
+            return None;
+        }
+    }
+
+    Some(segments)
+}
+
+/// Find the schema for the value at `path`. Returns `Ok(None)` if the schema doesn't say enough
+/// to tell, and an error if the path names a field that the schema doesn't allow.
+fn schema_at_path<'a>(schema: &'a Value, path: &str) -> Result<Option<&'a Value>, String> {
+    let segments = match path_segments(path) {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+
+    let mut current = schema;
+    for segment in segments {
+        let next = match segment {
+            PathSegment::Field(name) => {
+                let properties = match current.get("properties").and_then(|p| p.as_object()) {
+                    Some(p) => p,
+                    None => return Ok(None),
+                };
+
+                match properties.get(&name) {
+                    Some(s) => s,
+                    None if allows_other_properties(current) => return Ok(None),
+                    None => {
+                        return Err(format!(
+                            "{} refers to field {}, which is not in the payload schema",
+                            path, name
+                        ))
+                    }
+                }
+            }
+            PathSegment::Element => match current.get("items") {
+                Some(items) if items.is_object() => items,
+                _ => return Ok(None),
+            },
+        };
+
+        current = next;
+    }
+
+    Ok(Some(current))
+}
+
+/// A schema that lists its properties is taken to mean that those are the only ones, unless it
+/// explicitly allows others. This catches misspelled field names in conditions.
+fn allows_other_properties(schema: &Value) -> bool {
+    schema.get("patternProperties").is_some()
+        || match schema.get("additionalProperties") {
+            Some(Value::Bool(allowed)) => *allowed,
+            Some(Value::Object(_)) => true,
+            _ => false,
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn condition(value: Value) -> Condition {
+        serde_json::from_value(value).expect("parsing condition")
+    }
+
+    #[test]
+    fn comparisons() {
+        let payload = json!({
+            "amount": 150,
+            "status": "paid",
+            "tags": ["rush", "gift"],
+            "lines": [{ "sku": "a" }, { "sku": "b" }]
+        });
+        let context = json!({ "count": 2 });
+
+        let cases = [
+            (
+                json!({ "path": "$.amount", "op": "gte", "value": 150.0 }),
+                true,
+            ),
+            (
+                json!({ "path": "$.amount", "op": "lt", "value": 100 }),
+                false,
+            ),
+            (
+                json!({ "path": "$.status", "op": "in", "value": ["paid", "sent"] }),
+                true,
+            ),
+            (
+                json!({ "path": "$.status", "op": "contains", "value": "ai" }),
+                true,
+            ),
+            (
+                json!({ "path": "$.tags", "op": "contains", "value": "gift" }),
+                true,
+            ),
+            (
+                json!({ "path": "$.lines[*].sku", "op": "eq", "value": "b" }),
+                true,
+            ),
+            (
+                json!({ "path": "$.lines[*].sku", "op": "ne", "value": "b" }),
+                false,
+            ),
+            (json!({ "path": "$.missing", "op": "missing" }), true),
+            (
+                json!({ "path": "$.missing", "op": "eq", "value": 1 }),
+                false,
+            ),
+            (
+                json!({ "path": "$.count", "source": "context", "op": "eq", "value": 2 }),
+                true,
+            ),
+            (json!({ "path": "$.status", "op": "gt", "value": 5 }), false),
+        ];
+
+        for (c, expected) in cases {
+            assert_eq!(
+                condition(c.clone()).evaluate(&context, Some(&payload)),
+                expected,
+                "{}",
+                c
+            );
+        }
+    }
+
+    #[test]
+    fn combinators() {
+        let c = condition(json!({
+            "all": [
+                { "path": "$.a", "op": "eq", "value": 1 },
+                { "any": [
+                    { "path": "$.b", "op": "eq", "value": 1 },
+                    { "not": { "path": "$.c", "op": "exists" } }
+                ]}
+            ]
+        }));
+
+        let context = json!({});
+        assert!(c.evaluate(&context, Some(&json!({ "a": 1 }))));
+        assert!(c.evaluate(&context, Some(&json!({ "a": 1, "b": 1, "c": 1 }))));
+        assert!(!c.evaluate(&context, Some(&json!({ "a": 1, "c": 1 }))));
+        assert!(!c.evaluate(&context, None));
+    }
+
+    #[test]
+    fn validate_against_schema() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "amount": { "type": "number" },
+                "name": { "type": "string" },
+                "lines": {
+                    "type": "array",
+                    "items": { "type": "object", "properties": { "sku": { "type": "string" } } }
+                },
+                "extra": { "type": "object", "additionalProperties": true }
+            }
+        });
+
+        let valid = condition(json!({ "all": [
+            { "path": "$.amount", "op": "gt", "value": 5 },
+            { "path": "$.lines[0].sku", "op": "eq", "value": "a" },
+            { "path": "$['name']", "op": "contains", "value": "x" },
+            { "path": "$.extra.anything", "op": "exists" },
+            { "path": "$..sku", "op": "exists" },
+            { "path": "$.unknown", "source": "context", "op": "exists" }
+        ]}));
+        assert_eq!(valid.validate(Some(&schema)), Vec::<String>::new());
+
+        let invalid = condition(json!({ "all": [
+            { "path": "$.amont", "op": "gt", "value": 5 },
+            { "path": "$.lines[*].size", "op": "eq", "value": 1 },
+            { "path": "$.name", "op": "gt", "value": 5 },
+            { "path": "$.amount", "op": "in", "value": 5 },
+            { "path": "$.amount", "op": "exists", "value": 5 },
+            { "path": "$.[", "op": "exists" }
+        ]}));
+        assert_eq!(invalid.validate(Some(&schema)).len(), 6);
+
+        // Without a schema, only the condition itself is checked.
+        let c = condition(json!({ "path": "$.amont", "op": "gt", "value": 5 }));
+        assert_eq!(c.validate(None), Vec::<String>::new());
+    }
+}
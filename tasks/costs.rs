@@ -0,0 +1,290 @@
+//! Resource usage per task, counted by day so that expensive automations can be found and their
+//! cost charged back to the teams that own them. Each input run adds its queue wait and script
+//! time, and each action execution adds its queue wait and how long the executor took, which is
+//! mostly time spent waiting on external services.
+//!
+//! Usage is recorded outside of the run's transaction, so failed runs are counted too. Days are
+//! in UTC, the same as the daily quotas in [crate::quotas].
+
+use chrono::{DateTime, NaiveDate, Utc};
+use ergo_database::{
+    object_id::{OrgId, TaskId},
+    PostgresPool,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::Error;
+
+/// The longest span of days that a single report can cover.
+pub const MAX_REPORT_DAYS: i64 = 366;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct UsageTotals {
+    /// The number of inputs that the task handled.
+    pub runs: i64,
+    /// Time that inputs spent waiting in the queue before they were handled.
+    pub input_queue_wait_ms: i64,
+    /// Time spent running scripts.
+    pub js_cpu_ms: i64,
+    /// The number of actions executed.
+    pub actions: i64,
+    /// Time that actions spent waiting in the queue before they were executed.
+    pub action_queue_wait_ms: i64,
+    /// Time spent executing actions, including calls to external services.
+    pub action_ms: i64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct TaskUsageDay {
+    pub day: NaiveDate,
+    pub task_id: TaskId,
+    pub task_name: String,
+    #[serde(flatten)]
+    pub usage: UsageTotals,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct OrgUsageDay {
+    pub day: NaiveDate,
+    #[serde(flatten)]
+    pub usage: UsageTotals,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct UsageReport {
+    pub since: NaiveDate,
+    pub until: NaiveDate,
+    /// The org's totals for each day with any usage.
+    pub days: Vec<OrgUsageDay>,
+    /// The usage of each task, by day.
+    pub tasks: Vec<TaskUsageDay>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct UsageReportFilter {
+    /// The first day to include. Defaults to 30 days before `until`.
+    pub since: Option<NaiveDate>,
+    /// The last day to include. Defaults to today.
+    pub until: Option<NaiveDate>,
+    pub task_id: Option<TaskId>,
+    /// Only list the tasks that these user entities can read. The org totals still include
+    /// every task. `None` lists every task.
+    pub readable_by: Option<Vec<Uuid>>,
+}
+
+/// Record an input run. `started` is when the worker started handling the input, and the queue
+/// wait is measured from when the input arrived, or from its scheduled time for delayed inputs.
+pub async fn record_run(
+    pool: &PostgresPool,
+    task_id: &TaskId,
+    inputs_log_id: Uuid,
+    started: DateTime<Utc>,
+    js_cpu_ms: i64,
+) -> Result<(), Error> {
+    sqlx::query!(
+        r##"INSERT INTO task_usage (task_id, day, org_id, runs, input_queue_wait_ms, js_cpu_ms)
+        SELECT tasks.task_id, (now() AT TIME ZONE 'UTC')::date, tasks.org_id, 1,
+            COALESCE(GREATEST(0,
+                EXTRACT(EPOCH FROM $3 - COALESCE(il.scheduled_for, il.created)) * 1000
+            )::bigint, 0),
+            $4
+        FROM tasks
+        LEFT JOIN inputs_log il ON il.inputs_log_id = $2
+        WHERE tasks.task_id = $1
+        ON CONFLICT (task_id, day) DO UPDATE SET
+            runs = task_usage.runs + 1,
+            input_queue_wait_ms = task_usage.input_queue_wait_ms + EXCLUDED.input_queue_wait_ms,
+            js_cpu_ms = task_usage.js_cpu_ms + EXCLUDED.js_cpu_ms"##,
+        task_id.0,
+        inputs_log_id,
+        started,
+        js_cpu_ms.max(0)
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Record an action execution that started at `started` and took `duration_ms`. The queue wait
+/// is measured from when the action was added to the log.
+pub async fn record_action(
+    pool: &PostgresPool,
+    task_id: &TaskId,
+    actions_log_id: Uuid,
+    started: DateTime<Utc>,
+    duration_ms: i64,
+) -> Result<(), Error> {
+    sqlx::query!(
+        r##"INSERT INTO task_usage (task_id, day, org_id, actions, action_queue_wait_ms, action_ms)
+        SELECT tasks.task_id, (now() AT TIME ZONE 'UTC')::date, tasks.org_id, 1,
+            COALESCE(GREATEST(0, EXTRACT(EPOCH FROM $3 - al.created) * 1000)::bigint, 0),
+            $4
+        FROM tasks
+        LEFT JOIN actions_log al ON al.actions_log_id = $2
+        WHERE tasks.task_id = $1
+        ON CONFLICT (task_id, day) DO UPDATE SET
+            actions = task_usage.actions + 1,
+            action_queue_wait_ms = task_usage.action_queue_wait_ms + EXCLUDED.action_queue_wait_ms,
+            action_ms = task_usage.action_ms + EXCLUDED.action_ms"##,
+        task_id.0,
+        actions_log_id,
+        started,
+        duration_ms.max(0)
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+fn report_range(filter: &UsageReportFilter) -> Result<(NaiveDate, NaiveDate), Error> {
+    let until = filter
+        .until
+        .unwrap_or_else(|| Utc::now().naive_utc().date());
+    let since = filter
+        .since
+        .unwrap_or_else(|| until - chrono::Duration::days(30));
+
+    if since > until {
+        return Err(Error::InvalidUsageReport(
+            "since must not be after until".to_string(),
+        ));
+    }
+
+    if (until - since).num_days() >= MAX_REPORT_DAYS {
+        return Err(Error::InvalidUsageReport(format!(
+            "A report can cover at most {MAX_REPORT_DAYS} days"
+        )));
+    }
+
+    Ok((since, until))
+}
+
+/// Build a usage report for an org, with the most expensive tasks first within each day.
+pub async fn get_usage_report(
+    tx: &mut PgConnection,
+    org_id: &OrgId,
+    filter: &UsageReportFilter,
+) -> Result<UsageReport, Error> {
+    let (since, until) = report_range(filter)?;
+
+    let days = sqlx::query!(
+        r##"SELECT day,
+            SUM(runs)::bigint AS "runs!",
+            SUM(input_queue_wait_ms)::bigint AS "input_queue_wait_ms!",
+            SUM(js_cpu_ms)::bigint AS "js_cpu_ms!",
+            SUM(actions)::bigint AS "actions!",
+            SUM(action_queue_wait_ms)::bigint AS "action_queue_wait_ms!",
+            SUM(action_ms)::bigint AS "action_ms!"
+        FROM task_usage
+        WHERE org_id = $1 AND day BETWEEN $2 AND $3
+        GROUP BY day
+        ORDER BY day"##,
+        org_id.0,
+        since,
+        until
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .map(|row| OrgUsageDay {
+        day: row.day,
+        usage: UsageTotals {
+            runs: row.runs,
+            input_queue_wait_ms: row.input_queue_wait_ms,
+            js_cpu_ms: row.js_cpu_ms,
+            actions: row.actions,
+            action_queue_wait_ms: row.action_queue_wait_ms,
+            action_ms: row.action_ms,
+        },
+    })
+    .collect();
+
+    let tasks = sqlx::query!(
+        r##"SELECT tu.day, tu.task_id AS "task_id: TaskId", tasks.name AS task_name,
+            tu.runs, tu.input_queue_wait_ms, tu.js_cpu_ms,
+            tu.actions, tu.action_queue_wait_ms, tu.action_ms
+        FROM task_usage tu
+        JOIN tasks USING (task_id)
+        WHERE tu.org_id = $1 AND tu.day BETWEEN $2 AND $3
+            AND ($4::uuid IS NULL OR tu.task_id = $4)
+            AND ($5::uuid[] IS NULL OR EXISTS(
+                SELECT 1 FROM user_entity_permissions
+                WHERE user_entity_id = ANY($5)
+                AND permission_type = 'read'
+                AND permissioned_object IN (uuid_nil(), tu.task_id)
+            ))
+        ORDER BY tu.day, tu.js_cpu_ms + tu.action_ms DESC, tu.task_id"##,
+        org_id.0,
+        since,
+        until,
+        filter.task_id.as_ref().map(|id| id.0),
+        filter.readable_by.as_deref()
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .map(|row| TaskUsageDay {
+        day: row.day,
+        task_id: row.task_id,
+        task_name: row.task_name,
+        usage: UsageTotals {
+            runs: row.runs,
+            input_queue_wait_ms: row.input_queue_wait_ms,
+            js_cpu_ms: row.js_cpu_ms,
+            actions: row.actions,
+            action_queue_wait_ms: row.action_queue_wait_ms,
+            action_ms: row.action_ms,
+        },
+    })
+    .collect();
+
+    Ok(UsageReport {
+        since,
+        until,
+        days,
+        tasks,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn default_range() {
+        let filter = UsageReportFilter {
+            until: Some(date("2023-03-01")),
+            ..Default::default()
+        };
+        assert_eq!(
+            report_range(&filter).unwrap(),
+            (date("2023-01-30"), date("2023-03-01"))
+        );
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        let backwards = UsageReportFilter {
+            since: Some(date("2023-03-02")),
+            until: Some(date("2023-03-01")),
+            ..Default::default()
+        };
+        assert!(report_range(&backwards).is_err());
+
+        let too_long = UsageReportFilter {
+            since: Some(date("2021-01-01")),
+            until: Some(date("2023-03-01")),
+            ..Default::default()
+        };
+        assert!(report_range(&too_long).is_err());
+    }
+}
@@ -6,11 +6,13 @@ mod dag;
 mod node;
 #[cfg(not(target_family = "wasm"))]
 mod run;
+mod runner;
 
 pub use node::*;
 
 pub use config::*;
 pub use dag::toposort_nodes;
+pub use runner::DataFlowNodeRunner;
 
 #[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
 #[cfg_attr(not(target_family = "wasm"), derive(JsonSchema))]
@@ -26,6 +28,17 @@ pub struct DataFlowEdge {
     to: u32,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DataFlowLog {
+    pub run: Vec<DataFlowNodeLog>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DataFlowNodeLog {
+    pub node: String,
+    pub console: Vec<ConsoleMessage>,
+}
+
 impl PartialOrd for DataFlowEdge {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         Some(self.cmp(other))
@@ -51,7 +64,9 @@ mod tests {
         Mock, MockServer, ResponseTemplate,
     };
 
-    use crate::{actions::TaskActionInvocation, Error, Result};
+    use crate::{
+        actions::TaskActionInvocation, egress::EgressPolicy, limits::RunBudget, Error, Result,
+    };
 
     use super::{config::DataFlowConfig, *};
 
@@ -251,7 +266,15 @@ mod tests {
 
         println!("Sending 1 to trigger1");
         let (state, log, actions) = config
-            .evaluate_trigger("task", state, trigger1, "trigger1", json!({ "value": 1 }))
+            .evaluate_trigger(
+                "task",
+                state,
+                trigger1,
+                "trigger1",
+                json!({ "value": 1 }),
+                &mut RunBudget::default(),
+                &EgressPolicy::allow_all(),
+            )
             .await
             .unwrap();
 
@@ -276,6 +299,7 @@ mod tests {
             vec![TaskActionInvocation {
                 name: "send_email".to_string(),
                 payload: json!({ "contents": "The value: 5" }),
+                node: Some("send_email".to_string()),
             }]
             .as_slice()
         );
@@ -295,7 +319,15 @@ mod tests {
 
         println!("Sending -1 to trigger2");
         let (state, log, actions) = config
-            .evaluate_trigger("task", state, trigger2, "trigger2", json!({ "value": -1 }))
+            .evaluate_trigger(
+                "task",
+                state,
+                trigger2,
+                "trigger2",
+                json!({ "value": -1 }),
+                &mut RunBudget::default(),
+                &EgressPolicy::allow_all(),
+            )
             .await
             .unwrap();
 
@@ -327,7 +359,15 @@ mod tests {
 
         println!("Sending 2 to trigger2");
         let (state, log, actions) = config
-            .evaluate_trigger("task", state, trigger2, "trigger2", json!({ "value": 2 }))
+            .evaluate_trigger(
+                "task",
+                state,
+                trigger2,
+                "trigger2",
+                json!({ "value": 2 }),
+                &mut RunBudget::default(),
+                &EgressPolicy::allow_all(),
+            )
             .await
             .unwrap();
 
@@ -352,6 +392,7 @@ mod tests {
             vec![TaskActionInvocation {
                 name: "send_email".to_string(),
                 payload: json!({ "contents": "The value: 7" }),
+                node: Some("send_email".to_string()),
             }]
             .as_slice()
         );
@@ -377,7 +418,15 @@ mod tests {
 
         println!("Sending 1 to trigger1");
         let (state, log, actions) = config
-            .evaluate_trigger("task", state, trigger1, "trigger1", json!({ "value": 1 }))
+            .evaluate_trigger(
+                "task",
+                state,
+                trigger1,
+                "trigger1",
+                json!({ "value": 1 }),
+                &mut RunBudget::default(),
+                &EgressPolicy::allow_all(),
+            )
             .await
             .unwrap();
 
@@ -391,7 +440,15 @@ mod tests {
 
         println!("Sending 2 to trigger2");
         let (state, log, actions) = config
-            .evaluate_trigger("task", state, trigger2, "trigger2", json!({ "value": 2 }))
+            .evaluate_trigger(
+                "task",
+                state,
+                trigger2,
+                "trigger2",
+                json!({ "value": 2 }),
+                &mut RunBudget::default(),
+                &EgressPolicy::allow_all(),
+            )
             .await
             .unwrap();
 
@@ -412,6 +469,7 @@ mod tests {
             vec![TaskActionInvocation {
                 name: "send_email".to_string(),
                 payload: json!({ "contents": "The value: 7" }),
+                node: Some("send_email".to_string()),
             }]
             .as_slice()
         );
@@ -437,7 +495,15 @@ mod tests {
 
         println!("Sending 1 to trigger1");
         let err = config
-            .evaluate_trigger("task", state, trigger1, "trigger1", json!({ "value": 1 }))
+            .evaluate_trigger(
+                "task",
+                state,
+                trigger1,
+                "trigger1",
+                json!({ "value": 1 }),
+                &mut RunBudget::default(),
+                &EgressPolicy::allow_all(),
+            )
             .await
             .expect_err("should have failed");
 
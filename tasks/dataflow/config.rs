@@ -1,12 +1,12 @@
-use crate::{actions::TaskActionInvocations, Error, Result};
+use crate::{actions::TaskActionInvocations, limits::RunBudget, Error, Result};
 use fxhash::FxHashSet;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use tracing::{event, Level};
 
 use super::{
-    dag::NodeWalker, toposort_nodes, DataFlowEdge, DataFlowNode, DataFlowNodeFunction,
-    DataFlowState,
+    dag::NodeWalker, runner::DataFlowNodeRunner, toposort_nodes, DataFlowEdge, DataFlowLog,
+    DataFlowNode, DataFlowNodeFunction, DataFlowNodeLog, DataFlowState,
 };
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
@@ -43,6 +43,19 @@ impl DataFlowConfig {
         DataFlowState { nodes: Vec::new() }
     }
 
+    /// Initialize state for nodes that don't actually run but just supply data.
+    pub async fn init_static_nodes(&self, runner: &impl DataFlowNodeRunner) -> Result<()> {
+        for node in self.nodes.iter() {
+            if let DataFlowNodeFunction::Text(t) = &node.func {
+                runner
+                    .set_node_state(node.name.as_str(), &serde_json::json!(t.body.as_str()))
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
     #[cfg(not(target_family = "wasm"))]
     pub async fn evaluate_trigger(
         &self,
@@ -51,23 +64,46 @@ impl DataFlowConfig {
         task_trigger_id: ergo_database::object_id::TaskTriggerId,
         task_trigger_local_id: &str,
         payload: serde_json::Value,
-    ) -> Result<(
-        DataFlowState,
-        Option<super::run::DataFlowLog>,
-        TaskActionInvocations,
-    )> {
-        use crate::dataflow::run::DataFlowNodeLog;
+        budget: &mut RunBudget,
+        egress: &crate::egress::EgressPolicy,
+    ) -> Result<(DataFlowState, Option<DataFlowLog>, TaskActionInvocations)> {
+        if state.nodes.len() != self.nodes.len() {
+            state.nodes.resize_with(self.nodes.len(), String::new);
+        }
 
-        use super::run::{DataFlowLog, DataFlowRunner};
+        let runner = super::run::DataFlowRunner::new(self, &state, egress.permissions()).await?;
+        self.evaluate_trigger_with_runner(
+            &runner,
+            task_name,
+            state,
+            task_trigger_id,
+            task_trigger_local_id,
+            payload,
+            budget,
+        )
+        .await
+    }
 
+    /// Run a trigger through the graph using the given runner, which must already hold the
+    /// current state of every node. Action nodes are not executed; the actions that they produce
+    /// are returned instead.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn evaluate_trigger_with_runner(
+        &self,
+        runner: &impl DataFlowNodeRunner,
+        task_name: &str,
+        mut state: DataFlowState,
+        task_trigger_id: ergo_database::object_id::TaskTriggerId,
+        task_trigger_local_id: &str,
+        payload: serde_json::Value,
+        budget: &mut RunBudget,
+    ) -> Result<(DataFlowState, Option<DataFlowLog>, TaskActionInvocations)> {
         if state.nodes.len() != self.nodes.len() {
             state.nodes.resize_with(self.nodes.len(), String::new);
         }
 
         let mut to_run = FxHashSet::default();
 
-        let runner = DataFlowRunner::new(self, &state).await?;
-
         let trigger_node = self
             .nodes
             .iter()
@@ -88,7 +124,7 @@ impl DataFlowConfig {
         let first_node = &self.nodes[first_node_idx];
         let new_state = first_node
             .func
-            .execute(task_name, &first_node.name, &runner, &[], Some(payload))
+            .execute(task_name, &first_node.name, runner, &[], Some(payload))
             .await?;
 
         let Some(new_state) = new_state else {
@@ -128,12 +164,16 @@ impl DataFlowConfig {
                     .collect()
             };
 
+            if node.func.runs_script() {
+                budget.js_evaluation()?;
+            }
+
             event!(Level::DEBUG, node=%node.name, state=?state, "Evaluating node");
             dbg!(&node);
             dbg!(&state);
             let result = node
                 .func
-                .execute(task_name, &node.name, &runner, &null_check_nodes, None)
+                .execute(task_name, &node.name, runner, &null_check_nodes, None)
                 .await?;
             dbg!(&result);
 
@@ -159,6 +199,7 @@ impl DataFlowConfig {
             }
 
             if let Some(action) = result.action {
+                budget.add_actions(1)?;
                 actions.push(action);
             }
         }
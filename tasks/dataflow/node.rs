@@ -1,4 +1,4 @@
-use crate::{actions::TaskActionInvocation, Error, Result};
+use crate::{actions::TaskActionInvocation, Result};
 use ergo_database::object_id::TaskTriggerId;
 #[cfg(not(target_family = "wasm"))]
 pub use ergo_js::ConsoleMessage;
@@ -6,13 +6,31 @@ use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-#[cfg(not(target_family = "wasm"))]
+use super::runner::DataFlowNodeRunner;
+
 pub use node_result::*;
 
-#[cfg(not(target_family = "wasm"))]
-mod node_result {
-    pub use ergo_js::ConsoleMessage;
+/// A console message logged by a node in the browser. This serializes the same way as the
+/// server's messages, so simulated runs can be displayed the same way as real ones.
+#[cfg(target_family = "wasm")]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConsoleMessage {
+    pub level: ConsoleLevel,
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub message: String,
+}
+
+#[cfg(target_family = "wasm")]
+#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConsoleLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
 
+mod node_result {
+    use super::ConsoleMessage;
     use crate::actions::TaskActionInvocation;
 
     #[derive(Debug)]
@@ -133,12 +151,11 @@ pub struct DataFlowAction {
 }
 
 impl DataFlowNodeFunction {
-    #[cfg(not(target_family = "wasm"))]
     pub(super) async fn execute(
         &self,
         task_name: &str,
         node_name: &str,
-        runner: &super::run::DataFlowRunner,
+        runner: &impl DataFlowNodeRunner,
         null_check_nodes: &[&str],
         input: Option<serde_json::Value>,
     ) -> Result<Option<NodeResult>> {
@@ -170,6 +187,11 @@ impl DataFlowNodeFunction {
         }
     }
 
+    /// Whether executing this node evaluates any JavaScript.
+    pub(super) fn runs_script(&self) -> bool {
+        matches!(self, Self::Js(_) | Self::Action(_))
+    }
+
     pub(super) fn persist_output(&self) -> bool {
         match self {
             Self::Js(_) | Self::Action(_) | Self::Trigger(_) | Self::Table | Self::Graph => true,
@@ -178,11 +200,10 @@ impl DataFlowNodeFunction {
     }
 }
 
-#[cfg(not(target_family = "wasm"))]
 async fn evaluate_action_node(
     task_name: &str,
     node_name: &str,
-    runner: &super::run::DataFlowRunner,
+    runner: &impl DataFlowNodeRunner,
     null_check_nodes: &[&str],
     action: &DataFlowAction,
 ) -> Result<Option<NodeResult>> {
@@ -193,34 +214,20 @@ async fn evaluate_action_node(
         null_check_nodes,
         &action.payload_code,
     )
-    .await
-    .map_err(|e| match e {
-        Error::TaskScript { error, console } => Error::DataflowScript {
-            node: node_name.to_string(),
-            error,
-            console,
-        },
-        _ => e,
-    })?;
+    .await?;
 
     let Some((result, console)) = result else {
         return Ok(None);
     };
 
-    let action_payload =
-        runner
-            .get_raw_state(node_name)
-            .await
-            .map_err(|e| Error::DataflowGetStateError {
-                node: node_name.to_string(),
-                error: e,
-            })?;
+    let action_payload = runner.get_raw_state(node_name).await?;
 
     let action = match action_payload {
         serde_json::Value::Null => None,
         serde_json::Value::Object(_) => Some(TaskActionInvocation {
             name: action.action_id.clone(),
             payload: action_payload,
+            node: Some(node_name.to_string()),
         }),
         // TODO Log an error here?
         _ => None,
@@ -233,11 +240,10 @@ async fn evaluate_action_node(
     }))
 }
 
-#[cfg(not(target_family = "wasm"))]
 async fn run_js(
     task_name: &str,
     node_name: &str,
-    runner: &super::run::DataFlowRunner,
+    runner: &impl DataFlowNodeRunner,
     null_check_nodes: &[&str],
     expr: &DataFlowJs,
 ) -> Result<Option<(String, Vec<ConsoleMessage>)>> {
@@ -251,12 +257,4 @@ async fn run_js(
                 Some((result, console))
             }
         })
-        .map_err(|e| match e {
-            Error::TaskScript { error, console } => Error::DataflowScript {
-                node: node_name.to_string(),
-                error,
-                console,
-            },
-            _ => e,
-        })
 }
@@ -1,24 +1,13 @@
-use ergo_js::{worker::JsWorker, ConsoleMessage};
+use async_trait::async_trait;
+use ergo_js::{permissions::Permissions, worker::JsWorker, ConsoleMessage};
 use futures::FutureExt;
 use fxhash::FxHashMap;
-use serde::{Deserialize, Serialize};
 
-use super::{config::DataFlowConfig, DataFlowNodeFunction, DataFlowState};
+use super::{config::DataFlowConfig, runner::DataFlowNodeRunner, DataFlowState};
 use crate::{Error, Result};
 
 pub const DATAFLOW_ENV_CODE: &str = include_str!("../js_helpers/rust-dist/dataflow.js");
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct DataFlowLog {
-    pub run: Vec<DataFlowNodeLog>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct DataFlowNodeLog {
-    pub node: String,
-    pub console: Vec<ConsoleMessage>,
-}
-
 pub struct DataFlowRunner {
     worker: ergo_js::worker::JsWorker,
 }
@@ -27,6 +16,7 @@ impl DataFlowRunner {
     pub async fn new(
         config: &DataFlowConfig,
         state: &DataFlowState,
+        permissions: Permissions,
     ) -> Result<DataFlowRunner, Error> {
         let state: FxHashMap<&str, &str> = config
             .nodes
@@ -39,7 +29,7 @@ impl DataFlowRunner {
         let code = format!("globalThis.__ergo_nodecode = {};", config.compiled);
         let set_node_state = format!("__ergo_dataflow.initState({state})");
 
-        let worker = JsWorker::new();
+        let worker = JsWorker::with_permissions(permissions);
         worker
             .run(|runtime| {
                 async move {
@@ -54,21 +44,29 @@ impl DataFlowRunner {
             .map_err(|e| Error::DataflowInitScriptError { error: e })?;
 
         let runner = DataFlowRunner { worker };
-
-        // Initialize state for nodes that don't actually run but just supply data.
-        for node in config.nodes.iter() {
-            if let DataFlowNodeFunction::Text(t) = &node.func {
-                runner
-                    .set_node_state(node.name.as_str(), &serde_json::json!(t.body.as_str()))
-                    .await?;
-            }
-        }
+        config.init_static_nodes(&runner).await?;
 
         Ok(runner)
     }
 
-    /// Run a node and return the result, serialized for storage.
-    pub async fn run_node(
+    pub async fn retrieve_state(&self) -> Result<FxHashMap<String, String>, ergo_js::Error> {
+        self.worker
+            .run(|runtime| {
+                async move {
+                    runtime.run_expression::<FxHashMap<String, String>>(
+                        "retrieve_state",
+                        "__ergo_dataflow.serializeState()",
+                    )
+                }
+                .boxed_local()
+            })
+            .await
+    }
+}
+
+#[async_trait(?Send)]
+impl DataFlowNodeRunner for DataFlowRunner {
+    async fn run_node(
         &self,
         task_name: &str,
         node_name: &str,
@@ -85,6 +83,7 @@ impl DataFlowRunner {
         let func_call = format!(
             r##"__ergo_dataflow.runNode("{node_name}", "__ergo_nodecode", "{node_func}", {null_checks})"##
         );
+        let node = node_name.to_string();
 
         self.worker
             .run(move |runtime| {
@@ -94,7 +93,11 @@ impl DataFlowRunner {
                     match run_result {
                         Ok(value) => Ok((value, console)),
                         // TODO Generate a source map and use it to translate the code locations in the error.
-                        Err(error) => Err(Error::TaskScript { error, console }),
+                        Err(error) => Err(Error::DataflowScript {
+                            node,
+                            error,
+                            console,
+                        }),
                     }
                 }
                 .boxed_local()
@@ -102,12 +105,7 @@ impl DataFlowRunner {
             .await
     }
 
-    /// Set a node's state and return the serialized version of the state.
-    pub async fn set_node_state(
-        &self,
-        node_name: &str,
-        value: &serde_json::Value,
-    ) -> Result<String> {
+    async fn set_node_state(&self, node_name: &str, value: &serde_json::Value) -> Result<String> {
         let json = serde_json::to_string(value)?;
         let code = format!(r##"__ergo_dataflow.setNodeState("{node_name}", {json})"##);
 
@@ -123,10 +121,7 @@ impl DataFlowRunner {
             })
     }
 
-    pub async fn get_raw_state(
-        &self,
-        node_name: &str,
-    ) -> Result<serde_json::Value, ergo_js::Error> {
+    async fn get_raw_state(&self, node_name: &str) -> Result<serde_json::Value> {
         let code = format!(r##"__ergo_dataflow.getState("{node_name}")"##);
         self.worker
             .run(|runtime| {
@@ -134,19 +129,9 @@ impl DataFlowRunner {
                     .boxed_local()
             })
             .await
-    }
-
-    pub async fn retrieve_state(&self) -> Result<FxHashMap<String, String>, ergo_js::Error> {
-        self.worker
-            .run(|runtime| {
-                async move {
-                    runtime.run_expression::<FxHashMap<String, String>>(
-                        "retrieve_state",
-                        "__ergo_dataflow.serializeState()",
-                    )
-                }
-                .boxed_local()
+            .map_err(|e| Error::DataflowGetStateError {
+                node: node_name.to_string(),
+                error: e,
             })
-            .await
     }
 }
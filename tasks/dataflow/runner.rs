@@ -0,0 +1,29 @@
+//! The interface between the dataflow evaluator and the JavaScript environment that runs the
+//! nodes. On the server this is a [super::run::DataFlowRunner] backed by a JS worker. In the
+//! browser, the wasm build implements it on top of a sandbox supplied by the script editor, so
+//! that a task can be simulated without sending anything to the server.
+
+use async_trait::async_trait;
+
+use super::ConsoleMessage;
+use crate::Result;
+
+#[async_trait(?Send)]
+pub trait DataFlowNodeRunner {
+    /// Run a node's function and return its new state, serialized for storage, along with any
+    /// console output. An empty string means that the node did not run because one of the nodes
+    /// in `null_check_nodes` had no value.
+    async fn run_node(
+        &self,
+        task_name: &str,
+        node_name: &str,
+        node_func: &str,
+        null_check_nodes: &[&str],
+    ) -> Result<(String, Vec<ConsoleMessage>)>;
+
+    /// Set a node's state and return the serialized version of the state.
+    async fn set_node_state(&self, node_name: &str, value: &serde_json::Value) -> Result<String>;
+
+    /// Get the current value of a node's state.
+    async fn get_raw_state(&self, node_name: &str) -> Result<serde_json::Value>;
+}
@@ -0,0 +1,295 @@
+//! When an action or input changes, the tasks that use it may no longer be valid. These
+//! functions re-validate the dependent tasks so that the broken ones can be flagged or disabled
+//! up front instead of failing every time they run.
+
+use ergo_database::object_id::{InputId, OrgId, PeriodicTriggerId, TaskId};
+use ergo_localization::Localize;
+use ergo_notifications::{Notification, NotificationManager, NotifyEvent};
+use ergo_queues::remove_pending_job;
+use fxhash::FxHashMap;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use tracing::{event, Level};
+
+use crate::{
+    actions::{
+        template::{self, TemplateError},
+        Action, TaskActionTemplate,
+    },
+    inputs::queue::InputQueue,
+    DependencyValidateError, Error,
+};
+
+/// What to do with dependent tasks that no longer validate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum InvalidDependentPolicy {
+    /// Record the validation errors on the task, but leave it enabled.
+    Flag,
+    /// Record the validation errors and disable the task.
+    #[default]
+    Disable,
+}
+
+#[derive(Debug)]
+pub struct InvalidTask {
+    pub task_id: TaskId,
+    pub org_id: OrgId,
+    pub name: String,
+    pub errors: Vec<DependencyValidateError>,
+}
+
+#[derive(Debug, Default)]
+pub struct DependentValidation {
+    /// Every task that was checked, whether or not it was valid.
+    pub checked: Vec<TaskId>,
+    pub invalid: Vec<InvalidTask>,
+}
+
+impl DependentValidation {
+    pub(crate) fn add_checked(&mut self, task_id: &TaskId) {
+        if !self.checked.contains(task_id) {
+            self.checked.push(task_id.clone());
+        }
+    }
+
+    pub(crate) fn add_error(
+        &mut self,
+        task_id: &TaskId,
+        org_id: &OrgId,
+        name: &str,
+        error: DependencyValidateError,
+    ) {
+        match self.invalid.iter_mut().find(|t| &t.task_id == task_id) {
+            Some(task) => task.errors.push(error),
+            None => self.invalid.push(InvalidTask {
+                task_id: task_id.clone(),
+                org_id: org_id.clone(),
+                name: name.to_string(),
+                errors: vec![error],
+            }),
+        }
+    }
+}
+
+/// Check the tasks that use an action against its new definition.
+pub async fn validate_action_dependents(
+    tx: &mut PgConnection,
+    action: &Action,
+) -> Result<DependentValidation, Error> {
+    let task_actions = sqlx::query!(
+        r##"SELECT ta.task_id AS "task_id: TaskId",
+            tasks.org_id AS "org_id: OrgId",
+            tasks.name AS task_name,
+            ta.task_action_local_id,
+            NULLIF(ta.action_template, 'null'::jsonb)
+                AS "action_template: sqlx::types::Json<TaskActionTemplate>",
+            ta.account_id IS NOT NULL AS "has_account!",
+            accounts.account_type_id AS "account_type_id?"
+        FROM task_actions ta
+        JOIN tasks USING(task_id)
+        LEFT JOIN accounts USING(account_id)
+        WHERE ta.action_id=$1 AND NOT tasks.deleted"##,
+        action.action_id.0
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut result = DependentValidation::default();
+    for ta in task_actions {
+        result.add_checked(&ta.task_id);
+
+        if action.account_required && !ta.has_account {
+            result.add_error(
+                &ta.task_id,
+                &ta.org_id,
+                &ta.task_name,
+                DependencyValidateError::AccountRequired {
+                    task_action: ta.task_action_local_id.clone(),
+                },
+            );
+        }
+
+        if let Some(account_type) = ta.account_type_id {
+            if !action.account_types.is_empty() && !action.account_types.contains(&account_type) {
+                result.add_error(
+                    &ta.task_id,
+                    &ta.org_id,
+                    &ta.task_name,
+                    DependencyValidateError::AccountType {
+                        task_action: ta.task_action_local_id.clone(),
+                        account_type,
+                    },
+                );
+            }
+        }
+
+        // Values missing from the task action template may be supplied when the action is
+        // invoked, so only the values that are present can be checked here.
+        if let Some(action_template) = ta.action_template {
+            let values = action_template.0.into_iter().collect::<FxHashMap<_, _>>();
+            let validated = template::validate_present(
+                "task action",
+                Some(&ta.task_action_local_id),
+                &action.template_fields,
+                &values,
+            );
+
+            if let Err(TemplateError::Validation(e)) = validated {
+                result.add_error(
+                    &ta.task_id,
+                    &ta.org_id,
+                    &ta.task_name,
+                    DependencyValidateError::ActionTemplate(e),
+                );
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Check the tasks that use an input against its new payload schema.
+pub async fn validate_input_dependents(
+    tx: &mut PgConnection,
+    input_id: &InputId,
+    payload_schema: &serde_json::Value,
+) -> Result<DependentValidation, Error> {
+    let compiled_schema = jsonschema::JSONSchema::compile(payload_schema)?;
+
+    let triggers = sqlx::query!(
+        r##"SELECT tt.task_id AS "task_id: TaskId",
+            tasks.org_id AS "org_id: OrgId",
+            tasks.name AS task_name,
+            tt.task_trigger_local_id,
+            pt.periodic_trigger_id AS "periodic_trigger_id?: PeriodicTriggerId",
+            pt.name AS "periodic_trigger_name?",
+            pt.payload AS "payload?"
+        FROM task_triggers tt
+        JOIN tasks USING(task_id)
+        LEFT JOIN periodic_triggers pt USING(task_trigger_id)
+        WHERE tt.input_id=$1 AND NOT tasks.deleted"##,
+        input_id.0
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut result = DependentValidation::default();
+    for trigger in triggers {
+        result.add_checked(&trigger.task_id);
+
+        let (periodic_trigger_id, payload) = match (trigger.periodic_trigger_id, trigger.payload) {
+            (Some(id), Some(payload)) => (id, payload),
+            _ => continue,
+        };
+
+        if let Err(errors) = compiled_schema.validate(&payload) {
+            let error = errors.map(|e| e.to_string()).collect::<Vec<_>>().join("; ");
+            result.add_error(
+                &trigger.task_id,
+                &trigger.org_id,
+                &trigger.task_name,
+                DependencyValidateError::PeriodicPayload {
+                    task_trigger: trigger.task_trigger_local_id,
+                    periodic_trigger: trigger
+                        .periodic_trigger_name
+                        .unwrap_or_else(|| periodic_trigger_id.to_string()),
+                    error,
+                },
+            );
+        }
+    }
+
+    Ok(result)
+}
+
+/// Save the validation results on the checked tasks, disable the invalid tasks if the policy
+/// says to, and send notifications about the invalid tasks.
+pub async fn apply_dependent_validation(
+    tx: &mut PgConnection,
+    notifications: Option<&NotificationManager>,
+    redis_key_prefix: Option<&str>,
+    validation: &DependentValidation,
+    policy: InvalidDependentPolicy,
+    locale: Option<&str>,
+) -> Result<(), Error> {
+    let valid_ids = validation
+        .checked
+        .iter()
+        .filter(|id| !validation.invalid.iter().any(|t| &t.task_id == *id))
+        .map(|id| id.0)
+        .collect::<Vec<_>>();
+
+    if !valid_ids.is_empty() {
+        sqlx::query!(
+            r##"UPDATE tasks SET validation_errors=NULL
+            WHERE task_id=ANY($1) AND validation_errors IS NOT NULL"##,
+            valid_ids.as_slice()
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    let disable = policy == InvalidDependentPolicy::Disable;
+    let queue_name = InputQueue::queue_name(redis_key_prefix);
+
+    for task in &validation.invalid {
+        let errors = task
+            .errors
+            .iter()
+            .map(|e| e.localize(locale))
+            .collect::<Vec<_>>();
+
+        event!(Level::INFO, task_id=%task.task_id, ?errors, %disable, "Task no longer validates");
+
+        sqlx::query!(
+            r##"UPDATE tasks
+            SET validation_errors=$2, enabled = enabled AND NOT $3, modified=now()
+            WHERE task_id=$1"##,
+            task.task_id.0,
+            sqlx::types::Json(&errors) as _,
+            disable
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        if disable {
+            // Unschedule the pending periodic inputs, the same as when a task is disabled
+            // through the API.
+            let pending = sqlx::query!(
+                r##"SELECT queue_job_id
+                FROM inputs_log
+                WHERE task_id=$1 AND status='pending' AND periodic_trigger_id IS NOT NULL"##,
+                task.task_id.0
+            )
+            .fetch_all(&mut *tx)
+            .await?;
+
+            for job in pending {
+                remove_pending_job(tx, queue_name.as_ref(), &job.queue_job_id).await?;
+            }
+        }
+
+        if let Some(notifications) = notifications {
+            let notification = Notification {
+                event: NotifyEvent::TaskInvalidated,
+                task_id: task.task_id.clone(),
+                task_name: task.name.clone(),
+                local_id: String::new(),
+                local_object_name: task.name.clone(),
+                local_object_id: None,
+                payload: None,
+                error: Some(errors.join("\n")),
+                log_id: None,
+                suppressed_count: None,
+            };
+
+            notifications
+                .notify(tx, &task.org_id.0, notification)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,244 @@
+//! Per-org policies for the outbound requests made by task scripts, `js` actions, and the HTTP
+//! executor. Requests to loopback, private, and link-local addresses are blocked unless the
+//! org's policy allows them, so that user-written scripts and templates can't reach internal
+//! services.
+
+use std::{convert::TryFrom, str::FromStr};
+
+use ergo_database::object_id::OrgId;
+use ergo_js::permissions::{NetHostAndPort, Permissions};
+use ipnet::IpNet;
+use lazy_static::lazy_static;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, PgConnection};
+use thiserror::Error;
+
+use crate::Error;
+
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+#[error("Requests to {0} are blocked by the organization's egress policy")]
+pub struct EgressDenied(pub String);
+
+/// A single egress rule. This is either a CIDR range such as `10.0.0.0/8`, an IP address, or a
+/// hostname with an optional port. Hostnames may start with `*.` to match all subdomains.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum EgressRule {
+    Network(IpNet),
+    Host(NetHostAndPort),
+}
+
+impl FromStr for EgressRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(net) = s.parse::<IpNet>() {
+            return Ok(EgressRule::Network(net));
+        }
+
+        if let Ok(ip) = s.parse::<std::net::IpAddr>() {
+            return Ok(EgressRule::Network(IpNet::from(ip)));
+        }
+
+        NetHostAndPort::try_from(s)
+            .map(EgressRule::Host)
+            .map_err(|_| format!("Invalid egress rule {}", s))
+    }
+}
+
+impl TryFrom<String> for EgressRule {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<EgressRule> for String {
+    fn from(rule: EgressRule) -> String {
+        rule.to_string()
+    }
+}
+
+impl std::fmt::Display for EgressRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Network(net) => write!(f, "{}", net),
+            Self::Host(NetHostAndPort {
+                host,
+                port: Some(port),
+            }) => write!(f, "{}:{}", host, port),
+            Self::Host(NetHostAndPort { host, port: None }) => f.write_str(host),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct EgressPolicy {
+    /// If not empty, requests may only go to hosts that match one of these rules.
+    #[schemars(with = "Vec<String>")]
+    #[serde(default)]
+    pub allow: Vec<EgressRule>,
+    /// Requests to hosts that match any of these rules are always blocked.
+    #[schemars(with = "Vec<String>")]
+    #[serde(default)]
+    pub deny: Vec<EgressRule>,
+    /// Allow requests to loopback, private, and link-local addresses. When this is false,
+    /// these addresses can still be reached by listing them in `allow`.
+    #[serde(default)]
+    pub allow_private_networks: bool,
+}
+
+lazy_static! {
+    /// The policy for orgs that haven't set one. `EGRESS_ALLOW_PRIVATE_NETWORKS` can be set to
+    /// allow requests to private networks by default, which is useful for local development.
+    pub static ref DEFAULT_EGRESS_POLICY: EgressPolicy = EgressPolicy {
+        allow_private_networks: std::env::var("EGRESS_ALLOW_PRIVATE_NETWORKS")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false),
+        ..Default::default()
+    };
+}
+
+impl EgressPolicy {
+    /// A policy that doesn't restrict anything.
+    pub fn allow_all() -> Self {
+        EgressPolicy {
+            allow_private_networks: true,
+            ..Default::default()
+        }
+    }
+
+    /// Convert the policy into the permissions used by the JS runtime.
+    pub fn permissions(&self) -> Permissions {
+        fn split(rules: &[EgressRule]) -> (Vec<NetHostAndPort>, Vec<IpNet>) {
+            let mut hosts = Vec::new();
+            let mut nets = Vec::new();
+            for rule in rules {
+                match rule {
+                    EgressRule::Network(net) => nets.push(*net),
+                    EgressRule::Host(host) => hosts.push(host.clone()),
+                }
+            }
+
+            (hosts, nets)
+        }
+
+        let (net_allow_list, cidr_allow_list) = split(&self.allow);
+        let (net_block_list, cidr_block_list) = split(&self.deny);
+
+        Permissions {
+            allow_relative_urls: false,
+            net_allow_list,
+            net_block_list,
+            net_restrict_list: Vec::new(),
+            cidr_allow_list,
+            cidr_block_list,
+            block_private_networks: !self.allow_private_networks,
+        }
+    }
+
+    /// Check if a request to `url` is allowed. This may do a DNS lookup, so it runs on the
+    /// blocking thread pool.
+    pub async fn check_url(&self, url: &url::Url) -> Result<(), EgressDenied> {
+        let permissions = self.permissions();
+        let check_url = url.clone();
+        let allowed =
+            tokio::task::spawn_blocking(move || permissions.check_url(&check_url).is_ok())
+                .await
+                .unwrap_or(false);
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(EgressDenied(url.host_str().unwrap_or_default().to_string()))
+        }
+    }
+}
+
+/// Get the egress policy for an org, or the default policy if it has not set one.
+pub async fn get_policy(tx: &mut PgConnection, org_id: &OrgId) -> Result<EgressPolicy, Error> {
+    let policy = sqlx::query_scalar!(
+        r##"SELECT policy AS "policy: Json<EgressPolicy>"
+        FROM org_egress_policies
+        WHERE org_id=$1"##,
+        org_id.0
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    Ok(policy
+        .map(|p| p.0)
+        .unwrap_or_else(|| DEFAULT_EGRESS_POLICY.clone()))
+}
+
+pub async fn set_policy(
+    tx: &mut PgConnection,
+    org_id: &OrgId,
+    policy: &EgressPolicy,
+) -> Result<(), Error> {
+    sqlx::query!(
+        r##"INSERT INTO org_egress_policies (org_id, policy)
+        VALUES ($1, $2)
+        ON CONFLICT (org_id) DO UPDATE SET policy=EXCLUDED.policy, updated=now()"##,
+        org_id.0,
+        Json(policy) as _
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rules() {
+        let rules = [
+            "10.0.0.0/8",
+            "192.168.1.5",
+            "*.example.com",
+            "example.org:8080",
+        ]
+        .into_iter()
+        .map(|r| r.parse::<EgressRule>().expect(r))
+        .collect::<Vec<_>>();
+
+        assert_eq!(rules[0], EgressRule::Network("10.0.0.0/8".parse().unwrap()));
+        assert_eq!(
+            rules[1],
+            EgressRule::Network("192.168.1.5/32".parse().unwrap())
+        );
+        assert_eq!(
+            rules.iter().map(|r| r.to_string()).collect::<Vec<_>>(),
+            vec![
+                "10.0.0.0/8",
+                "192.168.1.5/32",
+                "*.example.com",
+                "example.org:8080"
+            ]
+        );
+
+        assert!("http://".parse::<EgressRule>().is_err());
+    }
+
+    #[test]
+    fn private_networks_blocked_by_default() {
+        let policy = EgressPolicy::default();
+        let permissions = policy.permissions();
+        assert!(permissions.check_host("169.254.169.254", None).is_err());
+        assert!(permissions.check_host("93.184.216.34", None).is_ok());
+
+        let policy = EgressPolicy {
+            allow: vec!["10.1.0.0/16".parse().unwrap()],
+            ..Default::default()
+        };
+        let permissions = policy.permissions();
+        assert!(permissions.check_host("10.1.2.3", None).is_ok());
+        assert!(permissions.check_host("10.2.0.1", None).is_err());
+    }
+}
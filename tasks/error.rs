@@ -2,10 +2,12 @@ use std::{borrow::Cow, fmt::Write};
 
 #[cfg(not(target_family = "wasm"))]
 use ergo_js::ConsoleMessage;
+use ergo_localization::{format_message, Localize};
+use ergo_problem::{format_field_errors, FieldError, ProblemDetails};
 use smallvec::{smallvec, SmallVec};
 use thiserror::Error;
 
-use crate::actions::template::TemplateError;
+use crate::actions::template::{TemplateError, TemplateValidationError};
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
@@ -22,8 +24,8 @@ pub enum Error {
     #[error("SQL Error: {0}")]
     SqlError(#[from] sqlx::error::Error),
 
-    #[error("{0:?}")]
-    JsonSchemaValidationError(SmallVec<[String; 2]>),
+    #[error("{}", format_field_errors(.0))]
+    JsonSchemaValidationError(SmallVec<[FieldError; 2]>),
 
     #[cfg(not(target_family = "wasm"))]
     #[error(transparent)]
@@ -61,6 +63,12 @@ pub enum Error {
     #[error("Setting up task script: {0}")]
     TaskScriptSetup(anyhow::Error),
 
+    #[error("Bundling task script dependencies: {0}")]
+    TaskScriptBundle(String),
+
+    #[error("JS library error: {0}")]
+    JsLibrary(String),
+
     #[error("Task script error: {error}")]
     #[cfg(not(target_family = "wasm"))]
     TaskScript {
@@ -101,9 +109,19 @@ pub enum Error {
         error: ergo_js::Error,
     },
 
+    #[error("State reset script error: {0}")]
+    #[cfg(not(target_family = "wasm"))]
+    StateResetScript(#[source] ergo_js::Error),
+
     #[error("Parsing cron schedule: {0}")]
     CronParseError(#[from] cron::error::Error),
 
+    #[error("Unknown timezone {0}")]
+    InvalidTimezone(String),
+
+    #[error("Invalid schedule: {0}")]
+    InvalidSchedule(String),
+
     #[error("Tried to run empty task")]
     TaskIsEmpty,
 
@@ -122,6 +140,59 @@ pub enum Error {
     #[error("Periodic task was deleted")]
     PeriodicTaskDeleted,
 
+    #[error("Task is disabled and not accepting inputs")]
+    TaskDisabled,
+
+    #[cfg(not(target_family = "wasm"))]
+    #[error("Approval is already {0}")]
+    ApprovalClosed(crate::approvals::ApprovalStatus),
+
+    #[error("Action circuit breaker is open, retry in {}s", .0.as_secs().max(1))]
+    ActionCircuitOpen(std::time::Duration),
+
+    #[error(transparent)]
+    ExecutionLimitExceeded(#[from] crate::limits::LimitExceeded),
+
+    #[cfg(not(target_family = "wasm"))]
+    #[error(transparent)]
+    QuotaExceeded(#[from] crate::quotas::QuotaExceeded),
+
+    #[cfg(not(target_family = "wasm"))]
+    #[error(transparent)]
+    PayloadTooLarge(#[from] crate::payload_limits::PayloadTooLarge),
+
+    #[cfg(not(target_family = "wasm"))]
+    #[error("Parsing email: {0}")]
+    EmailParseError(#[from] mailparse::MailParseError),
+
+    #[cfg(not(target_family = "wasm"))]
+    #[error("IMAP error: {0}")]
+    ImapError(String),
+
+    #[cfg(not(target_family = "wasm"))]
+    #[error("Chat bot error: {0}")]
+    ChatBotError(String),
+
+    #[cfg(not(target_family = "wasm"))]
+    #[error("Archiving logs: {0}")]
+    LogArchive(String),
+
+    #[cfg(not(target_family = "wasm"))]
+    #[error("Exporting log event: {0}")]
+    FirehoseExport(String),
+
+    #[cfg(not(target_family = "wasm"))]
+    #[error("File watch error: {0}")]
+    FileWatch(String),
+
+    #[cfg(not(target_family = "wasm"))]
+    #[error("Vault error: {0}")]
+    Vault(String),
+
+    #[cfg(not(target_family = "wasm"))]
+    #[error("Invalid usage report: {0}")]
+    InvalidUsageReport(String),
+
     #[cfg(target_family = "wasm")]
     #[error(transparent)]
     JsSerdeError(#[from] serde_wasm_bindgen::Error),
@@ -129,6 +200,10 @@ pub enum Error {
     #[cfg(target_family = "wasm")]
     #[error("JS Error")]
     JsError(wasm_bindgen::JsValue),
+
+    #[cfg(target_family = "wasm")]
+    #[error("Dataflow node {node} script error: {error}")]
+    DataflowSimulation { node: String, error: String },
 }
 
 #[cfg(target_family = "wasm")]
@@ -140,14 +215,195 @@ impl From<wasm_bindgen::JsValue> for Error {
 
 impl<'a> From<jsonschema::ErrorIterator<'a>> for Error {
     fn from(e: jsonschema::ErrorIterator<'a>) -> Error {
-        let inner = e.map(|e| e.to_string()).collect::<SmallVec<_>>();
+        let inner = e.map(|e| schema_field_error(&e)).collect::<SmallVec<_>>();
         Error::JsonSchemaValidationError(inner)
     }
 }
 
 impl<'a> From<jsonschema::ValidationError<'a>> for Error {
     fn from(e: jsonschema::ValidationError<'a>) -> Error {
-        Error::JsonSchemaValidationError(smallvec![e.to_string()])
+        Error::JsonSchemaValidationError(smallvec![schema_field_error(&e)])
+    }
+}
+
+/// Convert a JSON schema failure into a field error, keyed by the JSON pointer to the value that
+/// failed.
+pub fn schema_field_error(e: &jsonschema::ValidationError) -> FieldError {
+    FieldError::new(e.instance_path.to_string(), e.to_string())
+}
+
+impl ProblemDetails for Error {
+    fn code(&self) -> &'static str {
+        match self {
+            #[cfg(not(target_family = "wasm"))]
+            Self::QueueError(_) => "queue_error",
+            Self::SerdeJsonError(_) => "serialization_error",
+            #[cfg(not(target_family = "wasm"))]
+            Self::SqlError(_) | Self::DatabaseError(_) => "database_error",
+            Self::JsonSchemaValidationError(_)
+            | Self::TaskValidateError(_)
+            | Self::ActionValidateError(_) => "validation_failed",
+            Self::StateMachineError(_) => "state_machine_error",
+            #[cfg(not(target_family = "wasm"))]
+            Self::NotificationError(_) => "notification_error",
+            #[cfg(not(target_family = "wasm"))]
+            Self::ExecuteError(_) => "action_failed",
+            Self::NotFound | Self::PeriodicTaskDeleted => "not_found",
+            Self::TaskActionNotFound(_) => "task_action_not_found",
+            Self::TaskTriggerNotFound(_) => "task_trigger_not_found",
+            Self::ConfigStateMismatch(_) => "config_state_mismatch",
+            Self::TaskScriptSetup(_) => "script_setup_failed",
+            Self::TaskScriptBundle(_) => "invalid_script_bundle",
+            Self::JsLibrary(_) => "invalid_js_library",
+            #[cfg(not(target_family = "wasm"))]
+            Self::TaskScript { .. } | Self::StateResetScript(_) => "script_error",
+            #[cfg(not(target_family = "wasm"))]
+            Self::DataflowInitScriptError { .. }
+            | Self::DataflowScript { .. }
+            | Self::DataflowGetStateError { .. }
+            | Self::DataflowSetStateError { .. } => "dataflow_script_error",
+            Self::CronParseError(_) | Self::InvalidSchedule(_) => "invalid_schedule",
+            Self::InvalidTimezone(_) => "invalid_timezone",
+            Self::TaskIsEmpty => "task_empty",
+            Self::MissingDataFlowNode(_)
+            | Self::MissingDataFlowNodeName(_)
+            | Self::BadEdgeIndex(_, _)
+            | Self::DataflowCycle(_) => "invalid_dataflow",
+            Self::TaskDisabled => "task_disabled",
+            #[cfg(not(target_family = "wasm"))]
+            Self::ApprovalClosed(_) => "approval_closed",
+            Self::ActionCircuitOpen(_) => "circuit_open",
+            Self::ExecutionLimitExceeded(_) => "execution_limit_exceeded",
+            #[cfg(not(target_family = "wasm"))]
+            Self::QuotaExceeded(_) => "quota_exceeded",
+            #[cfg(not(target_family = "wasm"))]
+            Self::PayloadTooLarge(_) => "payload_too_large",
+            #[cfg(not(target_family = "wasm"))]
+            Self::EmailParseError(_) => "invalid_email",
+            #[cfg(not(target_family = "wasm"))]
+            Self::ImapError(_) => "imap_error",
+            #[cfg(not(target_family = "wasm"))]
+            Self::ChatBotError(_) => "chat_bot_error",
+            #[cfg(not(target_family = "wasm"))]
+            Self::LogArchive(_) => "log_archive_failed",
+            #[cfg(not(target_family = "wasm"))]
+            Self::FirehoseExport(_) => "firehose_export_failed",
+            #[cfg(not(target_family = "wasm"))]
+            Self::FileWatch(_) => "file_watch_error",
+            #[cfg(not(target_family = "wasm"))]
+            Self::Vault(_) => "vault_error",
+            #[cfg(not(target_family = "wasm"))]
+            Self::InvalidUsageReport(_) => "invalid_usage_report",
+            #[cfg(target_family = "wasm")]
+            Self::JsSerdeError(_) | Self::JsError(_) => "script_error",
+            #[cfg(target_family = "wasm")]
+            Self::DataflowSimulation { .. } => "dataflow_script_error",
+        }
+    }
+
+    fn status(&self) -> u16 {
+        match self {
+            Self::NotFound
+            | Self::PeriodicTaskDeleted
+            | Self::TaskActionNotFound(_)
+            | Self::TaskTriggerNotFound(_) => 404,
+            Self::JsonSchemaValidationError(_)
+            | Self::TaskValidateError(_)
+            | Self::ActionValidateError(_)
+            | Self::ConfigStateMismatch(_)
+            | Self::TaskScriptBundle(_)
+            | Self::JsLibrary(_)
+            | Self::CronParseError(_)
+            | Self::InvalidSchedule(_)
+            | Self::InvalidTimezone(_)
+            | Self::TaskIsEmpty
+            | Self::MissingDataFlowNode(_)
+            | Self::MissingDataFlowNodeName(_)
+            | Self::BadEdgeIndex(_, _)
+            | Self::DataflowCycle(_)
+            | Self::TaskDisabled => 400,
+            Self::ExecutionLimitExceeded(_) => 422,
+            #[cfg(not(target_family = "wasm"))]
+            Self::ApprovalClosed(_) => 409,
+            Self::ActionCircuitOpen(_) => 503,
+            #[cfg(not(target_family = "wasm"))]
+            Self::EmailParseError(_) | Self::InvalidUsageReport(_) => 400,
+            #[cfg(not(target_family = "wasm"))]
+            Self::PayloadTooLarge(_) => 413,
+            #[cfg(not(target_family = "wasm"))]
+            Self::QuotaExceeded(e) => {
+                if e.is_daily() {
+                    429
+                } else {
+                    403
+                }
+            }
+            _ => 500,
+        }
+    }
+
+    fn field_errors(&self) -> Vec<FieldError> {
+        match self {
+            Self::JsonSchemaValidationError(errors) => errors.to_vec(),
+            Self::TaskValidateError(errors) => errors
+                .0
+                .iter()
+                .map(|e| FieldError {
+                    field: e.path().map(|p| p.to_string()),
+                    message: e.to_string(),
+                })
+                .collect(),
+            Self::ActionValidateError(errors) => errors
+                .0
+                .iter()
+                .map(|e| FieldError {
+                    field: e.path().map(|p| p.to_string()),
+                    message: e.to_string(),
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl Error {
+    /// Returns true if processing the same job again would fail the same way.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Self::ExecuteError(e) => e.error.is_permanent(),
+            Self::SerdeJsonError(_)
+            | Self::JsonSchemaValidationError(_)
+            | Self::TaskValidateError(_)
+            | Self::ActionValidateError(_)
+            | Self::TaskActionNotFound(_)
+            | Self::TaskTriggerNotFound(_)
+            | Self::ConfigStateMismatch(_)
+            | Self::TaskIsEmpty
+            | Self::MissingDataFlowNode(_)
+            | Self::MissingDataFlowNodeName(_)
+            | Self::BadEdgeIndex(_, _)
+            | Self::DataflowCycle(_)
+            | Self::CronParseError(_)
+            | Self::InvalidTimezone(_)
+            | Self::InvalidSchedule(_)
+            | Self::ExecutionLimitExceeded(_)
+            | Self::QuotaExceeded(_)
+            | Self::PayloadTooLarge(_)
+            | Self::JsLibrary(_)
+            | Self::TaskDisabled
+            | Self::ActionCircuitOpen(_)
+            | Self::EmailParseError(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn error_class(&self) -> ergo_queues::ErrorClass {
+        if self.is_permanent() {
+            ergo_queues::ErrorClass::Permanent
+        } else {
+            ergo_queues::ErrorClass::Retryable
+        }
     }
 }
 
@@ -173,6 +429,16 @@ impl std::fmt::Display for TaskValidateErrors {
     }
 }
 
+impl Localize for TaskValidateErrors {
+    fn localize(&self, locale: Option<&str>) -> String {
+        self.0
+            .iter()
+            .map(|e| e.localize(locale))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
 pub type ValidatePathSegments = SmallVec<[ValidatePathSegment; 8]>;
 pub struct ValidatePath(ValidatePathSegments);
 
@@ -272,6 +538,16 @@ pub enum TaskValidateError {
         index: usize,
         target: String,
     },
+
+    #[error(
+        "Event handler {source}.on[{index}] has an invalid condition: {message}",
+        source=.state.as_deref().unwrap_or("<root>")
+    )]
+    InvalidCondition {
+        state: Option<String>,
+        index: usize,
+        message: String,
+    },
 }
 
 fn path_segment_for_state(state: &Option<String>) -> ValidatePathSegments {
@@ -299,6 +575,11 @@ impl TaskValidateError {
                 path.extend(["on".into(), (*index).into(), "target".into()]);
                 Some(ValidatePath(path))
             }
+            Self::InvalidCondition { state, index, .. } => {
+                let mut path = path_segment_for_state(state);
+                path.extend(["on".into(), (*index).into(), "when".into()]);
+                Some(ValidatePath(path))
+            }
         }
     }
 
@@ -307,6 +588,58 @@ impl TaskValidateError {
             Self::InvalidInitialState(_) => Some(Cow::from("a state in the `states` object")),
             Self::InvalidTriggerId { .. } => Some(Cow::from("valid trigger id for this task")),
             Self::InvalidTarget { .. } => Some(Cow::from("a state in the `states` object")),
+            Self::InvalidCondition { .. } => None,
+        }
+    }
+}
+
+impl Localize for TaskValidateError {
+    fn localize(&self, locale: Option<&str>) -> String {
+        match self {
+            Self::InvalidInitialState(state) => format_message(
+                locale,
+                "validate.task.invalid_initial_state",
+                &[("state", state)],
+            ),
+            Self::InvalidTriggerId {
+                trigger_id,
+                index,
+                state,
+            } => format_message(
+                locale,
+                "validate.task.invalid_trigger_id",
+                &[
+                    ("source", &state.as_deref().unwrap_or("<root>")),
+                    ("index", index),
+                    ("trigger_id", trigger_id),
+                ],
+            ),
+            Self::InvalidTarget {
+                state,
+                index,
+                target,
+            } => format_message(
+                locale,
+                "validate.task.invalid_target",
+                &[
+                    ("source", &state.as_deref().unwrap_or("<root>")),
+                    ("index", index),
+                    ("target", target),
+                ],
+            ),
+            Self::InvalidCondition {
+                state,
+                index,
+                message,
+            } => format_message(
+                locale,
+                "validate.task.invalid_condition",
+                &[
+                    ("source", &state.as_deref().unwrap_or("<root>")),
+                    ("index", index),
+                    ("message", message),
+                ],
+            ),
         }
     }
 }
@@ -325,6 +658,9 @@ pub enum ActionValidateError {
 
     #[error("Template error: {0}")]
     TemplateError(#[from] TemplateError),
+
+    #[error("Invalid {field}: {message}")]
+    ExecutorConfig { field: String, message: String },
 }
 
 impl ActionValidateError {
@@ -343,6 +679,11 @@ impl ActionValidateError {
                 ]))
             }
             Self::TemplateError(_) => None,
+            Self::ExecutorConfig { field, .. } => Some(ValidatePath(smallvec![
+                "executor_template".into(),
+                "c".into(),
+                field.clone().into(),
+            ])),
         }
     }
 
@@ -352,6 +693,7 @@ impl ActionValidateError {
             Self::ScriptError(_) => None,
             // TODO Take info from the template error
             Self::TemplateError(_) => None,
+            Self::ExecutorConfig { .. } => None,
         }
     }
 }
@@ -368,8 +710,122 @@ impl std::fmt::Display for ActionValidateErrors {
     }
 }
 
+impl Localize for ActionValidateError {
+    fn localize(&self, locale: Option<&str>) -> String {
+        match self {
+            Self::UnknownExecutor(executor) => format_message(
+                locale,
+                "validate.action.unknown_executor",
+                &[("executor", executor)],
+            ),
+            Self::ScriptError(error) => {
+                format_message(locale, "validate.action.script_error", &[("error", error)])
+            }
+            Self::TemplateError(TemplateError::Validation(e)) => e.localize(locale),
+            Self::TemplateError(error) => format_message(
+                locale,
+                "validate.action.template_error",
+                &[("error", error)],
+            ),
+            Self::ExecutorConfig { field, message } => format_message(
+                locale,
+                "validate.action.executor_config",
+                &[("field", field), ("message", message)],
+            ),
+        }
+    }
+}
+
+impl Localize for ActionValidateErrors {
+    fn localize(&self, locale: Option<&str>) -> String {
+        self.0
+            .iter()
+            .map(|e| e.localize(locale))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
 impl From<ActionValidateError> for ActionValidateErrors {
     fn from(err: ActionValidateError) -> Self {
         Self(smallvec![err])
     }
 }
+
+/// An error found when re-validating a task after an action or input that it uses has changed,
+/// or after the task engine itself has changed.
+#[derive(Debug, Error)]
+pub enum DependencyValidateError {
+    #[error("Action {task_action} requires an account")]
+    AccountRequired { task_action: String },
+
+    #[error("Action {task_action} does not allow accounts of type {account_type}")]
+    AccountType {
+        task_action: String,
+        account_type: String,
+    },
+
+    #[error(transparent)]
+    ActionTemplate(TemplateValidationError),
+
+    #[error("Periodic trigger {periodic_trigger} on trigger {task_trigger} has an invalid payload: {error}")]
+    PeriodicPayload {
+        task_trigger: String,
+        periodic_trigger: String,
+        error: String,
+    },
+
+    #[error("The task's config could not be read: {0}")]
+    ConfigFormat(String),
+
+    #[error(transparent)]
+    Config(TaskValidateError),
+
+    #[error("The task's script could not be bundled: {0}")]
+    ScriptBundle(String),
+}
+
+impl Localize for DependencyValidateError {
+    fn localize(&self, locale: Option<&str>) -> String {
+        match self {
+            Self::AccountRequired { task_action } => format_message(
+                locale,
+                "validate.dependency.account_required",
+                &[("task_action", task_action)],
+            ),
+            Self::AccountType {
+                task_action,
+                account_type,
+            } => format_message(
+                locale,
+                "validate.dependency.account_type",
+                &[("task_action", task_action), ("account_type", account_type)],
+            ),
+            Self::ActionTemplate(e) => e.localize(locale),
+            Self::PeriodicPayload {
+                task_trigger,
+                periodic_trigger,
+                error,
+            } => format_message(
+                locale,
+                "validate.dependency.periodic_payload",
+                &[
+                    ("task_trigger", task_trigger),
+                    ("periodic_trigger", periodic_trigger),
+                    ("error", error),
+                ],
+            ),
+            Self::ConfigFormat(error) => format_message(
+                locale,
+                "validate.dependency.config_format",
+                &[("error", error)],
+            ),
+            Self::Config(e) => e.localize(locale),
+            Self::ScriptBundle(error) => format_message(
+                locale,
+                "validate.dependency.script_bundle",
+                &[("error", error)],
+            ),
+        }
+    }
+}
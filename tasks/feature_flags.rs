@@ -0,0 +1,375 @@
+//! Flags that turn features on and off at runtime, so that risky features can be rolled out
+//! gradually. Each flag has an optional global setting, which can enable it for every org or for
+//! a percentage of orgs, and orgs can override the global setting.
+//!
+//! Flags are read often and change rarely, so [FeatureFlags] caches them in Redis for a short
+//! time. Global settings are managed directly in the `feature_flags` table.
+//!
+//! A process can also force flags on or off with [set_overrides], which takes precedence over
+//! everything in the database. The server uses this to apply flags from its runtime
+//! configuration.
+
+use std::{sync::RwLock, time::Duration};
+
+use ergo_database::{object_id::OrgId, PostgresPool, RedisPool};
+use fxhash::{FxHashMap, FxHashSet};
+use once_cell::sync::Lazy;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use tracing::{event, Level};
+
+use crate::error::Error;
+
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+static OVERRIDES: Lazy<RwLock<FxHashMap<String, bool>>> = Lazy::new(Default::default);
+
+/// Force flags on or off for every org in this process, replacing any previous overrides.
+pub fn set_overrides(overrides: FxHashMap<String, bool>) {
+    *OVERRIDES.write().unwrap() = overrides;
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+pub struct FeatureFlag {
+    pub flag: String,
+    /// The org that this setting applies to, or None for the global setting.
+    pub org_id: Option<OrgId>,
+    pub enabled: bool,
+    /// For a global setting, enable the flag for only this percentage of orgs.
+    pub rollout_percent: Option<i16>,
+    pub description: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct FeatureFlagInput {
+    pub enabled: bool,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+/// The flags that are enabled for an org.
+#[derive(Clone, Debug, Default, Serialize, PartialEq, Eq)]
+pub struct FeatureFlagSet(FxHashSet<String>);
+
+impl FeatureFlagSet {
+    /// Combine the global settings and the org's settings.
+    pub fn evaluate(org_id: &OrgId, global: &[FeatureFlag], org: &[FeatureFlag]) -> Self {
+        let mut enabled = global
+            .iter()
+            .filter(|f| {
+                f.enabled
+                    && f.rollout_percent
+                        .map(|percent| rollout_bucket(&f.flag, org_id) < percent)
+                        .unwrap_or(true)
+            })
+            .map(|f| f.flag.clone())
+            .collect::<FxHashSet<_>>();
+
+        for f in org {
+            if f.enabled {
+                enabled.insert(f.flag.clone());
+            } else {
+                enabled.remove(&f.flag);
+            }
+        }
+
+        FeatureFlagSet(enabled)
+    }
+
+    fn apply_overrides(&mut self, overrides: &FxHashMap<String, bool>) {
+        for (flag, enabled) in overrides {
+            if *enabled {
+                self.0.insert(flag.clone());
+            } else {
+                self.0.remove(flag);
+            }
+        }
+    }
+
+    pub fn is_enabled(&self, flag: &str) -> bool {
+        self.0.contains(flag)
+    }
+
+    /// The enabled flags, in sorted order.
+    pub fn enabled(&self) -> Vec<&str> {
+        let mut flags = self.0.iter().map(|f| f.as_str()).collect::<Vec<_>>();
+        flags.sort_unstable();
+        flags
+    }
+}
+
+/// Place an org in a bucket from 0 to 99 for a flag. This is stable, so an org stays enabled as
+/// the rollout percentage grows, and each flag orders the orgs differently.
+fn rollout_bucket(flag: &str, org_id: &OrgId) -> i16 {
+    (fxhash::hash64(&(flag, org_id.0)) % 100) as i16
+}
+
+/// Read feature flags, with caching.
+#[derive(Clone)]
+pub struct FeatureFlags {
+    pg_pool: PostgresPool,
+    redis_pool: Option<RedisPool>,
+}
+
+impl FeatureFlags {
+    /// Create a reader for feature flags. Without a Redis pool, every read goes to Postgres.
+    pub fn new(pg_pool: PostgresPool, redis_pool: Option<RedisPool>) -> FeatureFlags {
+        FeatureFlags {
+            pg_pool,
+            redis_pool,
+        }
+    }
+
+    /// Get the flags that are enabled for an org.
+    pub async fn for_org(&self, org_id: &OrgId) -> Result<FeatureFlagSet, Error> {
+        let global = self.load(None).await?;
+        let org = self.load(Some(org_id)).await?;
+        let mut flags = FeatureFlagSet::evaluate(org_id, &global, &org);
+        flags.apply_overrides(&OVERRIDES.read().unwrap());
+        Ok(flags)
+    }
+
+    pub async fn is_enabled(&self, org_id: &OrgId, flag: &str) -> Result<bool, Error> {
+        let flags = self.for_org(org_id).await?;
+        Ok(flags.is_enabled(flag))
+    }
+
+    /// Clear the cached settings for an org, or the global settings if `org_id` is None.
+    pub async fn invalidate(&self, org_id: Option<&OrgId>) {
+        let (redis, key) = match (&self.redis_pool, self.cache_key(org_id)) {
+            (Some(redis), Some(key)) => (redis, key),
+            _ => return,
+        };
+
+        let result: Result<(), anyhow::Error> = async {
+            let mut conn = redis.get().await?;
+            redis::cmd("DEL")
+                .arg(&key)
+                .query_async::<_, ()>(&mut conn)
+                .await?;
+            Ok(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            event!(Level::WARN, error=%e, %key, "Failed to clear feature flag cache");
+        }
+    }
+
+    fn cache_key(&self, org_id: Option<&OrgId>) -> Option<String> {
+        let redis = self.redis_pool.as_ref()?;
+        let scope = match org_id {
+            Some(org_id) => org_id.to_string(),
+            None => "global".to_string(),
+        };
+
+        let key = match redis.key_prefix() {
+            Some(prefix) => format!("erf:{}:{}", prefix, scope),
+            None => format!("erf:{}", scope),
+        };
+        Some(key)
+    }
+
+    async fn load(&self, org_id: Option<&OrgId>) -> Result<Vec<FeatureFlag>, Error> {
+        let key = self.cache_key(org_id);
+        if let Some(key) = &key {
+            if let Some(flags) = self.read_cache(key).await {
+                return Ok(flags);
+            }
+        }
+
+        let mut conn = self.pg_pool.acquire().await?;
+        let flags = list_flags(&mut conn, org_id).await?;
+
+        if let Some(key) = &key {
+            self.write_cache(key, &flags).await;
+        }
+
+        Ok(flags)
+    }
+
+    /// Read flags from the cache. Errors are logged and treated as a cache miss, so that a Redis
+    /// problem doesn't stop everything that checks a flag.
+    async fn read_cache(&self, key: &str) -> Option<Vec<FeatureFlag>> {
+        let redis = self.redis_pool.as_ref()?;
+        let result: Result<Option<String>, anyhow::Error> = async {
+            let mut conn = redis.get().await?;
+            let value = redis::cmd("GET").arg(key).query_async(&mut conn).await?;
+            Ok(value)
+        }
+        .await;
+
+        match result {
+            Ok(value) => value.and_then(|v| serde_json::from_str(&v).ok()),
+            Err(e) => {
+                event!(Level::WARN, error=%e, %key, "Failed to read feature flag cache");
+                None
+            }
+        }
+    }
+
+    async fn write_cache(&self, key: &str, flags: &[FeatureFlag]) {
+        let redis = match self.redis_pool.as_ref() {
+            Some(r) => r,
+            None => return,
+        };
+
+        let result: Result<(), anyhow::Error> = async {
+            let value = serde_json::to_string(flags)?;
+            let mut conn = redis.get().await?;
+            redis::cmd("SET")
+                .arg(key)
+                .arg(value)
+                .arg("PX")
+                .arg(CACHE_TTL.as_millis() as u64)
+                .query_async::<_, ()>(&mut conn)
+                .await?;
+            Ok(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            event!(Level::WARN, error=%e, %key, "Failed to write feature flag cache");
+        }
+    }
+}
+
+/// List the settings for an org, or the global settings if `org_id` is None.
+pub async fn list_flags(
+    tx: &mut PgConnection,
+    org_id: Option<&OrgId>,
+) -> Result<Vec<FeatureFlag>, Error> {
+    let flags = sqlx::query_as!(
+        FeatureFlag,
+        r##"SELECT flag, org_id AS "org_id: OrgId", enabled, rollout_percent, description
+        FROM feature_flags
+        WHERE org_id IS NOT DISTINCT FROM $1
+        ORDER BY flag"##,
+        org_id.map(|o| o.0)
+    )
+    .fetch_all(tx)
+    .await?;
+
+    Ok(flags)
+}
+
+/// Set an org's override for a flag.
+pub async fn set_org_flag(
+    tx: &mut PgConnection,
+    org_id: &OrgId,
+    flag: &str,
+    input: &FeatureFlagInput,
+) -> Result<(), Error> {
+    sqlx::query!(
+        r##"INSERT INTO feature_flags (flag, org_id, enabled, description)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (org_id, flag) WHERE org_id IS NOT NULL
+        DO UPDATE SET enabled=EXCLUDED.enabled, description=EXCLUDED.description, updated=now()"##,
+        flag,
+        org_id.0,
+        input.enabled,
+        input.description
+    )
+    .execute(tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Remove an org's override for a flag, so that the global setting applies again. Returns false
+/// if the org had no override.
+pub async fn delete_org_flag(
+    tx: &mut PgConnection,
+    org_id: &OrgId,
+    flag: &str,
+) -> Result<bool, Error> {
+    let result = sqlx::query!(
+        "DELETE FROM feature_flags WHERE org_id=$1 AND flag=$2",
+        org_id.0,
+        flag
+    )
+    .execute(tx)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flag(name: &str, enabled: bool, rollout_percent: Option<i16>) -> FeatureFlag {
+        FeatureFlag {
+            flag: name.to_string(),
+            org_id: None,
+            enabled,
+            rollout_percent,
+            description: None,
+        }
+    }
+
+    #[test]
+    fn org_overrides_global() {
+        let org_id = OrgId::new();
+        let global = vec![flag("a", true, None), flag("b", false, None)];
+        let org = vec![
+            flag("a", false, None),
+            flag("b", true, None),
+            flag("c", true, None),
+        ];
+
+        let flags = FeatureFlagSet::evaluate(&org_id, &global, &[]);
+        assert!(flags.is_enabled("a"));
+        assert!(!flags.is_enabled("b"));
+
+        let flags = FeatureFlagSet::evaluate(&org_id, &global, &org);
+        assert!(!flags.is_enabled("a"));
+        assert!(flags.is_enabled("b"));
+        assert!(flags.is_enabled("c"));
+        assert!(!flags.is_enabled("d"));
+    }
+
+    #[test]
+    fn overrides_win() {
+        let org_id = OrgId::new();
+        let global = vec![flag("a", true, None)];
+        let org = vec![flag("b", true, None)];
+        let overrides = [("a".to_string(), false), ("c".to_string(), true)]
+            .into_iter()
+            .collect::<FxHashMap<_, _>>();
+
+        let mut flags = FeatureFlagSet::evaluate(&org_id, &global, &org);
+        flags.apply_overrides(&overrides);
+        assert_eq!(flags.enabled(), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn gradual_rollout() {
+        let orgs = (0..1000).map(|_| OrgId::new()).collect::<Vec<_>>();
+        let enabled_count = |percent| {
+            let global = vec![flag("new_executor", true, Some(percent))];
+            orgs.iter()
+                .filter(|org_id| {
+                    FeatureFlagSet::evaluate(org_id, &global, &[]).is_enabled("new_executor")
+                })
+                .count()
+        };
+
+        assert_eq!(enabled_count(0), 0);
+        assert_eq!(enabled_count(100), orgs.len());
+        let half = enabled_count(50);
+        assert!(half > 400 && half < 600, "{} orgs enabled at 50%", half);
+
+        // Growing the rollout keeps the orgs that were already enabled.
+        let global_10 = vec![flag("new_executor", true, Some(10))];
+        let global_20 = vec![flag("new_executor", true, Some(20))];
+        for org_id in &orgs {
+            if FeatureFlagSet::evaluate(org_id, &global_10, &[]).is_enabled("new_executor") {
+                assert!(
+                    FeatureFlagSet::evaluate(org_id, &global_20, &[]).is_enabled("new_executor")
+                );
+            }
+        }
+    }
+}
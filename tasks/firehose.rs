@@ -0,0 +1,313 @@
+//! Mirror the inputs and actions logs to an external sink, such as a Kafka topic or an HTTP
+//! endpoint, for analysis outside of Ergo. Each finished log entry enqueues an export job in
+//! the same transaction that writes its final status, and the job is retried until the sink
+//! accepts it, so every event is delivered at least once. Consumers should deduplicate on
+//! `log_id` and `status`.
+
+use std::{borrow::Cow, collections::BTreeMap, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use ergo_database::{
+    object_id::{OrgId, TaskId, TaskTriggerId},
+    PostgresPool, RedisPool,
+};
+use ergo_graceful_shutdown::GracefulShutdownConsumer;
+use ergo_queues::{generic_stage::QueueJob, ErrorClass, Queue, QueueJobProcessor, QueueWorkItem};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use tokio::sync::OnceCell;
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use crate::{actions::ActionStatus, error::Error, inputs::InputStatus};
+
+const QUEUE_NAME: &str = "er-firehose";
+
+lazy_static! {
+    static ref FIREHOSE_ENABLED: bool = FirehoseSink::from_env().ok().flatten().is_some();
+}
+
+fn queue_name(key_prefix: Option<&str>) -> Cow<'static, str> {
+    key_prefix
+        .map(|prefix| Cow::Owned(format!("{}-{}", prefix, QUEUE_NAME)))
+        .unwrap_or(Cow::Borrowed(QUEUE_NAME))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FirehoseEventKind {
+    Input {
+        task_trigger_id: TaskTriggerId,
+        status: InputStatus,
+    },
+    Action {
+        task_action_local_id: String,
+        /// The input that caused the action to run.
+        input_arrival_id: Option<Uuid>,
+        status: ActionStatus,
+    },
+}
+
+/// A log entry that reached its final status.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FirehoseEvent {
+    #[serde(flatten)]
+    pub kind: FirehoseEventKind,
+    /// The `inputs_log_id` or `actions_log_id`.
+    pub log_id: Uuid,
+    pub task_id: TaskId,
+    /// Filled in when the event is exported.
+    #[serde(default)]
+    pub org_id: Option<OrgId>,
+    /// The input's log info, or the action's result.
+    pub result: serde_json::Value,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Queue a log event for export. This does nothing when no sink is configured.
+pub async fn export_event(
+    tx: &mut PgConnection,
+    event: &FirehoseEvent,
+    redis_key_prefix: Option<&str>,
+) -> Result<(), Error> {
+    if !*FIREHOSE_ENABLED {
+        return Ok(());
+    }
+
+    let queue_name = queue_name(redis_key_prefix);
+    QueueJob::new(queue_name.as_ref(), event)
+        .enqueue(tx)
+        .await?;
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+pub enum FirehoseSink {
+    /// POST each event as JSON to a URL.
+    Webhook {
+        url: String,
+        /// Sent as a bearer token, if set.
+        token: Option<String>,
+    },
+    /// Produce each event to a Kafka topic, keyed by task ID.
+    Kafka {
+        brokers: Vec<String>,
+        topic: String,
+        partition: i32,
+    },
+}
+
+impl FirehoseSink {
+    /// Read the sink from `FIREHOSE_WEBHOOK_URL` or `FIREHOSE_KAFKA_BROKERS`. Returns None if
+    /// neither is set.
+    pub fn from_env() -> Result<Option<FirehoseSink>, Error> {
+        if let Ok(url) = std::env::var("FIREHOSE_WEBHOOK_URL") {
+            return Ok(Some(FirehoseSink::Webhook {
+                url,
+                token: std::env::var("FIREHOSE_WEBHOOK_TOKEN").ok(),
+            }));
+        }
+
+        let brokers = match std::env::var("FIREHOSE_KAFKA_BROKERS") {
+            Ok(brokers) => brokers
+                .split(',')
+                .map(|b| b.trim().to_string())
+                .filter(|b| !b.is_empty())
+                .collect::<Vec<_>>(),
+            Err(_) => return Ok(None),
+        };
+
+        let topic =
+            std::env::var("FIREHOSE_KAFKA_TOPIC").unwrap_or_else(|_| "ergo-activity".to_string());
+        let partition = match std::env::var("FIREHOSE_KAFKA_PARTITION") {
+            Ok(p) => p
+                .parse()
+                .map_err(|_| Error::FirehoseExport(format!("Invalid Kafka partition {}", p)))?,
+            Err(_) => 0,
+        };
+
+        Ok(Some(FirehoseSink::Kafka {
+            brokers,
+            topic,
+            partition,
+        }))
+    }
+}
+
+pub struct FirehoseExporterConfig {
+    pub pg_pool: PostgresPool,
+    pub redis_pool: RedisPool,
+    pub shutdown: GracefulShutdownConsumer,
+    pub sink: FirehoseSink,
+}
+
+pub struct FirehoseExporter {
+    queue: Queue,
+}
+
+impl FirehoseExporter {
+    pub fn new(config: FirehoseExporterConfig) -> Result<FirehoseExporter, Error> {
+        let queue_name = queue_name(config.redis_pool.key_prefix());
+        // Keep retrying for a while, since the sink may be down for maintenance.
+        let queue = Queue::new(
+            config.redis_pool,
+            queue_name.into_owned(),
+            None,
+            Some(20),
+            Some(Duration::from_secs(30)),
+        );
+
+        let processor = FirehoseProcessor {
+            pg_pool: config.pg_pool,
+            http_client: reqwest::ClientBuilder::new()
+                .timeout(Duration::from_secs(30))
+                .build()
+                .map_err(|e| Error::FirehoseExport(e.to_string()))?,
+            sink: Arc::new(config.sink),
+            kafka: Arc::new(OnceCell::new()),
+        };
+
+        queue.start_dequeuer_loop(config.shutdown, None, None, processor);
+        Ok(FirehoseExporter { queue })
+    }
+
+    pub fn queue(&self) -> &Queue {
+        &self.queue
+    }
+}
+
+#[derive(Clone)]
+struct FirehoseProcessor {
+    pg_pool: PostgresPool,
+    http_client: reqwest::Client,
+    sink: Arc<FirehoseSink>,
+    /// Connected on first use, so that an unavailable broker doesn't stop the server from
+    /// starting.
+    kafka: Arc<OnceCell<rskafka::client::partition::PartitionClient>>,
+}
+
+#[async_trait]
+impl QueueJobProcessor for FirehoseProcessor {
+    type Payload = FirehoseEvent;
+    type Error = Error;
+
+    async fn process(
+        &self,
+        _item: &QueueWorkItem<FirehoseEvent>,
+        mut event: FirehoseEvent,
+    ) -> Result<(), Error> {
+        if event.org_id.is_none() {
+            event.org_id = sqlx::query_scalar!(
+                r##"SELECT org_id AS "org_id: OrgId" FROM tasks WHERE task_id=$1"##,
+                event.task_id.0
+            )
+            .fetch_optional(&self.pg_pool)
+            .await?;
+        }
+
+        let body = serde_json::to_vec(&event)?;
+        match self.sink.as_ref() {
+            FirehoseSink::Webhook { url, token } => {
+                let mut request = self
+                    .http_client
+                    .post(url)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(body);
+                if let Some(token) = token {
+                    request = request.bearer_auth(token);
+                }
+
+                request
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status())
+                    .map_err(|e| Error::FirehoseExport(e.to_string()))?;
+            }
+            FirehoseSink::Kafka {
+                brokers,
+                topic,
+                partition,
+            } => {
+                let client = self
+                    .kafka
+                    .get_or_try_init(|| async {
+                        let client = rskafka::client::ClientBuilder::new(brokers.clone())
+                            .build()
+                            .await?;
+                        client.partition_client(topic.clone(), *partition)
+                    })
+                    .await
+                    .map_err(|e| Error::FirehoseExport(e.to_string()))?;
+
+                let record = rskafka::record::Record {
+                    key: Some(event.task_id.to_string().into_bytes()),
+                    value: Some(body),
+                    headers: BTreeMap::new(),
+                    timestamp: time::OffsetDateTime::now_utc(),
+                };
+
+                client
+                    .produce(
+                        vec![record],
+                        rskafka::client::partition::Compression::NoCompression,
+                    )
+                    .await
+                    .map_err(|e| Error::FirehoseExport(e.to_string()))?;
+            }
+        }
+
+        event!(Level::DEBUG, log_id=%event.log_id, "Exported log event");
+        Ok(())
+    }
+
+    fn error_class(&self, error: &Error) -> ErrorClass {
+        error.error_class()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn event_format() {
+        let task_id = TaskId::new();
+        let log_id = Uuid::new_v4();
+        let event = FirehoseEvent {
+            kind: FirehoseEventKind::Action {
+                task_action_local_id: "notify".to_string(),
+                input_arrival_id: None,
+                status: ActionStatus::Success,
+            },
+            log_id,
+            task_id,
+            org_id: None,
+            result: json!({ "output": 5 }),
+            timestamp: Utc.with_ymd_and_hms(2023, 2, 3, 10, 0, 0).unwrap(),
+        };
+
+        let value = serde_json::to_value(&event).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "kind": "action",
+                "task_action_local_id": "notify",
+                "input_arrival_id": null,
+                "status": "success",
+                "log_id": log_id,
+                "task_id": task_id,
+                "org_id": null,
+                "result": { "output": 5 },
+                "timestamp": "2023-02-03T10:00:00Z",
+            })
+        );
+
+        let round_trip: FirehoseEvent = serde_json::from_value(value).unwrap();
+        assert_eq!(round_trip.log_id, log_id);
+    }
+}
@@ -0,0 +1,155 @@
+//! Inputs that arrive while a task is disabled, for tasks set to [DisabledInputMode::Buffer].
+//! These are recorded in the inputs log with a `buffered` status and held in the
+//! `buffered_inputs` table instead of the queue. Flushing the buffer, which also happens when
+//! the task is enabled again, queues them in the order they arrived.
+
+use ergo_database::object_id::{OrgId, TaskId};
+use ergo_queues::generic_stage::QueueJob;
+use sqlx::PgConnection;
+
+use super::{
+    queue::{input_fairness_key, InputQueue},
+    DisabledInputMode, InputInvocation,
+};
+use crate::error::Error;
+
+/// Lock the task's inputs against a flush of its buffer. Inputs take this lock shared before
+/// checking if the task is enabled, so that an input buffered just as the task is enabled
+/// can't miss the flush.
+async fn lock_task_inputs(
+    tx: &mut PgConnection,
+    task_id: &TaskId,
+    exclusive: bool,
+) -> Result<(), Error> {
+    let key = format!("task_inputs:{}", task_id.0);
+    if exclusive {
+        sqlx::query!("SELECT pg_advisory_xact_lock(hashtextextended($1, 0))", key)
+            .execute(&mut *tx)
+            .await?;
+    } else {
+        sqlx::query!(
+            "SELECT pg_advisory_xact_lock_shared(hashtextextended($1, 0))",
+            key
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Return how to handle an input for the task, or `None` if the task is enabled and the input
+/// should be queued as usual.
+pub(crate) async fn disabled_input_mode(
+    tx: &mut PgConnection,
+    task_id: &TaskId,
+) -> Result<Option<DisabledInputMode>, Error> {
+    lock_task_inputs(&mut *tx, task_id, false).await?;
+
+    let task = sqlx::query!(
+        r##"SELECT enabled, disabled_input_mode AS "disabled_input_mode: DisabledInputMode"
+        FROM tasks WHERE task_id=$1"##,
+        task_id.0
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    Ok((!task.enabled).then_some(task.disabled_input_mode))
+}
+
+/// Hold an input until the task's buffer is flushed.
+pub(crate) async fn buffer_input(
+    tx: &mut PgConnection,
+    org_id: &OrgId,
+    invocation: &InputInvocation,
+) -> Result<(), Error> {
+    sqlx::query!(
+        "INSERT INTO buffered_inputs (inputs_log_id, task_id, org_id, invocation)
+        VALUES ($1, $2, $3, $4)",
+        invocation.inputs_log_id,
+        invocation.task_id.0,
+        org_id.0,
+        sqlx::types::Json(invocation) as _
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Queue the task's buffered inputs in the order that they arrived. Returns the number of inputs
+/// queued.
+pub async fn flush_buffered_inputs(
+    tx: &mut PgConnection,
+    task_id: &TaskId,
+    redis_key_prefix: Option<&str>,
+) -> Result<usize, Error> {
+    lock_task_inputs(&mut *tx, task_id, true).await?;
+
+    let mut buffered = sqlx::query!(
+        r##"DELETE FROM buffered_inputs WHERE task_id=$1
+        RETURNING org_id AS "org_id: OrgId",
+            invocation AS "invocation: sqlx::types::Json<InputInvocation>",
+            created"##,
+        task_id.0
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    buffered.sort_by_key(|b| b.created);
+
+    let queue_name = InputQueue::queue_name(redis_key_prefix);
+    for input in &buffered {
+        let invocation = &input.invocation.0;
+        let org_key = input_fairness_key(&input.org_id, invocation);
+        let job = QueueJob {
+            queue: queue_name.as_ref(),
+            payload: invocation,
+            id: None,
+            run_at: None,
+            timeout: None,
+            max_retries: None,
+            retry_backoff: None,
+            high_priority: invocation.interactive,
+            fairness_key: Some(org_key.as_str()),
+        };
+
+        let job_id = job.enqueue(&mut *tx).await?;
+
+        sqlx::query!(
+            "UPDATE inputs_log SET status='pending', queue_job_id=$2, updated=now()
+            WHERE inputs_log_id=$1",
+            invocation.inputs_log_id,
+            job_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    Ok(buffered.len())
+}
+
+/// Remove the task's buffered inputs without running them. They stay in the inputs log with
+/// a `dropped` status. Returns the number of inputs removed.
+pub async fn discard_buffered_inputs(
+    tx: &mut PgConnection,
+    task_id: &TaskId,
+) -> Result<usize, Error> {
+    lock_task_inputs(&mut *tx, task_id, true).await?;
+
+    let ids = sqlx::query_scalar!(
+        "DELETE FROM buffered_inputs WHERE task_id=$1 RETURNING inputs_log_id",
+        task_id.0
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "UPDATE inputs_log SET status='dropped', updated=now() WHERE inputs_log_id = ANY($1)",
+        ids.as_slice()
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(ids.len())
+}
@@ -0,0 +1,112 @@
+//! Tasks can send inputs to other tasks with the `send_input` action. Each input carries the
+//! list of tasks that led to it, so that a pipeline which loops back on itself or grows too
+//! deep is stopped instead of running forever.
+
+use ergo_database::object_id::TaskId;
+use lazy_static::lazy_static;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+lazy_static! {
+    /// The maximum number of tasks that a chain can pass through before reaching the last
+    /// task. Set with `TASK_MAX_CHAIN_DEPTH`.
+    pub static ref MAX_CHAIN_DEPTH: usize = std::env::var("TASK_MAX_CHAIN_DEPTH")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(10);
+}
+
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+pub enum ChainError {
+    #[error("Task chain exceeded the maximum depth of {0}")]
+    TooDeep(usize),
+    #[error("Task chain loops back to task {0}")]
+    Loop(TaskId),
+}
+
+/// The tasks that an input passed through before arriving, in order. An input sent from
+/// outside of a task has an empty chain.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct InputChain {
+    #[serde(default)]
+    pub tasks: Vec<TaskId>,
+    /// The input to the previous task in the chain.
+    pub source_inputs_log_id: Option<Uuid>,
+    /// The action in the previous task that sent the input.
+    #[serde(default)]
+    pub source_actions_log_id: Option<Uuid>,
+}
+
+impl InputChain {
+    /// The number of tasks that came before this input.
+    pub fn depth(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// The chain for an input sent by the `actions_log_id` action of `task_id`, while the task
+    /// was handling `inputs_log_id`.
+    pub fn next(
+        &self,
+        task_id: &TaskId,
+        inputs_log_id: Option<Uuid>,
+        actions_log_id: Option<Uuid>,
+    ) -> InputChain {
+        let mut tasks = self.tasks.clone();
+        tasks.push(*task_id);
+        InputChain {
+            tasks,
+            source_inputs_log_id: inputs_log_id,
+            source_actions_log_id: actions_log_id,
+        }
+    }
+
+    /// Check that an input with this chain may be sent to `target`.
+    pub fn check_target(&self, target: &TaskId, max_depth: usize) -> Result<(), ChainError> {
+        if self.tasks.contains(target) {
+            return Err(ChainError::Loop(*target));
+        }
+
+        if self.depth() > max_depth {
+            return Err(ChainError::TooDeep(max_depth));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_loops() {
+        let a = TaskId::new();
+        let b = TaskId::new();
+        let c = TaskId::new();
+
+        let chain = InputChain::default()
+            .next(&a, None, None)
+            .next(&b, None, None);
+        assert_eq!(chain.depth(), 2);
+        chain.check_target(&c, 10).unwrap();
+        assert_eq!(chain.check_target(&a, 10), Err(ChainError::Loop(a)));
+        assert_eq!(chain.check_target(&b, 10), Err(ChainError::Loop(b)));
+    }
+
+    #[test]
+    fn limits_depth() {
+        let chain =
+            InputChain::default()
+                .next(&TaskId::new(), None, None)
+                .next(&TaskId::new(), None, None);
+        chain.check_target(&TaskId::new(), 2).unwrap();
+
+        let chain = chain.next(&TaskId::new(), None, None);
+        assert_eq!(
+            chain.check_target(&TaskId::new(), 2),
+            Err(ChainError::TooDeep(2))
+        );
+    }
+}
@@ -0,0 +1,854 @@
+//! The chat bridge runs the org's Discord and Telegram bots, and sends the messages and commands
+//! in their subscribed channels to the subscriptions' task triggers. Discord bots connect to the
+//! gateway, and Telegram bots long-poll for updates.
+//!
+//! Every server runs the bridge, but each bot only runs on one server at a time. Discord would
+//! send the same events to every connection, and Telegram only allows one poller per bot.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use ergo_database::{
+    encryption::decrypt_account_fields,
+    object_id::{AccountId, ChatSubscriptionId, InputId, OrgId, TaskId, TaskTriggerId, UserId},
+    PostgresPool,
+};
+use ergo_graceful_shutdown::GracefulShutdownConsumer;
+use futures::{SinkExt, StreamExt};
+use fxhash::FxHashMap;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Connection, PgConnection};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{event, Level};
+
+use super::{chain::InputChain, enqueue_input, EnqueueInputOptions};
+use crate::Error;
+
+const DISCORD_GATEWAY_URL: &str = "wss://gateway.discord.gg/?v=10&encoding=json";
+const DISCORD_API_URL: &str = "https://discord.com/api/v10";
+/// GUILD_MESSAGES, DIRECT_MESSAGES, and MESSAGE_CONTENT
+const DISCORD_INTENTS: u64 = (1 << 9) | (1 << 12) | (1 << 15);
+const TELEGRAM_API_URL: &str = "https://api.telegram.org";
+const TELEGRAM_POLL_TIMEOUT_SECS: u64 = 30;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatPlatform {
+    Discord,
+    Telegram,
+}
+
+impl ChatPlatform {
+    /// The platform for a bot account type.
+    pub fn from_account_type(account_type_id: &str) -> Option<ChatPlatform> {
+        match account_type_id {
+            "discord_bot" => Some(ChatPlatform::Discord),
+            "telegram_bot" => Some(ChatPlatform::Telegram),
+            _ => None,
+        }
+    }
+}
+
+/// The fields of a bot account.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct BotAccount {
+    pub token: String,
+}
+
+/// The input payload for a chat message or command.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+pub struct ChatPayload {
+    pub platform: ChatPlatform,
+    pub channel_id: String,
+    pub message_id: Option<String>,
+    pub user_id: String,
+    pub user_name: Option<String>,
+    /// The full text of the message. For a Discord slash command, this is the command and its
+    /// option values.
+    pub text: String,
+    /// The command, without the leading slash, if the message was a command.
+    pub command: Option<String>,
+    /// The text after the command.
+    pub args: String,
+    /// The options of a Discord slash command.
+    #[serde(default)]
+    pub options: BTreeMap<String, serde_json::Value>,
+}
+
+/// Split a `/command args` message into the command and its arguments. Telegram commands may
+/// be addressed to a bot, as in `/command@bot_name`.
+pub fn parse_command(text: &str) -> Option<(String, String)> {
+    let text = text.trim_start().strip_prefix('/')?;
+    let (command, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let command = command.split('@').next().unwrap_or_default();
+    if command.is_empty() {
+        return None;
+    }
+
+    Some((command.to_string(), args.trim().to_string()))
+}
+
+impl ChatPayload {
+    fn from_text(
+        platform: ChatPlatform,
+        channel_id: String,
+        message_id: Option<String>,
+        user_id: String,
+        user_name: Option<String>,
+        text: String,
+    ) -> ChatPayload {
+        let (command, args) = match parse_command(&text) {
+            Some((command, args)) => (Some(command), args),
+            None => (None, String::new()),
+        };
+
+        ChatPayload {
+            platform,
+            channel_id,
+            message_id,
+            user_id,
+            user_name,
+            text,
+            command,
+            args,
+            options: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChatSubscriptionTarget {
+    pub chat_subscription_id: ChatSubscriptionId,
+    pub channel_id: String,
+    pub command: Option<String>,
+    pub org_id: OrgId,
+    pub run_as_user: UserId,
+    pub task_id: TaskId,
+    pub task_name: String,
+    pub task_trigger_id: TaskTriggerId,
+    pub task_trigger_local_id: String,
+    pub task_trigger_name: String,
+    pub input_id: InputId,
+    pub payload_schema: serde_json::Value,
+}
+
+impl ChatSubscriptionTarget {
+    /// Returns true if the message should be sent to this subscription's trigger.
+    pub fn matches(&self, payload: &ChatPayload) -> bool {
+        self.channel_id == payload.channel_id
+            && match self.command.as_deref() {
+                Some(command) => payload.command.as_deref() == Some(command),
+                None => true,
+            }
+    }
+}
+
+/// The subscriptions that use a single bot.
+#[derive(Clone, Debug, PartialEq)]
+struct BotSubscriptions {
+    platform: ChatPlatform,
+    account: BotAccount,
+    targets: Vec<ChatSubscriptionTarget>,
+}
+
+async fn load_subscriptions(
+    pool: &PostgresPool,
+) -> Result<FxHashMap<AccountId, BotSubscriptions>, Error> {
+    let rows = sqlx::query!(
+        r##"SELECT
+            sub.chat_subscription_id AS "chat_subscription_id: ChatSubscriptionId",
+            sub.account_id AS "account_id: AccountId",
+            sub.channel_id,
+            sub.command,
+            sub.org_id AS "org_id: OrgId",
+            sub.run_as_user AS "run_as_user: UserId",
+            tasks.task_id AS "task_id: TaskId",
+            tasks.name AS task_name,
+            tt.task_trigger_id AS "task_trigger_id: TaskTriggerId",
+            tt.task_trigger_local_id,
+            tt.name AS task_trigger_name,
+            inputs.input_id AS "input_id: InputId",
+            inputs.payload_schema,
+            accounts.account_type_id,
+            accounts.fields
+        FROM chat_subscriptions sub
+        JOIN accounts USING (account_id)
+        JOIN task_triggers tt USING (task_trigger_id)
+        JOIN tasks ON tasks.task_id = tt.task_id
+        JOIN inputs ON inputs.input_id = tt.input_id
+        WHERE sub.enabled AND tasks.enabled AND NOT tasks.deleted
+        ORDER BY sub.chat_subscription_id"##
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut bots: FxHashMap<AccountId, BotSubscriptions> = FxHashMap::default();
+    for row in rows {
+        if !bots.contains_key(&row.account_id) {
+            let platform = ChatPlatform::from_account_type(&row.account_type_id);
+            let account = row
+                .fields
+                .map(|fields| {
+                    let fields = decrypt_account_fields(fields)?;
+                    serde_json::from_value::<BotAccount>(fields).map_err(Error::from)
+                })
+                .transpose();
+
+            match (platform, account) {
+                (Some(platform), Ok(Some(account))) => {
+                    bots.insert(
+                        row.account_id.clone(),
+                        BotSubscriptions {
+                            platform,
+                            account,
+                            targets: Vec::new(),
+                        },
+                    );
+                }
+                _ => {
+                    event!(Level::WARN, account_id=%row.account_id, "Chat bot account has an invalid type or token");
+                    continue;
+                }
+            }
+        }
+
+        if let Some(subs) = bots.get_mut(&row.account_id) {
+            subs.targets.push(ChatSubscriptionTarget {
+                chat_subscription_id: row.chat_subscription_id,
+                channel_id: row.channel_id,
+                command: row.command,
+                org_id: row.org_id,
+                run_as_user: row.run_as_user,
+                task_id: row.task_id,
+                task_name: row.task_name,
+                task_trigger_id: row.task_trigger_id,
+                task_trigger_local_id: row.task_trigger_local_id,
+                task_trigger_name: row.task_trigger_name,
+                input_id: row.input_id,
+                payload_schema: row.payload_schema,
+            });
+        }
+    }
+
+    Ok(bots)
+}
+
+/// Send a message to the triggers of the subscriptions that match it, and return how many
+/// inputs were enqueued.
+async fn handle_message(
+    pool: &PostgresPool,
+    redis_key_prefix: Option<&str>,
+    targets: &[ChatSubscriptionTarget],
+    payload: &ChatPayload,
+) -> usize {
+    let mut count = 0;
+    for target in targets.iter().filter(|t| t.matches(payload)) {
+        let result = async {
+            let mut conn = pool.acquire().await?;
+            enqueue_input(EnqueueInputOptions {
+                pg: &mut conn,
+                notifications: None,
+                org_id: target.org_id.clone(),
+                user_id: target.run_as_user.clone(),
+                task_id: target.task_id.clone(),
+                task_name: target.task_name.clone(),
+                input_id: target.input_id.clone(),
+                task_trigger_id: target.task_trigger_id.clone(),
+                task_trigger_local_id: target.task_trigger_local_id.clone(),
+                task_trigger_name: target.task_trigger_name.clone(),
+                periodic_trigger_id: None,
+                payload_schema: &target.payload_schema,
+                payload: serde_json::to_value(payload)?,
+                redis_key_prefix,
+                trigger_at: None,
+                replay_of: None,
+                // Someone is waiting for the bot to react.
+                interactive: true,
+                chain: InputChain::default(),
+            })
+            .await
+        }
+        .await;
+
+        match result {
+            Ok(_) => count += 1,
+            Err(e) => {
+                event!(Level::WARN, subscription=%target.chat_subscription_id, channel=%payload.channel_id, error=%e, "Failed to enqueue chat message")
+            }
+        }
+    }
+
+    count
+}
+
+/// Take the lock that lets this server run the bot. The lock belongs to a connection that is
+/// detached from the pool, so it's released when the connection is dropped.
+async fn lock_bot(
+    pool: &PostgresPool,
+    account_id: &AccountId,
+) -> Result<Option<PgConnection>, Error> {
+    let mut conn = pool.acquire().await?.detach();
+    let locked = sqlx::query_scalar!(
+        "SELECT pg_try_advisory_lock(hashtextextended($1, 0))",
+        format!("chat_bot:{}", account_id)
+    )
+    .fetch_one(&mut conn)
+    .await?
+    .unwrap_or(false);
+
+    if locked {
+        Ok(Some(conn))
+    } else {
+        conn.close().await.ok();
+        Ok(None)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscordGatewayEvent {
+    op: u8,
+    #[serde(default)]
+    d: serde_json::Value,
+    s: Option<u64>,
+    t: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscordUser {
+    id: String,
+    username: Option<String>,
+    #[serde(default)]
+    bot: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscordMessage {
+    id: String,
+    channel_id: String,
+    author: DiscordUser,
+    #[serde(default)]
+    content: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscordMember {
+    user: DiscordUser,
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscordCommandOption {
+    name: String,
+    value: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscordCommandData {
+    name: String,
+    #[serde(default)]
+    options: Vec<DiscordCommandOption>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscordInteraction {
+    id: String,
+    token: String,
+    #[serde(rename = "type")]
+    interaction_type: u8,
+    channel_id: Option<String>,
+    member: Option<DiscordMember>,
+    user: Option<DiscordUser>,
+    data: Option<DiscordCommandData>,
+}
+
+impl DiscordInteraction {
+    /// The payload for an application command interaction.
+    fn payload(self) -> Option<ChatPayload> {
+        // Only application commands are handled.
+        if self.interaction_type != 2 {
+            return None;
+        }
+
+        let data = self.data?;
+        let user = self.member.map(|m| m.user).or(self.user)?;
+        let args = data
+            .options
+            .iter()
+            .filter_map(|o| o.value.as_ref())
+            .map(|v| match v {
+                serde_json::Value::String(s) => s.clone(),
+                v => v.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        let text = if args.is_empty() {
+            format!("/{}", data.name)
+        } else {
+            format!("/{} {}", data.name, args)
+        };
+
+        Some(ChatPayload {
+            platform: ChatPlatform::Discord,
+            channel_id: self.channel_id?,
+            message_id: None,
+            user_id: user.id,
+            user_name: user.username,
+            text,
+            command: Some(data.name),
+            args,
+            options: data
+                .options
+                .into_iter()
+                .filter_map(|o| Some((o.name, o.value?)))
+                .collect(),
+        })
+    }
+}
+
+/// Reply to a slash command. Discord shows an error to the user if an interaction isn't
+/// answered within a few seconds. The reply is only visible to the user who sent the command.
+async fn respond_to_interaction(
+    client: &reqwest::Client,
+    interaction_id: &str,
+    interaction_token: &str,
+    content: &str,
+) -> Result<(), reqwest::Error> {
+    client
+        .post(format!(
+            "{}/interactions/{}/{}/callback",
+            DISCORD_API_URL, interaction_id, interaction_token
+        ))
+        .json(&json!({
+            "type": 4,
+            "data": { "content": content, "flags": 64 },
+        }))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+fn heartbeat_interval(period: Duration) -> tokio::time::Interval {
+    tokio::time::interval_at(tokio::time::Instant::now() + period, period)
+}
+
+/// Run a Discord gateway session until it disconnects.
+async fn run_discord_session(
+    pool: &PostgresPool,
+    redis_key_prefix: Option<&str>,
+    client: &reqwest::Client,
+    subs: &BotSubscriptions,
+) -> Result<(), Error> {
+    let (mut socket, _) = tokio_tungstenite::connect_async(DISCORD_GATEWAY_URL)
+        .await
+        .map_err(|e| Error::ChatBotError(format!("Connecting to Discord gateway: {}", e)))?;
+
+    // The real interval arrives in the Hello event.
+    let mut heartbeat = heartbeat_interval(Duration::from_secs(3600));
+    let mut seq: Option<u64> = None;
+
+    loop {
+        let message = tokio::select! {
+            _ = heartbeat.tick() => {
+                socket
+                    .send(Message::Text(json!({ "op": 1, "d": seq }).to_string()))
+                    .await
+                    .map_err(|e| Error::ChatBotError(format!("Sending Discord heartbeat: {}", e)))?;
+                continue;
+            }
+            message = socket.next() => message,
+        };
+
+        let text = match message {
+            Some(Ok(Message::Text(text))) => text,
+            Some(Ok(Message::Close(frame))) => {
+                return Err(Error::ChatBotError(format!(
+                    "Discord gateway closed the connection: {:?}",
+                    frame
+                )))
+            }
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => {
+                return Err(Error::ChatBotError(format!("Discord gateway error: {}", e)))
+            }
+            None => return Ok(()),
+        };
+
+        let gateway_event: DiscordGatewayEvent = serde_json::from_str(&text)?;
+        if gateway_event.s.is_some() {
+            seq = gateway_event.s;
+        }
+
+        match gateway_event.op {
+            // Hello
+            10 => {
+                let interval = gateway_event.d["heartbeat_interval"]
+                    .as_u64()
+                    .unwrap_or(41250);
+                heartbeat = heartbeat_interval(Duration::from_millis(interval));
+
+                let identify = json!({
+                    "op": 2,
+                    "d": {
+                        "token": subs.account.token,
+                        "intents": DISCORD_INTENTS,
+                        "properties": { "os": "linux", "browser": "ergo", "device": "ergo" },
+                    },
+                });
+                socket
+                    .send(Message::Text(identify.to_string()))
+                    .await
+                    .map_err(|e| Error::ChatBotError(format!("Identifying to Discord: {}", e)))?;
+            }
+            // Reconnect or Invalid Session. Both are handled by starting a new session.
+            7 | 9 => return Ok(()),
+            // Dispatch
+            0 => match gateway_event.t.as_deref() {
+                Some("MESSAGE_CREATE") => {
+                    let message: DiscordMessage = serde_json::from_value(gateway_event.d)?;
+                    // Ignore bots, including this one, so that bots can't trigger each other.
+                    if message.author.bot || message.content.is_empty() {
+                        continue;
+                    }
+
+                    let payload = ChatPayload::from_text(
+                        ChatPlatform::Discord,
+                        message.channel_id,
+                        Some(message.id),
+                        message.author.id,
+                        message.author.username,
+                        message.content,
+                    );
+                    handle_message(pool, redis_key_prefix, &subs.targets, &payload).await;
+                }
+                Some("INTERACTION_CREATE") => {
+                    let interaction: DiscordInteraction = serde_json::from_value(gateway_event.d)?;
+                    let id = interaction.id.clone();
+                    let token = interaction.token.clone();
+                    let Some(payload) = interaction.payload() else {
+                        continue;
+                    };
+
+                    let count =
+                        handle_message(pool, redis_key_prefix, &subs.targets, &payload).await;
+                    let reply = if count > 0 {
+                        "Got it."
+                    } else {
+                        "That command isn't set up for this channel."
+                    };
+
+                    if let Err(e) = respond_to_interaction(client, &id, &token, reply).await {
+                        event!(Level::WARN, error=%e, "Failed to respond to Discord interaction");
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TelegramResponse<T> {
+    ok: bool,
+    result: Option<T>,
+    description: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TelegramUser {
+    id: i64,
+    #[serde(default)]
+    is_bot: bool,
+    username: Option<String>,
+    first_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TelegramChat {
+    id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct TelegramMessage {
+    message_id: i64,
+    from: Option<TelegramUser>,
+    chat: TelegramChat,
+    text: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TelegramUpdate {
+    update_id: i64,
+    message: Option<TelegramMessage>,
+    channel_post: Option<TelegramMessage>,
+}
+
+impl TelegramMessage {
+    fn payload(self) -> Option<ChatPayload> {
+        let text = self.text?;
+        // Posts in channels don't have a sender.
+        let (user_id, user_name) = match self.from {
+            Some(user) if user.is_bot => return None,
+            Some(user) => (user.id.to_string(), user.username.or(user.first_name)),
+            None => (String::new(), None),
+        };
+
+        Some(ChatPayload::from_text(
+            ChatPlatform::Telegram,
+            self.chat.id.to_string(),
+            Some(self.message_id.to_string()),
+            user_id,
+            user_name,
+            text,
+        ))
+    }
+}
+
+/// Long-poll a Telegram bot's updates until a request fails.
+async fn run_telegram_session(
+    pool: &PostgresPool,
+    redis_key_prefix: Option<&str>,
+    client: &reqwest::Client,
+    subs: &BotSubscriptions,
+) -> Result<(), Error> {
+    // The URL contains the token, so it's removed from the request errors.
+    let url = format!("{}/bot{}/getUpdates", TELEGRAM_API_URL, subs.account.token);
+    // Telegram drops the updates before the offset, so an update is only confirmed once the
+    // next poll starts.
+    let mut offset: Option<i64> = None;
+
+    loop {
+        let response: TelegramResponse<Vec<TelegramUpdate>> = client
+            .get(&url)
+            .query(&[
+                ("timeout", TELEGRAM_POLL_TIMEOUT_SECS.to_string()),
+                (
+                    "allowed_updates",
+                    r#"["message","channel_post"]"#.to_string(),
+                ),
+            ])
+            .query(&[("offset", offset)])
+            .timeout(Duration::from_secs(TELEGRAM_POLL_TIMEOUT_SECS + 10))
+            .send()
+            .await
+            .map_err(|e| Error::ChatBotError(format!("Polling Telegram: {}", e.without_url())))?
+            .json()
+            .await
+            .map_err(|e| {
+                Error::ChatBotError(format!("Reading Telegram updates: {}", e.without_url()))
+            })?;
+
+        if !response.ok {
+            return Err(Error::ChatBotError(format!(
+                "Telegram error: {}",
+                response.description.unwrap_or_default()
+            )));
+        }
+
+        for update in response.result.unwrap_or_default() {
+            offset = Some(update.update_id + 1);
+            let Some(payload) = update
+                .message
+                .or(update.channel_post)
+                .and_then(|m| m.payload())
+            else {
+                continue;
+            };
+
+            handle_message(pool, redis_key_prefix, &subs.targets, &payload).await;
+        }
+    }
+}
+
+/// Run a bot, reconnecting when its session ends, for as long as this server holds the bot's
+/// lock.
+async fn run_bot(
+    pool: PostgresPool,
+    redis_key_prefix: Option<String>,
+    account_id: AccountId,
+    subs: BotSubscriptions,
+) {
+    // Keep the connection, and so the lock, until this task is stopped.
+    let _lock = loop {
+        match lock_bot(&pool, &account_id).await {
+            Ok(Some(conn)) => break conn,
+            Ok(None) => {}
+            Err(e) => event!(Level::ERROR, %account_id, error=%e, "Failed to lock chat bot"),
+        }
+
+        // Another server is running the bot. Try again in case that server goes away.
+        tokio::time::sleep(Duration::from_secs(30)).await;
+    };
+
+    event!(Level::INFO, %account_id, platform=?subs.platform, subscriptions=subs.targets.len(), "Starting chat bot");
+    let client = reqwest::Client::new();
+    loop {
+        let result = match subs.platform {
+            ChatPlatform::Discord => {
+                run_discord_session(&pool, redis_key_prefix.as_deref(), &client, &subs).await
+            }
+            ChatPlatform::Telegram => {
+                run_telegram_session(&pool, redis_key_prefix.as_deref(), &client, &subs).await
+            }
+        };
+
+        if let Err(e) = result {
+            event!(Level::ERROR, %account_id, error=%e, "Chat bot connection error");
+        }
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+/// Start the chat bridge. The subscriptions are reloaded every `refresh_interval`, and a bot is
+/// restarted when its subscriptions change.
+pub fn start_chat_bridge(
+    mut shutdown: GracefulShutdownConsumer,
+    pool: PostgresPool,
+    redis_key_prefix: Option<String>,
+    refresh_interval: Option<Duration>,
+) -> tokio::task::JoinHandle<()> {
+    let refresh_interval = refresh_interval.unwrap_or_else(|| Duration::from_secs(60));
+    tokio::spawn(async move {
+        let mut running: FxHashMap<AccountId, (BotSubscriptions, tokio::task::JoinHandle<()>)> =
+            FxHashMap::default();
+
+        loop {
+            match load_subscriptions(&pool).await {
+                Ok(mut bots) => {
+                    running.retain(|account_id, (subs, handle)| {
+                        let unchanged = bots.get(account_id) == Some(subs);
+                        if unchanged {
+                            bots.remove(account_id);
+                        } else {
+                            handle.abort();
+                        }
+                        unchanged
+                    });
+
+                    for (account_id, subs) in bots {
+                        let handle = tokio::spawn(run_bot(
+                            pool.clone(),
+                            redis_key_prefix.clone(),
+                            account_id.clone(),
+                            subs.clone(),
+                        ));
+                        running.insert(account_id, (subs, handle));
+                    }
+                }
+                Err(e) => event!(Level::ERROR, error=%e, "Failed to load chat subscriptions"),
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(refresh_interval) => continue,
+                _ = shutdown.wait_for_shutdown() => break,
+            }
+        }
+
+        for (_, (_, handle)) in running {
+            handle.abort();
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        assert_eq!(
+            parse_command("/deploy prod now"),
+            Some(("deploy".to_string(), "prod now".to_string()))
+        );
+        assert_eq!(
+            parse_command("/status@ergo_bot"),
+            Some(("status".to_string(), String::new()))
+        );
+        assert_eq!(parse_command("deploy prod"), None);
+        assert_eq!(parse_command("/ deploy"), None);
+    }
+
+    fn target(command: Option<&str>) -> ChatSubscriptionTarget {
+        ChatSubscriptionTarget {
+            chat_subscription_id: ChatSubscriptionId::new(),
+            channel_id: "123".to_string(),
+            command: command.map(String::from),
+            org_id: OrgId::new(),
+            run_as_user: UserId::new(),
+            task_id: TaskId::new(),
+            task_name: "task".to_string(),
+            task_trigger_id: TaskTriggerId::new(),
+            task_trigger_local_id: "chat".to_string(),
+            task_trigger_name: "Chat".to_string(),
+            input_id: InputId::new(),
+            payload_schema: json!({}),
+        }
+    }
+
+    fn message(channel_id: &str, text: &str) -> ChatPayload {
+        ChatPayload::from_text(
+            ChatPlatform::Telegram,
+            channel_id.to_string(),
+            None,
+            "1".to_string(),
+            None,
+            text.to_string(),
+        )
+    }
+
+    #[test]
+    fn matching_subscriptions() {
+        let any_message = target(None);
+        let deploy = target(Some("deploy"));
+
+        assert!(any_message.matches(&message("123", "hello")));
+        assert!(any_message.matches(&message("123", "/deploy prod")));
+        assert!(!any_message.matches(&message("456", "hello")));
+
+        assert!(deploy.matches(&message("123", "/deploy prod")));
+        assert!(!deploy.matches(&message("123", "deploy prod")));
+        assert!(!deploy.matches(&message("123", "/status")));
+        assert!(!deploy.matches(&message("456", "/deploy prod")));
+    }
+
+    #[test]
+    fn discord_slash_command() {
+        let interaction: DiscordInteraction = serde_json::from_value(json!({
+            "id": "1",
+            "token": "abc",
+            "type": 2,
+            "channel_id": "123",
+            "member": { "user": { "id": "99", "username": "someone" } },
+            "data": {
+                "name": "deploy",
+                "options": [
+                    { "name": "env", "type": 3, "value": "prod" },
+                    { "name": "force", "type": 5, "value": true }
+                ]
+            }
+        }))
+        .unwrap();
+
+        let payload = interaction.payload().unwrap();
+        assert_eq!(payload.command.as_deref(), Some("deploy"));
+        assert_eq!(payload.args, "prod true");
+        assert_eq!(payload.text, "/deploy prod true");
+        assert_eq!(payload.user_name.as_deref(), Some("someone"));
+        assert_eq!(payload.options["env"], json!("prod"));
+        assert_eq!(payload.options["force"], json!(true));
+    }
+
+    #[test]
+    fn telegram_bot_messages_are_ignored() {
+        let message: TelegramMessage = serde_json::from_value(json!({
+            "message_id": 5,
+            "from": { "id": 7, "is_bot": true, "first_name": "Bot" },
+            "chat": { "id": -100 },
+            "text": "/deploy"
+        }))
+        .unwrap();
+        assert_eq!(message.payload(), None);
+    }
+}
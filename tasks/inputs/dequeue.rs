@@ -1,16 +1,17 @@
 //! Read events from the queues and execute tasks
 
-use std::num::NonZeroU32;
+use std::{num::NonZeroU32, time::Duration};
 
 use async_trait::async_trait;
 use ergo_database::{PostgresPool, RedisPool};
 use ergo_graceful_shutdown::GracefulShutdownConsumer;
 use ergo_notifications::NotificationManager;
-use ergo_queues::{QueueJobProcessor, QueueWorkItem};
+use ergo_queues::{ErrorClass, QueueJobProcessor, QueueWorkItem};
+use tracing::{event, Instrument, Level};
 
-use crate::error::Error;
+use crate::{actions::queue::ActionQueue, error::Error, payload_limits::load_input_payload};
 
-use super::{super::Task, queue::InputQueue, InputInvocation};
+use super::{super::Task, queue::InputQueue, sensitive::decrypt_payload, InputInvocation};
 
 pub struct TaskExecutor {
     queue: InputQueue,
@@ -23,12 +24,25 @@ pub struct TaskExecutorConfig {
     pub notifications: Option<NotificationManager>,
     /// The highest number of concurrent jobs to run. Defaults to twice the number of CPUs.
     pub max_concurrent_jobs: Option<usize>,
+    /// The highest number of inputs for a single org to run at once. Periodic inputs are
+    /// counted separately, so they can't take up all of an org's slots.
+    pub max_jobs_per_org: Option<usize>,
+    /// Slow down taking new inputs while the action queue has more than this many jobs
+    /// waiting.
+    pub max_action_backlog: Option<usize>,
+    /// How often to extend the processing deadline of inputs that are still running.
+    pub heartbeat_interval: Option<Duration>,
 }
 
 impl TaskExecutor {
     pub fn new(config: TaskExecutorConfig) -> Result<TaskExecutor, Error> {
         let redis_key_prefix = config.redis_pool.key_prefix().map(|s| s.to_string());
 
+        let action_backlog = config.max_action_backlog.map(|max_backlog| ActionBacklog {
+            queue: ActionQueue::new(config.redis_pool.clone()),
+            max_backlog,
+        });
+
         // Start the event queue reader.
         let queue = InputQueue::new(config.redis_pool);
 
@@ -38,6 +52,9 @@ impl TaskExecutor {
             pg_pool: config.pg_pool,
             notifications: config.notifications,
             redis_key_prefix,
+            max_jobs_per_org: config.max_jobs_per_org,
+            action_backlog,
+            heartbeat_interval: config.heartbeat_interval,
         };
 
         executor.queue.start_dequeuer_loop(
@@ -51,6 +68,37 @@ impl TaskExecutor {
 
         Ok(executor)
     }
+
+    pub fn queue(&self) -> &InputQueue {
+        &self.queue
+    }
+}
+
+#[derive(Clone)]
+struct ActionBacklog {
+    queue: ActionQueue,
+    max_backlog: usize,
+}
+
+impl ActionBacklog {
+    /// Wait longer the further the action queue is over the limit, up to a second at a time.
+    async fn delay(&self) -> Option<Duration> {
+        let backlog = match self.queue.backlog().await {
+            Ok(backlog) => backlog,
+            Err(e) => {
+                event!(Level::ERROR, error=%e, "Failed to read action queue backlog");
+                return None;
+            }
+        };
+
+        if backlog <= self.max_backlog {
+            return None;
+        }
+
+        let over = (backlog - self.max_backlog) as u64;
+        let delay_ms = over.saturating_mul(10).clamp(50, 1000);
+        Some(Duration::from_millis(delay_ms))
+    }
 }
 
 #[derive(Clone)]
@@ -58,6 +106,9 @@ struct TaskExecutorJobProcessor {
     pg_pool: PostgresPool,
     notifications: Option<NotificationManager>,
     redis_key_prefix: Option<String>,
+    max_jobs_per_org: Option<usize>,
+    action_backlog: Option<ActionBacklog>,
+    heartbeat_interval: Option<Duration>,
 }
 
 #[async_trait]
@@ -68,8 +119,13 @@ impl QueueJobProcessor for TaskExecutorJobProcessor {
     async fn process(
         &self,
         item: &QueueWorkItem<InputInvocation>,
-        invocation: InputInvocation,
+        mut invocation: InputInvocation,
     ) -> Result<(), Error> {
+        let span = tracing::info_span!("process_input", inputs_log_id=%invocation.inputs_log_id);
+        invocation.trace.set_parent_of(&span);
+        load_input_payload(&self.pg_pool, &mut invocation).await?;
+        invocation.payload = decrypt_payload(invocation.payload).await?;
+
         Task::apply_input(
             &self.pg_pool,
             self.notifications.clone(),
@@ -77,7 +133,27 @@ impl QueueJobProcessor for TaskExecutorJobProcessor {
             item.is_final_retry(),
             invocation,
         )
+        .instrument(span)
         .await?;
         Ok(())
     }
+
+    fn error_class(&self, error: &Error) -> ErrorClass {
+        error.error_class()
+    }
+
+    fn max_jobs_per_key(&self) -> Option<usize> {
+        self.max_jobs_per_org
+    }
+
+    fn heartbeat_interval(&self) -> Option<Duration> {
+        self.heartbeat_interval
+    }
+
+    async fn backpressure(&self) -> Option<Duration> {
+        match self.action_backlog.as_ref() {
+            Some(backlog) => backlog.delay().await,
+            None => None,
+        }
+    }
 }
@@ -0,0 +1,590 @@
+//! Email ingestion. Messages arrive either by polling an IMAP folder or from an inbound mail
+//! provider posting the raw message to the API, and each message is sent as an input to the
+//! mailbox's task trigger.
+
+use chrono::{DateTime, TimeZone, Utc};
+use ergo_database::{
+    encryption::decrypt_account_fields,
+    object_id::{AccountId, EmailMailboxId, InputId, OrgId, TaskId, TaskTriggerId, UserId},
+    PostgresPool,
+};
+use ergo_graceful_shutdown::GracefulShutdownConsumer;
+use ergo_notifications::NotificationManager;
+use itertools::Itertools;
+use mailparse::{DispositionType, MailAddr, MailHeaderMap, ParsedMail};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::{Connection, PgConnection};
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use super::{chain::InputChain, enqueue_input, EnqueueInputOptions};
+use crate::Error;
+
+/// The most messages to ingest from a mailbox in one poll. Any others are picked up by the
+/// following polls.
+const MAX_MESSAGES_PER_POLL: usize = 50;
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+pub struct EmailAddress {
+    pub name: Option<String>,
+    pub address: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+pub struct EmailAttachment {
+    pub filename: Option<String>,
+    pub content_type: String,
+    /// The decoded size of the attachment, in bytes.
+    pub size: usize,
+}
+
+/// The input payload for a received email.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+pub struct EmailPayload {
+    pub message_id: Option<String>,
+    pub from: Vec<EmailAddress>,
+    pub to: Vec<EmailAddress>,
+    pub cc: Vec<EmailAddress>,
+    pub reply_to: Vec<EmailAddress>,
+    pub subject: Option<String>,
+    pub date: Option<DateTime<Utc>>,
+    /// The first plain text part of the message.
+    pub text: Option<String>,
+    /// The first HTML part of the message.
+    pub html: Option<String>,
+    /// The attachments' metadata. Their contents are not included.
+    pub attachments: Vec<EmailAttachment>,
+}
+
+fn addresses(mail: &ParsedMail, header: &str) -> Vec<EmailAddress> {
+    let headers = mail.get_headers();
+    let Some(header) = headers.get_first_header(header) else {
+        return Vec::new();
+    };
+
+    let list = match mailparse::addrparse_header(header) {
+        Ok(list) => list,
+        Err(_) => return Vec::new(),
+    };
+
+    list.iter()
+        .flat_map(|addr| match addr {
+            MailAddr::Single(info) => vec![info.clone()],
+            MailAddr::Group(group) => group.addrs.clone(),
+        })
+        .map(|info| EmailAddress {
+            name: info.display_name,
+            address: info.addr,
+        })
+        .collect()
+}
+
+/// Parse a raw RFC 5322 message into an input payload.
+pub fn parse_email(raw: &[u8]) -> Result<EmailPayload, Error> {
+    let mail = mailparse::parse_mail(raw)?;
+
+    let mut text = None;
+    let mut html = None;
+    let mut attachments = Vec::new();
+    for part in mail.parts() {
+        if !part.subparts.is_empty() {
+            continue;
+        }
+
+        let disposition = part.get_content_disposition();
+        let filename = disposition
+            .params
+            .get("filename")
+            .or_else(|| part.ctype.params.get("name"))
+            .cloned();
+
+        if matches!(disposition.disposition, DispositionType::Attachment) || filename.is_some() {
+            attachments.push(EmailAttachment {
+                filename,
+                content_type: part.ctype.mimetype.clone(),
+                size: part.get_body_raw()?.len(),
+            });
+        } else if part.ctype.mimetype == "text/plain" && text.is_none() {
+            text = Some(part.get_body()?);
+        } else if part.ctype.mimetype == "text/html" && html.is_none() {
+            html = Some(part.get_body()?);
+        }
+    }
+
+    let headers = mail.get_headers();
+    let date = headers
+        .get_first_value("Date")
+        .and_then(|d| mailparse::dateparse(&d).ok())
+        .and_then(|timestamp| Utc.timestamp_opt(timestamp, 0).single());
+
+    Ok(EmailPayload {
+        message_id: headers.get_first_value("Message-ID"),
+        from: addresses(&mail, "From"),
+        to: addresses(&mail, "To"),
+        cc: addresses(&mail, "Cc"),
+        reply_to: addresses(&mail, "Reply-To"),
+        subject: headers.get_first_value("Subject"),
+        date,
+        text,
+        html,
+        attachments,
+    })
+}
+
+/// Connection settings for an IMAP server, read from the fields of an account.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ImapAccount {
+    pub host: String,
+    #[serde(default = "default_imap_port")]
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    #[serde(default = "default_tls")]
+    pub tls: bool,
+}
+
+fn default_imap_port() -> u16 {
+    993
+}
+
+fn default_tls() -> bool {
+    true
+}
+
+/// How far a mailbox has read in its folder. UIDs can only be compared while the folder's
+/// UIDVALIDITY stays the same.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImapPosition {
+    pub uid_validity: u32,
+    pub last_uid: u32,
+}
+
+#[derive(Debug)]
+pub struct ImapFetch {
+    pub position: ImapPosition,
+    /// The raw messages, with their UIDs.
+    pub messages: Vec<(u32, Vec<u8>)>,
+}
+
+fn imap_error(e: impl std::fmt::Display) -> Error {
+    Error::ImapError(e.to_string())
+}
+
+/// Fetch the messages that arrived in `folder` after `position`. When there is no position yet,
+/// or the folder's UIDVALIDITY has changed, this only returns the folder's current position so
+/// that mail which was already there isn't ingested.
+///
+/// This blocks, so run it with `spawn_blocking`.
+pub fn fetch_new_messages(
+    account: &ImapAccount,
+    folder: &str,
+    position: Option<ImapPosition>,
+) -> Result<ImapFetch, Error> {
+    if account.tls {
+        let tls = native_tls::TlsConnector::builder()
+            .build()
+            .map_err(imap_error)?;
+        let client = imap::connect((account.host.as_str(), account.port), &account.host, &tls)
+            .map_err(imap_error)?;
+        let mut session = client
+            .login(&account.username, &account.password)
+            .map_err(|(e, _)| imap_error(e))?;
+        let result = fetch_from_session(&mut session, folder, position);
+        session.logout().ok();
+        result
+    } else {
+        let stream = std::net::TcpStream::connect((account.host.as_str(), account.port))
+            .map_err(imap_error)?;
+        let mut client = imap::Client::new(stream);
+        client.read_greeting().map_err(imap_error)?;
+        let mut session = client
+            .login(&account.username, &account.password)
+            .map_err(|(e, _)| imap_error(e))?;
+        let result = fetch_from_session(&mut session, folder, position);
+        session.logout().ok();
+        result
+    }
+}
+
+fn fetch_from_session<T: std::io::Read + std::io::Write>(
+    session: &mut imap::Session<T>,
+    folder: &str,
+    position: Option<ImapPosition>,
+) -> Result<ImapFetch, Error> {
+    let mailbox = session.select(folder).map_err(imap_error)?;
+    let uid_validity = mailbox.uid_validity.unwrap_or_default();
+
+    let last_uid = match position {
+        Some(p) if p.uid_validity == uid_validity => p.last_uid,
+        _ => {
+            return Ok(ImapFetch {
+                position: ImapPosition {
+                    uid_validity,
+                    last_uid: mailbox.uid_next.unwrap_or(1).saturating_sub(1),
+                },
+                messages: Vec::new(),
+            });
+        }
+    };
+
+    // The search always returns the newest message, even when its UID is below the range.
+    let uids = session
+        .uid_search(format!("UID {}:*", last_uid + 1))
+        .map_err(imap_error)?
+        .into_iter()
+        .filter(|uid| *uid > last_uid)
+        .sorted()
+        .take(MAX_MESSAGES_PER_POLL)
+        .collect::<Vec<_>>();
+
+    let Some(newest) = uids.last().copied() else {
+        return Ok(ImapFetch {
+            position: ImapPosition {
+                uid_validity,
+                last_uid,
+            },
+            messages: Vec::new(),
+        });
+    };
+
+    // BODY.PEEK leaves the messages unread for anyone else using the mailbox.
+    let fetches = session
+        .uid_fetch(uids.iter().join(","), "(UID BODY.PEEK[])")
+        .map_err(imap_error)?;
+    let messages = fetches
+        .iter()
+        .filter_map(|fetch| Some((fetch.uid?, fetch.body()?.to_vec())))
+        .sorted_by_key(|(uid, _)| *uid)
+        .collect::<Vec<_>>();
+
+    Ok(ImapFetch {
+        position: ImapPosition {
+            uid_validity,
+            last_uid: newest,
+        },
+        messages,
+    })
+}
+
+/// The task trigger that a mailbox sends its messages to.
+#[derive(Debug)]
+pub struct MailboxTarget {
+    pub email_mailbox_id: EmailMailboxId,
+    pub org_id: OrgId,
+    pub run_as_user: UserId,
+    pub task_id: TaskId,
+    pub task_name: String,
+    pub task_trigger_id: TaskTriggerId,
+    pub task_trigger_local_id: String,
+    pub task_trigger_name: String,
+    pub input_id: InputId,
+    pub payload_schema: serde_json::Value,
+}
+
+pub async fn mailbox_target(
+    tx: &mut PgConnection,
+    email_mailbox_id: &EmailMailboxId,
+) -> Result<Option<MailboxTarget>, Error> {
+    let target = sqlx::query_as!(
+        MailboxTarget,
+        r##"SELECT
+            mb.email_mailbox_id AS "email_mailbox_id: EmailMailboxId",
+            mb.org_id AS "org_id: OrgId",
+            mb.run_as_user AS "run_as_user: UserId",
+            tasks.task_id AS "task_id: TaskId",
+            tasks.name AS task_name,
+            tt.task_trigger_id AS "task_trigger_id: TaskTriggerId",
+            tt.task_trigger_local_id,
+            tt.name AS task_trigger_name,
+            inputs.input_id AS "input_id: InputId",
+            inputs.payload_schema
+        FROM email_mailboxes mb
+        JOIN task_triggers tt USING (task_trigger_id)
+        JOIN tasks ON tasks.task_id = tt.task_id
+        JOIN inputs ON inputs.input_id = tt.input_id
+        WHERE mb.email_mailbox_id = $1 AND NOT tasks.deleted"##,
+        email_mailbox_id.0
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    Ok(target)
+}
+
+/// Send an email to the mailbox's task trigger.
+pub async fn enqueue_email(
+    tx: &mut PgConnection,
+    notifications: Option<NotificationManager>,
+    redis_key_prefix: Option<&str>,
+    target: &MailboxTarget,
+    user_id: &UserId,
+    payload: &EmailPayload,
+) -> Result<Uuid, Error> {
+    enqueue_input(EnqueueInputOptions {
+        pg: tx,
+        notifications,
+        org_id: target.org_id.clone(),
+        user_id: user_id.clone(),
+        task_id: target.task_id.clone(),
+        task_name: target.task_name.clone(),
+        input_id: target.input_id.clone(),
+        task_trigger_id: target.task_trigger_id.clone(),
+        task_trigger_local_id: target.task_trigger_local_id.clone(),
+        task_trigger_name: target.task_trigger_name.clone(),
+        periodic_trigger_id: None,
+        payload_schema: &target.payload_schema,
+        payload: serde_json::to_value(payload)?,
+        redis_key_prefix,
+        trigger_at: None,
+        replay_of: None,
+        interactive: false,
+        chain: InputChain::default(),
+    })
+    .await
+}
+
+#[derive(Debug)]
+struct ClaimedMailbox {
+    email_mailbox_id: EmailMailboxId,
+    account_id: AccountId,
+    folder: String,
+    uid_validity: Option<i64>,
+    last_uid: Option<i64>,
+}
+
+/// Claim the mailboxes that are due to be polled. Setting `last_polled` as they're claimed keeps
+/// other servers from polling the same mailboxes.
+async fn claim_due_mailboxes(pool: &PostgresPool) -> Result<Vec<ClaimedMailbox>, Error> {
+    let mailboxes = sqlx::query_as!(
+        ClaimedMailbox,
+        r##"UPDATE email_mailboxes
+        SET last_polled = now()
+        WHERE email_mailbox_id IN (
+            SELECT email_mailbox_id
+            FROM email_mailboxes mb
+            JOIN task_triggers tt USING (task_trigger_id)
+            JOIN tasks ON tasks.task_id = tt.task_id
+            WHERE mb.enabled AND mb.account_id IS NOT NULL
+                AND tasks.enabled AND NOT tasks.deleted
+                AND (mb.last_polled IS NULL
+                    OR mb.last_polled + make_interval(secs => mb.poll_interval_secs) <= now())
+            ORDER BY mb.last_polled NULLS FIRST
+            LIMIT 20
+            FOR UPDATE OF mb SKIP LOCKED
+        )
+        RETURNING email_mailbox_id AS "email_mailbox_id: EmailMailboxId",
+            account_id AS "account_id!: AccountId",
+            folder, uid_validity, last_uid"##
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(mailboxes)
+}
+
+async fn poll_mailbox(
+    pool: &PostgresPool,
+    redis_key_prefix: Option<&str>,
+    mailbox: &ClaimedMailbox,
+) -> Result<usize, Error> {
+    let mut conn = pool.acquire().await?;
+
+    let fields = sqlx::query_scalar!(
+        "SELECT fields FROM accounts WHERE account_id=$1",
+        mailbox.account_id.0
+    )
+    .fetch_optional(&mut conn)
+    .await?
+    .flatten()
+    .ok_or_else(|| Error::ImapError("The mailbox's account has no connection settings".into()))?;
+    let account: ImapAccount = serde_json::from_value(decrypt_account_fields(fields)?)?;
+
+    let position = match (mailbox.uid_validity, mailbox.last_uid) {
+        (Some(uid_validity), Some(last_uid)) => Some(ImapPosition {
+            uid_validity: uid_validity as u32,
+            last_uid: last_uid as u32,
+        }),
+        _ => None,
+    };
+
+    let folder = mailbox.folder.clone();
+    let fetched =
+        tokio::task::spawn_blocking(move || fetch_new_messages(&account, &folder, position))
+            .await
+            .map_err(imap_error)??;
+
+    let target = mailbox_target(&mut conn, &mailbox.email_mailbox_id)
+        .await?
+        .ok_or(Error::NotFound)?;
+
+    let mut tx = conn.begin().await?;
+    let mut count = 0;
+    for (uid, raw) in &fetched.messages {
+        let result = match parse_email(raw) {
+            Ok(payload) => {
+                enqueue_email(
+                    &mut tx,
+                    None,
+                    redis_key_prefix,
+                    &target,
+                    &target.run_as_user,
+                    &payload,
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(_) => count += 1,
+            // Don't let one bad message block the rest of the mailbox.
+            Err(e) if e.is_permanent() => {
+                event!(Level::WARN, mailbox=%mailbox.email_mailbox_id, %uid, error=%e, "Skipping email");
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    sqlx::query!(
+        "UPDATE email_mailboxes
+        SET uid_validity=$2, last_uid=$3, last_error=NULL
+        WHERE email_mailbox_id=$1",
+        mailbox.email_mailbox_id.0,
+        i64::from(fetched.position.uid_validity),
+        i64::from(fetched.position.last_uid)
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(count)
+}
+
+/// Poll the mailboxes that are due, and return the number of messages ingested.
+pub async fn poll_mailboxes(
+    pool: &PostgresPool,
+    redis_key_prefix: Option<&str>,
+) -> Result<usize, Error> {
+    let mut total = 0;
+    for mailbox in claim_due_mailboxes(pool).await? {
+        match poll_mailbox(pool, redis_key_prefix, &mailbox).await {
+            Ok(count) => total += count,
+            Err(e) => {
+                event!(Level::ERROR, mailbox=%mailbox.email_mailbox_id, error=%e, "Failed to poll mailbox");
+                sqlx::query!(
+                    "UPDATE email_mailboxes SET last_error=$2 WHERE email_mailbox_id=$1",
+                    mailbox.email_mailbox_id.0,
+                    e.to_string()
+                )
+                .execute(pool)
+                .await?;
+            }
+        }
+    }
+
+    Ok(total)
+}
+
+pub fn start_email_poller(
+    mut shutdown: GracefulShutdownConsumer,
+    pool: PostgresPool,
+    redis_key_prefix: Option<String>,
+    check_interval: Option<std::time::Duration>,
+) -> tokio::task::JoinHandle<()> {
+    let check_interval = check_interval.unwrap_or_else(|| std::time::Duration::from_secs(30));
+    tokio::spawn(async move {
+        loop {
+            match poll_mailboxes(&pool, redis_key_prefix.as_deref()).await {
+                Ok(0) => {}
+                Ok(count) => event!(Level::INFO, %count, "Ingested emails"),
+                Err(e) => event!(Level::ERROR, error=%e, "Failed to poll mailboxes"),
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(check_interval) => continue,
+                _ = shutdown.wait_for_shutdown() => break,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MULTIPART: &str = "From: Sender Name <sender@example.com>\r
+To: one@example.com, \"Two\" <two@example.com>\r
+Subject: =?UTF-8?Q?Caf=C3=A9_report?=\r
+Date: Tue, 24 Jan 2023 10:00:00 +0000\r
+Message-ID: <abc@example.com>\r
+MIME-Version: 1.0\r
+Content-Type: multipart/mixed; boundary=\"outer\"\r
+\r
+--outer\r
+Content-Type: multipart/alternative; boundary=\"inner\"\r
+\r
+--inner\r
+Content-Type: text/plain; charset=utf-8\r
+\r
+Hello there\r
+--inner\r
+Content-Type: text/html; charset=utf-8\r
+\r
+<p>Hello there</p>\r
+--inner--\r
+--outer\r
+Content-Type: text/csv\r
+Content-Disposition: attachment; filename=\"report.csv\"\r
+Content-Transfer-Encoding: base64\r
+\r
+YSxiCjEsMgo=\r
+--outer--\r
+";
+
+    #[test]
+    fn parse_multipart() {
+        let payload = parse_email(MULTIPART.as_bytes()).unwrap();
+
+        assert_eq!(payload.subject.as_deref(), Some("Café report"));
+        assert_eq!(payload.message_id.as_deref(), Some("<abc@example.com>"));
+        assert_eq!(
+            payload.from,
+            vec![EmailAddress {
+                name: Some("Sender Name".to_string()),
+                address: "sender@example.com".to_string(),
+            }]
+        );
+        assert_eq!(payload.to.len(), 2);
+        assert_eq!(payload.to[1].name.as_deref(), Some("Two"));
+        assert_eq!(
+            payload.date,
+            Some(Utc.with_ymd_and_hms(2023, 1, 24, 10, 0, 0).unwrap())
+        );
+        assert_eq!(payload.text.as_deref().map(str::trim), Some("Hello there"));
+        assert_eq!(
+            payload.html.as_deref().map(str::trim),
+            Some("<p>Hello there</p>")
+        );
+        assert_eq!(
+            payload.attachments,
+            vec![EmailAttachment {
+                filename: Some("report.csv".to_string()),
+                content_type: "text/csv".to_string(),
+                size: 8,
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_plain() {
+        let raw = "From: a@example.com\r\nSubject: Hi\r\n\r\nJust text\r\n";
+        let payload = parse_email(raw.as_bytes()).unwrap();
+
+        assert_eq!(payload.from[0].address, "a@example.com");
+        assert!(payload.to.is_empty());
+        assert_eq!(payload.text.as_deref().map(str::trim), Some("Just text"));
+        assert_eq!(payload.html, None);
+        assert!(payload.attachments.is_empty());
+    }
+}
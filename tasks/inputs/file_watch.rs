@@ -0,0 +1,818 @@
+//! The file watcher sends each new file in a watched directory or S3 prefix to a task trigger,
+//! along with its metadata and optionally its parsed contents. Directories are watched for
+//! changes through the OS (inotify on Linux), and S3 prefixes are polled.
+//!
+//! Every server runs the watcher. The files that a watch has already handled are recorded in
+//! the database, so each file is only sent once even when the servers share a directory.
+
+use std::{
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, Utc};
+use ergo_database::{
+    encryption::decrypt_account_fields,
+    object_id::{AccountId, FileWatchId, InputId, OrgId, TaskId, TaskTriggerId, UserId},
+    PostgresPool,
+};
+use ergo_graceful_shutdown::GracefulShutdownConsumer;
+use fxhash::FxHashMap;
+use notify::{
+    event::{AccessKind, AccessMode, ModifyKind, RenameMode},
+    EventKind, RecursiveMode, Watcher,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::Connection;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tracing::{event, Level};
+
+use super::{chain::InputChain, enqueue_input, EnqueueInputOptions};
+use crate::Error;
+
+/// Files larger than this are sent without their contents.
+pub const MAX_PARSE_BYTES: u64 = 10 * 1024 * 1024;
+/// A file found while scanning a directory is skipped until it hasn't been modified for this
+/// long, so that files which are still being written aren't sent early.
+const SETTLE_TIME: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "file_watch_source", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum FileWatchSource {
+    Directory,
+    S3,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "file_watch_parse", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum FileParse {
+    /// Only send the file's metadata.
+    None,
+    Json,
+    /// Parse a CSV file with a header row into an array of objects.
+    Csv,
+}
+
+impl Default for FileParse {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+/// The directory that directory watches are relative to, from `FILE_WATCH_ROOT`. Directory
+/// watches are disabled when this is not set.
+pub fn watch_root() -> Option<PathBuf> {
+    std::env::var_os("FILE_WATCH_ROOT").map(PathBuf::from)
+}
+
+/// Returns true if `path` is a relative path that stays inside the directory it is joined to.
+pub fn valid_relative_path(path: &str) -> bool {
+    Path::new(path)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// The fields of an `s3` account.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct S3Account {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub region: Option<String>,
+    /// The endpoint of an S3-compatible service.
+    pub endpoint: Option<String>,
+}
+
+impl S3Account {
+    pub fn bucket(&self, name: &str) -> Result<s3::Bucket, Error> {
+        let region_name = self
+            .region
+            .clone()
+            .unwrap_or_else(|| "us-east-1".to_string());
+        let region = match self.endpoint.as_ref() {
+            Some(endpoint) => s3::Region::Custom {
+                region: region_name,
+                endpoint: endpoint.clone(),
+            },
+            None => region_name
+                .parse()
+                .map_err(|e| Error::FileWatch(format!("Invalid S3 region: {}", e)))?,
+        };
+
+        let credentials = s3::creds::Credentials::new(
+            Some(&self.access_key_id),
+            Some(&self.secret_access_key),
+            None,
+            None,
+            None,
+        )
+        .map_err(|e| Error::FileWatch(e.to_string()))?;
+
+        let mut bucket = s3::Bucket::new(name, region, credentials)
+            .map_err(|e| Error::FileWatch(e.to_string()))?;
+        if self.endpoint.is_some() {
+            // S3-compatible services usually don't support virtual-hosted buckets.
+            bucket.set_path_style();
+        }
+
+        Ok(bucket)
+    }
+}
+
+/// The input payload for a new file.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct FilePayload {
+    pub source: FileWatchSource,
+    pub bucket: Option<String>,
+    /// The file's path relative to the watched directory, or its S3 key.
+    pub path: String,
+    pub name: String,
+    pub size: u64,
+    pub modified: Option<DateTime<Utc>>,
+    /// The parsed contents of the file, if the watch parses files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<serde_json::Value>,
+    /// Why the contents could not be parsed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_error: Option<String>,
+}
+
+/// Parse the contents of a file.
+pub fn parse_content(parse: FileParse, data: &[u8]) -> Result<serde_json::Value, String> {
+    match parse {
+        FileParse::None => Ok(serde_json::Value::Null),
+        FileParse::Json => serde_json::from_slice(data).map_err(|e| e.to_string()),
+        FileParse::Csv => {
+            let mut reader = csv::Reader::from_reader(data);
+            let headers = reader.headers().map_err(|e| e.to_string())?.clone();
+            reader
+                .records()
+                .map(|record| {
+                    let record = record.map_err(|e| e.to_string())?;
+                    let row = headers
+                        .iter()
+                        .zip(record.iter())
+                        .map(|(header, value)| {
+                            (
+                                header.to_string(),
+                                serde_json::Value::String(value.to_string()),
+                            )
+                        })
+                        .collect::<serde_json::Map<_, _>>();
+                    Ok(serde_json::Value::Object(row))
+                })
+                .collect::<Result<Vec<_>, String>>()
+                .map(serde_json::Value::Array)
+        }
+    }
+}
+
+/// Returns true if a filesystem event means that a file is ready to be read: it was closed after
+/// being written, or moved into place.
+pub fn is_finished_write(kind: &EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::Access(AccessKind::Close(AccessMode::Write))
+            | EventKind::Modify(ModifyKind::Name(RenameMode::To | RenameMode::Both))
+    )
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileWatchTarget {
+    pub file_watch_id: FileWatchId,
+    pub source: FileWatchSource,
+    pub account_id: Option<AccountId>,
+    pub s3_account: Option<S3Account>,
+    pub bucket: Option<String>,
+    pub path: String,
+    pub parse: FileParse,
+    pub org_id: OrgId,
+    pub run_as_user: UserId,
+    pub task_id: TaskId,
+    pub task_name: String,
+    pub task_trigger_id: TaskTriggerId,
+    pub task_trigger_local_id: String,
+    pub task_trigger_name: String,
+    pub input_id: InputId,
+    pub payload_schema: serde_json::Value,
+}
+
+async fn load_watches(pool: &PostgresPool) -> Result<Vec<FileWatchTarget>, Error> {
+    let rows = sqlx::query!(
+        r##"SELECT
+            fw.file_watch_id AS "file_watch_id: FileWatchId",
+            fw.source AS "source: FileWatchSource",
+            fw.account_id AS "account_id: AccountId",
+            fw.bucket,
+            fw.path,
+            fw.parse AS "parse: FileParse",
+            fw.org_id AS "org_id: OrgId",
+            fw.run_as_user AS "run_as_user: UserId",
+            tasks.task_id AS "task_id: TaskId",
+            tasks.name AS task_name,
+            tt.task_trigger_id AS "task_trigger_id: TaskTriggerId",
+            tt.task_trigger_local_id,
+            tt.name AS task_trigger_name,
+            inputs.input_id AS "input_id: InputId",
+            inputs.payload_schema,
+            accounts.fields AS "fields?"
+        FROM file_watches fw
+        LEFT JOIN accounts USING (account_id)
+        JOIN task_triggers tt USING (task_trigger_id)
+        JOIN tasks ON tasks.task_id = tt.task_id
+        JOIN inputs ON inputs.input_id = tt.input_id
+        WHERE fw.enabled AND tasks.enabled AND NOT tasks.deleted
+        ORDER BY fw.file_watch_id"##
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut watches = Vec::with_capacity(rows.len());
+    for row in rows {
+        let s3_account = match row.source {
+            FileWatchSource::Directory => None,
+            FileWatchSource::S3 => {
+                let account = row
+                    .fields
+                    .map(|fields| {
+                        let fields = decrypt_account_fields(fields)?;
+                        serde_json::from_value::<S3Account>(fields).map_err(Error::from)
+                    })
+                    .transpose();
+
+                match account {
+                    Ok(Some(account)) => Some(account),
+                    Ok(None) | Err(_) => {
+                        event!(Level::WARN, file_watch_id=%row.file_watch_id, "File watch has an invalid S3 account");
+                        continue;
+                    }
+                }
+            }
+        };
+
+        watches.push(FileWatchTarget {
+            file_watch_id: row.file_watch_id,
+            source: row.source,
+            account_id: row.account_id,
+            s3_account,
+            bucket: row.bucket,
+            path: row.path,
+            parse: row.parse,
+            org_id: row.org_id,
+            run_as_user: row.run_as_user,
+            task_id: row.task_id,
+            task_name: row.task_name,
+            task_trigger_id: row.task_trigger_id,
+            task_trigger_local_id: row.task_trigger_local_id,
+            task_trigger_name: row.task_trigger_name,
+            input_id: row.input_id,
+            payload_schema: row.payload_schema,
+        });
+    }
+
+    Ok(watches)
+}
+
+/// A file found in a watched location.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct FoundFile {
+    key: String,
+    /// Changes when the file is replaced, so that a new file with the same name is sent again.
+    version: String,
+    size: u64,
+    modified: Option<DateTime<Utc>>,
+}
+
+enum WatchLocation {
+    Directory(PathBuf),
+    S3 {
+        bucket: Box<s3::Bucket>,
+        prefix: String,
+    },
+}
+
+impl WatchLocation {
+    async fn local_file(dir: &Path, path: &Path) -> Option<FoundFile> {
+        let name = path.file_name()?.to_str()?;
+        // Hidden files are usually temporary files from a program that is still writing.
+        if name.starts_with('.') || path.parent() != Some(dir) {
+            return None;
+        }
+
+        let metadata = tokio::fs::metadata(path).await.ok()?;
+        if !metadata.is_file() {
+            return None;
+        }
+
+        let modified = metadata.modified().ok();
+        let modified_nanos = modified
+            .and_then(|m| m.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+
+        Some(FoundFile {
+            key: name.to_string(),
+            version: format!("{}-{}", metadata.len(), modified_nanos),
+            size: metadata.len(),
+            modified: modified.map(DateTime::<Utc>::from),
+        })
+    }
+
+    /// List the files in the location. When `settled` is true, files in a directory that were
+    /// modified very recently are skipped.
+    async fn list(&self, settled: bool) -> Result<Vec<FoundFile>, Error> {
+        match self {
+            Self::Directory(dir) => {
+                let mut files = Vec::new();
+                let mut entries = tokio::fs::read_dir(dir)
+                    .await
+                    .map_err(|e| Error::FileWatch(format!("{}: {}", dir.display(), e)))?;
+                let settle_cutoff = SystemTime::now() - SETTLE_TIME;
+                while let Some(entry) = entries
+                    .next_entry()
+                    .await
+                    .map_err(|e| Error::FileWatch(e.to_string()))?
+                {
+                    let file = match Self::local_file(dir, &entry.path()).await {
+                        Some(file) => file,
+                        None => continue,
+                    };
+
+                    let recent = file
+                        .modified
+                        .map(|m| SystemTime::from(m) > settle_cutoff)
+                        .unwrap_or(false);
+                    if settled && recent {
+                        continue;
+                    }
+
+                    files.push(file);
+                }
+
+                Ok(files)
+            }
+            Self::S3 { bucket, prefix } => {
+                let mut files = Vec::new();
+                let mut continuation_token = None;
+                loop {
+                    let (result, status) = bucket
+                        .list_page(prefix.clone(), None, continuation_token, None, None)
+                        .await
+                        .map_err(|e| Error::FileWatch(e.to_string()))?;
+                    if status >= 300 {
+                        return Err(Error::FileWatch(format!(
+                            "Listing S3 objects returned status {}",
+                            status
+                        )));
+                    }
+
+                    files.extend(
+                        result
+                            .contents
+                            .into_iter()
+                            .filter(|object| !object.key.ends_with('/'))
+                            .map(|object| FoundFile {
+                                version: object.e_tag.unwrap_or_else(|| {
+                                    format!("{}-{}", object.size, object.last_modified)
+                                }),
+                                size: object.size,
+                                modified: DateTime::parse_from_rfc3339(&object.last_modified)
+                                    .ok()
+                                    .map(|d| d.with_timezone(&Utc)),
+                                key: object.key,
+                            }),
+                    );
+
+                    match result.next_continuation_token {
+                        Some(token) if result.is_truncated => continuation_token = Some(token),
+                        _ => break,
+                    }
+                }
+
+                Ok(files)
+            }
+        }
+    }
+
+    async fn read(&self, key: &str) -> Result<Vec<u8>, Error> {
+        match self {
+            Self::Directory(dir) => tokio::fs::read(dir.join(key))
+                .await
+                .map_err(|e| Error::FileWatch(e.to_string())),
+            Self::S3 { bucket, .. } => {
+                let response = bucket
+                    .get_object(key)
+                    .await
+                    .map_err(|e| Error::FileWatch(e.to_string()))?;
+                if response.status_code() >= 300 {
+                    return Err(Error::FileWatch(format!(
+                        "Reading S3 object returned status {}",
+                        response.status_code()
+                    )));
+                }
+
+                Ok(response.bytes().to_vec())
+            }
+        }
+    }
+}
+
+struct WatchRunner {
+    pool: PostgresPool,
+    redis_key_prefix: Option<String>,
+    target: FileWatchTarget,
+    location: WatchLocation,
+}
+
+impl WatchRunner {
+    async fn payload(&self, file: &FoundFile) -> FilePayload {
+        let name = file.key.rsplit('/').next().unwrap_or_default().to_string();
+        let mut payload = FilePayload {
+            source: self.target.source,
+            bucket: self.target.bucket.clone(),
+            path: file.key.clone(),
+            name,
+            size: file.size,
+            modified: file.modified,
+            content: None,
+            content_error: None,
+        };
+
+        if self.target.parse == FileParse::None {
+            return payload;
+        }
+
+        if file.size > MAX_PARSE_BYTES {
+            payload.content_error = Some(format!(
+                "The file is larger than the parse limit of {} bytes",
+                MAX_PARSE_BYTES
+            ));
+            return payload;
+        }
+
+        match self.location.read(&file.key).await {
+            Ok(data) => match parse_content(self.target.parse, &data) {
+                Ok(content) => payload.content = Some(content),
+                Err(e) => payload.content_error = Some(e),
+            },
+            Err(e) => payload.content_error = Some(e.to_string()),
+        }
+
+        payload
+    }
+
+    /// Record the file, and send it to the trigger if it hasn't been seen before.
+    async fn handle_file(&self, file: &FoundFile) -> Result<(), Error> {
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+
+        // Recording the file first locks the row, so another server that finds the same file
+        // waits until this one is done, and then skips it.
+        let inserted = sqlx::query!(
+            "INSERT INTO file_watch_files (file_watch_id, file_key, version)
+            VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING",
+            self.target.file_watch_id.0,
+            file.key,
+            file.version
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+
+        if !inserted {
+            return Ok(());
+        }
+
+        let payload = self.payload(file).await;
+        enqueue_input(EnqueueInputOptions {
+            pg: &mut *tx,
+            notifications: None,
+            org_id: self.target.org_id.clone(),
+            user_id: self.target.run_as_user.clone(),
+            task_id: self.target.task_id.clone(),
+            task_name: self.target.task_name.clone(),
+            input_id: self.target.input_id.clone(),
+            task_trigger_id: self.target.task_trigger_id.clone(),
+            task_trigger_local_id: self.target.task_trigger_local_id.clone(),
+            task_trigger_name: self.target.task_trigger_name.clone(),
+            periodic_trigger_id: None,
+            payload_schema: &self.target.payload_schema,
+            payload: serde_json::to_value(&payload)?,
+            redis_key_prefix: self.redis_key_prefix.as_deref(),
+            trigger_at: None,
+            replay_of: None,
+            interactive: false,
+            chain: InputChain::default(),
+        })
+        .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// The first time a watch runs, record the files that are already there without sending
+    /// them.
+    async fn record_baseline(&self) -> Result<(), Error> {
+        let baseline_done = sqlx::query_scalar!(
+            "SELECT baseline_done FROM file_watches WHERE file_watch_id=$1",
+            self.target.file_watch_id.0
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .unwrap_or(true);
+
+        if baseline_done {
+            return Ok(());
+        }
+
+        let files = self.location.list(false).await?;
+        let keys = files.iter().map(|f| f.key.clone()).collect::<Vec<_>>();
+        let versions = files.iter().map(|f| f.version.clone()).collect::<Vec<_>>();
+
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        sqlx::query!(
+            "INSERT INTO file_watch_files (file_watch_id, file_key, version)
+            SELECT $1, * FROM UNNEST($2::text[], $3::text[])
+            ON CONFLICT DO NOTHING",
+            self.target.file_watch_id.0,
+            keys.as_slice(),
+            versions.as_slice()
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "UPDATE file_watches SET baseline_done=true WHERE file_watch_id=$1",
+            self.target.file_watch_id.0
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn scan(&self) {
+        let files = match self.location.list(true).await {
+            Ok(files) => files,
+            Err(e) => {
+                event!(Level::WARN, file_watch_id=%self.target.file_watch_id, error=%e, "Failed to list watched files");
+                return;
+            }
+        };
+
+        for file in files {
+            if let Err(e) = self.handle_file(&file).await {
+                event!(Level::WARN, file_watch_id=%self.target.file_watch_id, path=%file.key, error=%e, "Failed to enqueue file");
+            }
+        }
+    }
+
+    async fn run(self, poll_interval: Duration, mut changes: Option<UnboundedReceiver<PathBuf>>) {
+        if let Err(e) = self.record_baseline().await {
+            event!(Level::ERROR, file_watch_id=%self.target.file_watch_id, error=%e, "Failed to record existing files");
+            return;
+        }
+
+        // Directories are also scanned periodically, to catch changes that the OS events missed.
+        let mut scan_interval = tokio::time::interval(poll_interval);
+        loop {
+            tokio::select! {
+                Some(path) = next_change(&mut changes) => {
+                    let file = match &self.location {
+                        WatchLocation::Directory(dir) => WatchLocation::local_file(dir, &path).await,
+                        WatchLocation::S3 { .. } => None,
+                    };
+
+                    if let Some(file) = file {
+                        if let Err(e) = self.handle_file(&file).await {
+                            event!(Level::WARN, file_watch_id=%self.target.file_watch_id, path=%file.key, error=%e, "Failed to enqueue file");
+                        }
+                    }
+                }
+                _ = scan_interval.tick() => self.scan().await,
+            }
+        }
+    }
+}
+
+async fn next_change(changes: &mut Option<UnboundedReceiver<PathBuf>>) -> Option<PathBuf> {
+    match changes {
+        Some(changes) => changes.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Start watching a location. The returned watcher must be kept alive for the directory
+/// events to be delivered.
+fn start_watch(
+    pool: PostgresPool,
+    redis_key_prefix: Option<String>,
+    root: Option<&Path>,
+    target: FileWatchTarget,
+    poll_interval: Duration,
+) -> Result<
+    (
+        tokio::task::JoinHandle<()>,
+        Option<notify::RecommendedWatcher>,
+    ),
+    Error,
+> {
+    let (location, watcher, changes) = match target.source {
+        FileWatchSource::Directory => {
+            let root =
+                root.ok_or_else(|| Error::FileWatch("FILE_WATCH_ROOT is not set".to_string()))?;
+            if !valid_relative_path(&target.path) {
+                return Err(Error::FileWatch(format!("Invalid path {}", target.path)));
+            }
+            let dir = root.join(&target.path);
+
+            let (tx, rx) = unbounded_channel();
+            let mut watcher =
+                notify::recommended_watcher(move |result: notify::Result<notify::Event>| {
+                    if let Ok(event) = result {
+                        if is_finished_write(&event.kind) {
+                            for path in event.paths {
+                                tx.send(path).ok();
+                            }
+                        }
+                    }
+                })
+                .map_err(|e| Error::FileWatch(e.to_string()))?;
+
+            // The periodic scans still run if the directory can't be watched.
+            let watching = watcher.watch(&dir, RecursiveMode::NonRecursive);
+            if let Err(e) = &watching {
+                event!(Level::WARN, file_watch_id=%target.file_watch_id, dir=%dir.display(), error=%e, "Failed to watch directory, falling back to polling");
+            }
+
+            let changes = if watching.is_ok() { Some(rx) } else { None };
+            (WatchLocation::Directory(dir), Some(watcher), changes)
+        }
+        FileWatchSource::S3 => {
+            let (account, bucket) = target
+                .s3_account
+                .as_ref()
+                .zip(target.bucket.as_deref())
+                .ok_or_else(|| Error::FileWatch("Missing S3 account or bucket".to_string()))?;
+            let location = WatchLocation::S3 {
+                bucket: Box::new(account.bucket(bucket)?),
+                prefix: target.path.clone(),
+            };
+            (location, None, None)
+        }
+    };
+
+    let runner = WatchRunner {
+        pool,
+        redis_key_prefix,
+        target,
+        location,
+    };
+
+    let handle = tokio::spawn(runner.run(poll_interval, changes));
+    Ok((handle, watcher))
+}
+
+struct RunningWatch {
+    target: FileWatchTarget,
+    handle: tokio::task::JoinHandle<()>,
+    _watcher: Option<notify::RecommendedWatcher>,
+}
+
+/// Start the file watcher. The watches are reloaded every `refresh_interval`, and each S3 prefix
+/// is listed every `poll_interval`.
+pub fn start_file_watcher(
+    mut shutdown: GracefulShutdownConsumer,
+    pool: PostgresPool,
+    redis_key_prefix: Option<String>,
+    refresh_interval: Option<Duration>,
+    poll_interval: Option<Duration>,
+) -> tokio::task::JoinHandle<()> {
+    let refresh_interval = refresh_interval.unwrap_or_else(|| Duration::from_secs(60));
+    let poll_interval = poll_interval.unwrap_or_else(|| Duration::from_secs(60));
+    let root = watch_root();
+
+    tokio::spawn(async move {
+        let mut running: FxHashMap<FileWatchId, RunningWatch> = FxHashMap::default();
+
+        loop {
+            match load_watches(&pool).await {
+                Ok(watches) => {
+                    let mut watches = watches
+                        .into_iter()
+                        .map(|w| (w.file_watch_id.clone(), w))
+                        .collect::<FxHashMap<_, _>>();
+
+                    running.retain(|file_watch_id, watch| {
+                        let unchanged = watches.get(file_watch_id) == Some(&watch.target)
+                            && !watch.handle.is_finished();
+                        if unchanged {
+                            watches.remove(file_watch_id);
+                        } else {
+                            watch.handle.abort();
+                        }
+                        unchanged
+                    });
+
+                    for (file_watch_id, target) in watches {
+                        let started = start_watch(
+                            pool.clone(),
+                            redis_key_prefix.clone(),
+                            root.as_deref(),
+                            target.clone(),
+                            poll_interval,
+                        );
+
+                        match started {
+                            Ok((handle, watcher)) => {
+                                running.insert(
+                                    file_watch_id,
+                                    RunningWatch {
+                                        target,
+                                        handle,
+                                        _watcher: watcher,
+                                    },
+                                );
+                            }
+                            Err(e) => {
+                                event!(Level::ERROR, %file_watch_id, error=%e, "Failed to start file watch")
+                            }
+                        }
+                    }
+                }
+                Err(e) => event!(Level::ERROR, error=%e, "Failed to load file watches"),
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(refresh_interval) => continue,
+                _ = shutdown.wait_for_shutdown() => break,
+            }
+        }
+
+        for (_, watch) in running {
+            watch.handle.abort();
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn relative_paths() {
+        assert!(valid_relative_path("incoming"));
+        assert!(valid_relative_path("org/incoming"));
+        assert!(valid_relative_path(""));
+
+        assert!(!valid_relative_path("/etc"));
+        assert!(!valid_relative_path("../other"));
+        assert!(!valid_relative_path("incoming/../../other"));
+    }
+
+    #[test]
+    fn parse_json() {
+        assert_eq!(
+            parse_content(FileParse::Json, br#"{"a": [1, 2]}"#).unwrap(),
+            json!({ "a": [1, 2] })
+        );
+        assert!(parse_content(FileParse::Json, b"not json").is_err());
+    }
+
+    #[test]
+    fn parse_csv() {
+        let data = b"name,count\nwidgets,3\n\"gadgets, large\",5\n";
+        assert_eq!(
+            parse_content(FileParse::Csv, data).unwrap(),
+            json!([
+                { "name": "widgets", "count": "3" },
+                { "name": "gadgets, large", "count": "5" },
+            ])
+        );
+
+        assert!(parse_content(FileParse::Csv, b"a,b\n1,2,3\n").is_err());
+    }
+
+    #[test]
+    fn finished_writes() {
+        assert!(is_finished_write(&EventKind::Access(AccessKind::Close(
+            AccessMode::Write
+        ))));
+        assert!(is_finished_write(&EventKind::Modify(ModifyKind::Name(
+            RenameMode::To
+        ))));
+        assert!(!is_finished_write(&EventKind::Create(
+            notify::event::CreateKind::File
+        )));
+        assert!(!is_finished_write(&EventKind::Modify(ModifyKind::Name(
+            RenameMode::From
+        ))));
+    }
+}
@@ -0,0 +1,689 @@
+//! Describe the form for an input's payload, so that the web app can render manual trigger forms
+//! from any payload schema. Local `$ref`s and `allOf` are merged into the field they apply to,
+//! and a `oneOf` or `anyOf` of object schemas becomes a union of field sets, switched on a
+//! discriminator property when one can be found.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::secrets::escape_pointer_segment;
+
+/// Stop following `$ref`s past this depth, to avoid looping on recursive schemas.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct FormField {
+    /// The property name, or an empty string for the root of the payload.
+    pub name: String,
+    /// A JSON pointer to the value within the payload.
+    pub path: String,
+    pub label: String,
+    pub description: Option<String>,
+    pub required: bool,
+    /// True if the value may be null.
+    pub nullable: bool,
+    /// True if the schema marks the value with `x-secret`.
+    pub secret: bool,
+    pub default: Option<Value>,
+    #[serde(flatten)]
+    pub kind: FieldKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FieldKind {
+    Text {
+        /// The string format from the schema, such as `date-time` or `email`.
+        format: Option<String>,
+        min_length: Option<u64>,
+        max_length: Option<u64>,
+        pattern: Option<String>,
+    },
+    Number {
+        integer: bool,
+        minimum: Option<f64>,
+        maximum: Option<f64>,
+        exclusive_minimum: Option<f64>,
+        exclusive_maximum: Option<f64>,
+        multiple_of: Option<f64>,
+    },
+    Boolean,
+    /// Choose one of a fixed set of values.
+    Select {
+        options: Vec<SelectOption>,
+    },
+    /// A value that is always the same, such as a union's discriminator.
+    Constant {
+        value: Value,
+    },
+    Object {
+        fields: Vec<FormField>,
+    },
+    List {
+        item: Box<FormField>,
+        min_items: Option<u64>,
+        max_items: Option<u64>,
+        unique_items: bool,
+    },
+    /// Choose one of several shapes for the value.
+    Union {
+        /// The property whose value decides which variant applies, if there is one.
+        discriminator: Option<String>,
+        variants: Vec<UnionVariant>,
+    },
+    /// A value that the schema doesn't describe well enough for a specific field, which should
+    /// be edited as raw JSON.
+    Json,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct SelectOption {
+    pub label: String,
+    pub value: Value,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct UnionVariant {
+    pub label: String,
+    /// The discriminator value for this variant.
+    pub value: Option<Value>,
+    pub field: FormField,
+}
+
+/// Describe the form for a payload that matches `schema`.
+pub fn payload_form(schema: &Value) -> FormField {
+    FormBuilder { root: schema }.field(schema, "", String::new(), false, 0)
+}
+
+struct FormBuilder<'a> {
+    root: &'a Value,
+}
+
+impl<'a> FormBuilder<'a> {
+    /// Merge `$ref` targets and `allOf` subschemas into a single schema. Keys on the referring
+    /// schema take precedence over the ones it refers to.
+    fn flatten(&self, schema: &Value, depth: usize) -> Map<String, Value> {
+        let mut output = match schema.as_object() {
+            Some(o) => o.clone(),
+            None => return Map::new(),
+        };
+
+        if depth > MAX_DEPTH {
+            return output;
+        }
+
+        if let Some(reference) = output.remove("$ref") {
+            let target = reference
+                .as_str()
+                .and_then(|r| r.strip_prefix('#'))
+                .and_then(|pointer| self.root.pointer(pointer));
+            if let Some(target) = target {
+                merge(&mut output, self.flatten(target, depth + 1));
+            }
+        }
+
+        if let Some(Value::Array(subschemas)) = output.remove("allOf") {
+            for subschema in &subschemas {
+                merge(&mut output, self.flatten(subschema, depth + 1));
+            }
+        }
+
+        output
+    }
+
+    fn field(
+        &self,
+        schema: &Value,
+        name: &str,
+        path: String,
+        required: bool,
+        depth: usize,
+    ) -> FormField {
+        let schema = self.flatten(schema, depth);
+        let (types, mut nullable) = schema_types(&schema);
+
+        let label = schema
+            .get("title")
+            .and_then(|t| t.as_str())
+            .map(|t| t.to_string())
+            .unwrap_or_else(|| label_from_name(name));
+
+        let kind = if depth > MAX_DEPTH {
+            FieldKind::Json
+        } else if let Some(value) = schema.get("const") {
+            FieldKind::Constant {
+                value: value.clone(),
+            }
+        } else if let Some(Value::Array(values)) = schema.get("enum") {
+            nullable = nullable || values.iter().any(|v| v.is_null());
+            FieldKind::Select {
+                options: values
+                    .iter()
+                    .filter(|v| !v.is_null())
+                    .map(|v| SelectOption {
+                        label: value_label(v),
+                        value: v.clone(),
+                    })
+                    .collect(),
+            }
+        } else if let Some(variants) = schema
+            .get("oneOf")
+            .or_else(|| schema.get("anyOf"))
+            .and_then(|v| v.as_array())
+        {
+            let variants = variants
+                .iter()
+                .map(|v| self.flatten(v, depth + 1))
+                .collect::<Vec<_>>();
+            let (null_variants, variants): (Vec<_>, Vec<_>) = variants
+                .into_iter()
+                .partition(|v| v.get("type").and_then(|t| t.as_str()) == Some("null"));
+            nullable = nullable || !null_variants.is_empty();
+
+            self.choice(&schema, variants, name, &path, depth)
+        } else {
+            self.typed_kind(&schema, &types, &path, depth)
+        };
+
+        FormField {
+            name: name.to_string(),
+            path,
+            label,
+            description: schema
+                .get("description")
+                .and_then(|d| d.as_str())
+                .map(|d| d.to_string()),
+            required,
+            nullable,
+            secret: schema
+                .get("x-secret")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false),
+            default: schema.get("default").cloned(),
+            kind,
+        }
+    }
+
+    /// Handle a `oneOf` or `anyOf` with the null variants already removed.
+    fn choice(
+        &self,
+        schema: &Map<String, Value>,
+        variants: Vec<Map<String, Value>>,
+        name: &str,
+        path: &str,
+        depth: usize,
+    ) -> FieldKind {
+        if variants.is_empty() {
+            return FieldKind::Json;
+        }
+
+        // A list of constants is a labeled enum.
+        if variants.iter().all(|v| v.contains_key("const")) {
+            return FieldKind::Select {
+                options: variants
+                    .iter()
+                    .map(|v| {
+                        let value = v["const"].clone();
+                        SelectOption {
+                            label: v
+                                .get("title")
+                                .and_then(|t| t.as_str())
+                                .map(|t| t.to_string())
+                                .unwrap_or_else(|| value_label(&value)),
+                            value,
+                        }
+                    })
+                    .collect(),
+            };
+        }
+
+        // A single variant is just an optional value.
+        if variants.len() == 1 {
+            let variant = Value::Object(variants.into_iter().next().unwrap());
+            return self
+                .field(&variant, name, path.to_string(), false, depth + 1)
+                .kind;
+        }
+
+        let discriminator = schema
+            .get("discriminator")
+            .and_then(|d| d.get("propertyName"))
+            .and_then(|p| p.as_str())
+            .map(|p| p.to_string())
+            .or_else(|| self.find_discriminator(&variants, depth));
+
+        let variants = variants
+            .into_iter()
+            .enumerate()
+            .map(|(i, variant)| {
+                let value = discriminator
+                    .as_deref()
+                    .and_then(|d| self.property_constant(&variant, d, depth));
+                let label = variant
+                    .get("title")
+                    .and_then(|t| t.as_str())
+                    .map(|t| t.to_string())
+                    .or_else(|| value.as_ref().map(value_label))
+                    .unwrap_or_else(|| format!("Option {}", i + 1));
+
+                let variant = Value::Object(variant);
+                UnionVariant {
+                    label,
+                    value,
+                    field: self.field(&variant, name, path.to_string(), false, depth + 1),
+                }
+            })
+            .collect();
+
+        FieldKind::Union {
+            discriminator,
+            variants,
+        }
+    }
+
+    /// The constant value of a property in an object schema, if it has one.
+    fn property_constant(
+        &self,
+        schema: &Map<String, Value>,
+        property: &str,
+        depth: usize,
+    ) -> Option<Value> {
+        let property = schema.get("properties")?.get(property)?;
+        let property = self.flatten(property, depth + 1);
+        match (property.get("const"), property.get("enum")) {
+            (Some(value), _) => Some(value.clone()),
+            (None, Some(Value::Array(values))) if values.len() == 1 => Some(values[0].clone()),
+            _ => None,
+        }
+    }
+
+    /// Find a property that every variant sets to a different constant.
+    fn find_discriminator(&self, variants: &[Map<String, Value>], depth: usize) -> Option<String> {
+        let first = variants.first()?.get("properties")?.as_object()?;
+        first
+            .keys()
+            .find(|property| {
+                let mut values = Vec::with_capacity(variants.len());
+                for variant in variants {
+                    match self.property_constant(variant, property, depth) {
+                        Some(value) if !values.contains(&value) => values.push(value),
+                        _ => return false,
+                    }
+                }
+                true
+            })
+            .cloned()
+    }
+
+    fn typed_kind(
+        &self,
+        schema: &Map<String, Value>,
+        types: &[&str],
+        path: &str,
+        depth: usize,
+    ) -> FieldKind {
+        let object_like = schema.contains_key("properties");
+        let kind = match types {
+            [t] => *t,
+            [] if object_like => "object",
+            _ => return FieldKind::Json,
+        };
+
+        match kind {
+            "string" => FieldKind::Text {
+                format: string_value(schema, "format"),
+                min_length: schema.get("minLength").and_then(|v| v.as_u64()),
+                max_length: schema.get("maxLength").and_then(|v| v.as_u64()),
+                pattern: string_value(schema, "pattern"),
+            },
+            "integer" | "number" => FieldKind::Number {
+                integer: kind == "integer",
+                minimum: schema.get("minimum").and_then(|v| v.as_f64()),
+                maximum: schema.get("maximum").and_then(|v| v.as_f64()),
+                exclusive_minimum: schema.get("exclusiveMinimum").and_then(|v| v.as_f64()),
+                exclusive_maximum: schema.get("exclusiveMaximum").and_then(|v| v.as_f64()),
+                multiple_of: schema.get("multipleOf").and_then(|v| v.as_f64()),
+            },
+            "boolean" => FieldKind::Boolean,
+            "object" if object_like => {
+                let required = schema
+                    .get("required")
+                    .and_then(|r| r.as_array())
+                    .map(|r| r.iter().filter_map(|v| v.as_str()).collect::<Vec<_>>())
+                    .unwrap_or_default();
+
+                let fields = schema
+                    .get("properties")
+                    .and_then(|p| p.as_object())
+                    .map(|properties| {
+                        properties
+                            .iter()
+                            .map(|(name, property)| {
+                                self.field(
+                                    property,
+                                    name,
+                                    format!("{}/{}", path, escape_pointer_segment(name)),
+                                    required.contains(&name.as_str()),
+                                    depth + 1,
+                                )
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default();
+
+                FieldKind::Object { fields }
+            }
+            "array" => match schema.get("items") {
+                Some(items) if items.is_object() => FieldKind::List {
+                    item: Box::new(self.field(items, "", format!("{}/-", path), true, depth + 1)),
+                    min_items: schema.get("minItems").and_then(|v| v.as_u64()),
+                    max_items: schema.get("maxItems").and_then(|v| v.as_u64()),
+                    unique_items: schema
+                        .get("uniqueItems")
+                        .and_then(|v| v.as_bool())
+                        .unwrap_or(false),
+                },
+                // Tuples and untyped lists are better edited as JSON.
+                _ => FieldKind::Json,
+            },
+            _ => FieldKind::Json,
+        }
+    }
+}
+
+/// Add the keys from `other` that `schema` doesn't already have. Properties and required lists
+/// are combined.
+fn merge(schema: &mut Map<String, Value>, other: Map<String, Value>) {
+    for (key, value) in other {
+        match (key.as_str(), schema.get_mut(&key), value) {
+            ("properties", Some(Value::Object(existing)), Value::Object(properties)) => {
+                for (name, property) in properties {
+                    existing.entry(name).or_insert(property);
+                }
+            }
+            ("required", Some(Value::Array(existing)), Value::Array(required)) => {
+                for name in required {
+                    if !existing.contains(&name) {
+                        existing.push(name);
+                    }
+                }
+            }
+            (_, Some(_), _) => {}
+            (_, None, value) => {
+                schema.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Return the non-null types allowed by the schema, and whether it allows null.
+fn schema_types(schema: &Map<String, Value>) -> (Vec<&str>, bool) {
+    let mut types = match schema.get("type") {
+        Some(Value::String(t)) => vec![t.as_str()],
+        Some(Value::Array(t)) => t.iter().filter_map(|t| t.as_str()).collect(),
+        _ => Vec::new(),
+    };
+
+    let had_null = types.contains(&"null");
+    types.retain(|t| *t != "null");
+    let nullable = had_null
+        || schema
+            .get("nullable")
+            .and_then(|n| n.as_bool())
+            .unwrap_or(false);
+
+    (types, nullable)
+}
+
+fn string_value(schema: &Map<String, Value>, key: &str) -> Option<String> {
+    schema
+        .get(key)
+        .and_then(|v| v.as_str())
+        .map(|v| v.to_string())
+}
+
+fn value_label(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Turn a property name like `start_date` or `startDate` into "Start date".
+fn label_from_name(name: &str) -> String {
+    let mut label = String::with_capacity(name.len() + 4);
+    let mut prev_lower = false;
+    for c in name.chars() {
+        if c == '_' || c == '-' {
+            label.push(' ');
+            prev_lower = false;
+        } else if c.is_uppercase() && prev_lower {
+            label.push(' ');
+            label.extend(c.to_lowercase());
+            prev_lower = false;
+        } else {
+            prev_lower = c.is_lowercase() || c.is_numeric();
+            if label.is_empty() {
+                label.extend(c.to_uppercase());
+            } else {
+                label.push(c);
+            }
+        }
+    }
+
+    label
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn field<'a>(form: &'a FormField, name: &str) -> &'a FormField {
+        match &form.kind {
+            FieldKind::Object { fields } => fields
+                .iter()
+                .find(|f| f.name == name)
+                .unwrap_or_else(|| panic!("field {} not found", name)),
+            other => panic!("expected an object, saw {:?}", other),
+        }
+    }
+
+    #[test]
+    fn simple_fields() {
+        let schema = json!({
+            "type": "object",
+            "required": ["url"],
+            "properties": {
+                "url": { "type": "string", "format": "uri", "description": "The page to fetch" },
+                "retryCount": { "type": ["integer", "null"], "minimum": 0, "default": 3 },
+                "verbose": { "type": "boolean" },
+                "method": { "type": "string", "enum": ["GET", "POST"] },
+                "token": { "type": "string", "x-secret": true },
+                "tags": { "type": "array", "items": { "type": "string" }, "maxItems": 5 }
+            }
+        });
+
+        let form = payload_form(&schema);
+
+        let url = field(&form, "url");
+        assert_eq!(url.label, "Url");
+        assert_eq!(url.path, "/url");
+        assert!(url.required);
+        assert_eq!(url.description.as_deref(), Some("The page to fetch"));
+        assert_eq!(
+            url.kind,
+            FieldKind::Text {
+                format: Some("uri".to_string()),
+                min_length: None,
+                max_length: None,
+                pattern: None,
+            }
+        );
+
+        let retry = field(&form, "retryCount");
+        assert_eq!(retry.label, "Retry count");
+        assert!(!retry.required);
+        assert!(retry.nullable);
+        assert_eq!(retry.default, Some(json!(3)));
+        assert!(matches!(
+            retry.kind,
+            FieldKind::Number {
+                integer: true,
+                minimum: Some(m),
+                ..
+            } if m == 0.0
+        ));
+
+        assert_eq!(field(&form, "verbose").kind, FieldKind::Boolean);
+        assert!(field(&form, "token").secret);
+
+        match &field(&form, "method").kind {
+            FieldKind::Select { options } => {
+                assert_eq!(options.len(), 2);
+                assert_eq!(options[1].value, json!("POST"));
+            }
+            other => panic!("expected a select, saw {:?}", other),
+        }
+
+        match &field(&form, "tags").kind {
+            FieldKind::List {
+                item, max_items, ..
+            } => {
+                assert_eq!(*max_items, Some(5));
+                assert_eq!(item.path, "/tags/-");
+                assert!(matches!(item.kind, FieldKind::Text { .. }));
+            }
+            other => panic!("expected a list, saw {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refs_and_all_of() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "account": { "$ref": "#/definitions/account", "title": "Login" }
+            },
+            "definitions": {
+                "named": {
+                    "properties": { "name": { "type": "string" } },
+                    "required": ["name"]
+                },
+                "account": {
+                    "title": "Account",
+                    "type": "object",
+                    "allOf": [{ "$ref": "#/definitions/named" }],
+                    "properties": { "password": { "type": "string", "x-secret": true } }
+                }
+            }
+        });
+
+        let form = payload_form(&schema);
+        let account = field(&form, "account");
+        assert_eq!(account.label, "Login", "referring title takes precedence");
+
+        let name = field(account, "name");
+        assert!(name.required);
+        assert_eq!(name.path, "/account/name");
+        assert!(field(account, "password").secret);
+    }
+
+    #[test]
+    fn recursive_ref() {
+        let schema = json!({
+            "$ref": "#/definitions/node",
+            "definitions": {
+                "node": {
+                    "type": "object",
+                    "properties": { "child": { "$ref": "#/definitions/node" } }
+                }
+            }
+        });
+
+        // This just needs to finish.
+        let form = payload_form(&schema);
+        assert!(matches!(form.kind, FieldKind::Object { .. }));
+    }
+
+    #[test]
+    fn discriminated_union() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "target": {
+                    "oneOf": [
+                        {
+                            "type": "object",
+                            "properties": {
+                                "kind": { "const": "email" },
+                                "address": { "type": "string", "format": "email" }
+                            }
+                        },
+                        {
+                            "type": "object",
+                            "title": "Webhook",
+                            "properties": {
+                                "kind": { "enum": ["webhook"] },
+                                "url": { "type": "string" }
+                            }
+                        },
+                        { "type": "null" }
+                    ]
+                }
+            }
+        });
+
+        let form = payload_form(&schema);
+        let target = field(&form, "target");
+        assert!(target.nullable);
+
+        match &target.kind {
+            FieldKind::Union {
+                discriminator,
+                variants,
+            } => {
+                assert_eq!(discriminator.as_deref(), Some("kind"));
+                assert_eq!(variants.len(), 2);
+                assert_eq!(variants[0].label, "email");
+                assert_eq!(variants[0].value, Some(json!("email")));
+                assert_eq!(variants[1].label, "Webhook");
+                assert_eq!(
+                    field(&variants[0].field, "kind").kind,
+                    FieldKind::Constant {
+                        value: json!("email")
+                    }
+                );
+                assert_eq!(field(&variants[1].field, "url").path, "/target/url");
+            }
+            other => panic!("expected a union, saw {:?}", other),
+        }
+    }
+
+    #[test]
+    fn labeled_enum() {
+        let schema = json!({
+            "oneOf": [
+                { "const": 1, "title": "Low" },
+                { "const": 2, "title": "High" }
+            ]
+        });
+
+        assert_eq!(
+            payload_form(&schema).kind,
+            FieldKind::Select {
+                options: vec![
+                    SelectOption {
+                        label: "Low".to_string(),
+                        value: json!(1)
+                    },
+                    SelectOption {
+                        label: "High".to_string(),
+                        value: json!(2)
+                    },
+                ]
+            }
+        );
+    }
+}
@@ -1,12 +1,30 @@
 #[cfg(not(target_family = "wasm"))]
+pub mod buffered;
+pub mod chain;
+#[cfg(not(target_family = "wasm"))]
+pub mod chat;
+#[cfg(not(target_family = "wasm"))]
 pub mod dequeue;
 #[cfg(not(target_family = "wasm"))]
+pub mod email;
+#[cfg(not(target_family = "wasm"))]
+pub mod file_watch;
+pub mod form;
+#[cfg(not(target_family = "wasm"))]
+pub mod mqtt;
+#[cfg(not(target_family = "wasm"))]
 pub mod queue;
+pub mod routing;
+pub mod schema_inference;
+pub mod secrets;
+#[cfg(not(target_family = "wasm"))]
+pub mod sensitive;
 
 #[cfg(not(target_family = "wasm"))]
 pub use queue::{enqueue_input, EnqueueInputOptions};
 
-use crate::error::Error;
+use self::chain::InputChain;
+use crate::{error::Error, trace_context::TraceContext};
 use ergo_database::object_id::{
     InputCategoryId, InputId, PeriodicTriggerId, TaskId, TaskTriggerId, UserId,
 };
@@ -50,6 +68,87 @@ pub enum InputStatus {
     Pending,
     Success,
     Error,
+    /// The input matched a recent input on the same trigger and was not run.
+    Duplicate,
+    /// The input arrived while the task was disabled and was not run.
+    Dropped,
+    /// The input arrived while the task was disabled, and will be queued when the task is
+    /// enabled again.
+    Buffered,
+    /// The payload didn't match the trigger's schema.
+    Rejected,
+}
+
+/// What to do with inputs that arrive while a task is disabled.
+#[derive(Debug, Clone, Copy, Default, JsonSchema, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(not(target_family = "wasm"), derive(sqlx::Type))]
+#[cfg_attr(
+    not(target_family = "wasm"),
+    sqlx(type_name = "disabled_input_mode", rename_all = "lowercase")
+)]
+#[serde(rename_all = "lowercase")]
+pub enum DisabledInputMode {
+    /// Return an error to the sender.
+    #[default]
+    Reject,
+    /// Accept the input and record it in the log, but don't run it.
+    Drop,
+    /// Hold the input until the task is enabled again, and then run it.
+    Buffer,
+}
+
+/// Drop inputs on a trigger that have the same key as another input within a time window.
+#[derive(Clone, Debug, JsonSchema, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TriggerDedupeConfig {
+    /// JSON pointers (e.g. `/user/id`) to the payload fields that make up the key. If empty,
+    /// the entire payload is used.
+    #[serde(default)]
+    pub key: Vec<String>,
+    /// How long after an input arrives that a matching input is considered a duplicate.
+    pub window_seconds: u32,
+}
+
+impl TriggerDedupeConfig {
+    /// Build the dedupe key for a payload. Object keys are sorted so that the same payload
+    /// always produces the same key regardless of field order.
+    pub fn key(&self, payload: &serde_json::Value) -> String {
+        let value = if self.key.is_empty() {
+            canonicalize(payload)
+        } else {
+            serde_json::Value::Array(
+                self.key
+                    .iter()
+                    .map(|pointer| {
+                        payload
+                            .pointer(pointer)
+                            .map(canonicalize)
+                            .unwrap_or(serde_json::Value::Null)
+                    })
+                    .collect(),
+            )
+        };
+
+        value.to_string()
+    }
+}
+
+fn canonicalize(value: &serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(map) => {
+            let mut entries = map.iter().collect::<Vec<_>>();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            serde_json::Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (k.clone(), canonicalize(v)))
+                    .collect(),
+            )
+        }
+        serde_json::Value::Array(items) => {
+            serde_json::Value::Array(items.iter().map(canonicalize).collect())
+        }
+        _ => value.clone(),
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -61,6 +160,19 @@ pub struct InputInvocation {
     pub inputs_log_id: uuid::Uuid,
     pub payload: serde_json::Value,
     pub user_id: UserId,
+    /// The input was triggered by a user from the UI, so it and its actions should skip
+    /// ahead of batch work in the queues.
+    #[serde(default)]
+    pub interactive: bool,
+    /// The tasks that sent this input, if it came from another task.
+    #[serde(default)]
+    pub chain: InputChain,
+    #[serde(default)]
+    pub trace: TraceContext,
+    /// The payload was too large to put in the queue job, and should be loaded from the
+    /// inputs log instead.
+    #[serde(default)]
+    pub payload_in_log: bool,
 }
 
 pub fn validate_input_payload(
@@ -72,3 +184,37 @@ pub fn validate_input_payload(
     compiled_schema.validate(payload)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::TriggerDedupeConfig;
+    use serde_json::json;
+
+    #[test]
+    fn dedupe_key_whole_payload_ignores_field_order() {
+        let config = TriggerDedupeConfig {
+            key: vec![],
+            window_seconds: 60,
+        };
+
+        let a = json!({ "a": 1, "b": { "c": 2, "d": [1, 2] } });
+        let b = json!({ "b": { "d": [1, 2], "c": 2 }, "a": 1 });
+        assert_eq!(config.key(&a), config.key(&b));
+        assert_ne!(config.key(&a), config.key(&json!({ "a": 2 })));
+    }
+
+    #[test]
+    fn dedupe_key_from_pointers() {
+        let config = TriggerDedupeConfig {
+            key: vec!["/id".to_string(), "/missing".to_string()],
+            window_seconds: 60,
+        };
+
+        let a = json!({ "id": 5, "time": 1 });
+        let b = json!({ "id": 5, "time": 2 });
+        let c = json!({ "id": 6, "time": 1 });
+        assert_eq!(config.key(&a), config.key(&b));
+        assert_ne!(config.key(&a), config.key(&c));
+        assert_eq!(config.key(&a), "[5,null]");
+    }
+}
@@ -0,0 +1,402 @@
+//! The MQTT bridge keeps a connection open to each broker that has subscriptions, and sends the
+//! messages on the subscribed topics to the subscriptions' task triggers.
+//!
+//! Every server runs the bridge, so the topics are subscribed through a shared subscription
+//! group. The broker delivers each message to only one member of the group.
+
+use std::time::Duration;
+
+use ergo_database::{
+    encryption::decrypt_account_fields,
+    new_uuid,
+    object_id::{AccountId, InputId, MqttSubscriptionId, OrgId, TaskId, TaskTriggerId, UserId},
+    PostgresPool,
+};
+use ergo_graceful_shutdown::GracefulShutdownConsumer;
+use fxhash::FxHashMap;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, Publish, QoS, Transport};
+use serde::Deserialize;
+use tracing::{event, Level};
+
+use super::{chain::InputChain, enqueue_input, EnqueueInputOptions};
+use crate::Error;
+
+/// Connection settings for an MQTT broker, read from the fields of an account.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct MqttBroker {
+    pub host: String,
+    /// Defaults to 8883 with TLS and 1883 without.
+    pub port: Option<u16>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default)]
+    pub tls: bool,
+}
+
+impl MqttBroker {
+    pub fn options(&self, client_id: &str) -> MqttOptions {
+        let port = self.port.unwrap_or(if self.tls { 8883 } else { 1883 });
+        let mut options = MqttOptions::new(client_id, &self.host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+
+        if let Some(username) = self.username.as_ref() {
+            options.set_credentials(username, self.password.as_deref().unwrap_or_default());
+        }
+
+        if self.tls {
+            options.set_transport(Transport::tls_with_default_config());
+        }
+
+        options
+    }
+}
+
+/// A unique client ID for a connection from this process.
+pub fn client_id() -> String {
+    format!("ergo-{}", new_uuid().simple())
+}
+
+pub fn qos(level: i16) -> QoS {
+    match level {
+        0 => QoS::AtMostOnce,
+        2 => QoS::ExactlyOnce,
+        _ => QoS::AtLeastOnce,
+    }
+}
+
+/// Returns true if `topic` matches the MQTT topic `filter`, which may contain `+` to match a
+/// single level and a trailing `#` to match any number of levels.
+pub fn topic_matches(topic: &str, filter: &str) -> bool {
+    // Wildcards at the start of a filter don't match the broker's `$` topics.
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut topic_levels = topic.split('/');
+    for filter_level in filter.split('/') {
+        if filter_level == "#" {
+            return true;
+        }
+
+        match topic_levels.next() {
+            Some(level) if filter_level == "+" || filter_level == level => {}
+            _ => return false,
+        }
+    }
+
+    topic_levels.next().is_none()
+}
+
+/// Returns true if `filter` is a valid topic filter. `+` must take up an entire level, and `#`
+/// must be the entire last level.
+pub fn valid_topic_filter(filter: &str) -> bool {
+    if filter.is_empty() || filter.starts_with("$share/") {
+        return false;
+    }
+
+    let levels = filter.split('/').collect::<Vec<_>>();
+    levels.iter().enumerate().all(|(i, level)| {
+        let multi_level_ok = !level.contains('#') || (*level == "#" && i == levels.len() - 1);
+        let single_level_ok = !level.contains('+') || *level == "+";
+        multi_level_ok && single_level_ok
+    })
+}
+
+/// The input payload for a message. The message is parsed as JSON if possible, and otherwise
+/// used as a string.
+pub fn message_payload(publish: &Publish) -> serde_json::Value {
+    let payload = serde_json::from_slice(&publish.payload).unwrap_or_else(|_| {
+        serde_json::Value::String(String::from_utf8_lossy(&publish.payload).into_owned())
+    });
+
+    serde_json::json!({
+        "topic": publish.topic,
+        "payload": payload,
+        "retain": publish.retain,
+    })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MqttSubscriptionTarget {
+    pub mqtt_subscription_id: MqttSubscriptionId,
+    pub account_id: AccountId,
+    pub topic_filter: String,
+    pub qos: i16,
+    pub org_id: OrgId,
+    pub run_as_user: UserId,
+    pub task_id: TaskId,
+    pub task_name: String,
+    pub task_trigger_id: TaskTriggerId,
+    pub task_trigger_local_id: String,
+    pub task_trigger_name: String,
+    pub input_id: InputId,
+    pub payload_schema: serde_json::Value,
+}
+
+/// The subscriptions to a single broker.
+#[derive(Clone, Debug, PartialEq)]
+struct BrokerSubscriptions {
+    broker: MqttBroker,
+    targets: Vec<MqttSubscriptionTarget>,
+}
+
+async fn load_subscriptions(
+    pool: &PostgresPool,
+) -> Result<FxHashMap<AccountId, BrokerSubscriptions>, Error> {
+    let rows = sqlx::query!(
+        r##"SELECT
+            sub.mqtt_subscription_id AS "mqtt_subscription_id: MqttSubscriptionId",
+            sub.account_id AS "account_id: AccountId",
+            sub.topic_filter,
+            sub.qos,
+            sub.org_id AS "org_id: OrgId",
+            sub.run_as_user AS "run_as_user: UserId",
+            tasks.task_id AS "task_id: TaskId",
+            tasks.name AS task_name,
+            tt.task_trigger_id AS "task_trigger_id: TaskTriggerId",
+            tt.task_trigger_local_id,
+            tt.name AS task_trigger_name,
+            inputs.input_id AS "input_id: InputId",
+            inputs.payload_schema,
+            accounts.fields
+        FROM mqtt_subscriptions sub
+        JOIN accounts USING (account_id)
+        JOIN task_triggers tt USING (task_trigger_id)
+        JOIN tasks ON tasks.task_id = tt.task_id
+        JOIN inputs ON inputs.input_id = tt.input_id
+        WHERE sub.enabled AND tasks.enabled AND NOT tasks.deleted
+        ORDER BY sub.mqtt_subscription_id"##
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut brokers: FxHashMap<AccountId, BrokerSubscriptions> = FxHashMap::default();
+    for row in rows {
+        if !brokers.contains_key(&row.account_id) {
+            let broker = row
+                .fields
+                .map(|fields| {
+                    let fields = decrypt_account_fields(fields)?;
+                    serde_json::from_value::<MqttBroker>(fields).map_err(Error::from)
+                })
+                .transpose();
+
+            match broker {
+                Ok(Some(broker)) => {
+                    brokers.insert(
+                        row.account_id.clone(),
+                        BrokerSubscriptions {
+                            broker,
+                            targets: Vec::new(),
+                        },
+                    );
+                }
+                Ok(None) | Err(_) => {
+                    event!(Level::WARN, account_id=%row.account_id, "MQTT account has invalid connection settings");
+                    continue;
+                }
+            }
+        }
+
+        if let Some(subs) = brokers.get_mut(&row.account_id) {
+            subs.targets.push(MqttSubscriptionTarget {
+                mqtt_subscription_id: row.mqtt_subscription_id,
+                account_id: row.account_id,
+                topic_filter: row.topic_filter,
+                qos: row.qos,
+                org_id: row.org_id,
+                run_as_user: row.run_as_user,
+                task_id: row.task_id,
+                task_name: row.task_name,
+                task_trigger_id: row.task_trigger_id,
+                task_trigger_local_id: row.task_trigger_local_id,
+                task_trigger_name: row.task_trigger_name,
+                input_id: row.input_id,
+                payload_schema: row.payload_schema,
+            });
+        }
+    }
+
+    Ok(brokers)
+}
+
+async fn handle_message(
+    pool: &PostgresPool,
+    redis_key_prefix: Option<&str>,
+    targets: &[MqttSubscriptionTarget],
+    publish: &Publish,
+) {
+    let payload = message_payload(publish);
+
+    for target in targets
+        .iter()
+        .filter(|t| topic_matches(&publish.topic, &t.topic_filter))
+    {
+        let result = async {
+            let mut conn = pool.acquire().await?;
+            enqueue_input(EnqueueInputOptions {
+                pg: &mut conn,
+                notifications: None,
+                org_id: target.org_id.clone(),
+                user_id: target.run_as_user.clone(),
+                task_id: target.task_id.clone(),
+                task_name: target.task_name.clone(),
+                input_id: target.input_id.clone(),
+                task_trigger_id: target.task_trigger_id.clone(),
+                task_trigger_local_id: target.task_trigger_local_id.clone(),
+                task_trigger_name: target.task_trigger_name.clone(),
+                periodic_trigger_id: None,
+                payload_schema: &target.payload_schema,
+                payload: payload.clone(),
+                redis_key_prefix,
+                trigger_at: None,
+                replay_of: None,
+                interactive: false,
+                chain: InputChain::default(),
+            })
+            .await
+        }
+        .await;
+
+        if let Err(e) = result {
+            event!(Level::WARN, subscription=%target.mqtt_subscription_id, topic=%publish.topic, error=%e, "Failed to enqueue MQTT message");
+        }
+    }
+}
+
+async fn run_broker(
+    pool: PostgresPool,
+    redis_key_prefix: Option<String>,
+    share_group: String,
+    subs: BrokerSubscriptions,
+) {
+    let (client, mut eventloop) = AsyncClient::new(subs.broker.options(&client_id()), 64);
+
+    loop {
+        match eventloop.poll().await {
+            // The session isn't persisted, so subscribe again after every connect.
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                for target in &subs.targets {
+                    let filter = format!("$share/{}/{}", share_group, target.topic_filter);
+                    if let Err(e) = client.subscribe(filter, qos(target.qos)).await {
+                        event!(Level::ERROR, host=%subs.broker.host, error=%e, "Failed to subscribe to MQTT topic");
+                    }
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                handle_message(&pool, redis_key_prefix.as_deref(), &subs.targets, &publish).await;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                event!(Level::ERROR, host=%subs.broker.host, error=%e, "MQTT connection error");
+                // Polling again reconnects.
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+/// Start the MQTT bridge. The subscriptions are reloaded every `refresh_interval`, and the
+/// connection to a broker is restarted when its subscriptions change.
+pub fn start_mqtt_bridge(
+    mut shutdown: GracefulShutdownConsumer,
+    pool: PostgresPool,
+    redis_key_prefix: Option<String>,
+    share_group: String,
+    refresh_interval: Option<Duration>,
+) -> tokio::task::JoinHandle<()> {
+    let refresh_interval = refresh_interval.unwrap_or_else(|| Duration::from_secs(60));
+    tokio::spawn(async move {
+        let mut running: FxHashMap<AccountId, (BrokerSubscriptions, tokio::task::JoinHandle<()>)> =
+            FxHashMap::default();
+
+        loop {
+            match load_subscriptions(&pool).await {
+                Ok(mut brokers) => {
+                    running.retain(|account_id, (subs, handle)| {
+                        let unchanged = brokers.get(account_id) == Some(subs);
+                        if unchanged {
+                            brokers.remove(account_id);
+                        } else {
+                            handle.abort();
+                        }
+                        unchanged
+                    });
+
+                    for (account_id, subs) in brokers {
+                        event!(Level::INFO, %account_id, host=%subs.broker.host, subscriptions=subs.targets.len(), "Connecting to MQTT broker");
+                        let handle = tokio::spawn(run_broker(
+                            pool.clone(),
+                            redis_key_prefix.clone(),
+                            share_group.clone(),
+                            subs.clone(),
+                        ));
+                        running.insert(account_id, (subs, handle));
+                    }
+                }
+                Err(e) => event!(Level::ERROR, error=%e, "Failed to load MQTT subscriptions"),
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(refresh_interval) => continue,
+                _ = shutdown.wait_for_shutdown() => break,
+            }
+        }
+
+        for (_, (_, handle)) in running {
+            handle.abort();
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching_topics() {
+        assert!(topic_matches("home/kitchen/temp", "home/kitchen/temp"));
+        assert!(topic_matches("home/kitchen/temp", "home/+/temp"));
+        assert!(topic_matches("home/kitchen/temp", "home/#"));
+        assert!(topic_matches("home", "home/#"));
+        assert!(topic_matches("home/kitchen/temp", "#"));
+
+        assert!(!topic_matches("home/kitchen/temp", "home/+"));
+        assert!(!topic_matches("home/kitchen", "home/kitchen/temp"));
+        assert!(!topic_matches("home/kitchen/temp", "home/+/humidity"));
+        assert!(!topic_matches("$SYS/uptime", "#"));
+        assert!(topic_matches("$SYS/uptime", "$SYS/#"));
+    }
+
+    #[test]
+    fn topic_filter_validation() {
+        assert!(valid_topic_filter("home/+/temp"));
+        assert!(valid_topic_filter("home/#"));
+        assert!(valid_topic_filter("#"));
+
+        assert!(!valid_topic_filter(""));
+        assert!(!valid_topic_filter("home/#/temp"));
+        assert!(!valid_topic_filter("home/kit+chen"));
+        assert!(!valid_topic_filter("home#"));
+        assert!(!valid_topic_filter("$share/group/home"));
+    }
+
+    #[test]
+    fn json_payload() {
+        let publish = Publish::new("sensors/1", QoS::AtLeastOnce, r#"{"value": 21.5}"#);
+        assert_eq!(
+            message_payload(&publish),
+            serde_json::json!({
+                "topic": "sensors/1",
+                "payload": { "value": 21.5 },
+                "retain": false,
+            })
+        );
+    }
+
+    #[test]
+    fn string_payload() {
+        let publish = Publish::new("lights/porch", QoS::AtLeastOnce, "ON");
+        assert_eq!(message_payload(&publish)["payload"], "ON");
+    }
+}
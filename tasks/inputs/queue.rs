@@ -1,12 +1,24 @@
 use std::{borrow::Cow, ops::Deref};
 
-use crate::{error::Error, inputs::InputInvocation};
+use crate::{
+    error::Error,
+    inputs::{
+        buffered::{buffer_input, disabled_input_mode},
+        chain::InputChain,
+        sensitive, DisabledInputMode, InputInvocation, InputStatus, TriggerDedupeConfig,
+    },
+    payload_limits::{PayloadKind, PAYLOAD_LIMITS},
+    quotas::{self, QuotaKind},
+    trace_context::TraceContext,
+};
 
 use chrono::{DateTime, Utc};
 use ergo_database::{new_uuid, object_id::*, RedisPool};
 use ergo_notifications::{Notification, NotificationManager, NotifyEvent};
-use ergo_queues::{generic_stage::QueueJob, Queue};
+use ergo_problem::FieldError;
+use ergo_queues::{background_fairness_key, generic_stage::QueueJob, Queue};
 use sqlx::{Connection, PgConnection};
+use tracing::{event, Level};
 use uuid::Uuid;
 
 use super::validate_input_payload;
@@ -44,6 +56,15 @@ impl InputQueue {
     }
 }
 
+/// Inputs take turns by org. Periodic inputs go in the background lane, so that a lot of
+/// periodic triggers firing at once doesn't hold up the org's other inputs.
+pub(crate) fn input_fairness_key(org_id: &OrgId, invocation: &InputInvocation) -> String {
+    match invocation.periodic_trigger_id {
+        Some(_) => background_fairness_key(&org_id.to_string()),
+        None => org_id.to_string(),
+    }
+}
+
 pub struct EnqueueInputOptions<'a> {
     pub pg: &'a mut PgConnection,
     pub notifications: Option<NotificationManager>,
@@ -60,8 +81,122 @@ pub struct EnqueueInputOptions<'a> {
     pub payload: serde_json::Value,
     pub redis_key_prefix: Option<&'a str>,
     pub trigger_at: Option<DateTime<Utc>>,
+    /// The `inputs_log_id` of the input that this one replays.
+    pub replay_of: Option<Uuid>,
+    /// The input was triggered by a user waiting on the result, so it runs in the queue's
+    /// priority lane.
+    pub interactive: bool,
+    /// The tasks that sent this input, if it came from another task.
+    pub chain: InputChain,
 }
 
+struct DedupeCheck {
+    key: String,
+    duplicate_of: Option<Uuid>,
+}
+
+/// If the trigger has a dedupe configuration, calculate the payload's key and look for an
+/// earlier input with the same key inside the window. This takes a transaction-level advisory
+/// lock on the key so that two identical inputs arriving together can't both miss each other.
+async fn check_duplicate(
+    tx: &mut PgConnection,
+    task_trigger_id: &TaskTriggerId,
+    payload: &serde_json::Value,
+) -> Result<Option<DedupeCheck>, Error> {
+    let config = sqlx::query_scalar!(
+        r##"SELECT dedupe AS "dedupe: sqlx::types::Json<TriggerDedupeConfig>"
+        FROM task_triggers WHERE task_trigger_id=$1"##,
+        task_trigger_id.0
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .flatten();
+
+    let config = match config {
+        Some(c) => c.0,
+        None => return Ok(None),
+    };
+
+    let key = config.key(payload);
+
+    sqlx::query!(
+        "SELECT pg_advisory_xact_lock(hashtextextended($1::text || md5($2), 0))",
+        task_trigger_id.0.to_string(),
+        &key
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let duplicate_of = sqlx::query_scalar!(
+        r##"SELECT inputs_log_id FROM inputs_log
+        WHERE task_trigger_id=$1 AND dedupe_key=md5($2) AND status <> 'duplicate'
+            AND created > now() - ($3::int * interval '1 second')
+        ORDER BY created DESC
+        LIMIT 1"##,
+        task_trigger_id.0,
+        &key,
+        config.window_seconds as i32
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    Ok(Some(DedupeCheck { key, duplicate_of }))
+}
+
+async fn trigger_is_sensitive(
+    pg: &mut PgConnection,
+    task_trigger_id: &TaskTriggerId,
+) -> Result<bool, Error> {
+    let sensitive = sqlx::query_scalar!(
+        "SELECT sensitive FROM task_triggers WHERE task_trigger_id=$1",
+        task_trigger_id.0
+    )
+    .fetch_optional(pg)
+    .await?;
+
+    Ok(sensitive.unwrap_or(false))
+}
+
+/// Record an input that failed its trigger's payload schema, so that changes in what the sender
+/// is sending show up in the log instead of only as errors returned to the sender.
+async fn record_rejected_input(
+    tx: &mut PgConnection,
+    task_id: &TaskId,
+    task_trigger_id: &TaskTriggerId,
+    task_trigger_local_id: &str,
+    payload: &serde_json::Value,
+    errors: &[FieldError],
+) -> Result<(), Error> {
+    sqlx::query!(
+        r##"INSERT INTO inputs_log
+        (inputs_log_id, task_trigger_id, task_id, task_trigger_local_id, status, payload,
+            queue_job_id, info)
+        VALUES
+        ($1, $2, $3, $4, 'rejected', $5, '', jsonb_build_object('errors', $6::jsonb))"##,
+        new_uuid(),
+        task_trigger_id.0,
+        task_id.0,
+        task_trigger_local_id,
+        payload,
+        serde_json::to_value(errors)?
+    )
+    .execute(tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Add an input to the queue. If the input duplicates a recent input according to the
+/// trigger's dedupe configuration, it is recorded with a `duplicate` status instead of being
+/// queued. If the task is disabled, the input is rejected with [Error::TaskDisabled], dropped,
+/// or buffered, depending on the task's [DisabledInputMode]. Unless the input was rejected, the
+/// returned ID is the new inputs_log entry.
+///
+/// A payload that doesn't match the trigger's schema returns the validation errors, and is
+/// recorded with a `rejected` status.
+///
+/// If the trigger is sensitive, the payload is encrypted before it is written anywhere, and
+/// notifications about the input don't include it.
 pub async fn enqueue_input(options: EnqueueInputOptions<'_>) -> Result<Uuid, Error> {
     let EnqueueInputOptions {
         pg,
@@ -79,9 +214,39 @@ pub async fn enqueue_input(options: EnqueueInputOptions<'_>) -> Result<Uuid, Err
         payload,
         redis_key_prefix,
         trigger_at,
+        replay_of,
+        interactive,
+        chain,
     } = options;
 
-    validate_input_payload(&input_id, payload_schema, &payload)?;
+    let payload_in_log = PAYLOAD_LIMITS.check(PayloadKind::Input, &payload)?;
+
+    // The plaintext payload is still used for validation and the dedupe key, but only the
+    // encrypted payload is stored.
+    let encrypted_payload = if trigger_is_sensitive(&mut *pg, &task_trigger_id).await? {
+        Some(sensitive::encrypt_payload(&payload).await?)
+    } else {
+        None
+    };
+
+    if let Err(e) = validate_input_payload(&input_id, payload_schema, &payload) {
+        if let Error::JsonSchemaValidationError(errors) = &e {
+            let recorded = record_rejected_input(
+                &mut *pg,
+                &task_id,
+                &task_trigger_id,
+                &task_trigger_local_id,
+                encrypted_payload.as_ref().unwrap_or(&payload),
+                errors,
+            )
+            .await;
+            if let Err(record_err) = recorded {
+                event!(Level::WARN, error=%record_err, %task_trigger_id, "Failed to record rejected input");
+            }
+        }
+
+        return Err(e);
+    }
 
     let input_arrival_id = new_uuid();
     let queue_name = InputQueue::queue_name(redis_key_prefix);
@@ -93,16 +258,95 @@ pub async fn enqueue_input(options: EnqueueInputOptions<'_>) -> Result<Uuid, Err
         let user_id = user_id.clone();
 
         Box::pin(async move {
+            let stored_payload = encrypted_payload.as_ref().unwrap_or(&payload);
+            let disabled_mode = disabled_input_mode(&mut *tx, &task_id).await?;
+            match disabled_mode {
+                Some(DisabledInputMode::Reject) => return Err(Error::TaskDisabled),
+                Some(DisabledInputMode::Drop) => {
+                    sqlx::query!(
+                        r##"INSERT INTO inputs_log
+                (inputs_log_id, task_trigger_id, task_id, task_trigger_local_id, status, payload,
+                    queue_job_id, info)
+                VALUES
+                ($1, $2, $3, $4, 'dropped', $5, '', jsonb_build_object('reason', 'task_disabled'))"##,
+                        input_arrival_id,
+                        task_trigger_id.0,
+                        task_id.0,
+                        task_trigger_local_id,
+                        stored_payload
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+
+                    return Ok(());
+                }
+                Some(DisabledInputMode::Buffer) | None => {}
+            }
+
+            // Periodic triggers are expected to send the same payload every time, and a replay
+            // is expected to match the original.
+            let dedupe = match (&periodic_trigger_id, &replay_of) {
+                (None, None) => check_duplicate(&mut *tx, &task_trigger_id, &payload).await?,
+                _ => None,
+            };
+
+            if let Some(DedupeCheck {
+                key,
+                duplicate_of: Some(duplicate_of),
+            }) = &dedupe
+            {
+                sqlx::query!(
+                    r##"INSERT INTO inputs_log
+            (inputs_log_id, task_trigger_id, task_id, task_trigger_local_id, status, payload,
+                queue_job_id, info, dedupe_key)
+            VALUES
+            ($1, $2, $3, $4, 'duplicate', $5, '', jsonb_build_object('duplicate_of', $6::uuid), md5($7))"##,
+                    input_arrival_id,
+                    task_trigger_id.0,
+                    task_id.0,
+                    task_trigger_local_id,
+                    stored_payload,
+                    duplicate_of,
+                    key
+                )
+                .execute(&mut *tx)
+                .await?;
+
+                return Ok::<(), Error>(());
+            }
+
+            // Periodic inputs count toward the quota but aren't rejected, since that would stop
+            // the trigger from being rescheduled.
+            match periodic_trigger_id {
+                Some(_) => {
+                    quotas::add_usage(&mut *tx, &org_id, QuotaKind::InputsPerDay, 1).await?;
+                }
+                None => {
+                    quotas::consume_daily_quota(&mut *tx, &org_id, QuotaKind::InputsPerDay, 1)
+                        .await?;
+                }
+            }
+
+            // A large payload is only stored in the inputs log, and the worker loads it from there.
             let invocation = InputInvocation {
                 task_trigger_id: task_trigger_id.clone(),
                 periodic_trigger_id: periodic_trigger_id.clone(),
-                payload: payload.clone(),
+                payload: if payload_in_log {
+                    serde_json::Value::Null
+                } else {
+                    stored_payload.clone()
+                },
                 task_id: task_id.clone(),
                 input_id,
                 inputs_log_id: input_arrival_id,
                 user_id,
+                interactive,
+                chain: chain.clone(),
+                trace: TraceContext::current(),
+                payload_in_log,
             };
 
+            let org_key = input_fairness_key(&org_id, &invocation);
             let job = QueueJob {
                 queue: queue_name.as_ref(),
                 payload: &invocation,
@@ -111,26 +355,45 @@ pub async fn enqueue_input(options: EnqueueInputOptions<'_>) -> Result<Uuid, Err
                 timeout: None,
                 max_retries: None,
                 retry_backoff: None,
+                high_priority: interactive,
+                fairness_key: Some(org_key.as_str()),
             };
 
-            let job_id = job.enqueue(&mut *tx).await?;
+            let buffered = disabled_mode == Some(DisabledInputMode::Buffer);
+            let (status, job_id) = if buffered {
+                (InputStatus::Buffered, String::new())
+            } else {
+                (InputStatus::Pending, job.enqueue(&mut *tx).await?)
+            };
 
             sqlx::query!(
                 r##"INSERT INTO inputs_log
-        (inputs_log_id, task_trigger_id, task_id, task_trigger_local_id, status, payload, queue_job_id, periodic_trigger_id)
+        (inputs_log_id, task_trigger_id, task_id, task_trigger_local_id, status, payload, queue_job_id, periodic_trigger_id, dedupe_key, replay_of, interactive,
+            source_inputs_log_id, chain_depth, source_actions_log_id)
         VALUES
-        ($1, $2, $3, $4, 'pending', $5, $6, $7)"##,
+        ($1, $2, $3, $4, $13, $5, $6, $7, md5($8), $9, $10, $11, $12, $14)"##,
                 input_arrival_id,
                 task_trigger_id.0,
                 task_id.0,
                 task_trigger_local_id,
-                payload,
+                stored_payload,
                 job_id,
-                periodic_trigger_id.as_ref().map(|p| p.0)
+                periodic_trigger_id.as_ref().map(|p| p.0),
+                dedupe.as_ref().map(|d| d.key.as_str()) as _,
+                replay_of,
+                interactive,
+                chain.source_inputs_log_id,
+                chain.depth() as i32,
+                status as _,
+                chain.source_actions_log_id
             )
             .execute(&mut *tx)
             .await?;
 
+            if buffered {
+                buffer_input(&mut *tx, &org_id, &invocation).await?;
+            }
+
             if let Some(notify) = notifications {
                 let notification = Notification {
                     task_id,
@@ -141,7 +404,8 @@ pub async fn enqueue_input(options: EnqueueInputOptions<'_>) -> Result<Uuid, Err
                     event: NotifyEvent::InputArrived,
                     task_name,
                     log_id: Some(input_arrival_id),
-                    payload: Some(payload),
+                    suppressed_count: None,
+                    payload: encrypted_payload.is_none().then_some(payload),
                 };
                 notify.notify(&mut *tx, &org_id, notification).await?;
             }
@@ -0,0 +1,156 @@
+//! Routing rules send an input that arrives at one trigger to a trigger of another task,
+//! depending on the payload. This lets a single webhook URL handle events for several tasks
+//! without a task whose only job is to forward them.
+//!
+//! The rules are checked in order against the payload as it was received, and the input goes to
+//! the target of the first rule that matches. If no rule matches, the input goes to the trigger
+//! that received it, as usual. Routing only happens once, so the target trigger's own rules are
+//! not checked.
+//!
+//! ```json
+//! [
+//!   {
+//!     "condition": { "path": "$.event", "op": "eq", "value": "invoice.paid" },
+//!     "target": { "task": "billing", "trigger": "paid" }
+//!   },
+//!   {
+//!     "condition": { "path": "$.event", "op": "in", "value": ["refund.created", "refund.updated"] },
+//!     "target": { "task": "refunds", "trigger": "refund" }
+//!   }
+//! ]
+//! ```
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::conditions::Condition;
+
+#[derive(Clone, Debug, JsonSchema, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TriggerRoute {
+    /// The condition to check against the payload. Conditions can only look at the payload,
+    /// since the input hasn't reached a task yet.
+    pub condition: Condition,
+    pub target: TriggerRouteTarget,
+}
+
+#[derive(Clone, Debug, JsonSchema, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TriggerRouteTarget {
+    /// The ID or alias of the task to send the input to. Defaults to the task that owns the
+    /// trigger with the rule.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub task: Option<String>,
+    /// The local ID of the trigger in the target task.
+    pub trigger: String,
+}
+
+/// Find the target of the first rule that matches the payload.
+pub fn select_route<'a>(
+    routes: &'a [TriggerRoute],
+    payload: &Value,
+) -> Option<&'a TriggerRouteTarget> {
+    routes
+        .iter()
+        .find(|route| route.condition.evaluate(&Value::Null, Some(payload)))
+        .map(|route| &route.target)
+}
+
+/// Check a trigger's routing rules, using the payload schema of the trigger's input if there is
+/// one.
+pub fn validate_routes(
+    trigger_local_id: &str,
+    routes: &[TriggerRoute],
+    payload_schema: Option<&Value>,
+) -> Vec<String> {
+    let mut errors = Vec::new();
+    for (i, route) in routes.iter().enumerate() {
+        let prefix = format!("Trigger {} route {}", trigger_local_id, i + 1);
+        if route.condition.uses_context() {
+            errors.push(format!(
+                "{}: Routing conditions can only check the payload",
+                prefix
+            ));
+        }
+
+        if route.target.task.is_none() && route.target.trigger == trigger_local_id {
+            errors.push(format!("{}: A trigger can not route to itself", prefix));
+        }
+
+        errors.extend(
+            route
+                .condition
+                .validate(payload_schema)
+                .into_iter()
+                .map(|e| format!("{}: {}", prefix, e)),
+        );
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn routes() -> Vec<TriggerRoute> {
+        serde_json::from_value(json!([
+            {
+                "condition": { "path": "$.event", "op": "eq", "value": "paid" },
+                "target": { "task": "billing", "trigger": "paid" }
+            },
+            {
+                "condition": { "path": "$.amount", "op": "gte", "value": 100 },
+                "target": { "trigger": "large" }
+            },
+            {
+                "condition": { "path": "$.event", "op": "exists" },
+                "target": { "task": "catch-all", "trigger": "any" }
+            }
+        ]))
+        .unwrap()
+    }
+
+    #[test]
+    fn first_matching_route_wins() {
+        let routes = routes();
+
+        let target = select_route(&routes, &json!({ "event": "paid", "amount": 500 }))
+            .expect("payload should match");
+        assert_eq!(target.task.as_deref(), Some("billing"));
+        assert_eq!(target.trigger, "paid");
+
+        let target = select_route(&routes, &json!({ "event": "refund", "amount": 500 }))
+            .expect("payload should match");
+        assert_eq!(target.task, None);
+        assert_eq!(target.trigger, "large");
+
+        let target = select_route(&routes, &json!({ "event": "refund", "amount": 5 }))
+            .expect("payload should match");
+        assert_eq!(target.task.as_deref(), Some("catch-all"));
+
+        assert!(select_route(&routes, &json!({ "amount": 5 })).is_none());
+    }
+
+    #[test]
+    fn validation() {
+        assert!(validate_routes("incoming", &routes(), None).is_empty());
+
+        let bad: Vec<TriggerRoute> = serde_json::from_value(json!([
+            {
+                "condition": { "path": "$.seen", "source": "context", "op": "exists" },
+                "target": { "trigger": "other" }
+            },
+            {
+                "condition": { "path": "$.event", "op": "exists" },
+                "target": { "trigger": "incoming" }
+            }
+        ]))
+        .unwrap();
+        let errors = validate_routes("incoming", &bad, None);
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors[0].starts_with("Trigger incoming route 1:"));
+        assert!(errors[1].contains("itself"));
+    }
+}
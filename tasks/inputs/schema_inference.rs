@@ -0,0 +1,223 @@
+//! Infer a JSON schema from sample payloads, as a starting point for an input's payload schema.
+//!
+//! Each sample is merged into a shape that records every type seen at each location. A location
+//! that held more than one type gets a type union, and an object property that was missing
+//! from some of the samples is left out of `required`. Strings that were all RFC 3339
+//! timestamps get the `date-time` format.
+
+use std::collections::BTreeMap;
+
+use serde_json::{json, Map, Value};
+
+#[derive(Debug, Default)]
+struct Shape {
+    null: bool,
+    boolean: bool,
+    integer: bool,
+    number: bool,
+    string: Option<StringShape>,
+    array: Option<Box<Shape>>,
+    object: Option<ObjectShape>,
+}
+
+#[derive(Debug)]
+struct StringShape {
+    all_date_time: bool,
+}
+
+#[derive(Debug, Default)]
+struct ObjectShape {
+    /// The number of object samples merged in.
+    count: usize,
+    /// Each property's shape, and the number of samples that had it.
+    properties: BTreeMap<String, (Shape, usize)>,
+}
+
+impl Shape {
+    fn add(&mut self, value: &Value) {
+        match value {
+            Value::Null => self.null = true,
+            Value::Bool(_) => self.boolean = true,
+            Value::Number(n) => {
+                if n.is_f64() {
+                    self.number = true;
+                } else {
+                    self.integer = true;
+                }
+            }
+            Value::String(s) => {
+                let date_time = chrono::DateTime::parse_from_rfc3339(s).is_ok();
+                let shape = self.string.get_or_insert(StringShape {
+                    all_date_time: true,
+                });
+                shape.all_date_time &= date_time;
+            }
+            Value::Array(items) => {
+                let shape = self.array.get_or_insert_with(Default::default);
+                for item in items {
+                    shape.add(item);
+                }
+            }
+            Value::Object(fields) => {
+                let shape = self.object.get_or_insert_with(Default::default);
+                shape.count += 1;
+                for (name, value) in fields {
+                    let (property, seen) = shape
+                        .properties
+                        .entry(name.clone())
+                        .or_insert_with(|| (Shape::default(), 0));
+                    property.add(value);
+                    *seen += 1;
+                }
+            }
+        }
+    }
+
+    fn to_schema(&self) -> Value {
+        let mut types = Vec::new();
+        let mut schema = Map::new();
+
+        if let Some(object) = &self.object {
+            types.push("object");
+
+            let mut properties = Map::new();
+            let mut required = Vec::new();
+            for (name, (shape, seen)) in &object.properties {
+                properties.insert(name.clone(), shape.to_schema());
+                if *seen == object.count {
+                    required.push(Value::String(name.clone()));
+                }
+            }
+
+            schema.insert("properties".to_string(), Value::Object(properties));
+            if !required.is_empty() {
+                schema.insert("required".to_string(), Value::Array(required));
+            }
+        }
+
+        if let Some(items) = &self.array {
+            types.push("array");
+            schema.insert("items".to_string(), items.to_schema());
+        }
+
+        if let Some(string) = &self.string {
+            types.push("string");
+            if string.all_date_time {
+                schema.insert("format".to_string(), json!("date-time"));
+            }
+        }
+
+        // Integers are also numbers, so only list one of them.
+        if self.number {
+            types.push("number");
+        } else if self.integer {
+            types.push("integer");
+        }
+
+        if self.boolean {
+            types.push("boolean");
+        }
+
+        if self.null {
+            types.push("null");
+        }
+
+        match types.as_slice() {
+            // No samples, or only empty arrays, so anything is allowed.
+            [] => {}
+            [t] => {
+                schema.insert("type".to_string(), json!(t));
+            }
+            _ => {
+                schema.insert("type".to_string(), json!(types));
+            }
+        }
+
+        Value::Object(schema)
+    }
+}
+
+/// Infer a schema that all of the sample payloads match.
+pub fn infer_schema<'a>(samples: impl IntoIterator<Item = &'a Value>) -> Value {
+    let mut shape = Shape::default();
+    for sample in samples {
+        shape.add(sample);
+    }
+
+    let mut schema = match shape.to_schema() {
+        Value::Object(o) => o,
+        _ => unreachable!(),
+    };
+    schema.insert(
+        "$schema".to_string(),
+        json!("http://json-schema.org/draft-07/schema#"),
+    );
+    Value::Object(schema)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::infer_schema;
+
+    #[test]
+    fn unions_and_optional_properties() {
+        let samples = [
+            json!({ "id": 1, "name": "a", "tags": ["x"], "sent": "2023-01-05T10:00:00Z" }),
+            json!({ "id": 2.5, "name": null, "tags": [], "sent": "2023-01-06T10:00:00Z" }),
+            json!({ "id": 3, "name": "c", "extra": { "ok": true } }),
+        ];
+
+        let schema = infer_schema(samples.iter());
+        assert_eq!(
+            schema,
+            json!({
+                "$schema": "http://json-schema.org/draft-07/schema#",
+                "type": "object",
+                "properties": {
+                    "extra": {
+                        "type": "object",
+                        "properties": { "ok": { "type": "boolean" } },
+                        "required": ["ok"],
+                    },
+                    "id": { "type": "number" },
+                    "name": { "type": ["string", "null"] },
+                    "sent": { "type": "string", "format": "date-time" },
+                    "tags": { "type": "array", "items": { "type": "string" } },
+                },
+                "required": ["id", "name"],
+            })
+        );
+    }
+
+    #[test]
+    fn mixed_root_types() {
+        let samples = [json!({ "a": 1 }), json!("text"), json!(5)];
+        let schema = infer_schema(samples.iter());
+        assert_eq!(schema["type"], json!(["object", "string", "integer"]));
+        assert_eq!(schema["required"], json!(["a"]));
+    }
+
+    #[test]
+    fn no_samples() {
+        let schema = infer_schema([].iter());
+        assert_eq!(
+            schema,
+            json!({ "$schema": "http://json-schema.org/draft-07/schema#" })
+        );
+    }
+
+    #[test]
+    fn samples_match_inferred_schema() {
+        let samples = [
+            json!({ "a": [1, "two", null], "b": { "c": 1 } }),
+            json!({ "a": [], "b": null }),
+        ];
+        let schema = infer_schema(samples.iter());
+        let compiled = jsonschema::JSONSchema::compile(&schema).unwrap();
+        for sample in &samples {
+            assert!(compiled.is_valid(sample));
+        }
+    }
+}
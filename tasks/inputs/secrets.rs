@@ -0,0 +1,267 @@
+//! Input payload schemas can mark fields as secret with `"x-secret": true`. Secret values are
+//! stored and passed to actions as usual, but are masked whenever a payload is returned from a
+//! read API.
+
+use serde_json::Value;
+
+/// The value that replaces secret fields in API responses.
+pub const MASKED_VALUE: &str = "********";
+
+/// Stop following `$ref`s past this depth, to avoid looping on recursive schemas.
+const MAX_DEPTH: usize = 32;
+
+fn is_secret(schema: &Value) -> bool {
+    schema
+        .get("x-secret")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false)
+}
+
+/// Resolve a local reference such as `#/definitions/token` against the root schema.
+fn resolve_ref<'a>(root: &'a Value, schema: &'a Value) -> Option<&'a Value> {
+    let reference = schema.get("$ref")?.as_str()?;
+    let pointer = reference.strip_prefix('#')?;
+    root.pointer(pointer)
+}
+
+pub(super) fn escape_pointer_segment(s: &str) -> String {
+    s.replace('~', "~0").replace('/', "~1")
+}
+
+/// Call `f` with each subschema of `schema` that applies to `payload`, along with the JSON
+/// pointer to the value within the root payload.
+fn walk<'a, F>(
+    root: &'a Value,
+    schema: &'a Value,
+    payload: &mut Value,
+    path: &str,
+    depth: usize,
+    f: &mut F,
+) where
+    F: FnMut(&'a Value, &mut Value, &str),
+{
+    if depth > MAX_DEPTH || !schema.is_object() {
+        return;
+    }
+
+    f(schema, payload, path);
+
+    if let Some(target) = resolve_ref(root, schema) {
+        walk(root, target, payload, path, depth + 1, f);
+    }
+
+    for key in ["allOf", "anyOf", "oneOf"] {
+        if let Some(subschemas) = schema.get(key).and_then(|s| s.as_array()) {
+            for subschema in subschemas {
+                walk(root, subschema, payload, path, depth + 1, f);
+            }
+        }
+    }
+
+    match payload {
+        Value::Object(map) => {
+            let properties = schema.get("properties").and_then(|p| p.as_object());
+            let additional = schema.get("additionalProperties");
+            for (key, value) in map.iter_mut() {
+                let child_schema = properties.and_then(|p| p.get(key)).or(additional);
+                if let Some(child_schema) = child_schema {
+                    let child_path = format!("{}/{}", path, escape_pointer_segment(key));
+                    walk(root, child_schema, value, &child_path, depth + 1, f);
+                }
+            }
+        }
+        Value::Array(items) => {
+            let item_schemas = schema.get("items");
+            for (i, item) in items.iter_mut().enumerate() {
+                let item_schema = match item_schemas {
+                    Some(Value::Array(tuple)) => tuple.get(i),
+                    other => other,
+                };
+
+                if let Some(item_schema) = item_schema {
+                    walk(
+                        root,
+                        item_schema,
+                        item,
+                        &format!("{}/{}", path, i),
+                        depth + 1,
+                        f,
+                    );
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Replace every value in `payload` that `schema` marks as secret with [MASKED_VALUE].
+pub fn mask_secrets(schema: &Value, payload: &mut Value) {
+    walk(
+        schema,
+        schema,
+        payload,
+        "",
+        0,
+        &mut |subschema, value, _| {
+            if is_secret(subschema) && !value.is_null() {
+                *value = Value::String(MASKED_VALUE.to_string());
+            }
+        },
+    );
+}
+
+/// Return a copy of `payload` with its secret values masked.
+pub fn masked(schema: &Value, payload: &Value) -> Value {
+    let mut payload = payload.clone();
+    mask_secrets(schema, &mut payload);
+    payload
+}
+
+/// Collect the string values in `payload` that `schema` marks as secret, for registering with
+/// [ergo_database::redaction].
+pub fn secret_values(schema: &Value, payload: &Value) -> Vec<String> {
+    let mut payload = payload.clone();
+    let mut values = Vec::new();
+    walk(
+        schema,
+        schema,
+        &mut payload,
+        "",
+        0,
+        &mut |subschema, value, _| {
+            if is_secret(subschema) {
+                match value {
+                    Value::String(s) => values.push(s.clone()),
+                    Value::Null => {}
+                    other => values.push(other.to_string()),
+                }
+            }
+        },
+    );
+    values
+}
+
+/// When a client sends back a payload that it previously read, its secret fields will still
+/// contain [MASKED_VALUE]. Replace those with the matching values from `previous` so that
+/// saving an unchanged payload does not overwrite the secrets.
+pub fn restore_masked_secrets(schema: &Value, payload: &mut Value, previous: &Value) {
+    walk(
+        schema,
+        schema,
+        payload,
+        "",
+        0,
+        &mut |subschema, value, path| {
+            if is_secret(subschema) && value.as_str() == Some(MASKED_VALUE) {
+                if let Some(old) = previous.pointer(path) {
+                    *value = old.clone();
+                }
+            }
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "token": { "type": "string", "x-secret": true },
+                "accounts": {
+                    "type": "array",
+                    "items": { "$ref": "#/definitions/account" }
+                },
+                "headers": {
+                    "type": "object",
+                    "additionalProperties": { "type": "string", "x-secret": true }
+                }
+            },
+            "definitions": {
+                "account": {
+                    "type": "object",
+                    "properties": {
+                        "user": { "type": "string" },
+                        "password": { "type": "string", "x-secret": true }
+                    }
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn masks_secret_fields() {
+        let payload = json!({
+            "name": "abc",
+            "token": "secret-token",
+            "accounts": [
+                { "user": "a", "password": "pw1" },
+                { "user": "b", "password": null }
+            ],
+            "headers": { "Authorization": "Bearer xyz" }
+        });
+
+        let result = masked(&schema(), &payload);
+        assert_eq!(
+            result,
+            json!({
+                "name": "abc",
+                "token": MASKED_VALUE,
+                "accounts": [
+                    { "user": "a", "password": MASKED_VALUE },
+                    { "user": "b", "password": null }
+                ],
+                "headers": { "Authorization": MASKED_VALUE }
+            })
+        );
+    }
+
+    #[test]
+    fn collects_secret_values() {
+        let payload = json!({
+            "name": "abc",
+            "token": "secret-token",
+            "accounts": [{ "user": "a", "password": "pw1" }],
+        });
+
+        let mut values = secret_values(&schema(), &payload);
+        values.sort();
+        assert_eq!(values, vec!["pw1".to_string(), "secret-token".to_string()]);
+    }
+
+    #[test]
+    fn schema_without_secrets() {
+        let schema = json!({ "type": "object", "properties": { "a": { "type": "string" } } });
+        let payload = json!({ "a": "b", "c": 5 });
+        assert_eq!(masked(&schema, &payload), payload);
+    }
+
+    #[test]
+    fn restore_secrets() {
+        let previous = json!({
+            "name": "abc",
+            "token": "secret-token",
+            "accounts": [{ "user": "a", "password": "pw1" }]
+        });
+
+        let mut payload = json!({
+            "name": "def",
+            "token": MASKED_VALUE,
+            "accounts": [{ "user": "a", "password": "new-password" }]
+        });
+
+        restore_masked_secrets(&schema(), &mut payload, &previous);
+        assert_eq!(
+            payload,
+            json!({
+                "name": "def",
+                "token": "secret-token",
+                "accounts": [{ "user": "a", "password": "new-password" }]
+            })
+        );
+    }
+}
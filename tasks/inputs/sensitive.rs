@@ -0,0 +1,228 @@
+//! Payloads sent to triggers marked `sensitive` are encrypted before they are written to the
+//! input queue or the inputs log, and are only decrypted by the worker that applies the input.
+//! The API returns them redacted unless the caller has the `read_sensitive` permission on the
+//! task.
+//!
+//! Each payload is encrypted with a data key from Vault's transit engine, using the key named by
+//! `SENSITIVE_PAYLOAD_TRANSIT_KEY` in the engine mounted at `SENSITIVE_PAYLOAD_TRANSIT_MOUNT`.
+//! The encrypted payload carries the data key as wrapped by Vault, so it looks like
+//! `{"$sensitive": {"v": 1, "key": "vault:v1:...", "data": ...}}`. A data key is reused for a few
+//! minutes, so that a busy trigger doesn't need a Vault request for every input.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use ergo_database::encryption::{open_value, seal_value};
+use fxhash::FxHashMap;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::secrets::MASKED_VALUE;
+use crate::{error::Error, vault::vault_client};
+
+/// The key of the object that wraps an encrypted payload.
+pub const SENSITIVE_KEY: &str = "$sensitive";
+
+/// How long to keep encrypting new payloads with the same data key.
+const DATA_KEY_LIFETIME: Duration = Duration::from_secs(300);
+/// Stop caching unwrapped data keys past this many, so that an old backlog of inputs can't grow
+/// the cache forever.
+const MAX_CACHED_KEYS: usize = 1000;
+
+lazy_static! {
+    static ref TRANSIT_MOUNT: String =
+        std::env::var("SENSITIVE_PAYLOAD_TRANSIT_MOUNT").unwrap_or_else(|_| "transit".to_string());
+    static ref TRANSIT_KEY: String = std::env::var("SENSITIVE_PAYLOAD_TRANSIT_KEY")
+        .unwrap_or_else(|_| "ergo-payloads".to_string());
+    static ref CURRENT_KEY: Mutex<Option<CurrentKey>> = Mutex::new(None);
+    static ref UNWRAPPED_KEYS: Mutex<FxHashMap<String, Vec<u8>>> = Mutex::new(FxHashMap::default());
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct SensitiveEnvelope {
+    v: u32,
+    /// The data key, encrypted by Vault.
+    key: String,
+    /// The payload, encrypted with the data key.
+    data: String,
+}
+
+#[derive(Clone)]
+struct DataKey {
+    wrapped: String,
+    plaintext: Vec<u8>,
+}
+
+struct CurrentKey {
+    key: DataKey,
+    created: Instant,
+}
+
+#[derive(Deserialize)]
+struct TransitDataKey {
+    plaintext: String,
+    ciphertext: String,
+}
+
+#[derive(Deserialize)]
+struct TransitPlaintext {
+    plaintext: String,
+}
+
+fn envelope(value: &Value) -> Option<&Value> {
+    let obj = value.as_object()?;
+    if obj.len() != 1 {
+        return None;
+    }
+
+    obj.get(SENSITIVE_KEY)
+}
+
+/// Returns true if the payload is encrypted.
+pub fn is_sensitive(payload: &Value) -> bool {
+    envelope(payload).is_some()
+}
+
+/// Replace an encrypted payload with a placeholder. Other payloads are returned unchanged.
+pub fn redact(payload: Value) -> Value {
+    if is_sensitive(&payload) {
+        Value::String(MASKED_VALUE.to_string())
+    } else {
+        payload
+    }
+}
+
+fn seal(key: &DataKey, payload: &Value) -> Result<Value, Error> {
+    let envelope = SensitiveEnvelope {
+        v: 1,
+        key: key.wrapped.clone(),
+        data: seal_value(&key.plaintext, payload)?,
+    };
+
+    Ok(json!({ SENSITIVE_KEY: envelope }))
+}
+
+fn parse_envelope(payload: &Value) -> Result<Option<SensitiveEnvelope>, Error> {
+    envelope(payload)
+        .map(|e| serde_json::from_value(e.clone()))
+        .transpose()
+        .map_err(Error::from)
+}
+
+async fn new_data_key() -> Result<DataKey, Error> {
+    let vault = vault_client().ok_or_else(|| {
+        Error::Vault("Vault is required to encrypt sensitive payloads".to_string())
+    })?;
+
+    let path = format!("{}/datakey/plaintext/{}", *TRANSIT_MOUNT, *TRANSIT_KEY);
+    let key: TransitDataKey = serde_json::from_value(vault.post(&path, &json!({})).await?)?;
+    let plaintext = base64::decode(key.plaintext).map_err(|e| Error::Vault(e.to_string()))?;
+
+    Ok(DataKey {
+        wrapped: key.ciphertext,
+        plaintext,
+    })
+}
+
+async fn current_data_key() -> Result<DataKey, Error> {
+    {
+        let current = CURRENT_KEY.lock().unwrap();
+        if let Some(current) = current.as_ref() {
+            if current.created.elapsed() < DATA_KEY_LIFETIME {
+                return Ok(current.key.clone());
+            }
+        }
+    }
+
+    let key = new_data_key().await?;
+    CURRENT_KEY.lock().unwrap().replace(CurrentKey {
+        key: key.clone(),
+        created: Instant::now(),
+    });
+    Ok(key)
+}
+
+async fn unwrap_data_key(wrapped: &str) -> Result<Vec<u8>, Error> {
+    if let Some(key) = UNWRAPPED_KEYS.lock().unwrap().get(wrapped) {
+        return Ok(key.clone());
+    }
+
+    let vault = vault_client().ok_or_else(|| {
+        Error::Vault("Vault is required to decrypt sensitive payloads".to_string())
+    })?;
+
+    let path = format!("{}/decrypt/{}", *TRANSIT_MOUNT, *TRANSIT_KEY);
+    let result: TransitPlaintext =
+        serde_json::from_value(vault.post(&path, &json!({ "ciphertext": wrapped })).await?)?;
+    let key = base64::decode(result.plaintext).map_err(|e| Error::Vault(e.to_string()))?;
+
+    let mut keys = UNWRAPPED_KEYS.lock().unwrap();
+    if keys.len() >= MAX_CACHED_KEYS {
+        keys.clear();
+    }
+    keys.insert(wrapped.to_string(), key.clone());
+
+    Ok(key)
+}
+
+/// Encrypt a payload for a sensitive trigger.
+pub async fn encrypt_payload(payload: &Value) -> Result<Value, Error> {
+    let key = current_data_key().await?;
+    seal(&key, payload)
+}
+
+/// Decrypt a payload if it is encrypted. Other payloads are returned unchanged.
+pub async fn decrypt_payload(payload: Value) -> Result<Value, Error> {
+    let envelope = match parse_envelope(&payload)? {
+        Some(e) => e,
+        None => return Ok(payload),
+    };
+
+    let key = unwrap_data_key(&envelope.key).await?;
+    Ok(open_value(&key, &envelope.data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_key() -> DataKey {
+        DataKey {
+            wrapped: "vault:v1:test".to_string(),
+            plaintext: b"0123456789abcdef0123456789abcdef".to_vec(),
+        }
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let key = test_key();
+        let payload = json!({ "ssn": "123-45-6789" });
+
+        let sealed = seal(&key, &payload).unwrap();
+        assert!(is_sensitive(&sealed));
+        assert!(!sealed.to_string().contains("123-45"));
+
+        let envelope = parse_envelope(&sealed).unwrap().expect("envelope");
+        assert_eq!(envelope.key, "vault:v1:test");
+        assert_eq!(open_value(&key.plaintext, &envelope.data).unwrap(), payload);
+    }
+
+    #[test]
+    fn redaction() {
+        let sealed = seal(&test_key(), &json!({ "a": 1 })).unwrap();
+        assert_eq!(redact(sealed), json!(MASKED_VALUE));
+
+        let plain = json!({ "a": 1 });
+        assert_eq!(redact(plain.clone()), plain);
+    }
+
+    #[tokio::test]
+    async fn plain_payloads_pass_through() {
+        let payload = json!({ "a": 1, "$sensitive": true });
+        assert!(!is_sensitive(&payload));
+        assert_eq!(decrypt_payload(payload.clone()).await.unwrap(), payload);
+    }
+}
@@ -1,19 +1,54 @@
 #![allow(clippy::bool_assert_comparison)]
 
 pub mod actions;
+#[cfg(not(target_family = "wasm"))]
+pub mod approvals;
+pub mod conditions;
+#[cfg(not(target_family = "wasm"))]
+pub mod costs;
 pub mod dataflow;
+#[cfg(not(target_family = "wasm"))]
+pub mod dependents;
+#[cfg(not(target_family = "wasm"))]
+pub mod egress;
 mod error;
+#[cfg(not(target_family = "wasm"))]
+pub mod feature_flags;
+#[cfg(not(target_family = "wasm"))]
+pub mod firehose;
 pub mod inputs;
+pub mod limits;
+#[cfg(not(target_family = "wasm"))]
+pub mod log_retention;
+#[cfg(not(target_family = "wasm"))]
+pub mod maintenance;
+#[cfg(not(target_family = "wasm"))]
+pub mod payload_limits;
 pub mod periodic;
 #[cfg(not(target_family = "wasm"))]
 pub mod queue_drain_runner;
+#[cfg(not(target_family = "wasm"))]
+pub mod quotas;
+#[cfg(not(target_family = "wasm"))]
+pub mod revalidate;
+#[cfg(not(target_family = "wasm"))]
+pub mod script_logs;
 pub mod scripting;
+pub mod shorthand;
+#[cfg(not(target_family = "wasm"))]
+pub mod state_history;
 pub mod state_machine;
+pub mod state_reset;
+#[cfg(not(target_family = "wasm"))]
+pub mod timeline;
+pub mod trace_context;
+#[cfg(not(target_family = "wasm"))]
+pub mod vault;
 
 use actions::{Action, TaskAction};
 use ergo_database::object_id::{InputId, PeriodicTriggerId, TaskId, TaskTriggerId};
 pub use error::*;
-use inputs::Input;
+use inputs::{routing::TriggerRoute, Input, TriggerDedupeConfig};
 #[cfg(not(target_family = "wasm"))]
 pub use native::*;
 pub use periodic::{PeriodicSchedule, PeriodicTaskTrigger, PeriodicTaskTriggerInput};
@@ -85,6 +120,30 @@ pub struct TaskTrigger {
     #[schemars(with = "Option<String>")]
     pub last_payload: Option<Box<serde_json::value::RawValue>>,
     pub periodic: Option<Vec<PeriodicTaskTrigger>>,
+    pub dedupe: Option<TriggerDedupeConfig>,
+    /// Payloads sent to this trigger are encrypted when stored.
+    #[serde(default)]
+    pub sensitive: bool,
+    /// Rules that send inputs to other triggers based on their payload.
+    #[serde(default)]
+    pub routes: Option<Vec<TriggerRoute>>,
+}
+
+impl TaskTrigger {
+    /// Mask the fields of the trigger's payloads that the input schema marks as secret.
+    pub fn mask_secrets(&mut self, payload_schema: &serde_json::Value) {
+        if let Some(last_payload) = self.last_payload.as_ref() {
+            let masked = serde_json::from_str::<serde_json::Value>(last_payload.get())
+                .map(|payload| inputs::secrets::masked(payload_schema, &payload))
+                .and_then(|payload| serde_json::value::to_raw_value(&payload));
+            // If the payload can't be processed, don't risk returning it unmasked.
+            self.last_payload = masked.ok();
+        }
+
+        for periodic in self.periodic.iter_mut().flatten() {
+            inputs::secrets::mask_secrets(payload_schema, &mut periodic.payload);
+        }
+    }
 }
 
 #[cfg(not(target_family = "wasm"))]
@@ -94,16 +153,28 @@ mod native {
         actions::{
             enqueue_actions,
             execute::{
-                validate_and_prepare_invocation, ExecuteError, PrepareInvocationAction,
-                ScriptOrTemplate,
+                register_account_secrets, validate_and_prepare_invocation, ExecuteError,
+                PrepareInvocationAction, ScriptOrTemplate,
             },
             template::TemplateFields,
-            ActionInvocation, ActionInvocations, ActionStatus, TaskActionTemplate,
+            ActionInvocation, ActionInvocations, ActionSource, ActionStatus, TaskActionTemplate,
         },
+        costs,
         dataflow::DataFlowState,
-        inputs::{enqueue_input, EnqueueInputOptions, InputInvocation, InputStatus},
+        egress,
+        firehose::{self, FirehoseEvent, FirehoseEventKind},
+        inputs::{
+            chain::InputChain, enqueue_input, secrets::secret_values, EnqueueInputOptions,
+            InputInvocation, InputStatus,
+        },
+        limits::RunBudget,
+        payload_limits::{PayloadKind, PAYLOAD_LIMITS},
+        quotas::{self, QuotaKind},
         scripting::TaskJsState,
-        state_machine::{StateMachineStates, StateMachineWithData},
+        state_history,
+        state_machine::{StateMachineError, StateMachineStates, StateMachineWithData},
+        timeline::TimelineRecorder,
+        trace_context::TraceContext,
         TaskConfig,
     };
     use chrono::{DateTime, Utc};
@@ -112,7 +183,7 @@ mod native {
         object_id::{
             AccountId, ActionId, InputId, OrgId, TaskId, TaskTemplateId, TaskTriggerId, UserId,
         },
-        sql_insert_parameters,
+        redaction, sql_insert_parameters,
         transaction::serializable,
         PostgresPool,
     };
@@ -121,6 +192,13 @@ mod native {
     use serde::{Deserialize, Serialize};
     use smallvec::SmallVec;
     use sqlx::{types::Json, FromRow};
+    use std::{
+        sync::{
+            atomic::{AtomicI64, Ordering},
+            Arc,
+        },
+        time::Instant,
+    };
     use tracing::{event, instrument, Level};
     use uuid::Uuid;
 
@@ -166,6 +244,11 @@ mod native {
             let inv = invocation.clone();
             let not = notifications.clone();
             let rkp = redis_key_prefix.clone();
+            let timeline = TimelineRecorder::new();
+            let tl = timeline.clone();
+            let apply_start = Utc::now();
+            let js_time = Arc::new(AtomicI64::new(0));
+            let jt = js_time.clone();
 
             let result = serializable(&mut conn, 5, move |tx| {
                 let InputInvocation{
@@ -175,10 +258,14 @@ mod native {
                     task_trigger_id,
                     user_id,
                     periodic_trigger_id,
+                    interactive,
+                    chain,
                     ..
                 } = inv.clone();
                 let notifications = not.clone();
                 let redis_key_prefix = rkp.clone();
+                let timeline = tl.clone();
+                let js_time = jt.clone();
 
                 Box::pin(async move {
                     #[derive(Debug, Deserialize)]
@@ -192,7 +279,7 @@ mod native {
                         executor_id: String,
                         account_id: Option<AccountId>,
                         account_required: bool,
-                        account_fields: Option<TaskActionTemplate>,
+                        account_fields: Option<serde_json::Value>,
                         account_expires: Option<DateTime<Utc>>,
                     }
 
@@ -204,6 +291,8 @@ mod native {
                         org_id: OrgId,
                         task_name: String,
                         task_trigger_name: String,
+                        sensitive: bool,
+                        payload_schema: serde_json::Value,
                         task_actions: Json<SmallVec<[TaskAction; 4]>>,
                         periodic_trigger_id: Option<PeriodicTriggerId>,
                     }
@@ -217,6 +306,8 @@ mod native {
                             tasks.org_id as "org_id: OrgId",
                             tasks.name as task_name,
                             tt.name as task_trigger_name,
+                            tt.sensitive,
+                            inputs.payload_schema,
                             pt.periodic_trigger_id as "periodic_trigger_id: Option<PeriodicTriggerId>",
                             jsonb_agg(jsonb_build_object(
                                 'task_action_local_id', ta.task_action_local_id,
@@ -235,13 +326,14 @@ mod native {
                             FROM tasks
                             JOIN task_templates USING (task_template_id, task_template_version)
                             JOIN task_triggers tt ON tt.task_id=$1 AND task_trigger_id=$2
+                            JOIN inputs ON inputs.input_id=tt.input_id
                             JOIN task_actions ta ON ta.task_id=$1
                             LEFT JOIN periodic_triggers pt on pt.task_trigger_id=tt.task_trigger_id AND pt.periodic_trigger_id=$3 AND pt.enabled
                             JOIN actions ac USING(action_id)
                             LEFT JOIN accounts USING(account_id)
                             WHERE tasks.task_id=$1
                             GROUP BY task_trigger_local_id, compiled, state, tasks.org_id, task_name,
-                                task_trigger_name, periodic_trigger_id"##,
+                                task_trigger_name, tt.sensitive, inputs.input_id, periodic_trigger_id"##,
                             task_id.0,
                             task_trigger_id.0,
                             periodic_trigger_id as _
@@ -252,21 +344,32 @@ mod native {
                     let task = task.ok_or(Error::NotFound)?;
 
                     let TaskInputData {
-                        task_trigger_local_id, config, state, org_id, task_name, task_trigger_name, task_actions, periodic_trigger_id: found_periodic_trigger
+                        task_trigger_local_id, config, state, org_id, task_name, task_trigger_name, sensitive, payload_schema, task_actions, periodic_trigger_id: found_periodic_trigger
                     } = task;
 
+                    // Keep the input's secret fields and the accounts' fields out of everything
+                    // logged while handling the input.
+                    let _input_secrets = redaction::register(secret_values(&payload_schema, &payload));
+                    let _account_secrets = task_actions
+                        .iter()
+                        .filter_map(|a| a.account_fields.as_ref())
+                        .map(register_account_secrets)
+                        .collect::<Vec<_>>();
+
                     if periodic_trigger_id.is_some() && found_periodic_trigger.is_none() {
                         // If this run is for a periodic trigger that doesn't exist anymore or was
                         // disabled, then don't do anything.
                         return Err(Error::PeriodicTaskDeleted);
                     }
 
-                    let (new_data, log_info, actions, changed) = match (config.0, state.0) {
+                    let mut budget = RunBudget::default();
+                    let (new_data, log_info, mut actions, changed) = match (config.0, state.0) {
                         (TaskConfig::StateMachine(machine), TaskState::StateMachine(state)) => {
                             let num_machines = machine.len();
                             let mut new_data = StateMachineStates::with_capacity(num_machines);
                             let mut actions = ActionInvocations::new();
                             let mut changed = false;
+                            let machine_start = Utc::now();
                             for (idx, (machine, state)) in machine
                                 .into_iter()
                                 .zip(state.into_iter())
@@ -278,14 +381,19 @@ mod native {
                                           &user_id,
                                           &Some(input_arrival_id),
                                           Some(&payload),
+                                          &mut budget,
                                       ).await
-                                      .map_err(Error::from)?;
+                                      .map_err(|e| match e {
+                                          StateMachineError::LimitExceeded(e) => Error::ExecutionLimitExceeded(e),
+                                          e => Error::from(e),
+                                      })?;
 
                                   let (data, this_changed) = m.take();
                                   new_data.push(data);
                                   actions.extend(this_actions.into_iter());
                                   changed = changed || this_changed;
                             }
+                            timeline.add("state_machine", machine_start, None);
 
                             (TaskState::StateMachine(new_data), serde_json::Value::Null, actions, changed)
                         },
@@ -293,7 +401,21 @@ mod native {
                             return Err(Error::ConfigStateMismatch("StateMachine"))
                         },
                         (TaskConfig::Js(config), TaskState::Js(state)) => {
-                            let run_result = scripting::immediate::run_task(&task_name, config, state, payload.clone()).await?;
+                            quotas::check_daily_quota(&mut *tx, &org_id, QuotaKind::JsCpuMsPerDay).await?;
+                            let egress = egress::get_policy(&mut *tx, &org_id).await?;
+                            let lock_scope = scripting::locks::LockScope {
+                                org_id: org_id.clone(),
+                                redis_key_prefix: redis_key_prefix.clone(),
+                            };
+                            let script_start = Instant::now();
+                            let run_result = timeline.span(
+                                "task_script",
+                                scripting::immediate::run_task(&task_name, config, state, payload.clone(), egress, Some(lock_scope))
+                            ).await;
+                            js_time.fetch_add(script_start.elapsed().as_millis() as i64, Ordering::Relaxed);
+                            let run_result = run_result?;
+                            script_logs::record(&mut *tx, &task_id, input_arrival_id, &run_result.console).await?;
+                            budget.add_actions(run_result.actions.len())?;
                             let actions = run_result.actions.into_iter().map(|action| {
                                 ActionInvocation{
                                     task_id,
@@ -302,17 +424,34 @@ mod native {
                                     user_id,
                                     task_action_local_id: action.name,
                                     actions_log_id: new_uuid(),
+                                    chain: InputChain::default(),
+                                    trace: TraceContext::default(),
+                                    payload_in_log: false,
+                                    compensates: None,
+                                    source: Some(ActionSource::Script),
                                 }
                             }).collect::<ActionInvocations>();
 
                             // TODO Return console messages here
-                            (TaskState::Js(run_result.state), serde_json::Value::Null ,actions, run_result.state_changed)
+                            let log_out = match run_result.console_stats {
+                                Some(stats) => serde_json::json!({ "truncated": { "console": stats } }),
+                                None => serde_json::Value::Null,
+                            };
+                            (TaskState::Js(run_result.state), log_out, actions, run_result.state_changed)
                         },
                         (TaskConfig::Js(_), _) =>  {
                             return Err(Error::ConfigStateMismatch("Js"))
                         },
                         (TaskConfig::DataFlow(config), TaskState::DataFlow(state)) => {
-                            let (state, log, actions) = config.evaluate_trigger(&task_name, state, task_trigger_id, &task_trigger_local_id, payload.clone()).await?;
+                            quotas::check_daily_quota(&mut *tx, &org_id, QuotaKind::JsCpuMsPerDay).await?;
+                            let egress = egress::get_policy(&mut *tx, &org_id).await?;
+                            let script_start = Instant::now();
+                            let result = timeline.span(
+                                "dataflow",
+                                config.evaluate_trigger(&task_name, state, task_trigger_id, &task_trigger_local_id, payload.clone(), &mut budget, &egress)
+                            ).await;
+                            js_time.fetch_add(script_start.elapsed().as_millis() as i64, Ordering::Relaxed);
+                            let (state, log, actions) = result?;
                             let actions = actions.into_iter().map(|action| {
                                 ActionInvocation{
                                     task_id,
@@ -321,6 +460,11 @@ mod native {
                                     user_id,
                                     task_action_local_id: action.name,
                                     actions_log_id: new_uuid(),
+                                    chain: InputChain::default(),
+                                    trace: TraceContext::default(),
+                                    payload_in_log: false,
+                                    compensates: None,
+                                    source: action.node.map(|node| ActionSource::DataFlowNode { node }),
                                 }
                             }).collect::<ActionInvocations>();
 
@@ -333,29 +477,43 @@ mod native {
                         }
                     };
 
-                    if changed {
+                    let trace = TraceContext::current();
+                    for action in actions.iter_mut() {
+                        action.chain = chain.clone();
+                        action.trace = trace.clone();
+                    }
+
+                    let saved_state = if changed {
                         event!(Level::INFO, state=?new_data, "New state");
+                        let state = serde_json::value::to_value(&new_data)?;
                         sqlx::query!(
                             r##"UPDATE tasks
                             SET state = $1::jsonb
                             WHERE task_id = $2;
                             "##,
-                            serde_json::value::to_value(&new_data)?,
+                            &state,
                             *task_id,
                         )
                         .execute(&mut *tx)
                         .await?;
-                    }
+                        Some(state)
+                    } else {
+                        None
+                    };
+
+                    state_history::record_state(&mut *tx, &task_id, input_arrival_id, saved_state.as_ref()).await?;
 
                     if !actions.is_empty() {
+                        quotas::consume_daily_quota(&mut *tx, &org_id, QuotaKind::ActionsPerDay, actions.len() as i64).await?;
+                        let enqueue_start = Utc::now();
                         event!(Level::INFO, ?actions, "Enqueueing actions");
                         event!(Level::DEBUG, ?task_actions);
                         let q = format!(
-                            "INSERT INTO actions_log (task_id, task_action_local_id, actions_log_id, inputs_log_id, payload, status)
+                            "INSERT INTO actions_log (task_id, task_action_local_id, actions_log_id, inputs_log_id, payload, status, source)
                             VALUES
                             {}
                             ",
-                            sql_insert_parameters::<6>(actions.len())
+                            sql_insert_parameters::<7>(actions.len())
                         );
 
                         let mut log_query = sqlx::query(&q);
@@ -389,23 +547,38 @@ mod native {
                                     error: e,
                                 })?;
 
+                            // The worker reads large payloads back from the log, so those are
+                            // stored as they are. Other payloads are only kept for reference.
+                            let logged_payload = if PAYLOAD_LIMITS.check(PayloadKind::Action, &action.payload)? {
+                                action.payload.clone()
+                            } else {
+                                redaction::redacted_json(&action.payload)
+                            };
+
                             log_query = log_query
                                 .bind(action.task_id)
                                 .bind(&action.task_action_local_id)
                                 .bind(action.actions_log_id)
                                 .bind(action.input_arrival_id)
-                                .bind(&action.payload)
-                                .bind(ActionStatus::Pending);
+                                .bind(logged_payload)
+                                .bind(ActionStatus::Pending)
+                                .bind(action.source.as_ref().map(sqlx::types::Json));
                         }
 
                         log_query.fetch_all(&mut *tx).await?;
-                        enqueue_actions(&mut *tx, &actions, &redis_key_prefix).await?;
+                        enqueue_actions(&mut *tx, &actions, &redis_key_prefix, interactive).await?;
+                        timeline.add(
+                            "enqueue_actions",
+                            enqueue_start,
+                            Some(serde_json::json!({ "count": actions.len() })),
+                        );
                     }
 
                     if let Some(notifications) = notifications {
                         let input_notification = Notification{
                             event: NotifyEvent::InputProcessed,
-                            payload: Some(payload),
+                            // Sensitive payloads stay out of notifications.
+                            payload: (!sensitive).then_some(payload),
                             task_id,
                             task_name,
                             local_id: task_trigger_local_id,
@@ -422,6 +595,14 @@ mod native {
             })
             .await;
 
+            timeline.add("apply_input", apply_start, None);
+
+            // Script time is recorded outside of the transaction so that failed runs still count.
+            let js_ms = js_time.load(Ordering::Relaxed);
+            if let Err(e) = quotas::record_js_time(pool, &invocation.task_id, js_ms).await {
+                event!(Level::ERROR, err=?e, "Failed to record script time");
+            }
+
             let (log_info, status, retval) = match result {
                 Ok(log_info) => (log_info, InputStatus::Success, Ok(())),
                 Err(Error::PeriodicTaskDeleted) => {
@@ -439,29 +620,73 @@ mod native {
                 }
                 Err(e) => {
                     event!(Level::ERROR, err=?e, "Error applying input");
+                    // The run's transaction was rolled back, but the log entries from a failed
+                    // script are the ones most worth keeping.
+                    if let Error::TaskScript { console, .. } = &e {
+                        let saved = async {
+                            let mut conn = pool.acquire().await?;
+                            script_logs::record(&mut conn, &invocation.task_id, invocation.inputs_log_id, console).await
+                        }
+                        .await;
+                        if let Err(log_err) = saved {
+                            event!(Level::ERROR, err=?log_err, "Failed to save script log entries");
+                        }
+                    }
+
                     (
-                        serde_json::json!({ "msg": e.to_string(), "info": format!("{:?}", e) }),
+                        serde_json::json!({
+                            "msg": e.to_string(),
+                            "error_class": e.error_class().as_str(),
+                            "info": format!("{:?}", e),
+                        }),
                         InputStatus::Error,
                         Err(e),
                     )
                 }
             };
 
+            if let Err(e) = costs::record_run(pool, &invocation.task_id, invocation.inputs_log_id, apply_start, js_ms).await {
+                event!(Level::ERROR, err=?e, "Failed to record task usage");
+            }
+
             event!(Level::INFO, input_arrival_id=%invocation.inputs_log_id, ?status, ?log_info, "Updating input status");
+            let mut tx = pool.begin().await?;
             sqlx::query!(
                 "UPDATE inputs_log SET status=$2, info=$3, updated=now() WHERE inputs_log_id=$1",
                 invocation.inputs_log_id,
                 status as _,
                 log_info
             )
-            .execute(pool)
+            .execute(&mut tx)
+            .await?;
+
+            firehose::export_event(
+                &mut tx,
+                &FirehoseEvent {
+                    kind: FirehoseEventKind::Input {
+                        task_trigger_id: invocation.task_trigger_id,
+                        status,
+                    },
+                    log_id: invocation.inputs_log_id,
+                    task_id: invocation.task_id,
+                    org_id: None,
+                    result: log_info,
+                    timestamp: Utc::now(),
+                },
+                redis_key_prefix.as_deref(),
+            )
             .await?;
+            tx.commit().await?;
+
+            timeline.write(pool, Some(invocation.inputs_log_id)).await;
 
-            // If this was a periodic trigger, enqueue it again.
-            if let Some(periodic_id) = invocation
-                .periodic_trigger_id
-                .filter(|_| retval.is_ok() || reschedule_periodic_task_on_error)
-            {
+            // If this was a periodic trigger, enqueue it again. Permanent errors won't be
+            // retried, so the next run is scheduled right away for those too.
+            let reschedule = match &retval {
+                Ok(_) => true,
+                Err(e) => reschedule_periodic_task_on_error || e.is_permanent(),
+            };
+            if let Some(periodic_id) = invocation.periodic_trigger_id.filter(|_| reschedule) {
                 let info = sqlx::query!(
                     r##"SELECT
                     pt.payload,
@@ -511,6 +736,9 @@ mod native {
                             payload: info.payload,
                             redis_key_prefix: redis_key_prefix.as_deref(),
                             trigger_at: Some(next_time),
+                            replay_of: None,
+                            interactive: false,
+                            chain: InputChain::default(),
                         })
                         .await?;
                     }
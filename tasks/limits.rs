@@ -0,0 +1,199 @@
+//! Limits on how much work a single input can cause a task to do. These keep a misconfigured
+//! state machine or dataflow from running away and starving the rest of the system.
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitKind {
+    Transitions,
+    Actions,
+    JsEvaluations,
+}
+
+impl std::fmt::Display for LimitKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let desc = match self {
+            Self::Transitions => "state transitions",
+            Self::Actions => "actions",
+            Self::JsEvaluations => "script evaluations",
+        };
+
+        f.write_str(desc)
+    }
+}
+
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+#[error("Task run exceeded the limit of {limit} {kind}")]
+pub struct LimitExceeded {
+    pub kind: LimitKind,
+    pub limit: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RunLimits {
+    /// The maximum number of state changes, across all state machines in the task.
+    pub max_transitions: usize,
+    /// The maximum number of actions that can be enqueued.
+    pub max_actions: usize,
+    /// The maximum number of scripts that can be run, including state machine scripts and
+    /// dataflow nodes.
+    pub max_js_evaluations: usize,
+}
+
+impl Default for RunLimits {
+    fn default() -> Self {
+        RunLimits {
+            max_transitions: 100,
+            max_actions: 100,
+            max_js_evaluations: 1000,
+        }
+    }
+}
+
+impl RunLimits {
+    /// Read the limits from `TASK_MAX_TRANSITIONS`, `TASK_MAX_ACTIONS`, and
+    /// `TASK_MAX_JS_EVALUATIONS`.
+    pub fn from_env() -> Self {
+        fn env_or(name: &str, default: usize) -> usize {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        }
+
+        let default = RunLimits::default();
+        RunLimits {
+            max_transitions: env_or("TASK_MAX_TRANSITIONS", default.max_transitions),
+            max_actions: env_or("TASK_MAX_ACTIONS", default.max_actions),
+            max_js_evaluations: env_or("TASK_MAX_JS_EVALUATIONS", default.max_js_evaluations),
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref RUN_LIMITS: RunLimits = RunLimits::from_env();
+}
+
+/// Tracks the work done while applying a single input to a task.
+#[derive(Debug)]
+pub struct RunBudget {
+    limits: RunLimits,
+    transitions: usize,
+    actions: usize,
+    js_evaluations: usize,
+}
+
+impl Default for RunBudget {
+    fn default() -> Self {
+        RunBudget::new(RUN_LIMITS.clone())
+    }
+}
+
+impl RunBudget {
+    pub fn new(limits: RunLimits) -> Self {
+        RunBudget {
+            limits,
+            transitions: 0,
+            actions: 0,
+            js_evaluations: 0,
+        }
+    }
+
+    fn consume(
+        used: &mut usize,
+        count: usize,
+        limit: usize,
+        kind: LimitKind,
+    ) -> Result<(), LimitExceeded> {
+        *used = used.saturating_add(count);
+        if *used > limit {
+            Err(LimitExceeded { kind, limit })
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn transition(&mut self) -> Result<(), LimitExceeded> {
+        Self::consume(
+            &mut self.transitions,
+            1,
+            self.limits.max_transitions,
+            LimitKind::Transitions,
+        )
+    }
+
+    pub fn add_actions(&mut self, count: usize) -> Result<(), LimitExceeded> {
+        Self::consume(
+            &mut self.actions,
+            count,
+            self.limits.max_actions,
+            LimitKind::Actions,
+        )
+    }
+
+    pub fn js_evaluation(&mut self) -> Result<(), LimitExceeded> {
+        Self::consume(
+            &mut self.js_evaluations,
+            1,
+            self.limits.max_js_evaluations,
+            LimitKind::JsEvaluations,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> RunLimits {
+        RunLimits {
+            max_transitions: 2,
+            max_actions: 3,
+            max_js_evaluations: 1,
+        }
+    }
+
+    #[test]
+    fn within_limits() {
+        let mut budget = RunBudget::new(limits());
+        budget.transition().unwrap();
+        budget.transition().unwrap();
+        budget.add_actions(3).unwrap();
+        budget.js_evaluation().unwrap();
+    }
+
+    #[test]
+    fn exceeds_limits() {
+        let mut budget = RunBudget::new(limits());
+        budget.transition().unwrap();
+        budget.transition().unwrap();
+        assert_eq!(
+            budget.transition(),
+            Err(LimitExceeded {
+                kind: LimitKind::Transitions,
+                limit: 2
+            })
+        );
+
+        budget.add_actions(2).unwrap();
+        assert_eq!(
+            budget.add_actions(2),
+            Err(LimitExceeded {
+                kind: LimitKind::Actions,
+                limit: 3
+            })
+        );
+
+        budget.js_evaluation().unwrap();
+        assert_eq!(
+            budget.js_evaluation(),
+            Err(LimitExceeded {
+                kind: LimitKind::JsEvaluations,
+                limit: 1
+            })
+        );
+    }
+}
@@ -0,0 +1,450 @@
+//! Retention for the inputs and actions logs.
+//!
+//! Each org has a policy for how long log entries are kept. Entries that have not been updated
+//! for longer than that are removed by the `log_retention` maintenance job, and when the policy
+//! asks for it they are first written to the archive store as gzipped NDJSON, one JSON object
+//! per log row. Action artifacts are removed along with their log entries and are not archived.
+
+use std::{io::Write, path::PathBuf};
+
+use chrono::{DateTime, Duration, Utc};
+use ergo_database::{object_id::OrgId, PostgresPool};
+use flate2::{write::GzEncoder, Compression};
+use lazy_static::lazy_static;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use crate::Error;
+
+/// The number of log entries to archive and remove at once.
+const BATCH_SIZE: i64 = 5000;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct RetentionPolicy {
+    /// Remove log entries that were last updated more than this many days ago. `None` keeps
+    /// them forever.
+    pub keep_days: Option<i32>,
+    /// Write log entries to the archive store before removing them.
+    #[serde(default)]
+    pub archive: bool,
+}
+
+lazy_static! {
+    /// The policy for orgs that have not set their own, from `LOG_RETENTION_DAYS` and
+    /// `LOG_ARCHIVE`.
+    pub static ref DEFAULT_RETENTION_POLICY: RetentionPolicy = RetentionPolicy {
+        keep_days: std::env::var("LOG_RETENTION_DAYS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|days| *days > 0),
+        archive: std::env::var("LOG_ARCHIVE")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false),
+    };
+}
+
+pub async fn get_policy(tx: &mut PgConnection, org_id: &OrgId) -> Result<RetentionPolicy, Error> {
+    let policy = sqlx::query_as!(
+        RetentionPolicy,
+        "SELECT keep_days, archive FROM org_log_retention WHERE org_id=$1",
+        org_id.0
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    Ok(policy.unwrap_or_else(|| DEFAULT_RETENTION_POLICY.clone()))
+}
+
+pub async fn set_policy(
+    tx: &mut PgConnection,
+    org_id: &OrgId,
+    policy: &RetentionPolicy,
+) -> Result<(), Error> {
+    sqlx::query!(
+        r##"INSERT INTO org_log_retention (org_id, keep_days, archive)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (org_id) DO UPDATE
+            SET keep_days=EXCLUDED.keep_days, archive=EXCLUDED.archive, updated=now()"##,
+        org_id.0,
+        policy.keep_days,
+        policy.archive
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+/// A record of a file written to the archive store.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct LogArchive {
+    pub log_archive_id: Uuid,
+    pub log_table: String,
+    pub location: String,
+    pub entries: i32,
+    pub oldest: DateTime<Utc>,
+    pub newest: DateTime<Utc>,
+    pub created: DateTime<Utc>,
+}
+
+pub async fn list_archives(
+    tx: &mut PgConnection,
+    org_id: &OrgId,
+) -> Result<Vec<LogArchive>, Error> {
+    let archives = sqlx::query_as!(
+        LogArchive,
+        r##"SELECT log_archive_id, log_table, location, entries, oldest, newest, created
+        FROM log_archives
+        WHERE org_id=$1
+        ORDER BY created DESC"##,
+        org_id.0
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    Ok(archives)
+}
+
+/// Where archived log entries are written.
+pub enum ArchiveStore {
+    /// Write files under a local directory.
+    Directory(PathBuf),
+    /// Upload files to an S3-compatible bucket, with keys starting with `prefix`.
+    S3 {
+        bucket: Box<s3::Bucket>,
+        prefix: String,
+    },
+}
+
+impl std::fmt::Debug for ArchiveStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Directory(dir) => f.debug_tuple("Directory").field(dir).finish(),
+            Self::S3 { bucket, prefix } => f
+                .debug_struct("S3")
+                .field("bucket", &bucket.name)
+                .field("prefix", prefix)
+                .finish(),
+        }
+    }
+}
+
+impl ArchiveStore {
+    /// Configure the store from `LOG_ARCHIVE_DIR`, or from `LOG_ARCHIVE_S3_BUCKET` along with
+    /// `LOG_ARCHIVE_S3_REGION`, `LOG_ARCHIVE_S3_ENDPOINT`, and `LOG_ARCHIVE_S3_PREFIX`. S3
+    /// credentials come from the usual AWS environment variables or profile. Returns `None`
+    /// if neither is set.
+    pub fn from_env() -> Result<Option<ArchiveStore>, Error> {
+        if let Ok(dir) = std::env::var("LOG_ARCHIVE_DIR") {
+            return Ok(Some(ArchiveStore::Directory(PathBuf::from(dir))));
+        }
+
+        let bucket_name = match std::env::var("LOG_ARCHIVE_S3_BUCKET") {
+            Ok(name) => name,
+            Err(_) => return Ok(None),
+        };
+
+        let region_name =
+            std::env::var("LOG_ARCHIVE_S3_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+        let region = match std::env::var("LOG_ARCHIVE_S3_ENDPOINT") {
+            Ok(endpoint) => s3::Region::Custom {
+                region: region_name,
+                endpoint,
+            },
+            Err(_) => region_name
+                .parse()
+                .map_err(|e| Error::LogArchive(format!("Invalid S3 region: {}", e)))?,
+        };
+
+        let credentials =
+            s3::creds::Credentials::default().map_err(|e| Error::LogArchive(e.to_string()))?;
+        let mut bucket = s3::Bucket::new(&bucket_name, region, credentials)
+            .map_err(|e| Error::LogArchive(e.to_string()))?;
+        if std::env::var("LOG_ARCHIVE_S3_ENDPOINT").is_ok() {
+            // S3-compatible services usually don't support virtual-hosted buckets.
+            bucket.set_path_style();
+        }
+
+        Ok(Some(ArchiveStore::S3 {
+            bucket: Box::new(bucket),
+            prefix: std::env::var("LOG_ARCHIVE_S3_PREFIX").unwrap_or_default(),
+        }))
+    }
+
+    /// Write a file to the store, and return a URL that describes where it went.
+    async fn put(&self, path: &str, data: Vec<u8>) -> Result<String, Error> {
+        match self {
+            Self::Directory(dir) => {
+                let file_path = dir.join(path);
+                if let Some(parent) = file_path.parent() {
+                    tokio::fs::create_dir_all(parent)
+                        .await
+                        .map_err(|e| Error::LogArchive(e.to_string()))?;
+                }
+                tokio::fs::write(&file_path, data)
+                    .await
+                    .map_err(|e| Error::LogArchive(e.to_string()))?;
+                Ok(format!("file://{}", file_path.display()))
+            }
+            Self::S3 { bucket, prefix } => {
+                let key = format!("{}{}", prefix, path);
+                let response = bucket
+                    .put_object_with_content_type(&key, &data, "application/x-ndjson")
+                    .await
+                    .map_err(|e| Error::LogArchive(e.to_string()))?;
+                if response.status_code() >= 300 {
+                    return Err(Error::LogArchive(format!(
+                        "Upload to S3 returned status {}",
+                        response.status_code()
+                    )));
+                }
+                Ok(format!("s3://{}/{}", bucket.name, key))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LogTable {
+    Inputs,
+    Actions,
+}
+
+impl LogTable {
+    fn table(&self) -> &'static str {
+        match self {
+            Self::Inputs => "inputs_log",
+            Self::Actions => "actions_log",
+        }
+    }
+
+    fn id_column(&self) -> &'static str {
+        match self {
+            Self::Inputs => "inputs_log_id",
+            Self::Actions => "actions_log_id",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionSummary {
+    /// The number of orgs whose logs were checked.
+    pub orgs: usize,
+    /// The number of log entries removed.
+    pub removed: usize,
+    /// The number of log entries written to the archive store.
+    pub archived: usize,
+}
+
+/// Apply the retention policies for every org, or just for `org_id` if it is given.
+pub async fn apply_retention(
+    pool: &PostgresPool,
+    store: Option<&ArchiveStore>,
+    org_id: Option<&OrgId>,
+    now: DateTime<Utc>,
+) -> Result<RetentionSummary, Error> {
+    let orgs = sqlx::query!(
+        r##"SELECT orgs.org_id,
+            CASE WHEN r.org_id IS NULL THEN $1 ELSE r.keep_days END AS keep_days,
+            COALESCE(r.archive, $2) AS "archive!"
+        FROM orgs
+        LEFT JOIN org_log_retention r USING (org_id)
+        WHERE NOT COALESCE(orgs.deleted, false) AND ($3::uuid IS NULL OR orgs.org_id = $3)"##,
+        DEFAULT_RETENTION_POLICY.keep_days,
+        DEFAULT_RETENTION_POLICY.archive,
+        org_id.map(|id| id.0)
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut summary = RetentionSummary::default();
+    for org in orgs {
+        let keep_days = match org.keep_days {
+            Some(days) => days,
+            None => continue,
+        };
+
+        let org_id = OrgId::from_uuid(org.org_id);
+        if org.archive && store.is_none() {
+            event!(
+                Level::WARN,
+                %org_id,
+                "Skipping log retention because the org archives its logs and no archive store is configured"
+            );
+            continue;
+        }
+
+        summary.orgs += 1;
+        let cutoff = now - Duration::days(keep_days as i64);
+        // Remove actions first, so that their inputs are still linked when they are archived.
+        for table in [LogTable::Actions, LogTable::Inputs] {
+            let store = if org.archive { store } else { None };
+            let (removed, archived) = expire_entries(pool, store, &org_id, table, cutoff).await?;
+            summary.removed += removed;
+            summary.archived += archived;
+        }
+    }
+
+    Ok(summary)
+}
+
+#[derive(sqlx::FromRow)]
+struct ExpiredEntry {
+    id: Uuid,
+    updated: DateTime<Utc>,
+    entry: serde_json::Value,
+}
+
+/// Archive and remove an org's entries in a log table that were last updated before `cutoff`.
+/// Returns the number of entries removed and archived.
+async fn expire_entries(
+    pool: &PostgresPool,
+    store: Option<&ArchiveStore>,
+    org_id: &OrgId,
+    table: LogTable,
+    cutoff: DateTime<Utc>,
+) -> Result<(usize, usize), Error> {
+    let select_query = format!(
+        r##"SELECT l.{id} AS id, l.updated, to_jsonb(l) AS entry
+        FROM {table} l
+        JOIN tasks USING (task_id)
+        WHERE tasks.org_id = $1 AND l.updated < $2
+        ORDER BY l.updated
+        LIMIT $3"##,
+        id = table.id_column(),
+        table = table.table(),
+    );
+    let delete_query = format!(
+        "DELETE FROM {table} WHERE {id} = ANY($1)",
+        table = table.table(),
+        id = table.id_column(),
+    );
+
+    let mut removed = 0;
+    let mut archived = 0;
+    loop {
+        let entries: Vec<ExpiredEntry> = sqlx::query_as(&select_query)
+            .bind(org_id.0)
+            .bind(cutoff)
+            .bind(BATCH_SIZE)
+            .fetch_all(pool)
+            .await?;
+        if entries.is_empty() {
+            break;
+        }
+
+        let mut tx = pool.begin().await?;
+        if let Some(store) = store {
+            write_archive(&mut tx, store, org_id, table, &entries).await?;
+            archived += entries.len();
+        }
+
+        let ids = entries.iter().map(|e| e.id).collect::<Vec<_>>();
+        sqlx::query(&delete_query)
+            .bind(&ids)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        removed += entries.len();
+        event!(Level::INFO, %org_id, table=%table.table(), count=%entries.len(), "Removed expired log entries");
+
+        if (entries.len() as i64) < BATCH_SIZE {
+            break;
+        }
+    }
+
+    Ok((removed, archived))
+}
+
+/// Encode the entries as gzipped NDJSON.
+fn encode_entries(entries: &[ExpiredEntry]) -> Result<Vec<u8>, Error> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    for entry in entries {
+        serde_json::to_writer(&mut encoder, &entry.entry)?;
+        encoder
+            .write_all(b"\n")
+            .map_err(|e| Error::LogArchive(e.to_string()))?;
+    }
+
+    encoder
+        .finish()
+        .map_err(|e| Error::LogArchive(e.to_string()))
+}
+
+async fn write_archive(
+    tx: &mut PgConnection,
+    store: &ArchiveStore,
+    org_id: &OrgId,
+    table: LogTable,
+    entries: &[ExpiredEntry],
+) -> Result<(), Error> {
+    let (oldest, newest) = match (entries.first(), entries.last()) {
+        (Some(first), Some(last)) => (first.updated, last.updated),
+        _ => return Ok(()),
+    };
+
+    let log_archive_id = Uuid::new_v4();
+    let path = format!(
+        "{}/{}/{}/{}.ndjson.gz",
+        org_id,
+        table.table(),
+        oldest.format("%Y/%m/%d"),
+        log_archive_id
+    );
+    let data = encode_entries(entries)?;
+    let location = store.put(&path, data).await?;
+
+    sqlx::query!(
+        r##"INSERT INTO log_archives
+            (log_archive_id, org_id, log_table, location, entries, oldest, newest)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)"##,
+        log_archive_id,
+        org_id.0,
+        table.table(),
+        location,
+        entries.len() as i32,
+        oldest,
+        newest
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn encode_ndjson() {
+        let entries = vec![
+            ExpiredEntry {
+                id: Uuid::new_v4(),
+                updated: Utc::now(),
+                entry: json!({ "a": 1 }),
+            },
+            ExpiredEntry {
+                id: Uuid::new_v4(),
+                updated: Utc::now(),
+                entry: json!({ "b": "two" }),
+            },
+        ];
+
+        let data = encode_entries(&entries).unwrap();
+        let mut decoded = String::new();
+        GzDecoder::new(data.as_slice())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "{\"a\":1}\n{\"b\":\"two\"}\n");
+    }
+}
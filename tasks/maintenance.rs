@@ -0,0 +1,166 @@
+//! Internal housekeeping jobs, such as applying log retention. These run from their own queue,
+//! and the periodic ones are recurring queue jobs so that they keep themselves scheduled.
+
+use std::{borrow::Cow, num::NonZeroU32, ops::Deref, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use ergo_database::{object_id::OrgId, PostgresPool, RedisPool};
+use ergo_graceful_shutdown::GracefulShutdownConsumer;
+use ergo_queues::{
+    generic_stage::{enqueue_jobs, QueueJob},
+    recurring::{RecurringJob, RecurringSchedule},
+    ErrorClass, Queue, QueueJobProcessor, QueueWorkItem,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use tracing::{event, Level};
+
+use crate::{
+    error::Error,
+    log_retention::{apply_retention, ArchiveStore},
+};
+
+const QUEUE_NAME: &str = "er-maintenance";
+const LOG_RETENTION_JOB: &str = "log_retention";
+
+#[derive(Clone)]
+pub struct MaintenanceQueue(Queue);
+impl Deref for MaintenanceQueue {
+    type Target = Queue;
+
+    fn deref(&self) -> &Queue {
+        &self.0
+    }
+}
+
+impl MaintenanceQueue {
+    pub fn new(redis_pool: RedisPool) -> MaintenanceQueue {
+        let queue_name = match redis_pool.key_prefix() {
+            Some(prefix) => format!("{}-{}", prefix, QUEUE_NAME),
+            None => QUEUE_NAME.to_string(),
+        };
+
+        MaintenanceQueue(Queue::new(redis_pool, queue_name, None, Some(1), None))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MaintenanceJob {
+    /// Archive and remove old log entries, for every org or just one.
+    LogRetention { org_id: Option<OrgId> },
+}
+
+/// Enqueue a maintenance job to run as soon as possible. Returns the job ID.
+pub async fn enqueue_maintenance_job(
+    tx: &mut PgConnection,
+    job: &MaintenanceJob,
+    key_prefix: Option<&str>,
+) -> Result<String, Error> {
+    let queue_name = key_prefix
+        .map(|prefix| Cow::Owned(format!("{}-{}", prefix, QUEUE_NAME)))
+        .unwrap_or(Cow::Borrowed(QUEUE_NAME));
+
+    let ids = enqueue_jobs(tx, &[QueueJob::new(queue_name.as_ref(), job)]).await?;
+    Ok(ids.into_iter().next().unwrap_or_default())
+}
+
+pub struct MaintenanceRunnerConfig {
+    pub pg_pool: PostgresPool,
+    pub redis_pool: RedisPool,
+    pub shutdown: GracefulShutdownConsumer,
+    /// When to apply log retention. `None` turns off the scheduled runs, though they can
+    /// still be started manually.
+    pub log_retention_schedule: Option<RecurringSchedule>,
+    pub archive_store: Option<ArchiveStore>,
+}
+
+pub struct MaintenanceRunner {
+    queue: MaintenanceQueue,
+}
+
+impl MaintenanceRunner {
+    pub async fn new(config: MaintenanceRunnerConfig) -> Result<MaintenanceRunner, Error> {
+        let queue = MaintenanceQueue::new(config.redis_pool);
+
+        match config.log_retention_schedule {
+            Some(schedule) => {
+                let job = RecurringJob::from_json_payload(
+                    LOG_RETENTION_JOB,
+                    schedule,
+                    &MaintenanceJob::LogRetention { org_id: None },
+                )?;
+                queue.add_recurring(&job).await?;
+            }
+            None => {
+                queue.remove_recurring(LOG_RETENTION_JOB).await?;
+            }
+        }
+
+        queue.start_scheduled_jobs_enqueuer(config.shutdown.clone());
+        queue.start_dequeuer_loop(
+            config.shutdown,
+            None,
+            NonZeroU32::new(1),
+            MaintenanceJobProcessor {
+                pg_pool: config.pg_pool,
+                archive_store: config.archive_store.map(Arc::new),
+            },
+        );
+
+        Ok(MaintenanceRunner { queue })
+    }
+
+    pub fn queue(&self) -> &MaintenanceQueue {
+        &self.queue
+    }
+}
+
+#[derive(Clone)]
+struct MaintenanceJobProcessor {
+    pg_pool: PostgresPool,
+    archive_store: Option<Arc<ArchiveStore>>,
+}
+
+#[async_trait]
+impl QueueJobProcessor for MaintenanceJobProcessor {
+    type Payload = MaintenanceJob;
+    type Error = Error;
+
+    async fn process(
+        &self,
+        _item: &QueueWorkItem<MaintenanceJob>,
+        job: MaintenanceJob,
+    ) -> Result<(), Error> {
+        match job {
+            MaintenanceJob::LogRetention { org_id } => {
+                let summary = apply_retention(
+                    &self.pg_pool,
+                    self.archive_store.as_deref(),
+                    org_id.as_ref(),
+                    Utc::now(),
+                )
+                .await?;
+                event!(
+                    Level::INFO,
+                    orgs = summary.orgs,
+                    removed = summary.removed,
+                    archived = summary.archived,
+                    "Applied log retention"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn error_class(&self, error: &Error) -> ErrorClass {
+        error.error_class()
+    }
+
+    // Retention can take a while when there's a large backlog of old entries.
+    fn heartbeat_interval(&self) -> Option<Duration> {
+        Some(Duration::from_secs(30))
+    }
+}
@@ -0,0 +1,234 @@
+//! Limits on the size of input and action payloads.
+//!
+//! A payload over the maximum size is rejected before it is queued. A payload that is allowed
+//! but still large is left out of the queue job, and the worker loads it from the inputs_log or
+//! actions_log entry, which always holds a copy, when the job runs. This keeps big webhook
+//! bodies from bloating Redis.
+
+use std::io::Write;
+
+use ergo_database::PostgresPool;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{actions::ActionInvocation, inputs::InputInvocation, Error};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadKind {
+    Input,
+    Action,
+}
+
+impl std::fmt::Display for PayloadKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let desc = match self {
+            Self::Input => "input",
+            Self::Action => "action",
+        };
+
+        f.write_str(desc)
+    }
+}
+
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+#[error("The {kind} payload is {size} bytes, which is over the limit of {max} bytes")]
+pub struct PayloadTooLarge {
+    pub kind: PayloadKind,
+    pub size: usize,
+    pub max: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PayloadLimits {
+    /// The largest input payload that will be accepted.
+    pub max_input_bytes: usize,
+    /// The largest payload that an action can be run with.
+    pub max_action_bytes: usize,
+    /// Payloads larger than this are loaded from the database when the job runs instead of
+    /// being stored in the queue.
+    pub max_queued_bytes: usize,
+}
+
+impl Default for PayloadLimits {
+    fn default() -> Self {
+        PayloadLimits {
+            max_input_bytes: 10 * 1024 * 1024,
+            max_action_bytes: 10 * 1024 * 1024,
+            max_queued_bytes: 64 * 1024,
+        }
+    }
+}
+
+impl PayloadLimits {
+    /// Read the limits from `INPUT_MAX_PAYLOAD_BYTES`, `ACTION_MAX_PAYLOAD_BYTES`, and
+    /// `QUEUE_MAX_PAYLOAD_BYTES`.
+    pub fn from_env() -> Self {
+        fn env_or(name: &str, default: usize) -> usize {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        }
+
+        let default = PayloadLimits::default();
+        PayloadLimits {
+            max_input_bytes: env_or("INPUT_MAX_PAYLOAD_BYTES", default.max_input_bytes),
+            max_action_bytes: env_or("ACTION_MAX_PAYLOAD_BYTES", default.max_action_bytes),
+            max_queued_bytes: env_or("QUEUE_MAX_PAYLOAD_BYTES", default.max_queued_bytes),
+        }
+    }
+
+    pub fn max_bytes(&self, kind: PayloadKind) -> usize {
+        match kind {
+            PayloadKind::Input => self.max_input_bytes,
+            PayloadKind::Action => self.max_action_bytes,
+        }
+    }
+
+    /// Check a payload against the limits. On success, returns true if the payload is too
+    /// large to store in the queue job.
+    pub fn check(
+        &self,
+        kind: PayloadKind,
+        payload: &serde_json::Value,
+    ) -> Result<bool, PayloadTooLarge> {
+        let size = payload_size(payload);
+        let max = self.max_bytes(kind);
+        if size > max {
+            return Err(PayloadTooLarge { kind, size, max });
+        }
+
+        Ok(size > self.max_queued_bytes)
+    }
+}
+
+lazy_static! {
+    pub static ref PAYLOAD_LIMITS: PayloadLimits = PayloadLimits::from_env();
+}
+
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The size of the payload when serialized as JSON.
+pub fn payload_size(payload: &serde_json::Value) -> usize {
+    let mut counter = ByteCounter(0);
+    // Writing to the counter can't fail, and neither can serializing a `Value`.
+    serde_json::to_writer(&mut counter, payload).ok();
+    counter.0
+}
+
+/// If the input's payload was left out of the queue job, load it from the inputs log.
+pub async fn load_input_payload(
+    pool: &PostgresPool,
+    invocation: &mut InputInvocation,
+) -> Result<(), Error> {
+    if !invocation.payload_in_log {
+        return Ok(());
+    }
+
+    let payload = sqlx::query_scalar!(
+        "SELECT payload FROM inputs_log WHERE inputs_log_id=$1",
+        invocation.inputs_log_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .flatten()
+    .ok_or(Error::NotFound)?;
+
+    invocation.payload = payload;
+    invocation.payload_in_log = false;
+    Ok(())
+}
+
+/// If the action's payload was left out of the queue job, load it from the actions log.
+pub async fn load_action_payload(
+    pool: &PostgresPool,
+    invocation: &mut ActionInvocation,
+) -> Result<(), Error> {
+    if !invocation.payload_in_log {
+        return Ok(());
+    }
+
+    let payload = sqlx::query_scalar!(
+        "SELECT payload FROM actions_log WHERE actions_log_id=$1",
+        invocation.actions_log_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .flatten()
+    .ok_or(Error::NotFound)?;
+
+    invocation.payload = payload;
+    invocation.payload_in_log = false;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn limits() -> PayloadLimits {
+        PayloadLimits {
+            max_input_bytes: 100,
+            max_action_bytes: 50,
+            max_queued_bytes: 20,
+        }
+    }
+
+    #[test]
+    fn size() {
+        let payload = json!({ "a": [1, 2, "three"] });
+        assert_eq!(
+            payload_size(&payload),
+            serde_json::to_vec(&payload).unwrap().len()
+        );
+        assert_eq!(payload_size(&serde_json::Value::Null), 4);
+    }
+
+    #[test]
+    fn small_payload_is_queued() {
+        let stored = limits()
+            .check(PayloadKind::Input, &json!({ "a": 1 }))
+            .expect("payload is allowed");
+        assert!(!stored);
+    }
+
+    #[test]
+    fn large_payload_is_stored() {
+        let payload = json!({ "value": "x".repeat(30) });
+        let stored = limits()
+            .check(PayloadKind::Input, &payload)
+            .expect("payload is allowed");
+        assert!(stored);
+    }
+
+    #[test]
+    fn oversized_payload_is_rejected() {
+        let payload = json!({ "value": "x".repeat(60) });
+        let size = payload_size(&payload);
+
+        assert!(limits().check(PayloadKind::Input, &payload).is_ok());
+        assert_eq!(
+            limits().check(PayloadKind::Action, &payload),
+            Err(PayloadTooLarge {
+                kind: PayloadKind::Action,
+                size,
+                max: 50
+            })
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use crate::Error;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use ergo_database::object_id::PeriodicTriggerId;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
@@ -13,21 +13,185 @@ pub use native::*;
 pub enum PeriodicSchedule {
     /// A cron string of the format
     /// second   minute   hour   day-of-month   month   day-of-week   year
+    /// evaluated in UTC.
     Cron(String),
+    /// A cron string evaluated in a specific timezone.
+    ZonedCron(ZonedCronSchedule),
+    /// Run at a fixed interval.
+    Interval(IntervalSchedule),
+    /// An RFC5545 recurrence rule.
+    Rrule(RruleSchedule),
+}
+
+#[derive(Debug, Clone, JsonSchema, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct ZonedCronSchedule {
+    /// A cron string of the format
+    /// second   minute   hour   day-of-month   month   day-of-week   year
+    pub cron: String,
+    /// An IANA timezone name such as "America/Los_Angeles". Defaults to UTC.
+    pub timezone: Option<String>,
+    /// Delay each run by a random amount, up to this percentage of the time until the
+    /// following run.
+    pub jitter_percent: Option<u8>,
+}
+
+#[derive(Debug, Clone, JsonSchema, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct IntervalSchedule {
+    /// The number of seconds between runs.
+    pub seconds: u64,
+    /// Runs are aligned to this time. Defaults to the Unix epoch.
+    pub start: Option<DateTime<Utc>>,
+    /// Delay each run by a random amount, up to this percentage of the interval.
+    pub jitter_percent: Option<u8>,
+}
+
+#[derive(Debug, Clone, JsonSchema, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct RruleSchedule {
+    /// The RRULE portion of the recurrence, such as `FREQ=MONTHLY;BYDAY=1MO;BYHOUR=9`.
+    pub rule: String,
+    /// The first occurrence of the rule, used as DTSTART.
+    pub start: DateTime<Utc>,
+    /// An IANA timezone name such as "America/Los_Angeles". Defaults to UTC.
+    pub timezone: Option<String>,
+    /// Delay each run by a random amount, up to this percentage of the time until the
+    /// following run.
+    pub jitter_percent: Option<u8>,
 }
 
 #[cfg(not(target_family = "wasm"))]
 ergo_database::sqlx_json_decode!(PeriodicSchedule);
 
+fn parse_timezone(timezone: Option<&str>) -> Result<chrono_tz::Tz, Error> {
+    match timezone {
+        Some(tz) => chrono_tz::Tz::from_str(tz).map_err(|_| Error::InvalidTimezone(tz.to_string())),
+        None => Ok(chrono_tz::UTC),
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+fn random_jitter(max: Duration) -> Duration {
+    use rand::Rng;
+    let max_ms = max.num_milliseconds();
+    if max_ms <= 0 {
+        return Duration::zero();
+    }
+
+    Duration::milliseconds(rand::thread_rng().gen_range(0..=max_ms))
+}
+
+#[cfg(target_family = "wasm")]
+fn random_jitter(_max: Duration) -> Duration {
+    Duration::zero()
+}
+
 impl PeriodicSchedule {
+    /// Return the next time that the schedule should run, including jitter.
     pub fn next_run(&self) -> Result<Option<DateTime<Utc>>, Error> {
+        let next = match self.next_scheduled_after(Utc::now())? {
+            Some(next) => next,
+            None => return Ok(None),
+        };
+
+        let jitter = match self.max_jitter(next)? {
+            Some(max) => random_jitter(max),
+            None => Duration::zero(),
+        };
+
+        Ok(Some(next + jitter))
+    }
+
+    /// Return the first scheduled time strictly after `after`, without any jitter applied.
+    pub fn next_scheduled_after(
+        &self,
+        after: DateTime<Utc>,
+    ) -> Result<Option<DateTime<Utc>>, Error> {
         match self {
             Self::Cron(c) => {
                 let schedule = cron::Schedule::from_str(c.as_str())?;
-                Ok(schedule.upcoming(Utc).next())
+                Ok(schedule.after(&after).next())
+            }
+            Self::ZonedCron(ZonedCronSchedule { cron, timezone, .. }) => {
+                let tz = parse_timezone(timezone.as_deref())?;
+                let schedule = cron::Schedule::from_str(cron.as_str())?;
+                Ok(schedule
+                    .after(&after.with_timezone(&tz))
+                    .next()
+                    .map(|d| d.with_timezone(&Utc)))
+            }
+            Self::Interval(IntervalSchedule { seconds, start, .. }) => {
+                if *seconds == 0 {
+                    return Err(Error::InvalidSchedule(
+                        "Interval must be at least one second".to_string(),
+                    ));
+                }
+
+                let start = start.unwrap_or_else(|| Utc.timestamp_opt(0, 0).unwrap());
+                if after < start {
+                    return Ok(Some(start));
+                }
+
+                let interval = *seconds as i64;
+                let elapsed = (after - start).num_seconds();
+                let periods = elapsed / interval + 1;
+                Ok(Some(start + Duration::seconds(periods * interval)))
+            }
+            Self::Rrule(RruleSchedule {
+                rule,
+                start,
+                timezone,
+                ..
+            }) => {
+                let tz = rrule::Tz::Tz(parse_timezone(timezone.as_deref())?);
+                let rrule = rule
+                    .parse::<rrule::RRule<rrule::Unvalidated>>()
+                    .map_err(|e| Error::InvalidSchedule(e.to_string()))?;
+                let set = rrule
+                    .build(start.with_timezone(&tz))
+                    .map_err(|e| Error::InvalidSchedule(e.to_string()))?;
+
+                let next = set
+                    .after(after.with_timezone(&tz))
+                    .all(2)
+                    .dates
+                    .into_iter()
+                    .map(|d| d.with_timezone(&Utc))
+                    .find(|d| *d > after);
+                Ok(next)
             }
         }
     }
+
+    fn jitter_percent(&self) -> Option<u8> {
+        match self {
+            Self::Cron(_) => None,
+            Self::ZonedCron(s) => s.jitter_percent,
+            Self::Interval(s) => s.jitter_percent,
+            Self::Rrule(s) => s.jitter_percent,
+        }
+        .filter(|p| *p > 0)
+    }
+
+    /// The maximum jitter to apply to a run at `scheduled`. This is a percentage of the gap
+    /// between `scheduled` and the run after it, so that jitter never pushes a run past the
+    /// next one.
+    fn max_jitter(&self, scheduled: DateTime<Utc>) -> Result<Option<Duration>, Error> {
+        let percent = match self.jitter_percent() {
+            Some(p) => p.min(100) as i64,
+            None => return Ok(None),
+        };
+
+        let gap = match self {
+            Self::Interval(IntervalSchedule { seconds, .. }) => Duration::seconds(*seconds as i64),
+            _ => match self.next_scheduled_after(scheduled)? {
+                Some(following) => following - scheduled,
+                None => return Ok(None),
+            },
+        };
+
+        Ok(Some(Duration::milliseconds(
+            gap.num_milliseconds() * percent / 100,
+        )))
+    }
 }
 
 #[derive(Debug, JsonSchema, Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -50,7 +214,10 @@ pub struct PeriodicTaskTriggerInput {
 
 #[cfg(not(target_family = "wasm"))]
 mod native {
-    use crate::inputs::{enqueue_input, queue::InputQueue, EnqueueInputOptions};
+    use crate::inputs::{
+        chain::InputChain, enqueue_input, queue::InputQueue, secrets::restore_masked_secrets,
+        EnqueueInputOptions,
+    };
 
     use super::*;
     use ergo_database::{
@@ -92,11 +259,35 @@ mod native {
         .fetch_all(&mut *tx)
         .await?;
 
+        let input = sqlx::query!(
+            "SELECT payload_schema FROM inputs WHERE input_id=$1",
+            input_id.0
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        // Payloads read from the API have their secret fields masked, so fill those back in
+        // from the existing triggers.
+        let periodic = periodic
+            .iter()
+            .map(|new_value| {
+                let mut new_value = new_value.clone();
+                if let Some(ex) = existing.iter().find(|ex| ex.schedule == new_value.schedule) {
+                    restore_masked_secrets(
+                        &input.payload_schema,
+                        &mut new_value.payload,
+                        &ex.payload,
+                    );
+                }
+                new_value
+            })
+            .collect::<SmallVec<[PeriodicTaskTriggerInput; 2]>>();
+
         let queue_name = InputQueue::queue_name(redis_key_prefix);
         let mut matched_existing = SmallVec::<[&PeriodicTriggerId; 2]>::new();
         let mut new_to_add = SmallVec::<[(PeriodicTriggerId, &PeriodicTaskTriggerInput); 2]>::new();
 
-        for new_value in periodic {
+        for new_value in &periodic {
             let should_enqueue_task = task_enabled && new_value.enabled;
 
             if let Some(ex) = existing.iter().find(|ex| ex.schedule == new_value.schedule) {
@@ -180,17 +371,6 @@ mod native {
         }
 
         if !new_to_add.is_empty() {
-            let info = sqlx::query!(
-                r##"
-                SELECT payload_schema
-                FROM inputs
-                WHERE input_id=$1
-                "##,
-                input_id.0
-            )
-            .fetch_one(&mut *tx)
-            .await?;
-
             for (periodic_trigger_id, trigger) in new_to_add {
                 if let Some(next_date) = trigger.schedule.next_run()? {
                     enqueue_input(EnqueueInputOptions {
@@ -205,10 +385,13 @@ mod native {
                         task_trigger_local_id: task_trigger_local_id.to_string(),
                         task_trigger_name: task_trigger_name.to_string(),
                         periodic_trigger_id: Some(periodic_trigger_id),
-                        payload_schema: &info.payload_schema,
+                        payload_schema: &input.payload_schema,
                         payload: trigger.payload.clone(),
                         redis_key_prefix: redis_key_prefix.as_deref(),
                         trigger_at: Some(next_date),
+                        replay_of: None,
+                        interactive: false,
+                        chain: InputChain::default(),
                     })
                     .await?;
                 }
@@ -273,6 +456,9 @@ mod native {
                     periodic_trigger_id: Some(trigger.periodic_trigger_id),
                     redis_key_prefix,
                     trigger_at: Some(next_time),
+                    replay_of: None,
+                    interactive: false,
+                    chain: InputChain::default(),
                 })
                 .await?;
             }
@@ -298,6 +484,10 @@ mod native {
                     event!(Level::ERROR, error=%e, "Failed to check missing periodic triggers");
                 }
 
+                if let Err(e) = crate::state_reset::run_due_state_resets(&pool).await {
+                    event!(Level::ERROR, error=%e, "Failed to run scheduled state resets");
+                }
+
                 tokio::select! {
                     _ = tokio::time::sleep(check_interval) => continue,
                     _ = shutdown.wait_for_shutdown() => break,
@@ -306,3 +496,118 @@ mod native {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn cron() {
+        let schedule = PeriodicSchedule::Cron("0 0 9 * * * *".to_string());
+        let next = schedule
+            .next_scheduled_after(date("2023-03-11T15:00:00Z"))
+            .unwrap();
+        assert_eq!(next, Some(date("2023-03-12T09:00:00Z")));
+    }
+
+    #[test]
+    fn zoned_cron_across_dst() {
+        let schedule = PeriodicSchedule::ZonedCron(ZonedCronSchedule {
+            cron: "0 0 9 * * * *".to_string(),
+            timezone: Some("America/New_York".to_string()),
+            jitter_percent: None,
+        });
+
+        let before_dst = schedule
+            .next_scheduled_after(date("2023-03-10T15:00:00Z"))
+            .unwrap();
+        assert_eq!(before_dst, Some(date("2023-03-11T14:00:00Z")));
+
+        let after_dst = schedule
+            .next_scheduled_after(date("2023-03-11T15:00:00Z"))
+            .unwrap();
+        assert_eq!(after_dst, Some(date("2023-03-12T13:00:00Z")));
+    }
+
+    #[test]
+    fn bad_timezone() {
+        let schedule = PeriodicSchedule::ZonedCron(ZonedCronSchedule {
+            cron: "0 0 9 * * * *".to_string(),
+            timezone: Some("Not/A_Zone".to_string()),
+            jitter_percent: None,
+        });
+
+        let result = schedule.next_scheduled_after(date("2023-03-10T15:00:00Z"));
+        assert!(matches!(result, Err(Error::InvalidTimezone(_))));
+    }
+
+    #[test]
+    fn interval() {
+        let schedule = PeriodicSchedule::Interval(IntervalSchedule {
+            seconds: 90 * 60,
+            start: Some(date("2023-01-01T00:00:00Z")),
+            jitter_percent: None,
+        });
+
+        assert_eq!(
+            schedule
+                .next_scheduled_after(date("2023-01-01T02:00:00Z"))
+                .unwrap(),
+            Some(date("2023-01-01T03:00:00Z"))
+        );
+
+        // Exactly on a boundary moves to the next one
+        assert_eq!(
+            schedule
+                .next_scheduled_after(date("2023-01-01T03:00:00Z"))
+                .unwrap(),
+            Some(date("2023-01-01T04:30:00Z"))
+        );
+
+        // Before the start
+        assert_eq!(
+            schedule
+                .next_scheduled_after(date("2022-12-01T00:00:00Z"))
+                .unwrap(),
+            Some(date("2023-01-01T00:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn rrule_monthly_by_weekday() {
+        let schedule = PeriodicSchedule::Rrule(RruleSchedule {
+            rule: "FREQ=MONTHLY;BYDAY=1MO;BYHOUR=9;BYMINUTE=0;BYSECOND=0".to_string(),
+            start: date("2023-01-02T14:00:00Z"),
+            timezone: Some("America/New_York".to_string()),
+            jitter_percent: None,
+        });
+
+        let next = schedule
+            .next_scheduled_after(date("2023-03-10T00:00:00Z"))
+            .unwrap();
+        // First Monday of April, 9am EDT
+        assert_eq!(next, Some(date("2023-04-03T13:00:00Z")));
+    }
+
+    #[test]
+    fn jitter_is_bounded() {
+        let schedule = PeriodicSchedule::Interval(IntervalSchedule {
+            seconds: 1000,
+            start: None,
+            jitter_percent: Some(10),
+        });
+
+        let scheduled = date("2023-01-01T00:00:00Z");
+        let max = schedule.max_jitter(scheduled).unwrap();
+        assert_eq!(max, Some(Duration::seconds(100)));
+
+        for _ in 0..20 {
+            let jitter = random_jitter(max.unwrap());
+            assert!(jitter >= Duration::zero() && jitter <= Duration::seconds(100));
+        }
+    }
+}
@@ -6,11 +6,31 @@ use ergo_database::{PostgresPool, RedisPool};
 use ergo_graceful_shutdown::GracefulShutdownConsumer;
 use ergo_queues::{
     generic_stage,
-    postgres_drain::{QueueStageDrain, QueueStageDrainConfig},
+    postgres_drain::{DrainStatsHandle, Drainer, QueueStageDrain, QueueStageDrainConfig},
+    table_stage::DEFAULT_BATCH_SIZE,
 };
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// The most jobs to drain from a stage table in one transaction. Set with
+    /// `QUEUE_DRAIN_BATCH_SIZE`.
+    static ref DRAIN_BATCH_SIZE: usize = std::env::var("QUEUE_DRAIN_BATCH_SIZE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_BATCH_SIZE);
+
+    /// How many drained jobs to send to Redis at once. Set with `QUEUE_DRAIN_PARALLELISM`.
+    static ref DRAIN_PARALLELISM: usize = std::env::var("QUEUE_DRAIN_PARALLELISM")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1);
+}
 
 pub struct AllQueuesDrain {
-    generic_drain: QueueStageDrain,
+    pg_pool: PostgresPool,
+    redis_pool: RedisPool,
+    shutdown: GracefulShutdownConsumer,
+    drains: Vec<QueueStageDrain>,
 }
 
 impl AllQueuesDrain {
@@ -19,14 +39,39 @@ impl AllQueuesDrain {
         redis_pool: RedisPool,
         shutdown: GracefulShutdownConsumer,
     ) -> Result<AllQueuesDrain, Error> {
-        let generic_drain = QueueStageDrain::new(QueueStageDrainConfig {
-            queue: None,
-            drainer: generic_stage::QueueDrainer {},
-            db_pool: pg_pool,
+        let mut drain = AllQueuesDrain {
+            pg_pool,
             redis_pool,
             shutdown,
+            drains: Vec::new(),
+        };
+
+        drain.add_stage(generic_stage::queue_stage().batch_size(*DRAIN_BATCH_SIZE))?;
+
+        Ok(drain)
+    }
+
+    /// Start draining another stage table.
+    pub fn add_stage<D: Drainer + 'static>(&mut self, drainer: D) -> Result<(), Error> {
+        let drain = QueueStageDrain::new(QueueStageDrainConfig {
+            queue: None,
+            drainer,
+            parallelism: *DRAIN_PARALLELISM,
+            db_pool: self.pg_pool.clone(),
+            redis_pool: self.redis_pool.clone(),
+            shutdown: self.shutdown.clone(),
         })?;
 
-        Ok(AllQueuesDrain { generic_drain })
+        self.drains.push(drain);
+        Ok(())
+    }
+
+    /// Get a handle that reads the latest stats of each stage.
+    pub fn stats(&self) -> DrainStatsHandle {
+        let mut handle = DrainStatsHandle::default();
+        for drain in &self.drains {
+            handle.add(drain);
+        }
+        handle
     }
 }
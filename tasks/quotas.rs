@@ -0,0 +1,318 @@
+//! Per-org quotas on the number of tasks and on daily usage. Daily usage is counted in the
+//! `org_usage` table and resets at midnight UTC. A quota that is not set has no limit.
+
+use chrono::NaiveDate;
+use ergo_database::{
+    object_id::{OrgId, TaskId},
+    PostgresPool,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use thiserror::Error;
+
+use crate::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaKind {
+    Tasks,
+    InputsPerDay,
+    ActionsPerDay,
+    JsCpuMsPerDay,
+}
+
+impl std::fmt::Display for QuotaKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let desc = match self {
+            Self::Tasks => "tasks",
+            Self::InputsPerDay => "inputs per day",
+            Self::ActionsPerDay => "action executions per day",
+            Self::JsCpuMsPerDay => "milliseconds of script time per day",
+        };
+
+        f.write_str(desc)
+    }
+}
+
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+#[error("Organization quota exceeded: {used} of {limit} {kind} used")]
+pub struct QuotaExceeded {
+    pub kind: QuotaKind,
+    pub limit: i64,
+    pub used: i64,
+}
+
+impl QuotaExceeded {
+    /// True if the quota will reset at the start of the next day.
+    pub fn is_daily(&self) -> bool {
+        self.kind != QuotaKind::Tasks
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct OrgQuotas {
+    pub max_tasks: Option<i64>,
+    pub max_inputs_per_day: Option<i64>,
+    pub max_actions_per_day: Option<i64>,
+    pub max_js_cpu_ms_per_day: Option<i64>,
+}
+
+impl OrgQuotas {
+    fn limit(&self, kind: QuotaKind) -> Option<i64> {
+        match kind {
+            QuotaKind::Tasks => self.max_tasks,
+            QuotaKind::InputsPerDay => self.max_inputs_per_day,
+            QuotaKind::ActionsPerDay => self.max_actions_per_day,
+            QuotaKind::JsCpuMsPerDay => self.max_js_cpu_ms_per_day,
+        }
+    }
+
+    /// Return an error if `used` is over the limit for `kind`.
+    pub fn check(&self, kind: QuotaKind, used: i64) -> Result<(), QuotaExceeded> {
+        match self.limit(kind) {
+            Some(limit) if used > limit => Err(QuotaExceeded { kind, limit, used }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct OrgUsage {
+    /// The day that the daily counts apply to, in UTC.
+    pub day: Option<NaiveDate>,
+    pub tasks: i64,
+    pub inputs: i64,
+    pub actions: i64,
+    pub js_cpu_ms: i64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct QuotaReport {
+    pub quotas: OrgQuotas,
+    pub usage: OrgUsage,
+}
+
+pub async fn get_quotas(tx: &mut PgConnection, org_id: &OrgId) -> Result<OrgQuotas, Error> {
+    let quotas = sqlx::query_as!(
+        OrgQuotas,
+        r##"SELECT max_tasks, max_inputs_per_day, max_actions_per_day, max_js_cpu_ms_per_day
+        FROM org_quotas
+        WHERE org_id=$1"##,
+        org_id.0
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    Ok(quotas.unwrap_or_default())
+}
+
+pub async fn set_quotas(
+    tx: &mut PgConnection,
+    org_id: &OrgId,
+    quotas: &OrgQuotas,
+) -> Result<(), Error> {
+    sqlx::query!(
+        r##"INSERT INTO org_quotas
+            (org_id, max_tasks, max_inputs_per_day, max_actions_per_day, max_js_cpu_ms_per_day)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (org_id) DO UPDATE SET
+            max_tasks=EXCLUDED.max_tasks,
+            max_inputs_per_day=EXCLUDED.max_inputs_per_day,
+            max_actions_per_day=EXCLUDED.max_actions_per_day,
+            max_js_cpu_ms_per_day=EXCLUDED.max_js_cpu_ms_per_day,
+            updated=now()"##,
+        org_id.0,
+        quotas.max_tasks,
+        quotas.max_inputs_per_day,
+        quotas.max_actions_per_day,
+        quotas.max_js_cpu_ms_per_day
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Get the org's quotas along with its usage for the current day.
+pub async fn get_quota_report(tx: &mut PgConnection, org_id: &OrgId) -> Result<QuotaReport, Error> {
+    let quotas = get_quotas(tx, org_id).await?;
+    let usage = sqlx::query_as!(
+        OrgUsage,
+        r##"SELECT (now() AT TIME ZONE 'UTC')::date AS day,
+            (SELECT COUNT(*) FROM tasks WHERE org_id=$1 AND NOT deleted) AS "tasks!",
+            COALESCE(ou.inputs, 0) AS "inputs!",
+            COALESCE(ou.actions, 0) AS "actions!",
+            COALESCE(ou.js_cpu_ms, 0) AS "js_cpu_ms!"
+        FROM (SELECT 1) dummy
+        LEFT JOIN org_usage ou ON ou.org_id=$1 AND ou.day=(now() AT TIME ZONE 'UTC')::date"##,
+        org_id.0
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    Ok(QuotaReport { quotas, usage })
+}
+
+/// Check that the org can create another task. This locks the org's quota row so that
+/// concurrent task creation can't go over the limit.
+pub async fn check_task_quota(tx: &mut PgConnection, org_id: &OrgId) -> Result<(), Error> {
+    let max_tasks = sqlx::query_scalar!(
+        "SELECT max_tasks FROM org_quotas WHERE org_id=$1 FOR UPDATE",
+        org_id.0
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .flatten();
+
+    let Some(max_tasks) = max_tasks else {
+        return Ok(());
+    };
+
+    let tasks = sqlx::query_scalar!(
+        r##"SELECT COUNT(*) AS "count!" FROM tasks WHERE org_id=$1 AND NOT deleted"##,
+        org_id.0
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    OrgQuotas {
+        max_tasks: Some(max_tasks),
+        ..Default::default()
+    }
+    .check(QuotaKind::Tasks, tasks + 1)?;
+
+    Ok(())
+}
+
+/// Return an error if the org has already used up a daily quota.
+pub async fn check_daily_quota(
+    tx: &mut PgConnection,
+    org_id: &OrgId,
+    kind: QuotaKind,
+) -> Result<(), Error> {
+    let quotas = get_quotas(tx, org_id).await?;
+    let Some(limit) = quotas.limit(kind) else {
+        return Ok(());
+    };
+
+    let usage = sqlx::query!(
+        r##"SELECT inputs, actions, js_cpu_ms
+        FROM org_usage
+        WHERE org_id=$1 AND day=(now() AT TIME ZONE 'UTC')::date"##,
+        org_id.0
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let used = usage
+        .map(|u| match kind {
+            QuotaKind::InputsPerDay => u.inputs,
+            QuotaKind::ActionsPerDay => u.actions,
+            _ => u.js_cpu_ms,
+        })
+        .unwrap_or(0);
+
+    if used >= limit {
+        return Err(QuotaExceeded { kind, limit, used }.into());
+    }
+
+    Ok(())
+}
+
+/// Add `amount` to today's usage for a daily quota, and return an error if that puts the org
+/// over its limit. The usage is only saved if the transaction commits.
+pub async fn consume_daily_quota(
+    tx: &mut PgConnection,
+    org_id: &OrgId,
+    kind: QuotaKind,
+    amount: i64,
+) -> Result<(), Error> {
+    let quotas = get_quotas(tx, org_id).await?;
+    let used = add_usage(tx, org_id, kind, amount).await?;
+    quotas.check(kind, used)?;
+    Ok(())
+}
+
+/// Add to today's usage without enforcing the quota, returning the new total.
+pub(crate) async fn add_usage(
+    tx: &mut PgConnection,
+    org_id: &OrgId,
+    kind: QuotaKind,
+    amount: i64,
+) -> Result<i64, Error> {
+    let (inputs, actions, js_cpu_ms) = match kind {
+        QuotaKind::InputsPerDay => (amount, 0, 0),
+        QuotaKind::ActionsPerDay => (0, amount, 0),
+        QuotaKind::JsCpuMsPerDay => (0, 0, amount),
+        QuotaKind::Tasks => return Ok(0),
+    };
+
+    let usage = sqlx::query!(
+        r##"INSERT INTO org_usage (org_id, day, inputs, actions, js_cpu_ms)
+        VALUES ($1, (now() AT TIME ZONE 'UTC')::date, $2, $3, $4)
+        ON CONFLICT (org_id, day) DO UPDATE SET
+            inputs=org_usage.inputs + EXCLUDED.inputs,
+            actions=org_usage.actions + EXCLUDED.actions,
+            js_cpu_ms=org_usage.js_cpu_ms + EXCLUDED.js_cpu_ms
+        RETURNING inputs, actions, js_cpu_ms"##,
+        org_id.0,
+        inputs,
+        actions,
+        js_cpu_ms
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    Ok(match kind {
+        QuotaKind::InputsPerDay => usage.inputs,
+        QuotaKind::ActionsPerDay => usage.actions,
+        _ => usage.js_cpu_ms,
+    })
+}
+
+/// Record time spent running a task's scripts. This runs outside of the task's transaction so
+/// that the time is counted even when the script fails.
+pub async fn record_js_time(pool: &PostgresPool, task_id: &TaskId, ms: i64) -> Result<(), Error> {
+    if ms <= 0 {
+        return Ok(());
+    }
+
+    sqlx::query!(
+        r##"INSERT INTO org_usage (org_id, day, js_cpu_ms)
+        SELECT org_id, (now() AT TIME ZONE 'UTC')::date, $2
+        FROM tasks WHERE task_id=$1
+        ON CONFLICT (org_id, day) DO UPDATE SET js_cpu_ms=org_usage.js_cpu_ms + EXCLUDED.js_cpu_ms"##,
+        task_id.0,
+        ms
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_quota() {
+        let quotas = OrgQuotas {
+            max_tasks: Some(5),
+            max_inputs_per_day: None,
+            ..Default::default()
+        };
+
+        quotas.check(QuotaKind::Tasks, 5).unwrap();
+        quotas.check(QuotaKind::InputsPerDay, 1_000_000).unwrap();
+        assert_eq!(
+            quotas.check(QuotaKind::Tasks, 6),
+            Err(QuotaExceeded {
+                kind: QuotaKind::Tasks,
+                limit: 5,
+                used: 6
+            })
+        );
+    }
+}
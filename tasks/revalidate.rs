@@ -0,0 +1,238 @@
+//! Re-check every stored task against the current task engine. After an upgrade, a task's saved
+//! config may no longer deserialize or validate, and a JS task's bundle may be out of date. This
+//! finds those tasks up front instead of when they are next triggered, and rewrites the
+//! `compiled` column of the valid tasks in the current format.
+
+use ergo_database::object_id::{OrgId, TaskId};
+use ergo_localization::Localize;
+use fxhash::FxHashMap;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::{
+    actions::{execute::ScriptOrTemplate, template::TemplateFields, Action, TaskAction},
+    dependents::DependentValidation,
+    inputs::Input,
+    scripting::bundle::{bundle_task, BUNDLE_CONFIG},
+    DependencyValidateError, Error, TaskConfig, TaskTrigger,
+};
+
+#[derive(Debug, Default)]
+pub struct RevalidateResult {
+    pub validation: DependentValidation,
+    /// The tasks whose `compiled` config changed when it was recompiled.
+    pub recompiled: Vec<TaskId>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct InvalidTaskReport {
+    pub task_id: TaskId,
+    pub org_id: OrgId,
+    pub name: String,
+    pub errors: Vec<String>,
+}
+
+/// A summary of [RevalidateResult] for display.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct RevalidateReport {
+    pub checked: usize,
+    pub recompiled: Vec<TaskId>,
+    pub invalid: Vec<InvalidTaskReport>,
+}
+
+impl RevalidateResult {
+    pub fn report(&self, locale: Option<&str>) -> RevalidateReport {
+        RevalidateReport {
+            checked: self.validation.checked.len(),
+            recompiled: self.recompiled.clone(),
+            invalid: self
+                .validation
+                .invalid
+                .iter()
+                .map(|task| InvalidTaskReport {
+                    task_id: task.task_id.clone(),
+                    org_id: task.org_id.clone(),
+                    name: task.name.clone(),
+                    errors: task.errors.iter().map(|e| e.localize(locale)).collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Validate and recompile the tasks, optionally only those in a single org. With `dry_run`, the
+/// recompiled configs are not saved.
+///
+/// This doesn't record the validation errors on the tasks. Pass the result's `validation` to
+/// [crate::dependents::apply_dependent_validation] to do that.
+pub async fn revalidate_tasks(
+    tx: &mut PgConnection,
+    org_id: Option<&OrgId>,
+    dry_run: bool,
+) -> Result<RevalidateResult, Error> {
+    let tasks = sqlx::query!(
+        r##"SELECT task_id AS "task_id: TaskId",
+            tasks.org_id AS "org_id: OrgId",
+            tasks.name,
+            task_template_id,
+            task_template_version,
+            task_templates.compiled,
+            COALESCE(task_triggers, '{}'::jsonb) AS "triggers!",
+            COALESCE(task_actions, '{}'::jsonb) AS "actions!"
+        FROM tasks
+        JOIN task_templates USING (task_template_id, task_template_version)
+
+        LEFT JOIN LATERAL (
+            SELECT jsonb_object_agg(task_action_local_id, jsonb_build_object(
+                'action_id', action_id,
+                'task_local_id', task_action_local_id,
+                'task_id', task_actions.task_id,
+                'account_id', account_id,
+                'name', task_actions.name,
+                'action_template', task_actions.action_template,
+                'compensate_with', task_actions.compensate_with
+            )) AS task_actions
+            FROM task_actions WHERE task_actions.task_id = tasks.task_id
+        ) ta ON true
+
+        LEFT JOIN LATERAL (
+            SELECT jsonb_object_agg(task_trigger_local_id, jsonb_build_object(
+                'task_trigger_id', task_triggers.task_trigger_id,
+                'task_id', task_triggers.task_id,
+                'input_id', input_id,
+                'name', task_triggers.name,
+                'description', task_triggers.description,
+                'last_payload', null,
+                'periodic', null,
+                'dedupe', task_triggers.dedupe,
+                'sensitive', task_triggers.sensitive,
+                'routes', task_triggers.routes
+            )) AS task_triggers
+            FROM task_triggers WHERE task_triggers.task_id = tasks.task_id
+        ) tt ON true
+
+        WHERE NOT tasks.deleted AND ($1::uuid IS NULL OR tasks.org_id=$1)
+        ORDER BY tasks.org_id, task_id"##,
+        org_id.map(|o| o.0)
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let actions = sqlx::query_as!(
+        Action,
+        r##"SELECT
+        action_id as "action_id: _",
+        action_category_id as "action_category_id: _",
+        name,
+        description,
+        executor_id,
+        executor_template as "executor_template: ScriptOrTemplate",
+        template_fields as "template_fields: TemplateFields",
+        timeout,
+        postprocess_script,
+        account_required,
+        COALESCE(array_agg(account_type_id) FILTER(WHERE account_type_id IS NOT NULL), ARRAY[]::text[]) "account_types!",
+        tags
+        FROM actions
+        LEFT JOIN allowed_action_account_types USING(action_id)
+        GROUP BY action_id"##,
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .map(|action| (action.action_id.to_string(), action))
+    .collect::<FxHashMap<_, _>>();
+
+    let inputs = sqlx::query_as!(
+        Input,
+        r##"SELECT
+            input_id as "input_id: _",
+            input_category_id as "input_category_id: _",
+            name, description, payload_schema
+        FROM inputs"##
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .map(|input| (input.input_id.to_string(), input))
+    .collect::<FxHashMap<_, _>>();
+
+    let mut result = RevalidateResult::default();
+    for task in tasks {
+        result.validation.add_checked(&task.task_id);
+        let mut add_error = |error| {
+            result
+                .validation
+                .add_error(&task.task_id, &task.org_id, &task.name, error)
+        };
+
+        let task_triggers: FxHashMap<String, TaskTrigger> = serde_json::from_value(task.triggers)?;
+        let task_actions: FxHashMap<String, TaskAction> = serde_json::from_value(task.actions)?;
+        let mut config = match serde_json::from_value::<TaskConfig>(task.compiled.clone()) {
+            Ok(c) => c,
+            Err(e) => {
+                add_error(DependencyValidateError::ConfigFormat(e.to_string()));
+                continue;
+            }
+        };
+
+        if let Err(errors) = config.validate(&actions, &inputs, &task_triggers, &task_actions) {
+            for e in errors.0 {
+                add_error(DependencyValidateError::Config(e));
+            }
+            continue;
+        }
+
+        // Rebuild the bundle in case the bundler changed. The libraries are left as they are,
+        // since updating them would change which library versions the task runs.
+        if let TaskConfig::Js(js) = &mut config {
+            if let Err(e) = bundle_task(js, &BUNDLE_CONFIG).await {
+                add_error(DependencyValidateError::ScriptBundle(e.to_string()));
+                continue;
+            }
+        }
+
+        let compiled = serde_json::to_value(&config)?;
+        if compiled == task.compiled {
+            continue;
+        }
+
+        result.recompiled.push(task.task_id.clone());
+        if !dry_run {
+            update_compiled(
+                &mut *tx,
+                task.task_template_id,
+                task.task_template_version,
+                &task.compiled,
+                &compiled,
+            )
+            .await?;
+        }
+    }
+
+    Ok(result)
+}
+
+/// Save a recompiled config, unless the task was updated since it was read.
+async fn update_compiled(
+    tx: &mut PgConnection,
+    task_template_id: Uuid,
+    task_template_version: i64,
+    old: &serde_json::Value,
+    compiled: &serde_json::Value,
+) -> Result<(), Error> {
+    sqlx::query!(
+        "UPDATE task_templates SET compiled=$4
+        WHERE task_template_id=$1 AND task_template_version=$2 AND compiled=$3",
+        task_template_id,
+        task_template_version,
+        old,
+        compiled
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
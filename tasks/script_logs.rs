@@ -0,0 +1,192 @@
+//! Structured log entries that task scripts write with `Ergo.log`. Console output is only kept
+//! in a truncated form in the inputs log, but these entries are saved individually and linked to
+//! the run that wrote them, so that task authors can look back at what a production run did.
+//!
+//! Entries below `TASK_SCRIPT_LOG_LEVEL` (default `debug`) are not saved. Entries are removed
+//! along with their inputs log entry, so they follow the org's log retention policy.
+
+use chrono::{DateTime, Utc};
+use ergo_database::object_id::TaskId;
+use ergo_js::{truncate_with_marker, ConsoleLevel, ConsoleMessage};
+use lazy_static::lazy_static;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use crate::Error;
+
+/// The most entries saved for a single run. Anything past this is dropped.
+pub const MAX_ENTRIES_PER_RUN: usize = 1000;
+/// Longer messages are truncated.
+pub const MAX_MESSAGE_BYTES: usize = 8192;
+
+lazy_static! {
+    static ref MIN_LEVEL: ConsoleLevel = std::env::var("TASK_SCRIPT_LOG_LEVEL")
+        .ok()
+        .and_then(|level| parse_level(&level))
+        .unwrap_or(ConsoleLevel::Debug);
+}
+
+/// Parse a level name such as `warn`, ignoring case.
+pub fn parse_level(level: &str) -> Option<ConsoleLevel> {
+    match level.to_ascii_lowercase().as_str() {
+        "debug" => Some(ConsoleLevel::Debug),
+        "info" => Some(ConsoleLevel::Info),
+        "warn" | "warning" => Some(ConsoleLevel::Warn),
+        "error" => Some(ConsoleLevel::Error),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ScriptLogEntry {
+    pub task_script_log_id: i64,
+    pub task_id: TaskId,
+    pub inputs_log_id: Uuid,
+    pub level: ConsoleLevel,
+    pub message: String,
+    pub fields: serde_json::Value,
+    pub created: DateTime<Utc>,
+}
+
+/// Pick out the structured messages that should be saved.
+fn entries_to_save(messages: &[ConsoleMessage]) -> impl Iterator<Item = &ConsoleMessage> {
+    messages
+        .iter()
+        .filter(|m| m.is_structured() && m.level >= *MIN_LEVEL)
+        .take(MAX_ENTRIES_PER_RUN)
+}
+
+/// Save the structured messages from a script's console output. Other console output is
+/// ignored. Returns the number of entries saved.
+pub async fn record(
+    tx: &mut PgConnection,
+    task_id: &TaskId,
+    inputs_log_id: Uuid,
+    messages: &[ConsoleMessage],
+) -> Result<usize, Error> {
+    let mut levels = Vec::new();
+    let mut texts = Vec::new();
+    let mut fields = Vec::new();
+    let mut times = Vec::new();
+    for m in entries_to_save(messages) {
+        let mut text = m.message.clone();
+        truncate_with_marker(&mut text, MAX_MESSAGE_BYTES);
+
+        levels.push(m.level as i16);
+        texts.push(text);
+        fields.push(serde_json::Value::Object(
+            m.fields.clone().unwrap_or_default(),
+        ));
+        times.push(m.time);
+    }
+
+    if levels.is_empty() {
+        return Ok(0);
+    }
+
+    sqlx::query!(
+        r##"INSERT INTO task_script_logs (task_id, inputs_log_id, level, message, fields, created)
+        SELECT $1, $2, * FROM UNNEST($3::smallint[], $4::text[], $5::jsonb[], $6::timestamptz[])"##,
+        task_id.0,
+        inputs_log_id,
+        &levels,
+        &texts,
+        &fields,
+        &times
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    event!(Level::DEBUG, %task_id, %inputs_log_id, count=%levels.len(), "Saved script log entries");
+    Ok(levels.len())
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ScriptLogFilter {
+    pub task_id: Option<TaskId>,
+    pub inputs_log_id: Option<Uuid>,
+    /// Only return entries at this level or above.
+    pub min_level: Option<ConsoleLevel>,
+    /// Only return entries older than this entry, for paging backwards.
+    pub before: Option<i64>,
+    pub limit: i64,
+}
+
+/// List log entries, newest first. The caller is responsible for checking that the requester
+/// can read the task.
+pub async fn list(
+    tx: &mut PgConnection,
+    filter: &ScriptLogFilter,
+) -> Result<Vec<ScriptLogEntry>, Error> {
+    let rows = sqlx::query!(
+        r##"SELECT task_script_log_id, task_id AS "task_id: TaskId", inputs_log_id, level,
+            message, fields, created
+        FROM task_script_logs
+        WHERE ($1::uuid IS NULL OR task_id = $1)
+            AND ($2::uuid IS NULL OR inputs_log_id = $2)
+            AND level >= $3
+            AND ($4::bigint IS NULL OR task_script_log_id < $4)
+        ORDER BY task_script_log_id DESC
+        LIMIT $5"##,
+        filter.task_id.as_ref().map(|id| id.0),
+        filter.inputs_log_id,
+        filter.min_level.unwrap_or(ConsoleLevel::Debug) as i16,
+        filter.before,
+        filter.limit
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let entries = rows
+        .into_iter()
+        .map(|row| ScriptLogEntry {
+            task_script_log_id: row.task_script_log_id,
+            task_id: row.task_id,
+            inputs_log_id: row.inputs_log_id,
+            level: ConsoleLevel::from(row.level as usize),
+            message: row.message,
+            fields: row.fields,
+            created: row.created,
+        })
+        .collect();
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(level: ConsoleLevel, structured: bool) -> ConsoleMessage {
+        ConsoleMessage {
+            level,
+            time: Utc::now(),
+            message: format!("{:?}", level),
+            fields: structured.then(serde_json::Map::new),
+        }
+    }
+
+    #[test]
+    fn only_structured_messages_are_saved() {
+        let messages = vec![
+            message(ConsoleLevel::Info, false),
+            message(ConsoleLevel::Debug, true),
+            message(ConsoleLevel::Error, true),
+        ];
+
+        let saved = entries_to_save(&messages)
+            .map(|m| m.level)
+            .collect::<Vec<_>>();
+        assert_eq!(saved, vec![ConsoleLevel::Debug, ConsoleLevel::Error]);
+    }
+
+    #[test]
+    fn levels() {
+        assert_eq!(parse_level("WARN"), Some(ConsoleLevel::Warn));
+        assert_eq!(parse_level("error"), Some(ConsoleLevel::Error));
+        assert_eq!(parse_level("verbose"), None);
+    }
+}
@@ -1,12 +1,24 @@
+use std::collections::BTreeMap;
+
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+#[cfg(not(target_family = "wasm"))]
+pub mod bundle;
 #[cfg(not(target_family = "wasm"))]
 mod runtime;
 #[cfg(not(target_family = "wasm"))]
 pub use runtime::*;
 #[cfg(not(target_family = "wasm"))]
 pub mod immediate;
+#[cfg(not(target_family = "wasm"))]
+pub mod libraries;
+#[cfg(not(target_family = "wasm"))]
+pub mod locks;
+#[cfg(not(target_family = "wasm"))]
+pub mod process;
+#[cfg(not(target_family = "wasm"))]
+pub mod repl;
 
 #[derive(Clone, Debug, JsonSchema, Serialize, Deserialize, PartialEq, Eq)]
 pub struct TaskJsConfig {
@@ -15,6 +27,26 @@ pub struct TaskJsConfig {
     /// The source map for the compiled script
     #[serde(default)]
     pub map: String,
+    /// NPM packages that the script imports, in the same format as the `dependencies`
+    /// field of a package.json.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dependencies: BTreeMap<String, String>,
+    /// The script bundled with its dependencies. This is generated by the server when the
+    /// task is saved, and any value sent by the client is replaced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bundle: Option<TaskJsBundle>,
+    /// The source of the shared libraries that the script imports, keyed by specifier. This is
+    /// filled in by the server when the task is saved.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub libraries: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, JsonSchema, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TaskJsBundle {
+    /// A hash of the script and the installed dependency versions.
+    pub hash: String,
+    pub script: String,
+    pub map: String,
 }
 
 impl TaskJsConfig {
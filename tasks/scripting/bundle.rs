@@ -0,0 +1,238 @@
+//! Task scripts can import NPM packages. The packages are installed with npm into a directory
+//! shared by all scripts with the same dependency list, and then each script is bundled with
+//! esbuild into a single module that the isolate can run without loading anything else.
+//!
+//! Bundles are cached on disk, keyed by the hash of the installed package-lock.json and the
+//! script, so that saving or running an unchanged task doesn't rebuild it.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use tokio::{process::Command, sync::Mutex};
+use tracing::{event, Level};
+
+use super::{libraries::LIBRARY_PREFIX, TaskJsBundle, TaskJsConfig};
+use crate::Error;
+
+#[derive(Debug, Clone)]
+pub struct BundleConfig {
+    /// The npm executable used to install dependencies.
+    pub npm: String,
+    /// The esbuild executable used to bundle the scripts.
+    pub esbuild: String,
+    /// Where to keep the installed dependencies and the built bundles.
+    pub cache_dir: PathBuf,
+}
+
+impl BundleConfig {
+    /// Read the configuration from `JS_BUNDLE_NPM`, `JS_BUNDLE_ESBUILD`, and
+    /// `JS_BUNDLE_CACHE_DIR`.
+    pub fn from_env() -> Self {
+        BundleConfig {
+            npm: std::env::var("JS_BUNDLE_NPM").unwrap_or_else(|_| "npm".to_string()),
+            esbuild: std::env::var("JS_BUNDLE_ESBUILD").unwrap_or_else(|_| "esbuild".to_string()),
+            cache_dir: std::env::var("JS_BUNDLE_CACHE_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| std::env::temp_dir().join("ergo-js-bundles")),
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref BUNDLE_CONFIG: BundleConfig = BundleConfig::from_env();
+    /// npm doesn't handle concurrent installs into the same directory, so only run one at a time.
+    static ref INSTALL_LOCK: Mutex<()> = Mutex::new(());
+}
+
+fn bundle_error(context: &str, e: impl std::fmt::Display) -> Error {
+    Error::TaskScriptBundle(format!("{}: {}", context, e))
+}
+
+fn hash_hex(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hex::encode(hasher.finalize())
+}
+
+async fn run_command(command: &mut Command, name: &str) -> Result<(), Error> {
+    let output = command
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| bundle_error(&format!("Running {}", name), e))?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::TaskScriptBundle(format!(
+            "{} failed: {}",
+            name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+/// Install the dependencies, if they aren't already, and return the directory they were
+/// installed into along with the hash of the resulting lockfile.
+async fn install_dependencies(
+    config: &BundleConfig,
+    dependencies: &BTreeMap<String, String>,
+) -> Result<(PathBuf, String), Error> {
+    let package_json = serde_json::to_vec_pretty(&serde_json::json!({
+        "private": true,
+        "dependencies": dependencies,
+    }))?;
+
+    let dir = config
+        .cache_dir
+        .join("deps")
+        .join(hash_hex(&[&package_json]));
+    let lockfile_path = dir.join("package-lock.json");
+
+    let _lock = INSTALL_LOCK.lock().await;
+    if tokio::fs::metadata(&lockfile_path).await.is_err() {
+        event!(Level::INFO, dir=%dir.display(), ?dependencies, "Installing task script dependencies");
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .map_err(|e| bundle_error("Creating dependency directory", e))?;
+        tokio::fs::write(dir.join("package.json"), &package_json)
+            .await
+            .map_err(|e| bundle_error("Writing package.json", e))?;
+
+        run_command(
+            Command::new(&config.npm)
+                .args(["install", "--ignore-scripts", "--no-audit", "--no-fund"])
+                .current_dir(&dir),
+            "npm install",
+        )
+        .await?;
+    }
+
+    let lockfile = tokio::fs::read(&lockfile_path)
+        .await
+        .map_err(|e| bundle_error("Reading package-lock.json", e))?;
+
+    Ok((dir, hash_hex(&[&lockfile])))
+}
+
+async fn read_bundle(hash: &str, script_path: &Path, map_path: &Path) -> Option<TaskJsBundle> {
+    let script = tokio::fs::read_to_string(script_path).await.ok()?;
+    let map = tokio::fs::read_to_string(map_path).await.ok()?;
+    Some(TaskJsBundle {
+        hash: hash.to_string(),
+        script,
+        map,
+    })
+}
+
+/// Bundle a script with its dependencies, using the cached bundle if there is one.
+pub async fn bundle_script(
+    config: &BundleConfig,
+    script: &str,
+    dependencies: &BTreeMap<String, String>,
+) -> Result<TaskJsBundle, Error> {
+    let (deps_dir, lockfile_hash) = install_dependencies(config, dependencies).await?;
+    let hash = hash_hex(&[lockfile_hash.as_bytes(), script.as_bytes()]);
+
+    let bundle_dir = config.cache_dir.join("bundles");
+    let script_path = bundle_dir.join(format!("{}.js", hash));
+    let map_path = bundle_dir.join(format!("{}.js.map", hash));
+
+    if let Some(bundle) = read_bundle(&hash, &script_path, &map_path).await {
+        return Ok(bundle);
+    }
+
+    tokio::fs::create_dir_all(&bundle_dir)
+        .await
+        .map_err(|e| bundle_error("Creating bundle directory", e))?;
+
+    // The entry point goes in the dependency directory so that imports resolve against its
+    // node_modules. The output is written to a temporary name and then moved into place so
+    // that a failed build never leaves a partial bundle in the cache.
+    let entry_path = deps_dir.join(format!("entry-{}.js", hash));
+    let temp_script_path = bundle_dir.join(format!("{}.tmp-{}.js", hash, std::process::id()));
+    let temp_map_path = temp_script_path.with_extension("js.map");
+
+    tokio::fs::write(&entry_path, script)
+        .await
+        .map_err(|e| bundle_error("Writing script", e))?;
+
+    let build_result = run_command(
+        Command::new(&config.esbuild)
+            .arg(&entry_path)
+            .args([
+                "--bundle",
+                "--format=esm",
+                "--platform=neutral",
+                "--main-fields=module,main",
+                "--sourcemap=external",
+            ])
+            // Shared libraries are loaded by the runtime, not bundled.
+            .arg(format!("--external:{}*", LIBRARY_PREFIX))
+            .arg(format!("--outfile={}", temp_script_path.display())),
+        "esbuild",
+    )
+    .await;
+
+    tokio::fs::remove_file(&entry_path).await.ok();
+    build_result?;
+
+    tokio::fs::rename(&temp_script_path, &script_path)
+        .await
+        .map_err(|e| bundle_error("Saving bundle", e))?;
+    tokio::fs::rename(&temp_map_path, &map_path)
+        .await
+        .map_err(|e| bundle_error("Saving bundle source map", e))?;
+
+    read_bundle(&hash, &script_path, &map_path)
+        .await
+        .ok_or_else(|| Error::TaskScriptBundle("Reading built bundle".to_string()))
+}
+
+/// Build the bundle for a task script, or clear it if the script has no dependencies.
+pub async fn bundle_task(task: &mut TaskJsConfig, config: &BundleConfig) -> Result<(), Error> {
+    task.bundle = if task.dependencies.is_empty() {
+        None
+    } else {
+        Some(bundle_script(config, &task.script, &task.dependencies).await?)
+    };
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn no_dependencies_clears_bundle() {
+        let mut task = TaskJsConfig {
+            timeout: None,
+            script: "console.log('hi')".to_string(),
+            map: String::new(),
+            dependencies: BTreeMap::new(),
+            bundle: Some(TaskJsBundle {
+                hash: "abc".to_string(),
+                script: "sent by the client".to_string(),
+                map: String::new(),
+            }),
+            libraries: BTreeMap::new(),
+        };
+
+        // This would fail if it tried to run npm or esbuild.
+        let config = BundleConfig {
+            npm: "/nonexistent/npm".to_string(),
+            esbuild: "/nonexistent/esbuild".to_string(),
+            cache_dir: std::env::temp_dir().join("ergo-js-bundle-test"),
+        };
+
+        bundle_task(&mut task, &config).await.unwrap();
+        assert_eq!(task.bundle, None);
+    }
+}
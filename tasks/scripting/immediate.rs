@@ -1,42 +1,125 @@
 //! Immediate mode scripts run once every time a trigger comes in. They can save a context
 //! value to allow persistent state across runs.
 
-use ergo_js::{ConsoleMessage, Runtime};
-use serde::Deserialize;
+use std::{collections::BTreeMap, rc::Rc};
+
+use ergo_js::{locks::LockProvider, ConsoleMessage, ConsoleStats, Runtime};
+use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
 
 use crate::{
     actions::TaskActionInvocations,
-    scripting::{create_task_script_runtime, POOL},
+    egress::EgressPolicy,
+    scripting::{
+        bundle::{bundle_script, BUNDLE_CONFIG},
+        create_task_script_runtime,
+        locks::{LockScope, RedisLocks},
+        process::{ExecutionMode, WorkerJob, WorkerResponse, EXECUTION_MODE, PROCESS_POOL},
+        POOL,
+    },
     Error,
 };
 
 use super::{TaskJsConfig, TaskJsState};
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct RunTaskResult {
     pub state_changed: bool,
     pub state: TaskJsState,
     pub console: Vec<ConsoleMessage>,
+    /// Set when the console output was over the size limits and was truncated.
+    #[serde(default)]
+    pub console_stats: Option<ConsoleStats>,
     pub actions: TaskActionInvocations,
 }
 
 pub async fn run_task(
     task_name: &str,
     config: TaskJsConfig,
+    state: TaskJsState,
+    payload: serde_json::Value,
+    egress: EgressPolicy,
+    lock_scope: Option<LockScope>,
+) -> Result<RunTaskResult, Error> {
+    let libraries = config.libraries;
+    let script = match config.bundle {
+        Some(bundle) => bundle.script,
+        None if config.dependencies.is_empty() => config.script,
+        // The task was saved without a bundle, so build it now. This will usually hit the cache.
+        None => {
+            bundle_script(&BUNDLE_CONFIG, &config.script, &config.dependencies)
+                .await?
+                .script
+        }
+    };
+
+    match *EXECUTION_MODE {
+        ExecutionMode::InProcess => {
+            run_bundled_task(
+                task_name, script, libraries, state, payload, egress, lock_scope,
+            )
+            .await
+        }
+        ExecutionMode::Subprocess => {
+            let job = WorkerJob::TaskScript {
+                task_name: task_name.to_string(),
+                script,
+                libraries,
+                state,
+                payload,
+                egress,
+                lock_scope,
+            };
+
+            let response = PROCESS_POOL
+                .run(&job)
+                .await
+                .map_err(|e| Error::TaskScript {
+                    error: anyhow::Error::from(e).into(),
+                    console: Vec::new(),
+                })?;
+
+            match response {
+                WorkerResponse::Ok(result) => Ok(serde_json::from_value(result)?),
+                WorkerResponse::Err { message, console } => Err(Error::TaskScript {
+                    error: anyhow::anyhow!(message).into(),
+                    console: serde_json::from_value(console).unwrap_or_default(),
+                }),
+            }
+        }
+    }
+}
+
+/// Run a task script, after its dependencies have been bundled, in [POOL].
+pub async fn run_bundled_task(
+    task_name: &str,
+    script: String,
+    libraries: BTreeMap<String, String>,
     mut state: TaskJsState,
     payload: serde_json::Value,
+    egress: EgressPolicy,
+    lock_scope: Option<LockScope>,
 ) -> Result<RunTaskResult, Error> {
     let main_url = url::Url::parse(&format!("https://ergo/tasks/{}.js", task_name))
         .map_err(|e| Error::TaskScriptSetup(e.into()))?;
 
     POOL.run(move || async move {
+        let locks = lock_scope.and_then(RedisLocks::new);
         // TODO ability to configure `allow_net`
-        let mut runtime = create_task_script_runtime(true);
+        let mut runtime = create_task_script_runtime(
+            true,
+            libraries.into_iter().collect(),
+            egress.permissions(),
+            locks.clone().map(|l| l as Rc<dyn LockProvider>),
+        );
 
         set_up_task_env(&mut runtime, &state, &payload).map_err(Error::TaskScriptSetup)?;
 
-        let run_result = runtime.run_main_module(main_url, config.script).await;
+        let run_result = runtime.run_main_module(main_url, script).await;
+        if let Some(locks) = locks {
+            locks.release_all().await;
+        }
+        let console_stats = runtime.console_stats().filter(|s| s.is_truncated());
         let console = runtime.take_console_messages();
 
         match run_result {
@@ -61,6 +144,7 @@ pub async fn run_task(
                     state_changed,
                     state,
                     console,
+                    console_stats,
                     actions,
                 })
             }
@@ -75,7 +159,7 @@ pub async fn run_task(
 
 const TASK_HELPERS: &str = include_str!("./task_helpers.js");
 
-fn set_up_task_env(
+pub(super) fn set_up_task_env(
     runtime: &mut Runtime,
     state: &TaskJsState,
     payload: &serde_json::Value,
@@ -103,13 +187,24 @@ mod tests {
             script: script.to_string(),
             map: String::new(),
             timeout: None,
+            dependencies: Default::default(),
+            bundle: None,
+            libraries: Default::default(),
         };
 
         let state = TaskJsState {
             context: r##"{data:new Map([["a",5]])}"##.to_string(),
         };
 
-        let result = run_task("test task", config, state, json!({ "a": 10 })).await;
+        let result = run_task(
+            "test task",
+            config,
+            state,
+            json!({ "a": 10 }),
+            EgressPolicy::allow_all(),
+            None,
+        )
+        .await;
 
         match result {
             Ok(result) => {
@@ -141,6 +236,9 @@ mod tests {
             script: script.to_string(),
             map: String::new(),
             timeout: None,
+            dependencies: Default::default(),
+            bundle: None,
+            libraries: Default::default(),
         };
 
         let input_context = r##"{data:new Map([["a",5]])}"##;
@@ -148,7 +246,15 @@ mod tests {
             context: input_context.to_string(),
         };
 
-        let result = run_task("test task", config, state, json!({ "a": 10 })).await;
+        let result = run_task(
+            "test task",
+            config,
+            state,
+            json!({ "a": 10 }),
+            EgressPolicy::allow_all(),
+            None,
+        )
+        .await;
 
         match result {
             Ok(result) => {
@@ -181,6 +287,9 @@ mod tests {
             script: script.to_string(),
             map: String::new(),
             timeout: None,
+            dependencies: Default::default(),
+            bundle: None,
+            libraries: Default::default(),
         };
 
         let input_context = "";
@@ -188,9 +297,16 @@ mod tests {
             context: input_context.to_string(),
         };
 
-        let result = run_task("test task", config, state, serde_json::Value::Null)
-            .await
-            .expect("running task");
+        let result = run_task(
+            "test task",
+            config,
+            state,
+            serde_json::Value::Null,
+            EgressPolicy::allow_all(),
+            None,
+        )
+        .await
+        .expect("running task");
         assert_eq!(result.state_changed, true);
         assert_eq!(result.state.context, r##""context was undefined""##);
     }
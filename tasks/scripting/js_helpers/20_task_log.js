@@ -0,0 +1,21 @@
+// Structured logging. Unlike console output, these entries are saved with the run, so they can be
+// looked up later to see what a task did.
+Ergo.log = (function() {
+  function write(level, message, fields) {
+    // Round trip through JSON so that values without a JSON representation are dropped instead
+    // of failing the call.
+    let data = fields === undefined ? null : JSON.parse(JSON.stringify(fields) ?? 'null');
+    Deno.core.ops.ergo_js_log(String(message), level, data);
+  }
+
+  return Object.freeze({
+    debug: (message, fields) => write(0, message, fields),
+    info: (message, fields) => write(1, message, fields),
+    warn: (message, fields) => write(2, message, fields),
+    error: (message, fields) => write(3, message, fields),
+  });
+})();
+
+if(globalThis.log === undefined) {
+  globalThis.log = Ergo.log;
+}
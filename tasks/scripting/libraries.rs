@@ -0,0 +1,183 @@
+//! Orgs can upload shared JS modules that task scripts and `js` action scripts import with
+//! `ergo:lib/<name>`, or `ergo:lib/<name>@<version>` to pin a version. Each upload creates a
+//! new version of the library, and an unpinned import uses the latest one.
+//!
+//! Task scripts resolve their imports when the task is saved, so a task keeps running the
+//! library versions that it was saved with. Action scripts resolve them each time they run.
+//! Libraries can import other libraries in the same way.
+
+use std::collections::BTreeMap;
+
+use ergo_database::object_id::OrgId;
+use sqlx::PgConnection;
+
+use crate::Error;
+
+/// The start of the specifier used to import a shared library.
+pub const LIBRARY_PREFIX: &str = "ergo:lib/";
+
+/// The most libraries that a single script can pull in, including indirect imports.
+const MAX_LIBRARIES: usize = 50;
+
+/// A reference to a shared library found in a script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LibraryImport {
+    /// The full specifier, such as `ergo:lib/dates@3`.
+    pub specifier: String,
+    pub name: String,
+    pub version: Option<i32>,
+}
+
+/// Returns true if the name can be used for a library.
+pub fn valid_library_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 100
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn parse_import(specifier: &str) -> Option<LibraryImport> {
+    let rest = specifier.strip_prefix(LIBRARY_PREFIX)?;
+    let (name, version) = match rest.split_once('@') {
+        Some((name, version)) => (name, Some(version.parse::<i32>().ok()?)),
+        None => (rest, None),
+    };
+
+    if !valid_library_name(name) {
+        return None;
+    }
+
+    Some(LibraryImport {
+        specifier: specifier.to_string(),
+        name: name.to_string(),
+        version,
+    })
+}
+
+/// Find the shared libraries that a script imports. This looks for string literals that hold a
+/// library specifier, which covers static imports, re-exports, and dynamic imports.
+pub fn library_imports(script: &str) -> Vec<LibraryImport> {
+    let mut imports: Vec<LibraryImport> = Vec::new();
+    for (start, _) in script.match_indices(LIBRARY_PREFIX) {
+        let quote = match script[..start].chars().last() {
+            Some(q @ ('"' | '\'' | '`')) => q,
+            _ => continue,
+        };
+
+        let literal = &script[start..];
+        let specifier = match literal.find(quote) {
+            Some(end) => &literal[..end],
+            None => continue,
+        };
+
+        if let Some(import) = parse_import(specifier) {
+            if !imports.iter().any(|i| i.specifier == import.specifier) {
+                imports.push(import);
+            }
+        }
+    }
+
+    imports
+}
+
+/// Look up the source of each library that the script imports, directly or through other
+/// libraries. The result is keyed by specifier.
+pub async fn resolve_libraries(
+    conn: &mut PgConnection,
+    org_id: &OrgId,
+    script: &str,
+) -> Result<BTreeMap<String, String>, Error> {
+    let mut libraries = BTreeMap::new();
+    let mut pending = library_imports(script);
+
+    while let Some(import) = pending.pop() {
+        if libraries.contains_key(&import.specifier) {
+            continue;
+        }
+
+        if libraries.len() >= MAX_LIBRARIES {
+            return Err(Error::JsLibrary(format!(
+                "Scripts can import at most {} libraries",
+                MAX_LIBRARIES
+            )));
+        }
+
+        let source = sqlx::query_scalar!(
+            "SELECT source FROM js_libraries
+            WHERE org_id=$1 AND name=$2 AND ($3::int IS NULL OR version=$3)
+            ORDER BY version DESC
+            LIMIT 1",
+            org_id.0,
+            import.name,
+            import.version
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or_else(|| Error::JsLibrary(format!("{} does not exist", import.specifier)))?;
+
+        pending.extend(library_imports(&source));
+        libraries.insert(import.specifier, source);
+    }
+
+    Ok(libraries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn specifiers(script: &str) -> Vec<String> {
+        library_imports(script)
+            .into_iter()
+            .map(|i| i.specifier)
+            .collect()
+    }
+
+    #[test]
+    fn finds_imports() {
+        let script = r##"
+            import { format } from "ergo:lib/dates";
+            import * as text from 'ergo:lib/text@3';
+            export { sum } from "ergo:lib/math";
+            const lazy = await import(`ergo:lib/lazy`);
+            import { format as again } from "ergo:lib/dates";
+        "##;
+
+        assert_eq!(
+            specifiers(script),
+            vec![
+                "ergo:lib/dates",
+                "ergo:lib/text@3",
+                "ergo:lib/math",
+                "ergo:lib/lazy"
+            ]
+        );
+
+        let text = &library_imports(script)[1];
+        assert_eq!(text.name, "text");
+        assert_eq!(text.version, Some(3));
+    }
+
+    #[test]
+    fn ignores_invalid_specifiers() {
+        let script = r##"
+            import a from "ergo:lib/";
+            import b from "ergo:lib/bad name";
+            import c from "ergo:lib/versioned@latest";
+            import d from "ergo:lib/nested/path";
+            // ergo:lib/in-a-comment
+        "##;
+
+        assert_eq!(specifiers(script), Vec::<String>::new());
+    }
+
+    #[test]
+    fn library_names() {
+        assert!(valid_library_name("date-utils_2"));
+        assert!(!valid_library_name(""));
+        assert!(!valid_library_name("a/b"));
+        assert!(!valid_library_name("a@1"));
+        assert!(!valid_library_name(&"a".repeat(101)));
+    }
+}
@@ -0,0 +1,144 @@
+//! Script locks stored in Redis. Each lock is a key holding a random token, set with `NX` and an
+//! expiration so that a lock held by a script that crashed is freed once its TTL passes. Lock
+//! names are scoped to the org, so scripts in different orgs never contend for the same lock.
+
+use std::{cell::RefCell, rc::Rc, time::Duration};
+
+use ergo_database::{new_uuid, object_id::OrgId};
+use ergo_js::locks::LockProvider;
+use lazy_static::lazy_static;
+use redis::aio::MultiplexedConnection;
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+type AnyError = anyhow::Error;
+
+lazy_static! {
+    /// Set with `REDIS_URL`. Scripts can't take locks when it isn't set.
+    static ref REDIS_CLIENT: Option<redis::Client> = std::env::var("REDIS_URL")
+        .ok()
+        .and_then(|url| redis::Client::open(url).ok());
+
+    static ref RELEASE_SCRIPT: redis::Script = redis::Script::new(
+        r##"if redis.call("GET", KEYS[1]) == ARGV[1] then
+            return redis.call("DEL", KEYS[1])
+        else
+            return 0
+        end"##
+    );
+
+    static ref EXTEND_SCRIPT: redis::Script = redis::Script::new(
+        r##"if redis.call("GET", KEYS[1]) == ARGV[1] then
+            return redis.call("PEXPIRE", KEYS[1], ARGV[2])
+        else
+            return 0
+        end"##
+    );
+}
+
+/// Where a script's locks are kept. This is sent along with the script when it runs in a worker
+/// process.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LockScope {
+    pub org_id: OrgId,
+    pub redis_key_prefix: Option<String>,
+}
+
+/// The locks for a single run of a script. Any locks that the script didn't release are
+/// released by [RedisLocks::release_all] when it finishes.
+pub struct RedisLocks {
+    scope: LockScope,
+    client: redis::Client,
+    conn: RefCell<Option<MultiplexedConnection>>,
+    held: RefCell<Vec<(String, String)>>,
+}
+
+impl RedisLocks {
+    /// Returns `None` if Redis is not configured.
+    pub fn new(scope: LockScope) -> Option<Rc<RedisLocks>> {
+        let client = REDIS_CLIENT.as_ref()?.clone();
+        Some(Rc::new(RedisLocks {
+            scope,
+            client,
+            conn: RefCell::new(None),
+            held: RefCell::new(Vec::new()),
+        }))
+    }
+
+    fn key(&self, name: &str) -> String {
+        match self.scope.redis_key_prefix.as_deref() {
+            Some(prefix) => format!("erl:{}:{}:{}", prefix, self.scope.org_id, name),
+            None => format!("erl:{}:{}", self.scope.org_id, name),
+        }
+    }
+
+    async fn connection(&self) -> Result<MultiplexedConnection, AnyError> {
+        if let Some(conn) = self.conn.borrow().as_ref() {
+            return Ok(conn.clone());
+        }
+
+        let conn = self.client.get_multiplexed_tokio_connection().await?;
+        self.conn.replace(Some(conn.clone()));
+        Ok(conn)
+    }
+
+    /// Release the locks that the script still holds.
+    pub async fn release_all(&self) {
+        let held = self.held.take();
+        for (name, token) in held {
+            if let Err(e) = self.release(&name, &token).await {
+                event!(Level::WARN, org_id=%self.scope.org_id, lock=%name, error=%e, "Failed to release script lock");
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl LockProvider for RedisLocks {
+    async fn try_acquire(&self, name: &str, ttl: Duration) -> Result<Option<String>, AnyError> {
+        let token = new_uuid().simple().to_string();
+        let mut conn = self.connection().await?;
+        let set: Option<String> = redis::cmd("SET")
+            .arg(self.key(name))
+            .arg(&token)
+            .arg("NX")
+            .arg("PX")
+            .arg(ttl.as_millis() as u64)
+            .query_async(&mut conn)
+            .await?;
+
+        if set.is_none() {
+            return Ok(None);
+        }
+
+        self.held
+            .borrow_mut()
+            .push((name.to_string(), token.clone()));
+        Ok(Some(token))
+    }
+
+    async fn extend(&self, name: &str, token: &str, ttl: Duration) -> Result<bool, AnyError> {
+        let mut conn = self.connection().await?;
+        let extended: i64 = EXTEND_SCRIPT
+            .key(self.key(name))
+            .arg(token)
+            .arg(ttl.as_millis() as u64)
+            .invoke_async(&mut conn)
+            .await?;
+        Ok(extended > 0)
+    }
+
+    async fn release(&self, name: &str, token: &str) -> Result<bool, AnyError> {
+        self.held
+            .borrow_mut()
+            .retain(|(n, t)| n != name || t != token);
+
+        let mut conn = self.connection().await?;
+        let released: i64 = RELEASE_SCRIPT
+            .key(self.key(name))
+            .arg(token)
+            .invoke_async(&mut conn)
+            .await?;
+        Ok(released > 0)
+    }
+}
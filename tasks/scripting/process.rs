@@ -0,0 +1,466 @@
+//! Run scripts in a pool of worker subprocesses instead of in-process isolates, so that a V8
+//! crash or a native memory leak takes down a single worker instead of the whole server.
+//!
+//! Each worker is a `js-worker` subcommand of the main binary. It reads one JSON [WorkerJob] per
+//! line on stdin, runs it in its own runtime pool, and writes one JSON [WorkerResponse] per line
+//! to stdout. A worker handles one job at a time, and is replaced when it crashes, times out,
+//! or has run `JS_WORKER_MAX_JOBS` jobs.
+
+use std::{collections::BTreeMap, path::PathBuf, process::Stdio, str::FromStr, time::Duration};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    process::{Child, ChildStdin, ChildStdout, Command},
+};
+use tracing::{event, Level};
+
+use super::{locks::LockScope, TaskJsState};
+use crate::{
+    actions::js_executor::{self, ScriptLimits},
+    egress::EgressPolicy,
+    Error,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExecutionMode {
+    /// Run scripts in isolates on the threads of [super::POOL].
+    InProcess,
+    /// Run scripts in worker subprocesses.
+    Subprocess,
+}
+
+impl FromStr for ExecutionMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "in_process" => Ok(ExecutionMode::InProcess),
+            "subprocess" => Ok(ExecutionMode::Subprocess),
+            _ => Err(format!("Unknown JS execution mode {}", s)),
+        }
+    }
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+lazy_static! {
+    /// Set with `JS_EXECUTION_MODE`, either `in_process` (the default) or `subprocess`.
+    pub static ref EXECUTION_MODE: ExecutionMode =
+        env_or("JS_EXECUTION_MODE", ExecutionMode::InProcess);
+
+    pub static ref PROCESS_POOL: ProcessPool = ProcessPool::new(ProcessPoolConfig::from_env());
+}
+
+#[derive(Debug, Error)]
+pub enum ProcessPoolError {
+    #[error("Failed to start JS worker process: {0}")]
+    Spawn(#[source] std::io::Error),
+    #[error("Failed to communicate with JS worker process: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid message from JS worker process: {0}")]
+    Protocol(#[from] serde_json::Error),
+    #[error("JS worker process exited while running a script")]
+    Exited,
+    #[error("JS worker process did not finish within {0:?}")]
+    Timeout(Duration),
+}
+
+/// A job sent to a worker process.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum WorkerJob {
+    /// Evaluate an expression and return its value.
+    Expression { script: String },
+    /// Run a bundled task script.
+    TaskScript {
+        task_name: String,
+        script: String,
+        #[serde(default)]
+        libraries: BTreeMap<String, String>,
+        state: TaskJsState,
+        payload: serde_json::Value,
+        #[serde(default)]
+        egress: EgressPolicy,
+        #[serde(default)]
+        lock_scope: Option<LockScope>,
+    },
+    /// Run a script for the `js` action executor.
+    Executor {
+        url: String,
+        script: String,
+        #[serde(default)]
+        libraries: BTreeMap<String, String>,
+        args: serde_json::Value,
+        #[serde(default)]
+        egress: EgressPolicy,
+        #[serde(default)]
+        limits: ScriptLimits,
+        #[serde(default)]
+        lock_scope: Option<LockScope>,
+    },
+    /// Evaluate code for the task REPL.
+    Repl {
+        state: TaskJsState,
+        payload: serde_json::Value,
+        history: Vec<String>,
+        code: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum WorkerResponse {
+    Ok(serde_json::Value),
+    Err {
+        message: String,
+        /// Console output from the script, if there was any.
+        console: serde_json::Value,
+    },
+}
+
+/// The message for an error, without the prefix that it gets when the parent process wraps it
+/// back up in an [ergo_js::Error].
+fn js_error_message(error: &ergo_js::Error) -> String {
+    match error {
+        ergo_js::Error::Runtime(e) => format!("{:#}", e),
+        e => e.to_string(),
+    }
+}
+
+impl WorkerJob {
+    /// Run the job in this process. This is what the worker process does with each job.
+    pub async fn run_in_process(self) -> WorkerResponse {
+        match self {
+            WorkerJob::Expression { script } => {
+                match super::run_expression::<serde_json::Value>(script).await {
+                    Ok(value) => WorkerResponse::Ok(value),
+                    Err(e) => WorkerResponse::Err {
+                        message: js_error_message(&e),
+                        console: serde_json::Value::Null,
+                    },
+                }
+            }
+            WorkerJob::TaskScript {
+                task_name,
+                script,
+                libraries,
+                state,
+                payload,
+                egress,
+                lock_scope,
+            } => {
+                let result = super::immediate::run_bundled_task(
+                    &task_name, script, libraries, state, payload, egress, lock_scope,
+                )
+                .await;
+                match result.and_then(|r| serde_json::to_value(r).map_err(Error::from)) {
+                    Ok(value) => WorkerResponse::Ok(value),
+                    Err(Error::TaskScript { error, console }) => WorkerResponse::Err {
+                        message: js_error_message(&error),
+                        console: serde_json::to_value(console).unwrap_or_default(),
+                    },
+                    Err(e) => WorkerResponse::Err {
+                        message: e.to_string(),
+                        console: serde_json::Value::Null,
+                    },
+                }
+            }
+            WorkerJob::Executor {
+                url,
+                script,
+                libraries,
+                args,
+                egress,
+                limits,
+                lock_scope,
+            } => {
+                let url = match url::Url::parse(&url) {
+                    Ok(url) => url,
+                    Err(e) => {
+                        return WorkerResponse::Err {
+                            message: e.to_string(),
+                            console: serde_json::Value::Null,
+                        }
+                    }
+                };
+
+                match js_executor::run_executor_script(
+                    url, script, libraries, args, egress, limits, lock_scope,
+                )
+                .await
+                {
+                    Ok(output) => WorkerResponse::Ok(output),
+                    Err(js_executor::ScriptError { error, console }) => WorkerResponse::Err {
+                        message: format!("{:#}", error),
+                        console,
+                    },
+                }
+            }
+            WorkerJob::Repl {
+                state,
+                payload,
+                history,
+                code,
+            } => {
+                let result = super::repl::evaluate_in_process(state, payload, history, code).await;
+                match result.and_then(|r| serde_json::to_value(r).map_err(Error::from)) {
+                    Ok(value) => WorkerResponse::Ok(value),
+                    Err(e) => WorkerResponse::Err {
+                        message: e.to_string(),
+                        console: serde_json::Value::Null,
+                    },
+                }
+            }
+        }
+    }
+}
+
+/// Read jobs from stdin and write the results to stdout until stdin is closed.
+pub async fn run_worker() -> Result<(), std::io::Error> {
+    let mut jobs = BufReader::new(tokio::io::stdin()).lines();
+    let mut stdout = tokio::io::stdout();
+
+    while let Some(line) = jobs.next_line().await? {
+        let response = match serde_json::from_str::<WorkerJob>(&line) {
+            Ok(job) => job.run_in_process().await,
+            Err(e) => WorkerResponse::Err {
+                message: format!("Invalid job: {}", e),
+                console: serde_json::Value::Null,
+            },
+        };
+
+        let mut output = serde_json::to_vec(&response)?;
+        output.push(b'\n');
+        stdout.write_all(&output).await?;
+        stdout.flush().await?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcessPoolConfig {
+    /// The binary to run. It is passed the `js-worker` argument.
+    pub command: PathBuf,
+    pub processes: usize,
+    /// How long a job can run before its worker is killed.
+    pub timeout: Duration,
+    /// Replace each worker after it has run this many jobs, to clean up any memory that it leaked.
+    pub max_jobs_per_worker: usize,
+}
+
+impl ProcessPoolConfig {
+    /// Read the configuration from `JS_WORKER_COMMAND`, `JS_WORKER_PROCESSES`,
+    /// `JS_WORKER_TIMEOUT_SECS`, and `JS_WORKER_MAX_JOBS`.
+    pub fn from_env() -> Self {
+        let command = std::env::var("JS_WORKER_COMMAND")
+            .ok()
+            .map(PathBuf::from)
+            .or_else(|| std::env::current_exe().ok())
+            .unwrap_or_else(|| PathBuf::from("ergo"));
+        let default_processes = std::thread::available_parallelism()
+            .map(|n| n.into())
+            .unwrap_or(4);
+
+        ProcessPoolConfig {
+            command,
+            processes: env_or("JS_WORKER_PROCESSES", default_processes),
+            timeout: Duration::from_secs(env_or("JS_WORKER_TIMEOUT_SECS", 300)),
+            max_jobs_per_worker: env_or("JS_WORKER_MAX_JOBS", 1000),
+        }
+    }
+}
+
+struct WorkerProcess {
+    // The process is killed when this is dropped.
+    child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+    jobs_run: usize,
+}
+
+impl WorkerProcess {
+    fn spawn(command: &PathBuf) -> Result<WorkerProcess, ProcessPoolError> {
+        let mut child = Command::new(command)
+            .arg("js-worker")
+            // The worker must run scripts itself instead of starting its own workers.
+            .env("JS_EXECUTION_MODE", "in_process")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(ProcessPoolError::Spawn)?;
+
+        let stdin = child.stdin.take().expect("worker stdin is piped");
+        let stdout = child.stdout.take().expect("worker stdout is piped");
+
+        Ok(WorkerProcess {
+            child,
+            stdin,
+            stdout: BufReader::new(stdout).lines(),
+            jobs_run: 0,
+        })
+    }
+
+    async fn send(&mut self, job: &WorkerJob) -> Result<WorkerResponse, ProcessPoolError> {
+        let mut request = serde_json::to_vec(job)?;
+        request.push(b'\n');
+        self.stdin.write_all(&request).await?;
+        self.stdin.flush().await?;
+
+        let line = self
+            .stdout
+            .next_line()
+            .await?
+            .ok_or(ProcessPoolError::Exited)?;
+        self.jobs_run += 1;
+        Ok(serde_json::from_str(&line)?)
+    }
+}
+
+/// Holds a place in the pool. When dropped it returns its worker to the pool, or an empty slot
+/// if the worker was discarded, so that the pool never loses capacity.
+struct Slot<'a> {
+    pool: &'a ProcessPool,
+    worker: Option<WorkerProcess>,
+}
+
+impl<'a> Drop for Slot<'a> {
+    fn drop(&mut self) {
+        self.pool.slot_sender.try_send(self.worker.take()).ok();
+    }
+}
+
+pub struct ProcessPool {
+    config: ProcessPoolConfig,
+    // Each entry is an idle worker, or None for a worker that needs to be started.
+    slot_sender: async_channel::Sender<Option<WorkerProcess>>,
+    slot_receiver: async_channel::Receiver<Option<WorkerProcess>>,
+}
+
+impl ProcessPool {
+    /// Create the pool and start its workers. This must be called from within a Tokio runtime.
+    pub fn new(config: ProcessPoolConfig) -> Self {
+        let (slot_sender, slot_receiver) = async_channel::unbounded();
+        for _ in 0..config.processes.max(1) {
+            let worker = match WorkerProcess::spawn(&config.command) {
+                Ok(worker) => Some(worker),
+                Err(e) => {
+                    event!(Level::ERROR, error=%e, "Failed to start JS worker process");
+                    None
+                }
+            };
+            slot_sender.try_send(worker).ok();
+        }
+
+        ProcessPool {
+            config,
+            slot_sender,
+            slot_receiver,
+        }
+    }
+
+    pub async fn run(&self, job: &WorkerJob) -> Result<WorkerResponse, ProcessPoolError> {
+        let mut slot = Slot {
+            pool: self,
+            worker: self
+                .slot_receiver
+                .recv()
+                .await
+                .expect("pool holds a sender"),
+        };
+
+        // Take the worker out of the slot while it's running, so that if this future is dropped
+        // partway through the job the worker is killed instead of being reused.
+        let mut worker = match slot.worker.take() {
+            Some(worker) => worker,
+            None => WorkerProcess::spawn(&self.config.command)?,
+        };
+
+        let result = match tokio::time::timeout(self.config.timeout, worker.send(job)).await {
+            Ok(result) => result,
+            Err(_) => Err(ProcessPoolError::Timeout(self.config.timeout)),
+        };
+
+        match &result {
+            Ok(_) if worker.jobs_run < self.config.max_jobs_per_worker => {
+                slot.worker = Some(worker);
+            }
+            Ok(_) => {
+                event!(Level::DEBUG, jobs=%worker.jobs_run, "Replacing JS worker process");
+            }
+            Err(e) => {
+                let status = worker.child.try_wait().ok().flatten();
+                event!(Level::ERROR, error=%e, ?status, "JS worker process failed");
+            }
+        }
+
+        result
+    }
+
+    pub async fn run_expression(
+        &self,
+        script: String,
+    ) -> Result<serde_json::Value, ergo_js::Error> {
+        let job = WorkerJob::Expression { script };
+        match self.run(&job).await.map_err(anyhow::Error::from)? {
+            WorkerResponse::Ok(value) => Ok(value),
+            WorkerResponse::Err { message, .. } => Err(anyhow::anyhow!(message).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn worker_jobs_run_in_process() {
+        let job = WorkerJob::Expression {
+            script: "1 + 2".to_string(),
+        };
+        let job: WorkerJob = serde_json::from_str(&serde_json::to_string(&job).unwrap()).unwrap();
+        match job.run_in_process().await {
+            WorkerResponse::Ok(value) => assert_eq!(value, serde_json::json!(3)),
+            WorkerResponse::Err { message, .. } => panic!("{}", message),
+        }
+
+        let job = WorkerJob::Expression {
+            script: "(() => { throw new Error('bad') })()".to_string(),
+        };
+        match job.run_in_process().await {
+            WorkerResponse::Ok(value) => panic!("Expected an error, got {}", value),
+            WorkerResponse::Err { message, .. } => assert!(message.contains("bad"), "{}", message),
+        }
+    }
+
+    #[tokio::test]
+    async fn replaces_failed_workers() {
+        // `false` exits immediately, which looks like a worker that crashed.
+        let pool = ProcessPool::new(ProcessPoolConfig {
+            command: PathBuf::from("false"),
+            processes: 1,
+            timeout: Duration::from_secs(5),
+            max_jobs_per_worker: 10,
+        });
+
+        for _ in 0..2 {
+            let result = pool
+                .run(&WorkerJob::Expression {
+                    script: "1".to_string(),
+                })
+                .await;
+            assert!(result.is_err());
+        }
+
+        assert_eq!(pool.slot_receiver.len(), 1, "pool keeps its capacity");
+    }
+}
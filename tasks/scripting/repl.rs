@@ -0,0 +1,156 @@
+//! Evaluate code interactively in a task's JS environment. The runtime has the task's context,
+//! the `Ergo` helpers, and an input payload, but no network access, and any changes to the
+//! context are thrown away.
+//!
+//! The runtime isn't kept between evaluations. Instead the client sends the code that it has
+//! already run successfully along with each new entry, and that history is replayed first to
+//! rebuild the session.
+
+use ergo_js::ConsoleMessage;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    create_task_script_runtime,
+    immediate::set_up_task_env,
+    process::{ExecutionMode, WorkerJob, WorkerResponse, EXECUTION_MODE, PROCESS_POOL},
+    TaskJsState, POOL,
+};
+use crate::Error;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplOutput {
+    /// The value of the code, if it ran successfully.
+    pub result: Option<serde_json::Value>,
+    pub error: Option<String>,
+    pub console: Vec<ConsoleMessage>,
+    /// The task's context after the code ran. This is not saved to the task.
+    pub context: String,
+}
+
+/// Replay `history` and then evaluate `code` in the task's environment.
+pub async fn evaluate(
+    state: TaskJsState,
+    payload: serde_json::Value,
+    history: Vec<String>,
+    code: String,
+) -> Result<ReplOutput, Error> {
+    match *EXECUTION_MODE {
+        ExecutionMode::InProcess => evaluate_in_process(state, payload, history, code).await,
+        ExecutionMode::Subprocess => {
+            let job = WorkerJob::Repl {
+                state,
+                payload,
+                history,
+                code,
+            };
+
+            let response = PROCESS_POOL
+                .run(&job)
+                .await
+                .map_err(|e| Error::TaskScriptSetup(e.into()))?;
+
+            match response {
+                WorkerResponse::Ok(output) => Ok(serde_json::from_value(output)?),
+                WorkerResponse::Err { message, .. } => {
+                    Err(Error::TaskScriptSetup(anyhow::anyhow!(message)))
+                }
+            }
+        }
+    }
+}
+
+/// Replay `history` and then evaluate `code` in [POOL].
+pub async fn evaluate_in_process(
+    state: TaskJsState,
+    payload: serde_json::Value,
+    history: Vec<String>,
+    code: String,
+) -> Result<ReplOutput, Error> {
+    POOL.run(move || async move {
+        let mut runtime =
+            create_task_script_runtime(false, Default::default(), Default::default(), None);
+        set_up_task_env(&mut runtime, &state, &payload).map_err(Error::TaskScriptSetup)?;
+
+        for (i, entry) in history.iter().enumerate() {
+            runtime
+                .await_expression::<serde_json::Value>(&format!("repl_history_{}", i), entry)
+                .await
+                .map_err(|e| {
+                    Error::TaskScriptSetup(anyhow::anyhow!("Replaying history entry {}: {}", i, e))
+                })?;
+        }
+
+        // Only show the console output from the new code.
+        runtime.take_console_messages();
+
+        let result = runtime
+            .await_expression::<serde_json::Value>("repl", &code)
+            .await;
+        let console = runtime.take_console_messages();
+        let context = runtime
+            .get_global_value("__ergo_context")
+            .ok()
+            .flatten()
+            .unwrap_or(state.context);
+
+        let (result, error) = match result {
+            Ok(value) => (Some(value), None),
+            Err(e) => (None, Some(e.to_string())),
+        };
+
+        Ok(ReplOutput {
+            result,
+            error,
+            console,
+            context,
+        })
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn replays_history() {
+        let state = TaskJsState {
+            context: r##"{"count":1}"##.to_string(),
+        };
+
+        let history = vec![
+            "let total = Ergo.getContext().count + Ergo.getPayload().value".to_string(),
+            "console.log('not shown')".to_string(),
+        ];
+
+        let output = evaluate_in_process(
+            state,
+            json!({ "value": 2 }),
+            history,
+            "console.log('shown'); total * 10".to_string(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(output.error, None);
+        assert_eq!(output.result, Some(json!(30)));
+        assert_eq!(output.console.len(), 1);
+        assert_eq!(output.console[0].message.trim(), "shown");
+    }
+
+    #[tokio::test]
+    async fn returns_errors() {
+        let state = TaskJsState {
+            context: "null".to_string(),
+        };
+
+        let output = evaluate_in_process(state, json!(null), Vec::new(), "nope()".to_string())
+            .await
+            .unwrap();
+
+        assert_eq!(output.result, None);
+        assert!(output.error.unwrap().contains("nope"));
+    }
+}
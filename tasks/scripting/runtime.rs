@@ -1,18 +1,70 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, rc::Rc};
 
+use ergo_graceful_shutdown::GracefulShutdownConsumer;
 use ergo_js::{
-    BufferConsole, ConsoleMessage, Extension, Runtime, RuntimeOptions, RuntimePool, Snapshot,
+    locks::LockProvider, module_loader::ModuleSources, permissions::Permissions,
+    truncate_with_marker, BufferConsole, Console, ConsoleLevel, ConsoleLimit, ConsoleMessage,
+    Extension, Runtime, RuntimeOptions, RuntimePool, Snapshot,
 };
 use itertools::Itertools;
 use schemars::JsonSchema;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tracing::{event, Level};
 
+use super::process::{ExecutionMode, EXECUTION_MODE, PROCESS_POOL};
+
 const NET_SNAPSHOT: &[u8] = include_bytes!("./snapshots/net");
 const CORE_SNAPSHOT: &[u8] = include_bytes!("./snapshots/core");
 
 lazy_static::lazy_static! {
     pub static ref POOL : RuntimePool = RuntimePool::new(None);
+
+    /// The heap limit for each script runtime. Set with `JS_MAX_HEAP_MB`.
+    static ref MAX_HEAP_SIZE: Option<usize> = std::env::var("JS_MAX_HEAP_MB")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .map(|mb| mb * 1024 * 1024);
+
+    /// Limits on console output from scripts. Set with `JS_CONSOLE_MAX_BYTES` and
+    /// `JS_CONSOLE_MAX_MESSAGE_BYTES`.
+    static ref CONSOLE_LIMIT: ConsoleLimit = ConsoleLimit::new(
+        env_bytes("JS_CONSOLE_MAX_BYTES", 1024 * 1024),
+        env_bytes("JS_CONSOLE_MAX_MESSAGE_BYTES", 64 * 1024),
+    );
+
+    /// The largest result that a script can return. Set with `JS_MAX_RESULT_BYTES`.
+    pub static ref MAX_RESULT_SIZE: usize = env_bytes("JS_MAX_RESULT_BYTES", 1024 * 1024);
+}
+
+fn env_bytes(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+fn buffer_console(level: ConsoleLevel) -> Box<dyn Console> {
+    Box::new(BufferConsole::new(level).capacity(Some(CONSOLE_LIMIT.clone())))
+}
+
+/// Replace a result that serializes to more than `max` bytes with a marker that holds its size
+/// and the start of its JSON. Returns the untruncated size if the value was replaced.
+pub fn truncate_result(value: serde_json::Value, max: usize) -> (serde_json::Value, Option<usize>) {
+    let mut json = match serde_json::to_string(&value) {
+        Ok(json) if json.len() > max => json,
+        _ => return (value, None),
+    };
+
+    let size = json.len();
+    // Leave room for the rest of the marker.
+    truncate_with_marker(&mut json, max.saturating_sub(100));
+    let marker = serde_json::json!({
+        "$truncated": {
+            "bytes": size,
+            "preview": json,
+        }
+    });
+    (marker, Some(size))
 }
 
 #[derive(Clone, Debug, JsonSchema, Serialize, Deserialize)]
@@ -31,25 +83,48 @@ fn snapshot_and_extensions(
     }
 }
 
-/// Create a runtime suitable for running tasks, with optional network access.
-pub fn create_task_script_runtime(allow_net: bool) -> Runtime {
+/// Create a runtime suitable for running tasks, with optional network access. `libraries` holds
+/// the shared libraries that the script can import, `permissions` restricts the hosts that
+/// the script can reach, and `locks` stores the locks that the script takes.
+pub fn create_task_script_runtime(
+    allow_net: bool,
+    libraries: ModuleSources,
+    permissions: Permissions,
+    locks: Option<Rc<dyn LockProvider>>,
+) -> Runtime {
     let (snapshot, extensions) = snapshot_and_extensions(allow_net, None);
 
     Runtime::new(RuntimeOptions {
-        console: Some(Box::new(BufferConsole::new(ergo_js::ConsoleLevel::Debug))),
+        console: Some(buffer_console(ConsoleLevel::Debug)),
         extensions,
         snapshot: Some(Snapshot::Static(snapshot)),
+        max_heap_size: *MAX_HEAP_SIZE,
+        modules: libraries,
+        permissions: Some(permissions),
+        locks,
         ..Default::default()
     })
 }
 
-/// Create a full-featured, non-serialized runtime.
-pub fn create_executor_runtime() -> Runtime {
-    let (snapshot, extensions) = snapshot_and_extensions(true, None);
+/// Create a full-featured, non-serialized runtime. Passing a `random_seed` makes the runtime's
+/// random values reproducible, and also leaves out network access.
+pub fn create_executor_runtime(
+    libraries: ModuleSources,
+    permissions: Permissions,
+    allow_timers: bool,
+    random_seed: Option<u64>,
+    locks: Option<Rc<dyn LockProvider>>,
+) -> Runtime {
+    let (snapshot, extensions) = snapshot_and_extensions(random_seed.is_none(), random_seed);
     Runtime::new(RuntimeOptions {
-        console: Some(Box::new(BufferConsole::new(ergo_js::ConsoleLevel::Info))),
+        console: Some(buffer_console(ConsoleLevel::Info)),
         extensions,
         snapshot: Some(Snapshot::Static(snapshot)),
+        max_heap_size: *MAX_HEAP_SIZE,
+        modules: libraries,
+        permissions: Some(permissions),
+        allow_timers,
+        locks,
         ..Default::default()
     })
 }
@@ -58,13 +133,45 @@ pub fn create_executor_runtime() -> Runtime {
 /// This is used for things like evaluating guard conditions in state machines.
 pub fn create_simple_runtime() -> Runtime {
     Runtime::new(RuntimeOptions {
-        console: Some(Box::new(BufferConsole::new(ergo_js::ConsoleLevel::Debug))),
+        console: Some(buffer_console(ConsoleLevel::Debug)),
         extensions: ergo_js::core_extensions(None),
         snapshot: Some(Snapshot::Static(CORE_SNAPSHOT)),
+        max_heap_size: *MAX_HEAP_SIZE,
         ..Default::default()
     })
 }
 
+/// Periodically run a trivial script in [POOL], so that a wedged worker shows up in the logs
+/// and health checks instead of as tasks that time out.
+pub fn start_pool_probe(
+    mut shutdown: GracefulShutdownConsumer,
+    interval: Option<std::time::Duration>,
+    timeout: Option<std::time::Duration>,
+) -> tokio::task::JoinHandle<()> {
+    let interval = interval.unwrap_or_else(|| std::time::Duration::from_secs(30));
+    let timeout = timeout.unwrap_or_else(|| std::time::Duration::from_secs(10));
+    tokio::spawn(async move {
+        loop {
+            let probe = POOL.probe(timeout).await;
+            if !probe.ok {
+                let stats = POOL.stats();
+                event!(
+                    Level::ERROR,
+                    failures=%probe.consecutive_failures,
+                    queued=%stats.queued,
+                    active=%stats.active,
+                    "JS runtime pool probe failed"
+                );
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => continue,
+                _ = shutdown.wait_for_shutdown() => break,
+            }
+        }
+    })
+}
+
 pub fn wrap_in_function(script: &str) -> String {
     format!(
         r##"(function() {{
@@ -131,9 +238,22 @@ pub async fn run_simple_with_args<RESULT: DeserializeOwned + std::fmt::Debug + S
 
     event!(Level::TRACE, script=%wrapped, "running script");
 
+    match *EXECUTION_MODE {
+        ExecutionMode::InProcess => run_expression(wrapped).await,
+        ExecutionMode::Subprocess => {
+            let value = PROCESS_POOL.run_expression(wrapped).await?;
+            serde_json::from_value(value).map_err(|e| ergo_js::Error::Runtime(e.into()))
+        }
+    }
+}
+
+/// Evaluate an expression in [POOL].
+pub(crate) async fn run_expression<RESULT: DeserializeOwned + std::fmt::Debug + Send + 'static>(
+    script: String,
+) -> Result<RESULT, ergo_js::Error> {
     POOL.run(move || async move {
         let mut runtime = create_simple_runtime();
-        let result: RESULT = runtime.run_expression("script", wrapped.as_str())?;
+        let result: RESULT = runtime.run_expression("script", script.as_str())?;
         Ok(result)
     })
     .await
@@ -155,4 +275,18 @@ mod tests {
         .unwrap();
         assert_eq!(result, 5);
     }
+
+    #[test]
+    fn truncate_result() {
+        let small = json!({ "a": 1 });
+        assert_eq!(super::truncate_result(small.clone(), 1000), (small, None));
+
+        let large = json!({ "data": "x".repeat(1000) });
+        let (result, size) = super::truncate_result(large, 200);
+        assert_eq!(size, Some(1011));
+        assert_eq!(result["$truncated"]["bytes"], json!(1011));
+        let preview = result["$truncated"]["preview"].as_str().unwrap();
+        assert!(preview.starts_with(r#"{"data":"xxx"#));
+        assert!(preview.ends_with("... [truncated 911 bytes]"));
+    }
 }
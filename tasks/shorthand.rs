@@ -0,0 +1,468 @@
+//! A compact text format for simple tasks, for people who don't want to write a state machine by
+//! hand. Each line is a rule that runs actions when a trigger fires:
+//!
+//! ```text
+//! # Lines starting with # are comments.
+//! when url_input if payload.status == "down" then notify_slack(message: payload.url, channel: "ops")
+//! when url_input then log_check
+//! ```
+//!
+//! The condition after `if` is a JavaScript expression that can use `payload` and `context`.
+//! Actions are separated by commas. An action's payload is built from the fields in parentheses,
+//! where each value is a JSON constant or a `payload.` or `context.` path. An action without
+//! fields gets the trigger's payload unchanged.
+//!
+//! Rules are checked in order, and only the first rule that matches a trigger runs.
+//!
+//! The rules compile to a state machine with a single state.
+
+use fxhash::FxHashMap;
+use serde_json::Value;
+use smallvec::smallvec;
+use thiserror::Error;
+
+use crate::state_machine::{
+    ActionInvokeDef, ActionInvokeDefDataField, ActionPayloadBuilder, EventHandler, StateDefinition,
+    StateMachine, StateMachineConfig,
+};
+
+/// The name of the only state in a compiled machine.
+pub const SHORTHAND_STATE: &str = "start";
+
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+#[error("Line {line}: {message}")]
+pub struct ShorthandError {
+    /// The line number, starting from 1.
+    pub line: usize,
+    pub message: String,
+}
+
+/// Compile shorthand rules into a state machine config. All the lines are checked, so the result
+/// contains every error instead of only the first one.
+pub fn compile_shorthand(
+    name: &str,
+    source: &str,
+) -> Result<StateMachineConfig, Vec<ShorthandError>> {
+    let mut handlers = smallvec![];
+    let mut errors = Vec::new();
+
+    for (index, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match parse_rule(line) {
+            Ok(handler) => handlers.push(handler),
+            Err(message) => errors.push(ShorthandError {
+                line: index + 1,
+                message,
+            }),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    if handlers.is_empty() {
+        return Err(vec![ShorthandError {
+            line: 1,
+            message: "No rules found".to_string(),
+        }]);
+    }
+
+    let mut states = FxHashMap::default();
+    states.insert(
+        SHORTHAND_STATE.to_string(),
+        StateDefinition {
+            description: None,
+            on: handlers,
+        },
+    );
+
+    Ok(smallvec![StateMachine {
+        name: name.to_string(),
+        description: None,
+        initial: SHORTHAND_STATE.to_string(),
+        on: smallvec![],
+        states,
+    }])
+}
+
+fn parse_rule(line: &str) -> Result<EventHandler, String> {
+    let rest =
+        strip_keyword(line, "when").ok_or_else(|| "Rules must start with `when`".to_string())?;
+    let mut cursor = Cursor::new(rest);
+    let trigger_id = cursor
+        .ident()
+        .ok_or_else(|| "Expected a trigger name after `when`".to_string())?
+        .to_string();
+    let rest = cursor.rest();
+
+    let (cond, actions) = match strip_keyword(rest, "if") {
+        Some(rest) => {
+            let (cond, actions) = split_at_keyword(rest, "then")
+                .ok_or_else(|| "Expected `then` after the condition".to_string())?;
+            let cond = cond.trim();
+            if cond.is_empty() {
+                return Err("Expected a condition after `if`".to_string());
+            }
+            (Some(format!("return Boolean({cond});")), actions)
+        }
+        None => {
+            let actions = strip_keyword(rest, "then")
+                .ok_or_else(|| "Expected `if` or `then` after the trigger".to_string())?;
+            (None, actions)
+        }
+    };
+
+    Ok(EventHandler {
+        trigger_id,
+        cond,
+        when: None,
+        target: None,
+        actions: Some(parse_actions(actions)?),
+    })
+}
+
+fn parse_actions(text: &str) -> Result<Vec<ActionInvokeDef>, String> {
+    let mut cursor = Cursor::new(text);
+    let mut actions = Vec::new();
+
+    loop {
+        let task_action_local_id = cursor
+            .ident()
+            .ok_or_else(|| "Expected an action name".to_string())?
+            .to_string();
+
+        let data = if cursor.eat('(') {
+            ActionPayloadBuilder::FieldMap(parse_fields(&mut cursor)?)
+        } else {
+            ActionPayloadBuilder::Script("return payload;".to_string())
+        };
+
+        actions.push(ActionInvokeDef {
+            task_action_local_id,
+            data,
+        });
+
+        if cursor.at_end() {
+            return Ok(actions);
+        }
+
+        if !cursor.eat(',') {
+            return Err(format!("Unexpected text `{}`", cursor.rest().trim()));
+        }
+    }
+}
+
+/// Parse `name: value` pairs up to the closing parenthesis.
+fn parse_fields(
+    cursor: &mut Cursor,
+) -> Result<FxHashMap<String, ActionInvokeDefDataField>, String> {
+    let mut fields = FxHashMap::default();
+    if cursor.eat(')') {
+        return Ok(fields);
+    }
+
+    loop {
+        let name = cursor
+            .ident()
+            .ok_or_else(|| "Expected a field name".to_string())?
+            .to_string();
+        if !cursor.eat(':') {
+            return Err(format!("Expected `:` after field `{name}`"));
+        }
+
+        let value = cursor.value()?;
+        if fields.insert(name.clone(), value).is_some() {
+            return Err(format!("Field `{name}` is set more than once"));
+        }
+
+        if cursor.eat(')') {
+            return Ok(fields);
+        }
+
+        if !cursor.eat(',') {
+            return Err("Expected `,` or `)` after a field value".to_string());
+        }
+    }
+}
+
+/// Convert a dotted path like `a.b.0` to a JSON pointer.
+fn json_pointer(path: &str) -> String {
+    path.split('.')
+        .filter(|s| !s.is_empty())
+        .map(|s| format!("/{}", s.replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+/// If `text` starts with `keyword` as a whole word, return the text after it.
+fn strip_keyword<'a>(text: &'a str, keyword: &str) -> Option<&'a str> {
+    let text = text.trim_start();
+    let rest = text.strip_prefix(keyword)?;
+    match rest.chars().next() {
+        None => Some(rest),
+        Some(c) if c.is_whitespace() => Some(rest),
+        _ => None,
+    }
+}
+
+/// Split the text at the first occurrence of `keyword` as a whole word, ignoring anything inside
+/// string literals.
+fn split_at_keyword<'a>(text: &'a str, keyword: &str) -> Option<(&'a str, &'a str)> {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut prev_boundary = true;
+
+    for (i, c) in text.char_indices() {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            prev_boundary = false;
+            continue;
+        }
+
+        if matches!(c, '"' | '\'' | '`') {
+            quote = Some(c);
+        } else if prev_boundary && text[i..].starts_with(keyword) {
+            let after = &text[i + keyword.len()..];
+            if after.is_empty() || after.starts_with(char::is_whitespace) {
+                return Some((&text[..i], after));
+            }
+        }
+
+        prev_boundary = c.is_whitespace();
+    }
+
+    None
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-'
+}
+
+struct Cursor<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(text: &'a str) -> Self {
+        Cursor { text, pos: 0 }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn at_end(&mut self) -> bool {
+        self.skip_whitespace();
+        self.pos == self.text.len()
+    }
+
+    /// Consume the character if it's next, ignoring whitespace.
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.rest().starts_with(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        let rest = self.rest();
+        let len = rest.find(|c| !f(c)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn ident(&mut self) -> Option<&'a str> {
+        self.skip_whitespace();
+        let ident = self.take_while(is_ident_char);
+        (!ident.is_empty()).then_some(ident)
+    }
+
+    fn value(&mut self) -> Result<ActionInvokeDefDataField, String> {
+        self.skip_whitespace();
+        let rest = self.rest();
+
+        if rest.starts_with('"') {
+            let mut escaped = false;
+            let end = rest
+                .char_indices()
+                .skip(1)
+                .find(|&(_, c)| {
+                    let is_end = !escaped && c == '"';
+                    escaped = !escaped && c == '\\';
+                    is_end
+                })
+                .map(|(i, _)| i + 1)
+                .ok_or_else(|| "Unterminated string".to_string())?;
+            self.pos += end;
+            let value: Value = serde_json::from_str(&rest[..end])
+                .map_err(|e| format!("Invalid string {}: {e}", &rest[..end]))?;
+            return Ok(ActionInvokeDefDataField::Constant(value));
+        }
+
+        let token = self.take_while(|c| is_ident_char(c) || c == '.' || c == '+');
+        if token.is_empty() {
+            return Err("Expected a field value".to_string());
+        }
+
+        let path = |prefix: &str| {
+            token
+                .strip_prefix(prefix)
+                .filter(|p| p.is_empty() || p.starts_with('.'))
+                .map(json_pointer)
+        };
+
+        if let Some(path) = path("payload") {
+            Ok(ActionInvokeDefDataField::Input(path, true))
+        } else if let Some(path) = path("context") {
+            Ok(ActionInvokeDefDataField::Context(path, true))
+        } else {
+            serde_json::from_str::<Value>(token)
+                .map(ActionInvokeDefDataField::Constant)
+                .map_err(|_| {
+                    format!(
+                        "Invalid value `{token}`. Use a JSON value or a payload or context path"
+                    )
+                })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn handlers(source: &str) -> Vec<EventHandler> {
+        let config = compile_shorthand("rules", source).expect("compiling");
+        assert_eq!(config.len(), 1);
+        config[0].states[SHORTHAND_STATE].on.to_vec()
+    }
+
+    #[test]
+    fn rule_with_condition_and_fields() {
+        let rules = handlers(
+            r##"
+            # Watch the site
+            when url_input if payload.status == "down" then notify_slack(message: payload.url, channel: "ops", count: 3)
+            "##,
+        );
+
+        assert_eq!(rules.len(), 1);
+        let rule = &rules[0];
+        assert_eq!(rule.trigger_id, "url_input");
+        assert_eq!(
+            rule.cond.as_deref(),
+            Some(r#"return Boolean(payload.status == "down");"#)
+        );
+        assert_eq!(rule.target, None);
+
+        let actions = rule.actions.as_ref().unwrap();
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].task_action_local_id, "notify_slack");
+        let fields = match &actions[0].data {
+            ActionPayloadBuilder::FieldMap(f) => f,
+            other => panic!("Expected a field map, saw {other:?}"),
+        };
+        assert_eq!(
+            fields["message"],
+            ActionInvokeDefDataField::Input("/url".to_string(), true)
+        );
+        assert_eq!(
+            fields["channel"],
+            ActionInvokeDefDataField::Constant(json!("ops"))
+        );
+        assert_eq!(
+            fields["count"],
+            ActionInvokeDefDataField::Constant(json!(3))
+        );
+    }
+
+    #[test]
+    fn multiple_actions_without_fields() {
+        let rules = handlers("when tick then log_it, save(value: context.last.value)");
+        let rule = &rules[0];
+        assert_eq!(rule.cond, None);
+
+        let actions = rule.actions.as_ref().unwrap();
+        assert_eq!(actions.len(), 2);
+        assert_eq!(
+            actions[0].data,
+            ActionPayloadBuilder::Script("return payload;".to_string())
+        );
+        match &actions[1].data {
+            ActionPayloadBuilder::FieldMap(f) => assert_eq!(
+                f["value"],
+                ActionInvokeDefDataField::Context("/last/value".to_string(), true)
+            ),
+            other => panic!("Expected a field map, saw {other:?}"),
+        }
+    }
+
+    #[test]
+    fn then_inside_condition_string() {
+        let rules = handlers(r#"when msg if payload.text == "and then some" then reply"#);
+        assert_eq!(
+            rules[0].cond.as_deref(),
+            Some(r#"return Boolean(payload.text == "and then some");"#)
+        );
+        assert_eq!(
+            rules[0].actions.as_ref().unwrap()[0].task_action_local_id,
+            "reply"
+        );
+    }
+
+    #[test]
+    fn rules_keep_their_order() {
+        let rules = handlers(
+            "when a if payload.x > 5 then big\n\
+             when a then small",
+        );
+        assert_eq!(rules.len(), 2);
+        assert!(rules[0].cond.is_some());
+        assert_eq!(rules[1].cond, None);
+    }
+
+    #[test]
+    fn errors_report_every_line() {
+        let errors = compile_shorthand(
+            "rules",
+            "when a then b\n\
+             if x then y\n\
+             when a if then b\n\
+             when a then b(x: nope)\n\
+             when a then b(x: 1, x: 2)",
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            errors.iter().map(|e| e.line).collect::<Vec<_>>(),
+            vec![2, 3, 4, 5]
+        );
+    }
+
+    #[test]
+    fn empty_source() {
+        let errors = compile_shorthand("rules", "# nothing here\n").unwrap_err();
+        assert_eq!(errors[0].message, "No rules found");
+    }
+}
@@ -0,0 +1,195 @@
+//! A record of each task's state after every input that it handled, so that the state can be
+//! looked up as of any past run and compared between runs.
+//!
+//! A row is written for every applied input, but the state is only saved when the input
+//! changed it. The state as of a run is the most recent saved state at or before that run.
+//! Scheduled state resets also add a row, which isn't linked to any input.
+
+use ergo_database::object_id::TaskId;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::Error;
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct StateSnapshot {
+    pub inputs_log_id: Uuid,
+    pub task_id: TaskId,
+    /// True if this input changed the state.
+    pub changed: bool,
+    /// The task's state after the input was applied. This is null if the state was never
+    /// saved before this input, which happens for tasks that predate state history.
+    pub state: Option<serde_json::Value>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct StateChange {
+    /// A JSON Pointer to the value that changed.
+    pub path: String,
+    /// The old value, or None if the value was added.
+    pub before: Option<serde_json::Value>,
+    /// The new value, or None if the value was removed.
+    pub after: Option<serde_json::Value>,
+}
+
+/// Record the result of applying an input. `state` should be None if the state didn't change.
+pub async fn record_state(
+    tx: &mut PgConnection,
+    task_id: &TaskId,
+    inputs_log_id: Uuid,
+    state: Option<&serde_json::Value>,
+) -> Result<(), Error> {
+    sqlx::query!(
+        r##"INSERT INTO task_state_history (task_id, inputs_log_id, state)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (inputs_log_id) DO UPDATE SET state=EXCLUDED.state"##,
+        task_id.0,
+        inputs_log_id,
+        state
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Get the task's state as it was right after the input `inputs_log_id` was applied.
+pub async fn state_as_of(
+    tx: &mut PgConnection,
+    inputs_log_id: Uuid,
+) -> Result<Option<StateSnapshot>, Error> {
+    let snapshot = sqlx::query_as!(
+        StateSnapshot,
+        r##"SELECT h.inputs_log_id AS "inputs_log_id!",
+            h.task_id AS "task_id: TaskId",
+            h.state IS NOT NULL AS "changed!",
+            (
+                SELECT prev.state FROM task_state_history prev
+                WHERE prev.task_id = h.task_id
+                    AND prev.state IS NOT NULL
+                    AND prev.task_state_history_id <= h.task_state_history_id
+                ORDER BY prev.task_state_history_id DESC
+                LIMIT 1
+            ) AS state
+        FROM task_state_history h
+        WHERE h.inputs_log_id = $1"##,
+        inputs_log_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    Ok(snapshot)
+}
+
+/// List the values that differ between two states.
+pub fn diff_states(before: &serde_json::Value, after: &serde_json::Value) -> Vec<StateChange> {
+    let mut changes = Vec::new();
+    diff_value(String::new(), Some(before), Some(after), &mut changes);
+    changes
+}
+
+fn escape_pointer(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+fn diff_value(
+    path: String,
+    before: Option<&serde_json::Value>,
+    after: Option<&serde_json::Value>,
+    changes: &mut Vec<StateChange>,
+) {
+    use serde_json::Value;
+
+    match (before, after) {
+        (Some(Value::Object(b)), Some(Value::Object(a))) => {
+            for (key, b_value) in b {
+                let child = format!("{}/{}", path, escape_pointer(key));
+                diff_value(child, Some(b_value), a.get(key), changes);
+            }
+
+            for (key, a_value) in a.iter().filter(|(key, _)| !b.contains_key(*key)) {
+                let child = format!("{}/{}", path, escape_pointer(key));
+                diff_value(child, None, Some(a_value), changes);
+            }
+        }
+        (Some(Value::Array(b)), Some(Value::Array(a))) => {
+            for i in 0..b.len().max(a.len()) {
+                diff_value(format!("{}/{}", path, i), b.get(i), a.get(i), changes);
+            }
+        }
+        (b, a) if b != a => changes.push(StateChange {
+            path,
+            before: b.cloned(),
+            after: a.cloned(),
+        }),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn diff() {
+        let before = json!({
+            "state": "idle",
+            "context": { "count": 1, "a/b": true, "items": [1, 2] },
+            "removed": "x",
+        });
+        let after = json!({
+            "state": "running",
+            "context": { "count": 1, "a/b": false, "items": [1, 3, 4] },
+            "added": null,
+        });
+
+        let mut changes = diff_states(&before, &after);
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+
+        assert_eq!(
+            changes,
+            vec![
+                StateChange {
+                    path: "/added".into(),
+                    before: None,
+                    after: Some(json!(null)),
+                },
+                StateChange {
+                    path: "/context/a~1b".into(),
+                    before: Some(json!(true)),
+                    after: Some(json!(false)),
+                },
+                StateChange {
+                    path: "/context/items/1".into(),
+                    before: Some(json!(2)),
+                    after: Some(json!(3)),
+                },
+                StateChange {
+                    path: "/context/items/2".into(),
+                    before: None,
+                    after: Some(json!(4)),
+                },
+                StateChange {
+                    path: "/removed".into(),
+                    before: Some(json!("x")),
+                    after: None,
+                },
+                StateChange {
+                    path: "/state".into(),
+                    before: Some(json!("idle")),
+                    after: Some(json!("running")),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn no_changes() {
+        let state = json!({ "a": [1, { "b": 2 }] });
+        assert!(diff_states(&state, &state).is_empty());
+    }
+}
@@ -9,6 +9,7 @@ pub use native::*;
 
 use crate::{
     actions::{Action, TaskAction},
+    conditions::Condition,
     inputs::Input,
     TaskTrigger, TaskValidateError,
 };
@@ -21,6 +22,8 @@ pub enum StateMachineError {
     ContextMissingField(String),
     #[error("Payload is missing required field {0}")]
     InputPayloadMissingField(String),
+    #[error(transparent)]
+    LimitExceeded(#[from] crate::limits::LimitExceeded),
 
     #[cfg(not(target_family = "wasm"))]
     #[error(transparent)]
@@ -58,6 +61,14 @@ pub struct StateDefinition {
 #[derive(Clone, Debug, JsonSchema, Serialize, Deserialize, PartialEq, Eq)]
 pub struct EventHandler {
     pub trigger_id: String,
+    /// A script that returns whether this handler should run. If it returns false, the next
+    /// handler for the same trigger is tried instead.
+    #[serde(default)]
+    pub cond: Option<String>,
+    /// A declarative condition that works like `cond`, but without running a script. If both
+    /// are set, both must pass.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub when: Option<Condition>,
     pub target: Option<TransitionTarget>,
     pub actions: Option<Vec<ActionInvokeDef>>,
 }
@@ -138,6 +149,54 @@ impl StateMachine {
             );
         }
 
+        errors.extend(self.validate_conditions(|trigger_id| {
+            let trigger = task_triggers.get(trigger_id)?;
+            inputs
+                .get(&trigger.input_id.to_string())
+                .map(|input| &input.payload_schema)
+        }));
+
+        errors
+    }
+
+    /// Returns true if any event handler has a declarative condition.
+    pub fn has_conditions(&self) -> bool {
+        self.on
+            .iter()
+            .chain(self.states.values().flat_map(|s| s.on.iter()))
+            .any(|handler| handler.when.is_some())
+    }
+
+    /// Check the declarative conditions on the event handlers. `payload_schema` returns the
+    /// payload schema for a trigger, if it's known.
+    pub fn validate_conditions<'a>(
+        &self,
+        payload_schema: impl Fn(&str) -> Option<&'a serde_json::Value>,
+    ) -> Vec<TaskValidateError> {
+        let mut errors = Vec::new();
+        let mut check = |state: Option<&String>, handlers: &[EventHandler]| {
+            for (index, handler) in handlers.iter().enumerate() {
+                let when = match handler.when.as_ref() {
+                    Some(w) => w,
+                    None => continue,
+                };
+
+                let schema = payload_schema(&handler.trigger_id);
+                errors.extend(when.validate(schema).into_iter().map(|message| {
+                    TaskValidateError::InvalidCondition {
+                        state: state.cloned(),
+                        index,
+                        message,
+                    }
+                }));
+            }
+        };
+
+        check(None, &self.on);
+        for (name, state) in self.states.iter() {
+            check(Some(name), &state.on);
+        }
+
         errors
     }
 
@@ -198,8 +257,11 @@ mod native {
 
     use super::*;
     use crate::{
-        actions::{ActionInvocation, ActionInvocations},
+        actions::{ActionInvocation, ActionInvocations, ActionSource},
+        inputs::chain::InputChain,
+        limits::RunBudget,
         scripting::{self, run_simple_with_context_and_payload},
+        trace_context::TraceContext,
     };
 
     #[derive(Debug)]
@@ -212,6 +274,39 @@ mod native {
     }
 
     impl EventHandler {
+        async fn matches(
+            &self,
+            trigger_id: &str,
+            context: &serde_json::Value,
+            payload: &Option<&serde_json::Value>,
+            budget: &mut RunBudget,
+        ) -> Result<bool, StateMachineError> {
+            if self.trigger_id != trigger_id {
+                return Ok(false);
+            }
+
+            if let Some(when) = &self.when {
+                if !when.evaluate(context, *payload) {
+                    return Ok(false);
+                }
+            }
+
+            match &self.cond {
+                None => Ok(true),
+                Some(cond) => {
+                    budget.js_evaluation()?;
+                    let result: Option<bool> = scripting::run_simple_with_context_and_payload(
+                        cond.as_str(),
+                        Some(context),
+                        *payload,
+                    )
+                    .await
+                    .map_err(StateMachineError::ScriptError)?;
+                    Ok(result.unwrap_or(false))
+                }
+            }
+        }
+
         async fn resolve_actions(
             &self,
             task_id: &TaskId,
@@ -219,13 +314,16 @@ mod native {
             input_arrival_id: &Option<uuid::Uuid>,
             context: &serde_json::Value,
             payload: &Option<&serde_json::Value>,
+            source: &ActionSource,
+            budget: &mut RunBudget,
         ) -> Result<ActionInvocations, StateMachineError> {
             match &self.actions {
                 None => Ok(ActionInvocations::new()),
                 Some(actions) => {
+                    budget.add_actions(actions.len())?;
                     let mut output = ActionInvocations::with_capacity(actions.len());
                     for def in actions {
-                        let built_payload = def.data.build(context, payload).await?;
+                        let built_payload = def.data.build(context, payload, budget).await?;
                         event!(Level::DEBUG, ?context, ?built_payload, "built payload");
                         let invocation = ActionInvocation {
                             input_arrival_id: *input_arrival_id,
@@ -234,6 +332,11 @@ mod native {
                             task_action_local_id: def.task_action_local_id.clone(),
                             user_id: user_id.clone(),
                             payload: built_payload,
+                            chain: InputChain::default(),
+                            trace: TraceContext::default(),
+                            payload_in_log: false,
+                            compensates: None,
+                            source: Some(source.clone()),
                         };
                         output.push(invocation);
                     }
@@ -246,11 +349,13 @@ mod native {
             &self,
             context: &serde_json::Value,
             payload: &Option<&serde_json::Value>,
+            budget: &mut RunBudget,
         ) -> Result<Option<String>, StateMachineError> {
             match &self.target {
                 None => Ok(None),
                 Some(TransitionTarget::One(s)) => Ok(Some(s.clone())),
                 Some(TransitionTarget::Script(s)) => {
+                    budget.js_evaluation()?;
                     scripting::run_simple_with_context_and_payload(
                         s.as_str(),
                         Some(context),
@@ -268,6 +373,7 @@ mod native {
             &self,
             context: &serde_json::Value,
             payload: &Option<&serde_json::Value>,
+            budget: &mut RunBudget,
         ) -> Result<serde_json::Value, StateMachineError> {
             match self {
                 ActionPayloadBuilder::FieldMap(data) => {
@@ -296,6 +402,7 @@ mod native {
                                 }
                             }
                             ActionInvokeDefDataField::Script(script) => {
+                                budget.js_evaluation()?;
                                 run_simple_with_context_and_payload::<serde_json::Value>(
                                     script.as_str(),
                                     Some(context),
@@ -311,6 +418,7 @@ mod native {
                     Ok(serde_json::Value::Object(output))
                 }
                 ActionPayloadBuilder::Script(s) => {
+                    budget.js_evaluation()?;
                     let result = scripting::run_simple_with_context_and_payload(
                         s.as_str(),
                         Some(context),
@@ -325,6 +433,25 @@ mod native {
         }
     }
 
+    async fn find_handler<'a>(
+        handlers: &'a [EventHandler],
+        trigger_id: &str,
+        context: &serde_json::Value,
+        payload: &Option<&serde_json::Value>,
+        budget: &mut RunBudget,
+    ) -> Result<Option<&'a EventHandler>, StateMachineError> {
+        for handler in handlers {
+            if handler
+                .matches(trigger_id, context, payload, budget)
+                .await?
+            {
+                return Ok(Some(handler));
+            }
+        }
+
+        Ok(None)
+    }
+
     impl<'d> StateMachineWithData {
         pub fn new(
             task_id: TaskId,
@@ -352,8 +479,9 @@ mod native {
             user_id: &UserId,
             input_arrival_id: &Option<uuid::Uuid>,
             payload: Option<&serde_json::Value>,
+            budget: &mut RunBudget,
         ) -> Result<ActionInvocations, StateMachineError> {
-            let handler = self
+            let state_handlers = &self
                 .machine
                 .states
                 .get(&self.data.state)
@@ -361,18 +489,43 @@ mod native {
                     idx: self.idx,
                     state: self.data.state.clone(),
                 })?
-                .on
-                .iter()
-                .find(|o| o.trigger_id == trigger_id)
-                .or_else(|| {
-                    // Look it up in the global event handlers
-                    self.machine.on.iter().find(|o| o.trigger_id == trigger_id)
-                });
+                .on;
+
+            let handler = match find_handler(
+                state_handlers,
+                trigger_id,
+                &self.data.context,
+                &payload,
+                budget,
+            )
+            .await?
+            {
+                Some(h) => Some(h),
+                // Look it up in the global event handlers
+                None => {
+                    find_handler(
+                        &self.machine.on,
+                        trigger_id,
+                        &self.data.context,
+                        &payload,
+                        budget,
+                    )
+                    .await?
+                }
+            };
 
             match handler {
                 Some(h) => {
                     event!(Level::DEBUG, handler=?h, "Running event handler");
-                    let next_state = h.next_state(&self.data.context, &payload).await?;
+                    let next_state = h.next_state(&self.data.context, &payload, budget).await?;
+                    let source = ActionSource::StateMachine {
+                        machine: self.idx,
+                        trigger: trigger_id.to_string(),
+                        from_state: self.data.state.clone(),
+                        to_state: next_state
+                            .clone()
+                            .unwrap_or_else(|| self.data.state.clone()),
+                    };
                     let actions = h
                         .resolve_actions(
                             &self.task_id,
@@ -380,11 +533,14 @@ mod native {
                             input_arrival_id,
                             &self.data.context,
                             &payload,
+                            &source,
+                            budget,
                         )
                         .await?;
 
                     if let Some(s) = next_state {
                         if self.data.state != s {
+                            budget.transition()?;
                             self.changed = true;
                             self.data.state = s;
                         }
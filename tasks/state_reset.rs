@@ -0,0 +1,230 @@
+//! Scheduled resets of a task's state, for tasks that accumulate data over a period of time
+//! such as daily counters or digests. The state from before each reset is saved in the
+//! `task_state_resets` table.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::periodic::PeriodicSchedule;
+
+#[cfg(not(target_family = "wasm"))]
+pub use native::*;
+
+#[derive(Debug, Clone, JsonSchema, Serialize, Deserialize, PartialEq, Eq)]
+pub struct StateResetPolicy {
+    /// When to reset the state.
+    pub schedule: PeriodicSchedule,
+    pub reset: StateReset,
+}
+
+#[derive(Debug, Clone, JsonSchema, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", content = "data")]
+pub enum StateReset {
+    /// Replace the state with the task's initial state.
+    Default,
+    /// Run a script to calculate the new state. The script receives the current state as
+    /// `state` and the initial state as `defaultState`, and returns the new state.
+    Script(String),
+}
+
+#[cfg(not(target_family = "wasm"))]
+mod native {
+    use chrono::Utc;
+    use ergo_database::{object_id::TaskId, PostgresPool};
+    use sqlx::{types::Json, PgConnection};
+    use tracing::{event, Level};
+
+    use super::*;
+    use crate::{scripting::run_simple_with_args, Error, TaskConfig, TaskState};
+
+    impl StateResetPolicy {
+        /// Calculate the state that the task should have after the reset.
+        pub async fn apply(
+            &self,
+            config: &TaskConfig,
+            state: &TaskState,
+        ) -> Result<TaskState, Error> {
+            let default_state = config.default_state();
+            match &self.reset {
+                StateReset::Default => Ok(default_state),
+                StateReset::Script(script) => {
+                    let state = serde_json::to_value(state)?;
+                    let default_state = serde_json::to_value(&default_state)?;
+                    run_simple_with_args(
+                        script,
+                        &[("state", &state), ("defaultState", &default_state)],
+                    )
+                    .await
+                    .map_err(Error::StateResetScript)
+                }
+            }
+        }
+    }
+
+    /// Set or clear a task's reset policy, and schedule its next reset.
+    pub async fn set_state_reset(
+        tx: &mut PgConnection,
+        task_id: &TaskId,
+        policy: Option<&StateResetPolicy>,
+    ) -> Result<(), Error> {
+        let next_reset = match policy {
+            Some(policy) => policy.schedule.next_scheduled_after(Utc::now())?,
+            None => None,
+        };
+
+        sqlx::query!(
+            "UPDATE tasks SET state_reset=$2, state_reset_at=$3 WHERE task_id=$1",
+            task_id.0,
+            policy.map(Json) as _,
+            next_reset
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Reset the state of each task that is due for a reset, and return the number of tasks
+    /// that were reset.
+    pub async fn run_due_state_resets(pool: &PostgresPool) -> Result<usize, Error> {
+        let mut tx = pool.begin().await?;
+
+        let due = sqlx::query!(
+            r##"SELECT task_id AS "task_id: TaskId",
+                state AS "state: Json<TaskState>",
+                state_reset AS "state_reset!: Json<StateResetPolicy>",
+                compiled AS "compiled: Json<TaskConfig>"
+            FROM tasks
+            JOIN task_templates USING (task_template_id, task_template_version)
+            WHERE state_reset_at <= now() AND NOT tasks.deleted
+            LIMIT 50
+            FOR UPDATE OF tasks SKIP LOCKED"##
+        )
+        .fetch_all(&mut tx)
+        .await?;
+
+        let count = due.len();
+        for task in due {
+            let policy = task.state_reset.0;
+            let next_reset = policy.schedule.next_scheduled_after(Utc::now())?;
+
+            let new_state = match policy.apply(&task.compiled.0, &task.state.0).await {
+                Ok(state) => state,
+                Err(e) => {
+                    // Leave the state alone and try again at the next scheduled time.
+                    event!(Level::ERROR, task_id=%task.task_id, error=%e, "Failed to reset task state");
+                    sqlx::query!(
+                        "UPDATE tasks SET state_reset_at=$2 WHERE task_id=$1",
+                        task.task_id.0,
+                        next_reset
+                    )
+                    .execute(&mut tx)
+                    .await?;
+                    continue;
+                }
+            };
+
+            let new_state = serde_json::to_value(&new_state)?;
+            sqlx::query!(
+                "INSERT INTO task_state_resets (task_id, previous_state, new_state)
+                VALUES ($1, $2, $3)",
+                task.task_id.0,
+                serde_json::to_value(&task.state.0)?,
+                &new_state
+            )
+            .execute(&mut tx)
+            .await?;
+
+            sqlx::query!(
+                "UPDATE tasks SET state=$2, state_reset_at=$3 WHERE task_id=$1",
+                task.task_id.0,
+                &new_state,
+                next_reset
+            )
+            .execute(&mut tx)
+            .await?;
+
+            // Keep the state history in line with the reset, so that it shows the new state
+            // for the inputs that come after it.
+            sqlx::query!(
+                "INSERT INTO task_state_history (task_id, state) VALUES ($1, $2)",
+                task.task_id.0,
+                &new_state
+            )
+            .execute(&mut tx)
+            .await?;
+
+            event!(Level::INFO, task_id=%task.task_id, "Reset task state");
+        }
+
+        tx.commit().await?;
+        Ok(count)
+    }
+}
+
+#[cfg(all(test, not(target_family = "wasm")))]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{TaskConfig, TaskState};
+
+    fn js_task() -> TaskConfig {
+        serde_json::from_value(json!({
+            "type": "Js",
+            "data": {
+                "script": "",
+                "timeout": null,
+            }
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn reset_to_default() {
+        let config = js_task();
+        let policy = StateResetPolicy {
+            schedule: PeriodicSchedule::Cron("0 0 0 * * * *".to_string()),
+            reset: StateReset::Default,
+        };
+
+        let state: TaskState = serde_json::from_value(json!({
+            "type": "Js",
+            "data": { "context": r#"{"count":5}"# }
+        }))
+        .unwrap();
+
+        let new_state = policy.apply(&config, &state).await.unwrap();
+        assert_eq!(new_state, config.default_state());
+    }
+
+    #[tokio::test]
+    async fn reset_with_script() {
+        let config = js_task();
+        let policy = StateResetPolicy {
+            schedule: PeriodicSchedule::Cron("0 0 0 * * * *".to_string()),
+            reset: StateReset::Script(
+                r##"const context = JSON.parse(state.data.context);
+                return {
+                    ...state,
+                    data: { context: JSON.stringify({ count: 0, previous: context.count }) },
+                };"##
+                    .to_string(),
+            ),
+        };
+
+        let state: TaskState = serde_json::from_value(json!({
+            "type": "Js",
+            "data": { "context": r#"{"count":5}"# }
+        }))
+        .unwrap();
+
+        let new_state = policy.apply(&config, &state).await.unwrap();
+        let expected: TaskState = serde_json::from_value(json!({
+            "type": "Js",
+            "data": { "context": r#"{"count":0,"previous":5}"# }
+        }))
+        .unwrap();
+        assert_eq!(new_state, expected);
+    }
+}
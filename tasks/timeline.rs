@@ -0,0 +1,99 @@
+//! Timing spans for a single task run, stored so that the run can later be viewed as a timeline.
+
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use ergo_database::{sql_insert_parameters, PostgresPool};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+use uuid::Uuid;
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct TimelineSpan {
+    pub name: String,
+    pub actions_log_id: Option<Uuid>,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub info: Option<serde_json::Value>,
+}
+
+/// Collects spans during a run. Clones share the same list of spans, so a recorder can be
+/// passed into closures that may run more than once, such as a serializable transaction.
+#[derive(Clone, Debug, Default)]
+pub struct TimelineRecorder {
+    actions_log_id: Option<Uuid>,
+    spans: Arc<Mutex<Vec<TimelineSpan>>>,
+}
+
+impl TimelineRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a recorder whose spans are all associated with an action invocation.
+    pub fn for_action(actions_log_id: Uuid) -> Self {
+        TimelineRecorder {
+            actions_log_id: Some(actions_log_id),
+            spans: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Add a span that started at `start` and ends now.
+    pub fn add(&self, name: &str, start: DateTime<Utc>, info: Option<serde_json::Value>) {
+        let span = TimelineSpan {
+            name: name.to_string(),
+            actions_log_id: self.actions_log_id,
+            start,
+            end: Utc::now(),
+            info,
+        };
+
+        self.spans.lock().unwrap().push(span);
+    }
+
+    /// Run a future and record the time it took.
+    pub async fn span<T>(&self, name: &str, f: impl Future<Output = T>) -> T {
+        let start = Utc::now();
+        let result = f.await;
+        self.add(name, start, None);
+        result
+    }
+
+    pub fn spans(&self) -> Vec<TimelineSpan> {
+        self.spans.lock().unwrap().clone()
+    }
+
+    /// Save the recorded spans. Failures are logged but not returned, since the timeline is
+    /// diagnostic data and shouldn't cause the run itself to fail.
+    pub async fn write(&self, pool: &PostgresPool, inputs_log_id: Option<Uuid>) {
+        let spans = std::mem::take(&mut *self.spans.lock().unwrap());
+        if spans.is_empty() {
+            return;
+        }
+
+        let q = format!(
+            "INSERT INTO run_timeline_spans (inputs_log_id, actions_log_id, name, start_time, end_time, info)
+            VALUES {}",
+            sql_insert_parameters::<6>(spans.len())
+        );
+
+        let mut query = sqlx::query(&q);
+        for span in &spans {
+            query = query
+                .bind(inputs_log_id)
+                .bind(span.actions_log_id)
+                .bind(&span.name)
+                .bind(span.start)
+                .bind(span.end)
+                .bind(&span.info);
+        }
+
+        if let Err(e) = query.execute(pool).await {
+            event!(Level::ERROR, error=%e, ?inputs_log_id, "Failed to write timeline spans");
+        }
+    }
+}
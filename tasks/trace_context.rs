@@ -0,0 +1,66 @@
+//! Trace context carried in queue job payloads, so that a trace started by an HTTP request
+//! continues through the input queue, the task evaluation, and the actions that it runs, even
+//! when those run in other processes.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// The propagation headers (e.g. W3C `traceparent`) for the span that enqueued a job.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceContext(BTreeMap<String, String>);
+
+impl TraceContext {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+mod native {
+    use opentelemetry::propagation::{Extractor, Injector};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    use super::TraceContext;
+
+    impl Injector for TraceContext {
+        fn set(&mut self, key: &str, value: String) {
+            self.0.insert(key.to_string(), value);
+        }
+    }
+
+    impl Extractor for TraceContext {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).map(|v| v.as_str())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(|k| k.as_str()).collect()
+        }
+    }
+
+    impl TraceContext {
+        /// Capture the context of the current span. This is empty when no OpenTelemetry
+        /// exporter is configured.
+        pub fn current() -> TraceContext {
+            let context = tracing::Span::current().context();
+            let mut carrier = TraceContext::default();
+            opentelemetry::global::get_text_map_propagator(|propagator| {
+                propagator.inject_context(&context, &mut carrier)
+            });
+            carrier
+        }
+
+        /// Make `span` a child of the span that this context was captured from.
+        pub fn set_parent_of(&self, span: &tracing::Span) {
+            if self.is_empty() {
+                return;
+            }
+
+            let context = opentelemetry::global::get_text_map_propagator(|propagator| {
+                propagator.extract(self)
+            });
+            span.set_parent(context);
+        }
+    }
+}
@@ -0,0 +1,327 @@
+//! A minimal client for HashiCorp Vault, used for the transit engine. The client logs in with
+//! the token in `VAULT_TOKEN`, with AppRole using `VAULT_ROLE_ID` and `VAULT_SECRET_ID`, or
+//! with the Kubernetes auth method using the pod's service account token and `VAULT_K8S_ROLE`.
+//! Tokens from a login are fetched when first needed, and fetched again shortly before their
+//! lease runs out or when Vault rejects them.
+//!
+//! When `VAULT_NAMESPACE` is set, every request is made in that namespace.
+
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::RwLock;
+
+use crate::error::Error;
+
+lazy_static! {
+    static ref VAULT_CLIENT: Option<VaultClient> = VaultClient::from_env();
+}
+
+/// The Vault client configured by the environment, or `None` if `VAULT_ADDR` is not set.
+pub fn vault_client() -> Option<&'static VaultClient> {
+    VAULT_CLIENT.as_ref()
+}
+
+fn vault_error(e: impl std::fmt::Display) -> Error {
+    Error::Vault(e.to_string())
+}
+
+/// Where Kubernetes mounts the pod's service account token.
+pub const DEFAULT_K8S_TOKEN_PATH: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+
+#[derive(Clone, Debug)]
+pub enum VaultAuth {
+    Token(String),
+    AppRole {
+        role_id: String,
+        secret_id: String,
+    },
+    Kubernetes {
+        /// The Vault role bound to the service account.
+        role: String,
+        /// The path where the Kubernetes auth method is mounted, usually `kubernetes`.
+        mount: String,
+        /// The file containing the service account JWT. This is read again on every login,
+        /// since projected service account tokens are rotated by the kubelet.
+        token_path: PathBuf,
+    },
+}
+
+impl VaultAuth {
+    /// Read the auth method from the environment, given a function that looks up a variable.
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Option<VaultAuth> {
+        if let (Some(role_id), Some(secret_id)) = (var("VAULT_ROLE_ID"), var("VAULT_SECRET_ID")) {
+            return Some(VaultAuth::AppRole { role_id, secret_id });
+        }
+
+        if let Some(role) = var("VAULT_K8S_ROLE") {
+            return Some(VaultAuth::Kubernetes {
+                role,
+                mount: var("VAULT_K8S_MOUNT").unwrap_or_else(|| "kubernetes".to_string()),
+                token_path: var("VAULT_K8S_TOKEN_PATH")
+                    .unwrap_or_else(|| DEFAULT_K8S_TOKEN_PATH.to_string())
+                    .into(),
+            });
+        }
+
+        var("VAULT_TOKEN").map(VaultAuth::Token)
+    }
+
+    /// If tokens come from logging in, as opposed to a fixed token.
+    fn can_login(&self) -> bool {
+        !matches!(self, VaultAuth::Token(_))
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginResponse {
+    auth: LoginAuth,
+}
+
+#[derive(Deserialize)]
+struct LoginAuth {
+    client_token: String,
+    /// The token's TTL in seconds, or 0 if it doesn't expire.
+    #[serde(default)]
+    lease_duration: u64,
+}
+
+struct CachedToken {
+    token: String,
+    /// When to log in again, before the token expires.
+    renew_at: Option<Instant>,
+}
+
+impl CachedToken {
+    fn new(token: String, lease_duration: u64) -> CachedToken {
+        // Renew after most of the lease has passed, leaving time for in-flight requests.
+        let renew_at = (lease_duration > 0)
+            .then(|| Instant::now() + Duration::from_secs(lease_duration * 9 / 10));
+        CachedToken { token, renew_at }
+    }
+
+    fn is_fresh(&self) -> bool {
+        self.renew_at.map(|t| Instant::now() < t).unwrap_or(true)
+    }
+}
+
+#[derive(Deserialize)]
+struct DataResponse {
+    data: serde_json::Value,
+}
+
+pub struct VaultClient {
+    addr: String,
+    namespace: Option<String>,
+    auth: VaultAuth,
+    http: reqwest::Client,
+    token: RwLock<Option<CachedToken>>,
+}
+
+impl std::fmt::Debug for VaultClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VaultClient")
+            .field("addr", &self.addr)
+            .field("namespace", &self.namespace)
+            .finish_non_exhaustive()
+    }
+}
+
+impl VaultClient {
+    pub fn new(addr: impl Into<String>, auth: VaultAuth) -> VaultClient {
+        let token = match &auth {
+            VaultAuth::Token(token) => Some(CachedToken::new(token.clone(), 0)),
+            VaultAuth::AppRole { .. } | VaultAuth::Kubernetes { .. } => None,
+        };
+
+        VaultClient {
+            addr: addr.into().trim_end_matches('/').to_string(),
+            namespace: None,
+            auth,
+            http: reqwest::Client::new(),
+            token: RwLock::new(token),
+        }
+    }
+
+    /// Make every request, including logins, in a Vault Enterprise namespace.
+    pub fn with_namespace(mut self, namespace: Option<String>) -> VaultClient {
+        self.namespace = namespace.filter(|n| !n.is_empty());
+        self
+    }
+
+    /// Read the client configuration from `VAULT_ADDR` and `VAULT_NAMESPACE`, and the
+    /// credentials from `VAULT_ROLE_ID` and `VAULT_SECRET_ID`, `VAULT_K8S_ROLE`, or
+    /// `VAULT_TOKEN`, in that order.
+    pub fn from_env() -> Option<VaultClient> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        let addr = var("VAULT_ADDR")?;
+        let auth = VaultAuth::from_vars(var)?;
+
+        Some(VaultClient::new(addr, auth).with_namespace(var("VAULT_NAMESPACE")))
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/v1/{}", self.addr, path.trim_start_matches('/'))
+    }
+
+    fn request(&self, path: &str) -> reqwest::RequestBuilder {
+        let request = self.http.post(self.url(path));
+        match &self.namespace {
+            Some(namespace) => request.header("X-Vault-Namespace", namespace),
+            None => request,
+        }
+    }
+
+    async fn login(&self) -> Result<String, Error> {
+        let (path, body) = match &self.auth {
+            VaultAuth::Token(token) => return Ok(token.clone()),
+            VaultAuth::AppRole { role_id, secret_id } => (
+                "auth/approle/login".to_string(),
+                json!({ "role_id": role_id, "secret_id": secret_id }),
+            ),
+            VaultAuth::Kubernetes {
+                role,
+                mount,
+                token_path,
+            } => {
+                let jwt = tokio::fs::read_to_string(token_path).await.map_err(|e| {
+                    Error::Vault(format!(
+                        "Reading service account token {}: {e}",
+                        token_path.display()
+                    ))
+                })?;
+                (
+                    format!("auth/{}/login", mount.trim_matches('/')),
+                    json!({ "role": role, "jwt": jwt.trim() }),
+                )
+            }
+        };
+
+        let response: LoginResponse = self
+            .request(&path)
+            .json(&body)
+            .send()
+            .await
+            .map_err(vault_error)?
+            .error_for_status()
+            .map_err(vault_error)?
+            .json()
+            .await
+            .map_err(vault_error)?;
+
+        let token = response.auth.client_token;
+        self.token.write().await.replace(CachedToken::new(
+            token.clone(),
+            response.auth.lease_duration,
+        ));
+        Ok(token)
+    }
+
+    async fn token(&self) -> Result<String, Error> {
+        if let Some(cached) = self.token.read().await.as_ref() {
+            if cached.is_fresh() {
+                return Ok(cached.token.clone());
+            }
+        }
+
+        self.login().await
+    }
+
+    /// POST to a Vault API path, such as `transit/encrypt/my-key`, and return the `data` field
+    /// of the response.
+    pub async fn post(
+        &self,
+        path: &str,
+        body: &serde_json::Value,
+    ) -> Result<serde_json::Value, Error> {
+        let mut token = self.token().await?;
+        let mut retried = false;
+        loop {
+            let response = self
+                .request(path)
+                .header("X-Vault-Token", &token)
+                .json(body)
+                .send()
+                .await
+                .map_err(vault_error)?;
+
+            // An expired or revoked token is rejected with a 403, so log in again once.
+            if response.status() == reqwest::StatusCode::FORBIDDEN
+                && self.auth.can_login()
+                && !retried
+            {
+                retried = true;
+                token = self.login().await?;
+                continue;
+            }
+
+            let response: DataResponse = response
+                .error_for_status()
+                .map_err(vault_error)?
+                .json()
+                .await
+                .map_err(vault_error)?;
+            return Ok(response.data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn auth_from(vars: &[(&str, &str)]) -> Option<VaultAuth> {
+        let vars = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<_, _>>();
+        VaultAuth::from_vars(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn kubernetes_auth_from_vars() {
+        let auth = auth_from(&[("VAULT_K8S_ROLE", "ergo"), ("VAULT_TOKEN", "unused")]);
+        match auth {
+            Some(VaultAuth::Kubernetes {
+                role,
+                mount,
+                token_path,
+            }) => {
+                assert_eq!(role, "ergo");
+                assert_eq!(mount, "kubernetes");
+                assert_eq!(token_path, PathBuf::from(DEFAULT_K8S_TOKEN_PATH));
+            }
+            other => panic!("Expected Kubernetes auth, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn auth_precedence() {
+        let auth = auth_from(&[
+            ("VAULT_ROLE_ID", "role"),
+            ("VAULT_SECRET_ID", "secret"),
+            ("VAULT_K8S_ROLE", "ergo"),
+        ]);
+        assert!(matches!(auth, Some(VaultAuth::AppRole { .. })));
+
+        let auth = auth_from(&[("VAULT_TOKEN", "a-token")]);
+        assert!(matches!(auth, Some(VaultAuth::Token(t)) if t == "a-token"));
+
+        assert!(auth_from(&[]).is_none());
+    }
+
+    #[test]
+    fn token_renewal() {
+        assert!(CachedToken::new("t".to_string(), 0).is_fresh());
+        assert!(CachedToken::new("t".to_string(), 3600).is_fresh());
+        // A one second lease is renewed immediately.
+        assert!(!CachedToken::new("t".to_string(), 1).is_fresh());
+    }
+}
@@ -1,3 +1,5 @@
+mod task;
+
 use std::{future::Future, time::Duration};
 
 use once_cell::sync::Lazy;
@@ -7,6 +9,8 @@ use tracing_log::LogTracer;
 use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt, EnvFilter, Registry};
 use tracing_tree::HierarchicalLayer;
 
+pub use task::*;
+
 fn configure_tracing<W>(name: impl Into<String>, sink: W)
 where
     for<'writer> W: MakeWriter<'writer> + Send + Sync + 'static,
@@ -0,0 +1,318 @@
+//! Run a task's logic in memory, without a database or queues, so that task authors can unit
+//! test their state machines, scripts, and dataflows.
+//!
+//! ```ignore
+//! let mut task = TestTask::new(config);
+//! let result = task.trigger("run", json!({ "value": 5 })).await?;
+//! assert_eq!(result.actions[0].name, "send");
+//! ```
+//!
+//! The task's state carries over from one trigger to the next, just like a saved task.
+
+use std::collections::HashMap;
+
+use ergo_database::object_id::{TaskId, TaskTriggerId, UserId};
+use ergo_js::ConsoleMessage;
+use ergo_tasks::{
+    actions::TaskActionInvocation,
+    egress::EgressPolicy,
+    limits::RunBudget,
+    scripting::immediate::run_task,
+    state_machine::{StateMachineError, StateMachineStates, StateMachineWithData},
+    Error, TaskConfig, TaskState,
+};
+
+/// The outcome of sending one trigger to a [TestTask].
+#[derive(Debug)]
+pub struct TriggerResult {
+    /// The actions that the task asked to run, in order.
+    pub actions: Vec<TaskActionInvocation>,
+    /// The task's state after the trigger.
+    pub state: TaskState,
+    pub state_changed: bool,
+    /// Console output from scripts. For dataflow tasks, this combines the output of each node
+    /// that ran.
+    pub console: Vec<ConsoleMessage>,
+}
+
+pub struct TestTask {
+    name: String,
+    task_id: TaskId,
+    user_id: UserId,
+    config: TaskConfig,
+    state: TaskState,
+    trigger_ids: HashMap<String, TaskTriggerId>,
+    egress: EgressPolicy,
+}
+
+impl TestTask {
+    /// Create a task with the config's default state.
+    pub fn new(config: TaskConfig) -> TestTask {
+        TestTask {
+            name: "test task".to_string(),
+            task_id: TaskId::new(),
+            user_id: UserId::new(),
+            state: config.default_state(),
+            config,
+            trigger_ids: HashMap::new(),
+            egress: EgressPolicy::allow_all(),
+        }
+    }
+
+    /// Start from this state instead of the default.
+    pub fn with_state(mut self, state: TaskState) -> Self {
+        self.state = state;
+        self
+    }
+
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Dataflow trigger nodes refer to triggers by ID, so map each trigger's local ID to the ID
+    /// used in the dataflow config.
+    pub fn with_trigger_id(mut self, local_id: impl Into<String>, id: TaskTriggerId) -> Self {
+        self.trigger_ids.insert(local_id.into(), id);
+        self
+    }
+
+    /// Restrict the network requests that scripts can make. Everything is allowed by default.
+    pub fn with_egress(mut self, egress: EgressPolicy) -> Self {
+        self.egress = egress;
+        self
+    }
+
+    pub fn state(&self) -> &TaskState {
+        &self.state
+    }
+
+    /// Send a payload to the task's trigger. On success, the task keeps the new state for the
+    /// next trigger. On error, the state is unchanged.
+    pub async fn trigger(
+        &mut self,
+        trigger: &str,
+        payload: serde_json::Value,
+    ) -> Result<TriggerResult, Error> {
+        let mut budget = RunBudget::default();
+        let (state, actions, state_changed, console) = match (&self.config, &self.state) {
+            (TaskConfig::StateMachine(machines), TaskState::StateMachine(states)) => {
+                let mut new_states = StateMachineStates::with_capacity(machines.len());
+                let mut actions = Vec::new();
+                let mut changed = false;
+                for (idx, (machine, state)) in machines.iter().zip(states.iter()).enumerate() {
+                    let mut m = StateMachineWithData::new(
+                        self.task_id,
+                        idx,
+                        machine.clone(),
+                        state.clone(),
+                    );
+                    let this_actions = m
+                        .apply_trigger(trigger, &self.user_id, &None, Some(&payload), &mut budget)
+                        .await
+                        .map_err(|e| match e {
+                            StateMachineError::LimitExceeded(e) => Error::ExecutionLimitExceeded(e),
+                            e => Error::from(e),
+                        })?;
+
+                    let (data, this_changed) = m.take();
+                    new_states.push(data);
+                    actions.extend(this_actions.into_iter().map(|a| TaskActionInvocation {
+                        name: a.task_action_local_id,
+                        payload: a.payload,
+                        node: None,
+                    }));
+                    changed = changed || this_changed;
+                }
+
+                (
+                    TaskState::StateMachine(new_states),
+                    actions,
+                    changed,
+                    Vec::new(),
+                )
+            }
+            (TaskConfig::StateMachine(_), _) => {
+                return Err(Error::ConfigStateMismatch("StateMachine"))
+            }
+            (TaskConfig::Js(config), TaskState::Js(state)) => {
+                let result = run_task(
+                    &self.name,
+                    config.clone(),
+                    state.clone(),
+                    payload,
+                    self.egress.clone(),
+                )
+                .await?;
+                budget.add_actions(result.actions.len())?;
+
+                (
+                    TaskState::Js(result.state),
+                    result.actions.into_iter().collect(),
+                    result.state_changed,
+                    result.console,
+                )
+            }
+            (TaskConfig::Js(_), _) => return Err(Error::ConfigStateMismatch("Js")),
+            (TaskConfig::DataFlow(config), TaskState::DataFlow(state)) => {
+                let task_trigger_id = self
+                    .trigger_ids
+                    .get(trigger)
+                    .copied()
+                    .ok_or_else(|| Error::TaskTriggerNotFound(trigger.to_string()))?;
+                let (new_state, log, actions) = config
+                    .evaluate_trigger(
+                        &self.name,
+                        state.clone(),
+                        task_trigger_id,
+                        trigger,
+                        payload,
+                        &mut budget,
+                        &self.egress,
+                    )
+                    .await?;
+
+                let changed = &new_state != state;
+                let console = log
+                    .map(|log| log.run.into_iter().flat_map(|node| node.console).collect())
+                    .unwrap_or_default();
+                (
+                    TaskState::DataFlow(new_state),
+                    actions.into_iter().collect(),
+                    changed,
+                    console,
+                )
+            }
+            (TaskConfig::DataFlow(_), _) => return Err(Error::ConfigStateMismatch("DataFlow")),
+        };
+
+        self.state = state.clone();
+        Ok(TriggerResult {
+            actions,
+            state,
+            state_changed,
+            console,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ergo_tasks::{
+        actions::TaskActionInvocation,
+        scripting::{TaskJsConfig, TaskJsState},
+        state_machine::{
+            ActionInvokeDef, ActionPayloadBuilder, EventHandler, StateDefinition, StateMachine,
+            TransitionTarget,
+        },
+        TaskConfig, TaskState,
+    };
+    use serde_json::json;
+    use smallvec::smallvec;
+
+    use super::TestTask;
+
+    #[tokio::test]
+    async fn state_machine() {
+        let config = TaskConfig::StateMachine(smallvec![StateMachine {
+            name: "machine".to_string(),
+            description: None,
+            initial: "off".to_string(),
+            on: smallvec![],
+            states: [
+                (
+                    "off".to_string(),
+                    StateDefinition {
+                        description: None,
+                        on: smallvec![EventHandler {
+                            trigger_id: "toggle".to_string(),
+                            cond: None,
+                            when: None,
+                            target: Some(TransitionTarget::One("on".to_string())),
+                            actions: Some(vec![ActionInvokeDef {
+                                task_action_local_id: "notify".to_string(),
+                                data: ActionPayloadBuilder::Script(
+                                    "return { value: payload.value }".to_string()
+                                ),
+                            }]),
+                        }],
+                    },
+                ),
+                (
+                    "on".to_string(),
+                    StateDefinition {
+                        description: None,
+                        on: smallvec![EventHandler {
+                            trigger_id: "toggle".to_string(),
+                            cond: None,
+                            when: None,
+                            target: Some(TransitionTarget::One("off".to_string())),
+                            actions: None,
+                        }],
+                    },
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        }]);
+
+        let mut task = TestTask::new(config);
+        let result = task.trigger("toggle", json!({ "value": 5 })).await.unwrap();
+        assert_eq!(
+            result.actions,
+            vec![TaskActionInvocation {
+                name: "notify".to_string(),
+                payload: json!({ "value": 5 }),
+                node: None,
+            }]
+        );
+        assert!(result.state_changed);
+        match task.state() {
+            TaskState::StateMachine(states) => assert_eq!(states[0].state, "on"),
+            _ => panic!("Expected state machine state"),
+        }
+
+        let result = task.trigger("toggle", json!({})).await.unwrap();
+        assert!(result.actions.is_empty());
+        match result.state {
+            TaskState::StateMachine(states) => assert_eq!(states[0].state, "off"),
+            _ => panic!("Expected state machine state"),
+        }
+    }
+
+    #[tokio::test]
+    async fn js() {
+        let config = TaskConfig::Js(TaskJsConfig {
+            map: String::new(),
+            script: r##"
+                let context = Ergo.getContext() ?? { count: 0 };
+                context.count += Ergo.getPayload().add;
+                console.log('count', context.count);
+                Ergo.setContext(context);
+                Ergo.runAction('report', { count: context.count });
+            "##
+            .to_string(),
+            timeout: None,
+            dependencies: Default::default(),
+            bundle: None,
+            libraries: Default::default(),
+        });
+
+        let mut task = TestTask::new(config).with_state(TaskState::Js(TaskJsState {
+            context: String::new(),
+        }));
+
+        task.trigger("add", json!({ "add": 2 })).await.unwrap();
+        let result = task.trigger("add", json!({ "add": 3 })).await.unwrap();
+        assert_eq!(
+            result.actions,
+            vec![TaskActionInvocation {
+                name: "report".to_string(),
+                payload: json!({ "count": 5 }),
+                node: None,
+            }]
+        );
+        assert_eq!(result.console.len(), 1);
+        assert!(result.console[0].message.contains("count 5"));
+    }
+}
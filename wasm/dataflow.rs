@@ -0,0 +1,215 @@
+//! Client-side simulation of DataFlow tasks, so that the script editor can do a dry run of a
+//! trigger against a sample payload. The Rust side walks the graph exactly as the server does,
+//! while the editor's sandbox holds the node state and runs the node code. Action nodes only
+//! report the actions they would have run.
+
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use ergo_database::object_id::TaskTriggerId;
+use ergo_tasks::{
+    actions::TaskActionInvocations,
+    dataflow::{ConsoleMessage, DataFlowConfig, DataFlowLog, DataFlowNodeRunner, DataFlowState},
+    limits::RunBudget,
+    Error,
+};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+
+#[wasm_bindgen(typescript_custom_section)]
+const DATAFLOW_HOST_TS: &str = r#"
+export interface DataFlowConsoleMessage {
+  level: 'Debug' | 'Info' | 'Warn' | 'Error';
+  /** An ISO 8601 timestamp */
+  time: string;
+  message: string;
+}
+
+export interface DataFlowSimulationHost {
+  /** Run a node's function and return its new state, serialized with `devalue`. Return an empty
+   * string if any of the nodes in `nullCheckNodes` has no value. */
+  runNode(
+    taskName: string,
+    nodeName: string,
+    nodeFunc: string,
+    nullCheckNodes: string[] | null
+  ): Promise<{ state: string; console: DataFlowConsoleMessage[] }>;
+  /** Set a node's state and return the serialized version of the state. */
+  setNodeState(nodeName: string, value: unknown): string | Promise<string>;
+  /** Get the current value of a node's state. */
+  getState(nodeName: string): unknown | Promise<unknown>;
+}
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    /// The sandbox that runs the node code. It must already have the task's compiled code and
+    /// the current node state loaded.
+    #[wasm_bindgen(typescript_type = "DataFlowSimulationHost")]
+    pub type DataFlowSimulationHost;
+
+    #[wasm_bindgen(method, catch, js_name = runNode)]
+    fn run_node(
+        this: &DataFlowSimulationHost,
+        task_name: &str,
+        node_name: &str,
+        node_func: &str,
+        null_check_nodes: JsValue,
+    ) -> Result<JsValue, JsValue>;
+
+    #[wasm_bindgen(method, catch, js_name = setNodeState)]
+    fn set_node_state(
+        this: &DataFlowSimulationHost,
+        node_name: &str,
+        value: JsValue,
+    ) -> Result<JsValue, JsValue>;
+
+    #[wasm_bindgen(method, catch, js_name = getState)]
+    fn get_state(this: &DataFlowSimulationHost, node_name: &str) -> Result<JsValue, JsValue>;
+}
+
+#[derive(Deserialize)]
+struct RunNodeResult {
+    state: String,
+    console: Vec<ConsoleMessage>,
+}
+
+#[derive(Serialize)]
+struct SimulationResult {
+    state: DataFlowState,
+    log: Option<DataFlowLog>,
+    actions: TaskActionInvocations,
+}
+
+/// Convert a value through JSON, so that objects come out as plain JS objects instead of Maps.
+fn to_js(value: &impl Serialize) -> Result<JsValue, Error> {
+    let json = serde_json::to_string(value)?;
+    Ok(js_sys::JSON::parse(&json)?)
+}
+
+fn from_js<T: serde::de::DeserializeOwned>(value: JsValue) -> Result<T, Error> {
+    let json = js_sys::JSON::stringify(&value)?
+        .as_string()
+        .unwrap_or_else(|| "null".to_string());
+    Ok(serde_json::from_str(&json)?)
+}
+
+fn error_message(error: &JsValue) -> String {
+    match error.dyn_ref::<js_sys::Error>() {
+        Some(e) => String::from(e.to_string()),
+        None => error.as_string().unwrap_or_else(|| format!("{error:?}")),
+    }
+}
+
+/// Wait for the value if the host returned a promise.
+async fn resolve(value: Result<JsValue, JsValue>, node_name: &str) -> Result<JsValue, Error> {
+    let result = match value {
+        Ok(value) => JsFuture::from(js_sys::Promise::resolve(&value)).await,
+        Err(e) => Err(e),
+    };
+
+    result.map_err(|e| Error::DataflowSimulation {
+        node: node_name.to_string(),
+        error: error_message(&e),
+    })
+}
+
+struct HostRunner<'a>(&'a DataFlowSimulationHost);
+
+#[async_trait(?Send)]
+impl<'a> DataFlowNodeRunner for HostRunner<'a> {
+    async fn run_node(
+        &self,
+        task_name: &str,
+        node_name: &str,
+        node_func: &str,
+        null_check_nodes: &[&str],
+    ) -> Result<(String, Vec<ConsoleMessage>), Error> {
+        let null_checks = if null_check_nodes.is_empty() {
+            JsValue::NULL
+        } else {
+            to_js(&null_check_nodes)?
+        };
+
+        let result = self
+            .0
+            .run_node(task_name, node_name, node_func, null_checks);
+        let result: RunNodeResult = from_js(resolve(result, node_name).await?)?;
+        Ok((result.state, result.console))
+    }
+
+    async fn set_node_state(
+        &self,
+        node_name: &str,
+        value: &serde_json::Value,
+    ) -> Result<String, Error> {
+        let result = self.0.set_node_state(node_name, to_js(value)?);
+        let state = resolve(result, node_name).await?;
+        Ok(state.as_string().unwrap_or_default())
+    }
+
+    async fn get_raw_state(&self, node_name: &str) -> Result<serde_json::Value, Error> {
+        let result = self.0.get_state(node_name);
+        from_js(resolve(result, node_name).await?)
+    }
+}
+
+async fn simulate(
+    task_name: &str,
+    config: JsValue,
+    state: JsValue,
+    task_trigger_id: &str,
+    payload: JsValue,
+    host: &DataFlowSimulationHost,
+) -> Result<SimulationResult, Error> {
+    let config: DataFlowConfig = from_js(config)?;
+    let state: DataFlowState = if state.is_null() || state.is_undefined() {
+        config.default_state()
+    } else {
+        from_js(state)?
+    };
+    let trigger_id = TaskTriggerId::from_str(task_trigger_id)
+        .map_err(|_| Error::TaskTriggerNotFound(task_trigger_id.to_string()))?;
+    let payload: serde_json::Value = from_js(payload)?;
+
+    let runner = HostRunner(host);
+    config.init_static_nodes(&runner).await?;
+
+    let (state, log, actions) = config
+        .evaluate_trigger_with_runner(
+            &runner,
+            task_name,
+            state,
+            trigger_id,
+            task_trigger_id,
+            payload,
+            &mut RunBudget::default(),
+        )
+        .await?;
+
+    Ok(SimulationResult {
+        state,
+        log,
+        actions,
+    })
+}
+
+/// Send a payload to a trigger of a DataFlow task and run the affected nodes, without running any
+/// actions. Returns the new state, the console output of each node, and the actions that would
+/// have run.
+#[wasm_bindgen]
+pub async fn simulate_dataflow(
+    task_name: String,
+    config: JsValue,
+    state: JsValue,
+    task_trigger_id: String,
+    payload: JsValue,
+    host: DataFlowSimulationHost,
+) -> Result<JsValue, JsValue> {
+    let result = simulate(&task_name, config, state, &task_trigger_id, payload, &host)
+        .await
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+    to_js(&result).map_err(|e| JsValue::from_str(&e.to_string()))
+}
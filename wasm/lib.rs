@@ -5,6 +5,7 @@ use ergo_tasks::{
     actions::{Action, TaskAction},
     dataflow::DataFlowEdge,
     inputs::Input,
+    shorthand::compile_shorthand,
     PeriodicSchedule, TaskConfig, TaskTrigger, ValidatePathSegment,
 };
 use fxhash::FxHashMap;
@@ -12,6 +13,8 @@ use serde::Serialize;
 use serde_path_to_error::Segment;
 use wasm_bindgen::prelude::*;
 
+mod dataflow;
+
 #[derive(Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum LintSeverity {
@@ -188,6 +191,22 @@ pub fn parse_schedule(schedule: String) -> Result<Option<i64>, JsValue> {
     Ok(next)
 }
 
+/// Compile shorthand task rules into a state machine task config.
+#[wasm_bindgen]
+pub fn compile_task_shorthand(name: String, source: String) -> Result<JsValue, JsValue> {
+    let machines = compile_shorthand(&name, &source).map_err(|errors| {
+        errors
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    })?;
+
+    Ok(serde_wasm_bindgen::to_value(&TaskConfig::StateMachine(
+        machines,
+    ))?)
+}
+
 #[wasm_bindgen]
 pub fn toposort_nodes(num_nodes: usize, edges: JsValue) -> Result<JsValue, JsValue> {
     let edges_de = serde_wasm_bindgen::Deserializer::from(edges);
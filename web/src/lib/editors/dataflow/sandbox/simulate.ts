@@ -0,0 +1,90 @@
+import type { DataFlowConfig, DataFlowState } from '$lib/api_types';
+import initWasm from '$lib/wasm';
+import { parse, stringify } from 'devalue';
+import {
+  simulate_dataflow,
+  type DataFlowConsoleMessage,
+  type DataFlowSimulationHost,
+} from 'ergo-wasm';
+
+export interface SimulationResult {
+  state: DataFlowState;
+  log: { run: { node: string; console: DataFlowConsoleMessage[] }[] } | null;
+  actions: { name: string; payload: object }[];
+}
+
+const consoleLevels = {
+  debug: 'Debug',
+  log: 'Info',
+  info: 'Info',
+  warn: 'Warn',
+  error: 'Error',
+} as const;
+
+/** Capture console output while running `fn`, in the same format as the server's logs. */
+async function captureConsole<T>(fn: () => Promise<T>) {
+  let messages: DataFlowConsoleMessage[] = [];
+  let original = new Map<string, (...args: unknown[]) => void>();
+
+  for (let [method, level] of Object.entries(consoleLevels)) {
+    original.set(method, console[method]);
+    console[method] = (...args: unknown[]) => {
+      let message = args.map((a) => (typeof a === 'string' ? a : JSON.stringify(a))).join(' ');
+      messages.push({ level, time: new Date().toISOString(), message: message + '\n' });
+    };
+  }
+
+  try {
+    let result = await fn();
+    return { result, messages };
+  } finally {
+    for (let [method, fn] of original) {
+      console[method] = fn;
+    }
+  }
+}
+
+/** Create a host that runs the nodes in this context, mirroring the server's dataflow environment. */
+function createHost(config: DataFlowConfig, state: DataFlowState | null): DataFlowSimulationHost {
+  let nodeState: Record<string, unknown> = {};
+  config.nodes.forEach((node, i) => {
+    let value = state?.nodes[i];
+    nodeState[node.name] = value ? parse(value) : null;
+  });
+
+  let nodeCode = new Function(`return ${config.compiled}`)();
+
+  return {
+    async runNode(_taskName, nodeName, nodeFunc, nullCheckNodes) {
+      // Skip on null or undefined. Empty string indicates that nothing happened.
+      if (nullCheckNodes?.some((node) => nodeState[node] == null)) {
+        return { state: '', console: [] };
+      }
+
+      let { result, messages } = await captureConsole(() => nodeCode[nodeFunc](nodeState));
+      nodeState[nodeName] = result;
+      return { state: stringify(result ?? null), console: messages };
+    },
+    setNodeState(nodeName, value) {
+      nodeState[nodeName] = value;
+      return stringify(value);
+    },
+    getState(nodeName) {
+      return nodeState[nodeName];
+    },
+  };
+}
+
+/** Do a dry run of a DataFlow task by sending `payload` to a trigger. Actions are returned instead
+ * of being run. */
+export async function simulateDataFlow(
+  taskName: string,
+  config: DataFlowConfig,
+  state: DataFlowState | null,
+  taskTriggerId: string,
+  payload: object
+): Promise<SimulationResult> {
+  await initWasm();
+  let host = createHost(config, state);
+  return simulate_dataflow(taskName, config, state, taskTriggerId, payload, host);
+}
@@ -114,6 +114,28 @@ declare namespace Ergo {
 
   function getContext<CONTEXT>(): CONTEXT | undefined;
   function setContext<CONTEXT>(context: CONTEXT): void;
+
+  interface Lock {
+    name: string;
+    token: string;
+    /** Reset the lock's expiration. Returns false if the lock had already expired. */
+    extend(ttl: number): Promise<boolean>;
+    /** Release the lock. Returns false if the lock had already expired. */
+    release(): Promise<boolean>;
+  }
+
+  /** Take a named lock, shared with the org's other scripts. \`ttl\` and \`wait\` are in milliseconds.
+   * Throws if the lock isn't free within \`wait\`. */
+  function lock(name: string, ttl?: number, options?: { wait?: number }): Promise<Lock>;
+  /** Take a named lock if it is free, or return null. */
+  function tryLock(name: string, ttl?: number): Promise<Lock | null>;
+  /** Run \`fn\` while holding a named lock. */
+  function withLock<T>(
+    name: string,
+    ttl: number,
+    fn: (lock: Lock) => Promise<T>,
+    options?: { wait?: number }
+  ): Promise<T>;
 }
 
 `;